@@ -0,0 +1,963 @@
+//! `sovereignctl`: inspects and drives the local node over IPC.
+
+mod repl;
+mod setup;
+mod snapshot;
+
+use anyhow::{anyhow, bail, Context, Result};
+use sovereign_client::NodeClient;
+use sovereign_protocol::{default_data_dir, BuildInfo, CoreChangeOp, CoreFailure, CoreGrantRights, EventTopic, IpcEndpoint, MachineBinding, MeshPhase, MetricsSnapshot, NodeEvent, NodeMode, NodeStatus, Request, Response, SnapshotSummary, WasmManifest, WasmPathRejection};
+use std::io::Read;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+
+const USAGE: &str = "\
+Usage: sovereignctl [--json] [--endpoint <endpoint>] <command>
+       sovereignctl --raw [--endpoint <endpoint>]
+
+Commands:
+  status                               Node status
+  peers                                Connected mesh peers
+  presence                             Nodes heard from over presence beacons, and
+                                       whether each is stale
+  dial <multiaddr> [--persist]         Dial a mesh peer; --persist redials it on every start
+  unpin <multiaddr>                    Stop redialing a persisted peer
+  outbox [--all]                       Replicated ops waiting to be gossiped; --all
+                                       lists the ones gossiped lately too
+  outbox requeue <seq>...              Gossip outbox entries again, or retry them now
+  query <cozoscript> [--param k=v]...  Run a core query; values are JSON, or else strings
+  wasm run <name|path> [--input <text>|-]
+                                       Run a registered module, or a .wasm file by path
+  wasm list                            Registered modules
+  wasm upload <name> <path>            Register the module at path under name
+  wasm delete <name>                   Remove a registered module
+  license verify <txid>                Check a license transaction on-chain
+  license info                         The last known license state
+  subscribe <topic>                    Print pushed events until Ctrl-C; topic is
+                                       license, core:<relation>, mesh or wasm-jobs
+  grant <principal> <pattern> read|write
+                                       Let a principal (token:<name>, uid:<uid> or
+                                       module:<name>) query the relations a pattern
+                                       names; a trailing * matches any rest
+  revoke <principal> <pattern>         Remove a grant
+  grants [<principal>]                 Core grants, all or one principal's
+  logs [--limit <n>]                   Recent core audit entries (default 20)
+  audit [--limit <n>]                  Recent IPC audit entries (default 20)
+  slow [--limit <n>]                   Recent requests over the node's slow-request
+                                       threshold (default 20)
+  connections                          Open IPC connections, their traffic and requests
+  check                                Run the node's self-check
+  setup [--force]                      Set up a node started with an empty data
+                                       directory; --force sets up one that already is,
+                                       from when it next starts
+  snapshot export <file>               Save the node's data, modules, jobs, peers and
+                                       config to file, to move the node to another
+                                       machine; keys and the license stay behind
+  snapshot import <file>               Restore a snapshot into the node; requests are
+                                       turned away until it is applied
+  metrics                              Node counters
+  version                              This tool's version and how the node was built
+  repl                                 Interactive shell for core queries; \\help lists
+                                       its commands
+
+Options:
+  --json                  Print the node's responses as JSON
+  --raw                   Send each line of stdin to the node as a JSON
+                          message and print each line it sends back, until
+                          the node closes the connection
+  --version               Same as the version command
+  --token <token>         Access token the node's config grants permissions
+                          to; defaults to SOVEREIGN_TOKEN
+  --endpoint <endpoint>   Node socket or pipe; defaults to SOVEREIGN_IPC, the
+                          node's discovery file, then the platform default
+
+Exit codes: 0 success, 1 the request failed, the license is not valid or
+a self-check failed, 2 bad usage, 3 the node could not be reached.";
+
+const EXIT_FAILED: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_UNREACHABLE: i32 = 3;
+
+enum Command {
+    Status,
+    Peers,
+    Presence,
+    Dial { addr: String, persist: bool },
+    Unpin(String),
+    Outbox { all: bool },
+    OutboxRequeue(Vec<u64>),
+    Query { script: String, params: serde_json::Map<String, serde_json::Value> },
+    WasmRun { target: String, input: String },
+    WasmList,
+    WasmUpload { name: String, path: String },
+    WasmDelete(String),
+    LicenseVerify(String),
+    LicenseInfo,
+    Subscribe(Request),
+    Grant { principal: String, pattern: String, rights: CoreGrantRights },
+    Revoke { principal: String, pattern: String },
+    Grants(Option<String>),
+    Logs { limit: u32 },
+    Audit { limit: u32 },
+    Slow { limit: u32 },
+    Connections,
+    Check,
+    Setup { force: bool },
+    SnapshotExport(String),
+    SnapshotImport(String),
+    Metrics,
+    Version,
+    Repl,
+    /// `--raw`: the client speaks JSON lines itself.
+    Raw,
+}
+
+struct Options {
+    json: bool,
+    endpoint: Option<IpcEndpoint>,
+    token: Option<String>,
+    command: Command,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let options = match parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("sovereignctl: {}\n\n{}", e, USAGE);
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+    let endpoint = options.endpoint.clone().unwrap_or_else(|| IpcEndpoint::discover(&default_data_dir()));
+    if let Command::Raw = options.command {
+        if let Err(e) = raw(&endpoint).await {
+            eprintln!("sovereignctl: {:#}", e);
+            std::process::exit(EXIT_UNREACHABLE);
+        }
+        return;
+    }
+    let client = NodeClient::connect_with_token(&endpoint, "sovereignctl", options.token.as_deref()).await;
+    let version = matches!(options.command, Command::Version);
+    if version && !options.json {
+        println!("sovereignctl {}", env!("CARGO_PKG_VERSION"));
+    }
+    let client = match client {
+        Ok(client) => client,
+        // The tool's own version is worth printing with no node running.
+        Err(e) if version => {
+            eprintln!("node: unreachable ({:#})", e);
+            return;
+        }
+        Err(e) => {
+            eprintln!("sovereignctl: {:#}", e);
+            std::process::exit(EXIT_UNREACHABLE);
+        }
+    };
+    match run(&client, options.command, options.json).await {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(EXIT_FAILED),
+        Err(e) => {
+            eprintln!("sovereignctl: {:#}", e);
+            std::process::exit(EXIT_FAILED);
+        }
+    }
+}
+
+fn parse(args: Vec<String>) -> Result<Options> {
+    let mut json = false;
+    let mut raw = false;
+    let mut version = false;
+    let mut endpoint = None;
+    let mut token = std::env::var("SOVEREIGN_TOKEN").ok().filter(|t| !t.is_empty());
+    let mut words = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--raw" => raw = true,
+            "--version" => version = true,
+            "--token" => token = Some(args.next().ok_or_else(|| anyhow!("--token needs a value"))?),
+            "--endpoint" => endpoint = Some(IpcEndpoint::parse(&args.next().ok_or_else(|| anyhow!("--endpoint needs a value"))?)),
+            _ => words.push(arg),
+        }
+    }
+    if raw {
+        if let Some(word) = words.first() {
+            bail!("--raw takes no command, not '{}'", word);
+        }
+        return Ok(Options {
+            json,
+            endpoint,
+            token,
+            command: Command::Raw,
+        });
+    }
+    if version && words.is_empty() {
+        words.push("version".into());
+    }
+    let mut words = words.into_iter();
+    let mut next = |what: &str| words.next().ok_or_else(|| anyhow!("missing {}", what));
+    let command = match next("command")?.as_str() {
+        "status" => Command::Status,
+        "peers" => Command::Peers,
+        "presence" => Command::Presence,
+        "dial" => {
+            let addr = next("multiaddr")?;
+            let persist = match next("") {
+                Ok(flag) if flag == "--persist" => true,
+                Ok(flag) => bail!("unexpected '{}'", flag),
+                Err(_) => false,
+            };
+            Command::Dial { addr, persist }
+        }
+        "unpin" => Command::Unpin(next("multiaddr")?),
+        "outbox" => match next("").ok().as_deref() {
+            None => Command::Outbox { all: false },
+            Some("--all") => Command::Outbox { all: true },
+            Some("requeue") => {
+                let mut seqs = vec![next("outbox entry")?];
+                while let Ok(seq) = next("") {
+                    seqs.push(seq);
+                }
+                let seqs = seqs
+                    .iter()
+                    .map(|seq| seq.parse().map_err(|_| anyhow!("outbox entries are numbered, not '{}'", seq)))
+                    .collect::<Result<_>>()?;
+                Command::OutboxRequeue(seqs)
+            }
+            Some(other) => bail!("unexpected '{}'", other),
+        },
+        "query" => {
+            let script = next("query")?;
+            let mut params = serde_json::Map::new();
+            while let Ok(flag) = next("") {
+                if flag != "--param" {
+                    bail!("unexpected '{}'", flag);
+                }
+                let pair = next("--param value")?;
+                let (key, value) = pair.split_once('=').ok_or_else(|| anyhow!("--param must be key=value, not '{}'", pair))?;
+                let value = serde_json::from_str(value).unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+                params.insert(key.to_string(), value);
+            }
+            Command::Query { script, params }
+        }
+        "wasm" => match next("wasm command")?.as_str() {
+            "run" => {
+                let target = next("module name or path")?;
+                let input = match (next("").ok().as_deref(), next("").ok()) {
+                    (None, _) => String::new(),
+                    (Some("--input"), Some(input)) if input == "-" => {
+                        let mut input = String::new();
+                        std::io::stdin().read_to_string(&mut input).context("Failed to read stdin")?;
+                        input
+                    }
+                    (Some("--input"), Some(input)) => input,
+                    (Some(other), _) => bail!("unexpected '{}'", other),
+                };
+                Command::WasmRun { target, input }
+            }
+            "list" => Command::WasmList,
+            "upload" => Command::WasmUpload {
+                name: next("module name")?,
+                path: next("module path")?,
+            },
+            "delete" => Command::WasmDelete(next("module name")?),
+            other => bail!("unknown wasm command '{}'", other),
+        },
+        "license" => match next("license command")?.as_str() {
+            "verify" => Command::LicenseVerify(next("txid")?),
+            "info" => Command::LicenseInfo,
+            other => bail!("unknown license command '{}'", other),
+        },
+        "subscribe" => Command::Subscribe(topic_request(&next("topic")?)?),
+        "grant" => {
+            let principal = next("principal")?;
+            let pattern = next("relation pattern")?;
+            let rights = match next("rights")?.as_str() {
+                "read" => CoreGrantRights::Read,
+                "write" => CoreGrantRights::Write,
+                other => bail!("rights must be read or write, not '{}'", other),
+            };
+            Command::Grant { principal, pattern, rights }
+        }
+        "revoke" => Command::Revoke {
+            principal: next("principal")?,
+            pattern: next("relation pattern")?,
+        },
+        "grants" => Command::Grants(next("").ok()),
+        "logs" => Command::Logs { limit: limit(&mut next)? },
+        "audit" => Command::Audit { limit: limit(&mut next)? },
+        "slow" => Command::Slow { limit: limit(&mut next)? },
+        "connections" => Command::Connections,
+        "check" => Command::Check,
+        "setup" => match next("") {
+            Ok(flag) if flag == "--force" => Command::Setup { force: true },
+            Ok(flag) => bail!("unexpected '{}'", flag),
+            Err(_) => Command::Setup { force: false },
+        },
+        "snapshot" => match next("snapshot command")?.as_str() {
+            "export" => Command::SnapshotExport(next("file")?),
+            "import" => Command::SnapshotImport(next("file")?),
+            other => bail!("unknown snapshot command '{}'", other),
+        },
+        "metrics" => Command::Metrics,
+        "version" => Command::Version,
+        "repl" => Command::Repl,
+        other => bail!("unknown command '{}'", other),
+    };
+    if let Ok(extra) = next("") {
+        bail!("unexpected '{}'", extra);
+    }
+    Ok(Options { json, endpoint, token, command })
+}
+
+/// Sends `command` and prints what comes back. False if the node reported
+/// a failure.
+async fn run(client: &NodeClient, command: Command, json: bool) -> Result<bool> {
+    let req = match command {
+        Command::Status => Request::GetStatus,
+        Command::Peers => Request::MeshPeers,
+        Command::Presence => Request::MeshPresence,
+        Command::Dial { addr, persist } => Request::MeshDial { addr, persist },
+        Command::Unpin(addr) => Request::MeshUnpin { addr },
+        Command::Outbox { all } => Request::ReplicationOutbox {
+            after_seq: None,
+            include_published: all,
+            limit: None,
+        },
+        Command::OutboxRequeue(seqs) => Request::ReplicationRequeue { seqs },
+        Command::Query { script, params } => Request::QueryCore {
+            query: script,
+            params: serde_json::Value::Object(params),
+            timeout_ms: None,
+            readonly: false,
+            limit: None,
+        },
+        Command::WasmRun { target, input } if is_path(&target) => Request::RunWasm {
+            path: node_path(&target)?,
+            input,
+            args: Vec::new(),
+            env: Vec::new(),
+            fuel_limit: None,
+            signature: None,
+        },
+        Command::WasmRun { target, input } => Request::RunWasmModule {
+            name: target,
+            input,
+            args: Vec::new(),
+            env: Vec::new(),
+            fuel_limit: None,
+        },
+        Command::WasmList => Request::WasmList,
+        Command::WasmUpload { name, path } => Request::WasmUpload {
+            name,
+            path: node_path(&path)?,
+            manifest: WasmManifest::default(),
+            signature: None,
+            watch: false,
+        },
+        Command::WasmDelete(name) => Request::WasmRemove { name },
+        Command::LicenseVerify(tx_id) => Request::VerifyLicense {
+            tx_id,
+            developer_addr: String::new(),
+            required_sats: 0,
+        },
+        Command::LicenseInfo => Request::GetLicenseInfo,
+        Command::Subscribe(req) => return subscribe(client, req, json).await,
+        Command::Grant { principal, pattern, rights } => Request::CoreGrant {
+            principal,
+            relation_pattern: pattern,
+            rights,
+        },
+        Command::Revoke { principal, pattern } => Request::CoreRevoke {
+            principal,
+            relation_pattern: pattern,
+        },
+        Command::Grants(principal) => Request::CoreListGrants { principal },
+        Command::Logs { limit } => Request::CoreAuditTail { limit },
+        Command::Audit { limit } => Request::AuditTail { limit },
+        Command::Slow { limit } => Request::SlowRequests { limit },
+        Command::Connections => Request::ConnectionStats,
+        Command::Check => Request::SelfCheck,
+        Command::Setup { force } => return setup::run(client, force, json).await,
+        Command::SnapshotExport(file) => return snapshot::export(client, &file, json).await,
+        Command::SnapshotImport(file) => return snapshot::import(client, &file, json).await,
+        Command::Metrics => Request::GetMetrics,
+        Command::Version => Request::GetVersion,
+        Command::Repl => return repl::run(client, json).await,
+        Command::Raw => unreachable!("--raw is handled before connecting"),
+    };
+    let resp = client.request(req).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&resp)?);
+    } else {
+        print_response(&resp);
+    }
+    Ok(succeeded(&resp))
+}
+
+/// Prints the watch's answer, then every push until Ctrl-C or the node
+/// goes away.
+async fn subscribe(client: &NodeClient, req: Request, json: bool) -> Result<bool> {
+    let mut pushes = client.pushes();
+    let resp = client.request(req).await?;
+    if !succeeded(&resp) {
+        print_response(&resp);
+        return Ok(false);
+    }
+    if json {
+        println!("{}", serde_json::to_string(&resp)?);
+    } else {
+        print_response(&resp);
+    }
+    loop {
+        tokio::select! {
+            push = pushes.recv() => {
+                let Some(push) = push else {
+                    bail!("Connection to node lost");
+                };
+                if json {
+                    println!("{}", serde_json::to_string(&push)?);
+                } else {
+                    print_response(&push);
+                }
+            }
+            _ = tokio::signal::ctrl_c() => return Ok(true),
+        }
+    }
+}
+
+/// Copies stdin's lines to the node and the node's lines to stdout. The
+/// node picks JSON-lines framing from the first line, which must be a JSON
+/// object or string; Hello is optional. At the end of stdin the connection
+/// is half closed, and the node closes it once it has answered.
+async fn raw(endpoint: &IpcEndpoint) -> Result<()> {
+    let (reader, mut writer) = sovereign_client::connect_raw(endpoint).await?;
+    let mut replies = BufReader::new(reader).lines();
+
+    // Stdin is read on a thread of its own: tokio's needs the io-std feature.
+    let (line_tx, mut lines) = mpsc::unbounded_channel::<String>();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else { break };
+            if line_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut input_open = true;
+    loop {
+        tokio::select! {
+            line = lines.recv(), if input_open => match line {
+                Some(line) if line.trim().is_empty() => {}
+                Some(line) => {
+                    writer.write_all(line.trim().as_bytes()).await?;
+                    writer.write_all(b"\n").await?;
+                }
+                None => {
+                    input_open = false;
+                    writer.shutdown().await?;
+                }
+            },
+            reply = replies.next_line() => match reply.context("Connection to node lost")? {
+                Some(reply) => println!("{}", reply),
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+/// The request that watches `topic`.
+fn topic_request(topic: &str) -> Result<Request> {
+    Ok(match topic.split_once(':') {
+        None if topic == "license" => Request::WatchLicense,
+        None if topic == "mesh" => Request::Subscribe { topics: vec![EventTopic::Mesh] },
+        None if topic == "wasm-jobs" => Request::Subscribe { topics: vec![EventTopic::WasmJobs] },
+        Some(("core", relation)) if !relation.is_empty() => Request::CoreWatch {
+            relation: relation.to_string(),
+            filter: None,
+        },
+        _ => bail!("topic must be license, core:<relation>, mesh or wasm-jobs, not '{}'", topic),
+    })
+}
+
+/// `--limit <n>` if given, else 20.
+fn limit(next: &mut impl FnMut(&str) -> Result<String>) -> Result<u32> {
+    match (next("").ok().as_deref(), next("").ok()) {
+        (None, _) => Ok(20),
+        (Some("--limit"), Some(n)) => n.parse().map_err(|_| anyhow!("--limit must be a number, not '{}'", n)),
+        (Some(other), _) => bail!("unexpected '{}'", other),
+    }
+}
+
+fn is_path(target: &str) -> bool {
+    target.ends_with(".wasm") || target.contains('/') || target.contains('\\')
+}
+
+/// The node resolves paths itself, from its own working directory.
+fn node_path(path: &str) -> Result<String> {
+    let absolute = std::fs::canonicalize(Path::new(path)).with_context(|| format!("Cannot find {}", path))?;
+    Ok(absolute.to_string_lossy().into_owned())
+}
+
+fn rights_text(rights: CoreGrantRights) -> &'static str {
+    match rights {
+        CoreGrantRights::Read => "read",
+        CoreGrantRights::Write => "write",
+    }
+}
+
+fn succeeded(resp: &Response) -> bool {
+    match resp {
+        Response::Error(_)
+        | Response::CoreFailed(_)
+        | Response::TimedOut { .. }
+        | Response::Unavailable { .. }
+        | Response::WasmPathRejected { .. }
+        | Response::RateLimited { .. }
+        | Response::Overloaded { .. }
+        | Response::Busy { .. }
+        | Response::PermissionDenied { .. }
+        | Response::SetupRequired { .. }
+        | Response::FrameTooLarge { .. } => false,
+        Response::WasmResult { trapped, exit_code, .. } => !trapped && exit_code.unwrap_or(0) == 0,
+        Response::WasmRemoved { removed, .. } => *removed,
+        Response::CoreRevoked { revoked, .. } => *revoked,
+        Response::ReplicationRequeued { requeued } => *requeued > 0,
+        Response::LicenseResult { valid, .. } => *valid,
+        Response::SelfCheck(report) => !report.failed(),
+        _ => true,
+    }
+}
+
+fn print_response(resp: &Response) {
+    match resp {
+        Response::Status(status) => print_status(status),
+        Response::Metrics(metrics) => print_metrics(metrics),
+        Response::Version(build) => print_build(build),
+        Response::MeshGeneric(text) => println!("{}", text),
+        Response::MeshPresence { nodes, stale_after_ms } => {
+            print_table(
+                &["PEER", "VERSION", "HEALTH", "UPTIME", "LAST SEEN", "STATE", "CAPABILITIES"],
+                nodes
+                    .iter()
+                    .map(|n| {
+                        vec![
+                            n.peer_id.clone(),
+                            n.node_version.clone(),
+                            n.health.clone(),
+                            format!("{}s", n.uptime_ms / 1000),
+                            utc(n.last_seen_ms),
+                            match (n.stale, n.connected) {
+                                (true, _) => "stale".into(),
+                                (false, true) => "connected".into(),
+                                (false, false) => "relayed".into(),
+                            },
+                            n.capabilities.join(","),
+                        ]
+                    })
+                    .collect(),
+            );
+            println!("nodes are stale after {}s without a beacon", stale_after_ms / 1000);
+        }
+        Response::ReplicationOutbox(report) => {
+            print_table(
+                &["SEQ", "RELATION", "QUEUED", "PUBLISHED", "ATTEMPTS", "ERROR"],
+                report
+                    .entries
+                    .iter()
+                    .map(|e| {
+                        vec![
+                            e.seq.to_string(),
+                            e.relation.clone(),
+                            utc(e.queued_ms),
+                            e.published_ms.map_or("-".into(), utc),
+                            e.attempts.to_string(),
+                            e.error.clone().unwrap_or_else(|| "-".into()),
+                        ]
+                    })
+                    .collect(),
+            );
+            println!("{} ops waiting to be gossiped, high-water mark {}", report.depth, report.high_water);
+        }
+        Response::ReplicationRequeued { requeued } => println!("Requeued {} outbox entries", requeued),
+        Response::CoreResult(result) => print_rows(result),
+        Response::WasmResult { stdout, stderr, output, exit_code, trapped, trap_message, fuel_used, duration_ms, .. } => {
+            print!("{}", stdout);
+            if let Some(output) = output {
+                println!("{}", output);
+            }
+            eprint!("{}", stderr);
+            if *trapped {
+                eprintln!("trapped: {}", trap_message.as_deref().unwrap_or("unknown trap"));
+            }
+            eprintln!(
+                "exit {} in {} ms, {} fuel",
+                exit_code.map_or("-".into(), |c| c.to_string()),
+                duration_ms,
+                fuel_used.map_or("-".into(), |f| f.to_string())
+            );
+        }
+        Response::WasmModules(modules) => print_table(
+            &["NAME", "REVISION", "SIZE", "SHA256", "CAPABILITIES"],
+            modules
+                .iter()
+                .map(|m| {
+                    vec![
+                        m.name.clone(),
+                        m.revision.to_string(),
+                        m.size_bytes.to_string(),
+                        m.sha256.chars().take(12).collect(),
+                        m.capabilities.join(","),
+                    ]
+                })
+                .collect(),
+        ),
+        Response::WasmModule(module) => println!("{} revision {} ({} bytes, sha256 {})", module.name, module.revision, module.size_bytes, module.sha256),
+        Response::WasmRemoved { name, removed: true } => println!("Removed {}", name),
+        Response::WasmRemoved { name, removed: false } => eprintln!("No module named {}", name),
+        Response::CoreGranted(grant) => println!("{} may now {} {}", grant.principal, rights_text(grant.rights), grant.relation_pattern),
+        Response::CoreRevoked { principal, relation_pattern, revoked: true } => println!("Revoked {} on {}", principal, relation_pattern),
+        Response::CoreRevoked { principal, relation_pattern, revoked: false } => eprintln!("{} has no grant on {}", principal, relation_pattern),
+        Response::CoreGrants(grants) => print_table(
+            &["PRINCIPAL", "PATTERN", "RIGHTS", "GRANTED"],
+            grants
+                .iter()
+                .map(|g| vec![g.principal.clone(), g.relation_pattern.clone(), rights_text(g.rights).into(), utc(g.granted_ms)])
+                .collect(),
+        ),
+        Response::LicenseResult { valid, details, report, .. } => {
+            println!("valid:          {}", valid);
+            println!("details:        {}", details);
+            if let Some(report) = report {
+                println!("txid:           {}", report.txid);
+                println!("paid:           {} sats", report.paid_sats);
+                println!("confirmations:  {}", report.confirmations);
+                println!("tier:           {}", report.tier.as_deref().unwrap_or("-"));
+                println!("checked:        {}", utc(report.checked_at_ms));
+            }
+        }
+        Response::LicenseStatusChanged { active, .. } => println!("license is now {}", if *active { "active" } else { "inactive" }),
+        Response::CoreAudit(entries) => print_table(
+            &["TIME", "SOURCE", "MS", "ROWS", "QUERY"],
+            entries
+                .iter()
+                .map(|e| {
+                    vec![
+                        utc(e.at_ms),
+                        e.source.clone(),
+                        format!("{:.1}", e.duration_ms),
+                        e.rows.map_or("-".into(), |r| r.to_string()),
+                        match &e.error {
+                            Some(error) => format!("failed: {}", error),
+                            None => e.query.clone().unwrap_or_else(|| e.query_hash.chars().take(12).collect()),
+                        },
+                    ]
+                })
+                .collect(),
+        ),
+        Response::IpcAudit { entries, dropped } => {
+            print_table(
+                &["TIME", "CLIENT", "UID", "REQUEST", "OUTCOME", "MS"],
+                entries
+                    .iter()
+                    .map(|e| {
+                        vec![
+                            utc(e.at_ms),
+                            format!("{} #{}", e.client, e.connection_id),
+                            e.uid.map_or("-".into(), |uid| uid.to_string()),
+                            e.request.clone(),
+                            e.outcome.clone(),
+                            format!("{:.1}", e.duration_ms),
+                        ]
+                    })
+                    .collect(),
+            );
+            if *dropped > 0 {
+                println!("{} entries were dropped while the writer fell behind", dropped);
+            }
+        }
+        Response::SlowRequests { requests, threshold_ms } => match threshold_ms {
+            Some(threshold_ms) => {
+                println!("requests over {} ms:", threshold_ms);
+                print_table(
+                    &["TIME", "CLIENT", "REQUEST", "OUTCOME", "MS", "PARAMS"],
+                    requests
+                        .iter()
+                        .map(|r| {
+                            vec![
+                                utc(r.at_ms),
+                                format!("{} #{}", r.client, r.connection_id),
+                                r.request.clone(),
+                                r.outcome.clone(),
+                                format!("{:.1}", r.duration_ms),
+                                r.params.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join(" "),
+                            ]
+                        })
+                        .collect(),
+                );
+            }
+            None => println!("the node records no slow requests; set ipc_slow_request_ms"),
+        },
+        Response::Connections(connections) => print_table(
+            &["ID", "CLIENT", "UID", "OPENED", "IN", "OUT", "REQUESTS", "ERRORS", "AVG MS"],
+            connections
+                .iter()
+                .map(|c| {
+                    let count: u64 = c.requests.values().map(|s| s.count).sum();
+                    let micros: u64 = c.requests.values().map(|s| s.total_micros).sum();
+                    vec![
+                        c.connection_id.to_string(),
+                        c.client.clone(),
+                        c.uid.map_or("-".into(), |uid| uid.to_string()),
+                        utc(c.opened_at_ms),
+                        c.bytes_in.to_string(),
+                        c.bytes_out.to_string(),
+                        count.to_string(),
+                        c.requests.values().map(|s| s.errors).sum::<u64>().to_string(),
+                        micros.checked_div(count).map_or("-".into(), |avg| format!("{:.1}", avg as f64 / 1000.0)),
+                    ]
+                })
+                .collect(),
+        ),
+        Response::SelfCheck(report) => print!("{}", report),
+        Response::SetupState(state) => {
+            println!("pending:        {}", state.pending);
+            println!("config file:    {}", state.config_path);
+            println!("data dir:       {}", state.data_dir);
+            println!("network:        {}", state.network);
+            println!("swarm key:      {}", if state.swarm_key_present { "present" } else { "missing" });
+            for peer in &state.bootstrap_peers {
+                println!("bootstrap peer: {}", peer);
+            }
+        }
+        Response::SetupApplied { restart_required: true } => println!("Set up; the new settings take effect when the node restarts"),
+        Response::SetupApplied { restart_required: false } => println!("Set up"),
+        Response::SnapshotExported(summary) => println!("{}", snapshot_text(summary)),
+        Response::SnapshotImported { summary, config_written } => {
+            println!("imported {}, exported by node {}", snapshot_text(summary), summary.node_version);
+            if let Some(path) = config_written {
+                println!("The snapshot's config file is in {}; review it and move it over the node's own to use it", path);
+            }
+        }
+        Response::CoreWatching { watch_id } => println!("watching (watch {})", watch_id),
+        Response::Subscribed { topics } => println!("subscribed to {:?}", topics),
+        Response::Event { event, .. } => match event {
+            NodeEvent::PeerConnected { peer } => println!("peer connected: {}", peer),
+            NodeEvent::PeerDisconnected { peer } => println!("peer disconnected: {}", peer),
+            NodeEvent::ListenersChanged { addrs } => println!("listening on: {}", addrs.join(", ")),
+            NodeEvent::PresenceStale { peer } => println!("node stale: {}", peer),
+            NodeEvent::PresenceReturned { peer } => println!("node returned: {}", peer),
+            NodeEvent::LicenseChanged { active, .. } => println!("license is now {}", if *active { "active" } else { "inactive" }),
+            NodeEvent::CoreChanged(change) => println!("{} changed: {} row(s)", change.relation, change.rows.len()),
+            NodeEvent::WasmJobCompleted { job, run } => println!("job {} finished run {}", job, run.run),
+        },
+        Response::PushDropped { count } => eprintln!("missed {} event(s): this client fell behind", count),
+        Response::CoreChanged(change) => {
+            let op = match change.op {
+                CoreChangeOp::Put => "put",
+                CoreChangeOp::Remove => "remove",
+            };
+            println!("{} {} {} row(s)", change.relation, op, change.rows.len());
+            for row in &change.rows {
+                println!("  {}", serde_json::Value::Array(row.clone()));
+            }
+        }
+        Response::CoreImported(summary) => {
+            println!("read {} row(s): {} inserted, {} rejected", summary.rows_read, summary.inserted, summary.rejected);
+            for reject in &summary.rejects {
+                eprintln!("  line {}: {}", reject.line, reject.reason);
+            }
+        }
+        Response::CoreFailed(failure) => eprintln!("{}", failure_text(failure)),
+        Response::Error(message) => eprintln!("{}", message),
+        Response::TimedOut { subsystem, timeout_ms } => eprintln!("Timed out after {} ms waiting on the {}", timeout_ms, subsystem),
+        Response::Unavailable { subsystem, reason } => eprintln!("The {} subsystem is unavailable: {}", subsystem, reason),
+        Response::RateLimited { kind, retry_after_ms } => eprintln!("The node is refusing {} requests for now; retry in {} ms", kind, retry_after_ms),
+        Response::Overloaded { subsystem, retry_after_ms } => eprintln!("The node's {} workers are all busy; retry in {} ms", subsystem, retry_after_ms),
+        Response::Busy { max_connections } => eprintln!("The node is at its limit of {} connections", max_connections),
+        Response::SetupRequired { kind } => eprintln!("The node is waiting to be set up and cannot answer {} yet; run `sovereignctl setup`", kind),
+        Response::PermissionDenied { kind, permission } => {
+            eprintln!("This connection may not send {}: it lacks the {} permission", kind, permission.name())
+        }
+        Response::WasmPathRejected { path, reason } => match reason {
+            WasmPathRejection::NotFound => eprintln!("The node found no module at {}", path),
+            WasmPathRejection::TooLarge { size, max } => eprintln!("{} is {} bytes, over the node's limit of {}", path, size, max),
+            WasmPathRejection::OutsideAllowedDirs => eprintln!("{} is outside the directories the node runs modules from", path),
+            WasmPathRejection::Unreadable { message } => eprintln!("The node cannot read {}: {}", path, message),
+        },
+        other => println!("{:?}", other),
+    }
+}
+
+/// A core failure as its code and message, and where in the query it was.
+fn failure_text(failure: &CoreFailure) -> String {
+    let code = serde_json::to_value(failure.code).ok().and_then(|c| c.as_str().map(str::to_owned)).unwrap_or_default();
+    match (failure.line, failure.column) {
+        (Some(line), Some(column)) => format!("{}: {} (line {}, column {})", code, failure.message, line, column),
+        (Some(line), None) => format!("{}: {} (line {})", code, failure.message, line),
+        _ => format!("{}: {}", code, failure.message),
+    }
+}
+
+/// What a snapshot holds, on one line.
+fn snapshot_text(summary: &SnapshotSummary) -> String {
+    format!(
+        "a snapshot of {} relation(s) with {} row(s), {} named queries, {} module(s), {} job(s) and {} peer(s), {} bytes",
+        summary.relations, summary.rows, summary.named_queries, summary.modules, summary.jobs, summary.peers, summary.bytes
+    )
+}
+
+fn print_status(status: &NodeStatus) {
+    if !status.version.version.is_empty() {
+        println!("version:        {}", status.version.describe());
+    }
+    if status.mode == NodeMode::Setup {
+        println!("mode:           waiting for setup; run `sovereignctl setup`");
+    }
+    println!("uptime:         {}s", status.uptime_ms / 1000);
+    println!("peer id:        {}", status.mesh_peer_id);
+    println!("connections:    {}", status.mesh_connections);
+    match &status.mesh_phase {
+        MeshPhase::WarmingUp => println!("mesh:           warming up"),
+        MeshPhase::Ready => println!("mesh:           ready"),
+        MeshPhase::Isolated => println!("mesh:           isolated (no peer found during warm-up)"),
+        MeshPhase::Restarting { attempt, reason } => println!("mesh:           restarting (attempt {}; {})", attempt, reason),
+        MeshPhase::Failed { reason } => println!("mesh:           failed ({})", reason),
+        MeshPhase::Unknown => {}
+    }
+    for addr in &status.mesh_listen_addrs {
+        println!("listening on:   {}", addr);
+    }
+    let ipc = &status.ipc_connections;
+    println!("ipc clients:    {} open, {} accepted, {} turned away, {} idled out", ipc.open, ipc.accepted, ipc.rejected, ipc.idled_out);
+    println!("license active: {}", status.license_active);
+    if let MachineBinding::Unbindable { reason } = &status.machine_binding {
+        println!("license binding: unavailable ({})", reason);
+    }
+    println!("health:         {}", status.system_health);
+    for reason in status.health.reasons() {
+        println!("  ! {}", reason);
+    }
+    for detail in &status.health_details {
+        println!("  {}", detail);
+    }
+    let resources = &status.resources;
+    if let Some(rss) = resources.rss_bytes {
+        println!("memory:         {} MB resident", rss / (1024 * 1024));
+    }
+    if let Some(cpu) = resources.cpu_percent {
+        println!("cpu:            {:.1}%", cpu);
+    }
+    if let Some(free) = resources.data_dir_free_bytes {
+        println!("disk free:      {} MB", free / (1024 * 1024));
+    }
+}
+
+fn print_build(build: &BuildInfo) {
+    println!("node:           {}", build.describe());
+    println!("built at:       {} (unix seconds)", build.built_at_unix);
+    println!("protocol:       v{}", build.protocol_version);
+    match build.features.is_empty() {
+        true => println!("features:       none"),
+        false => println!("features:       {}", build.features.join(", ")),
+    }
+    println!("wasmtime:       {}", build.wasmtime_version);
+    println!("libp2p:         {}", build.libp2p_version);
+}
+
+fn print_metrics(metrics: &MetricsSnapshot) {
+    let wasm = &metrics.wasm;
+    println!("wasm running:          {}", wasm.running);
+    println!("wasm queued:           {}", wasm.queued);
+    println!("wasm admitted:         {}", wasm.admitted);
+    println!("wasm rejected busy:    {}", wasm.rejected_busy);
+    println!("wasm module runs:      {}", wasm.module_runs);
+    println!("core backend:          {}", metrics.core.backend);
+    println!("core size:             {} bytes", metrics.core.size_bytes);
+    if let Some(replication) = &metrics.replication {
+        println!("outbox waiting:        {} (high-water mark {})", replication.outbox_depth, replication.outbox_high_water);
+        println!("outbox gossiped:       {}", replication.outbox_published);
+        println!("outbox failures:       {}", replication.outbox_failures);
+    }
+    println!("ipc accept failures:   {}", metrics.ipc.accept_failures);
+    println!("ipc bytes in/out:      {}/{}", metrics.ipc.bytes_in, metrics.ipc.bytes_out);
+    println!("ipc parked sessions:   {}", metrics.ipc.parked_sessions);
+    for (name, pool) in &metrics.pools {
+        println!(
+            "pool {:<16} {}/{} running, {}/{} queued, {} rejected",
+            format!("{}:", name),
+            pool.running,
+            pool.threads,
+            pool.queued,
+            pool.queue_capacity,
+            pool.rejected
+        );
+    }
+    for (kind, count) in &metrics.timeouts {
+        println!("timeouts {:<14} {}", format!("{}:", kind), count);
+    }
+}
+
+/// A core result's `headers` and `rows` as a table, or the JSON if it has
+/// neither.
+fn print_rows(result: &serde_json::Value) {
+    let (Some(headers), Some(rows)) = (result["headers"].as_array(), result["rows"].as_array()) else {
+        println!("{}", serde_json::to_string_pretty(result).unwrap_or_default());
+        return;
+    };
+    let headers: Vec<String> = headers.iter().map(cell).collect();
+    let headers: Vec<&str> = headers.iter().map(String::as_str).collect();
+    let rows = rows.iter().map(|row| row.as_array().map_or_else(|| vec![cell(row)], |r| r.iter().map(cell).collect())).collect();
+    print_table(&headers, rows);
+}
+
+fn cell(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn print_table(headers: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (i, value) in row.iter().enumerate() {
+            if let Some(width) = widths.get_mut(i) {
+                *width = (*width).max(value.chars().count());
+            }
+        }
+    }
+    let line = |values: Vec<&str>| {
+        let cells: Vec<String> = values.iter().zip(&widths).map(|(v, w)| format!("{:<w$}", v, w = *w)).collect();
+        println!("{}", cells.join("  ").trim_end());
+    };
+    line(headers.to_vec());
+    for row in &rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
+
+/// `ms` since the Unix epoch as `YYYY-MM-DD HH:MM:SS` UTC.
+fn utc(ms: u64) -> String {
+    let secs = ms / 1000;
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+    // Days to a civil date, after Howard Hinnant's days_from_civil inverse.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
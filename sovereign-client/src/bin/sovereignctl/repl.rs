@@ -0,0 +1,577 @@
+//! `sovereignctl repl`: an interactive shell over one node connection.
+//! Input ending in `;` runs as a core query; lines starting with `\` are
+//! the shell's own commands.
+
+use super::{failure_text, print_response, succeeded, topic_request};
+use anyhow::{anyhow, bail, Result};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Editor, Helper};
+use sovereign_client::NodeClient;
+use sovereign_protocol::{CoreDataFormat, CoreFailure, CoreImportMode, ErrorCode, EventTopic, Request, Response};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+const PROMPT: &str = "sovereign> ";
+
+const HELP: &str = "\
+Queries run when the input ends with ';', which may be lines later.
+
+  \\status                                  Node status
+  \\peers                                   Connected mesh peers
+  \\relations                               Stored relations
+  \\watch <topic>                           Print pushed events until Ctrl-C; topic is
+                                           license, core:<relation>, mesh or wasm-jobs
+  \\import <file> <relation> [append|upsert|replace]
+                                           Load a .csv or JSON-lines file; append is
+                                           the default
+  \\timing on|off                           Print how long each request took
+  \\json on|off                             Print responses as JSON
+  \\help                                    This text
+  \\quit                                    Leave; so does Ctrl-D";
+
+/// The shell's own commands, for completion.
+const COMMANDS: &[&str] = &["\\status", "\\peers", "\\relations", "\\watch", "\\import", "\\timing", "\\json", "\\help", "\\quit"];
+
+/// One complete input.
+#[derive(Debug)]
+enum Input {
+    Empty,
+    Query(String),
+    Status,
+    Peers,
+    Relations,
+    Watch(Request),
+    Import {
+        path: PathBuf,
+        relation: String,
+        format: CoreDataFormat,
+        mode: CoreImportMode,
+    },
+    Timing(bool),
+    Json(bool),
+    Help,
+    Quit,
+}
+
+/// Parses one complete input, as the editor hands it over.
+fn parse_input(input: &str) -> Result<Input> {
+    let input = input.trim();
+    let Some(command) = input.strip_prefix('\\') else {
+        let query = input.strip_suffix(';').unwrap_or(input).trim_end();
+        return Ok(if query.is_empty() { Input::Empty } else { Input::Query(query.to_string()) });
+    };
+    let mut words = command.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let args: Vec<&str> = words.collect();
+    let parsed = match (name, args.as_slice()) {
+        ("status", []) => Input::Status,
+        ("peers", []) => Input::Peers,
+        ("relations", []) => Input::Relations,
+        ("watch", [topic]) => Input::Watch(topic_request(topic)?),
+        ("import", [path, relation, mode @ ..]) if mode.len() <= 1 => Input::Import {
+            path: PathBuf::from(path),
+            relation: relation.to_string(),
+            format: data_format(Path::new(path)),
+            mode: match mode.first().copied() {
+                None | Some("append") => CoreImportMode::Append,
+                Some("upsert") => CoreImportMode::Upsert,
+                Some("replace") => CoreImportMode::Replace,
+                Some(other) => bail!("import mode must be append, upsert or replace, not '{}'", other),
+            },
+        },
+        ("timing", [flag]) => Input::Timing(switch(flag)?),
+        ("json", [flag]) => Input::Json(switch(flag)?),
+        ("help" | "?", []) => Input::Help,
+        ("quit" | "q", []) => Input::Quit,
+        ("watch", _) => bail!("usage: \\watch <topic>"),
+        ("import", _) => bail!("usage: \\import <file> <relation> [append|upsert|replace]"),
+        ("timing" | "json", _) => bail!("usage: \\{} on|off", name),
+        _ if COMMANDS.contains(&format!("\\{}", name).as_str()) => bail!("\\{} takes no arguments", name),
+        _ => bail!("unknown command '\\{}'; \\help lists them", name),
+    };
+    Ok(parsed)
+}
+
+fn switch(flag: &str) -> Result<bool> {
+    match flag {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        other => bail!("expected on or off, not '{}'", other),
+    }
+}
+
+/// CSV by extension; anything else is taken for JSON lines.
+fn data_format(path: &Path) -> CoreDataFormat {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("csv") => CoreDataFormat::Csv,
+        _ => CoreDataFormat::JsonLines,
+    }
+}
+
+/// Whether the editor should hand `input` over or keep reading lines:
+/// commands end at the line, queries at a `;`.
+fn is_complete(input: &str) -> bool {
+    let input = input.trim();
+    input.is_empty() || input.starts_with('\\') || input.ends_with(';')
+}
+
+/// Where the word being completed starts, and what may finish it.
+fn completions(line: &str, pos: usize, relations: &[String]) -> (usize, Vec<String>) {
+    let before = &line[..pos];
+    let start = before
+        .char_indices()
+        .rev()
+        .find(|&(_, c)| !(c.is_alphanumeric() || matches!(c, '_' | '.' | '\\')))
+        .map_or(0, |(i, c)| i + c.len_utf8());
+    let word = &before[start..];
+    let candidates: Vec<String> = if word.starts_with('\\') {
+        if start != 0 {
+            return (pos, Vec::new());
+        }
+        COMMANDS.iter().filter(|c| c.starts_with(word)).map(|c| c.to_string()).collect()
+    } else {
+        relations.iter().filter(|r| r.starts_with(word)).cloned().collect()
+    };
+    (start, candidates)
+}
+
+/// The rendering of a core failure: its code, message and position, the
+/// query line it points into, and a hint where the code suggests one.
+fn render_failure(failure: &CoreFailure, query: Option<&str>) -> String {
+    let mut text = failure_text(failure);
+    if let (Some(query), Some(line)) = (query, failure.line) {
+        if let Some(source) = query.lines().nth(line.saturating_sub(1) as usize) {
+            text.push_str(&format!("\n  {}", source));
+            if let Some(column) = failure.column {
+                text.push_str(&format!("\n  {}^", " ".repeat(column.saturating_sub(1) as usize)));
+            }
+        }
+    }
+    if let Some(hint) = hint(failure.code) {
+        text.push_str(&format!("\nhint: {}", hint));
+    }
+    text
+}
+
+fn hint(code: ErrorCode) -> Option<&'static str> {
+    Some(match code {
+        ErrorCode::UnknownRelation => "\\relations lists the stored relations",
+        ErrorCode::MissingParam => "the shell sends no parameters; write the values into the query",
+        ErrorCode::TransactionConflict => "another writer held the store; running it again may succeed",
+        ErrorCode::ResultTooLarge => "narrow the query, or cap it with :limit",
+        ErrorCode::NamespaceDenied => "this connection's token confines it to a namespace",
+        ErrorCode::GrantDenied => "this connection's principal has core grants, and none covers that relation; see `sovereignctl grants`",
+        _ => return None,
+    })
+}
+
+/// Completes commands and relation names, and holds the input open until
+/// it is complete.
+struct ReplHelper {
+    relations: Arc<Mutex<Vec<String>>>,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let relations = self.relations.lock().unwrap();
+        Ok(completions(line, pos, &relations))
+    }
+}
+
+impl Validator for ReplHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        Ok(if is_complete(ctx.input()) {
+            ValidationResult::Valid(None)
+        } else {
+            ValidationResult::Incomplete
+        })
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+/// What the editor read.
+enum Line {
+    Input(String),
+    /// Ctrl-C: the input so far is dropped.
+    Interrupted,
+    /// Ctrl-D, or the terminal went away.
+    Eof,
+}
+
+/// The editor, on a thread of its own: reading blocks, and the client's
+/// connection needs this runtime meanwhile for heartbeats and reconnects.
+struct LineReader {
+    prompts: std::sync::mpsc::Sender<()>,
+    lines: mpsc::UnboundedReceiver<Line>,
+    editor: std::thread::JoinHandle<()>,
+}
+
+impl LineReader {
+    /// Starts the editor's thread. If the terminal cannot be edited, the
+    /// first read is the end of input.
+    fn start(relations: Arc<Mutex<Vec<String>>>) -> Self {
+        let (prompts, prompt_rx) = std::sync::mpsc::channel::<()>();
+        let (line_tx, lines) = mpsc::unbounded_channel();
+        let editor = std::thread::spawn(move || {
+            let mut editor: Editor<ReplHelper, DefaultHistory> = match Editor::new() {
+                Ok(editor) => editor,
+                Err(e) => {
+                    eprintln!("sovereignctl: cannot start the line editor: {}", e);
+                    return;
+                }
+            };
+            editor.set_helper(Some(ReplHelper { relations }));
+            let history = history_path();
+            if let Some(path) = &history {
+                // There is none on the first run.
+                let _ = editor.load_history(path);
+            }
+            while prompt_rx.recv().is_ok() {
+                let line = match editor.readline(PROMPT) {
+                    Ok(line) => {
+                        if !line.trim().is_empty() {
+                            let _ = editor.add_history_entry(line.as_str());
+                        }
+                        Line::Input(line)
+                    }
+                    Err(ReadlineError::Interrupted) => Line::Interrupted,
+                    Err(ReadlineError::Eof) => Line::Eof,
+                    Err(e) => {
+                        eprintln!("sovereignctl: {}", e);
+                        Line::Eof
+                    }
+                };
+                let eof = matches!(line, Line::Eof);
+                if line_tx.send(line).is_err() || eof {
+                    break;
+                }
+            }
+            if let Some(path) = &history {
+                if let Some(dir) = path.parent() {
+                    let _ = std::fs::create_dir_all(dir);
+                }
+                if let Err(e) = editor.save_history(path) {
+                    eprintln!("sovereignctl: failed to save history to {}: {}", path.display(), e);
+                }
+            }
+        });
+        Self { prompts, lines, editor }
+    }
+
+    async fn read(&mut self) -> Line {
+        if self.prompts.send(()).is_err() {
+            return Line::Eof;
+        }
+        self.lines.recv().await.unwrap_or(Line::Eof)
+    }
+
+    /// Stops the editor, waiting while it saves the history.
+    fn finish(self) {
+        drop(self.prompts);
+        let _ = self.editor.join();
+    }
+}
+
+/// `sovereign/sovereignctl_history` beside the node's config:
+/// `$XDG_CONFIG_HOME`, else `~/.config`.
+fn history_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("sovereign").join("sovereignctl_history"))
+}
+
+struct Repl<'a> {
+    client: &'a NodeClient,
+    json: bool,
+    timing: bool,
+    relations: Arc<Mutex<Vec<String>>>,
+}
+
+/// Reads and runs inputs until `\quit` or Ctrl-D. A failed request is
+/// reported and the shell carries on; the client reconnects by itself.
+pub async fn run(client: &NodeClient, json: bool) -> Result<bool> {
+    let mut repl = Repl {
+        client,
+        json,
+        timing: false,
+        relations: Arc::new(Mutex::new(Vec::new())),
+    };
+    let mut reader = LineReader::start(repl.relations.clone());
+    repl.refresh_relations().await;
+    println!("Connected to the node; \\help lists commands, \\quit leaves.");
+    loop {
+        let input = match reader.read().await {
+            Line::Input(input) => input,
+            Line::Interrupted => continue,
+            Line::Eof => break,
+        };
+        let input = match parse_input(&input) {
+            Ok(input) => input,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        match repl.execute(input).await {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => eprintln!("{:#}", e),
+        }
+    }
+    reader.finish();
+    Ok(true)
+}
+
+impl Repl<'_> {
+    /// Runs one input; false once the shell should exit.
+    async fn execute(&mut self, input: Input) -> Result<bool> {
+        match input {
+            Input::Empty => {}
+            Input::Query(query) => {
+                let req = Request::QueryCore {
+                    query: query.clone(),
+                    params: serde_json::Value::Object(Default::default()),
+                    timeout_ms: None,
+                    readonly: false,
+                    limit: None,
+                };
+                self.send(req, Some(&query)).await?;
+                // The query may have created or removed relations.
+                self.refresh_relations().await;
+            }
+            Input::Status => self.send(Request::GetStatus, None).await?,
+            Input::Peers => self.send(Request::MeshPeers, None).await?,
+            Input::Relations => self.send(Request::CoreListRelations, None).await?,
+            Input::Watch(req) => self.watch(req).await?,
+            Input::Import {
+                path,
+                relation,
+                format,
+                mode,
+            } => {
+                let file = tokio::fs::File::open(&path).await.map_err(|e| anyhow!("Cannot open {}: {}", path.display(), e))?;
+                let started = Instant::now();
+                let resp = self.client.import_core(&relation, format, mode, file).await?;
+                self.show(&resp, None);
+                self.print_timing(started);
+                self.refresh_relations().await;
+            }
+            Input::Timing(on) => {
+                self.timing = on;
+                println!("Timing is {}.", if on { "on" } else { "off" });
+            }
+            Input::Json(on) => {
+                self.json = on;
+                println!("JSON output is {}.", if on { "on" } else { "off" });
+            }
+            Input::Help => println!("{}", HELP),
+            Input::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    async fn send(&self, req: Request, query: Option<&str>) -> Result<()> {
+        let started = Instant::now();
+        let resp = self.client.request(req).await?;
+        self.show(&resp, query);
+        self.print_timing(started);
+        Ok(())
+    }
+
+    fn show(&self, resp: &Response, query: Option<&str>) {
+        match resp {
+            _ if self.json => println!("{}", serde_json::to_string_pretty(resp).unwrap_or_default()),
+            Response::CoreFailed(failure) => eprintln!("{}", render_failure(failure, query)),
+            Response::CoreRelations(relations) => {
+                for relation in relations {
+                    println!("{}  ({} columns, {} keys, {} rows)", relation.name, relation.arity, relation.keys, relation.rows);
+                }
+            }
+            _ => print_response(resp),
+        }
+    }
+
+    fn print_timing(&self, started: Instant) {
+        if self.timing {
+            println!("Time: {:.1} ms", started.elapsed().as_secs_f64() * 1000.0);
+        }
+    }
+
+    /// Prints pushes until Ctrl-C, then takes the watch back down so the
+    /// node stops sending them.
+    async fn watch(&self, req: Request) -> Result<()> {
+        // WatchLicense cannot be undone; the subscription can.
+        let req = match req {
+            Request::WatchLicense => Request::Subscribe { topics: vec![EventTopic::License] },
+            other => other,
+        };
+        let mut pushes = self.client.pushes();
+        let resp = self.client.request(req.clone()).await?;
+        self.show(&resp, None);
+        if !succeeded(&resp) {
+            return Ok(());
+        }
+        println!("Watching; Ctrl-C stops.");
+        let watched = loop {
+            tokio::select! {
+                push = pushes.recv() => match push {
+                    Some(push) => self.show(&push, None),
+                    None => break Err(anyhow!("Connection to node lost")),
+                },
+                _ = tokio::signal::ctrl_c() => break Ok(()),
+            }
+        };
+        drop(pushes);
+        let undo = match (req, &resp) {
+            (Request::CoreWatch { .. }, Response::CoreWatching { watch_id }) => Request::CoreUnwatch { watch_id: *watch_id },
+            (Request::Subscribe { topics }, _) => Request::Unsubscribe { topics },
+            _ => return watched,
+        };
+        // After a lost connection the watch went with it.
+        if watched.is_ok() {
+            self.client.request(undo).await?;
+        }
+        watched
+    }
+
+    /// Fetches relation names for completion; a failure leaves the old ones.
+    async fn refresh_relations(&self) {
+        if let Ok(Response::CoreRelations(relations)) = self.client.request(Request::CoreListRelations).await {
+            *self.relations.lock().unwrap() = relations.into_iter().map(|r| r.name).collect();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(input: &str) -> Input {
+        parse_input(input).unwrap_or_else(|e| panic!("{:?}: {}", input, e))
+    }
+
+    fn refused(input: &str) -> String {
+        match parse_input(input) {
+            Ok(parsed) => panic!("{:?} parsed as {:?}", input, parsed),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_queries_up_to_their_semicolon() {
+        assert!(matches!(parsed("?[x] := x = 1;"), Input::Query(q) if q == "?[x] := x = 1"));
+        assert!(matches!(parsed("  ?[x] :=\n    x = 1 ;  \n"), Input::Query(q) if q == "?[x] :=\n    x = 1"));
+        assert!(matches!(parsed(""), Input::Empty));
+        assert!(matches!(parsed("  ;  "), Input::Empty));
+    }
+
+    #[test]
+    fn parses_commands_and_their_arguments() {
+        assert!(matches!(parsed("\\status"), Input::Status));
+        assert!(matches!(parsed("  \\peers  "), Input::Peers));
+        assert!(matches!(parsed("\\relations"), Input::Relations));
+        assert!(matches!(parsed("\\watch mesh"), Input::Watch(Request::Subscribe { topics }) if topics == [EventTopic::Mesh]));
+        assert!(matches!(parsed("\\watch core:people"), Input::Watch(Request::CoreWatch { relation, filter: None }) if relation == "people"));
+        assert!(matches!(parsed("\\watch license"), Input::Watch(Request::WatchLicense)));
+        assert!(matches!(parsed("\\timing on"), Input::Timing(true)));
+        assert!(matches!(parsed("\\json off"), Input::Json(false)));
+        assert!(matches!(parsed("\\?"), Input::Help));
+        assert!(matches!(parsed("\\q"), Input::Quit));
+
+        match parsed("\\import people.CSV people upsert") {
+            Input::Import { path, relation, format, mode } => {
+                assert_eq!((path, relation.as_str()), (PathBuf::from("people.CSV"), "people"));
+                assert_eq!((format, mode), (CoreDataFormat::Csv, CoreImportMode::Upsert));
+            }
+            other => panic!("Expected Import, got {:?}", other),
+        }
+        assert!(matches!(
+            parsed("\\import rows.jsonl people"),
+            Input::Import { format: CoreDataFormat::JsonLines, mode: CoreImportMode::Append, .. }
+        ));
+        assert!(matches!(parsed("\\import rows people replace"), Input::Import { mode: CoreImportMode::Replace, .. }));
+    }
+
+    #[test]
+    fn says_what_is_wrong_with_a_command() {
+        assert_eq!(refused("\\frobnicate"), "unknown command '\\frobnicate'; \\help lists them");
+        assert_eq!(refused("\\status now"), "\\status takes no arguments");
+        assert_eq!(refused("\\watch"), "usage: \\watch <topic>");
+        assert!(refused("\\watch weather").starts_with("topic must be"));
+        assert_eq!(refused("\\import rows.csv"), "usage: \\import <file> <relation> [append|upsert|replace]");
+        assert_eq!(refused("\\import rows.csv people merge"), "import mode must be append, upsert or replace, not 'merge'");
+        assert_eq!(refused("\\timing"), "usage: \\timing on|off");
+        assert_eq!(refused("\\json yes"), "expected on or off, not 'yes'");
+    }
+
+    #[test]
+    fn holds_a_query_open_until_its_semicolon() {
+        assert!(!is_complete("?[x] :="));
+        assert!(!is_complete("?[x] :=\n    x = 1"));
+        assert!(is_complete("?[x] :=\n    x = 1;"));
+        assert!(is_complete("?[x] := x = 1;  "));
+        assert!(is_complete("\\status"));
+        assert!(is_complete("   "));
+    }
+
+    #[test]
+    fn completes_commands_at_the_start_and_relations_anywhere() {
+        let relations = vec!["people".to_string(), "pets".to_string(), "tenant.notes".to_string()];
+        assert_eq!(completions("\\st", 3, &relations), (0, vec!["\\status".to_string()]));
+        let (start, all) = completions("\\", 1, &relations);
+        assert_eq!((start, all.len()), (0, COMMANDS.len()));
+        // A command goes first on the line.
+        assert_eq!(completions("?[x] := \\st", 11, &relations), (11, Vec::new()));
+
+        assert_eq!(completions("?[n] := *pe", 11, &relations), (9, vec!["people".to_string(), "pets".to_string()]));
+        assert_eq!(completions("?[n] := *tenant.n", 17, &relations), (9, vec!["tenant.notes".to_string()]));
+        // Only up to the cursor counts.
+        assert_eq!(completions("*peo[x]", 4, &relations), (1, vec!["people".to_string()]));
+        assert_eq!(completions("\\watch core:pe", 14, &relations).1, ["people", "pets"]);
+    }
+
+    #[test]
+    fn renders_failures_with_the_line_they_point_into_and_a_hint() {
+        let failure = CoreFailure {
+            code: ErrorCode::UnknownRelation,
+            message: "Cannot find relation 'peple'".into(),
+            line: Some(2),
+            column: Some(5),
+        };
+        let query = "?[name] :=\n    *peple{name}";
+        assert_eq!(
+            render_failure(&failure, Some(query)),
+            "unknown_relation: Cannot find relation 'peple' (line 2, column 5)\n      *peple{name}\n      ^\nhint: \\relations lists the stored relations"
+        );
+        // Without the query, or past its end, only the message and hint.
+        let bare = "unknown_relation: Cannot find relation 'peple' (line 2, column 5)\nhint: \\relations lists the stored relations";
+        assert_eq!(render_failure(&failure, None), bare);
+        assert_eq!(render_failure(&failure, Some("?[x] := x = 1")), bare);
+
+        let parse = CoreFailure {
+            code: ErrorCode::Parse,
+            message: "unexpected end of input".into(),
+            line: None,
+            column: None,
+        };
+        assert_eq!(render_failure(&parse, Some(query)), "parse: unexpected end of input");
+    }
+}
@@ -0,0 +1,152 @@
+//! `sovereignctl setup`: walks through a node's first-run setup, asking
+//! for each setting, and sends the answers in one `SetupApply`.
+
+use super::{print_response, succeeded};
+use anyhow::{bail, Context, Result};
+use sovereign_client::NodeClient;
+use sovereign_protocol::{LicenseTerms, Request, Response, SetupSettings, SetupState, SwarmKeyChoice};
+use std::io::Write;
+use std::path::Path;
+
+/// Asks for each setting, then applies them. Without `force` a node that
+/// is already set up is left alone.
+pub(crate) async fn run(client: &NodeClient, force: bool, json: bool) -> Result<bool> {
+    let state = match client.request(Request::SetupState).await? {
+        Response::SetupState(state) => state,
+        other => {
+            print_response(&other);
+            return Ok(false);
+        }
+    };
+    if !state.pending && !force {
+        eprintln!("The node is already set up. `sovereignctl setup --force` sets it up again, from when it next starts.");
+        return Ok(false);
+    }
+    println!("Setting up the node with config file {}", state.config_path);
+
+    if !confirm(&format!("Keep the node's data in {}?", state.data_dir), true).await? {
+        println!("Set data_dir in {} or SOVEREIGN_DATA_DIR, restart the node and run setup again.", state.config_path);
+        return Ok(false);
+    }
+    let network = network(&state).await?;
+    let swarm_key = swarm_key(&state).await?;
+    let bootstrap_peers = bootstrap_peers(&state).await?;
+    print_terms(&state.license_terms);
+    if !confirm("Accept the license policy?", false).await? {
+        eprintln!("Setup needs the license policy accepted.");
+        return Ok(false);
+    }
+
+    let settings = SetupSettings {
+        network,
+        swarm_key,
+        bootstrap_peers,
+        accept_license_policy: true,
+        data_dir: state.data_dir,
+    };
+    let resp = client.request(Request::SetupApply { settings, force }).await?;
+    match &resp {
+        _ if json => println!("{}", serde_json::to_string_pretty(&resp)?),
+        Response::SetupApplied { restart_required: true } => println!("Set up. The new settings take effect when the node restarts."),
+        Response::SetupApplied { restart_required: false } => println!("Set up. The node is starting."),
+        other => print_response(other),
+    }
+    Ok(succeeded(&resp))
+}
+
+async fn network(state: &SetupState) -> Result<String> {
+    loop {
+        let network = ask(&format!("Bitcoin network ({})", state.networks.join(", ")), Some(&state.network)).await?;
+        if state.networks.contains(&network) {
+            return Ok(network);
+        }
+        eprintln!("'{}' is not one of {}", network, state.networks.join(", "));
+    }
+}
+
+async fn swarm_key(state: &SetupState) -> Result<SwarmKeyChoice> {
+    if state.swarm_key_present {
+        println!("The node has a swarm key already; setup replaces it.");
+    }
+    loop {
+        match ask("Swarm key: generate a new one, or import your mesh's? (generate, import)", Some("generate")).await?.as_str() {
+            "generate" => return Ok(SwarmKeyChoice::Generate),
+            "import" => break,
+            other => eprintln!("Answer generate or import, not '{}'", other),
+        }
+    }
+    let answer = ask("Swarm key file, or its 64 hex digits", None).await?;
+    // Read here: the node may not see this machine's files.
+    let key = match Path::new(&answer).is_file() {
+        true => std::fs::read_to_string(&answer).with_context(|| format!("Failed to read {}", answer))?,
+        false => answer,
+    };
+    Ok(SwarmKeyChoice::Import { key })
+}
+
+async fn bootstrap_peers(state: &SetupState) -> Result<Vec<String>> {
+    let current = match state.bootstrap_peers.is_empty() {
+        true => "none".to_string(),
+        false => state.bootstrap_peers.join(", "),
+    };
+    let answer = ask("Bootstrap peers, as multiaddrs separated by commas, or none", Some(&current)).await?;
+    if answer == "none" {
+        return Ok(Vec::new());
+    }
+    Ok(answer.split(',').map(str::trim).filter(|peer| !peer.is_empty()).map(str::to_string).collect())
+}
+
+fn print_terms(terms: &LicenseTerms) {
+    println!();
+    println!("License policy:");
+    println!("  pay to:         {}", terms.developer_addr);
+    println!("  at least:       {} sats", terms.required_sats);
+    println!("  confirmations:  {}", terms.min_confirmations);
+    match terms.validity_blocks {
+        Some(blocks) => println!("  valid for:      {} blocks after confirmation", blocks),
+        None => println!("  valid for:      ever"),
+    }
+    for tier in &terms.tiers {
+        println!("  tier {:<10} {} sats", format!("{}:", tier.name), tier.min_sats);
+    }
+}
+
+/// A yes or no answer; a blank line is `default`.
+async fn confirm(question: &str, default: bool) -> Result<bool> {
+    let hint = if default { "Y/n" } else { "y/N" };
+    loop {
+        match ask(&format!("{} [{}]", question, hint), Some("")).await?.to_lowercase().as_str() {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            other => eprintln!("Answer yes or no, not '{}'", other),
+        }
+    }
+}
+
+/// Prints `question` and reads a line, off the runtime's thread so the
+/// connection keeps answering the node's heartbeats meanwhile. A blank
+/// line is `default`; with none, it is asked again.
+async fn ask(question: &str, default: Option<&str>) -> Result<String> {
+    let prompt = match default {
+        Some(default) if !default.is_empty() => format!("{} [{}]: ", question, default),
+        _ => format!("{}: ", question),
+    };
+    loop {
+        print!("{}", prompt);
+        std::io::stdout().flush()?;
+        let line = tokio::task::spawn_blocking(|| {
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line).map(|read| (read > 0).then_some(line))
+        })
+        .await??;
+        let Some(line) = line else {
+            bail!("stdin closed before setup finished");
+        };
+        match (line.trim(), default) {
+            ("", Some(default)) => return Ok(default.to_string()),
+            ("", None) => continue,
+            (answer, _) => return Ok(answer.to_string()),
+        }
+    }
+}
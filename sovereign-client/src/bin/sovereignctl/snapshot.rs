@@ -0,0 +1,45 @@
+//! `sovereignctl snapshot export|import`: moves a node's snapshot between
+//! the node and a file on this machine, so the archive can be carried to
+//! another one.
+
+use super::{print_response, succeeded};
+use anyhow::{Context, Result};
+use sovereign_client::NodeClient;
+use sovereign_protocol::Response;
+
+/// Saves the node's snapshot to `file`, through a `.partial` file beside it
+/// that is only renamed once the node reports the snapshot complete.
+pub(crate) async fn export(client: &NodeClient, file: &str, json: bool) -> Result<bool> {
+    let partial = format!("{}.partial", file);
+    let out = tokio::fs::File::create(&partial).await.with_context(|| format!("Failed to create {}", partial))?;
+    let resp = client.export_snapshot(out).await;
+    let saved = matches!(&resp, Ok(resp) if succeeded(resp));
+    if !saved {
+        let _ = tokio::fs::remove_file(&partial).await;
+    }
+    let resp = resp?;
+    if saved {
+        tokio::fs::rename(&partial, file).await.with_context(|| format!("Failed to move {} to {}", partial, file))?;
+    }
+    print(&resp, json)?;
+    if saved && !json {
+        println!("Saved to {}", file);
+    }
+    Ok(saved)
+}
+
+/// Sends the snapshot in `file` to the node, which applies it.
+pub(crate) async fn import(client: &NodeClient, file: &str, json: bool) -> Result<bool> {
+    let input = tokio::fs::File::open(file).await.with_context(|| format!("Failed to open {}", file))?;
+    let resp = client.import_snapshot(input).await?;
+    print(&resp, json)?;
+    Ok(succeeded(&resp))
+}
+
+fn print(resp: &Response, json: bool) -> Result<()> {
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(resp)?),
+        false => print_response(resp),
+    }
+    Ok(())
+}
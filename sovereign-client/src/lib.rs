@@ -0,0 +1,923 @@
+use anyhow::{anyhow, bail, Result};
+use futures::StreamExt;
+use log::{debug, warn};
+use sovereign_protocol::{
+    default_data_dir, envelope_id, framing, BuildInfo, CertPin, Compression, CoreDataFormat, CoreExplainReport, CoreImportMode, FrameCodec, FrameCompression,
+    Framing, IpcEndpoint, Request, RequestEnvelope, Response, ResponseEnvelope, SessionGrant, SessionResume, DEFAULT_MAX_FRAME_SIZE, ENVELOPE_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+use tokio_util::codec::FramedRead;
+
+mod tls;
+
+/// Responses (query results, module output) may legitimately exceed the
+/// request limit, so the client accepts larger frames than it may send.
+const MAX_RESPONSE_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Largest data frame payload sent for streamed input.
+const STREAM_CHUNK_BYTES: usize = 64 * 1024;
+
+/// The pause between attempts to resume a session, doubling up to the max.
+const RESUME_BACKOFF_MIN: Duration = Duration::from_millis(100);
+const RESUME_BACKOFF_MAX: Duration = Duration::from_secs(2);
+
+/// A connection's halves, whichever transport it runs over.
+pub type ReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+pub type WriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
+type FrameReader = FramedRead<ReadHalf, FrameCodec>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// The connection dropped and its session is being resumed. Requests
+    /// fail until it is.
+    Reconnecting,
+    Disconnected,
+}
+
+type DisconnectCallback = Box<dyn FnOnce() + Send>;
+
+struct Shared {
+    /// Waiters in send order, with the envelope id each request went out
+    /// with. Enveloped responses are matched by id; the node answers bare
+    /// requests in order, so a bare response goes to the oldest bare waiter.
+    pending: StdMutex<VecDeque<(Option<u64>, oneshot::Sender<Response>)>>,
+    /// Where data frames go while a streamed request is in flight. Taken when
+    /// its response arrives, which ends the output.
+    data_sink: StdMutex<Option<mpsc::Sender<Vec<u8>>>>,
+    /// Where pushed responses go, once asked for with `NodeClient::pushes`.
+    pushes: StdMutex<Option<mpsc::UnboundedSender<Response>>>,
+    on_disconnect: StdMutex<Vec<DisconnectCallback>>,
+    connected: AtomicBool,
+    /// Set while a dropped connection's session is being resumed.
+    resuming: AtomicBool,
+    last_seen: StdMutex<Instant>,
+    /// The session the node granted, if it keeps them.
+    session: StdMutex<Option<SessionGrant>>,
+    /// The `seq` of the last event pushed, which resuming asks to continue
+    /// from.
+    last_seq: AtomicU64,
+    /// How the node settled that frames are compressed, if at all; each
+    /// connection settles it again.
+    compression: StdMutex<Option<FrameCompression>>,
+}
+
+impl Shared {
+    /// Fails every in-flight request: none of them will be answered on a
+    /// new connection.
+    fn fail_in_flight(&self) {
+        self.pending.lock().unwrap().clear();
+        self.data_sink.lock().unwrap().take();
+    }
+
+    fn mark_disconnected(&self) {
+        let mut callbacks = self.on_disconnect.lock().unwrap();
+        if !self.connected.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        let callbacks = std::mem::take(&mut *callbacks);
+        // Dropping the senders fails every in-flight request.
+        self.pending.lock().unwrap().clear();
+        self.data_sink.lock().unwrap().take();
+        self.pushes.lock().unwrap().take();
+        for cb in callbacks {
+            cb();
+        }
+    }
+}
+
+/// A connection to a running sovereign-node.
+///
+/// Heartbeats from the node are answered transparently. If the node goes
+/// silent for longer than the idle timeout it advertised while no request is
+/// outstanding, the connection is declared dead.
+///
+/// If the node grants a session and the connection drops, the client
+/// reconnects and resumes it within the node's grace period, keeping its
+/// subscriptions and watches; events it missed are pushed again. Requests
+/// in flight when the connection dropped fail.
+pub struct NodeClient {
+    writer: Arc<Mutex<WriteHalf>>,
+    shared: Arc<Shared>,
+    server_protocol_version: u32,
+    /// How the node was built, if it said.
+    node_build: Option<BuildInfo>,
+    /// The next envelope id, once the node accepts envelopes.
+    next_id: AtomicU64,
+    heartbeat_interval: Duration,
+    idle_timeout: Duration,
+    max_frame_size: usize,
+    connection_task: JoinHandle<()>,
+    watchdog_task: JoinHandle<()>,
+}
+
+/// A connection that has said Hello, and what the node answered.
+struct Handshake {
+    reader: FrameReader,
+    writer: WriteHalf,
+    protocol_version: u32,
+    heartbeat_interval: Duration,
+    idle_timeout: Duration,
+    max_frame_size: usize,
+    build: Option<BuildInfo>,
+    session: Option<SessionGrant>,
+    compression: Option<FrameCompression>,
+}
+
+/// What it takes to say Hello again.
+struct Reconnect {
+    target: Target,
+    client_name: String,
+    token: Option<String>,
+}
+
+/// Where the node is reached.
+enum Target {
+    Local(IpcEndpoint),
+    /// A `host:port`, over TLS unless `tls` is unset.
+    Tcp { addr: String, tls: Option<TlsConnector> },
+}
+
+impl Target {
+    async fn open(&self) -> Result<(ReadHalf, WriteHalf)> {
+        match self {
+            Target::Local(endpoint) => connect_raw(endpoint).await,
+            Target::Tcp { addr, tls } => {
+                let stream = TcpStream::connect(addr.as_str()).await.map_err(|e| anyhow!("Cannot connect to node at {}: {}", addr, e))?;
+                stream.set_nodelay(true)?;
+                let Some(tls) = tls else {
+                    let (reader, writer) = stream.into_split();
+                    return Ok((Box::new(reader), Box::new(writer)));
+                };
+                let stream = tls
+                    .connect(ServerName::try_from(tls::SERVER_NAME)?, stream)
+                    .await
+                    .map_err(|e| anyhow!("TLS handshake with node at {} failed: {}", addr, e))?;
+                let (reader, writer) = tokio::io::split(stream);
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+        }
+    }
+}
+
+/// Connects to a local node's endpoint, for tools such as `sovereignctl
+/// --raw` that speak the framing themselves.
+pub async fn connect_raw(endpoint: &IpcEndpoint) -> Result<(ReadHalf, WriteHalf)> {
+    match endpoint {
+        #[cfg(unix)]
+        IpcEndpoint::UnixSocket(path) => {
+            let stream = UnixStream::connect(path).await.map_err(|e| anyhow!("Cannot connect to node at {}: {}", endpoint, e))?;
+            let (reader, writer) = stream.into_split();
+            Ok((Box::new(reader), Box::new(writer)))
+        }
+        #[cfg(windows)]
+        IpcEndpoint::NamedPipe(name) => {
+            let pipe = open_pipe(name).await.map_err(|e| anyhow!("Cannot connect to node at {}: {}", endpoint, e))?;
+            let (reader, writer) = tokio::io::split(pipe);
+            Ok((Box::new(reader), Box::new(writer)))
+        }
+        other => bail!("IPC endpoint {} is not supported on this platform", other),
+    }
+}
+
+/// Opens a client end of the node's pipe. While every instance the node
+/// has created is taken, it waits for the node to create the next one.
+#[cfg(windows)]
+async fn open_pipe(name: &str) -> std::io::Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+    use tokio::net::windows::named_pipe::ClientOptions;
+    /// `ERROR_PIPE_BUSY`.
+    const PIPE_BUSY: i32 = 231;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match ClientOptions::new().open(name) {
+            Err(e) if e.raw_os_error() == Some(PIPE_BUSY) && Instant::now() < deadline => {}
+            opened => return opened,
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+impl NodeClient {
+    /// Connects to wherever the local node can be found: `SOVEREIGN_IPC`, the
+    /// node's discovery file, or the platform default, in that order.
+    pub async fn connect_default(client_name: &str) -> Result<Self> {
+        Self::connect(&IpcEndpoint::discover(&default_data_dir()), client_name).await
+    }
+
+    /// Connects to `endpoint` and performs the Hello handshake.
+    pub async fn connect(endpoint: &IpcEndpoint, client_name: &str) -> Result<Self> {
+        Self::connect_with_token(endpoint, client_name, None).await
+    }
+
+    /// `connect`, presenting an access token from the node's config, which
+    /// decides what the connection may do.
+    pub async fn connect_with_token(endpoint: &IpcEndpoint, client_name: &str, token: Option<&str>) -> Result<Self> {
+        Self::open(Reconnect {
+            target: Target::Local(endpoint.clone()),
+            client_name: client_name.to_string(),
+            token: token.map(str::to_string),
+        })
+        .await
+    }
+
+    /// Connects to a node's TCP listener at `addr` (`host:port`) over TLS,
+    /// trusting only the certificate `pin` names: the fingerprint the node
+    /// logs at startup. TCP connections must present an access `token`.
+    pub async fn connect_tcp(addr: &str, client_name: &str, pin: &str, token: &str) -> Result<Self> {
+        let pin = CertPin::parse(pin).ok_or_else(|| anyhow!("'{}' is not a SHA-256 certificate fingerprint", pin))?;
+        Self::open(Reconnect {
+            target: Target::Tcp {
+                addr: addr.to_string(),
+                tls: Some(tls::connector(pin)?),
+            },
+            client_name: client_name.to_string(),
+            token: Some(token.to_string()),
+        })
+        .await
+    }
+
+    /// `connect_tcp` without TLS, for a node that allows plaintext TCP.
+    /// The token and everything after it cross the network in the clear.
+    pub async fn connect_tcp_insecure(addr: &str, client_name: &str, token: &str) -> Result<Self> {
+        Self::open(Reconnect {
+            target: Target::Tcp {
+                addr: addr.to_string(),
+                tls: None,
+            },
+            client_name: client_name.to_string(),
+            token: Some(token.to_string()),
+        })
+        .await
+    }
+
+    /// Says Hello over a new connection to `reconnect`'s target.
+    async fn open(reconnect: Reconnect) -> Result<Self> {
+        let handshake = handshake(&reconnect, None).await?;
+
+        if let Some(build) = &handshake.build {
+            if release(&build.version) != release(env!("CARGO_PKG_VERSION")) {
+                warn!(
+                    "Node version {} differs from client version {}; some requests may not be understood",
+                    build.describe(),
+                    env!("CARGO_PKG_VERSION")
+                );
+            }
+        }
+
+        let shared = Arc::new(Shared {
+            pending: StdMutex::new(VecDeque::new()),
+            data_sink: StdMutex::new(None),
+            pushes: StdMutex::new(None),
+            on_disconnect: StdMutex::new(Vec::new()),
+            connected: AtomicBool::new(true),
+            resuming: AtomicBool::new(false),
+            last_seen: StdMutex::new(Instant::now()),
+            session: StdMutex::new(handshake.session),
+            last_seq: AtomicU64::new(0),
+            compression: StdMutex::new(handshake.compression),
+        });
+        let writer = Arc::new(Mutex::new(handshake.writer));
+        let (heartbeat_interval, idle_timeout) = (handshake.heartbeat_interval, handshake.idle_timeout);
+
+        let connection_task = tokio::spawn(keep_connected(handshake.reader, writer.clone(), shared.clone(), reconnect));
+        let watchdog_task = tokio::spawn(watchdog(shared.clone(), heartbeat_interval, idle_timeout));
+
+        Ok(Self {
+            writer,
+            shared,
+            server_protocol_version: handshake.protocol_version,
+            node_build: handshake.build,
+            next_id: AtomicU64::new(1),
+            heartbeat_interval,
+            idle_timeout,
+            max_frame_size: handshake.max_frame_size,
+            connection_task,
+            watchdog_task,
+        })
+    }
+
+    /// Sends a request and waits for its response. Nodes that accept
+    /// envelopes may answer concurrent requests out of order.
+    pub async fn request(&self, req: Request) -> Result<Response> {
+        match self.connection_state() {
+            ConnectionState::Connected => {}
+            ConnectionState::Reconnecting => bail!("Reconnecting to node"),
+            ConnectionState::Disconnected => bail!("Not connected to node"),
+        }
+
+        let id = (self.server_protocol_version >= ENVELOPE_PROTOCOL_VERSION).then(|| self.next_id.fetch_add(1, Ordering::Relaxed));
+        // Refuse locally rather than have the node reject the frame.
+        let bytes = match id {
+            Some(id) => serde_json::to_vec(&RequestEnvelope { id, request: req })?,
+            None => serde_json::to_vec(&req)?,
+        };
+        if bytes.len() > self.max_frame_size {
+            bail!(
+                "Request of {} bytes exceeds the node's frame limit of {} bytes",
+                bytes.len(),
+                self.max_frame_size
+            );
+        }
+
+        let (tx, rx) = oneshot::channel();
+        {
+            // Queue the waiter under the writer lock so queue order matches wire order.
+            let mut writer = self.writer.lock().await;
+            self.shared.pending.lock().unwrap().push_back((id, tx));
+            let compression = *self.shared.compression.lock().unwrap();
+            if let Err(e) = write_bytes(&mut *writer, &bytes, compression).await {
+                // With a session, the reader sees the connection drop and
+                // resumes it.
+                if self.shared.session.lock().unwrap().is_none() {
+                    self.shared.mark_disconnected();
+                }
+                return Err(e);
+            }
+        }
+
+        rx.await.map_err(|_| anyhow!("Connection to node lost"))
+    }
+
+    /// Parses and plans a core query without running it, for tools that
+    /// check queries as they are written. A syntax error's message gives
+    /// its line and column.
+    pub async fn explain_core(&self, query: &str, params: serde_json::Value) -> Result<CoreExplainReport> {
+        let req = Request::CoreExplain {
+            query: query.to_string(),
+            params,
+        };
+        match self.request(req).await? {
+            Response::CoreExplained(report) => Ok(report),
+            Response::CoreFailed(failure) => bail!(failure.message),
+            Response::Error(e) => bail!(e),
+            other => bail!("Unexpected response to CoreExplain: {:?}", other),
+        }
+    }
+
+    /// Sends a `RunWasmStreamed` request, streams `input` to the module and
+    /// copies what it writes into `output` as it arrives. Other requests on
+    /// this client wait until the input has been sent.
+    pub async fn run_wasm_streamed<I, O>(&self, req: Request, input: I, output: O) -> Result<Response>
+    where
+        I: AsyncRead + Unpin,
+        O: AsyncWrite + Unpin,
+    {
+        if !matches!(req, Request::RunWasmStreamed { .. }) {
+            bail!("run_wasm_streamed only sends RunWasmStreamed requests");
+        }
+        self.streamed(req, Some(input), output).await
+    }
+
+    /// Sends a `QueryCoreStreamed` request and copies the rows, as JSON
+    /// Lines, into `output` as they arrive; answered with `CoreStreamed`.
+    pub async fn query_core_streamed<O>(&self, req: Request, output: O) -> Result<Response>
+    where
+        O: AsyncWrite + Unpin,
+    {
+        if !matches!(req, Request::QueryCoreStreamed { .. }) {
+            bail!("query_core_streamed only sends QueryCoreStreamed requests");
+        }
+        self.streamed(req, None::<tokio::io::Empty>, output).await
+    }
+
+    /// Sends a `CoreImport` request and streams `input`, in `format`, into
+    /// the relation `name`; answered with `CoreImported` once it is read.
+    pub async fn import_core<I>(&self, name: &str, format: CoreDataFormat, mode: CoreImportMode, input: I) -> Result<Response>
+    where
+        I: AsyncRead + Unpin,
+    {
+        let req = Request::CoreImport {
+            name: name.to_string(),
+            format,
+            mode,
+        };
+        self.streamed(req, Some(input), tokio::io::sink()).await
+    }
+
+    /// Sends a `CoreExport` request and copies the relation `name`, in
+    /// `format`, into `output`; answered with `CoreExported`.
+    pub async fn export_core<O>(&self, name: &str, format: CoreDataFormat, output: O) -> Result<Response>
+    where
+        O: AsyncWrite + Unpin,
+    {
+        let req = Request::CoreExport {
+            name: name.to_string(),
+            format,
+        };
+        self.streamed(req, None::<tokio::io::Empty>, output).await
+    }
+
+    /// Sends `ExportSnapshot` and copies the archive the node streams back
+    /// into `output`; answered with `SnapshotExported`.
+    pub async fn export_snapshot<O>(&self, output: O) -> Result<Response>
+    where
+        O: AsyncWrite + Unpin,
+    {
+        self.streamed(Request::ExportSnapshot { path: None }, None::<tokio::io::Empty>, output).await
+    }
+
+    /// Sends `ImportSnapshot` and streams the archive from `input`; answered
+    /// with `SnapshotImported` once the node has applied it.
+    pub async fn import_snapshot<I>(&self, input: I) -> Result<Response>
+    where
+        I: AsyncRead + Unpin,
+    {
+        self.streamed(Request::ImportSnapshot { path: None }, Some(input), tokio::io::sink()).await
+    }
+
+    /// Sends a streamed request, then `input`, if it takes any, as data
+    /// frames, copying the data frames that come back into `output`.
+    async fn streamed<I, O>(&self, req: Request, mut input: Option<I>, mut output: O) -> Result<Response>
+    where
+        I: AsyncRead + Unpin,
+        O: AsyncWrite + Unpin,
+    {
+        match self.connection_state() {
+            ConnectionState::Connected => {}
+            ConnectionState::Reconnecting => bail!("Reconnecting to node"),
+            ConnectionState::Disconnected => bail!("Not connected to node"),
+        }
+        let bytes = serde_json::to_vec(&req)?;
+        if bytes.len() > self.max_frame_size {
+            bail!(
+                "Request of {} bytes exceeds the node's frame limit of {} bytes",
+                bytes.len(),
+                self.max_frame_size
+            );
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let (data_tx, mut data_rx) = mpsc::channel(16);
+        let mut writer = self.writer.lock().await;
+        *self.shared.data_sink.lock().unwrap() = Some(data_tx);
+        self.shared.pending.lock().unwrap().push_back((None, tx));
+
+        // The input is sent while output is drained so neither side stalls
+        // the other; the writer stays locked until the input is ended.
+        let chunk_len = STREAM_CHUNK_BYTES.min(self.max_frame_size - 1);
+        let compression = *self.shared.compression.lock().unwrap();
+        let send_input = async {
+            let sent = async {
+                write_bytes(&mut *writer, &bytes, compression).await?;
+                let Some(input) = input.as_mut() else {
+                    return Ok::<_, anyhow::Error>(());
+                };
+                let mut buf = vec![0u8; chunk_len];
+                loop {
+                    let n = input.read(&mut buf).await?;
+                    // Each chunk is compressed on its own, as it is sent.
+                    writer.write_all(&Framing::Binary.encode_data(&buf[..n], compression)?).await?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                }
+            }
+            .await;
+            drop(writer);
+            // A half-sent stream leaves the connection out of step for good.
+            if sent.is_err() {
+                self.shared.mark_disconnected();
+            }
+            sent
+        };
+        let copy_output = async {
+            // Keep draining after a failed write so the connection doesn't stall.
+            let mut copied = Ok(());
+            while let Some(data) = data_rx.recv().await {
+                if copied.is_ok() {
+                    copied = output.write_all(&data).await;
+                }
+            }
+            copied?;
+            output.flush().await
+        };
+        let (sent, copied) = tokio::join!(send_input, copy_output);
+        sent?;
+        copied?;
+
+        rx.await.map_err(|_| anyhow!("Connection to node lost"))
+    }
+
+    /// Responses the node pushes after `CoreWatch` or `WatchLicense`, from
+    /// now until the connection is lost. Replaces any earlier receiver;
+    /// without one, pushes are logged and dropped.
+    pub fn pushes(&self) -> mpsc::UnboundedReceiver<Response> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        *self.shared.pushes.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        if !self.shared.connected.load(Ordering::SeqCst) {
+            ConnectionState::Disconnected
+        } else if self.shared.resuming.load(Ordering::SeqCst) {
+            ConnectionState::Reconnecting
+        } else {
+            ConnectionState::Connected
+        }
+    }
+
+    /// The id of the node-side session, if the node keeps sessions.
+    pub fn session_id(&self) -> Option<u64> {
+        self.shared.session.lock().unwrap().as_ref().map(|session| session.session_id)
+    }
+
+    /// Registers a callback fired once when the connection is lost for good,
+    /// not when it drops and its session is resumed.
+    /// Fires immediately if the connection is already gone.
+    pub fn on_disconnect(&self, callback: impl FnOnce() + Send + 'static) {
+        let mut callbacks = self.shared.on_disconnect.lock().unwrap();
+        if self.shared.connected.load(Ordering::SeqCst) {
+            callbacks.push(Box::new(callback));
+            return;
+        }
+        drop(callbacks);
+        callback();
+    }
+
+    pub fn server_protocol_version(&self) -> u32 {
+        self.server_protocol_version
+    }
+
+    /// How the node was built, as it said in the handshake. `None` for
+    /// nodes too old to say.
+    pub fn node_build(&self) -> Option<&BuildInfo> {
+        self.node_build.as_ref()
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.heartbeat_interval
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Largest request body the node accepts on this connection.
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+}
+
+/// The major and minor parts of a `major.minor.patch` version.
+fn release(version: &str) -> Option<(&str, &str)> {
+    let mut parts = version.split('.');
+    Some((parts.next()?, parts.next()?))
+}
+
+impl Drop for NodeClient {
+    fn drop(&mut self) {
+        self.connection_task.abort();
+        self.watchdog_task.abort();
+    }
+}
+
+async fn read_loop(mut reader: FrameReader, writer: Arc<Mutex<WriteHalf>>, shared: Arc<Shared>) {
+    loop {
+        let frame = match read_frame(&mut reader).await {
+            Ok(frame) => frame,
+            Err(e) => {
+                debug!("Node connection closed: {}", e);
+                break;
+            }
+        };
+        *shared.last_seen.lock().unwrap() = Instant::now();
+
+        let (id, resp) = match frame {
+            Frame::Response(id, resp) => (id, *resp),
+            Frame::Data(data) => {
+                let sink = shared.data_sink.lock().unwrap().clone();
+                match sink {
+                    Some(sink) => {
+                        let _ = sink.send(data).await;
+                    }
+                    None => warn!("Discarding {} bytes of unsolicited stream data from node", data.len()),
+                }
+                continue;
+            }
+        };
+
+        match resp {
+            Response::Heartbeat { seq } => {
+                // A streamed request holds the writer until its input is sent,
+                // and this loop must keep draining its output meanwhile.
+                let writer = writer.clone();
+                let shared = shared.clone();
+                tokio::spawn(async move {
+                    let mut w = writer.lock().await;
+                    if write_frame(&mut *w, &Request::HeartbeatAck { seq }, DEFAULT_MAX_FRAME_SIZE).await.is_err() {
+                        shared.mark_disconnected();
+                    }
+                });
+            }
+            resp if resp.is_push() => {
+                if let Response::Event { seq, .. } = &resp {
+                    shared.last_seq.fetch_max(*seq, Ordering::SeqCst);
+                }
+                let sink = shared.pushes.lock().unwrap().clone();
+                match sink {
+                    Some(sink) => {
+                        let _ = sink.send(resp);
+                    }
+                    None => debug!("Discarding pushed response from node: {:?}", resp),
+                }
+            }
+            resp => {
+                // Data frames come before the response, so the stream is
+                // complete. Streamed requests are always sent bare.
+                if id.is_none() {
+                    shared.data_sink.lock().unwrap().take();
+                }
+                let waiter = {
+                    let mut pending = shared.pending.lock().unwrap();
+                    let at = pending.iter().position(|(waiting, _)| *waiting == id);
+                    at.and_then(|at| pending.remove(at))
+                };
+                match waiter {
+                    Some((_, tx)) => {
+                        let _ = tx.send(resp);
+                    }
+                    None => warn!("Discarding unsolicited response from node: {:?}", resp),
+                }
+            }
+        }
+    }
+}
+
+/// Reads from the node until the connection is lost for good, resuming the
+/// session each time it drops.
+async fn keep_connected(mut reader: FrameReader, writer: Arc<Mutex<WriteHalf>>, shared: Arc<Shared>, reconnect: Reconnect) {
+    loop {
+        read_loop(reader, writer.clone(), shared.clone()).await;
+        let session = shared.session.lock().unwrap().clone();
+        let Some(session) = session.filter(|_| shared.connected.load(Ordering::SeqCst)) else { break };
+        shared.resuming.store(true, Ordering::SeqCst);
+        shared.fail_in_flight();
+        let Some(resumed) = resume(&reconnect, &session, shared.last_seq.load(Ordering::SeqCst)).await else { break };
+        {
+            // Under the writer lock, so no request goes out on the old
+            // connection and waits on the new one.
+            let mut w = writer.lock().await;
+            shared.fail_in_flight();
+            *w = resumed.writer;
+        }
+        reader = resumed.reader;
+        *shared.session.lock().unwrap() = resumed.session;
+        *shared.compression.lock().unwrap() = resumed.compression;
+        *shared.last_seen.lock().unwrap() = Instant::now();
+        shared.resuming.store(false, Ordering::SeqCst);
+        debug!("Resumed session {} with the node", session.session_id);
+    }
+    shared.mark_disconnected();
+}
+
+/// Says Hello on a new connection to resume `session`, retrying until its
+/// grace period runs out. `None` if the node no longer holds it.
+async fn resume(reconnect: &Reconnect, session: &SessionGrant, last_seq: u64) -> Option<Handshake> {
+    let deadline = Instant::now() + Duration::from_millis(session.grace_ms);
+    let mut backoff = RESUME_BACKOFF_MIN;
+    loop {
+        let resume = SessionResume {
+            session_id: session.session_id,
+            token: session.token.clone(),
+            last_seq,
+        };
+        match handshake(reconnect, Some(resume)).await {
+            Ok(handshake) if handshake.session.as_ref().is_some_and(|s| s.resumed) => return Some(handshake),
+            Ok(_) => {
+                warn!("The node no longer holds session {}", session.session_id);
+                return None;
+            }
+            Err(e) => debug!("Failed to resume session {}: {}", session.session_id, e),
+        }
+        if Instant::now() + backoff >= deadline {
+            warn!("Could not resume session {} within {:?}", session.session_id, Duration::from_millis(session.grace_ms));
+            return None;
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(RESUME_BACKOFF_MAX);
+    }
+}
+
+/// Connects and says Hello.
+async fn handshake(reconnect: &Reconnect, resume: Option<SessionResume>) -> Result<Handshake> {
+    let (reader, mut writer) = reconnect.target.open().await?;
+    // An oversized response cannot be skipped: its request would go unanswered.
+    let mut reader = FramedRead::new(reader, FrameCodec::new(MAX_RESPONSE_FRAME_SIZE).with_discard_limit(0));
+
+    let hello = Request::Hello {
+        client_name: reconnect.client_name.clone(),
+        protocol_version: PROTOCOL_VERSION,
+        token: reconnect.token.clone(),
+        resume,
+        compressions_supported: Compression::ALL.iter().map(|c| c.name().to_string()).collect(),
+    };
+    write_frame(&mut writer, &hello, DEFAULT_MAX_FRAME_SIZE).await?;
+
+    let ack = match read_frame(&mut reader).await? {
+        Frame::Response(_, resp) => *resp,
+        Frame::Data(_) => bail!("Unexpected data frame during handshake"),
+    };
+    match ack {
+        Response::HelloAck { protocol_version, heartbeat_interval_ms, idle_timeout_ms, max_frame_size, build, session, compression } => Ok(Handshake {
+            reader,
+            writer,
+            protocol_version,
+            heartbeat_interval: Duration::from_millis(heartbeat_interval_ms),
+            idle_timeout: Duration::from_millis(idle_timeout_ms),
+            max_frame_size: max_frame_size as usize,
+            build,
+            session,
+            compression,
+        }),
+        Response::Busy { max_connections } => bail!("The node is at its limit of {} connections", max_connections),
+        other => bail!("Unexpected handshake response: {:?}", other),
+    }
+}
+
+/// Declares the connection dead when the node stays silent past its idle timeout.
+/// A slow in-flight request is not silence, so the check is skipped while one is pending.
+async fn watchdog(shared: Arc<Shared>, heartbeat_interval: Duration, idle_timeout: Duration) {
+    let mut ticker = tokio::time::interval(heartbeat_interval.max(Duration::from_millis(100)));
+    loop {
+        ticker.tick().await;
+        if !shared.connected.load(Ordering::SeqCst) {
+            return;
+        }
+        if shared.resuming.load(Ordering::SeqCst) || !shared.pending.lock().unwrap().is_empty() {
+            continue;
+        }
+        let silent_for = shared.last_seen.lock().unwrap().elapsed();
+        if silent_for > idle_timeout {
+            warn!("Node silent for {:?}. Treating connection as lost.", silent_for);
+            shared.mark_disconnected();
+            return;
+        }
+    }
+}
+
+enum Frame {
+    /// A response, with its envelope id if it came in one.
+    Response(Option<u64>, Box<Response>),
+    /// A data frame's payload, without the tag byte.
+    Data(Vec<u8>),
+}
+
+async fn read_frame(frames: &mut FrameReader) -> Result<Frame> {
+    let buf = match frames.next().await.transpose()? {
+        Some(framing::Frame::Message(buf)) => buf,
+        Some(framing::Frame::Data(data)) => return Ok(Frame::Data(data.to_vec())),
+        Some(framing::Frame::Oversized { declared }) => bail!("Node announced an oversized frame of {} bytes", declared),
+        None => bail!("The node closed the connection"),
+    };
+    if let Some(id) = envelope_id(&buf) {
+        let envelope: ResponseEnvelope = serde_json::from_slice(&buf)?;
+        return Ok(Frame::Response(Some(id), Box::new(envelope.response)));
+    }
+    Ok(Frame::Response(None, Box::new(serde_json::from_slice(&buf)?)))
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(stream: &mut W, req: &Request, max_frame_size: usize) -> Result<()> {
+    let bytes = serde_json::to_vec(req)?;
+    if bytes.len() > max_frame_size {
+        bail!("Request of {} bytes exceeds the frame limit of {} bytes", bytes.len(), max_frame_size);
+    }
+    write_bytes(stream, &bytes, None).await
+}
+
+/// Frames `bytes`, compressed as the node settled.
+async fn write_bytes<W: AsyncWrite + Unpin>(stream: &mut W, bytes: &[u8], compression: Option<FrameCompression>) -> Result<()> {
+    stream.write_all(&Framing::Binary.encode(bytes, compression)?).await?;
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::net::UnixListener;
+
+    /// One client's connection to a fake node.
+    struct FakeNode {
+        frames: FramedRead<OwnedReadHalf, FrameCodec>,
+        writer: OwnedWriteHalf,
+    }
+
+    impl FakeNode {
+        /// Accepts one client and answers its Hello as a node would, with
+        /// `heartbeat` and `idle_timeout`.
+        async fn accept(listener: &UnixListener, heartbeat: Duration, idle_timeout: Duration) -> Self {
+            let (reader, writer) = listener.accept().await.unwrap().0.into_split();
+            let mut node = Self {
+                frames: FramedRead::new(reader, FrameCodec::new(DEFAULT_MAX_FRAME_SIZE)),
+                writer,
+            };
+            let hello = node.next().await;
+            assert!(matches!(hello, Request::Hello { .. }), "Expected Hello, got {:?}", hello);
+            node.send(&Response::HelloAck {
+                protocol_version: PROTOCOL_VERSION,
+                heartbeat_interval_ms: heartbeat.as_millis() as u64,
+                idle_timeout_ms: idle_timeout.as_millis() as u64,
+                max_frame_size: DEFAULT_MAX_FRAME_SIZE as u64,
+                build: None,
+                session: None,
+                compression: None,
+            })
+            .await;
+            node
+        }
+
+        async fn send(&mut self, resp: &Response) {
+            self.writer.write_all(&framing::encode_frame(&serde_json::to_vec(resp).unwrap()).unwrap()).await.unwrap();
+        }
+
+        async fn next(&mut self) -> Request {
+            match self.frames.next().await {
+                Some(Ok(framing::Frame::Message(body))) => serde_json::from_slice(&body).unwrap(),
+                other => panic!("Expected a request, got {:?}", other),
+            }
+        }
+    }
+
+    fn listen() -> (tempfile::TempDir, UnixListener, IpcEndpoint) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("node.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        (dir, listener, IpcEndpoint::UnixSocket(path))
+    }
+
+    #[test]
+    fn warns_only_across_minor_releases() {
+        assert_eq!(release("0.3.0"), Some(("0", "3")));
+        assert_eq!(release("0.3.7"), release("0.3.0"));
+        assert_ne!(release("0.4.0"), release("0.3.0"));
+        assert_ne!(release("1.3.0"), release("0.3.0"));
+        assert_eq!(release("7"), None);
+    }
+
+    #[tokio::test]
+    async fn answers_heartbeats() {
+        let (_dir, listener, endpoint) = listen();
+        let (client, node) = tokio::join!(NodeClient::connect(&endpoint, "test"), FakeNode::accept(&listener, Duration::from_secs(1), Duration::from_secs(3)));
+        let (client, mut node) = (client.unwrap(), node);
+        assert_eq!(client.heartbeat_interval(), Duration::from_secs(1));
+        assert_eq!(client.idle_timeout(), Duration::from_secs(3));
+
+        node.send(&Response::Heartbeat { seq: 7 }).await;
+        assert!(matches!(node.next().await, Request::HeartbeatAck { seq: 7 }));
+        assert_eq!(client.connection_state(), ConnectionState::Connected);
+    }
+
+    #[tokio::test]
+    async fn notices_a_node_gone_silent() {
+        let (_dir, listener, endpoint) = listen();
+        let (heartbeat, idle_timeout) = (Duration::from_millis(100), Duration::from_millis(300));
+        let (client, _node) = tokio::join!(NodeClient::connect(&endpoint, "test"), FakeNode::accept(&listener, heartbeat, idle_timeout));
+        let client = client.unwrap();
+        let (tx, rx) = oneshot::channel();
+        client.on_disconnect(move || {
+            let _ = tx.send(Instant::now());
+        });
+
+        // The connection stays open, but nothing more comes over it.
+        let started = Instant::now();
+        let noticed = tokio::time::timeout(Duration::from_secs(5), rx).await.unwrap().unwrap();
+        let after = noticed - started;
+        assert!(after >= idle_timeout && after <= idle_timeout + heartbeat * 3, "Noticed after {:?}", after);
+        assert_eq!(client.connection_state(), ConnectionState::Disconnected);
+        assert!(client.request(Request::Ping).await.is_err());
+
+        // A callback registered too late still fires.
+        let (tx, rx) = oneshot::channel();
+        client.on_disconnect(move || {
+            let _ = tx.send(());
+        });
+        rx.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn notices_a_node_that_closes() {
+        let (_dir, listener, endpoint) = listen();
+        let (client, node) = tokio::join!(NodeClient::connect(&endpoint, "test"), FakeNode::accept(&listener, Duration::from_secs(60), Duration::from_secs(240)));
+        let client = client.unwrap();
+        let (tx, rx) = oneshot::channel();
+        client.on_disconnect(move || {
+            let _ = tx.send(());
+        });
+        drop(node);
+        tokio::time::timeout(Duration::from_secs(1), rx).await.unwrap().unwrap();
+        assert_eq!(client.connection_state(), ConnectionState::Disconnected);
+    }
+}
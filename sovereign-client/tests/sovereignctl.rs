@@ -0,0 +1,386 @@
+//! Runs the `sovereignctl` binary against an in-process node and checks
+//! what it prints and how it exits.
+
+use sovereign_node::testkit::{TestNode, TestNodeOptions};
+use sovereign_protocol::Response;
+use std::process::{Command, Output};
+
+/// Writes "hello\n" to stdout.
+const HELLO: &str = r#"(module
+  (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 1024) "hello\n")
+  (func (export "_start")
+    (i32.store (i32.const 0) (i32.const 1024))
+    (i32.store (i32.const 4) (i32.const 6))
+    (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))"#;
+
+/// Runs `sovereignctl` with `args` against `node`, off the runtime's
+/// threads so the node keeps serving.
+async fn ctl(node: &TestNode, args: &[&str]) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_sovereignctl"));
+    command.arg("--endpoint").arg(node.endpoint().to_string()).args(args).env_remove("SOVEREIGN_TOKEN");
+    tokio::task::spawn_blocking(move || command.output().unwrap()).await.unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn assert_exit(output: &Output, code: i32, args: &[&str]) {
+    assert_eq!(
+        output.status.code(),
+        Some(code),
+        "sovereignctl {:?}\nstdout: {}\nstderr: {}",
+        args,
+        stdout(output),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// `sovereignctl args` exits with `code`; its stdout.
+async fn expect(node: &TestNode, args: &[&str], code: i32) -> String {
+    let output = ctl(node, args).await;
+    assert_exit(&output, code, args);
+    stdout(&output)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn status_peers_and_metrics() {
+    let node = TestNode::start(Vec::new()).await.unwrap();
+
+    let status = expect(&node, &["status"], 0).await;
+    assert!(status.contains(&format!("peer id:        {}", node.peer_id())), "{}", status);
+    assert!(status.contains("license active: false"), "{}", status);
+    match serde_json::from_str(&expect(&node, &["--json", "status"], 0).await).unwrap() {
+        Response::Status(status) => assert_eq!(status.mesh_peer_id, node.peer_id()),
+        other => panic!("Expected Status, got {:?}", other),
+    }
+
+    assert!(status.contains(&format!("version:        {}", env!("CARGO_PKG_VERSION"))), "{}", status);
+
+    let version = expect(&node, &["--version"], 0).await;
+    assert!(version.starts_with(&format!("sovereignctl {}\n", env!("CARGO_PKG_VERSION"))), "{}", version);
+    assert!(version.contains(&format!("node:           {}", env!("CARGO_PKG_VERSION"))), "{}", version);
+
+    expect(&node, &["peers"], 0).await;
+    let metrics = expect(&node, &["metrics"], 0).await;
+    assert!(metrics.contains("core backend:          mem"), "{}", metrics);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn queries_print_tables_and_fail_with_the_query() {
+    let node = TestNode::start(Vec::new()).await.unwrap();
+
+    let rows = expect(&node, &["query", "?[x, y] := x = 1, y = 'one'"], 0).await;
+    assert_eq!(rows, "x  y\n1  one\n");
+    let rows = expect(&node, &["query", "?[n, s] := n = $n, s = $s", "--param", "n=5", "--param", "s=five"], 0).await;
+    assert_eq!(rows, "n  s\n5  five\n");
+    match serde_json::from_str(&expect(&node, &["--json", "query", "?[x] := x = 2"], 0).await).unwrap() {
+        Response::CoreResult(result) => assert_eq!(result["rows"], serde_json::json!([[2]])),
+        other => panic!("Expected CoreResult, got {:?}", other),
+    }
+
+    expect(&node, &["query", "?[x] := x = "], 1).await;
+    expect(&node, &["query"], 2).await;
+    expect(&node, &["query", "?[x] := x = 1", "--param", "novalue"], 2).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn uploads_runs_lists_and_deletes_modules() {
+    let modules = tempfile::tempdir().unwrap();
+    let path = modules.path().join("hello.wat");
+    std::fs::write(&path, HELLO).unwrap();
+    let node = TestNode::start_with(TestNodeOptions {
+        config: Some(format!("ipc_idle_timeout_mins = 0\n[wasm]\nrun_dirs = [{:?}]", modules.path())),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let path = path.to_str().unwrap();
+
+    expect(&node, &["wasm", "upload", "hello", path], 0).await;
+    let list = expect(&node, &["wasm", "list"], 0).await;
+    assert!(list.starts_with("NAME") && list.lines().any(|line| line.starts_with("hello ")), "{}", list);
+    assert!(expect(&node, &["wasm", "run", "hello"], 0).await.starts_with("hello\n"));
+    assert!(expect(&node, &["wasm", "run", path], 0).await.starts_with("hello\n"));
+
+    expect(&node, &["wasm", "delete", "hello"], 0).await;
+    expect(&node, &["wasm", "delete", "hello"], 1).await;
+    expect(&node, &["wasm", "run", "hello"], 1).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn license_commands_exit_with_the_license_state() {
+    // The testkit's finance backend never connects.
+    let node = TestNode::start(Vec::new()).await.unwrap();
+
+    let info = expect(&node, &["license", "info"], 1).await;
+    assert!(info.contains("valid:          false") && info.contains("details:        Never verified"), "{}", info);
+    let output = ctl(&node, &["license", "verify", &"ab".repeat(32)]).await;
+    assert_exit(&output, 1, &["license", "verify"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("The finance subsystem is unavailable"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn finds_the_node_and_exits_for_usage_and_unreachable_nodes() {
+    let node = TestNode::start(Vec::new()).await.unwrap();
+
+    // SOVEREIGN_IPC stands in for --endpoint.
+    let mut command = Command::new(env!("CARGO_BIN_EXE_sovereignctl"));
+    command.arg("status").env("SOVEREIGN_IPC", node.endpoint().to_string()).env_remove("SOVEREIGN_TOKEN");
+    let output = tokio::task::spawn_blocking(move || command.output().unwrap()).await.unwrap();
+    assert_exit(&output, 0, &["status"]);
+    assert!(stdout(&output).contains(node.peer_id()));
+
+    expect(&node, &["frobnicate"], 2).await;
+    expect(&node, &["subscribe", "weather"], 2).await;
+    let gone = tempfile::tempdir().unwrap();
+    let missing = gone.path().join("node.sock");
+    let output = Command::new(env!("CARGO_BIN_EXE_sovereignctl")).arg("--endpoint").arg(&missing).arg("status").output().unwrap();
+    assert_exit(&output, 3, &["status"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn raw_mode_passes_json_lines_both_ways() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let node = TestNode::start(Vec::new()).await.unwrap();
+    let script = format!(
+        "{{\"Hello\":{{\"client_name\":\"shell\",\"protocol_version\":{}}}}}\n\n  \"GetStatus\"  \n{{\"NoSuchRequest\":{{}}}}\n\"Ping\"\n",
+        sovereign_protocol::PROTOCOL_VERSION
+    );
+    let endpoint = node.endpoint().to_string();
+    let output = tokio::task::spawn_blocking(move || {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_sovereignctl"))
+            .args(["--raw", "--endpoint", &endpoint])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+        child.wait_with_output().unwrap()
+    })
+    .await
+    .unwrap();
+    assert_exit(&output, 0, &["--raw"]);
+
+    // A line per answer, blank input lines skipped and the rest trimmed.
+    let lines: Vec<serde_json::Value> = stdout(&output).lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(lines.len(), 4, "{:?}", lines);
+    assert!(lines[0]["HelloAck"].is_object(), "{}", lines[0]);
+    assert_eq!(lines[1]["Status"]["mesh_peer_id"], node.peer_id());
+    assert!(lines[2]["Error"].as_str().is_some_and(|e| e.contains("NoSuchRequest")), "{}", lines[2]);
+    assert_eq!(lines[3], serde_json::json!("Pong"));
+
+    expect(&node, &["--raw", "status"], 2).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn repl_runs_a_scripted_session() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let node = TestNode::start(Vec::new()).await.unwrap();
+    let config = tempfile::tempdir().unwrap();
+    let script = r#":create people {id: Int => name: String};
+?[id, name] <- [[1, "ada"], [2, "alan"]] :put people {id => name};
+\timing on
+?[name] := *people{name};
+?[name] := *peple{name};
+\relations
+\frobnicate
+\json on
+\status
+\quit
+?[never] := never = 1;
+"#;
+    let mut command = Command::new(env!("CARGO_BIN_EXE_sovereignctl"));
+    command
+        .args(["--endpoint", &node.endpoint().to_string(), "repl"])
+        .env("XDG_CONFIG_HOME", config.path())
+        .env_remove("SOVEREIGN_TOKEN")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let output = tokio::task::spawn_blocking(move || {
+        let mut child = command.spawn().unwrap();
+        child.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+        child.wait_with_output().unwrap()
+    })
+    .await
+    .unwrap();
+    assert_exit(&output, 0, &["repl"]);
+
+    let out = stdout(&output);
+    let err = String::from_utf8_lossy(&output.stderr);
+    assert!(out.contains("ada") && out.contains("alan"), "{}", out);
+    assert!(out.contains("Timing is on.") && out.contains(" ms"), "{}", out);
+    assert!(out.contains("people  (2 columns, 1 keys, 2 rows)"), "{}", out);
+    assert!(out.contains("\"Status\""), "{}", out);
+    assert!(!out.contains("never"), "{}", out);
+    assert!(err.contains("unknown_relation: "), "{}", err);
+    assert!(err.contains("hint: \\relations lists the stored relations"), "{}", err);
+    assert!(err.contains("unknown command '\\frobnicate'"), "{}", err);
+
+    let history = std::fs::read_to_string(config.path().join("sovereign/sovereignctl_history")).unwrap();
+    assert!(history.contains("\\relations"), "{}", history);
+}
+
+/// A node waiting to be set up, as far as `sovereignctl setup` can tell:
+/// it says Hello, answers `SetupState` with `state` and `SetupApply` with
+/// `SetupApplied`, and returns every request it was sent once the client
+/// hangs up.
+async fn setup_node(socket: &std::path::Path, state: sovereign_protocol::SetupState) -> tokio::task::JoinHandle<Vec<serde_json::Value>> {
+    use futures::StreamExt;
+    use sovereign_protocol::{envelope_id, framing, FrameCodec, Request, RequestEnvelope, ResponseEnvelope, PROTOCOL_VERSION};
+    use tokio::io::AsyncWriteExt;
+
+    let listener = tokio::net::UnixListener::bind(socket).unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut frames = tokio_util::codec::FramedRead::new(reader, FrameCodec::new(1024 * 1024));
+        let mut seen = Vec::new();
+        while let Some(Ok(framing::Frame::Message(body))) = frames.next().await {
+            let id = envelope_id(&body);
+            let req = match id {
+                Some(_) => serde_json::from_slice::<RequestEnvelope>(&body).unwrap().request,
+                None => serde_json::from_slice::<Request>(&body).unwrap(),
+            };
+            seen.push(serde_json::to_value(&req).unwrap());
+            let resp = match req {
+                Request::Hello { .. } => Response::HelloAck {
+                    protocol_version: PROTOCOL_VERSION,
+                    heartbeat_interval_ms: 60_000,
+                    idle_timeout_ms: 240_000,
+                    max_frame_size: 1024 * 1024,
+                    build: None,
+                    session: None,
+                    compression: None,
+                },
+                Request::SetupState => Response::SetupState(state.clone()),
+                Request::SetupApply { .. } => Response::SetupApplied { restart_required: false },
+                Request::HeartbeatAck { .. } => continue,
+                other => Response::SetupRequired { kind: other.kind().into() },
+            };
+            let body = match id {
+                Some(id) => serde_json::to_vec(&ResponseEnvelope { id, response: resp }).unwrap(),
+                None => serde_json::to_vec(&resp).unwrap(),
+            };
+            writer.write_all(&framing::encode_frame(&body).unwrap()).await.unwrap();
+        }
+        seen
+    })
+}
+
+/// Runs `sovereignctl` with `args` against `socket`, answering its
+/// questions with the lines of `answers`.
+async fn ctl_answering(socket: &std::path::Path, args: &[&str], answers: &str) -> Output {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut command = Command::new(env!("CARGO_BIN_EXE_sovereignctl"));
+    command
+        .arg("--endpoint")
+        .arg(socket)
+        .args(args)
+        .env_remove("SOVEREIGN_TOKEN")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let answers = answers.to_string();
+    tokio::task::spawn_blocking(move || {
+        let mut child = command.spawn().unwrap();
+        child.stdin.take().unwrap().write_all(answers.as_bytes()).unwrap();
+        child.wait_with_output().unwrap()
+    })
+    .await
+    .unwrap()
+}
+
+fn setup_state(pending: bool) -> sovereign_protocol::SetupState {
+    sovereign_protocol::SetupState {
+        pending,
+        config_path: "/etc/sovereign/node.toml".into(),
+        data_dir: "/var/lib/sovereign".into(),
+        network: "bitcoin".into(),
+        networks: vec!["bitcoin".into(), "signet".into()],
+        bootstrap_peers: Vec::new(),
+        swarm_key_present: false,
+        license_terms: sovereign_protocol::LicenseTerms {
+            developer_addr: "bc1qdeveloper".into(),
+            required_sats: 50_000,
+            min_confirmations: 3,
+            validity_blocks: None,
+            tiers: Vec::new(),
+        },
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn setup_asks_for_each_setting_and_applies_them() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("node.sock");
+    let key_file = dir.path().join("swarm.key");
+    let key = format!("/key/swarm/psk/1.0.0/\n/base16/\n{}\n", "ab".repeat(32));
+    std::fs::write(&key_file, &key).unwrap();
+    let node = setup_node(&socket, setup_state(true)).await;
+
+    // The data directory as it is, a network not offered and then one
+    // that is, a key imported from a file, two peers, and the policy.
+    let answers = format!("\nmainnet\nsignet\nmaybe\nimport\n{}\n/ip4/10.0.0.1/tcp/4001, /ip4/10.0.0.2/tcp/4001\ny\n", key_file.display());
+    let output = ctl_answering(&socket, &["setup"], &answers).await;
+    assert_exit(&output, 0, &["setup"]);
+    assert!(stdout(&output).contains("pay to:         bc1qdeveloper"), "{}", stdout(&output));
+    assert!(stdout(&output).contains("Set up. The node is starting."), "{}", stdout(&output));
+    let err = String::from_utf8_lossy(&output.stderr);
+    assert!(err.contains("'mainnet' is not one of bitcoin, signet") && err.contains("Answer generate or import, not 'maybe'"), "{}", err);
+
+    let seen = node.await.unwrap();
+    let apply = seen.iter().find_map(|req| req.get("SetupApply")).expect("no SetupApply");
+    assert_eq!(
+        apply,
+        &serde_json::json!({
+            "settings": {
+                "network": "signet",
+                "swarm_key": {"kind": "import", "key": key},
+                "bootstrap_peers": ["/ip4/10.0.0.1/tcp/4001", "/ip4/10.0.0.2/tcp/4001"],
+                "accept_license_policy": true,
+                "data_dir": "/var/lib/sovereign",
+            },
+            "force": false,
+        })
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn setup_applies_nothing_unless_every_answer_allows_it() {
+    let dir = tempfile::tempdir().unwrap();
+    // The license policy declined, a blank line taking the default no.
+    let socket = dir.path().join("declined.sock");
+    let node = setup_node(&socket, setup_state(true)).await;
+    let output = ctl_answering(&socket, &["setup"], "y\n\ngenerate\nnone\n\n").await;
+    assert_exit(&output, 1, &["setup"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Setup needs the license policy accepted."));
+    assert!(node.await.unwrap().iter().all(|req| req.get("SetupApply").is_none()));
+
+    // Stdin closed partway.
+    let socket = dir.path().join("closed.sock");
+    let node = setup_node(&socket, setup_state(true)).await;
+    let output = ctl_answering(&socket, &["setup"], "y\n").await;
+    assert_exit(&output, 1, &["setup"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("stdin closed before setup finished"));
+    assert!(node.await.unwrap().iter().all(|req| req.get("SetupApply").is_none()));
+
+    // Already set up: nothing is asked without --force.
+    let socket = dir.path().join("set-up.sock");
+    let node = setup_node(&socket, setup_state(false)).await;
+    let output = ctl_answering(&socket, &["setup"], "").await;
+    assert_exit(&output, 1, &["setup"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("The node is already set up."));
+    assert!(!stdout(&output).contains('?'));
+    assert!(node.await.unwrap().iter().all(|req| req.get("SetupApply").is_none()));
+}
@@ -0,0 +1,611 @@
+use crate::engine::Engine;
+use crate::interrupt::Launch;
+use crate::{CognitiveCore, CoreError};
+use anyhow::Context;
+use cozo::{DataValue, NamedRows, ScriptMutability};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Audited queries, when the sink is a relation.
+const AUDIT: &str = "sovereign_audit";
+
+/// Entries written to a relation in one script, at most.
+const BATCH: usize = 256;
+
+/// How often a relation sink tries again to write entries held back by
+/// another write.
+const HELD_RETRY: Duration = Duration::from_millis(50);
+
+/// Records every query run against the core, through `run`, the API
+/// methods built on it, or a transaction.
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    pub sink: AuditSink,
+    pub redaction: AuditRedaction,
+}
+
+#[derive(Debug, Clone)]
+pub enum AuditSink {
+    /// One JSON object per line. Once the file would pass `max_bytes` it is
+    /// renamed to `<path>.1`, shifting older files up to `<path>.<keep>`;
+    /// with `keep` 0 it is truncated instead.
+    File { path: PathBuf, max_bytes: u64, keep: u32 },
+    /// The `sovereign_audit` relation in the core itself, trimmed to the
+    /// newest `max_entries`.
+    Relation { max_entries: u64 },
+}
+
+/// What is recorded beyond each query's hash and parameter names. Both are
+/// off by default, in which case a failure is recorded as its error code
+/// or kind only, since error messages quote the query and its values.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AuditRedaction {
+    pub full_text: bool,
+    pub param_values: bool,
+}
+
+/// Who ran a query, as recorded in its audit entry.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum AuditSource {
+    /// The node itself, or any caller that does not say.
+    #[default]
+    Internal,
+    /// An IPC connection, by client name or connection id.
+    Ipc(String),
+    /// A WASM module's host call.
+    Wasm,
+}
+
+impl fmt::Display for AuditSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditSource::Internal => write!(f, "internal"),
+            AuditSource::Ipc(client) => write!(f, "ipc:{}", client),
+            AuditSource::Wasm => write!(f, "wasm"),
+        }
+    }
+}
+
+/// One audited query.
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    /// When the query started, in milliseconds since the Unix epoch.
+    pub at_ms: u64,
+    /// `internal`, `ipc:<client>` or `wasm`; see `AuditSource`.
+    pub source: String,
+    /// Hex SHA-256 of the query text.
+    pub query_hash: String,
+    /// Only if `AuditRedaction::full_text` is set.
+    pub query: Option<String>,
+    pub params: Vec<String>,
+    /// An object of the bound values; only if `AuditRedaction::param_values`
+    /// is set.
+    pub param_values: Option<Value>,
+    pub duration_ms: f64,
+    /// Rows returned; `None` if the query failed.
+    pub rows: Option<u64>,
+    /// Why the query failed; `None` if it succeeded.
+    pub error: Option<String>,
+}
+
+impl AuditEntry {
+    fn to_json(&self) -> Value {
+        json!({
+            "at_ms": self.at_ms,
+            "source": self.source,
+            "query_hash": self.query_hash,
+            "query": self.query,
+            "params": self.params,
+            "param_values": self.param_values,
+            "duration_ms": self.duration_ms,
+            "rows": self.rows,
+            "outcome": if self.error.is_none() { "ok" } else { "error" },
+            "error": self.error,
+        })
+    }
+
+    fn from_json(value: &Value) -> Self {
+        let text = |name: &str| value[name].as_str().map(str::to_string);
+        Self {
+            at_ms: value["at_ms"].as_u64().unwrap_or_default(),
+            source: text("source").unwrap_or_default(),
+            query_hash: text("query_hash").unwrap_or_default(),
+            query: text("query"),
+            params: serde_json::from_value(value["params"].clone()).unwrap_or_default(),
+            param_values: Some(value["param_values"].clone()).filter(|v| !v.is_null()),
+            duration_ms: value["duration_ms"].as_f64().unwrap_or_default(),
+            rows: value["rows"].as_u64(),
+            error: text("error"),
+        }
+    }
+}
+
+impl CognitiveCore {
+    /// The newest `limit` audited queries, oldest first, including any a
+    /// relation sink is holding until an open `CoreTransaction` ends.
+    pub fn audit_tail(&self, limit: usize) -> Result<Vec<AuditEntry>, CoreError> {
+        let Some(audit) = &self.audit else {
+            return Err(CoreError::AuditDisabled);
+        };
+        audit.tail(limit, self.query_timeout)
+    }
+}
+
+enum Message {
+    Entry(AuditEntry),
+    Tail { limit: usize, reply: Sender<Result<Vec<AuditEntry>, CoreError>> },
+}
+
+/// Hands entries to a writer thread, so a query never waits on the sink.
+/// The queue is unbounded: a relation sink's writes wait out an open
+/// transaction, and a query in that transaction must not wait on them.
+pub(crate) struct AuditLog {
+    redaction: AuditRedaction,
+    writer: Sender<Message>,
+}
+
+/// A query being audited, from `AuditLog::start`.
+pub(crate) struct Pending {
+    at_ms: u64,
+    started: Instant,
+    source: String,
+    query_hash: String,
+    query: Option<String>,
+    params: Vec<String>,
+    param_values: Option<Value>,
+}
+
+impl AuditLog {
+    /// Opens the sink and starts its writer. `launch` is the core's query
+    /// start lock, which relation writes take like any other query.
+    pub(crate) fn open(config: AuditConfig, db: &Engine, launch: &Arc<Launch>) -> anyhow::Result<Self> {
+        let sink = match config.sink {
+            AuditSink::File { path, max_bytes, keep } => {
+                let file = open_append(&path)?;
+                let size = file.metadata().map(|m| m.len()).unwrap_or_default();
+                Sink::File { path, file, size, max_bytes, keep }
+            }
+            AuditSink::Relation { max_entries } => {
+                let seq = relation_seq(db).context("cannot prepare the audit relation")?;
+                Sink::Relation {
+                    db: db.clone(),
+                    launch: launch.clone(),
+                    seq,
+                    max_entries,
+                }
+            }
+        };
+        let (writer, messages) = crossbeam_channel::unbounded();
+        std::thread::Builder::new()
+            .name("core-audit".into())
+            .spawn(move || sink.serve(messages))
+            .context("cannot start the audit writer")?;
+        Ok(Self {
+            redaction: config.redaction,
+            writer,
+        })
+    }
+
+    pub(crate) fn start(&self, source: &AuditSource, query: &str, params: &BTreeMap<String, DataValue>) -> Pending {
+        let param_values = self.redaction.param_values.then(|| {
+            Value::Object(params.iter().map(|(name, value)| (name.clone(), Value::from(value.clone()))).collect())
+        });
+        Pending {
+            at_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            started: Instant::now(),
+            source: source.to_string(),
+            query_hash: hex::encode(Sha256::digest(query.as_bytes())),
+            query: self.redaction.full_text.then(|| query.to_string()),
+            params: params.keys().cloned().collect(),
+            param_values,
+        }
+    }
+
+    pub(crate) fn finish(&self, pending: Pending, outcome: &Result<NamedRows, CoreError>) {
+        let entry = AuditEntry {
+            at_ms: pending.at_ms,
+            source: pending.source,
+            query_hash: pending.query_hash,
+            query: pending.query,
+            params: pending.params,
+            param_values: pending.param_values,
+            duration_ms: pending.started.elapsed().as_secs_f64() * 1000.0,
+            rows: outcome.as_ref().ok().map(|rows| rows.rows.len() as u64),
+            error: outcome.as_ref().err().map(|e| self.describe(e)),
+        };
+        let _ = self.writer.send(Message::Entry(entry));
+    }
+
+    /// The newest `limit` entries, oldest first, once every entry before
+    /// the call is written. Gives up after `timeout`.
+    pub(crate) fn tail(&self, limit: usize, timeout: Option<Duration>) -> Result<Vec<AuditEntry>, CoreError> {
+        let (reply, result) = crossbeam_channel::bounded(1);
+        let stopped = || CoreError::Interrupted("the audit writer stopped".into());
+        self.writer.send(Message::Tail { limit, reply }).map_err(|_| stopped())?;
+        let started = Instant::now();
+        match timeout {
+            Some(timeout) => result.recv_timeout(timeout).map_err(|e| match e {
+                crossbeam_channel::RecvTimeoutError::Timeout => CoreError::Timeout { elapsed: started.elapsed() },
+                crossbeam_channel::RecvTimeoutError::Disconnected => stopped(),
+            })?,
+            None => result.recv().map_err(|_| stopped())?,
+        }
+    }
+
+    fn describe(&self, e: &CoreError) -> String {
+        if self.redaction.full_text {
+            return e.to_string();
+        }
+        match e {
+            CoreError::Query(error) | CoreError::Parse(error) => error.code.clone().unwrap_or_else(|| e.kind().into()),
+            other => other.kind().into(),
+        }
+    }
+}
+
+enum Sink {
+    File {
+        path: PathBuf,
+        file: File,
+        size: u64,
+        max_bytes: u64,
+        keep: u32,
+    },
+    Relation {
+        db: Engine,
+        launch: Arc<Launch>,
+        /// The last entry's sequence number.
+        seq: i64,
+        max_entries: u64,
+    },
+}
+
+impl Sink {
+    /// Writes entries until the core, and with it the sender, is gone.
+    /// Failed writes are dropped; the sink may be back for the next ones.
+    /// Entries a relation sink cannot write while another write holds the
+    /// core's writer are held and written once it can.
+    fn serve(mut self, messages: Receiver<Message>) {
+        let mut held = Vec::new();
+        loop {
+            let mut next = if held.is_empty() {
+                match messages.recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => return,
+                }
+            } else {
+                match messages.recv_timeout(HELD_RETRY) {
+                    Ok(message) => Some(message),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return self.flush(&mut held),
+                }
+            };
+            while let Some(message) = next.take() {
+                match message {
+                    Message::Entry(entry) => held.push(entry),
+                    Message::Tail { limit, reply } => {
+                        self.flush(&mut held);
+                        let tail = self.tail(limit).map(|mut entries| {
+                            entries.extend(held.iter().cloned());
+                            entries.split_off(entries.len().saturating_sub(limit))
+                        });
+                        let _ = reply.send(tail);
+                    }
+                }
+                if held.len() < BATCH {
+                    next = messages.try_recv().ok();
+                }
+            }
+            self.flush(&mut held);
+        }
+    }
+
+    /// Writes `held`, keeping it if another write holds the writer.
+    fn flush(&mut self, held: &mut Vec<AuditEntry>) {
+        if !matches!(self.write(held), Err(CoreError::TransactionConflict(_))) {
+            held.clear();
+        }
+    }
+
+    fn write(&mut self, entries: &[AuditEntry]) -> Result<(), CoreError> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        match self {
+            Sink::File { path, file, size, max_bytes, keep } => {
+                for entry in entries {
+                    let mut line = entry.to_json().to_string();
+                    line.push('\n');
+                    if *size > 0 && *size + line.len() as u64 > *max_bytes {
+                        *file = rotate(path, *keep).map_err(|e| CoreError::Io(e.to_string()))?;
+                        *size = 0;
+                    }
+                    file.write_all(line.as_bytes()).map_err(|e| CoreError::Io(e.to_string()))?;
+                    *size += line.len() as u64;
+                }
+                Ok(())
+            }
+            Sink::Relation { db, launch, seq, max_entries } => {
+                let last = *seq + entries.len() as i64;
+                let rows: Vec<DataValue> = entries
+                    .iter()
+                    .zip(*seq + 1..)
+                    .map(|(entry, n)| DataValue::List(vec![DataValue::from(n), DataValue::from(entry.to_json().to_string())]))
+                    .collect();
+                let script = format!(
+                    "{{ ?[seq, entry] <- $rows :put {audit} {{seq => entry}} }}\n\
+                     {{ ?[seq] := *{audit}{{seq}}, seq <= $cutoff :rm {audit} {{seq}} }}",
+                    audit = AUDIT
+                );
+                let params = BTreeMap::from([
+                    ("rows".to_string(), DataValue::List(rows)),
+                    ("cutoff".to_string(), DataValue::from(last - *max_entries as i64)),
+                ]);
+                let written = run(db, launch, &script, params, ScriptMutability::Mutable);
+                if !matches!(written, Err(CoreError::TransactionConflict(_))) {
+                    *seq = last;
+                }
+                written.map(|_| ())
+            }
+        }
+    }
+
+    fn tail(&mut self, limit: usize) -> Result<Vec<AuditEntry>, CoreError> {
+        match self {
+            Sink::File { path, keep, .. } => {
+                // The current file holds the newest lines, `.1` the next
+                // newest, and so on.
+                let mut lines: Vec<String> = Vec::new();
+                for n in 0..=*keep {
+                    if lines.len() >= limit {
+                        break;
+                    }
+                    let Ok(file) = File::open(numbered(path, n)) else { break };
+                    let mut older: Vec<String> = BufReader::new(file).lines().map_while(Result::ok).collect();
+                    older.append(&mut lines);
+                    lines = older;
+                }
+                let skip = lines.len().saturating_sub(limit);
+                Ok(lines[skip..]
+                    .iter()
+                    .filter_map(|line| serde_json::from_str(line).ok())
+                    .map(|value| AuditEntry::from_json(&value))
+                    .collect())
+            }
+            Sink::Relation { db, launch, .. } => {
+                let script = format!("?[seq, entry] := *{}{{seq, entry}} :order -seq :limit $limit", AUDIT);
+                let params = BTreeMap::from([("limit".to_string(), DataValue::from(limit as i64))]);
+                let listed = run(db, launch, &script, params, ScriptMutability::Immutable)?;
+                Ok(listed
+                    .rows
+                    .iter()
+                    .rev()
+                    .filter_map(|row| serde_json::from_str(row[1].get_str()?).ok())
+                    .map(|value| AuditEntry::from_json(&value))
+                    .collect())
+            }
+        }
+    }
+}
+
+/// Runs a script of the sink's own, which is not audited. Holding the
+/// launch lock keeps it from being taken for a query being started.
+fn run(
+    db: &Engine,
+    launch: &Launch,
+    script: &str,
+    params: BTreeMap<String, DataValue>,
+    mutability: ScriptMutability,
+) -> Result<NamedRows, CoreError> {
+    let _launch = launch.hold();
+    db.run_script(script, params, mutability).map_err(|e| CoreError::from_report(&e, script))
+}
+
+/// Creates the audit relation if it is missing, and returns its last
+/// sequence number.
+fn relation_seq(db: &Engine) -> Result<i64, CoreError> {
+    let listed = db
+        .run_script("::relations", BTreeMap::new(), ScriptMutability::Immutable)
+        .map_err(|e| CoreError::from_report(&e, "::relations"))?;
+    if !listed.rows.iter().any(|row| row.first().and_then(DataValue::get_str) == Some(AUDIT)) {
+        let create = format!(":create {} {{seq: Int => entry: String}}", AUDIT);
+        db.run_script(&create, BTreeMap::new(), ScriptMutability::Mutable)
+            .map_err(|e| CoreError::from_report(&e, &create))?;
+        return Ok(0);
+    }
+    let last = format!("?[seq] := *{}{{seq}} :order -seq :limit 1", AUDIT);
+    let listed = db
+        .run_script(&last, BTreeMap::new(), ScriptMutability::Immutable)
+        .map_err(|e| CoreError::from_report(&e, &last))?;
+    Ok(listed.rows.first().and_then(|row| row[0].get_int()).unwrap_or_default())
+}
+
+fn open_append(path: &Path) -> anyhow::Result<File> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("cannot open the audit log {}", path.display()))
+}
+
+/// `path` itself for 0, otherwise `<path>.<n>`.
+fn numbered(path: &Path, n: u32) -> PathBuf {
+    if n == 0 {
+        return path.to_path_buf();
+    }
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Shifts the rotated files up one, dropping the oldest, and starts a new
+/// current file.
+fn rotate(path: &Path, keep: u32) -> std::io::Result<File> {
+    if keep > 0 {
+        let _ = fs::remove_file(numbered(path, keep));
+        for n in (0..keep).rev() {
+            let from = numbered(path, n);
+            if from.exists() {
+                fs::rename(from, numbered(path, n + 1))?;
+            }
+        }
+    }
+    OpenOptions::new().create(true).write(true).truncate(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+    use crate::{CoreConfig, QueryOptions};
+    use serde_json::json;
+
+    fn audited(sink: AuditSink, redaction: AuditRedaction) -> CognitiveCore {
+        CognitiveCore::new(CoreConfig {
+            audit: Some(AuditConfig { sink, redaction }),
+            // Otherwise reads run, and are hashed, with a `:limit` added.
+            max_result_rows: None,
+            ..CoreConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn file(dir: &TempDir, max_bytes: u64, keep: u32) -> AuditSink {
+        AuditSink::File {
+            path: dir.path().join("audit").join("core.jsonl"),
+            max_bytes,
+            keep,
+        }
+    }
+
+    fn hash(query: &str) -> String {
+        hex::encode(Sha256::digest(query.as_bytes()))
+    }
+
+    const READ: &str = "?[k, v] := *secrets{k, v}, k == $k";
+    const BROKEN: &str = "?[k] := *secrets{k}, k == $k,, v";
+
+    /// Creates `secrets`, then reads it as `ipc:cli` and runs a query that
+    /// fails to parse, both with `hunter2` bound.
+    fn run_queries(core: &CognitiveCore) {
+        core.run(":create secrets {k: String => v: String}", Value::Null).unwrap();
+        core.run("?[k, v] <- [['hunter2', 'x']] :put secrets {k => v}", Value::Null).unwrap();
+        let options = QueryOptions {
+            source: AuditSource::Ipc("cli".into()),
+            ..Default::default()
+        };
+        core.run_with(READ, json!({"k": "hunter2"}), &options).unwrap();
+        assert!(core.run_with(BROKEN, json!({"k": "hunter2"}), &options).is_err());
+    }
+
+    #[test]
+    fn every_query_is_written_with_its_outcome() {
+        let dir = TempDir::new("audit-entries");
+        for sink in [file(&dir, 1 << 20, 0), AuditSink::Relation { max_entries: 100 }] {
+            let core = audited(sink.clone(), AuditRedaction::default());
+            run_queries(&core);
+            let entries = core.audit_tail(100).unwrap();
+            // The core's own queries are recorded too, as internal.
+            let (from_cli, internal): (Vec<_>, Vec<_>) = entries.iter().partition(|e| e.source == "ipc:cli");
+            assert_eq!(from_cli.len(), 2, "{:?}", sink);
+            assert!(internal.len() >= 2 && internal.iter().all(|e| e.source == "internal" && e.rows.is_some()));
+
+            let (read, broken) = (from_cli[0], from_cli[1]);
+            assert_eq!(read.source, "ipc:cli");
+            assert_eq!(read.query_hash, hash(READ));
+            assert_eq!((read.rows, &read.error), (Some(1), &None));
+            assert_eq!(broken.query_hash, hash(BROKEN));
+            assert_eq!(broken.rows, None);
+            assert!(broken.error.is_some());
+            assert!(entries.windows(2).all(|pair| pair[0].at_ms <= pair[1].at_ms));
+
+            assert_eq!(core.audit_tail(1).unwrap()[0].query_hash, hash(BROKEN));
+        }
+    }
+
+    #[test]
+    fn parameters_and_text_are_redacted_unless_asked_for() {
+        let dir = TempDir::new("audit-redaction");
+        let core = audited(file(&dir, 1 << 20, 0), AuditRedaction::default());
+        run_queries(&core);
+        for entry in core.audit_tail(100).unwrap() {
+            assert_eq!((&entry.query, &entry.param_values), (&None, &None));
+        }
+        let read = &core.audit_tail(2).unwrap()[0];
+        assert_eq!(read.params, vec!["k".to_string()]);
+        // Nothing written anywhere quotes a bound value or the query.
+        let written = fs::read_to_string(dir.path().join("audit").join("core.jsonl")).unwrap();
+        assert!(!written.contains("hunter2") && !written.contains("secrets"), "{}", written);
+
+        let dir = TempDir::new("audit-full");
+        let redaction = AuditRedaction {
+            full_text: true,
+            param_values: true,
+        };
+        let core = audited(file(&dir, 1 << 20, 0), redaction);
+        run_queries(&core);
+        let entries = core.audit_tail(2).unwrap();
+        assert_eq!(entries[0].query.as_deref(), Some(READ));
+        assert_eq!(entries[0].param_values, Some(json!({"k": "hunter2"})));
+        assert!(entries[1].error.as_ref().is_some_and(|e| e.len() > 20));
+    }
+
+    #[test]
+    fn the_relation_sink_is_trimmed_to_its_newest_entries() {
+        let core = audited(AuditSink::Relation { max_entries: 3 }, AuditRedaction::default());
+        for n in 0..6 {
+            core.run(&format!("?[n] <- [[{}]]", n), Value::Null).unwrap();
+        }
+        let entries = core.audit_tail(10).unwrap();
+        let hashes: Vec<String> = (3..6).map(|n| hash(&format!("?[n] <- [[{}]]", n))).collect();
+        assert_eq!(entries.iter().map(|e| e.query_hash.clone()).collect::<Vec<_>>(), hashes);
+
+
+        // Entries wait out a transaction holding the writer, and are listed
+        // meanwhile.
+        let mut transaction = core.begin().unwrap();
+        transaction.exec("?[n] <- [[6]]", Value::Null).unwrap();
+        core.run("?[n] <- [[7]]", Value::Null).unwrap();
+        let held: Vec<String> = ["?[n] <- [[6]]", "?[n] <- [[7]]"].into_iter().map(hash).collect();
+        let tail = |core: &CognitiveCore| core.audit_tail(2).unwrap().into_iter().map(|e| e.query_hash).collect::<Vec<_>>();
+        assert_eq!(tail(&core), held);
+        drop(transaction);
+        assert_eq!(tail(&core), held);
+    }
+
+    #[test]
+    fn a_failing_sink_drops_entries_without_failing_queries() {
+        let dir = TempDir::new("audit-failing");
+        // Every entry rotates the file, which fails while its directory is
+        // gone.
+        let core = audited(file(&dir, 1, 1), AuditRedaction::default());
+        core.run("?[n] <- [[1]]", Value::Null).unwrap();
+        assert_eq!(core.audit_tail(1).unwrap()[0].query_hash, hash("?[n] <- [[1]]"));
+
+        fs::remove_dir_all(dir.path().join("audit")).unwrap();
+        core.run("?[n] <- [[2]]", Value::Null).unwrap();
+        assert!(core.audit_tail(10).unwrap().is_empty());
+
+        fs::create_dir_all(dir.path().join("audit")).unwrap();
+        core.run("?[n] <- [[3]]", Value::Null).unwrap();
+        let hashes: Vec<String> = core.audit_tail(10).unwrap().into_iter().map(|e| e.query_hash).collect();
+        assert_eq!(hashes, vec![hash("?[n] <- [[3]]")]);
+    }
+
+    #[test]
+    fn tail_needs_a_sink() {
+        let core = CognitiveCore::new(CoreConfig::default()).unwrap();
+        assert!(matches!(core.audit_tail(1), Err(CoreError::AuditDisabled)));
+    }
+}
@@ -0,0 +1,824 @@
+use crate::history::HISTORY_AT;
+use crate::params::to_data_value;
+use crate::schema::check_relation;
+use crate::{CognitiveCore, ColumnInfo, ColumnType, CoreError, CoreTransaction};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use cozo::{DataValue, Num, ScriptMutability, UuidWrapper};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+/// Rows written per engine statement, and read per export page, so neither
+/// side holds more than this many rows at once.
+pub(crate) const BATCH_ROWS: usize = 1000;
+
+/// Rejected rows reported with their reason; later ones are only counted.
+pub const MAX_REPORTED_REJECTS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFormat {
+    /// One JSON object per line, keyed by column name.
+    JsonLines,
+    /// A header row of column names, then one record per row.
+    Csv,
+}
+
+/// How imported rows combine with the relation's existing rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Delete every existing row first.
+    Replace,
+    /// Reject rows whose key is already present.
+    Append,
+    /// Overwrite rows whose key is already present.
+    Upsert,
+}
+
+/// The outcome of `import_relation`; `rows_read` is `inserted + rejected`.
+#[derive(Debug, Clone, Default)]
+pub struct ImportSummary {
+    pub rows_read: u64,
+    pub inserted: u64,
+    pub rejected: u64,
+    /// The first `MAX_REPORTED_REJECTS` rejected rows.
+    pub rejects: Vec<RowReject>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RowReject {
+    /// 1-based line of the row in the input.
+    pub line: u64,
+    pub reason: String,
+}
+
+/// A relation column as the import checks values against it.
+pub(crate) struct Column {
+    pub(crate) name: String,
+    /// `None` for types `create_relation` cannot declare, like lists and
+    /// tuples, which the engine checks.
+    column_type: Option<ColumnType>,
+    nullable: bool,
+    pub(crate) key: bool,
+    has_default: bool,
+}
+
+impl Column {
+    pub(crate) fn new(info: &ColumnInfo) -> Self {
+        let (base, nullable) = match info.column_type.strip_suffix('?') {
+            Some(base) => (base, true),
+            None => (info.column_type.as_str(), false),
+        };
+        Self {
+            name: info.name.clone(),
+            column_type: ColumnType::parse(base),
+            nullable,
+            key: info.key,
+            has_default: info.has_default,
+        }
+    }
+}
+
+impl CognitiveCore {
+    /// Writes every row of `name` to `writer`, keys first in key order, and
+    /// returns the number of rows written.
+    ///
+    /// Rows are read a page at a time in key order, each page as its own
+    /// query, so rows written during the export may or may not appear.
+    pub fn export_relation(&self, name: &str, format: DataFormat, writer: impl Write) -> Result<u64, CoreError> {
+        check_relation(name)?;
+        let columns = self.columns(name)?;
+        let names: Vec<&str> = columns.iter().map(|c| c.name.as_str()).collect();
+        let keys: Vec<&str> = columns.iter().filter(|c| c.key).map(|c| c.name.as_str()).collect();
+
+        let mut out = match format {
+            DataFormat::JsonLines => Output::JsonLines(BufWriter::new(writer)),
+            DataFormat::Csv => {
+                let mut csv = csv::Writer::from_writer(writer);
+                csv.write_record(&names).map_err(csv_error)?;
+                Output::Csv(Box::new(csv))
+            }
+        };
+
+        // Each page starts after the last key of the previous one. Scanning
+        // from its first key column lets the engine seek rather than rescan.
+        let body = format!("?[{}] := *{}{{{}}}", names.join(", "), name, names.join(", "));
+        let first_page = format!("{} :limit {}", body, BATCH_ROWS);
+        let next_page = match keys.first() {
+            Some(first) => format!(
+                "{}, {} >= $first, [{}] > $last :limit {}",
+                body,
+                first,
+                keys.join(", "),
+                BATCH_ROWS
+            ),
+            None => first_page.clone(),
+        };
+
+        let mut exported = 0;
+        let mut last: Option<Vec<DataValue>> = None;
+        loop {
+            let page = match last.take() {
+                None => self.script(&first_page, BTreeMap::new(), ScriptMutability::Immutable)?,
+                Some(last) => {
+                    let params = BTreeMap::from([
+                        ("first".to_string(), last[0].clone()),
+                        ("last".to_string(), DataValue::List(last)),
+                    ]);
+                    self.script(&next_page, params, ScriptMutability::Immutable)?
+                }
+            };
+            for row in &page.rows {
+                out.write_row(&names, row)?;
+            }
+            exported += page.rows.len() as u64;
+            if page.rows.len() < BATCH_ROWS || keys.is_empty() {
+                break;
+            }
+            last = page.rows.last().map(|row| row[..keys.len()].to_vec());
+        }
+        out.finish()?;
+        Ok(exported)
+    }
+
+    /// Loads rows from `reader` into the stored relation `name`.
+    ///
+    /// Rows that do not fit the relation's columns are rejected and counted
+    /// while the rest are imported. The import runs as one transaction, so
+    /// a read or engine failure imports nothing, and other core writes wait
+    /// for it to end.
+    ///
+    /// JSON values convert as for query parameters. A column missing from a
+    /// row is null if the column is nullable, its default if it has one, and
+    /// rejects the row otherwise; an unknown field rejects the row.
+    ///
+    /// CSV fields convert by column type:
+    ///
+    /// | column              | field                                              |
+    /// |---------------------|----------------------------------------------------|
+    /// | any, empty field    | null if nullable; `""` for `String`; else rejected |
+    /// | `Int`               | decimal integer in the i64 range                   |
+    /// | `Float`             | decimal or exponent number, `inf` or `NaN`         |
+    /// | `Bool`              | `true`, `false`, `1` or `0`, in any case           |
+    /// | `String`            | the text as is                                     |
+    /// | `Bytes`             | standard base64                                    |
+    /// | `Uuid`              | hyphenated UUID                                    |
+    /// | `Any`               | an integer, else a finite float, else `true` or `false`, else the text |
+    /// | other types         | JSON text, converted as a JSON value               |
+    ///
+    /// Leading and trailing spaces are ignored for numbers and booleans. The
+    /// header may leave out columns that are nullable or have a default.
+    /// CSV cannot tell a list in an `Any` column from text, so only JSON
+    /// Lines round-trips every value.
+    pub fn import_relation(
+        &self,
+        name: &str,
+        format: DataFormat,
+        reader: impl Read,
+        mode: ImportMode,
+    ) -> Result<ImportSummary, CoreError> {
+        check_relation(name)?;
+        let columns: Vec<Column> = self.columns(name)?.iter().map(Column::new).collect();
+        self.transact(|tx| {
+            let mut import = Import::new(tx, name, &columns, mode);
+            if mode == ImportMode::Replace {
+                import.clear()?;
+            }
+            match format {
+                DataFormat::JsonLines => read_json_lines(reader, &mut import)?,
+                DataFormat::Csv => read_csv(reader, &mut import)?,
+            }
+            import.finish()
+        })
+    }
+}
+
+enum Output<W: Write> {
+    JsonLines(BufWriter<W>),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> Output<W> {
+    fn write_row(&mut self, names: &[&str], row: &[DataValue]) -> Result<(), CoreError> {
+        match self {
+            Output::JsonLines(out) => {
+                let object: serde_json::Map<String, Value> = names
+                    .iter()
+                    .zip(row)
+                    .map(|(name, value)| (name.to_string(), Value::from(value.clone())))
+                    .collect();
+                serde_json::to_writer(&mut *out, &object).map_err(|e| CoreError::Io(e.to_string()))?;
+                out.write_all(b"\n").map_err(|e| CoreError::Io(e.to_string()))
+            }
+            Output::Csv(out) => out.write_record(row.iter().map(csv_field)).map_err(csv_error),
+        }
+    }
+
+    fn finish(self) -> Result<(), CoreError> {
+        match self {
+            Output::JsonLines(mut out) => out.flush().map_err(|e| CoreError::Io(e.to_string())),
+            Output::Csv(mut out) => out.flush().map_err(|e| CoreError::Io(e.to_string())),
+        }
+    }
+}
+
+/// The CSV text for a value; the inverse of `from_csv` for every column type.
+fn csv_field(value: &DataValue) -> String {
+    match value {
+        DataValue::Null => String::new(),
+        DataValue::Str(s) => s.to_string(),
+        DataValue::Bool(b) => b.to_string(),
+        DataValue::Num(Num::Int(i)) => i.to_string(),
+        // Debug keeps the `.0` of whole numbers and spells `inf` and `NaN`
+        // the way `f64::from_str` reads them.
+        DataValue::Num(Num::Float(f)) => format!("{:?}", f),
+        DataValue::Bytes(bytes) => STANDARD.encode(bytes),
+        DataValue::Uuid(UuidWrapper(uuid)) => uuid.to_string(),
+        other => Value::from(other.clone()).to_string(),
+    }
+}
+
+fn csv_error(e: csv::Error) -> CoreError {
+    CoreError::Io(e.to_string())
+}
+
+/// Rows of an import waiting to be written.
+pub(crate) struct Import<'a> {
+    tx: &'a mut CoreTransaction,
+    relation: &'a str,
+    columns: &'a [Column],
+    mode: ImportMode,
+    /// Which columns the batched rows carry. A row that leaves out another
+    /// set of defaulted columns starts a new batch.
+    present: Vec<bool>,
+    /// Line and the values of the present columns.
+    batch: Vec<(u64, Vec<DataValue>)>,
+    summary: ImportSummary,
+    /// The relation's history and the timestamp written rows are recorded
+    /// at there, if they are.
+    history: Option<(String, DataValue)>,
+}
+
+impl<'a> Import<'a> {
+    pub(crate) fn new(tx: &'a mut CoreTransaction, relation: &'a str, columns: &'a [Column], mode: ImportMode) -> Self {
+        Self {
+            tx,
+            relation,
+            columns,
+            mode,
+            present: Vec::new(),
+            batch: Vec::new(),
+            summary: ImportSummary::default(),
+            history: None,
+        }
+    }
+
+    /// Also records every row written in `history`, at `at`.
+    pub(crate) fn recorded(mut self, history: Option<String>, at: DataValue) -> Self {
+        self.history = history.map(|history| (history, at));
+        self
+    }
+
+    /// Queues a row read from `line`; `None` leaves a column to its default.
+    pub(crate) fn row(&mut self, line: u64, row: Vec<Option<DataValue>>) -> Result<(), CoreError> {
+        self.summary.rows_read += 1;
+        let present: Vec<bool> = row.iter().map(Option::is_some).collect();
+        if present != self.present {
+            self.flush()?;
+            self.present = present;
+        }
+        self.batch.push((line, row.into_iter().flatten().collect()));
+        if self.batch.len() >= BATCH_ROWS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Counts a row from `line` that could not be read.
+    fn invalid(&mut self, line: u64, reason: String) {
+        self.summary.rows_read += 1;
+        self.reject(line, reason);
+    }
+
+    fn reject(&mut self, line: u64, reason: String) {
+        self.summary.rejected += 1;
+        if self.summary.rejects.len() < MAX_REPORTED_REJECTS {
+            self.summary.rejects.push(RowReject { line, reason });
+        }
+    }
+
+    pub(crate) fn clear(&mut self) -> Result<(), CoreError> {
+        let keys: Vec<&str> = self.columns.iter().filter(|c| c.key).map(|c| c.name.as_str()).collect();
+        let keys = keys.join(", ");
+        let script = format!("?[{keys}] := *{rel}{{{keys}}} :rm {rel} {{{keys}}}", keys = keys, rel = self.relation);
+        self.tx.exec_bound(&script, BTreeMap::new())?;
+        Ok(())
+    }
+
+    /// Writes the rows still queued.
+    pub(crate) fn finish(mut self) -> Result<ImportSummary, CoreError> {
+        self.flush()?;
+        Ok(self.summary)
+    }
+
+    fn flush(&mut self) -> Result<(), CoreError> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::take(&mut self.batch);
+        let present: Vec<&Column> = self
+            .columns
+            .iter()
+            .zip(&self.present)
+            .filter(|(_, present)| **present)
+            .map(|(column, _)| column)
+            .collect();
+        let keys = present.iter().filter(|c| c.key).count();
+        // A key column left to its default gets a key nobody can clash with.
+        let keyed = keys == self.columns.iter().filter(|c| c.key).count();
+
+        let rows = if !keyed {
+            batch
+        } else if self.mode == ImportMode::Append {
+            let batch_keys: Vec<&[DataValue]> = batch.iter().map(|(_, row)| &row[..keys]).collect();
+            let first = unique_keys(&batch_keys, false);
+            let mut rows = Vec::with_capacity(first.len());
+            for (i, (line, row)) in batch.into_iter().enumerate() {
+                if first.contains(&i) {
+                    rows.push((line, row));
+                } else {
+                    self.reject(line, "duplicate key in the input".into());
+                }
+            }
+            let row_keys: Vec<&[DataValue]> = rows.iter().map(|(_, row)| &row[..keys]).collect();
+            let stored = existing(self.tx, self.relation, &present[..keys], &row_keys)?;
+            let mut kept = Vec::with_capacity(rows.len());
+            for (i, (line, row)) in rows.into_iter().enumerate() {
+                if stored.contains(&i) {
+                    self.reject(line, "key already exists".into());
+                } else {
+                    kept.push((line, row));
+                }
+            }
+            kept
+        } else {
+            // The last row for a key wins, as if each were written in turn.
+            let batch_keys: Vec<&[DataValue]> = batch.iter().map(|(_, row)| &row[..keys]).collect();
+            let last = unique_keys(&batch_keys, true);
+            self.summary.inserted += (batch.len() - last.len()) as u64;
+            batch.into_iter().enumerate().filter(|(i, _)| last.contains(i)).map(|(_, row)| row).collect()
+        };
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let names: Vec<&str> = present.iter().map(|c| c.name.as_str()).collect();
+        let spec = match keys {
+            _ if keys == names.len() => names.join(", "),
+            _ => format!("{} => {}", names[..keys].join(", "), names[keys..].join(", ")),
+        };
+        let mut script = format!("?[{}] <- $rows :put {} {{{}}}", names.join(", "), self.relation, spec);
+        if self.history.is_some() {
+            // The rows as stored, with their defaults filled in.
+            script.push_str(" :returning");
+        }
+        self.summary.inserted += rows.len() as u64;
+        let rows = rows.into_iter().map(|(_, row)| DataValue::List(row)).collect();
+        let written = self.tx.exec_bound(&script, BTreeMap::from([("rows".to_string(), DataValue::List(rows))]))?;
+        if let Some((history, at)) = &self.history {
+            let keys = self.columns.iter().filter(|c| c.key).count();
+            let rows = written
+                .rows
+                .into_iter()
+                .filter(|row| row.first().and_then(DataValue::get_str) == Some("inserted"))
+                .map(|row| {
+                    let mut entry = row[1..=keys].to_vec();
+                    entry.push(at.clone());
+                    entry.extend_from_slice(&row[keys + 1..]);
+                    DataValue::List(entry)
+                })
+                .collect();
+            let script = history_put(history, self.columns);
+            self.tx.exec_bound(&script, BTreeMap::from([("rows".to_string(), DataValue::List(rows))]))?;
+        }
+        Ok(())
+    }
+}
+
+/// The statement writing `$rows`, each a relation row with its timestamp
+/// after the keys, to the relation's `history`.
+pub(crate) fn history_put(history: &str, columns: &[Column]) -> String {
+    let keys: Vec<&str> = columns.iter().filter(|c| c.key).map(|c| c.name.as_str()).collect();
+    let values: Vec<&str> = columns.iter().filter(|c| !c.key).map(|c| c.name.as_str()).collect();
+    let mut spec = keys.join(", ");
+    spec.push_str(&format!(", {}", HISTORY_AT));
+    if !values.is_empty() {
+        spec.push_str(&format!(" => {}", values.join(", ")));
+    }
+    let head: Vec<&str> = keys.iter().copied().chain([HISTORY_AT]).chain(values.iter().copied()).collect();
+    format!("?[{}] <- $rows :put {} {{{}}}", head.join(", "), history, spec)
+}
+
+/// Positions in `keys` that are already stored in `relation`.
+pub(crate) fn existing(
+    tx: &mut CoreTransaction,
+    relation: &str,
+    columns: &[&Column],
+    keys: &[&[DataValue]],
+) -> Result<BTreeSet<usize>, CoreError> {
+    if keys.is_empty() {
+        return Ok(BTreeSet::new());
+    }
+    let vars: Vec<String> = (0..columns.len()).map(|i| format!("k{}", i)).collect();
+    let bindings: Vec<String> = columns.iter().zip(&vars).map(|(c, v)| format!("{}: {}", c.name, v)).collect();
+    let script = format!(
+        "input[i, {vars}] <- $keys ?[i] := input[i, {vars}], *{}{{{}}}",
+        relation,
+        bindings.join(", "),
+        vars = vars.join(", ")
+    );
+    let input = keys
+        .iter()
+        .enumerate()
+        .map(|(i, key)| {
+            let mut entry = vec![DataValue::from(i as i64)];
+            entry.extend_from_slice(key);
+            DataValue::List(entry)
+        })
+        .collect();
+    let found = tx.exec_bound(&script, BTreeMap::from([("keys".to_string(), DataValue::List(input))]))?;
+    Ok(found.rows.iter().filter_map(|row| row.first()?.get_int()).map(|i| i as usize).collect())
+}
+
+/// Positions in `keys` of the first, or the last, of each distinct key.
+pub(crate) fn unique_keys(keys: &[&[DataValue]], last: bool) -> BTreeSet<usize> {
+    let key = |i: usize| keys[i];
+    let mut order: Vec<usize> = (0..keys.len()).collect();
+    // Stable, so rows with the same key stay in input order.
+    order.sort_by(|&a, &b| key(a).cmp(key(b)));
+    order
+        .chunk_by(|&a, &b| key(a) == key(b))
+        .map(|same| if last { same[same.len() - 1] } else { same[0] })
+        .collect()
+}
+
+pub(crate) fn read_json_lines(reader: impl Read, import: &mut Import<'_>) -> Result<(), CoreError> {
+    let mut reader = BufReader::new(reader);
+    let mut buf = Vec::new();
+    let mut line = 0;
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf).map_err(|e| CoreError::Io(e.to_string()))? == 0 {
+            return Ok(());
+        }
+        line += 1;
+        if buf.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        match json_row(&buf, import.columns) {
+            Ok(row) => import.row(line, row)?,
+            Err(reason) => import.invalid(line, reason),
+        }
+    }
+}
+
+fn json_row(text: &[u8], columns: &[Column]) -> Result<Vec<Option<DataValue>>, String> {
+    match serde_json::from_slice(text) {
+        Ok(Value::Object(object)) => object_row(object, columns),
+        Ok(other) => Err(format!("expected an object, got {}", other)),
+        Err(e) => Err(format!("invalid JSON: {}", e)),
+    }
+}
+
+/// A row from an object keyed by column name. A missing column is null if
+/// nullable and `None`, for its default, if it has one.
+pub(crate) fn object_row(
+    mut object: serde_json::Map<String, Value>,
+    columns: &[Column],
+) -> Result<Vec<Option<DataValue>>, String> {
+    let row = columns
+        .iter()
+        .map(|column| match object.remove(&column.name) {
+            Some(value) => {
+                let value = to_data_value(value).map_err(|e| format!("{}: {}", column.name, e))?;
+                check(column, value).map(Some)
+            }
+            None if column.has_default => Ok(None),
+            None if column.nullable => Ok(Some(DataValue::Null)),
+            None => Err(format!("{}: missing", column.name)),
+        })
+        .collect::<Result<_, _>>()?;
+    match object.keys().next() {
+        Some(unknown) => Err(format!("{}: no such column", unknown)),
+        None => Ok(row),
+    }
+}
+
+fn read_csv(reader: impl Read, import: &mut Import<'_>) -> Result<(), CoreError> {
+    let mut csv = csv::Reader::from_reader(reader);
+    let header = csv.headers().map_err(csv_error)?.clone();
+
+    // Where each column's field is in a record, if it has one.
+    let mut fields: Vec<Option<usize>> = vec![None; import.columns.len()];
+    for (i, name) in header.iter().enumerate() {
+        let Some(column) = import.columns.iter().position(|c| c.name == name) else {
+            return Err(CoreError::InvalidImport(format!("the header names {}, which is not a column", name)));
+        };
+        if fields[column].replace(i).is_some() {
+            return Err(CoreError::InvalidImport(format!("the header names {} twice", name)));
+        }
+    }
+    for (column, field) in import.columns.iter().zip(&fields) {
+        if field.is_none() && !column.nullable && !column.has_default {
+            return Err(CoreError::InvalidImport(format!("the header leaves out {}, which needs a value", column.name)));
+        }
+    }
+
+    let mut record = csv::StringRecord::new();
+    loop {
+        match csv.read_record(&mut record) {
+            Ok(false) => return Ok(()),
+            Ok(true) => {
+                let line = record.position().map_or(0, |p| p.line());
+                let row = import
+                    .columns
+                    .iter()
+                    .zip(&fields)
+                    .map(|(column, field)| match field {
+                        Some(i) => from_csv(column, &record[*i]).map(Some),
+                        None if column.has_default => Ok(None),
+                        None => Ok(Some(DataValue::Null)),
+                    })
+                    .collect::<Result<_, _>>();
+                match row {
+                    Ok(row) => import.row(line, row)?,
+                    Err(reason) => import.invalid(line, reason),
+                }
+            }
+            Err(e) => match e.kind() {
+                csv::ErrorKind::Io(_) => return Err(csv_error(e)),
+                _ => {
+                    let line = e.position().map_or(0, |p| p.line());
+                    import.invalid(line, e.to_string());
+                }
+            },
+        }
+    }
+}
+
+/// Converts a CSV field by the rules in `import_relation`'s table.
+fn from_csv(column: &Column, field: &str) -> Result<DataValue, String> {
+    if field.is_empty() {
+        return match column.column_type {
+            _ if column.nullable => Ok(DataValue::Null),
+            Some(ColumnType::String) => Ok(DataValue::from("")),
+            _ => Err(format!("{}: empty", column.name)),
+        };
+    }
+    let trimmed = field.trim();
+    let invalid = || format!("{}: {:?} is not a valid {}", column.name, field, column.column_type.unwrap_or(ColumnType::Any));
+    let value = match column.column_type {
+        Some(ColumnType::Int) => DataValue::from(trimmed.parse::<i64>().map_err(|_| invalid())?),
+        Some(ColumnType::Float) => DataValue::from(trimmed.parse::<f64>().map_err(|_| invalid())?),
+        Some(ColumnType::Bool) => DataValue::from(parse_bool(trimmed).ok_or_else(invalid)?),
+        Some(ColumnType::String | ColumnType::Bytes | ColumnType::Uuid) => DataValue::from(field),
+        Some(ColumnType::Any) => {
+            if let Ok(i) = trimmed.parse::<i64>() {
+                DataValue::from(i)
+            } else if let Some(f) = trimmed.parse::<f64>().ok().filter(|f| f.is_finite()) {
+                DataValue::from(f)
+            } else if trimmed.eq_ignore_ascii_case("true") || trimmed.eq_ignore_ascii_case("false") {
+                DataValue::from(trimmed.eq_ignore_ascii_case("true"))
+            } else {
+                DataValue::from(field)
+            }
+        }
+        Some(ColumnType::Json | ColumnType::Validity | ColumnType::Vector(_)) | None => {
+            let json: Value = serde_json::from_str(field).map_err(|e| format!("{}: invalid JSON: {}", column.name, e))?;
+            to_data_value(json).map_err(|e| format!("{}: {}", column.name, e))?
+        }
+    };
+    check(column, value)
+}
+
+fn parse_bool(text: &str) -> Option<bool> {
+    match text.to_ascii_lowercase().as_str() {
+        "true" | "1" => Some(true),
+        "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Checks `value` against the column's type, converting it to the form the
+/// engine stores. The engine would fail the whole statement on a bad value,
+/// which aborts the import, so declarable types are checked here and only
+/// the row is rejected.
+pub(crate) fn check(column: &Column, value: DataValue) -> Result<DataValue, String> {
+    if matches!(value, DataValue::Null) {
+        return if column.nullable {
+            Ok(value)
+        } else {
+            Err(format!("{}: cannot be null", column.name))
+        };
+    }
+    let Some(column_type) = column.column_type else {
+        return Ok(value);
+    };
+    let mismatch = |value: &DataValue| format!("{}: expected {}, got {}", column.name, column_type, Value::from(value.clone()));
+    Ok(match column_type {
+        ColumnType::Int => DataValue::from(value.get_int().ok_or_else(|| mismatch(&value))?),
+        ColumnType::Float => DataValue::from(value.get_float().ok_or_else(|| mismatch(&value))?),
+        ColumnType::Bool => DataValue::from(value.get_bool().ok_or_else(|| mismatch(&value))?),
+        ColumnType::String if matches!(value, DataValue::Str(_)) => value,
+        ColumnType::Bytes => match &value {
+            DataValue::Str(s) => {
+                let bytes = STANDARD.decode(s.as_bytes()).map_err(|e| format!("{}: not base64: {}", column.name, e))?;
+                DataValue::Bytes(bytes)
+            }
+            _ => return Err(mismatch(&value)),
+        },
+        ColumnType::Uuid => match value.get_str().map(uuid::Uuid::try_parse) {
+            Some(Ok(uuid)) => DataValue::Uuid(UuidWrapper(uuid)),
+            _ => return Err(mismatch(&value)),
+        },
+        ColumnType::Vector(dim) => {
+            let DataValue::List(components) = &value else {
+                return Err(mismatch(&value));
+            };
+            if components.len() != dim {
+                return Err(format!("{}: expected {} components, got {}", column.name, dim, components.len()));
+            }
+            if !components.iter().all(|c| c.get_float().is_some_and(f64::is_finite)) {
+                return Err(format!("{}: components must be finite numbers", column.name));
+            }
+            value
+        }
+        ColumnType::String => return Err(mismatch(&value)),
+        ColumnType::Any | ColumnType::Json | ColumnType::Validity => value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CoreConfig;
+    use serde_json::json;
+
+    const SCHEMA: &str = "{id: Int => name: String, score: Float, ok: Bool, blob: Bytes, tag: Uuid, extra: Json, any: Any?}";
+
+    fn core() -> CognitiveCore {
+        let core = CognitiveCore::new(CoreConfig::default()).unwrap();
+        for relation in ["source", "copy"] {
+            core.run(&format!(":create {} {}", relation, SCHEMA), Value::Null).unwrap();
+        }
+        core
+    }
+
+    fn all_rows(core: &CognitiveCore, relation: &str) -> Value {
+        let query = format!("?[id, name, score, ok, blob, tag, extra, any] := *{}{{id, name, score, ok, blob, tag, extra, any}}", relation);
+        core.run(&query, Value::Null).unwrap()["rows"].clone()
+    }
+
+    fn export(core: &CognitiveCore, relation: &str, format: DataFormat) -> Vec<u8> {
+        let mut out = Vec::new();
+        core.export_relation(relation, format, &mut out).unwrap();
+        out
+    }
+
+    fn import(core: &CognitiveCore, relation: &str, format: DataFormat, data: &str, mode: ImportMode) -> ImportSummary {
+        core.import_relation(relation, format, data.as_bytes(), mode).unwrap()
+    }
+
+    fn column(column_type: ColumnType, nullable: bool) -> Column {
+        Column {
+            name: "c".into(),
+            column_type: Some(column_type),
+            nullable,
+            key: false,
+            has_default: false,
+        }
+    }
+
+    #[test]
+    fn round_trips_a_relation_through_both_formats() {
+        let core = core();
+        // Enough rows to take several batches and export pages.
+        let rows = BATCH_ROWS * 2 + 7;
+        core.run(
+            "?[id, name, score, ok, blob, tag, extra, any] := id in int_range($n), name = concat('row, \"', to_string(id), '\"\nend'),
+               score = id / 3, ok = id % 2 == 0, blob = decode_base64('aGVsbG8AAQ=='), tag = rand_uuid_v4(), extra = json({'id': id}),
+               any = if(id % 3 == 0, null, [id, 'x'])
+             :put source {id => name, score, ok, blob, tag, extra, any}",
+            json!({ "n": rows }),
+        )
+        .unwrap();
+        let expected = all_rows(&core, "source");
+        assert_eq!(expected.as_array().unwrap().len(), rows);
+
+        let jsonl = export(&core, "source", DataFormat::JsonLines);
+        assert_eq!(jsonl.iter().filter(|b| **b == b'\n').count(), rows);
+        let summary = import(&core, "copy", DataFormat::JsonLines, std::str::from_utf8(&jsonl).unwrap(), ImportMode::Replace);
+        assert_eq!((summary.rows_read, summary.inserted, summary.rejected), (rows as u64, rows as u64, 0));
+        assert_eq!(all_rows(&core, "copy"), expected);
+
+        // CSV reads a list in an `Any` column back as text, so leave those out.
+        core.run("?[id, any] := *source{id, any: old}, is_list(old), any = null :update source {id => any}", Value::Null).unwrap();
+        let expected = all_rows(&core, "source");
+        let csv = export(&core, "source", DataFormat::Csv);
+        let summary = import(&core, "copy", DataFormat::Csv, std::str::from_utf8(&csv).unwrap(), ImportMode::Replace);
+        assert_eq!((summary.inserted, summary.rejected), (rows as u64, 0));
+        assert_eq!(all_rows(&core, "copy"), expected);
+        assert_eq!(export(&core, "copy", DataFormat::Csv), csv);
+    }
+
+    #[test]
+    fn converts_csv_fields_by_column_type() {
+        let convert = |column_type, nullable, field: &str| from_csv(&column(column_type, nullable), field);
+        assert_eq!(convert(ColumnType::Int, false, " -42 "), Ok(DataValue::from(-42)));
+        assert!(convert(ColumnType::Int, false, "4.2").is_err());
+        assert!(convert(ColumnType::Int, false, "9223372036854775808").is_err());
+        assert_eq!(convert(ColumnType::Float, false, "1e3"), Ok(DataValue::from(1000.0)));
+        assert_eq!(convert(ColumnType::Float, false, "inf"), Ok(DataValue::from(f64::INFINITY)));
+        assert!(convert(ColumnType::Float, false, "NaN").unwrap().get_float().unwrap().is_nan());
+        for (field, expected) in [("TRUE", true), ("1", true), ("false", false), (" 0 ", false)] {
+            assert_eq!(convert(ColumnType::Bool, false, field), Ok(DataValue::from(expected)));
+        }
+        assert!(convert(ColumnType::Bool, false, "yes").is_err());
+        assert_eq!(convert(ColumnType::String, false, " as is "), Ok(DataValue::from(" as is ")));
+        assert_eq!(convert(ColumnType::String, false, ""), Ok(DataValue::from("")));
+        assert_eq!(convert(ColumnType::Bytes, false, "aGk="), Ok(DataValue::Bytes(b"hi".to_vec())));
+        assert!(convert(ColumnType::Bytes, false, "not base64!").is_err());
+        let uuid = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        assert_eq!(convert(ColumnType::Uuid, false, uuid), Ok(DataValue::Uuid(UuidWrapper(uuid::Uuid::parse_str(uuid).unwrap()))));
+        assert!(convert(ColumnType::Uuid, false, "67e55044").is_err());
+        assert_eq!(convert(ColumnType::Any, true, "7"), Ok(DataValue::from(7)));
+        assert_eq!(convert(ColumnType::Any, true, "7.5"), Ok(DataValue::from(7.5)));
+        assert_eq!(convert(ColumnType::Any, true, "True"), Ok(DataValue::from(true)));
+        assert_eq!(convert(ColumnType::Any, true, "inf"), Ok(DataValue::from("inf")));
+        assert_eq!(convert(ColumnType::Any, true, ""), Ok(DataValue::Null));
+        assert_eq!(convert(ColumnType::Json, false, r#"{"a":[1]}"#), Ok(DataValue::Json(cozo::JsonData(json!({"a": [1]})))));
+        assert!(convert(ColumnType::Json, false, "{").is_err());
+        assert!(convert(ColumnType::Int, false, "").is_err());
+        assert_eq!(convert(ColumnType::Int, true, ""), Ok(DataValue::Null));
+    }
+
+    #[test]
+    fn rejects_malformed_rows_and_keeps_the_rest() {
+        let core = CognitiveCore::new(CoreConfig::default()).unwrap();
+        core.run(":create people {id: Int => name: String, age: Int?}", Value::Null).unwrap();
+        let jsonl = [
+            r#"{"id": 1, "name": "ada", "age": 36}"#,
+            r#"{"id": "two", "name": "alan"}"#,
+            r#"{"id": 3}"#,
+            r#"{"id": 4, "name": "grace", "height": 1.5}"#,
+            r#"{"id": 5, "name": "#,
+            r#"[6, "array"]"#,
+            "",
+            r#"{"id": 7, "name": "edsger"}"#,
+        ]
+        .join("\n");
+        let summary = import(&core, "people", DataFormat::JsonLines, &jsonl, ImportMode::Append);
+        assert_eq!((summary.rows_read, summary.inserted, summary.rejected), (7, 2, 5));
+        let lines: Vec<u64> = summary.rejects.iter().map(|r| r.line).collect();
+        assert_eq!(lines, [2, 3, 4, 5, 6]);
+        assert!(summary.rejects[0].reason.starts_with("id: "), "{}", summary.rejects[0].reason);
+        assert_eq!(summary.rejects[1].reason, "name: missing");
+        assert_eq!(summary.rejects[2].reason, "height: no such column");
+
+        let csv = "id,name\n8,barbara\nnine,x\n10\n11,\"frances\"\n";
+        let summary = import(&core, "people", DataFormat::Csv, csv, ImportMode::Append);
+        assert_eq!((summary.inserted, summary.rejected), (2, 2));
+        assert_eq!(summary.rejects.iter().map(|r| r.line).collect::<Vec<_>>(), [3, 4]);
+
+        let ids = core.run("?[id] := *people{id}", Value::Null).unwrap()["rows"].clone();
+        assert_eq!(ids, json!([[1], [7], [8], [11]]));
+
+        // Only the first rejects are described; the rest are counted.
+        let many: String = (0..MAX_REPORTED_REJECTS + 5).map(|_| "{\"id\": null}\n").collect();
+        let summary = import(&core, "people", DataFormat::JsonLines, &many, ImportMode::Append);
+        assert_eq!((summary.rejected, summary.rejects.len()), (MAX_REPORTED_REJECTS as u64 + 5, MAX_REPORTED_REJECTS));
+
+        let err = core.import_relation("people", DataFormat::Csv, "id,nickname\n1,x\n".as_bytes(), ImportMode::Append).unwrap_err();
+        assert!(matches!(err, CoreError::InvalidImport(_)), "{:?}", err);
+        let err = core.import_relation("people", DataFormat::Csv, "name\nx\n".as_bytes(), ImportMode::Append).unwrap_err();
+        assert!(matches!(err, CoreError::InvalidImport(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn modes_decide_what_happens_to_existing_rows() {
+        let core = CognitiveCore::new(CoreConfig::default()).unwrap();
+        core.run(":create kv {k: Int => v: String}", Value::Null).unwrap();
+        let contents = |core: &CognitiveCore| core.run("?[k, v] := *kv{k, v}", Value::Null).unwrap()["rows"].clone();
+        import(&core, "kv", DataFormat::Csv, "k,v\n1,a\n2,b\n", ImportMode::Append);
+
+        let summary = import(&core, "kv", DataFormat::Csv, "k,v\n2,changed\n3,c\n", ImportMode::Append);
+        assert_eq!((summary.inserted, summary.rejected), (1, 1));
+        assert_eq!(contents(&core), json!([[1, "a"], [2, "b"], [3, "c"]]));
+
+        import(&core, "kv", DataFormat::Csv, "k,v\n2,changed\n", ImportMode::Upsert);
+        assert_eq!(contents(&core), json!([[1, "a"], [2, "changed"], [3, "c"]]));
+
+        import(&core, "kv", DataFormat::Csv, "k,v\n9,z\n", ImportMode::Replace);
+        assert_eq!(contents(&core), json!([[9, "z"]]));
+    }
+}
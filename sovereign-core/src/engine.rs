@@ -0,0 +1,492 @@
+use crate::storage::SqliteStore;
+use cozo::{
+    decode_tuple_from_kv, CallbackOp, DataValue, Db, MemStorage, MultiTransaction, NamedRows, ScriptMutability, Storage,
+    StoreTx, ValidityTs,
+};
+use crossbeam_channel::Receiver;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// What a write gets while another one holds the writer. `CoreError`
+/// sorts it as a conflict, which the core waits out.
+pub(crate) const WRITER_BUSY: &str = "another write holds the core's writer";
+
+/// The engine, over a store that lets reads run beside a write.
+///
+/// The engine's own mem and SQLite stores hold every other access off for
+/// as long as a write transaction is open, which for a `CoreTransaction`
+/// is as long as its caller likes. Here a write instead stages its changes
+/// over a read of the committed state, and takes the store only to apply
+/// them at commit. Reads see committed state throughout. One write runs at
+/// a time; another is failed with `WRITER_BUSY` rather than blocked, since
+/// the engine would block it holding locks the running one may need.
+///
+/// RocksDB already reads from snapshots, and is used as it is.
+#[derive(Clone)]
+pub(crate) struct Engine {
+    db: Layout,
+    slot: Arc<WriteSlot>,
+}
+
+#[derive(Clone)]
+enum Layout {
+    Mem(Db<Layered<MemStorage>>),
+    Sqlite(Db<Layered<SqliteStore>>),
+    #[cfg(feature = "rocksdb")]
+    RocksDb(Db<cozo::RocksDbStorage>),
+}
+
+macro_rules! each {
+    ($engine:expr, $db:ident => $body:expr) => {
+        match &$engine.db {
+            Layout::Mem($db) => $body,
+            Layout::Sqlite($db) => $body,
+            #[cfg(feature = "rocksdb")]
+            Layout::RocksDb($db) => $body,
+        }
+    };
+}
+
+impl Engine {
+    pub(crate) fn mem() -> Result<Self, cozo::Error> {
+        let slot = Arc::new(WriteSlot::default());
+        Ok(Self {
+            db: Layout::Mem(layered(MemStorage::default(), &slot)?),
+            slot,
+        })
+    }
+
+    pub(crate) fn sqlite(path: &Path) -> Result<Self, cozo::Error> {
+        let slot = Arc::new(WriteSlot::default());
+        Ok(Self {
+            db: Layout::Sqlite(layered(SqliteStore::open(path)?, &slot)?),
+            slot,
+        })
+    }
+
+    #[cfg(feature = "rocksdb")]
+    pub(crate) fn rocksdb(path: &Path) -> Result<Self, cozo::Error> {
+        Ok(Self {
+            db: Layout::RocksDb(cozo::new_cozo_rocksdb(path)?),
+            slot: Arc::default(),
+        })
+    }
+
+    pub(crate) fn run_script(
+        &self,
+        script: &str,
+        params: BTreeMap<String, DataValue>,
+        mutability: ScriptMutability,
+    ) -> Result<NamedRows, cozo::Error> {
+        each!(self, db => db.run_script(script, params, mutability))
+    }
+
+    /// A write transaction, run on a thread of its own. It takes the
+    /// writer when it starts, and if that fails its first reply is the
+    /// failure.
+    pub(crate) fn multi_transaction(&self) -> MultiTransaction {
+        let (sender, payloads) = crossbeam_channel::bounded(1);
+        let (replies, receiver) = crossbeam_channel::bounded(1);
+        let engine = self.clone();
+        std::thread::spawn(move || each!(engine, db => db.run_multi_transaction(true, payloads, replies)));
+        MultiTransaction { sender, receiver }
+    }
+
+    pub(crate) fn register_callback(&self, relation: &str) -> (u32, Receiver<(CallbackOp, NamedRows, NamedRows)>) {
+        each!(self, db => db.register_callback(relation, None))
+    }
+
+    pub(crate) fn unregister_callback(&self, id: u32) -> bool {
+        each!(self, db => db.unregister_callback(id))
+    }
+
+    /// Waits, for at most `up_to`, for the writer to be free. A conflict
+    /// it did not cause, such as another process holding SQLite's lock, is
+    /// waited out for all of `up_to`.
+    pub(crate) fn wait_for_writer(&self, up_to: Duration) {
+        let taken = self.slot.taken.lock().unwrap_or_else(|e| e.into_inner());
+        if *taken {
+            let _ = self.slot.freed.wait_timeout_while(taken, up_to, |taken| *taken);
+        } else {
+            drop(taken);
+            std::thread::sleep(up_to);
+        }
+    }
+}
+
+fn layered<B: Base>(base: B, slot: &Arc<WriteSlot>) -> Result<Db<Layered<B>>, cozo::Error> {
+    let db = Db::new(Layered {
+        base,
+        slot: slot.clone(),
+    })?;
+    db.initialize()?;
+    Ok(db)
+}
+
+/// The one write the engine runs at a time.
+#[derive(Default)]
+struct WriteSlot {
+    taken: Mutex<bool>,
+    freed: Condvar,
+}
+
+impl WriteSlot {
+    fn take(&self) -> Result<SlotGuard<'_>, cozo::Error> {
+        let mut taken = self.taken.lock().unwrap_or_else(|e| e.into_inner());
+        if *taken {
+            return Err(cozo::Error::msg(WRITER_BUSY));
+        }
+        *taken = true;
+        Ok(SlotGuard(self))
+    }
+}
+
+struct SlotGuard<'a>(&'a WriteSlot);
+
+impl Drop for SlotGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.taken.lock().unwrap_or_else(|e| e.into_inner()) = false;
+        self.0.freed.notify_all();
+    }
+}
+
+/// A store a write can be staged over.
+pub(crate) trait Base: Clone + Send + Sync + 'static {
+    /// Reads committed state, and holds off `apply` until dropped.
+    type Reader<'s>: StoreTx<'s>
+    where
+        Self: 's;
+
+    fn kind(&self) -> &'static str;
+
+    fn read(&self) -> Result<Self::Reader<'_>, cozo::Error>;
+
+    /// Applies a write's changes all together, or none of them.
+    fn apply(&self, changes: &Staged) -> Result<(), cozo::Error>;
+}
+
+impl Base for MemStorage {
+    type Reader<'s> = <MemStorage as Storage<'s>>::Tx;
+
+    fn kind(&self) -> &'static str {
+        "mem"
+    }
+
+    fn read(&self) -> Result<Self::Reader<'_>, cozo::Error> {
+        self.transact(false)
+    }
+
+    fn apply(&self, changes: &Staged) -> Result<(), cozo::Error> {
+        let mut tx = self.transact(true)?;
+        for (lower, upper) in &changes.cleared {
+            tx.del_range_from_persisted(lower, upper)?;
+        }
+        for (key, value) in &changes.writes {
+            match value {
+                Some(value) => tx.put(key, value)?,
+                None => tx.del(key)?,
+            }
+        }
+        tx.commit()
+    }
+}
+
+/// A write's changes: key ranges cleared, then keys written, `None` for
+/// a delete.
+#[derive(Default)]
+pub(crate) struct Staged {
+    pub(crate) cleared: Vec<(Vec<u8>, Vec<u8>)>,
+    pub(crate) writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Staged {
+    fn clears(&self, key: &[u8]) -> bool {
+        self.cleared.iter().any(|(lower, upper)| key >= lower.as_slice() && key < upper.as_slice())
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Layered<B> {
+    base: B,
+    slot: Arc<WriteSlot>,
+}
+
+impl<'s, B: Base> Storage<'s> for Layered<B> {
+    type Tx = LayeredTx<'s, B>;
+
+    fn storage_kind(&self) -> &'static str {
+        self.base.kind()
+    }
+
+    fn transact(&'s self, write: bool) -> Result<Self::Tx, cozo::Error> {
+        if !write {
+            return Ok(LayeredTx::Reader(self.base.read()?));
+        }
+        let slot = self.slot.take()?;
+        Ok(LayeredTx::Writer(Writer {
+            base: &self.base,
+            snapshot: Some(self.base.read()?),
+            staged: Staged::default(),
+            _slot: slot,
+        }))
+    }
+
+    fn range_compact(&'s self, _lower: &[u8], _upper: &[u8]) -> Result<(), cozo::Error> {
+        Ok(())
+    }
+
+    fn batch_put<'a>(
+        &'a self,
+        data: Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>), cozo::Error>> + 'a>,
+    ) -> Result<(), cozo::Error> {
+        let mut tx = self.transact(true)?;
+        for pair in data {
+            let (key, value) = pair?;
+            tx.put(&key, &value)?;
+        }
+        tx.commit()
+    }
+}
+
+pub(crate) enum LayeredTx<'s, B: Base + 's> {
+    Reader(B::Reader<'s>),
+    Writer(Writer<'s, B>),
+}
+
+/// A write: its changes, over what was committed when it started, which
+/// stays current since no other write can commit meanwhile.
+pub(crate) struct Writer<'s, B: Base + 's> {
+    base: &'s B,
+    /// Dropped at commit, which the base waits for.
+    snapshot: Option<B::Reader<'s>>,
+    staged: Staged,
+    _slot: SlotGuard<'s>,
+}
+
+impl<'s, B: Base> Writer<'s, B> {
+    fn snapshot(&self) -> Result<&B::Reader<'s>, cozo::Error> {
+        self.snapshot.as_ref().ok_or_else(|| cozo::Error::msg("read after commit"))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, cozo::Error> {
+        match self.staged.writes.get(key) {
+            Some(value) => Ok(value.clone()),
+            None if self.staged.clears(key) => Ok(None),
+            None => self.snapshot()?.get(key, false),
+        }
+    }
+
+    fn range_scan<'a>(&'a self, lower: &[u8], upper: &[u8]) -> Rows<'a>
+    where
+        's: 'a,
+    {
+        let snapshot = match self.snapshot() {
+            Ok(snapshot) => snapshot,
+            Err(e) => return Box::new(std::iter::once(Err(e))),
+        };
+        if lower >= upper {
+            return Box::new(std::iter::empty());
+        }
+        let staged = self.staged.writes.range(lower.to_vec()..upper.to_vec());
+        Box::new(merge(self, snapshot.range_scan(lower, upper), staged))
+    }
+
+    fn total_scan<'a>(&'a self) -> Rows<'a>
+    where
+        's: 'a,
+    {
+        match self.snapshot() {
+            Ok(snapshot) => Box::new(merge(self, snapshot.total_scan(), self.staged.writes.iter())),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+}
+
+/// Key and value rows, as the engine's stores scan them.
+pub(crate) type Rows<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>), cozo::Error>> + 'a>;
+
+/// `committed` with a write's changes over it, in key order.
+fn merge<'a, B: Base, S>(writer: &'a Writer<'_, B>, committed: Rows<'a>, staged: S) -> Merged<'a, S>
+where
+    S: Iterator<Item = (&'a Vec<u8>, &'a Option<Vec<u8>>)>,
+{
+    let staged_changes = &writer.staged;
+    let committed: Rows<'a> = Box::new(committed.filter(move |row| match row {
+        Ok((key, _)) => !staged_changes.clears(key),
+        Err(_) => true,
+    }));
+    Merged {
+        committed: committed.peekable(),
+        staged: staged.peekable(),
+    }
+}
+
+struct Merged<'a, S: Iterator> {
+    committed: Peekable<Rows<'a>>,
+    staged: Peekable<S>,
+}
+
+impl<'a, S> Iterator for Merged<'a, S>
+where
+    S: Iterator<Item = (&'a Vec<u8>, &'a Option<Vec<u8>>)>,
+{
+    type Item = Result<(Vec<u8>, Vec<u8>), cozo::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let order = match (self.committed.peek(), self.staged.peek()) {
+                (None, None) => return None,
+                (Some(Err(_)), _) | (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(Ok((committed, _))), Some((staged, _))) => committed.cmp(staged),
+            };
+            match order {
+                Ordering::Less => return self.committed.next(),
+                // Overwritten or deleted by the write.
+                Ordering::Equal => {
+                    self.committed.next();
+                }
+                Ordering::Greater => {}
+            }
+            if let Some((key, Some(value))) = self.staged.next() {
+                return Some(Ok((key.clone(), value.clone())));
+            }
+        }
+    }
+}
+
+/// The engine's time-travel scan over `rows`, which hold keys ending in
+/// their validity: for each key, its newest version valid at `valid_at`,
+/// if that version is an assertion.
+pub(crate) fn skip_scan<'a>(
+    rows: Rows<'a>,
+    valid_at: ValidityTs,
+) -> Box<dyn Iterator<Item = Result<Vec<DataValue>, cozo::Error>> + 'a> {
+    let mut decided: Option<Vec<DataValue>> = None;
+    Box::new(rows.filter_map(move |row| {
+        let (key, value) = match row {
+            Ok(row) => row,
+            Err(e) => return Some(Err(e)),
+        };
+        let mut prefix = decode_tuple_from_kv(&key, &[], None);
+        let Some(DataValue::Validity(validity)) = prefix.pop() else {
+            return Some(Err(cozo::Error::msg("time travel on a relation without validity")));
+        };
+        // Versions sort newest first, so the first one not after
+        // `valid_at` decides the key.
+        if decided.as_ref() == Some(&prefix) || validity.timestamp < valid_at {
+            return None;
+        }
+        decided = Some(prefix);
+        validity.is_assert.0.then(|| Ok(decode_tuple_from_kv(&key, &value, None)))
+    }))
+}
+
+impl<'s, B: Base> StoreTx<'s> for LayeredTx<'s, B> {
+    fn get(&self, key: &[u8], for_update: bool) -> Result<Option<Vec<u8>>, cozo::Error> {
+        match self {
+            LayeredTx::Reader(reader) => reader.get(key, for_update),
+            LayeredTx::Writer(writer) => writer.get(key),
+        }
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), cozo::Error> {
+        match self {
+            LayeredTx::Reader(reader) => reader.put(key, value),
+            LayeredTx::Writer(writer) => {
+                writer.staged.writes.insert(key.to_vec(), Some(value.to_vec()));
+                Ok(())
+            }
+        }
+    }
+
+    fn supports_par_put(&self) -> bool {
+        false
+    }
+
+    fn del(&mut self, key: &[u8]) -> Result<(), cozo::Error> {
+        match self {
+            LayeredTx::Reader(reader) => reader.del(key),
+            LayeredTx::Writer(writer) => {
+                writer.staged.writes.insert(key.to_vec(), None);
+                Ok(())
+            }
+        }
+    }
+
+    fn del_range_from_persisted(&mut self, lower: &[u8], upper: &[u8]) -> Result<(), cozo::Error> {
+        match self {
+            LayeredTx::Reader(reader) => reader.del_range_from_persisted(lower, upper),
+            LayeredTx::Writer(writer) => {
+                if lower < upper {
+                    writer.staged.writes.retain(|key, _| key.as_slice() < lower || key.as_slice() >= upper);
+                    writer.staged.cleared.push((lower.to_vec(), upper.to_vec()));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn exists(&self, key: &[u8], for_update: bool) -> Result<bool, cozo::Error> {
+        match self {
+            LayeredTx::Reader(reader) => reader.exists(key, for_update),
+            LayeredTx::Writer(writer) => Ok(writer.get(key)?.is_some()),
+        }
+    }
+
+    fn commit(&mut self) -> Result<(), cozo::Error> {
+        match self {
+            LayeredTx::Reader(reader) => reader.commit(),
+            LayeredTx::Writer(writer) => {
+                writer.snapshot = None;
+                writer.base.apply(&std::mem::take(&mut writer.staged))
+            }
+        }
+    }
+
+    fn range_skip_scan_tuple<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+        valid_at: ValidityTs,
+    ) -> Box<dyn Iterator<Item = Result<Vec<DataValue>, cozo::Error>> + 'a> {
+        match self {
+            LayeredTx::Reader(reader) => reader.range_skip_scan_tuple(lower, upper, valid_at),
+            LayeredTx::Writer(writer) => skip_scan(writer.range_scan(lower, upper), valid_at),
+        }
+    }
+
+    fn range_scan<'a>(&'a self, lower: &[u8], upper: &[u8]) -> Rows<'a>
+    where
+        's: 'a,
+    {
+        match self {
+            LayeredTx::Reader(reader) => reader.range_scan(lower, upper),
+            LayeredTx::Writer(writer) => writer.range_scan(lower, upper),
+        }
+    }
+
+    fn range_count<'a>(&'a self, lower: &[u8], upper: &[u8]) -> Result<usize, cozo::Error>
+    where
+        's: 'a,
+    {
+        match self {
+            LayeredTx::Reader(reader) => reader.range_count(lower, upper),
+            LayeredTx::Writer(writer) => writer.range_scan(lower, upper).try_fold(0, |count, row| row.map(|_| count + 1)),
+        }
+    }
+
+    fn total_scan<'a>(&'a self) -> Rows<'a>
+    where
+        's: 'a,
+    {
+        match self {
+            LayeredTx::Reader(reader) => reader.total_scan(),
+            LayeredTx::Writer(writer) => writer.total_scan(),
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use crate::engine::WRITER_BUSY;
+use std::fmt;
+use std::time::Duration;
+
+/// Why a core query failed.
+#[derive(Debug, Clone)]
+pub enum CoreError {
+    /// The engine failed the query for a reason not covered below; see
+    /// `QueryError` for where and why.
+    Query(QueryError),
+    /// The query is not valid CozoScript, or misuses it, e.g. an unknown
+    /// function or a rule head that does not match its body.
+    Parse(QueryError),
+    /// No stored relation has this name.
+    UnknownRelation(String),
+    /// A value that does not fit its column's type. `detail` is the
+    /// engine's, naming the type and the value.
+    TypeMismatch { relation: Option<String>, detail: String },
+    /// A query run read-only tried to write.
+    ReadOnlyViolation(String),
+    /// The storage backend failed.
+    Storage(String),
+    /// The store was locked by another writer; retrying may succeed. The
+    /// core's own queries wait and retry rather than fail with this.
+    TransactionConflict(String),
+    /// `params` must be a JSON object (or null for none) of convertible values.
+    InvalidParams(String),
+    /// The query uses `$name` but `params` has no `name`.
+    MissingParam(String),
+    /// The query ran past its timeout and was abandoned.
+    Timeout { elapsed: Duration },
+    /// The query's cancel token fired.
+    Cancelled,
+    /// The query could not be run or waited for.
+    Interrupted(String),
+    /// Another transaction is open; the core runs one at a time.
+    TransactionOpen,
+    /// A statement in the transaction failed, so it can only be rolled back.
+    TransactionAborted(String),
+    /// A relation or column name that is not a plain identifier.
+    InvalidName(String),
+    InvalidSchema(String),
+    /// `drop_relation` without `force` on a relation that holds rows.
+    RelationNotEmpty { relation: String, rows: u64 },
+    /// Reading import data or writing export data failed.
+    Io(String),
+    /// The import data does not fit the relation as a whole, e.g. a CSV
+    /// header naming an unknown column.
+    InvalidImport(String),
+    /// An `assert_facts` row or `retract_facts` key, by position, that does
+    /// not fit the relation.
+    InvalidRow { index: usize, reason: String },
+    /// No query is registered under this name.
+    UnknownNamedQuery(String),
+    /// A migration failed and was rolled back; earlier ones stay applied.
+    Migration { version: u32, name: String, reason: String },
+    /// A vector whose size is not the one its column declares.
+    VectorDimension { expected: usize, got: usize },
+    /// A vector with a non-finite component, or a column that holds none.
+    InvalidVector(String),
+    /// `audit_tail` on a core configured without an audit sink.
+    AuditDisabled,
+    /// A query confined to `namespace` named another namespace's relation,
+    /// wrote a shared one, applied `fixed_rule`, which reaches outside the
+    /// store, or (both `None`) was a system op.
+    NamespaceDenied { namespace: String, relation: Option<String>, fixed_rule: Option<String> },
+    /// `principal` has grants, and none lets it read, or if `written` write,
+    /// `relation`, or apply `fixed_rule`, which reaches outside the store;
+    /// with both `None` the query was a system op.
+    GrantDenied { principal: String, relation: Option<String>, written: bool, fixed_rule: Option<String> },
+    /// The result passed a configured cap, e.g. `1000000 rows`, and was
+    /// dropped rather than returned; `hint` says how to get it anyway.
+    ResultTooLarge { limit: String, hint: String },
+}
+
+/// The engine's diagnostic for a failed query.
+#[derive(Debug, Clone)]
+pub struct QueryError {
+    /// Engine error code, e.g. `parser::pest` or `query::relation_not_found`.
+    pub code: Option<String>,
+    pub message: String,
+    pub help: Option<String>,
+    /// 1-based line and column in the query that the engine pointed at.
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+/// Engine codes for a query that parses but cannot be compiled, which
+/// are reported as `CoreError::Parse` like a syntax error.
+const MISUSES: &[&str] = &["eval::no_implementation", "eval::unbound_symb_in_head"];
+
+impl CoreError {
+    /// Sorts an engine failure by its error code, or for the engine's
+    /// uncoded errors by their message.
+    pub(crate) fn from_report(report: &cozo::Error, query: &str) -> Self {
+        let code = report.code().map(|code| code.to_string()).unwrap_or_default();
+        let message = report.to_string();
+        match code.as_str() {
+            "parser::param_not_found" => {
+                // The engine labels the `$name` reference itself.
+                let name = report
+                    .labels()
+                    .and_then(|mut labels| labels.next())
+                    .and_then(|label| query.get(label.offset()..label.offset() + label.len()))
+                    .and_then(|text| text.strip_prefix('$'));
+                match name {
+                    Some(name) => CoreError::MissingParam(name.to_string()),
+                    None => CoreError::Parse(QueryError::from_report(report, query)),
+                }
+            }
+            code if code.starts_with("parser::") || MISUSES.contains(&code) => {
+                CoreError::Parse(QueryError::from_report(report, query))
+            }
+            "query::relation_not_found" => CoreError::UnknownRelation(quoted(&message).unwrap_or(&message).to_string()),
+            "eval::stored_relation_not_found" => {
+                let name = message.strip_prefix("Stored relation ").and_then(|m| m.strip_suffix(" not found"));
+                CoreError::UnknownRelation(name.unwrap_or(&message).to_string())
+            }
+            code if code.starts_with("eval::coercion_") || code == "eval::col_type_mismatch" => CoreError::TypeMismatch {
+                relation: message
+                    .strip_prefix("when executing against relation ")
+                    .and_then(quoted)
+                    .map(str::to_string),
+                detail: report.chain().last().map_or(message.clone(), |cause| cause.to_string()),
+            },
+            "" if message == "write lock required for read-only query" || message.ends_with("in read-only mode") => {
+                CoreError::ReadOnlyViolation(message)
+            }
+            // SQLite's errors end with their result code; 5 and 6 are
+            // SQLITE_BUSY and SQLITE_LOCKED.
+            "" if message.ends_with("(code 5)") || message.ends_with("(code 6)") => CoreError::TransactionConflict(message),
+            "" if message.contains("Resource busy") || message == WRITER_BUSY => CoreError::TransactionConflict(message),
+            "" if message.ends_with(')') && message.contains("(code ") => CoreError::Storage(message),
+            _ => CoreError::Query(QueryError::from_report(report, query)),
+        }
+    }
+
+    /// A short name for the kind of failure, e.g. `parse` or `timeout`,
+    /// that quotes nothing of the query.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CoreError::Query(_) => "query",
+            CoreError::Parse(_) => "parse",
+            CoreError::UnknownRelation(_) => "unknown_relation",
+            CoreError::TypeMismatch { .. } => "type_mismatch",
+            CoreError::ReadOnlyViolation(_) => "read_only_violation",
+            CoreError::Storage(_) => "storage",
+            CoreError::TransactionConflict(_) => "transaction_conflict",
+            CoreError::InvalidParams(_) => "invalid_params",
+            CoreError::MissingParam(_) => "missing_param",
+            CoreError::Timeout { .. } => "timeout",
+            CoreError::Cancelled => "cancelled",
+            CoreError::Interrupted(_) => "interrupted",
+            CoreError::TransactionOpen => "transaction_open",
+            CoreError::TransactionAborted(_) => "transaction_aborted",
+            CoreError::InvalidName(_) => "invalid_name",
+            CoreError::InvalidSchema(_) => "invalid_schema",
+            CoreError::RelationNotEmpty { .. } => "relation_not_empty",
+            CoreError::Io(_) => "io",
+            CoreError::InvalidImport(_) => "invalid_import",
+            CoreError::InvalidRow { .. } => "invalid_row",
+            CoreError::UnknownNamedQuery(_) => "unknown_named_query",
+            CoreError::Migration { .. } => "migration",
+            CoreError::VectorDimension { .. } => "vector_dimension",
+            CoreError::InvalidVector(_) => "invalid_vector",
+            CoreError::AuditDisabled => "audit_disabled",
+            CoreError::NamespaceDenied { .. } => "namespace_denied",
+            CoreError::GrantDenied { .. } => "grant_denied",
+            CoreError::ResultTooLarge { .. } => "result_too_large",
+        }
+    }
+}
+
+/// The text between the first pair of single quotes.
+fn quoted(text: &str) -> Option<&str> {
+    let start = text.find('\'')? + 1;
+    let len = text[start..].find('\'')?;
+    Some(&text[start..start + len])
+}
+
+impl QueryError {
+    pub(crate) fn from_report(report: &cozo::Error, query: &str) -> Self {
+        let offset = report.labels().and_then(|mut labels| labels.next()).map(|label| label.offset());
+        let (line, column) = match offset {
+            Some(offset) => {
+                let (line, column) = position(query, offset);
+                (Some(line), Some(column))
+            }
+            None => (None, None),
+        };
+        // The engine adds context as causes, e.g. the value that did not
+        // fit behind the relation it was written to.
+        let message = report.chain().map(|cause| cause.to_string()).collect::<Vec<_>>().join(": ");
+        Self {
+            code: report.code().map(|code| code.to_string()),
+            message,
+            help: report.help().map(|help| help.to_string()),
+            line,
+            column,
+        }
+    }
+
+    fn write(&self, f: &mut fmt::Formatter<'_>, lead: &str) -> fmt::Result {
+        write!(f, "{}", lead)?;
+        if let Some(code) = &self.code {
+            write!(f, " [{}]", code)?;
+        }
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, " at line {}, column {}", line, column)?;
+        }
+        write!(f, ": {}", self.message)?;
+        if let Some(help) = &self.help {
+            write!(f, " ({})", help)?;
+        }
+        Ok(())
+    }
+}
+
+/// Line and column, both 1-based, of byte `offset` in `text`.
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text.as_bytes()[..offset.min(text.len())];
+    let line = before.iter().filter(|&&b| b == b'\n').count() + 1;
+    let line_start = before.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    let column = String::from_utf8_lossy(&before[line_start..]).chars().count() + 1;
+    (line, column)
+}
+
+impl fmt::Display for CoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoreError::Query(error) => write!(f, "{}", error),
+            CoreError::Parse(error) => error.write(f, "Invalid query"),
+            CoreError::UnknownRelation(name) => write!(f, "No stored relation is named '{}'", name),
+            CoreError::TypeMismatch { relation: Some(relation), detail } => {
+                write!(f, "Type mismatch writing {}: {}", relation, detail)
+            }
+            CoreError::TypeMismatch { relation: None, detail } => write!(f, "Type mismatch: {}", detail),
+            CoreError::ReadOnlyViolation(msg) => write!(f, "A read-only query cannot write: {}", msg),
+            CoreError::Storage(msg) => write!(f, "Storage failed: {}", msg),
+            CoreError::TransactionConflict(msg) => write!(f, "The store is locked by another write; retry: {}", msg),
+            CoreError::InvalidParams(msg) => write!(f, "Invalid query parameters: {}", msg),
+            CoreError::MissingParam(name) => write!(f, "Query parameter ${} is not bound", name),
+            CoreError::Timeout { elapsed } => write!(f, "Query timed out after {} ms", elapsed.as_millis()),
+            CoreError::Cancelled => write!(f, "Query cancelled"),
+            CoreError::Interrupted(msg) => write!(f, "Query interrupted: {}", msg),
+            CoreError::TransactionOpen => write!(f, "A core transaction is open; retry once it commits or rolls back"),
+            CoreError::TransactionAborted(reason) => write!(f, "Transaction aborted: {}", reason),
+            CoreError::InvalidName(msg) => write!(f, "Invalid name: {}", msg),
+            CoreError::InvalidSchema(msg) => write!(f, "Invalid schema: {}", msg),
+            CoreError::RelationNotEmpty { relation, rows } => {
+                write!(f, "Relation {} still holds {} rows; drop it with force to delete them", relation, rows)
+            }
+            CoreError::Io(msg) => write!(f, "I/O error: {}", msg),
+            CoreError::InvalidImport(msg) => write!(f, "Cannot import: {}", msg),
+            CoreError::InvalidRow { index, reason } => write!(f, "Invalid row {}: {}", index, reason),
+            CoreError::UnknownNamedQuery(name) => write!(f, "No query is registered as '{}'", name),
+            CoreError::Migration { version, name, reason } => {
+                write!(f, "Migration {} ({}) failed: {}", version, name, reason)
+            }
+            CoreError::VectorDimension { expected, got } => {
+                write!(f, "Vector has {} components; the column holds {}", got, expected)
+            }
+            CoreError::InvalidVector(msg) => write!(f, "Invalid vector: {}", msg),
+            CoreError::AuditDisabled => write!(f, "Query auditing is not enabled"),
+            CoreError::NamespaceDenied { namespace, relation: Some(relation), .. } => {
+                write!(f, "Relation '{}' is not accessible from namespace '{}'", relation, namespace)
+            }
+            CoreError::NamespaceDenied { namespace, fixed_rule: Some(rule), .. } => {
+                write!(f, "Fixed rule '{}' cannot run in namespace '{}'", rule, namespace)
+            }
+            CoreError::NamespaceDenied { namespace, .. } => {
+                write!(f, "System ops cannot run in namespace '{}'", namespace)
+            }
+            CoreError::GrantDenied { principal, relation: Some(relation), written, .. } => {
+                let access = if *written { "write" } else { "read" };
+                write!(f, "{} has no grant to {} relation '{}'", principal, access, relation)
+            }
+            CoreError::GrantDenied { principal, fixed_rule: Some(rule), .. } => {
+                write!(f, "{} has no grant to apply fixed rule '{}'", principal, rule)
+            }
+            CoreError::GrantDenied { principal, .. } => write!(f, "{} is held to its grants and cannot run system ops", principal),
+            CoreError::ResultTooLarge { limit, hint } => write!(f, "Query result is over {}; {}", limit, hint),
+        }
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, "Query failed")
+    }
+}
+
+
+impl std::error::Error for CoreError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CognitiveCore, CoreConfig, QueryOptions};
+    use serde_json::{json, Value};
+
+    fn core() -> CognitiveCore {
+        let core = CognitiveCore::new(CoreConfig::default()).unwrap();
+        core.run(":create people {name: String => age: Int}", Value::Null).unwrap();
+        core
+    }
+
+    #[test]
+    fn positions_are_one_based_lines_and_characters() {
+        for (text, offset, expected) in [
+            ("abc", 0, (1, 1)),
+            ("abc", 2, (1, 3)),
+            ("a\nbc", 2, (2, 1)),
+            ("a\nbc", 3, (2, 2)),
+            ("a\n\n\nb", 4, (4, 1)),
+            ("é = ö", 5, (1, 5)),
+            ("abc", 10, (1, 4)),
+        ] {
+            assert_eq!(position(text, offset), expected, "{:?} at {}", text, offset);
+        }
+        assert_eq!(quoted("relation 'people' not found"), Some("people"));
+        assert_eq!(quoted("no quotes"), None);
+        assert_eq!(quoted("one ' only"), None);
+    }
+
+    #[test]
+    fn parse_errors_point_into_the_query_when_run_or_explained() {
+        let core = core();
+        for (query, expected) in [
+            ("?[name] := *people{name,, age}", (1, 25)),
+            ("?[name] :=\n  *people{name,, age}", (2, 16)),
+            ("# comment\n\n?[name] := *people{name,, age}", (3, 25)),
+        ] {
+            let run = match core.run(query, Value::Null) {
+                Err(CoreError::Parse(error)) => (error.line, error.column),
+                other => panic!("expected a parse error, got {:?}", other),
+            };
+            // `explain` wraps the query, then moves the position back.
+            let explained = match core.explain(query, Value::Null) {
+                Err(CoreError::Parse(error)) => (error.line, error.column),
+                other => panic!("expected a parse error, got {:?}", other),
+            };
+            assert_eq!(run, (Some(expected.0), Some(expected.1)), "{:?}", query);
+            assert_eq!(explained, run, "{:?}", query);
+        }
+    }
+
+    #[test]
+    fn engine_failures_are_sorted_by_code() {
+        let core = core();
+        let readonly = QueryOptions {
+            readonly: true,
+            ..Default::default()
+        };
+        for (result, kind) in [
+            (core.run("?[x] := *nobody{x}", Value::Null), "unknown_relation"),
+            (core.run("?[x] <- [[1]] :rm nobody {x}", Value::Null), "unknown_relation"),
+            (core.run("?[name, age] <- [['ada', 'old']] :put people {name => age}", Value::Null), "type_mismatch"),
+            (core.run("?[x] <- [[$missing]]", json!({})), "missing_param"),
+            (core.run("?[x] := x = unknown_fn(1)", Value::Null), "parse"),
+            (core.run("?[x, y] := x = 1", Value::Null), "parse"),
+            (core.run_with("?[name, age] <- [['ada', 1]] :put people {name => age}", Value::Null, &readonly), "read_only_violation"),
+            (core.run("?[x] <- [[1]]", json!([1])), "invalid_params"),
+        ] {
+            let error = result.unwrap_err();
+            assert_eq!(error.kind(), kind, "{}", error);
+        }
+    }
+
+    #[test]
+    fn messages_carry_the_code_and_position() {
+        let error = CoreError::Parse(QueryError {
+            code: Some("parser::pest".into()),
+            message: "unexpected input".into(),
+            help: Some("check the rule".into()),
+            line: Some(2),
+            column: Some(16),
+        });
+        assert_eq!(error.to_string(), "Invalid query [parser::pest] at line 2, column 16: unexpected input (check the rule)");
+        let error = CoreError::Query(QueryError {
+            code: None,
+            message: "failed".into(),
+            help: None,
+            line: Some(1),
+            column: None,
+        });
+        assert_eq!(error.to_string(), "Query failed: failed");
+        assert_eq!(
+            CoreError::NamespaceDenied {
+                namespace: "a".into(),
+                relation: None,
+                fixed_rule: None
+            }
+            .to_string(),
+            "System ops cannot run in namespace 'a'"
+        );
+        assert_eq!(
+            CoreError::NamespaceDenied {
+                namespace: "a".into(),
+                relation: None,
+                fixed_rule: Some("CsvReader".into())
+            }
+            .to_string(),
+            "Fixed rule 'CsvReader' cannot run in namespace 'a'"
+        );
+    }
+}
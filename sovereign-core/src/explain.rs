@@ -0,0 +1,508 @@
+use crate::params::bind_params;
+use crate::{CognitiveCore, CoreError, QueryError};
+use cozo::{DataValue, NamedRows, ScriptMutability};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// Relation options that write their relation.
+const WRITES: &[&str] = &["create", "replace", "insert", "put", "update", "rm", "delete"];
+
+/// System ops that change the store.
+const SYSTEM_WRITES: &[&str] = &[
+    "remove",
+    "rename",
+    "index",
+    "fts",
+    "hnsw",
+    "lsh",
+    "set_triggers",
+    "access_level",
+    "compact",
+];
+
+/// What `CognitiveCore::explain` found out about a query without running it.
+#[derive(Debug, Clone)]
+pub struct ExplainReport {
+    /// Stored relations the query reads or writes, in order of first
+    /// mention. Not worked out for system ops.
+    pub relations: Vec<ExplainRelation>,
+    /// The columns the query returns; empty for system ops.
+    pub headers: Vec<String>,
+    /// How the query writes, e.g. `:put` or `::remove`; `None` for a read.
+    pub mutation: Option<String>,
+    /// The engine's plan, one object per step keyed like the columns of
+    /// `::explain`. Empty for system ops, and when a relation the query
+    /// reads is missing.
+    pub plan: Vec<Value>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExplainRelation {
+    pub name: String,
+    pub exists: bool,
+    /// The query writes the relation, rather than only reading it.
+    pub written: bool,
+}
+
+impl CognitiveCore {
+    /// Parses and plans a single query with `params` bound, without running
+    /// it. A syntax error fails with `CoreError::Parse` at its line and
+    /// column in `query`; a missing relation is reported, not an error.
+    pub fn explain(&self, query: &str, params: Value) -> Result<ExplainReport, CoreError> {
+        let params = bind_params(params)?;
+        let text = blank_literals(query);
+        if let Some(op) = system_op(&text) {
+            return Ok(ExplainReport {
+                relations: Vec::new(),
+                headers: Vec::new(),
+                mutation: SYSTEM_WRITES.contains(&op.as_str()).then(|| format!("::{}", op)),
+                plan: Vec::new(),
+            });
+        }
+
+        let found = scan(&text);
+        let existing = self.relation_names()?;
+        let relations: Vec<ExplainRelation> = found
+            .relations
+            .into_iter()
+            .map(|(name, written)| ExplainRelation {
+                exists: existing.contains(&name),
+                name,
+                written,
+            })
+            .collect();
+
+        let plan = match self.plan(query, params) {
+            Ok(rows) => rows
+                .rows
+                .into_iter()
+                .map(|row| Value::Object(rows.headers.iter().cloned().zip(row.into_iter().map(Value::from)).collect()))
+                .collect(),
+            Err(CoreError::UnknownRelation(_)) if relations.iter().any(|r| !r.exists) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        let headers = match (&found.mutation, found.returning) {
+            (None, _) => found.head,
+            (Some(_), false) => vec!["status".to_string()],
+            (Some(_), true) => {
+                let target = relations.iter().find(|r| r.written && r.exists);
+                let columns = match target {
+                    Some(target) => self.columns(&target.name)?.into_iter().map(|c| c.name).collect(),
+                    None => Vec::new(),
+                };
+                std::iter::once("_kind".to_string()).chain(columns).collect()
+            }
+        };
+        Ok(ExplainReport {
+            relations,
+            headers,
+            mutation: found.mutation.map(|op| format!(":{}", op)),
+            plan,
+        })
+    }
+}
+
+impl CognitiveCore {
+    /// The engine's `::explain` of a single query, with positions in
+    /// errors given in `query`.
+    pub(crate) fn plan(&self, query: &str, params: BTreeMap<String, DataValue>) -> Result<NamedRows, CoreError> {
+        // The query gets a line of its own, so engine positions are a line
+        // off, and one on the first line is in the wrapper.
+        let explain = format!("::explain {{\n{}\n}}", query);
+        self.script(&explain, params, ScriptMutability::Immutable).map_err(|e| match e {
+            CoreError::Query(error) => CoreError::Query(unwrap_position(error)),
+            CoreError::Parse(error) => CoreError::Parse(unwrap_position(error)),
+            other => other,
+        })
+    }
+}
+
+fn unwrap_position(mut error: QueryError) -> QueryError {
+    error.line = error.line.filter(|&line| line > 1).map(|line| line - 1);
+    error.column = error.column.filter(|_| error.line.is_some());
+    error
+}
+
+/// What the text of a query says about it.
+#[derive(Default)]
+pub(crate) struct Scanned {
+    /// Relation names and whether they are written.
+    relations: Vec<(String, bool)>,
+    /// Every mention of a relation, in order.
+    pub references: Vec<Reference>,
+    /// The relation option that writes, without its colon.
+    pub mutation: Option<String>,
+    returning: bool,
+    /// The query sets `:limit`.
+    pub limited: bool,
+    /// The entry rule's head, as the engine names result columns.
+    head: Vec<String>,
+}
+
+/// Where a query names a stored relation.
+pub(crate) struct Reference {
+    /// Byte range of the name in the query.
+    pub span: Range<usize>,
+    pub written: bool,
+    /// By `:create` or `:replace`.
+    pub creates: bool,
+}
+
+/// `query` with its strings and comments blanked out, so that what is left
+/// can be scanned for syntax. Byte offsets are kept.
+pub(crate) fn blank_literals(query: &str) -> Vec<u8> {
+    let src = query.as_bytes();
+    let mut out = src.to_vec();
+    let mut i = 0;
+    while i < src.len() {
+        let start = i;
+        match src[i] {
+            b'#' => {
+                while i < src.len() && src[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'/' if src.get(i + 1) == Some(&b'*') => {
+                let mut depth = 0;
+                while i < src.len() {
+                    if src[i..].starts_with(b"/*") {
+                        depth += 1;
+                        i += 2;
+                    } else if src[i..].starts_with(b"*/") {
+                        depth -= 1;
+                        i += 2;
+                        if depth == 0 {
+                            break;
+                        }
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+            b'\'' => {
+                i += 1;
+                while i < src.len() && src[i] != b'\'' {
+                    i += if src[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 1;
+            }
+            // Double-quoted strings are raw: no escapes, and optionally
+            // fenced with underscores, as in `__"a "quote""__`.
+            b'_' | b'"' if src[i] == b'"' || i == 0 || !is_ident(src[i - 1]) => {
+                let fence = src[i..].iter().take_while(|&&b| b == b'_').count();
+                if src.get(i + fence) != Some(&b'"') {
+                    i += fence.max(1);
+                    continue;
+                }
+                i += fence + 1;
+                let close: Vec<u8> = std::iter::once(b'"').chain(std::iter::repeat_n(b'_', fence)).collect();
+                while i < src.len() && !src[i..].starts_with(&close) {
+                    i += 1;
+                }
+                i += close.len();
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        }
+        let end = i.min(src.len());
+        out[start..end].fill(b' ');
+        i = end;
+    }
+    out
+}
+
+/// The op of a system op script, e.g. `relations` for `::relations`.
+pub(crate) fn system_op(text: &[u8]) -> Option<String> {
+    let start = text.iter().position(|b| !b.is_ascii_whitespace())?;
+    let rest = text[start..].strip_prefix(b"::")?;
+    Some(String::from_utf8_lossy(word(rest)).into_owned())
+}
+
+/// The op of every system op anywhere in `text`, in order. An imperative
+/// script can run them from inside its blocks, as in `{ ::remove a }`, so
+/// this is what says whether a script runs any; `system_op` only looks at
+/// the start. An op that cannot be read is listed as empty.
+pub(crate) fn system_ops(text: &[u8]) -> Vec<String> {
+    let mut ops = Vec::new();
+    let mut i = 0;
+    while let Some(at) = text[i..].windows(2).position(|pair| pair == b"::") {
+        let mut start = i + at + 2;
+        start += text[start..].iter().take_while(|b| b.is_ascii_whitespace()).count();
+        let op = word(&text[start..]);
+        ops.push(String::from_utf8_lossy(op).into_owned());
+        i = start + op.len();
+    }
+    ops
+}
+
+/// Fixed rules that only compute on their inputs. The others, such as
+/// `CsvReader`, can read files and URLs outside the store.
+pub(crate) const PURE_FIXED_RULES: &[&str] = &["Constant", "ReorderSort"];
+
+/// The name of every fixed rule `text` applies with `<~`, in order. A name
+/// that cannot be read is listed as empty.
+pub(crate) fn fixed_rules(text: &[u8]) -> Vec<String> {
+    let mut rules = Vec::new();
+    let mut i = 0;
+    while let Some(at) = text[i..].windows(2).position(|pair| pair == b"<~") {
+        let mut start = i + at + 2;
+        start += text[start..].iter().take_while(|b| b.is_ascii_whitespace()).count();
+        let rule = word(&text[start..]);
+        rules.push(String::from_utf8_lossy(rule).into_owned());
+        i = start + rule.len();
+    }
+    rules
+}
+
+pub(crate) fn scan(text: &[u8]) -> Scanned {
+    let mut found = Scanned::default();
+
+    let mut i = 0;
+    while i < text.len() {
+        match text[i] {
+            // `*name{...}` reads a relation, `*name:index{...}` one of its
+            // indices and `~name:index{...}` searches one.
+            sigil @ (b'*' | b'~') if text.get(i + 1).is_some_and(|&b| is_ident(b) && !b.is_ascii_digit()) => {
+                let name = relation_name(&text[i + 1..]);
+                let mut end = i + 1 + name.len();
+                end += text[end..].iter().take_while(|&&b| b == b':' || is_ident(b)).count();
+                end += text[end..].iter().take_while(|b| b.is_ascii_whitespace()).count();
+                let opens = text.get(end) == Some(&b'{') || (sigil == b'*' && text.get(end) == Some(&b'['));
+                if opens {
+                    found.refer(i + 1..i + 1 + name.len(), text, false, false);
+                }
+                i = end.max(i + 1);
+            }
+            b':' if text.get(i + 1).is_some_and(|&b| b.is_ascii_alphabetic()) && (i == 0 || text[i - 1] != b':') => {
+                let option = String::from_utf8_lossy(word(&text[i + 1..])).into_owned();
+                let mut end = i + 1 + option.len();
+                end += text[end..].iter().take_while(|b| b.is_ascii_whitespace()).count();
+                let name = end..end + relation_name(&text[end..]).len();
+                if WRITES.contains(&option.as_str()) {
+                    found.refer(name, text, true, option == "create" || option == "replace");
+                    found.mutation = Some(option);
+                } else if option == "ensure" || option == "ensure_not" {
+                    found.refer(name, text, false, false);
+                } else if option == "returning" {
+                    found.returning = true;
+                } else if option == "limit" {
+                    found.limited = true;
+                }
+                i = end;
+            }
+            b'?' => {
+                let mut end = i + 1;
+                end += text[end..].iter().take_while(|b| b.is_ascii_whitespace()).count();
+                if text.get(end) == Some(&b'[') {
+                    found.head = head(&text[end + 1..]);
+                }
+                i = end;
+            }
+            _ => i += 1,
+        }
+    }
+    found
+}
+
+impl Scanned {
+    fn refer(&mut self, span: Range<usize>, text: &[u8], written: bool, creates: bool) {
+        if span.is_empty() {
+            return;
+        }
+        let name = String::from_utf8_lossy(&text[span.clone()]).into_owned();
+        match self.relations.iter_mut().find(|(n, _)| *n == name) {
+            Some(entry) => entry.1 |= written,
+            None => self.relations.push((name, written)),
+        }
+        self.references.push(Reference { span, written, creates });
+    }
+}
+
+/// The comma-separated items up to the closing bracket, without spaces.
+fn head(text: &[u8]) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut item = Vec::new();
+    let mut depth = 0;
+    for &b in text {
+        match b {
+            b'(' | b'[' => depth += 1,
+            b')' => depth -= 1,
+            b']' if depth == 0 => break,
+            b']' => depth -= 1,
+            b',' if depth == 0 => {
+                items.push(std::mem::take(&mut item));
+                continue;
+            }
+            _ => {}
+        }
+        if !b.is_ascii_whitespace() {
+            item.push(b);
+        }
+    }
+    items.push(item);
+    items
+        .into_iter()
+        .filter(|item| !item.is_empty())
+        .map(|item| String::from_utf8_lossy(&item).into_owned())
+        .collect()
+}
+
+/// A relation name, which may be dotted, at the start of `text`.
+fn relation_name(text: &[u8]) -> &[u8] {
+    &text[..text.iter().take_while(|&&b| b == b'.' || is_ident(b)).count()]
+}
+
+fn word(text: &[u8]) -> &[u8] {
+    &text[..text.iter().take_while(|&&b| is_ident(b)).count()]
+}
+
+fn is_ident(b: u8) -> bool {
+    b == b'_' || b.is_ascii_alphanumeric() || b >= 0x80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CoreConfig;
+    use serde_json::json;
+
+    fn blanked(query: &str) -> String {
+        String::from_utf8(blank_literals(query)).unwrap()
+    }
+
+    #[test]
+    fn literals_and_comments_are_blanked_in_place() {
+        for (query, expected) in [
+            ("?[a] := a = 'b*c'", "?[a] := a =      "),
+            (r"a 'x\'*y{}' b", "a           b"),
+            // Raw strings have no escapes.
+            (r#"a "x 'y' \" b"#, "a           b"),
+            (r#"a __"say "hi" *x{}"__ b"#, "a                     b"),
+            (r#"a _"x"_ b"#, "a       b"),
+            (r#"foo_"x""#, "foo_   "),
+            ("a # *x{k}\nb", "a        \nb"),
+            ("a /* x /* *y{k} */ z */ b", "a                       b"),
+            ("a /* open", "a        "),
+            ("a 'open", "a      "),
+        ] {
+            assert_eq!(blanked(query), expected, "{:?}", query);
+        }
+    }
+
+    /// Relations, with whether they are written, the mutation and the
+    /// relations created, as scanned from `query`.
+    fn scanned(query: &str) -> (Vec<(String, bool)>, Option<String>, Vec<String>) {
+        let found = scan(&blank_literals(query));
+        let created = found
+            .references
+            .iter()
+            .filter(|r| r.creates)
+            .map(|r| query[r.span.clone()].to_string())
+            .collect();
+        (found.relations, found.mutation, created)
+    }
+
+    #[test]
+    fn scans_references_and_mutations() {
+        let read = |name: &str| (name.to_string(), false);
+        let written = |name: &str| (name.to_string(), true);
+        for (query, relations, mutation, created) in [
+            ("?[k, v] := *notes{k, v}", vec![read("notes")], None, vec![]),
+            ("?[k] := *a{k}, *b.c[k], *a{k}", vec![read("a"), read("b.c")], None, vec![]),
+            ("?[k] := *notes:by_k{k}", vec![read("notes")], None, vec![]),
+            ("?[k] := ~notes:fts{k | query: 'x'}", vec![read("notes")], None, vec![]),
+            ("?[k] := k = '*fake{k}' # *other{k}", vec![], None, vec![]),
+            ("?[k] := *a{k}, k = 2 * 3", vec![read("a")], None, vec![]),
+            ("?[k] <- [[1]] :put notes {k}", vec![written("notes")], Some("put"), vec![]),
+            ("?[k] <- [[1]] :ensure notes {k}", vec![read("notes")], None, vec![]),
+            (":create shared.notes {k: Int}", vec![written("shared.notes")], Some("create"), vec!["shared.notes"]),
+            (
+                "?[k] := *a{k} :replace b {k}",
+                vec![read("a"), written("b")],
+                Some("replace"),
+                vec!["b"],
+            ),
+            (
+                "{ ?[k] := *a{k} :create b {k} } { ?[k] := *b{k} :rm a {k} }",
+                vec![written("a"), written("b")],
+                Some("rm"),
+                vec!["b"],
+            ),
+            (
+                "%if { ?[x] := *flag{x} } %then { ?[k] <- [[1]] :put notes {k} } %end",
+                vec![read("flag"), written("notes")],
+                Some("put"),
+                vec![],
+            ),
+            ("{ ?[k] := *_tmp{k} } { ?[k] <- [[1]] :replace _tmp {k} }", vec![written("_tmp")], Some("replace"), vec!["_tmp"]),
+        ] {
+            let expected = (relations, mutation.map(str::to_string), created.into_iter().map(str::to_string).collect());
+            assert_eq!(scanned(query), expected, "{:?}", query);
+        }
+    }
+
+    #[test]
+    fn scans_heads_and_limits() {
+        for (query, head, limited) in [
+            ("?[a, b] := *r{a, b}", vec!["a", "b"], false),
+            ("? [a, count(b), c] := *r{a, b, c}", vec!["a", "count(b)", "c"], false),
+            ("?[x] <- [[1]] :limit 3", vec!["x"], true),
+            ("?[x] <- [[':limit']]", vec!["x"], false),
+        ] {
+            let found = scan(&blank_literals(query));
+            assert_eq!((found.head, found.limited), (head.into_iter().map(str::to_string).collect(), limited), "{:?}", query);
+        }
+    }
+
+    #[test]
+    fn finds_system_ops() {
+        for (query, first, all) in [
+            ("::relations", Some("relations"), vec!["relations"]),
+            ("\n  ::remove a", Some("remove"), vec!["remove"]),
+            (":: remove a", Some(""), vec!["remove"]),
+            ("?[a] <- [[1]]", None, vec![]),
+            ("?[a] <- [['::remove a']] # ::compact", None, vec![]),
+            ("{ ::relations }", None, vec!["relations"]),
+            ("{ ?[a] := *b{a} } { ::remove b } { ::compact }", None, vec!["remove", "compact"]),
+            ("{ ::/* hidden */remove b }", None, vec!["remove"]),
+            ("{ :: }", None, vec![""]),
+        ] {
+            let text = blank_literals(query);
+            assert_eq!(system_op(&text).as_deref(), first, "{:?}", query);
+            assert_eq!(system_ops(&text), all, "{:?}", query);
+        }
+    }
+
+    #[test]
+    fn explains_without_running() {
+        let core = CognitiveCore::new(CoreConfig::default()).unwrap();
+        core.run(":create notes {k: Int => v: String}", Value::Null).unwrap();
+
+        let read = core.explain("?[k, v] := *notes{k, v}, k > $min", json!({"min": 1})).unwrap();
+        assert_eq!(read.headers, vec!["k", "v"]);
+        assert_eq!(read.mutation, None);
+        assert!(!read.plan.is_empty());
+        assert!(read.relations[0].exists && !read.relations[0].written);
+
+        let put = core.explain("?[k, v] <- [[1, 'a']] :put notes {k => v}", Value::Null).unwrap();
+        assert_eq!((put.headers, put.mutation.as_deref()), (vec!["status".to_string()], Some(":put")));
+        let returning = core.explain("?[k, v] <- [[1, 'a']] :put notes {k => v} :returning", Value::Null).unwrap();
+        assert_eq!(returning.headers, vec!["_kind", "k", "v"]);
+
+        let missing = core.explain("?[k] := *missing{k}", Value::Null).unwrap();
+        assert!(!missing.relations[0].exists && missing.plan.is_empty());
+
+        assert_eq!(core.explain("::remove notes", Value::Null).unwrap().mutation.as_deref(), Some("::remove"));
+        assert_eq!(core.explain("::relations", Value::Null).unwrap().mutation, None);
+
+        match core.explain("?[k] :=\n  *notes{k,, v}", Value::Null) {
+            Err(CoreError::Parse(error)) => assert_eq!(error.line, Some(2)),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+        assert_eq!(core.run("?[k] := *notes{k}", Value::Null).unwrap()["rows"], json!([]));
+    }
+}
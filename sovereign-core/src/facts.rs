@@ -0,0 +1,250 @@
+use crate::bulk::{check, existing, history_put, object_row, unique_keys, Column, Import, BATCH_ROWS};
+use crate::history::validity;
+use crate::params::to_data_value;
+use crate::schema::check_relation;
+use crate::{CognitiveCore, CoreError, ImportMode};
+use cozo::DataValue;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+impl CognitiveCore {
+    /// Writes `rows` to the stored relation `name`, replacing any row with
+    /// the same key, and returns how many were written.
+    ///
+    /// A row is either an object keyed by column name, where a missing
+    /// column is null or its default as in a JSON Lines import, or an array
+    /// of every column's value in `describe` order. All rows are checked
+    /// before any is written, and they are written as one transaction.
+    pub fn assert_facts(&self, name: &str, rows: Vec<Value>) -> Result<u64, CoreError> {
+        self.assert_facts_stamped(name, rows, self.history_clock.stamp())
+    }
+
+    /// `assert_facts`, recorded in the relation's history, if it keeps one,
+    /// as written at `at_ms` rather than now.
+    pub fn assert_facts_at(&self, name: &str, rows: Vec<Value>, at_ms: i64) -> Result<u64, CoreError> {
+        self.assert_facts_stamped(name, rows, at_ms.saturating_mul(1000))
+    }
+
+    /// `assert_facts`, recorded at `at_us` in microseconds.
+    fn assert_facts_stamped(&self, name: &str, rows: Vec<Value>, at_us: i64) -> Result<u64, CoreError> {
+        check_relation(name)?;
+        let history = self.history_of(name)?;
+        let columns: Vec<Column> = self.columns(name)?.iter().map(Column::new).collect();
+        let rows = rows
+            .into_iter()
+            .enumerate()
+            .map(|(index, row)| fact_row(row, &columns).map_err(|reason| CoreError::InvalidRow { index, reason }))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.transact(|tx| {
+            let mut import = Import::new(tx, name, &columns, ImportMode::Upsert).recorded(history, validity(at_us, true));
+            for (index, row) in rows.into_iter().enumerate() {
+                import.row(index as u64, row)?;
+            }
+            Ok(import.finish()?.inserted)
+        })
+    }
+
+    /// Deletes the rows of `name` with the given keys and returns how many
+    /// there were; a key with no row is skipped.
+    ///
+    /// A key is an array of the key columns' values in key order, an object
+    /// with just the key columns, or, if there is one key column, its value.
+    pub fn retract_facts(&self, name: &str, keys: Vec<Value>) -> Result<u64, CoreError> {
+        self.retract_facts_stamped(name, keys, self.history_clock.stamp())
+    }
+
+    /// `retract_facts`, recorded in the relation's history, if it keeps one,
+    /// as retracted at `at_ms` rather than now.
+    pub fn retract_facts_at(&self, name: &str, keys: Vec<Value>, at_ms: i64) -> Result<u64, CoreError> {
+        self.retract_facts_stamped(name, keys, at_ms.saturating_mul(1000))
+    }
+
+    /// `retract_facts`, recorded at `at_us` in microseconds.
+    fn retract_facts_stamped(&self, name: &str, keys: Vec<Value>, at_us: i64) -> Result<u64, CoreError> {
+        check_relation(name)?;
+        let history = self.history_of(name)?;
+        let columns: Vec<Column> = self.columns(name)?.iter().map(Column::new).collect();
+        let key_columns: Vec<&Column> = columns.iter().filter(|c| c.key).collect();
+        let keys = keys
+            .into_iter()
+            .enumerate()
+            .map(|(index, key)| fact_key(key, &key_columns).map_err(|reason| CoreError::InvalidRow { index, reason }))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let names: Vec<&str> = key_columns.iter().map(|c| c.name.as_str()).collect();
+        let script = format!("?[{keys}] <- $keys :rm {} {{{keys}}}", name, keys = names.join(", "));
+        self.transact(|tx| {
+            let mut retracted = 0;
+            for chunk in keys.chunks(BATCH_ROWS) {
+                let chunk_keys: Vec<&[DataValue]> = chunk.iter().map(Vec::as_slice).collect();
+                // A key given twice is only counted once.
+                let first = unique_keys(&chunk_keys, false);
+                let stored: Vec<usize> =
+                    existing(tx, name, &key_columns, &chunk_keys)?.intersection(&first).copied().collect();
+                retracted += stored.len() as u64;
+                let rows = chunk.iter().cloned().map(DataValue::List).collect();
+                tx.exec_bound(&script, BTreeMap::from([("keys".to_string(), DataValue::List(rows))]))?;
+                if let Some(history) = &history {
+                    // A retraction records the key alone.
+                    let values = columns.len() - key_columns.len();
+                    let rows = stored
+                        .iter()
+                        .map(|&i| {
+                            let mut entry = chunk[i].clone();
+                            entry.push(validity(at_us, false));
+                            entry.extend(std::iter::repeat_n(DataValue::Null, values));
+                            DataValue::List(entry)
+                        })
+                        .collect();
+                    let put = history_put(history, &columns);
+                    tx.exec_bound(&put, BTreeMap::from([("rows".to_string(), DataValue::List(rows))]))?;
+                }
+            }
+            Ok(retracted)
+        })
+    }
+}
+
+fn fact_row(row: Value, columns: &[Column]) -> Result<Vec<Option<DataValue>>, String> {
+    match row {
+        Value::Object(object) => object_row(object, columns),
+        Value::Array(values) if values.len() == columns.len() => columns
+            .iter()
+            .zip(values)
+            .map(|(column, value)| convert(column, value).map(Some))
+            .collect(),
+        Value::Array(values) => Err(format!("expected {} values, got {}", columns.len(), values.len())),
+        other => Err(format!("expected an object or an array, got {}", other)),
+    }
+}
+
+fn fact_key(key: Value, columns: &[&Column]) -> Result<Vec<DataValue>, String> {
+    match key {
+        Value::Array(values) if values.len() == columns.len() => {
+            columns.iter().zip(values).map(|(column, value)| convert(column, value)).collect()
+        }
+        Value::Array(values) => Err(format!("expected {} key values, got {}", columns.len(), values.len())),
+        Value::Object(mut object) => {
+            let key = columns
+                .iter()
+                .map(|column| match object.remove(&column.name) {
+                    Some(value) => convert(column, value),
+                    None => Err(format!("{}: missing", column.name)),
+                })
+                .collect::<Result<_, _>>()?;
+            match object.keys().next() {
+                Some(other) => Err(format!("{}: not a key column", other)),
+                None => Ok(key),
+            }
+        }
+        value => match columns {
+            [column] => Ok(vec![convert(column, value)?]),
+            _ => Err(format!("expected an array of {} key values, got {}", columns.len(), value)),
+        },
+    }
+}
+
+fn convert(column: &Column, value: Value) -> Result<DataValue, String> {
+    let value = to_data_value(value).map_err(|e| format!("{}: {}", column.name, e))?;
+    check(column, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CoreConfig;
+    use serde_json::json;
+
+    fn core() -> CognitiveCore {
+        let core = CognitiveCore::new(CoreConfig::default()).unwrap();
+        core.run(":create edges {from: String, to: String => weight: Float default 1.0, note: String?}", Value::Null).unwrap();
+        core
+    }
+
+    fn edges(core: &CognitiveCore) -> Value {
+        core.run("?[f, t, w, n] := *edges[f, t, w, n] :order f, t", Value::Null).unwrap()["rows"].clone()
+    }
+
+    #[test]
+    fn asserts_objects_and_arrays_and_replaces_by_key() {
+        let core = core();
+        let rows = vec![json!({"from": "a", "to": "b"}), json!(["a", "c", 2.5, "two and a half"])];
+        assert_eq!(core.assert_facts("edges", rows).unwrap(), 2);
+        assert_eq!(core.assert_facts("edges", vec![json!({"from": "a", "to": "b", "weight": 3.0})]).unwrap(), 1);
+        assert_eq!(edges(&core), json!([["a", "b", 3.0, null], ["a", "c", 2.5, "two and a half"]]));
+
+        // Values are bound as parameters, never spliced into the script.
+        let hostile = "x'}] :rm edges {from, to} ?[a] <- [['";
+        core.assert_facts("edges", vec![json!([hostile, hostile, 0.0, hostile])]).unwrap();
+        assert_eq!(edges(&core)[2], json!([hostile, hostile, 0.0, hostile]));
+        assert_eq!(edges(&core).as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn rejects_rows_that_do_not_fit_the_schema() {
+        let core = core();
+        let bad = [
+            (json!(["a", "b"]), "expected 4 values, got 2"),
+            (json!({"to": "b"}), "from"),
+            (json!({"from": "a", "to": "b", "colour": "red"}), "colour"),
+            (json!({"from": "a", "to": "b", "weight": "heavy"}), "weight"),
+            (json!("a"), "expected an object or an array"),
+        ];
+        for (row, expected) in bad {
+            match core.assert_facts("edges", vec![json!({"from": "ok", "to": "ok"}), row.clone()]) {
+                Err(CoreError::InvalidRow { index: 1, reason }) => assert!(reason.contains(expected), "{}: {}", row, reason),
+                other => panic!("{}: expected InvalidRow, got {:?}", row, other),
+            }
+        }
+        // A rejected batch writes nothing, not even its good rows.
+        assert_eq!(edges(&core), json!([]));
+        assert!(matches!(core.assert_facts("nowhere", vec![json!([1])]), Err(CoreError::UnknownRelation(_))));
+        assert!(matches!(core.assert_facts("edges} :rm", vec![]), Err(CoreError::InvalidName(_))));
+    }
+
+    #[test]
+    fn writes_and_retracts_batches_larger_than_a_chunk() {
+        let core = core();
+        let count = BATCH_ROWS * 2 + 17;
+        let rows = (0..count).map(|i| json!([format!("n{:05}", i), "hub", i as f64, null])).collect();
+        assert_eq!(core.assert_facts("edges", rows).unwrap(), count as u64);
+        assert_eq!(core.run("?[count(f)] := *edges{from: f}", Value::Null).unwrap()["rows"], json!([[count]]));
+
+        let keys = (0..count).step_by(2).map(|i| json!([format!("n{:05}", i), "hub"])).collect::<Vec<_>>();
+        let retracted = keys.len() as u64;
+        assert_eq!(core.retract_facts("edges", keys).unwrap(), retracted);
+        let left = core.run("?[count(f)] := *edges{from: f}", Value::Null).unwrap()["rows"][0][0].as_u64().unwrap();
+        assert_eq!(left, count as u64 - retracted);
+    }
+
+    #[test]
+    fn retracts_by_key_and_counts_only_stored_rows() {
+        let core = core();
+        core.assert_facts("edges", vec![json!(["a", "b", 1.0, null]), json!(["a", "c", 1.0, null]), json!(["b", "c", 1.0, null])])
+            .unwrap();
+        // Arrays and objects name the same key; a repeated or absent key is
+        // not counted.
+        let keys = vec![json!(["a", "b"]), json!({"from": "a", "to": "b"}), json!({"to": "c", "from": "b"}), json!(["z", "z"])];
+        assert_eq!(core.retract_facts("edges", keys).unwrap(), 2);
+        assert_eq!(edges(&core), json!([["a", "c", 1.0, null]]));
+
+        for (key, expected) in [
+            (json!(["a"]), "expected 2 key values, got 1"),
+            (json!({"from": "a"}), "to: missing"),
+            (json!({"from": "a", "to": "c", "weight": 1.0}), "weight: not a key column"),
+            (json!("a"), "expected an array of 2 key values"),
+        ] {
+            match core.retract_facts("edges", vec![key.clone()]) {
+                Err(CoreError::InvalidRow { index: 0, reason }) => assert!(reason.contains(expected), "{}: {}", key, reason),
+                other => panic!("{}: expected InvalidRow, got {:?}", key, other),
+            }
+        }
+        assert_eq!(edges(&core), json!([["a", "c", 1.0, null]]));
+
+        // With a single key column a bare value is the key.
+        core.run(":create tags {tag: String}", Value::Null).unwrap();
+        core.assert_facts("tags", vec![json!(["red"]), json!({"tag": "blue"})]).unwrap();
+        assert_eq!(core.retract_facts("tags", vec![json!("red")]).unwrap(), 1);
+        assert_eq!(core.run("?[t] := *tags[t]", Value::Null).unwrap()["rows"], json!([["blue"]]));
+    }
+}
@@ -0,0 +1,253 @@
+use crate::schema::{check_name, check_relation};
+use crate::{result_json, CognitiveCore, CoreError};
+use cozo::{DataValue, ScriptMutability};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Instant;
+
+/// How `create_fts_index` splits text into words. The same analysis is
+/// applied to search queries.
+#[derive(Debug, Clone)]
+pub struct FtsOptions {
+    pub tokenizer: FtsTokenizer,
+    /// Applied to each token in order.
+    pub filters: Vec<FtsFilter>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FtsTokenizer {
+    /// The whole text is one token.
+    Raw,
+    /// Splits on anything that is not a letter or digit.
+    Simple,
+    /// Splits on whitespace.
+    Whitespace,
+    /// Overlapping substrings of `min` to `max` characters; with
+    /// `prefix_only`, only those starting a word.
+    NGram { min: usize, max: usize, prefix_only: bool },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FtsFilter {
+    Lowercase,
+    /// Drops tokens with characters other than ASCII letters and digits.
+    AlphaNumOnly,
+    /// Replaces accented characters with their ASCII equivalents.
+    AsciiFolding,
+    /// Reduces words to their stem in a language, e.g. `english`.
+    Stemmer(String),
+    /// Drops common words of a language, e.g. `en`.
+    Stopwords(String),
+}
+
+impl Default for FtsOptions {
+    fn default() -> Self {
+        Self {
+            tokenizer: FtsTokenizer::Simple,
+            filters: vec![FtsFilter::Lowercase],
+        }
+    }
+}
+
+impl fmt::Display for FtsTokenizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FtsTokenizer::Raw => write!(f, "Raw"),
+            FtsTokenizer::Simple => write!(f, "Simple"),
+            FtsTokenizer::Whitespace => write!(f, "Whitespace"),
+            FtsTokenizer::NGram { min, max, prefix_only } => write!(f, "NGram({}, {}, {})", min, max, prefix_only),
+        }
+    }
+}
+
+impl fmt::Display for FtsFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FtsFilter::Lowercase => write!(f, "Lowercase"),
+            FtsFilter::AlphaNumOnly => write!(f, "AlphaNumOnly"),
+            FtsFilter::AsciiFolding => write!(f, "AsciiFolding"),
+            FtsFilter::Stemmer(language) => write!(f, "Stemmer('{}')", language),
+            FtsFilter::Stopwords(language) => write!(f, "Stopwords('{}')", language),
+        }
+    }
+}
+
+impl CognitiveCore {
+    /// Indexes the `String` column `column` of `relation` for `search`. The
+    /// index is kept up to date as rows are written and deleted.
+    ///
+    /// Existing rows are indexed straight away, within the query timeout.
+    /// The engine cannot stop an index build, so one that times out goes on
+    /// in the background while the store stays locked for writing.
+    pub fn create_fts_index(&self, relation: &str, column: &str, options: &FtsOptions) -> Result<(), CoreError> {
+        check_relation(relation)?;
+        check_name(column)?;
+        for filter in &options.filters {
+            if let FtsFilter::Stemmer(language) | FtsFilter::Stopwords(language) = filter {
+                if language.is_empty() || !language.chars().all(|c| c.is_ascii_alphabetic()) {
+                    return Err(CoreError::InvalidSchema(format!("'{}' is not a language name", language.escape_debug())));
+                }
+            }
+        }
+        let mut config = format!("extractor: {}, tokenizer: {}", column, options.tokenizer);
+        // The engine rejects an empty filter list.
+        if !options.filters.is_empty() {
+            let filters: Vec<String> = options.filters.iter().map(ToString::to_string).collect();
+            config.push_str(&format!(", filters: [{}]", filters.join(", ")));
+        }
+        let script = format!("::fts create {}:{} {{{}}}", relation, fts_index_name(column), config);
+        self.script(&script, BTreeMap::new(), ScriptMutability::Mutable)?;
+        Ok(())
+    }
+
+    pub fn drop_fts_index(&self, relation: &str, column: &str) -> Result<(), CoreError> {
+        check_relation(relation)?;
+        check_name(column)?;
+        let script = format!("::fts drop {}:{}", relation, fts_index_name(column));
+        self.script(&script, BTreeMap::new(), ScriptMutability::Mutable)?;
+        Ok(())
+    }
+
+    /// The `k` rows of `relation` whose indexed `column` best matches
+    /// `query`, best first, in the shape `run` returns. The first column is
+    /// the match `score`, followed by the relation's columns.
+    ///
+    /// `query` is the engine's search syntax: words, `"quoted phrases"`,
+    /// `prefix*`, `AND`, `OR`, `NOT` and `NEAR(...)`.
+    pub fn search(&self, relation: &str, column: &str, query: &str, k: usize) -> Result<serde_json::Value, CoreError> {
+        check_relation(relation)?;
+        check_name(column)?;
+        let columns: Vec<String> = self.columns(relation)?.into_iter().map(|c| c.name).collect();
+        // Columns are bound to generated variables so none clashes with `score`.
+        let vars: Vec<String> = (0..columns.len()).map(|i| format!("c{}", i)).collect();
+        let bindings: Vec<String> = columns.iter().zip(&vars).map(|(c, v)| format!("{}: {}", c, v)).collect();
+        let script = format!(
+            "?[score, {}] := ~{}:{}{{{} | query: $query, k: {}, bind_score: score}} :order -score",
+            vars.join(", "),
+            relation,
+            fts_index_name(column),
+            bindings.join(", "),
+            k
+        );
+        let params = BTreeMap::from([("query".to_string(), DataValue::from(query))]);
+        let started = Instant::now();
+        let mut rows = self.script(&script, params, ScriptMutability::Immutable)?;
+        rows.headers = std::iter::once("score".to_string()).chain(columns).collect();
+        Ok(result_json(rows, started.elapsed()))
+    }
+}
+
+fn fts_index_name(column: &str) -> String {
+    format!("{}_fts", column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CoreConfig;
+    use serde_json::{json, Value};
+
+    const WORDS: [&str; 8] = ["river", "mountain", "forest", "desert", "ocean", "valley", "glacier", "meadow"];
+
+    /// A core with `notes`, holding `count` documents: note `i` mentions
+    /// `WORDS[i % 8]` once and, every tenth note, "comet" `i % 3 + 1` times.
+    fn core(count: usize) -> CognitiveCore {
+        let core = CognitiveCore::new(CoreConfig::default()).unwrap();
+        core.run(":create notes {id: Int => body: String}", Value::Null).unwrap();
+        let rows = (0..count)
+            .map(|i| {
+                let mut body = format!("Note {} about the {} and nothing else", i, WORDS[i % WORDS.len()]);
+                if i % 10 == 0 {
+                    body.push_str(&" Comet".repeat(i % 3 + 1));
+                }
+                json!([i, body])
+            })
+            .collect();
+        core.assert_facts("notes", rows).unwrap();
+        core
+    }
+
+    fn ids(result: &Value) -> Vec<i64> {
+        result["rows"].as_array().unwrap().iter().map(|row| row[1].as_i64().unwrap()).collect()
+    }
+
+    #[test]
+    fn ranks_matches_over_an_indexed_relation() {
+        let core = core(400);
+        core.create_fts_index("notes", "body", &FtsOptions::default()).unwrap();
+
+        let result = core.search("notes", "body", "glacier", 1000).unwrap();
+        assert_eq!(result["headers"], json!(["score", "id", "body"]));
+        let mut found = ids(&result);
+        found.sort();
+        assert_eq!(found, (0..400).filter(|i| i % 8 == 6).collect::<Vec<_>>());
+
+        // Lowercasing applies to queries too, and more mentions score higher.
+        let result = core.search("notes", "body", "COMET", 1000).unwrap();
+        let rows = result["rows"].as_array().unwrap();
+        assert_eq!(rows.len(), 40);
+        let scores: Vec<f64> = rows.iter().map(|row| row[0].as_f64().unwrap()).collect();
+        assert!(scores.windows(2).all(|pair| pair[0] >= pair[1]), "{:?}", scores);
+        assert_eq!(rows[0][1].as_i64().unwrap() % 3, 2, "{}", rows[0]);
+        assert_eq!(rows[39][1].as_i64().unwrap() % 3, 0, "{}", rows[39]);
+        assert_eq!(core.search("notes", "body", "comet", 5).unwrap()["rows"].as_array().unwrap().len(), 5);
+
+        let both = core.search("notes", "body", "comet AND river", 1000).unwrap();
+        assert!(ids(&both).into_iter().all(|i| i % 10 == 0 && i % 8 == 0));
+        assert_eq!(ids(&both).len(), 10);
+        assert!(core.search("notes", "body", "volcano", 10).unwrap()["rows"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn follows_rows_written_and_deleted_after_indexing() {
+        let core = core(300);
+        let options = FtsOptions {
+            tokenizer: FtsTokenizer::Simple,
+            filters: vec![FtsFilter::Lowercase, FtsFilter::Stemmer("english".into())],
+        };
+        core.create_fts_index("notes", "body", &options).unwrap();
+        assert!(core.search("notes", "body", "volcano", 10).unwrap()["rows"].as_array().unwrap().is_empty());
+
+        core.assert_facts("notes", vec![json!([1000, "Volcanoes erupting"]), json!([7, "Now about a volcano"])]).unwrap();
+        let mut found = ids(&core.search("notes", "body", "volcano", 10).unwrap());
+        found.sort();
+        assert_eq!(found, [7, 1000]);
+        // The rewritten note no longer matches its old word.
+        assert!(!ids(&core.search("notes", "body", "glacier", 1000).unwrap()).contains(&7));
+
+        core.retract_facts("notes", vec![json!(1000)]).unwrap();
+        assert_eq!(ids(&core.search("notes", "body", "volcano", 10).unwrap()), [7]);
+    }
+
+    #[test]
+    fn indices_are_reported_and_dropped() {
+        let core = core(10);
+        core.create_fts_index("notes", "body", &FtsOptions::default()).unwrap();
+        let listed = core.list_relations().unwrap();
+        let notes = listed.iter().find(|r| r.name == "notes").unwrap();
+        assert_eq!(notes.indices.len(), 1);
+        assert_eq!((notes.indices[0].name.as_str(), notes.indices[0].kind.as_str()), ("body_fts", "fts"));
+        assert_eq!(core.describe("notes").unwrap().indices[0].name, "body_fts");
+
+        core.drop_fts_index("notes", "body").unwrap();
+        assert!(core.describe("notes").unwrap().indices.is_empty());
+        assert!(core.search("notes", "body", "river", 10).is_err());
+    }
+
+    #[test]
+    fn refuses_unsafe_names_and_languages() {
+        let core = core(1);
+        let stemmer = FtsOptions {
+            tokenizer: FtsTokenizer::Simple,
+            filters: vec![FtsFilter::Stemmer("english') ::remove notes".into())],
+        };
+        assert!(matches!(core.create_fts_index("notes", "body", &stemmer), Err(CoreError::InvalidSchema(_))));
+        assert!(matches!(
+            core.create_fts_index("notes", "body} ::remove notes {", &FtsOptions::default()),
+            Err(CoreError::InvalidName(_))
+        ));
+        assert!(matches!(core.search("notes; ::remove notes", "body", "x", 1), Err(CoreError::InvalidName(_))));
+        assert!(core.describe("notes").unwrap().indices.is_empty());
+        assert_eq!(core.describe("notes").unwrap().rows, 1);
+    }
+}
@@ -0,0 +1,433 @@
+use crate::explain::{blank_literals, fixed_rules, scan, system_ops, PURE_FIXED_RULES};
+use crate::schema::{check_relation, SYSTEM_PREFIX};
+use crate::{CognitiveCore, CoreError};
+use cozo::{DataValue, ScriptMutability};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Which relations each principal may read and write, created on the first
+/// grant.
+const GRANTS: &str = "sovereign_grants";
+
+/// The pattern of the row every granted principal keeps, covering nothing,
+/// so revoking its last grant leaves it restricted rather than free.
+const MARKER: &str = "";
+
+/// Module principals are held to their grants even with none.
+const MODULE_PREFIX: &str = "module:";
+
+/// A principal that never had a grant, other than a module, is not held to
+/// any; one that has may only touch the relations its grants cover.
+#[derive(Debug, Clone)]
+pub struct Grant {
+    /// Whoever the caller says runs the query, e.g. `token:ops` or
+    /// `module:indexer`.
+    pub principal: String,
+    /// A stored relation name, or a prefix of one followed by `*`, as in
+    /// `app.*`. A fixed rule that reads outside the store, such as
+    /// `CsvReader`, is only allowed by a grant on exactly its name.
+    pub pattern: String,
+    pub rights: GrantRights,
+    pub granted_ms: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GrantRights {
+    Read,
+    /// Read and write.
+    Write,
+}
+
+impl GrantRights {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(GrantRights::Read),
+            "write" => Some(GrantRights::Write),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for GrantRights {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            GrantRights::Read => "read",
+            GrantRights::Write => "write",
+        })
+    }
+}
+
+impl Grant {
+    fn is_marker(&self) -> bool {
+        self.pattern == MARKER
+    }
+
+    fn covers(&self, relation: &str) -> bool {
+        match self.pattern.strip_suffix('*') {
+            Some(prefix) => relation.starts_with(prefix),
+            None => relation == self.pattern,
+        }
+    }
+}
+
+impl CognitiveCore {
+    /// Gives `principal` `rights` on the relations `pattern` matches,
+    /// replacing what it had on that pattern. Takes effect from the next
+    /// query or transaction statement it runs.
+    pub fn grant(&self, principal: &str, pattern: &str, rights: GrantRights) -> Result<Grant, CoreError> {
+        check_principal(principal)?;
+        check_pattern(pattern)?;
+        self.ensure_grants()?;
+        let grant = Grant {
+            principal: principal.to_string(),
+            pattern: pattern.to_string(),
+            rights,
+            granted_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64,
+        };
+        let rows = vec![grant_row(principal, pattern, rights, grant.granted_ms), grant_row(principal, MARKER, GrantRights::Read, grant.granted_ms)];
+        self.put_grants(rows)?;
+        self.load_grants()?;
+        Ok(grant)
+    }
+
+    /// Returns false if `principal` had no grant on exactly `pattern`. A
+    /// principal left with no grants stays restricted, to nothing.
+    pub fn revoke(&self, principal: &str, pattern: &str) -> Result<bool, CoreError> {
+        let held = self.grants().iter().any(|g| g.principal == principal && g.pattern == pattern && !g.is_marker());
+        if !held {
+            return Ok(false);
+        }
+        // Grants from before markers were kept have none yet.
+        let granted_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+        self.put_grants(vec![grant_row(principal, MARKER, GrantRights::Read, granted_ms)])?;
+        let script = format!("?[principal, pattern] <- [[$principal, $pattern]] :rm {} {{principal, pattern}}", GRANTS);
+        let params = BTreeMap::from([
+            ("principal".to_string(), DataValue::from(principal)),
+            ("pattern".to_string(), DataValue::from(pattern)),
+        ]);
+        self.script(&script, params, ScriptMutability::Mutable)?;
+        self.load_grants()?;
+        Ok(true)
+    }
+
+    /// Grants by principal then pattern, only `principal`'s if given.
+    pub fn list_grants(&self, principal: Option<&str>) -> Vec<Grant> {
+        self.grants().iter().filter(|g| !g.is_marker() && principal.is_none_or(|p| g.principal == p)).cloned().collect()
+    }
+
+    /// Fails with `CoreError::GrantDenied` unless `principal` may read, or
+    /// if `written` write, the stored relation `relation`. For callers that
+    /// touch a relation without a query, e.g. an import.
+    pub fn check_grant(&self, principal: &str, relation: &str, written: bool) -> Result<(), CoreError> {
+        let grants = self.grants();
+        let Some(held) = restricted(&grants, principal) else { return Ok(()) };
+        allowed(principal, &held, relation, written)
+    }
+
+    /// Reads the stored grants into the cache that checks consult.
+    pub(crate) fn load_grants(&self) -> Result<(), CoreError> {
+        let mut grants = Vec::new();
+        if self.relation_exists(GRANTS)? {
+            let script = format!("?[principal, pattern, rights, granted_ms] := *{}{{principal, pattern, rights, granted_ms}}", GRANTS);
+            let listed = self.script(&script, BTreeMap::new(), ScriptMutability::Immutable)?;
+            for row in listed.rows {
+                let text = |i: usize| row.get(i).and_then(DataValue::get_str).unwrap_or_default().to_string();
+                grants.push(Grant {
+                    principal: text(0),
+                    pattern: text(1),
+                    // Anything unreadable grants the least.
+                    rights: GrantRights::parse(&text(2)).unwrap_or(GrantRights::Read),
+                    granted_ms: row.get(3).and_then(DataValue::get_int).unwrap_or_default(),
+                });
+            }
+        }
+        grants.sort_by(|a, b| (&a.principal, &a.pattern).cmp(&(&b.principal, &b.pattern)));
+        *self.grants.write().unwrap_or_else(|e| e.into_inner()) = grants.into();
+        Ok(())
+    }
+
+    pub(crate) fn grants(&self) -> Arc<[Grant]> {
+        current(&self.grants)
+    }
+
+    fn put_grants(&self, rows: Vec<DataValue>) -> Result<(), CoreError> {
+        let script = format!(
+            "?[principal, pattern, rights, granted_ms] <- $rows :put {} {{principal, pattern => rights, granted_ms}}",
+            GRANTS
+        );
+        self.script(&script, BTreeMap::from([("rows".to_string(), DataValue::List(rows))]), ScriptMutability::Mutable)?;
+        Ok(())
+    }
+
+    fn ensure_grants(&self) -> Result<(), CoreError> {
+        if self.relation_exists(GRANTS)? {
+            return Ok(());
+        }
+        let create = format!(":create {} {{principal: String, pattern: String => rights: String, granted_ms: Int}}", GRANTS);
+        match self.script(&create, BTreeMap::new(), ScriptMutability::Mutable) {
+            // Another grant may have created it meanwhile.
+            Err(e) if !self.relation_exists(GRANTS)? => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The grants as they stand, for checks to hold on to while they run.
+pub(crate) fn current(grants: &RwLock<Arc<[Grant]>>) -> Arc<[Grant]> {
+    grants.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// `CognitiveCore::check_grant` for every relation `query` reads or writes,
+/// failing on the first it may not touch. A restricted principal may not
+/// run system ops, nor apply a fixed rule that reaches outside the store
+/// unless a grant names it.
+pub(crate) fn check_query(grants: &[Grant], principal: &str, query: &str) -> Result<(), CoreError> {
+    let Some(held) = restricted(grants, principal) else { return Ok(()) };
+    let text = blank_literals(query);
+    if !system_ops(&text).is_empty() {
+        return Err(CoreError::GrantDenied {
+            principal: principal.to_string(),
+            relation: None,
+            written: true,
+            fixed_rule: None,
+        });
+    }
+    for rule in fixed_rules(&text) {
+        let named = !rule.is_empty() && held.iter().any(|g| g.pattern == rule);
+        if !named && !PURE_FIXED_RULES.contains(&rule.as_str()) {
+            return Err(CoreError::GrantDenied {
+                principal: principal.to_string(),
+                relation: None,
+                written: false,
+                fixed_rule: Some(rule),
+            });
+        }
+    }
+    for reference in scan(&text).references {
+        let name = &query[reference.span];
+        if !name.starts_with('_') {
+            allowed(principal, &held, name, reference.written)?;
+        }
+    }
+    Ok(())
+}
+
+/// `principal`'s grants, markers included, or `None` if it is not held to
+/// any.
+fn restricted<'a>(grants: &'a [Grant], principal: &str) -> Option<Vec<&'a Grant>> {
+    let held: Vec<&Grant> = grants.iter().filter(|g| g.principal == principal).collect();
+    (!held.is_empty() || principal.starts_with(MODULE_PREFIX)).then_some(held)
+}
+
+fn grant_row(principal: &str, pattern: &str, rights: GrantRights, granted_ms: i64) -> DataValue {
+    DataValue::List(vec![
+        DataValue::from(principal),
+        DataValue::from(pattern),
+        DataValue::from(rights.to_string().as_str()),
+        DataValue::from(granted_ms),
+    ])
+}
+
+/// The core's own relations are never covered, whatever the pattern.
+fn allowed(principal: &str, held: &[&Grant], relation: &str, written: bool) -> Result<(), CoreError> {
+    let needed = if written { GrantRights::Write } else { GrantRights::Read };
+    let covered = !relation.starts_with(SYSTEM_PREFIX) && held.iter().any(|g| g.rights >= needed && g.covers(relation));
+    if covered {
+        return Ok(());
+    }
+    Err(CoreError::GrantDenied {
+        principal: principal.to_string(),
+        relation: Some(relation.to_string()),
+        written,
+        fixed_rule: None,
+    })
+}
+
+fn check_principal(principal: &str) -> Result<(), CoreError> {
+    let valid = !principal.is_empty() && principal.len() <= 128 && principal.chars().all(|c| c.is_ascii_graphic());
+    if valid {
+        Ok(())
+    } else {
+        Err(CoreError::InvalidName(format!("principal '{}' must be printable ASCII without spaces", principal.escape_debug())))
+    }
+}
+
+/// A relation name, or a prefix of one then `*`; the wildcard only ends a
+/// pattern.
+fn check_pattern(pattern: &str) -> Result<(), CoreError> {
+    let invalid = || CoreError::InvalidName(format!("'{}' is not a relation name, or a prefix of one followed by '*'", pattern.escape_debug()));
+    match pattern.strip_suffix('*') {
+        Some(prefix) => {
+            let plain = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+            if prefix.contains('*') || !prefix.chars().all(plain) || prefix.len() > 257 {
+                return Err(invalid());
+            }
+        }
+        None => check_relation(pattern).map_err(|_| invalid())?,
+    }
+    if pattern.starts_with(SYSTEM_PREFIX) {
+        return Err(CoreError::InvalidName(format!("'{}' names are reserved for the core", SYSTEM_PREFIX)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+    use crate::{CoreBackend, CoreConfig, QueryOptions};
+    use serde_json::{json, Value};
+    use std::path::PathBuf;
+
+    fn core_at(backend: CoreBackend) -> CognitiveCore {
+        let core = CognitiveCore::new(CoreConfig { backend, ..CoreConfig::default() }).unwrap();
+        for name in ["metrics_cpu", "metrics_disk", "secrets"] {
+            core.run(&format!(":create {} {{k: String => v: Int}}", name), Value::Null).unwrap();
+            core.run(&format!("?[k, v] <- [['a', 1]] :put {} {{k => v}}", name), Value::Null).unwrap();
+        }
+        core
+    }
+
+    fn run_as(core: &CognitiveCore, principal: &str, query: &str) -> Result<Value, CoreError> {
+        let options = QueryOptions {
+            principal: Some(principal.to_string()),
+            ..QueryOptions::default()
+        };
+        core.run_with(query, json!({}), &options)
+    }
+
+    fn denied(result: Result<Value, CoreError>) -> Option<String> {
+        match result {
+            Err(CoreError::GrantDenied { relation, .. }) => relation,
+            other => panic!("expected a grant denial, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn read_grant_blocks_writes() {
+        let core = core_at(CoreBackend::Mem);
+        core.grant("token:ro", "metrics_cpu", GrantRights::Read).unwrap();
+        run_as(&core, "token:ro", "?[k, v] := *metrics_cpu{k, v}").unwrap();
+        let write = run_as(&core, "token:ro", "?[k, v] <- [['b', 2]] :put metrics_cpu {k => v}");
+        assert_eq!(denied(write).as_deref(), Some("metrics_cpu"));
+        let other = run_as(&core, "token:ro", "?[k, v] := *secrets{k, v}");
+        assert_eq!(denied(other).as_deref(), Some("secrets"));
+    }
+
+    #[test]
+    fn wildcard_covers_its_prefix_only() {
+        let core = core_at(CoreBackend::Mem);
+        core.grant("token:ops", "metrics_*", GrantRights::Write).unwrap();
+        run_as(&core, "token:ops", "?[k, v] <- [['b', 2]] :put metrics_disk {k => v}").unwrap();
+        run_as(&core, "token:ops", "?[k, v] := *metrics_cpu{k, v}").unwrap();
+        assert_eq!(denied(run_as(&core, "token:ops", "?[k, v] := *secrets{k, v}")).as_deref(), Some("secrets"));
+        assert_eq!(denied(run_as(&core, "token:ops", "::relations")), None);
+    }
+
+    #[test]
+    fn mixed_query_fails_on_the_uncovered_relation() {
+        let core = core_at(CoreBackend::Mem);
+        core.grant("token:ro", "metrics_cpu", GrantRights::Read).unwrap();
+        let mixed = run_as(&core, "token:ro", "?[k, v, s] := *metrics_cpu{k, v}, *secrets{k, v: s}");
+        assert_eq!(denied(mixed).as_deref(), Some("secrets"));
+        let copy = run_as(&core, "token:ro", "?[k, v] := *metrics_cpu{k, v} :put metrics_disk {k => v}");
+        assert_eq!(denied(copy).as_deref(), Some("metrics_disk"));
+    }
+
+    #[test]
+    fn system_ops_are_denied_inside_blocks_too() {
+        let core = core_at(CoreBackend::Mem);
+        core.grant("token:ro", "metrics_cpu", GrantRights::Read).unwrap();
+        for query in [
+            "{ ::relations }",
+            "{ ::remove secrets }",
+            "{ ?[k] := *metrics_cpu{k} } { ::remove sovereign_grants }",
+        ] {
+            assert_eq!(denied(run_as(&core, "token:ro", query)), None, "{}", query);
+        }
+        assert!(core.relation_exists("secrets").unwrap());
+        assert!(core.relation_exists(GRANTS).unwrap());
+        assert_eq!(core.list_grants(Some("token:ro")).len(), 1);
+    }
+
+    #[test]
+    fn revoking_the_last_grant_does_not_widen_access() {
+        let core = core_at(CoreBackend::Mem);
+        run_as(&core, "token:ro", "?[k, v] := *secrets{k, v}").unwrap();
+        core.grant("token:ro", "metrics_*", GrantRights::Read).unwrap();
+        assert!(core.revoke("token:ro", "metrics_*").unwrap());
+        assert!(core.list_grants(Some("token:ro")).is_empty());
+        assert_eq!(denied(run_as(&core, "token:ro", "?[k, v] := *secrets{k, v}")).as_deref(), Some("secrets"));
+        assert!(core.check_grant("token:ro", "metrics_cpu", false).is_err());
+        assert!(!core.revoke("token:ro", MARKER).unwrap());
+    }
+
+    #[test]
+    fn modules_are_restricted_without_grants() {
+        let core = core_at(CoreBackend::Mem);
+        assert_eq!(denied(run_as(&core, "module:indexer", "?[k, v] := *metrics_cpu{k, v}")).as_deref(), Some("metrics_cpu"));
+        core.grant("module:indexer", "metrics_cpu", GrantRights::Read).unwrap();
+        run_as(&core, "module:indexer", "?[k, v] := *metrics_cpu{k, v}").unwrap();
+        // Principals never granted anything stay free.
+        run_as(&core, "uid:1000", "?[k, v] := *secrets{k, v}").unwrap();
+    }
+
+    #[test]
+    fn grants_survive_a_restart() {
+        let path = temp_path("grants");
+        {
+            let core = core_at(CoreBackend::Sqlite { path: path.clone() });
+            core.grant("token:ro", "metrics_*", GrantRights::Read).unwrap();
+            core.grant("token:gone", "secrets", GrantRights::Write).unwrap();
+            core.revoke("token:gone", "secrets").unwrap();
+        }
+        let core = CognitiveCore::new(CoreConfig {
+            backend: CoreBackend::Sqlite { path: path.clone() },
+            ..CoreConfig::default()
+        })
+        .unwrap();
+        let listed = core.list_grants(None);
+        assert_eq!(listed.len(), 1);
+        assert_eq!((listed[0].principal.as_str(), listed[0].pattern.as_str(), listed[0].rights), ("token:ro", "metrics_*", GrantRights::Read));
+        run_as(&core, "token:ro", "?[k, v] := *metrics_disk{k, v}").unwrap();
+        assert!(run_as(&core, "token:ro", "?[k, v] := *secrets{k, v}").is_err());
+        assert!(run_as(&core, "token:gone", "?[k, v] := *secrets{k, v}").is_err());
+        drop(core);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn fixed_rules_that_read_files_need_a_grant_naming_them() {
+        let core = core_at(CoreBackend::Mem);
+        let dir = TempDir::new("grant-fixed-rules");
+        let path = dir.path().join("secret.csv");
+        std::fs::write(&path, "hunter2\n").unwrap();
+        let read = format!("?[line] <~ CsvReader(types: ['String'], url: 'file://{}', has_headers: false)", path.display());
+        core.grant("token:ops", "*", GrantRights::Write).unwrap();
+        match run_as(&core, "token:ops", &read) {
+            Err(CoreError::GrantDenied { fixed_rule: Some(rule), .. }) => assert_eq!(rule, "CsvReader"),
+            other => panic!("expected a grant denial, got {:?}", other),
+        }
+        run_as(&core, "token:ops", "?[k] <~ Constant(data: [['x']])").unwrap();
+        core.grant("token:ops", "CsvReader", GrantRights::Read).unwrap();
+        assert_eq!(run_as(&core, "token:ops", &read).unwrap()["rows"], json!([["hunter2"]]));
+        // Principals never granted anything stay free.
+        run_as(&core, "uid:1000", &read).unwrap();
+    }
+
+    #[test]
+    fn patterns_are_checked() {
+        let core = core_at(CoreBackend::Mem);
+        for bad in ["", "a*b*", "sovereign_grants", "with space*"] {
+            assert!(core.grant("token:x", bad, GrantRights::Read).is_err(), "{:?} was accepted", bad);
+        }
+        assert!(core.grant("", "metrics_cpu", GrantRights::Read).is_err());
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        std::env::temp_dir().join(format!("sovereign-core-{}-{}-{}.db", name, std::process::id(), nanos))
+    }
+}
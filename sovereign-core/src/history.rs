@@ -0,0 +1,375 @@
+use crate::explain::{blank_literals, scan};
+use crate::params::bind_params;
+use crate::schema::check_relation;
+use crate::{result_json, CognitiveCore, CoreError, QueryOptions};
+use cozo::{DataValue, ScriptMutability, Validity, ValidityTs};
+use serde_json::Value;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// A relation's history is kept in `sovereign_history.<relation>`.
+const HISTORY_PREFIX: &str = "sovereign_history.";
+
+/// The history's last key column: when a row was written or retracted.
+pub(crate) const HISTORY_AT: &str = "sovereign_at";
+
+/// The parameter an as-of query's instant is bound to.
+const ASOF: &str = "sovereign_asof";
+
+impl CognitiveCore {
+    /// Starts keeping the history of `name`, from its rows as they are now.
+    /// From then on every `assert_facts` and `retract_facts` on it is also
+    /// recorded, so `run_asof` can read it as it was at an earlier instant.
+    /// Writes through `run`, transactions and imports are not recorded.
+    /// Does nothing if the history is already kept.
+    pub fn enable_history(&self, name: &str) -> Result<(), CoreError> {
+        check_relation(name)?;
+        if self.history_of(name)?.is_some() {
+            return Ok(());
+        }
+        let columns = self.columns(name)?;
+        let keys: Vec<&str> = columns.iter().filter(|c| c.key).map(|c| c.name.as_str()).collect();
+        let values: Vec<&str> = columns.iter().filter(|c| !c.key).map(|c| c.name.as_str()).collect();
+        // Values are nullable, as a retraction records none.
+        let mut spec: Vec<String> =
+            columns.iter().filter(|c| c.key).map(|c| format!("{}: {}", c.name, c.column_type)).collect();
+        spec.push(format!("{}: Validity", HISTORY_AT));
+        let value_spec: Vec<String> = columns
+            .iter()
+            .filter(|c| !c.key)
+            .map(|c| format!("{}: {}?", c.name, c.column_type.trim_end_matches('?')))
+            .collect();
+        let history = history_name(name);
+        let all: Vec<&str> = keys.iter().chain(&values).copied().collect();
+        let head: Vec<&str> = keys.iter().copied().chain([HISTORY_AT]).chain(values.iter().copied()).collect();
+        let script = format!(
+            "{{ :create {history} {{{} => {}}} }}\n\
+             {{ ?[{head}] := *{name}{{{all}}}, {AT} = $at :put {history} {{{head}}} }}",
+            spec.join(", "),
+            value_spec.join(", "),
+            history = history,
+            head = head.join(", "),
+            name = name,
+            all = all.join(", "),
+            AT = HISTORY_AT,
+        );
+        let params = BTreeMap::from([("at".to_string(), validity(self.history_clock.stamp(), true))]);
+        self.script(&script, params, ScriptMutability::Mutable)?;
+        Ok(())
+    }
+
+    /// Stops keeping the history of `name` and deletes what was kept.
+    pub fn disable_history(&self, name: &str) -> Result<(), CoreError> {
+        check_relation(name)?;
+        if let Some(history) = self.history_of(name)? {
+            self.script(&format!("::remove {}", history), BTreeMap::new(), ScriptMutability::Mutable)?;
+        }
+        Ok(())
+    }
+
+    /// `run`, read-only, with every relation that keeps its history read
+    /// as it was at `at_ms`, in milliseconds since the Unix epoch. Other
+    /// relations, and indices, are read as they are now. History that was
+    /// compacted away reads as it was at the compaction's cutoff.
+    pub fn run_asof(&self, query: &str, at_ms: i64, params: Value) -> Result<Value, CoreError> {
+        let mut params = bind_params(params)?;
+        params.insert(ASOF.to_string(), DataValue::from(end_of(at_ms)));
+        let query = self.travel(query)?;
+        let options = QueryOptions {
+            readonly: true,
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let rows = self.evaluate(&query, params, &options, self.result_caps(&options))?;
+        Ok(result_json(rows, started.elapsed()))
+    }
+
+    /// Compacts the history of `name` older than the configured retention,
+    /// and returns how many history rows were removed. Without a retention
+    /// nothing is removed.
+    pub fn compact_history(&self, name: &str) -> Result<u64, CoreError> {
+        let Some(retention) = self.history_retention else {
+            return Ok(0);
+        };
+        let cutoff = self.history_clock.now().saturating_sub(retention.as_micros() as i64);
+        self.compact_history_until(name, cutoff)
+    }
+
+    /// Removes the history of `name` that only matters before `cutoff_ms`:
+    /// every row but the last each key had at the cutoff, and that one too
+    /// if it was a retraction. As-of reads from the cutoff on are unchanged.
+    /// Runs as one transaction, and returns how many rows were removed.
+    pub fn compact_history_before(&self, name: &str, cutoff_ms: i64) -> Result<u64, CoreError> {
+        self.compact_history_until(name, end_of(cutoff_ms))
+    }
+
+    /// `compact_history_before` a cutoff in microseconds.
+    fn compact_history_until(&self, name: &str, cutoff_us: i64) -> Result<u64, CoreError> {
+        check_relation(name)?;
+        let Some(history) = self.history_of(name)? else {
+            return Ok(0);
+        };
+        let keys: Vec<String> = self.columns(name)?.into_iter().filter(|c| c.key).map(|c| c.name).collect();
+        let vars: Vec<String> = (0..keys.len()).map(|i| format!("k{}", i)).collect();
+        let bindings: Vec<String> = keys.iter().zip(&vars).map(|(c, v)| format!("{}: {}", c, v)).collect();
+        let found = format!(
+            "newest[{vars}, max(ts)] := *{history}{{{bindings}, {AT}: at}}, ts = to_int(at), ts <= $cutoff\n\
+             live[{vars}] := *{history}{{{bindings} @ $cutoff}}\n\
+             ?[{vars}, at] := *{history}{{{bindings}, {AT}: at}}, ts = to_int(at), newest[{vars}, last], ts < last\n\
+             ?[{vars}, at] := *{history}{{{bindings}, {AT}: at}}, ts = to_int(at), newest[{vars}, last], ts == last, \
+             not live[{vars}]",
+            vars = vars.join(", "),
+            bindings = bindings.join(", "),
+            history = history,
+            AT = HISTORY_AT,
+        );
+        let remove = format!(
+            "?[{keys}, {AT}] <- $rows :rm {history} {{{keys}, {AT}}}",
+            keys = keys.join(", "),
+            history = history,
+            AT = HISTORY_AT,
+        );
+        let cutoff = BTreeMap::from([("cutoff".to_string(), DataValue::from(cutoff_us))]);
+        self.transact(|tx| {
+            let stale = tx.exec_bound(&found, cutoff)?;
+            let removed = stale.rows.len() as u64;
+            if removed > 0 {
+                let rows = stale.rows.into_iter().map(DataValue::List).collect();
+                tx.exec_bound(&remove, BTreeMap::from([("rows".to_string(), DataValue::List(rows))]))?;
+            }
+            Ok(removed)
+        })
+    }
+
+    /// The history relation of `name`, if its history is kept.
+    pub(crate) fn history_of(&self, name: &str) -> Result<Option<String>, CoreError> {
+        let history = history_name(name);
+        Ok(self.relation_exists(&history)?.then_some(history))
+    }
+
+    /// `query` with each read of a relation that keeps its history made a
+    /// read of the history at `$sovereign_asof`.
+    fn travel(&self, query: &str) -> Result<String, CoreError> {
+        let text = blank_literals(query);
+        let mut histories: HashMap<String, Option<usize>> = self
+            .relation_names()?
+            .into_iter()
+            .filter_map(|name| Some(name.strip_prefix(HISTORY_PREFIX)?.to_string()))
+            .map(|name| (name, None))
+            .collect();
+        let mut travelled = String::with_capacity(query.len() + 64);
+        let mut copied = 0;
+        for reference in scan(&text).references {
+            let span = reference.span;
+            let name = &query[span.clone()];
+            let stored = span.start > 0 && text[span.start - 1] == b'*';
+            if reference.written || !stored || span.start < copied || !histories.contains_key(name) {
+                continue;
+            }
+            let open = span.end + text[span.end..].iter().take_while(|b| b.is_ascii_whitespace()).count();
+            // `*name:index{...}` reads an index, which has no history.
+            let Some((close, commas)) = bracketed(&text, open) else {
+                continue;
+            };
+            travelled.push_str(&query[copied..span.start]);
+            travelled.push_str(&history_name(name));
+            if text[open] == b'[' {
+                // Positional columns are keys, then values; the history's
+                // timestamp goes between them.
+                let keys = match histories[name] {
+                    Some(keys) => keys,
+                    None => {
+                        let keys = self.columns(name)?.iter().filter(|c| c.key).count();
+                        histories.insert(name.to_string(), Some(keys));
+                        keys
+                    }
+                };
+                let at = commas.get(keys.saturating_sub(1)).copied().unwrap_or(close);
+                travelled.push_str(&query[span.end..at]);
+                travelled.push_str(", _");
+                travelled.push_str(&query[at..close]);
+            } else {
+                travelled.push_str(&query[span.end..close]);
+            }
+            travelled.push_str(&format!(" @ ${}", ASOF));
+            copied = close;
+        }
+        travelled.push_str(&query[copied..]);
+        Ok(travelled)
+    }
+}
+
+/// The closing bracket of the `{` or `[` at `open`, and the commas between
+/// them that are not nested further.
+fn bracketed(text: &[u8], open: usize) -> Option<(usize, Vec<usize>)> {
+    let closing = match text.get(open)? {
+        b'{' => b'}',
+        b'[' => b']',
+        _ => return None,
+    };
+    let mut depth = 0;
+    let mut commas = Vec::new();
+    for (i, &b) in text.iter().enumerate().skip(open + 1) {
+        match b {
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' if depth > 0 => depth -= 1,
+            b if b == closing => return Some((i, commas)),
+            b',' if depth == 0 => commas.push(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+pub(crate) fn history_name(name: &str) -> String {
+    format!("{}{}", HISTORY_PREFIX, name)
+}
+
+/// The history's timestamp for a write at `at_us`, in microseconds since
+/// the Unix epoch.
+pub(crate) fn validity(at_us: i64, asserted: bool) -> DataValue {
+    DataValue::Validity(Validity {
+        timestamp: ValidityTs(Reverse(at_us)),
+        is_assert: Reverse(asserted),
+    })
+}
+
+/// The last microsecond of `at_ms`, so that an instant given in
+/// milliseconds takes in every write made during it.
+fn end_of(at_ms: i64) -> i64 {
+    at_ms.saturating_mul(1000).saturating_add(999)
+}
+
+/// Timestamps for the core's writes to history. Two writes given the same
+/// timestamp would be one history row, the later overwriting the earlier,
+/// so each write is stamped after the last even if the clock has not moved
+/// on.
+#[derive(Default)]
+pub(crate) struct HistoryClock {
+    /// The last stamp given, in microseconds.
+    last: AtomicI64,
+}
+
+impl HistoryClock {
+    /// A stamp for a write now, later than any given before.
+    pub(crate) fn stamp(&self) -> i64 {
+        let now = now_us();
+        let previous = self
+            .last
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |last| Some(now.max(last.saturating_add(1))))
+            .unwrap_or_else(|last| last);
+        now.max(previous.saturating_add(1))
+    }
+
+    /// Now, or the last stamp given if that is later.
+    pub(crate) fn now(&self) -> i64 {
+        now_us().max(self.last.load(Ordering::Acquire))
+    }
+}
+
+fn now_us() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CoreConfig;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn core(retention: Option<Duration>) -> CognitiveCore {
+        let core = CognitiveCore::new(CoreConfig {
+            history_retention: retention,
+            ..CoreConfig::default()
+        })
+        .unwrap();
+        core.run(":create prices {sym: String => px: Int}", Value::Null).unwrap();
+        core.run("?[sym, px] <- [['a', 1], ['b', 10]] :put prices {sym => px}", Value::Null).unwrap();
+        core
+    }
+
+    fn px_at(core: &CognitiveCore, at_ms: i64) -> Value {
+        let named = core.run_asof("?[sym, px] := *prices{sym, px} :order sym", at_ms, Value::Null).unwrap();
+        let positional = core.run_asof("?[sym, px] := *prices[sym, px] :order sym", at_ms, Value::Null).unwrap();
+        assert_eq!(named["rows"], positional["rows"]);
+        named["rows"].clone()
+    }
+
+    fn kept(core: &CognitiveCore) -> Value {
+        let query = format!("?[sym, px] := *{}{{sym, px}} :order sym", history_name("prices"));
+        core.run(&query, Value::Null).unwrap()["rows"].clone()
+    }
+
+    /// `a` goes 1, 2, 3 and is then retracted, a second apart from `t0`;
+    /// `b` stays 10 throughout.
+    fn write_history(core: &CognitiveCore) -> i64 {
+        core.enable_history("prices").unwrap();
+        let t0 = now_us() / 1000;
+        core.assert_facts_at("prices", vec![json!({"sym": "a", "px": 2})], t0 + 1000).unwrap();
+        core.assert_facts_at("prices", vec![json!({"sym": "a", "px": 3})], t0 + 2000).unwrap();
+        core.retract_facts_at("prices", vec![json!({"sym": "a"})], t0 + 3000).unwrap();
+        t0
+    }
+
+    #[test]
+    fn reads_relations_as_they_were() {
+        let core = core(None);
+        let t0 = write_history(&core);
+        assert_eq!(px_at(&core, t0 + 500), json!([["a", 1], ["b", 10]]));
+        assert_eq!(px_at(&core, t0 + 1500), json!([["a", 2], ["b", 10]]));
+        assert_eq!(px_at(&core, t0 + 2500), json!([["a", 3], ["b", 10]]));
+        assert_eq!(px_at(&core, t0 + 3500), json!([["b", 10]]));
+        // Enabling again keeps what was recorded.
+        core.enable_history("prices").unwrap();
+        assert_eq!(px_at(&core, t0 + 1500), json!([["a", 2], ["b", 10]]));
+    }
+
+    #[test]
+    fn compaction_keeps_reads_from_the_cutoff_on() {
+        let core = core(None);
+        let t0 = write_history(&core);
+        assert_eq!(kept(&core).as_array().unwrap().len(), 5);
+
+        // Before the first cutoff `a` was 1 then 2; only the 2 still
+        // matters there.
+        assert_eq!(core.compact_history_before("prices", t0 + 1500).unwrap(), 1);
+        assert_eq!(px_at(&core, t0 + 1500), json!([["a", 2], ["b", 10]]));
+        assert_eq!(px_at(&core, t0 + 2500), json!([["a", 3], ["b", 10]]));
+        assert_eq!(core.compact_history_before("prices", t0 + 1500).unwrap(), 0);
+
+        // Once `a` is retracted none of its history matters.
+        assert_eq!(core.compact_history_before("prices", t0 + 3500).unwrap(), 3);
+        assert_eq!(kept(&core), json!([["b", 10]]));
+        assert_eq!(px_at(&core, t0 + 3500), json!([["b", 10]]));
+        assert_eq!(core.run("?[sym] := *prices{sym}", Value::Null).unwrap()["rows"], json!([["b"]]));
+    }
+
+    #[test]
+    fn retention_sets_the_cutoff() {
+        let core = core(None);
+        write_history(&core);
+        assert_eq!(core.compact_history("prices").unwrap(), 0);
+
+        // Enabling and writing in the same instant still records both.
+        let core = self::core(Some(Duration::ZERO));
+        core.enable_history("prices").unwrap();
+        core.assert_facts("prices", vec![json!({"sym": "a", "px": 2})]).unwrap();
+        // The first `a` was replaced before the cutoff; `b` never was.
+        assert_eq!(core.compact_history("prices").unwrap(), 1);
+        assert_eq!(kept(&core), json!([["a", 2], ["b", 10]]));
+        assert_eq!(core.compact_history("missing_history").unwrap(), 0);
+    }
+
+    #[test]
+    fn disabling_deletes_the_history() {
+        let core = core(None);
+        write_history(&core);
+        core.disable_history("prices").unwrap();
+        assert!(!core.relation_exists(&history_name("prices")).unwrap());
+        assert_eq!(px_at(&core, 0), json!([["b", 10]]));
+        core.disable_history("prices").unwrap();
+    }
+}
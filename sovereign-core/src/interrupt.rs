@@ -0,0 +1,323 @@
+use crate::engine::Engine;
+use crate::{AuditSource, CoreError};
+use cozo::{DataValue, NamedRows, ScriptMutability};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// How often a waiting query checks its deadline and cancel token.
+const POLL: Duration = Duration::from_millis(10);
+/// How often a starting query looks for its engine id.
+const LAUNCH_POLL: Duration = Duration::from_millis(1);
+
+/// Cancels a core query from another thread; cloning shares the token.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// Per-call overrides for `CognitiveCore::run_with`.
+#[derive(Debug, Clone, Default)]
+pub struct QueryOptions {
+    /// Replaces `CoreConfig::query_timeout` for this call.
+    pub timeout: Option<Duration>,
+    pub cancel: Option<CancelToken>,
+    /// Run the query read-only; the engine rejects one that would write.
+    pub readonly: bool,
+    /// Who the query is recorded as run by, if queries are audited.
+    pub source: AuditSource,
+    /// Confines the query to a namespace, as `CognitiveCore::run_in` does.
+    pub namespace: Option<String>,
+    /// Holds the query to this principal's grants, if it has any; see
+    /// `CognitiveCore::grant`.
+    pub principal: Option<String>,
+    /// Lowers `CoreConfig::max_result_rows`, or for `run_streaming`
+    /// `CoreConfig::max_streamed_rows`, for this call; it cannot raise them.
+    pub max_rows: Option<u64>,
+}
+
+/// How the core's queries start: one at a time, so each can be told apart
+/// from the others in the engine's running list.
+#[derive(Default)]
+pub(crate) struct Launch {
+    lock: Mutex<()>,
+}
+
+impl Launch {
+    /// Takes the start lock, for a query whose id must not be taken for
+    /// that of one being started.
+    pub(crate) fn hold(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Runs `query` on its own thread and stops waiting for it once `timeout`
+/// passes or `cancel` fires, so the caller is never held longer than that.
+///
+/// The engine can only stop a query by its id, which it does not return.
+/// A watcher thread finds it: holding the start lock, so no other query of
+/// ours starts meanwhile, it starts the query and waits for a new id to appear
+/// in the engine's running list. An abandoned query is then killed, which
+/// the engine notices at its next evaluation step. For a multi-query script
+/// only the first query is identified.
+pub(crate) fn run_interruptible(
+    db: &Engine,
+    launch: &Arc<Launch>,
+    query: &str,
+    params: BTreeMap<String, DataValue>,
+    mutability: ScriptMutability,
+    timeout: Option<Duration>,
+    cancel: Option<&CancelToken>,
+) -> Result<NamedRows, CoreError> {
+    let started = Instant::now();
+    let abandoned = Arc::new(AtomicBool::new(false));
+    let (reply, result) = mpsc::channel();
+    {
+        let (db, launch) = (db.clone(), launch.clone());
+        let query = query.to_string();
+        let abandoned = abandoned.clone();
+        std::thread::Builder::new()
+            .name("core-query".into())
+            .spawn(move || watch(db, launch, query, params, mutability, abandoned, reply))
+            .map_err(|e| CoreError::Interrupted(format!("cannot start the query thread: {}", e)))?;
+    }
+
+    let interrupted = loop {
+        match result.recv_timeout(POLL) {
+            Ok(outcome) => return outcome.map_err(|e| CoreError::from_report(&e, query)),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err(CoreError::Interrupted("the query thread stopped without a result".into()))
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+        }
+        if cancel.is_some_and(CancelToken::is_cancelled) {
+            break CoreError::Cancelled;
+        }
+        if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+            break CoreError::Timeout { elapsed: started.elapsed() };
+        }
+    };
+    abandoned.store(true, Ordering::Release);
+    Err(interrupted)
+}
+
+/// Starts the query, identifies it, and kills it if it is abandoned.
+fn watch(
+    db: Engine,
+    launch: Arc<Launch>,
+    query: String,
+    params: BTreeMap<String, DataValue>,
+    mutability: ScriptMutability,
+    abandoned: Arc<AtomicBool>,
+    reply: mpsc::Sender<Result<NamedRows, cozo::Error>>,
+) {
+    let (done_tx, done) = mpsc::channel();
+    let id = {
+        let _launch = launch.hold();
+        let before = running_ids(&db);
+        let worker_db = db.clone();
+        std::thread::spawn(move || {
+            let _ = done_tx.send(worker_db.run_script(&query, params, mutability));
+        });
+        loop {
+            match done.recv_timeout(LAUNCH_POLL) {
+                Ok(outcome) => {
+                    let _ = reply.send(outcome);
+                    return;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+            }
+            // Listing waits while a write commits, ours included.
+            if let Some(id) = running_ids(&db).difference(&before).min() {
+                break *id;
+            }
+        }
+    };
+    loop {
+        match done.recv_timeout(POLL) {
+            Ok(outcome) => {
+                let _ = reply.send(outcome);
+                return;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+        }
+        if abandoned.load(Ordering::Acquire) {
+            let _ = db.run_script(&format!("::kill {}", id), BTreeMap::new(), ScriptMutability::Immutable);
+            return;
+        }
+    }
+}
+
+fn running_ids(db: &Engine) -> HashSet<i64> {
+    let Ok(running) = db.run_script("::running", BTreeMap::new(), ScriptMutability::Immutable) else {
+        return HashSet::new();
+    };
+    running.rows.iter().filter_map(|row| row.first().and_then(DataValue::get_int)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+    use crate::{CognitiveCore, CoreBackend, CoreConfig};
+    use serde_json::{json, Value};
+
+    /// Counts up forever: every round of the rule derives one more row.
+    const EXPLOSIVE: &str = "r[n] := n = 0\nr[m] := r[n], m = n + 1\n?[n] := r[n]";
+
+    /// A write that takes a while, but ends on its own.
+    const COUNTED: &str = "r[n] := n = 0\nr[m] := r[n], m = n + 1, m < 200000\n?[n] := r[n] :put counted {n}";
+    const COUNT: u64 = 200000;
+
+    /// Read-only: written, the listing would wait on the queries it lists.
+    fn running(core: &CognitiveCore) -> Value {
+        let read = QueryOptions {
+            readonly: true,
+            ..Default::default()
+        };
+        core.run_with("::running", Value::Null, &read).unwrap()["rows"].clone()
+    }
+
+    /// Bounded, so that a query that cannot be stopped fails the test
+    /// rather than growing until the machine runs out of memory.
+    fn cancellable(cancel: &CancelToken) -> QueryOptions {
+        QueryOptions {
+            timeout: Some(Duration::from_secs(5)),
+            cancel: Some(cancel.clone()),
+            ..Default::default()
+        }
+    }
+
+    /// Waits for the engine to notice its query was killed.
+    fn wait_until_idle(core: &CognitiveCore) {
+        let started = Instant::now();
+        while running(core) != json!([]) {
+            assert!(started.elapsed() < Duration::from_secs(10), "the abandoned query is still running");
+            std::thread::sleep(POLL);
+        }
+    }
+
+    #[test]
+    fn times_out_a_runaway_query_and_serves_the_next_at_once() {
+        let core = CognitiveCore::new(CoreConfig {
+            query_timeout: Some(Duration::from_millis(200)),
+            ..CoreConfig::default()
+        })
+        .unwrap();
+        let started = Instant::now();
+        match core.run(EXPLOSIVE, Value::Null) {
+            Err(CoreError::Timeout { elapsed }) => assert!(elapsed >= Duration::from_millis(200)),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let started = Instant::now();
+        assert_eq!(core.run("?[x] <- [[1]]", Value::Null).unwrap()["rows"], json!([[1]]));
+        assert!(started.elapsed() < Duration::from_millis(500), "took {:?}", started.elapsed());
+        wait_until_idle(&core);
+
+        // A per-call timeout replaces the configured one.
+        let options = QueryOptions {
+            timeout: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        assert!(matches!(core.run_with(EXPLOSIVE, Value::Null, &options), Err(CoreError::Timeout { .. })));
+        assert!(started.elapsed() < Duration::from_millis(200));
+    }
+
+    #[test]
+    fn a_cancel_token_stops_its_query_only() {
+        let core = CognitiveCore::new(CoreConfig {
+            query_timeout: Some(Duration::from_secs(5)),
+            ..CoreConfig::default()
+        })
+        .unwrap();
+        let cancel = CancelToken::new();
+        let options = cancellable(&cancel);
+        std::thread::scope(|scope| {
+            let runaway = scope.spawn(|| core.run_with(EXPLOSIVE, Value::Null, &options));
+            std::thread::sleep(Duration::from_millis(100));
+            // Other queries go on meanwhile.
+            let started = Instant::now();
+            assert_eq!(core.run("?[x] <- [[2]]", Value::Null).unwrap()["rows"], json!([[2]]));
+            assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
+            assert!(!runaway.is_finished());
+            cancel.cancel();
+            assert!(matches!(runaway.join().unwrap(), Err(CoreError::Cancelled)));
+        });
+        wait_until_idle(&core);
+    }
+
+    #[test]
+    fn queries_started_together_are_each_stopped_by_their_own_token() {
+        let core = CognitiveCore::new(CoreConfig::default()).unwrap();
+        let (first, second) = (CancelToken::new(), CancelToken::new());
+        let (first_options, second_options) = (cancellable(&first), cancellable(&second));
+        std::thread::scope(|scope| {
+            let first_run = scope.spawn(|| core.run_with(EXPLOSIVE, Value::Null, &first_options));
+            let second_run = scope.spawn(|| core.run_with(EXPLOSIVE, Value::Null, &second_options));
+            std::thread::sleep(Duration::from_millis(200));
+            first.cancel();
+            assert!(matches!(first_run.join().unwrap(), Err(CoreError::Cancelled)));
+            std::thread::sleep(Duration::from_millis(200));
+            assert!(!second_run.is_finished());
+            assert_eq!(running(&core).as_array().unwrap().len(), 1, "the first query is still running");
+            second.cancel();
+            assert!(matches!(second_run.join().unwrap(), Err(CoreError::Cancelled)));
+        });
+        wait_until_idle(&core);
+    }
+
+    #[test]
+    fn a_query_is_stopped_while_a_transaction_statement_runs() {
+        let dir = TempDir::new("interrupt");
+        let mut backends = vec![CoreBackend::Mem, CoreBackend::Sqlite { path: dir.path().join("core.db") }];
+        if cfg!(feature = "rocksdb") {
+            backends.push(CoreBackend::RocksDb { path: dir.path().join("core.rocks") });
+        }
+        for backend in backends {
+            let core = CognitiveCore::new(CoreConfig {
+                backend: backend.clone(),
+                ..CoreConfig::default()
+            })
+            .unwrap();
+            core.run(":create counted {n: Int}", Value::Null).unwrap();
+            let cancel = CancelToken::new();
+            let options = cancellable(&cancel);
+            std::thread::scope(|scope| {
+                let runaway = scope.spawn(|| core.run_with(EXPLOSIVE, Value::Null, &options));
+                std::thread::sleep(Duration::from_millis(100));
+                // The statement runs beside the query.
+                let mut tx = core.begin().unwrap();
+                let session = scope.spawn(move || {
+                    tx.exec(COUNTED, Value::Null)?;
+                    tx.commit()
+                });
+                std::thread::sleep(Duration::from_millis(100));
+                cancel.cancel();
+                assert!(matches!(runaway.join().unwrap(), Err(CoreError::Cancelled)), "{}", backend.name());
+                // The statement is not the query stopped in its place.
+                assert_eq!(session.join().unwrap().unwrap(), 1, "{}", backend.name());
+            });
+            wait_until_idle(&core);
+            let counted = core.run("?[count(n)] := *counted{n}", Value::Null).unwrap();
+            assert_eq!(counted["rows"], json!([[COUNT]]), "{}", backend.name());
+        }
+    }
+}
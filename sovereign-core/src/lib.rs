@@ -1,22 +1,456 @@
 use anyhow::Result;
+use audit::AuditLog;
+use interrupt::Launch;
+use cozo::{DataValue, NamedRows, ScriptMutability};
+use engine::Engine;
+use history::HistoryClock;
+use limits::ResultCaps;
+use params::bind_params;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
+mod audit;
+mod bulk;
+mod engine;
+mod error;
+mod explain;
+mod facts;
+mod fts;
+mod grants;
+mod history;
+mod interrupt;
+mod limits;
+mod migrate;
+mod named;
+mod namespace;
+mod params;
+mod restore;
+mod schema;
+mod storage;
+mod stream;
+#[cfg(test)]
+mod temp_dir;
+mod transaction;
+mod vector;
+mod watch;
+
+pub use audit::{AuditConfig, AuditEntry, AuditRedaction, AuditSink, AuditSource};
+pub use bulk::{DataFormat, ImportMode, ImportSummary, RowReject, MAX_REPORTED_REJECTS};
+pub use error::{CoreError, QueryError};
+pub use explain::{ExplainRelation, ExplainReport};
+pub use interrupt::{CancelToken, QueryOptions};
+pub use migrate::{AppliedMigration, Migration, MigrationStep};
+pub use named::{NamedQuery, ParamSpec, ParamType};
+pub use namespace::{resolve_relation, SHARED_NAMESPACE};
+pub use fts::{FtsFilter, FtsOptions, FtsTokenizer};
+pub use grants::{Grant, GrantRights};
+pub use restore::RelationRestore;
+pub use schema::{ColumnDef, ColumnInfo, ColumnType, IndexInfo, RelationInfo, RelationSchema};
+pub use storage::CoreBackend;
+pub use stream::RowStream;
+pub use transaction::CoreTransaction;
+pub use vector::{VectorIndexOptions, VectorMetric, MAX_KNN, MAX_VECTOR_DIM};
+pub use watch::{ChangeOp, CoreChangeEvent, WatchHandle};
+
+/// Defaults to the in-memory backend; the node stores its core in SQLite
+/// under its data directory.
+#[derive(Debug, Clone)]
+pub struct CoreConfig {
+    pub backend: CoreBackend,
+    /// How long `run` waits for a query; `None` waits indefinitely.
+    pub query_timeout: Option<Duration>,
+    /// Applied by `migrate`. Opening a store migrated past the last of
+    /// these fails.
+    pub migrations: &'static [Migration],
+    /// Where to record each query run; `None` records nothing.
+    pub audit: Option<AuditConfig>,
+    /// How much relation history `compact_history` keeps; `None` keeps all.
+    pub history_retention: Option<Duration>,
+    /// Rows a query may return; more fail with `CoreError::ResultTooLarge`.
+    /// The engine has no cap on its working memory, but a single read
+    /// query is stopped one row past this one.
+    pub max_result_rows: Option<u64>,
+    /// Bytes a query's result may take, as estimated from the engine's
+    /// values before they are converted to JSON.
+    pub max_result_bytes: Option<u64>,
+    /// Rows `run_streaming` may return, which are not held to the caps
+    /// above.
+    pub max_streamed_rows: Option<u64>,
+}
+
+impl Default for CoreConfig {
+    fn default() -> Self {
+        Self {
+            backend: CoreBackend::default(),
+            query_timeout: Some(Duration::from_secs(30)),
+            migrations: &[],
+            audit: None,
+            history_retention: None,
+            max_result_rows: Some(1_000_000),
+            max_result_bytes: Some(256 * 1024 * 1024),
+            max_streamed_rows: Some(100_000_000),
+        }
+    }
+}
+
+/// Where the core keeps its data and how much of it there is.
+#[derive(Debug, Clone)]
+pub struct CoreStats {
+    /// `mem`, `sqlite` or `rocksdb`.
+    pub backend: &'static str,
+    pub path: Option<PathBuf>,
+    pub size_bytes: u64,
+}
+
+/// How often a write waiting for another checks its deadline and cancel
+/// token.
+const WRITER_POLL: Duration = Duration::from_millis(10);
+
+/// The cognitive layer: an embedded Cozo database queried with CozoScript.
+/// Shared between threads as is; the engine does its own locking.
 pub struct CognitiveCore {
-    // stub: db not used
+    db: Engine,
+    backend: CoreBackend,
+    /// Set while a `CoreTransaction` is open.
+    open: Arc<AtomicBool>,
+    /// How queries start; see `interrupt::run_interruptible`.
+    launch: Arc<Launch>,
+    query_timeout: Option<Duration>,
+    migrations: &'static [Migration],
+    audit: Option<Arc<AuditLog>>,
+    history_retention: Option<Duration>,
+    /// When the core's own writes are recorded in history.
+    history_clock: HistoryClock,
+    max_result_rows: Option<u64>,
+    max_result_bytes: Option<u64>,
+    max_streamed_rows: Option<u64>,
+    /// The stored grants, read at open and after each change.
+    grants: Arc<RwLock<Arc<[Grant]>>>,
 }
 
 impl CognitiveCore {
-    pub fn new() -> Result<Self> {
-        Ok(Self {})
+    /// Opens the configured store. Fails if the store at the path was
+    /// written by another backend or engine release, or migrated past the
+    /// configured migrations.
+    pub fn new(config: CoreConfig) -> Result<Self> {
+        let db = config.backend.open()?;
+        let launch = Arc::new(Launch::default());
+        let audit = config.audit.map(|audit| AuditLog::open(audit, &db, &launch).map(Arc::new)).transpose()?;
+        let core = Self {
+            db,
+            backend: config.backend,
+            open: Arc::new(AtomicBool::new(false)),
+            launch,
+            query_timeout: config.query_timeout,
+            migrations: config.migrations,
+            audit,
+            history_retention: config.history_retention,
+            history_clock: HistoryClock::default(),
+            max_result_rows: config.max_result_rows,
+            max_result_bytes: config.max_result_bytes,
+            max_streamed_rows: config.max_streamed_rows,
+            grants: Arc::new(RwLock::new(Arc::from([]))),
+        };
+        core.check_schema_version()?;
+        core.load_grants()?;
+        Ok(core)
+    }
+
+    pub fn stats(&self) -> CoreStats {
+        CoreStats {
+            backend: self.backend.name(),
+            path: self.backend.path().map(|p| p.to_path_buf()),
+            size_bytes: self.backend.size_on_disk(),
+        }
+    }
+
+    /// Runs a CozoScript query with `params` bound as `$name` parameters,
+    /// committing it on its own. Unlike `CoreTransaction::exec` it accepts
+    /// system ops and multi-query scripts, which still commit atomically.
+    ///
+    /// Queries run concurrently, on committed state. Writes run one at a
+    /// time: a write waits, within the timeout, for the one before it or for
+    /// an open `CoreTransaction` that has written to end.
+    ///
+    /// Returns `{ "headers": [...], "rows": [[...], ...], "took_ms": f64 }`,
+    /// or `CoreError::Timeout` once the configured query timeout passes, or
+    /// `CoreError::ResultTooLarge` for a result over the configured caps.
+    pub fn run(&self, query: &str, params: serde_json::Value) -> Result<serde_json::Value, CoreError> {
+        self.run_with(query, params, &QueryOptions::default())
+    }
+
+    /// `run` with a per-call timeout, a token to cancel the query with, and
+    /// optionally read-only.
+    pub fn run_with(
+        &self,
+        query: &str,
+        params: serde_json::Value,
+        options: &QueryOptions,
+    ) -> Result<serde_json::Value, CoreError> {
+        let params = bind_params(params)?;
+        let started = Instant::now();
+        let rows = self.evaluate(query, params, options, self.result_caps(options))?;
+        Ok(result_json(rows, started.elapsed()))
+    }
+
+    /// `run_with`, returning the rows one at a time instead of as one JSON
+    /// document, for results too large to build as one.
+    ///
+    /// The engine evaluates a query to completion before it hands over any
+    /// rows, so the timeout and cancel token stop evaluation but the whole
+    /// result is still held once, as engine values. Each row becomes JSON
+    /// only as it is read, and is freed once read. Only
+    /// `CoreConfig::max_streamed_rows` caps the result.
+    pub fn run_streaming(
+        &self,
+        query: &str,
+        params: serde_json::Value,
+        options: &QueryOptions,
+    ) -> Result<RowStream, CoreError> {
+        let params = bind_params(params)?;
+        let started = Instant::now();
+        let rows = self.evaluate(query, params, options, self.stream_caps(options))?;
+        Ok(RowStream::new(rows, started.elapsed()))
+    }
+
+    fn evaluate(
+        &self,
+        query: &str,
+        mut params: BTreeMap<String, DataValue>,
+        options: &QueryOptions,
+        caps: ResultCaps,
+    ) -> Result<NamedRows, CoreError> {
+        let mutability = if options.readonly {
+            ScriptMutability::Immutable
+        } else {
+            ScriptMutability::Mutable
+        };
+        let timeout = options.timeout.or(self.query_timeout);
+        let script = match &options.namespace {
+            None => query.to_string(),
+            Some(namespace) => {
+                let confined = namespace::confine(namespace, query)?;
+                if !confined.created.is_empty() {
+                    self.ensure_owners()?;
+                }
+                let (script, owners) = confined.script();
+                if let Some(owners) = owners {
+                    params.insert(namespace::OWNER_ROWS.to_string(), owners);
+                }
+                if let Some(principal) = &options.principal {
+                    grants::check_query(&self.grants(), principal, &confined.query)?;
+                }
+                script
+            }
+        };
+        if let (None, Some(principal)) = (&options.namespace, &options.principal) {
+            grants::check_query(&self.grants(), principal, query)?;
+        }
+        let script = caps.bound(&script);
+        let rows = self.launch(&script, params, mutability, timeout, options.cancel.as_ref(), &options.source)?;
+        caps.check(&rows)?;
+        Ok(rows)
+    }
+
+    /// Runs a whole script as one engine transaction, within the configured
+    /// query timeout.
+    fn script(
+        &self,
+        query: &str,
+        params: BTreeMap<String, DataValue>,
+        mutability: ScriptMutability,
+    ) -> Result<NamedRows, CoreError> {
+        self.launch(query, params, mutability, self.query_timeout, None, &AuditSource::Internal)
+    }
+
+    /// Every engine query outside a transaction starts here, so each can be
+    /// told apart from the others when it has to be stopped. Audited, if
+    /// auditing is on, once it has started.
+    fn launch(
+        &self,
+        query: &str,
+        params: BTreeMap<String, DataValue>,
+        mutability: ScriptMutability,
+        timeout: Option<Duration>,
+        cancel: Option<&CancelToken>,
+        source: &AuditSource,
+    ) -> Result<NamedRows, CoreError> {
+        let pending = self.audit.as_ref().map(|audit| audit.start(source, query, &params));
+        let outcome = self.run_waiting(query, params, mutability, timeout, cancel);
+        if let (Some(audit), Some(pending)) = (&self.audit, pending) {
+            audit.finish(pending, &outcome);
+        }
+        outcome
+    }
+
+    /// Runs `query`, and runs it again each time it fails for meeting
+    /// another write, which leaves nothing behind, once that write ends.
+    fn run_waiting(
+        &self,
+        query: &str,
+        params: BTreeMap<String, DataValue>,
+        mutability: ScriptMutability,
+        timeout: Option<Duration>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<NamedRows, CoreError> {
+        let started = Instant::now();
+        loop {
+            let left = timeout.map(|timeout| timeout.saturating_sub(started.elapsed()));
+            match interrupt::run_interruptible(&self.db, &self.launch, query, params.clone(), mutability, left, cancel) {
+                Err(CoreError::TransactionConflict(_)) => {}
+                Err(CoreError::Timeout { .. }) => return Err(CoreError::Timeout { elapsed: started.elapsed() }),
+                outcome => return outcome,
+            }
+            if cancel.is_some_and(CancelToken::is_cancelled) {
+                return Err(CoreError::Cancelled);
+            }
+            if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+                return Err(CoreError::Timeout { elapsed: started.elapsed() });
+            }
+            self.db.wait_for_writer(WRITER_POLL);
+        }
+    }
+}
+
+pub(crate) fn result_json(result: NamedRows, took: Duration) -> serde_json::Value {
+    let rows: Vec<Vec<serde_json::Value>> = result
+        .rows
+        .into_iter()
+        .map(|row| row.into_iter().map(serde_json::Value::from).collect())
+        .collect();
+    serde_json::json!({
+        "headers": result.headers,
+        "rows": rows,
+        "took_ms": took.as_secs_f64() * 1000.0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn core() -> CognitiveCore {
+        CognitiveCore::new(CoreConfig::default()).unwrap()
     }
 
-    pub fn run(&mut self, query: &str, params: serde_json::Value) -> Result<serde_json::Value> {
-        // Stub: For now, just return a simple response
-        // In a full implementation, execute the Datalog query against Cozo
-        let response = serde_json::json!({
-            "result": "stub_response",
-            "query": query,
-            "params": params
+    #[test]
+    fn stores_rows_and_queries_them_back() {
+        let core = core();
+        core.run(":create people {name: String => age: Int, city: String}", Value::Null).unwrap();
+        core.run(
+            "?[name, age, city] <- $rows :put people {name => age, city}",
+            json!({"rows": [["ada", 36, "london"], ["alan", 41, "wilmslow"], ["grace", 85, "arlington"]]}),
+        )
+        .unwrap();
+
+        let result = core.run("?[name, age] := *people{name, age}, age >= $min :order name", json!({"min": 40})).unwrap();
+        assert_eq!(result["headers"], json!(["name", "age"]));
+        assert_eq!(result["rows"], json!([["alan", 41], ["grace", 85]]));
+        assert!(result["took_ms"].as_f64().is_some_and(|took| took >= 0.0));
+
+        let empty = core.run("?[name] := *people{name, city: 'paris'}", Value::Null).unwrap();
+        assert_eq!(empty["rows"], json!([]));
+    }
+
+    #[test]
+    fn engine_errors_keep_their_detail() {
+        let core = core();
+        core.run(":create people {name: String => age: Int}", Value::Null).unwrap();
+
+        match core.run("?[name] :=\n  *people{name,, age}", Value::Null) {
+            Err(CoreError::Parse(error)) => {
+                assert_eq!((error.line, error.column), (Some(2), Some(16)), "{:?}", error);
+                assert!(error.code.is_some_and(|code| code.starts_with("parser::")));
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+        assert!(matches!(
+            core.run("?[x] := *nobody{x}", Value::Null),
+            Err(CoreError::UnknownRelation(name)) if name == "nobody"
+        ));
+        match core.run("?[name, age] <- [['ada', 'old']] :put people {name => age}", Value::Null) {
+            Err(CoreError::TypeMismatch { relation, detail }) => {
+                assert_eq!(relation.as_deref(), Some("people"));
+                assert!(detail.contains("old"), "{}", detail);
+            }
+            other => panic!("expected a type mismatch, got {:?}", other),
+        }
+        assert!(matches!(
+            core.run("?[x] <- [[$missing]]", json!({})),
+            Err(CoreError::MissingParam(name)) if name == "missing"
+        ));
+        assert!(matches!(core.run("?[x] <- [[1]]", json!([1])), Err(CoreError::InvalidParams(_))));
+    }
+
+    #[test]
+    fn reads_do_not_queue_behind_each_other() {
+        let core = core();
+        let slow = QueryOptions {
+            timeout: Some(Duration::from_secs(1)),
+            readonly: true,
+            ..Default::default()
+        };
+        let runaway = "r[n] := n = 0\nr[m] := r[n], m = n + 1\n?[n] := r[n]";
+        std::thread::scope(|scope| {
+            let slow_reads: Vec<_> = (0..4).map(|_| scope.spawn(|| core.run_with(runaway, Value::Null, &slow))).collect();
+            std::thread::sleep(Duration::from_millis(100));
+            // Queued one at a time, this would wait out all four timeouts.
+            let started = Instant::now();
+            assert_eq!(core.run("?[x] <- [[1]]", Value::Null).unwrap()["rows"], json!([[1]]));
+            assert!(started.elapsed() < Duration::from_millis(500), "took {:?}", started.elapsed());
+            assert!(slow_reads.iter().all(|read| !read.is_finished()));
+            for read in slow_reads {
+                assert!(matches!(read.join().unwrap(), Err(CoreError::Timeout { .. })));
+            }
         });
-        Ok(response)
+    }
+
+    #[test]
+    fn readers_never_see_half_a_write() {
+        let core = core();
+        core.run(":create numbers {n: Int}", Value::Null).unwrap();
+        let counts = std::sync::Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            let writer = scope.spawn(|| core.run("?[n] := n in int_range(20000) :put numbers {n}", Value::Null));
+            for _ in 0..3 {
+                scope.spawn(|| {
+                    for _ in 0..20 {
+                        let result = core.run("?[count(n)] := *numbers{n}", Value::Null).unwrap();
+                        counts.lock().unwrap().push(result["rows"][0][0].as_i64().unwrap());
+                    }
+                });
+            }
+            writer.join().unwrap().unwrap();
+        });
+        let counts = counts.into_inner().unwrap();
+        assert!(counts.iter().all(|count| *count == 0 || *count == 20000), "{:?}", counts);
+    }
+
+    #[test]
+    fn streams_a_large_result_a_row_at_a_time() {
+        let core = core();
+        core.run(":create numbers {n: Int => square: Int}", Value::Null).unwrap();
+        core.run("?[n, square] := n in int_range(300000), square = n * n :put numbers {n => square}", Value::Null).unwrap();
+
+        let mut rows = core.run_streaming("?[n, square] := *numbers[n, square]", Value::Null, &QueryOptions::default()).unwrap();
+        assert_eq!(rows.headers(), ["n", "square"]);
+        assert_eq!(rows.len(), 300_000);
+        let (mut count, mut last) = (0u64, -1);
+        for row in rows.by_ref() {
+            let n = row[0].as_i64().unwrap();
+            assert_eq!(row[1].as_i64().unwrap(), n * n);
+            assert!(n > last);
+            (count, last) = (count + 1, n);
+        }
+        assert_eq!(count, 300_000);
+        assert_eq!(rows.next(), None);
+
+        // A stream can be abandoned part way.
+        let rows = core.run_streaming("?[n] := *numbers{n}", Value::Null, &QueryOptions::default()).unwrap();
+        assert_eq!(rows.take(10).map(|row| row[0].as_i64().unwrap()).sum::<i64>(), 45);
     }
 }
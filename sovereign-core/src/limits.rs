@@ -0,0 +1,248 @@
+use crate::explain::{blank_literals, scan, system_op};
+use crate::{CognitiveCore, CoreError, QueryOptions};
+use cozo::{DataValue, NamedRows, Vector};
+use std::borrow::Cow;
+
+/// How large a query's result may be before it is dropped with
+/// `CoreError::ResultTooLarge`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ResultCaps {
+    rows: Option<u64>,
+    bytes: Option<u64>,
+    /// The rows are streamed, so `rows` is the streamed row ceiling.
+    streamed: bool,
+}
+
+impl CognitiveCore {
+    /// The caps on a result returned whole.
+    pub(crate) fn result_caps(&self, options: &QueryOptions) -> ResultCaps {
+        ResultCaps {
+            rows: lower(self.max_result_rows, options.max_rows),
+            bytes: self.max_result_bytes,
+            streamed: false,
+        }
+    }
+
+    /// The caps on a result streamed a row at a time, which only the
+    /// streamed row ceiling applies to.
+    pub(crate) fn stream_caps(&self, options: &QueryOptions) -> ResultCaps {
+        ResultCaps {
+            rows: lower(self.max_streamed_rows, options.max_rows),
+            bytes: None,
+            streamed: true,
+        }
+    }
+}
+
+impl ResultCaps {
+    /// `query`, made to stop one row past the row cap if it is a single
+    /// read query without a `:limit` of its own, so the engine stops
+    /// evaluating there instead of building the whole result.
+    pub(crate) fn bound<'a>(&self, query: &'a str) -> Cow<'a, str> {
+        let Some(rows) = self.rows else {
+            return Cow::Borrowed(query);
+        };
+        let text = blank_literals(query);
+        let single = match text.iter().find(|b| !b.is_ascii_whitespace()) {
+            None | Some(b'{') | Some(b'%') => false,
+            Some(_) => system_op(&text).is_none(),
+        };
+        if !single {
+            return Cow::Borrowed(query);
+        }
+        let found = scan(&text);
+        if found.mutation.is_some() || found.limited {
+            return Cow::Borrowed(query);
+        }
+        // On a line of its own, so a comment ending the query cannot hide it.
+        Cow::Owned(format!("{}\n:limit {}", query, rows.saturating_add(1)))
+    }
+
+    /// Fails if `result` is over a cap. Its size is estimated from the
+    /// engine's values, before any of them is converted.
+    pub(crate) fn check(&self, result: &NamedRows) -> Result<(), CoreError> {
+        if let Some(rows) = self.rows {
+            if result.rows.len() as u64 > rows {
+                return Err(self.too_large(format!("{} rows", rows)));
+            }
+        }
+        if let Some(bytes) = self.bytes {
+            let mut size = 0u64;
+            for row in &result.rows {
+                size += row.iter().map(estimated_size).sum::<u64>();
+                if size > bytes {
+                    return Err(self.too_large(format!("{} bytes", bytes)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn too_large(&self, limit: String) -> CoreError {
+        let hint = if self.streamed {
+            "add :limit"
+        } else {
+            "add :limit or use streaming"
+        };
+        CoreError::ResultTooLarge { limit, hint: hint.into() }
+    }
+}
+
+/// `cap`, or `requested` if that is lower.
+fn lower(cap: Option<u64>, requested: Option<u64>) -> Option<u64> {
+    match (cap, requested) {
+        (Some(cap), Some(requested)) => Some(cap.min(requested)),
+        (cap, requested) => cap.or(requested),
+    }
+}
+
+/// Roughly the bytes `value` takes as JSON.
+fn estimated_size(value: &DataValue) -> u64 {
+    match value {
+        DataValue::Null | DataValue::Bool(_) | DataValue::Bot => 5,
+        DataValue::Num(_) => 8,
+        DataValue::Str(s) => s.len() as u64 + 2,
+        DataValue::Bytes(bytes) => bytes.len() as u64 * 4 / 3 + 2,
+        DataValue::Uuid(_) => 38,
+        DataValue::Regex(regex) => regex.0.as_str().len() as u64 + 2,
+        DataValue::List(items) => items.iter().map(|item| estimated_size(item) + 1).sum::<u64>() + 2,
+        DataValue::Set(items) => items.iter().map(|item| estimated_size(item) + 1).sum::<u64>() + 2,
+        DataValue::Vec(Vector::F32(v)) => v.len() as u64 * 10 + 2,
+        DataValue::Vec(Vector::F64(v)) => v.len() as u64 * 18 + 2,
+        DataValue::Json(json) => json.0.to_string().len() as u64,
+        DataValue::Validity(_) => 24,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CoreConfig;
+    use serde_json::{json, Value};
+
+    fn core(rows: Option<u64>, bytes: Option<u64>) -> CognitiveCore {
+        let core = CognitiveCore::new(CoreConfig {
+            max_result_rows: rows,
+            max_result_bytes: bytes,
+            max_streamed_rows: Some(5),
+            ..CoreConfig::default()
+        })
+        .unwrap();
+        core.run(":create items {k: Int => v: String}", Value::Null).unwrap();
+        let rows: Vec<Value> = (0..8).map(|k| json!([k, "x".repeat(30)])).collect();
+        core.run("?[k, v] <- $rows :put items {k => v}", json!({ "rows": rows })).unwrap();
+        core
+    }
+
+    fn too_large(result: Result<Value, CoreError>) -> Option<String> {
+        match result {
+            Err(CoreError::ResultTooLarge { limit, .. }) => Some(limit),
+            Ok(_) => None,
+            Err(e) => panic!("expected a result or ResultTooLarge, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn one_row_past_the_cap_is_too_large() {
+        let core = core(Some(3), None);
+        assert_eq!(too_large(core.run("?[k] := *items{k}, k < 3", Value::Null)), None);
+        assert_eq!(too_large(core.run("?[k] := *items{k}, k < 4", Value::Null)).as_deref(), Some("3 rows"));
+        assert_eq!(too_large(core.run("?[k] := *items{k} :limit 3", Value::Null)), None);
+        // Scripts are not bounded, but are still held to the cap.
+        assert_eq!(too_large(core.run("{ ?[k] := *items{k}, k < 4 }", Value::Null)).as_deref(), Some("3 rows"));
+
+        let lowered = |max_rows| QueryOptions {
+            max_rows: Some(max_rows),
+            ..Default::default()
+        };
+        let three = "?[k] := *items{k}, k < 3";
+        assert_eq!(too_large(core.run_with(three, Value::Null, &lowered(2))).as_deref(), Some("2 rows"));
+        assert_eq!(too_large(core.run_with("?[k] := *items{k}", Value::Null, &lowered(10))).as_deref(), Some("3 rows"));
+    }
+
+    #[test]
+    fn one_row_past_the_byte_cap_is_too_large() {
+        // Each row is estimated at 8 + 32 bytes.
+        let core = core(None, Some(120));
+        assert_eq!(too_large(core.run("?[k, v] := *items{k, v}, k < 3", Value::Null)), None);
+        assert_eq!(too_large(core.run("?[k, v] := *items{k, v}, k < 4", Value::Null)).as_deref(), Some("120 bytes"));
+        assert_eq!(too_large(core.run("?[k] := *items{k}", Value::Null)), None);
+    }
+
+    #[test]
+    fn streams_are_held_to_their_own_cap() {
+        let core = core(Some(1), Some(16));
+        let options = QueryOptions::default();
+        assert_eq!(core.run_streaming("?[k, v] := *items{k, v}, k < 5", Value::Null, &options).unwrap().count(), 5);
+        match core.run_streaming("?[k, v] := *items{k, v}", Value::Null, &options) {
+            Err(CoreError::ResultTooLarge { limit, hint }) => assert_eq!((limit.as_str(), hint.as_str()), ("5 rows", "add :limit")),
+            Err(e) => panic!("expected ResultTooLarge, got {:?}", e),
+            Ok(_) => panic!("expected ResultTooLarge"),
+        }
+    }
+
+    /// Two relations of `n` keys each, whose cross join has `n * n` rows.
+    fn pairs(core: &CognitiveCore, n: i64) {
+        core.run(":create left {a: Int}", Value::Null).unwrap();
+        core.run(":create right {b: Int}", Value::Null).unwrap();
+        let keys: Vec<Value> = (0..n).map(|k| json!([k])).collect();
+        core.run("?[a] <- $keys :put left {a}", json!({ "keys": keys })).unwrap();
+        core.run("?[b] <- $keys :put right {b}", json!({ "keys": keys })).unwrap();
+    }
+
+    #[test]
+    fn a_cross_join_stops_at_the_cap_instead_of_building_its_result() {
+        let core = CognitiveCore::new(CoreConfig {
+            max_result_rows: Some(1000),
+            ..CoreConfig::default()
+        })
+        .unwrap();
+        // 400 million rows, far more than the test could hold, so this
+        // only returns because evaluation stops one row past the cap.
+        pairs(&core, 20_000);
+        let started = std::time::Instant::now();
+        let result = core.run("?[a, b] := *left{a}, *right{b}", Value::Null);
+        assert_eq!(too_large(result).as_deref(), Some("1000 rows"));
+        assert!(started.elapsed() < std::time::Duration::from_secs(10), "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn a_cross_join_too_large_whole_streams_under_the_streamed_ceiling() {
+        let core = CognitiveCore::new(CoreConfig {
+            max_result_rows: Some(1000),
+            max_streamed_rows: Some(100_000),
+            ..CoreConfig::default()
+        })
+        .unwrap();
+        pairs(&core, 300);
+        let query = "?[a, b] := *left{a}, *right{b}";
+        assert_eq!(too_large(core.run(query, Value::Null)).as_deref(), Some("1000 rows"));
+        let stream = core.run_streaming(query, Value::Null, &QueryOptions::default()).unwrap();
+        assert_eq!(stream.count(), 90_000);
+    }
+
+    #[test]
+    fn only_single_unlimited_reads_are_bounded() {
+        let caps = ResultCaps {
+            rows: Some(3),
+            bytes: None,
+            streamed: false,
+        };
+        for (query, bounded) in [
+            ("?[k] := *items{k}", true),
+            ("?[k] := *items{k} # :limit 1", true),
+            ("?[k] <- [[':limit 1']]", true),
+            ("?[k] := *items{k} :limit 1", false),
+            ("?[k] <- [[1]] :put items {k}", false),
+            ("{ ?[k] := *items{k} }", false),
+            ("%if { ?[k] := *items{k} } %then { ?[k] <- [[1]] }", false),
+            ("::relations", false),
+            ("", false),
+        ] {
+            let expected = if bounded { format!("{}\n:limit 4", query) } else { query.to_string() };
+            assert_eq!(caps.bound(query), expected, "{:?}", query);
+        }
+        let uncapped = ResultCaps { rows: None, ..caps };
+        assert_eq!(uncapped.bound("?[k] := *items{k}"), "?[k] := *items{k}");
+    }
+}
@@ -0,0 +1,278 @@
+use crate::{CognitiveCore, CoreError, CoreTransaction};
+use cozo::{DataValue, ScriptMutability};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Applied migrations, one row per version.
+const MIGRATIONS: &str = "sovereign_migrations";
+
+/// One step in the history of the schema, applied once by
+/// `CognitiveCore::migrate`. Steps are listed in `CoreConfig::migrations`
+/// oldest first; once released, a step must not change.
+#[derive(Debug, Clone, Copy)]
+pub struct Migration {
+    /// Starts at 1 and increases from step to step.
+    pub version: u32,
+    pub name: &'static str,
+    pub up: MigrationStep,
+}
+
+/// What a migration does, inside the transaction that records it. Neither
+/// kind can run system ops such as `::index create`, which the engine does
+/// not allow in a transaction.
+#[derive(Debug, Clone, Copy)]
+pub enum MigrationStep {
+    /// Queries run in order, each a single program such as `:create`,
+    /// `:replace` or a `:put` that rewrites rows.
+    Queries(&'static [&'static str]),
+    Code(fn(&mut CoreTransaction) -> Result<(), CoreError>),
+}
+
+/// A migration recorded as applied.
+#[derive(Debug, Clone)]
+pub struct AppliedMigration {
+    pub version: u32,
+    pub name: String,
+    /// Milliseconds since the Unix epoch.
+    pub applied_at_ms: u64,
+}
+
+impl CognitiveCore {
+    /// Applies the configured migrations newer than the store's schema
+    /// version, one transaction each, and returns their versions.
+    ///
+    /// Running it again applies nothing. If a migration fails, its changes
+    /// are rolled back, the ones before it stay applied, and the error is
+    /// `CoreError::Migration`.
+    pub fn migrate(&self) -> Result<Vec<u32>, CoreError> {
+        let mut last = 0;
+        for migration in self.migrations {
+            if migration.version <= last {
+                return Err(CoreError::InvalidSchema(format!(
+                    "migration {} ({}) is out of order; versions must increase from 1",
+                    migration.version, migration.name
+                )));
+            }
+            last = migration.version;
+        }
+
+        let current = self.schema_version()?;
+        let pending: Vec<&Migration> = self.migrations.iter().filter(|m| m.version > current).collect();
+        if pending.is_empty() {
+            return Ok(Vec::new());
+        }
+        if !self.relation_exists(MIGRATIONS)? {
+            let create = format!(":create {} {{version: Int => name: String, applied_at_ms: Int}}", MIGRATIONS);
+            self.script(&create, BTreeMap::new(), ScriptMutability::Mutable)?;
+        }
+
+        let record = format!(
+            "?[version, name, applied_at_ms] <- [[$version, $name, $applied_at_ms]] :put {} {{version => name, applied_at_ms}}",
+            MIGRATIONS
+        );
+        let mut applied = Vec::new();
+        for migration in pending {
+            let failed = |e: CoreError| CoreError::Migration {
+                version: migration.version,
+                name: migration.name.to_string(),
+                reason: e.to_string(),
+            };
+            self.transact(|tx| {
+                match migration.up {
+                    MigrationStep::Queries(queries) => {
+                        for query in queries {
+                            tx.exec(query, serde_json::Value::Null)?;
+                        }
+                    }
+                    MigrationStep::Code(up) => up(tx)?,
+                }
+                let applied_at_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+                let params = BTreeMap::from([
+                    ("version".to_string(), DataValue::from(migration.version as i64)),
+                    ("name".to_string(), DataValue::from(migration.name)),
+                    ("applied_at_ms".to_string(), DataValue::from(applied_at_ms)),
+                ]);
+                tx.exec_bound(&record, params)?;
+                Ok(())
+            })
+            .map_err(failed)?;
+            applied.push(migration.version);
+        }
+        Ok(applied)
+    }
+
+    /// The newest applied migration's version, or 0 before the first.
+    pub fn schema_version(&self) -> Result<u32, CoreError> {
+        Ok(self.applied_migrations()?.last().map_or(0, |m| m.version))
+    }
+
+    /// Applied migrations, oldest first.
+    pub fn applied_migrations(&self) -> Result<Vec<AppliedMigration>, CoreError> {
+        if !self.relation_exists(MIGRATIONS)? {
+            return Ok(Vec::new());
+        }
+        let script = format!(
+            "?[version, name, applied_at_ms] := *{}{{version, name, applied_at_ms}} :order version",
+            MIGRATIONS
+        );
+        let listed = self.script(&script, BTreeMap::new(), ScriptMutability::Immutable)?;
+        Ok(listed
+            .rows
+            .iter()
+            .map(|row| AppliedMigration {
+                version: row[0].get_int().unwrap_or_default() as u32,
+                name: row[1].get_str().unwrap_or_default().to_string(),
+                applied_at_ms: row[2].get_int().unwrap_or_default() as u64,
+            })
+            .collect())
+    }
+
+    /// Refuses a store migrated past this build's newest migration, which
+    /// this build's code does not know how to use.
+    pub(crate) fn check_schema_version(&self) -> anyhow::Result<()> {
+        let applied = self.applied_migrations()?;
+        let Some(newest) = applied.last() else {
+            return Ok(());
+        };
+        let known = self.migrations.last().map_or(0, |m| m.version);
+        if newest.version > known {
+            anyhow::bail!(
+                "the core's schema is at version {} ({}), newer than this build's latest, {}; run a newer release of the node",
+                newest.version,
+                newest.name,
+                known
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+    use crate::{CoreBackend, CoreConfig};
+    use serde_json::{json, Value};
+
+    fn add_emails(tx: &mut CoreTransaction) -> Result<(), CoreError> {
+        tx.exec(":create emails {person: Int => email: String}", Value::Null)?;
+        tx.exec("?[person, email] := *people{id: person, name}, email = concat(name, '@example.com') :put emails {person => email}", Value::Null)?;
+        Ok(())
+    }
+
+    fn fails_halfway(tx: &mut CoreTransaction) -> Result<(), CoreError> {
+        tx.exec(":create emails {person: Int => email: String}", Value::Null)?;
+        tx.exec("?[person, email] := *nobody{person, email} :put emails {person => email}", Value::Null)?;
+        Ok(())
+    }
+
+    const CHAIN: &[Migration] = &[
+        Migration {
+            version: 1,
+            name: "people",
+            up: MigrationStep::Queries(&[
+                ":create people {id: Int => name: String}",
+                "?[id, name] <- [[1, 'ada'], [2, 'alan']] :put people {id => name}",
+            ]),
+        },
+        Migration {
+            version: 2,
+            name: "emails",
+            up: MigrationStep::Code(add_emails),
+        },
+        Migration {
+            version: 3,
+            name: "ages",
+            up: MigrationStep::Queries(&[
+                ":create people_v3 {id: Int => name: String, age: Int default 0}",
+                "?[id, name] := *people{id, name} :put people_v3 {id => name}",
+            ]),
+        },
+    ];
+
+    const BROKEN: &[Migration] = &[
+        CHAIN[0],
+        Migration {
+            version: 2,
+            name: "emails",
+            up: MigrationStep::Code(fails_halfway),
+        },
+        CHAIN[2],
+    ];
+
+    fn open(path: &std::path::Path, migrations: &'static [Migration]) -> anyhow::Result<CognitiveCore> {
+        CognitiveCore::new(CoreConfig {
+            backend: CoreBackend::Sqlite { path: path.to_path_buf() },
+            migrations,
+            ..CoreConfig::default()
+        })
+    }
+
+    #[test]
+    fn applies_a_chain_once() {
+        let core = CognitiveCore::new(CoreConfig {
+            migrations: CHAIN,
+            ..CoreConfig::default()
+        })
+        .unwrap();
+        assert_eq!(core.schema_version().unwrap(), 0);
+        assert_eq!(core.migrate().unwrap(), [1, 2, 3]);
+        assert_eq!(core.schema_version().unwrap(), 3);
+        let applied = core.applied_migrations().unwrap();
+        let names: Vec<(u32, &str)> = applied.iter().map(|m| (m.version, m.name.as_str())).collect();
+        assert_eq!(names, [(1, "people"), (2, "emails"), (3, "ages")]);
+        assert!(applied.iter().all(|m| m.applied_at_ms > 0));
+
+        let emails = core.run("?[p, e] := *emails[p, e] :order p", Value::Null).unwrap()["rows"].clone();
+        assert_eq!(emails, json!([[1, "ada@example.com"], [2, "alan@example.com"]]));
+        assert_eq!(core.run("?[id, age] := *people_v3{id, age}", Value::Null).unwrap()["rows"], json!([[1, 0], [2, 0]]));
+
+        // Nothing is applied twice.
+        assert!(core.migrate().unwrap().is_empty());
+        assert_eq!(core.applied_migrations().unwrap().len(), 3);
+        assert_eq!(core.run("?[count(id)] := *people{id}", Value::Null).unwrap()["rows"], json!([[2]]));
+    }
+
+    #[test]
+    fn stops_at_a_failing_step_and_resumes_there() {
+        let dir = TempDir::new("migrate");
+        let path = dir.path().join("core.db");
+        let core = open(&path, BROKEN).unwrap();
+        match core.migrate() {
+            Err(CoreError::Migration { version: 2, name, .. }) => assert_eq!(name, "emails"),
+            other => panic!("expected migration 2 to fail, got {:?}", other),
+        }
+        // The first step stands; the failed one left nothing behind.
+        assert_eq!(core.schema_version().unwrap(), 1);
+        assert!(core.list_relations().unwrap().iter().all(|r| r.name == "people"));
+        drop(core);
+
+        let core = open(&path, CHAIN).unwrap();
+        assert_eq!(core.migrate().unwrap(), [2, 3]);
+        assert_eq!(core.schema_version().unwrap(), 3);
+        assert!(core.migrate().unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_stores_from_a_newer_build() {
+        let dir = TempDir::new("downgrade");
+        let path = dir.path().join("core.db");
+        open(&path, CHAIN).unwrap().migrate().unwrap();
+
+        let err = open(&path, &CHAIN[..2]).err().unwrap().to_string();
+        assert!(err.contains("schema is at version 3 (ages), newer than this build's latest, 2"), "{}", err);
+        assert!(open(&path, CHAIN).is_ok());
+    }
+
+    #[test]
+    fn refuses_migrations_out_of_order() {
+        const SHUFFLED: &[Migration] = &[CHAIN[1], CHAIN[0]];
+        let core = CognitiveCore::new(CoreConfig {
+            migrations: SHUFFLED,
+            ..CoreConfig::default()
+        })
+        .unwrap();
+        assert!(matches!(core.migrate(), Err(CoreError::InvalidSchema(_))));
+        assert_eq!(core.schema_version().unwrap(), 0);
+    }
+}
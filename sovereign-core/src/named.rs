@@ -0,0 +1,370 @@
+use crate::params::to_data_value;
+use crate::schema::check_name;
+use crate::{result_json, CognitiveCore, CoreError, QueryOptions};
+use cozo::{DataValue, JsonData, ScriptMutability};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Instant;
+
+/// Where registered queries are kept, created on first registration.
+const NAMED_QUERIES: &str = "sovereign_named_queries";
+
+/// A query registered under a name with `register_query`.
+#[derive(Debug, Clone)]
+pub struct NamedQuery {
+    pub name: String,
+    /// A single CozoScript query, not a system op or a multi-query script.
+    pub text: String,
+    /// Every `$name` the query uses must be declared here.
+    pub params: Vec<ParamSpec>,
+    /// Always run read-only, so the query cannot write whoever calls it.
+    pub readonly: bool,
+}
+
+/// A parameter a named query takes.
+#[derive(Debug, Clone)]
+pub struct ParamSpec {
+    pub name: String,
+    pub param_type: ParamType,
+    /// An optional parameter that is left out, or given as null, is bound
+    /// to null.
+    pub required: bool,
+}
+
+/// The JSON types a named query's parameters are checked against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamType {
+    Any,
+    Bool,
+    /// An integer in the i64 range.
+    Int,
+    /// Any number.
+    Float,
+    String,
+    List,
+    Object,
+}
+
+impl fmt::Display for ParamType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ParamType::Any => "Any",
+            ParamType::Bool => "Bool",
+            ParamType::Int => "Int",
+            ParamType::Float => "Float",
+            ParamType::String => "String",
+            ParamType::List => "List",
+            ParamType::Object => "Object",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl ParamType {
+    pub fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "Any" => ParamType::Any,
+            "Bool" => ParamType::Bool,
+            "Int" => ParamType::Int,
+            "Float" => ParamType::Float,
+            "String" => ParamType::String,
+            "List" => ParamType::List,
+            "Object" => ParamType::Object,
+            _ => return None,
+        })
+    }
+
+    fn accepts(self, value: &Value) -> bool {
+        match self {
+            ParamType::Any => true,
+            ParamType::Bool => value.is_boolean(),
+            ParamType::Int => value.is_i64(),
+            ParamType::Float => value.is_number(),
+            ParamType::String => value.is_string(),
+            ParamType::List => value.is_array(),
+            ParamType::Object => value.is_object(),
+        }
+    }
+}
+
+impl CognitiveCore {
+    /// Stores `query` under its name, replacing any query of that name.
+    ///
+    /// The query is compiled, not run, before it is stored, so syntax
+    /// errors, unknown stored relations and `$name`s missing from `params`
+    /// are reported here rather than on first use.
+    pub fn register_query(&self, query: &NamedQuery) -> Result<(), CoreError> {
+        check_name(&query.name)?;
+        let mut bound = BTreeMap::new();
+        for param in &query.params {
+            check_name(&param.name)?;
+            if bound.insert(param.name.clone(), DataValue::Null).is_some() {
+                return Err(CoreError::InvalidParams(format!("${} is declared twice", param.name)));
+            }
+        }
+        self.plan(&query.text, bound).map_err(|e| match e {
+            CoreError::MissingParam(name) => CoreError::InvalidParams(format!("the query uses ${}, which is not declared", name)),
+            other => other,
+        })?;
+
+        if !self.relation_exists(NAMED_QUERIES)? {
+            let create = format!(":create {} {{name: String => text: String, params: Json, readonly: Bool}}", NAMED_QUERIES);
+            self.script(&create, BTreeMap::new(), ScriptMutability::Mutable)?;
+        }
+        let params: Vec<Value> = query
+            .params
+            .iter()
+            .map(|p| json!({"name": p.name, "type": p.param_type.to_string(), "required": p.required}))
+            .collect();
+        let row = DataValue::List(vec![
+            DataValue::from(query.name.as_str()),
+            DataValue::from(query.text.as_str()),
+            DataValue::Json(JsonData(Value::Array(params))),
+            DataValue::Bool(query.readonly),
+        ]);
+        let script = format!(
+            "?[name, text, params, readonly] <- $rows :put {} {{name => text, params, readonly}}",
+            NAMED_QUERIES
+        );
+        self.script(&script, BTreeMap::from([("rows".to_string(), DataValue::List(vec![row]))]), ScriptMutability::Mutable)?;
+        Ok(())
+    }
+
+    /// Runs the named query with `params`, which are checked against its
+    /// declared parameters first, in the shape `run` returns.
+    pub fn run_named(&self, name: &str, params: Value) -> Result<Value, CoreError> {
+        self.run_named_with(name, params, &QueryOptions::default())
+    }
+
+    /// `run_named` with a timeout and cancel token. A query registered as
+    /// read-only runs read-only whatever `options` say.
+    pub fn run_named_with(&self, name: &str, params: Value, options: &QueryOptions) -> Result<Value, CoreError> {
+        let query = self.named_query(name)?.ok_or_else(|| CoreError::UnknownNamedQuery(name.to_string()))?;
+        let params = check_params(&query.params, params)?;
+        let options = QueryOptions {
+            readonly: options.readonly || query.readonly,
+            ..options.clone()
+        };
+        let started = Instant::now();
+        let rows = self.evaluate(&query.text, params, &options, self.result_caps(&options))?;
+        Ok(result_json(rows, started.elapsed()))
+    }
+
+    /// Registered queries, by name.
+    pub fn list_named(&self) -> Result<Vec<NamedQuery>, CoreError> {
+        if !self.relation_exists(NAMED_QUERIES)? {
+            return Ok(Vec::new());
+        }
+        let script = format!("?[name, text, params, readonly] := *{}{{name, text, params, readonly}}", NAMED_QUERIES);
+        let listed = self.script(&script, BTreeMap::new(), ScriptMutability::Immutable)?;
+        Ok(listed.rows.iter().map(|row| named_query(row)).collect())
+    }
+
+    /// Returns false if no query had that name.
+    pub fn remove_named(&self, name: &str) -> Result<bool, CoreError> {
+        check_name(name)?;
+        if self.named_query(name)?.is_none() {
+            return Ok(false);
+        }
+        let script = format!("?[name] <- [[$name]] :rm {} {{name}}", NAMED_QUERIES);
+        self.script(&script, BTreeMap::from([("name".to_string(), DataValue::from(name))]), ScriptMutability::Mutable)?;
+        Ok(true)
+    }
+
+    fn named_query(&self, name: &str) -> Result<Option<NamedQuery>, CoreError> {
+        check_name(name)?;
+        if !self.relation_exists(NAMED_QUERIES)? {
+            return Ok(None);
+        }
+        let script = format!(
+            "?[name, text, params, readonly] := *{}{{name, text, params, readonly}}, name = $name",
+            NAMED_QUERIES
+        );
+        let found = self.script(&script, BTreeMap::from([("name".to_string(), DataValue::from(name))]), ScriptMutability::Immutable)?;
+        Ok(found.rows.first().map(|row| named_query(row)))
+    }
+}
+
+/// Binds `params` for a query declaring `specs`, or says why they do not fit.
+fn check_params(specs: &[ParamSpec], params: Value) -> Result<BTreeMap<String, DataValue>, CoreError> {
+    let mut given = match params {
+        Value::Null => serde_json::Map::new(),
+        Value::Object(map) => map,
+        other => return Err(CoreError::InvalidParams(format!("expected an object, got {}", other))),
+    };
+    let mut bound = BTreeMap::new();
+    for spec in specs {
+        let value = match given.remove(&spec.name) {
+            None | Some(Value::Null) if !spec.required => Value::Null,
+            None => return Err(CoreError::MissingParam(spec.name.clone())),
+            Some(value) if !spec.param_type.accepts(&value) => {
+                return Err(CoreError::InvalidParams(format!("${}: expected {}, got {}", spec.name, spec.param_type, value)))
+            }
+            Some(value) => value,
+        };
+        let value = to_data_value(value).map_err(|e| CoreError::InvalidParams(format!("${}: {}", spec.name, e)))?;
+        bound.insert(spec.name.clone(), value);
+    }
+    match given.keys().next() {
+        Some(extra) => Err(CoreError::InvalidParams(format!("${} is not a parameter of this query", extra))),
+        None => Ok(bound),
+    }
+}
+
+fn named_query(row: &[DataValue]) -> NamedQuery {
+    let text = |i: usize| row.get(i).and_then(DataValue::get_str).unwrap_or_default().to_string();
+    let params = match row.get(2) {
+        Some(DataValue::Json(JsonData(Value::Array(params)))) => params
+            .iter()
+            .map(|p| ParamSpec {
+                name: p["name"].as_str().unwrap_or_default().to_string(),
+                param_type: p["type"].as_str().and_then(ParamType::parse).unwrap_or(ParamType::Any),
+                required: p["required"].as_bool().unwrap_or(true),
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    NamedQuery {
+        name: text(0),
+        text: text(1),
+        params,
+        readonly: row.get(3).and_then(DataValue::get_bool).unwrap_or(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+    use crate::{CoreBackend, CoreConfig};
+
+    fn core() -> CognitiveCore {
+        let core = CognitiveCore::new(CoreConfig::default()).unwrap();
+        core.run(":create people {id: Int => name: String, age: Int}", Value::Null).unwrap();
+        core.run("?[id, name, age] <- [[1, 'ada', 36], [2, 'alan', 41]] :put people {id => name, age}", Value::Null).unwrap();
+        core
+    }
+
+    fn param(name: &str, param_type: ParamType, required: bool) -> ParamSpec {
+        ParamSpec {
+            name: name.into(),
+            param_type,
+            required,
+        }
+    }
+
+    fn older_than() -> NamedQuery {
+        NamedQuery {
+            name: "older_than".into(),
+            text: "?[name] := *people{name, age}, age > $age, starts_with(name, $prefix ~ '')".into(),
+            params: vec![param("age", ParamType::Int, true), param("prefix", ParamType::String, false)],
+            readonly: true,
+        }
+    }
+
+    #[test]
+    fn runs_registered_queries_with_checked_params() {
+        let core = core();
+        core.register_query(&older_than()).unwrap();
+        assert_eq!(core.run_named("older_than", json!({"age": 30})).unwrap()["rows"], json!([["ada"], ["alan"]]));
+        assert_eq!(core.run_named("older_than", json!({"age": 30, "prefix": "al"})).unwrap()["rows"], json!([["alan"]]));
+
+        let refused = [
+            (json!({}), "age"),
+            (json!({"prefix": "a"}), "age"),
+            (json!({"age": "thirty"}), "$age: expected Int, got \"thirty\""),
+            (json!({"age": 30.5}), "$age: expected Int"),
+            (json!({"age": 30, "prefix": 7}), "$prefix: expected String"),
+            (json!({"age": 30, "colour": "red"}), "$colour is not a parameter"),
+            (json!([30]), "expected an object"),
+        ];
+        for (params, expected) in refused {
+            match core.run_named("older_than", params.clone()) {
+                Err(CoreError::MissingParam(name)) => assert_eq!(name, expected, "{}", params),
+                Err(CoreError::InvalidParams(reason)) => assert!(reason.contains(expected), "{}: {}", params, reason),
+                other => panic!("{}: expected a parameter error, got {:?}", params, other),
+            }
+        }
+        assert!(matches!(core.run_named("nothing", Value::Null), Err(CoreError::UnknownNamedQuery(_))));
+    }
+
+    #[test]
+    fn checks_queries_when_they_are_registered() {
+        let core = core();
+        let broken = |text: &str, params| NamedQuery {
+            name: "broken".into(),
+            text: text.into(),
+            params,
+            readonly: false,
+        };
+        assert!(matches!(core.register_query(&broken("?[x] := *people{id: x", Vec::new())), Err(CoreError::Parse(_))));
+        assert!(matches!(core.register_query(&broken("?[x] := *nobody{x}", Vec::new())), Err(CoreError::UnknownRelation(_))));
+        let undeclared = core.register_query(&broken("?[x] := *people{id: x}, x > $min", Vec::new()));
+        assert!(matches!(&undeclared, Err(CoreError::InvalidParams(reason)) if reason.contains("$min")), "{:?}", undeclared);
+        let twice = vec![param("min", ParamType::Int, true), param("min", ParamType::Int, true)];
+        assert!(matches!(core.register_query(&broken("?[x] := *people{id: x}, x > $min", twice)), Err(CoreError::InvalidParams(_))));
+        assert!(matches!(
+            core.register_query(&NamedQuery { name: "bad name}".into(), ..older_than() }),
+            Err(CoreError::InvalidName(_))
+        ));
+        assert!(core.list_named().unwrap().is_empty());
+    }
+
+    #[test]
+    fn readonly_queries_never_write() {
+        let core = core();
+        let rename = NamedQuery {
+            name: "rename".into(),
+            text: "?[id, name, age] := *people{id, age}, id = $id, name = $name :put people {id => name, age}".into(),
+            params: vec![param("id", ParamType::Int, true), param("name", ParamType::String, true)],
+            readonly: true,
+        };
+        core.register_query(&rename).unwrap();
+        let params = json!({"id": 1, "name": "countess"});
+        assert!(matches!(core.run_named("rename", params.clone()), Err(CoreError::ReadOnlyViolation(_))));
+        assert_eq!(core.run("?[n] := *people{id: 1, name: n}", Value::Null).unwrap()["rows"], json!([["ada"]]));
+
+        // Registered writable, it writes unless the caller asks for read-only.
+        core.register_query(&NamedQuery { readonly: false, ..rename }).unwrap();
+        let readonly = QueryOptions {
+            readonly: true,
+            ..QueryOptions::default()
+        };
+        assert!(matches!(core.run_named_with("rename", params.clone(), &readonly), Err(CoreError::ReadOnlyViolation(_))));
+        core.run_named("rename", params).unwrap();
+        assert_eq!(core.run("?[n] := *people{id: 1, name: n}", Value::Null).unwrap()["rows"], json!([["countess"]]));
+    }
+
+    #[test]
+    fn registered_queries_survive_reopening() {
+        let dir = TempDir::new("named");
+        let open = || {
+            CognitiveCore::new(CoreConfig {
+                backend: CoreBackend::Sqlite { path: dir.path().join("core.db") },
+                ..CoreConfig::default()
+            })
+            .unwrap()
+        };
+        let core = open();
+        core.run(":create people {id: Int => name: String, age: Int}", Value::Null).unwrap();
+        core.run("?[id, name, age] <- [[1, 'ada', 36]] :put people {id => name, age}", Value::Null).unwrap();
+        core.register_query(&older_than()).unwrap();
+        drop(core);
+
+        let core = open();
+        let listed = core.list_named().unwrap();
+        assert_eq!(listed.len(), 1);
+        let query = &listed[0];
+        assert_eq!((query.name.as_str(), query.text.as_str(), query.readonly), ("older_than", older_than().text.as_str(), true));
+        let params: Vec<(&str, ParamType, bool)> = query.params.iter().map(|p| (p.name.as_str(), p.param_type, p.required)).collect();
+        assert_eq!(params, [("age", ParamType::Int, true), ("prefix", ParamType::String, false)]);
+        assert_eq!(core.run_named("older_than", json!({"age": 1})).unwrap()["rows"], json!([["ada"]]));
+        // The registry is the core's own, not one of the user's relations.
+        assert!(core.list_relations().unwrap().iter().all(|r| r.name == "people"));
+
+        assert!(core.remove_named("older_than").unwrap());
+        assert!(!core.remove_named("older_than").unwrap());
+        assert!(matches!(core.run_named("older_than", json!({"age": 1})), Err(CoreError::UnknownNamedQuery(_))));
+    }
+}
@@ -0,0 +1,294 @@
+use crate::explain::{blank_literals, fixed_rules, scan, system_ops, PURE_FIXED_RULES};
+use crate::schema::{check_name, SYSTEM_PREFIX};
+use crate::{CognitiveCore, CoreError, ExplainReport, QueryOptions, RelationInfo};
+use cozo::{DataValue, ScriptMutability};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Relations in this namespace are readable from every namespace, and
+/// written only by queries confined to it or not confined at all.
+pub const SHARED_NAMESPACE: &str = "shared";
+
+/// Which namespace created each namespaced relation, and when.
+const OWNERS: &str = "sovereign_namespaces";
+
+/// The parameter the registry's rows are bound to in a confined query.
+pub(crate) const OWNER_ROWS: &str = "sovereign_owners";
+
+impl CognitiveCore {
+    /// `run`, confined to `namespace`. A relation named plainly is the
+    /// namespace's own, stored as `<namespace>.<name>`; one named
+    /// `shared.<name>` is read from the shared namespace. Naming any other
+    /// namespace's relation, writing a shared one, running a system op or
+    /// applying a fixed rule that reads outside the store, such as
+    /// `CsvReader`, fails with `CoreError::NamespaceDenied` before the query
+    /// runs.
+    pub fn run_in(&self, namespace: &str, query: &str, params: Value) -> Result<Value, CoreError> {
+        let options = QueryOptions {
+            namespace: Some(namespace.to_string()),
+            ..Default::default()
+        };
+        self.run_with(query, params, &options)
+    }
+
+    /// `explain`, for the query as `run_in` would run it. Relations are
+    /// reported by their stored names.
+    pub fn explain_in(&self, namespace: &str, query: &str, params: Value) -> Result<ExplainReport, CoreError> {
+        let confined = confine(namespace, query)?;
+        self.explain(&confined.query, params)
+    }
+
+    /// The namespace's relations, by the names its queries use: its own
+    /// plainly, then the shared namespace's qualified.
+    pub fn list_relations_in(&self, namespace: &str) -> Result<Vec<RelationInfo>, CoreError> {
+        check_namespace(namespace)?;
+        let own = format!("{}.", namespace);
+        let shared = format!("{}.", SHARED_NAMESPACE);
+        let mut relations: Vec<RelationInfo> = Vec::new();
+        let mut shared_relations = Vec::new();
+        for mut relation in self.list_relations()? {
+            if let Some(name) = relation.name.strip_prefix(&own) {
+                relation.name = name.to_string();
+                relations.push(relation);
+            } else if relation.name.starts_with(&shared) {
+                shared_relations.push(relation);
+            }
+        }
+        relations.append(&mut shared_relations);
+        Ok(relations)
+    }
+
+    /// `drop_relation` on one of the namespace's own relations.
+    pub fn drop_relation_in(&self, namespace: &str, name: &str, force: bool) -> Result<(), CoreError> {
+        let name = resolve_relation(namespace, name, true)?;
+        self.drop_relation(&name, force)
+    }
+
+    /// Records `relations` as created by their namespaces.
+    pub(crate) fn register_owners(&self, relations: &[String]) -> Result<(), CoreError> {
+        if relations.is_empty() {
+            return Ok(());
+        }
+        self.ensure_owners()?;
+        let (script, rows) = owners_put(relations);
+        self.script(&script, BTreeMap::from([(OWNER_ROWS.to_string(), rows)]), ScriptMutability::Mutable)?;
+        Ok(())
+    }
+
+    pub(crate) fn unregister_owner(&self, relation: &str) -> Result<(), CoreError> {
+        if !relation.contains('.') || !self.relation_exists(OWNERS)? {
+            return Ok(());
+        }
+        let script = format!("?[relation] <- [[$relation]] :rm {} {{relation}}", OWNERS);
+        let params = BTreeMap::from([("relation".to_string(), DataValue::from(relation))]);
+        self.script(&script, params, ScriptMutability::Mutable)?;
+        Ok(())
+    }
+
+    /// Creates the ownership registry if it is missing.
+    pub(crate) fn ensure_owners(&self) -> Result<(), CoreError> {
+        if self.relation_exists(OWNERS)? {
+            return Ok(());
+        }
+        let create = format!(":create {} {{relation: String => namespace: String, created_ms: Int}}", OWNERS);
+        match self.script(&create, BTreeMap::new(), ScriptMutability::Mutable) {
+            // Another query may have created it meanwhile.
+            Err(e) if !self.relation_exists(OWNERS)? => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The stored name of `relation` as named in a query confined to
+/// `namespace`: its own relations plainly or qualified, shared ones
+/// qualified and only if not `written`.
+pub fn resolve_relation(namespace: &str, relation: &str, written: bool) -> Result<String, CoreError> {
+    check_namespace(namespace)?;
+    let denied = || CoreError::NamespaceDenied {
+        namespace: namespace.to_string(),
+        relation: Some(relation.to_string()),
+        fixed_rule: None,
+    };
+    match relation.split_once('.') {
+        None => {
+            check_name(relation)?;
+            Ok(format!("{}.{}", namespace, relation))
+        }
+        Some((owner, name)) if owner == namespace || (owner == SHARED_NAMESPACE && !written) => {
+            check_name(name)?;
+            Ok(relation.to_string())
+        }
+        Some(_) => Err(denied()),
+    }
+}
+
+/// A namespace is a plain name that cannot be confused with the core's own
+/// relations.
+fn check_namespace(namespace: &str) -> Result<(), CoreError> {
+    check_name(namespace)?;
+    if namespace.starts_with(SYSTEM_PREFIX) {
+        return Err(CoreError::InvalidName(format!("'{}' names are reserved for the core", SYSTEM_PREFIX)));
+    }
+    Ok(())
+}
+
+/// A query rewritten to run in a namespace.
+pub(crate) struct Confined {
+    pub query: String,
+    /// Stored names of the relations the query creates.
+    pub created: Vec<String>,
+}
+
+/// Rewrites every relation `query` names to its stored name in `namespace`.
+/// Names starting with `_` are an imperative script's temporary relations
+/// and are left alone. System ops, and fixed rules that reach outside the
+/// store, are refused.
+pub(crate) fn confine(namespace: &str, query: &str) -> Result<Confined, CoreError> {
+    check_namespace(namespace)?;
+    let text = blank_literals(query);
+    if !system_ops(&text).is_empty() {
+        return Err(CoreError::NamespaceDenied {
+            namespace: namespace.to_string(),
+            relation: None,
+            fixed_rule: None,
+        });
+    }
+    if let Some(rule) = fixed_rules(&text).into_iter().find(|rule| !PURE_FIXED_RULES.contains(&rule.as_str())) {
+        return Err(CoreError::NamespaceDenied {
+            namespace: namespace.to_string(),
+            relation: None,
+            fixed_rule: Some(rule),
+        });
+    }
+    let mut confined = String::with_capacity(query.len() + 32);
+    let mut created = Vec::new();
+    let mut copied = 0;
+    for reference in scan(&text).references {
+        let name = &query[reference.span.clone()];
+        if name.starts_with('_') {
+            continue;
+        }
+        let stored = resolve_relation(namespace, name, reference.written)?;
+        if reference.creates && !created.contains(&stored) {
+            created.push(stored.clone());
+        }
+        confined.push_str(&query[copied..reference.span.start]);
+        confined.push_str(&stored);
+        copied = reference.span.end;
+    }
+    confined.push_str(&query[copied..]);
+    Ok(Confined { query: confined, created })
+}
+
+impl Confined {
+    /// The query as one script that also registers what it creates, so both
+    /// commit together. The registry must exist.
+    pub(crate) fn script(&self) -> (String, Option<DataValue>) {
+        if self.created.is_empty() {
+            return (self.query.clone(), None);
+        }
+        let (put, rows) = owners_put(&self.created);
+        // The registration goes first, on the query's first line, so the
+        // result and the lines errors point at stay the query's. A script
+        // of blocks or imperative statements takes another block as is; a
+        // single query becomes one.
+        let text = blank_literals(&self.query);
+        let script = match text.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{') | Some(b'%') => format!("{{ {} }} {}", put, self.query),
+            _ => format!("{{ {} }} {{{}\n}}", put, self.query),
+        };
+        (script, Some(rows))
+    }
+}
+
+/// The statement registering `relations`, which takes its rows from
+/// `$sovereign_owners`.
+pub(crate) fn owners_put(relations: &[String]) -> (String, DataValue) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+    let rows = relations
+        .iter()
+        .filter_map(|relation| {
+            let (namespace, _) = relation.split_once('.')?;
+            Some(DataValue::List(vec![
+                DataValue::from(relation.as_str()),
+                DataValue::from(namespace),
+                DataValue::from(now),
+            ]))
+        })
+        .collect();
+    let script = format!(
+        "?[relation, namespace, created_ms] <- ${} :put {} {{relation => namespace, created_ms}}",
+        OWNER_ROWS, OWNERS
+    );
+    (script, DataValue::List(rows))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+    use crate::CoreConfig;
+    use serde_json::json;
+
+    fn core() -> CognitiveCore {
+        let core = CognitiveCore::new(CoreConfig::default()).unwrap();
+        for namespace in ["a", "b"] {
+            core.run_in(namespace, ":create secret {k: String => v: Int}", Value::Null).unwrap();
+            core.run_in(namespace, "?[k, v] <- [['x', 1]] :put secret {k => v}", Value::Null).unwrap();
+        }
+        core
+    }
+
+    fn denied(result: Result<Value, CoreError>) -> bool {
+        matches!(result, Err(CoreError::NamespaceDenied { .. }))
+    }
+
+    #[test]
+    fn other_namespaces_are_out_of_reach() {
+        let core = core();
+        assert_eq!(core.run_in("a", "?[k, v] := *secret{k, v}", Value::Null).unwrap()["rows"], json!([["x", 1]]));
+        assert!(denied(core.run_in("a", "?[k, v] := *b.secret{k, v}", Value::Null)));
+        assert!(denied(core.run_in("a", "?[k, v] <- [['y', 2]] :put b.secret {k => v}", Value::Null)));
+        assert!(denied(core.run_in("a", "{ ?[k, v] := *b.secret{k, v} }", Value::Null)));
+    }
+
+    #[test]
+    fn system_ops_are_denied_in_every_form() {
+        let core = core();
+        for query in [
+            "::relations",
+            "::remove b.secret",
+            "{ ::relations }",
+            "{ ::remove b.secret }",
+            "{ ?[k] := *secret{k} } { ::remove b.secret }",
+            "{ :: remove b.secret }",
+            "{ ::/* hidden */remove b.secret }",
+        ] {
+            assert!(denied(core.run_in("a", query, Value::Null)), "{:?} was let through", query);
+        }
+        assert!(core.relation_exists("b.secret").unwrap());
+        assert_eq!(core.run_in("b", "?[k] := *secret{k}", Value::Null).unwrap()["rows"], json!([["x"]]));
+        // Inside a string it is only text.
+        core.run_in("a", "?[k, v] <- [['::remove b.secret', 2]] :put secret {k => v}", Value::Null).unwrap();
+    }
+
+    #[test]
+    fn fixed_rules_that_read_files_are_denied() {
+        let core = core();
+        let dir = TempDir::new("fixed-rules");
+        let path = dir.path().join("secret.csv");
+        std::fs::write(&path, "hunter2\n").unwrap();
+        let read = format!("?[line] <~ CsvReader(types: ['String'], url: 'file://{}', has_headers: false)", path.display());
+        assert_eq!(core.run(&read, Value::Null).unwrap()["rows"], json!([["hunter2"]]));
+        for query in [read.clone(), read.replace("<~ CsvReader", "<~/* hidden */CsvReader"), format!("{{ {} }}", read)] {
+            match core.run_in("a", &query, Value::Null) {
+                Err(CoreError::NamespaceDenied { fixed_rule: Some(rule), .. }) => assert_eq!(rule, "CsvReader"),
+                other => panic!("{:?} was let through: {:?}", query, other),
+            }
+        }
+        // Those that only compute on their inputs still run.
+        let constant = core.run_in("a", "?[k] <~ Constant(data: [['x']])", Value::Null).unwrap();
+        assert_eq!(constant["rows"], json!([["x"]]));
+    }
+}
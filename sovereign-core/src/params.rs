@@ -0,0 +1,129 @@
+use crate::CoreError;
+use cozo::{DataValue, JsonData};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Converts a JSON object into `$name` bindings. `null` binds nothing; any
+/// other non-object is rejected.
+pub(crate) fn bind_params(params: Value) -> Result<BTreeMap<String, DataValue>, CoreError> {
+    match params {
+        Value::Null => Ok(BTreeMap::new()),
+        Value::Object(map) => map
+            .into_iter()
+            .map(|(name, value)| {
+                let bound = to_data_value(value).map_err(|e| CoreError::InvalidParams(format!("${}: {}", name, e)))?;
+                Ok((name, bound))
+            })
+            .collect(),
+        other => Err(CoreError::InvalidParams(format!("expected an object, got {}", other))),
+    }
+}
+
+/// The JSON to engine type mapping:
+///
+/// | JSON                        | engine  |
+/// |-----------------------------|---------|
+/// | `null`                      | `Null`  |
+/// | `true` / `false`            | `Bool`  |
+/// | integer in the i64 range    | `Int`   |
+/// | other number                | `Float` |
+/// | string                      | `String`|
+/// | array                       | `List`, converted element by element |
+/// | object                      | `Json`, kept as is |
+///
+/// Integers above `i64::MAX` are rejected rather than rounded to a float.
+pub(crate) fn to_data_value(value: Value) -> Result<DataValue, String> {
+    Ok(match value {
+        Value::Null => DataValue::Null,
+        Value::Bool(b) => DataValue::Bool(b),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                DataValue::from(i)
+            } else if n.is_u64() {
+                return Err(format!("{} does not fit in a 64-bit signed integer", n));
+            } else {
+                match n.as_f64() {
+                    Some(f) => DataValue::from(f),
+                    None => return Err(format!("{} is not a representable number", n)),
+                }
+            }
+        }
+        Value::String(s) => DataValue::from(s),
+        Value::Array(items) => DataValue::List(items.into_iter().map(to_data_value).collect::<Result<_, _>>()?),
+        Value::Object(map) => DataValue::Json(JsonData(Value::Object(map))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CognitiveCore, CoreConfig};
+    use serde_json::json;
+
+    /// Binds `value` as `$x` and reads it back out of a query.
+    fn round_trip(core: &CognitiveCore, value: Value) -> Result<Value, CoreError> {
+        let result = core.run("?[x] <- [[$x]]", json!({ "x": value }))?;
+        Ok(result["rows"][0][0].clone())
+    }
+
+    #[test]
+    fn maps_each_json_type_to_its_engine_type() {
+        assert_eq!(to_data_value(json!(null)), Ok(DataValue::Null));
+        assert_eq!(to_data_value(json!(true)), Ok(DataValue::Bool(true)));
+        assert_eq!(to_data_value(json!(i64::MIN)), Ok(DataValue::from(i64::MIN)));
+        assert_eq!(to_data_value(json!(2.5)), Ok(DataValue::from(2.5)));
+        assert_eq!(to_data_value(json!("naïve")), Ok(DataValue::from("naïve")));
+        assert_eq!(
+            to_data_value(json!([1, [null, "a"]])),
+            Ok(DataValue::List(vec![DataValue::from(1), DataValue::List(vec![DataValue::Null, DataValue::from("a")])]))
+        );
+        assert_eq!(to_data_value(json!({"a": [1]})), Ok(DataValue::Json(JsonData(json!({"a": [1]})))));
+        assert!(to_data_value(json!(i64::MAX as u64 + 1)).unwrap_err().contains("64-bit signed integer"));
+    }
+
+    #[test]
+    fn every_json_type_round_trips_through_a_query() {
+        let core = CognitiveCore::new(CoreConfig::default()).unwrap();
+        let values = [
+            json!(null),
+            json!(true),
+            json!(false),
+            json!(0),
+            json!(-17),
+            json!(i64::MAX),
+            json!(i64::MIN),
+            // Past 2^53 an f64 can no longer hold every integer, but the binding stays an Int.
+            json!(9_007_199_254_740_993i64),
+            json!(0.1),
+            json!(-2.5e-300),
+            json!(f64::MAX),
+            json!(""),
+            json!("naïve café, 日本語, 🦀, \"quoted\" and 'single'"),
+            json!("line\nbreak\ttab\u{0}nul"),
+            json!([]),
+            json!([1, "two", 3.5, null, [true, [false]]]),
+        ];
+        for value in values {
+            assert_eq!(round_trip(&core, value.clone()).unwrap(), value);
+        }
+        assert_eq!(round_trip(&core, json!({"nested": {"list": [1, 2]}})).unwrap(), json!({"nested": {"list": [1, 2]}}));
+
+        // Bound values are data, never query text.
+        let injected = "x'] :put victim {k} <- [['";
+        assert_eq!(round_trip(&core, json!(injected)).unwrap(), json!(injected));
+    }
+
+    #[test]
+    fn refuses_params_that_cannot_be_bound() {
+        let core = CognitiveCore::new(CoreConfig::default()).unwrap();
+        for params in [json!([1]), json!("x"), json!(3), json!(true)] {
+            assert!(matches!(core.run("?[x] <- [[1]]", params.clone()), Err(CoreError::InvalidParams(_))), "{}", params);
+        }
+        match core.run("?[x] <- [[$x]]", json!({ "x": u64::MAX })) {
+            Err(CoreError::InvalidParams(message)) => assert!(message.starts_with("$x: "), "{}", message),
+            other => panic!("expected InvalidParams, got {:?}", other),
+        }
+        assert!(matches!(core.run("?[x, y] <- [[$x, $y]]", json!({ "x": 1 })), Err(CoreError::MissingParam(name)) if name == "y"));
+        assert_eq!(core.run("?[x] <- [[1]]", Value::Null).unwrap()["rows"], json!([[1]]));
+    }
+}
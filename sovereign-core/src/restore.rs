@@ -0,0 +1,122 @@
+use crate::bulk::{read_json_lines, Column, Import};
+use crate::schema::{check_name, check_relation, SYSTEM_PREFIX};
+use crate::{CognitiveCore, ColumnInfo, CoreError, CoreTransaction, ImportMode};
+use cozo::ScriptMutability;
+use std::collections::BTreeMap;
+use std::io::Read;
+
+/// One relation for `restore_relations`: its columns as `describe` lists
+/// them, keys first in key order, and its rows as JSON Lines, as
+/// `export_relation` writes them.
+pub struct RelationRestore<R> {
+    pub name: String,
+    pub columns: Vec<ColumnInfo>,
+    pub rows: R,
+}
+
+impl CognitiveCore {
+    /// Replaces the rows of every relation in `relations`, creating those
+    /// that are missing, and returns the rows restored to each.
+    ///
+    /// All rows are written in one transaction. A row that does not fit
+    /// its relation fails the restore like any other error: nothing is
+    /// replaced and the relations created for it are dropped again. A
+    /// relation that exists with other columns fails it before anything
+    /// changes. Relations created here have no indices and no column
+    /// defaults.
+    pub fn restore_relations<R: Read>(&self, relations: Vec<RelationRestore<R>>) -> Result<Vec<u64>, CoreError> {
+        let existing = self.relation_names()?;
+        let mut missing = Vec::new();
+        for relation in &relations {
+            check_relation(&relation.name)?;
+            if relation.name.starts_with(SYSTEM_PREFIX) {
+                return Err(CoreError::InvalidName(format!("'{}' names are reserved for the core", SYSTEM_PREFIX)));
+            }
+            if !existing.contains(&relation.name) {
+                missing.push((relation.name.clone(), relation.columns.clone()));
+            } else if !same_columns(&self.columns(&relation.name)?, &relation.columns) {
+                return Err(CoreError::InvalidImport(format!("relation '{}' exists with other columns", relation.name)));
+            }
+        }
+
+        let mut created = Vec::new();
+        let restored = missing
+            .iter()
+            .try_for_each(|(name, columns)| {
+                self.create_as_listed(name, columns)?;
+                created.push(name.as_str());
+                Ok(())
+            })
+            .and_then(|()| self.transact(|tx| replace_rows(tx, relations)));
+        if restored.is_err() {
+            for name in created.iter().rev() {
+                let _ = self.drop_relation(name, true);
+            }
+        }
+        restored
+    }
+
+    /// Creates `name` with columns as `::columns` lists them, type text and
+    /// all. The text is spliced into the script, so only the characters
+    /// types are written with pass.
+    fn create_as_listed(&self, name: &str, columns: &[ColumnInfo]) -> Result<(), CoreError> {
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        for column in columns {
+            check_name(&column.name)?;
+            let plain = |c: char| c.is_ascii_alphanumeric() || " ;,?<>[]_".contains(c);
+            if column.column_type.is_empty() || !column.column_type.chars().all(plain) {
+                return Err(CoreError::InvalidSchema(format!(
+                    "column '{}' of '{}' has the unknown type '{}'",
+                    column.name,
+                    name,
+                    column.column_type.escape_debug()
+                )));
+            }
+            let def = format!("{}: {}", column.name, column.column_type);
+            if column.key {
+                keys.push(def);
+            } else {
+                values.push(def);
+            }
+        }
+        if keys.is_empty() {
+            return Err(CoreError::InvalidSchema(format!("relation '{}' needs at least one key column", name)));
+        }
+        let script = if values.is_empty() {
+            format!(":create {} {{{}}}", name, keys.join(", "))
+        } else {
+            format!(":create {} {{{} => {}}}", name, keys.join(", "), values.join(", "))
+        };
+        self.script(&script, BTreeMap::new(), ScriptMutability::Mutable)?;
+        if name.contains('.') {
+            self.register_owners(&[name.to_string()])?;
+        }
+        Ok(())
+    }
+}
+
+fn replace_rows<R: Read>(tx: &mut CoreTransaction, relations: Vec<RelationRestore<R>>) -> Result<Vec<u64>, CoreError> {
+    let mut restored = Vec::with_capacity(relations.len());
+    for relation in relations {
+        let columns: Vec<Column> = relation.columns.iter().map(Column::new).collect();
+        let mut import = Import::new(tx, &relation.name, &columns, ImportMode::Replace);
+        import.clear()?;
+        read_json_lines(relation.rows, &mut import)?;
+        let summary = import.finish()?;
+        if let Some(reject) = summary.rejects.first() {
+            return Err(CoreError::InvalidImport(format!(
+                "row {} of '{}' was rejected: {}",
+                reject.line, relation.name, reject.reason
+            )));
+        }
+        restored.push(summary.inserted);
+    }
+    Ok(restored)
+}
+
+/// Whether two column lists name the same columns, of the same types, in
+/// the same order and with the same keys.
+fn same_columns(a: &[ColumnInfo], b: &[ColumnInfo]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.name == b.name && a.column_type == b.column_type && a.key == b.key)
+}
@@ -0,0 +1,393 @@
+use crate::{CognitiveCore, CoreError};
+use cozo::{DataValue, NamedRows, ScriptMutability};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+/// Relations the core keeps for itself start with this; `create_relation`
+/// refuses the prefix, as does a namespace, and `list_relations` leaves
+/// them out.
+pub(crate) const SYSTEM_PREFIX: &str = "sovereign_";
+
+/// A column for `create_relation`.
+#[derive(Debug, Clone)]
+pub struct ColumnDef {
+    pub name: String,
+    pub column_type: ColumnType,
+    /// Accepts null as well as `column_type`.
+    pub nullable: bool,
+    /// Part of the relation's key; key columns form it in the order given.
+    pub key: bool,
+}
+
+/// Column types `create_relation` can declare.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Any,
+    Bool,
+    Int,
+    Float,
+    String,
+    Bytes,
+    Uuid,
+    Json,
+    Validity,
+    /// A vector of 32-bit floats with this many components, as indexed by
+    /// `create_vector_index`.
+    Vector(usize),
+}
+
+/// A stored relation as listed by `list_relations`.
+#[derive(Debug, Clone)]
+pub struct RelationInfo {
+    pub name: String,
+    pub arity: usize,
+    /// Number of key columns.
+    pub keys: usize,
+    pub rows: u64,
+    pub indices: Vec<IndexInfo>,
+}
+
+/// A stored relation's columns, from `describe`.
+#[derive(Debug, Clone)]
+pub struct RelationSchema {
+    pub name: String,
+    pub columns: Vec<ColumnInfo>,
+    pub rows: u64,
+    pub indices: Vec<IndexInfo>,
+    /// Rows kept in the relation's history, if it keeps one; see
+    /// `CognitiveCore::enable_history`.
+    pub history_rows: Option<u64>,
+}
+
+/// An index attached to a stored relation.
+#[derive(Debug, Clone)]
+pub struct IndexInfo {
+    pub name: String,
+    /// `normal`, `fts`, `hnsw` or `lsh`.
+    pub kind: String,
+    /// The engine's settings for the index, e.g. an FTS index's tokenizer.
+    pub config: serde_json::Value,
+}
+
+#[derive(Debug, Clone)]
+pub struct ColumnInfo {
+    pub name: String,
+    /// The engine's type text, e.g. `Int?` or `[Float; 3]`.
+    pub column_type: String,
+    pub key: bool,
+    pub has_default: bool,
+}
+
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ColumnType::Any => "Any",
+            ColumnType::Bool => "Bool",
+            ColumnType::Int => "Int",
+            ColumnType::Float => "Float",
+            ColumnType::String => "String",
+            ColumnType::Bytes => "Bytes",
+            ColumnType::Uuid => "Uuid",
+            ColumnType::Json => "Json",
+            ColumnType::Validity => "Validity",
+            ColumnType::Vector(dim) => return write!(f, "<F32; {}>", dim),
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl ColumnType {
+    /// Reads a type as `::columns` prints it, without the `?`.
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "Any" => ColumnType::Any,
+            "Bool" => ColumnType::Bool,
+            "Int" => ColumnType::Int,
+            "Float" => ColumnType::Float,
+            "String" => ColumnType::String,
+            "Bytes" => ColumnType::Bytes,
+            "Uuid" => ColumnType::Uuid,
+            "Json" => ColumnType::Json,
+            "Validity" => ColumnType::Validity,
+            _ => {
+                let dim = name.strip_prefix("<F32;")?.strip_suffix('>')?;
+                ColumnType::Vector(dim.trim().parse().ok()?)
+            }
+        })
+    }
+}
+
+impl CognitiveCore {
+    /// Creates a stored relation. Fails if it already exists.
+    pub fn create_relation(&self, name: &str, columns: &[ColumnDef]) -> Result<(), CoreError> {
+        check_relation(name)?;
+        if name.starts_with(SYSTEM_PREFIX) {
+            return Err(CoreError::InvalidName(format!("'{}' names are reserved for the core", SYSTEM_PREFIX)));
+        }
+        if columns.is_empty() {
+            return Err(CoreError::InvalidSchema(format!("relation '{}' needs at least one column", name)));
+        }
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        for column in columns {
+            check_name(&column.name)?;
+            let nullable = if column.nullable { "?" } else { "" };
+            let def = format!("{}: {}{}", column.name, column.column_type, nullable);
+            if column.key {
+                keys.push(def);
+            } else {
+                values.push(def);
+            }
+        }
+        let script = if values.is_empty() {
+            format!(":create {} {{{}}}", name, keys.join(", "))
+        } else {
+            format!(":create {} {{{} => {}}}", name, keys.join(", "), values.join(", "))
+        };
+        self.script(&script, BTreeMap::new(), ScriptMutability::Mutable)?;
+        if name.contains('.') {
+            self.register_owners(&[name.to_string()])?;
+        }
+        Ok(())
+    }
+
+    /// Stored relations, without their indices, with row counts.
+    pub fn list_relations(&self) -> Result<Vec<RelationInfo>, CoreError> {
+        let listed = self.script("::relations", BTreeMap::new(), ScriptMutability::Immutable)?;
+        let mut relations = Vec::new();
+        for row in &listed.rows {
+            let name = cell_str(&listed, row, "name");
+            // Indices are listed as `relation:index`.
+            if name.contains(':') || name.starts_with(SYSTEM_PREFIX) {
+                continue;
+            }
+            let name = name.to_string();
+            let rows = self.count_rows(&name)?;
+            let indices = self.indices(&name)?;
+            relations.push(RelationInfo {
+                arity: cell_int(&listed, row, "arity") as usize,
+                keys: cell_int(&listed, row, "n_keys") as usize,
+                name,
+                rows,
+                indices,
+            });
+        }
+        Ok(relations)
+    }
+
+    pub fn describe(&self, name: &str) -> Result<RelationSchema, CoreError> {
+        check_relation(name)?;
+        let columns = self.columns(name)?;
+        let history_rows = self.history_of(name)?.map(|history| self.count_rows(&history)).transpose()?;
+        Ok(RelationSchema {
+            rows: self.count_rows(name)?,
+            indices: self.indices(name)?,
+            history_rows,
+            name: name.to_string(),
+            columns,
+        })
+    }
+
+    /// Removes a stored relation, its indices, its data and its history. A
+    /// relation that still holds rows is only removed with `force`.
+    pub fn drop_relation(&self, name: &str, force: bool) -> Result<(), CoreError> {
+        check_relation(name)?;
+        if !force {
+            let rows = self.count_rows(name)?;
+            if rows > 0 {
+                return Err(CoreError::RelationNotEmpty { relation: name.to_string(), rows });
+            }
+        }
+        // The engine refuses to remove a relation with indices attached.
+        for index in self.indices(name)? {
+            let kind = match index.kind.as_str() {
+                "normal" => "index",
+                other => other,
+            };
+            let script = format!("::{} drop {}:{}", kind, name, index.name);
+            self.script(&script, BTreeMap::new(), ScriptMutability::Mutable)?;
+        }
+        self.script(&format!("::remove {}", name), BTreeMap::new(), ScriptMutability::Mutable)?;
+        self.disable_history(name)?;
+        self.unregister_owner(name)
+    }
+
+    pub(crate) fn relation_exists(&self, name: &str) -> Result<bool, CoreError> {
+        Ok(self.relation_names()?.contains(name))
+    }
+
+    /// Every stored relation's name, system relations included.
+    pub(crate) fn relation_names(&self) -> Result<HashSet<String>, CoreError> {
+        let listed = self.script("::relations", BTreeMap::new(), ScriptMutability::Immutable)?;
+        Ok(listed.rows.iter().map(|row| cell_str(&listed, row, "name").to_string()).collect())
+    }
+
+    /// The relation's columns, keys first in key order.
+    pub(crate) fn columns(&self, name: &str) -> Result<Vec<ColumnInfo>, CoreError> {
+        let listed = self.script(&format!("::columns {}", name), BTreeMap::new(), ScriptMutability::Immutable)?;
+        Ok(listed
+            .rows
+            .iter()
+            .map(|row| ColumnInfo {
+                name: cell_str(&listed, row, "column").to_string(),
+                column_type: cell_str(&listed, row, "type").to_string(),
+                key: cell_bool(&listed, row, "is_key"),
+                has_default: cell_bool(&listed, row, "has_default"),
+            })
+            .collect())
+    }
+
+    fn indices(&self, name: &str) -> Result<Vec<IndexInfo>, CoreError> {
+        let listed = self.script(&format!("::indices {}", name), BTreeMap::new(), ScriptMutability::Immutable)?;
+        Ok(listed
+            .rows
+            .iter()
+            .map(|row| IndexInfo {
+                name: cell_str(&listed, row, "name").to_string(),
+                kind: cell_str(&listed, row, "type").to_string(),
+                config: cell(&listed, row, "config").cloned().map_or(serde_json::Value::Null, serde_json::Value::from),
+            })
+            .collect())
+    }
+
+    /// Counts rows by scanning the relation's keys.
+    fn count_rows(&self, name: &str) -> Result<u64, CoreError> {
+        let columns = self.columns(name)?;
+        let keys: Vec<&str> = columns.iter().filter(|c| c.key).map(|c| c.name.as_str()).collect();
+        let Some(first) = keys.first() else {
+            return Ok(0);
+        };
+        let script = format!("?[count({})] := *{}{{{}}}", first, name, keys.join(", "));
+        let counted = self.script(&script, BTreeMap::new(), ScriptMutability::Immutable)?;
+        Ok(counted.rows.first().and_then(|row| row.first()).and_then(DataValue::get_int).unwrap_or(0) as u64)
+    }
+}
+
+/// Names are spliced into script text, so only plain identifiers pass:
+/// an ASCII letter, then letters, digits or underscores.
+pub(crate) fn check_name(name: &str) -> Result<(), CoreError> {
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && name.len() <= 128;
+    if valid {
+        Ok(())
+    } else {
+        Err(CoreError::InvalidName(format!(
+            "'{}' must be a letter followed by letters, digits or underscores",
+            name.escape_debug()
+        )))
+    }
+}
+
+/// A relation name: a plain name, or one qualified by its namespace as
+/// `<namespace>.<name>`.
+pub(crate) fn check_relation(name: &str) -> Result<(), CoreError> {
+    match name.split_once('.') {
+        Some((namespace, name)) => {
+            check_name(namespace)?;
+            check_name(name)
+        }
+        None => check_name(name),
+    }
+}
+
+fn cell<'a>(rows: &NamedRows, row: &'a [DataValue], header: &str) -> Option<&'a DataValue> {
+    rows.headers.iter().position(|h| h == header).and_then(|i| row.get(i))
+}
+
+fn cell_str<'a>(rows: &NamedRows, row: &'a [DataValue], header: &str) -> &'a str {
+    cell(rows, row, header).and_then(DataValue::get_str).unwrap_or_default()
+}
+
+fn cell_int(rows: &NamedRows, row: &[DataValue], header: &str) -> i64 {
+    cell(rows, row, header).and_then(DataValue::get_int).unwrap_or_default()
+}
+
+fn cell_bool(rows: &NamedRows, row: &[DataValue], header: &str) -> bool {
+    cell(rows, row, header).and_then(DataValue::get_bool).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CoreConfig;
+    use serde_json::Value;
+
+    fn column(name: &str, column_type: ColumnType, key: bool) -> ColumnDef {
+        ColumnDef {
+            name: name.to_string(),
+            column_type,
+            nullable: false,
+            key,
+        }
+    }
+
+    #[test]
+    fn creates_lists_describes_and_drops_relations() {
+        let core = CognitiveCore::new(CoreConfig::default()).unwrap();
+        let columns = [
+            column("id", ColumnType::Int, true),
+            column("name", ColumnType::String, false),
+            ColumnDef {
+                nullable: true,
+                ..column("embedding", ColumnType::Vector(3), false)
+            },
+        ];
+        core.create_relation("people", &columns).unwrap();
+        core.create_relation("tags", &[column("tag", ColumnType::String, true)]).unwrap();
+        assert!(core.create_relation("people", &columns).is_err());
+        core.run("?[id, name, embedding] <- [[1, 'ada', null], [2, 'alan', null]] :put people {id => name, embedding}", Value::Null).unwrap();
+
+        let listed: Vec<(String, usize, usize, u64)> =
+            core.list_relations().unwrap().into_iter().map(|r| (r.name, r.arity, r.keys, r.rows)).collect();
+        assert_eq!(listed, [("people".to_string(), 3, 1, 2), ("tags".to_string(), 1, 1, 0)]);
+
+        let schema = core.describe("people").unwrap();
+        let described: Vec<(&str, &str, bool)> =
+            schema.columns.iter().map(|c| (c.name.as_str(), c.column_type.as_str(), c.key)).collect();
+        assert_eq!(described, [("id", "Int", true), ("name", "String", false), ("embedding", "<F32;3>?", false)]);
+        assert_eq!((schema.rows, schema.history_rows), (2, None));
+        assert!(schema.indices.is_empty());
+
+        assert!(matches!(
+            core.drop_relation("people", false),
+            Err(CoreError::RelationNotEmpty { relation, rows: 2 }) if relation == "people"
+        ));
+        core.drop_relation("tags", false).unwrap();
+        core.drop_relation("people", true).unwrap();
+        assert!(core.list_relations().unwrap().is_empty());
+        assert!(core.describe("people").is_err());
+    }
+
+    #[test]
+    fn refuses_names_that_could_change_the_script() {
+        let core = CognitiveCore::new(CoreConfig::default()).unwrap();
+        core.create_relation("victim", &[column("k", ColumnType::Int, true)]).unwrap();
+        let hostile = [
+            "",
+            "a'b",
+            "a\"b",
+            "a{b}",
+            "victim} ::remove victim {",
+            "victim\n::remove victim",
+            "a b",
+            "1abc",
+            "a.b.c",
+            "a;b",
+            "über",
+        ];
+        for name in hostile {
+            assert!(matches!(core.create_relation(name, &[column("k", ColumnType::Int, true)]), Err(CoreError::InvalidName(_))), "{:?}", name);
+            assert!(matches!(core.create_relation("fine", &[column(name, ColumnType::Int, true)]), Err(CoreError::InvalidName(_))), "{:?}", name);
+            assert!(matches!(core.describe(name), Err(CoreError::InvalidName(_))), "{:?}", name);
+            assert!(matches!(core.drop_relation(name, true), Err(CoreError::InvalidName(_))), "{:?}", name);
+        }
+        assert!(matches!(core.create_relation(&"a".repeat(129), &[column("k", ColumnType::Int, true)]), Err(CoreError::InvalidName(_))));
+        assert!(matches!(core.create_relation("sovereign_grants", &[column("k", ColumnType::Int, true)]), Err(CoreError::InvalidName(_))));
+        assert!(matches!(core.create_relation("empty", &[]), Err(CoreError::InvalidSchema(_))));
+
+        let names: Vec<String> = core.list_relations().unwrap().into_iter().map(|r| r.name).collect();
+        assert_eq!(names, ["victim"]);
+    }
+}
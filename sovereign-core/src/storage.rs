@@ -0,0 +1,469 @@
+use crate::engine::{skip_scan, Base, Engine, Rows, Staged};
+use anyhow::{bail, Context, Result};
+use cozo::{decode_tuple_from_kv, DataValue, StoreTx, ValidityTs};
+use sqlite::{Connection, ConnectionThreadSafe, State, Statement};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+
+/// Storage engine written to each store's marker. Stores written by another
+/// engine release are refused rather than opened and possibly corrupted.
+const ENGINE: &str = "cozo-0.7";
+
+/// How the core stores its data.
+#[derive(Debug, Clone, Default)]
+pub enum CoreBackend {
+    /// Kept in memory and lost when the core is dropped.
+    #[default]
+    Mem,
+    /// A single SQLite file.
+    Sqlite { path: PathBuf },
+    /// A RocksDB directory. Only available with the `rocksdb` feature.
+    RocksDb { path: PathBuf },
+}
+
+impl CoreBackend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CoreBackend::Mem => "mem",
+            CoreBackend::Sqlite { .. } => "sqlite",
+            CoreBackend::RocksDb { .. } => "rocksdb",
+        }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            CoreBackend::Mem => None,
+            CoreBackend::Sqlite { path } | CoreBackend::RocksDb { path } => Some(path),
+        }
+    }
+
+    /// Opens the store, creating it if it does not exist yet.
+    pub(crate) fn open(&self) -> Result<Engine> {
+        let Some(path) = self.path() else {
+            return Engine::mem().map_err(|e| anyhow::anyhow!("opening the core database: {}", e));
+        };
+        #[cfg(not(feature = "rocksdb"))]
+        if let CoreBackend::RocksDb { .. } = self {
+            bail!("this build has no RocksDB support; rebuild sovereign-core with the `rocksdb` feature");
+        }
+        self.check_store(path)?;
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent).with_context(|| format!("creating {}", parent.display()))?;
+        }
+        let db = match self {
+            #[cfg(feature = "rocksdb")]
+            CoreBackend::RocksDb { .. } => Engine::rocksdb(path),
+            _ => Engine::sqlite(path),
+        }
+        .map_err(|e| anyhow::anyhow!("opening the core database at {}: {}", path.display(), e))?;
+        let marker = serde_json::json!({ "backend": self.name(), "engine": ENGINE });
+        std::fs::write(marker_path(path), marker.to_string())
+            .with_context(|| format!("writing {}", marker_path(path).display()))?;
+        Ok(db)
+    }
+
+    /// Refuses a store written by another backend or engine release. Stores
+    /// from before markers were written are recognised by their layout.
+    fn check_store(&self, path: &Path) -> Result<()> {
+        let marker = marker_path(path);
+        match std::fs::read(&marker) {
+            Ok(bytes) => {
+                let marker: serde_json::Value =
+                    serde_json::from_slice(&bytes).with_context(|| format!("reading {}", marker.display()))?;
+                let backend = marker["backend"].as_str().unwrap_or("unknown");
+                let engine = marker["engine"].as_str().unwrap_or("unknown");
+                if backend != self.name() {
+                    bail!(
+                        "{} holds a {} core database, but the core is configured for {}",
+                        path.display(),
+                        backend,
+                        self.name()
+                    );
+                }
+                if engine != ENGINE {
+                    bail!(
+                        "{} was written by {}, which this node ({}) cannot open safely; export it with the old release and import it here",
+                        path.display(),
+                        engine,
+                        ENGINE
+                    );
+                }
+                return Ok(());
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("reading {}", marker.display())),
+        }
+        let Ok(meta) = std::fs::metadata(path) else {
+            return Ok(());
+        };
+        let found = if meta.is_dir() {
+            "a directory"
+        } else if meta.len() == 0 || is_sqlite(path) {
+            "an SQLite file"
+        } else {
+            "a file of unknown format"
+        };
+        let expected = match self {
+            CoreBackend::Sqlite { .. } => "an SQLite file",
+            _ => "a directory",
+        };
+        if found != expected {
+            bail!(
+                "{} is {}, not {} as the {} backend needs",
+                path.display(),
+                found,
+                expected,
+                self.name()
+            );
+        }
+        Ok(())
+    }
+
+    /// Bytes the store takes on disk, including SQLite's write-ahead log.
+    pub(crate) fn size_on_disk(&self) -> u64 {
+        match self {
+            CoreBackend::Mem => 0,
+            CoreBackend::Sqlite { path } => {
+                let mut wal = path.clone().into_os_string();
+                wal.push("-wal");
+                file_size(path) + file_size(Path::new(&wal))
+            }
+            CoreBackend::RocksDb { path } => dir_size(path),
+        }
+    }
+}
+
+/// How long an SQLite statement waits for a lock another process holds.
+const SQLITE_BUSY_TIMEOUT_MS: usize = 5000;
+
+/// An SQLite file in the engine's own layout, one `cozo` table of key and
+/// value blobs, so stores written by the engine's SQLite backend open as
+/// they are. Reads share the file and a commit has it to itself; within
+/// the node that is the lock below, and another process is waited for.
+#[derive(Clone)]
+pub(crate) struct SqliteStore(Arc<SqliteFile>);
+
+struct SqliteFile {
+    path: PathBuf,
+    lock: RwLock<()>,
+    pool: Mutex<Vec<ConnectionThreadSafe>>,
+}
+
+impl SqliteStore {
+    pub(crate) fn open(path: &Path) -> Result<Self, cozo::Error> {
+        let store = Self(Arc::new(SqliteFile {
+            path: path.to_path_buf(),
+            lock: RwLock::new(()),
+            pool: Mutex::new(Vec::new()),
+        }));
+        let conn = store.connection()?;
+        conn.execute("create table if not exists cozo (k BLOB primary key, v BLOB);").map_err(sqlite_error)?;
+        store.give_back(conn);
+        Ok(store)
+    }
+
+    fn connection(&self) -> Result<ConnectionThreadSafe, cozo::Error> {
+        if let Some(conn) = self.0.pool.lock().unwrap_or_else(|e| e.into_inner()).pop() {
+            return Ok(conn);
+        }
+        let mut conn = Connection::open_thread_safe(&self.0.path).map_err(sqlite_error)?;
+        conn.set_busy_timeout(SQLITE_BUSY_TIMEOUT_MS).map_err(sqlite_error)?;
+        Ok(conn)
+    }
+
+    fn give_back(&self, conn: ConnectionThreadSafe) {
+        self.0.pool.lock().unwrap_or_else(|e| e.into_inner()).push(conn);
+    }
+}
+
+impl Base for SqliteStore {
+    type Reader<'s> = SqliteReader<'s>;
+
+    fn kind(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn read(&self) -> Result<SqliteReader<'_>, cozo::Error> {
+        let lock = self.0.lock.read().unwrap_or_else(|e| e.into_inner());
+        Ok(SqliteReader {
+            store: self,
+            conn: Some(self.connection()?),
+            _lock: lock,
+        })
+    }
+
+    fn apply(&self, changes: &Staged) -> Result<(), cozo::Error> {
+        let _lock = self.0.lock.write().unwrap_or_else(|e| e.into_inner());
+        let conn = self.connection()?;
+        let applied = write_changes(&conn, changes);
+        if applied.is_err() {
+            let _ = conn.execute("rollback;");
+        }
+        self.give_back(conn);
+        applied.map_err(sqlite_error)
+    }
+}
+
+fn write_changes(conn: &Connection, changes: &Staged) -> sqlite::Result<()> {
+    conn.execute("begin immediate;")?;
+    {
+        let mut clear = conn.prepare("delete from cozo where k >= ? and k < ?;")?;
+        for (lower, upper) in &changes.cleared {
+            clear.reset()?;
+            clear.bind((1, lower.as_slice()))?;
+            clear.bind((2, upper.as_slice()))?;
+            while clear.next()? != State::Done {}
+        }
+        let mut put = conn.prepare("insert into cozo(k, v) values (?, ?) on conflict(k) do update set v = excluded.v;")?;
+        let mut del = conn.prepare("delete from cozo where k = ?;")?;
+        for (key, value) in &changes.writes {
+            let statement = match value {
+                Some(value) => {
+                    put.reset()?;
+                    put.bind((2, value.as_slice()))?;
+                    &mut put
+                }
+                None => {
+                    del.reset()?;
+                    &mut del
+                }
+            };
+            statement.bind((1, key.as_slice()))?;
+            while statement.next()? != State::Done {}
+        }
+    }
+    conn.execute("commit;")
+}
+
+fn sqlite_error(e: sqlite::Error) -> cozo::Error {
+    cozo::Error::msg(e.to_string())
+}
+
+/// Reads committed rows. Each statement is finished before it returns,
+/// or, for a scan, once the scan is dropped, so none holds the file past
+/// the reader.
+pub(crate) struct SqliteReader<'s> {
+    store: &'s SqliteStore,
+    conn: Option<ConnectionThreadSafe>,
+    _lock: RwLockReadGuard<'s, ()>,
+}
+
+impl Drop for SqliteReader<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.store.give_back(conn);
+        }
+    }
+}
+
+impl SqliteReader<'_> {
+    fn query(&self, sql: &str, keys: &[&[u8]]) -> sqlite::Result<Statement<'_>> {
+        let conn = self.conn.as_ref().expect("reader has its connection until dropped");
+        let mut statement = conn.prepare(sql)?;
+        for (n, key) in keys.iter().enumerate() {
+            statement.bind((n + 1, *key))?;
+        }
+        Ok(statement)
+    }
+
+    fn rows<'a>(&'a self, sql: &str, keys: &[&[u8]]) -> Rows<'a> {
+        let mut statement = match self.query(sql, keys) {
+            Ok(statement) => statement,
+            Err(e) => return Box::new(std::iter::once(Err(sqlite_error(e)))),
+        };
+        Box::new(std::iter::from_fn(move || match statement.next() {
+            Ok(State::Row) => Some(
+                statement
+                    .read::<Vec<u8>, _>(0)
+                    .and_then(|key| Ok((key, statement.read::<Vec<u8>, _>(1)?)))
+                    .map_err(sqlite_error),
+            ),
+            Ok(State::Done) => None,
+            Err(e) => Some(Err(sqlite_error(e))),
+        }))
+    }
+}
+
+impl<'s> StoreTx<'s> for SqliteReader<'s> {
+    fn get(&self, key: &[u8], _for_update: bool) -> Result<Option<Vec<u8>>, cozo::Error> {
+        let mut statement = self.query("select v from cozo where k = ?;", &[key]).map_err(sqlite_error)?;
+        match statement.next().map_err(sqlite_error)? {
+            State::Row => statement.read::<Vec<u8>, _>(0).map(Some).map_err(sqlite_error),
+            State::Done => Ok(None),
+        }
+    }
+
+    fn put(&mut self, _key: &[u8], _value: &[u8]) -> Result<(), cozo::Error> {
+        Err(cozo::Error::msg("write in read transaction"))
+    }
+
+    fn supports_par_put(&self) -> bool {
+        false
+    }
+
+    fn del(&mut self, _key: &[u8]) -> Result<(), cozo::Error> {
+        Err(cozo::Error::msg("write in read transaction"))
+    }
+
+    fn del_range_from_persisted(&mut self, _lower: &[u8], _upper: &[u8]) -> Result<(), cozo::Error> {
+        Err(cozo::Error::msg("write in read transaction"))
+    }
+
+    fn exists(&self, key: &[u8], for_update: bool) -> Result<bool, cozo::Error> {
+        Ok(self.get(key, for_update)?.is_some())
+    }
+
+    fn commit(&mut self) -> Result<(), cozo::Error> {
+        Ok(())
+    }
+
+    fn range_skip_scan_tuple<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+        valid_at: ValidityTs,
+    ) -> Box<dyn Iterator<Item = Result<Vec<DataValue>, cozo::Error>> + 'a> {
+        skip_scan(self.range_scan(lower, upper), valid_at)
+    }
+
+    fn range_scan_tuple<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+    ) -> Box<dyn Iterator<Item = Result<Vec<DataValue>, cozo::Error>> + 'a>
+    where
+        's: 'a,
+    {
+        Box::new(self.range_scan(lower, upper).map(|row| row.map(|(key, value)| decode_tuple_from_kv(&key, &value, None))))
+    }
+
+    fn range_scan<'a>(&'a self, lower: &[u8], upper: &[u8]) -> Rows<'a>
+    where
+        's: 'a,
+    {
+        self.rows("select k, v from cozo where k >= ? and k < ? order by k;", &[lower, upper])
+    }
+
+    fn range_count<'a>(&'a self, lower: &[u8], upper: &[u8]) -> Result<usize, cozo::Error>
+    where
+        's: 'a,
+    {
+        let mut statement =
+            self.query("select count(*) from cozo where k >= ? and k < ?;", &[lower, upper]).map_err(sqlite_error)?;
+        statement.next().map_err(sqlite_error)?;
+        statement.read::<i64, _>(0).map(|count| count as usize).map_err(sqlite_error)
+    }
+
+    fn total_scan<'a>(&'a self) -> Rows<'a>
+    where
+        's: 'a,
+    {
+        self.rows("select k, v from cozo order by k;", &[])
+    }
+}
+
+/// `<path>.meta.json`, next to the store rather than inside it.
+fn marker_path(path: &Path) -> PathBuf {
+    let mut marker = path.as_os_str().to_owned();
+    marker.push(".meta.json");
+    PathBuf::from(marker)
+}
+
+fn is_sqlite(path: &Path) -> bool {
+    use std::io::Read;
+    let mut header = [0u8; 16];
+    std::fs::File::open(path).and_then(|mut f| f.read_exact(&mut header)).is_ok() && &header == b"SQLite format 3\0"
+}
+
+fn file_size(path: &Path) -> u64 {
+    std::fs::metadata(path).map_or(0, |m| m.len())
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(t) if t.is_dir() => dir_size(&entry.path()),
+            Ok(_) => entry.metadata().map_or(0, |m| m.len()),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+    use crate::{CognitiveCore, CoreConfig};
+    use serde_json::{json, Value};
+
+    fn open(backend: &CoreBackend) -> Result<CognitiveCore> {
+        CognitiveCore::new(CoreConfig {
+            backend: backend.clone(),
+            ..CoreConfig::default()
+        })
+    }
+
+    fn write_fact(core: &CognitiveCore) {
+        core.run(":create facts {k: String => v: Int}", Value::Null).unwrap();
+        core.run("?[k, v] <- [['answer', 42]] :put facts {k => v}", Value::Null).unwrap();
+    }
+
+    fn read_facts(core: &CognitiveCore) -> Option<Value> {
+        core.run("?[k, v] := *facts{k, v}", Value::Null).ok().map(|result| result["rows"].clone())
+    }
+
+    #[test]
+    fn facts_survive_reopening_a_durable_store() {
+        let dir = TempDir::new("reopen");
+        let mut backends = vec![CoreBackend::Sqlite { path: dir.path().join("core.db") }];
+        if cfg!(feature = "rocksdb") {
+            backends.push(CoreBackend::RocksDb { path: dir.path().join("core.rocks") });
+        }
+        for backend in backends {
+            write_fact(&open(&backend).unwrap());
+            let core = open(&backend).unwrap();
+            assert_eq!(read_facts(&core), Some(json!([["answer", 42]])), "{}", backend.name());
+            let stats = core.stats();
+            assert_eq!((stats.backend, stats.path.as_deref()), (backend.name(), backend.path()));
+            assert!(stats.size_bytes > 0);
+        }
+
+        write_fact(&open(&CoreBackend::Mem).unwrap());
+        let core = open(&CoreBackend::Mem).unwrap();
+        assert_eq!(read_facts(&core), None);
+        let stats = core.stats();
+        assert_eq!((stats.backend, stats.path, stats.size_bytes), ("mem", None, 0));
+    }
+
+    #[test]
+    fn refuses_stores_it_cannot_open_safely() {
+        let dir = TempDir::new("refuse");
+        let path = dir.path().join("core.db");
+        let sqlite = CoreBackend::Sqlite { path: path.clone() };
+        write_fact(&open(&sqlite).unwrap());
+        let size = std::fs::metadata(&path).unwrap().len();
+
+        // Checked directly, since builds without RocksDB refuse the backend first.
+        let err = CoreBackend::RocksDb { path: path.clone() }.check_store(&path).unwrap_err();
+        assert!(err.to_string().contains("holds a sqlite core database, but the core is configured for rocksdb"), "{}", err);
+
+        std::fs::write(marker_path(&path), json!({"backend": "sqlite", "engine": "cozo-0.6"}).to_string()).unwrap();
+        let err = open(&sqlite).err().unwrap();
+        assert!(format!("{:#}", err).contains("written by cozo-0.6"), "{:#}", err);
+        // The store itself is left alone.
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+
+        let unknown = dir.path().join("notes.txt");
+        std::fs::write(&unknown, "not a database").unwrap();
+        let err = open(&CoreBackend::Sqlite { path: unknown.clone() }).err().unwrap();
+        assert!(format!("{:#}", err).contains("a file of unknown format"), "{:#}", err);
+        assert_eq!(std::fs::read_to_string(&unknown).unwrap(), "not a database");
+
+        let err = open(&CoreBackend::Sqlite { path: dir.path().to_path_buf() }).err().unwrap();
+        assert!(format!("{:#}", err).contains("is a directory, not an SQLite file"), "{:#}", err);
+    }
+}
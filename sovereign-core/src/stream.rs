@@ -0,0 +1,43 @@
+use cozo::{DataValue, NamedRows};
+use std::time::Duration;
+
+/// A query result read a row at a time, each row a JSON array in header
+/// order; see `CognitiveCore::run_streaming`.
+pub struct RowStream {
+    headers: Vec<String>,
+    rows: std::vec::IntoIter<Vec<DataValue>>,
+    took: Duration,
+}
+
+impl RowStream {
+    pub(crate) fn new(result: NamedRows, took: Duration) -> Self {
+        Self {
+            headers: result.headers,
+            rows: result.rows.into_iter(),
+            took,
+        }
+    }
+
+    pub fn headers(&self) -> &[String] {
+        &self.headers
+    }
+
+    /// How long the engine took to evaluate the query.
+    pub fn took(&self) -> Duration {
+        self.took
+    }
+}
+
+impl Iterator for RowStream {
+    type Item = Vec<serde_json::Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rows.next().map(|row| row.into_iter().map(serde_json::Value::from).collect())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.rows.size_hint()
+    }
+}
+
+impl ExactSizeIterator for RowStream {}
@@ -0,0 +1,455 @@
+use crate::audit::AuditLog;
+use crate::engine::Engine;
+use crate::grants::{self, Grant};
+use crate::namespace::{self, owners_put};
+use crate::params::bind_params;
+use crate::{result_json, AuditSource, CancelToken, CognitiveCore, CoreError};
+use cozo::{DataValue, MultiTransaction, NamedRows, TransactionPayload};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// How often a statement being waited for checks its cancel token.
+const WAIT_POLL: Duration = Duration::from_millis(10);
+
+/// A write transaction on the core. Statements run through it see each
+/// other's writes, but nothing is visible outside it until `commit`;
+/// `rollback` or dropping it discards everything.
+///
+/// Other queries go on meanwhile and see the committed state. The core
+/// allows one transaction at a time, refusing another `begin` with
+/// `CoreError::TransactionOpen`, and once its first statement has run,
+/// other writes wait for it to end.
+pub struct CoreTransaction {
+    db: Engine,
+    /// Opened by the first statement; see `engine`.
+    tx: Option<MultiTransaction>,
+    open: Arc<AtomicBool>,
+    statements: usize,
+    /// Set by the first failed statement; the transaction can then only be
+    /// rolled back.
+    failed: Option<String>,
+    /// Set when a statement was given up on before the engine answered.
+    unanswered: bool,
+    done: bool,
+    audit: Option<Arc<AuditLog>>,
+    source: AuditSource,
+    namespace: Option<String>,
+    /// Whose grants statements are held to, set by `run_as`.
+    principal: Option<String>,
+    /// Stops statements from being waited for, set by `cancel_on`.
+    cancel: Option<CancelToken>,
+    grants: Arc<RwLock<Arc<[Grant]>>>,
+}
+
+impl CognitiveCore {
+    /// Opens a transaction. Fails with `CoreError::TransactionOpen` while
+    /// another one is open.
+    pub fn begin(&self) -> Result<CoreTransaction, CoreError> {
+        self.begin_as(AuditSource::Internal)
+    }
+
+    /// `begin`, with its statements audited as run by `source`.
+    pub fn begin_as(&self, source: AuditSource) -> Result<CoreTransaction, CoreError> {
+        self.open_transaction(source, None)
+    }
+
+    /// `begin_as`, with every statement confined to `namespace` as by
+    /// `run_in`.
+    pub fn begin_in(&self, namespace: &str, source: AuditSource) -> Result<CoreTransaction, CoreError> {
+        // Statements cannot create the registry once the transaction holds
+        // the store.
+        self.ensure_owners()?;
+        self.open_transaction(source, Some(namespace.to_string()))
+    }
+
+    fn open_transaction(&self, source: AuditSource, namespace: Option<String>) -> Result<CoreTransaction, CoreError> {
+        if self.open.swap(true, Ordering::SeqCst) {
+            return Err(CoreError::TransactionOpen);
+        }
+        Ok(CoreTransaction {
+            db: self.db.clone(),
+            tx: None,
+            open: self.open.clone(),
+            statements: 0,
+            failed: None,
+            unanswered: false,
+            done: false,
+            audit: self.audit.clone(),
+            source,
+            namespace,
+            principal: None,
+            cancel: None,
+            grants: self.grants.clone(),
+        })
+    }
+
+    /// Runs `f` in a transaction, committing if it returns `Ok` and rolling
+    /// back otherwise.
+    pub fn transact<T>(&self, f: impl FnOnce(&mut CoreTransaction) -> Result<T, CoreError>) -> Result<T, CoreError> {
+        let mut tx = self.begin()?;
+        match f(&mut tx) {
+            Ok(value) => {
+                tx.commit()?;
+                Ok(value)
+            }
+            Err(e) => {
+                tx.rollback();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl CoreTransaction {
+    /// Holds the statements run from here on to `principal`'s grants, as
+    /// `QueryOptions::principal` does for a query.
+    pub fn run_as(&mut self, principal: &str) {
+        self.principal = Some(principal.to_string());
+    }
+
+    /// Gives up on a statement once `cancel` is cancelled, which fails the
+    /// transaction with `CoreError::Cancelled`. The engine finishes the
+    /// statement on its own and discards it, and only then lets go of the
+    /// writer, so other writes wait and another transaction is refused until
+    /// it does. A commit is always waited for.
+    pub fn cancel_on(&mut self, cancel: CancelToken) {
+        self.cancel = Some(cancel);
+    }
+
+    /// Runs one query (a single program; not a system op or imperative
+    /// script) inside the transaction, with the same result shape as
+    /// `CognitiveCore::run`. A failed statement aborts the transaction.
+    pub fn exec(&mut self, query: &str, params: serde_json::Value) -> Result<serde_json::Value, CoreError> {
+        if let Some(reason) = &self.failed {
+            return Err(CoreError::TransactionAborted(reason.clone()));
+        }
+        let started = Instant::now();
+        let params = match bind_params(params) {
+            Ok(params) => params,
+            Err(e) => return Err(self.fail(e)),
+        };
+        let rows = self.exec_bound(query, params)?;
+        Ok(result_json(rows, started.elapsed()))
+    }
+
+    /// `exec` with the parameters already converted.
+    pub(crate) fn exec_bound(&mut self, query: &str, params: BTreeMap<String, DataValue>) -> Result<NamedRows, CoreError> {
+        if let Some(reason) = &self.failed {
+            return Err(CoreError::TransactionAborted(reason.clone()));
+        }
+        let rows = match self.namespace.clone() {
+            None => {
+                self.check_grants(query)?;
+                self.statement(query, params)?
+            }
+            Some(namespace) => {
+                let confined = namespace::confine(&namespace, query).map_err(|e| self.fail(e))?;
+                self.check_grants(&confined.query)?;
+                let rows = self.statement(&confined.query, params)?;
+                // Registered in the transaction, so only if it commits.
+                if !confined.created.is_empty() {
+                    let (put, owners) = owners_put(&confined.created);
+                    self.statement(&put, BTreeMap::from([(namespace::OWNER_ROWS.to_string(), owners)]))?;
+                }
+                rows
+            }
+        };
+        self.statements += 1;
+        Ok(rows)
+    }
+
+    /// Fails the transaction if `query`, as stored names, touches a relation
+    /// its principal may not.
+    fn check_grants(&mut self, query: &str) -> Result<(), CoreError> {
+        let Some(principal) = &self.principal else { return Ok(()) };
+        let checked = grants::check_query(&grants::current(&self.grants), principal, query);
+        checked.map_err(|e| self.fail(e))
+    }
+
+    /// Runs one statement, audited, failing the transaction if it fails.
+    fn statement(&mut self, query: &str, params: BTreeMap<String, DataValue>) -> Result<NamedRows, CoreError> {
+        let pending = self.audit.as_ref().map(|audit| audit.start(&self.source, query, &params));
+        let cancel = self.cancel.clone();
+        let reply = loop {
+            let opening = self.tx.is_none();
+            let statement = TransactionPayload::Query((query.to_string(), params.clone()));
+            let reply = self.engine().and_then(|tx| request(tx, statement, cancel.as_ref()));
+            if matches!(reply, Err(CoreError::Cancelled)) {
+                self.unanswered = true;
+            }
+            let reply = reply.and_then(|reply| reply.map_err(|e| CoreError::from_report(&e, query)));
+            // The engine transaction met another write and ended without
+            // running the statement; the next one waits that write out.
+            if !opening || !matches!(reply, Err(CoreError::TransactionConflict(_))) {
+                break reply;
+            }
+            self.tx = None;
+            if cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+                break Err(CoreError::Cancelled);
+            }
+            self.db.wait_for_writer(WAIT_POLL);
+        };
+        if let (Some(audit), Some(pending)) = (&self.audit, pending) {
+            audit.finish(pending, &reply);
+        }
+        reply.map_err(|e| self.fail(e))
+    }
+
+    /// Commits and returns the number of statements applied. A transaction
+    /// with a failed statement is rolled back instead.
+    pub fn commit(mut self) -> Result<usize, CoreError> {
+        if let Some(reason) = self.failed.take() {
+            return Err(CoreError::TransactionAborted(reason));
+        }
+        match self.finish(TransactionPayload::Commit) {
+            Some(Ok(_)) => Ok(self.statements),
+            Some(Err(e)) => Err(CoreError::from_report(&e, "")),
+            None => Err(ended()),
+        }
+    }
+
+    pub fn rollback(mut self) {
+        self.finish(TransactionPayload::Abort);
+    }
+
+    /// Aborts the transaction because of `e`, which is returned.
+    fn fail(&mut self, e: CoreError) -> CoreError {
+        self.failed = Some(e.to_string());
+        self.finish(TransactionPayload::Abort);
+        e
+    }
+
+    /// The engine transaction, opened by the first statement.
+    fn engine(&mut self) -> Result<&MultiTransaction, CoreError> {
+        Ok(self.tx.get_or_insert_with(|| self.db.multi_transaction()))
+    }
+
+    /// Ends the engine transaction and waits for it to let go of the
+    /// writer, so the next write does not meet it. Behind a statement given
+    /// up on, the wait is left to a thread of its own, and another
+    /// transaction stays refused until it ends.
+    fn finish(&mut self, payload: TransactionPayload) -> Option<Result<NamedRows, cozo::Error>> {
+        if self.done {
+            return None;
+        }
+        self.done = true;
+        let Some(tx) = &self.tx else {
+            self.open.store(false, Ordering::Release);
+            return Some(Ok(NamedRows::default()));
+        };
+        if self.unanswered {
+            let (sender, receiver, open) = (tx.sender.clone(), tx.receiver.clone(), self.open.clone());
+            std::thread::spawn(move || {
+                // The statement's reply, then the abort's.
+                let _ = receiver.recv();
+                if sender.send(TransactionPayload::Abort).is_ok() {
+                    let _ = receiver.recv();
+                }
+                open.store(false, Ordering::Release);
+            });
+            return None;
+        }
+        let reply = request(tx, payload, None).ok();
+        self.open.store(false, Ordering::Release);
+        reply
+    }
+}
+
+impl Drop for CoreTransaction {
+    fn drop(&mut self) {
+        self.finish(TransactionPayload::Abort);
+    }
+}
+
+/// Sends `payload` to the engine transaction and waits for its reply, or
+/// until `cancel` is cancelled.
+fn request(
+    tx: &MultiTransaction,
+    payload: TransactionPayload,
+    cancel: Option<&CancelToken>,
+) -> Result<Result<NamedRows, cozo::Error>, CoreError> {
+    tx.sender.send(payload).map_err(|_| ended())?;
+    let Some(cancel) = cancel else {
+        return tx.receiver.recv().map_err(|_| ended());
+    };
+    loop {
+        match tx.receiver.recv_timeout(WAIT_POLL) {
+            Ok(reply) => return Ok(reply),
+            Err(e) if e.is_timeout() && !cancel.is_cancelled() => {}
+            Err(e) if e.is_timeout() => return Err(CoreError::Cancelled),
+            Err(_) => return Err(ended()),
+        }
+    }
+}
+
+fn ended() -> CoreError {
+    CoreError::TransactionAborted("the engine ended the transaction".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+    use crate::{CoreBackend, CoreConfig, QueryOptions};
+    use serde_json::{json, Value};
+
+    fn core() -> CognitiveCore {
+        core_on(CoreBackend::Mem)
+    }
+
+    fn core_on(backend: CoreBackend) -> CognitiveCore {
+        let core = CognitiveCore::new(CoreConfig {
+            backend,
+            ..CoreConfig::default()
+        })
+        .unwrap();
+        core.run(":create accounts {name: String => balance: Int}", Value::Null).unwrap();
+        core.run(":create ledger {id: Int => amount: Int}", Value::Null).unwrap();
+        core
+    }
+
+    /// Every row of `relation`, which has two columns, in key order.
+    fn rows(core: &CognitiveCore, relation: &str) -> Value {
+        core.run(&format!("?[a, b] := *{}[a, b] :order a", relation), Value::Null).unwrap()["rows"].clone()
+    }
+
+    fn transfer(tx: &mut CoreTransaction) -> Result<(), CoreError> {
+        tx.exec("?[name, balance] <- [['ada', 90]] :put accounts {name => balance}", Value::Null)?;
+        tx.exec("?[id, amount] <- [[1, -10]] :put ledger {id => amount}", Value::Null)?;
+        Ok(())
+    }
+
+    #[test]
+    fn commits_every_statement_together() {
+        let core = core();
+        let mut tx = core.begin().unwrap();
+        transfer(&mut tx).unwrap();
+        // Statements see each other's writes.
+        assert_eq!(tx.exec("?[a] := *ledger{id: a}", Value::Null).unwrap()["rows"], json!([[1]]));
+        assert_eq!(tx.commit().unwrap(), 3);
+        assert_eq!(rows(&core, "accounts"), json!([["ada", 90]]));
+        assert_eq!(rows(&core, "ledger"), json!([[1, -10]]));
+    }
+
+    #[test]
+    fn nothing_is_visible_until_commit() {
+        let dir = TempDir::new("transaction-visibility");
+        for backend in [CoreBackend::Mem, CoreBackend::Sqlite { path: dir.path().join("core.db") }] {
+            let core = core_on(backend);
+            core.run("?[name, balance] <- [['ada', 100]] :put accounts {name => balance}", Value::Null).unwrap();
+            let mut tx = core.begin().unwrap();
+            transfer(&mut tx).unwrap();
+            // Readers go on, and see the committed rows only.
+            assert_eq!(rows(&core, "accounts"), json!([["ada", 100]]));
+            assert_eq!(rows(&core, "ledger"), json!([]));
+            assert!(matches!(core.begin(), Err(CoreError::TransactionOpen)));
+            tx.rollback();
+            assert_eq!(rows(&core, "accounts"), json!([["ada", 100]]));
+            assert_eq!(rows(&core, "ledger"), json!([]));
+        }
+    }
+
+    #[test]
+    fn writes_wait_for_an_open_transaction_that_has_written() {
+        let core = core();
+        let write = "?[id, amount] <- [[2, 5]] :put ledger {id => amount}";
+        let mut tx = core.begin().unwrap();
+        transfer(&mut tx).unwrap();
+        let quick = QueryOptions {
+            timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        assert!(matches!(core.run_with(write, Value::Null, &quick), Err(CoreError::Timeout { .. })));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        std::thread::scope(|scope| {
+            let waiting = scope.spawn(|| core.run(write, Value::Null));
+            std::thread::sleep(Duration::from_millis(100));
+            assert!(!waiting.is_finished());
+            tx.commit().unwrap();
+            waiting.join().unwrap().unwrap();
+        });
+        assert_eq!(rows(&core, "ledger"), json!([[1, -10], [2, 5]]));
+    }
+
+    #[test]
+    fn dropping_an_uncommitted_transaction_rolls_it_back() {
+        let core = core();
+        {
+            let mut tx = core.begin().unwrap();
+            transfer(&mut tx).unwrap();
+        }
+        assert_eq!(rows(&core, "accounts"), json!([]));
+        // The slot is free again.
+        let mut tx = core.begin().unwrap();
+        transfer(&mut tx).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(rows(&core, "accounts"), json!([["ada", 90]]));
+    }
+
+    #[test]
+    fn a_failed_statement_leaves_no_partial_state() {
+        let core = core();
+        let mut tx = core.begin().unwrap();
+        tx.exec("?[name, balance] <- [['ada', 90]] :put accounts {name => balance}", Value::Null).unwrap();
+        let failed = tx.exec("?[id, amount] <- [[1, 'ten']] :put ledger {id => amount}", Value::Null);
+        assert!(matches!(failed, Err(CoreError::TypeMismatch { .. })));
+        assert!(matches!(tx.exec("?[a] <- [[1]]", Value::Null), Err(CoreError::TransactionAborted(_))));
+        assert!(matches!(tx.commit(), Err(CoreError::TransactionAborted(_))));
+        assert_eq!(rows(&core, "accounts"), json!([]));
+        assert_eq!(rows(&core, "ledger"), json!([]));
+    }
+
+    #[test]
+    fn transact_commits_on_ok_and_rolls_back_on_err() {
+        let core = core();
+        let failed: Result<(), CoreError> = core.transact(|tx| {
+            transfer(tx)?;
+            Err(CoreError::InvalidParams("changed my mind".into()))
+        });
+        assert!(matches!(failed, Err(CoreError::InvalidParams(_))));
+        assert_eq!(rows(&core, "accounts"), json!([]));
+
+        core.transact(transfer).unwrap();
+        assert_eq!(rows(&core, "accounts"), json!([["ada", 90]]));
+        assert_eq!(rows(&core, "ledger"), json!([[1, -10]]));
+    }
+
+    #[test]
+    fn a_cancelled_statement_fails_the_transaction() {
+        let core = core();
+        let mut tx = core.begin().unwrap();
+        transfer(&mut tx).unwrap();
+        let cancel = CancelToken::new();
+        tx.cancel_on(cancel.clone());
+        // Counts up forever, but the engine gives up after a second.
+        let runaway = "r[n] := n = 0\nr[m] := r[n], m = n + 1\n?[n] := r[n]\n:timeout 1";
+        let started = Instant::now();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(50));
+                cancel.cancel();
+            });
+            assert!(matches!(tx.exec(runaway, Value::Null), Err(CoreError::Cancelled)));
+        });
+        assert!(started.elapsed() < Duration::from_millis(500), "took {:?}", started.elapsed());
+        assert!(matches!(tx.exec("?[a] <- [[1]]", Value::Null), Err(CoreError::TransactionAborted(_))));
+        // Reads go on meanwhile. The engine holds the writer until it gives
+        // up on the statement, and then nothing was kept.
+        assert_eq!(rows(&core, "accounts"), json!([]));
+        assert!(matches!(core.begin(), Err(CoreError::TransactionOpen)));
+        let waited = Instant::now();
+        let next = loop {
+            match core.begin() {
+                Err(CoreError::TransactionOpen) if waited.elapsed() < Duration::from_secs(5) => {
+                    std::thread::sleep(WAIT_POLL)
+                }
+                next => break next,
+            }
+        };
+        next.unwrap().rollback();
+        assert_eq!(rows(&core, "accounts"), json!([]));
+    }
+}
@@ -0,0 +1,335 @@
+use crate::params::to_data_value;
+use crate::schema::{check_name, check_relation};
+use crate::{result_json, CognitiveCore, ColumnType, CoreError};
+use cozo::{DataValue, ScriptMutability};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Instant;
+
+/// Largest vector a column can be indexed with.
+pub const MAX_VECTOR_DIM: usize = 4096;
+
+/// Most neighbours one `knn_query` returns.
+pub const MAX_KNN: usize = 1000;
+
+/// Candidates a search keeps at least; more finds true neighbours more
+/// reliably at the cost of speed.
+const SEARCH_EF: usize = 64;
+
+/// How many times more candidates a filtered search weighs, since filters
+/// are checked against the candidates rather than guiding the search.
+const FILTERED_EF_FACTOR: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorMetric {
+    /// Squared Euclidean distance.
+    L2,
+    /// One minus the cosine of the angle between the vectors.
+    Cosine,
+    /// One minus the dot product, for normalised vectors.
+    InnerProduct,
+}
+
+/// Settings for `create_vector_index`.
+#[derive(Debug, Clone)]
+pub struct VectorIndexOptions {
+    /// Must match the column's declared `ColumnType::Vector` size.
+    pub dim: usize,
+    pub metric: VectorMetric,
+    /// Links kept per vector; more improves recall but costs memory.
+    pub m: usize,
+    /// Candidates weighed while linking a vector; more improves recall but
+    /// slows writes.
+    pub ef_construction: usize,
+}
+
+impl VectorIndexOptions {
+    pub fn new(dim: usize, metric: VectorMetric) -> Self {
+        Self {
+            dim,
+            metric,
+            m: 16,
+            ef_construction: 64,
+        }
+    }
+}
+
+impl fmt::Display for VectorMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VectorMetric::L2 => write!(f, "L2"),
+            VectorMetric::Cosine => write!(f, "Cosine"),
+            VectorMetric::InnerProduct => write!(f, "IP"),
+        }
+    }
+}
+
+impl CognitiveCore {
+    /// Indexes the vector column `column` of `relation` for `knn_query`. The
+    /// index is kept up to date as rows are written and deleted; existing
+    /// rows are indexed within the query timeout, as for `create_fts_index`.
+    pub fn create_vector_index(
+        &self,
+        relation: &str,
+        column: &str,
+        options: &VectorIndexOptions,
+    ) -> Result<(), CoreError> {
+        check_relation(relation)?;
+        check_name(column)?;
+        if options.dim == 0 || options.dim > MAX_VECTOR_DIM {
+            return Err(CoreError::InvalidSchema(format!(
+                "vectors must have 1 to {} components, not {}",
+                MAX_VECTOR_DIM, options.dim
+            )));
+        }
+        if options.m == 0 || options.ef_construction == 0 {
+            return Err(CoreError::InvalidSchema("m and ef_construction must be at least 1".into()));
+        }
+        let dim = self.vector_dim(relation, column)?;
+        if dim != options.dim {
+            return Err(CoreError::VectorDimension { expected: dim, got: options.dim });
+        }
+        let script = format!(
+            "::hnsw create {}:{} {{fields: [{}], dim: {}, dtype: F32, distance: {}, m: {}, ef_construction: {}}}",
+            relation,
+            vector_index_name(column),
+            column,
+            options.dim,
+            options.metric,
+            options.m,
+            options.ef_construction
+        );
+        self.script(&script, BTreeMap::new(), ScriptMutability::Mutable)?;
+        Ok(())
+    }
+
+    pub fn drop_vector_index(&self, relation: &str, column: &str) -> Result<(), CoreError> {
+        check_relation(relation)?;
+        check_name(column)?;
+        let script = format!("::hnsw drop {}:{}", relation, vector_index_name(column));
+        self.script(&script, BTreeMap::new(), ScriptMutability::Mutable)?;
+        Ok(())
+    }
+
+    /// The `k` rows of `relation` whose indexed `column` is nearest to
+    /// `vector`, nearest first, in the shape `run` returns. The first column
+    /// is the `distance`, followed by the relation's columns.
+    ///
+    /// `filters` is null or an object of column values a row must equal to
+    /// be returned. Filters are checked against the search's candidates, so
+    /// a filtered search weighs more of them, but one matching few rows can
+    /// still return fewer than `k`. The search is approximate.
+    pub fn knn_query(
+        &self,
+        relation: &str,
+        column: &str,
+        vector: &[f64],
+        k: usize,
+        filters: &serde_json::Value,
+    ) -> Result<serde_json::Value, CoreError> {
+        check_relation(relation)?;
+        check_name(column)?;
+        if k == 0 || k > MAX_KNN {
+            return Err(CoreError::InvalidParams(format!("k must be 1 to {}, not {}", MAX_KNN, k)));
+        }
+        if let Some(i) = vector.iter().position(|c| !c.is_finite()) {
+            return Err(CoreError::InvalidVector(format!("component {} is {}", i, vector[i])));
+        }
+        let dim = self.vector_dim(relation, column)?;
+        if vector.len() != dim {
+            return Err(CoreError::VectorDimension { expected: dim, got: vector.len() });
+        }
+
+        let columns: Vec<String> = self.columns(relation)?.into_iter().map(|c| c.name).collect();
+        // Columns are bound to generated variables so none clashes with `distance`.
+        let var = |name: &str| columns.iter().position(|c| c == name).map(|i| format!("c{}", i));
+        let vars: Vec<String> = (0..columns.len()).map(|i| format!("c{}", i)).collect();
+        let bindings: Vec<String> = columns.iter().zip(&vars).map(|(c, v)| format!("{}: {}", c, v)).collect();
+
+        let mut params = BTreeMap::from([(
+            "query".to_string(),
+            DataValue::List(vector.iter().map(|c| DataValue::from(*c)).collect()),
+        )]);
+        let mut conditions = Vec::new();
+        match filters {
+            serde_json::Value::Null => {}
+            serde_json::Value::Object(filters) => {
+                for (i, (name, value)) in filters.iter().enumerate() {
+                    let Some(var) = var(name) else {
+                        return Err(CoreError::InvalidParams(format!("filter on {}, which is not a column", name)));
+                    };
+                    let value = to_data_value(value.clone())
+                        .map_err(|e| CoreError::InvalidParams(format!("filter on {}: {}", name, e)))?;
+                    params.insert(format!("f{}", i), value);
+                    conditions.push(format!("{} == $f{}", var, i));
+                }
+            }
+            other => return Err(CoreError::InvalidParams(format!("filters must be an object, got {}", other))),
+        }
+        let (filter, ef) = match conditions.is_empty() {
+            true => (String::new(), k.max(SEARCH_EF)),
+            false => (format!(", filter: {}", conditions.join(" && ")), k.max(SEARCH_EF) * FILTERED_EF_FACTOR),
+        };
+
+        let script = format!(
+            "?[distance, {}] := q = vec($query), ~{}:{}{{{} | query: q, k: {}, ef: {}, bind_distance: distance{}}} :order distance",
+            vars.join(", "),
+            relation,
+            vector_index_name(column),
+            bindings.join(", "),
+            k,
+            ef,
+            filter
+        );
+        let started = Instant::now();
+        let mut rows = self.script(&script, params, ScriptMutability::Immutable)?;
+        rows.headers = std::iter::once("distance".to_string()).chain(columns).collect();
+        Ok(result_json(rows, started.elapsed()))
+    }
+
+    /// The declared size of the vector column `column`.
+    fn vector_dim(&self, relation: &str, column: &str) -> Result<usize, CoreError> {
+        let columns = self.columns(relation)?;
+        let Some(info) = columns.iter().find(|c| c.name == column) else {
+            return Err(CoreError::InvalidSchema(format!("{} has no column {}", relation, column)));
+        };
+        match ColumnType::parse(info.column_type.trim_end_matches('?')) {
+            Some(ColumnType::Vector(dim)) => Ok(dim),
+            _ => Err(CoreError::InvalidVector(format!(
+                "{}.{} is {}, not a vector of 32-bit floats",
+                relation, column, info.column_type
+            ))),
+        }
+    }
+}
+
+fn vector_index_name(column: &str) -> String {
+    format!("{}_hnsw", column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CoreConfig;
+    use serde_json::{json, Value};
+
+    const DIM: usize = 8;
+
+    /// A deterministic stream of floats in [-1, 1).
+    struct Noise(u64);
+
+    impl Noise {
+        fn next(&mut self) -> f64 {
+            // xorshift64*
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+        }
+
+        fn vector(&mut self) -> Vec<f64> {
+            // Rounded to f32, as the column stores them.
+            (0..DIM).map(|_| self.next() as f32 as f64).collect()
+        }
+    }
+
+    /// A core whose `items` holds `vectors`, the `i`th with id `i` and
+    /// group `i % 4`, indexed with `metric`.
+    fn core(vectors: &[Vec<f64>], metric: VectorMetric) -> CognitiveCore {
+        let core = CognitiveCore::new(CoreConfig::default()).unwrap();
+        core.run(&format!(":create items {{id: Int => group: Int, embedding: <F32; {}>}}", DIM), Value::Null).unwrap();
+        let rows = vectors.iter().enumerate().map(|(i, v)| json!([i, i % 4, v])).collect();
+        core.assert_facts("items", rows).unwrap();
+        core.create_vector_index("items", "embedding", &VectorIndexOptions::new(DIM, metric)).unwrap();
+        core
+    }
+
+    /// Squared Euclidean distance, as `VectorMetric::L2` measures it.
+    fn l2(a: &[f64], b: &[f64]) -> f64 {
+        a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+    }
+
+    /// The ids in a `knn_query` result, nearest first.
+    fn ids(result: &Value) -> Vec<usize> {
+        result["rows"].as_array().unwrap().iter().map(|row| row[1].as_u64().unwrap() as usize).collect()
+    }
+
+    #[test]
+    fn finds_the_exact_nearest_neighbours() {
+        let mut noise = Noise(0x5eed);
+        let vectors: Vec<Vec<f64>> = (0..3000).map(|_| noise.vector()).collect();
+        let core = core(&vectors, VectorMetric::L2);
+
+        let (mut found, mut wanted) = (0, 0);
+        for _ in 0..20 {
+            let query = noise.vector();
+            let mut exact: Vec<usize> = (0..vectors.len()).collect();
+            exact.sort_by(|&a, &b| l2(&vectors[a], &query).total_cmp(&l2(&vectors[b], &query)));
+            exact.truncate(10);
+
+            let result = core.knn_query("items", "embedding", &query, 10, &Value::Null).unwrap();
+            assert_eq!(result["headers"], json!(["distance", "id", "group", "embedding"]));
+            let rows = result["rows"].as_array().unwrap();
+            let distances: Vec<f64> = rows.iter().map(|row| row[0].as_f64().unwrap()).collect();
+            assert!(distances.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", distances);
+            let got = ids(&result);
+            assert!((distances[0] - l2(&vectors[got[0]], &query)).abs() < 1e-4);
+            found += got.iter().filter(|id| exact.contains(id)).count();
+            wanted += exact.len();
+        }
+        // The search is approximate, but at this size it should rarely miss.
+        assert!(found * 100 >= wanted * 95, "recall {} of {}", found, wanted);
+    }
+
+    #[test]
+    fn filters_narrow_the_search() {
+        let mut noise = Noise(7);
+        let vectors: Vec<Vec<f64>> = (0..1000).map(|_| noise.vector()).collect();
+        let core = core(&vectors, VectorMetric::Cosine);
+
+        let query = noise.vector();
+        let result = core.knn_query("items", "embedding", &query, 25, &json!({"group": 2})).unwrap();
+        let got = ids(&result);
+        assert_eq!(got.len(), 25);
+        assert!(got.iter().all(|id| id % 4 == 2), "{:?}", got);
+        // A row's own vector is its nearest neighbour.
+        let own = core.knn_query("items", "embedding", &vectors[42], 1, &json!({"group": 2, "id": 42})).unwrap();
+        assert_eq!(ids(&own), [42]);
+        assert!(own["rows"][0][0].as_f64().unwrap().abs() < 1e-6);
+        assert!(core.knn_query("items", "embedding", &query, 5, &json!({"group": 9})).unwrap()["rows"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_vectors_of_the_wrong_shape() {
+        let core = core(&[vec![0.5; DIM]], VectorMetric::L2);
+
+        let short = core.assert_facts("items", vec![json!([1, 0, [1.0, 2.0]])]);
+        assert!(matches!(&short, Err(CoreError::InvalidRow { reason, .. }) if reason.contains("expected 8 components, got 2")), "{:?}", short);
+        let words = core.assert_facts("items", vec![json!([1, 0, vec!["a"; DIM]])]);
+        assert!(matches!(&words, Err(CoreError::InvalidRow { reason, .. }) if reason.contains("finite")), "{:?}", words);
+
+        let query = vec![0.0; DIM - 1];
+        assert!(matches!(
+            core.knn_query("items", "embedding", &query, 1, &Value::Null),
+            Err(CoreError::VectorDimension { expected: DIM, got: 7 })
+        ));
+        let mut nan = vec![0.0; DIM];
+        nan[3] = f64::NAN;
+        assert!(matches!(core.knn_query("items", "embedding", &nan, 1, &Value::Null), Err(CoreError::InvalidVector(_))));
+        nan[3] = f64::INFINITY;
+        assert!(matches!(core.knn_query("items", "embedding", &nan, 1, &Value::Null), Err(CoreError::InvalidVector(_))));
+
+        let fine = vec![0.0; DIM];
+        for k in [0, MAX_KNN + 1] {
+            assert!(matches!(core.knn_query("items", "embedding", &fine, k, &Value::Null), Err(CoreError::InvalidParams(_))));
+        }
+        for filters in [json!({"colour": 1}), json!([1])] {
+            assert!(matches!(core.knn_query("items", "embedding", &fine, 1, &filters), Err(CoreError::InvalidParams(_))));
+        }
+        assert!(matches!(core.knn_query("items", "group", &fine, 1, &Value::Null), Err(CoreError::InvalidVector(_))));
+        assert!(matches!(
+            core.create_vector_index("items", "embedding", &VectorIndexOptions::new(4, VectorMetric::L2)),
+            Err(CoreError::VectorDimension { expected: DIM, got: 4 })
+        ));
+    }
+}
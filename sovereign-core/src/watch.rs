@@ -0,0 +1,268 @@
+use crate::engine::Engine;
+use crate::schema::check_relation;
+use crate::{CognitiveCore, CoreError};
+use cozo::{CallbackOp, DataValue, NamedRows};
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeOp {
+    /// Rows written, as they now are.
+    Put,
+    /// Rows deleted, as they were.
+    Remove,
+}
+
+/// Committed changes to a watched relation.
+#[derive(Debug, Clone)]
+pub struct CoreChangeEvent {
+    pub relation: String,
+    pub op: ChangeOp,
+    /// The relation's columns, keys first.
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<serde_json::Value>>,
+}
+
+/// Receives the changes to one relation from `CognitiveCore::watch`. The
+/// watch ends when the handle is dropped.
+///
+/// Changes arrive once their transaction commits, so a rolled-back write is
+/// never seen. The engine hands a commit's changes over a statement at a
+/// time; changes of the same kind already waiting are merged into one event,
+/// so a transaction's writes normally arrive as one event per kind. Unread
+/// changes are buffered without limit.
+pub struct WatchHandle {
+    db: Engine,
+    id: u32,
+    relation: String,
+    headers: Vec<String>,
+    filter: Option<String>,
+    changes: Receiver<(CallbackOp, NamedRows, NamedRows)>,
+    /// A change read while merging that starts the next event.
+    pending: RefCell<Option<(CallbackOp, NamedRows, NamedRows)>>,
+}
+
+impl CognitiveCore {
+    /// Watches the stored relation `relation` for committed writes and
+    /// deletions, through any API or query.
+    ///
+    /// `filter` is an expression over the relation's columns in query
+    /// syntax, e.g. `age > 30 && starts_with(name, 'a')`; only rows for which
+    /// it is true are reported, and events left with no rows are skipped.
+    pub fn watch(&self, relation: &str, filter: Option<&str>) -> Result<WatchHandle, CoreError> {
+        check_relation(relation)?;
+        let headers: Vec<String> = self.columns(relation)?.into_iter().map(|c| c.name).collect();
+        if let Some(filter) = filter {
+            let vars = cozo::get_variables(filter, &BTreeMap::new())
+                .map_err(|e| CoreError::from_report(&e, filter))?;
+            if let Some(unknown) = vars.iter().find(|v| !headers.contains(v)) {
+                return Err(CoreError::InvalidSchema(format!(
+                    "the filter uses {}, which is not a column of {}",
+                    unknown, relation
+                )));
+            }
+        }
+        let (id, changes) = self.db.register_callback(relation);
+        Ok(WatchHandle {
+            db: self.db.clone(),
+            id,
+            relation: relation.to_string(),
+            headers,
+            filter: filter.map(str::to_string),
+            changes,
+            pending: RefCell::new(None),
+        })
+    }
+}
+
+impl WatchHandle {
+    pub fn relation(&self) -> &str {
+        &self.relation
+    }
+
+    /// Waits for the next event. `None` means the core was closed.
+    pub fn recv(&self) -> Option<CoreChangeEvent> {
+        loop {
+            let first = match self.pending.take() {
+                Some(change) => change,
+                None => self.changes.recv().ok()?,
+            };
+            if let Some(event) = self.event(first) {
+                return Some(event);
+            }
+        }
+    }
+
+    /// Waits up to `timeout` for the next event; `None` if there was none.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<CoreChangeEvent> {
+        let first = match self.pending.take() {
+            Some(change) => change,
+            None => match self.changes.recv_timeout(timeout) {
+                Ok(change) => change,
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => return None,
+            },
+        };
+        match self.event(first) {
+            Some(event) => Some(event),
+            // Everything was filtered out; look at what else has arrived.
+            None => self.try_recv(),
+        }
+    }
+
+    /// The next event if one is waiting.
+    pub fn try_recv(&self) -> Option<CoreChangeEvent> {
+        loop {
+            let first = match self.pending.take() {
+                Some(change) => change,
+                None => self.changes.try_recv().ok()?,
+            };
+            if let Some(event) = self.event(first) {
+                return Some(event);
+            }
+        }
+    }
+
+    /// Builds an event from `first` and the waiting changes of the same
+    /// kind. `None` if the filter left no rows.
+    fn event(&self, first: (CallbackOp, NamedRows, NamedRows)) -> Option<CoreChangeEvent> {
+        let op = first.0;
+        let mut rows = self.rows(first);
+        while let Ok(next) = self.changes.try_recv() {
+            if next.0 != op {
+                *self.pending.borrow_mut() = Some(next);
+                break;
+            }
+            rows.extend(self.rows(next));
+        }
+        if rows.is_empty() {
+            return None;
+        }
+        Some(CoreChangeEvent {
+            relation: self.relation.clone(),
+            op: match op {
+                CallbackOp::Put => ChangeOp::Put,
+                CallbackOp::Rm => ChangeOp::Remove,
+            },
+            headers: self.headers.clone(),
+            rows: rows.into_iter().map(|row| row.into_iter().map(serde_json::Value::from).collect()).collect(),
+        })
+    }
+
+    /// The full rows a change touched, as filtered. A deletion's requested
+    /// keys include ones that were not there, so its old rows are used.
+    fn rows(&self, (op, new, old): (CallbackOp, NamedRows, NamedRows)) -> Vec<Vec<DataValue>> {
+        let rows = match op {
+            CallbackOp::Put => new.rows,
+            CallbackOp::Rm => old.rows,
+        };
+        match &self.filter {
+            Some(filter) => rows.into_iter().filter(|row| self.matches(filter, row)).collect(),
+            None => rows,
+        }
+    }
+
+    /// A row the filter cannot be evaluated on, e.g. for a type mismatch,
+    /// does not match.
+    fn matches(&self, filter: &str, row: &[DataValue]) -> bool {
+        let vars = self.headers.iter().cloned().zip(row.iter().cloned()).collect();
+        matches!(cozo::evaluate_expressions(filter, &BTreeMap::new(), &vars), Ok(DataValue::Bool(true)))
+    }
+}
+
+impl Drop for WatchHandle {
+    fn drop(&mut self) {
+        self.db.unregister_callback(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CoreConfig;
+    use serde_json::{json, Value};
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    fn core() -> CognitiveCore {
+        let core = CognitiveCore::new(CoreConfig::default()).unwrap();
+        core.run(":create people {id: Int => name: String, age: Int}", Value::Null).unwrap();
+        core
+    }
+
+    fn add(core: &CognitiveCore, rows: Value) {
+        core.run("?[id, name, age] <- $rows :put people {id => name, age}", json!({ "rows": rows })).unwrap();
+    }
+
+    #[test]
+    fn reports_changes_once_committed() {
+        let core = core();
+        let watch = core.watch("people", None).unwrap();
+
+        let mut tx = core.begin().unwrap();
+        tx.exec("?[id, name, age] <- [[1, 'ada', 36]] :put people {id => name, age}", Value::Null).unwrap();
+        assert!(watch.recv_timeout(Duration::from_millis(200)).is_none());
+        tx.rollback();
+        assert!(watch.recv_timeout(Duration::from_millis(200)).is_none());
+
+        let mut tx = core.begin().unwrap();
+        tx.exec("?[id, name, age] <- [[1, 'ada', 36]] :put people {id => name, age}", Value::Null).unwrap();
+        tx.exec("?[id, name, age] <- [[2, 'alan', 41]] :put people {id => name, age}", Value::Null).unwrap();
+        assert!(watch.try_recv().is_none());
+        tx.commit().unwrap();
+        let event = watch.recv_timeout(WAIT).unwrap();
+        assert_eq!((event.relation.as_str(), event.op), ("people", ChangeOp::Put));
+        assert_eq!(event.headers, ["id", "name", "age"]);
+        assert_eq!(event.rows, [[json!(1), json!("ada"), json!(36)], [json!(2), json!("alan"), json!(41)]]);
+
+        // Deletions report the rows as they were, without keys that had none.
+        core.retract_facts("people", vec![json!(1), json!(99)]).unwrap();
+        let event = watch.recv_timeout(WAIT).unwrap();
+        assert_eq!(event.op, ChangeOp::Remove);
+        assert_eq!(event.rows, [[json!(1), json!("ada"), json!(36)]]);
+        assert!(watch.try_recv().is_none());
+    }
+
+    #[test]
+    fn filters_choose_the_rows_reported() {
+        let core = core();
+        let older = core.watch("people", Some("age > 40")).unwrap();
+        let named_a = core.watch("people", Some("starts_with(name, 'a')")).unwrap();
+        let everyone = core.watch("people", None).unwrap();
+
+        add(&core, json!([[1, "ada", 36], [2, "alan", 41], [3, "grace", 85]]));
+        let ids = |event: CoreChangeEvent| event.rows.iter().map(|row| row[0].as_i64().unwrap()).collect::<Vec<_>>();
+        assert_eq!(ids(older.recv_timeout(WAIT).unwrap()), [2, 3]);
+        assert_eq!(ids(named_a.recv_timeout(WAIT).unwrap()), [1, 2]);
+        assert_eq!(ids(everyone.recv_timeout(WAIT).unwrap()), [1, 2, 3]);
+
+        // A write no filter matches is skipped rather than reported empty.
+        add(&core, json!([[4, "bob", 20]]));
+        assert_eq!(ids(everyone.recv_timeout(WAIT).unwrap()), [4]);
+        assert!(older.recv_timeout(Duration::from_millis(200)).is_none());
+        assert!(named_a.try_recv().is_none());
+
+        assert!(matches!(core.watch("people", Some("colour == 'red'")), Err(CoreError::InvalidSchema(_))));
+        assert!(matches!(core.watch("people", Some("age >")), Err(CoreError::Parse(_))));
+        assert!(matches!(core.watch("nobody", None), Err(CoreError::UnknownRelation(_))));
+    }
+
+    #[test]
+    fn dropping_the_handle_ends_the_watch() {
+        let core = core();
+        let kept = core.watch("people", None).unwrap();
+        let dropped = core.watch("people", None).unwrap();
+        let id = dropped.id;
+        drop(dropped);
+        // Already gone from the engine.
+        assert!(!core.db.unregister_callback(id));
+
+        add(&core, json!([[1, "ada", 36]]));
+        assert_eq!(kept.recv_timeout(WAIT).unwrap().rows.len(), 1);
+        let id = kept.id;
+        drop(kept);
+        assert!(!core.db.unregister_callback(id));
+        add(&core, json!([[2, "alan", 41]]));
+    }
+}
@@ -3,12 +3,88 @@
 use bdk::bitcoin::{Address, Txid};
 use bdk::bitcoin::blockdata::script::Instruction;
 use bdk::blockchain::{ElectrumBlockchain, GetTx};
-use bdk::electrum_client::Client;
+use bdk::blockchain::GetHeight;
+use bdk::electrum_client::{Client, ElectrumApi};
 use sha2::{Sha256, Digest};
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 use log::{info, warn, error};
 use anyhow::Context;
 
+pub use bdk::bitcoin::Network;
+
+/// A named price point. A license's tier is the highest one its payment reaches.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LicenseTier {
+    pub name: String,
+    pub min_sats: u64,
+}
+
+/// The rules a license transaction is judged against, beyond the payment itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LicensePolicy {
+    /// Confirmations required before a license counts. 0 accepts mempool transactions.
+    pub min_confirmations: u32,
+    /// Blocks after confirmation the license stays valid. `None` means perpetual.
+    pub validity_blocks: Option<u32>,
+    /// Ascending by `min_sats`.
+    pub tiers: Vec<LicenseTier>,
+}
+
+impl LicensePolicy {
+    /// A single perpetual "standard" tier at `required_sats`, accepting unconfirmed payments.
+    pub fn for_required_sats(required_sats: u64) -> Self {
+        Self {
+            min_confirmations: 0,
+            validity_blocks: None,
+            tiers: vec![LicenseTier { name: "standard".into(), min_sats: required_sats }],
+        }
+    }
+
+    fn tier_for(&self, paid_sats: u64) -> Option<&LicenseTier> {
+        self.tiers.iter().filter(|t| paid_sats >= t.min_sats).max_by_key(|t| t.min_sats)
+    }
+}
+
+/// Everything learned while checking one license transaction.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerificationReport {
+    pub txid: String,
+    /// The overall verdict: payment, binding, confirmations and expiry all hold.
+    pub valid: bool,
+    /// False when the transaction is unknown to the backend.
+    pub found: bool,
+    /// Largest single output paying the developer address.
+    pub paid_sats: u64,
+    pub payment_ok: bool,
+    pub binding_ok: bool,
+    pub confirmations: u32,
+    pub confirmed_height: Option<u32>,
+    pub tier: Option<String>,
+    pub valid_until_height: Option<u32>,
+}
+
+/// SHA256("LICENSE" + machine_id): the OP_RETURN payload that binds a license to a machine.
+pub fn binding_hash(machine_id: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(format!("LICENSE{}", machine_id).as_bytes());
+    hasher.finalize().into()
+}
+
+/// Fails unless `developer_addr` is an address on `network`.
+pub fn check_address(developer_addr: &str, network: Network) -> anyhow::Result<()> {
+    Address::from_str(developer_addr)
+        .context("Invalid Developer Address format")?
+        .require_network(network)
+        .with_context(|| format!("Developer Address is not a {} address", network))?;
+    Ok(())
+}
+
+/// Hex form of [`binding_hash`], as it should appear in the purchase transaction.
+pub fn binding_payload_hex(machine_id: &str) -> String {
+    binding_hash(machine_id).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 // We wrap the verifier in a struct that manages the connection.
 // ElectrumBlockchain wraps an Arc<Client>, so it is cheap to clone and strictly Thread-Safe.
 pub struct LicenseVerifier {
@@ -17,10 +93,20 @@ pub struct LicenseVerifier {
     // to encapsulate the "Business Logic" within the crate.
     developer_addr: String,
     required_sats: u64,
+    policy: LicensePolicy,
 }
 
 impl LicenseVerifier {
     pub fn new(electrum_url: &str, developer_addr: &str, required_sats: u64) -> anyhow::Result<Self> {
+        Self::with_policy(electrum_url, developer_addr, required_sats, LicensePolicy::for_required_sats(required_sats))
+    }
+
+    pub fn with_policy(
+        electrum_url: &str,
+        developer_addr: &str,
+        required_sats: u64,
+        policy: LicensePolicy,
+    ) -> anyhow::Result<Self> {
         // Validate inputs immediately to fail fast
         let _ = Address::from_str(developer_addr).context("Invalid Developer Address format")?;
 
@@ -31,9 +117,32 @@ impl LicenseVerifier {
             blockchain: ElectrumBlockchain::from(client),
             developer_addr: developer_addr.to_string(),
             required_sats,
+            policy,
         })
     }
 
+    pub fn developer_addr(&self) -> &str {
+        &self.developer_addr
+    }
+
+    pub fn required_sats(&self) -> u64 {
+        self.required_sats
+    }
+
+    pub fn policy(&self) -> &LicensePolicy {
+        &self.policy
+    }
+
+    /// BIP21 URI for paying the license fee, suitable for rendering as a QR code.
+    pub fn payment_uri(&self) -> String {
+        format!(
+            "bitcoin:{}?amount={}.{:08}",
+            self.developer_addr,
+            self.required_sats / 100_000_000,
+            self.required_sats % 100_000_000
+        )
+    }
+
     /// Verifies a machine-locked license on the Bitcoin blockchain.
     ///
     /// LOGIC:
@@ -43,6 +152,14 @@ impl LicenseVerifier {
     ///
     /// This function is BLOCKING. The caller must run it in a separate thread.
     pub fn verify_license_sync(&self, txid_str: &str, machine_id: &str) -> anyhow::Result<bool> {
+        Ok(self.verify_license_report(txid_str, machine_id)?.valid)
+    }
+
+    /// Like [`verify_license_sync`](Self::verify_license_sync), but returns the full
+    /// report, including confirmations, tier and expiry under the configured policy.
+    ///
+    /// This function is BLOCKING. The caller must run it in a separate thread.
+    pub fn verify_license_report(&self, txid_str: &str, machine_id: &str) -> anyhow::Result<VerificationReport> {
         // 1. Type Conversion
         let txid = Txid::from_str(txid_str).context("Invalid TxID")?;
         let target_script = Address::from_str(&self.developer_addr)?.assume_checked().script_pubkey();
@@ -51,16 +168,27 @@ impl LicenseVerifier {
         // This cryptographically binds the license to THIS specific machine.
         // Even if the TxID is public, it cannot be reused on another machine
         // because the OP_RETURN hash wouldn't match the new machine's ID.
-        let mut hasher = Sha256::new();
-        hasher.update(format!("LICENSE{}", machine_id).as_bytes());
-        let expected_hash = hasher.finalize();
+        let expected_hash = binding_hash(machine_id);
+
+        let mut report = VerificationReport {
+            txid: txid.to_string(),
+            valid: false,
+            found: false,
+            paid_sats: 0,
+            payment_ok: false,
+            binding_ok: false,
+            confirmations: 0,
+            confirmed_height: None,
+            tier: None,
+            valid_until_height: None,
+        };
 
         // 3. Network Query (The Blocking Step)
         let tx = match self.blockchain.get_tx(&txid) {
             Ok(Some(t)) => t,
             Ok(None) => {
                 warn!("License Tx {} not found in blockchain history.", txid);
-                return Ok(false);
+                return Ok(report);
             },
             Err(e) => {
                 // We map network errors to anyhow::Error to avoid exposing electrum types
@@ -68,15 +196,13 @@ impl LicenseVerifier {
                 return Err(anyhow::anyhow!("Network error: {}", e));
             }
         };
+        report.found = true;
 
         // 4. Verification Loop
-        let mut paid_dev = false;
-        let mut found_metadata = false;
-
         for output in tx.output {
             // Check Payment Condition
-            if output.script_pubkey == target_script && output.value >= self.required_sats {
-                paid_dev = true;
+            if output.script_pubkey == target_script {
+                report.paid_sats = report.paid_sats.max(output.value);
             }
 
             // Check Metadata Condition (OP_RETURN)
@@ -85,17 +211,40 @@ impl LicenseVerifier {
                     // We look for a PushBytes instruction containing our hash
                     if let Ok(Instruction::PushBytes(data)) = instruction {
                         if data.as_bytes() == expected_hash.as_slice() {
-                            found_metadata = true;
+                            report.binding_ok = true;
                         }
                     }
                 }
             }
         }
+        report.payment_ok = report.paid_sats >= self.required_sats;
+        report.tier = self.policy.tier_for(report.paid_sats).map(|t| t.name.clone());
 
-        info!("License Audit Result for {}: Payment={}, Metadata={}", txid, paid_dev, found_metadata);
+        // 5. Confirmation Depth
+        // The history of the developer script tells us which block (if any) mined the tx.
+        let mut not_expired = true;
+        let history = self.blockchain.script_get_history(&target_script).map_err(|e| anyhow::anyhow!("Network error: {}", e))?;
+        if let Some(entry) = history.iter().find(|h| h.tx_hash == txid && h.height > 0) {
+            let height = entry.height as u32;
+            let tip = self.blockchain.get_height().map_err(|e| anyhow::anyhow!("Network error: {}", e))?;
+            report.confirmed_height = Some(height);
+            report.confirmations = tip.saturating_sub(height) + 1;
+            report.valid_until_height = self.policy.validity_blocks.map(|blocks| height.saturating_add(blocks));
+            if let Some(until) = report.valid_until_height {
+                not_expired = tip < until;
+            }
+        }
+
+        let confirmed_enough = report.confirmations >= self.policy.min_confirmations;
+
+        info!(
+            "License Audit Result for {}: Payment={}, Metadata={}, Confirmations={}",
+            txid, report.payment_ok, report.binding_ok, report.confirmations
+        );
 
         // Strict AND condition
-        Ok(paid_dev && found_metadata)
+        report.valid = report.payment_ok && report.binding_ok && confirmed_enough && not_expired;
+        Ok(report)
     }
 }
 
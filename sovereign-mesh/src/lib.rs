@@ -1,14 +1,27 @@
 use libp2p::{
     gossipsub, kad, mdns, noise,
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux, Multiaddr, PeerId, Swarm, SwarmBuilder, Transport,
+    request_response::{self, OutboundRequestId, ProtocolSupport, ResponseChannel},
+    swarm::{dial_opts::DialOpts, ConnectionId, NetworkBehaviour, SwarmEvent},
+    tcp, yamux, PeerId, StreamProtocol, Swarm, SwarmBuilder, Transport,
     core::upgrade::Version,
 };
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::time::Duration;
 use tokio::sync::{mpsc, oneshot};
-use log::{info, error, warn, debug};
-use futures::StreamExt;
+use log::{info, error, debug, warn};
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+
+pub use libp2p::{identity, Multiaddr};
+
+/// Largest gossiped message. Larger publishes fail.
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// The request-response protocol behind `MeshCommand::Request`.
+const REQUEST_PROTOCOL: StreamProtocol = StreamProtocol::new("/sovereign/request/1");
 
 // --- 1. The Behaviour Definition ---
 // In libp2p 0.53, the NetworkBehaviour derive auto-generates the event enum.
@@ -19,32 +32,135 @@ pub struct SovereignBehaviour {
     kademlia: kad::Behaviour<kad::store::MemoryStore>,
     mdns: mdns::tokio::Behaviour,
     ping: libp2p::ping::Behaviour,
+    request_response: request_response::cbor::Behaviour<Payload, Payload>,
+}
+
+/// Opaque request and response bytes, sent as a CBOR byte string.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Payload(#[serde(with = "serde_bytes")] Vec<u8>);
+
+/// Where the mesh listens. Peers to dial are sent as commands once it runs.
+#[derive(Debug, Clone)]
+pub struct MeshConfig {
+    /// The swarm's pre-shared key file.
+    pub psk_path: PathBuf,
+    /// The mesh stops if it cannot listen on any of these.
+    pub listen_addrs: Vec<Multiaddr>,
+}
+
+impl Default for MeshConfig {
+    fn default() -> Self {
+        Self {
+            psk_path: PathBuf::from("swarm.key"),
+            listen_addrs: vec!["/ip4/0.0.0.0/tcp/0".parse().expect("valid multiaddr")],
+        }
+    }
 }
 
 pub struct MeshNode {
     swarm: Swarm<SovereignBehaviour>,
+    keys: identity::Keypair,
+    listen_addrs: Vec<Multiaddr>,
     command_rx: mpsc::Receiver<MeshCommand>,
+    /// Receivers of each subscribed topic's messages.
+    subscriptions: HashMap<gossipsub::TopicHash, Vec<mpsc::Sender<MeshMessage>>>,
+    /// Requests sent, waiting for the peer's answer.
+    requests: HashMap<OutboundRequestId, oneshot::Sender<anyhow::Result<Vec<u8>>>>,
+    /// `Connect` dials waiting for their connection to open or fail.
+    dials: HashMap<ConnectionId, oneshot::Sender<anyhow::Result<String>>>,
+    request_handler: Option<mpsc::Sender<MeshRequest>>,
+    /// Inbound requests waiting for the handler's answer.
+    responses: FuturesUnordered<PendingResponse>,
+    /// Receivers of connection and listener changes.
+    event_subscribers: Vec<mpsc::UnboundedSender<MeshEvent>>,
 }
 
+/// Resolves once the handler answers an inbound request, or drops it.
+type PendingResponse = BoxFuture<'static, (ResponseChannel<Payload>, Option<Vec<u8>>)>;
+
 pub enum MeshCommand {
     Dial(String),
+    /// Dial `addr` and answer with the peer id once connected, or with why
+    /// the dial failed.
+    Connect { addr: String, reply: oneshot::Sender<anyhow::Result<String>> },
+    /// Start a Kademlia bootstrap from the peers known so far. Fails when
+    /// none are known.
+    Bootstrap(oneshot::Sender<anyhow::Result<()>>),
     GetPeers(oneshot::Sender<Vec<String>>),
     GetPeerId(oneshot::Sender<String>),
+    /// Gossip `data` on `topic`. Dropped with a debug log if no peer is subscribed.
+    Publish { topic: String, data: Vec<u8> },
+    /// Gossip `data` on `topic` and answer once gossipsub has taken it, or
+    /// with why it would not, e.g. `InsufficientPeers` while no peer is
+    /// subscribed. The error is a `gossipsub::PublishError`.
+    PublishChecked { topic: String, data: Vec<u8>, reply: oneshot::Sender<anyhow::Result<()>> },
+    /// Join `topic` and send what peers gossip on it to `messages`. Messages
+    /// are dropped while `messages` is full.
+    Subscribe { topic: String, messages: mpsc::Sender<MeshMessage> },
+    /// Send `data` to `peer` and pass on its answer, or why there was none.
+    Request { peer: String, data: Vec<u8>, reply: oneshot::Sender<anyhow::Result<Vec<u8>>> },
+    /// Send peers' requests to `requests`, replacing any earlier handler.
+    /// Without one, requests go unanswered.
+    ServeRequests(mpsc::Sender<MeshRequest>),
+    GetListenAddrs(oneshot::Sender<Vec<String>>),
+    /// Send connection and listener changes to `events`, starting with a
+    /// `PeerConnected` per connected peer and the current listeners.
+    SubscribeEvents(mpsc::UnboundedSender<MeshEvent>),
+    /// Close every peer connection and stop the actor, answering on the
+    /// channel once done.
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// A change in the mesh's connections or listeners.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MeshEvent {
+    /// The first connection to a peer opened.
+    PeerConnected(String),
+    /// The last connection to a peer closed.
+    PeerDisconnected(String),
+    /// The addresses the mesh listens on, after one was added or removed.
+    ListenersChanged(Vec<String>),
+}
+
+/// A message gossiped on a subscribed topic.
+#[derive(Debug, Clone)]
+pub struct MeshMessage {
+    pub topic: String,
+    /// The peer that signed the message.
+    pub source: String,
+    pub data: Vec<u8>,
+}
+
+/// A peer's request, answered by sending on `reply`. Dropping `reply`
+/// leaves the request unanswered.
+#[derive(Debug)]
+pub struct MeshRequest {
+    pub peer: String,
+    pub data: Vec<u8>,
+    pub reply: oneshot::Sender<Vec<u8>>,
 }
 
 impl MeshNode {
     pub fn new(
-        key_path: &Path,
+        config: &MeshConfig,
+        command_rx: mpsc::Receiver<MeshCommand>,
+    ) -> anyhow::Result<Self> {
+        Self::with_keypair(config, identity::Keypair::generate_ed25519(), command_rx)
+    }
+
+    /// Like `new`, under an identity the caller already has, so a restarted
+    /// mesh keeps its peer id.
+    pub fn with_keypair(
+        config: &MeshConfig,
+        id_keys: identity::Keypair,
         command_rx: mpsc::Receiver<MeshCommand>,
     ) -> anyhow::Result<Self> {
-        // --- Identity & Key Generation ---
-        let id_keys = libp2p::identity::Keypair::generate_ed25519();
         let peer_id = PeerId::from(id_keys.public());
         info!("Mesh Identity Initialized: {}", peer_id);
 
         // --- PNet Configuration (The "Dark" Layer) ---
         // TODO: Implement PNet transport conditional on PSK
-        // let _psk = match load_swarm_key(key_path) {
+        // let _psk = match load_swarm_key(&config.psk_path) {
         //     Ok(key) => Some(key),
         //     Err(e) => {
         //         warn!("CRITICAL: Swarm key error: {}. Mesh running in OPEN/INSECURE mode.", e);
@@ -69,6 +185,7 @@ impl MeshNode {
         let gossipsub_config = gossipsub::ConfigBuilder::default()
             .heartbeat_interval(Duration::from_secs(1))
             .validation_mode(gossipsub::ValidationMode::Strict)
+            .max_transmit_size(MAX_MESSAGE_SIZE)
             .build()
             .map_err(|msg| anyhow::anyhow!("Gossipsub config error: {}", msg))?;
 
@@ -76,24 +193,50 @@ impl MeshNode {
         let kademlia = kad::Behaviour::new(peer_id, kad::store::MemoryStore::new(peer_id));
         let mdns = mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id)?;
         let ping = libp2p::ping::Behaviour::new(libp2p::ping::Config::new());
+        let request_response = request_response::cbor::Behaviour::new(
+            [(REQUEST_PROTOCOL, ProtocolSupport::Full)],
+            request_response::Config::default().with_request_timeout(Duration::from_secs(30)),
+        );
 
-        let behaviour = SovereignBehaviour { gossipsub, kademlia, mdns, ping };
+        let behaviour = SovereignBehaviour { gossipsub, kademlia, mdns, ping, request_response };
 
         // --- Swarm Builder (0.53 Syntax) ---
-        let swarm = SwarmBuilder::with_existing_identity(id_keys)
+        let swarm = SwarmBuilder::with_existing_identity(id_keys.clone())
             .with_tokio()
             .with_other_transport(|_keypair| transport)?
             .with_behaviour(|_| behaviour)?
             .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
             .build();
 
-        Ok(Self { swarm, command_rx })
+        Ok(Self {
+            swarm,
+            keys: id_keys,
+            listen_addrs: config.listen_addrs.clone(),
+            command_rx,
+            subscriptions: HashMap::new(),
+            requests: HashMap::new(),
+            dials: HashMap::new(),
+            request_handler: None,
+            responses: FuturesUnordered::new(),
+            event_subscribers: Vec::new(),
+        })
+    }
+
+    /// The node's identity, for signing what it sends.
+    pub fn keypair(&self) -> identity::Keypair {
+        self.keys.clone()
     }
 
     // --- The Mesh Actor Loop ---
     pub async fn run(mut self) {
-        if let Err(e) = self.swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap()) {
-            error!("Failed to start listener: {}", e);
+        let mut listening = false;
+        for addr in std::mem::take(&mut self.listen_addrs) {
+            match self.swarm.listen_on(addr.clone()) {
+                Ok(_) => listening = true,
+                Err(e) => error!("Failed to start listener on {}: {}", addr, e),
+            }
+        }
+        if !listening {
             return;
         }
 
@@ -105,6 +248,29 @@ impl MeshNode {
                             let _ = self.swarm.dial(ma);
                         }
                     },
+                    Some(MeshCommand::Connect { addr, reply }) => {
+                        let ma = match addr.parse::<Multiaddr>() {
+                            Ok(ma) => ma,
+                            Err(e) => {
+                                let _ = reply.send(Err(anyhow::anyhow!("Invalid address {}: {}", addr, e)));
+                                continue;
+                            }
+                        };
+                        let opts = DialOpts::unknown_peer_id().address(ma).build();
+                        let id = opts.connection_id();
+                        match self.swarm.dial(opts) {
+                            Ok(()) => {
+                                self.dials.insert(id, reply);
+                            },
+                            Err(e) => {
+                                let _ = reply.send(Err(anyhow::anyhow!("{}", e)));
+                            },
+                        }
+                    },
+                    Some(MeshCommand::Bootstrap(reply)) => {
+                        let started = self.swarm.behaviour_mut().kademlia.bootstrap();
+                        let _ = reply.send(started.map(|_| ()).map_err(|e| anyhow::anyhow!("{}", e)));
+                    },
                     Some(MeshCommand::GetPeers(tx)) => {
                         let peers = self.swarm.connected_peers().map(|p| p.to_string()).collect();
                         let _ = tx.send(peers);
@@ -112,13 +278,94 @@ impl MeshNode {
                     Some(MeshCommand::GetPeerId(tx)) => {
                         let _ = tx.send(self.swarm.local_peer_id().to_string());
                     },
+                    Some(MeshCommand::Publish { topic, data }) => {
+                        let topic = gossipsub::IdentTopic::new(topic);
+                        if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(topic.clone(), data) {
+                            debug!("Publish to {} failed: {}", topic, e);
+                        }
+                    },
+                    Some(MeshCommand::PublishChecked { topic, data, reply }) => {
+                        let topic = gossipsub::IdentTopic::new(topic);
+                        let published = self.swarm.behaviour_mut().gossipsub.publish(topic, data);
+                        let _ = reply.send(published.map(|_| ()).map_err(anyhow::Error::from));
+                    },
+                    Some(MeshCommand::Subscribe { topic, messages }) => {
+                        let topic = gossipsub::IdentTopic::new(topic);
+                        if let Err(e) = self.swarm.behaviour_mut().gossipsub.subscribe(&topic) {
+                            warn!("Subscribing to {} failed: {}", topic, e);
+                            continue;
+                        }
+                        self.subscriptions.entry(topic.hash()).or_default().push(messages);
+                    },
+                    Some(MeshCommand::Request { peer, data, reply }) => match peer.parse::<PeerId>() {
+                        Ok(peer) => {
+                            let id = self.swarm.behaviour_mut().request_response.send_request(&peer, Payload(data));
+                            self.requests.insert(id, reply);
+                        },
+                        Err(e) => {
+                            let _ = reply.send(Err(anyhow::anyhow!("Invalid peer id {}: {}", peer, e)));
+                        },
+                    },
+                    Some(MeshCommand::ServeRequests(requests)) => {
+                        self.request_handler = Some(requests);
+                    },
+                    Some(MeshCommand::GetListenAddrs(tx)) => {
+                        let _ = tx.send(self.listeners());
+                    },
+                    Some(MeshCommand::SubscribeEvents(events)) => {
+                        let current = self.swarm.connected_peers().map(|p| MeshEvent::PeerConnected(p.to_string()));
+                        let listeners = MeshEvent::ListenersChanged(self.listeners());
+                        if current.chain(Some(listeners)).all(|event| events.send(event).is_ok()) {
+                            self.event_subscribers.push(events);
+                        }
+                    },
+                    Some(MeshCommand::Shutdown(done)) => {
+                        self.close_connections().await;
+                        info!("Mesh Actor shut down.");
+                        let _ = done.send(());
+                        break;
+                    },
                     None => {
                         info!("Mesh Command Channel closed. Shutting down Mesh Actor.");
                         break;
                     },
                 },
                 event = self.swarm.select_next_some() => match event {
-                    SwarmEvent::NewListenAddr { address,.. } => info!("Mesh listening on {:?}", address),
+                    SwarmEvent::NewListenAddr { address,.. } => {
+                        info!("Mesh listening on {:?}", address);
+                        self.publish_event(MeshEvent::ListenersChanged(self.listeners()));
+                    },
+                    SwarmEvent::ExpiredListenAddr { address, .. } => {
+                        info!("Mesh stopped listening on {:?}", address);
+                        self.publish_event(MeshEvent::ListenersChanged(self.listeners()));
+                    },
+                    SwarmEvent::ListenerClosed { .. } => {
+                        self.publish_event(MeshEvent::ListenersChanged(self.listeners()));
+                    },
+                    SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, num_established, .. } => {
+                        if let Some(reply) = self.dials.remove(&connection_id) {
+                            let _ = reply.send(Ok(peer_id.to_string()));
+                        }
+                        // Peers we dialed are reachable where we found them,
+                        // so Kademlia can bootstrap from them.
+                        if endpoint.is_dialer() {
+                            self.swarm.behaviour_mut().kademlia.add_address(&peer_id, endpoint.get_remote_address().clone());
+                        }
+                        if num_established.get() == 1 {
+                            self.publish_event(MeshEvent::PeerConnected(peer_id.to_string()));
+                        }
+                    },
+                    SwarmEvent::OutgoingConnectionError { connection_id, peer_id, error } => {
+                        match self.dials.remove(&connection_id) {
+                            Some(reply) => {
+                                let _ = reply.send(Err(anyhow::anyhow!("{}", error)));
+                            },
+                            None => debug!("Dial to {:?} failed: {}", peer_id, error),
+                        }
+                    },
+                    SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                        self.publish_event(MeshEvent::PeerDisconnected(peer_id.to_string()));
+                    },
                     SwarmEvent::Behaviour(SovereignBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
                         for (peer, addr) in list {
                             info!("mDNS Discovered: {} at {}", peer, addr);
@@ -128,11 +375,106 @@ impl MeshNode {
                     SwarmEvent::Behaviour(SovereignBehaviourEvent::Ping(event)) => {
                         debug!("Ping event: {:?}", event);
                     },
+                    SwarmEvent::Behaviour(SovereignBehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. })) => {
+                        self.deliver(message);
+                    },
+                    SwarmEvent::Behaviour(SovereignBehaviourEvent::RequestResponse(event)) => {
+                        self.request_event(event);
+                    },
                     _ => {}
-                }
+                },
+                Some((channel, answer)) = self.responses.next(), if !self.responses.is_empty() => {
+                    if let Some(answer) = answer {
+                        let _ = self.swarm.behaviour_mut().request_response.send_response(channel, Payload(answer));
+                    }
+                },
             }
         }
     }
+
+    /// Disconnects every peer and waits, for up to two seconds, for the
+    /// connections to close.
+    async fn close_connections(&mut self) {
+        let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+        for peer in peers {
+            let _ = self.swarm.disconnect_peer_id(peer);
+        }
+        let closing = async {
+            while self.swarm.connected_peers().next().is_some() {
+                self.swarm.select_next_some().await;
+            }
+        };
+        let _ = tokio::time::timeout(Duration::from_secs(2), closing).await;
+    }
+
+    fn listeners(&self) -> Vec<String> {
+        self.swarm.listeners().map(|a| a.to_string()).collect()
+    }
+
+    /// Passes `event` to every subscriber, forgetting those that are gone.
+    fn publish_event(&mut self, event: MeshEvent) {
+        self.event_subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    fn deliver(&mut self, message: gossipsub::Message) {
+        let Some(receivers) = self.subscriptions.get_mut(&message.topic) else {
+            return;
+        };
+        let Some(source) = message.source else {
+            return;
+        };
+        let delivered = MeshMessage {
+            topic: message.topic.to_string(),
+            source: source.to_string(),
+            data: message.data,
+        };
+        receivers.retain(|tx| match tx.try_send(delivered.clone()) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                debug!("Dropped a message on {}: its receiver is full", delivered.topic);
+                true
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        });
+    }
+
+    fn request_event(&mut self, event: request_response::Event<Payload, Payload>) {
+        match event {
+            request_response::Event::Message { peer, message } => match message {
+                request_response::Message::Request { request, channel, .. } => {
+                    let Some(handler) = &self.request_handler else {
+                        debug!("No handler for the request from {}", peer);
+                        return;
+                    };
+                    let (reply, answer) = oneshot::channel();
+                    let request = MeshRequest {
+                        peer: peer.to_string(),
+                        data: request.0,
+                        reply,
+                    };
+                    if handler.try_send(request).is_err() {
+                        debug!("Dropped the request from {}: its handler is busy or gone", peer);
+                        return;
+                    }
+                    self.responses.push(answer.map(|answer| (channel, answer.ok())).boxed());
+                },
+                request_response::Message::Response { request_id, response } => {
+                    if let Some(reply) = self.requests.remove(&request_id) {
+                        let _ = reply.send(Ok(response.0));
+                    }
+                },
+            },
+            request_response::Event::OutboundFailure { peer, request_id, error } => {
+                if let Some(reply) = self.requests.remove(&request_id) {
+                    let _ = reply.send(Err(anyhow::anyhow!("Request to {} failed: {}", peer, error)));
+                }
+            },
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                debug!("Request from {} failed: {}", peer, error);
+            },
+            request_response::Event::ResponseSent { .. } => {},
+        }
+    }
 }
 
 // --- Helper: Robust Key Loading ---
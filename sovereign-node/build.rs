@@ -0,0 +1,56 @@
+//! Records what the node is built from, for `BuildInfo`.
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".into());
+    let root = Path::new(&manifest_dir).join("..");
+
+    let commit = git(&root, &["rev-parse", "HEAD"]).unwrap_or_else(|| "unknown".into());
+    let dirty = git(&root, &["status", "--porcelain", "--untracked-files=no"]).is_some_and(|s| !s.is_empty());
+    println!("cargo:rustc-env=SOVEREIGN_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=SOVEREIGN_GIT_DIRTY={}", dirty as u8);
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/index");
+
+    // Reproducible builds set SOURCE_DATE_EPOCH.
+    let built_at = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default());
+    println!("cargo:rustc-env=SOVEREIGN_BUILT_AT={}", built_at);
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(name, _)| name.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase().replace('_', "-")))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=SOVEREIGN_FEATURES={}", features.join(","));
+
+    let lock = std::fs::read_to_string(root.join("Cargo.lock")).unwrap_or_default();
+    println!("cargo:rustc-env=SOVEREIGN_WASMTIME_VERSION={}", locked_version(&lock, "wasmtime"));
+    println!("cargo:rustc-env=SOVEREIGN_LIBP2P_VERSION={}", locked_version(&lock, "libp2p"));
+    println!("cargo:rerun-if-changed=../Cargo.lock");
+}
+
+/// The trimmed output of a git command that succeeded, if any.
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git").arg("-C").arg(dir).args(args).output().ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The version of `name` in the lockfile, or `unknown`.
+fn locked_version(lock: &str, name: &str) -> String {
+    let wanted = format!("name = \"{}\"", name);
+    let mut lines = lock.lines();
+    while let Some(line) = lines.next() {
+        if line.trim() == wanted {
+            if let Some(version) = lines.next().and_then(|l| l.trim().strip_prefix("version = ")) {
+                return version.trim_matches('"').to_string();
+            }
+        }
+    }
+    "unknown".into()
+}
@@ -0,0 +1,345 @@
+use crate::ipc_transport::PeerCred;
+use sha2::{Digest, Sha256};
+use sovereign_protocol::{Permission, Request};
+use std::collections::{BTreeSet, HashMap};
+
+/// The permissions one connection holds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PermissionSet(BTreeSet<Permission>);
+
+impl PermissionSet {
+    pub fn all() -> Self {
+        Self(Permission::ALL.into_iter().collect())
+    }
+
+    pub fn allows(&self, permission: Permission) -> bool {
+        self.0.contains(&permission)
+    }
+}
+
+impl FromIterator<Permission> for PermissionSet {
+    fn from_iter<I: IntoIterator<Item = Permission>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+/// Who gets which permissions: a token presented in Hello wins, then a
+/// rule for the connecting user, then `default`.
+#[derive(Debug, Clone)]
+pub struct AccessPolicy {
+    pub default: PermissionSet,
+    /// Keyed by the SHA-256 of the token, so lookups compare digests, with
+    /// the principal the token's queries are held to grants as.
+    tokens: Vec<([u8; 32], String, PermissionSet)>,
+    pub users: HashMap<u32, PermissionSet>,
+}
+
+impl Default for AccessPolicy {
+    /// Every connection may do everything, as before access rules.
+    fn default() -> Self {
+        Self {
+            default: PermissionSet::all(),
+            tokens: Vec::new(),
+            users: HashMap::new(),
+        }
+    }
+}
+
+impl AccessPolicy {
+    /// A token is the principal `token:<name>`, or without a name
+    /// `token:<token_id>`.
+    pub fn add_token(&mut self, token: &str, name: Option<&str>, permissions: PermissionSet) {
+        let principal = format!("token:{}", name.map_or_else(|| token_id(token), str::to_string));
+        self.tokens.push((digest(token), principal, permissions));
+    }
+
+    /// What a connection holds before it says Hello.
+    pub(crate) fn for_peer(&self, peer: Option<&PeerCred>) -> PermissionSet {
+        peer.and_then(|peer| self.users.get(&peer.uid)).unwrap_or(&self.default).clone()
+    }
+
+    /// What `token` grants and its principal, or `None` if no configured
+    /// token matches.
+    pub(crate) fn for_token(&self, token: &str) -> Option<(PermissionSet, String)> {
+        let presented = digest(token);
+        let mut found = None;
+        // Every entry is compared in full, so timing does not tell how
+        // close a guess came.
+        for (known, principal, permissions) in &self.tokens {
+            let differs = known.iter().zip(presented.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+            if differs == 0 && found.is_none() {
+                found = Some((permissions.clone(), principal.clone()));
+            }
+        }
+        found
+    }
+}
+
+/// The principal a connection without a token is held to core grants as.
+pub(crate) fn user_principal(peer: Option<&PeerCred>) -> Option<String> {
+    peer.map(|peer| format!("uid:{}", peer.uid))
+}
+
+/// Whether requests needing `permission` run within a core namespace, so
+/// that a connection that may be confined to one must be known first.
+pub(crate) fn namespaced(permission: Permission) -> bool {
+    use Permission::*;
+    matches!(permission, QueryCoreReadonly | QueryCoreWrite | RunWasm | ManageWasm)
+}
+
+/// Names a token in the IPC audit log without revealing it.
+pub(crate) fn token_id(token: &str) -> String {
+    hex::encode(&digest(token)[..6])
+}
+
+fn digest(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+/// The permission each request needs, or `None` for those any connection
+/// may send. Exhaustive on purpose: a new request type does not build
+/// until it is given an entry here.
+pub(crate) fn required(req: &Request) -> Option<Permission> {
+    use Permission::*;
+    let permission = match req {
+        Request::Ping | Request::Hello { .. } | Request::HeartbeatAck { .. } => return None,
+        // Undoing a watch or subscription never needs more than making it.
+        Request::CoreUnwatch { .. } | Request::Unsubscribe { .. } => return None,
+
+        Request::GetStatus
+        | Request::GetMetrics
+        | Request::GetVersion
+        | Request::WasmList
+        | Request::WasmInfo { .. }
+        | Request::WasmExecutions
+        | Request::WasmListJobs
+        | Request::WasmJobHistory { .. }
+        | Request::WasmAllowlistList
+        | Request::MeshPeers
+        | Request::MeshPresence
+        | Request::GetLicenseInfo
+        | Request::WatchLicense
+        | Request::SetupState
+        | Request::Subscribe { .. } => ReadStatus,
+
+        Request::QueryCore { readonly: true, .. }
+        | Request::QueryCoreStreamed { readonly: true, .. }
+        | Request::CoreRunNamed { readonly: true, .. } => QueryCoreReadonly,
+        Request::CoreExplain { .. }
+        | Request::CoreListNamed
+        | Request::CoreListRelations
+        | Request::CoreDescribe { .. }
+        | Request::CoreExport { .. }
+        | Request::CoreKnn { .. }
+        | Request::CoreWatch { .. } => QueryCoreReadonly,
+
+        Request::QueryCore { .. }
+        | Request::QueryCoreStreamed { .. }
+        | Request::CoreRegisterQuery { .. }
+        | Request::CoreRunNamed { .. }
+        | Request::CoreRemoveNamed { .. }
+        | Request::CoreImport { .. }
+        | Request::CoreAssert { .. }
+        | Request::CoreRetract { .. }
+        | Request::CoreBegin
+        | Request::CoreExec { .. }
+        | Request::CoreCommit { .. }
+        | Request::CoreRollback { .. } => QueryCoreWrite,
+
+        Request::RunWasm { .. } | Request::RunWasmStreamed { .. } | Request::RunWasmModule { .. } | Request::RunWasmPipeline { .. } => RunWasm,
+
+        Request::WasmUpload { .. }
+        | Request::WasmRemove { .. }
+        | Request::WasmScheduleJob { .. }
+        | Request::WasmDeleteJob { .. }
+        | Request::WasmAllowlistAdd { .. }
+        | Request::WasmAllowlistRemove { .. } => ManageWasm,
+
+        Request::MeshDial { .. } | Request::MeshUnpin { .. } => MeshControl,
+        Request::VerifyLicense { .. } => LicenseAdmin,
+        // Cancel takes any connection's query or execution id, and the
+        // running queries and core audit show every connection's query text.
+        Request::Cancel { .. }
+        | Request::CoreQueries
+        | Request::AuditTail { .. }
+        | Request::CoreAuditTail { .. }
+        | Request::SelfCheck
+        | Request::SetupApply { .. }
+        | Request::ExportSnapshot { .. }
+        | Request::ImportSnapshot { .. }
+        | Request::SlowRequests { .. }
+        | Request::ConnectionStats
+        | Request::CoreGrant { .. }
+        | Request::CoreRevoke { .. }
+        | Request::CoreListGrants { .. }
+        | Request::ReplicationOutbox { .. }
+        | Request::ReplicationRequeue { .. } => NodeAdmin,
+        #[cfg(feature = "fault-injection")]
+        Request::InjectFault { .. } => NodeAdmin,
+    };
+    Some(permission)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+    use Permission::*;
+
+    /// One request of each kind, as it comes off the wire, with the
+    /// permission it needs.
+    fn table() -> Vec<(serde_json::Value, Option<Permission>)> {
+        let query = |readonly| json!({"query": "?[a] <- [[1]]", "params": {}, "readonly": readonly});
+        let path = json!({"path": "m.wasm", "input": ""});
+        let named = json!({"name": "m"});
+        let session = json!({"session_id": 1});
+        let limit = json!({"limit": 10});
+        let topics = json!({"topics": ["mesh"]});
+        let sha = json!({"sha256": "00"});
+        let mut table = vec![
+            (json!("Ping"), None),
+            (json!({"Hello": {"client_name": "c", "protocol_version": 1}}), None),
+            (json!({"HeartbeatAck": {"seq": 1}}), None),
+            (json!({"CoreUnwatch": {"watch_id": 1}}), None),
+            (json!({"Unsubscribe": topics}), None),
+            (json!("GetStatus"), Some(ReadStatus)),
+            (json!("GetMetrics"), Some(ReadStatus)),
+            (json!("GetVersion"), Some(ReadStatus)),
+            (json!("WasmList"), Some(ReadStatus)),
+            (json!({"WasmInfo": named}), Some(ReadStatus)),
+            (json!("WasmExecutions"), Some(ReadStatus)),
+            (json!("WasmListJobs"), Some(ReadStatus)),
+            (json!({"WasmJobHistory": named}), Some(ReadStatus)),
+            (json!("WasmAllowlistList"), Some(ReadStatus)),
+            (json!("MeshPeers"), Some(ReadStatus)),
+            (json!("MeshPresence"), Some(ReadStatus)),
+            (json!("GetLicenseInfo"), Some(ReadStatus)),
+            (json!("WatchLicense"), Some(ReadStatus)),
+            (json!("SetupState"), Some(ReadStatus)),
+            (json!({"Subscribe": topics}), Some(ReadStatus)),
+            (json!({"QueryCore": query(true)}), Some(QueryCoreReadonly)),
+            (json!({"QueryCoreStreamed": query(true)}), Some(QueryCoreReadonly)),
+            (json!({"CoreRunNamed": {"name": "q", "readonly": true}}), Some(QueryCoreReadonly)),
+            (json!({"CoreExplain": {"query": "?[a] <- [[1]]"}}), Some(QueryCoreReadonly)),
+            (json!("CoreListNamed"), Some(QueryCoreReadonly)),
+            (json!("CoreListRelations"), Some(QueryCoreReadonly)),
+            (json!({"CoreDescribe": named}), Some(QueryCoreReadonly)),
+            (json!({"CoreExport": {"name": "r", "format": "csv"}}), Some(QueryCoreReadonly)),
+            (json!({"CoreKnn": {"name": "r", "column": "v", "vector": [1.0], "k": 1}}), Some(QueryCoreReadonly)),
+            (json!({"CoreWatch": {"relation": "r"}}), Some(QueryCoreReadonly)),
+            (json!({"QueryCore": query(false)}), Some(QueryCoreWrite)),
+            (json!({"QueryCoreStreamed": query(false)}), Some(QueryCoreWrite)),
+            (json!({"CoreRunNamed": {"name": "q"}}), Some(QueryCoreWrite)),
+            (json!({"CoreRegisterQuery": {"name": "q", "query": "?[a] <- [[1]]"}}), Some(QueryCoreWrite)),
+            (json!({"CoreRemoveNamed": named}), Some(QueryCoreWrite)),
+            (json!({"CoreImport": {"name": "r", "format": "json_lines", "mode": "append"}}), Some(QueryCoreWrite)),
+            (json!({"CoreAssert": {"name": "r", "rows": []}}), Some(QueryCoreWrite)),
+            (json!({"CoreRetract": {"name": "r", "keys": []}}), Some(QueryCoreWrite)),
+            (json!("CoreBegin"), Some(QueryCoreWrite)),
+            (json!({"CoreExec": {"session_id": 1, "query": "?[a] <- [[1]]", "params": {}}}), Some(QueryCoreWrite)),
+            (json!({"CoreCommit": session}), Some(QueryCoreWrite)),
+            (json!({"CoreRollback": session}), Some(QueryCoreWrite)),
+            (json!({"RunWasm": path}), Some(RunWasm)),
+            (json!({"RunWasmStreamed": {"path": "m.wasm"}}), Some(RunWasm)),
+            (json!({"RunWasmModule": {"name": "m", "input": ""}}), Some(RunWasm)),
+            (json!({"RunWasmPipeline": {"stages": [], "input": ""}}), Some(RunWasm)),
+            (json!({"WasmUpload": {"name": "m", "path": "m.wasm"}}), Some(ManageWasm)),
+            (json!({"WasmRemove": named}), Some(ManageWasm)),
+            (json!({"WasmScheduleJob": {"name": "j", "job": {"module": "m", "schedule": {"interval": {"every_ms": 1000}}}}}), Some(ManageWasm)),
+            (json!({"WasmDeleteJob": named}), Some(ManageWasm)),
+            (json!({"WasmAllowlistAdd": sha}), Some(ManageWasm)),
+            (json!({"WasmAllowlistRemove": sha}), Some(ManageWasm)),
+            (json!({"MeshDial": {"addr": "/ip4/127.0.0.1/tcp/1"}}), Some(MeshControl)),
+            (json!({"MeshUnpin": {"addr": "/ip4/127.0.0.1/tcp/1"}}), Some(MeshControl)),
+            (json!({"VerifyLicense": {"tx_id": "ab", "developer_addr": "bc1q", "required_sats": 1}}), Some(LicenseAdmin)),
+            (json!({"Cancel": {"execution_id": 1}}), Some(NodeAdmin)),
+            (json!({"AuditTail": limit}), Some(NodeAdmin)),
+            (json!("CoreQueries"), Some(NodeAdmin)),
+            (json!({"CoreAuditTail": limit}), Some(NodeAdmin)),
+            (json!("SelfCheck"), Some(NodeAdmin)),
+            (
+                json!({"SetupApply": {"settings": {"network": "n", "swarm_key": {"kind": "generate"}, "accept_license_policy": true, "data_dir": "/d"}}}),
+                Some(NodeAdmin),
+            ),
+            (json!({"ExportSnapshot": {}}), Some(NodeAdmin)),
+            (json!({"ImportSnapshot": {}}), Some(NodeAdmin)),
+            (json!({"SlowRequests": limit}), Some(NodeAdmin)),
+            (json!("ConnectionStats"), Some(NodeAdmin)),
+            (json!({"CoreGrant": {"principal": "p", "relation_pattern": "r", "rights": "read"}}), Some(NodeAdmin)),
+            (json!({"CoreRevoke": {"principal": "p", "relation_pattern": "r"}}), Some(NodeAdmin)),
+            (json!({"CoreListGrants": {}}), Some(NodeAdmin)),
+            (json!({"ReplicationOutbox": {}}), Some(NodeAdmin)),
+            (json!({"ReplicationRequeue": {"seqs": [1]}}), Some(NodeAdmin)),
+        ];
+        table.extend(fault_injection());
+        table
+    }
+
+    #[cfg(feature = "fault-injection")]
+    fn fault_injection() -> Vec<(serde_json::Value, Option<Permission>)> {
+        vec![(json!({"InjectFault": {"target": "mesh", "kind": {"kind": "crash"}, "duration_ms": 0}}), Some(NodeAdmin))]
+    }
+
+    #[cfg(not(feature = "fault-injection"))]
+    fn fault_injection() -> Vec<(serde_json::Value, Option<Permission>)> {
+        Vec::new()
+    }
+
+    /// Every `Request` variant, by name, as serde lists them when asked
+    /// for one that does not exist.
+    fn variants() -> BTreeSet<String> {
+        let error = serde_json::from_value::<Request>(json!("NoSuchRequest")).unwrap_err().to_string();
+        let listed = error.split_once("expected one of ").expect(&error).1;
+        listed.split(", ").map(|name| name.trim_matches(|c: char| c == '`' || c.is_whitespace()).to_string()).collect()
+    }
+
+    #[test]
+    fn every_request_kind_is_classified() {
+        let mut classified = BTreeMap::new();
+        for (wire, expected) in table() {
+            let req: Request = serde_json::from_value(wire.clone()).unwrap_or_else(|e| panic!("{}: {}", wire, e));
+            assert_eq!(required(&req), expected, "{}", wire);
+            classified.insert(req.kind().to_string(), expected);
+        }
+        let all = variants();
+        assert!(all.len() > 60, "{:?}", all);
+        let unlisted: Vec<_> = all.iter().filter(|kind| !classified.contains_key(*kind)).collect();
+        assert!(unlisted.is_empty(), "Add these to the table: {:?}", unlisted);
+    }
+
+    #[test]
+    fn tokens_win_over_user_rules_over_the_default() {
+        let reader: PermissionSet = [ReadStatus].into_iter().collect();
+        let runner: PermissionSet = [ReadStatus, RunWasm].into_iter().collect();
+        let mut policy = AccessPolicy {
+            default: PermissionSet::default(),
+            users: HashMap::from([(1000, reader.clone())]),
+            ..Default::default()
+        };
+        policy.add_token("runner-token-0123456789", Some("runner"), runner.clone());
+        policy.add_token("admin-token-0123456789", None, PermissionSet::all());
+
+        let user = |uid| PeerCred { uid, gid: uid, pid: None };
+        assert_eq!(policy.for_peer(Some(&user(1000))), reader);
+        assert_eq!(policy.for_peer(Some(&user(1001))), PermissionSet::default());
+        assert_eq!(policy.for_peer(None), PermissionSet::default());
+        assert!(!policy.for_peer(None).allows(ReadStatus));
+
+        assert_eq!(policy.for_token("runner-token-0123456789"), Some((runner, "token:runner".to_string())));
+        let (all, principal) = policy.for_token("admin-token-0123456789").unwrap();
+        assert!(Permission::ALL.into_iter().all(|p| all.allows(p)));
+        assert_eq!(principal, format!("token:{}", token_id("admin-token-0123456789")));
+        assert_eq!(policy.for_token("runner-token-012345678"), None);
+        assert_eq!(policy.for_token(""), None);
+        assert_eq!(user_principal(Some(&user(1000))).as_deref(), Some("uid:1000"));
+    }
+
+    #[test]
+    fn token_ids_do_not_reveal_the_token() {
+        let id = token_id("secret-token-0123456789");
+        assert_eq!(id.len(), 12);
+        assert!(!"secret-token-0123456789".contains(&id));
+        assert_ne!(id, token_id("secret-token-0123456780"));
+    }
+}
@@ -0,0 +1,308 @@
+use crate::mesh_supervisor::panic_message;
+use sovereign_protocol::{PoolMetrics, Response};
+use std::fmt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::debug;
+
+/// Bounds on the retry-after a saturated pool suggests.
+const RETRY_AFTER_MIN: Duration = Duration::from_millis(10);
+const RETRY_AFTER_MAX: Duration = Duration::from_secs(5);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// How large a pool is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PoolConfig {
+    pub threads: usize,
+    /// Work that may wait for a thread; past it, work is refused. 0 refuses
+    /// whatever finds every thread busy.
+    pub queue: usize,
+}
+
+/// Threads of its own for one subsystem's blocking work, behind a bounded
+/// queue. Work past the queue is refused rather than left to wait, so a
+/// burst in one subsystem neither holds up another's work nor piles onto
+/// tokio's shared blocking pool.
+pub(crate) struct BlockingPool {
+    name: &'static str,
+    config: PoolConfig,
+    jobs: SyncSender<Job>,
+    stats: Arc<PoolStats>,
+}
+
+#[derive(Default)]
+struct PoolStats {
+    queued: AtomicU64,
+    running: AtomicU64,
+    completed: AtomicU64,
+    rejected: AtomicU64,
+    /// A moving average of how long work runs, for the retry-after.
+    avg_run_micros: AtomicU64,
+}
+
+/// Why `BlockingPool::spawn` produced no value.
+#[derive(Debug)]
+pub(crate) enum PoolError {
+    /// Every thread was busy and the queue full; the work was not run.
+    Saturated { pool: &'static str, retry_after: Duration },
+    /// The work panicked.
+    Panicked(String),
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::Saturated { pool, retry_after } => write!(f, "The {} pool is saturated; retry in {:?}", pool, retry_after),
+            PoolError::Panicked(message) => write!(f, "task panicked: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+impl PoolError {
+    /// The response for the client: `Overloaded` when saturated, else an
+    /// error starting with `failed`.
+    pub fn into_response(self, failed: &str) -> Response {
+        match self {
+            PoolError::Saturated { pool, retry_after } => Response::Overloaded {
+                subsystem: pool.into(),
+                retry_after_ms: retry_after.as_millis() as u64,
+            },
+            e @ PoolError::Panicked(_) => Response::Error(format!("{}: {}", failed, e)),
+        }
+    }
+}
+
+impl BlockingPool {
+    /// Starts `config.threads` threads named after `name`.
+    pub fn new(name: &'static str, config: PoolConfig) -> std::io::Result<Self> {
+        let (jobs, queue) = mpsc::sync_channel::<Job>(config.queue);
+        let queue = Arc::new(Mutex::new(queue));
+        for n in 0..config.threads.max(1) {
+            let queue = queue.clone();
+            std::thread::Builder::new().name(format!("{}-pool-{}", name, n)).spawn(move || work(&queue))?;
+        }
+        Ok(Self {
+            name,
+            config,
+            jobs,
+            stats: Arc::new(PoolStats::default()),
+        })
+    }
+
+    /// Queues `f` now, or refuses it if the pool is saturated; the future
+    /// yields its result. Dropping the future does not stop `f`.
+    pub fn spawn<F, T>(&self, f: F) -> impl Future<Output = Result<T, PoolError>> + Send + 'static
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (done_tx, done_rx) = oneshot::channel();
+        let stats = self.stats.clone();
+        let job: Job = Box::new(move || {
+            stats.queued.fetch_sub(1, Ordering::Relaxed);
+            stats.running.fetch_add(1, Ordering::Relaxed);
+            let started = Instant::now();
+            let result = std::panic::catch_unwind(AssertUnwindSafe(f)).map_err(|panic| PoolError::Panicked(panic_message(panic)));
+            stats.finished(started.elapsed());
+            let _ = done_tx.send(result);
+        });
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        let queued = match self.jobs.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) => {
+                self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+                let retry_after = self.retry_after();
+                debug!("The {} pool is saturated; refusing work for {:?}", self.name, retry_after);
+                Err(PoolError::Saturated {
+                    pool: self.name,
+                    retry_after,
+                })
+            }
+        };
+        async move {
+            queued?;
+            done_rx.await.unwrap_or_else(|_| Err(PoolError::Panicked("the worker thread exited".into())))
+        }
+    }
+
+    pub fn metrics(&self) -> PoolMetrics {
+        PoolMetrics {
+            threads: self.config.threads.max(1) as u64,
+            queue_capacity: self.config.queue as u64,
+            queued: self.stats.queued.load(Ordering::Relaxed),
+            running: self.stats.running.load(Ordering::Relaxed),
+            completed: self.stats.completed.load(Ordering::Relaxed),
+            rejected: self.stats.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    /// About when a thread frees up: the average run, times the queue
+    /// ahead of it over the threads working it off.
+    fn retry_after(&self) -> Duration {
+        let avg = Duration::from_micros(self.stats.avg_run_micros.load(Ordering::Relaxed));
+        let rounds = (self.config.queue / self.config.threads.max(1)) as u32 + 1;
+        (avg * rounds).clamp(RETRY_AFTER_MIN, RETRY_AFTER_MAX)
+    }
+}
+
+impl PoolStats {
+    fn finished(&self, took: Duration) {
+        self.running.fetch_sub(1, Ordering::Relaxed);
+        self.completed.fetch_add(1, Ordering::Relaxed);
+        let took = took.as_micros() as u64;
+        let _ = self.avg_run_micros.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |avg| {
+            Some(if avg == 0 { took } else { (avg * 7 + took) / 8 })
+        });
+    }
+}
+
+/// A pool thread: runs jobs until the pool is dropped.
+fn work(queue: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = queue.lock().unwrap_or_else(|e| e.into_inner()).recv();
+        match job {
+            Ok(job) => job(),
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Work that blocks its thread until `release` is sent to or dropped.
+    fn blocker() -> (SyncSender<()>, impl FnOnce() + Send + 'static) {
+        let (release, wait) = mpsc::sync_channel::<()>(0);
+        (release, move || {
+            let _ = wait.recv();
+        })
+    }
+
+    /// Waits for `pool` to have `running` jobs on its threads.
+    async fn until_running(pool: &BlockingPool, running: u64) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.metrics().running != running {
+            assert!(Instant::now() < deadline, "{:?}", pool.metrics());
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_work_past_its_queue_with_a_retry_after() {
+        let pool = BlockingPool::new("test", PoolConfig { threads: 1, queue: 1 }).unwrap();
+        let (release_running, running) = blocker();
+        let running = pool.spawn(running);
+        until_running(&pool, 1).await;
+        let (release_queued, queued) = blocker();
+        let queued = pool.spawn(queued);
+
+        match pool.spawn(|| ()).await {
+            Err(PoolError::Saturated { pool: "test", retry_after }) => assert!((RETRY_AFTER_MIN..=RETRY_AFTER_MAX).contains(&retry_after)),
+            other => panic!("Expected Saturated, got {:?}", other),
+        }
+        let metrics = pool.metrics();
+        assert_eq!((metrics.threads, metrics.queue_capacity), (1, 1));
+        assert_eq!((metrics.running, metrics.queued, metrics.rejected, metrics.completed), (1, 1, 1, 0));
+
+        drop((release_running, release_queued));
+        running.await.unwrap();
+        queued.await.unwrap();
+        assert_eq!(pool.spawn(|| 7).await.unwrap(), 7);
+        let metrics = pool.metrics();
+        assert_eq!((metrics.running, metrics.queued, metrics.rejected, metrics.completed), (0, 0, 1, 3));
+    }
+
+    #[tokio::test]
+    async fn without_a_queue_takes_only_what_a_thread_is_free_for() {
+        let pool = BlockingPool::new("test", PoolConfig { threads: 1, queue: 0 }).unwrap();
+        // Refused until the thread is up and waiting for work.
+        let (release, work) = blocker();
+        let work = Arc::new(Mutex::new(Some(work)));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let busy = loop {
+            let (refused, work) = (pool.metrics().rejected, work.clone());
+            let busy = pool.spawn(move || work.lock().unwrap().take().map(|work| work()));
+            if pool.metrics().rejected == refused || Instant::now() > deadline {
+                break busy;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        };
+        until_running(&pool, 1).await;
+        assert!(matches!(pool.spawn(|| ()).await, Err(PoolError::Saturated { .. })));
+        drop(release);
+        busy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_saturated_pool_does_not_hold_up_another() {
+        let wasm = BlockingPool::new("wasm", PoolConfig { threads: 2, queue: 2 }).unwrap();
+        let finance = BlockingPool::new("finance", PoolConfig { threads: 1, queue: 1 }).unwrap();
+        let mut releases = Vec::new();
+        let mut held = Vec::new();
+        for n in 1..=4 {
+            let (release, work) = blocker();
+            releases.push(release);
+            held.push(wasm.spawn(work));
+            // Both threads take work before any waits in the queue.
+            until_running(&wasm, n.min(2)).await;
+        }
+        assert_eq!(wasm.metrics().queued, 2);
+        assert!(matches!(wasm.spawn(|| ()).await, Err(PoolError::Saturated { pool: "wasm", .. })));
+
+        let started = Instant::now();
+        assert_eq!(finance.spawn(|| "verified").await.unwrap(), "verified");
+        assert!(started.elapsed() < Duration::from_millis(500), "{:?}", started.elapsed());
+        assert_eq!(finance.metrics().rejected, 0);
+
+        drop(releases);
+        for work in held {
+            work.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn suggests_a_longer_wait_the_longer_work_runs() {
+        let pool = BlockingPool::new("test", PoolConfig { threads: 1, queue: 3 }).unwrap();
+        assert_eq!(pool.retry_after(), RETRY_AFTER_MIN);
+        pool.spawn(|| std::thread::sleep(Duration::from_millis(40))).await.unwrap();
+        // Four rounds of a 40 ms run: the three queued and the running one.
+        let retry_after = pool.retry_after();
+        assert!(retry_after >= Duration::from_millis(160) && retry_after < RETRY_AFTER_MAX, "{:?}", retry_after);
+    }
+
+    #[tokio::test]
+    async fn a_panic_fails_its_own_work_only() {
+        let pool = BlockingPool::new("test", PoolConfig { threads: 1, queue: 1 }).unwrap();
+        match pool.spawn(|| panic!("module trapped")).await {
+            Err(e @ PoolError::Panicked(_)) => assert_eq!(e.to_string(), "task panicked: module trapped"),
+            other => panic!("Expected Panicked, got {:?}", other.map(drop)),
+        }
+        assert_eq!(pool.spawn(|| 1 + 1).await.unwrap(), 2);
+    }
+
+    #[test]
+    fn surfaces_saturation_as_overloaded_and_panics_as_errors() {
+        let saturated = PoolError::Saturated {
+            pool: "finance",
+            retry_after: Duration::from_millis(250),
+        };
+        match saturated.into_response("License check failed") {
+            Response::Overloaded { subsystem, retry_after_ms } => assert_eq!((subsystem.as_str(), retry_after_ms), ("finance", 250)),
+            other => panic!("Expected Overloaded, got {:?}", other),
+        }
+        match PoolError::Panicked("boom".into()).into_response("License check failed") {
+            Response::Error(message) => assert_eq!(message, "License check failed: task panicked: boom"),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use sovereign_protocol::{BuildInfo, PROTOCOL_VERSION};
+
+/// How this binary was built, as recorded by `build.rs`.
+pub(crate) fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION").into(),
+        git_commit: env!("SOVEREIGN_GIT_COMMIT").into(),
+        git_dirty: env!("SOVEREIGN_GIT_DIRTY") == "1",
+        built_at_unix: env!("SOVEREIGN_BUILT_AT").parse().unwrap_or_default(),
+        features: env!("SOVEREIGN_FEATURES").split(',').filter(|f| !f.is_empty()).map(str::to_string).collect(),
+        protocol_version: PROTOCOL_VERSION,
+        wasmtime_version: env!("SOVEREIGN_WASMTIME_VERSION").into(),
+        libp2p_version: env!("SOVEREIGN_LIBP2P_VERSION").into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_what_the_node_was_built_from() {
+        let build = build_info();
+        assert_eq!(build.version, env!("CARGO_PKG_VERSION"));
+        // Builds outside a git checkout, as from crates.io, cannot tell.
+        assert!(build.git_commit == "unknown" || (build.git_commit.len() == 40 && build.git_commit.chars().all(|c| c.is_ascii_hexdigit())), "{}", build.git_commit);
+        assert!(build.built_at_unix > 1_600_000_000);
+        assert_eq!(build.features.iter().any(|f| f == "fault-injection"), cfg!(feature = "fault-injection"));
+        assert_eq!(build.protocol_version, PROTOCOL_VERSION);
+        assert!(!build.wasmtime_version.is_empty() && !build.libp2p_version.is_empty());
+        assert!(build.describe().starts_with(&build.version));
+    }
+
+    #[test]
+    fn round_trips_through_the_protocol() {
+        let build = build_info();
+        let json = serde_json::to_vec(&sovereign_protocol::Response::Version(build.clone())).unwrap();
+        match serde_json::from_slice(&json).unwrap() {
+            sovereign_protocol::Response::Version(decoded) => assert_eq!(decoded, build),
+            other => panic!("Expected Version, got {:?}", other),
+        }
+        // Only the version and commit are needed from older nodes.
+        let old: BuildInfo = serde_json::from_str(r#"{"version": "0.1.0", "git_commit": "unknown"}"#).unwrap();
+        assert_eq!(old.describe(), "0.1.0 (unknown)");
+    }
+}
@@ -0,0 +1,1460 @@
+use crate::access::{AccessPolicy, PermissionSet};
+use crate::blocking_pool::PoolConfig;
+use crate::data_dir::DataDir;
+use crate::health::HealthThresholds;
+use crate::ipc_audit::IpcAuditConfig;
+use crate::ipc_tcp::{TcpConfig, TcpSecurity};
+use crate::ipc_transport::PeerPolicy;
+use crate::license_monitor::LicenseRecheck;
+use crate::logging::FileLog;
+use crate::mesh_warmup::MeshWarmup;
+use crate::module_paths::ModulePaths;
+use crate::presence::PresenceConfig;
+use crate::rate_limit::RateLimits;
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use sovereign_finance::{LicensePolicy, LicenseVerifier, Network};
+use sovereign_mesh::{MeshConfig, Multiaddr};
+use sovereign_core::{AuditConfig, AuditRedaction, AuditSink};
+use sovereign_protocol::{Compression, IpcEndpoint, Permission, DEFAULT_MAX_FRAME_SIZE};
+use sovereign_replication::ReplicationConfig;
+use sovereign_runtime_wasm::{AllowlistConfig, AllowlistMode, RuntimeConfig};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Written where the config file is missing. Every setting is commented
+/// out, so it reads as the defaults.
+const DEFAULT_CONFIG: &str = r#"# sovereign-node configuration. Uncomment a setting to change it.
+# SOVEREIGN_* environment variables, named beside each setting, win over
+# what is set here.
+
+# Where IPC clients connect: a socket path, or \\.\pipe\<name> on Windows.
+# Defaults to the per-user platform endpoint. (SOVEREIGN_IPC)
+# ipc_endpoint = "/run/user/1000/sovereign/node.sock"
+
+# Users besides the node's own that may connect to its Unix socket.
+# ipc_allowed_uids = []
+# ipc_allowed_gids = []
+
+# Clients connected at once; those past it are told the node is busy.
+# (SOVEREIGN_IPC_MAX_CONNECTIONS)
+# ipc_max_connections = 256
+# Close connections that send nothing for this long; 0 never does. Clients
+# that said Hello are warned this many seconds before.
+# ipc_idle_timeout_mins = 10
+# ipc_idle_warning_secs = 30
+# Idle clients that said Hello are sent a heartbeat this often, and dropped
+# after missing this many in a row. Both go to clients in HelloAck.
+# ipc_heartbeat_secs = 15
+# ipc_missed_heartbeats = 3
+# Largest request a client may send, in bytes; clients learn it in HelloAck.
+# Larger requests are answered with FrameTooLarge.
+# ipc_max_frame_bytes = 65536
+# How long a dropped client's session (its subscriptions and watches) is
+# kept for it to reconnect and resume; 0 keeps no sessions.
+# ipc_session_grace_secs = 60
+# Requests that take longer are logged as warnings and listed by
+# `sovereignctl slow`; 0 records none. (SOVEREIGN_IPC_SLOW_REQUEST_MS)
+# ipc_slow_request_ms = 1000
+# Compressions offered to clients, most preferred first: "zstd", "gzip".
+# Frame bodies smaller than ipc_compression_min_bytes go as they are; an
+# empty list, or "none" in the variable, compresses nothing.
+# (SOVEREIGN_IPC_COMPRESSION)
+# ipc_compression = ["zstd", "gzip"]
+# ipc_compression_min_bytes = 8192
+
+# Defaults to the platform data directory. (SOVEREIGN_DATA_DIR)
+# data_dir = "/var/lib/sovereign"
+
+# A tracing filter, such as "info" or "info,libp2p=warn"; RUST_LOG wins
+# over it. SIGHUP re-reads it from this file. (SOVEREIGN_LOG)
+# log_level = "info"
+
+# Serves Prometheus /metrics and /healthz on 127.0.0.1 at this port, in
+# builds with the metrics-http feature. (SOVEREIGN_METRICS_PORT)
+# metrics_port = 9464
+
+[log_file]
+# Also write the log to logs/node.log in the data directory.
+# (SOVEREIGN_LOG_FILE=1)
+# enabled = false
+# Start a new file past this size, or this age; 0 hours never does.
+# max_bytes = 16777216
+# max_age_hours = 24
+# Old files kept as node.log.1 and up.
+# keep = 4
+
+[ipc_audit]
+# Record every IPC request, who sent it and how it ended in
+# logs/ipc-audit.jsonl in the data directory. Query text and transaction
+# ids are hashed; tokens and parameter values are never written.
+# (SOVEREIGN_IPC_AUDIT=1)
+# enabled = false
+# max_bytes = 16777216
+# keep = 4
+# Entries waiting to be written, past which new ones are dropped and counted.
+# queue = 1024
+
+[mesh]
+# The swarm's pre-shared key, created with a development key if missing.
+# Defaults to keys/swarm.key in the data directory. (SOVEREIGN_SWARM_KEY)
+# psk_path = "/var/lib/sovereign/keys/swarm.key"
+# Comma-separated in SOVEREIGN_MESH_LISTEN and SOVEREIGN_MESH_BOOTSTRAP.
+# listen_addrs = ["/ip4/0.0.0.0/tcp/0"]
+# Dialed at startup, with peers pinned by MeshDial.
+# bootstrap_peers = []
+# Gossip topics joined at startup. (SOVEREIGN_MESH_TOPICS, comma-separated)
+# topics = []
+# How long startup waits for a first peer before reporting the mesh isolated.
+# warmup_timeout_secs = 30
+
+[presence]
+# Publish a signed beacon with this node's version, uptime, health and
+# capabilities every interval_secs, give or take a fifth, and keep the
+# beacons of other nodes for `sovereignctl presence`. Every node of a mesh
+# should use the same interval. (SOVEREIGN_PRESENCE=1)
+# enabled = false
+# interval_secs = 30
+# A node not heard from for this long is stale, and degrades health until
+# it is heard from again or forgotten.
+# stale_after_secs = 90
+# forget_after_hours = 24
+# Advertised beside core, wasm, replication and tcp-ipc, which the node
+# works out for itself.
+# capabilities = []
+
+[finance]
+# Tried in order until one connects. (SOVEREIGN_ELECTRUM_URLS, comma-separated)
+# electrum_urls = ["ssl://electrum.blockstream.info:50002"]
+# bitcoin, testnet, signet or regtest. (SOVEREIGN_BITCOIN_NETWORK)
+# network = "bitcoin"
+# (SOVEREIGN_DEVELOPER_ADDRESS)
+# developer_address = "bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh"
+# (SOVEREIGN_REQUIRED_SATS)
+# required_sats = 50000
+# 0 accepts unconfirmed payments. (SOVEREIGN_MIN_CONFIRMATIONS)
+# min_confirmations = 0
+# How often the last verified license is checked again on-chain.
+# recheck_hours = 24
+# How long checks that cannot reach Electrum leave an active license active.
+# offline_grace_hours = 168
+
+[wasm]
+# Defaults to wasm-store/ in the data directory. (SOVEREIGN_WASM_MODULES)
+# module_store = "/var/lib/sovereign/wasm-store"
+# default_fuel_limit = 1000000000
+# max_fuel_limit = 10000000000
+# default_memory_bytes = 67108864
+# max_memory_bytes = 536870912
+# max_concurrent_executions = 8
+# max_executions_per_source = 4
+# Directories besides the module store that RunWasm may load files from.
+# run_dirs = []
+# The largest module file RunWasm reads.
+# max_module_bytes = 67108864
+# "enforce" only runs modules listed in wasm-allowlist.json in the data
+# directory; "allow-all" runs any, logging those it would refuse.
+# (SOVEREIGN_WASM_ALLOWLIST)
+# allowlist = "off"
+
+[rate_limits]
+# Requests any one IPC connection may send, of any kind.
+# requests_per_second = 100
+# burst = 200
+# Stricter budgets for expensive requests, per connection.
+# verify_license_per_minute = 6
+# run_wasm_per_minute = 120
+# core_import_per_minute = 10
+# Close a connection refused this many times within a minute; 0 never does.
+# close_after_rejections = 0
+
+[core]
+# sqlite, rocksdb or mem. (SOVEREIGN_CORE_BACKEND)
+# backend = "sqlite"
+# Defaults to core/core.db or core/core.rocksdb in the data directory.
+# (SOVEREIGN_CORE_PATH)
+# path = "/var/lib/sovereign/core/core.db"
+
+[core.namespaces]
+# Confines IPC clients to a core namespace by who they are, as core grants
+# name them: token:<name> for a named access token, uid:<uid> for a user.
+# While any are set, a connection that is neither, such as a named pipe
+# client without a token, may not make core or WASM requests.
+# (SOVEREIGN_CORE_NAMESPACES, as comma-separated principal=namespace pairs)
+# "token:reporting" = "reports"
+
+[core.audit]
+# Record every core query: "file" in logs/core-audit.jsonl in the data
+# directory, rotated at 16 MiB with four old files kept, or "relation" for
+# the newest 10000 in the core. (SOVEREIGN_CORE_AUDIT)
+# sink = "off"
+# Also record query text and parameter values.
+# (SOVEREIGN_CORE_AUDIT_TEXT=1, SOVEREIGN_CORE_AUDIT_PARAMS=1)
+# query_text = false
+# param_values = false
+
+[replication]
+# Core relations to keep in sync with the other nodes replicating them in
+# the same namespace; each must exist with the same columns on every node.
+# (SOVEREIGN_REPLICATE, comma-separated)
+# relations = []
+# Keeps separate groups of nodes apart. (SOVEREIGN_REPLICATION_NAMESPACE)
+# namespace = "sovereign"
+# Ops waiting to be gossiped past which the node reports itself degraded;
+# none are dropped. (SOVEREIGN_REPLICATION_OUTBOX_HIGH_WATER)
+# outbox_high_water = 10000
+
+[pools]
+# Threads of the node's own for blocking work, each pool behind a queue;
+# work past the queue is refused as overloaded, with a time to retry.
+# On-chain license checks and Electrum connections.
+# finance_threads = 2
+# finance_queue = 8
+# Core queries, writes and streams, and compiling uploaded modules.
+# compute_threads = 8
+# compute_queue = 64
+
+[health]
+# Where NodeStatus, /healthz and the metrics turn DEGRADED or CRITICAL.
+# 0 turns a limit off.
+# rss_degraded_mb = 1024
+# rss_critical_mb = 4096
+# The node's CPU use as a share of the whole machine.
+# cpu_degraded_percent = 90
+# Free space on the data directory's volume.
+# disk_free_degraded_mb = 1024
+# disk_free_critical_mb = 100
+# core_size_degraded_mb = 0
+# WASM executions waiting for a slot.
+# wasm_queue_degraded = 32
+
+[tcp]
+# Also serve IPC over TCP at this address, for clients on other machines;
+# off when unset. Every TCP client must present one of the access.tokens
+# in Hello, whatever access.default grants. (SOVEREIGN_TCP_LISTEN)
+# listen = "0.0.0.0:7443"
+# The PEM certificate chain and key to serve. Unset, a self-signed pair is
+# generated in keys/ on first run; its fingerprint, for clients to pin, is
+# logged at startup and written to state/tls-fingerprint.txt.
+# cert_path = "/etc/sovereign/tls/cert.pem"
+# key_path = "/etc/sovereign/tls/key.pem"
+# Turning TLS off sends tokens and data in the clear, so it also takes
+# i_know_this_is_insecure = true.
+# tls = true
+# i_know_this_is_insecure = false
+
+[access]
+# What IPC connections may do: read_status, query_core_readonly,
+# query_core_write, run_wasm, manage_wasm, mesh_control, license_admin and
+# node_admin, or "all". A token a client presents in Hello wins, then a
+# rule for its user, then the default. Core grants (sovereignctl grant)
+# further limit which relations a token, as token:<name>, or a user, as
+# uid:<uid>, may query.
+# default = ["all"]
+#
+# [[access.tokens]]
+# token = "a long random string"
+# name = "reporting"
+# permissions = ["read_status", "query_core_readonly"]
+#
+# [[access.users]]
+# uid = 1001
+# permissions = ["read_status"]
+"#;
+
+/// The node's settings, from its config file and `SOVEREIGN_*` variables.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    /// The platform default when unset.
+    pub ipc_endpoint: Option<String>,
+    /// Users besides the node's own that may connect over a Unix socket.
+    pub ipc_allowed_uids: Vec<u32>,
+    pub ipc_allowed_gids: Vec<u32>,
+    pub ipc_max_connections: usize,
+    /// 0 never closes idle connections.
+    pub ipc_idle_timeout_mins: u64,
+    /// 0 closes them without a warning.
+    pub ipc_idle_warning_secs: u64,
+    pub ipc_heartbeat_secs: u64,
+    pub ipc_missed_heartbeats: u32,
+    pub ipc_max_frame_bytes: usize,
+    /// 0 keeps no sessions.
+    pub ipc_session_grace_secs: u64,
+    /// 0 records no slow requests.
+    pub ipc_slow_request_ms: u64,
+    /// Most preferred first; empty compresses nothing.
+    pub ipc_compression: Vec<Compression>,
+    pub ipc_compression_min_bytes: usize,
+    /// The platform default when unset.
+    pub data_dir: Option<PathBuf>,
+    /// A `tracing` filter; `RUST_LOG` wins over it.
+    pub log_level: String,
+    pub log_file: LogFileSettings,
+    pub ipc_audit: IpcAuditSettings,
+    /// Localhost port for `/metrics` and `/healthz`; off when unset.
+    pub metrics_port: Option<u16>,
+    pub mesh: MeshSettings,
+    pub presence: PresenceSettings,
+    pub finance: FinanceSettings,
+    pub wasm: WasmSettings,
+    pub rate_limits: RateLimitSettings,
+    pub core: CoreSettings,
+    pub pools: PoolSettings,
+    pub health: HealthSettings,
+    pub tcp: TcpSettings,
+    pub access: AccessSettings,
+    pub replication: ReplicationSettings,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogFileSettings {
+    pub enabled: bool,
+    pub max_bytes: u64,
+    /// 0 never starts a new file by age.
+    pub max_age_hours: u64,
+    pub keep: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpcAuditSettings {
+    pub enabled: bool,
+    pub max_bytes: u64,
+    pub keep: u32,
+    pub queue: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MeshSettings {
+    /// `keys/swarm.key` in the data directory when unset.
+    pub psk_path: Option<PathBuf>,
+    pub listen_addrs: Vec<String>,
+    pub bootstrap_peers: Vec<String>,
+    pub topics: Vec<String>,
+    pub warmup_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PresenceSettings {
+    pub enabled: bool,
+    pub interval_secs: u64,
+    pub stale_after_secs: u64,
+    /// 0 forgets stale nodes at once.
+    pub forget_after_hours: u64,
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FinanceSettings {
+    /// Tried in order until one connects.
+    pub electrum_urls: Vec<String>,
+    pub network: String,
+    pub developer_address: String,
+    pub required_sats: u64,
+    pub min_confirmations: u32,
+    pub recheck_hours: u64,
+    pub offline_grace_hours: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WasmSettings {
+    /// `modules/` in the data directory when unset.
+    pub module_store: Option<PathBuf>,
+    pub default_fuel_limit: u64,
+    pub max_fuel_limit: u64,
+    pub default_memory_bytes: usize,
+    pub max_memory_bytes: usize,
+    pub max_concurrent_executions: usize,
+    pub max_executions_per_source: usize,
+    pub run_dirs: Vec<PathBuf>,
+    pub max_module_bytes: u64,
+    /// `off`, `enforce` or `allow-all`.
+    pub allowlist: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitSettings {
+    pub requests_per_second: u32,
+    pub burst: u32,
+    pub verify_license_per_minute: u32,
+    pub run_wasm_per_minute: u32,
+    pub core_import_per_minute: u32,
+    /// 0 never closes a connection for being refused.
+    pub close_after_rejections: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoreSettings {
+    /// `sqlite`, `rocksdb` or `mem`.
+    pub backend: String,
+    /// Where a durable backend keeps its store, if not in the data directory.
+    pub path: Option<PathBuf>,
+    /// Namespace by principal, `token:<name>` or `uid:<uid>`.
+    pub namespaces: BTreeMap<String, String>,
+    pub audit: CoreAuditSettings,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoreAuditSettings {
+    /// `off`, `file` or `relation`.
+    pub sink: String,
+    pub query_text: bool,
+    pub param_values: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolSettings {
+    pub finance_threads: usize,
+    pub finance_queue: usize,
+    pub compute_threads: usize,
+    pub compute_queue: usize,
+}
+
+/// Limits behind `NodeStatus::health`; 0 turns one off.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthSettings {
+    pub rss_degraded_mb: u64,
+    pub rss_critical_mb: u64,
+    pub cpu_degraded_percent: f64,
+    pub disk_free_degraded_mb: u64,
+    pub disk_free_critical_mb: u64,
+    pub core_size_degraded_mb: u64,
+    pub wasm_queue_degraded: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpSettings {
+    /// An address and port; no TCP listener when unset.
+    pub listen: Option<String>,
+    /// A generated self-signed pair in `keys/` when unset.
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+    pub tls: bool,
+    /// Required to turn `tls` off.
+    pub i_know_this_is_insecure: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicationSettings {
+    /// None replicates nothing.
+    pub relations: Vec<String>,
+    pub namespace: String,
+    pub outbox_high_water: u64,
+}
+
+/// Permission sets by token and by user, named as in `Permission::name`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessSettings {
+    pub default: Vec<String>,
+    pub tokens: Vec<TokenRule>,
+    pub users: Vec<UserRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenRule {
+    pub token: String,
+    /// Core grants name the token's principal as `token:<name>`.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserRule {
+    pub uid: u32,
+    pub permissions: Vec<String>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            ipc_endpoint: None,
+            ipc_allowed_uids: Vec::new(),
+            ipc_allowed_gids: Vec::new(),
+            ipc_max_connections: 256,
+            ipc_idle_timeout_mins: 10,
+            ipc_idle_warning_secs: 30,
+            ipc_heartbeat_secs: 15,
+            ipc_missed_heartbeats: 3,
+            ipc_max_frame_bytes: DEFAULT_MAX_FRAME_SIZE,
+            ipc_session_grace_secs: 60,
+            ipc_slow_request_ms: 1000,
+            ipc_compression: Compression::ALL.to_vec(),
+            ipc_compression_min_bytes: 8 * 1024,
+            data_dir: None,
+            log_level: "info".into(),
+            log_file: LogFileSettings::default(),
+            ipc_audit: IpcAuditSettings::default(),
+            metrics_port: None,
+            mesh: MeshSettings::default(),
+            presence: PresenceSettings::default(),
+            finance: FinanceSettings::default(),
+            wasm: WasmSettings::default(),
+            rate_limits: RateLimitSettings::default(),
+            core: CoreSettings::default(),
+            pools: PoolSettings::default(),
+            health: HealthSettings::default(),
+            tcp: TcpSettings::default(),
+            access: AccessSettings::default(),
+            replication: ReplicationSettings::default(),
+        }
+    }
+}
+
+impl Default for LogFileSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_bytes: 16 * 1024 * 1024,
+            max_age_hours: 24,
+            keep: 4,
+        }
+    }
+}
+
+impl Default for IpcAuditSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_bytes: 16 * 1024 * 1024,
+            keep: 4,
+            queue: 1024,
+        }
+    }
+}
+
+impl Default for MeshSettings {
+    fn default() -> Self {
+        let mesh = MeshConfig::default();
+        let warmup = MeshWarmup::default();
+        Self {
+            psk_path: None,
+            listen_addrs: mesh.listen_addrs.iter().map(Multiaddr::to_string).collect(),
+            bootstrap_peers: warmup.bootstrap_peers,
+            topics: warmup.topics,
+            warmup_timeout_secs: warmup.timeout.as_secs(),
+        }
+    }
+}
+
+impl Default for PresenceSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 30,
+            stale_after_secs: 90,
+            forget_after_hours: 24,
+            capabilities: Vec::new(),
+        }
+    }
+}
+
+impl Default for FinanceSettings {
+    fn default() -> Self {
+        Self {
+            electrum_urls: vec!["ssl://electrum.blockstream.info:50002".into()],
+            network: "bitcoin".into(),
+            developer_address: "bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh".into(),
+            required_sats: 50000,
+            min_confirmations: 0,
+            recheck_hours: 24,
+            offline_grace_hours: 168,
+        }
+    }
+}
+
+impl Default for WasmSettings {
+    fn default() -> Self {
+        let runtime = RuntimeConfig::default();
+        Self {
+            module_store: None,
+            default_fuel_limit: runtime.default_fuel_limit,
+            max_fuel_limit: runtime.max_fuel_limit,
+            default_memory_bytes: runtime.default_memory_bytes,
+            max_memory_bytes: runtime.max_memory_bytes,
+            max_concurrent_executions: runtime.max_concurrent_executions,
+            // Half the default slots, so a second client always finds one free.
+            max_executions_per_source: 4,
+            run_dirs: Vec::new(),
+            max_module_bytes: 64 * 1024 * 1024,
+            allowlist: "off".into(),
+        }
+    }
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        let limits = RateLimits::default();
+        let per_minute = |kind: &str| limits.per_minute.get(kind).copied().unwrap_or_default();
+        Self {
+            requests_per_second: limits.requests_per_sec,
+            burst: limits.burst,
+            verify_license_per_minute: per_minute("VerifyLicense"),
+            run_wasm_per_minute: per_minute("RunWasm"),
+            core_import_per_minute: per_minute("CoreImport"),
+            close_after_rejections: 0,
+        }
+    }
+}
+
+impl Default for CoreSettings {
+    fn default() -> Self {
+        Self {
+            backend: "sqlite".into(),
+            path: None,
+            namespaces: BTreeMap::new(),
+            audit: CoreAuditSettings::default(),
+        }
+    }
+}
+
+impl Default for CoreAuditSettings {
+    fn default() -> Self {
+        Self {
+            sink: "off".into(),
+            query_text: false,
+            param_values: false,
+        }
+    }
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        Self {
+            finance_threads: 2,
+            finance_queue: 8,
+            compute_threads: 8,
+            compute_queue: 64,
+        }
+    }
+}
+
+impl Default for HealthSettings {
+    fn default() -> Self {
+        let limits = HealthThresholds::default();
+        let mb = |bytes: u64| bytes / (1024 * 1024);
+        Self {
+            rss_degraded_mb: mb(limits.rss_degraded_bytes),
+            rss_critical_mb: mb(limits.rss_critical_bytes),
+            cpu_degraded_percent: limits.cpu_degraded_percent,
+            disk_free_degraded_mb: mb(limits.disk_free_degraded_bytes),
+            disk_free_critical_mb: mb(limits.disk_free_critical_bytes),
+            core_size_degraded_mb: mb(limits.core_size_degraded_bytes),
+            wasm_queue_degraded: limits.wasm_queue_degraded,
+        }
+    }
+}
+
+impl Default for TcpSettings {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            cert_path: None,
+            key_path: None,
+            tls: true,
+            i_know_this_is_insecure: false,
+        }
+    }
+}
+
+impl Default for ReplicationSettings {
+    fn default() -> Self {
+        let replication = ReplicationConfig::new(Vec::new());
+        Self {
+            relations: Vec::new(),
+            namespace: replication.namespace,
+            outbox_high_water: replication.outbox_high_water,
+        }
+    }
+}
+
+impl Default for AccessSettings {
+    fn default() -> Self {
+        Self {
+            default: vec!["all".into()],
+            tokens: Vec::new(),
+            users: Vec::new(),
+        }
+    }
+}
+
+impl NodeConfig {
+    /// Reads `path`, applies `SOVEREIGN_*` overrides and checks the result.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let mut config = Self::parse(&text).with_context(|| format!("Invalid config file {}", path.display()))?;
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parses a config file's text, defaulting whatever it leaves out.
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Writes the commented default config to `path` unless a file is
+    /// already there. True if it wrote one.
+    pub fn write_default(path: &Path) -> std::io::Result<bool> {
+        if path.exists() {
+            return Ok(false);
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, DEFAULT_CONFIG)?;
+        Ok(true)
+    }
+
+    /// Overrides settings with the `SOVEREIGN_*` variables that are set and
+    /// not empty.
+    pub fn apply_env(&mut self) -> anyhow::Result<()> {
+        let var = |name: &str| std::env::var(name).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+        let list = |value: String| value.split(',').map(str::trim).filter(|v| !v.is_empty()).map(str::to_string).collect();
+
+        if let Some(value) = var("SOVEREIGN_IPC") {
+            self.ipc_endpoint = Some(value);
+        }
+        if let Some(value) = var("SOVEREIGN_IPC_MAX_CONNECTIONS") {
+            self.ipc_max_connections = value.parse().with_context(|| format!("SOVEREIGN_IPC_MAX_CONNECTIONS must be a number, not '{}'", value))?;
+        }
+        if let Some(value) = var("SOVEREIGN_IPC_SLOW_REQUEST_MS") {
+            self.ipc_slow_request_ms = value.parse().with_context(|| format!("SOVEREIGN_IPC_SLOW_REQUEST_MS must be a number, not '{}'", value))?;
+        }
+        if let Some(value) = var("SOVEREIGN_IPC_COMPRESSION") {
+            self.ipc_compression = match value.as_str() {
+                "none" => Vec::new(),
+                _ => value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|v| !v.is_empty())
+                    .map(|name| Compression::from_name(name).with_context(|| format!("SOVEREIGN_IPC_COMPRESSION names an unknown compression '{}'", name)))
+                    .collect::<anyhow::Result<_>>()?,
+            };
+        }
+        if let Some(value) = var("SOVEREIGN_DATA_DIR") {
+            self.data_dir = Some(PathBuf::from(value));
+        }
+        if let Some(value) = var("SOVEREIGN_LOG") {
+            self.log_level = value;
+        }
+        if let Some(value) = var("SOVEREIGN_LOG_FILE") {
+            self.log_file.enabled = value == "1";
+        }
+        if let Some(value) = var("SOVEREIGN_IPC_AUDIT") {
+            self.ipc_audit.enabled = value == "1";
+        }
+        if let Some(value) = var("SOVEREIGN_METRICS_PORT") {
+            self.metrics_port = Some(value.parse().with_context(|| format!("SOVEREIGN_METRICS_PORT must be a port number, not '{}'", value))?);
+        }
+        if let Some(value) = var("SOVEREIGN_SWARM_KEY") {
+            self.mesh.psk_path = Some(PathBuf::from(value));
+        }
+        if let Some(value) = var("SOVEREIGN_MESH_LISTEN") {
+            self.mesh.listen_addrs = list(value);
+        }
+        if let Some(value) = var("SOVEREIGN_MESH_BOOTSTRAP") {
+            self.mesh.bootstrap_peers = list(value);
+        }
+        if let Some(value) = var("SOVEREIGN_MESH_TOPICS") {
+            self.mesh.topics = list(value);
+        }
+        if let Some(value) = var("SOVEREIGN_PRESENCE") {
+            self.presence.enabled = value == "1";
+        }
+        if let Some(value) = var("SOVEREIGN_ELECTRUM_URLS") {
+            self.finance.electrum_urls = list(value);
+        }
+        if let Some(value) = var("SOVEREIGN_BITCOIN_NETWORK") {
+            self.finance.network = value;
+        }
+        if let Some(value) = var("SOVEREIGN_DEVELOPER_ADDRESS") {
+            self.finance.developer_address = value;
+        }
+        if let Some(value) = var("SOVEREIGN_REQUIRED_SATS") {
+            self.finance.required_sats = value.parse().with_context(|| format!("SOVEREIGN_REQUIRED_SATS must be a number, not '{}'", value))?;
+        }
+        if let Some(value) = var("SOVEREIGN_MIN_CONFIRMATIONS") {
+            self.finance.min_confirmations = value.parse().with_context(|| format!("SOVEREIGN_MIN_CONFIRMATIONS must be a number, not '{}'", value))?;
+        }
+        if let Some(value) = var("SOVEREIGN_WASM_MODULES") {
+            self.wasm.module_store = Some(PathBuf::from(value));
+        }
+        if let Some(value) = var("SOVEREIGN_WASM_ALLOWLIST") {
+            self.wasm.allowlist = value;
+        }
+        if let Some(value) = var("SOVEREIGN_CORE_BACKEND") {
+            self.core.backend = value;
+        }
+        if let Some(value) = var("SOVEREIGN_CORE_PATH") {
+            self.core.path = Some(PathBuf::from(value));
+        }
+        if let Some(value) = var("SOVEREIGN_CORE_NAMESPACES") {
+            self.core.namespaces = BTreeMap::new();
+            for pair in list(value) {
+                let Some((principal, namespace)) = pair.split_once('=') else {
+                    bail!("SOVEREIGN_CORE_NAMESPACES entries must be principal=namespace, not '{}'", pair);
+                };
+                self.core.namespaces.insert(principal.trim().to_string(), namespace.trim().to_string());
+            }
+        }
+        if let Some(value) = var("SOVEREIGN_CORE_AUDIT") {
+            self.core.audit.sink = value;
+        }
+        if let Some(value) = var("SOVEREIGN_CORE_AUDIT_TEXT") {
+            self.core.audit.query_text = value == "1";
+        }
+        if let Some(value) = var("SOVEREIGN_CORE_AUDIT_PARAMS") {
+            self.core.audit.param_values = value == "1";
+        }
+        if let Some(value) = var("SOVEREIGN_REPLICATE") {
+            self.replication.relations = list(value);
+        }
+        if let Some(value) = var("SOVEREIGN_REPLICATION_NAMESPACE") {
+            self.replication.namespace = value;
+        }
+        if let Some(value) = var("SOVEREIGN_REPLICATION_OUTBOX_HIGH_WATER") {
+            self.replication.outbox_high_water =
+                value.parse().with_context(|| format!("SOVEREIGN_REPLICATION_OUTBOX_HIGH_WATER must be a number, not '{}'", value))?;
+        }
+        if let Some(value) = var("SOVEREIGN_TCP_LISTEN") {
+            self.tcp.listen = Some(value);
+        }
+        Ok(())
+    }
+
+    /// Fails on the first setting that is out of range, naming it.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.ipc_endpoint.as_deref().is_some_and(|e| e.trim().is_empty()) {
+            bail!("ipc_endpoint must not be empty");
+        }
+        if self.ipc_max_connections == 0 {
+            bail!("ipc_max_connections must be above 0");
+        }
+        if self.ipc_heartbeat_secs == 0 {
+            bail!("ipc_heartbeat_secs must be above 0");
+        }
+        if self.ipc_missed_heartbeats == 0 {
+            bail!("ipc_missed_heartbeats must be above 0");
+        }
+        // Room for a Hello with a token and a session to resume.
+        if self.ipc_max_frame_bytes < 1024 {
+            bail!("ipc_max_frame_bytes must be at least 1024");
+        }
+        if self.log_level.trim().is_empty() {
+            bail!("log_level must not be empty");
+        }
+        if self.log_file.max_bytes == 0 {
+            bail!("log_file.max_bytes must be above 0");
+        }
+        if self.ipc_audit.max_bytes == 0 {
+            bail!("ipc_audit.max_bytes must be above 0");
+        }
+        if self.ipc_audit.queue == 0 {
+            bail!("ipc_audit.queue must be above 0");
+        }
+        if self.metrics_port == Some(0) {
+            bail!("metrics_port must be above 0");
+        }
+        self.mesh_config(&DataDir::at(self.data_dir()))?;
+        if self.mesh.warmup_timeout_secs == 0 {
+            bail!("mesh.warmup_timeout_secs must be above 0");
+        }
+        if self.mesh.topics.iter().any(|t| t.trim().is_empty()) {
+            bail!("mesh.topics must not contain empty names");
+        }
+        if self.presence.interval_secs == 0 {
+            bail!("presence.interval_secs must be above 0");
+        }
+        if self.presence.stale_after_secs <= self.presence.interval_secs {
+            bail!("presence.stale_after_secs must be above presence.interval_secs");
+        }
+        if self.presence.capabilities.len() > 16 {
+            bail!("presence.capabilities may name at most 16 capabilities");
+        }
+        if let Some(capability) = self.presence.capabilities.iter().find(|c| c.trim().is_empty() || c.len() > 32) {
+            bail!("presence.capabilities must be names of 1 to 32 characters, not '{}'", capability);
+        }
+        if self.finance.electrum_urls.is_empty() {
+            bail!("finance.electrum_urls must name at least one server");
+        }
+        let network = self.network()?;
+        sovereign_finance::check_address(&self.finance.developer_address, network).context("finance.developer_address")?;
+        if self.finance.required_sats == 0 {
+            bail!("finance.required_sats must be above 0");
+        }
+        if self.finance.recheck_hours == 0 {
+            bail!("finance.recheck_hours must be above 0");
+        }
+        if self.wasm.default_fuel_limit > self.wasm.max_fuel_limit {
+            bail!("wasm.default_fuel_limit must not be above wasm.max_fuel_limit");
+        }
+        if self.wasm.default_memory_bytes > self.wasm.max_memory_bytes {
+            bail!("wasm.default_memory_bytes must not be above wasm.max_memory_bytes");
+        }
+        if self.wasm.max_concurrent_executions == 0 {
+            bail!("wasm.max_concurrent_executions must be above 0");
+        }
+        if self.wasm.max_module_bytes == 0 {
+            bail!("wasm.max_module_bytes must be above 0");
+        }
+        if !matches!(self.wasm.allowlist.as_str(), "off" | "enforce" | "allow-all") {
+            bail!("wasm.allowlist must be off, enforce or allow-all, not '{}'", self.wasm.allowlist);
+        }
+        let limits = &self.rate_limits;
+        for (name, value) in [
+            ("requests_per_second", limits.requests_per_second),
+            ("burst", limits.burst),
+            ("verify_license_per_minute", limits.verify_license_per_minute),
+            ("run_wasm_per_minute", limits.run_wasm_per_minute),
+            ("core_import_per_minute", limits.core_import_per_minute),
+        ] {
+            if value == 0 {
+                bail!("rate_limits.{} must be above 0", name);
+            }
+        }
+        if !matches!(self.core.backend.as_str(), "sqlite" | "rocksdb" | "mem") {
+            bail!("core.backend must be sqlite, rocksdb or mem, not '{}'", self.core.backend);
+        }
+        for (principal, namespace) in &self.core.namespaces {
+            let known = match principal.split_once(':') {
+                Some(("token", name)) => !name.is_empty(),
+                Some(("uid", uid)) => uid.parse::<u32>().is_ok(),
+                _ => false,
+            };
+            if !known {
+                bail!("core.namespaces must name principals as token:<name> or uid:<uid>, not '{}'", principal);
+            }
+            if namespace.is_empty() {
+                bail!("core.namespaces must give '{}' a namespace", principal);
+            }
+        }
+        if !matches!(self.core.audit.sink.as_str(), "off" | "file" | "relation") {
+            bail!("core.audit.sink must be off, file or relation, not '{}'", self.core.audit.sink);
+        }
+        if self.replication.relations.iter().any(|r| r.trim().is_empty()) {
+            bail!("replication.relations must not contain empty names");
+        }
+        if self.replication.namespace.trim().is_empty() {
+            bail!("replication.namespace must not be empty");
+        }
+        if self.replication.outbox_high_water == 0 {
+            bail!("replication.outbox_high_water must be above 0");
+        }
+        if self.pools.finance_threads == 0 {
+            bail!("pools.finance_threads must be above 0");
+        }
+        if self.pools.compute_threads == 0 {
+            bail!("pools.compute_threads must be above 0");
+        }
+        let health = &self.health;
+        if !(0.0..=100.0).contains(&health.cpu_degraded_percent) {
+            bail!("health.cpu_degraded_percent must be between 0 and 100");
+        }
+        if health.rss_critical_mb > 0 && health.rss_degraded_mb > health.rss_critical_mb {
+            bail!("health.rss_degraded_mb must not be above health.rss_critical_mb");
+        }
+        if health.disk_free_degraded_mb > 0 && health.disk_free_critical_mb > health.disk_free_degraded_mb {
+            bail!("health.disk_free_critical_mb must not be above health.disk_free_degraded_mb");
+        }
+        if let Some(listen) = &self.tcp.listen {
+            if listen.trim().parse::<SocketAddr>().is_err() {
+                bail!("tcp.listen must be an address and port, such as 0.0.0.0:7443, not '{}'", listen);
+            }
+            if self.access.tokens.is_empty() {
+                bail!("tcp.listen is set, but access.tokens is empty: TCP clients must present a token");
+            }
+        }
+        if self.tcp.cert_path.is_some() != self.tcp.key_path.is_some() {
+            bail!("tcp.cert_path and tcp.key_path must be set together");
+        }
+        if !self.tcp.tls && !self.tcp.i_know_this_is_insecure {
+            bail!("tcp.tls = false sends tokens and data in the clear; it also takes tcp.i_know_this_is_insecure = true");
+        }
+        self.access_policy()?;
+        Ok(())
+    }
+
+    pub fn endpoint(&self) -> IpcEndpoint {
+        match &self.ipc_endpoint {
+            Some(endpoint) => IpcEndpoint::parse(endpoint.trim()),
+            None => IpcEndpoint::default_for_platform(),
+        }
+    }
+
+    /// The TCP listener, if `tcp.listen` is set. Certificates go in
+    /// `data_dir` unless configured elsewhere.
+    pub fn tcp(&self, data_dir: &DataDir) -> Option<TcpConfig> {
+        let addr = self.tcp.listen.as_deref()?.trim().parse().ok()?;
+        let security = match (&self.tcp.cert_path, &self.tcp.key_path) {
+            _ if !self.tcp.tls => TcpSecurity::Plaintext,
+            (Some(cert), Some(key)) => TcpSecurity::Tls {
+                cert: cert.clone(),
+                key: key.clone(),
+                generate: false,
+                fingerprint: data_dir.tls_fingerprint(),
+            },
+            _ => TcpSecurity::Tls {
+                cert: data_dir.tls_cert(),
+                key: data_dir.tls_key(),
+                generate: true,
+                fingerprint: data_dir.tls_fingerprint(),
+            },
+        };
+        Some(TcpConfig { addr, security })
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.ipc_idle_timeout_mins.saturating_mul(60))).filter(|t| !t.is_zero())
+    }
+
+    pub fn idle_warning(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.ipc_idle_warning_secs)).filter(|t| !t.is_zero())
+    }
+
+    pub fn slow_request_threshold(&self) -> Option<Duration> {
+        Some(Duration::from_millis(self.ipc_slow_request_ms)).filter(|t| !t.is_zero())
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.ipc_heartbeat_secs)
+    }
+
+    pub fn session_grace(&self) -> Duration {
+        Duration::from_secs(self.ipc_session_grace_secs)
+    }
+
+    pub fn peer_policy(&self) -> PeerPolicy {
+        PeerPolicy {
+            allowed_uids: self.ipc_allowed_uids.clone(),
+            allowed_gids: self.ipc_allowed_gids.clone(),
+        }
+    }
+
+    pub fn data_dir(&self) -> PathBuf {
+        self.data_dir_or(sovereign_protocol::default_data_dir)
+    }
+
+    /// `data_dir` if the file or `SOVEREIGN_DATA_DIR` sets it, else `default`.
+    pub fn data_dir_or(&self, default: impl FnOnce() -> PathBuf) -> PathBuf {
+        self.data_dir.clone().unwrap_or_else(default)
+    }
+
+    /// `logs/node.log` in `data_dir`, if `log_file.enabled`.
+    pub fn file_log(&self, data_dir: &DataDir) -> Option<FileLog> {
+        let settings = &self.log_file;
+        settings.enabled.then(|| FileLog {
+            path: data_dir.node_log(),
+            max_bytes: settings.max_bytes,
+            max_age: Some(Duration::from_secs(settings.max_age_hours.saturating_mul(3600))).filter(|age| !age.is_zero()),
+            keep: settings.keep,
+        })
+    }
+
+    /// `logs/ipc-audit.jsonl` in `data_dir`, if `ipc_audit.enabled`.
+    pub fn ipc_audit(&self, data_dir: &DataDir) -> Option<IpcAuditConfig> {
+        let settings = &self.ipc_audit;
+        settings.enabled.then(|| IpcAuditConfig {
+            file: FileLog {
+                path: data_dir.ipc_audit_log(),
+                max_bytes: settings.max_bytes,
+                max_age: None,
+                keep: settings.keep,
+            },
+            queue: settings.queue,
+        })
+    }
+
+    pub fn mesh_config(&self, data_dir: &DataDir) -> anyhow::Result<MeshConfig> {
+        let parse = |field: &str, addrs: &[String]| {
+            addrs
+                .iter()
+                .map(|a| Multiaddr::from_str(a).with_context(|| format!("mesh.{} has an invalid address '{}'", field, a)))
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        let listen_addrs = parse("listen_addrs", &self.mesh.listen_addrs)?;
+        if listen_addrs.is_empty() {
+            bail!("mesh.listen_addrs must name at least one address");
+        }
+        parse("bootstrap_peers", &self.mesh.bootstrap_peers)?;
+        Ok(MeshConfig {
+            psk_path: self.psk_path(data_dir),
+            listen_addrs,
+        })
+    }
+
+    pub fn psk_path(&self, data_dir: &DataDir) -> PathBuf {
+        self.mesh.psk_path.clone().unwrap_or_else(|| data_dir.swarm_key())
+    }
+
+    pub fn mesh_warmup(&self) -> MeshWarmup {
+        MeshWarmup {
+            bootstrap_peers: self.mesh.bootstrap_peers.clone(),
+            topics: self.mesh.topics.clone(),
+            timeout: Duration::from_secs(self.mesh.warmup_timeout_secs),
+        }
+    }
+
+    /// The presence beacons' settings, if `presence.enabled`.
+    pub fn presence(&self) -> Option<PresenceConfig> {
+        let settings = &self.presence;
+        settings.enabled.then(|| PresenceConfig {
+            interval: Duration::from_secs(settings.interval_secs),
+            stale_after: Duration::from_secs(settings.stale_after_secs),
+            forget_after: Duration::from_secs(settings.forget_after_hours * 3600),
+            capabilities: settings.capabilities.clone(),
+        })
+    }
+
+    /// What `finance.network` may be.
+    pub const NETWORKS: &'static [&'static str] = &["bitcoin", "testnet", "signet", "regtest"];
+
+    fn network(&self) -> anyhow::Result<Network> {
+        Network::from_str(&self.finance.network)
+            .map_err(|_| anyhow::anyhow!("finance.network must be bitcoin, testnet, signet or regtest, not '{}'", self.finance.network))
+    }
+
+    pub fn license_policy(&self) -> LicensePolicy {
+        LicensePolicy {
+            min_confirmations: self.finance.min_confirmations,
+            ..LicensePolicy::for_required_sats(self.finance.required_sats)
+        }
+    }
+
+    /// Connects to the first Electrum server that answers.
+    pub fn license_verifier(&self) -> anyhow::Result<LicenseVerifier> {
+        let finance = &self.finance;
+        let policy = self.license_policy();
+        let mut failure = None;
+        for url in &finance.electrum_urls {
+            match LicenseVerifier::with_policy(url, &finance.developer_address, finance.required_sats, policy.clone()) {
+                Ok(verifier) => return Ok(verifier),
+                Err(e) => {
+                    tracing::warn!("Electrum server {} is unusable: {:#}", url, e);
+                    failure = Some(e);
+                }
+            }
+        }
+        Err(failure.unwrap_or_else(|| anyhow::anyhow!("No Electrum server configured")))
+    }
+
+    pub fn license_recheck(&self) -> LicenseRecheck {
+        let hours = |h: u64| Duration::from_secs(h.saturating_mul(3600));
+        LicenseRecheck {
+            interval: hours(self.finance.recheck_hours),
+            offline_grace: hours(self.finance.offline_grace_hours),
+        }
+    }
+
+    pub fn finance_pool(&self) -> PoolConfig {
+        PoolConfig {
+            threads: self.pools.finance_threads,
+            queue: self.pools.finance_queue,
+        }
+    }
+
+    pub fn compute_pool(&self) -> PoolConfig {
+        PoolConfig {
+            threads: self.pools.compute_threads,
+            queue: self.pools.compute_queue,
+        }
+    }
+
+    pub fn rate_limits(&self) -> RateLimits {
+        let limits = &self.rate_limits;
+        RateLimits {
+            requests_per_sec: limits.requests_per_second,
+            burst: limits.burst,
+            per_minute: [
+                ("VerifyLicense", limits.verify_license_per_minute),
+                ("RunWasm", limits.run_wasm_per_minute),
+                ("CoreImport", limits.core_import_per_minute),
+            ]
+            .into(),
+            close_after: Some(limits.close_after_rejections).filter(|n| *n > 0),
+        }
+    }
+
+    /// The WASM runtime's settings, with its cache under `data_dir`.
+    pub fn runtime_config(&self, data_dir: &DataDir) -> RuntimeConfig {
+        RuntimeConfig {
+            cache_dir: Some(data_dir.wasm_cache()),
+            default_fuel_limit: self.wasm.default_fuel_limit,
+            max_fuel_limit: self.wasm.max_fuel_limit,
+            default_memory_bytes: self.wasm.default_memory_bytes,
+            max_memory_bytes: self.wasm.max_memory_bytes,
+            max_concurrent_executions: self.wasm.max_concurrent_executions,
+            max_executions_per_source: self.wasm.max_executions_per_source,
+            allowlist: self.allowlist(data_dir),
+            ..Default::default()
+        }
+    }
+
+    /// The WASM allow-list, unless `wasm.allowlist` is off.
+    fn allowlist(&self, data_dir: &DataDir) -> Option<AllowlistConfig> {
+        let mode = match self.wasm.allowlist.as_str() {
+            "enforce" => AllowlistMode::Enforce,
+            "allow-all" => AllowlistMode::AllowAll,
+            _ => return None,
+        };
+        Some(AllowlistConfig {
+            path: data_dir.wasm_allowlist(),
+            mode,
+        })
+    }
+
+    /// The core query audit, unless `core.audit.sink` is off.
+    pub fn core_audit(&self, data_dir: &DataDir) -> Option<AuditConfig> {
+        let settings = &self.core.audit;
+        let sink = match settings.sink.as_str() {
+            "file" => AuditSink::File {
+                path: data_dir.core_audit_log(),
+                max_bytes: 16 * 1024 * 1024,
+                keep: 4,
+            },
+            "relation" => AuditSink::Relation { max_entries: 10_000 },
+            _ => return None,
+        };
+        Some(AuditConfig {
+            sink,
+            redaction: AuditRedaction {
+                full_text: settings.query_text,
+                param_values: settings.param_values,
+            },
+        })
+    }
+
+    /// The relations to replicate, if `replication.relations` names any.
+    pub fn replication(&self) -> Option<ReplicationConfig> {
+        let settings = &self.replication;
+        if settings.relations.is_empty() {
+            return None;
+        }
+        let mut config = ReplicationConfig::new(settings.relations.iter().map(|r| r.trim().to_string()).collect());
+        config.namespace = settings.namespace.trim().to_string();
+        config.outbox_high_water = settings.outbox_high_water;
+        Some(config)
+    }
+
+    pub fn health_thresholds(&self) -> HealthThresholds {
+        let health = &self.health;
+        let bytes = |mb: u64| mb.saturating_mul(1024 * 1024);
+        HealthThresholds {
+            rss_degraded_bytes: bytes(health.rss_degraded_mb),
+            rss_critical_bytes: bytes(health.rss_critical_mb),
+            cpu_degraded_percent: health.cpu_degraded_percent,
+            disk_free_degraded_bytes: bytes(health.disk_free_degraded_mb),
+            disk_free_critical_bytes: bytes(health.disk_free_critical_mb),
+            core_size_degraded_bytes: bytes(health.core_size_degraded_mb),
+            wasm_queue_degraded: health.wasm_queue_degraded,
+        }
+    }
+
+    pub fn access_policy(&self) -> anyhow::Result<AccessPolicy> {
+        let access = &self.access;
+        let mut policy = AccessPolicy::default();
+        policy.default = permission_set(&access.default).context("access.default")?;
+        for (i, rule) in access.tokens.iter().enumerate() {
+            if rule.token.trim().len() < 16 {
+                bail!("access.tokens[{}].token must be at least 16 characters", i);
+            }
+            if let Some(name) = &rule.name {
+                if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) {
+                    bail!("access.tokens[{}].name must be letters, digits, '-', '_' or '.'", i);
+                }
+                if access.tokens[..i].iter().any(|other| other.name.as_ref() == Some(name)) {
+                    bail!("access.tokens names '{}' twice", name);
+                }
+            }
+            let permissions = permission_set(&rule.permissions).with_context(|| format!("access.tokens[{}].permissions", i))?;
+            policy.add_token(&rule.token, rule.name.as_deref(), permissions);
+        }
+        for rule in &access.users {
+            let permissions = permission_set(&rule.permissions).with_context(|| format!("access.users uid {}", rule.uid))?;
+            if policy.users.insert(rule.uid, permissions).is_some() {
+                bail!("access.users names uid {} twice", rule.uid);
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn module_store(&self, data_dir: &DataDir) -> PathBuf {
+        self.wasm.module_store.clone().unwrap_or_else(|| data_dir.wasm_store())
+    }
+
+    /// Where `RunWasm` may read modules from: the module store, then
+    /// `wasm.run_dirs`.
+    pub fn module_paths(&self, data_dir: &DataDir) -> ModulePaths {
+        ModulePaths {
+            roots: std::iter::once(self.module_store(data_dir)).chain(self.wasm.run_dirs.iter().cloned()).collect(),
+            max_bytes: self.wasm.max_module_bytes,
+        }
+    }
+}
+
+/// `names` as permissions, with `all` standing for every one.
+fn permission_set(names: &[String]) -> anyhow::Result<PermissionSet> {
+    let mut permissions = Vec::new();
+    for name in names {
+        match Permission::ALL.into_iter().find(|p| p.name() == name) {
+            Some(permission) => permissions.push(permission),
+            None if name == "all" => permissions.extend(Permission::ALL),
+            None => bail!("unknown permission '{}'", name),
+        }
+    }
+    Ok(permissions.into_iter().collect())
+}
+
+/// Where the node looks for its config without `--config`.
+///
+/// Unix: `$XDG_CONFIG_HOME/sovereign/node.toml` or `~/.config/sovereign/node.toml`.
+/// Windows: `%APPDATA%\Sovereign\node.toml`.
+pub fn default_config_path() -> PathBuf {
+    #[cfg(windows)]
+    {
+        let base = std::env::var_os("APPDATA").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("."));
+        base.join("Sovereign").join("node.toml")
+    }
+    #[cfg(not(windows))]
+    {
+        let base = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => std::env::var_os("HOME").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(".")).join(".config"),
+        };
+        base.join("sovereign").join("node.toml")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validated(text: &str) -> anyhow::Result<NodeConfig> {
+        let config = NodeConfig::parse(text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn the_default_file_reads_as_the_defaults() {
+        let written = validated(DEFAULT_CONFIG).unwrap();
+        let default = validated("").unwrap();
+        assert_eq!(format!("{:?}", written), format!("{:?}", default));
+        assert_eq!(default.ipc_max_connections, 256);
+        assert_eq!(default.heartbeat_interval(), Duration::from_secs(15));
+    }
+
+    #[test]
+    fn unknown_and_mistyped_settings_are_refused() {
+        for text in [
+            "ipc_max_conections = 3",
+            "[mesh]\nwarmup_timeout = 3",
+            "[tcp]\ntls_off = true",
+            "ipc_max_connections = \"many\"",
+            "ipc_max_connections = -1",
+            "ipc_compression = [\"lz4\"]",
+            "[finance]\nelectrum_urls = \"tcp://one\"",
+        ] {
+            assert!(NodeConfig::parse(text).is_err(), "{:?} was accepted", text);
+        }
+    }
+
+    #[test]
+    fn out_of_range_settings_are_named() {
+        let token = "[[access.tokens]]\ntoken = \"0123456789abcdef\"\npermissions = [\"all\"]\n";
+        for (text, expected) in [
+            ("ipc_endpoint = \"  \"", "ipc_endpoint must not be empty"),
+            ("ipc_max_connections = 0", "ipc_max_connections must be above 0"),
+            ("ipc_heartbeat_secs = 0", "ipc_heartbeat_secs must be above 0"),
+            ("ipc_missed_heartbeats = 0", "ipc_missed_heartbeats must be above 0"),
+            ("ipc_max_frame_bytes = 1023", "ipc_max_frame_bytes must be at least 1024"),
+            ("log_level = \"\"", "log_level must not be empty"),
+            ("[log_file]\nmax_bytes = 0", "log_file.max_bytes must be above 0"),
+            ("[ipc_audit]\nqueue = 0", "ipc_audit.queue must be above 0"),
+            ("metrics_port = 0", "metrics_port must be above 0"),
+            ("[mesh]\ntopics = [\"a\", \"\"]", "mesh.topics must not contain empty names"),
+            ("[presence]\ninterval_secs = 30\nstale_after_secs = 30", "presence.stale_after_secs must be above"),
+            ("[presence]\ncapabilities = [\"has space in it and is far too long\"]", "presence.capabilities must be names"),
+            ("[finance]\nelectrum_urls = []", "finance.electrum_urls must name at least one server"),
+            ("[finance]\nrequired_sats = 0", "finance.required_sats must be above 0"),
+            ("[wasm]\ndefault_fuel_limit = 10\nmax_fuel_limit = 9", "wasm.default_fuel_limit must not be above"),
+            ("[wasm]\nmax_concurrent_executions = 0", "wasm.max_concurrent_executions must be above 0"),
+            ("[rate_limits]\nburst = 0", "rate_limits.burst must be above 0"),
+            ("[core]\nbackend = \"postgres\"", "core.backend must be sqlite, rocksdb or mem, not 'postgres'"),
+            ("[core.namespaces]\nreporting = \"reports\"", "core.namespaces must name principals as token:<name> or uid:<uid>, not 'reporting'"),
+            ("[core.namespaces]\n\"uid:7\" = \"\"", "core.namespaces must give 'uid:7' a namespace"),
+            ("[core.audit]\nsink = \"syslog\"", "core.audit.sink must be off, file or relation, not 'syslog'"),
+            ("[wasm]\nallowlist = \"strict\"", "wasm.allowlist must be off, enforce or allow-all, not 'strict'"),
+            ("[replication]\nnamespace = \"\"", "replication.namespace must not be empty"),
+            ("[replication]\noutbox_high_water = 0", "replication.outbox_high_water must be above 0"),
+            ("[pools]\ncompute_threads = 0", "pools.compute_threads must be above 0"),
+            ("[health]\ncpu_degraded_percent = 101.0", "health.cpu_degraded_percent must be between 0 and 100"),
+            ("[health]\nrss_degraded_mb = 10\nrss_critical_mb = 5", "health.rss_degraded_mb must not be above"),
+            ("[tcp]\nlisten = \"nowhere\"", "tcp.listen must be an address and port"),
+            ("[tcp]\nlisten = \"127.0.0.1:7443\"", "access.tokens is empty"),
+            ("[tcp]\ncert_path = \"cert.pem\"", "tcp.cert_path and tcp.key_path must be set together"),
+            ("[tcp]\ntls = false", "it also takes tcp.i_know_this_is_insecure = true"),
+            ("[access]\ndefault = [\"fly\"]", "unknown permission 'fly'"),
+            ("[[access.tokens]]\ntoken = \"short\"\npermissions = []", "access.tokens[0].token must be at least 16 characters"),
+            (&format!("{}name = \"a b\"", token), "access.tokens[0].name must be letters"),
+            (&format!("{0}name = \"ci\"\n{0}name = \"ci\"", token), "access.tokens names 'ci' twice"),
+            ("[[access.users]]\nuid = 7\npermissions = []\n[[access.users]]\nuid = 7\npermissions = []", "access.users names uid 7 twice"),
+        ] {
+            let error = validated(text).map(|_| ()).unwrap_err();
+            assert!(format!("{:#}", error).contains(expected), "{:?}: {:#}", text, error);
+        }
+        validated(&format!("{}\n[tcp]\nlisten = \"127.0.0.1:7443\"", token)).unwrap();
+        validated("[tcp]\ntls = false\ni_know_this_is_insecure = true").unwrap();
+    }
+
+    #[test]
+    fn replication_and_core_settings_come_from_the_file() {
+        assert!(validated("").unwrap().replication().is_none());
+        let config = validated(
+            "[replication]\nrelations = [\"notes\", \"tags\"]\nnamespace = \"lab\"\noutbox_high_water = 50\n\
+             [core.namespaces]\n\"token:reporting\" = \"reports\"\n[core.audit]\nsink = \"relation\"\nquery_text = true\n",
+        )
+        .unwrap();
+        let replication = config.replication().unwrap();
+        assert_eq!((replication.relations, replication.namespace.as_str()), (vec!["notes".to_string(), "tags".to_string()], "lab"));
+        assert_eq!(replication.outbox_high_water, 50);
+        assert_eq!(config.core.namespaces.get("token:reporting").map(String::as_str), Some("reports"));
+        let audit = config.core_audit(&DataDir::at("/d")).unwrap();
+        assert!(matches!(audit.sink, AuditSink::Relation { max_entries: 10_000 }));
+        assert!(audit.redaction.full_text && !audit.redaction.param_values);
+    }
+
+    #[test]
+    fn the_default_file_is_written_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sovereign").join("node.toml");
+        assert!(NodeConfig::write_default(&path).unwrap());
+        std::fs::write(&path, "ipc_max_connections = 3\n").unwrap();
+        assert!(!NodeConfig::write_default(&path).unwrap());
+        assert_eq!(NodeConfig::parse(&std::fs::read_to_string(&path).unwrap()).unwrap().ipc_max_connections, 3);
+
+        std::fs::write(&path, "ipc_max_connections = 0\n").unwrap();
+        assert!(format!("{:#}", NodeConfig::load(&path).unwrap_err()).contains("ipc_max_connections"));
+        std::fs::write(&path, "ipc_max_connections = [\n").unwrap();
+        assert!(format!("{:#}", NodeConfig::load(&path).unwrap_err()).contains("Invalid config file"));
+    }
+}
@@ -0,0 +1,126 @@
+use sovereign_core::CancelToken;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Core query ids start here so `Cancel` can tell them from WASM execution
+/// ids, which count up from 1.
+const FIRST_QUERY_ID: u64 = 1 << 48;
+
+/// Longest query text kept for listing.
+const MAX_LISTED_QUERY: usize = 200;
+
+struct Entry {
+    cancel: CancelToken,
+    query: String,
+    started: Instant,
+}
+
+/// In-flight `QueryCore` requests, so `Cancel` can stop one from another
+/// connection.
+pub struct CoreQueries {
+    next_id: AtomicU64,
+    running: Arc<Mutex<HashMap<u64, Entry>>>,
+}
+
+/// A registered query; dropping it unregisters the query, and cancels it if
+/// it is still running, as when its request is abandoned.
+pub struct RunningQuery {
+    id: u64,
+    cancel: CancelToken,
+    running: Arc<Mutex<HashMap<u64, Entry>>>,
+}
+
+/// A running query, as listed by `CoreQueries::list`.
+pub struct QuerySummary {
+    pub id: u64,
+    pub query: String,
+    pub running_for: Duration,
+}
+
+impl Default for CoreQueries {
+    fn default() -> Self {
+        Self {
+            next_id: AtomicU64::new(FIRST_QUERY_ID),
+            running: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl CoreQueries {
+    pub fn start(&self, query: &str) -> RunningQuery {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let cancel = CancelToken::new();
+        let query = match query.char_indices().nth(MAX_LISTED_QUERY) {
+            Some((end, _)) => format!("{}...", &query[..end]),
+            None => query.to_string(),
+        };
+        self.running.lock().unwrap_or_else(|e| e.into_inner()).insert(
+            id,
+            Entry {
+                cancel: cancel.clone(),
+                query,
+                started: Instant::now(),
+            },
+        );
+        RunningQuery {
+            id,
+            cancel,
+            running: self.running.clone(),
+        }
+    }
+
+    /// Returns false if no query with that id is running.
+    pub fn cancel(&self, id: u64) -> bool {
+        match self.running.lock().unwrap_or_else(|e| e.into_inner()).get(&id) {
+            Some(entry) => {
+                entry.cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn list(&self) -> Vec<QuerySummary> {
+        let mut list: Vec<QuerySummary> = self
+            .running
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(id, entry)| QuerySummary {
+                id: *id,
+                query: entry.query.clone(),
+                running_for: entry.started.elapsed(),
+            })
+            .collect();
+        list.sort_by_key(|q| q.id);
+        list
+    }
+
+    /// Poisons the lock running queries are kept under, as a thread that
+    /// panicked holding it would. Panics must unwind, as they do outside
+    /// release builds.
+    #[cfg(feature = "fault-injection")]
+    pub fn poison(&self) {
+        let running = self.running.clone();
+        let _ = std::thread::spawn(move || {
+            let _held = running.lock();
+            panic!("Injected fault: poisoning the running-query lock");
+        })
+        .join();
+    }
+}
+
+impl RunningQuery {
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+}
+
+impl Drop for RunningQuery {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        self.running.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.id);
+    }
+}
@@ -0,0 +1,388 @@
+use crate::blocking_pool::{BlockingPool, PoolError};
+use crate::core_queries::CoreQueries;
+use crate::service_loop::{core_failed, Caller};
+use sovereign_core::{CognitiveCore, CoreError, CoreTransaction};
+use sovereign_protocol::{Request, Response};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// Ids are unique node-wide so a leaked id never aliases another connection's session.
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Shared with the compute pool while a statement runs; taken to end it.
+type SharedTransaction = Arc<Mutex<Option<CoreTransaction>>>;
+
+struct OpenSession {
+    tx: SharedTransaction,
+    last_used: Instant,
+}
+
+/// Core transactions opened by a single IPC connection.
+///
+/// The map lives inside the connection task, so sessions are unreachable from
+/// other connections and are rolled back when the connection goes away.
+/// Their work runs on the compute pool, and each statement and commit is
+/// registered with `CoreQueries`: a statement that is cancelled, or whose
+/// request is given up on, fails its transaction. A commit is waited for,
+/// since one given up on would leave its outcome unknown.
+pub struct CoreSessions {
+    open: HashMap<u64, OpenSession>,
+    idle_timeout: Duration,
+}
+
+impl CoreSessions {
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            open: HashMap::new(),
+            idle_timeout,
+        }
+    }
+
+    /// `namespace` confines the sessions' statements, and the client's
+    /// principal holds them to its grants, as for its other core requests.
+    pub async fn handle(
+        &mut self,
+        compute: &BlockingPool,
+        core: &Arc<CognitiveCore>,
+        queries: &CoreQueries,
+        req: Request,
+        client: &Caller,
+        namespace: Option<&str>,
+    ) -> Response {
+        match req {
+            Request::CoreBegin => {
+                let core = core.clone();
+                let source = client.audit_source();
+                let principal = client.principal.clone();
+                let namespace = namespace.map(str::to_string);
+                let begin = move || {
+                    let mut tx = match &namespace {
+                        Some(namespace) => core.begin_in(namespace, source)?,
+                        None => core.begin_as(source)?,
+                    };
+                    if let Some(principal) = &principal {
+                        tx.run_as(principal);
+                    }
+                    Ok::<_, CoreError>(tx)
+                };
+                match compute.spawn(begin).await {
+                    Ok(Ok(tx)) => {
+                        let session_id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+                        let tx = Arc::new(Mutex::new(Some(tx)));
+                        self.open.insert(session_id, OpenSession { tx, last_used: Instant::now() });
+                        Response::CoreSession { session_id }
+                    }
+                    Ok(Err(e)) => core_failed(e),
+                    Err(e) => e.into_response("Core session failed to begin"),
+                }
+            }
+            Request::CoreExec { session_id, query, params } => {
+                let Some(session) = self.open.get_mut(&session_id) else {
+                    return unknown_session(session_id);
+                };
+                session.last_used = Instant::now();
+                let tx = session.tx.clone();
+                let running = queries.start(&query);
+                let cancel = running.cancel_token();
+                let exec = move || match lock(&tx).as_mut() {
+                    Some(tx) => {
+                        tx.cancel_on(cancel);
+                        tx.exec(&query, params)
+                    }
+                    None => Err(ended()),
+                };
+                match compute.spawn(exec).await {
+                    Ok(Ok(val)) => Response::CoreResult(val),
+                    Ok(Err(e)) => core_failed(e),
+                    Err(e) => e.into_response("Core session statement failed"),
+                }
+            }
+            Request::CoreCommit { session_id } => {
+                let Some(session) = self.open.remove(&session_id) else {
+                    return unknown_session(session_id);
+                };
+                let tx = session.tx.clone();
+                let _running = queries.start(&format!("commit of core session {}", session_id));
+                let commit = move || lock(&tx).take().ok_or_else(ended).and_then(CoreTransaction::commit);
+                match compute.spawn(commit).await {
+                    Ok(Ok(_)) => Response::CoreSessionClosed { session_id, committed: true },
+                    Ok(Err(e)) => core_failed(e),
+                    Err(e @ PoolError::Saturated { .. }) => {
+                        // Never run, so the session is still open.
+                        self.open.insert(session_id, session);
+                        e.into_response("Core session commit failed")
+                    }
+                    Err(e) => {
+                        // The transaction was taken, and rolled back as it was dropped.
+                        warn!("Commit of core session {} failed: {}", session_id, e);
+                        Response::CoreSessionClosed { session_id, committed: false }
+                    }
+                }
+            }
+            Request::CoreRollback { session_id } => {
+                let Some(session) = self.open.remove(&session_id) else {
+                    return unknown_session(session_id);
+                };
+                // Waits out a statement given up on. If the pool refuses, the
+                // session is rolled back here as it is dropped.
+                let _ = compute.spawn(move || lock(&session.tx).take().map(CoreTransaction::rollback)).await;
+                Response::CoreSessionClosed { session_id, committed: false }
+            }
+            _ => Response::Error("Not a core session request".into()),
+        }
+    }
+
+    /// Rolls back sessions that have not been used within the idle timeout.
+    pub fn reap_idle(&mut self) {
+        let timeout = self.idle_timeout;
+        self.open.retain(|id, session| {
+            let keep = session.last_used.elapsed() < timeout;
+            if !keep {
+                warn!("Core session {} idle for over {:?}. Rolling back.", id, timeout);
+            }
+            keep
+        });
+    }
+}
+
+impl Drop for CoreSessions {
+    fn drop(&mut self) {
+        if !self.open.is_empty() {
+            info!("Connection closed with {} open core session(s). Rolling back.", self.open.len());
+        }
+    }
+}
+
+fn unknown_session(session_id: u64) -> Response {
+    Response::Error(format!("Unknown core session {}", session_id))
+}
+
+fn lock(tx: &SharedTransaction) -> MutexGuard<'_, Option<CoreTransaction>> {
+    tx.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn ended() -> CoreError {
+    CoreError::TransactionAborted("the session has ended".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocking_pool::PoolConfig;
+    use crate::testkit::TestNode;
+    use serde_json::{json, Value};
+    use sovereign_client::NodeClient;
+    use sovereign_core::CoreConfig;
+    use sovereign_protocol::ErrorCode;
+
+    async fn begin(client: &NodeClient) -> u64 {
+        match client.request(Request::CoreBegin).await.unwrap() {
+            Response::CoreSession { session_id } => session_id,
+            other => panic!("Expected CoreSession, got {:?}", other),
+        }
+    }
+
+    async fn exec(client: &NodeClient, session_id: u64, query: &str) -> Response {
+        let req = Request::CoreExec {
+            session_id,
+            query: query.into(),
+            params: json!({}),
+        };
+        client.request(req).await.unwrap()
+    }
+
+    /// The rows of `query`, run outside any session.
+    async fn rows(client: &NodeClient, query: &str) -> Value {
+        let req = Request::QueryCore {
+            query: query.into(),
+            params: json!({}),
+            timeout_ms: None,
+            readonly: false,
+            limit: None,
+        };
+        match client.request(req).await.unwrap() {
+            Response::CoreResult(result) => result["rows"].clone(),
+            other => panic!("Expected CoreResult, got {:?}", other),
+        }
+    }
+
+    async fn node_with_facts() -> TestNode {
+        let node = TestNode::start(Vec::new()).await.unwrap();
+        rows(node.client(), ":create facts {k: String => v: Int}").await;
+        node
+    }
+
+    const INSERT: &str = "?[k, v] <- [['a', 1], ['b', 2]] :put facts {k => v}";
+    const ALL: &str = "?[k, v] := *facts{k, v}";
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn committed_writes_are_visible() {
+        let node = node_with_facts().await;
+        let session_id = begin(node.client()).await;
+        assert!(matches!(exec(node.client(), session_id, INSERT).await, Response::CoreResult(_)));
+        // The session sees its own writes.
+        match exec(node.client(), session_id, ALL).await {
+            Response::CoreResult(result) => assert_eq!(result["rows"], json!([["a", 1], ["b", 2]])),
+            other => panic!("Expected CoreResult, got {:?}", other),
+        }
+        match node.client().request(Request::CoreCommit { session_id }).await.unwrap() {
+            Response::CoreSessionClosed { session_id: closed, committed } => assert!(closed == session_id && committed),
+            other => panic!("Expected CoreSessionClosed, got {:?}", other),
+        }
+        assert_eq!(rows(node.client(), ALL).await, json!([["a", 1], ["b", 2]]));
+        // A closed session is gone.
+        assert!(matches!(exec(node.client(), session_id, ALL).await, Response::Error(_)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rolled_back_writes_are_not() {
+        let node = node_with_facts().await;
+        let session_id = begin(node.client()).await;
+        exec(node.client(), session_id, INSERT).await;
+        match node.client().request(Request::CoreRollback { session_id }).await.unwrap() {
+            Response::CoreSessionClosed { committed, .. } => assert!(!committed),
+            other => panic!("Expected CoreSessionClosed, got {:?}", other),
+        }
+        assert_eq!(rows(node.client(), ALL).await, json!([]));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dropping_the_connection_rolls_back() {
+        let node = node_with_facts().await;
+        let client = node.connect("leaver").await.unwrap();
+        let session_id = begin(&client).await;
+        exec(&client, session_id, INSERT).await;
+        drop(client);
+
+        // The node rolls back once it notices, which frees the core again.
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(10);
+        let req = || Request::QueryCore {
+            query: ALL.into(),
+            params: json!({}),
+            timeout_ms: None,
+            readonly: true,
+            limit: None,
+        };
+        loop {
+            match node.client().request(req()).await.unwrap() {
+                Response::CoreResult(result) => {
+                    assert_eq!(result["rows"], json!([]));
+                    break;
+                }
+                other if tokio::time::Instant::now() >= deadline => panic!("The session was never rolled back: {:?}", other),
+                _ => tokio::time::sleep(std::time::Duration::from_millis(50)).await,
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sessions_belong_to_their_connection() {
+        let node = node_with_facts().await;
+        let owner = node.connect("owner").await.unwrap();
+        let other = node.connect("other").await.unwrap();
+        let session_id = begin(&owner).await;
+
+        for req in [
+            Request::CoreExec {
+                session_id,
+                query: INSERT.into(),
+                params: json!({}),
+            },
+            Request::CoreCommit { session_id },
+            Request::CoreRollback { session_id },
+        ] {
+            match other.request(req).await.unwrap() {
+                Response::Error(e) => assert!(e.contains("Unknown core session"), "{}", e),
+                resp => panic!("Another connection used the session: {:?}", resp),
+            }
+        }
+
+        // Still the owner's to use.
+        assert!(matches!(exec(&owner, session_id, INSERT).await, Response::CoreResult(_)));
+        assert!(matches!(owner.request(Request::CoreCommit { session_id }).await.unwrap(), Response::CoreSessionClosed { committed: true, .. }));
+    }
+
+    fn caller() -> Caller {
+        Caller {
+            name: "test".into(),
+            peer: None,
+            principal: None,
+        }
+    }
+
+    /// A connection's view of the node, for calling `CoreSessions::handle`
+    /// directly.
+    struct Node {
+        compute: BlockingPool,
+        core: Arc<CognitiveCore>,
+        queries: CoreQueries,
+    }
+
+    impl Node {
+        fn new() -> Self {
+            Self {
+                compute: BlockingPool::new("compute", PoolConfig { threads: 2, queue: 4 }).unwrap(),
+                core: Arc::new(CognitiveCore::new(CoreConfig::default()).unwrap()),
+                queries: CoreQueries::default(),
+            }
+        }
+
+        async fn handle(&self, sessions: &mut CoreSessions, req: Request) -> Response {
+            sessions.handle(&self.compute, &self.core, &self.queries, req, &caller(), None).await
+        }
+    }
+
+    fn exec_req(session_id: u64, query: &str) -> Request {
+        Request::CoreExec {
+            session_id,
+            query: query.into(),
+            params: json!({}),
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_sessions_are_rolled_back() {
+        let node = Node::new();
+        let mut sessions = CoreSessions::new(Duration::ZERO);
+        let Response::CoreSession { session_id } = node.handle(&mut sessions, Request::CoreBegin).await else { panic!() };
+        sessions.reap_idle();
+        assert!(matches!(node.handle(&mut sessions, exec_req(session_id, "?[a] <- [[1]]")).await, Response::Error(_)));
+        // The core is free for the next transaction.
+        node.core.begin().unwrap().rollback();
+    }
+
+    #[tokio::test]
+    async fn a_statement_given_up_on_fails_its_session() {
+        let node = Node::new();
+        let mut sessions = CoreSessions::new(Duration::from_secs(60));
+        let Response::CoreSession { session_id } = node.handle(&mut sessions, Request::CoreBegin).await else { panic!() };
+        // Counts up forever, but the engine gives up after a second.
+        let runaway = "r[n] := n = 0\nr[m] := r[n], m = n + 1\n?[n] := r[n]\n:timeout 1";
+        let started = Instant::now();
+        let handled = node.handle(&mut sessions, exec_req(session_id, runaway));
+        assert!(tokio::time::timeout(Duration::from_millis(100), handled).await.is_err());
+        // Cancelled, and no longer listed, once abandoned.
+        assert!(node.queries.list().is_empty());
+        match node.handle(&mut sessions, exec_req(session_id, "?[a] <- [[1]]")).await {
+            Response::CoreFailed(failure) => {
+                assert_eq!(failure.code, ErrorCode::TransactionAborted);
+                assert!(failure.message.contains("cancelled"), "{}", failure.message);
+            }
+            other => panic!("Expected the session to have failed, got {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_millis(500), "took {:?}", started.elapsed());
+        node.handle(&mut sessions, Request::CoreRollback { session_id }).await;
+        // The core is free again once the engine gives up on the statement.
+        loop {
+            match node.core.begin() {
+                Err(CoreError::TransactionOpen) if started.elapsed() < Duration::from_secs(5) => {
+                    tokio::time::sleep(Duration::from_millis(10)).await
+                }
+                next => break next.unwrap().rollback(),
+            }
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use crate::blocking_pool::BlockingPool;
+use crate::core_queries::RunningQuery;
+use crate::service_loop::{core_failed, write_data_frame, InboundFrame, Wire};
+use crate::wasm_stream::is_heartbeat_ack;
+use sovereign_core::{CognitiveCore, CoreError, DataFormat, ImportMode, ImportSummary, QueryOptions};
+use sovereign_protocol::{CoreDataFormat, CoreImportMode, CoreImportReject, CoreImportSummary, Response};
+use std::io;
+use std::sync::Arc;
+use tokio::io::AsyncWrite;
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Chunks queued between the connection and the core in either direction.
+const QUEUE_DEPTH: usize = 4;
+
+/// Query rows are sent once this much JSON has built up.
+const ROW_CHUNK_BYTES: usize = 16 * 1024;
+
+/// Streams the relation `name` to the client as data frames, then answers
+/// with the number of rows. An `Err` means the connection is broken.
+pub(crate) async fn export<W: AsyncWrite + Unpin>(
+    pool: &BlockingPool,
+    core: Arc<CognitiveCore>,
+    name: String,
+    format: CoreDataFormat,
+    writer: &mut Wire<W>,
+) -> io::Result<Response> {
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(QUEUE_DEPTH);
+    let export = pool.spawn(move || core.export_relation(&name, data_format(format), ChannelWriter(tx)));
+    // The export stops with a write error once the receiver is dropped.
+    while let Some(chunk) = rx.recv().await {
+        write_data_frame(writer, &chunk).await?;
+    }
+    Ok(match export.await {
+        Ok(Ok(rows)) => Response::CoreExported { rows },
+        Ok(Err(e)) => core_failed(e),
+        Err(e) => e.into_response("Core export failed"),
+    })
+}
+
+/// Runs `query` and streams its rows to the client as JSON Lines data
+/// frames, then answers with the headers and row count. Apart from the
+/// engine's own result, at most `QUEUE_DEPTH` chunks are held. The rows stop
+/// when `running` is cancelled or the connection closes. An `Err` means the
+/// connection is broken.
+pub(crate) async fn query<W: AsyncWrite + Unpin>(
+    pool: &BlockingPool,
+    core: Arc<CognitiveCore>,
+    running: RunningQuery,
+    query: String,
+    params: serde_json::Value,
+    options: QueryOptions,
+    writer: &mut Wire<W>,
+) -> io::Result<Response> {
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(QUEUE_DEPTH);
+    let cancel = running.cancel_token();
+    let stream = pool.spawn(move || {
+        let rows = core.run_streaming(&query, params, &options)?;
+        let (headers, took) = (rows.headers().to_vec(), rows.took());
+        let mut sent = 0u64;
+        let mut chunk = Vec::new();
+        for row in rows {
+            serde_json::to_writer(&mut chunk, &row).expect("JSON values serialize");
+            chunk.push(b'\n');
+            sent += 1;
+            if chunk.len() >= ROW_CHUNK_BYTES {
+                if cancel.is_cancelled() {
+                    return Err(CoreError::Cancelled);
+                }
+                if tx.blocking_send(std::mem::take(&mut chunk)).is_err() {
+                    // The connection closed; nobody is left to answer.
+                    return Err(CoreError::Cancelled);
+                }
+            }
+        }
+        if !chunk.is_empty() && tx.blocking_send(chunk).is_err() {
+            return Err(CoreError::Cancelled);
+        }
+        Ok((headers, sent, took))
+    });
+    while let Some(chunk) = rx.recv().await {
+        write_data_frame(writer, &chunk).await?;
+    }
+    drop(running);
+    Ok(match stream.await {
+        Ok(Ok((headers, rows, took))) => Response::CoreStreamed {
+            headers,
+            rows,
+            took_ms: took.as_secs_f64() * 1000.0,
+        },
+        Ok(Err(e)) => core_failed(e),
+        Err(e) => e.into_response("Core query failed"),
+    })
+}
+
+/// Imports the data frames that follow the request into the relation
+/// `name`, or fails with its error if the client may not write it. Input
+/// is consumed up to the empty end-of-input frame even if the
+/// import fails early, so the connection is back in step when the response
+/// goes out. An `Err` means the connection is broken or the client sent a
+/// request before finishing its input.
+pub(crate) async fn import(
+    pool: &BlockingPool,
+    core: Arc<CognitiveCore>,
+    name: Result<String, CoreError>,
+    format: CoreDataFormat,
+    mode: CoreImportMode,
+    frames: &mut mpsc::Receiver<InboundFrame>,
+) -> io::Result<Response> {
+    let (tx, rx) = mpsc::channel::<Vec<u8>>(QUEUE_DEPTH);
+    let mode = match mode {
+        CoreImportMode::Replace => ImportMode::Replace,
+        CoreImportMode::Append => ImportMode::Append,
+        CoreImportMode::Upsert => ImportMode::Upsert,
+    };
+    let import = pool.spawn(move || {
+        core.import_relation(&name?, data_format(format), ChannelReader::new(rx), mode)
+    });
+
+    // Dropping the sender is what the import sees as end of input.
+    let mut input = Some(tx);
+    loop {
+        match frames.recv().await {
+            Some(InboundFrame::Data(data)) if data.is_empty() => break,
+            Some(InboundFrame::Data(data)) => {
+                if let Some(tx) = &input {
+                    if tx.send(data).await.is_err() {
+                        debug!("Discarding import data after the import stopped");
+                        input = None;
+                    }
+                }
+            }
+            Some(InboundFrame::Request(body)) if is_heartbeat_ack(&body) => {}
+            Some(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "request sent before the end of import data"));
+            }
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+    drop(input);
+
+    Ok(match import.await {
+        Ok(Ok(summary)) => Response::CoreImported(import_summary(summary)),
+        Ok(Err(e)) => core_failed(e),
+        Err(e) => e.into_response("Core import failed"),
+    })
+}
+
+fn data_format(format: CoreDataFormat) -> DataFormat {
+    match format {
+        CoreDataFormat::JsonLines => DataFormat::JsonLines,
+        CoreDataFormat::Csv => DataFormat::Csv,
+    }
+}
+
+fn import_summary(summary: ImportSummary) -> CoreImportSummary {
+    CoreImportSummary {
+        rows_read: summary.rows_read,
+        inserted: summary.inserted,
+        rejected: summary.rejected,
+        rejects: summary
+            .rejects
+            .into_iter()
+            .map(|r| CoreImportReject { line: r.line, reason: r.reason })
+            .collect(),
+    }
+}
+
+/// Hands what the core writes to the connection, a chunk per write.
+struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+impl io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the connection closed"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Presents queued chunks to the core as one continuous byte stream.
+struct ChannelReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelReader {
+    fn new(rx: mpsc::Receiver<Vec<u8>>) -> Self {
+        Self { rx, chunk: Vec::new(), pos: 0 }
+    }
+}
+
+impl io::Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                // End of input.
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
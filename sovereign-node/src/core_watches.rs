@@ -0,0 +1,121 @@
+use crate::service_loop::core_failed;
+use sovereign_core::{ChangeOp, CognitiveCore, CoreChangeEvent, WatchHandle};
+use sovereign_protocol::{CoreChange, CoreChangeOp, Response};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::debug;
+
+// Ids are unique node-wide, like core session ids.
+static NEXT_WATCH_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Most relations one connection may watch at once.
+const MAX_WATCHES: usize = 16;
+
+/// Changes queued for the connection before the watch threads wait.
+const QUEUE_DEPTH: usize = 32;
+
+/// How often a watch thread checks whether it has been stopped.
+const POLL: Duration = Duration::from_millis(250);
+
+/// Core watches opened by a single IPC connection, whose changes are pushed
+/// to it as `Response::CoreChanged`.
+///
+/// Each watch has a thread waiting on the core; the watches stop when the
+/// connection goes away.
+pub struct CoreWatches {
+    stop: HashMap<u64, Arc<AtomicBool>>,
+    tx: mpsc::Sender<CoreChange>,
+    rx: mpsc::Receiver<CoreChange>,
+}
+
+impl CoreWatches {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_DEPTH);
+        Self { stop: HashMap::new(), tx, rx }
+    }
+
+    pub fn watch(&mut self, core: &CognitiveCore, relation: &str, filter: Option<&str>) -> Response {
+        if self.stop.len() >= MAX_WATCHES {
+            return Response::Error(format!("Too many core watches (limit {})", MAX_WATCHES));
+        }
+        let handle = match core.watch(relation, filter) {
+            Ok(handle) => handle,
+            Err(e) => return core_failed(e),
+        };
+        let watch_id = NEXT_WATCH_ID.fetch_add(1, Ordering::Relaxed);
+        let stop = Arc::new(AtomicBool::new(false));
+        let spawned = std::thread::Builder::new().name("core-watch".into()).spawn({
+            let stop = stop.clone();
+            let tx = self.tx.clone();
+            move || forward(watch_id, handle, &stop, &tx)
+        });
+        match spawned {
+            Ok(_) => {
+                self.stop.insert(watch_id, stop);
+                Response::CoreWatching { watch_id }
+            }
+            Err(e) => Response::Error(format!("Cannot start the watch: {}", e)),
+        }
+    }
+
+    pub fn unwatch(&mut self, watch_id: u64) -> Response {
+        let found = match self.stop.remove(&watch_id) {
+            Some(stop) => {
+                stop.store(true, Ordering::Release);
+                true
+            }
+            None => false,
+        };
+        Response::CoreUnwatched { watch_id, found }
+    }
+
+    /// The next change to push. Pending while nothing is watched.
+    pub async fn next(&mut self) -> CoreChange {
+        loop {
+            match self.rx.recv().await {
+                Some(change) if self.stop.contains_key(&change.watch_id) => return change,
+                // Its watch was stopped while the change was on the way.
+                Some(_) => {}
+                // Unreachable while `self.tx` is held.
+                None => std::future::pending().await,
+            }
+        }
+    }
+}
+
+impl Drop for CoreWatches {
+    fn drop(&mut self) {
+        for stop in self.stop.values() {
+            stop.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// Hands the watch's changes to the connection until it is stopped.
+fn forward(watch_id: u64, handle: WatchHandle, stop: &AtomicBool, tx: &mpsc::Sender<CoreChange>) {
+    while !stop.load(Ordering::Acquire) {
+        let Some(event) = handle.recv_timeout(POLL) else {
+            continue;
+        };
+        if tx.blocking_send(core_change(watch_id, event)).is_err() {
+            break;
+        }
+    }
+    debug!("Core watch {} on {} stopped", watch_id, handle.relation());
+}
+
+fn core_change(watch_id: u64, event: CoreChangeEvent) -> CoreChange {
+    CoreChange {
+        watch_id,
+        relation: event.relation,
+        op: match event.op {
+            ChangeOp::Put => CoreChangeOp::Put,
+            ChangeOp::Remove => CoreChangeOp::Remove,
+        },
+        headers: event.headers,
+        rows: event.rows,
+    }
+}
@@ -0,0 +1,392 @@
+//! Where the node keeps what it writes. Everything lives under one root,
+//! laid out by `DataDir` so no subsystem builds its own paths:
+//!
+//! ```text
+//! node.lock        held by the running node
+//! keys/            the swarm key, install salt and TLS key; the node's user's alone
+//! core/            the core's store
+//! wasm-store/      registered modules, unless wasm.module_store is set
+//! cache/           compiled modules and snapshots being packed; safe to delete
+//! state/           state.json, scheduled jobs, the WASM allow-list, the TLS pin
+//! logs/            the node log and audit logs
+//! ```
+
+use crate::config::NodeConfig;
+use anyhow::{bail, Context};
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// The lock file, in the root.
+const LOCK_FILE: &str = "node.lock";
+
+/// Files a node kept directly in the root before the layout, and the
+/// directory each goes to.
+const LEGACY: &[(&str, &str)] = &[
+    ("install.salt", "keys"),
+    ("state.json", "state"),
+    ("license.json", "state"),
+    ("jobs.json", "state"),
+    ("wasm-allowlist.txt", "state"),
+    ("core-audit.jsonl", "logs"),
+];
+
+/// Where a node looked for the swarm key before the layout: the working
+/// directory.
+const LEGACY_SWARM_KEY: &str = "swarm.key";
+
+/// The data directory's layout under `root`. Made with `at` it only names
+/// paths; made with `open` it also holds the lock for as long as it lives.
+#[derive(Debug)]
+pub(crate) struct DataDir {
+    root: PathBuf,
+    lock: Option<File>,
+}
+
+impl DataDir {
+    /// The layout under `root`, without touching the disk.
+    pub fn at(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into(), lock: None }
+    }
+
+    /// Creates the layout under `root` if it is missing and takes its lock,
+    /// before anything else opens a file there. Fails if another node holds
+    /// the lock, naming its pid.
+    pub fn open(root: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let mut data_dir = Self::at(root);
+        data_dir.create().with_context(|| format!("Failed to create the data directory {}", data_dir.root.display()))?;
+        data_dir.lock = Some(data_dir.acquire()?);
+        Ok(data_dir)
+    }
+
+    /// Whether nothing is under the root but empty directories and the
+    /// lock: a first run, or one that stopped before it was set up.
+    pub fn is_fresh(&self) -> bool {
+        holds_nothing(&self.root, true)
+    }
+
+    /// Creates the root and its directories, a new `keys/` readable by the
+    /// node's user alone.
+    pub fn create(&self) -> io::Result<()> {
+        std::fs::create_dir_all(&self.root)?;
+        private_dir(&self.keys())?;
+        for dir in [self.core(), self.wasm_store(), self.cache(), self.state(), self.logs()] {
+            std::fs::create_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn keys(&self) -> PathBuf {
+        self.root.join("keys")
+    }
+
+    pub fn core(&self) -> PathBuf {
+        self.root.join("core")
+    }
+
+    /// The default module store.
+    pub fn wasm_store(&self) -> PathBuf {
+        self.root.join("wasm-store")
+    }
+
+    pub fn cache(&self) -> PathBuf {
+        self.root.join("cache")
+    }
+
+    pub fn state(&self) -> PathBuf {
+        self.root.join("state")
+    }
+
+    pub fn logs(&self) -> PathBuf {
+        self.root.join("logs")
+    }
+
+    /// The default swarm key.
+    pub fn swarm_key(&self) -> PathBuf {
+        self.keys().join("swarm.key")
+    }
+
+    pub fn install_salt(&self) -> PathBuf {
+        self.keys().join("install.salt")
+    }
+
+    /// The generated TCP certificate and key.
+    pub fn tls_cert(&self) -> PathBuf {
+        self.keys().join("tls-cert.pem")
+    }
+
+    pub fn tls_key(&self) -> PathBuf {
+        self.keys().join("tls-key.pem")
+    }
+
+    /// The pin of the certificate TCP clients are served.
+    pub fn tls_fingerprint(&self) -> PathBuf {
+        self.state().join("tls-fingerprint.txt")
+    }
+
+    /// The default SQLite or RocksDB store, by `core.backend`.
+    pub fn core_store(&self, backend: &str) -> PathBuf {
+        self.core().join(match backend {
+            "rocksdb" => "core.rocksdb",
+            _ => "core.db",
+        })
+    }
+
+    pub fn jobs(&self) -> PathBuf {
+        self.state().join("jobs.json")
+    }
+
+    pub fn wasm_allowlist(&self) -> PathBuf {
+        self.state().join("wasm-allowlist.txt")
+    }
+
+    pub fn wasm_cache(&self) -> PathBuf {
+        self.cache().join("wasm")
+    }
+
+    /// Where snapshot archives are packed and unpacked.
+    pub fn snapshot_spool(&self) -> PathBuf {
+        self.cache().join("snapshot")
+    }
+
+    pub fn node_log(&self) -> PathBuf {
+        self.logs().join("node.log")
+    }
+
+    pub fn ipc_audit_log(&self) -> PathBuf {
+        self.logs().join("ipc-audit.jsonl")
+    }
+
+    pub fn core_audit_log(&self) -> PathBuf {
+        self.logs().join("core-audit.jsonl")
+    }
+
+    /// Moves what an older node kept in the root into the layout, with
+    /// `./swarm.key`, where it used to look for the swarm key. The swarm
+    /// key, core store and module store only move while `config` leaves
+    /// them at their defaults. Nothing already in place is overwritten; what
+    /// cannot be moved is left where it is, with a warning.
+    pub fn migrate(&self, config: &NodeConfig) {
+        self.migrate_from(config, Path::new(LEGACY_SWARM_KEY));
+    }
+
+    /// `migrate`, with the old swarm key looked for at `legacy_swarm_key`.
+    fn migrate_from(&self, config: &NodeConfig, legacy_swarm_key: &Path) {
+        let mut moves: Vec<(PathBuf, PathBuf)> = LEGACY.iter().map(|(name, dir)| (self.root.join(name), self.root.join(dir).join(name))).collect();
+        // The rotated core audit logs go with the current one.
+        if let Ok(entries) = std::fs::read_dir(&self.root) {
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with("core-audit.jsonl.") {
+                    moves.push((entry.path(), self.logs().join(name)));
+                }
+            }
+        }
+        if config.core.path.is_none() {
+            let store = self.core_store(&config.core.backend);
+            if let Some(name) = store.file_name() {
+                moves.push((self.root.join(name), store));
+            }
+        }
+        if config.wasm.module_store.is_none() {
+            moves.push((self.root.join("modules"), self.wasm_store()));
+        }
+        if config.mesh.psk_path.is_none() {
+            moves.push((legacy_swarm_key.to_path_buf(), self.swarm_key()));
+        }
+        for (from, to) in moves {
+            if !from.exists() {
+                continue;
+            }
+            match relocate(&from, &to) {
+                Ok(true) => info!("Moved {} to {}", from.display(), to.display()),
+                Ok(false) => warn!("Leaving {} where it is: {} already exists", from.display(), to.display()),
+                Err(e) => warn!("Failed to move {} to {}: {}", from.display(), to.display(), e),
+            }
+        }
+    }
+
+    /// Takes the lock and writes this process's pid into the file.
+    fn acquire(&self) -> anyhow::Result<File> {
+        let path = self.root.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        if let Err(e) = file.try_lock_exclusive() {
+            if e.raw_os_error() != fs2::lock_contended_error().raw_os_error() {
+                return Err(e).with_context(|| format!("Failed to lock {}", path.display()));
+            }
+            match holder(&path) {
+                Some(pid) => bail!("Another node (pid {}) is running with the data directory {}", pid, self.root.display()),
+                None => bail!("Another node is running with the data directory {}", self.root.display()),
+            }
+        }
+        let written = file.set_len(0).and_then(|()| file.rewind()).and_then(|()| write!(file, "{}", std::process::id())).and_then(|()| file.flush());
+        if let Err(e) = written {
+            warn!("Failed to write the pid to {}: {}", path.display(), e);
+        }
+        Ok(file)
+    }
+}
+
+impl Drop for DataDir {
+    fn drop(&mut self) {
+        if let Some(lock) = self.lock.take() {
+            let _ = lock.unlock();
+        }
+    }
+}
+
+/// The pid in a held lock file, if it can be read: Windows keeps a locked
+/// file from being read by anyone else.
+fn holder(path: &Path) -> Option<u32> {
+    let mut text = String::new();
+    File::open(path).ok()?.read_to_string(&mut text).ok()?;
+    text.trim().parse().ok()
+}
+
+/// True if `dir` holds only directories that hold nothing in turn, and,
+/// at the root, the lock file. A directory that cannot be read is taken to
+/// hold something.
+fn holds_nothing(dir: &Path, root: bool) -> bool {
+    let Ok(mut entries) = std::fs::read_dir(dir) else {
+        return false;
+    };
+    entries.all(|entry| match entry {
+        Ok(entry) if entry.path().is_dir() => holds_nothing(&entry.path(), false),
+        Ok(entry) => root && entry.file_name() == LOCK_FILE,
+        Err(_) => false,
+    })
+}
+
+/// Moves `from` to `to` unless `to` is there already, other than as an
+/// empty directory. False if it was.
+fn relocate(from: &Path, to: &Path) -> io::Result<bool> {
+    if to.is_dir() && to.read_dir()?.next().is_none() {
+        std::fs::remove_dir(to)?;
+    } else if to.exists() {
+        return Ok(false);
+    }
+    match std::fs::rename(from, to) {
+        Ok(()) => Ok(true),
+        // Across filesystems, as `./swarm.key` may be.
+        Err(_) if from.is_file() => {
+            std::fs::copy(from, to)?;
+            std::fs::remove_file(from)?;
+            Ok(true)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(unix)]
+fn private_dir(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)
+}
+
+#[cfg(not(unix))]
+fn private_dir(dir: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_first_run_creates_the_layout_and_locks_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("node");
+        let data_dir = DataDir::open(&root).unwrap();
+        for sub in ["keys", "core", "wasm-store", "cache", "state", "logs"] {
+            assert!(root.join(sub).is_dir(), "no {}", sub);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(data_dir.keys()).unwrap().permissions().mode() & 0o777, 0o700);
+        }
+        assert_eq!(std::fs::read_to_string(root.join(LOCK_FILE)).unwrap(), std::process::id().to_string());
+        assert!(data_dir.is_fresh());
+
+        std::fs::write(data_dir.core_store("sqlite"), b"rows").unwrap();
+        assert!(!data_dir.is_fresh());
+    }
+
+    #[test]
+    fn a_second_instance_fails_naming_the_holder_until_the_first_stops() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = DataDir::open(dir.path()).unwrap();
+        let e = DataDir::open(dir.path()).unwrap_err().to_string();
+        assert_eq!(e, format!("Another node (pid {}) is running with the data directory {}", std::process::id(), dir.path().display()));
+        // Naming paths takes no lock.
+        assert_eq!(DataDir::at(dir.path()).swarm_key(), first.swarm_key());
+
+        drop(first);
+        let second = DataDir::open(dir.path()).unwrap();
+        assert!(DataDir::open(dir.path()).is_err());
+        drop(second);
+    }
+
+    #[test]
+    fn moves_legacy_files_into_the_layout_without_overwriting() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("node");
+        std::fs::create_dir_all(root.join("modules/echo")).unwrap();
+        for name in ["install.salt", "state.json", "jobs.json", "core-audit.jsonl", "core-audit.jsonl.1", "core.db", "license.json"] {
+            std::fs::write(root.join(name), name).unwrap();
+        }
+        std::fs::write(root.join("modules/echo/module.wasm"), b"module").unwrap();
+        let legacy_key = dir.path().join("cwd-swarm.key");
+        std::fs::write(&legacy_key, b"old key").unwrap();
+        // The layout already has a license of its own.
+        let data_dir = DataDir::open(&root).unwrap();
+        std::fs::write(data_dir.state().join("license.json"), b"newer").unwrap();
+
+        data_dir.migrate_from(&NodeConfig::default(), &legacy_key);
+        let read = |path: PathBuf| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(data_dir.install_salt()), "install.salt");
+        assert_eq!(read(data_dir.state().join("state.json")), "state.json");
+        assert_eq!(read(data_dir.jobs()), "jobs.json");
+        assert_eq!(read(data_dir.core_audit_log()), "core-audit.jsonl");
+        assert_eq!(read(data_dir.logs().join("core-audit.jsonl.1")), "core-audit.jsonl.1");
+        assert_eq!(read(data_dir.core_store("sqlite")), "core.db");
+        assert_eq!(read(data_dir.wasm_store().join("echo/module.wasm")), "module");
+        assert_eq!(read(data_dir.swarm_key()), "old key");
+        assert!(!legacy_key.exists() && !root.join("modules").exists() && !root.join("core.db").exists());
+        // What was already in place stays, and so does the old copy.
+        assert_eq!(read(data_dir.state().join("license.json")), "newer");
+        assert_eq!(read(root.join("license.json")), "license.json");
+    }
+
+    #[test]
+    fn leaves_files_the_config_points_at_where_they_are() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("node");
+        std::fs::create_dir_all(root.join("modules")).unwrap();
+        std::fs::write(root.join("core.db"), b"rows").unwrap();
+        let legacy_key = dir.path().join("cwd-swarm.key");
+        std::fs::write(&legacy_key, b"old key").unwrap();
+        let data_dir = DataDir::open(&root).unwrap();
+
+        let mut config = NodeConfig::default();
+        config.core.path = Some(root.join("core.db").display().to_string().into());
+        config.wasm.module_store = Some(root.join("modules").display().to_string().into());
+        config.mesh.psk_path = Some(legacy_key.display().to_string().into());
+        data_dir.migrate_from(&config, &legacy_key);
+        assert!(root.join("core.db").is_file() && root.join("modules").is_dir() && legacy_key.is_file());
+        assert!(!data_dir.swarm_key().exists());
+    }
+}
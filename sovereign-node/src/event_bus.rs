@@ -0,0 +1,296 @@
+use crate::service_loop::{job_run, SharedState};
+use sovereign_protocol::{EventTopic, NodeEvent, Response};
+use sovereign_runtime_wasm::Scheduler;
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::watch;
+
+/// Events each connection may fall behind by before the oldest are dropped.
+const BACKLOG: usize = 256;
+
+/// Events last sent on a connection, kept to send again when its session
+/// is resumed.
+const REPLAY: usize = 64;
+
+/// Where the subsystems publish `NodeEvent`s for subscribed connections.
+/// Publishing never waits: a connection that falls `BACKLOG` events behind
+/// loses the oldest.
+pub(crate) struct EventBus(broadcast::Sender<NodeEvent>);
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self(broadcast::channel(BACKLOG).0)
+    }
+
+    pub fn publish(&self, event: NodeEvent) {
+        // No receivers just means no connection is subscribed.
+        let _ = self.0.send(event);
+    }
+}
+
+/// Publishes the license turning active or inactive, however it was
+/// checked. Ends once `state` is dropped.
+pub(crate) async fn publish_license(mut state: watch::Receiver<SharedState>, bus: Arc<EventBus>) {
+    let mut active = state.borrow_and_update().license_active;
+    while state.changed().await.is_ok() {
+        let (now_active, report) = {
+            let s = state.borrow_and_update();
+            (s.license_active, s.license_report.clone())
+        };
+        if now_active != active {
+            active = now_active;
+            bus.publish(NodeEvent::LicenseChanged { active, report });
+        }
+    }
+}
+
+/// Publishes every finished run of a scheduled job.
+pub(crate) async fn publish_jobs(scheduler: Arc<Scheduler>, bus: Arc<EventBus>) {
+    let mut completed = scheduler.subscribe();
+    loop {
+        match completed.recv().await {
+            Ok(done) => bus.publish(NodeEvent::WasmJobCompleted {
+                job: done.job,
+                run: job_run(done.run),
+            }),
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// One connection's subscriptions, and the count its events are numbered
+/// by. Dropped with the connection, or with its session if it has one.
+#[derive(Default)]
+pub(crate) struct Subscription {
+    topics: BTreeSet<EventTopic>,
+    events: Option<broadcast::Receiver<NodeEvent>>,
+    seq: u64,
+    /// The last `REPLAY` events stamped, oldest first.
+    sent: VecDeque<(u64, NodeEvent)>,
+}
+
+impl Subscription {
+    pub fn subscribe(&mut self, bus: &EventBus, topics: Vec<EventTopic>) -> Response {
+        self.topics.extend(topics);
+        if self.events.is_none() && !self.topics.is_empty() {
+            self.events = Some(bus.0.subscribe());
+        }
+        self.subscribed()
+    }
+
+    pub fn unsubscribe(&mut self, topics: Vec<EventTopic>) -> Response {
+        for topic in topics {
+            self.topics.remove(&topic);
+        }
+        if self.topics.is_empty() {
+            self.events = None;
+        }
+        self.subscribed()
+    }
+
+    pub fn wants(&self, topic: EventTopic) -> bool {
+        self.topics.contains(&topic)
+    }
+
+    /// `event` as the connection's next `Response::Event`.
+    pub fn stamp(&mut self, event: NodeEvent) -> Response {
+        self.seq += 1;
+        if self.sent.len() == REPLAY {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((self.seq, event.clone()));
+        Response::Event { seq: self.seq, event }
+    }
+
+    /// The `seq` of the last event stamped.
+    pub fn last_seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// The events stamped after `seq`, to send again on a resumed session,
+    /// led by `PushDropped` if some are no longer held.
+    pub fn replay_after(&self, seq: u64) -> Vec<Response> {
+        let mut pushes = Vec::new();
+        let held_from = self.sent.front().map_or(self.seq + 1, |(first, _)| *first);
+        if seq + 1 < held_from {
+            pushes.push(Response::PushDropped { count: held_from - seq - 1 });
+        }
+        pushes.extend(
+            self.sent
+                .iter()
+                .filter(|(stamped, _)| *stamped > seq)
+                .map(|(stamped, event)| Response::Event { seq: *stamped, event: event.clone() }),
+        );
+        pushes
+    }
+
+    /// The next push: an event on a subscribed topic, or `PushDropped` after
+    /// falling behind. Pending while nothing is subscribed. Cancel safe.
+    pub async fn next(&mut self) -> Response {
+        loop {
+            let Some(events) = &mut self.events else {
+                return std::future::pending().await;
+            };
+            match events.recv().await {
+                Ok(event) if self.topics.contains(&event.topic()) => return self.stamp(event),
+                Ok(_) => {}
+                // Counts what was missed on every topic, not only this
+                // connection's.
+                Err(RecvError::Lagged(count)) => return Response::PushDropped { count },
+                Err(RecvError::Closed) => self.events = None,
+            }
+        }
+    }
+
+    fn subscribed(&self) -> Response {
+        Response::Subscribed {
+            topics: self.topics.iter().copied().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sovereign_protocol::MeshPhase;
+    use std::time::Duration;
+
+    fn connected(peer: usize) -> NodeEvent {
+        NodeEvent::PeerConnected { peer: format!("peer-{}", peer) }
+    }
+
+    fn license(active: bool) -> NodeEvent {
+        NodeEvent::LicenseChanged { active, report: None }
+    }
+
+    /// The next push, failing rather than waiting forever.
+    async fn next(subscription: &mut Subscription) -> Response {
+        tokio::time::timeout(Duration::from_secs(5), subscription.next()).await.expect("a push")
+    }
+
+    async fn nothing_next(subscription: &mut Subscription) {
+        assert!(tokio::time::timeout(Duration::from_millis(100), subscription.next()).await.is_err());
+    }
+
+    /// Events are compared as they go on the wire.
+    fn json(event: NodeEvent) -> serde_json::Value {
+        serde_json::to_value(event).unwrap()
+    }
+
+    fn event(push: Response) -> (u64, serde_json::Value) {
+        match push {
+            Response::Event { seq, event } => (seq, json(event)),
+            other => panic!("Expected an event, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn fans_out_each_connections_topics_numbered_per_connection() {
+        let bus = EventBus::new();
+        let (mut mesh, mut both) = (Subscription::default(), Subscription::default());
+        assert!(matches!(mesh.subscribe(&bus, vec![EventTopic::Mesh]), Response::Subscribed { topics } if topics == [EventTopic::Mesh]));
+        both.subscribe(&bus, vec![EventTopic::License, EventTopic::Mesh]);
+
+        bus.publish(connected(1));
+        bus.publish(license(true));
+        bus.publish(connected(2));
+
+        assert_eq!(event(next(&mut mesh).await), (1, json(connected(1))));
+        assert_eq!(event(next(&mut mesh).await), (2, json(connected(2))));
+        nothing_next(&mut mesh).await;
+        assert_eq!(event(next(&mut both).await), (1, json(connected(1))));
+        assert_eq!(event(next(&mut both).await), (2, json(license(true))));
+        assert_eq!(event(next(&mut both).await), (3, json(connected(2))));
+        assert!(mesh.wants(EventTopic::Mesh) && !mesh.wants(EventTopic::License));
+    }
+
+    #[tokio::test]
+    async fn unsubscribed_connections_get_nothing() {
+        let bus = EventBus::new();
+        let mut subscription = Subscription::default();
+        nothing_next(&mut subscription).await;
+        subscription.subscribe(&bus, vec![EventTopic::Mesh, EventTopic::License]);
+        subscription.unsubscribe(vec![EventTopic::Mesh]);
+        bus.publish(connected(1));
+        bus.publish(license(false));
+        assert_eq!(event(next(&mut subscription).await), (1, json(license(false))));
+
+        assert!(matches!(subscription.unsubscribe(vec![EventTopic::License]), Response::Subscribed { topics } if topics.is_empty()));
+        bus.publish(license(true));
+        nothing_next(&mut subscription).await;
+        // Publishing with no one subscribed is fine.
+        bus.publish(license(true));
+    }
+
+    #[tokio::test]
+    async fn a_lagging_connection_is_told_what_it_missed_without_holding_up_others() {
+        let bus = EventBus::new();
+        let (mut slow, mut fast) = (Subscription::default(), Subscription::default());
+        slow.subscribe(&bus, vec![EventTopic::Mesh]);
+        fast.subscribe(&bus, vec![EventTopic::Mesh]);
+        for peer in 0..BACKLOG + 10 {
+            bus.publish(connected(peer));
+            assert_eq!(event(next(&mut fast).await), (peer as u64 + 1, json(connected(peer))));
+        }
+
+        assert!(matches!(next(&mut slow).await, Response::PushDropped { count: 10 }));
+        // It carries on from the oldest event still held.
+        assert_eq!(event(next(&mut slow).await), (1, json(connected(10))));
+    }
+
+    #[test]
+    fn replays_what_a_resumed_session_missed() {
+        let mut subscription = Subscription::default();
+        for peer in 0..REPLAY + 6 {
+            subscription.stamp(connected(peer));
+        }
+        assert_eq!(subscription.last_seq(), REPLAY as u64 + 6);
+
+        let replayed = subscription.replay_after(REPLAY as u64 + 3);
+        let replayed: Vec<_> = replayed.into_iter().map(event).collect();
+        assert_eq!(replayed, [3, 4, 5].map(|i| (REPLAY as u64 + i + 1, json(connected(REPLAY + i as usize)))));
+        assert!(subscription.replay_after(REPLAY as u64 + 6).is_empty());
+
+        // Events 1 to 6 are no longer held.
+        let replayed = subscription.replay_after(2);
+        assert!(matches!(replayed[0], Response::PushDropped { count: 4 }));
+        assert_eq!(replayed.len(), REPLAY + 1);
+        assert_eq!(event(replayed[1].clone()), (7, json(connected(6))));
+    }
+
+    #[tokio::test]
+    async fn publishes_the_license_only_when_it_flips() {
+        let state = watch::Sender::new(SharedState {
+            peer_id: "peer".into(),
+            connections: 0,
+            listen_addrs: Vec::new(),
+            mesh_phase: MeshPhase::Ready,
+            license_active: false,
+            license_report: None,
+            presence: None,
+        });
+        let bus = Arc::new(EventBus::new());
+        let mut subscription = Subscription::default();
+        subscription.subscribe(&bus, vec![EventTopic::License]);
+        let publisher = tokio::spawn(publish_license(state.subscribe(), bus.clone()));
+        // Each change is seen on its own, rather than only the last.
+        let change = |change: fn(&mut SharedState)| {
+            state.send_modify(change);
+            tokio::task::yield_now()
+        };
+
+        tokio::task::yield_now().await;
+        change(|s| s.connections = 3).await;
+        change(|s| s.license_active = true).await;
+        assert_eq!(event(next(&mut subscription).await), (1, json(license(true))));
+        change(|s| s.license_report = None).await;
+        change(|s| s.license_active = false).await;
+        assert_eq!(event(next(&mut subscription).await), (2, json(license(false))));
+        nothing_next(&mut subscription).await;
+
+        drop(state);
+        tokio::time::timeout(Duration::from_secs(5), publisher).await.unwrap().unwrap();
+    }
+}
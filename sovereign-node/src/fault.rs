@@ -0,0 +1,268 @@
+//! Fault injection for resilience tests, built only with the
+//! `fault-injection` feature. `Request::InjectFault` arms a fault in the
+//! node's `FaultPlan`, and the seam in front of its target acts on it: the
+//! mesh supervisor as it forwards commands, `FinanceBackend::verify`, core
+//! requests as their budget starts, the limits of WASM runs and `Wire`
+//! writes. Without the feature none of them exist.
+
+use sovereign_protocol::{FaultKind, FaultTarget};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::task::AbortHandle;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// The faults armed on each target, and when they end.
+#[derive(Default)]
+pub(crate) struct FaultPlan {
+    armed: Mutex<HashMap<FaultTarget, (FaultKind, Instant)>>,
+    /// Woken whenever a fault is armed or ended, for `hold`.
+    changed: Notify,
+    /// The running mesh actor, for `FaultKind::Crash`.
+    mesh_actor: Mutex<Option<AbortHandle>>,
+}
+
+impl FaultPlan {
+    /// Arms `kind` on `target` for `duration`, replacing the fault it had,
+    /// or ends it for a zero `duration`. One-off kinds strike here and are
+    /// not kept; `PoisonLock` is left to the caller, which holds the lock.
+    /// Returns whether a fault is now armed on `target`.
+    pub fn inject(&self, target: FaultTarget, kind: FaultKind, duration: Duration) -> anyhow::Result<bool> {
+        if !fits(target, kind) {
+            anyhow::bail!("A {:?} fault cannot be injected into {:?}", kind, target);
+        }
+        warn!("Injecting a {:?} fault into {:?} for {:?}", kind, target, duration);
+        let mut armed = self.armed.lock().unwrap_or_else(|e| e.into_inner());
+        armed.remove(&target);
+        let active = match kind {
+            FaultKind::Crash => {
+                if let Some(actor) = self.mesh_actor.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                    actor.abort();
+                }
+                false
+            }
+            FaultKind::PoisonLock => false,
+            _ if duration.is_zero() => false,
+            _ => {
+                armed.insert(target, (kind, Instant::now() + duration));
+                true
+            }
+        };
+        drop(armed);
+        self.changed.notify_waiters();
+        Ok(active)
+    }
+
+    /// The fault armed on `target`, while it lasts.
+    pub fn active(&self, target: FaultTarget) -> Option<FaultKind> {
+        self.armed(target).map(|(kind, _)| kind)
+    }
+
+    /// Waits while `target` has a `kind` fault armed.
+    pub async fn hold(&self, target: FaultTarget, kind: FaultKind) {
+        loop {
+            // Taken before looking, so an end in between still wakes us.
+            let changed = self.changed.notified();
+            let until = match self.armed(target) {
+                Some((armed, until)) if armed == kind => until,
+                _ => return,
+            };
+            tokio::select! {
+                () = tokio::time::sleep_until(until) => {}
+                () = changed => {}
+            }
+        }
+    }
+
+    /// Notes the mesh actor just started, for a later `Crash`.
+    pub fn mesh_actor(&self, actor: AbortHandle) {
+        *self.mesh_actor.lock().unwrap_or_else(|e| e.into_inner()) = Some(actor);
+    }
+
+    fn armed(&self, target: FaultTarget) -> Option<(FaultKind, Instant)> {
+        let mut armed = self.armed.lock().unwrap_or_else(|e| e.into_inner());
+        match armed.get(&target) {
+            Some(&(kind, until)) if Instant::now() < until => Some((kind, until)),
+            Some(_) => {
+                armed.remove(&target);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+/// Whether `target` has a seam that acts on `kind`.
+fn fits(target: FaultTarget, kind: FaultKind) -> bool {
+    matches!(
+        (target, kind),
+        (FaultTarget::Mesh, FaultKind::Drop | FaultKind::Delay { .. } | FaultKind::Crash)
+            | (FaultTarget::Finance, FaultKind::NetworkError | FaultKind::Hang)
+            | (FaultTarget::Core, FaultKind::Hang | FaultKind::PoisonLock)
+            | (FaultTarget::Wasm, FaultKind::Trap)
+            | (FaultTarget::IpcWriter, FaultKind::Stall)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::{TestNode, TestNodeOptions};
+    use sovereign_client::ConnectionState;
+    use sovereign_protocol::{MeshPhase, NodeStatus, Request, Response};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    async fn start(config: &str) -> TestNode {
+        TestNode::start_with(TestNodeOptions {
+            config: Some(config.into()),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Polls `node`'s status until `done` accepts it.
+    async fn wait_until(node: &TestNode, timeout: Duration, done: impl Fn(&NodeStatus) -> bool) -> NodeStatus {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let status = node.status().await.unwrap();
+            if done(&status) {
+                return status;
+            }
+            assert!(Instant::now() < deadline, "Gave up waiting; the node is {:?}", status.mesh_phase);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    fn query(timeout_ms: Option<u64>) -> Request {
+        Request::QueryCore {
+            query: "?[a] := a = 1".into(),
+            params: serde_json::json!({}),
+            timeout_ms,
+            readonly: true,
+            limit: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn faults_end_on_their_own_or_when_cleared() {
+        let plan = FaultPlan::default();
+        assert!(plan.inject(FaultTarget::Core, FaultKind::Hang, Duration::from_millis(50)).unwrap());
+        assert_eq!(plan.active(FaultTarget::Core), Some(FaultKind::Hang));
+        assert_eq!(plan.active(FaultTarget::Mesh), None);
+        let started = Instant::now();
+        plan.hold(FaultTarget::Core, FaultKind::Hang).await;
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        assert_eq!(plan.active(FaultTarget::Core), None);
+
+        assert!(plan.inject(FaultTarget::Finance, FaultKind::Hang, Duration::from_secs(60)).unwrap());
+        assert!(!plan.inject(FaultTarget::Finance, FaultKind::Hang, Duration::ZERO).unwrap());
+        assert_eq!(plan.active(FaultTarget::Finance), None);
+        assert!(plan.inject(FaultTarget::Wasm, FaultKind::Hang, Duration::from_secs(1)).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn the_supervisor_restarts_a_crashed_mesh_actor() {
+        let node = TestNode::start(Vec::new()).await.unwrap();
+        let before = node.status().await.unwrap();
+        assert!(!node.inject_fault(FaultTarget::Mesh, FaultKind::Crash, Duration::ZERO).await.unwrap());
+
+        let restarting = wait_until(&node, Duration::from_secs(10), |s| matches!(s.mesh_phase, MeshPhase::Restarting { .. })).await;
+        assert!(matches!(restarting.mesh_phase, MeshPhase::Restarting { attempt: 1, .. }), "{:?}", restarting.mesh_phase);
+        let back = wait_until(&node, Duration::from_secs(20), |s| {
+            !matches!(s.mesh_phase, MeshPhase::Restarting { .. }) && !s.mesh_listen_addrs.is_empty()
+        })
+        .await;
+        assert!(!matches!(back.mesh_phase, MeshPhase::Failed { .. }), "{:?}", back.mesh_phase);
+        // The restarted actor keeps the node's identity and answers again.
+        assert_eq!(back.mesh_peer_id, before.mesh_peer_id);
+        match node.client().request(Request::MeshPeers).await.unwrap() {
+            Response::MeshGeneric(_) => {}
+            other => panic!("Expected MeshGeneric, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn requests_time_out_per_subsystem() {
+        let node = TestNode::start(Vec::new()).await.unwrap();
+        assert!(node.inject_fault(FaultTarget::Core, FaultKind::Hang, Duration::from_secs(60)).await.unwrap());
+        let started = Instant::now();
+        match node.client().request(query(Some(200))).await.unwrap() {
+            Response::TimedOut { subsystem, timeout_ms } => assert_eq!((subsystem.as_str(), timeout_ms), ("core", 1200)),
+            other => panic!("Expected TimedOut, got {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5), "took {:?}", started.elapsed());
+        let named = Request::CoreRunNamed {
+            name: "anything".into(),
+            params: serde_json::json!({}),
+            timeout_ms: Some(100),
+            readonly: true,
+        };
+        match node.client().request(named).await.unwrap() {
+            Response::TimedOut { subsystem, timeout_ms } => assert_eq!((subsystem.as_str(), timeout_ms), ("core", 1100)),
+            other => panic!("Expected TimedOut, got {:?}", other),
+        }
+        // Only the core is held up.
+        assert!(matches!(node.client().request(Request::Ping).await.unwrap(), Response::Pong));
+
+        // Dropped mesh commands fail at once rather than waiting out the budget.
+        assert!(node.inject_fault(FaultTarget::Mesh, FaultKind::Drop, Duration::from_secs(60)).await.unwrap());
+        let started = Instant::now();
+        assert!(matches!(node.client().request(Request::MeshPeers).await.unwrap(), Response::Error(_)));
+        assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
+
+        assert!(!node.inject_fault(FaultTarget::Core, FaultKind::Hang, Duration::ZERO).await.unwrap());
+        assert!(!node.inject_fault(FaultTarget::Mesh, FaultKind::Drop, Duration::ZERO).await.unwrap());
+        assert!(matches!(node.client().request(query(Some(5_000))).await.unwrap(), Response::CoreResult(_)));
+        let metrics = match node.client().request(Request::GetMetrics).await.unwrap() {
+            Response::Metrics(metrics) => metrics,
+            other => panic!("Expected Metrics, got {:?}", other),
+        };
+        assert_eq!(metrics.timeouts.get("QueryCore"), Some(&1));
+        assert_eq!(metrics.timeouts.get("CoreRunNamed"), Some(&1));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shutdown_finishes_while_the_core_hangs() {
+        let node = TestNode::start(Vec::new()).await.unwrap();
+        assert!(node.inject_fault(FaultTarget::Core, FaultKind::Hang, Duration::from_secs(120)).await.unwrap());
+        let hung = node.connect("hung").await.unwrap();
+        let request = tokio::spawn(async move { hung.request(query(Some(60_000))).await });
+        // Until the request is on the node.
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let started = Instant::now();
+        tokio::time::timeout(Duration::from_secs(30), node.shutdown()).await.expect("shutdown hung").unwrap();
+        // The drain gives busy connections 10 seconds, then drops them.
+        assert!(started.elapsed() < Duration::from_secs(15), "took {:?}", started.elapsed());
+        assert!(request.await.unwrap().is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_client_gives_up_on_a_stalled_node_and_connects_again() {
+        let node = start("ipc_idle_timeout_mins = 0\nipc_heartbeat_secs = 1\nipc_missed_heartbeats = 3").await;
+        let idle = node.connect("idle").await.unwrap();
+        assert_eq!(idle.idle_timeout(), Duration::from_secs(4));
+        let lost = Arc::new(AtomicBool::new(false));
+        let noticed = lost.clone();
+        idle.on_disconnect(move || noticed.store(true, Ordering::SeqCst));
+
+        // The answer to this is stalled too, so it returns once writes resume.
+        let started = Instant::now();
+        assert!(node.inject_fault(FaultTarget::IpcWriter, FaultKind::Stall, Duration::from_secs(8)).await.unwrap());
+        assert!(started.elapsed() >= Duration::from_secs(7), "took {:?}", started.elapsed());
+
+        // Silent past its idle timeout with nothing outstanding, the node
+        // was given up on.
+        assert!(lost.load(Ordering::SeqCst));
+        assert_eq!(idle.connection_state(), ConnectionState::Disconnected);
+        assert!(idle.request(Request::Ping).await.is_err());
+
+        let again = node.connect("idle").await.unwrap();
+        assert!(matches!(again.request(Request::Ping).await.unwrap(), Response::Pong));
+        assert_eq!(again.connection_state(), ConnectionState::Connected);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::blocking_pool::BlockingPool;
+#[cfg(feature = "fault-injection")]
+use crate::fault::FaultPlan;
+use sovereign_finance::LicenseVerifier;
+#[cfg(feature = "fault-injection")]
+use sovereign_protocol::{FaultKind, FaultTarget};
+use sovereign_protocol::{FinanceState, LicenseReport, PoolMetrics, Response};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+use tracing::{info, warn};
+
+/// The pause after a failed connection attempt, doubling while they keep
+/// failing.
+const RETRY_MIN: Duration = Duration::from_secs(5);
+const RETRY_MAX: Duration = Duration::from_secs(300);
+
+/// The license verifier, connected in the background so the node starts,
+/// and serves everything else, while Electrum is unreachable.
+pub(crate) struct FinanceBackend {
+    state: watch::Sender<FinanceState>,
+    verifier: watch::Sender<Option<Arc<LicenseVerifier>>>,
+    /// Held through each on-chain check, so clients' checks and the
+    /// periodic one never hit the Electrum server at once.
+    checks: Mutex<()>,
+    /// Runs the verifier's blocking calls, apart from other subsystems'.
+    pool: BlockingPool,
+    /// Set once the node serving with this backend has a plan.
+    #[cfg(feature = "fault-injection")]
+    faults: std::sync::OnceLock<Arc<FaultPlan>>,
+}
+
+impl FinanceBackend {
+    /// Runs `connect` on `pool` until it succeeds.
+    pub fn start<F>(pool: BlockingPool, connect: F) -> Arc<Self>
+    where
+        F: Fn() -> anyhow::Result<LicenseVerifier> + Send + Sync + 'static,
+    {
+        let backend = Arc::new(Self {
+            state: watch::Sender::new(FinanceState::Connecting),
+            verifier: watch::Sender::new(None),
+            checks: Mutex::new(()),
+            pool,
+            #[cfg(feature = "fault-injection")]
+            faults: Default::default(),
+        });
+        let connecting = backend.clone();
+        let connect = Arc::new(connect);
+        tokio::spawn(async move {
+            let mut retry = RETRY_MIN;
+            loop {
+                let attempt = connect.clone();
+                let reason = match connecting.pool.spawn(move || attempt()).await {
+                    Ok(Ok(verifier)) => {
+                        connecting.verifier.send_replace(Some(Arc::new(verifier)));
+                        connecting.state.send_replace(FinanceState::Ready);
+                        info!("License verification is available");
+                        return;
+                    }
+                    Ok(Err(e)) => format!("{:#}", e),
+                    Err(e) => format!("Connection attempt failed: {}", e),
+                };
+                warn!("License verification is unavailable: {}. Retrying in {:?}.", reason, retry);
+                connecting.state.send_replace(FinanceState::Failed { reason });
+                tokio::time::sleep(retry).await;
+                retry = (retry * 2).min(RETRY_MAX);
+            }
+        });
+        backend
+    }
+
+    /// A backend that never connects, for nodes with no Electrum server to
+    /// reach. License checks answer `Unavailable`, and the node's health
+    /// is not marked down for it.
+    #[cfg(any(test, feature = "testkit"))]
+    pub fn offline() -> std::io::Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            state: watch::Sender::new(FinanceState::Connecting),
+            verifier: watch::Sender::new(None),
+            checks: Mutex::new(()),
+            pool: BlockingPool::new("finance", crate::blocking_pool::PoolConfig { threads: 1, queue: 0 })?,
+            #[cfg(feature = "fault-injection")]
+            faults: Default::default(),
+        }))
+    }
+
+    /// Lets `faults` break this backend's license checks.
+    #[cfg(feature = "fault-injection")]
+    pub fn inject_from(&self, faults: Arc<FaultPlan>) {
+        let _ = self.faults.set(faults);
+    }
+
+    pub fn state(&self) -> FinanceState {
+        self.state.borrow().clone()
+    }
+
+    /// Where the finance subsystem's blocking work runs.
+    pub fn pool(&self) -> &BlockingPool {
+        &self.pool
+    }
+
+    pub fn pool_metrics(&self) -> PoolMetrics {
+        self.pool.metrics()
+    }
+
+    /// The verifier once connected, or the response to give until then.
+    pub fn verifier(&self) -> Result<Arc<LicenseVerifier>, Box<Response>> {
+        if let Some(verifier) = self.verifier.borrow().clone() {
+            return Ok(verifier);
+        }
+        let reason = match self.state() {
+            FinanceState::Failed { reason } => reason,
+            _ => "Still connecting to the Electrum server".into(),
+        };
+        Err(Box::new(Response::Unavailable {
+            subsystem: "finance".into(),
+            reason,
+        }))
+    }
+
+    /// Checks `txid` on-chain for this machine, after any check already
+    /// under way. The verdict and the report, or the response to give.
+    pub async fn verify(&self, txid: String, machine_id: String) -> Result<(bool, LicenseReport), Response> {
+        // Ahead of the verifier, so nodes that never connect can be tested too.
+        #[cfg(feature = "fault-injection")]
+        if let Some(faults) = self.faults.get() {
+            match faults.active(FaultTarget::Finance) {
+                Some(FaultKind::NetworkError) => {
+                    return Err(Response::Error("Verification Logic Failed: injected fault: the Electrum server is unreachable".into()))
+                }
+                Some(FaultKind::Hang) => {
+                    let _turn = self.checks.lock().await;
+                    faults.hold(FaultTarget::Finance, FaultKind::Hang).await;
+                }
+                _ => {}
+            }
+        }
+        let verifier = self.verifier().map_err(|response| *response)?;
+        let _turn = self.checks.lock().await;
+        let res = self.pool.spawn(move || verifier.verify_license_report(&txid, &machine_id)).await;
+        match res {
+            Ok(Ok(report)) => Ok((
+                report.valid,
+                LicenseReport {
+                    txid: report.txid,
+                    found: report.found,
+                    paid_sats: report.paid_sats,
+                    payment_ok: report.payment_ok,
+                    binding_ok: report.binding_ok,
+                    confirmations: report.confirmations,
+                    confirmed_height: report.confirmed_height,
+                    tier: report.tier,
+                    valid_until_height: report.valid_until_height,
+                    checked_at_ms: crate::service_loop::unix_millis(),
+                },
+            )),
+            Ok(Err(e)) => Err(Response::Error(format!("Verification Logic Failed: {}", e))),
+            Err(e) => Err(e.into_response("Verification failed")),
+        }
+    }
+}
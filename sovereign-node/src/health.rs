@@ -0,0 +1,346 @@
+use crate::presence::PresenceTable;
+use sovereign_protocol::{FinanceState, HealthLevel, MachineBinding, MeshPhase, NodeResources};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use sysinfo::{Disks, Pid, System};
+
+/// How long a host sample is reused, so status requests stay cheap.
+const SAMPLE_TTL: Duration = Duration::from_secs(2);
+
+/// Where the node's health turns from `Ok`. A limit of 0 is never crossed.
+#[derive(Debug, Clone)]
+pub struct HealthThresholds {
+    pub rss_degraded_bytes: u64,
+    pub rss_critical_bytes: u64,
+    /// A share of the whole machine, in percent.
+    pub cpu_degraded_percent: f64,
+    /// Free space on the data directory's volume below which the node is
+    /// degraded, then critical.
+    pub disk_free_degraded_bytes: u64,
+    pub disk_free_critical_bytes: u64,
+    pub core_size_degraded_bytes: u64,
+    pub wasm_queue_degraded: u64,
+}
+
+impl Default for HealthThresholds {
+    fn default() -> Self {
+        const MB: u64 = 1024 * 1024;
+        Self {
+            rss_degraded_bytes: 1024 * MB,
+            rss_critical_bytes: 4096 * MB,
+            cpu_degraded_percent: 90.0,
+            disk_free_degraded_bytes: 1024 * MB,
+            disk_free_critical_bytes: 100 * MB,
+            core_size_degraded_bytes: 0,
+            wasm_queue_degraded: 32,
+        }
+    }
+}
+
+/// What the host says about the node process and its data directory.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct HostSample {
+    pub rss_bytes: Option<u64>,
+    pub cpu_percent: Option<f64>,
+    pub disk_free_bytes: Option<u64>,
+}
+
+/// Samples the host at most once per `SAMPLE_TTL`.
+pub(crate) struct HostProbe {
+    data_dir: PathBuf,
+    pid: Option<Pid>,
+    cpus: f64,
+    cache: Mutex<ProbeCache>,
+}
+
+struct ProbeCache {
+    system: System,
+    disks: Disks,
+    last: Option<(Instant, HostSample)>,
+}
+
+impl HostProbe {
+    pub fn new(data_dir: &Path) -> Self {
+        Self {
+            data_dir: data_dir.canonicalize().unwrap_or_else(|_| data_dir.to_path_buf()),
+            pid: sysinfo::get_current_pid().ok(),
+            cpus: std::thread::available_parallelism().map_or(1, |n| n.get()) as f64,
+            cache: Mutex::new(ProbeCache {
+                system: System::new(),
+                disks: Disks::new_with_refreshed_list(),
+                last: None,
+            }),
+        }
+    }
+
+    pub fn sample(&self) -> HostSample {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        let ProbeCache { system, disks, last } = &mut *cache;
+        if let Some((at, sample)) = *last {
+            if at.elapsed() < SAMPLE_TTL {
+                return sample;
+            }
+        }
+        let process = match self.pid {
+            Some(pid) if system.refresh_process(pid) => system.process(pid),
+            _ => None,
+        };
+        // The first refresh has nothing to measure CPU use against.
+        let cpu_percent = process.filter(|_| last.is_some()).map(|p| p.cpu_usage() as f64 / self.cpus);
+        disks.refresh();
+        // The volume mounted deepest above the data directory holds it.
+        let disk_free_bytes = disks
+            .iter()
+            .filter(|disk| self.data_dir.starts_with(disk.mount_point()))
+            .max_by_key(|disk| disk.mount_point().as_os_str().len())
+            .map(|disk| disk.available_space());
+        let sample = HostSample {
+            rss_bytes: process.map(|p| p.memory()),
+            cpu_percent,
+            disk_free_bytes,
+        };
+        *last = Some((Instant::now(), sample));
+        sample
+    }
+}
+
+/// Everything the node's health is judged on.
+pub(crate) struct Signals<'a> {
+    pub host: HostSample,
+    pub core_size_bytes: u64,
+    pub wasm_queued: u64,
+    pub finance: &'a FinanceState,
+    pub binding: &'a MachineBinding,
+    pub mesh: &'a MeshPhase,
+    /// Other nodes' beacons, if presence is on.
+    pub presence: Option<&'a PresenceTable>,
+    /// Ops waiting in the replication outbox and its high-water mark, if
+    /// replication is on.
+    pub outbox: Option<(u64, u64)>,
+}
+
+impl Signals<'_> {
+    pub fn resources(&self) -> NodeResources {
+        NodeResources {
+            rss_bytes: self.host.rss_bytes,
+            cpu_percent: self.host.cpu_percent,
+            data_dir_free_bytes: self.host.disk_free_bytes,
+            core_size_bytes: self.core_size_bytes,
+            wasm_queued: self.wasm_queued,
+        }
+    }
+}
+
+/// `Critical` if any critical limit is crossed, else `Degraded` if any
+/// degraded one is, with every reason either way.
+pub(crate) fn assess(signals: &Signals, limits: &HealthThresholds) -> HealthLevel {
+    let over = |value: Option<u64>, limit: u64| limit > 0 && value.is_some_and(|v| v >= limit);
+    let under = |value: Option<u64>, limit: u64| limit > 0 && value.is_some_and(|v| v < limit);
+    let mb = |bytes: Option<u64>| bytes.unwrap_or_default() / (1024 * 1024);
+    let host = &signals.host;
+
+    let mut critical = Vec::new();
+    if over(host.rss_bytes, limits.rss_critical_bytes) {
+        critical.push(format!("memory: {} MB resident", mb(host.rss_bytes)));
+    }
+    if under(host.disk_free_bytes, limits.disk_free_critical_bytes) {
+        critical.push(format!("disk: {} MB free for the data directory", mb(host.disk_free_bytes)));
+    }
+
+    let mut degraded = Vec::new();
+    if critical.is_empty() {
+        if over(host.rss_bytes, limits.rss_degraded_bytes) {
+            degraded.push(format!("memory: {} MB resident", mb(host.rss_bytes)));
+        }
+        if under(host.disk_free_bytes, limits.disk_free_degraded_bytes) {
+            degraded.push(format!("disk: {} MB free for the data directory", mb(host.disk_free_bytes)));
+        }
+    }
+    if let Some(cpu) = host.cpu_percent.filter(|cpu| limits.cpu_degraded_percent > 0.0 && *cpu >= limits.cpu_degraded_percent) {
+        degraded.push(format!("cpu: {:.0}% of the machine", cpu));
+    }
+    if over(Some(signals.core_size_bytes), limits.core_size_degraded_bytes) {
+        degraded.push(format!("core: {} MB on disk", mb(Some(signals.core_size_bytes))));
+    }
+    if over(Some(signals.wasm_queued), limits.wasm_queue_degraded) {
+        degraded.push(format!("wasm: {} executions queued", signals.wasm_queued));
+    }
+    if let FinanceState::Failed { reason } = signals.finance {
+        degraded.push(format!("finance: unavailable ({})", reason));
+    }
+    if let MachineBinding::Unbindable { reason } = signals.binding {
+        degraded.push(format!("license: unbindable ({})", reason));
+    }
+    match signals.mesh {
+        MeshPhase::Restarting { attempt, .. } => degraded.push(format!("mesh: restarting, attempt {}", attempt)),
+        MeshPhase::Failed { reason } => degraded.push(format!("mesh: failed ({})", reason)),
+        _ => {}
+    }
+    if let Some(presence) = signals.presence.filter(|presence| presence.stale() > 0) {
+        degraded.push(format!("presence: {} of {} known nodes stale", presence.stale(), presence.nodes.len()));
+    }
+    if let Some((depth, high_water)) = signals.outbox.filter(|(depth, high_water)| depth >= high_water) {
+        degraded.push(format!("replication: {} ops waiting to be gossiped, high-water mark {}", depth, high_water));
+    }
+
+    match (critical.is_empty(), degraded.is_empty()) {
+        (false, _) => HealthLevel::Critical {
+            reasons: critical.into_iter().chain(degraded).collect(),
+        },
+        (true, false) => HealthLevel::Degraded { reasons: degraded },
+        (true, true) => HealthLevel::Ok,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    /// A healthy node: well inside the default thresholds.
+    fn healthy() -> HostSample {
+        HostSample {
+            rss_bytes: Some(100 * MB),
+            cpu_percent: Some(5.0),
+            disk_free_bytes: Some(10_000 * MB),
+        }
+    }
+
+    /// The subsystems' states, all well unless a test says otherwise.
+    struct Subsystems {
+        finance: FinanceState,
+        binding: MachineBinding,
+        mesh: MeshPhase,
+    }
+
+    impl Default for Subsystems {
+        fn default() -> Self {
+            Self {
+                finance: FinanceState::Ready,
+                binding: MachineBinding::Bound,
+                mesh: MeshPhase::Ready,
+            }
+        }
+    }
+
+    fn assess_with(host: HostSample, limits: &HealthThresholds, tweak: impl FnOnce(&mut Signals)) -> HealthLevel {
+        assess_subsystems(host, limits, &Subsystems::default(), tweak)
+    }
+
+    fn assess_subsystems(host: HostSample, limits: &HealthThresholds, subsystems: &Subsystems, tweak: impl FnOnce(&mut Signals)) -> HealthLevel {
+        let mut signals = Signals {
+            host,
+            core_size_bytes: 10 * MB,
+            wasm_queued: 0,
+            finance: &subsystems.finance,
+            binding: &subsystems.binding,
+            mesh: &subsystems.mesh,
+            presence: None,
+            outbox: None,
+        };
+        tweak(&mut signals);
+        assess(&signals, limits)
+    }
+
+    fn assess_host(host: HostSample) -> HealthLevel {
+        assess_with(host, &HealthThresholds::default(), |_| {})
+    }
+
+    #[test]
+    fn memory_turns_degraded_then_critical() {
+        assert_eq!(assess_host(healthy()), HealthLevel::Ok);
+        let rss = |mb| HostSample { rss_bytes: Some(mb * MB), ..healthy() };
+        assert_eq!(assess_host(rss(1023)), HealthLevel::Ok);
+        assert_eq!(assess_host(rss(1024)), HealthLevel::Degraded { reasons: vec!["memory: 1024 MB resident".into()] });
+        assert_eq!(assess_host(rss(4096)), HealthLevel::Critical { reasons: vec!["memory: 4096 MB resident".into()] });
+    }
+
+    #[test]
+    fn disk_turns_degraded_then_critical() {
+        let free = |mb| HostSample { disk_free_bytes: Some(mb * MB), ..healthy() };
+        assert_eq!(assess_host(free(1024)), HealthLevel::Ok);
+        assert_eq!(assess_host(free(1023)), HealthLevel::Degraded { reasons: vec!["disk: 1023 MB free for the data directory".into()] });
+        assert_eq!(assess_host(free(99)), HealthLevel::Critical { reasons: vec!["disk: 99 MB free for the data directory".into()] });
+    }
+
+    #[test]
+    fn critical_lists_the_degraded_reasons_too() {
+        let host = HostSample {
+            rss_bytes: Some(5000 * MB),
+            cpu_percent: Some(95.0),
+            disk_free_bytes: Some(500 * MB),
+        };
+        let level = assess_with(host, &HealthThresholds::default(), |s| s.wasm_queued = 40);
+        assert_eq!(level.summary(), "CRITICAL");
+        assert_eq!(level.reasons(), ["memory: 5000 MB resident", "cpu: 95% of the machine", "wasm: 40 executions queued"]);
+    }
+
+    #[test]
+    fn subsystems_degrade_the_node() {
+        let limits = HealthThresholds {
+            core_size_degraded_bytes: 100 * MB,
+            ..Default::default()
+        };
+        let reasons = |tweak: fn(&mut Signals)| assess_with(healthy(), &limits, tweak).reasons().to_vec();
+        assert!(reasons(|_| {}).is_empty());
+        assert_eq!(reasons(|s| s.core_size_bytes = 150 * MB), ["core: 150 MB on disk"]);
+        assert_eq!(reasons(|s| s.wasm_queued = 32), ["wasm: 32 executions queued"]);
+        assert_eq!(reasons(|s| s.outbox = Some((10, 10))), ["replication: 10 ops waiting to be gossiped, high-water mark 10"]);
+        assert!(reasons(|s| s.outbox = Some((9, 10))).is_empty());
+
+        let failing = Subsystems {
+            finance: FinanceState::Failed { reason: "no server".into() },
+            binding: MachineBinding::Unbindable { reason: "no uid".into() },
+            mesh: MeshPhase::Restarting { attempt: 2, reason: "it panicked".into() },
+        };
+        let level = assess_subsystems(healthy(), &limits, &failing, |_| {});
+        assert_eq!(
+            level,
+            HealthLevel::Degraded {
+                reasons: vec!["finance: unavailable (no server)".into(), "license: unbindable (no uid)".into(), "mesh: restarting, attempt 2".into()]
+            }
+        );
+        let dead = Subsystems {
+            mesh: MeshPhase::Failed { reason: "gave up".into() },
+            ..Default::default()
+        };
+        assert_eq!(assess_subsystems(healthy(), &limits, &dead, |_| {}).reasons(), ["mesh: failed (gave up)"]);
+    }
+
+    #[test]
+    fn zero_limits_and_unknown_figures_are_never_crossed() {
+        let off = HealthThresholds {
+            rss_degraded_bytes: 0,
+            rss_critical_bytes: 0,
+            cpu_degraded_percent: 0.0,
+            disk_free_degraded_bytes: 0,
+            disk_free_critical_bytes: 0,
+            core_size_degraded_bytes: 0,
+            wasm_queue_degraded: 0,
+        };
+        let strained = HostSample {
+            rss_bytes: Some(u64::MAX),
+            cpu_percent: Some(100.0),
+            disk_free_bytes: Some(0),
+        };
+        assert_eq!(assess_with(strained, &off, |s| s.wasm_queued = 1000), HealthLevel::Ok);
+        assert_eq!(assess_host(HostSample::default()), HealthLevel::Ok);
+    }
+
+    #[test]
+    fn samples_the_host_once_per_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let probe = HostProbe::new(dir.path());
+        let first = probe.sample();
+        assert!(first.rss_bytes.is_some_and(|rss| rss > 0));
+        assert!(first.disk_free_bytes.is_some());
+        // Nothing to measure CPU use against yet.
+        assert_eq!(first.cpu_percent, None);
+
+        let _ballast = vec![1u8; 64 * MB as usize];
+        let again = probe.sample();
+        assert_eq!((again.rss_bytes, again.disk_free_bytes, again.cpu_percent), (first.rss_bytes, first.disk_free_bytes, first.cpu_percent));
+    }
+}
@@ -0,0 +1,619 @@
+use crate::logging::{self, FileLog, RotatingFile};
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use sovereign_protocol::{IpcAuditEntry, Request, Response, SwarmKeyChoice};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+/// Records every IPC request in a JSON Lines file.
+#[derive(Debug, Clone)]
+pub struct IpcAuditConfig {
+    pub file: FileLog,
+    /// Entries waiting for the writer, past which new ones are dropped.
+    pub queue: usize,
+}
+
+enum Message {
+    Entry(IpcAuditEntry),
+    Tail { limit: usize, reply: oneshot::Sender<Vec<IpcAuditEntry>> },
+}
+
+/// Hands entries to a writer thread through a bounded queue, so a request
+/// never waits on the file. Entries that find the queue full are counted
+/// and dropped.
+pub(crate) struct IpcAudit {
+    writer: mpsc::Sender<Message>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// A request being audited, from `IpcAudit::start`. Dropped unfinished,
+/// it is recorded as `aborted`.
+pub(crate) struct Pending {
+    entry: Option<IpcAuditEntry>,
+    started: Instant,
+    writer: mpsc::Sender<Message>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Who sent a request, as its entry records it.
+pub(crate) struct Principal<'a> {
+    pub connection_id: u64,
+    pub client: &'a str,
+    pub uid: Option<u32>,
+    pub token_id: Option<&'a str>,
+}
+
+impl IpcAudit {
+    pub fn open(config: IpcAuditConfig) -> anyhow::Result<Self> {
+        let file = RotatingFile::open(config.file.clone())
+            .with_context(|| format!("Failed to open the IPC audit log {}", config.file.path.display()))?;
+        let (writer, messages) = mpsc::channel(config.queue.max(1));
+        std::thread::Builder::new()
+            .name("ipc-audit".into())
+            .spawn(move || serve(file, config.file, messages))
+            .context("Failed to start the IPC audit writer")?;
+        Ok(Self {
+            writer,
+            dropped: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    pub fn start(&self, principal: Principal, req: &Request) -> Pending {
+        Pending {
+            entry: Some(IpcAuditEntry {
+                at_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+                connection_id: principal.connection_id,
+                client: principal.client.to_string(),
+                uid: principal.uid,
+                token_id: principal.token_id.map(str::to_string),
+                request: req.kind().into(),
+                params: summary(req),
+                outcome: String::new(),
+                duration_ms: 0.0,
+            }),
+            started: Instant::now(),
+            writer: self.writer.clone(),
+            dropped: self.dropped.clone(),
+        }
+    }
+
+    /// The newest `limit` entries, oldest first, once every entry queued
+    /// before the call is written. `None` if the writer has stopped.
+    pub async fn tail(&self, limit: usize) -> Option<Vec<IpcAuditEntry>> {
+        let (reply, entries) = oneshot::channel();
+        self.writer.send(Message::Tail { limit, reply }).await.ok()?;
+        entries.await.ok()
+    }
+
+    /// Entries dropped since startup because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Pending {
+    pub fn finish(mut self, resp: &Response) {
+        self.record(outcome(resp));
+    }
+
+    fn record(&mut self, outcome: String) {
+        let Some(mut entry) = self.entry.take() else { return };
+        entry.outcome = outcome;
+        entry.duration_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+        if self.writer.try_send(Message::Entry(entry)).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.record("aborted".into());
+    }
+}
+
+/// Records `resp` as the request's outcome, if it is audited.
+pub(crate) fn finish(pending: Option<Pending>, resp: &Response) {
+    if let Some(pending) = pending {
+        pending.finish(resp);
+    }
+}
+
+/// Writes entries until every sender is gone. A failed write loses that
+/// entry; the file may be back for the next.
+fn serve(mut file: RotatingFile, config: FileLog, mut messages: mpsc::Receiver<Message>) {
+    let mut failing = false;
+    while let Some(message) = messages.blocking_recv() {
+        match message {
+            Message::Entry(entry) => {
+                let mut line = serde_json::to_string(&entry).unwrap_or_default();
+                line.push('\n');
+                match file.write_all(line.as_bytes()) {
+                    Ok(()) => failing = false,
+                    Err(e) if !failing => {
+                        warn!("Failed to write the IPC audit log: {}", e);
+                        failing = true;
+                    }
+                    Err(_) => {}
+                }
+            }
+            Message::Tail { limit, reply } => {
+                let _ = file.flush();
+                let _ = reply.send(tail(&config, limit));
+            }
+        }
+    }
+}
+
+/// The current file holds the newest lines, `.1` the next newest, and so on.
+fn tail(config: &FileLog, limit: usize) -> Vec<IpcAuditEntry> {
+    let mut lines: Vec<String> = Vec::new();
+    for n in 0..=config.keep {
+        if lines.len() >= limit {
+            break;
+        }
+        let Ok(file) = File::open(logging::numbered(&config.path, n)) else { break };
+        let mut older: Vec<String> = BufReader::new(file).lines().map_while(Result::ok).collect();
+        older.append(&mut lines);
+        lines = older;
+    }
+    let skip = lines.len().saturating_sub(limit);
+    lines[skip..].iter().filter_map(|line| serde_json::from_str(line).ok()).collect()
+}
+
+/// `ok`, or what the node answered instead of doing what was asked.
+pub(crate) fn outcome(resp: &Response) -> String {
+    match resp {
+        Response::CoreFailed(failure) => {
+            let code = serde_json::to_value(failure.code).ok().and_then(|code| code.as_str().map(str::to_string));
+            format!("core_failed:{}", code.unwrap_or_default())
+        }
+        resp => failure(resp).unwrap_or("ok").into(),
+    }
+}
+
+/// What the node answered instead of doing what was asked, if it did not;
+/// `core_failed` for any core failure. Allocates nothing, for counting.
+pub(crate) fn failure(resp: &Response) -> Option<&'static str> {
+    let failure = match resp {
+        Response::Error(_) => "error",
+        Response::PermissionDenied { .. } => "permission_denied",
+        Response::RateLimited { .. } => "rate_limited",
+        Response::Overloaded { .. } => "overloaded",
+        Response::TimedOut { .. } => "timed_out",
+        Response::Unavailable { .. } => "unavailable",
+        Response::Cancelled { .. } => "cancelled",
+        Response::WasmPathRejected { .. } => "wasm_path_rejected",
+        Response::WasmResult { trapped: true, .. } => "trapped",
+        Response::CoreFailed(_) => "core_failed",
+        Response::SetupRequired { .. } => "setup_required",
+        _ => return None,
+    };
+    Some(failure)
+}
+
+/// What an entry records of `req`. Module names, relation names and
+/// limits are kept; query text, filters and transaction ids are hashed,
+/// with the text's length; inputs, rows and vectors are reduced to sizes;
+/// parameter and environment values, tokens and arguments are left out.
+/// Exhaustive on purpose: a new request type does not build until it is
+/// given an entry here.
+pub(crate) fn summary(req: &Request) -> BTreeMap<String, String> {
+    let mut params = Params::default();
+    match req {
+        Request::Ping
+        | Request::GetStatus
+        | Request::GetMetrics
+        | Request::GetVersion
+        | Request::CoreListNamed
+        | Request::CoreQueries
+        | Request::CoreListRelations
+        | Request::CoreBegin
+        | Request::WasmList
+        | Request::WasmExecutions
+        | Request::WasmListJobs
+        | Request::WasmAllowlistList
+        | Request::MeshPeers
+        | Request::MeshPresence
+        | Request::GetLicenseInfo
+        | Request::WatchLicense
+        | Request::SelfCheck
+        | Request::SetupState
+        | Request::ConnectionStats => {}
+        Request::Hello { client_name, protocol_version, token, resume, compressions_supported } => {
+            params.put("client_name", client_name);
+            params.put("protocol_version", protocol_version);
+            params.put("token", token.is_some());
+            if !compressions_supported.is_empty() {
+                params.put("compressions", compressions_supported.join(","));
+            }
+            if let Some(resume) = resume {
+                params.put("resume_session", resume.session_id);
+            }
+        }
+        Request::HeartbeatAck { seq } => params.put("seq", seq),
+        Request::QueryCore { query, params: values, timeout_ms, readonly, limit }
+        | Request::QueryCoreStreamed { query, params: values, timeout_ms, readonly, limit } => {
+            params.text("query", query);
+            params.names("params", values);
+            params.maybe("timeout_ms", timeout_ms);
+            params.put("readonly", readonly);
+            params.maybe("limit", limit);
+        }
+        Request::CoreExplain { query, params: values } => {
+            params.text("query", query);
+            params.names("params", values);
+        }
+        Request::CoreRegisterQuery { name, query, params: specs, readonly } => {
+            params.put("name", name);
+            params.text("query", query);
+            params.put("params", specs.iter().map(|p| p.name.as_str()).collect::<Vec<_>>().join(","));
+            params.put("readonly", readonly);
+        }
+        Request::CoreRunNamed { name, params: values, timeout_ms, readonly } => {
+            params.put("name", name);
+            params.names("params", values);
+            params.maybe("timeout_ms", timeout_ms);
+            params.put("readonly", readonly);
+        }
+        Request::CoreRemoveNamed { name } | Request::CoreDescribe { name } => params.put("name", name),
+        Request::CoreGrant { principal, relation_pattern, rights } => {
+            params.put("principal", principal);
+            params.put("relation_pattern", relation_pattern);
+            params.put("rights", format!("{:?}", rights).to_lowercase());
+        }
+        Request::CoreRevoke { principal, relation_pattern } => {
+            params.put("principal", principal);
+            params.put("relation_pattern", relation_pattern);
+        }
+        Request::CoreListGrants { principal } => params.maybe("principal", principal),
+        Request::ReplicationOutbox { after_seq, include_published, limit } => {
+            params.maybe("after_seq", after_seq);
+            params.put("include_published", include_published);
+            params.maybe("limit", limit);
+        }
+        Request::ReplicationRequeue { seqs } => params.put("seqs", seqs.len()),
+        Request::CoreExport { name, format } => {
+            params.put("name", name);
+            params.put("format", format!("{:?}", format).to_lowercase());
+        }
+        Request::CoreImport { name, format, mode } => {
+            params.put("name", name);
+            params.put("format", format!("{:?}", format).to_lowercase());
+            params.put("mode", format!("{:?}", mode).to_lowercase());
+        }
+        Request::CoreAssert { name, rows } => {
+            params.put("name", name);
+            params.put("rows", rows.len());
+        }
+        Request::CoreRetract { name, keys } => {
+            params.put("name", name);
+            params.put("keys", keys.len());
+        }
+        Request::CoreKnn { name, column, vector, k, filters } => {
+            params.put("name", name);
+            params.put("column", column);
+            params.put("dimensions", vector.len());
+            params.put("k", k);
+            params.names("filters", filters);
+        }
+        Request::CoreWatch { relation, filter } => {
+            params.put("relation", relation);
+            if let Some(filter) = filter {
+                params.text("filter", filter);
+            }
+        }
+        Request::CoreUnwatch { watch_id } => params.put("watch_id", watch_id),
+        Request::CoreAuditTail { limit } | Request::AuditTail { limit } | Request::SlowRequests { limit } => params.put("limit", limit),
+        Request::CoreExec { session_id, query, params: values } => {
+            params.put("session_id", session_id);
+            params.text("query", query);
+            params.names("params", values);
+        }
+        Request::CoreCommit { session_id } | Request::CoreRollback { session_id } => params.put("session_id", session_id),
+        Request::RunWasm { path, input, args, env, fuel_limit, signature } => {
+            params.put("path", path);
+            params.put("input_bytes", input.len());
+            params.put("args", args.len());
+            params.put("env", env.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(","));
+            params.maybe("fuel_limit", fuel_limit);
+            params.put("signed", signature.is_some());
+        }
+        Request::RunWasmStreamed { path, args, env, fuel_limit, signature } => {
+            params.put("path", path);
+            params.put("args", args.len());
+            params.put("env", env.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(","));
+            params.maybe("fuel_limit", fuel_limit);
+            params.put("signed", signature.is_some());
+        }
+        Request::WasmUpload { name, path, signature, watch, .. } => {
+            params.put("name", name);
+            params.put("path", path);
+            params.put("signed", signature.is_some());
+            params.put("watch", watch);
+        }
+        Request::WasmInfo { name }
+        | Request::WasmRemove { name }
+        | Request::WasmDeleteJob { name }
+        | Request::WasmJobHistory { name } => params.put("name", name),
+        Request::Cancel { execution_id } => params.put("execution_id", execution_id),
+        Request::RunWasmModule { name, input, args, env, fuel_limit } => {
+            params.put("name", name);
+            params.put("input_bytes", input.len());
+            params.put("args", args.len());
+            params.put("env", env.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(","));
+            params.maybe("fuel_limit", fuel_limit);
+        }
+        Request::RunWasmPipeline { stages, input, timeout_ms, total_fuel } => {
+            params.put("stages", stages.iter().map(|s| s.module.as_str()).collect::<Vec<_>>().join(","));
+            params.put("input_bytes", input.len());
+            params.maybe("timeout_ms", timeout_ms);
+            params.maybe("total_fuel", total_fuel);
+        }
+        Request::WasmScheduleJob { name, job } => {
+            params.put("name", name);
+            params.put("module", &job.module);
+            params.put("input_bytes", job.input.len());
+        }
+        Request::WasmAllowlistAdd { sha256 } | Request::WasmAllowlistRemove { sha256 } => params.put("sha256", sha256),
+        Request::MeshDial { addr, persist } => {
+            params.put("addr", addr);
+            params.put("persist", persist);
+        }
+        Request::MeshUnpin { addr } => params.put("addr", addr),
+        Request::VerifyLicense { tx_id, developer_addr, required_sats } => {
+            params.hash("tx_id", tx_id);
+            params.hash("developer_addr", developer_addr);
+            params.put("required_sats", required_sats);
+        }
+        // The swarm key itself is never recorded.
+        Request::SetupApply { settings, force } => {
+            params.put("network", &settings.network);
+            params.put(
+                "swarm_key",
+                match settings.swarm_key {
+                    SwarmKeyChoice::Generate => "generate",
+                    SwarmKeyChoice::Import { .. } => "import",
+                },
+            );
+            params.put("bootstrap_peers", settings.bootstrap_peers.len());
+            params.put("accept_license_policy", settings.accept_license_policy);
+            params.put("force", force);
+        }
+        Request::ExportSnapshot { path } | Request::ImportSnapshot { path } => params.put("path", path.as_deref().unwrap_or("streamed")),
+        #[cfg(feature = "fault-injection")]
+        Request::InjectFault { target, kind, duration_ms } => {
+            params.put("target", format!("{:?}", target));
+            params.put("kind", format!("{:?}", kind));
+            params.put("duration_ms", duration_ms);
+        }
+        Request::Subscribe { topics } | Request::Unsubscribe { topics } => {
+            params.put("topics", topics.iter().map(|t| t.name()).collect::<Vec<_>>().join(","));
+        }
+    }
+    params.0
+}
+
+#[derive(Default)]
+struct Params(BTreeMap<String, String>);
+
+impl Params {
+    fn put(&mut self, name: &str, value: impl ToString) {
+        self.0.insert(name.into(), value.to_string());
+    }
+
+    fn maybe(&mut self, name: &str, value: &Option<impl ToString>) {
+        if let Some(value) = value {
+            self.put(name, value.to_string());
+        }
+    }
+
+    /// The hex SHA-256 of `value`, which matches the core audit log's
+    /// `query_hash` for query text.
+    fn hash(&mut self, name: &str, value: &str) {
+        self.put(name, hex::encode(Sha256::digest(value.as_bytes())));
+    }
+
+    /// `value` hashed, and its length in bytes.
+    fn text(&mut self, name: &str, value: &str) {
+        self.hash(&format!("{}_sha256", name), value);
+        self.put(&format!("{}_bytes", name), value.len());
+    }
+
+    /// The keys of an object of values, never the values.
+    fn names(&mut self, name: &str, values: &serde_json::Value) {
+        if let Some(values) = values.as_object().filter(|values| !values.is_empty()) {
+            self.put(name, values.keys().map(String::as_str).collect::<Vec<_>>().join(","));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use sovereign_protocol::{CoreFailure, ErrorCode, SessionResume, SetupSettings, WasmJobSchedule, WasmJobSpec, WasmPipelineStage, WasmSignature};
+
+    /// Planted in requests; never to reach an entry.
+    const SECRET: &str = "hunter2-planted";
+
+    fn value() -> serde_json::Value {
+        json!({ "who": SECRET })
+    }
+
+    fn env() -> Vec<(String, String)> {
+        vec![("API_KEY".into(), SECRET.into())]
+    }
+
+    /// Requests carrying `SECRET` wherever a client might put something
+    /// sensitive.
+    fn planted() -> Vec<Request> {
+        vec![
+            Request::Hello {
+                client_name: "app".into(),
+                protocol_version: 1,
+                token: Some(SECRET.into()),
+                resume: Some(SessionResume { session_id: 3, token: SECRET.into(), last_seq: 9 }),
+                compressions_supported: Vec::new(),
+            },
+            Request::QueryCore { query: format!("?[a] <- [['{}']]", SECRET), params: value(), timeout_ms: None, readonly: false, limit: None },
+            Request::QueryCoreStreamed { query: SECRET.into(), params: value(), timeout_ms: Some(5), readonly: true, limit: Some(1) },
+            Request::CoreExplain { query: SECRET.into(), params: value() },
+            Request::CoreRegisterQuery { name: "named".into(), query: SECRET.into(), params: Vec::new(), readonly: true },
+            Request::CoreRunNamed { name: "named".into(), params: value(), timeout_ms: None, readonly: false },
+            Request::CoreAssert { name: "people".into(), rows: vec![value()] },
+            Request::CoreRetract { name: "people".into(), keys: vec![json!(SECRET)] },
+            Request::CoreKnn { name: "docs".into(), column: "v".into(), vector: vec![0.5], k: 3, filters: value() },
+            Request::CoreWatch { relation: "people".into(), filter: Some(SECRET.into()) },
+            Request::CoreExec { session_id: 1, query: SECRET.into(), params: value() },
+            Request::RunWasm { path: "/m/echo.wasm".into(), input: SECRET.into(), args: vec![SECRET.into()], env: env(), fuel_limit: None, signature: None },
+            Request::RunWasmStreamed {
+                path: "/m/echo.wasm".into(),
+                args: vec![SECRET.into()],
+                env: env(),
+                fuel_limit: None,
+                signature: Some(WasmSignature { key_id: "dev".into(), signature: SECRET.into() }),
+            },
+            Request::RunWasmModule { name: "echo".into(), input: SECRET.into(), args: vec![SECRET.into()], env: env(), fuel_limit: Some(10) },
+            Request::RunWasmPipeline {
+                stages: vec![WasmPipelineStage { module: "echo".into(), fuel_limit: None, max_memory_bytes: None }],
+                input: SECRET.into(),
+                timeout_ms: None,
+                total_fuel: None,
+            },
+            Request::WasmScheduleJob {
+                name: "nightly".into(),
+                job: WasmJobSpec {
+                    module: "echo".into(),
+                    input: SECRET.into(),
+                    schedule: WasmJobSchedule::Cron { spec: "0 0 * * *".into() },
+                    fuel_limit: None,
+                    max_memory_bytes: None,
+                    overlap: Default::default(),
+                    enabled: true,
+                },
+            },
+            Request::VerifyLicense { tx_id: SECRET.into(), developer_addr: SECRET.into(), required_sats: 50_000 },
+            Request::SetupApply {
+                settings: SetupSettings {
+                    network: "bitcoin".into(),
+                    swarm_key: SwarmKeyChoice::Import { key: SECRET.into() },
+                    bootstrap_peers: Vec::new(),
+                    accept_license_policy: true,
+                    data_dir: "/var/lib/sovereign".into(),
+                },
+                force: false,
+            },
+        ]
+    }
+
+    #[test]
+    fn never_records_what_requests_carry() {
+        for req in planted() {
+            let summary = serde_json::to_string(&summary(&req)).unwrap();
+            assert!(!summary.contains(SECRET), "{} records {}", req.kind(), summary);
+        }
+    }
+
+    #[test]
+    fn keeps_what_review_needs() {
+        let query = format!("?[a] <- [['{}']]", SECRET);
+        let summary = summary(&planted()[1]);
+        assert_eq!(summary["query_sha256"], hex::encode(Sha256::digest(query.as_bytes())));
+        assert_eq!(summary["query_bytes"], query.len().to_string());
+        assert_eq!(summary["params"], "who");
+        assert_eq!(summary["readonly"], "false");
+
+        let summary = super::summary(&planted()[0]);
+        assert_eq!((summary["client_name"].as_str(), summary["token"].as_str(), summary["resume_session"].as_str()), ("app", "true", "3"));
+        let summary = super::summary(&planted()[13]);
+        assert_eq!((summary["name"].as_str(), summary["env"].as_str(), summary["input_bytes"].as_str()), ("echo", "API_KEY", "15"));
+        let summary = super::summary(&planted()[16]);
+        assert_eq!(summary["tx_id"], hex::encode(Sha256::digest(SECRET.as_bytes())));
+        assert_eq!(super::summary(&planted()[17])["swarm_key"], "import");
+        assert!(super::summary(&Request::Ping).is_empty());
+    }
+
+    #[test]
+    fn names_how_requests_ended() {
+        assert_eq!(outcome(&Response::Pong), "ok");
+        assert_eq!(outcome(&Response::PermissionDenied { kind: "RunWasm".into(), permission: sovereign_protocol::Permission::RunWasm }), "permission_denied");
+        let failed = Response::CoreFailed(CoreFailure {
+            code: ErrorCode::ReadOnlyViolation,
+            message: String::new(),
+            line: None,
+            column: None,
+        });
+        assert_eq!(outcome(&failed), "core_failed:read_only_violation");
+        assert_eq!(failure(&failed), Some("core_failed"));
+        assert_eq!(failure(&Response::Pong), None);
+    }
+
+    fn open(dir: &std::path::Path, queue: usize, max_bytes: u64) -> IpcAudit {
+        IpcAudit::open(IpcAuditConfig {
+            file: FileLog {
+                path: dir.join("ipc-audit.jsonl"),
+                max_bytes,
+                max_age: None,
+                keep: 8,
+            },
+            queue,
+        })
+        .unwrap()
+    }
+
+    fn principal(client: &str) -> Principal<'_> {
+        Principal {
+            connection_id: 7,
+            client,
+            uid: Some(1000),
+            token_id: Some("0123456789ab"),
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_entries_and_tails_them_across_rotations() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit = open(dir.path(), 64, 1024);
+        for n in 0..12 {
+            audit.start(principal(&format!("client-{}", n)), &planted()[1]).finish(&Response::Pong);
+        }
+        // Dropped unfinished, as when a connection closes mid-request.
+        drop(audit.start(principal("gone"), &Request::GetStatus));
+
+        let entries = audit.tail(100).await.unwrap();
+        assert_eq!(entries.len(), 13);
+        assert_eq!(entries[0].client, "client-0");
+        assert_eq!((entries[0].connection_id, entries[0].uid, entries[0].token_id.as_deref()), (7, Some(1000), Some("0123456789ab")));
+        assert_eq!((entries[0].request.as_str(), entries[0].outcome.as_str()), ("QueryCore", "ok"));
+        assert_eq!((entries[12].request.as_str(), entries[12].outcome.as_str()), ("GetStatus", "aborted"));
+        let newest: Vec<_> = audit.tail(2).await.unwrap().into_iter().map(|e| e.client).collect();
+        assert_eq!(newest, ["client-11", "gone"]);
+
+        // Entries past 1 KiB went to older files, none holding the secret.
+        assert!(dir.path().join("ipc-audit.jsonl.1").exists());
+        for file in std::fs::read_dir(dir.path()).unwrap() {
+            assert!(!std::fs::read_to_string(file.unwrap().path()).unwrap().contains(SECRET));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn counts_what_a_full_queue_drops() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit = open(dir.path(), 1, u64::MAX);
+        let sent = 2000;
+        for _ in 0..sent {
+            audit.start(principal("flood"), &Request::Ping).finish(&Response::Pong);
+        }
+        // Each entry was either written or counted.
+        let written = audit.tail(usize::MAX).await.unwrap().len() as u64;
+        assert!(audit.dropped() > 0, "{} written", written);
+        assert_eq!(written + audit.dropped(), sent);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::access::PermissionSet;
+use crate::core_watches::CoreWatches;
+use crate::event_bus::Subscription;
+use crate::service_loop::SharedState;
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::debug;
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// What a connection's session keeps while it is disconnected. Core
+/// transactions are not among it: they roll back when the connection
+/// closes, as without sessions.
+pub(crate) struct Resumable {
+    /// The connecting user's, which a resuming connection must share.
+    pub uid: Option<u32>,
+    pub client_name: String,
+    pub namespace: Option<String>,
+    pub permissions: PermissionSet,
+    pub principal: Option<String>,
+    pub token_id: Option<String>,
+    pub subscription: Subscription,
+    pub watches: CoreWatches,
+    pub license_watch: Option<(watch::Receiver<SharedState>, bool)>,
+}
+
+struct Parked {
+    /// SHA-256 of the resumption token.
+    token: [u8; 32],
+    /// Tells a reaper whether the session was resumed and parked again
+    /// since it was started.
+    epoch: u64,
+    state: Resumable,
+}
+
+/// Sessions whose connection went away, each kept for `grace` in case the
+/// client reconnects. Dropping one releases its subscriptions and stops its
+/// watches.
+pub(crate) struct SessionStore {
+    grace: Duration,
+    parked: Mutex<HashMap<u64, Parked>>,
+    epochs: AtomicU64,
+}
+
+impl SessionStore {
+    /// With a `grace` of zero, no sessions are handed out.
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            grace,
+            parked: Mutex::new(HashMap::new()),
+            epochs: AtomicU64::new(0),
+        }
+    }
+
+    pub fn grace(&self) -> Duration {
+        self.grace
+    }
+
+    /// A new session id and its resumption token, or `None` if sessions
+    /// are off or no random token can be had.
+    pub fn issue(&self) -> Option<(u64, String)> {
+        if self.grace.is_zero() {
+            return None;
+        }
+        let mut token = [0u8; 32];
+        OsRng.try_fill_bytes(&mut token).ok()?;
+        Some((NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed), hex::encode(token)))
+    }
+
+    /// Keeps `state` until it is resumed or `grace` passes.
+    pub fn park(self: &Arc<Self>, session_id: u64, token: &str, state: Resumable) {
+        let epoch = self.epochs.fetch_add(1, Ordering::Relaxed);
+        self.lock().insert(
+            session_id,
+            Parked {
+                token: Sha256::digest(token.as_bytes()).into(),
+                epoch,
+                state,
+            },
+        );
+        let store = Arc::downgrade(self);
+        let grace = self.grace;
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            let Some(store) = store.upgrade() else { return };
+            let mut parked = store.lock();
+            if parked.get(&session_id).is_some_and(|p| p.epoch == epoch) {
+                parked.remove(&session_id);
+                debug!("IPC session {} was not resumed within {:?}; released it", session_id, grace);
+            }
+        });
+    }
+
+    /// Takes the parked session `session_id` if `token` is its token and
+    /// `uid` the user it was parked by. Otherwise it stays parked.
+    pub fn resume(&self, session_id: u64, token: &str, uid: Option<u32>) -> Option<Resumable> {
+        let presented: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+        let mut parked = self.lock();
+        let known = parked.get(&session_id)?;
+        let differs = known.token.iter().zip(presented.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if differs != 0 || known.state.uid != uid {
+            return None;
+        }
+        parked.remove(&session_id).map(|p| p.state)
+    }
+
+    /// Sessions waiting to be resumed.
+    pub fn parked(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Parked>> {
+        self.parked.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(uid: Option<u32>, client_name: &str) -> Resumable {
+        Resumable {
+            uid,
+            client_name: client_name.into(),
+            namespace: None,
+            permissions: PermissionSet::all(),
+            principal: None,
+            token_id: None,
+            subscription: Subscription::default(),
+            watches: CoreWatches::new(),
+            license_watch: None,
+        }
+    }
+
+    #[test]
+    fn hands_out_no_sessions_without_a_grace() {
+        assert!(SessionStore::new(Duration::ZERO).issue().is_none());
+    }
+
+    #[test]
+    fn issues_distinct_ids_and_random_tokens() {
+        let store = SessionStore::new(Duration::from_secs(30));
+        let (first_id, first_token) = store.issue().unwrap();
+        let (second_id, second_token) = store.issue().unwrap();
+        assert_ne!(first_id, second_id);
+        assert_ne!(first_token, second_token);
+        assert_eq!(first_token.len(), 64);
+        assert!(first_token.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[tokio::test]
+    async fn resumes_only_with_the_token_and_user_it_was_parked_by() {
+        let store = Arc::new(SessionStore::new(Duration::from_secs(30)));
+        let (id, token) = store.issue().unwrap();
+        store.park(id, &token, state(Some(1000), "alice"));
+
+        assert!(store.resume(id, "not-the-token", Some(1000)).is_none());
+        assert!(store.resume(id, &token, Some(1001)).is_none());
+        assert!(store.resume(id, &token, None).is_none());
+        assert!(store.resume(id + 1, &token, Some(1000)).is_none());
+        assert_eq!(store.parked(), 1, "a refused resume leaves the session parked");
+
+        let resumed = store.resume(id, &token, Some(1000)).unwrap();
+        assert_eq!(resumed.client_name, "alice");
+        assert_eq!(store.parked(), 0);
+        assert!(store.resume(id, &token, Some(1000)).is_none(), "a session is resumed once");
+    }
+
+    #[tokio::test]
+    async fn releases_a_session_not_resumed_within_the_grace() {
+        let store = Arc::new(SessionStore::new(Duration::from_millis(100)));
+        let (id, token) = store.issue().unwrap();
+        store.park(id, &token, state(None, "bob"));
+        assert_eq!(store.parked(), 1);
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(store.parked(), 0);
+        assert!(store.resume(id, &token, None).is_none());
+    }
+
+    #[tokio::test]
+    async fn a_session_parked_again_outlives_its_earlier_reaper() {
+        let store = Arc::new(SessionStore::new(Duration::from_millis(300)));
+        let (id, token) = store.issue().unwrap();
+        store.park(id, &token, state(None, "carol"));
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let resumed = store.resume(id, &token, None).unwrap();
+        store.park(id, &token, resumed);
+
+        // The first reaper wakes here and must leave the second parking be.
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_eq!(store.parked(), 1);
+        assert!(store.resume(id, &token, None).is_some());
+    }
+}
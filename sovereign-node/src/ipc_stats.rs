@@ -0,0 +1,423 @@
+use crate::ipc_audit;
+use sovereign_protocol::{IpcConnectionInfo, IpcRequestStats, Request, Response, SlowRequest, LATENCY_BUCKETS_MS};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::warn;
+
+/// Slow requests kept for `Request::SlowRequests`, oldest dropped first.
+const SLOW_REQUESTS_KEPT: usize = 128;
+
+/// Requests by `Request::kind`: counts, errors and a latency histogram,
+/// each an atomic, so recording takes a shared lock only to find the kind.
+#[derive(Default)]
+struct KindTable(RwLock<HashMap<&'static str, Arc<KindStats>>>);
+
+#[derive(Default)]
+struct KindStats {
+    count: AtomicU64,
+    errors: AtomicU64,
+    total_micros: AtomicU64,
+    /// Requests that fell in each of `LATENCY_BUCKETS_MS`, not cumulative;
+    /// what took longer is only in `count`.
+    buckets: [AtomicU64; LATENCY_BUCKETS_MS.len()],
+}
+
+impl KindTable {
+    fn record(&self, kind: &'static str, took: Duration, failed: bool) {
+        let stats = self.0.read().unwrap_or_else(|e| e.into_inner()).get(kind).cloned();
+        let stats = match stats {
+            Some(stats) => stats,
+            None => self.0.write().unwrap_or_else(|e| e.into_inner()).entry(kind).or_default().clone(),
+        };
+        stats.count.fetch_add(1, Ordering::Relaxed);
+        stats.total_micros.fetch_add(took.as_micros() as u64, Ordering::Relaxed);
+        if failed {
+            stats.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        let ms = took.as_millis() as u64;
+        if let Some(bucket) = LATENCY_BUCKETS_MS.iter().position(|&bound| ms <= bound) {
+            stats.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> BTreeMap<String, IpcRequestStats> {
+        let table = self.0.read().unwrap_or_else(|e| e.into_inner());
+        table.iter().map(|(kind, stats)| (kind.to_string(), stats.snapshot())).collect()
+    }
+}
+
+impl KindStats {
+    fn snapshot(&self) -> IpcRequestStats {
+        let latency = self
+            .buckets
+            .iter()
+            .scan(0, |below, bucket| {
+                *below += bucket.load(Ordering::Relaxed);
+                Some(*below)
+            })
+            .collect();
+        IpcRequestStats {
+            count: self.count.load(Ordering::Relaxed),
+            total_micros: self.total_micros.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            latency,
+        }
+    }
+}
+
+/// Request and traffic figures for the IPC listener: node-wide, per open
+/// connection, and the requests that took longer than `slow_threshold`.
+pub(crate) struct IpcStats {
+    requests: KindTable,
+    open: Mutex<BTreeMap<u64, Arc<ConnectionStats>>>,
+    /// Bytes of connections that have closed.
+    closed_in: AtomicU64,
+    closed_out: AtomicU64,
+    slow: Mutex<VecDeque<SlowRequest>>,
+    slow_threshold: Option<Duration>,
+}
+
+/// One connection's figures, shared with its `Metered` stream.
+pub(crate) struct ConnectionStats {
+    id: u64,
+    client: Mutex<String>,
+    uid: Option<u32>,
+    opened_at_ms: u64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    requests: KindTable,
+}
+
+/// An open connection's place in `IpcStats`, from `IpcStats::open`. Dropping
+/// it counts the connection closed.
+pub(crate) struct Connection {
+    stats: Arc<ConnectionStats>,
+    node: Arc<IpcStats>,
+}
+
+/// A request being timed, from `IpcStats::start`.
+pub(crate) struct Timing {
+    kind: &'static str,
+    at_ms: u64,
+    started: Instant,
+    /// What the request asked for, kept only in case it turns out slow.
+    params: Option<BTreeMap<String, String>>,
+}
+
+impl IpcStats {
+    /// `slow_threshold` unset records no slow requests.
+    pub fn new(slow_threshold: Option<Duration>) -> Self {
+        Self {
+            requests: KindTable::default(),
+            open: Mutex::default(),
+            closed_in: AtomicU64::new(0),
+            closed_out: AtomicU64::new(0),
+            slow: Mutex::default(),
+            slow_threshold,
+        }
+    }
+
+    pub fn open(self: &Arc<Self>, id: u64, client: &str, uid: Option<u32>) -> Connection {
+        let stats = Arc::new(ConnectionStats {
+            id,
+            client: Mutex::new(client.to_string()),
+            uid,
+            opened_at_ms: now_ms(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            requests: KindTable::default(),
+        });
+        self.open.lock().unwrap_or_else(|e| e.into_inner()).insert(id, stats.clone());
+        Connection { stats, node: self.clone() }
+    }
+
+    /// Starts timing `req`. Its parameters are summarised now, while the
+    /// request is at hand, but only if slow requests are recorded.
+    pub fn start(&self, req: &Request) -> Timing {
+        Timing {
+            kind: req.kind(),
+            at_ms: now_ms(),
+            started: Instant::now(),
+            params: self.slow_threshold.map(|_| ipc_audit::summary(req)),
+        }
+    }
+
+    /// Requests handled on every connection, open or closed.
+    pub fn requests(&self) -> BTreeMap<String, IpcRequestStats> {
+        self.requests.snapshot()
+    }
+
+    /// Bytes read from and written to clients, on every connection.
+    pub fn bytes(&self) -> (u64, u64) {
+        let open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        open.values().fold(
+            (self.closed_in.load(Ordering::Relaxed), self.closed_out.load(Ordering::Relaxed)),
+            |(bytes_in, bytes_out), conn| (bytes_in + conn.bytes_in.load(Ordering::Relaxed), bytes_out + conn.bytes_out.load(Ordering::Relaxed)),
+        )
+    }
+
+    pub fn connections(&self) -> Vec<IpcConnectionInfo> {
+        let open: Vec<_> = self.open.lock().unwrap_or_else(|e| e.into_inner()).values().cloned().collect();
+        open.iter().map(|conn| conn.info()).collect()
+    }
+
+    /// The newest `limit` slow requests, oldest first.
+    pub fn slow(&self, limit: usize) -> Vec<SlowRequest> {
+        let slow = self.slow.lock().unwrap_or_else(|e| e.into_inner());
+        slow.iter().skip(slow.len().saturating_sub(limit)).cloned().collect()
+    }
+
+    pub fn slow_threshold(&self) -> Option<Duration> {
+        self.slow_threshold
+    }
+}
+
+impl ConnectionStats {
+    fn info(&self) -> IpcConnectionInfo {
+        IpcConnectionInfo {
+            connection_id: self.id,
+            client: self.client.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+            uid: self.uid,
+            opened_at_ms: self.opened_at_ms,
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            requests: self.requests.snapshot(),
+        }
+    }
+}
+
+impl Connection {
+    /// Wraps the connection's stream so its traffic is counted.
+    pub fn meter<S>(&self, stream: S) -> Metered<S> {
+        Metered {
+            inner: Box::pin(stream),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// The name the client said Hello with.
+    pub fn rename(&self, client: &str) {
+        *self.stats.client.lock().unwrap_or_else(|e| e.into_inner()) = client.to_string();
+    }
+
+    /// Records the request `timing` was started for as answered with `resp`,
+    /// and as slow if it took longer than the threshold.
+    pub fn finish(&self, timing: Timing, resp: &Response) {
+        let took = timing.started.elapsed();
+        let failed = ipc_audit::failure(resp).is_some();
+        self.stats.requests.record(timing.kind, took, failed);
+        self.node.requests.record(timing.kind, took, failed);
+        if self.node.slow_threshold.is_none_or(|threshold| took < threshold) {
+            return;
+        }
+        let slow = SlowRequest {
+            at_ms: timing.at_ms,
+            connection_id: self.stats.id,
+            client: self.stats.client.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+            request: timing.kind.into(),
+            duration_ms: took.as_secs_f64() * 1000.0,
+            outcome: ipc_audit::outcome(resp),
+            params: timing.params.unwrap_or_default(),
+        };
+        warn!(
+            request = %slow.request,
+            duration_ms = slow.duration_ms,
+            connection_id = slow.connection_id,
+            client = %slow.client,
+            outcome = %slow.outcome,
+            params = ?slow.params,
+            "Slow IPC request"
+        );
+        let mut kept = self.node.slow.lock().unwrap_or_else(|e| e.into_inner());
+        if kept.len() == SLOW_REQUESTS_KEPT {
+            kept.pop_front();
+        }
+        kept.push_back(slow);
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.node.open.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.stats.id);
+        self.node.closed_in.fetch_add(self.stats.bytes_in.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.node.closed_out.fetch_add(self.stats.bytes_out.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+/// Records `resp` as the answer to the request `timing` was started for.
+pub(crate) fn finish(conn: &Connection, timing: Option<Timing>, resp: &Response) {
+    if let Some(timing) = timing {
+        conn.finish(timing, resp);
+    }
+}
+
+/// A connection's stream, counting the bytes that cross it.
+pub(crate) struct Metered<S> {
+    inner: Pin<Box<S>>,
+    stats: Arc<ConnectionStats>,
+}
+
+impl<S: AsyncRead> AsyncRead for Metered<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let polled = self.inner.as_mut().poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = polled {
+            self.stats.bytes_in.fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        }
+        polled
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for Metered<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let polled = self.inner.as_mut().poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = polled {
+            self.stats.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        polled
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_shutdown(cx)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn query(text: &str) -> Request {
+        Request::QueryCore {
+            query: text.into(),
+            params: serde_json::json!({ "password": "hunter2" }),
+            timeout_ms: None,
+            readonly: true,
+            limit: None,
+        }
+    }
+
+    /// Finishes `timing` as if the request had taken `took`.
+    fn finish_after(conn: &Connection, mut timing: Timing, took: Duration, resp: &Response) {
+        timing.started -= took;
+        conn.finish(timing, resp);
+    }
+
+    #[test]
+    fn keeps_requests_slower_than_the_threshold_with_what_they_asked() {
+        let stats = Arc::new(IpcStats::new(Some(Duration::from_millis(100))));
+        let conn = stats.open(7, "connection-7", Some(1000));
+        conn.rename("dashboard");
+
+        let fast = stats.start(&Request::Ping);
+        conn.finish(fast, &Response::Pong);
+        let slow = stats.start(&query("?[x] := x = 1"));
+        finish_after(&conn, slow, Duration::from_millis(250), &Response::Error("Query timed out".into()));
+
+        let kept = stats.slow(10);
+        assert_eq!(kept.len(), 1, "{:?}", kept);
+        let slow = &kept[0];
+        assert_eq!((slow.connection_id, slow.client.as_str(), slow.request.as_str()), (7, "dashboard", "QueryCore"));
+        assert!(slow.duration_ms >= 250.0, "{}", slow.duration_ms);
+        assert!(slow.at_ms > 0);
+        assert_ne!(slow.outcome, "ok");
+        // The redacted summary: the query's size and hash, the names of its
+        // parameters, never their values.
+        assert_eq!(slow.params.get("query_bytes").map(String::as_str), Some("13"));
+        assert!(slow.params.contains_key("query_sha256"), "{:?}", slow.params);
+        assert_eq!(slow.params.get("params").map(String::as_str), Some("password"));
+        assert!(!format!("{:?}", slow.params).contains("hunter2"), "{:?}", slow.params);
+        assert_eq!(stats.slow_threshold(), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn keeps_no_slow_requests_without_a_threshold() {
+        let stats = Arc::new(IpcStats::new(None));
+        let conn = stats.open(1, "connection-1", None);
+        let timing = stats.start(&query("?[x] := x = 1"));
+        assert!(timing.params.is_none(), "summarised for nothing");
+        finish_after(&conn, timing, Duration::from_secs(30), &Response::Pong);
+        assert!(stats.slow(10).is_empty());
+        assert_eq!(stats.requests()["QueryCore"].count, 1);
+    }
+
+    #[test]
+    fn keeps_the_newest_slow_requests() {
+        let stats = Arc::new(IpcStats::new(Some(Duration::ZERO)));
+        let conn = stats.open(1, "connection-1", None);
+        for n in 0..SLOW_REQUESTS_KEPT + 5 {
+            // Told apart by their length.
+            let timing = stats.start(&query(&"x".repeat(n + 1)));
+            conn.finish(timing, &Response::Pong);
+        }
+        let kept = stats.slow(usize::MAX);
+        assert_eq!(kept.len(), SLOW_REQUESTS_KEPT);
+        assert_eq!(kept[0].params["query_bytes"], "6");
+        let newest = stats.slow(2);
+        let lengths: Vec<&str> = newest.iter().map(|s| s.params["query_bytes"].as_str()).collect();
+        assert_eq!(lengths, [(SLOW_REQUESTS_KEPT + 4).to_string(), (SLOW_REQUESTS_KEPT + 5).to_string()]);
+    }
+
+    #[test]
+    fn counts_by_kind_per_connection_and_node_wide() {
+        let stats = Arc::new(IpcStats::new(None));
+        let first = stats.open(1, "first", None);
+        let second = stats.open(2, "second", None);
+        finish_after(&first, stats.start(&Request::Ping), Duration::ZERO, &Response::Pong);
+        finish_after(&first, stats.start(&Request::Ping), Duration::from_millis(30), &Response::Pong);
+        finish_after(&second, stats.start(&Request::Ping), Duration::from_secs(60), &Response::Error("no".into()));
+        finish_after(&second, stats.start(&Request::GetStatus), Duration::from_millis(3), &Response::Pong);
+
+        let ping = &stats.requests()["Ping"];
+        assert_eq!((ping.count, ping.errors), (3, 1));
+        assert!(ping.total_micros >= 60_030_000, "{}", ping.total_micros);
+        // Cumulative over LATENCY_BUCKETS_MS; a minute is past the last.
+        assert_eq!(ping.latency.len(), LATENCY_BUCKETS_MS.len());
+        assert_eq!(ping.latency[0], 1);
+        assert_eq!(ping.latency[LATENCY_BUCKETS_MS.iter().position(|&b| b == 50).unwrap()], 2);
+        assert_eq!(*ping.latency.last().unwrap(), 2);
+        assert_eq!(stats.requests()["GetStatus"].latency[2], 1);
+
+        let connections = stats.connections();
+        assert_eq!(connections.iter().map(|c| c.connection_id).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(connections[0].requests["Ping"].count, 2);
+        assert_eq!(connections[1].requests["Ping"].count, 1);
+        assert!(!connections[0].requests.contains_key("GetStatus"));
+    }
+
+    #[tokio::test]
+    async fn meters_bytes_and_keeps_them_once_the_connection_closes() {
+        let stats = Arc::new(IpcStats::new(None));
+        let conn = stats.open(1, "client", None);
+        let (client, server) = tokio::io::duplex(64);
+        let mut metered = conn.meter(server);
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        client_write.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        metered.read_exact(&mut buf).await.unwrap();
+        metered.write_all(b"hi there").await.unwrap();
+        let mut reply = [0u8; 8];
+        client_read.read_exact(&mut reply).await.unwrap();
+
+        assert_eq!(stats.bytes(), (5, 8));
+        assert_eq!((stats.connections()[0].bytes_in, stats.connections()[0].bytes_out), (5, 8));
+        drop(metered);
+        drop(conn);
+        assert!(stats.connections().is_empty());
+        assert_eq!(stats.bytes(), (5, 8));
+    }
+}
@@ -0,0 +1,235 @@
+//! IPC over TCP, for clients on other machines; off unless `tcp.listen` is
+//! set. Connections are TLS unless plaintext was explicitly allowed, and
+//! each must present an access token in Hello whatever the default and
+//! user rules grant. Past that they are served like local ones.
+
+use crate::ipc_transport::{AcceptFuture, Accepted, BoxedStream, IpcListener};
+use anyhow::{anyhow, bail, Context};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use sovereign_protocol::CertPin;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+
+/// How long a client gets to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TLS handshakes under way at once; past it no more clients are accepted
+/// until one ends.
+const MAX_HANDSHAKES: usize = 64;
+
+/// Clients through their handshake, waiting for the accept loop.
+const ACCEPTED_QUEUE: usize = 16;
+
+/// Where and how to listen, from `[tcp]`.
+#[derive(Debug, Clone)]
+pub(crate) struct TcpConfig {
+    pub addr: SocketAddr,
+    pub security: TcpSecurity,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum TcpSecurity {
+    /// The PEM certificate chain and key to serve. With `generate`, a
+    /// self-signed pair is made where the certificate is missing. The
+    /// certificate's pin is written to `fingerprint`.
+    Tls {
+        cert: PathBuf,
+        key: PathBuf,
+        generate: bool,
+        fingerprint: PathBuf,
+    },
+    /// Only with `tcp.i_know_this_is_insecure`.
+    Plaintext,
+}
+
+/// Accepts TCP clients on a task of its own, so a slow TLS handshake holds
+/// up no other client. Dropping it stops listening.
+pub(crate) struct TcpIpcListener {
+    accepted: mpsc::Receiver<io::Result<Accepted>>,
+    task: JoinHandle<()>,
+}
+
+impl TcpIpcListener {
+    pub async fn bind(config: &TcpConfig) -> anyhow::Result<Self> {
+        let acceptor = match &config.security {
+            TcpSecurity::Tls { cert, key, generate, fingerprint } => Some(TlsAcceptor::from(server_config(cert, key, *generate, fingerprint)?)),
+            TcpSecurity::Plaintext => {
+                warn!("TCP IPC on {} is plaintext: tokens and data cross the network in the clear", config.addr);
+                None
+            }
+        };
+        let listener = TcpListener::bind(config.addr).await.with_context(|| format!("Failed to listen for TCP IPC on {}", config.addr))?;
+        let (tx, accepted) = mpsc::channel(ACCEPTED_QUEUE);
+        let task = tokio::spawn(accept_loop(listener, acceptor, tx));
+        Ok(Self { accepted, task })
+    }
+}
+
+impl IpcListener for TcpIpcListener {
+    fn accept(&mut self) -> AcceptFuture<'_> {
+        Box::pin(async move {
+            match self.accepted.recv().await {
+                Some(accepted) => accepted,
+                // Only if the task panicked; taken as fatal.
+                None => Err(io::Error::new(io::ErrorKind::InvalidInput, "The TCP listener's task stopped")),
+            }
+        })
+    }
+}
+
+impl Drop for TcpIpcListener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Accepts clients and hands each on once its TLS handshake is done.
+/// Failed accepts are handed on too, for the accept loop to weigh.
+async fn accept_loop(listener: TcpListener, acceptor: Option<TlsAcceptor>, accepted: mpsc::Sender<io::Result<Accepted>>) {
+    let handshakes = Arc::new(Semaphore::new(MAX_HANDSHAKES));
+    loop {
+        let Ok(permit) = handshakes.clone().acquire_owned().await else { return };
+        let (stream, remote) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                if accepted.send(Err(e)).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+        let _ = stream.set_nodelay(true);
+        let Some(acceptor) = acceptor.clone() else {
+            let client = Accepted {
+                stream: Box::new(stream) as BoxedStream,
+                peer: None,
+                remote: Some(remote),
+            };
+            if accepted.send(Ok(client)).await.is_err() {
+                return;
+            }
+            continue;
+        };
+        let accepted = accepted.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let client = Accepted {
+                        stream: Box::new(stream) as BoxedStream,
+                        peer: None,
+                        remote: Some(remote),
+                    };
+                    let _ = accepted.send(Ok(client)).await;
+                }
+                Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", remote, e),
+                Err(_) => debug!("TLS handshake with {} took longer than {:?}", remote, HANDSHAKE_TIMEOUT),
+            }
+        });
+    }
+}
+
+/// Loads the certificate and key, generating them first if asked to and
+/// the certificate is missing, and logs and writes the certificate's pin.
+fn server_config(cert: &Path, key: &Path, generate: bool, fingerprint: &Path) -> anyhow::Result<Arc<ServerConfig>> {
+    if generate && !cert.exists() {
+        self_signed(cert, key)?;
+    }
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow!("Failed to read the TLS certificate {}: {}", cert.display(), e))?;
+    let Some(pin) = chain.first().map(|leaf| CertPin::of(leaf)) else {
+        bail!("{} holds no certificate", cert.display());
+    };
+    let private_key = PrivateKeyDer::from_pem_file(key).map_err(|e| anyhow!("Failed to read the TLS key {}: {}", key.display(), e))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .context("Failed to set up TLS")?
+        .with_no_client_auth()
+        .with_single_cert(chain, private_key)
+        .with_context(|| format!("The TLS key {} does not fit the certificate {}", key.display(), cert.display()))?;
+    info!("TCP IPC certificate fingerprint, for clients to pin: {}", pin);
+    if let Err(e) = std::fs::write(fingerprint, format!("{}\n", pin)) {
+        warn!("Failed to write the certificate fingerprint to {}: {}", fingerprint.display(), e);
+    }
+    Ok(Arc::new(config))
+}
+
+/// Writes a new self-signed certificate and its key. It names no real
+/// host: clients pin it instead of checking names.
+fn self_signed(cert: &Path, key: &Path) -> anyhow::Result<()> {
+    let generated = rcgen::generate_simple_self_signed(vec!["sovereign-node".to_string()]).context("Failed to generate a TLS certificate")?;
+    let pem = generated.serialize_pem().context("Failed to encode the TLS certificate")?;
+    // The key goes first, so a certificate on disk always has one.
+    write_private(key, generated.serialize_private_key_pem().as_bytes()).with_context(|| format!("Failed to write {}", key.display()))?;
+    std::fs::write(cert, pem).with_context(|| format!("Failed to write {}", cert.display()))?;
+    info!("Generated a self-signed TLS certificate at {}", cert.display());
+    Ok(())
+}
+
+/// Writes `contents` to a file only the node's user can read.
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_a_certificate_once_and_writes_its_pin() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key, fingerprint) = (dir.path().join("cert.pem"), dir.path().join("key.pem"), dir.path().join("fingerprint.txt"));
+        server_config(&cert, &key, true, &fingerprint).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&key).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        let leaf = CertificateDer::pem_file_iter(&cert).unwrap().next().unwrap().unwrap();
+        let pin = std::fs::read_to_string(&fingerprint).unwrap();
+        assert_eq!(CertPin::parse(&pin), Some(CertPin::of(&leaf)));
+
+        // The next start serves the same certificate.
+        std::fs::remove_file(&fingerprint).unwrap();
+        server_config(&cert, &key, true, &fingerprint).unwrap();
+        assert_eq!(std::fs::read_to_string(&fingerprint).unwrap(), pin);
+    }
+
+    #[test]
+    fn refuses_a_missing_certificate_or_a_key_that_does_not_fit() {
+        let dir = tempfile::tempdir().unwrap();
+        let fingerprint = dir.path().join("fingerprint.txt");
+        let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        let e = server_config(&cert, &key, false, &fingerprint).unwrap_err();
+        assert!(e.to_string().starts_with("Failed to read the TLS certificate"), "{:#}", e);
+        assert!(!cert.exists() && !fingerprint.exists());
+
+        let (other_cert, other_key) = (dir.path().join("other-cert.pem"), dir.path().join("other-key.pem"));
+        self_signed(&cert, &key).unwrap();
+        self_signed(&other_cert, &other_key).unwrap();
+        let e = server_config(&cert, &other_key, false, &fingerprint).unwrap_err();
+        assert!(e.to_string().contains("does not fit the certificate"), "{:#}", e);
+        std::fs::write(&other_cert, "not a certificate").unwrap();
+        let e = server_config(&other_cert, &key, false, &fingerprint).unwrap_err();
+        assert!(e.to_string().ends_with("holds no certificate"), "{:#}", e);
+    }
+}
@@ -0,0 +1,398 @@
+use sovereign_protocol::IpcEndpoint;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A connected IPC client, whichever transport it came in on.
+pub(crate) trait IpcStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> IpcStream for T {}
+
+pub(crate) type BoxedStream = Box<dyn IpcStream>;
+
+pub(crate) type AcceptFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Accepted>> + Send + 'a>>;
+
+/// A client just accepted.
+pub(crate) struct Accepted {
+    pub stream: BoxedStream,
+    /// `None` where the transport cannot tell.
+    pub peer: Option<PeerCred>,
+    /// Where a TCP client connected from; `None` on local transports.
+    pub remote: Option<SocketAddr>,
+}
+
+/// The connecting process, as the kernel reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PeerCred {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+}
+
+impl fmt::Display for PeerCred {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "uid {}, pid {}", self.uid, pid),
+            None => write!(f, "uid {}", self.uid),
+        }
+    }
+}
+
+/// Which local users besides the node's own may connect.
+#[derive(Debug, Clone, Default)]
+pub struct PeerPolicy {
+    pub allowed_uids: Vec<u32>,
+    pub allowed_gids: Vec<u32>,
+}
+
+impl PeerPolicy {
+    /// True for `own_uid` and the listed uids and gids. A peer without
+    /// credentials is let in: only named pipes lack them, and a pipe's own
+    /// access list already keeps other users out.
+    pub(crate) fn admits(&self, own_uid: u32, peer: Option<&PeerCred>) -> bool {
+        let Some(peer) = peer else {
+            return true;
+        };
+        peer.uid == own_uid || self.allowed_uids.contains(&peer.uid) || self.allowed_gids.contains(&peer.gid)
+    }
+}
+
+/// The user the node runs as; 0 where there are no uids.
+pub(crate) fn own_uid() -> u32 {
+    #[cfg(unix)]
+    {
+        // SAFETY: geteuid has no preconditions and cannot fail.
+        unsafe { libc::geteuid() }
+    }
+    #[cfg(not(unix))]
+    {
+        0
+    }
+}
+
+/// How the accept loop should take a failed accept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AcceptFailure {
+    /// One client's connection failed; accept the next.
+    Transient,
+    /// The process or system is out of descriptors or memory; accept again
+    /// after a pause.
+    Exhausted,
+    /// The listener itself is broken and will not accept again.
+    Fatal,
+}
+
+impl AcceptFailure {
+    pub(crate) fn classify(e: &io::Error) -> Self {
+        #[cfg(unix)]
+        if let Some(code) = e.raw_os_error() {
+            return match code {
+                libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM => Self::Exhausted,
+                libc::EBADF | libc::EINVAL | libc::ENOTSOCK | libc::EOPNOTSUPP | libc::EFAULT => Self::Fatal,
+                _ => Self::Transient,
+            };
+        }
+        match e.kind() {
+            io::ErrorKind::OutOfMemory => Self::Exhausted,
+            io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied | io::ErrorKind::InvalidInput | io::ErrorKind::AddrInUse => Self::Fatal,
+            _ => Self::Transient,
+        }
+    }
+}
+
+/// Where IPC clients connect: a Unix domain socket, or a named pipe on
+/// Windows, and TCP when `ipc_tcp` is configured.
+pub(crate) trait IpcListener: Send {
+    /// Waits for the next client.
+    fn accept(&mut self) -> AcceptFuture<'_>;
+}
+
+/// Listens on `endpoint`, if this platform has its kind of transport.
+pub(crate) fn bind(endpoint: &IpcEndpoint) -> anyhow::Result<Box<dyn IpcListener>> {
+    match endpoint {
+        #[cfg(unix)]
+        IpcEndpoint::UnixSocket(path) => Ok(Box::new(unix::SocketListener::bind(path)?)),
+        #[cfg(windows)]
+        IpcEndpoint::NamedPipe(name) => Ok(Box::new(windows::PipeListener::bind(name)?)),
+        other => anyhow::bail!("IPC endpoint {} is not supported on this platform", other),
+    }
+}
+
+/// The socket systemd bound for the node, if it socket-activated it.
+pub(crate) fn activated() -> anyhow::Result<Option<Box<dyn IpcListener>>> {
+    #[cfg(unix)]
+    if let Some(fd) = crate::sd_notify::listen_fd() {
+        return Ok(Some(Box::new(unix::SocketListener::from_fd(fd)?)));
+    }
+    Ok(None)
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::{own_uid, AcceptFuture, Accepted, BoxedStream, IpcListener, PeerCred};
+    use std::io;
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+    use std::os::unix::io::{FromRawFd, RawFd};
+    use std::path::{Path, PathBuf};
+    use tokio::net::UnixListener;
+
+    /// Removes its socket file when dropped, if it bound it.
+    pub(crate) struct SocketListener {
+        listener: UnixListener,
+        path: Option<PathBuf>,
+    }
+
+    impl SocketListener {
+        /// Binds `path`, readable and writable by the node's user alone.
+        pub(crate) fn bind(path: &Path) -> io::Result<Self> {
+            if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                std::fs::DirBuilder::new().recursive(true).mode(0o700).create(parent)?;
+                check_parent(parent)?;
+            }
+            let _ = std::fs::remove_file(path); // Remove old socket if exists
+            let listener = UnixListener::bind(path)?;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+            Ok(Self {
+                listener,
+                path: Some(path.to_path_buf()),
+            })
+        }
+
+        /// Takes over `fd`, a socket already bound and listening. Its file
+        /// belongs to whoever bound it and stays when this is dropped.
+        pub(crate) fn from_fd(fd: RawFd) -> io::Result<Self> {
+            // SAFETY: the caller hands over `fd`, which nothing else in the
+            // process owns: systemd passed it for this process alone.
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Ok(Self {
+                listener: UnixListener::from_std(listener)?,
+                path: None,
+            })
+        }
+    }
+
+    impl Drop for SocketListener {
+        fn drop(&mut self) {
+            if let Some(path) = &self.path {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    impl IpcListener for SocketListener {
+        fn accept(&mut self) -> AcceptFuture<'_> {
+            Box::pin(async move {
+                let (stream, _) = self.listener.accept().await?;
+                let peer = stream.peer_cred().ok().map(|cred| PeerCred {
+                    uid: cred.uid(),
+                    gid: cred.gid(),
+                    pid: cred.pid(),
+                });
+                Ok(Accepted {
+                    stream: Box::new(stream) as BoxedStream,
+                    peer,
+                    remote: None,
+                })
+            })
+        }
+    }
+
+    /// Refuses a socket directory that is a symlink, or that another user
+    /// owns and anyone may write to without the sticky bit, where someone
+    /// else could swap the socket out.
+    fn check_parent(parent: &Path) -> io::Result<()> {
+        let meta = std::fs::symlink_metadata(parent)?;
+        if meta.file_type().is_symlink() {
+            return Err(io::Error::other(format!("Socket directory {} is a symlink", parent.display())));
+        }
+        let shared = meta.mode() & 0o002 != 0 && meta.mode() & 0o1000 == 0;
+        if meta.uid() != own_uid() && shared {
+            return Err(io::Error::other(format!("Socket directory {} is writable by other users", parent.display())));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use super::{AcceptFuture, Accepted, BoxedStream, IpcListener};
+    use std::ffi::c_void;
+    use std::io;
+    use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+    use windows_sys::Win32::Foundation::LocalFree;
+    use windows_sys::Win32::Security::Authorization::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1};
+    use windows_sys::Win32::Security::{PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES};
+
+    /// Full access for the pipe's owner, the user the node runs as, and for
+    /// the system; none for anyone else.
+    const OWNER_ONLY: &str = "D:P(A;;GA;;;OW)(A;;GA;;;SY)";
+
+    /// A named pipe serves one client per instance, so the listener keeps
+    /// one instance waiting and creates the next as each client connects.
+    pub(crate) struct PipeListener {
+        name: String,
+        waiting: NamedPipeServer,
+    }
+
+    impl PipeListener {
+        pub(crate) fn bind(name: &str) -> io::Result<Self> {
+            // The first instance fails if another node already serves `name`.
+            let waiting = create(name, true)?;
+            Ok(Self {
+                name: name.to_string(),
+                waiting,
+            })
+        }
+    }
+
+    impl IpcListener for PipeListener {
+        fn accept(&mut self) -> AcceptFuture<'_> {
+            Box::pin(async move {
+                let connected = self.waiting.connect().await;
+                // Whether or not that worked, a fresh instance waits before the
+                // old one is handed over or dropped.
+                let next = create(&self.name, false)?;
+                let server = std::mem::replace(&mut self.waiting, next);
+                connected?;
+                Ok(Accepted {
+                    stream: Box::new(server) as BoxedStream,
+                    peer: None,
+                    remote: None,
+                })
+            })
+        }
+    }
+
+    fn create(name: &str, first: bool) -> io::Result<NamedPipeServer> {
+        let descriptor = SecurityDescriptor::parse(OWNER_ONLY)?;
+        let mut attributes = SECURITY_ATTRIBUTES {
+            nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+            lpSecurityDescriptor: descriptor.0,
+            bInheritHandle: 0,
+        };
+        let mut options = ServerOptions::new();
+        options.first_pipe_instance(first).reject_remote_clients(true);
+        // SAFETY: `attributes` and the descriptor it points to outlive the call.
+        unsafe { options.create_with_security_attributes_raw(name, &mut attributes as *mut SECURITY_ATTRIBUTES as *mut c_void) }
+    }
+
+    /// A security descriptor the system allocated from SDDL, freed on drop.
+    struct SecurityDescriptor(PSECURITY_DESCRIPTOR);
+
+    impl SecurityDescriptor {
+        fn parse(sddl: &str) -> io::Result<Self> {
+            let wide: Vec<u16> = sddl.encode_utf16().chain(Some(0)).collect();
+            let mut descriptor: PSECURITY_DESCRIPTOR = std::ptr::null_mut();
+            // SAFETY: `wide` is NUL-terminated and the out pointer is valid.
+            let ok = unsafe {
+                ConvertStringSecurityDescriptorToSecurityDescriptorW(wide.as_ptr(), SDDL_REVISION_1, &mut descriptor, std::ptr::null_mut())
+            };
+            if ok == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self(descriptor))
+        }
+    }
+
+    impl Drop for SecurityDescriptor {
+        fn drop(&mut self) {
+            // SAFETY: allocated with LocalAlloc by the conversion above.
+            unsafe { LocalFree(self.0) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_node_user_and_allowed_ids_are_admitted() {
+        let cred = |uid, gid| PeerCred { uid, gid, pid: Some(1) };
+        let policy = PeerPolicy {
+            allowed_uids: vec![1001],
+            allowed_gids: vec![50],
+        };
+        for (peer, admitted) in [
+            (Some(cred(1000, 1000)), true),
+            (Some(cred(1001, 1001)), true),
+            (Some(cred(1002, 50)), true),
+            (Some(cred(1002, 1002)), false),
+            (Some(cred(0, 0)), false),
+            (None, true),
+        ] {
+            assert_eq!(policy.admits(1000, peer.as_ref()), admitted, "{:?}", peer);
+        }
+        assert!(!PeerPolicy::default().admits(1000, Some(&cred(1001, 1000))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sorts_accept_failures() {
+        for (e, expected) in [
+            (io::Error::from_raw_os_error(libc::EMFILE), AcceptFailure::Exhausted),
+            (io::Error::from_raw_os_error(libc::ENOMEM), AcceptFailure::Exhausted),
+            (io::Error::from_raw_os_error(libc::EBADF), AcceptFailure::Fatal),
+            (io::Error::from_raw_os_error(libc::ECONNABORTED), AcceptFailure::Transient),
+            (io::Error::new(io::ErrorKind::OutOfMemory, "oom"), AcceptFailure::Exhausted),
+            (io::Error::new(io::ErrorKind::InvalidInput, "bad"), AcceptFailure::Fatal),
+            (io::Error::new(io::ErrorKind::ConnectionReset, "reset"), AcceptFailure::Transient),
+        ] {
+            assert_eq!(AcceptFailure::classify(&e), expected, "{}", e);
+        }
+    }
+
+    #[cfg(unix)]
+    mod unix {
+        use super::super::unix::SocketListener;
+        use super::super::{own_uid, IpcListener};
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        #[tokio::test]
+        async fn the_socket_is_the_node_users_alone() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("run").join("node.sock");
+            let mut listener = SocketListener::bind(&path).unwrap();
+            assert_eq!(std::fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+            assert_eq!(std::fs::metadata(path.parent().unwrap()).unwrap().mode() & 0o777, 0o700);
+
+            let _client = tokio::net::UnixStream::connect(&path).await.unwrap();
+            let accepted = listener.accept().await.unwrap();
+            let peer = accepted.peer.unwrap();
+            assert_eq!(peer.uid, own_uid());
+            assert_eq!(peer.pid, Some(std::process::id() as i32));
+
+            drop(listener);
+            assert!(!path.exists());
+        }
+
+        #[tokio::test]
+        async fn refuses_a_socket_directory_that_could_be_swapped() {
+            let dir = tempfile::tempdir().unwrap();
+            let real = dir.path().join("real");
+            std::fs::create_dir(&real).unwrap();
+            let link = dir.path().join("link");
+            std::os::unix::fs::symlink(&real, &link).unwrap();
+            let e = SocketListener::bind(&link.join("node.sock")).err().unwrap();
+            assert!(e.to_string().contains("is a symlink"), "{}", e);
+
+            // Only root can hand a directory to another user.
+            if own_uid() != 0 {
+                return;
+            }
+            let shared = dir.path().join("shared");
+            std::fs::create_dir(&shared).unwrap();
+            std::os::unix::fs::chown(&shared, Some(65534), Some(65534)).unwrap();
+            std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o777)).unwrap();
+            let e = SocketListener::bind(&shared.join("node.sock")).err().unwrap();
+            assert!(e.to_string().contains("writable by other users"), "{}", e);
+            // With the sticky bit others cannot replace the node's socket.
+            std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o1777)).unwrap();
+            SocketListener::bind(&shared.join("node.sock")).unwrap();
+        }
+    }
+}
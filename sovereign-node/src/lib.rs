@@ -0,0 +1,256 @@
+//! The sovereign-node service: what the `sovereign-node` binary runs, and,
+//! with the `testkit` feature, in-process nodes for integration tests.
+
+use blocking_pool::BlockingPool;
+use config::NodeConfig;
+use data_dir::DataDir;
+use finance_backend::FinanceBackend;
+use ipc_transport::IpcListener;
+use setup::SetupTarget;
+use sovereign_core::{CognitiveCore, CoreBackend, CoreConfig, Migration};
+use sovereign_replication::ReplicationConfig;
+use sovereign_runtime_wasm::{ModuleRegistry, Scheduler, WasmRuntime};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+use tracing::info;
+
+mod access;
+mod blocking_pool;
+mod build_info;
+mod config;
+mod core_queries;
+mod core_sessions;
+mod core_stream;
+mod core_watches;
+mod data_dir;
+mod event_bus;
+#[cfg(feature = "fault-injection")]
+mod fault;
+mod finance_backend;
+mod health;
+mod ipc_audit;
+mod ipc_sessions;
+mod ipc_stats;
+mod ipc_tcp;
+mod ipc_transport;
+mod license_monitor;
+mod logging;
+mod machine_identity;
+mod mesh_supervisor;
+mod mesh_warmup;
+#[cfg(feature = "metrics-http")]
+mod metrics_http;
+mod module_paths;
+mod node_state;
+mod presence;
+mod rate_limit;
+mod replica_outbox;
+mod request_timeouts;
+#[cfg(windows)]
+mod scm;
+mod sd_notify;
+mod self_check;
+mod service;
+mod service_loop;
+mod service_state;
+mod setup;
+mod shutdown;
+mod snapshot;
+#[cfg(all(any(test, feature = "testkit"), unix))]
+pub mod testkit;
+mod wasm_host;
+mod wasm_stream;
+
+#[cfg(all(feature = "fault-injection", not(debug_assertions)))]
+compile_error!("the fault-injection feature is for test builds; release builds must not carry it");
+
+pub use config::default_config_path;
+#[cfg(windows)]
+pub use scm::run_as_service;
+pub use service::{service, ServiceAction};
+
+/// Runs kept per scheduled job for `WasmJobHistory`.
+const JOB_HISTORY_LEN: usize = 20;
+
+/// The core's schema history, oldest first. Append only: a released
+/// migration is never edited or removed.
+const CORE_MIGRATIONS: &[Migration] = &[];
+
+/// Runs the node with the config file at `config_path`, writing a default
+/// one if it is missing, until SIGTERM or SIGINT.
+pub async fn run(config_path: PathBuf) -> anyhow::Result<()> {
+    run_until(config_path, shutdown::signalled()).await
+}
+
+/// `run`, until `stop` resolves.
+pub(crate) async fn run_until(config_path: PathBuf, stop: impl Future<Output = std::io::Result<()>> + Send) -> anyhow::Result<()> {
+    let created = NodeConfig::write_default(&config_path)?;
+    let config = NodeConfig::load(&config_path)?;
+    // Locked before anything opens a file there, the log included.
+    let data_dir = DataDir::open(config.data_dir())?;
+    // Before the log is opened there, when it goes to the data directory.
+    let fresh = data_dir.is_fresh();
+    let logging = Arc::new(logging::init(&config.log_level, config.file_log(&data_dir).as_ref())?);
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(config_path.clone(), logging.clone()));
+    info!("sovereign-node {}", build_info::build_info().describe());
+    if created {
+        info!("Wrote a default config file to {}", config_path.display());
+    }
+
+    let target = SetupTarget {
+        config_path,
+        config,
+        data_dir: data_dir.root().to_path_buf(),
+    };
+    let mut stop = std::pin::pin!(stop);
+    let (config, listener) = if fresh {
+        match setup::wait(target.clone(), stop.as_mut()).await? {
+            Some((config, listener)) => (config, Some(listener)),
+            None => return Ok(()),
+        }
+    } else {
+        (target.config.clone(), None)
+    };
+
+    // Connected in the background: the rest of the node does not need Electrum.
+    let finance = {
+        let pool = BlockingPool::new("finance", config.finance_pool())?;
+        let config = config.clone();
+        FinanceBackend::start(pool, move || config.license_verifier())
+    };
+    let target = SetupTarget { config: config.clone(), ..target };
+    let replication = config.replication();
+    serve(config, data_dir, finance, Some(target), listener, replication, stop).await
+}
+
+/// Checks the config file at `config_path` and what it points at without
+/// starting the node, printing a report. False if any check failed.
+pub async fn check(config_path: PathBuf) -> anyhow::Result<bool> {
+    let report = self_check::offline(&config_path).await;
+    print!("{}", report);
+    Ok(!report.failed())
+}
+
+/// Starts every subsystem from `config` in the locked `data_dir` and
+/// serves IPC until `stop` resolves: on `listener` if setup handed one
+/// over, else on the config's endpoint. `SetupApply` rewrites `setup`'s
+/// config file; without it the request is refused. `replication` is the
+/// config's, or a test's own.
+pub(crate) async fn serve(
+    config: NodeConfig,
+    data_dir: DataDir,
+    finance: Arc<FinanceBackend>,
+    setup: Option<SetupTarget>,
+    listener: Option<Box<dyn IpcListener>>,
+    replication: Option<ReplicationConfig>,
+    stop: impl Future<Output = std::io::Result<()>> + Send,
+) -> anyhow::Result<()> {
+    let start_time = SystemTime::now();
+
+    data_dir.migrate(&config);
+
+    // The mesh command channel is created up front so WASM host functions can publish.
+    let (mesh_tx, mesh_rx) = mpsc::channel(32);
+
+    // Initialize core and wasm
+    let core = Arc::new(CognitiveCore::new(core_config(&config, &data_dir)?)?);
+    let migrated = core.migrate()?;
+    if !migrated.is_empty() {
+        info!("Migrated the core through schema version(s) {:?}", migrated);
+    }
+    let wasm = Arc::new(
+        WasmRuntime::with_config(config.runtime_config(&data_dir))?
+        .with_host_context(Arc::new(wasm_host::NodeHost::new(core.clone(), mesh_tx.clone()))),
+    );
+    let modules = Arc::new(ModuleRegistry::open(wasm.clone(), config.module_store(&data_dir))?);
+    modules.watch()?;
+    let scheduler = Arc::new(Scheduler::open(modules.clone(), data_dir.jobs(), JOB_HISTORY_LEN)?);
+    service_loop::run_ipc_server(
+        core,
+        service_loop::WasmServices {
+            runtime: wasm,
+            modules,
+            scheduler,
+            paths: config.module_paths(&data_dir),
+        },
+        service_loop::MeshServices {
+            config: config.mesh_config(&data_dir)?,
+            commands: (mesh_tx, mesh_rx),
+            warmup: config.mesh_warmup(),
+            replication,
+            presence: config.presence(),
+        },
+        service_loop::FinanceServices {
+            backend: finance,
+            recheck: config.license_recheck(),
+        },
+        service_loop::NodeHome {
+            data_dir: &data_dir,
+            start_time,
+        },
+        service_loop::IpcSettings {
+            endpoint: config.endpoint(),
+            peers: config.peer_policy(),
+            namespaces: config.core.namespaces.clone().into_iter().collect(),
+            rate_limits: config.rate_limits(),
+            metrics_port: config.metrics_port,
+            max_connections: config.ipc_max_connections,
+            heartbeat_interval: config.heartbeat_interval(),
+            max_missed_heartbeats: config.ipc_missed_heartbeats,
+            max_frame_size: config.ipc_max_frame_bytes,
+            connection_idle_timeout: config.idle_timeout(),
+            idle_warning: config.idle_warning(),
+            session_grace: config.session_grace(),
+            slow_request_threshold: config.slow_request_threshold(),
+            compression: config.ipc_compression.clone(),
+            compression_min_size: config.ipc_compression_min_bytes,
+            tcp: config.tcp(&data_dir),
+            compute_pool: config.compute_pool(),
+            health: config.health_thresholds(),
+            access: config.access_policy()?,
+            audit: config.ipc_audit(&data_dir),
+            config: Some(config.clone()),
+            setup,
+            listener: std::sync::Mutex::new(listener),
+            ..Default::default()
+        },
+        stop,
+    )
+    .await
+}
+
+/// Re-reads `log_level` from the config file on each SIGHUP.
+#[cfg(unix)]
+async fn reload_on_hangup(config_path: PathBuf, logging: Arc<logging::Logging>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let Ok(mut hangups) = signal(SignalKind::hangup()) else {
+        return;
+    };
+    while hangups.recv().await.is_some() {
+        let reloaded = NodeConfig::load(&config_path).and_then(|config| logging.set_level(&config.log_level));
+        if let Err(e) = reloaded {
+            tracing::warn!("Not reloading the log level: {:#}", e);
+        }
+    }
+}
+
+/// The core's store, from `core.backend` and `core.path` in the config.
+pub(crate) fn core_config(config: &NodeConfig, data_dir: &DataDir) -> anyhow::Result<CoreConfig> {
+    let path = config.core.path.clone().unwrap_or_else(|| data_dir.core_store(&config.core.backend));
+    let backend = match config.core.backend.as_str() {
+        "rocksdb" => CoreBackend::RocksDb { path },
+        "mem" => CoreBackend::Mem,
+        _ => CoreBackend::Sqlite { path },
+    };
+    Ok(CoreConfig {
+        backend,
+        migrations: CORE_MIGRATIONS,
+        audit: config.core_audit(data_dir),
+        ..Default::default()
+    })
+}
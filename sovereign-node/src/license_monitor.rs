@@ -0,0 +1,305 @@
+use crate::finance_backend::FinanceBackend;
+use crate::node_state::StateStore;
+use crate::service_loop::SharedState;
+use serde::{Deserialize, Serialize};
+use sovereign_protocol::{LicenseReport, Response};
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+/// The wait before retrying a check the chain did not answer, if shorter
+/// than the interval.
+const RETRY_AFTER_FAILURE: Duration = Duration::from_secs(3600);
+
+/// How often the node re-checks the last verified license on-chain.
+#[derive(Debug, Clone)]
+pub struct LicenseRecheck {
+    /// Between checks, plus up to a tenth more at random so nodes started
+    /// together do not check together.
+    pub interval: Duration,
+    /// How long after the chain last answered that failed checks leave an
+    /// active license active.
+    pub offline_grace: Duration,
+}
+
+impl Default for LicenseRecheck {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(24 * 3600),
+            offline_grace: Duration::from_secs(7 * 24 * 3600),
+        }
+    }
+}
+
+/// The last license check the chain answered, kept in the node's state
+/// across restarts.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct LicenseRecord {
+    pub valid: bool,
+    pub report: LicenseReport,
+}
+
+impl LicenseRecord {
+    /// Whether the license counts as active at `now_ms`: valid when last
+    /// checked, and checked within `grace`.
+    pub fn active(&self, now_ms: u64, grace: Duration) -> bool {
+        self.valid && now_ms.saturating_sub(self.report.checked_at_ms) <= grace.as_millis() as u64
+    }
+}
+
+pub(crate) type CheckFuture<'a> = Pin<Box<dyn Future<Output = Result<(bool, LicenseReport), Response>> + Send + 'a>>;
+
+/// Where the monitor checks a license on-chain: the finance backend.
+pub(crate) trait LicenseCheck: Send + Sync {
+    /// The verdict on `txid` for this machine and the report, or the
+    /// response to give.
+    fn verify(&self, txid: String, machine_id: String) -> CheckFuture<'_>;
+}
+
+impl LicenseCheck for FinanceBackend {
+    fn verify(&self, txid: String, machine_id: String) -> CheckFuture<'_> {
+        Box::pin(FinanceBackend::verify(self, txid, machine_id))
+    }
+}
+
+/// Puts a check's outcome in `state` and persists it in `store`.
+pub(crate) fn record(state: &watch::Sender<SharedState>, store: &StateStore, valid: bool, report: LicenseReport) {
+    let record = LicenseRecord { valid, report };
+    store.update(|s| s.license = Some(record.clone()));
+    state.send_modify(|s| {
+        s.license_active = valid;
+        s.license_report = Some(record.report);
+    });
+}
+
+/// Re-checks the license `state` last reported, every `recheck.interval`,
+/// until `shutdown` turns true. A check the chain does not answer leaves
+/// the license as it was until `recheck.offline_grace` has passed since
+/// the last one it did.
+pub(crate) async fn run(
+    finance: Arc<dyn LicenseCheck>,
+    state: Arc<watch::Sender<SharedState>>,
+    machine_id: String,
+    store: Arc<StateStore>,
+    recheck: LicenseRecheck,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let since_last = |state: &SharedState| {
+        let checked_at = state.license_report.as_ref().map_or(0, |r| r.checked_at_ms);
+        Duration::from_millis(crate::service_loop::unix_millis().saturating_sub(checked_at))
+    };
+    let mut wait = recheck.interval.saturating_sub(since_last(&state.borrow()));
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(wait + jitter(recheck.interval / 10)) => {}
+            _ = shutdown.changed() => return,
+        }
+        wait = recheck.interval;
+        let Some(txid) = state.borrow().license_report.as_ref().map(|r| r.txid.clone()) else {
+            debug!("No license to re-check yet");
+            continue;
+        };
+        let failure = tokio::select! {
+            checked = finance.verify(txid.clone(), machine_id.clone()) => match checked {
+                Ok((valid, report)) => {
+                    let was_active = state.borrow().license_active;
+                    if valid != was_active {
+                        info!("License {} is now {}", txid, if valid { "active" } else { "inactive" });
+                    }
+                    record(&state, &store, valid, report);
+                    continue;
+                }
+                Err(failure) => failure,
+            },
+            _ = shutdown.changed() => return,
+        };
+        wait = recheck.interval.min(RETRY_AFTER_FAILURE);
+        let reason = match &failure {
+            Response::Unavailable { reason, .. } | Response::Error(reason) => reason.as_str(),
+            _ => "no answer",
+        };
+        let offline_for = since_last(&state.borrow());
+        if offline_for <= recheck.offline_grace {
+            warn!("Could not re-check license {}: {}. Keeping its state for now.", txid, reason);
+        } else if state.borrow().license_active {
+            warn!("Could not re-check license {}: {}. Last checked {:?} ago, past the offline grace: marking it inactive.", txid, reason, offline_for);
+            state.send_modify(|s| s.license_active = false);
+        }
+    }
+}
+
+/// A random duration up to `max`.
+fn jitter(max: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    max.mul_f64((random % 1000) as f64 / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_bus::{self, EventBus, Subscription};
+    use crate::service_loop::unix_millis;
+    use sovereign_protocol::{EventTopic, MeshPhase, NodeEvent};
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    /// Answers checks from a script, then as if Electrum were unreachable.
+    #[derive(Default)]
+    struct Chain {
+        answers: Mutex<VecDeque<(bool, LicenseReport)>>,
+        checked: Mutex<Vec<String>>,
+    }
+
+    impl LicenseCheck for Chain {
+        fn verify(&self, txid: String, _machine_id: String) -> CheckFuture<'_> {
+            self.checked.lock().unwrap().push(txid);
+            let answer = self.answers.lock().unwrap().pop_front();
+            Box::pin(async move { answer.ok_or_else(unreachable) })
+        }
+    }
+
+    fn unreachable() -> Response {
+        Response::Unavailable {
+            subsystem: "finance".into(),
+            reason: "the Electrum server is unreachable".into(),
+        }
+    }
+
+    fn report(checked_at_ms: u64) -> LicenseReport {
+        LicenseReport {
+            txid: "ab".repeat(32),
+            found: true,
+            paid_sats: 50_000,
+            payment_ok: true,
+            binding_ok: true,
+            confirmations: 6,
+            confirmed_height: Some(800_000),
+            tier: None,
+            valid_until_height: None,
+            checked_at_ms,
+        }
+    }
+
+    /// A node whose license was active when checked at `checked_at_ms`, or
+    /// that has none, and the monitor watching it.
+    struct Monitor {
+        chain: Arc<Chain>,
+        state: Arc<watch::Sender<SharedState>>,
+        store: Arc<StateStore>,
+        shutdown: watch::Sender<bool>,
+        task: tokio::task::JoinHandle<()>,
+        _dir: tempfile::TempDir,
+    }
+
+    fn monitor(checked_at_ms: Option<u64>, answers: Vec<(bool, LicenseReport)>, recheck: LicenseRecheck) -> Monitor {
+        let dir = tempfile::tempdir().unwrap();
+        let chain = Arc::new(Chain {
+            answers: Mutex::new(answers.into()),
+            ..Default::default()
+        });
+        let state = Arc::new(watch::Sender::new(SharedState {
+            peer_id: "local".into(),
+            connections: 0,
+            listen_addrs: Vec::new(),
+            mesh_phase: MeshPhase::Ready,
+            license_active: checked_at_ms.is_some(),
+            license_report: checked_at_ms.map(report),
+            presence: None,
+        }));
+        let store = Arc::new(StateStore::open(dir.path()));
+        let (shutdown, stop) = watch::channel(false);
+        let task = tokio::spawn(run(chain.clone(), state.clone(), "machine".into(), store.clone(), recheck, stop));
+        Monitor { chain, state, store, shutdown, task, _dir: dir }
+    }
+
+    fn every(interval_ms: u64, grace_ms: u64) -> LicenseRecheck {
+        LicenseRecheck {
+            interval: Duration::from_millis(interval_ms),
+            offline_grace: Duration::from_millis(grace_ms),
+        }
+    }
+
+    async fn wait_for(what: &str, done: impl Fn() -> bool) {
+        let started = Instant::now();
+        while !done() {
+            assert!(started.elapsed() < Duration::from_secs(10), "{} never happened", what);
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn a_license_the_chain_no_longer_accepts_turns_inactive() {
+        let now = unix_millis();
+        let m = monitor(Some(now), vec![(true, report(now)), (false, report(now + 1))], every(20, 3_600_000));
+        let bus = Arc::new(EventBus::new());
+        let mut events = Subscription::default();
+        events.subscribe(&bus, vec![EventTopic::License]);
+        tokio::spawn(event_bus::publish_license(m.state.subscribe(), bus.clone()));
+
+        wait_for("the second check", || m.chain.checked.lock().unwrap().len() >= 2).await;
+        wait_for("the license turning inactive", || !m.state.borrow().license_active).await;
+        assert_eq!(m.state.borrow().license_report.as_ref().unwrap().checked_at_ms, now + 1);
+        let record = m.store.get().license.unwrap();
+        assert!(!record.valid);
+        assert_eq!(record.report.checked_at_ms, now + 1);
+        assert!(m.chain.checked.lock().unwrap().iter().all(|txid| *txid == "ab".repeat(32)));
+
+        // Only the flip is pushed, not the check that kept it active.
+        match tokio::time::timeout(Duration::from_secs(5), events.next()).await.unwrap() {
+            Response::Event { seq: 1, event: NodeEvent::LicenseChanged { active: false, report } } => assert_eq!(report.unwrap().checked_at_ms, now + 1),
+            other => panic!("Expected the license turning inactive, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_checks_keep_the_license_until_the_grace_runs_out() {
+        // Checked just now: failures are within the grace.
+        let m = monitor(Some(unix_millis()), Vec::new(), every(20, 3_600_000));
+        wait_for("three checks", || m.chain.checked.lock().unwrap().len() >= 3).await;
+        assert!(m.state.borrow().license_active);
+        assert!(m.store.get().license.is_none());
+
+        // Last answered two hours ago, with an hour of grace.
+        let m = monitor(Some(unix_millis() - 7_200_000), Vec::new(), every(20, 3_600_000));
+        wait_for("the license turning inactive", || !m.state.borrow().license_active).await;
+        assert!(!m.chain.checked.lock().unwrap().is_empty());
+        // The report stays the one the chain last gave.
+        assert!(m.state.borrow().license_report.is_some());
+    }
+
+    #[tokio::test]
+    async fn waits_out_the_interval_since_the_last_check() {
+        // Checked just now, on a long interval: nothing to do yet.
+        let m = monitor(Some(unix_millis()), Vec::new(), every(3_600_000, 3_600_000));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(m.chain.checked.lock().unwrap().is_empty());
+
+        // No license to check at all.
+        let m = monitor(None, Vec::new(), every(20, 3_600_000));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(m.chain.checked.lock().unwrap().is_empty());
+        assert!(!m.state.borrow().license_active);
+    }
+
+    #[tokio::test]
+    async fn stops_with_the_node() {
+        let m = monitor(Some(unix_millis()), Vec::new(), every(3_600_000, 3_600_000));
+        m.shutdown.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), m.task).await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn a_record_is_active_while_valid_and_recent() {
+        let grace = Duration::from_secs(60);
+        for (valid, checked_at_ms, active) in [(true, 100_000, true), (true, 40_000, true), (true, 39_999, false), (false, 100_000, false)] {
+            let record = LicenseRecord { valid, report: report(checked_at_ms) };
+            assert_eq!(record.active(100_000, grace), active, "{} {}", valid, checked_at_ms);
+        }
+    }
+}
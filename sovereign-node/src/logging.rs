@@ -0,0 +1,325 @@
+use anyhow::Context;
+use sovereign_protocol::Request;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::Span;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::{fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry};
+
+/// Where the node's log is also written, besides stderr.
+#[derive(Debug, Clone)]
+pub struct FileLog {
+    pub path: PathBuf,
+    /// Start a new file once the current one would pass this size.
+    pub max_bytes: u64,
+    /// Start a new file once the current one is this old.
+    pub max_age: Option<Duration>,
+    /// Old files kept beside the current one, as `<path>.1` and up.
+    pub keep: u32,
+}
+
+/// The installed subscriber. Dropping it flushes the file log.
+pub(crate) struct Logging {
+    filter: reload::Handle<EnvFilter, Registry>,
+    /// Set when `RUST_LOG` chose the filter, which then stays.
+    from_env: bool,
+    _file: Option<WorkerGuard>,
+}
+
+/// Sends `tracing` events, and `log` records from the other crates, to
+/// stderr and to `file` if set, filtered by `RUST_LOG` or else `level`.
+pub(crate) fn init(level: &str, file: Option<&FileLog>) -> anyhow::Result<Logging> {
+    let (filter, from_env) = match EnvFilter::try_from_default_env() {
+        Ok(filter) => (filter, true),
+        Err(_) => (parse_filter(level)?, false),
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+    let (file_layer, guard) = match file {
+        Some(file) => {
+            let writer = RotatingFile::open(file.clone()).with_context(|| format!("Failed to open log file {}", file.path.display()))?;
+            let (writer, guard) = tracing_appender::non_blocking(writer);
+            (Some(fmt::layer().with_writer(writer).with_ansi(false)), Some(guard))
+        }
+        None => (None, None),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_writer(io::stderr))
+        .with(file_layer)
+        .try_init()?;
+    Ok(Logging {
+        filter: handle,
+        from_env,
+        _file: guard,
+    })
+}
+
+impl Logging {
+    /// Switches to `level` unless `RUST_LOG` set the filter.
+    pub fn set_level(&self, level: &str) -> anyhow::Result<()> {
+        if self.from_env {
+            tracing::info!("RUST_LOG is set, so the log level stays as it chose");
+            return Ok(());
+        }
+        self.filter.reload(parse_filter(level)?)?;
+        tracing::info!("Log level is now '{}'", level);
+        Ok(())
+    }
+}
+
+fn parse_filter(level: &str) -> anyhow::Result<EnvFilter> {
+    EnvFilter::try_new(level).with_context(|| format!("Invalid log level '{}'", level))
+}
+
+/// A span for one IPC request, naming what it acts on where that helps.
+/// License transaction ids are hashed rather than logged.
+pub(crate) fn request_span(connection: &str, id: Option<u64>, req: &Request) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        connection,
+        request_id = id,
+        kind = req.kind(),
+        txid_hash = tracing::field::Empty,
+        module = tracing::field::Empty,
+        relation = tracing::field::Empty,
+    );
+    match req {
+        Request::VerifyLicense { tx_id, .. } => {
+            let mut hasher = DefaultHasher::new();
+            tx_id.hash(&mut hasher);
+            span.record("txid_hash", format!("{:016x}", hasher.finish()));
+        }
+        Request::RunWasm { path: module, .. }
+        | Request::RunWasmModule { name: module, .. }
+        | Request::WasmInfo { name: module }
+        | Request::WasmRemove { name: module }
+        | Request::WasmUpload { name: module, .. } => {
+            span.record("module", module.as_str());
+        }
+        Request::CoreDescribe { name: relation }
+        | Request::CoreAssert { name: relation, .. }
+        | Request::CoreRetract { name: relation, .. }
+        | Request::CoreKnn { name: relation, .. } => {
+            span.record("relation", relation.as_str());
+        }
+        _ => {}
+    }
+    span
+}
+
+/// A log file that starts over, keeping `keep` old ones, once it passes
+/// its size or age.
+pub(crate) struct RotatingFile {
+    config: FileLog,
+    file: File,
+    size: u64,
+    opened: SystemTime,
+}
+
+impl RotatingFile {
+    pub(crate) fn open(config: FileLog) -> io::Result<Self> {
+        if let Some(parent) = config.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let meta = file.metadata()?;
+        let opened = meta.created().or_else(|_| meta.modified()).unwrap_or_else(|_| SystemTime::now());
+        Ok(Self {
+            size: meta.len(),
+            file,
+            opened,
+            config,
+        })
+    }
+
+    fn due(&self, incoming: usize) -> bool {
+        let full = self.size > 0 && self.size + incoming as u64 > self.config.max_bytes;
+        let old = self.config.max_age.is_some_and(|age| self.opened.elapsed().is_ok_and(|elapsed| elapsed >= age));
+        full || (old && self.size > 0)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let (path, keep) = (&self.config.path, self.config.keep);
+        if keep > 0 {
+            let _ = fs::remove_file(numbered(path, keep));
+            for n in (0..keep).rev() {
+                let from = numbered(path, n);
+                if from.exists() {
+                    fs::rename(from, numbered(path, n + 1))?;
+                }
+            }
+        }
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(path)?;
+        self.size = 0;
+        self.opened = SystemTime::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.due(buf.len()) {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// `path` itself for 0, otherwise `<path>.<n>`.
+pub(crate) fn numbered(path: &Path, n: u32) -> PathBuf {
+    if n == 0 {
+        return path.to_path_buf();
+    }
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// What a `fmt` layer writes, kept for the test to read.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The line logged inside `req`'s span.
+    fn logged_in_span(id: Option<u64>, req: &Request) -> String {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::registry().with(fmt::layer().with_writer(move || writer.clone()).with_ansi(false));
+        tracing::subscriber::with_default(subscriber, || {
+            let _entered = request_span("cli#7", id, req).entered();
+            tracing::info!("handled");
+        });
+        let text = captured.0.lock().unwrap().clone();
+        String::from_utf8(text).unwrap()
+    }
+
+    #[test]
+    fn request_spans_name_the_connection_kind_and_target() {
+        let line = logged_in_span(Some(42), &Request::Ping);
+        assert!(line.contains("request{connection=\"cli#7\" request_id=42 kind=\"Ping\"}: "), "{}", line);
+        assert!(line.contains("handled"), "{}", line);
+
+        let line = logged_in_span(None, &Request::WasmRemove { name: "hello".into() });
+        assert!(line.contains("kind=\"WasmRemove\" module=\"hello\"}"), "{}", line);
+        assert!(!line.contains("request_id"), "{}", line);
+
+        let line = logged_in_span(None, &Request::CoreDescribe { name: "people".into() });
+        assert!(line.contains("kind=\"CoreDescribe\" relation=\"people\"}"), "{}", line);
+    }
+
+    #[test]
+    fn license_transactions_are_hashed_in_spans() {
+        let tx_id = "ab".repeat(32);
+        let verify = |tx_id: &str| Request::VerifyLicense {
+            tx_id: tx_id.into(),
+            developer_addr: String::new(),
+            required_sats: 0,
+        };
+        let line = logged_in_span(None, &verify(&tx_id));
+        assert!(!line.contains(&tx_id), "{}", line);
+        let hash = line.split("txid_hash=\"").nth(1).and_then(|rest| rest.get(..16)).unwrap();
+        assert!(hash.chars().all(|c| c.is_ascii_hexdigit()), "{}", line);
+        // The same transaction always hashes the same, so lines can be matched up.
+        assert!(logged_in_span(None, &verify(&tx_id)).contains(hash));
+        assert!(!logged_in_span(None, &verify(&"cd".repeat(32))).contains(hash));
+    }
+
+    fn rotating(dir: &Path, max_bytes: u64, max_age: Option<Duration>, keep: u32) -> RotatingFile {
+        RotatingFile::open(FileLog {
+            path: dir.join("logs/node.log"),
+            max_bytes,
+            max_age,
+            keep,
+        })
+        .unwrap()
+    }
+
+    fn read(path: &Path) -> Option<String> {
+        fs::read_to_string(path).ok()
+    }
+
+    #[test]
+    fn rotates_at_the_size_keeping_old_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs/node.log");
+        let mut log = rotating(dir.path(), 100, None, 2);
+        for line in ["a", "b", "c", "d"] {
+            log.write_all(format!("{}\n", line.repeat(59)).as_bytes()).unwrap();
+        }
+        assert_eq!(read(&path).unwrap(), format!("{}\n", "d".repeat(59)));
+        assert_eq!(read(&numbered(&path, 1)).unwrap(), format!("{}\n", "c".repeat(59)));
+        assert_eq!(read(&numbered(&path, 2)).unwrap(), format!("{}\n", "b".repeat(59)));
+        assert_eq!(read(&numbered(&path, 3)), None);
+
+        // A write bigger than the cap still goes in a fresh file, whole.
+        log.write_all(&[b'e'; 250]).unwrap();
+        assert_eq!(read(&path).unwrap().len(), 250);
+
+        // Reopened, the file's size counts toward the cap.
+        drop(log);
+        let mut log = rotating(dir.path(), 300, None, 2);
+        log.write_all(&[b'f'; 60]).unwrap();
+        assert_eq!(read(&path).unwrap().len(), 60);
+        assert_eq!(read(&numbered(&path, 1)).unwrap().len(), 250);
+    }
+
+    #[test]
+    fn rotates_at_the_age_and_keeps_none_when_asked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs/node.log");
+        let mut log = rotating(dir.path(), u64::MAX, Some(Duration::ZERO), 1);
+        log.write_all(b"first\n").unwrap();
+        log.write_all(b"second\n").unwrap();
+        assert_eq!(read(&path).unwrap(), "second\n");
+        assert_eq!(read(&numbered(&path, 1)).unwrap(), "first\n");
+
+        let mut log = rotating(dir.path(), 10, None, 0);
+        log.write_all(b"0123456789").unwrap();
+        log.write_all(b"next").unwrap();
+        assert_eq!(read(&path).unwrap(), "next");
+        assert_eq!(read(&numbered(&path, 1)).unwrap(), "first\n");
+    }
+
+    #[test]
+    fn levels_are_checked() {
+        for level in ["info", "warn,sovereign_node=debug", "trace"] {
+            assert!(parse_filter(level).is_ok(), "{}", level);
+        }
+        // A bare word is a target to log at every level, so only a bad
+        // level after one is refused.
+        for level in ["sovereign_node=loud", "info,sovereign_node=[", "[{"] {
+            assert!(parse_filter(level).is_err(), "{}", level);
+        }
+    }
+
+    #[test]
+    fn numbers_old_files_after_the_name() {
+        let path = Path::new("/var/log/node.log");
+        assert_eq!(numbered(path, 0), path);
+        assert_eq!(numbered(path, 3), Path::new("/var/log/node.log.3"));
+    }
+}
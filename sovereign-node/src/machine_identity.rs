@@ -0,0 +1,204 @@
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use sovereign_protocol::MachineBinding;
+use std::io::{self, Write};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Keeps these hashes apart from any other SHA-256 of the same inputs.
+const DOMAIN: &[u8] = b"sovereign-node/machine-binding/v1";
+
+pub(crate) const SALT_LEN: usize = 32;
+
+/// What licenses are bound to on this install.
+#[derive(Clone)]
+pub(crate) enum MachineIdentity {
+    /// The derived identifier, as hex. Safe to show and send; the machine
+    /// uid it came from is neither.
+    Bound(String),
+    /// Neither the machine uid nor an install salt could be had, so no
+    /// license can be bound or verified.
+    Unbindable(String),
+}
+
+impl MachineIdentity {
+    /// Hashes the machine uid with the install salt at `salt_path`,
+    /// creating the salt on first run. Either alone still gives an
+    /// identifier; there is no shared fallback.
+    pub fn load(salt_path: &Path) -> Self {
+        let uid = machine_uid::get().map_err(|e| e.to_string());
+        let salt = load_salt(salt_path).map_err(|e| e.to_string());
+        match (uid, salt) {
+            (Ok(uid), Ok(salt)) => Self::Bound(derive(Some(&uid), Some(&salt))),
+            (Ok(uid), Err(e)) => {
+                warn!("No install salt ({}). Binding licenses to the machine alone.", e);
+                Self::Bound(derive(Some(&uid), None))
+            }
+            (Err(e), Ok(salt)) => {
+                warn!("The machine uid is unavailable ({}). Binding licenses to this install alone.", e);
+                Self::Bound(derive(None, Some(&salt)))
+            }
+            (Err(uid), Err(salt)) => Self::Unbindable(format!("no machine uid ({}) and no install salt ({})", uid, salt)),
+        }
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            Self::Bound(id) => Some(id),
+            Self::Unbindable(_) => None,
+        }
+    }
+
+    /// What `NodeStatus` reports.
+    pub fn binding(&self) -> MachineBinding {
+        match self {
+            Self::Bound(_) => MachineBinding::Bound,
+            Self::Unbindable(reason) => MachineBinding::Unbindable { reason: reason.clone() },
+        }
+    }
+}
+
+/// SHA-256 over the domain and each part, length-prefixed and marked when
+/// absent, so no two different inputs run together into the same bytes.
+fn derive(uid: Option<&str>, salt: Option<&[u8]>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(DOMAIN);
+    for part in [uid.map(str::as_bytes), salt] {
+        match part {
+            Some(bytes) => {
+                hasher.update([1]);
+                hasher.update((bytes.len() as u64).to_le_bytes());
+                hasher.update(bytes);
+            }
+            None => hasher.update([0]),
+        }
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Reads the install salt, or makes one. A salt of the wrong length is
+/// moved aside and replaced, which unbinds licenses bought against it.
+fn load_salt(path: &Path) -> io::Result<Vec<u8>> {
+    match std::fs::read(path) {
+        Ok(salt) if salt.len() == SALT_LEN => return Ok(salt),
+        Ok(_) => {
+            let aside = path.with_extension(format!("corrupt-{}", crate::service_loop::unix_millis()));
+            warn!(
+                "Install salt {} is malformed. Moving it to {} and making a new one; licenses bound to the old one will not verify.",
+                path.display(),
+                aside.display()
+            );
+            std::fs::rename(path, aside)?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let mut salt = vec![0; SALT_LEN];
+    OsRng.try_fill_bytes(&mut salt).map_err(|e| io::Error::other(e.to_string()))?;
+    write_private(path, &salt)?;
+    info!("Created install salt {}", path.display());
+    Ok(salt)
+}
+
+/// Creates `path` readable by the node's user only.
+fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_distinct_hex_ids_from_each_input() {
+        let salt = [7u8; SALT_LEN];
+        let id = derive(Some("machine"), Some(&salt));
+        assert_eq!(id.len(), 64);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(id, derive(Some("machine"), Some(&salt)));
+
+        let ids = [
+            id,
+            derive(Some("other"), Some(&salt)),
+            derive(Some("machine"), Some(&[8u8; SALT_LEN])),
+            derive(Some("machine"), None),
+            derive(None, Some(&salt)),
+            derive(None, None),
+            // Parts do not run together, nor swap places.
+            derive(Some("ab"), Some(b"c")),
+            derive(Some("a"), Some(b"bc")),
+            derive(Some("ab"), None),
+            derive(None, Some(b"ab")),
+        ];
+        for (i, a) in ids.iter().enumerate() {
+            for b in &ids[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+        // Not a plain hash of the uid.
+        assert_ne!(derive(Some("machine"), None), hex::encode(Sha256::digest(b"machine")));
+    }
+
+    #[test]
+    fn the_salt_is_made_once_and_kept_private() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("install.salt");
+        let salt = load_salt(&path).unwrap();
+        assert_eq!(salt.len(), SALT_LEN);
+        assert_eq!(std::fs::read(&path).unwrap(), salt);
+        assert_eq!(load_salt(&path).unwrap(), salt);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        // Another install gets another salt.
+        assert_ne!(load_salt(&dir.path().join("other.salt")).unwrap(), salt);
+    }
+
+    #[test]
+    fn a_malformed_salt_is_set_aside_and_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("install.salt");
+        std::fs::write(&path, b"short").unwrap();
+        let salt = load_salt(&path).unwrap();
+        assert_eq!(salt.len(), SALT_LEN);
+        let aside: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|p| p.to_string_lossy().contains("install.corrupt-"))
+            .collect();
+        assert_eq!(aside.len(), 1);
+        assert_eq!(std::fs::read(&aside[0]).unwrap(), b"short");
+
+        // Nowhere to keep one is an error, not a constant.
+        assert!(load_salt(&dir.path().join("missing/install.salt")).is_err());
+    }
+
+    #[test]
+    fn an_install_keeps_its_id_and_never_shows_the_machine_uid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("install.salt");
+        let first = MachineIdentity::load(&path);
+        let id = first.id().expect("this machine can bind licenses").to_string();
+        assert_eq!(MachineIdentity::load(&path).id(), Some(id.as_str()));
+        assert_ne!(MachineIdentity::load(&dir.path().join("other.salt")).id(), Some(id.as_str()));
+        assert!(matches!(first.binding(), MachineBinding::Bound));
+        if let Ok(uid) = machine_uid::get() {
+            assert!(!id.contains(&uid));
+        }
+
+        let unbindable = MachineIdentity::Unbindable("no machine uid and no install salt".into());
+        assert_eq!(unbindable.id(), None);
+        match unbindable.binding() {
+            MachineBinding::Unbindable { reason } => assert_eq!(reason, "no machine uid and no install salt"),
+            other => panic!("Expected Unbindable, got {other:?}"),
+        }
+    }
+}
@@ -1,23 +1,84 @@
-use sovereign_core::CognitiveCore;
-use sovereign_runtime_wasm::WasmRuntime;
-use std::sync::Arc;
-use std::time::SystemTime;
-use tokio::sync::Mutex;
+use sovereign_node::ServiceAction;
+use std::path::PathBuf;
 
-mod service_loop;
-
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    env_logger::init();
-
-    // Note: This is adapted for macOS. Original uses Windows services.
-    // For macOS, run as a regular process.
-
-    let start_time = SystemTime::now();
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse()?;
+    if let Some(action) = args.service {
+        return sovereign_node::service(action, args.config);
+    }
+    let config = args.config.unwrap_or_else(sovereign_node::default_config_path);
+    // The SCM's thread runs a runtime of its own.
+    #[cfg(windows)]
+    if args.scm {
+        return sovereign_node::run_as_service(config);
+    }
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    if args.check {
+        if !runtime.block_on(sovereign_node::check(config))? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    runtime.block_on(sovereign_node::run(config))
+}
 
-    // Initialize core and wasm
-    let core = Arc::new(Mutex::new(CognitiveCore::new()?));
-    let wasm = Arc::new(WasmRuntime::new()?);
+struct Args {
+    /// `--config <path>`; the platform's default config path if unset.
+    config: Option<PathBuf>,
+    /// `--check`: run the self-check and exit instead of starting.
+    check: bool,
+    /// `service install [--socket-activated]|uninstall|start|stop|status`.
+    service: Option<ServiceAction>,
+    /// `--service`: started by the Windows SCM, as `service install`
+    /// registers the node.
+    #[cfg(windows)]
+    scm: bool,
+}
 
-    service_loop::run_ipc_server(core, wasm, start_time).await
+impl Args {
+    fn parse() -> anyhow::Result<Self> {
+        let mut config = None;
+        let mut check = false;
+        let mut service = None;
+        #[cfg(windows)]
+        let mut scm = false;
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if let Some(path) = arg.strip_prefix("--config=") {
+                config = Some(PathBuf::from(path));
+                continue;
+            }
+            match arg.as_str() {
+                "--config" => match args.next() {
+                    Some(path) => config = Some(PathBuf::from(path)),
+                    None => anyhow::bail!("--config needs a path"),
+                },
+                "--check" => check = true,
+                #[cfg(windows)]
+                "--service" => scm = true,
+                "service" => {
+                    service = Some(match args.next().as_deref() {
+                        Some("install") => ServiceAction::Install { socket_activated: false },
+                        Some("uninstall") => ServiceAction::Uninstall,
+                        Some("start") => ServiceAction::Start,
+                        Some("stop") => ServiceAction::Stop,
+                        Some("status") => ServiceAction::Status,
+                        _ => anyhow::bail!("service needs install, uninstall, start, stop or status"),
+                    })
+                }
+                "--socket-activated" => match &mut service {
+                    Some(ServiceAction::Install { socket_activated }) => *socket_activated = true,
+                    _ => anyhow::bail!("--socket-activated only applies to service install"),
+                },
+                other => anyhow::bail!("Unknown argument '{}'", other),
+            }
+        }
+        Ok(Self {
+            config,
+            check,
+            service,
+            #[cfg(windows)]
+            scm,
+        })
+    }
 }
@@ -0,0 +1,466 @@
+#[cfg(feature = "fault-injection")]
+use crate::fault::FaultPlan;
+use crate::service_loop::SharedState;
+use sovereign_mesh::{identity, MeshCommand, MeshConfig, MeshMessage, MeshNode, MeshRequest};
+use sovereign_protocol::MeshPhase;
+#[cfg(feature = "fault-injection")]
+use sovereign_protocol::{FaultKind, FaultTarget};
+use std::any::Any;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinError;
+use tracing::{error, info, warn};
+
+/// Commands queued for the actor itself, behind the supervisor.
+const ACTOR_QUEUE: usize = 32;
+
+/// How often, and how patiently, a dead mesh actor is started again.
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    /// Restarts in a row before giving up. An actor that ran for
+    /// `stable_after` starts the count over.
+    pub max_restarts: u32,
+    /// The wait before the first restart, doubling up to `max_backoff`.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    pub stable_after: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: 5,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            stable_after: Duration::from_secs(300),
+        }
+    }
+}
+
+/// A mesh actor's run, to spawn.
+type ActorFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Builds an actor reading the given commands: the mesh node, or a stand-in
+/// in tests.
+type BuildActor = Box<dyn Fn(mpsc::Receiver<MeshCommand>) -> anyhow::Result<ActorFuture> + Send + Sync>;
+
+/// Builds the mesh actor and starts the task that keeps it running. The
+/// supervisor owns `commands`, so every sender the node has handed out
+/// keeps working across restarts. Returns the mesh's identity, which
+/// restarted actors keep.
+pub(crate) fn start(
+    config: MeshConfig,
+    commands: mpsc::Receiver<MeshCommand>,
+    state: Arc<watch::Sender<SharedState>>,
+    policy: RestartPolicy,
+    #[cfg(feature = "fault-injection")] faults: Arc<FaultPlan>,
+    on_restart: impl Fn() + Send + 'static,
+) -> anyhow::Result<identity::Keypair> {
+    let (actor_tx, actor_rx) = mpsc::channel(ACTOR_QUEUE);
+    let node = MeshNode::new(&config, actor_rx)?;
+    let keys = node.keypair();
+    let restarted_keys = keys.clone();
+    let build: BuildActor = Box::new(move |actor_rx| Ok(Box::pin(MeshNode::with_keypair(&config, restarted_keys.clone(), actor_rx)?.run())));
+    let supervisor = Supervisor {
+        build,
+        state,
+        policy,
+        replay: Replay::default(),
+        #[cfg(feature = "fault-injection")]
+        faults,
+    };
+    tokio::spawn(supervisor.run(Box::pin(node.run()), actor_tx, commands, on_restart));
+    Ok(keys)
+}
+
+struct Supervisor {
+    /// Builds restarted actors, under the first one's identity.
+    build: BuildActor,
+    state: Arc<watch::Sender<SharedState>>,
+    policy: RestartPolicy,
+    replay: Replay,
+    #[cfg(feature = "fault-injection")]
+    faults: Arc<FaultPlan>,
+}
+
+/// Commands whose effect outlives the actor, sent again to a new one so
+/// subscribers and the request handler never notice the restart.
+#[derive(Default)]
+struct Replay {
+    subscriptions: Vec<(String, mpsc::Sender<MeshMessage>)>,
+    request_handler: Option<mpsc::Sender<MeshRequest>>,
+}
+
+impl Replay {
+    fn note(&mut self, command: &MeshCommand) {
+        match command {
+            MeshCommand::Subscribe { topic, messages } => {
+                self.subscriptions.retain(|(_, tx)| !tx.is_closed());
+                self.subscriptions.push((topic.clone(), messages.clone()));
+            }
+            MeshCommand::ServeRequests(requests) => self.request_handler = Some(requests.clone()),
+            _ => {}
+        }
+    }
+
+    fn commands(&self) -> Vec<MeshCommand> {
+        let subscriptions = self.subscriptions.iter().filter(|(_, tx)| !tx.is_closed()).map(|(topic, messages)| MeshCommand::Subscribe {
+            topic: topic.clone(),
+            messages: messages.clone(),
+        });
+        subscriptions.chain(self.request_handler.clone().map(MeshCommand::ServeRequests)).collect()
+    }
+}
+
+impl Supervisor {
+    async fn run(
+        mut self,
+        mut node: ActorFuture,
+        mut actor_tx: mpsc::Sender<MeshCommand>,
+        mut commands: mpsc::Receiver<MeshCommand>,
+        on_restart: impl Fn(),
+    ) {
+        let mut restarts = 0u32;
+        let mut backoff = self.policy.backoff;
+        loop {
+            let started = Instant::now();
+            let mut actor = tokio::spawn(node);
+            #[cfg(feature = "fault-injection")]
+            self.faults.mesh_actor(actor.abort_handle());
+            let mut stopping = false;
+            let ended = loop {
+                tokio::select! {
+                    command = commands.recv() => {
+                        let Some(command) = command else {
+                            // Every sender is gone: the node has finished.
+                            drop(actor_tx);
+                            let _ = actor.await;
+                            return;
+                        };
+                        self.replay.note(&command);
+                        stopping |= matches!(command, MeshCommand::Shutdown(_));
+                        #[cfg(feature = "fault-injection")]
+                        if !stopping {
+                            match self.faults.active(FaultTarget::Mesh) {
+                                Some(FaultKind::Drop) => continue,
+                                Some(FaultKind::Delay { delay_ms }) => tokio::time::sleep(Duration::from_millis(delay_ms)).await,
+                                _ => {}
+                            }
+                        }
+                        // A dead actor drops the command, failing its caller
+                        // at once; the actor's end is picked up below.
+                        let _ = actor_tx.send(command).await;
+                    }
+                    ended = &mut actor => break ended,
+                }
+            };
+            if stopping {
+                return;
+            }
+            let reason = death(ended);
+            error!("The mesh actor died: {}", reason);
+            if started.elapsed() >= self.policy.stable_after {
+                restarts = 0;
+                backoff = self.policy.backoff;
+            }
+            (node, actor_tx) = loop {
+                restarts += 1;
+                if restarts > self.policy.max_restarts {
+                    self.give_up(reason, &mut commands).await;
+                    return;
+                }
+                warn!("Restarting the mesh actor in {:?} (attempt {} of {})", backoff, restarts, self.policy.max_restarts);
+                self.state.send_modify(|s| {
+                    s.mesh_phase = MeshPhase::Restarting {
+                        attempt: restarts,
+                        reason: reason.clone(),
+                    }
+                });
+                if !self.wait(backoff, &mut commands).await {
+                    return;
+                }
+                backoff = (backoff * 2).min(self.policy.max_backoff);
+                match self.rebuild().await {
+                    Ok(rebuilt) => break rebuilt,
+                    Err(e) => error!("Failed to rebuild the mesh actor: {:#}", e),
+                }
+            };
+            info!("Mesh actor restarted");
+            self.state.send_modify(|s| s.mesh_phase = MeshPhase::WarmingUp);
+            on_restart();
+        }
+    }
+
+    /// A fresh actor under the same identity, with the subscriptions and
+    /// request handler the old one had.
+    async fn rebuild(&self) -> anyhow::Result<(ActorFuture, mpsc::Sender<MeshCommand>)> {
+        let (actor_tx, actor_rx) = mpsc::channel(ACTOR_QUEUE);
+        let node = (self.build)(actor_rx)?;
+        for command in self.replay.commands() {
+            actor_tx.send(command).await?;
+        }
+        Ok((node, actor_tx))
+    }
+
+    /// Sleeps for `delay`, dropping the commands that arrive meanwhile so
+    /// their callers fail at once. False if the node is shutting down.
+    async fn wait(&mut self, delay: Duration, commands: &mut mpsc::Receiver<MeshCommand>) -> bool {
+        let sleep = tokio::time::sleep(delay);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                () = &mut sleep => return true,
+                command = commands.recv() => match command {
+                    Some(MeshCommand::Shutdown(done)) => {
+                        let _ = done.send(());
+                        return false;
+                    }
+                    Some(command) => self.replay.note(&command),
+                    None => return false,
+                },
+            }
+        }
+    }
+
+    /// Marks the mesh failed for good and fails every later command.
+    async fn give_up(&self, reason: String, commands: &mut mpsc::Receiver<MeshCommand>) {
+        error!("The mesh actor died {} times in a row. Running without a mesh.", self.policy.max_restarts + 1);
+        self.state.send_modify(|s| s.mesh_phase = MeshPhase::Failed { reason });
+        while let Some(command) = commands.recv().await {
+            if let MeshCommand::Shutdown(done) = command {
+                let _ = done.send(());
+                return;
+            }
+        }
+    }
+}
+
+/// Why the actor's task ended, with the panic message if it panicked.
+fn death(ended: Result<(), JoinError>) -> String {
+    match ended {
+        Ok(()) => "it stopped on its own".into(),
+        Err(e) if e.is_panic() => format!("it panicked: {}", panic_message(e.into_panic())),
+        Err(e) => e.to_string(),
+    }
+}
+
+pub(crate) fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "no message".into(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Mutex;
+
+    /// What the stand-in actors were asked to do.
+    #[derive(Default)]
+    struct Log {
+        /// When each restarted actor was built.
+        built: Vec<Instant>,
+        /// `(actor, topic)` for each subscription an actor took.
+        subscribed: Vec<(u32, String)>,
+    }
+
+    /// An actor that names itself by generation, panics when asked to
+    /// publish on "panic", and with `short_lived` stops at once.
+    async fn actor(generation: u32, mut commands: mpsc::Receiver<MeshCommand>, log: Arc<Mutex<Log>>, short_lived: bool) {
+        if short_lived {
+            return;
+        }
+        while let Some(command) = commands.recv().await {
+            match command {
+                MeshCommand::GetPeerId(tx) => drop(tx.send(format!("actor-{}", generation))),
+                MeshCommand::Subscribe { topic, .. } => log.lock().unwrap().subscribed.push((generation, topic)),
+                MeshCommand::Publish { topic, .. } if topic == "panic" => panic!("told to"),
+                MeshCommand::Shutdown(done) => {
+                    let _ = done.send(());
+                    return;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    struct Supervised {
+        commands: mpsc::Sender<MeshCommand>,
+        state: Arc<watch::Sender<SharedState>>,
+        restarts: Arc<AtomicU32>,
+        log: Arc<Mutex<Log>>,
+        started: Instant,
+    }
+
+    fn supervise(policy: RestartPolicy, short_lived: bool) -> Supervised {
+        let log = Arc::new(Mutex::new(Log::default()));
+        let generations = AtomicU32::new(1);
+        let build: BuildActor = {
+            let log = log.clone();
+            Box::new(move |commands| {
+                log.lock().unwrap().built.push(Instant::now());
+                let generation = generations.fetch_add(1, Ordering::Relaxed);
+                Ok(Box::pin(actor(generation, commands, log.clone(), short_lived)))
+            })
+        };
+        let state = Arc::new(watch::Sender::new(SharedState {
+            peer_id: "peer".into(),
+            connections: 0,
+            listen_addrs: Vec::new(),
+            mesh_phase: MeshPhase::Ready,
+            license_active: false,
+            license_report: None,
+            presence: None,
+        }));
+        let supervisor = Supervisor {
+            build,
+            state: state.clone(),
+            policy,
+            replay: Replay::default(),
+            #[cfg(feature = "fault-injection")]
+            faults: Arc::new(FaultPlan::default()),
+        };
+        let (commands, commands_rx) = mpsc::channel(8);
+        let (actor_tx, actor_rx) = mpsc::channel(ACTOR_QUEUE);
+        let restarts = Arc::new(AtomicU32::new(0));
+        let on_restart = {
+            let restarts = restarts.clone();
+            move || {
+                restarts.fetch_add(1, Ordering::Relaxed);
+            }
+        };
+        let started = Instant::now();
+        tokio::spawn(supervisor.run(Box::pin(actor(0, actor_rx, log.clone(), short_lived)), actor_tx, commands_rx, on_restart));
+        Supervised {
+            commands,
+            state,
+            restarts,
+            log,
+            started,
+        }
+    }
+
+    /// The actor's answer, or `None` if the command was dropped.
+    async fn peer_id(commands: &mpsc::Sender<MeshCommand>) -> Option<String> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        commands.send(MeshCommand::GetPeerId(tx)).await.ok()?;
+        tokio::time::timeout(Duration::from_secs(5), rx).await.unwrap().ok()
+    }
+
+    /// Waits for the actor of `generation` to answer.
+    async fn answered_by(commands: &mpsc::Sender<MeshCommand>, generation: u32) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let expected = format!("actor-{}", generation);
+        while peer_id(commands).await.as_ref() != Some(&expected) {
+            assert!(Instant::now() < deadline, "{} never answered", expected);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn crash(commands: &mpsc::Sender<MeshCommand>) {
+        let publish = MeshCommand::Publish {
+            topic: "panic".into(),
+            data: Vec::new(),
+        };
+        commands.send(publish).await.unwrap();
+    }
+
+    async fn phase(state: &watch::Sender<SharedState>, matches: impl Fn(&MeshPhase) -> bool) -> MeshPhase {
+        let mut state = state.subscribe();
+        let reached = tokio::time::timeout(Duration::from_secs(5), state.wait_for(|s| matches(&s.mesh_phase))).await.unwrap().unwrap();
+        reached.mesh_phase.clone()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restarts_a_panicked_actor_with_its_subscriptions() {
+        let policy = RestartPolicy {
+            backoff: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let mesh = supervise(policy, false);
+        assert_eq!(peer_id(&mesh.commands).await.as_deref(), Some("actor-0"));
+        let (messages, _messages_rx) = mpsc::channel(1);
+        mesh.commands.send(MeshCommand::Subscribe { topic: "news".into(), messages }).await.unwrap();
+
+        crash(&mesh.commands).await;
+        match phase(&mesh.state, |phase| matches!(phase, MeshPhase::Restarting { .. })).await {
+            MeshPhase::Restarting { attempt, reason } => {
+                assert_eq!(attempt, 1);
+                assert_eq!(reason, "it panicked: told to");
+            }
+            other => panic!("Expected Restarting, got {:?}", other),
+        }
+        // Commands sent meanwhile fail at once rather than hang.
+        assert_eq!(peer_id(&mesh.commands).await, None);
+
+        answered_by(&mesh.commands, 1).await;
+        assert!(matches!(mesh.state.borrow().mesh_phase, MeshPhase::WarmingUp));
+        assert_eq!(mesh.restarts.load(Ordering::Relaxed), 1);
+        assert_eq!(mesh.log.lock().unwrap().subscribed, [(0, "news".to_string()), (1, "news".to_string())]);
+
+        let (done, stopped) = tokio::sync::oneshot::channel();
+        mesh.commands.send(MeshCommand::Shutdown(done)).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), stopped).await.unwrap().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn backs_off_then_gives_up() {
+        let policy = RestartPolicy {
+            max_restarts: 3,
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(100),
+            stable_after: Duration::from_secs(3600),
+        };
+        let mesh = supervise(policy, true);
+        match phase(&mesh.state, |phase| matches!(phase, MeshPhase::Failed { .. })).await {
+            MeshPhase::Failed { reason } => assert_eq!(reason, "it stopped on its own"),
+            other => panic!("Expected Failed, got {:?}", other),
+        }
+
+        let built = mesh.log.lock().unwrap().built.clone();
+        assert_eq!(built.len(), 3);
+        let mut last = mesh.started;
+        for (built, backoff) in built.into_iter().zip([50, 100, 100]) {
+            assert!(built - last >= Duration::from_millis(backoff), "Waited {:?}, not {} ms", built - last, backoff);
+            last = built;
+        }
+        assert_eq!(mesh.restarts.load(Ordering::Relaxed), 3);
+
+        // Without a mesh, commands fail, and shutdown still finishes.
+        assert_eq!(peer_id(&mesh.commands).await, None);
+        let (done, stopped) = tokio::sync::oneshot::channel();
+        mesh.commands.send(MeshCommand::Shutdown(done)).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), stopped).await.unwrap().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn an_actor_that_ran_a_while_starts_the_count_over() {
+        let policy = RestartPolicy {
+            max_restarts: 1,
+            backoff: Duration::from_millis(10),
+            stable_after: Duration::ZERO,
+            ..Default::default()
+        };
+        let mesh = supervise(policy, false);
+        for generation in 1..=3 {
+            crash(&mesh.commands).await;
+            answered_by(&mesh.commands, generation).await;
+        }
+        assert_eq!(mesh.restarts.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn names_how_the_actor_died() {
+        assert_eq!(panic_message(Box::new("static")), "static");
+        assert_eq!(panic_message(Box::new(String::from("owned"))), "owned");
+        assert_eq!(panic_message(Box::new(42)), "no message");
+    }
+}
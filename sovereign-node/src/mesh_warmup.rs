@@ -0,0 +1,252 @@
+use crate::service_loop::SharedState;
+use futures::future::join_all;
+use sovereign_mesh::MeshCommand;
+use sovereign_protocol::MeshPhase;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::{debug, info, warn};
+
+/// What the node does with the mesh as it starts.
+#[derive(Debug, Clone)]
+pub struct MeshWarmup {
+    /// Dialed at startup, along with the peers pinned by `MeshDial`.
+    pub bootstrap_peers: Vec<String>,
+    /// Gossip topics joined at startup, so the node relays them without a
+    /// client asking.
+    pub topics: Vec<String>,
+    /// How long to wait for a first peer before reporting the mesh
+    /// isolated. Also bounds each dial.
+    pub timeout: Duration,
+}
+
+impl Default for MeshWarmup {
+    fn default() -> Self {
+        Self {
+            bootstrap_peers: Vec::new(),
+            topics: Vec::new(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Joins the configured topics, dials the bootstrap and `pinned` peers all
+/// at once and starts a Kademlia bootstrap once any answers, while moving
+/// `state` out of `WarmingUp` when a peer connects or the timeout passes.
+/// Runs beside the IPC server; nothing here can stop it starting.
+pub(crate) async fn run(mesh: mpsc::Sender<MeshCommand>, state: Arc<watch::Sender<SharedState>>, pinned: Vec<String>, warmup: MeshWarmup) {
+    let mut peers = warmup.bootstrap_peers;
+    for addr in pinned {
+        if !peers.contains(&addr) {
+            peers.push(addr);
+        }
+    }
+    join_topics(&mesh, warmup.topics).await;
+    tokio::join!(dial_all(&mesh, peers, warmup.timeout), settle_phase(&state, warmup.timeout));
+}
+
+async fn join_topics(mesh: &mpsc::Sender<MeshCommand>, topics: Vec<String>) {
+    if topics.is_empty() {
+        return;
+    }
+    // Nothing on the node consumes these yet; joining makes it a relay.
+    let (messages, mut received) = mpsc::channel(64);
+    for topic in topics {
+        info!("Joining gossip topic {}", topic);
+        let subscribe = MeshCommand::Subscribe {
+            topic,
+            messages: messages.clone(),
+        };
+        if mesh.send(subscribe).await.is_err() {
+            return;
+        }
+    }
+    drop(messages);
+    tokio::spawn(async move {
+        while let Some(message) = received.recv().await {
+            debug!("Gossip on {} from {}: {} bytes", message.topic, message.source, message.data.len());
+        }
+    });
+}
+
+async fn dial_all(mesh: &mpsc::Sender<MeshCommand>, peers: Vec<String>, timeout: Duration) {
+    if peers.is_empty() {
+        return;
+    }
+    let total = peers.len();
+    let dials = peers.into_iter().map(|addr| async move {
+        let (reply, answer) = oneshot::channel();
+        if mesh.send(MeshCommand::Connect { addr: addr.clone(), reply }).await.is_err() {
+            return false;
+        }
+        match tokio::time::timeout(timeout, answer).await {
+            Ok(Ok(Ok(peer))) => {
+                info!("Connected to {} at {}", peer, addr);
+                true
+            }
+            Ok(Ok(Err(e))) => {
+                warn!("Failed to dial {}: {}", addr, e);
+                false
+            }
+            Ok(Err(_)) => false,
+            Err(_) => {
+                warn!("Dialing {} did not finish within {:?}", addr, timeout);
+                false
+            }
+        }
+    });
+    let connected = join_all(dials).await.into_iter().filter(|ok| *ok).count();
+    info!("Mesh warm-up reached {} of {} peers", connected, total);
+    if connected == 0 {
+        return;
+    }
+    let (reply, started) = oneshot::channel();
+    if mesh.send(MeshCommand::Bootstrap(reply)).await.is_err() {
+        return;
+    }
+    match started.await {
+        Ok(Ok(())) => info!("Started a Kademlia bootstrap"),
+        Ok(Err(e)) => warn!("Failed to start a Kademlia bootstrap: {}", e),
+        Err(_) => {}
+    }
+}
+
+/// `Ready` once a peer connects. `Isolated` if none has by `timeout`,
+/// turning `Ready` should one connect later.
+async fn settle_phase(state: &watch::Sender<SharedState>, timeout: Duration) {
+    let mut changes = state.subscribe();
+    let connected = tokio::time::timeout(timeout, changes.wait_for(|s| s.connections > 0)).await.map(|found| found.is_ok());
+    match connected {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(_) => {
+            warn!("No mesh peer connected within {:?}. Carrying on without one.", timeout);
+            state.send_modify(|s| s.mesh_phase = MeshPhase::Isolated);
+            if changes.wait_for(|s| s.connections > 0).await.is_err() {
+                return;
+            }
+        }
+    }
+    info!("The mesh is ready");
+    state.send_modify(|s| s.mesh_phase = MeshPhase::Ready);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::time::Instant;
+
+    fn state() -> Arc<watch::Sender<SharedState>> {
+        Arc::new(watch::Sender::new(SharedState {
+            peer_id: "local".into(),
+            connections: 0,
+            listen_addrs: Vec::new(),
+            mesh_phase: MeshPhase::WarmingUp,
+            license_active: false,
+            license_report: None,
+            presence: None,
+        }))
+    }
+
+    /// What the node asked of the mesh.
+    #[derive(Default, Debug)]
+    struct Asked {
+        topics: Vec<String>,
+        dials: Vec<String>,
+        bootstraps: usize,
+    }
+
+    /// A mesh that connects to addresses starting `good`, fails ones
+    /// starting `bad` and never answers others.
+    fn mesh() -> (mpsc::Sender<MeshCommand>, Arc<Mutex<Asked>>) {
+        let (tx, mut rx) = mpsc::channel(16);
+        let asked = Arc::new(Mutex::new(Asked::default()));
+        let record = asked.clone();
+        tokio::spawn(async move {
+            let mut unanswered = Vec::new();
+            while let Some(command) = rx.recv().await {
+                let mut asked = record.lock().unwrap();
+                match command {
+                    MeshCommand::Subscribe { topic, .. } => asked.topics.push(topic),
+                    MeshCommand::Connect { addr, reply } => {
+                        asked.dials.push(addr.clone());
+                        if addr.starts_with("good") {
+                            let _ = reply.send(Ok(format!("peer-{}", addr)));
+                        } else if addr.starts_with("bad") {
+                            let _ = reply.send(Err(anyhow::anyhow!("connection refused")));
+                        } else {
+                            unanswered.push(reply);
+                        }
+                    }
+                    MeshCommand::Bootstrap(reply) => {
+                        asked.bootstraps += 1;
+                        let _ = reply.send(Ok(()));
+                    }
+                    other => panic!("Unexpected {:?}", std::mem::discriminant(&other)),
+                }
+            }
+        });
+        (tx, asked)
+    }
+
+    fn warmup(bootstrap_peers: &[&str], topics: &[&str], timeout_ms: u64) -> MeshWarmup {
+        MeshWarmup {
+            bootstrap_peers: bootstrap_peers.iter().map(|s| s.to_string()).collect(),
+            topics: topics.iter().map(|s| s.to_string()).collect(),
+            timeout: Duration::from_millis(timeout_ms),
+        }
+    }
+
+    async fn phase(state: &watch::Sender<SharedState>, want: MeshPhase) {
+        let mut changes = state.subscribe();
+        tokio::time::timeout(Duration::from_secs(5), changes.wait_for(|s| s.mesh_phase == want)).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn joins_topics_dials_every_peer_at_once_and_bootstraps() {
+        let (mesh, asked) = mesh();
+        let state = state();
+        let pinned = vec!["good-b".to_string(), "good-a".to_string()];
+        let started = Instant::now();
+        let running = tokio::spawn(run(mesh, state.clone(), pinned, warmup(&["good-a", "bad", "silent-1", "silent-2"], &["news", "jobs"], 300)));
+
+        phase(&state, MeshPhase::Isolated).await;
+        state.send_modify(|s| s.connections = 1);
+        phase(&state, MeshPhase::Ready).await;
+        running.await.unwrap();
+        // The two silent dials waited out one timeout together.
+        assert!(started.elapsed() < Duration::from_millis(550), "{:?}", started.elapsed());
+
+        let asked = asked.lock().unwrap();
+        assert_eq!(asked.topics, ["news", "jobs"]);
+        let mut dials = asked.dials.clone();
+        dials.sort();
+        assert_eq!(dials, ["bad", "good-a", "good-b", "silent-1", "silent-2"]);
+        assert_eq!(asked.bootstraps, 1);
+    }
+
+    #[tokio::test]
+    async fn does_not_bootstrap_with_no_peer_reached() {
+        let (mesh, asked) = mesh();
+        let state = state();
+        let running = tokio::spawn(run(mesh, state.clone(), Vec::new(), warmup(&["bad", "silent"], &[], 100)));
+        phase(&state, MeshPhase::Isolated).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(asked.lock().unwrap().bootstraps, 0);
+        assert_eq!(asked.lock().unwrap().dials.len(), 2);
+        state.send_modify(|s| s.connections = 1);
+        running.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn is_ready_as_soon_as_a_peer_connects() {
+        let (mesh, asked) = mesh();
+        let state = state();
+        let running = tokio::spawn(run(mesh, state.clone(), Vec::new(), warmup(&[], &[], 60_000)));
+        state.send_modify(|s| s.connections = 2);
+        phase(&state, MeshPhase::Ready).await;
+        tokio::time::timeout(Duration::from_secs(5), running).await.unwrap().unwrap();
+        assert!(asked.lock().unwrap().dials.is_empty());
+    }
+}
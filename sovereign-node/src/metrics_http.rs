@@ -0,0 +1,438 @@
+use crate::blocking_pool::{BlockingPool, PoolError};
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use sovereign_protocol::{FinanceState, MeshPhase, MetricsSnapshot, NodeStatus, LATENCY_BUCKETS_MS};
+use std::fmt::{Display, Write};
+use std::future::Future;
+use std::net::Ipv4Addr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+use tracing::{info, warn};
+
+/// How long a scrape waits for the subsystems before serving the last
+/// figures it has, marked stale.
+const SCRAPE_BUDGET: Duration = Duration::from_millis(250);
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+type Gathered = (NodeStatus, MetricsSnapshot);
+
+type Gathering = Pin<Box<dyn Future<Output = Result<Gathered, PoolError>> + Send>>;
+
+/// Where scrapes get the node's figures from.
+pub(crate) trait ScrapeSource: Send + Sync + 'static {
+    /// Gathers what `GetStatus` and `GetMetrics` report. May block on the
+    /// subsystems, so it runs off the async workers, on `pool`.
+    fn gather(&self) -> Gathered;
+
+    fn pool(&self) -> &BlockingPool;
+}
+
+struct Scraper<S> {
+    source: Arc<S>,
+    /// A gather that outlasted its scrape, picked up by the next one rather
+    /// than started again.
+    pending: Mutex<Option<Gathering>>,
+    last: StdMutex<Option<Gathered>>,
+}
+
+impl<S: ScrapeSource> Scraper<S> {
+    /// The node's figures, and whether they are older than this scrape.
+    async fn scrape(&self) -> (Option<Gathered>, bool) {
+        let mut pending = self.pending.lock().await;
+        let task = pending.get_or_insert_with(|| {
+            let source = self.source.clone();
+            Box::pin(self.source.pool().spawn(move || source.gather()))
+        });
+        let fresh = match tokio::time::timeout(SCRAPE_BUDGET, task).await {
+            Ok(done) => {
+                *pending = None;
+                done.ok()
+            }
+            Err(_) => None,
+        };
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        match fresh {
+            Some(gathered) => {
+                *last = Some(gathered.clone());
+                (Some(gathered), false)
+            }
+            None => (last.clone(), true),
+        }
+    }
+}
+
+/// Serves `/metrics` and `/healthz` on `port` on the loopback interface
+/// until `shutdown` turns true. Failing to bind is logged, not fatal.
+pub(crate) async fn serve<S: ScrapeSource>(port: u16, source: Arc<S>, mut shutdown: watch::Receiver<bool>) {
+    let listener = match tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Failed to serve metrics on 127.0.0.1:{}: {}. Continuing without them.", port, e);
+            return;
+        }
+    };
+    info!("Serving metrics on http://127.0.0.1:{}/metrics", port);
+    let scraper = Arc::new(Scraper {
+        source,
+        pending: Mutex::new(None),
+        last: StdMutex::new(None),
+    });
+    let app = Router::new().route("/metrics", get(metrics::<S>)).route("/healthz", get(healthz::<S>)).with_state(scraper);
+    let stopped = async move {
+        let _ = shutdown.wait_for(|stop| *stop).await;
+    };
+    if let Err(e) = axum::serve(listener, app).with_graceful_shutdown(stopped).await {
+        warn!("The metrics server failed: {}", e);
+    }
+}
+
+async fn metrics<S: ScrapeSource>(State(scraper): State<Arc<Scraper<S>>>) -> impl IntoResponse {
+    let (gathered, stale) = scraper.scrape().await;
+    let mut out = Exposition::default();
+    out.family("sovereign_scrape_stale", "gauge", "1 if the subsystems did not answer in time and these are the last figures gathered.");
+    out.sample("sovereign_scrape_stale", &[], stale as u8);
+    if let Some((status, metrics)) = gathered {
+        render(&mut out, &status, &metrics, stale);
+    }
+    ([(header::CONTENT_TYPE, CONTENT_TYPE)], out.0)
+}
+
+/// 200 while the node reports itself healthy; 503 when it does not, or
+/// does not answer in time.
+async fn healthz<S: ScrapeSource>(State(scraper): State<Arc<Scraper<S>>>) -> impl IntoResponse {
+    match scraper.scrape().await {
+        (Some((status, _)), false) if status.system_health == "OK" => (StatusCode::OK, "ok\n".to_string()),
+        (Some((status, _)), false) => {
+            let reasons = status.health.reasons().iter().map(|r| format!("{}\n", r)).collect::<String>();
+            (StatusCode::SERVICE_UNAVAILABLE, format!("{}\n{}{}\n", status.system_health, reasons, status.health_details.join("\n")))
+        }
+        _ => (StatusCode::SERVICE_UNAVAILABLE, "The node's subsystems did not answer in time\n".to_string()),
+    }
+}
+
+fn render(out: &mut Exposition, status: &NodeStatus, metrics: &MetricsSnapshot, stale: bool) {
+    out.family("sovereign_healthy", "gauge", "1 while the node reports itself healthy.");
+    out.sample("sovereign_healthy", &[], (!stale && status.system_health == "OK") as u8);
+    out.family("sovereign_health_level", "gauge", "1 for the node's overall health level.");
+    let level = status.system_health.to_ascii_lowercase();
+    for each in ["ok", "degraded", "critical"] {
+        out.sample("sovereign_health_level", &[("level", each)], (!stale && each == level) as u8);
+    }
+    let resources = &status.resources;
+    if let Some(rss) = resources.rss_bytes {
+        out.family("sovereign_process_resident_bytes", "gauge", "The node process's resident memory.");
+        out.sample("sovereign_process_resident_bytes", &[], rss);
+    }
+    if let Some(cpu) = resources.cpu_percent {
+        out.family("sovereign_process_cpu_percent", "gauge", "The node process's CPU use, as a share of the whole machine.");
+        out.sample("sovereign_process_cpu_percent", &[], cpu);
+    }
+    if let Some(free) = resources.data_dir_free_bytes {
+        out.family("sovereign_data_dir_free_bytes", "gauge", "Space left on the volume holding the data directory.");
+        out.sample("sovereign_data_dir_free_bytes", &[], free);
+    }
+    out.family("sovereign_uptime_seconds", "gauge", "Time since the node started.");
+    out.sample("sovereign_uptime_seconds", &[], status.uptime_ms as f64 / 1000.0);
+
+    out.family("sovereign_mesh_connections", "gauge", "Peers with at least one open connection.");
+    out.sample("sovereign_mesh_connections", &[], status.mesh_connections);
+    out.family("sovereign_mesh_listen_addrs", "gauge", "Addresses the mesh accepts peer connections on.");
+    out.sample("sovereign_mesh_listen_addrs", &[], status.mesh_listen_addrs.len());
+    out.family("sovereign_mesh_phase", "gauge", "1 for how far the mesh is through its startup dials.");
+    let phase = match status.mesh_phase {
+        MeshPhase::WarmingUp => "warming_up",
+        MeshPhase::Ready => "ready",
+        MeshPhase::Isolated => "isolated",
+        MeshPhase::Restarting { .. } => "restarting",
+        MeshPhase::Failed { .. } => "failed",
+        MeshPhase::Unknown => "unknown",
+    };
+    for each in ["warming_up", "ready", "isolated", "restarting", "failed", "unknown"] {
+        out.sample("sovereign_mesh_phase", &[("phase", each)], (each == phase) as u8);
+    }
+
+    out.family("sovereign_finance_state", "gauge", "1 for the license verifier's current connection state.");
+    let current = match status.finance {
+        FinanceState::Connecting => "connecting",
+        FinanceState::Ready => "ready",
+        FinanceState::Failed { .. } => "failed",
+        FinanceState::Unknown => "unknown",
+    };
+    for state in ["connecting", "ready", "failed", "unknown"] {
+        out.sample("sovereign_finance_state", &[("state", state)], (state == current) as u8);
+    }
+    out.family("sovereign_license_active", "gauge", "1 while the node's license is active.");
+    out.sample("sovereign_license_active", &[], status.license_active as u8);
+
+    let core = &metrics.core;
+    out.family("sovereign_core_size_bytes", "gauge", "Size of the core's store on disk.");
+    out.sample("sovereign_core_size_bytes", &[("backend", core.backend.as_str())], core.size_bytes);
+    out.family("sovereign_core_running_queries", "gauge", "Core queries running now.");
+    out.sample("sovereign_core_running_queries", &[], core.running_queries);
+
+    if let Some(replication) = &metrics.replication {
+        out.family("sovereign_replication_outbox_depth", "gauge", "Replicated ops waiting in the outbox to be gossiped.");
+        out.sample("sovereign_replication_outbox_depth", &[], replication.outbox_depth);
+        out.family("sovereign_replication_outbox_high_water", "gauge", "Outbox depth at which the node reports itself degraded.");
+        out.sample("sovereign_replication_outbox_high_water", &[], replication.outbox_high_water);
+        out.family("sovereign_replication_outbox_published_total", "counter", "Outbox entries gossiped.");
+        out.sample("sovereign_replication_outbox_published_total", &[], replication.outbox_published);
+        out.family("sovereign_replication_outbox_failures_total", "counter", "Failed tries at gossiping an outbox entry.");
+        out.sample("sovereign_replication_outbox_failures_total", &[], replication.outbox_failures);
+    }
+
+    let wasm = &metrics.wasm;
+    out.family("sovereign_wasm_running", "gauge", "WASM executions running now.");
+    out.sample("sovereign_wasm_running", &[], wasm.running);
+    out.family("sovereign_wasm_queued", "gauge", "WASM executions waiting for a slot.");
+    out.sample("sovereign_wasm_queued", &[], wasm.queued);
+    out.family("sovereign_wasm_admitted_total", "counter", "WASM executions admitted.");
+    out.sample("sovereign_wasm_admitted_total", &[], wasm.admitted);
+    out.family("sovereign_wasm_rejected_busy_total", "counter", "WASM executions refused because every slot was taken.");
+    out.sample("sovereign_wasm_rejected_busy_total", &[], wasm.rejected_busy);
+    out.family("sovereign_wasm_module_runs_total", "counter", "Runs of registered modules.");
+    out.sample("sovereign_wasm_module_runs_total", &[], wasm.module_runs);
+    out.family("sovereign_wasm_max_queue_wait_seconds", "gauge", "Longest wait any execution had for a slot.");
+    out.sample("sovereign_wasm_max_queue_wait_seconds", &[], wasm.max_queue_wait_ms as f64 / 1000.0);
+
+    out.family("sovereign_pool_running", "gauge", "Blocking work running now, by pool.");
+    for (pool, stats) in &metrics.pools {
+        out.sample("sovereign_pool_running", &[("pool", pool.as_str())], stats.running);
+    }
+    out.family("sovereign_pool_queued", "gauge", "Blocking work waiting for a thread, by pool.");
+    for (pool, stats) in &metrics.pools {
+        out.sample("sovereign_pool_queued", &[("pool", pool.as_str())], stats.queued);
+    }
+    out.family("sovereign_pool_rejected_total", "counter", "Blocking work refused because its pool was saturated, by pool.");
+    for (pool, stats) in &metrics.pools {
+        out.sample("sovereign_pool_rejected_total", &[("pool", pool.as_str())], stats.rejected);
+    }
+
+    let ipc = &metrics.ipc;
+    out.family("sovereign_ipc_accept_failures_total", "counter", "Failed attempts to accept an IPC client.");
+    out.sample("sovereign_ipc_accept_failures_total", &[], ipc.accept_failures);
+    let connections = &ipc.connections;
+    out.family("sovereign_ipc_connections", "gauge", "IPC clients connected now.");
+    out.sample("sovereign_ipc_connections", &[], connections.open);
+    out.family("sovereign_ipc_connections_accepted_total", "counter", "IPC clients accepted.");
+    out.sample("sovereign_ipc_connections_accepted_total", &[], connections.accepted);
+    out.family("sovereign_ipc_connections_rejected_total", "counter", "IPC clients turned away at the connection limit.");
+    out.sample("sovereign_ipc_connections_rejected_total", &[], connections.rejected);
+    out.family("sovereign_ipc_connections_idled_out_total", "counter", "IPC connections closed for sending nothing within the idle timeout.");
+    out.sample("sovereign_ipc_connections_idled_out_total", &[], connections.idled_out);
+    out.family("sovereign_ipc_parked_sessions", "gauge", "Sessions kept for clients that dropped to resume.");
+    out.sample("sovereign_ipc_parked_sessions", &[], ipc.parked_sessions);
+    out.family("sovereign_ipc_request_duration_seconds", "histogram", "Time spent handling IPC requests, by kind.");
+    for (kind, stats) in &ipc.requests {
+        for (bound, count) in LATENCY_BUCKETS_MS.iter().zip(&stats.latency) {
+            let le = (*bound as f64 / 1000.0).to_string();
+            out.sample("sovereign_ipc_request_duration_seconds_bucket", &[("kind", kind.as_str()), ("le", le.as_str())], count);
+        }
+        out.sample("sovereign_ipc_request_duration_seconds_bucket", &[("kind", kind.as_str()), ("le", "+Inf")], stats.count);
+        out.sample("sovereign_ipc_request_duration_seconds_sum", &[("kind", kind.as_str())], stats.total_micros as f64 / 1e6);
+        out.sample("sovereign_ipc_request_duration_seconds_count", &[("kind", kind.as_str())], stats.count);
+    }
+    out.family("sovereign_ipc_request_errors_total", "counter", "IPC requests answered with an error instead of what was asked, by kind.");
+    for (kind, stats) in &ipc.requests {
+        out.sample("sovereign_ipc_request_errors_total", &[("kind", kind.as_str())], stats.errors);
+    }
+    out.family("sovereign_ipc_received_bytes_total", "counter", "Bytes read from IPC clients.");
+    out.sample("sovereign_ipc_received_bytes_total", &[], ipc.bytes_in);
+    out.family("sovereign_ipc_sent_bytes_total", "counter", "Bytes written to IPC clients.");
+    out.sample("sovereign_ipc_sent_bytes_total", &[], ipc.bytes_out);
+    out.family("sovereign_ipc_rate_limited_total", "counter", "IPC requests refused for exceeding a budget, by kind.");
+    for (kind, count) in &ipc.rate_limited {
+        out.sample("sovereign_ipc_rate_limited_total", &[("kind", kind.as_str())], count);
+    }
+    out.family("sovereign_ipc_request_timeouts_total", "counter", "IPC requests answered with TimedOut, by kind.");
+    for (kind, count) in &metrics.timeouts {
+        out.sample("sovereign_ipc_request_timeouts_total", &[("kind", kind.as_str())], count);
+    }
+}
+
+/// Prometheus text exposition format.
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, escape(v))).collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', r"\\").replace('"', "\\\"").replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocking_pool::PoolConfig;
+    use crate::testkit::{TestNode, TestNodeOptions};
+    use sovereign_protocol::{Request, Response};
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::time::Instant;
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port()
+    }
+
+    /// GETs `path` from the metrics server on `port`, waiting for it to
+    /// come up. The status code and body.
+    async fn get(port: u16, path: &str) -> (u16, String) {
+        let started = Instant::now();
+        let mut stream = loop {
+            match TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await {
+                Ok(stream) => break stream,
+                Err(e) => assert!(started.elapsed() < Duration::from_secs(10), "the metrics server never came up: {}", e),
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        };
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let code = head.split(' ').nth(1).unwrap().parse().unwrap();
+        if path == "/metrics" {
+            assert!(head.to_ascii_lowercase().contains(&format!("content-type: {}", CONTENT_TYPE)), "{}", head);
+        }
+        (code, body.to_string())
+    }
+
+    /// The value of the sample `series`, name and labels as written.
+    fn sample(body: &str, series: &str) -> Option<f64> {
+        body.lines().find_map(|line| line.strip_prefix(series)?.strip_prefix(' ')?.parse().ok())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn serves_metrics_and_health_on_localhost() {
+        let port = free_port();
+        let node = TestNode::start_with(TestNodeOptions {
+            config: Some(format!("ipc_idle_timeout_mins = 0\nmetrics_port = {}", port)),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        for _ in 0..3 {
+            assert!(matches!(node.client().request(Request::Ping).await.unwrap(), Response::Pong));
+        }
+        let query = Request::QueryCore {
+            query: "?[x] := x = 1".into(),
+            params: serde_json::json!({}),
+            timeout_ms: None,
+            readonly: true,
+            limit: None,
+        };
+        node.client().request(query).await.unwrap();
+
+        let (code, body) = get(port, "/metrics").await;
+        assert_eq!(code, 200);
+        assert_eq!(sample(&body, "sovereign_scrape_stale"), Some(0.0));
+        assert!(sample(&body, r#"sovereign_ipc_request_duration_seconds_count{kind="Ping"}"#).unwrap() >= 3.0, "{}", body);
+        assert!(sample(&body, r#"sovereign_ipc_request_duration_seconds_bucket{kind="QueryCore",le="+Inf"}"#).unwrap() >= 1.0, "{}", body);
+        assert_eq!(sample(&body, r#"sovereign_finance_state{state="connecting"}"#), Some(1.0));
+        assert_eq!(sample(&body, "sovereign_license_active"), Some(0.0));
+        assert!(sample(&body, r#"sovereign_core_size_bytes{backend="mem"}"#).is_some(), "{}", body);
+        assert!(sample(&body, r#"sovereign_pool_running{pool="compute"}"#).is_some(), "{}", body);
+        assert!(sample(&body, "sovereign_ipc_connections").unwrap() >= 1.0, "{}", body);
+        for family in ["sovereign_mesh_phase", "sovereign_wasm_admitted_total", "sovereign_ipc_accept_failures_total", "sovereign_uptime_seconds"] {
+            assert!(body.contains(&format!("# TYPE {} ", family)), "{} is missing", family);
+        }
+
+        let healthy = node.status().await.unwrap().system_health == "OK";
+        let (code, body) = get(port, "/healthz").await;
+        assert_eq!(code, if healthy { 200 } else { 503 }, "{}", body);
+        assert_eq!(get(port, "/nothing").await.0, 404);
+    }
+
+    /// Gives out a node's figures after `delay_ms`.
+    struct Slow {
+        gathered: Gathered,
+        delay_ms: AtomicU64,
+        gathers: AtomicUsize,
+        pool: BlockingPool,
+    }
+
+    impl ScrapeSource for Slow {
+        fn gather(&self) -> Gathered {
+            self.gathers.fetch_add(1, Ordering::Relaxed);
+            std::thread::sleep(Duration::from_millis(self.delay_ms.load(Ordering::Relaxed)));
+            self.gathered.clone()
+        }
+
+        fn pool(&self) -> &BlockingPool {
+            &self.pool
+        }
+    }
+
+    async fn slow() -> Arc<Slow> {
+        let node = TestNode::start(Vec::new()).await.unwrap();
+        let Response::Metrics(metrics) = node.client().request(Request::GetMetrics).await.unwrap() else { panic!("Expected Metrics") };
+        Arc::new(Slow {
+            gathered: (node.status().await.unwrap(), metrics),
+            delay_ms: AtomicU64::new(0),
+            gathers: AtomicUsize::new(0),
+            pool: BlockingPool::new("scrape", PoolConfig { threads: 1, queue: 1 }).unwrap(),
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn slow_subsystems_are_served_stale() {
+        let source = slow().await;
+        let scraper = Scraper {
+            source: source.clone(),
+            pending: Mutex::new(None),
+            last: StdMutex::new(None),
+        };
+        let (gathered, stale) = scraper.scrape().await;
+        assert!(gathered.is_some() && !stale);
+
+        source.delay_ms.store(600, Ordering::Relaxed);
+        let started = Instant::now();
+        let (gathered, stale) = scraper.scrape().await;
+        assert!(started.elapsed() < Duration::from_millis(500), "{:?}", started.elapsed());
+        assert!(gathered.is_some() && stale);
+
+        // The next scrape waits on the same gather rather than starting one.
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let (gathered, stale) = scraper.scrape().await;
+        assert!(gathered.is_some() && !stale);
+        assert_eq!(source.gathers.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_port_in_use_is_not_fatal() {
+        let taken = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let (_shutdown, stop) = watch::channel(false);
+        let served = serve(taken.local_addr().unwrap().port(), slow().await, stop);
+        tokio::time::timeout(Duration::from_secs(5), served).await.unwrap();
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        let mut out = Exposition::default();
+        out.family("x_total", "counter", "Things.");
+        out.sample("x_total", &[("kind", "a\"b\\c\nd"), ("le", "+Inf")], 3);
+        out.sample("x_total", &[], 1.5);
+        assert_eq!(out.0, "# HELP x_total Things.\n# TYPE x_total counter\nx_total{kind=\"a\\\"b\\\\c\\nd\",le=\"+Inf\"} 3\nx_total 1.5\n");
+    }
+}
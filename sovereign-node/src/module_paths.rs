@@ -0,0 +1,168 @@
+use sovereign_protocol::WasmPathRejection;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+/// Where `RunWasm` may load modules from by path, and how big they may be.
+#[derive(Debug, Clone)]
+pub struct ModulePaths {
+    /// The module store first, then any directories the config adds.
+    pub roots: Vec<PathBuf>,
+    pub max_bytes: u64,
+}
+
+impl ModulePaths {
+    /// A bare name, with no path separators, names a registered module
+    /// rather than a file.
+    pub fn is_registered_name(path: &str) -> bool {
+        !path.is_empty() && !path.contains(['/', '\\']) && path != "." && path != ".."
+    }
+
+    /// Reads the module at `path` once it has resolved, links followed,
+    /// inside one of `roots`.
+    pub async fn read(&self, path: &str) -> Result<Vec<u8>, WasmPathRejection> {
+        let resolved = match tokio::fs::canonicalize(path).await {
+            Ok(resolved) => resolved,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(WasmPathRejection::NotFound),
+            Err(e) => return Err(unreadable(e)),
+        };
+        if !self.admits(&resolved).await {
+            return Err(WasmPathRejection::OutsideAllowedDirs);
+        }
+        let mut file = tokio::fs::File::open(&resolved).await.map_err(unreadable)?;
+        let size = file.metadata().await.map_err(unreadable)?.len();
+        if size > self.max_bytes {
+            return Err(WasmPathRejection::TooLarge { size, max: self.max_bytes });
+        }
+        // The file may grow after the size check, so the read is capped too.
+        let mut bytes = Vec::with_capacity(size as usize);
+        (&mut file).take(self.max_bytes + 1).read_to_end(&mut bytes).await.map_err(unreadable)?;
+        if bytes.len() as u64 > self.max_bytes {
+            return Err(WasmPathRejection::TooLarge {
+                size: bytes.len() as u64,
+                max: self.max_bytes,
+            });
+        }
+        Ok(bytes)
+    }
+
+    async fn admits(&self, resolved: &Path) -> bool {
+        for root in &self.roots {
+            // A root that does not exist yet holds nothing to run.
+            if let Ok(root) = tokio::fs::canonicalize(root).await {
+                if resolved.starts_with(&root) && resolved != root {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+fn unreadable(e: io::Error) -> WasmPathRejection {
+    WasmPathRejection::Unreadable { message: e.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(roots: Vec<PathBuf>, max_bytes: u64) -> ModulePaths {
+        ModulePaths { roots, max_bytes }
+    }
+
+    #[test]
+    fn only_bare_names_are_registered_names() {
+        for (path, registered) in [
+            ("hello", true),
+            ("hello.wasm", true),
+            ("", false),
+            (".", false),
+            ("..", false),
+            ("./hello", false),
+            ("mods/hello.wasm", false),
+            (r"mods\hello.wasm", false),
+            ("/etc/passwd", false),
+        ] {
+            assert_eq!(ModulePaths::is_registered_name(path), registered, "{:?}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_modules_inside_the_roots() {
+        let store = tempfile::tempdir().unwrap();
+        let extra = tempfile::tempdir().unwrap();
+        std::fs::create_dir(store.path().join("nested")).unwrap();
+        std::fs::write(store.path().join("nested/a.wasm"), b"module a").unwrap();
+        std::fs::write(extra.path().join("b.wasm"), b"module b").unwrap();
+        let paths = paths(vec![store.path().into(), extra.path().into()], 1024);
+
+        assert_eq!(paths.read(store.path().join("nested/a.wasm").to_str().unwrap()).await.unwrap(), b"module a");
+        assert_eq!(paths.read(extra.path().join("b.wasm").to_str().unwrap()).await.unwrap(), b"module b");
+        // `..` that stays inside is fine.
+        let inside = store.path().join("nested/../nested/a.wasm");
+        assert_eq!(paths.read(inside.to_str().unwrap()).await.unwrap(), b"module a");
+    }
+
+    #[tokio::test]
+    async fn refuses_paths_that_escape_the_roots() {
+        let parent = tempfile::tempdir().unwrap();
+        let store = parent.path().join("store");
+        std::fs::create_dir(&store).unwrap();
+        std::fs::write(parent.path().join("secret.wasm"), b"not for clients").unwrap();
+        let paths = paths(vec![store.clone()], 1024);
+
+        let escapes = [
+            store.join("../secret.wasm"),
+            store.join("../../../../../../etc/passwd"),
+            PathBuf::from("/etc/passwd"),
+            // The root itself is a directory, not a module.
+            store.clone(),
+        ];
+        for path in escapes {
+            assert_eq!(paths.read(path.to_str().unwrap()).await, Err(WasmPathRejection::OutsideAllowedDirs), "{}", path.display());
+        }
+        assert_eq!(paths.read(store.join("../missing.wasm").to_str().unwrap()).await, Err(WasmPathRejection::NotFound));
+
+        // No roots, nothing to run.
+        let nowhere = ModulePaths { roots: Vec::new(), max_bytes: 1024 };
+        assert_eq!(nowhere.read(parent.path().join("secret.wasm").to_str().unwrap()).await, Err(WasmPathRejection::OutsideAllowedDirs));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn follows_links_before_deciding() {
+        let parent = tempfile::tempdir().unwrap();
+        let store = parent.path().join("store");
+        std::fs::create_dir(&store).unwrap();
+        std::fs::write(parent.path().join("secret.wasm"), b"not for clients").unwrap();
+        std::fs::write(store.join("real.wasm"), b"module").unwrap();
+        std::os::unix::fs::symlink(parent.path().join("secret.wasm"), store.join("link.wasm")).unwrap();
+        std::os::unix::fs::symlink(parent.path(), store.join("up")).unwrap();
+        std::os::unix::fs::symlink(store.join("real.wasm"), parent.path().join("outside-link.wasm")).unwrap();
+        let paths = paths(vec![store.clone()], 1024);
+
+        for path in [store.join("link.wasm"), store.join("up/secret.wasm")] {
+            assert_eq!(paths.read(path.to_str().unwrap()).await, Err(WasmPathRejection::OutsideAllowedDirs), "{}", path.display());
+        }
+        // A link from outside to a module inside reads the module.
+        assert_eq!(paths.read(parent.path().join("outside-link.wasm").to_str().unwrap()).await.unwrap(), b"module");
+        // A dangling link is missing, not an escape.
+        std::os::unix::fs::symlink(store.join("gone.wasm"), store.join("dangling.wasm")).unwrap();
+        assert_eq!(paths.read(store.join("dangling.wasm").to_str().unwrap()).await, Err(WasmPathRejection::NotFound));
+    }
+
+    #[tokio::test]
+    async fn refuses_modules_over_the_size_cap() {
+        let store = tempfile::tempdir().unwrap();
+        std::fs::write(store.path().join("exact.wasm"), vec![0u8; 64]).unwrap();
+        std::fs::write(store.path().join("big.wasm"), vec![0u8; 65]).unwrap();
+        let paths = paths(vec![store.path().into()], 64);
+
+        assert_eq!(paths.read(store.path().join("exact.wasm").to_str().unwrap()).await.unwrap().len(), 64);
+        assert_eq!(
+            paths.read(store.path().join("big.wasm").to_str().unwrap()).await,
+            Err(WasmPathRejection::TooLarge { size: 65, max: 64 })
+        );
+    }
+}
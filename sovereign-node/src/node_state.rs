@@ -0,0 +1,292 @@
+use crate::license_monitor::LicenseRecord;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+/// The revision `state.json` is written in.
+const STATE_VERSION: u32 = 1;
+
+/// What the node remembers across restarts, kept in `state.json` in the
+/// data directory. Scheduled jobs and the WASM allow-list keep their own
+/// files.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub(crate) struct NodeState {
+    pub version: u32,
+    /// The last license check the chain answered.
+    #[serde(default)]
+    pub license: Option<LicenseRecord>,
+    /// Peers dialed with `persist`, dialed again on startup.
+    #[serde(default)]
+    pub peers: Vec<String>,
+}
+
+/// `NodeState`, saved whenever it changes.
+pub(crate) struct StateStore {
+    path: PathBuf,
+    state: Mutex<NodeState>,
+}
+
+impl StateStore {
+    /// Loads `state.json` from `dir`, the data directory's `state/`. A
+    /// file that cannot be read is moved aside and the node starts afresh.
+    pub fn open(dir: &Path) -> Self {
+        let path = dir.join("state.json");
+        let state = match std::fs::read(&path) {
+            Ok(bytes) => match parse(&bytes) {
+                Ok(state) => state,
+                Err(e) => {
+                    set_aside(&path, &e);
+                    NodeState::default()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => legacy(dir),
+            Err(e) => {
+                set_aside(&path, &e.to_string());
+                NodeState::default()
+            }
+        };
+        let store = Self {
+            path,
+            state: Mutex::new(NodeState {
+                version: STATE_VERSION,
+                ..state
+            }),
+        };
+        // Saved at once, so a migrated license record has a home before
+        // its old file goes.
+        store.flush();
+        if store.path.exists() {
+            let _ = std::fs::remove_file(dir.join("license.json"));
+        }
+        store
+    }
+
+    pub fn get(&self) -> NodeState {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Changes the state with `change` and saves it.
+    pub fn update(&self, change: impl FnOnce(&mut NodeState)) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        change(&mut state);
+        self.write(&state);
+    }
+
+    /// Saves the state as it is, on shutdown.
+    pub fn flush(&self) {
+        self.write(&self.state.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+
+    fn write(&self, state: &NodeState) {
+        if let Err(e) = save(&self.path, state) {
+            warn!("Failed to save node state to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Reads any revision of the file, migrating older ones forward.
+fn parse(bytes: &[u8]) -> Result<NodeState, String> {
+    let value: serde_json::Value = serde_json::from_slice(bytes).map_err(|e| e.to_string())?;
+    let version = value.get("version").and_then(serde_json::Value::as_u64).unwrap_or(0);
+    match version {
+        // Revision 1 is the first; later ones migrate to it here.
+        1 => serde_json::from_value(value).map_err(|e| e.to_string()),
+        v if v > STATE_VERSION as u64 => Err(format!("written by a newer node (version {})", v)),
+        v => Err(format!("unknown version {}", v)),
+    }
+}
+
+/// Before `state.json`, the license record had a file of its own.
+fn legacy(dir: &Path) -> NodeState {
+    let path = dir.join("license.json");
+    let Ok(bytes) = std::fs::read(&path) else {
+        return NodeState::default();
+    };
+    match serde_json::from_slice::<LicenseRecord>(&bytes) {
+        Ok(license) => {
+            info!("Moving the license record from {} into state.json", path.display());
+            NodeState {
+                license: Some(license),
+                ..Default::default()
+            }
+        }
+        Err(e) => {
+            warn!("Ignoring unreadable license record {}: {}", path.display(), e);
+            NodeState::default()
+        }
+    }
+}
+
+fn set_aside(path: &Path, reason: &str) {
+    let aside = path.with_extension(format!("corrupt-{}", crate::service_loop::unix_millis()));
+    warn!("Node state {} is unreadable ({}). Moving it to {} and starting afresh.", path.display(), reason, aside.display());
+    if let Err(e) = std::fs::rename(path, &aside) {
+        warn!("Failed to move {} aside: {}", path.display(), e);
+    }
+}
+
+fn save(path: &Path, state: &NodeState) -> io::Result<()> {
+    let bytes = serde_json::to_vec_pretty(state).map_err(io::Error::other)?;
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::{TestNode, TestNodeOptions};
+    use sovereign_protocol::{LicenseReport, Request, Response, WasmJobSchedule, WasmJobSpec};
+    use std::time::Duration;
+
+    fn record(valid: bool) -> LicenseRecord {
+        LicenseRecord {
+            valid,
+            report: LicenseReport {
+                txid: "ab".repeat(32),
+                found: true,
+                paid_sats: 50_000,
+                payment_ok: true,
+                binding_ok: true,
+                confirmations: 3,
+                confirmed_height: Some(800_000),
+                tier: Some("standard".into()),
+                valid_until_height: None,
+                checked_at_ms: 1_700_000_000_000,
+            },
+        }
+    }
+
+    /// The files in `dir` whose names start with `prefix`.
+    fn files(dir: &Path, prefix: &str) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with(prefix))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn changes_are_saved_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StateStore::open(dir.path());
+        assert!(store.get().license.is_none() && store.get().peers.is_empty());
+        store.update(|s| s.license = Some(record(true)));
+        store.update(|s| s.peers.push("/ip4/10.0.0.1/tcp/4001".into()));
+        drop(store);
+
+        let state = StateStore::open(dir.path()).get();
+        assert_eq!(state.version, STATE_VERSION);
+        let license = state.license.unwrap();
+        assert!(license.valid);
+        assert_eq!(license.report.txid, "ab".repeat(32));
+        assert_eq!(state.peers, ["/ip4/10.0.0.1/tcp/4001"]);
+        // Written whole and moved into place: no temporary file is left.
+        assert_eq!(files(dir.path(), "state"), ["state.json"]);
+    }
+
+    #[test]
+    fn unreadable_files_are_set_aside() {
+        for (contents, why) in [
+            (&b"{not json"[..], "garbage"),
+            (br#"{"version": 99, "peers": []}"#, "a newer node's file"),
+            (br#"{"version": 0}"#, "an unknown version"),
+            (br#"{"version": 1, "peers": "everyone"}"#, "a mistyped field"),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::write(dir.path().join("state.json"), contents).unwrap();
+            let state = StateStore::open(dir.path()).get();
+            assert!(state.peers.is_empty() && state.license.is_none(), "{}", why);
+            let aside = files(dir.path(), "state.corrupt-");
+            assert_eq!(aside.len(), 1, "{}", why);
+            assert_eq!(std::fs::read(dir.path().join(&aside[0])).unwrap(), contents, "{}", why);
+            // A fresh file takes its place.
+            assert!(parse(&std::fs::read(dir.path().join("state.json")).unwrap()).is_ok(), "{}", why);
+        }
+    }
+
+    #[test]
+    fn moves_an_old_license_file_in() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("license.json"), serde_json::to_vec(&record(false)).unwrap()).unwrap();
+        let license = StateStore::open(dir.path()).get().license.unwrap();
+        assert!(!license.valid);
+        assert!(!dir.path().join("license.json").exists());
+        assert!(StateStore::open(dir.path()).get().license.is_some());
+
+        // An unreadable one is dropped rather than holding up the node.
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("license.json"), b"[]").unwrap();
+        assert!(StateStore::open(dir.path()).get().license.is_none());
+        assert!(!dir.path().join("license.json").exists());
+    }
+
+    /// Writes "hello\n" to stdout.
+    const HELLO: &str = r#"(module
+      (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 1024) "hello\n")
+      (func (export "_start")
+        (i32.store (i32.const 0) (i32.const 1024))
+        (i32.store (i32.const 4) (i32.const 6))
+        (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))"#;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_restarted_node_keeps_its_peers_modules_and_jobs() {
+        let hub = TestNode::start(Vec::new()).await.unwrap();
+        let modules = tempfile::tempdir().unwrap();
+        let path = modules.path().join("hello.wat");
+        std::fs::write(&path, HELLO).unwrap();
+        let mut node = TestNode::start_with(TestNodeOptions {
+            config: Some(format!("ipc_idle_timeout_mins = 0\n[wasm]\nrun_dirs = [{:?}]", modules.path())),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let dial = Request::MeshDial {
+            addr: hub.dial_addrs()[0].clone(),
+            persist: true,
+        };
+        node.client().request(dial).await.unwrap();
+        node.wait_for_peers(1, Duration::from_secs(20)).await.unwrap();
+        let upload = Request::WasmUpload {
+            name: "hello".into(),
+            path: path.display().to_string(),
+            manifest: Default::default(),
+            signature: None,
+            watch: false,
+        };
+        assert!(matches!(node.client().request(upload).await.unwrap(), Response::WasmModule(_)));
+        let schedule = Request::WasmScheduleJob {
+            name: "hourly".into(),
+            job: WasmJobSpec {
+                module: "hello".into(),
+                input: String::new(),
+                schedule: WasmJobSchedule::Interval { every_ms: 3_600_000 },
+                fuel_limit: None,
+                max_memory_bytes: None,
+                overlap: Default::default(),
+                enabled: true,
+            },
+        };
+        assert!(matches!(node.client().request(schedule).await.unwrap(), Response::WasmJob(_)));
+
+        node.restart().await.unwrap();
+        // The peer is dialed again, from the node's new address.
+        node.wait_for_peers(1, Duration::from_secs(20)).await.unwrap();
+        assert_eq!(StateStore::open(&node.data_dir().join("state")).get().peers, [hub.dial_addrs()[0].clone()]);
+        match node.client().request(Request::WasmList).await.unwrap() {
+            Response::WasmModules(modules) => assert_eq!(modules.iter().map(|m| m.name.as_str()).collect::<Vec<_>>(), ["hello"]),
+            other => panic!("Expected WasmModules, got {:?}", other),
+        }
+        match node.client().request(Request::WasmListJobs).await.unwrap() {
+            Response::WasmJobs(jobs) => assert_eq!(jobs.iter().map(|j| (j.name.as_str(), j.job.module.as_str())).collect::<Vec<_>>(), [("hourly", "hello")]),
+            other => panic!("Expected WasmJobs, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,472 @@
+//! Presence beacons. With `[presence] enabled`, the node publishes a small
+//! signed beacon on a reserved gossip topic every `interval`, give or take a
+//! fifth, and keeps the last beacon heard from each other node in
+//! `SharedState::presence`. A node not heard from for `stale_after` turns
+//! stale, and one stale for `forget_after` is dropped from the table.
+
+use crate::event_bus::EventBus;
+use crate::service_loop::{unix_millis, SharedState};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sovereign_mesh::identity::{Keypair, PublicKey};
+use sovereign_mesh::{MeshCommand, MeshMessage};
+use sovereign_protocol::NodeEvent;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{debug, info, warn};
+
+/// The gossip topic beacons travel on. Modules may not publish under
+/// `RESERVED_PREFIX`.
+pub(crate) const TOPIC: &str = "sovereign/presence/1";
+pub(crate) const RESERVED_PREFIX: &str = "sovereign/presence/";
+
+/// Largest beacon sent or accepted, key and signature included.
+const MAX_BEACON_BYTES: usize = 2048;
+
+/// Other nodes tracked at once; beacons from more are dropped.
+const MAX_NODES: usize = 1024;
+
+/// Beacons waiting to be checked before more are dropped.
+const QUEUE: usize = 64;
+
+/// The longest gap between checks for stale nodes.
+const SWEEP: Duration = Duration::from_secs(1);
+
+/// How often beacons go out, and when a silent node counts as gone.
+#[derive(Debug, Clone)]
+pub struct PresenceConfig {
+    pub interval: Duration,
+    /// A node not heard from for this long is stale.
+    pub stale_after: Duration,
+    /// A stale node is forgotten after this long.
+    pub forget_after: Duration,
+    /// Advertised in beacons.
+    pub capabilities: Vec<String>,
+}
+
+/// Other nodes' last beacons, by peer id.
+#[derive(Debug, Clone)]
+pub(crate) struct PresenceTable {
+    pub stale_after: Duration,
+    pub nodes: BTreeMap<String, Presence>,
+}
+
+impl PresenceTable {
+    pub fn new(config: &PresenceConfig) -> Self {
+        Self {
+            stale_after: config.stale_after,
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    pub fn stale(&self) -> usize {
+        self.nodes.values().filter(|node| node.stale).count()
+    }
+}
+
+/// What a node's last beacon said, and when it came.
+#[derive(Debug, Clone)]
+pub(crate) struct Presence {
+    pub node_version: String,
+    pub uptime_ms: u64,
+    pub health: String,
+    pub capabilities: Vec<String>,
+    /// When the node stamped the beacon, to refuse older ones relayed late.
+    pub sent_ms: u64,
+    pub received_ms: u64,
+    /// Set once not heard from for `stale_after`; the next beacon clears it.
+    pub stale: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Beacon {
+    peer_id: String,
+    node_version: String,
+    uptime_ms: u64,
+    health: String,
+    capabilities: Vec<String>,
+    sent_ms: u64,
+}
+
+/// A `Beacon` as it travels, signed with its sender's mesh identity.
+#[derive(Serialize, Deserialize)]
+struct SignedBeacon {
+    /// The `Beacon` as JSON; the signature covers these bytes.
+    body: String,
+    /// The sender's public key, protobuf-encoded, in base64.
+    public_key: String,
+    signature: String,
+}
+
+impl SignedBeacon {
+    fn sign(beacon: &Beacon, keys: &Keypair) -> anyhow::Result<Self> {
+        let body = serde_json::to_string(beacon)?;
+        let signature = keys.sign(body.as_bytes())?;
+        Ok(Self {
+            public_key: STANDARD.encode(keys.public().encode_protobuf()),
+            signature: STANDARD.encode(signature),
+            body,
+        })
+    }
+
+    /// The beacon, if it was signed by the peer it names and that peer is
+    /// `source`, the one that gossiped it.
+    fn verify(&self, source: &str) -> anyhow::Result<Beacon> {
+        let beacon: Beacon = serde_json::from_str(&self.body)?;
+        let public_key = PublicKey::try_decode_protobuf(&STANDARD.decode(&self.public_key)?)?;
+        let signer = public_key.to_peer_id().to_string();
+        if signer != beacon.peer_id || signer != source {
+            anyhow::bail!("signed by {} for {}, gossiped by {}", signer, beacon.peer_id, source);
+        }
+        if !public_key.verify(self.body.as_bytes(), &STANDARD.decode(&self.signature)?) {
+            anyhow::bail!("bad signature from {}", source);
+        }
+        Ok(beacon)
+    }
+}
+
+/// Joins the presence topic, then publishes a beacon every interval and
+/// records the beacons of other nodes, publishing `PresenceStale` and
+/// `PresenceReturned` on `events` as they go quiet and come back. `status`
+/// gives the node's uptime and `system_health` for each beacon. Ends once
+/// the mesh is gone.
+pub(crate) async fn run(
+    config: PresenceConfig,
+    mesh: mpsc::Sender<MeshCommand>,
+    keys: Keypair,
+    state: Arc<watch::Sender<SharedState>>,
+    events: Arc<EventBus>,
+    status: impl Fn() -> (u64, String),
+) {
+    let (messages, mut received) = mpsc::channel(QUEUE);
+    let subscribe = MeshCommand::Subscribe {
+        topic: TOPIC.into(),
+        messages,
+    };
+    if mesh.send(subscribe).await.is_err() {
+        return;
+    }
+    let own = keys.public().to_peer_id().to_string();
+    info!("Publishing presence beacons every {:?} on {}", config.interval, TOPIC);
+
+    let mut limiter = HashMap::new();
+    let next_beacon = tokio::time::sleep(jittered(config.interval));
+    tokio::pin!(next_beacon);
+    let mut sweep = tokio::time::interval(SWEEP.min(config.stale_after / 2));
+    sweep.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            () = &mut next_beacon => {
+                let (uptime_ms, health) = status();
+                publish(&mesh, &keys, &own, &config, uptime_ms, health).await;
+                next_beacon.as_mut().reset(Instant::now() + jittered(config.interval));
+            }
+            message = received.recv() => match message {
+                Some(message) if message.source != own => receive(message, &config, &state, &events, &mut limiter),
+                Some(_) => {}
+                None => return,
+            },
+            _ = sweep.tick() => expire(&config, &state, &events, &mut limiter),
+        }
+    }
+}
+
+async fn publish(mesh: &mpsc::Sender<MeshCommand>, keys: &Keypair, own: &str, config: &PresenceConfig, uptime_ms: u64, health: String) {
+    let beacon = Beacon {
+        peer_id: own.to_string(),
+        node_version: env!("CARGO_PKG_VERSION").into(),
+        uptime_ms,
+        health,
+        capabilities: config.capabilities.clone(),
+        sent_ms: unix_millis(),
+    };
+    let data = match SignedBeacon::sign(&beacon, keys).and_then(|signed| Ok(serde_json::to_vec(&signed)?)) {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("Failed to sign a presence beacon: {:#}", e);
+            return;
+        }
+    };
+    if data.len() > MAX_BEACON_BYTES {
+        warn!("Not publishing a presence beacon of {} bytes, over the {} byte cap", data.len(), MAX_BEACON_BYTES);
+        return;
+    }
+    let _ = mesh.send(MeshCommand::Publish { topic: TOPIC.into(), data }).await;
+}
+
+/// Records a beacon that fits the cap, comes no sooner than half an
+/// interval after the sender's last, and is signed by its sender. The mesh
+/// checks gossip signatures, so `message.source` can be trusted for the
+/// rate limit before the beacon's own signature is.
+fn receive(
+    message: MeshMessage,
+    config: &PresenceConfig,
+    state: &watch::Sender<SharedState>,
+    events: &EventBus,
+    limiter: &mut HashMap<String, Instant>,
+) {
+    if message.data.len() > MAX_BEACON_BYTES {
+        debug!("Dropped a presence beacon of {} bytes from {}", message.data.len(), message.source);
+        return;
+    }
+    let now = Instant::now();
+    if limiter.get(&message.source).is_some_and(|last| now.duration_since(*last) < config.interval / 2) {
+        debug!("Dropped a presence beacon from {}: too soon after its last", message.source);
+        return;
+    }
+    if !limiter.contains_key(&message.source) && limiter.len() >= MAX_NODES {
+        debug!("Dropped a presence beacon from {}: {} nodes are tracked already", message.source, MAX_NODES);
+        return;
+    }
+    let beacon = match serde_json::from_slice::<SignedBeacon>(&message.data).map_err(anyhow::Error::from).and_then(|signed| signed.verify(&message.source)) {
+        Ok(beacon) => beacon,
+        Err(e) => {
+            warn!("Dropped a presence beacon from {}: {:#}", message.source, e);
+            return;
+        }
+    };
+    limiter.insert(message.source, now);
+
+    let mut returned = None;
+    state.send_modify(|s| {
+        let Some(table) = &mut s.presence else { return };
+        let previous = table.nodes.get(&beacon.peer_id);
+        if previous.is_some_and(|node| node.sent_ms >= beacon.sent_ms) {
+            return;
+        }
+        match previous {
+            None => info!("Heard from node {} ({})", beacon.peer_id, beacon.node_version),
+            Some(node) if node.stale => returned = Some(beacon.peer_id.clone()),
+            Some(_) => {}
+        }
+        table.nodes.insert(
+            beacon.peer_id,
+            Presence {
+                node_version: beacon.node_version,
+                uptime_ms: beacon.uptime_ms,
+                health: beacon.health,
+                capabilities: beacon.capabilities,
+                sent_ms: beacon.sent_ms,
+                received_ms: unix_millis(),
+                stale: false,
+            },
+        );
+    });
+    if let Some(peer) = returned {
+        info!("Node {} is back", peer);
+        events.publish(NodeEvent::PresenceReturned { peer });
+    }
+}
+
+/// Marks nodes not heard from for `stale_after` stale, and forgets those
+/// stale for `forget_after`.
+fn expire(config: &PresenceConfig, state: &watch::Sender<SharedState>, events: &EventBus, limiter: &mut HashMap<String, Instant>) {
+    let now = unix_millis();
+    let stale_after = config.stale_after.as_millis() as u64;
+    let forget_after = stale_after + config.forget_after.as_millis() as u64;
+    let mut stale = Vec::new();
+    state.send_if_modified(|s| {
+        let Some(table) = &mut s.presence else { return false };
+        let before = table.nodes.len();
+        table.nodes.retain(|peer, node| {
+            let silent = now.saturating_sub(node.received_ms);
+            if silent >= forget_after {
+                info!("Forgot node {}, not heard from for {:?}", peer, Duration::from_millis(silent));
+                limiter.remove(peer);
+                return false;
+            }
+            if silent >= stale_after && !node.stale {
+                node.stale = true;
+                stale.push(peer.clone());
+            }
+            true
+        });
+        table.nodes.len() != before || !stale.is_empty()
+    });
+    for peer in stale {
+        warn!("Node {} has gone stale: no presence beacon for {:?}", peer, config.stale_after);
+        events.publish(NodeEvent::PresenceStale { peer });
+    }
+}
+
+/// `interval`, give or take a fifth, so nodes started together drift apart.
+fn jittered(interval: Duration) -> Duration {
+    let fifth = interval.as_millis() as u64 / 5;
+    if fifth == 0 {
+        return interval;
+    }
+    let offset = OsRng.next_u64() % (2 * fifth + 1);
+    (interval + Duration::from_millis(offset)).saturating_sub(Duration::from_millis(fifth))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_bus::Subscription;
+    use sovereign_protocol::{EventTopic, MeshPhase, Response};
+
+    fn config() -> PresenceConfig {
+        PresenceConfig {
+            interval: Duration::from_secs(1),
+            stale_after: Duration::from_secs(3),
+            forget_after: Duration::from_secs(10),
+            capabilities: Vec::new(),
+        }
+    }
+
+    fn shared(config: &PresenceConfig) -> watch::Sender<SharedState> {
+        watch::channel(SharedState {
+            peer_id: "us".into(),
+            connections: 0,
+            listen_addrs: Vec::new(),
+            mesh_phase: MeshPhase::WarmingUp,
+            license_active: false,
+            license_report: None,
+            presence: Some(PresenceTable::new(config)),
+        })
+        .0
+    }
+
+    fn peer(keys: &Keypair) -> String {
+        keys.public().to_peer_id().to_string()
+    }
+
+    fn beacon(keys: &Keypair, sent_ms: u64) -> Beacon {
+        Beacon {
+            peer_id: peer(keys),
+            node_version: "0.3.0".into(),
+            uptime_ms: 1000,
+            health: "ok".into(),
+            capabilities: vec!["wasm".into()],
+            sent_ms,
+        }
+    }
+
+    /// `beacon` signed with `keys`, as gossiped by `source`.
+    fn message(beacon: &Beacon, keys: &Keypair, source: &Keypair) -> MeshMessage {
+        MeshMessage {
+            topic: TOPIC.into(),
+            source: peer(source),
+            data: serde_json::to_vec(&SignedBeacon::sign(beacon, keys).unwrap()).unwrap(),
+        }
+    }
+
+    /// The nodes in the table, with when each sent its beacon and whether
+    /// it is stale.
+    fn table(state: &watch::Sender<SharedState>) -> Vec<(String, u64, bool)> {
+        let s = state.borrow();
+        s.presence.as_ref().unwrap().nodes.iter().map(|(peer, node)| (peer.clone(), node.sent_ms, node.stale)).collect()
+    }
+
+    /// Events published so far, without waiting for more.
+    async fn published(events: &mut Subscription) -> Vec<NodeEvent> {
+        let mut published = Vec::new();
+        while let Ok(Response::Event { event, .. }) = tokio::time::timeout(Duration::from_millis(50), events.next()).await {
+            published.push(event);
+        }
+        published
+    }
+
+    #[test]
+    fn records_only_beacons_signed_by_the_node_that_sent_them() {
+        let config = config();
+        let (state, events) = (shared(&config), EventBus::new());
+        let mut limiter = HashMap::new();
+        let (a, b) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let now = unix_millis();
+
+        // Signed by `b` in `a`'s name, gossiped by either.
+        receive(message(&beacon(&a, now), &b, &b), &config, &state, &events, &mut limiter);
+        receive(message(&beacon(&a, now), &b, &a), &config, &state, &events, &mut limiter);
+        // `a`'s own beacon, relayed as if `b` had sent it.
+        receive(message(&beacon(&a, now), &a, &b), &config, &state, &events, &mut limiter);
+        // Changed after it was signed.
+        let mut tampered = message(&beacon(&a, now), &a, &a);
+        let text = String::from_utf8(tampered.data).unwrap().replace("\\\"ok\\\"", "\\\"degraded\\\"");
+        assert!(text.contains("degraded"));
+        tampered.data = text.into_bytes();
+        receive(tampered, &config, &state, &events, &mut limiter);
+        // Over the cap, however well signed.
+        let mut large = beacon(&a, now);
+        large.capabilities = vec!["x".repeat(MAX_BEACON_BYTES)];
+        receive(message(&large, &a, &a), &config, &state, &events, &mut limiter);
+        // Not a beacon at all.
+        let garbage = MeshMessage {
+            topic: TOPIC.into(),
+            source: peer(&a),
+            data: b"{\"body\": 1}".to_vec(),
+        };
+        receive(garbage, &config, &state, &events, &mut limiter);
+        assert!(table(&state).is_empty());
+        assert!(limiter.is_empty());
+
+        receive(message(&beacon(&a, now), &a, &a), &config, &state, &events, &mut limiter);
+        assert_eq!(table(&state), vec![(peer(&a), now, false)]);
+    }
+
+    #[test]
+    fn drops_beacons_sent_too_soon_or_older_than_the_last() {
+        let config = config();
+        let (state, events) = (shared(&config), EventBus::new());
+        let mut limiter = HashMap::new();
+        let a = Keypair::generate_ed25519();
+        let now = unix_millis();
+
+        receive(message(&beacon(&a, now), &a, &a), &config, &state, &events, &mut limiter);
+        receive(message(&beacon(&a, now + 10), &a, &a), &config, &state, &events, &mut limiter);
+        assert_eq!(table(&state), vec![(peer(&a), now, false)]);
+
+        // Half an interval later, only a newer beacon replaces the last.
+        let earlier = Instant::now() - config.interval;
+        limiter.insert(peer(&a), earlier);
+        receive(message(&beacon(&a, now - 10), &a, &a), &config, &state, &events, &mut limiter);
+        assert_eq!(table(&state), vec![(peer(&a), now, false)]);
+        limiter.insert(peer(&a), earlier);
+        receive(message(&beacon(&a, now + 20), &a, &a), &config, &state, &events, &mut limiter);
+        assert_eq!(table(&state), vec![(peer(&a), now + 20, false)]);
+    }
+
+    #[tokio::test]
+    async fn marks_silent_nodes_stale_then_back_then_forgets_them() {
+        let config = config();
+        let (state, events) = (shared(&config), EventBus::new());
+        let mut subscription = Subscription::default();
+        subscription.subscribe(&events, vec![EventTopic::Mesh]);
+        let mut limiter = HashMap::new();
+        let a = Keypair::generate_ed25519();
+        let silent_for = |state: &watch::Sender<SharedState>, ms: u64| {
+            state.send_modify(|s| {
+                let node = s.presence.as_mut().unwrap().nodes.values_mut().next().unwrap();
+                node.received_ms = unix_millis() - ms;
+            })
+        };
+
+        receive(message(&beacon(&a, unix_millis()), &a, &a), &config, &state, &events, &mut limiter);
+        expire(&config, &state, &events, &mut limiter);
+        assert!(!table(&state)[0].2);
+        silent_for(&state, 3000);
+        expire(&config, &state, &events, &mut limiter);
+        expire(&config, &state, &events, &mut limiter);
+        assert!(table(&state)[0].2);
+        assert_eq!(state.borrow().presence.as_ref().unwrap().stale(), 1);
+
+        limiter.insert(peer(&a), Instant::now() - config.interval);
+        receive(message(&beacon(&a, unix_millis()), &a, &a), &config, &state, &events, &mut limiter);
+        assert!(!table(&state)[0].2);
+
+        silent_for(&state, 13_000);
+        expire(&config, &state, &events, &mut limiter);
+        assert!(table(&state).is_empty());
+        assert!(limiter.is_empty());
+
+        // Stale once, back once, and nothing when it was forgotten.
+        let events: Vec<_> = published(&mut subscription).await.into_iter().map(|event| serde_json::to_value(event).unwrap()).collect();
+        let expected = [NodeEvent::PresenceStale { peer: peer(&a) }, NodeEvent::PresenceReturned { peer: peer(&a) }];
+        assert_eq!(events, expected.map(|event| serde_json::to_value(event).unwrap()));
+    }
+}
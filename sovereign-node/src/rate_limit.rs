@@ -0,0 +1,223 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How fast one client may send requests.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    /// Requests of any kind per second, sustained.
+    pub requests_per_sec: u32,
+    /// Requests of any kind that may arrive at once.
+    pub burst: u32,
+    /// Stricter budgets for expensive requests, per minute by
+    /// `Request::kind`. Each allows a burst of its whole budget.
+    pub per_minute: HashMap<&'static str, u32>,
+    /// Close a connection after this many refusals within a minute.
+    pub close_after: Option<u32>,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            requests_per_sec: 100,
+            burst: 200,
+            per_minute: HashMap::from([("VerifyLicense", 6), ("RunWasm", 120), ("CoreImport", 10)]),
+            close_after: None,
+        }
+    }
+}
+
+/// A token bucket.
+#[derive(Debug)]
+pub(crate) struct Bucket {
+    tokens: f64,
+    capacity: f64,
+    per_sec: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(capacity: u32, per_sec: f64) -> Self {
+        Self {
+            tokens: capacity as f64,
+            capacity: capacity as f64,
+            per_sec,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes a token, or says how long until one is there.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / self.per_sec))
+    }
+}
+
+/// Shared budgets for users other than the node's own, so such a user
+/// cannot get more by opening more connections. Clients of the node's own
+/// user are only limited per connection.
+#[derive(Default)]
+pub(crate) struct UserBuckets(Mutex<HashMap<u32, Arc<Mutex<Bucket>>>>);
+
+impl UserBuckets {
+    /// `uid`'s bucket, created on its first connection. Only taken when a
+    /// connection opens, not per request.
+    pub fn for_uid(&self, uid: u32, limits: &RateLimits) -> Arc<Mutex<Bucket>> {
+        let mut buckets = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        buckets
+            .entry(uid)
+            .or_insert_with(|| Arc::new(Mutex::new(Bucket::new(limits.burst, limits.requests_per_sec as f64))))
+            .clone()
+    }
+}
+
+/// One connection's budgets. Lives in the connection's task.
+pub(crate) struct ConnectionLimiter {
+    general: Bucket,
+    per_kind: HashMap<&'static str, Bucket>,
+    user: Option<Arc<Mutex<Bucket>>>,
+    refusals: VecDeque<Instant>,
+    close_after: Option<u32>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: &RateLimits, user: Option<Arc<Mutex<Bucket>>>) -> Self {
+        Self {
+            general: Bucket::new(limits.burst, limits.requests_per_sec as f64),
+            per_kind: limits.per_minute.iter().map(|(kind, n)| (*kind, Bucket::new(*n, *n as f64 / 60.0))).collect(),
+            user,
+            refusals: VecDeque::new(),
+            close_after: limits.close_after,
+        }
+    }
+
+    /// Admits a request of `kind`, or says how long the client should wait.
+    pub fn check(&mut self, kind: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let admitted = self.take(kind, now);
+        if admitted.is_err() {
+            self.refusals.push_back(now);
+        }
+        admitted
+    }
+
+    fn take(&mut self, kind: &str, now: Instant) -> Result<(), Duration> {
+        if let Some(bucket) = self.per_kind.get_mut(kind) {
+            bucket.take(now)?;
+        }
+        self.general.take(now)?;
+        match &self.user {
+            Some(user) => user.lock().unwrap_or_else(|e| e.into_inner()).take(now),
+            None => Ok(()),
+        }
+    }
+
+    /// True once the client has been refused `close_after` times within a
+    /// minute.
+    pub fn abusive(&mut self) -> bool {
+        let Some(limit) = self.close_after else {
+            return false;
+        };
+        let now = Instant::now();
+        while self.refusals.front().is_some_and(|at| now.duration_since(*at) > Duration::from_secs(60)) {
+            self.refusals.pop_front();
+        }
+        self.refusals.len() >= limit as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(burst: u32, per_minute: &[(&'static str, u32)], close_after: Option<u32>) -> RateLimits {
+        RateLimits {
+            requests_per_sec: 10,
+            burst,
+            per_minute: per_minute.iter().copied().collect(),
+            close_after,
+        }
+    }
+
+    #[test]
+    fn a_bucket_bursts_then_refills_at_its_rate() {
+        let mut bucket = Bucket::new(3, 10.0);
+        let start = bucket.updated;
+        for _ in 0..3 {
+            assert_eq!(bucket.take(start), Ok(()));
+        }
+        assert_eq!(bucket.take(start), Err(Duration::from_millis(100)));
+        let wait = bucket.take(start + Duration::from_millis(40)).unwrap_err();
+        assert!(wait > Duration::from_millis(59) && wait <= Duration::from_millis(60), "{:?}", wait);
+        assert_eq!(bucket.take(start + Duration::from_millis(100)), Ok(()));
+        assert!(bucket.take(start + Duration::from_millis(100)).is_err());
+
+        // A long quiet spell refills no more than the burst.
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(later), Ok(()));
+        }
+        assert!(bucket.take(later).is_err());
+    }
+
+    #[test]
+    fn expensive_kinds_have_budgets_of_their_own() {
+        let mut limiter = ConnectionLimiter::new(&limits(100, &[("VerifyLicense", 2)], None), None);
+        assert_eq!(limiter.check("VerifyLicense"), Ok(()));
+        assert_eq!(limiter.check("VerifyLicense"), Ok(()));
+        // Two a minute: one back in 30 seconds.
+        let wait = limiter.check("VerifyLicense").unwrap_err();
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30), "{:?}", wait);
+        assert_eq!(limiter.check("Ping"), Ok(()));
+
+        // The general budget covers every kind.
+        let mut limiter = ConnectionLimiter::new(&limits(2, &[("VerifyLicense", 6)], None), None);
+        assert_eq!(limiter.check("Ping"), Ok(()));
+        assert_eq!(limiter.check("GetStatus"), Ok(()));
+        assert!(limiter.check("VerifyLicense").is_err());
+        assert!(limiter.check("Ping").is_err());
+    }
+
+    #[test]
+    fn a_user_shares_one_budget_across_connections() {
+        let users = UserBuckets::default();
+        let limits = limits(3, &[], None);
+        assert!(Arc::ptr_eq(&users.for_uid(1000, &limits), &users.for_uid(1000, &limits)));
+        let mut first = ConnectionLimiter::new(&limits, Some(users.for_uid(1000, &limits)));
+        let mut second = ConnectionLimiter::new(&limits, Some(users.for_uid(1000, &limits)));
+        assert_eq!(first.check("Ping"), Ok(()));
+        assert_eq!(first.check("Ping"), Ok(()));
+        assert_eq!(second.check("Ping"), Ok(()));
+        assert!(second.check("Ping").is_err());
+        assert!(first.check("Ping").is_err());
+
+        // Another user, and the node's own user, are not held to it.
+        let mut other = ConnectionLimiter::new(&limits, Some(users.for_uid(1001, &limits)));
+        assert_eq!(other.check("Ping"), Ok(()));
+        let mut own = ConnectionLimiter::new(&limits, None);
+        assert_eq!(own.check("Ping"), Ok(()));
+    }
+
+    #[test]
+    fn refusals_mark_a_client_abusive_when_configured() {
+        let mut limiter = ConnectionLimiter::new(&limits(1, &[], Some(3)), None);
+        assert_eq!(limiter.check("Ping"), Ok(()));
+        for refused in 1..=3 {
+            assert!(!limiter.abusive());
+            assert!(limiter.check("Ping").is_err(), "{}", refused);
+        }
+        assert!(limiter.abusive());
+
+        let mut limiter = ConnectionLimiter::new(&limits(1, &[], None), None);
+        for _ in 0..10 {
+            let _ = limiter.check("Ping");
+        }
+        assert!(!limiter.abusive());
+    }
+}
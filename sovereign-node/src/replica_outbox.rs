@@ -0,0 +1,169 @@
+//! Gossips the replication outbox. Each local change is queued in the
+//! outbox as it commits, and replication fills in its op as it logs it;
+//! this task publishes the stamped entries in order and marks each
+//! published once gossipsub takes it. An entry that fails, e.g. on
+//! `InsufficientPeers` or while the mesh is down or restarting, stays
+//! pending, holds back the later entries of its relation and is retried
+//! with backoff, as is a round the core pool is too busy for. An op is
+//! published again only if marking it fails, which its receivers shrug off
+//! as an op they already hold.
+
+use crate::blocking_pool::{BlockingPool, PoolError};
+use anyhow::anyhow;
+use sovereign_mesh::MeshCommand;
+use sovereign_replication::{Outbox, OutboxEntry};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+/// Pending entries read at a time.
+const BATCH: usize = 64;
+
+const BACKOFF_MIN: Duration = Duration::from_millis(250);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// How long the mesh gets to take an entry before the try counts as failed.
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the outbox is looked at with nothing queued, in case an entry
+/// was added without a wake-up, e.g. by hand.
+const IDLE_POLL: Duration = Duration::from_secs(5);
+
+/// Published entries are kept this long for `Request::ReplicationOutbox`.
+const RETAIN_PUBLISHED: Duration = Duration::from_secs(60 * 60);
+const PRUNE_EVERY: Duration = Duration::from_secs(60);
+
+/// Runs until `shutdown` turns true or the mesh is gone. The outbox is
+/// read and marked on `pool`, the core's.
+pub(crate) async fn run(outbox: Arc<Outbox>, pool: Arc<BlockingPool>, mesh: mpsc::Sender<MeshCommand>, mut shutdown: watch::Receiver<bool>) {
+    let mut backoff = BACKOFF_MIN;
+    let mut failing = false;
+    let mut pruned = Instant::now();
+    loop {
+        let wait = match round(&outbox, &pool, &mesh).await.or_else(saturated) {
+            Ok(Round { sent, failure: None }) => {
+                if failing {
+                    info!("Gossiping the replication outbox again; {} op(s) waiting", outbox.depth());
+                    failing = false;
+                }
+                backoff = BACKOFF_MIN;
+                if sent > 0 {
+                    continue;
+                }
+                None
+            }
+            Ok(Round { failure: Some(reason), .. }) => {
+                if !failing {
+                    warn!("Could not gossip the replication outbox ({}); retrying, {} op(s) waiting", reason, outbox.depth());
+                    failing = true;
+                }
+                Some(back_off(&mut backoff))
+            }
+            Err(e) if mesh.is_closed() => {
+                debug!("Stopped gossiping the replication outbox: {:#}", e);
+                return;
+            }
+            Err(e) => {
+                warn!("Could not read the replication outbox: {:#}", e);
+                Some(back_off(&mut backoff))
+            }
+        };
+        if wait.is_none() && pruned.elapsed() >= PRUNE_EVERY {
+            pruned = Instant::now();
+            if let Err(e) = blocking(&pool, &outbox, |outbox| outbox.prune(RETAIN_PUBLISHED)).await {
+                debug!("Could not prune the replication outbox: {:#}", e);
+            }
+        }
+        tokio::select! {
+            () = outbox.changed(), if wait.is_none() => {}
+            () = tokio::time::sleep(wait.unwrap_or(IDLE_POLL)) => {}
+            _ = shutdown.changed() => return,
+        }
+        if *shutdown.borrow() {
+            return;
+        }
+    }
+}
+
+/// The wait before the next try, doubling the one after.
+fn back_off(backoff: &mut Duration) -> Duration {
+    let wait = *backoff;
+    *backoff = (wait * 2).min(BACKOFF_MAX);
+    wait
+}
+
+struct Round {
+    sent: usize,
+    /// Why the first entry that failed did.
+    failure: Option<String>,
+}
+
+/// Publishes the oldest pending entries in order. Once one of a
+/// relation's entries fails, the rest of that relation's wait for the next
+/// round, so each relation's ops arrive in the order they were made.
+async fn round(outbox: &Arc<Outbox>, pool: &BlockingPool, mesh: &mpsc::Sender<MeshCommand>) -> anyhow::Result<Round> {
+    let pending = blocking(pool, outbox, |outbox| outbox.pending(BATCH)).await?;
+    let mut held = HashSet::new();
+    let mut round = Round { sent: 0, failure: None };
+    for entry in pending {
+        if held.contains(&entry.relation) {
+            continue;
+        }
+        let (seq, attempts) = (entry.seq, entry.attempts + 1);
+        match publish(mesh, &entry).await {
+            Ok(()) => {
+                blocking(pool, outbox, move |outbox| outbox.published(seq, attempts)).await?;
+                round.sent += 1;
+            }
+            Err(e) => {
+                let reason = format!("{:#}", e);
+                debug!("Could not gossip outbox entry {} on {}: {}", seq, entry.relation, reason);
+                let error = reason.clone();
+                blocking(pool, outbox, move |outbox| outbox.failed(seq, attempts, &error)).await?;
+                round.failure.get_or_insert(reason);
+                held.insert(entry.relation);
+            }
+        }
+    }
+    Ok(round)
+}
+
+async fn publish(mesh: &mpsc::Sender<MeshCommand>, entry: &OutboxEntry) -> anyhow::Result<()> {
+    let (reply, published) = oneshot::channel();
+    let command = MeshCommand::PublishChecked {
+        topic: entry.topic.clone(),
+        data: entry.op.clone().into_bytes(),
+        reply,
+    };
+    mesh.send(command).await.map_err(|_| anyhow!("the mesh is gone"))?;
+    match tokio::time::timeout(PUBLISH_TIMEOUT, published).await {
+        Ok(Ok(result)) => result,
+        // Dropped by a mesh actor that died or is being restarted.
+        Ok(Err(_)) => Err(anyhow!("the mesh is unavailable")),
+        Err(_) => Err(anyhow!("the mesh did not answer within {:?}", PUBLISH_TIMEOUT)),
+    }
+}
+
+/// A round the core pool refused, to be retried like one the mesh failed.
+fn saturated(e: anyhow::Error) -> anyhow::Result<Round> {
+    match e.downcast_ref::<PoolError>() {
+        Some(PoolError::Saturated { .. }) => Ok(Round {
+            sent: 0,
+            failure: Some(format!("{:#}", e)),
+        }),
+        _ => Err(e),
+    }
+}
+
+/// Runs `f` on the outbox on `pool`, as its methods block on the core.
+async fn blocking<T: Send + 'static>(
+    pool: &BlockingPool,
+    outbox: &Arc<Outbox>,
+    f: impl FnOnce(&Outbox) -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    let outbox = outbox.clone();
+    pool.spawn(move || f(&outbox)).await?
+}
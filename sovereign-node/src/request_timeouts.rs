@@ -0,0 +1,159 @@
+use sovereign_protocol::Request;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long a request may wait on the subsystem serving it before the
+/// client is answered with `Response::TimedOut`.
+#[derive(Debug, Clone)]
+pub struct RequestTimeouts {
+    /// Status, metrics and other answers from the node's own state.
+    pub node: Duration,
+    pub mesh: Duration,
+    /// Core requests; a query that sets `timeout_ms` gets that plus a
+    /// second instead.
+    pub core: Duration,
+    /// On-chain license checks.
+    pub finance: Duration,
+    /// Module registry and scheduler requests. WASM runs are bounded by
+    /// their own fuel and queue limits instead.
+    pub wasm: Duration,
+}
+
+impl Default for RequestTimeouts {
+    fn default() -> Self {
+        Self {
+            node: Duration::from_secs(1),
+            mesh: Duration::from_secs(5),
+            core: Duration::from_secs(30),
+            finance: Duration::from_secs(60),
+            wasm: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RequestTimeouts {
+    /// The subsystem `req` waits on and how long it may, or `None` if it is
+    /// not timed here.
+    pub fn budget(&self, req: &Request) -> Option<(&'static str, Duration)> {
+        let own = |timeout_ms: &Option<u64>| timeout_ms.map(|ms| Duration::from_millis(ms) + Duration::from_secs(1));
+        let budget = match req {
+            Request::Ping | Request::GetStatus | Request::GetMetrics | Request::GetVersion => ("node", self.node),
+            Request::QueryCore { timeout_ms, .. } | Request::CoreRunNamed { timeout_ms, .. } => {
+                ("core", own(timeout_ms).unwrap_or(self.core))
+            }
+            Request::CoreExplain { .. }
+            | Request::CoreRegisterQuery { .. }
+            | Request::CoreListNamed
+            | Request::CoreRemoveNamed { .. }
+            | Request::CoreGrant { .. }
+            | Request::CoreRevoke { .. }
+            | Request::CoreListGrants { .. }
+            | Request::ReplicationOutbox { .. }
+            | Request::ReplicationRequeue { .. }
+            | Request::CoreQueries
+            | Request::CoreListRelations
+            | Request::CoreDescribe { .. }
+            | Request::CoreAssert { .. }
+            | Request::CoreRetract { .. }
+            | Request::CoreKnn { .. }
+            | Request::CoreAuditTail { .. } => ("core", self.core),
+            Request::RunWasm { .. } | Request::RunWasmModule { .. } | Request::RunWasmPipeline { .. } => return None,
+            // Bounded by its own Electrum timeout.
+            Request::SelfCheck => return None,
+            Request::WasmUpload { .. }
+            | Request::WasmList
+            | Request::WasmInfo { .. }
+            | Request::WasmRemove { .. }
+            | Request::WasmExecutions
+            | Request::Cancel { .. }
+            | Request::WasmScheduleJob { .. }
+            | Request::WasmListJobs
+            | Request::WasmDeleteJob { .. }
+            | Request::WasmJobHistory { .. }
+            | Request::WasmAllowlistAdd { .. }
+            | Request::WasmAllowlistRemove { .. }
+            | Request::WasmAllowlistList => ("wasm", self.wasm),
+            Request::MeshDial { .. } | Request::MeshPeers | Request::MeshPresence => ("mesh", self.mesh),
+            Request::MeshUnpin { .. } => ("node", self.node),
+            Request::VerifyLicense { .. } => ("finance", self.finance),
+            Request::GetLicenseInfo | Request::AuditTail { .. } | Request::SlowRequests { .. } | Request::ConnectionStats => ("node", self.node),
+            Request::SetupState | Request::SetupApply { .. } => ("node", self.node),
+            #[cfg(feature = "fault-injection")]
+            Request::InjectFault { .. } => ("node", self.node),
+            // Handled on the connection itself, not by `handle_request`.
+            _ => return None,
+        };
+        Some(budget)
+    }
+}
+
+/// Requests counted by `Request::kind`, such as those that timed out.
+#[derive(Default)]
+pub(crate) struct KindCounts(Mutex<HashMap<&'static str, u64>>);
+
+impl KindCounts {
+    pub fn record(&self, kind: &'static str) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()).entry(kind).or_default() += 1;
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, u64> {
+        let counts = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        counts.iter().map(|(kind, count)| (kind.to_string(), *count)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(value: serde_json::Value) -> Request {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn each_kind_waits_on_its_subsystem() {
+        let timeouts = RequestTimeouts {
+            node: Duration::from_millis(1),
+            mesh: Duration::from_millis(2),
+            core: Duration::from_millis(3),
+            finance: Duration::from_millis(4),
+            wasm: Duration::from_millis(5),
+        };
+        let query = |timeout_ms: Option<u64>| {
+            request(json!({"QueryCore": {"query": "?[x] <- [[1]]", "params": {}, "timeout_ms": timeout_ms, "readonly": true, "limit": null}}))
+        };
+        for (req, expected) in [
+            (request(json!("Ping")), Some(("node", 1))),
+            (request(json!("GetStatus")), Some(("node", 1))),
+            (request(json!({"SlowRequests": {"limit": 1}})), Some(("node", 1))),
+            (request(json!({"MeshUnpin": {"addr": "/ip4/127.0.0.1/tcp/1"}})), Some(("node", 1))),
+            (request(json!("MeshPeers")), Some(("mesh", 2))),
+            (request(json!({"MeshDial": {"addr": "/ip4/127.0.0.1/tcp/1"}})), Some(("mesh", 2))),
+            (query(None), Some(("core", 3))),
+            (query(Some(250)), Some(("core", 1250))),
+            (request(json!({"CoreRunNamed": {"name": "q", "timeout_ms": 10}})), Some(("core", 1010))),
+            (request(json!({"CoreDescribe": {"name": "r"}})), Some(("core", 3))),
+            (request(json!({"CoreAuditTail": {"limit": 1}})), Some(("core", 3))),
+            (request(json!({"VerifyLicense": {"tx_id": "t", "developer_addr": "a", "required_sats": 1}})), Some(("finance", 4))),
+            (request(json!("WasmList")), Some(("wasm", 5))),
+            (request(json!({"WasmInfo": {"name": "m"}})), Some(("wasm", 5))),
+            (request(json!({"RunWasm": {"path": "m.wasm", "input": ""}})), None),
+            (request(json!("SelfCheck")), None),
+        ] {
+            let kind = req.kind();
+            assert_eq!(timeouts.budget(&req), expected.map(|(s, ms)| (s, Duration::from_millis(ms))), "{}", kind);
+        }
+    }
+
+    #[test]
+    fn counts_by_kind() {
+        let counts = KindCounts::default();
+        assert!(counts.snapshot().is_empty());
+        for kind in ["QueryCore", "MeshPeers", "QueryCore"] {
+            counts.record(kind);
+        }
+        assert_eq!(counts.snapshot(), BTreeMap::from([("MeshPeers".to_string(), 1), ("QueryCore".to_string(), 2)]));
+    }
+}
@@ -0,0 +1,157 @@
+//! Running under the Windows service control manager: `sovereign-node
+//! --service`, as `sovereign-node service install` registers it. Stop and
+//! Shutdown take the same graceful path as Ctrl-C does interactively, and
+//! lifecycle events also go to the Application event log.
+
+use crate::service_state::{self, ServiceLifecycle, ServicePhase, StatusSink};
+use anyhow::Context;
+use std::ffi::OsString;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+use tokio::sync::watch;
+use windows_service::service::{ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+use windows_service::{define_windows_service, service_dispatcher};
+use windows_sys::Win32::System::EventLog::{
+    DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, REPORT_EVENT_TYPE,
+};
+
+/// The service's name with the SCM, and its event log source.
+pub(crate) const SERVICE_NAME: &str = "sovereign-node";
+
+/// How long the SCM waits between checkpoints while the node starts (the
+/// core may be migrating) and while it drains.
+const START_WAIT: Duration = Duration::from_secs(30);
+const STOP_WAIT: Duration = Duration::from_secs(30);
+
+/// How often a pending state's checkpoint is bumped.
+const PROGRESS_EVERY: Duration = Duration::from_secs(1);
+
+/// The config the service main runs with; the SCM passes it no arguments
+/// of its own worth keeping.
+static CONFIG: OnceLock<PathBuf> = OnceLock::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Runs the node as the Windows service, with the config file at
+/// `config_path`. This thread goes to the SCM, which calls the service main
+/// on one of its own; returns once the service has stopped. Fails when the
+/// process was not started by the SCM.
+pub fn run_as_service(config_path: PathBuf) -> anyhow::Result<()> {
+    let _ = CONFIG.set(config_path);
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+        .context("Failed to reach the service control manager; --service is for the SCM, run without it interactively")
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        report_event(EVENTLOG_ERROR_TYPE, &format!("{} failed: {:#}", SERVICE_NAME, e));
+    }
+}
+
+fn run_service() -> anyhow::Result<()> {
+    let (stop_tx, mut stop_rx) = watch::channel(false);
+    let handle = service_control_handler::register(SERVICE_NAME, move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            let _ = stop_tx.send(true);
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })
+    .context("Failed to register the service control handler")?;
+    let lifecycle = service_state::activate(ServiceLifecycle::new(Box::new(ScmStatus(handle))));
+    lock(lifecycle).starting(START_WAIT);
+    report_event(EVENTLOG_INFORMATION_TYPE, &format!("{} is starting", SERVICE_NAME));
+
+    let config_path = CONFIG.get().cloned().unwrap_or_else(crate::default_config_path);
+    let result = tokio::runtime::Runtime::new().context("Failed to start the async runtime").and_then(|runtime| {
+        runtime.block_on(async {
+            let mut stop = stop_rx.clone();
+            let stopped = async move {
+                stop.wait_for(|stop| *stop).await.map(|_| ()).map_err(|_| io::Error::other("the service control handler went away"))
+            };
+            let node = crate::run_until(config_path, stopped);
+            tokio::pin!(node);
+            // Pending states need checkpoints, or the SCM takes the node for hung.
+            let mut progress = tokio::time::interval(PROGRESS_EVERY);
+            loop {
+                tokio::select! {
+                    result = &mut node => break result,
+                    Ok(()) = stop_rx.changed() => {
+                        lock(lifecycle).stopping(STOP_WAIT);
+                        report_event(EVENTLOG_INFORMATION_TYPE, &format!("{} is stopping", SERVICE_NAME));
+                    }
+                    _ = progress.tick() => {
+                        lock(lifecycle).progress();
+                    }
+                }
+            }
+        })
+    });
+    match &result {
+        Ok(()) => {
+            lock(lifecycle).stopped(0);
+            report_event(EVENTLOG_INFORMATION_TYPE, &format!("{} stopped", SERVICE_NAME));
+        }
+        // A nonzero exit code has the SCM apply the restart policy.
+        Err(_) => {
+            lock(lifecycle).stopped(1);
+        }
+    }
+    result
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Reports states to the SCM.
+struct ScmStatus(ServiceStatusHandle);
+
+impl StatusSink for ScmStatus {
+    fn set_status(&mut self, phase: ServicePhase) -> io::Result<()> {
+        let (current_state, checkpoint, wait_hint, exit_code) = match phase {
+            ServicePhase::StartPending { checkpoint, wait_hint } => (ServiceState::StartPending, checkpoint, wait_hint, 0),
+            ServicePhase::Running => (ServiceState::Running, 0, Duration::ZERO, 0),
+            ServicePhase::StopPending { checkpoint, wait_hint } => (ServiceState::StopPending, checkpoint, wait_hint, 0),
+            ServicePhase::Stopped { exit_code } => (ServiceState::Stopped, 0, Duration::ZERO, exit_code),
+        };
+        // Pending and stopped states take no controls, as the SCM expects.
+        let controls_accepted = match phase {
+            ServicePhase::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        };
+        self.0
+            .set_service_status(ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state,
+                controls_accepted,
+                exit_code: ServiceExitCode::Win32(exit_code),
+                checkpoint,
+                wait_hint,
+                process_id: None,
+            })
+            .map_err(io::Error::other)
+    }
+}
+
+/// Writes `message` to the Application event log under the service's name.
+/// Best effort: the file log has the detail.
+fn report_event(kind: REPORT_EVENT_TYPE, message: &str) {
+    let source: Vec<u16> = SERVICE_NAME.encode_utf16().chain(Some(0)).collect();
+    let text: Vec<u16> = message.encode_utf16().chain(Some(0)).collect();
+    // SAFETY: both strings are NUL-terminated and outlive the calls, and the
+    // handle is only used between registering and deregistering it.
+    unsafe {
+        let log = RegisterEventSourceW(std::ptr::null(), source.as_ptr());
+        if log.is_null() {
+            return;
+        }
+        let strings = [text.as_ptr()];
+        ReportEventW(log, kind, 0, 0, std::ptr::null_mut(), 1, 0, strings.as_ptr(), std::ptr::null());
+        DeregisterEventSource(log);
+    }
+}
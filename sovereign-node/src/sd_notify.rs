@@ -0,0 +1,157 @@
+//! The systemd side of running as a service: the `sd_notify` protocol over
+//! `NOTIFY_SOCKET`, and listening sockets handed over by socket activation.
+//! Outside systemd, none of the variables are set and all of it is a no-op.
+
+use std::ffi::OsStr;
+use std::io;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// The first descriptor systemd passes with `LISTEN_FDS`.
+#[cfg(unix)]
+const LISTEN_FDS_START: std::os::unix::io::RawFd = 3;
+
+/// One notification: a `KEY=value` line per field. Newlines in a value
+/// would start a field of their own, so they become spaces.
+pub(crate) fn encode(fields: &[(&str, &str)]) -> String {
+    fields.iter().map(|(key, value)| format!("{}={}\n", key, value.replace('\n', " "))).collect()
+}
+
+/// Sends `fields` to systemd, if it started the node with a notify socket.
+/// False when there is none or the send failed.
+pub(crate) fn notify(fields: &[(&str, &str)]) -> bool {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET").filter(|s| !s.is_empty()) else {
+        return false;
+    };
+    match send(&socket, encode(fields).as_bytes()) {
+        Ok(()) => true,
+        Err(e) => {
+            debug!("Failed to notify systemd at {}: {}", socket.to_string_lossy(), e);
+            false
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send(socket: &OsStr, message: &[u8]) -> io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    // A leading '@' names a socket in the abstract namespace.
+    let addr = match socket.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(message, &addr)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send(_socket: &OsStr, _message: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "systemd notifications need Linux"))
+}
+
+/// How often to send `WATCHDOG=1`: half the `WATCHDOG_USEC` systemd
+/// expects it within, if it watches this process.
+pub(crate) fn watchdog_interval() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok()?;
+    watchdog_interval_for(&usec, std::env::var("WATCHDOG_PID").ok().as_deref(), std::process::id())
+}
+
+/// `WATCHDOG_USEC` and `WATCHDOG_PID` as read by process `own_pid`.
+fn watchdog_interval_for(usec: &str, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    let usec: u64 = usec.parse().ok()?;
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != own_pid {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec / 2)).filter(|interval| !interval.is_zero())
+}
+
+/// The listening socket systemd passed in, if it socket-activated this
+/// process. Only the first is used: the node listens on one endpoint.
+#[cfg(unix)]
+pub(crate) fn listen_fd() -> Option<std::os::unix::io::RawFd> {
+    let pid = std::env::var("LISTEN_PID").ok()?;
+    listen_fd_for(&pid, &std::env::var("LISTEN_FDS").ok()?, std::process::id())
+}
+
+/// `LISTEN_PID` and `LISTEN_FDS` as read by process `own_pid`.
+#[cfg(unix)]
+fn listen_fd_for(pid: &str, fds: &str, own_pid: u32) -> Option<std::os::unix::io::RawFd> {
+    let pid: u32 = pid.parse().ok()?;
+    if pid != own_pid {
+        return None;
+    }
+    let count: u32 = fds.parse().ok()?;
+    if count == 0 {
+        return None;
+    }
+    if count > 1 {
+        warn!("systemd passed {} sockets; listening on the first", count);
+    }
+    Some(LISTEN_FDS_START)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_a_line_per_field_and_keeps_values_on_theirs() {
+        assert_eq!(encode(&[("READY", "1"), ("STATUS", "Serving IPC")]), "READY=1\nSTATUS=Serving IPC\n");
+        assert_eq!(encode(&[("STOPPING", "1")]), "STOPPING=1\n");
+        // A reason spanning lines must not smuggle in a READY=1 of its own.
+        assert_eq!(encode(&[("STATUS", "Critical: disk\nREADY=1")]), "STATUS=Critical: disk READY=1\n");
+        assert_eq!(encode(&[]), "");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn sends_one_datagram_to_a_path_or_an_abstract_socket() {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        let message = encode(&[("WATCHDOG", "1"), ("STATUS", "Serving IPC, health OK")]);
+        send(path.as_os_str(), message.as_bytes()).unwrap();
+        let mut buf = [0u8; 256];
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], message.as_bytes());
+
+        let name = format!("sovereign-notify-test-{}", std::process::id());
+        let systemd = UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(name.as_bytes()).unwrap()).unwrap();
+        send(OsStr::new(&format!("@{}", name)), b"READY=1\n").unwrap();
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1\n");
+
+        assert!(send(dir.path().join("nobody").as_os_str(), b"READY=1\n").is_err());
+    }
+
+    #[test]
+    fn watchdogs_at_half_the_deadline_of_this_process_only() {
+        assert_eq!(watchdog_interval_for("60000000", None, 42), Some(Duration::from_secs(30)));
+        assert_eq!(watchdog_interval_for("60000000", Some("42"), 42), Some(Duration::from_secs(30)));
+        // Meant for another process, such as a parent shell.
+        assert_eq!(watchdog_interval_for("60000000", Some("41"), 42), None);
+        assert_eq!(watchdog_interval_for("60000000", Some("nobody"), 42), None);
+        assert_eq!(watchdog_interval_for("1", None, 42), None);
+        assert_eq!(watchdog_interval_for("0", None, 42), None);
+        assert_eq!(watchdog_interval_for("soon", None, 42), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn takes_the_first_socket_passed_to_this_process() {
+        assert_eq!(listen_fd_for("42", "1", 42), Some(LISTEN_FDS_START));
+        assert_eq!(listen_fd_for("42", "3", 42), Some(LISTEN_FDS_START));
+        assert_eq!(listen_fd_for("41", "1", 42), None);
+        assert_eq!(listen_fd_for("42", "0", 42), None);
+        assert_eq!(listen_fd_for("42", "many", 42), None);
+        assert_eq!(listen_fd_for("", "1", 42), None);
+    }
+}
@@ -0,0 +1,590 @@
+use crate::blocking_pool::{BlockingPool, PoolConfig};
+use crate::config::NodeConfig;
+use crate::data_dir::DataDir;
+use crate::health::{HealthThresholds, HostProbe};
+use crate::ipc_transport;
+use crate::machine_identity::SALT_LEN;
+use anyhow::anyhow;
+use sha2::{Digest, Sha256};
+use sovereign_core::{AppliedMigration, CognitiveCore};
+use sovereign_protocol::{CheckStatus, IpcEndpoint, SelfCheck, SelfCheckReport};
+use sovereign_runtime_wasm::{ModuleManifest, WasmRuntime};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long the Electrum check waits for a server before warning.
+const ELECTRUM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Checks a node that is not running, as `sovereign-node --check` does:
+/// the config file at `config_path`, then everything it points at. A
+/// config that fails its check still drives the others, or the defaults if
+/// it does not parse. Opening the core creates its store if it is missing,
+/// as starting the node would.
+pub(crate) async fn offline(config_path: &Path) -> SelfCheckReport {
+    let (checked, config) = load_config(config_path);
+    // The checks run one at a time, so one thread does for their blocking work.
+    let pool = match BlockingPool::new("check", PoolConfig { threads: 1, queue: 1 }) {
+        Ok(pool) => pool,
+        Err(e) => {
+            let failed = fail("self_check", format!("Cannot start a thread for the checks: {}", e), "Check the process's thread limit");
+            return SelfCheckReport { checks: vec![checked, failed] };
+        }
+    };
+    // Unless a node is running there, an older layout is moved first, as
+    // starting would, so the core check does not open a new, empty store.
+    let data_dir = match DataDir::open(config.data_dir()) {
+        Ok(data_dir) => {
+            data_dir.migrate(&config);
+            data_dir
+        }
+        Err(_) => DataDir::at(config.data_dir()),
+    };
+    let mut checks = vec![checked, data_dir_check(&data_dir, &config.health_thresholds())];
+    checks.push(ipc_endpoint(&config.endpoint()).await);
+    checks.extend(shared(&config, &data_dir));
+    checks.push(core(&pool, &config, &data_dir).await);
+    checks.push(wasm_engine(&config, &data_dir));
+    checks.push(electrum(&pool, &config).await);
+    SelfCheckReport { checks }
+}
+
+/// Checks the running node, for `Request::SelfCheck`. The endpoint, core
+/// and WASM engine are the ones it runs with; the core is read on
+/// `compute` and Electrum tried on `finance`.
+pub(crate) async fn live(config: &NodeConfig, core: Arc<CognitiveCore>, compute: &BlockingPool, finance: &BlockingPool) -> SelfCheckReport {
+    let data_dir = DataDir::at(config.data_dir());
+    let checked = match config.validate() {
+        Ok(()) => pass("config", "The running config is valid"),
+        Err(e) => fail("config", format!("{:#}", e), "Fix the setting named above before the node restarts"),
+    };
+    let mut checks = vec![checked, data_dir_check(&data_dir, &config.health_thresholds())];
+    checks.push(pass("ipc_endpoint", format!("Listening on {}", config.endpoint())));
+    checks.extend(shared(config, &data_dir));
+    let applied = compute.spawn(move || core.applied_migrations().map_err(anyhow::Error::from)).await;
+    checks.push(match applied {
+        Ok(Ok(applied)) => migrations(&applied),
+        Ok(Err(e)) => fail("core", format!("Cannot read the core's schema version: {:#}", e), "Check the core's store for damage"),
+        Err(e) => fail("core", format!("The core check failed: {}", e), "Check the node's log"),
+    });
+    checks.push(pass("wasm_engine", "The WASM engine is running"));
+    checks.push(electrum(finance, config).await);
+    SelfCheckReport { checks }
+}
+
+/// The checks that read the same files whether or not the node runs.
+fn shared(config: &NodeConfig, data_dir: &DataDir) -> Vec<SelfCheck> {
+    vec![
+        swarm_key(&config.psk_path(data_dir)),
+        machine_identity(&data_dir.install_salt()),
+        module_store(&config.module_store(data_dir)),
+    ]
+}
+
+fn load_config(path: &Path) -> (SelfCheck, NodeConfig) {
+    const NAME: &str = "config";
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut config = NodeConfig::default();
+            let checked = match config.apply_env() {
+                Ok(()) => warn(
+                    NAME,
+                    format!("No config file at {}", path.display()),
+                    "The node writes a commented default there when it first starts",
+                ),
+                Err(e) => fail(NAME, format!("{:#}", e), "Fix or unset the SOVEREIGN_* variable named above"),
+            };
+            return (checked, config);
+        }
+        Err(e) => {
+            let checked = fail(NAME, format!("Cannot read {}: {}", path.display(), e), "Make the config file readable by the node's user");
+            return (checked, NodeConfig::default());
+        }
+    };
+    let mut config = match NodeConfig::parse(&text) {
+        Ok(config) => config,
+        Err(e) => {
+            let hint = "Fix the setting named above, or remove it to use its default";
+            return (fail(NAME, format!("{} is not valid: {:#}", path.display(), e), hint), NodeConfig::default());
+        }
+    };
+    if let Err(e) = config.apply_env() {
+        return (fail(NAME, format!("{:#}", e), "Fix or unset the SOVEREIGN_* variable named above"), config);
+    }
+    let checked = match config.validate() {
+        Ok(()) => pass(NAME, format!("{} is valid", path.display())),
+        Err(e) => fail(
+            NAME,
+            format!("{}: {:#}", path.display(), e),
+            "Change the setting named above, or the SOVEREIGN_* variable that overrides it",
+        ),
+    };
+    (checked, config)
+}
+
+fn data_dir_check(data_dir: &DataDir, limits: &HealthThresholds) -> SelfCheck {
+    const NAME: &str = "data_dir";
+    let dir = data_dir.root();
+    let hint = format!("Make {} writable by the node's user, or point data_dir elsewhere", dir.display());
+    if let Err(e) = data_dir.create() {
+        return fail(NAME, format!("Cannot create {}: {}", dir.display(), e), hint);
+    }
+    let probe = dir.join(".self-check");
+    if let Err(e) = std::fs::write(&probe, b"ok").and_then(|()| std::fs::remove_file(&probe)) {
+        return fail(NAME, format!("{} is not writable: {}", dir.display(), e), hint);
+    }
+    let under = |free: u64, limit: u64| limit > 0 && free < limit;
+    let mb = |bytes: u64| bytes / (1024 * 1024);
+    match HostProbe::new(dir).sample().disk_free_bytes {
+        Some(free) if under(free, limits.disk_free_critical_bytes) => fail(
+            NAME,
+            format!("{} MB free for {}", mb(free), dir.display()),
+            "Free disk space, or move data_dir to a larger disk",
+        ),
+        Some(free) if under(free, limits.disk_free_degraded_bytes) => warn(
+            NAME,
+            format!("{} MB free for {}", mb(free), dir.display()),
+            "Free disk space before the node runs out",
+        ),
+        Some(free) => pass(NAME, format!("{} is writable, {} MB free", dir.display(), mb(free))),
+        None => pass(NAME, format!("{} is writable; its free space is unknown", dir.display())),
+    }
+}
+
+/// Binds the endpoint and lets it go, unless a node already listens there:
+/// binding a socket path replaces whatever socket is there.
+async fn ipc_endpoint(endpoint: &IpcEndpoint) -> SelfCheck {
+    const NAME: &str = "ipc_endpoint";
+    #[cfg(unix)]
+    if let IpcEndpoint::UnixSocket(path) = endpoint {
+        if tokio::net::UnixStream::connect(path).await.is_ok() {
+            return fail(
+                NAME,
+                format!("Something is already listening on {}", endpoint),
+                "Stop the node that is running, or give this one its own ipc_endpoint",
+            );
+        }
+    }
+    match ipc_transport::bind(endpoint) {
+        Ok(listener) => {
+            drop(listener);
+            pass(NAME, format!("{} can be bound", endpoint))
+        }
+        Err(e) => fail(
+            NAME,
+            format!("Cannot listen on {}: {:#}", endpoint, e),
+            "Make the endpoint's directory the node's user's own, or set ipc_endpoint",
+        ),
+    }
+}
+
+fn swarm_key(path: &Path) -> SelfCheck {
+    const NAME: &str = "swarm_key";
+    let format_hint = "A swarm key is three lines: /key/swarm/psk/1.0.0/, /base16/ and 64 hex digits";
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return warn(
+                NAME,
+                format!("No swarm key at {}", path.display()),
+                "The node writes the shared development key there when it starts; replace it with your mesh's own",
+            );
+        }
+        Err(e) => return fail(NAME, format!("Cannot read {}: {}", path.display(), e), "Make the swarm key readable by the node's user"),
+    };
+    let mut lines = text.lines();
+    let key = match (lines.next(), lines.next(), lines.next()) {
+        (Some("/key/swarm/psk/1.0.0/"), Some("/base16/"), Some(key)) => key.trim(),
+        _ => return fail(NAME, format!("{} is not a swarm key", path.display()), format_hint),
+    };
+    match hex::decode(key) {
+        Ok(bytes) if bytes.len() == 32 && bytes.iter().all(|b| *b == 0) => warn(
+            NAME,
+            format!("{} holds the development key", path.display()),
+            "Generate a random key and give every node in your mesh the same one",
+        ),
+        Ok(bytes) if bytes.len() == 32 => pass(NAME, format!("{} holds a 32-byte key", path.display())),
+        _ => fail(NAME, format!("The key in {} is not 64 hex digits", path.display()), format_hint),
+    }
+}
+
+/// The machine uid and install salt licenses are bound to.
+fn machine_identity(path: &Path) -> SelfCheck {
+    const NAME: &str = "machine_identity";
+    let uid = machine_uid::get().is_ok();
+    match std::fs::read(path) {
+        Ok(salt) if salt.len() != SALT_LEN => fail(
+            NAME,
+            format!("{} is {} bytes, not {}", path.display(), salt.len(), SALT_LEN),
+            "Restore it from a backup; otherwise the node replaces it, and licenses bound to it stop verifying",
+        ),
+        Ok(_) if uid => pass(NAME, "The machine uid and install salt are present"),
+        Ok(_) => warn(
+            NAME,
+            "The machine uid is unavailable; licenses are bound to the install salt alone",
+            "Back up the install salt with the data directory",
+        ),
+        Err(e) if e.kind() == io::ErrorKind::NotFound && uid => pass(NAME, "The machine uid is present; the install salt is made when the node starts"),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => warn(
+            NAME,
+            "The machine uid is unavailable and there is no install salt yet",
+            "Licenses will be bound to the salt the node makes when it starts; back it up with the data directory",
+        ),
+        Err(e) => fail(NAME, format!("Cannot read {}: {}", path.display(), e), "Make the install salt readable by the node's user"),
+    }
+}
+
+/// Every stored module's bytes against the hash its manifest pins.
+fn module_store(dir: &Path) -> SelfCheck {
+    const NAME: &str = "module_store";
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return pass(NAME, format!("No module store yet at {}", dir.display())),
+        Err(e) => {
+            return fail(
+                NAME,
+                format!("Cannot read {}: {}", dir.display(), e),
+                "Make the module store readable by the node's user, or set wasm.module_store",
+            );
+        }
+    };
+    let mut modules = 0;
+    let mut problems = Vec::new();
+    for entry in entries.flatten() {
+        let module_dir = entry.path();
+        // The registry skips directories without a manifest too.
+        if !module_dir.join("manifest.json").is_file() {
+            continue;
+        }
+        modules += 1;
+        if let Some(problem) = module_problem(&module_dir) {
+            problems.push(format!("{}: {}", entry.file_name().to_string_lossy(), problem));
+        }
+    }
+    if problems.is_empty() {
+        return pass(NAME, format!("{} module(s) in {} match their manifests", modules, dir.display()));
+    }
+    fail(
+        NAME,
+        problems.join("; "),
+        "Upload each module named above again, or remove its directory from the module store",
+    )
+}
+
+fn module_problem(dir: &Path) -> Option<String> {
+    let manifest = std::fs::read(dir.join("manifest.json"))
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| serde_json::from_slice::<ModuleManifest>(&bytes).map_err(|e| e.to_string()));
+    let manifest = match manifest {
+        Ok(manifest) => manifest,
+        Err(e) => return Some(format!("unreadable manifest: {}", e)),
+    };
+    let bytes = match std::fs::read(dir.join("module.wasm")) {
+        Ok(bytes) => bytes,
+        Err(e) => return Some(format!("unreadable module.wasm: {}", e)),
+    };
+    let sha256 = hex::encode(Sha256::digest(&bytes));
+    match manifest.sha256 {
+        Some(expected) if !expected.eq_ignore_ascii_case(&sha256) => Some(format!("SHA-256 is {}, the manifest pins {}", sha256, expected)),
+        _ => None,
+    }
+}
+
+async fn core(pool: &BlockingPool, config: &NodeConfig, data_dir: &DataDir) -> SelfCheck {
+    let core_config = match crate::core_config(config, data_dir) {
+        Ok(core_config) => core_config,
+        Err(e) => return fail("core", format!("{:#}", e), "Fix or unset the SOVEREIGN_CORE_* variable named above"),
+    };
+    let opened = pool.spawn(move || {
+        let core = CognitiveCore::new(core_config)?;
+        anyhow::Ok(core.applied_migrations()?)
+    })
+    .await;
+    match opened {
+        Ok(Ok(applied)) => migrations(&applied),
+        Ok(Err(e)) => fail(
+            "core",
+            format!("Cannot open the core: {:#}", e),
+            "Check core.backend and core.path, and that no running node holds the store",
+        ),
+        Err(e) => fail("core", format!("The core check failed: {}", e), "Run the check again with RUST_LOG=debug"),
+    }
+}
+
+/// Compares the store's schema version with this build's migrations.
+fn migrations(applied: &[AppliedMigration]) -> SelfCheck {
+    const NAME: &str = "core";
+    let current = applied.last().map_or(0, |m| m.version);
+    let known = crate::CORE_MIGRATIONS.last().map_or(0, |m| m.version);
+    if current > known {
+        return fail(
+            NAME,
+            format!("The core's schema is at version {}, newer than this build's latest, {}", current, known),
+            "Run a newer release of the node, or restore a backup from before the upgrade",
+        );
+    }
+    let pending = crate::CORE_MIGRATIONS.iter().filter(|m| m.version > current).count();
+    if pending > 0 {
+        return warn(
+            NAME,
+            format!("The core is at schema version {} with {} migration(s) pending", current, pending),
+            "They are applied when the node starts; back up the core first",
+        );
+    }
+    pass(NAME, format!("The core opens at schema version {}", current))
+}
+
+fn wasm_engine(config: &NodeConfig, data_dir: &DataDir) -> SelfCheck {
+    match WasmRuntime::with_config(config.runtime_config(data_dir)) {
+        Ok(_) => pass("wasm_engine", "The WASM engine starts"),
+        Err(e) => fail(
+            "wasm_engine",
+            format!("The WASM engine does not start: {:#}", e),
+            "Check the [wasm] settings",
+        ),
+    }
+}
+
+/// Only warns: the node runs without Electrum, on its last license check.
+async fn electrum(pool: &BlockingPool, config: &NodeConfig) -> SelfCheck {
+    const NAME: &str = "electrum";
+    let config = config.clone();
+    let connect = pool.spawn(move || config.license_verifier().map(drop));
+    let connected = match tokio::time::timeout(ELECTRUM_TIMEOUT, connect).await {
+        Ok(Ok(connected)) => connected,
+        Ok(Err(e)) => Err(anyhow!("{}", e)),
+        Err(_) => Err(anyhow!("no server answered within {:?}", ELECTRUM_TIMEOUT)),
+    };
+    match connected {
+        Ok(()) => pass(NAME, "An Electrum server answered"),
+        Err(e) => warn(
+            NAME,
+            format!("No Electrum server is reachable: {:#}", e),
+            "Check finance.electrum_urls and the network; licenses stay as last checked meanwhile",
+        ),
+    }
+}
+
+fn pass(name: &str, detail: impl Into<String>) -> SelfCheck {
+    check(name, CheckStatus::Pass, detail.into(), None)
+}
+
+fn warn(name: &str, detail: impl Into<String>, hint: impl Into<String>) -> SelfCheck {
+    check(name, CheckStatus::Warn, detail.into(), Some(hint.into()))
+}
+
+fn fail(name: &str, detail: impl Into<String>, hint: impl Into<String>) -> SelfCheck {
+    check(name, CheckStatus::Fail, detail.into(), Some(hint.into()))
+}
+
+fn check(name: &str, status: CheckStatus, detail: String, hint: Option<String>) -> SelfCheck {
+    SelfCheck {
+        name: name.into(),
+        status,
+        detail,
+        hint,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn find<'a>(report: &'a SelfCheckReport, name: &str) -> &'a SelfCheck {
+        report.checks.iter().find(|c| c.name == name).unwrap_or_else(|| panic!("no {} check in {:?}", name, report))
+    }
+
+    fn hint(check: &SelfCheck) -> &str {
+        check.hint.as_deref().unwrap_or_default()
+    }
+
+    /// A config file for a node kept entirely under `dir`, whose Electrum
+    /// server refuses at once.
+    fn config_in(dir: &Path, extra: &str) -> PathBuf {
+        let path = dir.join("node.toml");
+        let text = format!(
+            "{}\ndata_dir = {:?}\nipc_endpoint = {:?}\n[finance]\nelectrum_urls = [\"tcp://127.0.0.1:1\"]\n",
+            extra,
+            dir.join("data"),
+            dir.join("node.sock"),
+        );
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn plant_module(store: &Path, name: &str, bytes: &[u8], pinned: &[u8]) {
+        let dir = store.join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("module.wasm"), bytes).unwrap();
+        let manifest = serde_json::json!({ "sha256": hex::encode(Sha256::digest(pinned)) });
+        std::fs::write(dir.join("manifest.json"), manifest.to_string()).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_sound_node_passes_and_a_corrupt_module_fails_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = config_in(dir.path(), "");
+        let report = offline(&path).await;
+        for check in &report.checks {
+            assert_ne!(check.status, CheckStatus::Fail, "{:?}", check);
+        }
+        for name in ["config", "data_dir", "ipc_endpoint", "core", "wasm_engine", "module_store"] {
+            assert_eq!(find(&report, name).status, CheckStatus::Pass, "{:?}", find(&report, name));
+        }
+        // Electrum is only ever a warning.
+        assert_eq!(find(&report, "electrum").status, CheckStatus::Warn);
+
+        let config = NodeConfig::load(&path).unwrap();
+        let store = config.module_store(&DataDir::at(config.data_dir()));
+        plant_module(&store, "good", b"module bytes", b"module bytes");
+        plant_module(&store, "tampered", b"changed bytes", b"module bytes");
+        let report = offline(&path).await;
+        let modules = find(&report, "module_store");
+        assert_eq!(modules.status, CheckStatus::Fail);
+        assert!(modules.detail.starts_with("tampered: SHA-256 is "), "{}", modules.detail);
+        assert!(!modules.detail.contains("good"), "{}", modules.detail);
+        assert!(hint(modules).contains("Upload each module named above again"));
+        for check in report.checks.iter().filter(|c| c.name != "module_store") {
+            assert_ne!(check.status, CheckStatus::Fail, "{:?}", check);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_bad_config_value_fails_the_config_check_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let report = offline(&config_in(dir.path(), "ipc_max_connections = 0")).await;
+        let config = find(&report, "config");
+        assert_eq!(config.status, CheckStatus::Fail);
+        assert!(config.detail.contains("ipc_max_connections must be above 0"), "{}", config.detail);
+        assert!(hint(config).starts_with("Change the setting named above"), "{:?}", config.hint);
+        // The rest still run, against the config's own paths.
+        assert_eq!(find(&report, "data_dir").status, CheckStatus::Pass);
+        assert!(find(&report, "data_dir").detail.contains(&dir.path().display().to_string()));
+        assert_eq!(find(&report, "core").status, CheckStatus::Pass);
+
+        let report = offline(&config_in(dir.path(), "ipc_max_connections = \"many\"")).await;
+        let config = find(&report, "config");
+        assert_eq!(config.status, CheckStatus::Fail);
+        assert!(hint(config).starts_with("Fix the setting named above, or remove it"), "{:?}", config.hint);
+    }
+
+    #[test]
+    fn a_data_dir_that_cannot_be_made_fails_with_where() {
+        // A file where a directory must go stops even root.
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file"), b"").unwrap();
+        let check = data_dir_check(&DataDir::at(dir.path().join("file/data")), &HealthThresholds::default());
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.detail.starts_with("Cannot create "), "{}", check.detail);
+        assert!(hint(&check).contains("writable by the node's user"), "{:?}", check.hint);
+
+        let check = data_dir_check(&DataDir::at(dir.path().join("data")), &HealthThresholds::default());
+        assert_eq!(check.status, CheckStatus::Pass, "{:?}", check);
+        assert!(!dir.path().join("data/.self-check").exists());
+    }
+
+    #[test]
+    fn a_disk_below_the_thresholds_warns_then_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = DataDir::at(dir.path());
+        let everything = HealthThresholds {
+            disk_free_degraded_bytes: u64::MAX,
+            ..HealthThresholds::default()
+        };
+        assert_eq!(data_dir_check(&data_dir, &everything).status, CheckStatus::Warn);
+        let everything = HealthThresholds {
+            disk_free_critical_bytes: u64::MAX,
+            ..everything
+        };
+        let check = data_dir_check(&data_dir, &everything);
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(hint(&check).starts_with("Free disk space"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn an_endpoint_in_use_or_unbindable_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("node.sock");
+        let _listening = tokio::net::UnixListener::bind(&path).unwrap();
+        let check = ipc_endpoint(&IpcEndpoint::UnixSocket(path.clone())).await;
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.detail.starts_with("Something is already listening"), "{}", check.detail);
+
+        std::fs::write(dir.path().join("file"), b"").unwrap();
+        let nowhere = IpcEndpoint::UnixSocket(dir.path().join("file/node.sock"));
+        let check = ipc_endpoint(&nowhere).await;
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.detail.starts_with("Cannot listen on "), "{}", check.detail);
+    }
+
+    #[test]
+    fn swarm_keys_are_read_by_their_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("swarm.key");
+        assert_eq!(swarm_key(&path).status, CheckStatus::Warn);
+
+        let key = |hex: &str| format!("/key/swarm/psk/1.0.0/\n/base16/\n{}\n", hex);
+        std::fs::write(&path, key(&"ab".repeat(32))).unwrap();
+        assert_eq!(swarm_key(&path).status, CheckStatus::Pass);
+        std::fs::write(&path, key(&"00".repeat(32))).unwrap();
+        let check = swarm_key(&path);
+        assert_eq!(check.status, CheckStatus::Warn);
+        assert!(hint(&check).starts_with("Generate a random key"));
+
+        for broken in [key(&"ab".repeat(31)), key("not hex"), "just a key\n".into()] {
+            std::fs::write(&path, broken).unwrap();
+            let check = swarm_key(&path);
+            assert_eq!(check.status, CheckStatus::Fail);
+            assert!(hint(&check).starts_with("A swarm key is three lines"));
+        }
+    }
+
+    #[test]
+    fn a_truncated_install_salt_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("install.salt");
+        assert_ne!(machine_identity(&path).status, CheckStatus::Fail);
+        std::fs::write(&path, [7u8; SALT_LEN]).unwrap();
+        assert_ne!(machine_identity(&path).status, CheckStatus::Fail);
+
+        std::fs::write(&path, [7u8; 3]).unwrap();
+        let check = machine_identity(&path);
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.detail.ends_with(&format!("is 3 bytes, not {}", SALT_LEN)), "{}", check.detail);
+        assert!(hint(&check).starts_with("Restore it from a backup"));
+    }
+
+    #[test]
+    fn a_store_newer_than_the_build_fails() {
+        assert_eq!(migrations(&[]).status, CheckStatus::Pass);
+        let ahead = AppliedMigration {
+            version: crate::CORE_MIGRATIONS.last().map_or(0, |m| m.version) + 1,
+            name: "from the future".into(),
+            applied_at_ms: 0,
+        };
+        let check = migrations(&[ahead]);
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(hint(&check).starts_with("Run a newer release"));
+    }
+
+    #[test]
+    fn unreadable_manifests_and_missing_modules_are_named() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(module_store(&dir.path().join("none")).status, CheckStatus::Pass);
+
+        std::fs::create_dir_all(dir.path().join("garbled")).unwrap();
+        std::fs::write(dir.path().join("garbled/manifest.json"), b"{").unwrap();
+        std::fs::create_dir_all(dir.path().join("missing")).unwrap();
+        std::fs::write(dir.path().join("missing/manifest.json"), b"{}").unwrap();
+        // Without a manifest a directory is not a module.
+        std::fs::create_dir_all(dir.path().join("stray")).unwrap();
+        let check = module_store(dir.path());
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.detail.contains("garbled: unreadable manifest"), "{}", check.detail);
+        assert!(check.detail.contains("missing: unreadable module.wasm"), "{}", check.detail);
+        assert!(!check.detail.contains("stray"), "{}", check.detail);
+    }
+}
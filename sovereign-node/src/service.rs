@@ -0,0 +1,624 @@
+//! `sovereign-node service`: installs the node under the platform's service
+//! manager, as a systemd user unit on Linux, a launchd agent on macOS or a
+//! Windows service, restarted when it fails.
+
+use crate::config::NodeConfig;
+use anyhow::{bail, Context};
+use sovereign_protocol::IpcEndpoint;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// What `sovereign-node service` was asked to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceAction {
+    /// With `socket_activated`, systemd binds the IPC socket and starts the
+    /// node on the first connection.
+    Install { socket_activated: bool },
+    Uninstall,
+    Start,
+    Stop,
+    Status,
+}
+
+/// What the installed service runs.
+#[cfg_attr(not(any(target_os = "linux", target_os = "macos", windows)), allow(dead_code))]
+pub(crate) struct ServiceSpec {
+    pub binary: PathBuf,
+    pub config: PathBuf,
+    pub data_dir: PathBuf,
+    /// The socket systemd binds, for socket activation.
+    pub socket: Option<PathBuf>,
+}
+
+/// Carries out `action` for the node with the config file at `config_path`,
+/// or else the service's default one.
+pub fn service(action: ServiceAction, config_path: Option<PathBuf>) -> anyhow::Result<()> {
+    match action {
+        ServiceAction::Install { socket_activated } => {
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            if NodeConfig::write_default(&config_path)? {
+                println!("Wrote a default config file to {}", config_path.display());
+            }
+            let config = NodeConfig::load(&config_path)?;
+            let socket = match config.endpoint() {
+                IpcEndpoint::UnixSocket(path) if socket_activated => Some(path),
+                other if socket_activated => bail!("Socket activation needs a Unix socket endpoint, not {}", other),
+                _ => None,
+            };
+            let spec = ServiceSpec {
+                binary: std::env::current_exe().context("Failed to find the sovereign-node binary")?,
+                config: absolute(&config_path)?,
+                data_dir: absolute(&config.data_dir_or(default_data_dir))?,
+                socket,
+            };
+            platform::install(&spec)
+        }
+        ServiceAction::Uninstall => platform::uninstall(),
+        ServiceAction::Start => platform::start(),
+        ServiceAction::Stop => platform::stop(),
+        ServiceAction::Status => platform::status(),
+    }
+}
+
+/// The config a service runs from unless given one: the user's, since
+/// systemd and launchd run the node as the user.
+#[cfg(not(windows))]
+fn default_config_path() -> PathBuf {
+    crate::default_config_path()
+}
+
+/// The machine's, under `%ProgramData%`: the Windows service runs as
+/// LocalSystem, whatever user installed it.
+#[cfg(windows)]
+fn default_config_path() -> PathBuf {
+    program_data().join("node.toml")
+}
+
+#[cfg(not(windows))]
+fn default_data_dir() -> PathBuf {
+    sovereign_protocol::default_data_dir()
+}
+
+#[cfg(windows)]
+fn default_data_dir() -> PathBuf {
+    program_data()
+}
+
+/// `%ProgramData%\Sovereign`.
+#[cfg(windows)]
+fn program_data() -> PathBuf {
+    let base = std::env::var_os("ProgramData").filter(|d| !d.is_empty()).map(PathBuf::from);
+    base.unwrap_or_else(|| PathBuf::from(r"C:\ProgramData")).join("Sovereign")
+}
+
+fn absolute(path: &Path) -> anyhow::Result<PathBuf> {
+    std::path::absolute(path).with_context(|| format!("Failed to resolve {}", path.display()))
+}
+
+/// Runs `program` with `args`, failing unless it exits successfully.
+#[cfg_attr(not(any(target_os = "linux", target_os = "macos", windows)), allow(dead_code))]
+fn run(program: &str, args: &[&str]) -> anyhow::Result<()> {
+    let status = Command::new(program).args(args).status().with_context(|| format!("Failed to run {}", program))?;
+    if !status.success() {
+        bail!("`{} {}` failed: {}", program, args.join(" "), status);
+    }
+    Ok(())
+}
+
+/// The user's home directory.
+#[cfg_attr(not(any(target_os = "linux", target_os = "macos")), allow(dead_code))]
+fn home() -> anyhow::Result<PathBuf> {
+    std::env::var_os("HOME").filter(|h| !h.is_empty()).map(PathBuf::from).context("HOME is not set")
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::{home, run, ServiceSpec};
+    use anyhow::Context;
+    use std::path::{Path, PathBuf};
+
+    const UNIT: &str = "sovereign-node";
+
+    /// A systemd user unit: it runs while the user is logged in, or from
+    /// boot with `loginctl enable-linger`.
+    pub(super) fn install(spec: &ServiceSpec) -> anyhow::Result<()> {
+        let dir = unit_dir()?;
+        std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        write(&dir.join(format!("{}.service", UNIT)), &systemd_unit(spec))?;
+        if let Some(socket) = &spec.socket {
+            write(&dir.join(format!("{}.socket", UNIT)), &systemd_socket(socket))?;
+        }
+        run("systemctl", &["--user", "daemon-reload"])?;
+        // A socket-activated node is started by its socket.
+        let started = if spec.socket.is_some() { format!("{}.socket", UNIT) } else { format!("{}.service", UNIT) };
+        run("systemctl", &["--user", "enable", "--now", &started])?;
+        println!("Installed and started {}", started);
+        Ok(())
+    }
+
+    pub(super) fn uninstall() -> anyhow::Result<()> {
+        let dir = unit_dir()?;
+        let units = [format!("{}.socket", UNIT), format!("{}.service", UNIT)];
+        let installed: Vec<&String> = units.iter().filter(|unit| dir.join(unit).exists()).collect();
+        if installed.is_empty() {
+            println!("{} is not installed", UNIT);
+            return Ok(());
+        }
+        for unit in &installed {
+            // Already stopped or disabled is fine: the files go either way.
+            let _ = run("systemctl", &["--user", "disable", "--now", unit]);
+            let path = dir.join(unit);
+            std::fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+        run("systemctl", &["--user", "daemon-reload"])?;
+        println!("Uninstalled {}", UNIT);
+        Ok(())
+    }
+
+    pub(super) fn start() -> anyhow::Result<()> {
+        run("systemctl", &["--user", "start", &format!("{}.service", UNIT)])?;
+        println!("Started {}", UNIT);
+        Ok(())
+    }
+
+    pub(super) fn stop() -> anyhow::Result<()> {
+        run("systemctl", &["--user", "stop", &format!("{}.service", UNIT)])?;
+        println!("Stopped {}", UNIT);
+        Ok(())
+    }
+
+    pub(super) fn status() -> anyhow::Result<()> {
+        let path = unit_dir()?.join(format!("{}.service", UNIT));
+        if !path.exists() {
+            println!("{} is not installed", UNIT);
+            return Ok(());
+        }
+        println!("Installed at {}", path.display());
+        // `status` exits non-zero for a stopped unit, which is still an answer.
+        let _ = run("systemctl", &["--user", "status", "--no-pager", &format!("{}.service", UNIT)]);
+        Ok(())
+    }
+
+    fn unit_dir() -> anyhow::Result<PathBuf> {
+        let config = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => home()?.join(".config"),
+        };
+        Ok(config.join("systemd").join("user"))
+    }
+
+    fn write(path: &Path, contents: &str) -> anyhow::Result<()> {
+        std::fs::write(path, contents).with_context(|| format!("Failed to write {}", path.display()))?;
+        println!("Wrote {}", path.display());
+        Ok(())
+    }
+
+    /// The `.service` unit. `Type=notify`: systemd waits for `READY=1`, and
+    /// restarts the node if it stops sending `WATCHDOG=1`.
+    fn systemd_unit(spec: &ServiceSpec) -> String {
+        let requires = match spec.socket {
+            Some(_) => format!("Requires={}.socket\nAfter={}.socket\n", UNIT, UNIT),
+            None => String::new(),
+        };
+        format!(
+            "# Written by `sovereign-node service install`.\n\
+             [Unit]\n\
+             Description=Sovereign node\n\
+             {requires}\
+             \n\
+             [Service]\n\
+             Type=notify\n\
+             NotifyAccess=main\n\
+             ExecStart={binary} --config {config}\n\
+             Environment={data_dir}\n\
+             Restart=on-failure\n\
+             RestartSec=5\n\
+             WatchdogSec=60\n\
+             TimeoutStopSec=30\n\
+             \n\
+             [Install]\n\
+             WantedBy=default.target\n",
+            requires = requires,
+            binary = quote(&spec.binary.to_string_lossy(), true),
+            config = quote(&spec.config.to_string_lossy(), true),
+            data_dir = quote(&format!("SOVEREIGN_DATA_DIR={}", spec.data_dir.display()), false),
+        )
+    }
+
+    /// The `.socket` unit that binds the IPC socket for socket activation.
+    fn systemd_socket(socket: &Path) -> String {
+        format!(
+            "# Written by `sovereign-node service install --socket-activated`.\n\
+             [Unit]\n\
+             Description=Sovereign node IPC socket\n\
+             \n\
+             [Socket]\n\
+             ListenStream={}\n\
+             SocketMode=0600\n\
+             DirectoryMode=0700\n\
+             \n\
+             [Install]\n\
+             WantedBy=sockets.target\n",
+            // ListenStream takes the path unquoted; only specifiers expand.
+            socket.display().to_string().replace('%', "%%"),
+        )
+    }
+
+    /// `value` as one double-quoted word of a unit file. Specifiers (`%`)
+    /// are escaped everywhere; variables (`$`) only in `ExecStart`, the
+    /// only place they expand.
+    fn quote(value: &str, exec: bool) -> String {
+        let mut quoted = String::from("\"");
+        for c in value.chars() {
+            match c {
+                '"' | '\\' => {
+                    quoted.push('\\');
+                    quoted.push(c);
+                }
+                '%' => quoted.push_str("%%"),
+                '$' if exec => quoted.push_str("$$"),
+                _ => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn spec(socket: Option<&str>) -> ServiceSpec {
+            ServiceSpec {
+                binary: "/opt/sovereign/bin/sovereign-node".into(),
+                config: "/home/ada/.config/sovereign/node.toml".into(),
+                data_dir: "/home/ada/.local/share/sovereign".into(),
+                socket: socket.map(PathBuf::from),
+            }
+        }
+
+        #[test]
+        fn writes_the_golden_units() {
+            assert_eq!(systemd_unit(&spec(None)), include_str!("../tests/golden/sovereign-node.service"));
+            let activated = spec(Some("/run/user/1000/sovereign/node.sock"));
+            assert_eq!(systemd_unit(&activated), include_str!("../tests/golden/socket-activated.service"));
+            assert_eq!(
+                systemd_socket(activated.socket.as_deref().unwrap()),
+                include_str!("../tests/golden/sovereign-node.socket")
+            );
+        }
+
+        #[test]
+        fn quotes_paths_so_systemd_reads_them_back_verbatim() {
+            let spec = ServiceSpec {
+                binary: "/opt/my \"node\"/sovereign-node".into(),
+                config: "/home/ada/100% $HOME\\node.toml".into(),
+                data_dir: "/srv/50% $data".into(),
+                socket: Some("/run/50%/node.sock".into()),
+            };
+            let unit = systemd_unit(&spec);
+            assert!(unit.contains("ExecStart=\"/opt/my \\\"node\\\"/sovereign-node\" --config \"/home/ada/100%% $$HOME\\\\node.toml\"\n"), "{}", unit);
+            // Environment= expands no variables, so `$` stays as it is.
+            assert!(unit.contains("Environment=\"SOVEREIGN_DATA_DIR=/srv/50%% $data\"\n"), "{}", unit);
+            assert!(systemd_socket(spec.socket.as_deref().unwrap()).contains("ListenStream=/run/50%%/node.sock\n"));
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::{home, run, ServiceSpec};
+    use anyhow::{bail, Context};
+    use std::path::PathBuf;
+
+    const LABEL: &str = "org.sovereign.node";
+
+    /// A launchd agent in the user's GUI domain, started at login.
+    pub(super) fn install(spec: &ServiceSpec) -> anyhow::Result<()> {
+        if spec.socket.is_some() {
+            bail!("Socket activation is only supported with systemd");
+        }
+        let path = plist_path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let logs = spec.data_dir.join("logs");
+        std::fs::create_dir_all(&logs).with_context(|| format!("Failed to create {}", logs.display()))?;
+        // Replacing an installed agent: launchd keeps the old one until it is booted out.
+        let _ = run("launchctl", &["bootout", &target()]);
+        std::fs::write(&path, launchd_plist(spec)).with_context(|| format!("Failed to write {}", path.display()))?;
+        println!("Wrote {}", path.display());
+        run("launchctl", &["bootstrap", &domain(), &path.to_string_lossy()])?;
+        println!("Installed and started {}", LABEL);
+        Ok(())
+    }
+
+    pub(super) fn uninstall() -> anyhow::Result<()> {
+        let path = plist_path()?;
+        if !path.exists() {
+            println!("{} is not installed", LABEL);
+            return Ok(());
+        }
+        // Not loaded is fine: the file goes either way.
+        let _ = run("launchctl", &["bootout", &target()]);
+        std::fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+        println!("Uninstalled {}", LABEL);
+        Ok(())
+    }
+
+    pub(super) fn start() -> anyhow::Result<()> {
+        run("launchctl", &["kickstart", &target()])?;
+        println!("Started {}", LABEL);
+        Ok(())
+    }
+
+    /// SIGTERM: the node drains and exits 0, which `KeepAlive` leaves be.
+    pub(super) fn stop() -> anyhow::Result<()> {
+        run("launchctl", &["kill", "SIGTERM", &target()])?;
+        println!("Stopped {}", LABEL);
+        Ok(())
+    }
+
+    pub(super) fn status() -> anyhow::Result<()> {
+        let path = plist_path()?;
+        if !path.exists() {
+            println!("{} is not installed", LABEL);
+            return Ok(());
+        }
+        println!("Installed at {}", path.display());
+        if run("launchctl", &["print", &target()]).is_err() {
+            println!("{} is not loaded", LABEL);
+        }
+        Ok(())
+    }
+
+    fn plist_path() -> anyhow::Result<PathBuf> {
+        Ok(home()?.join("Library").join("LaunchAgents").join(format!("{}.plist", LABEL)))
+    }
+
+    fn domain() -> String {
+        format!("gui/{}", crate::ipc_transport::own_uid())
+    }
+
+    fn target() -> String {
+        format!("{}/{}", domain(), LABEL)
+    }
+
+    /// The agent's property list. `KeepAlive` restarts the node when it
+    /// exits unsuccessfully, at most every `ThrottleInterval` seconds.
+    fn launchd_plist(spec: &ServiceSpec) -> String {
+        let log = spec.data_dir.join("logs").join("launchd.log");
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<!-- Written by `sovereign-node service install`. -->
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{binary}</string>
+        <string>--config</string>
+        <string>{config}</string>
+    </array>
+    <key>EnvironmentVariables</key>
+    <dict>
+        <key>SOVEREIGN_DATA_DIR</key>
+        <string>{data_dir}</string>
+    </dict>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <dict>
+        <key>SuccessfulExit</key>
+        <false/>
+    </dict>
+    <key>ThrottleInterval</key>
+    <integer>5</integer>
+    <key>StandardOutPath</key>
+    <string>{log}</string>
+    <key>StandardErrorPath</key>
+    <string>{log}</string>
+</dict>
+</plist>
+"#,
+            label = LABEL,
+            binary = escape(&spec.binary.to_string_lossy()),
+            config = escape(&spec.config.to_string_lossy()),
+            data_dir = escape(&spec.data_dir.to_string_lossy()),
+            log = escape(&log.to_string_lossy()),
+        )
+    }
+
+    fn escape(value: &str) -> String {
+        value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn writes_the_golden_plist() {
+            let spec = ServiceSpec {
+                binary: "/Applications/Sovereign & Co/sovereign-node".into(),
+                config: "/Users/ada/Library/Application Support/sovereign/node.toml".into(),
+                data_dir: "/Users/ada/Library/Application Support/sovereign".into(),
+                socket: None,
+            };
+            assert_eq!(launchd_plist(&spec), include_str!("../tests/golden/org.sovereign.node.plist"));
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::{run, ServiceSpec};
+    use crate::scm::SERVICE_NAME;
+    use anyhow::{bail, Context};
+    use std::ffi::{OsStr, OsString};
+    use std::time::{Duration, Instant};
+    use windows_service::service::{
+        Service, ServiceAccess, ServiceAction, ServiceActionType, ServiceErrorControl, ServiceFailureActions, ServiceFailureResetPeriod,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceType,
+    };
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+    use windows_sys::Win32::Foundation::ERROR_SERVICE_DOES_NOT_EXIST;
+
+    const DISPLAY_NAME: &str = "Sovereign node";
+
+    /// How long `uninstall` and `stop` wait for the node to drain.
+    const STOP_TIMEOUT: Duration = Duration::from_secs(45);
+
+    /// A service that starts with the machine and runs as LocalSystem,
+    /// restarted 5 s after it fails. Needs an elevated prompt.
+    pub(super) fn install(spec: &ServiceSpec) -> anyhow::Result<()> {
+        if spec.socket.is_some() {
+            bail!("Socket activation is only supported with systemd");
+        }
+        let logs = spec.data_dir.join("logs");
+        std::fs::create_dir_all(&logs).with_context(|| format!("Failed to create {}", logs.display()))?;
+        let manager = manager(ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)?;
+        let info = ServiceInfo {
+            name: OsString::from(SERVICE_NAME),
+            display_name: OsString::from(DISPLAY_NAME),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: spec.binary.clone(),
+            launch_arguments: vec!["--service".into(), "--config".into(), spec.config.clone().into_os_string()],
+            dependencies: Vec::new(),
+            account_name: None,
+            account_password: None,
+        };
+        let service = manager
+            .create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)
+            .context("Failed to create the service")?;
+        service.set_description("Runs the sovereign node and serves its IPC pipe")?;
+        service.update_failure_actions(ServiceFailureActions {
+            reset_period: ServiceFailureResetPeriod::After(Duration::from_secs(86_400)),
+            reboot_msg: None,
+            command: None,
+            actions: Some(vec![ServiceAction {
+                action_type: ServiceActionType::Restart,
+                delay: Duration::from_secs(5),
+            }]),
+        })?;
+        // A clean stop with a nonzero exit code counts as a failure too.
+        service.set_failure_actions_on_non_crash_failures(true)?;
+        // The SCM sets a service's environment from this value, as
+        // systemd's Environment= does. The log file stands in for stderr,
+        // and the pipe drops the per-user suffix: the user is the machine.
+        let key = format!(r"HKLM\SYSTEM\CurrentControlSet\Services\{}", SERVICE_NAME);
+        let environment = format!(
+            r"SOVEREIGN_DATA_DIR={}\0SOVEREIGN_LOG_FILE=1\0SOVEREIGN_IPC={}",
+            spec.data_dir.display(),
+            sovereign_protocol::PIPE_NAME
+        );
+        run("reg", &["add", &key, "/v", "Environment", "/t", "REG_MULTI_SZ", "/d", &environment, "/f"])?;
+        service.start::<&OsStr>(&[]).context("Failed to start the service")?;
+        println!("Installed and started {}", SERVICE_NAME);
+        Ok(())
+    }
+
+    pub(super) fn uninstall() -> anyhow::Result<()> {
+        let manager = manager(ServiceManagerAccess::CONNECT)?;
+        let Some(service) = open(&manager, ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)? else {
+            println!("{} is not installed", SERVICE_NAME);
+            return Ok(());
+        };
+        // The SCM removes the service once it has stopped and its handles are closed.
+        stop_and_wait(&service)?;
+        service.delete().context("Failed to delete the service")?;
+        println!("Uninstalled {}", SERVICE_NAME);
+        Ok(())
+    }
+
+    pub(super) fn start() -> anyhow::Result<()> {
+        let manager = manager(ServiceManagerAccess::CONNECT)?;
+        let Some(service) = open(&manager, ServiceAccess::START)? else {
+            bail!("{} is not installed", SERVICE_NAME);
+        };
+        service.start::<&OsStr>(&[]).context("Failed to start the service")?;
+        println!("Started {}", SERVICE_NAME);
+        Ok(())
+    }
+
+    pub(super) fn stop() -> anyhow::Result<()> {
+        let manager = manager(ServiceManagerAccess::CONNECT)?;
+        let Some(service) = open(&manager, ServiceAccess::QUERY_STATUS | ServiceAccess::STOP)? else {
+            bail!("{} is not installed", SERVICE_NAME);
+        };
+        stop_and_wait(&service)?;
+        println!("Stopped {}", SERVICE_NAME);
+        Ok(())
+    }
+
+    pub(super) fn status() -> anyhow::Result<()> {
+        let manager = manager(ServiceManagerAccess::CONNECT)?;
+        let Some(service) = open(&manager, ServiceAccess::QUERY_STATUS | ServiceAccess::QUERY_CONFIG)? else {
+            println!("{} is not installed", SERVICE_NAME);
+            return Ok(());
+        };
+        let config = service.query_config()?;
+        println!("Installed as {}", config.executable_path.display());
+        println!("State: {:?}", service.query_status()?.current_state);
+        Ok(())
+    }
+
+    fn manager(access: ServiceManagerAccess) -> anyhow::Result<ServiceManager> {
+        ServiceManager::local_computer(None::<&str>, access).context("Failed to open the service control manager (this needs an elevated prompt)")
+    }
+
+    fn open(manager: &ServiceManager, access: ServiceAccess) -> anyhow::Result<Option<Service>> {
+        match manager.open_service(SERVICE_NAME, access) {
+            Ok(service) => Ok(Some(service)),
+            Err(windows_service::Error::Winapi(e)) if e.raw_os_error() == Some(ERROR_SERVICE_DOES_NOT_EXIST as i32) => Ok(None),
+            Err(e) => Err(e).context("Failed to open the service"),
+        }
+    }
+
+    /// Asks the node to stop unless it has, and waits while it drains.
+    fn stop_and_wait(service: &Service) -> anyhow::Result<()> {
+        if service.query_status()?.current_state == ServiceState::Stopped {
+            return Ok(());
+        }
+        service.stop().context("Failed to stop the service")?;
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        while service.query_status()?.current_state != ServiceState::Stopped {
+            if Instant::now() > deadline {
+                bail!("{} did not stop within {:?}", SERVICE_NAME, STOP_TIMEOUT);
+            }
+            std::thread::sleep(Duration::from_millis(250));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod platform {
+    use super::ServiceSpec;
+
+    const UNSUPPORTED: &str = "Services are supported on Linux (systemd), macOS (launchd) and Windows";
+
+    pub(super) fn install(_spec: &ServiceSpec) -> anyhow::Result<()> {
+        anyhow::bail!(UNSUPPORTED)
+    }
+
+    pub(super) fn uninstall() -> anyhow::Result<()> {
+        anyhow::bail!(UNSUPPORTED)
+    }
+
+    pub(super) fn start() -> anyhow::Result<()> {
+        anyhow::bail!(UNSUPPORTED)
+    }
+
+    pub(super) fn stop() -> anyhow::Result<()> {
+        anyhow::bail!(UNSUPPORTED)
+    }
+
+    pub(super) fn status() -> anyhow::Result<()> {
+        anyhow::bail!(UNSUPPORTED)
+    }
+}
@@ -1,40 +1,387 @@
+use crate::access::{self, AccessPolicy, PermissionSet};
+use crate::blocking_pool::{BlockingPool, PoolConfig};
+use crate::build_info::build_info;
+use crate::config::NodeConfig;
+use crate::core_queries::CoreQueries;
+use crate::core_sessions::CoreSessions;
+use crate::core_stream;
+use crate::core_watches::CoreWatches;
+use crate::data_dir::DataDir;
+use crate::event_bus::{self, EventBus, Subscription};
+#[cfg(feature = "fault-injection")]
+use crate::fault::FaultPlan;
+use crate::finance_backend::FinanceBackend;
+use crate::health::{self, HealthThresholds, HostProbe};
+use crate::ipc_audit::{self, IpcAudit, IpcAuditConfig, Principal};
+use crate::ipc_sessions::{Resumable, SessionStore};
+use crate::ipc_stats::{self, IpcStats};
+use crate::license_monitor::{self, LicenseRecheck};
+use crate::logging;
+use crate::machine_identity::MachineIdentity;
+use crate::mesh_supervisor::{self, RestartPolicy};
+use crate::mesh_warmup::{self, MeshWarmup};
+#[cfg(feature = "metrics-http")]
+use crate::metrics_http;
+use crate::module_paths::ModulePaths;
+use crate::node_state::StateStore;
+use crate::presence::{self, PresenceConfig, PresenceTable};
+use crate::ipc_tcp::{TcpConfig, TcpIpcListener, TcpSecurity};
+use crate::ipc_transport::{self, AcceptFailure, Accepted, IpcListener, PeerCred, PeerPolicy};
+use crate::rate_limit::{ConnectionLimiter, RateLimits, UserBuckets};
+use crate::replica_outbox;
+use crate::request_timeouts::{KindCounts, RequestTimeouts};
+use crate::sd_notify;
+use crate::self_check;
+use crate::service_state;
+use crate::setup::SetupTarget;
+use crate::shutdown;
+use crate::snapshot::{self, SnapshotTarget};
+use crate::wasm_stream;
 use anyhow::Result;
-use log::{info, error};
-use sovereign_core::CognitiveCore;
-use sovereign_finance::LicenseVerifier;
-use sovereign_mesh::{MeshCommand, MeshNode};
-use sovereign_protocol::{NodeStatus, Request, Response};
-use sovereign_runtime_wasm::WasmRuntime;
-use std::sync::{Arc, RwLock};
-use std::time::SystemTime;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::UnixListener;
-use tokio::sync::{mpsc, oneshot, Mutex};
-
-struct SharedState {
-    peer_id: String,
-    connections: u32,
-    license_active: bool,
+use futures::{FutureExt, StreamExt};
+use serde::Serialize;
+use sovereign_core::{
+    resolve_relation, AuditEntry, AuditSource, CognitiveCore, CoreError, CoreStats, ExplainReport, Grant, GrantRights, IndexInfo, NamedQuery, ParamSpec, ParamType, QueryOptions,
+};
+use sovereign_mesh::{MeshCommand, MeshConfig, MeshEvent};
+use sovereign_protocol::{
+    envelope_id, CoreAuditEntry, CoreColumn, CoreExplainRelation, CoreExplainReport, CoreFailure, CoreGrantInfo, CoreGrantRights, CoreIndex, CoreMetrics, CoreNamedQuery, CoreParamType, CoreQueryInfo, CoreQueryParam, CoreRelation, CoreRelationSchema, Compression, EndpointDiscovery, EventTopic, FinanceState, Frame, FrameCodec, FrameCompression, FrameError, Framing, HealthLevel, MachineBinding, IpcConnectionStats, IpcEndpoint, IpcMetrics, LicenseBinding, LineCodec, LicenseReport, LicenseTerms, LicenseTierInfo, ErrorCode, MeshPhase, MetricsSnapshot, NodeEvent, NodeMode, NodeStatus, OutboxEntryInfo, PeerPresence, ReplicationMetrics, ReplicationOutboxReport, Request, RequestEnvelope,
+    Response, ResponseEnvelope, SessionGrant, WasmExecutionInfo, WasmExport, WasmImport, WasmJobInfo, WasmJobOverlap, WasmJobRun, WasmJobSchedule, WasmJobSpec, WasmLimits, WasmLogLine, WasmMetrics, WasmModuleDetails, WasmModuleInfo, WasmModuleStats, WasmPipelineFailure, WasmSignature, WasmTrap, DEFAULT_MAX_FRAME_SIZE, PROTOCOL_VERSION,
+};
+#[cfg(feature = "fault-injection")]
+use sovereign_protocol::{FaultKind, FaultTarget};
+use sovereign_replication::{Outbox, OutboxEntry, ReplicationConfig};
+use sovereign_runtime_wasm::{
+    Allowlist, AllowlistMode, ExecutionLimits, ExecutionResult, JobInfo, JobRun, JobSchedule, JobSpec, LimitsInfo, PipelineLimits, PipelineStage, ModuleInfo, ModuleManifest, ModuleRegistry, ModuleSignature, ModuleStats, OverlapPolicy,
+    RunOptions, Scheduler, WasmError, WasmRuntime,
+};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, watch, RwLock};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::MissedTickBehavior;
+use tokio_util::codec::{Decoder, FramedRead};
+use tracing::{debug, info, error, warn, Instrument};
+
+/// The pause after an accept fails for want of descriptors or memory,
+/// doubling while it keeps failing.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// How long a turned-away client gets to show its framing before it is
+/// answered in binary frames.
+const FRAMING_WAIT: Duration = Duration::from_millis(250);
+
+/// The idle timer of a connection that is never closed for idling; the
+/// timer is reset past now, so it cannot be `Duration::MAX`.
+const NEVER_IDLE: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// Names connections whose client never says Hello.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Who a connection's requests come from.
+#[derive(Clone)]
+pub(crate) struct Caller {
+    /// The name the client said Hello with, or `connection-<n>`.
+    pub name: String,
+    /// The connecting process, where the transport tells.
+    pub peer: Option<PeerCred>,
+    /// Whose core grants the connection's queries are held to: its token's,
+    /// else its user's.
+    pub principal: Option<String>,
 }
 
-pub async fn run_ipc_server(
-    core: Arc<Mutex<CognitiveCore>>,
+impl Caller {
+    /// Audit entries record the name, and the uid and pid when known.
+    pub fn audit_source(&self) -> AuditSource {
+        match &self.peer {
+            Some(peer) => AuditSource::Ipc(format!("{} ({})", self.name, peer)),
+            None => AuditSource::Ipc(self.name.clone()),
+        }
+    }
+}
+
+/// What status and license requests report. Held in a watch channel:
+/// writers change it with `send_modify` and readers clone it out, and the
+/// channel's lock does not poison, so a writer that panics mid-update
+/// cannot stop readers from answering.
+#[derive(Clone)]
+pub(crate) struct SharedState {
+    pub peer_id: String,
+    /// Kept current by `track_mesh`.
+    pub connections: u32,
+    pub listen_addrs: Vec<String>,
+    /// Kept current by `mesh_warmup`.
+    pub mesh_phase: MeshPhase,
+    pub license_active: bool,
+    pub license_report: Option<LicenseReport>,
+    /// Other nodes' presence beacons, kept current by `presence::run`;
+    /// unset while presence is off.
+    pub presence: Option<PresenceTable>,
+}
+
+/// IPC server tunables.
+pub struct IpcSettings {
+    /// Where to listen.
+    pub endpoint: IpcEndpoint,
+    /// Largest request body accepted, to prevent memory exhaustion.
+    pub max_frame_size: usize,
+    /// Interval at which idle, handshaken connections are probed.
+    pub heartbeat_interval: Duration,
+    /// Consecutive unanswered heartbeats before the connection is dropped.
+    pub max_missed_heartbeats: u32,
+    /// Core transactions untouched for this long are rolled back.
+    pub core_session_idle_timeout: Duration,
+    /// Core namespace each principal is confined to: `token:<name>` or
+    /// `uid:<uid>`, as in core grants. Other principals' core requests are
+    /// not confined. While any are set, a connection with no principal, one
+    /// without peer credentials that presented no token, may not make core
+    /// or WASM requests.
+    pub namespaces: HashMap<String, String>,
+    /// How long open connections get to finish their requests on shutdown.
+    pub shutdown_drain: Duration,
+    /// Per-subsystem budgets for requests.
+    pub request_timeouts: RequestTimeouts,
+    /// Local users other than the node's own that may connect.
+    pub peers: PeerPolicy,
+    /// Enveloped requests one connection may have in flight at once.
+    pub max_concurrent_requests: usize,
+    /// How fast each client may send requests.
+    pub rate_limits: RateLimits,
+    /// Localhost port to serve `/metrics` and `/healthz` on, in builds with
+    /// the `metrics-http` feature.
+    pub metrics_port: Option<u16>,
+    /// Connections open at once. Clients past it get `Response::Busy`.
+    pub max_connections: usize,
+    /// Connections that send no frame for this long are closed, whether or
+    /// not they said Hello.
+    pub connection_idle_timeout: Option<Duration>,
+    /// How long before that close a handshaken client is sent
+    /// `Response::IdleWarning`.
+    pub idle_warning: Option<Duration>,
+    /// What each connection may do.
+    pub access: AccessPolicy,
+    /// Where `NodeStatus::health` turns degraded or critical.
+    pub health: HealthThresholds,
+    /// Where to record every request; off when unset.
+    pub audit: Option<IpcAuditConfig>,
+    /// How long a closed connection's session is kept for the client to
+    /// resume. Zero hands out no sessions.
+    pub session_grace: Duration,
+    /// The config the node started from, for `SelfCheck`.
+    pub config: Option<NodeConfig>,
+    /// Threads for core work and module compiles.
+    pub compute_pool: PoolConfig,
+    /// Requests that take longer are logged and kept for
+    /// `Request::SlowRequests`; none are when unset.
+    pub slow_request_threshold: Option<Duration>,
+    /// Compressions offered to clients that say Hello with binary frames,
+    /// most preferred first; empty compresses nothing.
+    pub compression: Vec<Compression>,
+    /// Frame bodies smaller than this are sent as they are.
+    pub compression_min_size: usize,
+    /// Also serve clients over TCP; local transports only when unset.
+    pub tcp: Option<TcpConfig>,
+    /// The config file and data directory `SetupApply` sets up again;
+    /// it is refused when unset.
+    pub setup: Option<SetupTarget>,
+    /// The listener first-run setup served on, to go on serving on.
+    pub listener: std::sync::Mutex<Option<Box<dyn IpcListener>>>,
+}
+
+impl Default for IpcSettings {
+    fn default() -> Self {
+        Self {
+            endpoint: IpcEndpoint::from_env(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            heartbeat_interval: Duration::from_secs(15),
+            max_missed_heartbeats: 3,
+            core_session_idle_timeout: Duration::from_secs(300),
+            namespaces: HashMap::new(),
+            shutdown_drain: Duration::from_secs(10),
+            request_timeouts: RequestTimeouts::default(),
+            peers: PeerPolicy::default(),
+            max_concurrent_requests: 16,
+            rate_limits: RateLimits::default(),
+            metrics_port: None,
+            max_connections: 256,
+            connection_idle_timeout: Some(Duration::from_secs(600)),
+            idle_warning: Some(Duration::from_secs(30)),
+            access: AccessPolicy::default(),
+            health: HealthThresholds::default(),
+            audit: None,
+            session_grace: Duration::from_secs(60),
+            config: None,
+            compute_pool: PoolConfig { threads: 8, queue: 64 },
+            slow_request_threshold: Some(Duration::from_secs(1)),
+            compression: Compression::ALL.to_vec(),
+            compression_min_size: 8 * 1024,
+            tcp: None,
+            setup: None,
+            listener: std::sync::Mutex::new(None),
+        }
+    }
+}
+
+impl IpcSettings {
+    fn idle_timeout(&self) -> Duration {
+        self.heartbeat_interval * (self.max_missed_heartbeats + 1)
+    }
+}
+
+/// Everything a request handler needs, shared by all connections.
+struct NodeContext {
+    core: Arc<CognitiveCore>,
+    core_queries: CoreQueries,
+    timeouts: KindCounts,
+    /// Requests and traffic, node-wide and per connection.
+    ipc: Arc<IpcStats>,
+    rate_limited: KindCounts,
+    /// Request budgets shared by each other user's connections.
+    users: UserBuckets,
+    accept_failures: AtomicU64,
+    connections: ConnectionCounts,
     wasm: Arc<WasmRuntime>,
+    modules: Arc<ModuleRegistry>,
+    scheduler: Arc<Scheduler>,
+    module_paths: ModulePaths,
+    mesh: mpsc::Sender<MeshCommand>,
+    /// The replication outbox, while replication is on.
+    outbox: Option<Arc<Outbox>>,
+    finance: Arc<FinanceBackend>,
+    /// Runs core work and module compiles off the async workers.
+    compute: Arc<BlockingPool>,
+    /// What the node keeps across restarts.
+    store: Arc<StateStore>,
+    state: Arc<watch::Sender<SharedState>>,
+    /// Events for connections that `Subscribe`.
+    events: Arc<EventBus>,
+    /// What licenses are bound to.
+    identity: MachineIdentity,
+    /// Memory, CPU and disk figures for `NodeStatus`.
+    host: HostProbe,
+    health: HealthThresholds,
+    /// Records requests, if `IpcSettings::audit` is set.
+    audit: Option<IpcAudit>,
+    /// Sessions of closed connections, waiting to be resumed.
+    sessions: Arc<SessionStore>,
+    /// What `SelfCheck` checks; unset, it is refused.
+    config: Option<NodeConfig>,
+    /// What `SetupApply` rewrites; unset, it is refused.
+    setup: Option<SetupTarget>,
+    /// Where snapshots are packed and unpacked.
+    snapshot_spool: PathBuf,
+    /// Read-held by each request while it is handled, and write-held by
+    /// `ImportSnapshot`, which turns other requests away meanwhile.
+    maintenance: Arc<RwLock<()>>,
+    /// What `InjectFault` arms, for the seams to act on.
+    #[cfg(feature = "fault-injection")]
+    faults: Arc<FaultPlan>,
     start_time: SystemTime,
+}
+
+impl NodeContext {
+    fn snapshot_target(&self) -> SnapshotTarget {
+        SnapshotTarget {
+            core: self.core.clone(),
+            modules: self.modules.clone(),
+            scheduler: self.scheduler.clone(),
+            store: self.store.clone(),
+            config_path: self.setup.as_ref().map(|setup| setup.config_path.clone()),
+            spool: self.snapshot_spool.clone(),
+        }
+    }
+}
+
+/// Counters behind `IpcConnectionStats`.
+#[derive(Default)]
+struct ConnectionCounts {
+    open: AtomicU64,
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    idled_out: AtomicU64,
+}
+
+impl ConnectionCounts {
+    fn snapshot(&self) -> IpcConnectionStats {
+        IpcConnectionStats {
+            open: self.open.load(Ordering::Relaxed),
+            accepted: self.accepted.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            idled_out: self.idled_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The WASM runtime and the registry and scheduler built on it.
+pub struct WasmServices {
+    pub runtime: Arc<WasmRuntime>,
+    pub modules: Arc<ModuleRegistry>,
+    pub scheduler: Arc<Scheduler>,
+    /// Where `RunWasm` may read modules by path.
+    pub paths: ModulePaths,
+}
+
+/// The mesh's settings, its command channel, what it does at startup, the
+/// core relations replicated over it and its presence beacons.
+pub struct MeshServices {
+    pub config: MeshConfig,
+    pub commands: (mpsc::Sender<MeshCommand>, mpsc::Receiver<MeshCommand>),
+    pub warmup: MeshWarmup,
+    pub replication: Option<ReplicationConfig>,
+    pub presence: Option<PresenceConfig>,
+}
+
+/// The license verifier and how often it re-checks the node's license.
+pub struct FinanceServices {
+    pub backend: Arc<FinanceBackend>,
+    pub recheck: LicenseRecheck,
+}
+
+/// The node's locked data directory, and when the node started.
+pub struct NodeHome<'a> {
+    pub data_dir: &'a DataDir,
+    pub start_time: SystemTime,
+}
+
+pub async fn run_ipc_server(
+    core: Arc<CognitiveCore>,
+    WasmServices { runtime: wasm, modules, scheduler, paths: module_paths }: WasmServices,
+    MeshServices { config: mesh_config, commands: (mesh_tx, mesh_rx), warmup, replication, presence }: MeshServices,
+    FinanceServices { backend: finance, recheck }: FinanceServices,
+    NodeHome { data_dir, start_time }: NodeHome<'_>,
+    settings: IpcSettings,
+    stop: impl Future<Output = std::io::Result<()>> + Send,
 ) -> Result<()> {
-    // 1. Hardware Identity
-    let machine_id = machine_uid::get().unwrap_or_else(|_| "fallback-id".into());
-    info!("Sovereign Agent ID: {}", machine_id);
+    // 1. Hardware Identity, as a salted hash: the machine uid is never shown.
+    let identity = MachineIdentity::load(&data_dir.install_salt());
+    match &identity {
+        MachineIdentity::Bound(id) => info!("Machine binding id: {}", id),
+        MachineIdentity::Unbindable(reason) => warn!("Licenses cannot be bound to this install: {}", reason),
+    }
 
-    let state = Arc::new(RwLock::new(SharedState {
+    // The last license check, from before a restart.
+    let store = Arc::new(StateStore::open(&data_dir.state()));
+    let license = store.get().license;
+    let state = Arc::new(watch::Sender::new(SharedState {
         peer_id: "Initializing...".into(),
         connections: 0,
-        license_active: false,
+        listen_addrs: Vec::new(),
+        mesh_phase: MeshPhase::WarmingUp,
+        license_active: identity.id().is_some() && license.as_ref().is_some_and(|l| l.active(unix_millis(), recheck.offline_grace)),
+        license_report: license.map(|l| l.report),
+        presence: presence.as_ref().map(PresenceTable::new),
     }));
 
     // 2. Start Mesh Actor
-    let (mesh_tx, mesh_rx) = mpsc::channel(32);
-    let key_path = std::path::Path::new("swarm.key");
+    let key_path = mesh_config.psk_path.as_path();
 
     // Generate dev key if missing
     if !key_path.exists() {
@@ -42,142 +389,4176 @@ pub async fn run_ipc_server(
         std::fs::write(key_path, dev_key).ok();
     }
 
-    let mesh_node = MeshNode::new(key_path, mesh_rx)?;
-    tokio::spawn(mesh_node.run());
+    let redial = {
+        // Weak, so the supervisor's own hook does not keep its command
+        // channel open.
+        let mesh = mesh_tx.downgrade();
+        let (state, store) = (state.clone(), store.clone());
+        // The supervisor rejoins the topics itself.
+        let warmup = MeshWarmup {
+            topics: Vec::new(),
+            ..warmup.clone()
+        };
+        move || {
+            if let Some(mesh) = mesh.upgrade() {
+                tokio::spawn(mesh_warmup::run(mesh, state.clone(), store.get().peers, warmup.clone()));
+            }
+        }
+    };
+    #[cfg(feature = "fault-injection")]
+    let faults = Arc::new(FaultPlan::default());
+    #[cfg(feature = "fault-injection")]
+    {
+        warn!("This node is built with fault injection; it is not fit for production");
+        finance.inject_from(faults.clone());
+    }
+    let mesh_keys = mesh_supervisor::start(
+        mesh_config,
+        mesh_rx,
+        state.clone(),
+        RestartPolicy::default(),
+        #[cfg(feature = "fault-injection")]
+        faults.clone(),
+        redial,
+    )?;
+
+    // What presence beacons advertise beside the configured capabilities.
+    let mut capabilities = vec!["core".to_string(), "wasm".to_string()];
+    if replication.is_some() {
+        capabilities.push("replication".into());
+    }
+    if settings.tcp.is_some() {
+        capabilities.push("tcp-ipc".into());
+    }
+    let outbox = match replication {
+        Some(config) => Some(sovereign_replication::start(core.clone(), mesh_tx.clone(), mesh_keys.clone(), config).await?),
+        None => None,
+    };
+    tokio::spawn(mesh_warmup::run(mesh_tx.clone(), state.clone(), store.get().peers, warmup));
+
+    let ctx = Arc::new(NodeContext {
+        core,
+        core_queries: CoreQueries::default(),
+        timeouts: KindCounts::default(),
+        ipc: Arc::new(IpcStats::new(settings.slow_request_threshold)),
+        rate_limited: KindCounts::default(),
+        users: UserBuckets::default(),
+        accept_failures: AtomicU64::new(0),
+        connections: ConnectionCounts::default(),
+        wasm,
+        modules,
+        scheduler,
+        module_paths,
+        mesh: mesh_tx,
+        outbox,
+        finance,
+        compute: Arc::new(BlockingPool::new("compute", settings.compute_pool)?),
+        store,
+        state,
+        events: Arc::new(EventBus::new()),
+        identity,
+        host: HostProbe::new(data_dir.root()),
+        health: settings.health.clone(),
+        audit: settings.audit.clone().map(IpcAudit::open).transpose()?,
+        sessions: Arc::new(SessionStore::new(settings.session_grace)),
+        config: settings.config.clone(),
+        setup: settings.setup.clone(),
+        snapshot_spool: data_dir.snapshot_spool(),
+        maintenance: Arc::new(RwLock::new(())),
+        #[cfg(feature = "fault-injection")]
+        faults,
+        start_time,
+    });
+    let settings = Arc::new(settings);
+
+    // 3. IPC Loop: a Unix socket, or a named pipe on Windows
+    let handed_over = settings.listener.lock().unwrap_or_else(|e| e.into_inner()).take();
+    let mut listener = match handed_over {
+        Some(listener) => {
+            info!("IPC server listening on {}, where setup was served", settings.endpoint);
+            listener
+        }
+        None => match ipc_transport::activated()? {
+            Some(listener) => {
+                info!("IPC server listening on the socket systemd passed in");
+                listener
+            }
+            None => {
+                let listener = ipc_transport::bind(&settings.endpoint)?;
+                info!("IPC server listening on {}", settings.endpoint);
+                listener
+            }
+        },
+    };
+    let mut tcp_listener: Option<Box<dyn IpcListener>> = match &settings.tcp {
+        Some(tcp) => {
+            let listener = TcpIpcListener::bind(tcp).await?;
+            match tcp.security {
+                TcpSecurity::Tls { .. } => info!("IPC server listening on TCP {} with TLS", tcp.addr),
+                TcpSecurity::Plaintext => info!("IPC server listening on TCP {} in plaintext", tcp.addr),
+            }
+            Some(Box::new(listener))
+        }
+        None => None,
+    };
+    // Migrations ran before the core was handed over, so clients can be
+    // served from here on; tell the service manager, if any.
+    sd_notify::notify(&[("READY", "1"), ("STATUS", "Serving IPC")]);
+    service_state::ready();
+
+    // Once the mesh knows its peer id: advertise what we actually bound so
+    // clients don't have to guess, and hand the id to modules.
+    let endpoint = settings.endpoint.clone();
+    let data_dir = data_dir.root().to_path_buf();
+    let wasm = ctx.wasm.clone();
+    let machine_hash = ctx.identity.id().map(sovereign_finance::binding_payload_hex);
+    tokio::spawn(event_bus::publish_license(ctx.state.subscribe(), ctx.events.clone()));
+    tokio::spawn(event_bus::publish_jobs(ctx.scheduler.clone(), ctx.events.clone()));
+    tokio::spawn(track_mesh(ctx.mesh.clone(), ctx.state.clone(), ctx.events.clone(), move |peer_id: &str| {
+        // Node-provided variables, injected into modules whose manifest allows them.
+        let mut env = vec![("SOVEREIGN_PEER_ID".into(), peer_id.to_string())];
+        if let Some(hash) = machine_hash {
+            env.push(("SOVEREIGN_MACHINE_HASH".into(), hash));
+        }
+        wasm.set_node_env(env);
+        if let Err(e) = EndpointDiscovery::new(endpoint, peer_id.to_string()).write(&data_dir) {
+            warn!("Failed to write endpoint discovery file to {}: {}", data_dir.display(), e);
+        }
+    }));
+    if let Some(mut config) = presence {
+        for capability in std::mem::replace(&mut config.capabilities, capabilities) {
+            if !config.capabilities.contains(&capability) {
+                config.capabilities.push(capability);
+            }
+        }
+        let status = {
+            let ctx = ctx.clone();
+            move || {
+                let status = node_status(&ctx, &ctx.core.stats());
+                (status.uptime_ms, status.system_health)
+            }
+        };
+        tokio::spawn(presence::run(config, ctx.mesh.clone(), mesh_keys, ctx.state.clone(), ctx.events.clone(), status));
+    }
+
+    let own_uid = ipc_transport::own_uid();
+    let signal = stop;
+    tokio::pin!(signal);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    if let Some(machine_id) = ctx.identity.id() {
+        tokio::spawn(license_monitor::run(
+            ctx.finance.clone(),
+            ctx.state.clone(),
+            machine_id.to_string(),
+            ctx.store.clone(),
+            recheck,
+            shutdown_rx.clone(),
+        ));
+    }
+    if let Some(outbox) = &ctx.outbox {
+        tokio::spawn(replica_outbox::run(outbox.clone(), ctx.compute.clone(), ctx.mesh.clone(), shutdown_rx.clone()));
+    }
+    #[cfg(feature = "metrics-http")]
+    if let Some(port) = settings.metrics_port {
+        tokio::spawn(metrics_http::serve(port, ctx.clone(), shutdown_rx.clone()));
+    }
+    #[cfg(not(feature = "metrics-http"))]
+    if settings.metrics_port.is_some() {
+        warn!("metrics_port is set, but this build lacks the metrics-http feature. Not serving metrics.");
+    }
+    if let Some(interval) = sd_notify::watchdog_interval() {
+        tokio::spawn(feed_watchdog(ctx.clone(), interval, shutdown_rx.clone()));
+    }
+    let mut connections = JoinSet::new();
+    // Set when the listener breaks, so the node still shuts down cleanly.
+    let mut failure = None;
+    loop {
+        tokio::select! {
+            accepted = accept_next(listener.as_mut(), tcp_listener.as_deref_mut(), &ctx.accept_failures) => {
+                let accepted = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        failure = Some(e);
+                        break;
+                    }
+                };
+                if !settings.peers.admits(own_uid, accepted.peer.as_ref()) {
+                    if let Some(peer) = &accepted.peer {
+                        warn!("Refused IPC connection from {}: not the node's user or an allowed uid or gid", peer);
+                    }
+                    continue;
+                }
+                if connections.len() >= settings.max_connections {
+                    ctx.connections.rejected.fetch_add(1, Ordering::Relaxed);
+                    warn!("Refused an IPC connection: {} are open, the limit", connections.len());
+                    tokio::spawn(turn_away(accepted.stream, settings.max_connections));
+                    continue;
+                }
+                ctx.connections.accepted.fetch_add(1, Ordering::Relaxed);
+                connections.spawn(handle_connection(accepted.stream, accepted.peer, accepted.remote, ctx.clone(), settings.clone(), shutdown_rx.clone()));
+                ctx.connections.open.store(connections.len() as u64, Ordering::Relaxed);
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {
+                ctx.connections.open.store(connections.len() as u64, Ordering::Relaxed);
+            }
+            received = &mut signal => {
+                received?;
+                break;
+            }
+        }
+    }
 
-    // Cache PeerID
-    let (pid_tx, pid_rx) = oneshot::channel();
-    let _ = mesh_tx.send(MeshCommand::GetPeerId(pid_tx)).await;
-    if let Ok(pid) = pid_rx.await {
-        if let Ok(mut s) = state.write() {
-            s.peer_id = pid;
+    // Dropping the listener removes the socket file.
+    drop(listener);
+    drop(tcp_listener);
+    info!("Shutting down: draining {} IPC connection(s)", connections.len());
+    sd_notify::notify(&[("STOPPING", "1"), ("STATUS", "Shutting down")]);
+    let _ = shutdown_tx.send(true);
+    let teardown = async {
+        let drain = async { while connections.join_next().await.is_some() {} };
+        if tokio::time::timeout(settings.shutdown_drain, drain).await.is_err() {
+            warn!("{} IPC connection(s) still busy after {:?}. Dropping them.", connections.len(), settings.shutdown_drain);
+            connections.abort_all();
+        }
+        ctx.store.flush();
+        let (done_tx, done_rx) = oneshot::channel();
+        if ctx.mesh.send(MeshCommand::Shutdown(done_tx)).await.is_ok() {
+            let _ = done_rx.await;
         }
+    };
+    tokio::select! {
+        _ = teardown => {}
+        _ = shutdown::signalled() => {
+            warn!("Second signal during shutdown. Exiting now.");
+            std::process::exit(shutdown::FORCED_EXIT_CODE);
+        }
+    }
+    info!("IPC server stopped");
+    match failure {
+        Some(e) => Err(anyhow::Error::new(e).context("IPC listener failed")),
+        None => Ok(()),
     }
+}
 
-    // 3. Start Finance Actor (The Verifier)
-    // We wrap it in Arc to share across threads.
-    let finance = Arc::new(LicenseVerifier::new("ssl://electrum.blockstream.info:50002", "bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh", 50000)?);
+/// Sends systemd's watchdog `WATCHDOG=1` every `interval` while the node's
+/// health is short of critical. A critical node stops feeding it, so systemd
+/// restarts it.
+async fn feed_watchdog(ctx: Arc<NodeContext>, interval: Duration, mut shutdown: watch::Receiver<bool>) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.wait_for(|stop| *stop) => return,
+        }
+        let core = ctx.core.stats();
+        match node_status(&ctx, &core).health {
+            HealthLevel::Critical { reasons } => {
+                warn!("Node health is critical ({}). Not feeding the systemd watchdog.", reasons.join("; "));
+                sd_notify::notify(&[("STATUS", &format!("Critical: {}", reasons.join("; ")))]);
+            }
+            level => {
+                sd_notify::notify(&[("WATCHDOG", "1"), ("STATUS", &format!("Serving IPC, health {}", level.summary()))]);
+            }
+        }
+    }
+}
 
-    // 4. IPC Loop using Unix socket on macOS
-    let socket_path = "/tmp/sovereign-node.sock";
-    let _ = std::fs::remove_file(socket_path); // Remove old socket if exists
-    let listener = UnixListener::bind(socket_path)?;
-    info!("IPC server listening on Unix socket: {}", socket_path);
+/// The next client from the local listener or, if there is one, the TCP
+/// listener.
+async fn accept_any<'a>(local: &'a mut (dyn IpcListener + 'static), tcp: Option<&'a mut (dyn IpcListener + 'static)>) -> std::io::Result<Accepted> {
+    match tcp {
+        Some(tcp) => tokio::select! {
+            accepted = local.accept() => accepted,
+            accepted = tcp.accept() => accepted,
+        },
+        None => local.accept().await,
+    }
+}
 
+/// The next client from either listener. Failed accepts are counted in
+/// `failures` and ridden out, pausing with a doubling backoff while the
+/// process is out of resources; only a broken listener's error is returned.
+async fn accept_next(
+    local: &mut (dyn IpcListener + 'static),
+    mut tcp: Option<&mut (dyn IpcListener + 'static)>,
+    failures: &AtomicU64,
+) -> std::io::Result<Accepted> {
+    let mut backoff = ACCEPT_BACKOFF_MIN;
     loop {
-        let (mut stream, _) = listener.accept().await?;
-        let core = core.clone();
-        let wasm_clone = wasm.clone();
-        let mesh = mesh_tx.clone();
-        let finance = finance.clone();
-        let state = state.clone();
-        let m_id = machine_id.clone();
-        let start = start_time;
+        let e = match accept_any(local, tcp.as_deref_mut()).await {
+            Ok(accepted) => return Ok(accepted),
+            Err(e) => e,
+        };
+        failures.fetch_add(1, Ordering::Relaxed);
+        match AcceptFailure::classify(&e) {
+            AcceptFailure::Transient => debug!("Failed to accept an IPC client: {}", e),
+            AcceptFailure::Exhausted => {
+                warn!("Failed to accept an IPC client: {}. Retrying in {:?}.", e, backoff);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+            }
+            AcceptFailure::Fatal => {
+                error!("The IPC listener failed: {}. Shutting down.", e);
+                return Err(e);
+            }
+        }
+    }
+}
 
-        tokio::spawn(async move {
-            let mut len_buf = [0u8; 4];
-            loop {
-                if stream.read_exact(&mut len_buf).await.is_err() {
-                    break;
+/// Tells a client that connected past `IpcSettings::max_connections` why
+/// it is turned away, then closes. A client that does not read gets a
+/// second, then is dropped.
+async fn turn_away(mut stream: ipc_transport::BoxedStream, max_connections: usize) {
+    let busy = Response::Busy {
+        max_connections: max_connections as u64,
+    };
+    // Clients speak first, so how this one frames its messages is known
+    // before the answer, unless it is slow to say anything.
+    let framing = match tokio::time::timeout(FRAMING_WAIT, detect_framing(&mut stream)).await {
+        Ok(Ok((framing, _))) => framing,
+        _ => Framing::Binary,
+    };
+    let mut stream = Wire::new(stream, framing);
+    let _ = tokio::time::timeout(Duration::from_secs(1), async {
+        let _ = write_frame(&mut stream, &busy).await;
+        let _ = stream.inner.shutdown().await;
+    })
+    .await;
+}
+
+/// Asks the mesh for its peer id until it answers, hands the id to
+/// `on_peer_id`, then keeps the connection count and listen addresses in
+/// `state` current from the mesh's events, publishing each on `events`.
+/// Ends once the mesh is gone.
+async fn track_mesh(mesh: mpsc::Sender<MeshCommand>, state: Arc<watch::Sender<SharedState>>, events: Arc<EventBus>, on_peer_id: impl FnOnce(&str)) {
+    let mut backoff = Duration::from_millis(100);
+    let peer_id = loop {
+        let (tx, rx) = oneshot::channel();
+        if mesh.send(MeshCommand::GetPeerId(tx)).await.is_err() {
+            warn!("The mesh stopped before reporting its peer id");
+            return;
+        }
+        match tokio::time::timeout(backoff.max(Duration::from_secs(1)), rx).await {
+            Ok(Ok(peer_id)) => break peer_id,
+            Ok(Err(_)) => {
+                warn!("The mesh stopped before reporting its peer id");
+                return;
+            }
+            Err(_) => {
+                debug!("The mesh has not reported its peer id yet. Retrying in {:?}.", backoff);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(30));
+            }
+        }
+    };
+    state.send_modify(|s| s.peer_id = peer_id.clone());
+    on_peer_id(&peer_id);
+
+    // The events end when the mesh actor does. A restarted one is
+    // subscribed to again; the command channel closes only at shutdown.
+    loop {
+        let (events_tx, mut mesh_events) = mpsc::unbounded_channel();
+        if mesh.send(MeshCommand::SubscribeEvents(events_tx)).await.is_err() {
+            return;
+        }
+        let mut peers = HashSet::new();
+        while let Some(event) = mesh_events.recv().await {
+            match event {
+                MeshEvent::PeerConnected(peer) => {
+                    peers.insert(peer.clone());
+                    events.publish(NodeEvent::PeerConnected { peer });
+                }
+                MeshEvent::PeerDisconnected(peer) => {
+                    peers.remove(&peer);
+                    events.publish(NodeEvent::PeerDisconnected { peer });
+                }
+                MeshEvent::ListenersChanged(addrs) => {
+                    state.send_modify(|s| s.listen_addrs = addrs.clone());
+                    events.publish(NodeEvent::ListenersChanged { addrs });
+                    continue;
                 }
-                let len = u32::from_le_bytes(len_buf) as usize;
+            }
+            state.send_modify(|s| s.connections = peers.len() as u32);
+        }
+        for peer in peers {
+            events.publish(NodeEvent::PeerDisconnected { peer });
+        }
+        state.send_modify(|s| {
+            s.connections = 0;
+            s.listen_addrs.clear();
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
 
-                // B. Security Check: Allocation Limit
-                const MAX_IPC_MSG_SIZE: usize = 64 * 1024; // 64 KB Limit to prevent memory exhaustion
-                if len > MAX_IPC_MSG_SIZE {
-                    error!("Security Violation: IPC client requested {} bytes. Dropping.", len);
-                    break;
+/// Drives one client connection: a reader task feeds complete frames into the
+/// loop below, which interleaves request handling with heartbeat probes.
+/// Enveloped requests handled off the connection run concurrently, up to
+/// `IpcSettings::max_concurrent_requests`, and are answered as they finish;
+/// at the limit no more frames are read, which backs up into the reader.
+/// Bare requests, and those that need the connection itself, are handled in
+/// turn. Generic over the stream, so every transport shares this one loop.
+/// A TCP client, from `remote`, holds no permissions until it says Hello
+/// with a token, and may send nothing else first.
+/// Once `shutdown` turns true it stops reading, answers what is in flight
+/// and closes; closing otherwise cancels what is in flight.
+async fn handle_connection<S>(
+    stream: S,
+    peer: Option<PeerCred>,
+    remote: Option<SocketAddr>,
+    ctx: Arc<NodeContext>,
+    settings: Arc<IpcSettings>,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let stats = Arc::new(ctx.ipc.open(connection_id, &format!("connection-{}", connection_id), peer.map(|peer| peer.uid)));
+    let (reader, writer) = tokio::io::split(stats.meter(stream));
+    // Binary until the client's first bytes say otherwise.
+    let mut writer = Wire::new(writer, Framing::Binary);
+    #[cfg(feature = "fault-injection")]
+    {
+        writer.faults = Some(ctx.faults.clone());
+    }
+
+    let (reader_task, mut frame_rx, mut framing_rx) = read_frames(reader, settings.max_frame_size);
+
+    let mut ticker = tokio::time::interval(settings.heartbeat_interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker.tick().await; // The first tick completes immediately.
+
+    // Heartbeats are only sent once the client has said Hello, so legacy
+    // clients never see an unsolicited frame.
+    let mut handshaken = false;
+    let mut idle = true;
+    let mut unacked = 0u32;
+    let mut seq = 0u64;
+
+    // Executions are admitted per client, so one client can't hold every slot.
+    let mut client = Caller {
+        name: format!("connection-{}", connection_id),
+        peer,
+        principal: access::user_principal(peer.as_ref()),
+    };
+    if let Some(peer) = &client.peer {
+        debug!("IPC {} opened by {}", client.name, peer);
+    }
+    if let Some(remote) = remote {
+        debug!("IPC {} opened over TCP from {}", client.name, remote);
+    }
+    let user = client
+        .peer
+        .filter(|peer| peer.uid != ipc_transport::own_uid())
+        .map(|peer| ctx.users.for_uid(peer.uid, &settings.rate_limits));
+    let mut limiter = ConnectionLimiter::new(&settings.rate_limits, user);
+    // From `IpcSettings::namespaces`, by the user's principal until Hello
+    // presents a token.
+    let mut namespace = confinement(&settings, &client);
+    // The user's, until Hello presents a token; none over TCP.
+    let mut permissions = match remote {
+        Some(_) => PermissionSet::default(),
+        None => settings.access.for_peer(client.peer.as_ref()),
+    };
+    // Names the token Hello presented, in audit entries.
+    let mut token_id: Option<String> = None;
+
+    let mut sessions = CoreSessions::new(settings.core_session_idle_timeout);
+    let mut watches = CoreWatches::new();
+    // Set by WatchLicense, with the license state last pushed.
+    let mut license_watch: Option<(watch::Receiver<SharedState>, bool)> = None;
+    let mut subscription = Subscription::default();
+    // Set by Hello: the session's id and resumption token.
+    let mut session: Option<(u64, String)> = None;
+    // Events to send again once a resumed session's HelloAck is written.
+    let mut replay: Vec<Response> = Vec::new();
+    // Enveloped requests being handled, each yielding its id and response.
+    let mut in_flight = JoinSet::new();
+    let mut draining = false;
+
+    // Fires `warn_before` ahead of the idle close, then at it. Any frame
+    // from the client starts it over.
+    let idle_limit = settings.connection_idle_timeout;
+    let warn_before = settings.idle_warning.zip(idle_limit).map_or(Duration::ZERO, |(warning, limit)| warning.min(limit));
+    let until_warning = idle_limit.map_or(NEVER_IDLE, |limit| limit - warn_before);
+    let idle_timer = tokio::time::sleep(until_warning);
+    tokio::pin!(idle_timer);
+    let mut idle_warned = false;
+
+    loop {
+        tokio::select! {
+            frame = frame_rx.recv(), if in_flight.len() < settings.max_concurrent_requests => {
+                let Some(frame) = frame else { break };
+                if let Ok(framing) = framing_rx.try_recv() {
+                    if framing == Framing::JsonLines {
+                        debug!("IPC {} speaks JSON lines", client.name);
+                    }
+                    writer.framing = framing;
                 }
+                idle = false;
+                unacked = 0;
+                idle_warned = false;
+                idle_timer.as_mut().reset(tokio::time::Instant::now() + until_warning);
+
+                let buf = match frame {
+                    InboundFrame::Request(buf) => buf,
+                    InboundFrame::Data(_) => {
+                        debug!("Ignoring data frame outside a streamed request");
+                        continue;
+                    }
+                    InboundFrame::TooLarge { declared, fatal } => {
+                        let resp = Response::FrameTooLarge {
+                            declared: declared as u64,
+                            max: settings.max_frame_size as u64,
+                        };
+                        if write_frame(&mut writer, &resp).await.is_err() || fatal {
+                            break;
+                        }
+                        continue;
+                    }
+                    InboundFrame::Broken(reason) => {
+                        debug!("IPC {}: {}. Dropping connection.", client.name, reason);
+                        let _ = write_frame(&mut writer, &Response::Error(reason)).await;
+                        break;
+                    }
+                };
+
+                // A body that is not a request is answered, and the next frame
+                // read as usual: the length prefix kept the stream in sync.
+                let id = envelope_id(&buf);
+                let parsed = match id {
+                    Some(_) => serde_json::from_slice::<RequestEnvelope>(&buf).map(|envelope| envelope.request),
+                    None => serde_json::from_slice::<Request>(&buf),
+                };
+                let req = match parsed {
+                    Ok(r) => r,
+                    Err(e) => {
+                        let offset = byte_offset(&buf, e.line(), e.column());
+                        debug!("IPC {} sent an invalid request at byte {}: {}", client.name, offset, e);
+                        let resp = Response::Error(format!("Invalid request at byte {}: {}", offset, e));
+                        if write_reply(&mut writer, id, resp).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                let audited = ctx.audit.as_ref().filter(|_| !matches!(req, Request::HeartbeatAck { .. })).map(|audit| {
+                    let principal = Principal {
+                        connection_id,
+                        client: &client.name,
+                        uid: client.peer.map(|peer| peer.uid),
+                        token_id: token_id.as_deref(),
+                    };
+                    audit.start(principal, &req)
+                });
+                let timing = Some(ctx.ipc.start(&req)).filter(|_| !matches!(req, Request::HeartbeatAck { .. }));
 
-                let mut buf = vec![0u8; len];
-                if stream.read_exact(&mut buf).await.is_err() {
+                if remote.is_some() && token_id.is_none() && !matches!(req, Request::Hello { .. }) {
+                    warn!("IPC {} over TCP sent {} before Hello with a token. Dropping connection.", client.name, req.kind());
+                    let resp = Response::Error("A TCP connection must say Hello with an access token first".into());
+                    ipc_audit::finish(audited, &resp);
+                    ipc_stats::finish(&stats, timing, &resp);
+                    let _ = write_reply(&mut writer, id, resp).await;
                     break;
                 }
 
-                let req: Request = match serde_json::from_slice(&buf) {
-                    Ok(r) => r,
-                    Err(_) => continue,
+                if !matches!(req, Request::HeartbeatAck { .. }) {
+                    if let Err(wait) = limiter.check(req.kind()) {
+                        ctx.rate_limited.record(req.kind());
+                        let resp = Response::RateLimited {
+                            kind: req.kind().into(),
+                            retry_after_ms: wait.as_millis().max(1) as u64,
+                        };
+                        ipc_audit::finish(audited, &resp);
+                        ipc_stats::finish(&stats, timing, &resp);
+                        if write_reply(&mut writer, id, resp).await.is_err() {
+                            break;
+                        }
+                        if limiter.abusive() {
+                            warn!("IPC {} keeps exceeding its request budget. Dropping connection.", client.name);
+                            break;
+                        }
+                        continue;
+                    }
+                }
+
+                if let Some(permission) = access::required(&req).filter(|p| !permissions.allows(*p)) {
+                    debug!("IPC {} lacks {} for {}", client.name, permission.name(), req.kind());
+                    let resp = Response::PermissionDenied {
+                        kind: req.kind().into(),
+                        permission,
+                    };
+                    ipc_audit::finish(audited, &resp);
+                    ipc_stats::finish(&stats, timing, &resp);
+                    if write_reply(&mut writer, id, resp).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                if client.principal.is_none() && !settings.namespaces.is_empty() && access::required(&req).is_some_and(access::namespaced) {
+                    let resp = Response::CoreFailed(CoreFailure {
+                        code: ErrorCode::NamespaceDenied,
+                        message: "The node confines clients to core namespaces by who they are, and this connection has not said; present an access token in Hello".into(),
+                        line: None,
+                        column: None,
+                    });
+                    ipc_audit::finish(audited, &resp);
+                    ipc_stats::finish(&stats, timing, &resp);
+                    if write_reply(&mut writer, id, resp).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+
+                // While a snapshot is imported, requests are turned away
+                // rather than queued behind it.
+                let paused = match &req {
+                    Request::HeartbeatAck { .. } | Request::Hello { .. } | Request::Ping | Request::ImportSnapshot { .. } => None,
+                    _ => match ctx.maintenance.clone().try_read_owned() {
+                        Ok(paused) => Some(paused),
+                        Err(_) => {
+                            let resp = Response::Unavailable {
+                                subsystem: "node".into(),
+                                reason: "The node is importing a snapshot".into(),
+                            };
+                            ipc_audit::finish(audited, &resp);
+                            ipc_stats::finish(&stats, timing, &resp);
+                            if write_reply(&mut writer, id, resp).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                    },
                 };
 
                 let resp = match req {
-                    Request::GetStatus => {
-                        let s = state.read().unwrap();
-                        Response::Status(NodeStatus {
-                            uptime_ms: SystemTime::now().duration_since(start).unwrap().as_millis()
-                                as u64,
-                            mesh_peer_id: s.peer_id.clone(),
-                            mesh_connections: s.connections,
-                            license_active: s.license_active,
-                            system_health: "OK".into(),
-                        })
-                    }
-                    Request::QueryCore { query, params } => {
-                        let mut c = core.lock().await;
-                        match c.run(&query, params) {
-                            Ok(val) => Response::CoreResult(val),
-                            Err(e) => Response::Error(e.to_string()),
+                    Request::HeartbeatAck { .. } => {
+                        // The client hears nothing back, so an ack must not
+                        // put off the next heartbeat past its idle timeout.
+                        idle = true;
+                        continue;
+                    }
+                    Request::Hello { client_name, protocol_version, token, resume, compressions_supported } => {
+                        if let Some(token) = token {
+                            let Some((granted, principal)) = settings.access.for_token(&token) else {
+                                warn!("IPC {} presented an unknown access token. Dropping connection.", client.name);
+                                let resp = Response::Error("Unknown access token".into());
+                                ipc_audit::finish(audited, &resp);
+                                ipc_stats::finish(&stats, timing, &resp);
+                                let _ = write_reply(&mut writer, id, resp).await;
+                                break;
+                            };
+                            permissions = granted;
+                            client.principal = Some(principal);
+                            token_id = Some(access::token_id(&token));
+                        } else if remote.is_some() {
+                            warn!("IPC {} over TCP said Hello without an access token. Dropping connection.", client.name);
+                            let resp = Response::Error("A TCP connection must present an access token".into());
+                            ipc_audit::finish(audited, &resp);
+                            ipc_stats::finish(&stats, timing, &resp);
+                            let _ = write_reply(&mut writer, id, resp).await;
+                            break;
+                        }
+                        match (&client.peer, remote) {
+                            (Some(peer), _) => info!("IPC client '{}' connected ({}, protocol v{})", client_name, peer, protocol_version),
+                            (None, Some(remote)) => info!("IPC client '{}' connected over TCP from {} (protocol v{})", client_name, remote, protocol_version),
+                            (None, None) => info!("IPC client '{}' connected (protocol v{})", client_name, protocol_version),
+                        }
+                        handshaken = true;
+                        let uid = client.peer.map(|peer| peer.uid);
+                        // A connection keeps the session it already has.
+                        let resumed = resume
+                            .filter(|_| session.is_none())
+                            .and_then(|resume| Some((ctx.sessions.resume(resume.session_id, &resume.token, uid)?, resume)));
+                        let resumed = match resumed {
+                            Some((state, resume)) => {
+                                info!("IPC client '{}' resumed session {}", state.client_name, resume.session_id);
+                                client.name = state.client_name;
+                                namespace = state.namespace;
+                                permissions = state.permissions;
+                                client.principal = state.principal;
+                                token_id = state.token_id;
+                                subscription = state.subscription;
+                                watches = state.watches;
+                                license_watch = state.license_watch;
+                                replay = subscription.replay_after(resume.last_seq);
+                                session = Some((resume.session_id, resume.token));
+                                true
+                            }
+                            None => {
+                                if !client_name.is_empty() {
+                                    client.name = client_name;
+                                }
+                                namespace = confinement(&settings, &client);
+                                if session.is_none() {
+                                    session = ctx.sessions.issue();
+                                }
+                                false
+                            }
+                        };
+                        stats.rename(&client.name);
+                        // Lines carry text, so only binary frames are compressed.
+                        writer.compression = Compression::pick(&settings.compression, &compressions_supported)
+                            .filter(|_| writer.framing == Framing::Binary)
+                            .map(|algorithm| FrameCompression {
+                                algorithm,
+                                min_size: settings.compression_min_size as u64,
+                            });
+                        Response::HelloAck {
+                            protocol_version: PROTOCOL_VERSION,
+                            heartbeat_interval_ms: settings.heartbeat_interval.as_millis() as u64,
+                            idle_timeout_ms: settings.idle_timeout().as_millis() as u64,
+                            max_frame_size: settings.max_frame_size as u64,
+                            build: Some(build_info()),
+                            session: session.as_ref().map(|(session_id, token)| SessionGrant {
+                                session_id: *session_id,
+                                token: token.clone(),
+                                resumed,
+                                last_seq: subscription.last_seq(),
+                                grace_ms: ctx.sessions.grace().as_millis() as u64,
+                            }),
+                            compression: writer.compression,
+                        }
+                    }
+                    req @ (Request::CoreBegin | Request::CoreExec { .. } | Request::CoreRollback { .. }) => {
+                        let kind = req.kind();
+                        let handled = sessions.handle(&ctx.compute, &ctx.core, &ctx.core_queries, req, &client, namespace.as_deref());
+                        timed(&ctx, kind, &client, "core", settings.request_timeouts.core, handled).await
+                    }
+                    // Not timed: a commit given up on would leave the client
+                    // not knowing whether it applied.
+                    req @ Request::CoreCommit { .. } => {
+                        sessions.handle(&ctx.compute, &ctx.core, &ctx.core_queries, req, &client, namespace.as_deref()).await
+                    }
+                    Request::CoreWatch { relation, filter } => match permitted(&ctx.core, &client, namespace.as_deref(), relation, false) {
+                        Ok(relation) => watches.watch(&ctx.core, &relation, filter.as_deref()),
+                        Err(e) => core_failed(e),
+                    },
+                    Request::CoreUnwatch { watch_id } => watches.unwatch(watch_id),
+                    Request::Subscribe { topics } => subscription.subscribe(&ctx.events, topics),
+                    Request::Unsubscribe { topics } => subscription.unsubscribe(topics),
+                    Request::WatchLicense => {
+                        let updates = ctx.state.subscribe();
+                        let active = updates.borrow().license_active;
+                        license_watch = Some((updates, active));
+                        handle_request(&ctx, Request::GetLicenseInfo, &client, namespace.as_deref()).await
+                    }
+                    Request::CoreExport { name, format } => match permitted(&ctx.core, &client, namespace.as_deref(), name, false) {
+                        Ok(name) => match core_stream::export(&ctx.compute, ctx.core.clone(), name, format, &mut writer).await {
+                            Ok(resp) => resp,
+                            Err(e) => {
+                                warn!("Core export aborted: {}. Dropping connection.", e);
+                                break;
+                            }
+                        },
+                        Err(e) => core_failed(e),
+                    },
+                    Request::QueryCoreStreamed { query, params, timeout_ms, readonly, limit } => {
+                        let running = ctx.core_queries.start(&query);
+                        let options = QueryOptions {
+                            timeout: timeout_ms.map(Duration::from_millis),
+                            cancel: Some(running.cancel_token()),
+                            readonly,
+                            source: client.audit_source(),
+                            namespace: namespace.clone(),
+                            principal: client.principal.clone(),
+                            max_rows: limit,
+                        };
+                        match core_stream::query(&ctx.compute, ctx.core.clone(), running, query, params, options, &mut writer).await {
+                            Ok(resp) => resp,
+                            Err(e) => {
+                                warn!("Core query stream aborted: {}. Dropping connection.", e);
+                                break;
+                            }
                         }
                     }
-                    Request::RunWasm { path: _, input } => {
-                        let wasm_for_task = wasm_clone.clone();
-                        let res = tokio::task::spawn_blocking(move || {
-                            wasm_for_task.run_module(&[], &input)
-                        })
-                        .await;
-                        match res {
-                            Ok(Ok(out)) => Response::WasmOutput(out),
-                            Ok(Err(e)) => Response::Error(e.to_string()),
-                            Err(e) => Response::Error(e.to_string()),
+                    Request::CoreImport { name, format, mode } => {
+                        let name = permitted(&ctx.core, &client, namespace.as_deref(), name, true);
+                        match core_stream::import(&ctx.compute, ctx.core.clone(), name, format, mode, &mut frame_rx).await {
+                            Ok(resp) => resp,
+                            Err(e) => {
+                                warn!("Core import aborted: {}. Dropping connection.", e);
+                                break;
+                            }
                         }
                     }
-                    Request::MeshDial { addr } => {
-                        let _ = mesh.send(MeshCommand::Dial(addr)).await;
-                        Response::MeshGeneric("Dialing...".into())
-                    }
-                    Request::MeshPeers => {
-                        let (tx, rx) = oneshot::channel();
-                        let _ = mesh.send(MeshCommand::GetPeers(tx)).await;
-                        match rx.await {
-                            Ok(peers) => {
-                                if let Ok(mut s) = state.write() {
-                                    s.connections = peers.len() as u32;
-                                }
-                                Response::MeshGeneric(format!("{:?}", peers))
+                    Request::ExportSnapshot { path } => match snapshot::export(&ctx.compute, ctx.snapshot_target(), path, &mut writer).await {
+                        Ok(resp) => resp,
+                        Err(e) => {
+                            warn!("Snapshot export aborted: {}. Dropping connection.", e);
+                            break;
+                        }
+                    },
+                    Request::ImportSnapshot { path } => {
+                        match snapshot::import(&ctx.compute, ctx.snapshot_target(), path, ctx.maintenance.clone(), &mut frame_rx).await {
+                            Ok(resp) => resp,
+                            Err(e) => {
+                                warn!("Snapshot import aborted: {}. Dropping connection.", e);
+                                break;
                             }
-                            Err(_) => Response::Error("Mesh timeout".into()),
                         }
                     }
-                    Request::VerifyLicense { tx_id, .. } => {
-                        let f = finance.clone();
-                        let s = state.clone();
-                        let tid = tx_id.clone();
-                        let mid = m_id.clone();
-
-                        // CRITICAL: Move the blocking verification to a separate thread
-                        let res = tokio::task::spawn_blocking(move || {
-                            f.verify_license_sync(&tid, &mid)
-                        })
-                        .await;
-
-                        match res {
-                            Ok(Ok(valid)) => {
-                                if let Ok(mut state_lock) = s.write() {
-                                    state_lock.license_active = valid;
-                                }
-                                Response::LicenseResult { valid, details: if valid { "Active".into() } else { "Invalid".into() } }
-                            },
-                            Ok(Err(e)) => Response::Error(format!("Verification Logic Failed: {}", e)),
-                            Err(e) => Response::Error(format!("Task Panicked: {}", e)),
+                    Request::RunWasmStreamed { path, args, env, fuel_limit, signature } => {
+                        let options = RunOptions {
+                            args,
+                            env,
+                            limits: run_limits(&ctx, ExecutionLimits { fuel_limit, ..Default::default() }),
+                            signature: signature.map(module_signature),
+                            label: path.clone(),
+                            source: client.name.clone(),
+                            namespace: namespace.clone(),
+                            ..Default::default()
+                        };
+                        match wasm_stream::run_streamed(&ctx.wasm, &path, options, &mut frame_rx, &mut writer).await {
+                            Ok(resp) => resp,
+                            Err(e) => {
+                                warn!("Streamed WASM run aborted: {}. Dropping connection.", e);
+                                break;
+                            }
+                        }
+                    }
+                    other => {
+                        let span = logging::request_span(&client.name, id, &other);
+                        match id {
+                            Some(id) => {
+                                let (ctx, settings, caller, namespace, stats) = (ctx.clone(), settings.clone(), client.clone(), namespace.clone(), stats.clone());
+                                in_flight.spawn(
+                                    async move {
+                                        let _paused = paused;
+                                        let handled = handle_timed(&ctx, &settings.request_timeouts, other, &caller, namespace.as_deref());
+                                        let resp = AssertUnwindSafe(handled)
+                                            .catch_unwind()
+                                            .await
+                                            .unwrap_or_else(|_| Response::Error("The request handler panicked".into()));
+                                        ipc_audit::finish(audited, &resp);
+                                        ipc_stats::finish(&stats, timing, &resp);
+                                        (id, resp)
+                                    }
+                                    .instrument(span),
+                                );
+                                continue;
+                            }
+                            None => {
+                                handle_timed(&ctx, &settings.request_timeouts, other, &client, namespace.as_deref())
+                                    .instrument(span)
+                                    .await
+                            }
                         }
                     }
-                    _ => Response::Pong, // Default response
                 };
 
-                let bytes = serde_json::to_vec(&resp).unwrap();
-                let len_bytes = (bytes.len() as u32).to_le_bytes();
-                stream.write_all(&len_bytes).await.ok();
-                stream.write_all(&bytes).await.ok();
+                ipc_audit::finish(audited, &resp);
+                ipc_stats::finish(&stats, timing, &resp);
+                if write_reply(&mut writer, id, resp).await.is_err() {
+                    break;
+                }
+                let replayed = async {
+                    for push in replay.drain(..) {
+                        write_frame(&mut writer, &push).await?;
+                    }
+                    std::io::Result::Ok(())
+                };
+                if replayed.await.is_err() {
+                    break;
+                }
             }
-        });
+            Some(done) = in_flight.join_next(), if !in_flight.is_empty() => {
+                let Ok((id, resp)) = done else { continue };
+                if write_reply(&mut writer, Some(id), resp).await.is_err() {
+                    break;
+                }
+            }
+            _ = shutdown.changed() => {
+                debug!("Closing IPC connection for shutdown");
+                draining = true;
+                break;
+            }
+            Some(()) = async { license_watch.as_mut()?.0.changed().await.ok() }, if license_watch.is_some() => {
+                let Some((updates, pushed)) = &mut license_watch else { continue };
+                let (active, report) = {
+                    let s = updates.borrow_and_update();
+                    (s.license_active, s.license_report.clone())
+                };
+                if active == *pushed {
+                    continue;
+                }
+                *pushed = active;
+                if write_frame(&mut writer, &Response::LicenseStatusChanged { active, report }).await.is_err() {
+                    break;
+                }
+            }
+            change = watches.next() => {
+                let push = if subscription.wants(EventTopic::Core) {
+                    subscription.stamp(NodeEvent::CoreChanged(change))
+                } else {
+                    Response::CoreChanged(change)
+                };
+                if write_frame(&mut writer, &push).await.is_err() {
+                    break;
+                }
+            }
+            push = subscription.next() => {
+                if write_frame(&mut writer, &push).await.is_err() {
+                    break;
+                }
+            }
+            () = &mut idle_timer, if idle_limit.is_some() => {
+                if !in_flight.is_empty() {
+                    // Still answering the client, so it is not idle.
+                    idle_timer.as_mut().reset(tokio::time::Instant::now() + until_warning);
+                    continue;
+                }
+                if idle_warned {
+                    info!("IPC {} sent nothing for {:?}. Closing it.", client.name, idle_limit.unwrap_or_default());
+                    ctx.connections.idled_out.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+                idle_warned = true;
+                idle_timer.as_mut().reset(tokio::time::Instant::now() + warn_before);
+                // Like heartbeats, warnings only go to clients that said Hello.
+                if handshaken && !warn_before.is_zero() {
+                    let warning = Response::IdleWarning {
+                        closes_in_ms: warn_before.as_millis() as u64,
+                    };
+                    if write_frame(&mut writer, &warning).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            _ = ticker.tick() => {
+                sessions.reap_idle();
+                if !handshaken {
+                    continue;
+                }
+                if !idle {
+                    idle = true;
+                    continue;
+                }
+                if unacked >= settings.max_missed_heartbeats {
+                    warn!("IPC client missed {} heartbeats. Dropping connection.", unacked);
+                    break;
+                }
+                seq += 1;
+                unacked += 1;
+                if write_frame(&mut writer, &Response::Heartbeat { seq }).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    reader_task.abort();
+    if draining {
+        while let Some(done) = in_flight.join_next().await {
+            let Ok((id, resp)) = done else { continue };
+            if write_reply(&mut writer, Some(id), resp).await.is_err() {
+                break;
+            }
+        }
+    }
+    // Dropping what is still in flight cancels it.
+    drop(in_flight);
+    let _ = writer.inner.shutdown().await;
+
+    // A node shutting down keeps no sessions.
+    if let Some((session_id, token)) = session.filter(|_| !draining) {
+        debug!("IPC {} closed; keeping session {} for {:?}", client.name, session_id, ctx.sessions.grace());
+        let state = Resumable {
+            uid: client.peer.map(|peer| peer.uid),
+            client_name: client.name,
+            namespace,
+            permissions,
+            principal: client.principal,
+            token_id,
+            subscription,
+            watches,
+            license_watch,
+        };
+        ctx.sessions.park(session_id, &token, state);
+    }
+}
+
+/// Reads frames on a task of their own, so that a heartbeat tick can never
+/// interrupt a partially read frame. The framing is sent ahead of the first
+/// frame.
+pub(crate) fn read_frames<R>(mut reader: R, max_frame_size: usize) -> (JoinHandle<()>, mpsc::Receiver<InboundFrame>, oneshot::Receiver<Framing>)
+where
+    R: AsyncRead + Send + Unpin + 'static,
+{
+    let (frame_tx, frame_rx) = mpsc::channel::<InboundFrame>(8);
+    let (framing_tx, framing_rx) = oneshot::channel::<Framing>();
+    let task = tokio::spawn(async move {
+        let Ok((framing, prefix)) = detect_framing(&mut reader).await else {
+            return;
+        };
+        let _ = framing_tx.send(framing);
+        let reader = std::io::Cursor::new(prefix).chain(reader);
+        match framing {
+            Framing::Binary => forward_frames(FramedRead::new(reader, FrameCodec::new(max_frame_size)), frame_tx, max_frame_size).await,
+            Framing::JsonLines => forward_frames(FramedRead::new(reader, LineCodec::new(max_frame_size)), frame_tx, max_frame_size).await,
+        }
+    });
+    (task, frame_rx, framing_rx)
+}
+
+/// Reads until `Framing::detect` can tell how the client frames its
+/// messages. Returns what was read, which belongs to the first message.
+async fn detect_framing<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<(Framing, Vec<u8>)> {
+    let mut prefix = [0u8; Framing::DETECT_LEN];
+    let mut len = 0;
+    loop {
+        if let Some(framing) = Framing::detect(&prefix[..len]) {
+            return Ok((framing, prefix[..len].to_vec()));
+        }
+        let n = reader.read(&mut prefix[len..]).await?;
+        if n == 0 {
+            // Too short for a length prefix: a line cut short, if anything.
+            let framing = if len == 0 { Framing::Binary } else { Framing::JsonLines };
+            return Ok((framing, prefix[..len].to_vec()));
+        }
+        len += n;
+    }
+}
+
+/// Hands each frame to the connection loop until the client goes away or
+/// the stream can no longer be read.
+async fn forward_frames<R, D>(mut frames: FramedRead<R, D>, frame_tx: mpsc::Sender<InboundFrame>, max_frame_size: usize)
+where
+    R: AsyncRead + Unpin,
+    D: Decoder<Item = Frame, Error = FrameError>,
+{
+    while let Some(frame) = frames.next().await {
+        let frame = InboundFrame::decoded(frame, max_frame_size);
+        let fatal = matches!(frame, InboundFrame::TooLarge { fatal: true, .. } | InboundFrame::Broken(_));
+        if frame_tx.send(frame).await.is_err() || fatal {
+            break;
+        }
+    }
+}
+
+pub(crate) enum InboundFrame {
+    Request(Vec<u8>),
+    /// A data frame's payload, without the tag byte.
+    Data(Vec<u8>),
+    /// The client declared a body over the limit. Non-fatal bodies were skipped.
+    TooLarge { declared: usize, fatal: bool },
+    /// The stream ended or failed partway through a frame, so no later frame
+    /// can be found. Says what went wrong.
+    Broken(String),
+}
+
+impl InboundFrame {
+    /// What the codec made of the client's bytes.
+    fn decoded(frame: Result<Frame, FrameError>, max_frame_size: usize) -> Self {
+        match frame {
+            Ok(Frame::Message(body)) => InboundFrame::Request(body.to_vec()),
+            Ok(Frame::Data(data)) => InboundFrame::Data(data.to_vec()),
+            Ok(Frame::Oversized { declared }) => Self::too_large(declared, false, max_frame_size),
+            Err(FrameError::TooLarge { declared, .. }) => Self::too_large(declared, true, max_frame_size),
+            Err(e) => InboundFrame::Broken(e.to_string()),
+        }
+    }
+
+    fn too_large(declared: usize, fatal: bool, max_frame_size: usize) -> Self {
+        error!("Security Violation: IPC client requested {} bytes (limit {}).", declared, max_frame_size);
+        InboundFrame::TooLarge { declared, fatal }
+    }
+}
+
+/// Where serde's 1-based `line` and `column` fall in `buf`, counted in bytes.
+pub(crate) fn byte_offset(buf: &[u8], line: usize, column: usize) -> usize {
+    let line_start = buf
+        .split_inclusive(|b| *b == b'\n')
+        .take(line.saturating_sub(1))
+        .map(<[u8]>::len)
+        .sum::<usize>();
+    (line_start + column.saturating_sub(1)).min(buf.len())
+}
+
+/// A connection's write half, framing messages the way the client frames
+/// its own, compressed as its Hello settled.
+pub(crate) struct Wire<W> {
+    pub inner: W,
+    pub framing: Framing,
+    compression: Option<FrameCompression>,
+    /// Stalls writes while an `IpcWriter` fault is armed; unset for
+    /// connections turned away and those served during setup.
+    #[cfg(feature = "fault-injection")]
+    faults: Option<Arc<FaultPlan>>,
+}
+
+impl<W: AsyncWrite + Unpin> Wire<W> {
+    pub fn new(inner: W, framing: Framing) -> Self {
+        Self {
+            inner,
+            framing,
+            compression: None,
+            #[cfg(feature = "fault-injection")]
+            faults: None,
+        }
+    }
+}
+
+pub(crate) async fn write_frame<W: AsyncWrite + Unpin>(stream: &mut Wire<W>, resp: &Response) -> std::io::Result<()> {
+    write_bytes(stream, &encode(resp, |failed| failed)?).await
+}
+
+/// `resp`, in an envelope with `id` if the request came in one.
+pub(crate) async fn write_reply<W: AsyncWrite + Unpin>(stream: &mut Wire<W>, id: Option<u64>, resp: Response) -> std::io::Result<()> {
+    let Some(id) = id else {
+        return write_frame(stream, &resp).await;
+    };
+    let envelope = ResponseEnvelope { id, response: resp };
+    write_bytes(stream, &encode(&envelope, |failed| ResponseEnvelope { id, response: failed })?).await
+}
+
+/// A response that cannot be encoded is replaced with an error, wrapped by
+/// `fallback`, so the client still gets an answer.
+fn encode<T: Serialize>(value: &T, fallback: impl FnOnce(Response) -> T) -> std::io::Result<Vec<u8>> {
+    serde_json::to_vec(value).or_else(|e| {
+        error!("Failed to encode a response: {}", e);
+        let failed = Response::Error(format!("The node failed to encode its response: {}", e));
+        Ok(serde_json::to_vec(&fallback(failed))?)
+    })
+}
+
+async fn write_bytes<W: AsyncWrite + Unpin>(stream: &mut Wire<W>, bytes: &[u8]) -> std::io::Result<()> {
+    #[cfg(feature = "fault-injection")]
+    if let Some(faults) = &stream.faults {
+        faults.hold(FaultTarget::IpcWriter, FaultKind::Stall).await;
+    }
+    let framed = stream.framing.encode(bytes, stream.compression)?;
+    stream.inner.write_all(&framed).await
+}
+
+pub(crate) async fn write_data_frame<W: AsyncWrite + Unpin>(stream: &mut Wire<W>, data: &[u8]) -> std::io::Result<()> {
+    #[cfg(feature = "fault-injection")]
+    if let Some(faults) = &stream.faults {
+        faults.hold(FaultTarget::IpcWriter, FaultKind::Stall).await;
+    }
+    let framed = stream.framing.encode_data(data, stream.compression)?;
+    stream.inner.write_all(&framed).await
+}
+
+/// `handle_request`, given up on once the request's budget passes. Dropping
+/// it drops the request's running query, which cancels the query.
+async fn handle_timed(ctx: &NodeContext, timeouts: &RequestTimeouts, req: Request, client: &Caller, namespace: Option<&str>) -> Response {
+    let kind = req.kind();
+    let Some((subsystem, budget)) = timeouts.budget(&req) else {
+        return handle_request(ctx, req, client, namespace).await;
+    };
+    // An injected core hang holds the request here, on the clock.
+    #[cfg(feature = "fault-injection")]
+    let handled = async {
+        if subsystem == "core" {
+            ctx.faults.hold(FaultTarget::Core, FaultKind::Hang).await;
+        }
+        handle_request(ctx, req, client, namespace).await
+    };
+    #[cfg(not(feature = "fault-injection"))]
+    let handled = handle_request(ctx, req, client, namespace);
+    timed(ctx, kind, client, subsystem, budget, handled).await
+}
+
+/// `handled`, answered with `Response::TimedOut` once `budget` passes.
+async fn timed(
+    ctx: &NodeContext,
+    kind: &'static str,
+    client: &Caller,
+    subsystem: &'static str,
+    budget: Duration,
+    handled: impl Future<Output = Response>,
+) -> Response {
+    match tokio::time::timeout(budget, handled).await {
+        Ok(resp) => resp,
+        Err(_) => {
+            warn!("{} request from '{}' timed out after {:?} waiting on the {}", kind, client.name, budget, subsystem);
+            ctx.timeouts.record(kind);
+            Response::TimedOut {
+                subsystem: subsystem.into(),
+                timeout_ms: budget.as_millis() as u64,
+            }
+        }
+    }
+}
+
+/// `namespace` is the client's, if it is confined to one.
+async fn handle_request(ctx: &NodeContext, req: Request, client: &Caller, namespace: Option<&str>) -> Response {
+    match req {
+        Request::GetStatus => {
+            let core = ctx.core.stats();
+            Response::Status(node_status(ctx, &core))
+        }
+        Request::GetMetrics => {
+            let core = ctx.core.stats();
+            Response::Metrics(metrics_snapshot(ctx, &core))
+        }
+        Request::GetVersion => Response::Version(build_info()),
+        Request::QueryCore { query, params, timeout_ms, readonly, limit } => {
+            let running = ctx.core_queries.start(&query);
+            let options = QueryOptions {
+                timeout: timeout_ms.map(Duration::from_millis),
+                cancel: Some(running.cancel_token()),
+                readonly,
+                source: client.audit_source(),
+                namespace: namespace.map(str::to_string),
+                principal: client.principal.clone(),
+                max_rows: limit,
+            };
+            // Off the async workers, so concurrent queries do not starve the connections.
+            let core = ctx.core.clone();
+            match ctx.compute.spawn(move || core.run_with(&query, params, &options)).await {
+                Ok(Ok(val)) => Response::CoreResult(val),
+                Ok(Err(e)) => core_failed(e),
+                Err(e) => e.into_response("Core query failed"),
+            }
+        }
+        Request::CoreRunNamed { name, params, timeout_ms, readonly } => {
+            let running = ctx.core_queries.start(&format!("named query {}", name));
+            let options = QueryOptions {
+                timeout: timeout_ms.map(Duration::from_millis),
+                cancel: Some(running.cancel_token()),
+                readonly,
+                source: client.audit_source(),
+                namespace: namespace.map(str::to_string),
+                principal: client.principal.clone(),
+                max_rows: None,
+            };
+            let core = ctx.core.clone();
+            match ctx.compute.spawn(move || core.run_named_with(&name, params, &options)).await {
+                Ok(Ok(val)) => Response::CoreResult(val),
+                Ok(Err(e)) => core_failed(e),
+                Err(e) => e.into_response("Core query failed"),
+            }
+        }
+        Request::CoreExplain { query, params } => {
+            let core = ctx.core.clone();
+            let namespace = namespace.map(str::to_string);
+            let explain = move || match &namespace {
+                Some(namespace) => core.explain_in(namespace, &query, params),
+                None => core.explain(&query, params),
+            };
+            match ctx.compute.spawn(explain).await {
+                Ok(Ok(report)) => Response::CoreExplained(core_explain_report(report)),
+                Ok(Err(e)) => core_failed(e),
+                Err(e) => e.into_response("Core explain failed"),
+            }
+        }
+        Request::CoreRegisterQuery { .. }
+        | Request::CoreRemoveNamed { .. }
+        | Request::CoreGrant { .. }
+        | Request::CoreRevoke { .. }
+        | Request::CoreListGrants { .. }
+        | Request::ReplicationOutbox { .. }
+        | Request::ReplicationRequeue { .. }
+        | Request::CoreAuditTail { .. }
+        | Request::AuditTail { .. }
+            if namespace.is_some() =>
+        {
+            node_wide(namespace)
+        }
+        Request::CoreRegisterQuery { name, query, params, readonly } => {
+            let named = named_query(CoreNamedQuery { name, query, params, readonly });
+            let core = ctx.core.clone();
+            match ctx.compute.spawn(move || core.register_query(&named).map(|_| named)).await {
+                Ok(Ok(named)) => Response::CoreNamedQuery(core_named_query(named)),
+                Ok(Err(e)) => core_failed(e),
+                Err(e) => e.into_response("Core query registration failed"),
+            }
+        }
+        Request::CoreListNamed => match ctx.core.list_named() {
+            Ok(queries) => Response::CoreNamedQueries(queries.into_iter().map(core_named_query).collect()),
+            Err(e) => core_failed(e),
+        },
+        Request::CoreRemoveNamed { name } => match ctx.core.remove_named(&name) {
+            Ok(removed) => Response::CoreNamedRemoved { name, removed },
+            Err(e) => core_failed(e),
+        },
+        Request::CoreGrant { principal, relation_pattern, rights } => {
+            let rights = match rights {
+                CoreGrantRights::Read => GrantRights::Read,
+                CoreGrantRights::Write => GrantRights::Write,
+            };
+            let core = ctx.core.clone();
+            match ctx.compute.spawn(move || core.grant(&principal, &relation_pattern, rights)).await {
+                Ok(Ok(grant)) => {
+                    info!("IPC {} granted {} {} on {}", client.name, grant.principal, grant.rights, grant.pattern);
+                    Response::CoreGranted(core_grant(grant))
+                }
+                Ok(Err(e)) => core_failed(e),
+                Err(e) => e.into_response("Core grant failed"),
+            }
+        }
+        Request::CoreRevoke { principal, relation_pattern } => {
+            let core = ctx.core.clone();
+            let (who, pattern) = (principal.clone(), relation_pattern.clone());
+            match ctx.compute.spawn(move || core.revoke(&who, &pattern)).await {
+                Ok(Ok(revoked)) => {
+                    if revoked {
+                        info!("IPC {} revoked the grant of {} on {}", client.name, principal, relation_pattern);
+                    }
+                    Response::CoreRevoked { principal, relation_pattern, revoked }
+                }
+                Ok(Err(e)) => core_failed(e),
+                Err(e) => e.into_response("Core revoke failed"),
+            }
+        }
+        Request::CoreListGrants { principal } => {
+            Response::CoreGrants(ctx.core.list_grants(principal.as_deref()).into_iter().map(core_grant).collect())
+        }
+        Request::CoreAssert { name, rows } => {
+            let name = match permitted(&ctx.core, client, namespace, name, true) {
+                Ok(name) => name,
+                Err(e) => return core_failed(e),
+            };
+            let core = ctx.core.clone();
+            match ctx.compute.spawn(move || core.assert_facts(&name, rows)).await {
+                Ok(Ok(rows)) => Response::CoreAsserted { rows },
+                Ok(Err(e)) => core_failed(e),
+                Err(e) => e.into_response("Core assert failed"),
+            }
+        }
+        Request::CoreRetract { name, keys } => {
+            let name = match permitted(&ctx.core, client, namespace, name, true) {
+                Ok(name) => name,
+                Err(e) => return core_failed(e),
+            };
+            let core = ctx.core.clone();
+            match ctx.compute.spawn(move || core.retract_facts(&name, keys)).await {
+                Ok(Ok(rows)) => Response::CoreRetracted { rows },
+                Ok(Err(e)) => core_failed(e),
+                Err(e) => e.into_response("Core retract failed"),
+            }
+        }
+        Request::CoreKnn { name, column, vector, k, filters } => {
+            let name = match permitted(&ctx.core, client, namespace, name, false) {
+                Ok(name) => name,
+                Err(e) => return core_failed(e),
+            };
+            let core = ctx.core.clone();
+            match ctx.compute.spawn(move || core.knn_query(&name, &column, &vector, k as usize, &filters)).await {
+                Ok(Ok(val)) => Response::CoreResult(val),
+                Ok(Err(e)) => core_failed(e),
+                Err(e) => e.into_response("Core nearest-neighbour query failed"),
+            }
+        }
+        Request::CoreAuditTail { limit } => {
+            let core = ctx.core.clone();
+            match ctx.compute.spawn(move || core.audit_tail(limit as usize)).await {
+                Ok(Ok(entries)) => Response::CoreAudit(entries.into_iter().map(core_audit_entry).collect()),
+                Ok(Err(e)) => core_failed(e),
+                Err(e) => e.into_response("Reading the core audit log failed"),
+            }
+        }
+        Request::AuditTail { limit } => match &ctx.audit {
+            Some(audit) => match audit.tail(limit as usize).await {
+                Some(entries) => Response::IpcAudit {
+                    entries,
+                    dropped: audit.dropped(),
+                },
+                None => Response::Error("The IPC audit writer has stopped".into()),
+            },
+            None => Response::Error("The node does not audit IPC requests; set ipc_audit.enabled".into()),
+        },
+        Request::SlowRequests { limit } => Response::SlowRequests {
+            requests: ctx.ipc.slow(limit as usize),
+            threshold_ms: ctx.ipc.slow_threshold().map(|threshold| threshold.as_millis() as u64),
+        },
+        Request::ConnectionStats => Response::Connections(ctx.ipc.connections()),
+        Request::SelfCheck => match &ctx.config {
+            Some(config) => Response::SelfCheck(self_check::live(config, ctx.core.clone(), &ctx.compute, ctx.finance.pool()).await),
+            None => Response::Error("The node was started without a config to check".into()),
+        },
+        Request::SetupState => match &ctx.setup {
+            Some(setup) => Response::SetupState(setup.state(false)),
+            None => Response::Error("The node was started without a config file to set up".into()),
+        },
+        Request::SetupApply { settings, force } => {
+            let Some(setup) = ctx.setup.clone() else {
+                return Response::Error("The node was started without a config file to set up".into());
+            };
+            if !force {
+                return Response::Error("The node is already set up; send force to set it up again".into());
+            }
+            // Takes effect when the node next starts.
+            match ctx.compute.spawn(move || setup.apply(settings)).await {
+                Ok(Ok(_)) => Response::SetupApplied { restart_required: true },
+                Ok(Err(e)) => Response::Error(format!("{:#}", e)),
+                Err(e) => e.into_response("Setup failed"),
+            }
+        }
+        #[cfg(feature = "fault-injection")]
+        Request::InjectFault { target, kind, duration_ms } => match ctx.faults.inject(target, kind, Duration::from_millis(duration_ms)) {
+            Ok(active) => {
+                if kind == FaultKind::PoisonLock {
+                    ctx.core_queries.poison();
+                }
+                Response::FaultInjected { target, active }
+            }
+            Err(e) => Response::Error(format!("{:#}", e)),
+        },
+        Request::CoreQueries => Response::CoreQueries(
+            ctx.core_queries
+                .list()
+                .into_iter()
+                .map(|q| CoreQueryInfo {
+                    query_id: q.id,
+                    query: q.query,
+                    running_ms: q.running_for.as_millis() as u64,
+                })
+                .collect(),
+        ),
+        Request::CoreListRelations => match namespace.map_or_else(|| ctx.core.list_relations(), |ns| ctx.core.list_relations_in(ns)) {
+            Ok(relations) => Response::CoreRelations(
+                relations
+                    .into_iter()
+                    .map(|r| CoreRelation {
+                        name: r.name,
+                        arity: r.arity as u32,
+                        keys: r.keys as u32,
+                        rows: r.rows,
+                        indices: r.indices.into_iter().map(core_index).collect(),
+                    })
+                    .collect(),
+            ),
+            Err(e) => core_failed(e),
+        },
+        Request::CoreDescribe { name } => match permitted(&ctx.core, client, namespace, name, false).and_then(|name| ctx.core.describe(&name)) {
+            Ok(schema) => Response::CoreSchema(CoreRelationSchema {
+                name: schema.name,
+                columns: schema
+                    .columns
+                    .into_iter()
+                    .map(|c| CoreColumn {
+                        name: c.name,
+                        column_type: c.column_type,
+                        key: c.key,
+                        has_default: c.has_default,
+                    })
+                    .collect(),
+                rows: schema.rows,
+                indices: schema.indices.into_iter().map(core_index).collect(),
+                history_rows: schema.history_rows,
+            }),
+            Err(e) => core_failed(e),
+        },
+        Request::RunWasm { path, input, args, env, fuel_limit, signature } => {
+            if ModulePaths::is_registered_name(&path) {
+                let options = RunOptions {
+                    args,
+                    env,
+                    limits: run_limits(ctx, ExecutionLimits { fuel_limit, ..Default::default() }),
+                    source: client.name.clone(),
+                    namespace: namespace.map(str::to_string),
+                    ..Default::default()
+                };
+                return wasm_result(ctx.modules.run(&path, &input, &options).await);
+            }
+            let bytes = match ctx.module_paths.read(&path).await {
+                Ok(bytes) => bytes,
+                Err(reason) => {
+                    debug!("RunWasm from {} refused {}: {:?}", client.name, path, reason);
+                    return Response::WasmPathRejected { path, reason };
+                }
+            };
+            // Path-based runs get no capabilities; grants come with registration.
+            let options = RunOptions {
+                args,
+                env,
+                limits: run_limits(ctx, ExecutionLimits { fuel_limit, ..Default::default() }),
+                signature: signature.map(module_signature),
+                label: path.clone(),
+                source: client.name.clone(),
+                namespace: namespace.map(str::to_string),
+                ..Default::default()
+            };
+            // Runs on the executor; the runtime yields as fuel is consumed.
+            wasm_result(ctx.wasm.run_module(&bytes, &input, &options).await)
+        }
+        Request::WasmUpload { name, path, manifest, signature, watch } => {
+            let bytes = match tokio::fs::read(&path).await {
+                Ok(bytes) => bytes,
+                Err(e) => return Response::Error(format!("Cannot read WASM module {}: {}", path, e)),
+            };
+            let capabilities = match manifest.capabilities.iter().map(|c| c.parse()).collect::<Result<Vec<_>>>() {
+                Ok(capabilities) => capabilities,
+                Err(e) => return Response::Error(e.to_string()),
+            };
+            // A confined client's modules are confined with it.
+            let module_namespace = match (namespace, manifest.namespace) {
+                (Some(client), Some(module)) if client != module => {
+                    return Response::Error(format!("Cannot upload a module confined to core namespace '{}' from '{}'", module, client))
+                }
+                (client, module) => client.map(str::to_string).or(module),
+            };
+            let manifest = ModuleManifest {
+                capabilities,
+                limits: ExecutionLimits {
+                    fuel_limit: manifest.fuel_limit,
+                    max_memory_bytes: manifest.max_memory_bytes.map(|b| b as usize),
+                    ..Default::default()
+                },
+                sha256: manifest.sha256,
+                signature: signature.map(module_signature),
+                source_path: watch.then(|| PathBuf::from(&path)),
+                allowed_env: manifest.allowed_env,
+                namespace: module_namespace,
+                artifact: None,
+            };
+            let modules = ctx.modules.clone();
+            // Registration compiles the module to validate it.
+            match ctx.compute.spawn(move || modules.register(&name, bytes, manifest)).await {
+                Ok(Ok(info)) => Response::WasmModule(module_info(info, &ctx.wasm.module_stats())),
+                Ok(Err(e)) => Response::Error(format!("{:#}", e)),
+                Err(e) => e.into_response("Module registration failed"),
+            }
+        }
+        Request::WasmList => {
+            let stats = ctx.wasm.module_stats();
+            Response::WasmModules(ctx.modules.list().into_iter().map(|info| module_info(info, &stats)).collect())
+        }
+        Request::WasmInfo { name } => match ctx.modules.get(&name) {
+            Some(info) => Response::WasmModule(WasmModuleInfo {
+                details: Some(module_details(&info)),
+                ..module_info(info, &ctx.wasm.module_stats())
+            }),
+            None => Response::Error(format!("No WASM module registered as '{}'", name)),
+        },
+        Request::WasmRemove { name } => match ctx.modules.remove(&name) {
+            Ok(removed) => Response::WasmRemoved { name, removed },
+            Err(e) => Response::Error(format!("{:#}", e)),
+        },
+        Request::WasmExecutions => Response::WasmExecutions(
+            ctx.wasm
+                .executions()
+                .into_iter()
+                .map(|e| WasmExecutionInfo {
+                    execution_id: e.id,
+                    label: e.label,
+                    running_ms: e.running_for.as_millis() as u64,
+                })
+                .collect(),
+        ),
+        Request::Cancel { execution_id } => Response::Cancelled {
+            execution_id,
+            found: ctx.wasm.cancel(execution_id) || ctx.core_queries.cancel(execution_id),
+        },
+        Request::RunWasmModule { name, input, args, env, fuel_limit } => {
+            let options = RunOptions {
+                args,
+                env,
+                limits: run_limits(ctx, ExecutionLimits { fuel_limit, ..Default::default() }),
+                source: client.name.clone(),
+                namespace: namespace.map(str::to_string),
+                ..Default::default()
+            };
+            wasm_result(ctx.modules.run(&name, &input, &options).await)
+        }
+        Request::RunWasmPipeline { stages, input, timeout_ms, total_fuel } => {
+            let stages: Vec<PipelineStage> = stages
+                .into_iter()
+                .map(|stage| PipelineStage {
+                    module: stage.module,
+                    limits: run_limits(
+                        ctx,
+                        ExecutionLimits {
+                            fuel_limit: stage.fuel_limit,
+                            max_memory_bytes: stage.max_memory_bytes.map(|b| b as usize),
+                            ..Default::default()
+                        },
+                    ),
+                })
+                .collect();
+            let limits = PipelineLimits {
+                timeout: timeout_ms.map(Duration::from_millis),
+                total_fuel,
+            };
+            let options = RunOptions {
+                source: client.name.clone(),
+                namespace: namespace.map(str::to_string),
+                ..Default::default()
+            };
+            match ctx.modules.run_pipeline(&stages, &input, &limits, &options).await {
+                Ok(result) => Response::WasmPipelineResult {
+                    stages: result.stages.into_iter().map(|out| wasm_result(Ok(out))).collect(),
+                    output: String::from_utf8_lossy(&result.output).into_owned(),
+                    failure: result.failure.map(|failure| WasmPipelineFailure {
+                        stage: failure.stage as u32,
+                        module: failure.module,
+                        reason: failure.reason,
+                    }),
+                },
+                Err(e) => Response::Error(e.to_string()),
+            }
+        }
+        Request::WasmAllowlistAdd { sha256 } => allowlist_response(&ctx.wasm, |list| list.add(&sha256)),
+        Request::WasmAllowlistRemove { sha256 } => allowlist_response(&ctx.wasm, |list| list.remove(&sha256)),
+        Request::WasmAllowlistList => allowlist_response(&ctx.wasm, |_| Ok(false)),
+        // Jobs run as the node, not as the client that scheduled them.
+        Request::WasmScheduleJob { .. } if namespace.is_some() => {
+            Response::Error("Clients confined to a core namespace cannot schedule jobs".into())
+        }
+        Request::WasmScheduleJob { name, job } => match ctx.scheduler.schedule(&name, job_spec(job)) {
+            Ok(info) => Response::WasmJob(job_info(info)),
+            Err(e) => Response::Error(format!("{:#}", e)),
+        },
+        Request::WasmListJobs => Response::WasmJobs(ctx.scheduler.list().into_iter().map(job_info).collect()),
+        Request::WasmDeleteJob { name } => match ctx.scheduler.remove(&name) {
+            Ok(deleted) => Response::WasmJobDeleted { name, deleted },
+            Err(e) => Response::Error(format!("{:#}", e)),
+        },
+        Request::WasmJobHistory { name } => match ctx.scheduler.history(&name) {
+            Some(runs) => Response::WasmJobHistory {
+                runs: runs.into_iter().map(job_run).collect(),
+                name,
+            },
+            None => Response::Error(format!("No job named '{}'", name)),
+        },
+        Request::MeshDial { addr, persist } => {
+            if persist {
+                ctx.store.update(|s| {
+                    if !s.peers.contains(&addr) {
+                        s.peers.push(addr.clone());
+                    }
+                });
+            }
+            let _ = ctx.mesh.send(MeshCommand::Dial(addr)).await;
+            Response::MeshGeneric("Dialing...".into())
+        }
+        Request::MeshUnpin { addr } => {
+            let mut found = false;
+            ctx.store.update(|s| {
+                let before = s.peers.len();
+                s.peers.retain(|p| *p != addr);
+                found = s.peers.len() != before;
+            });
+            Response::MeshGeneric(if found { format!("Unpinned {}", addr) } else { format!("{} was not pinned", addr) })
+        }
+        Request::MeshPeers => {
+            let (tx, rx) = oneshot::channel();
+            let _ = ctx.mesh.send(MeshCommand::GetPeers(tx)).await;
+            match rx.await {
+                Ok(peers) => Response::MeshGeneric(format!("{:?}", peers)),
+                Err(_) => Response::Error("Mesh timeout".into()),
+            }
+        }
+        Request::MeshPresence => {
+            let Some(table) = ctx.state.borrow().presence.clone() else {
+                return Response::Error("The node does not track presence; set presence.enabled".into());
+            };
+            let (tx, rx) = oneshot::channel();
+            let _ = ctx.mesh.send(MeshCommand::GetPeers(tx)).await;
+            let connected: HashSet<String> = rx.await.unwrap_or_default().into_iter().collect();
+            let nodes = table
+                .nodes
+                .into_iter()
+                .map(|(peer_id, node)| PeerPresence {
+                    connected: connected.contains(&peer_id),
+                    peer_id,
+                    node_version: node.node_version,
+                    uptime_ms: node.uptime_ms,
+                    health: node.health,
+                    capabilities: node.capabilities,
+                    last_seen_ms: node.received_ms,
+                    stale: node.stale,
+                })
+                .collect();
+            Response::MeshPresence {
+                nodes,
+                stale_after_ms: table.stale_after.as_millis() as u64,
+            }
+        }
+        Request::ReplicationOutbox { after_seq, include_published, limit } => {
+            let Some(outbox) = ctx.outbox.clone() else {
+                return Response::Error("The node does not replicate; list relations under [replication]".into());
+            };
+            let limit = limit.unwrap_or(100).clamp(1, 1000) as usize;
+            match ctx.compute.spawn(move || outbox.list(after_seq, include_published, limit).map(|entries| (outbox, entries))).await {
+                Ok(Ok((outbox, entries))) => Response::ReplicationOutbox(ReplicationOutboxReport {
+                    depth: outbox.depth(),
+                    high_water: outbox.high_water(),
+                    entries: entries.into_iter().map(outbox_entry).collect(),
+                }),
+                Ok(Err(e)) => Response::Error(format!("{:#}", e)),
+                Err(e) => e.into_response("Reading the replication outbox failed"),
+            }
+        }
+        Request::ReplicationRequeue { seqs } => {
+            let Some(outbox) = ctx.outbox.clone() else {
+                return Response::Error("The node does not replicate; list relations under [replication]".into());
+            };
+            match ctx.compute.spawn(move || outbox.requeue(&seqs)).await {
+                Ok(Ok(requeued)) => {
+                    info!("Requeued {} replication outbox entries for {}", requeued, client.name);
+                    Response::ReplicationRequeued { requeued }
+                }
+                Ok(Err(e)) => Response::Error(format!("{:#}", e)),
+                Err(e) => e.into_response("Requeueing replication outbox entries failed"),
+            }
+        }
+        Request::VerifyLicense { tx_id, .. } => match &ctx.identity {
+            MachineIdentity::Bound(machine_id) => match ctx.finance.verify(tx_id, machine_id.clone()).await {
+                Ok((valid, report)) => {
+                    license_monitor::record(&ctx.state, &ctx.store, valid, report.clone());
+                    license_result(ctx, valid, if valid { "Active" } else { "Invalid" }, Some(report))
+                }
+                Err(failed) => failed,
+            },
+            MachineIdentity::Unbindable(reason) => Response::Unavailable {
+                subsystem: "license".into(),
+                reason: format!("this install has no identity to bind a license to: {}", reason),
+            },
+        },
+        Request::GetLicenseInfo => {
+            let (valid, report) = {
+                let s = ctx.state.borrow();
+                (s.license_active, s.license_report.clone())
+            };
+            let details = match (&report, valid) {
+                (None, _) => "Never verified",
+                (Some(_), true) => "Active (cached)",
+                (Some(_), false) => "Invalid (cached)",
+            };
+            license_result(ctx, valid, details, report)
+        }
+        _ => Response::Pong, // Default response
+    }
+}
+
+/// Builds a `LicenseResult` carrying the verifier's terms and this machine's
+/// binding, or neither until the verifier has connected. There is no
+/// binding on an unbindable install.
+fn license_result(ctx: &NodeContext, valid: bool, details: &str, report: Option<LicenseReport>) -> Response {
+    let Ok(finance) = ctx.finance.verifier() else {
+        return Response::LicenseResult {
+            valid,
+            details: details.into(),
+            report,
+            terms: None,
+            binding: None,
+        };
+    };
+    let policy = finance.policy();
+    let terms = LicenseTerms {
+        developer_addr: finance.developer_addr().to_string(),
+        required_sats: finance.required_sats(),
+        min_confirmations: policy.min_confirmations,
+        validity_blocks: policy.validity_blocks,
+        tiers: policy
+            .tiers
+            .iter()
+            .map(|t| LicenseTierInfo { name: t.name.clone(), min_sats: t.min_sats })
+            .collect(),
+    };
+    let binding = ctx.identity.id().map(|machine_id| LicenseBinding {
+        op_return_hex: sovereign_finance::binding_payload_hex(machine_id),
+        payment_uri: finance.payment_uri(),
+    });
+    Response::LicenseResult {
+        valid,
+        details: details.into(),
+        report,
+        terms: Some(terms),
+        binding,
+    }
+}
+
+fn node_status(ctx: &NodeContext, core: &CoreStats) -> NodeStatus {
+    let s = ctx.state.borrow().clone();
+    let finance = ctx.finance.state();
+    let binding = ctx.identity.binding();
+    let signals = health::Signals {
+        host: ctx.host.sample(),
+        core_size_bytes: core.size_bytes,
+        wasm_queued: ctx.wasm.admission_stats().queued as u64,
+        finance: &finance,
+        binding: &binding,
+        mesh: &s.mesh_phase,
+        presence: s.presence.as_ref(),
+        outbox: ctx.outbox.as_ref().map(|outbox| (outbox.depth(), outbox.high_water())),
+    };
+    let level = health::assess(&signals, &ctx.health);
+    let resources = signals.resources();
+    NodeStatus {
+        uptime_ms: SystemTime::now().duration_since(ctx.start_time).unwrap_or_default().as_millis() as u64,
+        mesh_peer_id: s.peer_id,
+        mesh_connections: s.connections,
+        mesh_listen_addrs: s.listen_addrs,
+        license_active: s.license_active,
+        system_health: level.summary().into(),
+        health_details: vec![
+            wasm_health(ctx),
+            core_health(core),
+            finance_health(&finance),
+            binding_health(&binding),
+            mesh_health(&s.mesh_phase),
+        ]
+        .into_iter()
+        .chain(s.presence.as_ref().map(|table| presence_health(table, s.connections)))
+        .chain(ctx.outbox.as_deref().map(replication_health))
+        .collect(),
+        mesh_phase: s.mesh_phase,
+        finance,
+        machine_binding: binding,
+        ipc_connections: ctx.connections.snapshot(),
+        health: level,
+        resources,
+        version: build_info(),
+        mode: NodeMode::Running,
+    }
+}
+
+#[cfg(feature = "metrics-http")]
+impl metrics_http::ScrapeSource for NodeContext {
+    fn gather(&self) -> (NodeStatus, MetricsSnapshot) {
+        let core = self.core.stats();
+        (node_status(self, &core), metrics_snapshot(self, &core))
+    }
+
+    fn pool(&self) -> &BlockingPool {
+        &self.compute
+    }
+}
+
+fn metrics_snapshot(ctx: &NodeContext, core: &CoreStats) -> MetricsSnapshot {
+    let admission = ctx.wasm.admission_stats();
+    let stats = ctx.wasm.module_stats();
+    let top = top_module_by_fuel(&stats);
+    let (bytes_in, bytes_out) = ctx.ipc.bytes();
+    MetricsSnapshot {
+        wasm: WasmMetrics {
+            running: admission.running as u64,
+            queued: admission.queued as u64,
+            admitted: admission.admitted,
+            rejected_busy: admission.rejected,
+            avg_queue_wait_ms: admission.total_wait.as_millis().checked_div(admission.admitted as u128).unwrap_or(0) as u64,
+            max_queue_wait_ms: admission.max_wait.as_millis() as u64,
+            module_runs: stats.values().map(|s| s.runs).sum(),
+            top_module_fuel: top.map_or(0, |(_, s)| s.fuel_used),
+            top_module_by_fuel: top.map(|(name, _)| name.clone()),
+        },
+        core: CoreMetrics {
+            backend: core.backend.to_string(),
+            size_bytes: core.size_bytes,
+            running_queries: ctx.core_queries.list().len() as u64,
+        },
+        timeouts: ctx.timeouts.snapshot(),
+        ipc: IpcMetrics {
+            accept_failures: ctx.accept_failures.load(Ordering::Relaxed),
+            requests: ctx.ipc.requests(),
+            bytes_in,
+            bytes_out,
+            rate_limited: ctx.rate_limited.snapshot(),
+            connections: ctx.connections.snapshot(),
+            parked_sessions: ctx.sessions.parked() as u64,
+        },
+        pools: [("finance".to_string(), ctx.finance.pool_metrics()), ("compute".to_string(), ctx.compute.metrics())].into(),
+        replication: ctx.outbox.as_ref().map(|outbox| ReplicationMetrics {
+            outbox_depth: outbox.depth(),
+            outbox_high_water: outbox.high_water(),
+            outbox_published: outbox.published_total(),
+            outbox_failures: outbox.failures_total(),
+        }),
+    }
+}
+
+fn core_index(index: IndexInfo) -> CoreIndex {
+    CoreIndex {
+        name: index.name,
+        kind: index.kind,
+        config: index.config,
+    }
+}
+
+/// `relation` as stored, for a client confined to `namespace`; `written`
+/// if the request writes it.
+pub(crate) fn stored(namespace: Option<&str>, relation: String, written: bool) -> Result<String, CoreError> {
+    match namespace {
+        Some(namespace) => resolve_relation(namespace, &relation, written),
+        None => Ok(relation),
+    }
+}
+
+/// `stored`, then checked against the client's core grants.
+pub(crate) fn permitted(core: &CognitiveCore, client: &Caller, namespace: Option<&str>, relation: String, written: bool) -> Result<String, CoreError> {
+    let relation = stored(namespace, relation, written)?;
+    if let Some(principal) = &client.principal {
+        core.check_grant(principal, &relation, written)?;
+    }
+    Ok(relation)
+}
+
+/// The core namespace `client` is confined to, by its principal.
+fn confinement(settings: &IpcSettings, client: &Caller) -> Option<String> {
+    let namespace = settings.namespaces.get(client.principal.as_ref()?).cloned();
+    if let Some(namespace) = &namespace {
+        info!("IPC {} is confined to core namespace '{}'", client.name, namespace);
+    }
+    namespace
+}
+
+/// Refuses a request that acts on the whole core to a confined client.
+fn node_wide(namespace: Option<&str>) -> Response {
+    Response::CoreFailed(CoreFailure {
+        code: ErrorCode::NamespaceDenied,
+        message: format!("Clients confined to core namespace '{}' cannot make this request", namespace.unwrap_or_default()),
+        line: None,
+        column: None,
+    })
+}
+
+fn outbox_entry(entry: OutboxEntry) -> OutboxEntryInfo {
+    OutboxEntryInfo {
+        seq: entry.seq,
+        relation: entry.relation,
+        topic: entry.topic,
+        queued_ms: entry.queued_ms,
+        published_ms: entry.published_ms,
+        attempts: entry.attempts,
+        error: entry.error,
+    }
+}
+
+fn core_grant(grant: Grant) -> CoreGrantInfo {
+    CoreGrantInfo {
+        principal: grant.principal,
+        relation_pattern: grant.pattern,
+        rights: match grant.rights {
+            GrantRights::Read => CoreGrantRights::Read,
+            GrantRights::Write => CoreGrantRights::Write,
+        },
+        granted_ms: grant.granted_ms.max(0) as u64,
+    }
+}
+
+/// A failed core request, coded by what went wrong.
+pub(crate) fn core_failed(e: CoreError) -> Response {
+    let message = e.to_string();
+    let (line, column) = match &e {
+        CoreError::Parse(error) | CoreError::Query(error) => (error.line.map(|l| l as u32), error.column.map(|c| c as u32)),
+        _ => (None, None),
+    };
+    let code = match e {
+        CoreError::Parse(_) => ErrorCode::Parse,
+        CoreError::UnknownRelation(_) => ErrorCode::UnknownRelation,
+        CoreError::TypeMismatch { .. } => ErrorCode::TypeMismatch,
+        CoreError::ReadOnlyViolation(_) => ErrorCode::ReadOnlyViolation,
+        CoreError::Storage(_) => ErrorCode::Storage,
+        CoreError::TransactionConflict(_) => ErrorCode::TransactionConflict,
+        CoreError::InvalidParams(_) => ErrorCode::InvalidParams,
+        CoreError::MissingParam(_) => ErrorCode::MissingParam,
+        CoreError::Timeout { .. } => ErrorCode::Timeout,
+        CoreError::Cancelled => ErrorCode::Cancelled,
+        CoreError::Interrupted(_) => ErrorCode::Interrupted,
+        CoreError::TransactionOpen => ErrorCode::TransactionOpen,
+        CoreError::TransactionAborted(_) => ErrorCode::TransactionAborted,
+        CoreError::InvalidName(_) => ErrorCode::InvalidName,
+        CoreError::InvalidSchema(_) => ErrorCode::InvalidSchema,
+        CoreError::RelationNotEmpty { .. } => ErrorCode::RelationNotEmpty,
+        CoreError::Io(_) => ErrorCode::Io,
+        CoreError::InvalidImport(_) => ErrorCode::InvalidImport,
+        CoreError::InvalidRow { .. } => ErrorCode::InvalidRow,
+        CoreError::UnknownNamedQuery(_) => ErrorCode::UnknownNamedQuery,
+        CoreError::Migration { .. } => ErrorCode::Migration,
+        CoreError::VectorDimension { .. } | CoreError::InvalidVector(_) => ErrorCode::InvalidVector,
+        CoreError::AuditDisabled => ErrorCode::AuditDisabled,
+        CoreError::NamespaceDenied { .. } => ErrorCode::NamespaceDenied,
+        CoreError::GrantDenied { .. } => ErrorCode::GrantDenied,
+        CoreError::ResultTooLarge { .. } => ErrorCode::ResultTooLarge,
+        CoreError::Query(_) => ErrorCode::Query,
+    };
+    Response::CoreFailed(CoreFailure { code, message, line, column })
+}
+
+fn core_explain_report(report: ExplainReport) -> CoreExplainReport {
+    CoreExplainReport {
+        relations: report
+            .relations
+            .into_iter()
+            .map(|r| CoreExplainRelation {
+                name: r.name,
+                exists: r.exists,
+                written: r.written,
+            })
+            .collect(),
+        headers: report.headers,
+        mutation: report.mutation,
+        plan: report.plan,
+    }
+}
+
+fn core_audit_entry(entry: AuditEntry) -> CoreAuditEntry {
+    CoreAuditEntry {
+        at_ms: entry.at_ms,
+        source: entry.source,
+        query_hash: entry.query_hash,
+        query: entry.query,
+        params: entry.params,
+        param_values: entry.param_values,
+        duration_ms: entry.duration_ms,
+        rows: entry.rows,
+        error: entry.error,
+    }
+}
+
+pub(crate) fn named_query(query: CoreNamedQuery) -> NamedQuery {
+    NamedQuery {
+        name: query.name,
+        text: query.query,
+        params: query
+            .params
+            .into_iter()
+            .map(|p| ParamSpec {
+                name: p.name,
+                param_type: param_type(p.param_type),
+                required: p.required,
+            })
+            .collect(),
+        readonly: query.readonly,
+    }
+}
+
+pub(crate) fn core_named_query(query: NamedQuery) -> CoreNamedQuery {
+    CoreNamedQuery {
+        name: query.name,
+        query: query.text,
+        params: query
+            .params
+            .into_iter()
+            .map(|p| CoreQueryParam {
+                name: p.name,
+                param_type: core_param_type(p.param_type),
+                required: p.required,
+            })
+            .collect(),
+        readonly: query.readonly,
+    }
+}
+
+fn param_type(param_type: CoreParamType) -> ParamType {
+    match param_type {
+        CoreParamType::Any => ParamType::Any,
+        CoreParamType::Bool => ParamType::Bool,
+        CoreParamType::Int => ParamType::Int,
+        CoreParamType::Float => ParamType::Float,
+        CoreParamType::String => ParamType::String,
+        CoreParamType::List => ParamType::List,
+        CoreParamType::Object => ParamType::Object,
+    }
+}
+
+fn core_param_type(param_type: ParamType) -> CoreParamType {
+    match param_type {
+        ParamType::Any => CoreParamType::Any,
+        ParamType::Bool => CoreParamType::Bool,
+        ParamType::Int => CoreParamType::Int,
+        ParamType::Float => CoreParamType::Float,
+        ParamType::String => CoreParamType::String,
+        ParamType::List => CoreParamType::List,
+        ParamType::Object => CoreParamType::Object,
+    }
+}
+
+/// One-line core storage summary for `NodeStatus`.
+fn core_health(core: &CoreStats) -> String {
+    match &core.path {
+        Some(path) => format!("core: {}, {} bytes at {}", core.backend, core.size_bytes, path.display()),
+        None => format!("core: {}", core.backend),
+    }
+}
+
+/// One-line WASM summary for `NodeStatus`.
+fn finance_health(state: &FinanceState) -> String {
+    match state {
+        FinanceState::Connecting => "finance: connecting".into(),
+        FinanceState::Ready => "finance: ready".into(),
+        FinanceState::Failed { reason } => format!("finance: unavailable ({})", reason),
+        FinanceState::Unknown => "finance: unknown".into(),
+    }
+}
+
+fn binding_health(binding: &MachineBinding) -> String {
+    match binding {
+        MachineBinding::Unbindable { reason } => format!("license: unbindable ({})", reason),
+        _ => "license: bound".into(),
+    }
+}
+
+fn mesh_health(phase: &MeshPhase) -> String {
+    match phase {
+        MeshPhase::Restarting { attempt, reason } => format!("mesh: restarting, attempt {} ({})", attempt, reason),
+        MeshPhase::Failed { reason } => format!("mesh: failed ({})", reason),
+        _ => "mesh: running".into(),
+    }
+}
+
+fn presence_health(table: &PresenceTable, connections: u32) -> String {
+    let known = table.nodes.len();
+    format!("presence: {} of {} known nodes heard from, {} connected", known - table.stale(), known, connections)
+}
+
+fn replication_health(outbox: &Outbox) -> String {
+    format!("replication: {} ops waiting to be gossiped, {} gossiped", outbox.depth(), outbox.published_total())
+}
+
+fn wasm_health(ctx: &NodeContext) -> String {
+    let stats = ctx.wasm.module_stats();
+    let runs: u64 = stats.values().map(|s| s.runs).sum();
+    let running = ctx.wasm.admission_stats().running;
+    match top_module_by_fuel(&stats) {
+        Some((name, top)) => format!("wasm: {} runs, {} executing, top fuel {} ({})", runs, running, name, top.fuel_used),
+        None => format!("wasm: {} runs, {} executing", runs, running),
+    }
+}
+
+fn top_module_by_fuel(stats: &HashMap<String, ModuleStats>) -> Option<(&String, &ModuleStats)> {
+    stats.iter().filter(|(_, s)| s.fuel_used > 0).max_by_key(|(_, s)| s.fuel_used)
+}
+
+pub(crate) fn job_run(run: JobRun) -> WasmJobRun {
+    WasmJobRun {
+        run: run.run,
+        started_ms: run.started_ms,
+        result: Box::new(match run.result {
+            Ok(out) => wasm_result(Ok(out)),
+            Err(e) => Response::Error(e),
+        }),
+    }
+}
+
+/// A client's run limits, with no fuel at all while an injected `Trap` is
+/// armed, so the run traps at its first instruction.
+#[cfg(feature = "fault-injection")]
+fn run_limits(ctx: &NodeContext, limits: ExecutionLimits) -> ExecutionLimits {
+    match ctx.faults.active(FaultTarget::Wasm) {
+        Some(FaultKind::Trap) => ExecutionLimits { fuel_limit: Some(0), ..limits },
+        _ => limits,
+    }
+}
+
+#[cfg(not(feature = "fault-injection"))]
+fn run_limits(_ctx: &NodeContext, limits: ExecutionLimits) -> ExecutionLimits {
+    limits
+}
+
+pub(crate) fn wasm_result(res: std::result::Result<ExecutionResult, WasmError>) -> Response {
+    match res {
+        Ok(out) => Response::WasmResult {
+            stdout: out.stdout,
+            stderr: out.stderr,
+            exit_code: out.exit_code,
+            fuel_used: out.fuel_used,
+            duration_ms: out.duration.as_millis() as u64,
+            trapped: out.trapped,
+            trap_message: out.trap.as_ref().map(|trap| trap.message.clone()),
+            peak_memory_bytes: out.peak_memory_bytes,
+            output: out.output.map(|output| String::from_utf8_lossy(&output).into_owned()),
+            trap: out.trap.map(|trap| WasmTrap {
+                code: trap.code,
+                message: trap.message,
+                backtrace: trap.backtrace,
+            }),
+            deterministic: out.deterministic,
+            logs: out
+                .logs
+                .into_iter()
+                .map(|line| WasmLogLine {
+                    level: line.level.to_string(),
+                    message: line.message,
+                })
+                .collect(),
+            logs_dropped: out.logs_dropped,
+        },
+        Err(e) => Response::Error(e.to_string()),
+    }
+}
+
+fn module_signature(signature: WasmSignature) -> ModuleSignature {
+    ModuleSignature {
+        key_id: signature.key_id,
+        signature: signature.signature,
+    }
+}
+
+fn module_info(info: ModuleInfo, stats: &HashMap<String, ModuleStats>) -> WasmModuleInfo {
+    WasmModuleInfo {
+        stats: stats.get(&info.name).map(|s| WasmModuleStats {
+            runs: s.runs,
+            failures: s.failures,
+            traps: s.traps,
+            fuel_used: s.fuel_used,
+            total_duration_ms: s.total_duration.as_millis() as u64,
+            peak_memory_bytes: s.peak_memory_bytes,
+            last_run_ms: s.last_run_ms,
+            last_revision: s.last_revision,
+        }),
+        name: info.name,
+        sha256: info.sha256,
+        size_bytes: info.size_bytes,
+        capabilities: info.manifest.capabilities.iter().map(|c| c.to_string()).collect(),
+        allowed_env: info.manifest.allowed_env,
+        namespace: info.manifest.namespace,
+        revision: info.revision,
+        source_path: info.manifest.source_path.map(|p| p.display().to_string()),
+        reload_error: info.reload_error,
+        precompiled: info.precompiled,
+        details: None,
+    }
+}
+
+fn module_details(info: &ModuleInfo) -> WasmModuleDetails {
+    let report = &info.report;
+    let limits = |l: &LimitsInfo| WasmLimits {
+        initial: l.initial,
+        maximum: l.maximum,
+        imported: l.imported,
+        shared: l.shared,
+    };
+    WasmModuleDetails {
+        imports: report
+            .imports
+            .iter()
+            .map(|i| WasmImport {
+                module: i.module.clone(),
+                name: i.name.clone(),
+                kind: i.kind.clone(),
+            })
+            .collect(),
+        exports: report
+            .exports
+            .iter()
+            .map(|e| WasmExport {
+                name: e.name.clone(),
+                kind: e.kind.clone(),
+            })
+            .collect(),
+        memories: report.memories.iter().map(limits).collect(),
+        tables: report.tables.iter().map(limits).collect(),
+        has_start: report.has_start,
+        text_format: report.text_format,
+        component: report.component,
+    }
+}
+
+fn allowlist_response(wasm: &WasmRuntime, change: impl FnOnce(&Allowlist) -> anyhow::Result<bool>) -> Response {
+    let Some(allowlist) = wasm.allowlist() else {
+        return Response::Error("No WASM allow-list is configured (set wasm.allowlist)".into());
+    };
+    match change(allowlist) {
+        Ok(changed) => Response::WasmAllowlist {
+            enforced: allowlist.mode() == AllowlistMode::Enforce,
+            hashes: allowlist.list(),
+            changed,
+        },
+        Err(e) => Response::Error(format!("{:#}", e)),
+    }
+}
+
+fn job_spec(job: WasmJobSpec) -> JobSpec {
+    JobSpec {
+        module: job.module,
+        input: job.input,
+        schedule: match job.schedule {
+            WasmJobSchedule::Interval { every_ms } => JobSchedule::Interval { every_ms },
+            WasmJobSchedule::Cron { spec } => JobSchedule::Cron { spec },
+        },
+        limits: ExecutionLimits {
+            fuel_limit: job.fuel_limit,
+            max_memory_bytes: job.max_memory_bytes.map(|b| b as usize),
+            ..Default::default()
+        },
+        overlap: match job.overlap {
+            WasmJobOverlap::Skip => OverlapPolicy::Skip,
+            WasmJobOverlap::Queue => OverlapPolicy::Queue,
+        },
+        enabled: job.enabled,
+    }
+}
+
+fn job_info(info: JobInfo) -> WasmJobInfo {
+    let spec = info.spec;
+    WasmJobInfo {
+        name: info.name,
+        job: WasmJobSpec {
+            module: spec.module,
+            input: spec.input,
+            schedule: match spec.schedule {
+                JobSchedule::Interval { every_ms } => WasmJobSchedule::Interval { every_ms },
+                JobSchedule::Cron { spec } => WasmJobSchedule::Cron { spec },
+            },
+            fuel_limit: spec.limits.fuel_limit,
+            max_memory_bytes: spec.limits.max_memory_bytes.map(|b| b as u64),
+            overlap: match spec.overlap {
+                OverlapPolicy::Skip => WasmJobOverlap::Skip,
+                OverlapPolicy::Queue => WasmJobOverlap::Queue,
+            },
+            enabled: spec.enabled,
+        },
+        runs: info.runs,
+        skipped: info.skipped,
+        running: info.running,
+        next_run_ms: info.next_run_ms,
+    }
+}
+
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::{TestNode, TestNodeOptions};
+    use sovereign_protocol::{
+        framing, CheckStatus, CoreChangeOp, CoreDataFormat, CoreImportMode, CoreParamType, CoreQueryParam, FrameCodec, Permission, SessionResume, WasmManifest, WasmPipelineStage, PROTOCOL_VERSION,
+    };
+    use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+    use std::path::Path;
+    use tokio::net::UnixStream;
+    use tokio::time::Instant;
+
+    type RawFrames = FramedRead<OwnedReadHalf, FrameCodec>;
+
+    async fn start(config: &str) -> TestNode {
+        TestNode::start_with(TestNodeOptions {
+            config: Some(config.into()),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Opens a bare connection to `node`, which does nothing on its own,
+    /// not even answer heartbeats.
+    async fn raw_connect(node: &TestNode) -> (RawFrames, OwnedWriteHalf) {
+        let IpcEndpoint::UnixSocket(path) = node.endpoint() else { unreachable!() };
+        let (reader, writer) = UnixStream::connect(path).await.unwrap().into_split();
+        (FramedRead::new(reader, FrameCodec::new(16 * 1024 * 1024)), writer)
+    }
+
+    async fn raw_send(writer: &mut OwnedWriteHalf, req: &Request) {
+        writer.write_all(&framing::encode_frame(&serde_json::to_vec(req).unwrap()).unwrap()).await.unwrap();
+    }
+
+    /// The next response, or `None` once the node closes the connection.
+    async fn raw_next(frames: &mut RawFrames) -> Option<Response> {
+        match frames.next().await? {
+            Ok(framing::Frame::Message(body)) => Some(serde_json::from_slice(&body).unwrap()),
+            Ok(other) => panic!("Unexpected frame {:?}", other),
+            Err(_) => None,
+        }
+    }
+
+    fn hello(client_name: &str) -> Request {
+        Request::Hello {
+            client_name: client_name.into(),
+            protocol_version: PROTOCOL_VERSION,
+            token: None,
+            resume: None,
+            compressions_supported: Vec::new(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drops_a_client_that_stops_answering_heartbeats() {
+        let node = start("ipc_idle_timeout_mins = 0\nipc_heartbeat_secs = 1\nipc_missed_heartbeats = 1").await;
+        let (mut frames, mut writer) = raw_connect(&node).await;
+        raw_send(&mut writer, &hello("silent")).await;
+        match raw_next(&mut frames).await {
+            Some(Response::HelloAck { heartbeat_interval_ms, idle_timeout_ms, .. }) => {
+                assert_eq!(heartbeat_interval_ms, 1000);
+                assert_eq!(idle_timeout_ms, 2000);
+            }
+            other => panic!("Expected HelloAck, got {:?}", other),
+        }
+
+        let started = Instant::now();
+        let mut heartbeats = 0;
+        while let Some(resp) = tokio::time::timeout(Duration::from_secs(10), raw_next(&mut frames)).await.unwrap() {
+            assert!(matches!(resp, Response::Heartbeat { .. }), "Unexpected {:?}", resp);
+            heartbeats += 1;
+        }
+        assert_eq!(heartbeats, 1);
+        // A tick to go idle, one to send the heartbeat, one to give up.
+        assert!(started.elapsed() < Duration::from_secs(5), "Dropped after {:?}", started.elapsed());
+
+        // A client that answers them stays connected.
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        node.status().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn closes_connections_that_send_nothing() {
+        // Idle limits are whole minutes, so this takes one. Heartbeats are
+        // kept out of the way.
+        let node = start("ipc_idle_timeout_mins = 1\nipc_idle_warning_secs = 59\nipc_heartbeat_secs = 60").await;
+        let started = Instant::now();
+        let (mut greeted, mut greeted_writer) = raw_connect(&node).await;
+        raw_send(&mut greeted_writer, &hello("idle")).await;
+        assert!(matches!(raw_next(&mut greeted).await, Some(Response::HelloAck { .. })));
+        let (mut silent, _silent_writer) = raw_connect(&node).await;
+
+        match tokio::time::timeout(Duration::from_secs(10), raw_next(&mut greeted)).await.unwrap() {
+            Some(Response::IdleWarning { closes_in_ms }) => assert_eq!(closes_in_ms, 59_000),
+            other => panic!("Expected IdleWarning, got {:?}", other),
+        }
+
+        // The testkit's own client is busy halfway through, so stays.
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        node.status().await.unwrap();
+
+        // Only a client that said Hello is warned.
+        for frames in [&mut greeted, &mut silent] {
+            assert!(tokio::time::timeout(Duration::from_secs(40), raw_next(frames)).await.unwrap().is_none());
+        }
+        assert!(started.elapsed() >= Duration::from_secs(55), "Closed after {:?}", started.elapsed());
+        let status = node.status().await.unwrap();
+        assert_eq!(status.ipc_connections.idled_out, 2);
+        assert_eq!(status.ipc_connections.open, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn turns_away_connections_past_the_cap() {
+        // The testkit's own client is one of the three.
+        let node = start("ipc_idle_timeout_mins = 0\nipc_max_connections = 3").await;
+        let (mut first, mut first_writer) = raw_connect(&node).await;
+        raw_send(&mut first_writer, &hello("first")).await;
+        assert!(matches!(raw_next(&mut first).await, Some(Response::HelloAck { .. })));
+        let (mut second, mut second_writer) = raw_connect(&node).await;
+        raw_send(&mut second_writer, &hello("second")).await;
+        assert!(matches!(raw_next(&mut second).await, Some(Response::HelloAck { .. })));
+
+        let (mut over, mut over_writer) = raw_connect(&node).await;
+        raw_send(&mut over_writer, &hello("over")).await;
+        match raw_next(&mut over).await {
+            Some(Response::Busy { max_connections }) => assert_eq!(max_connections, 3),
+            other => panic!("Expected Busy, got {:?}", other),
+        }
+        assert!(raw_next(&mut over).await.is_none());
+        // Accepted connections are unaffected.
+        raw_send(&mut first_writer, &Request::Ping).await;
+        assert!(matches!(raw_next(&mut first).await, Some(Response::Pong)));
+
+        // A closed connection frees its place.
+        drop((second, second_writer));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let (mut again, mut again_writer) = loop {
+            let (mut frames, mut writer) = raw_connect(&node).await;
+            raw_send(&mut writer, &hello("again")).await;
+            match raw_next(&mut frames).await {
+                Some(Response::HelloAck { .. }) => break (frames, writer),
+                Some(Response::Busy { .. }) if Instant::now() < deadline => tokio::time::sleep(Duration::from_millis(50)).await,
+                other => panic!("Expected HelloAck, got {:?}", other),
+            }
+        };
+        raw_send(&mut again_writer, &Request::Ping).await;
+        assert!(matches!(raw_next(&mut again).await, Some(Response::Pong)));
+
+        let connections = node.status().await.unwrap().ipc_connections;
+        assert_eq!(connections.open, 3);
+        assert_eq!(connections.accepted, 4);
+        assert!(connections.rejected >= 1);
+    }
+
+    /// A WASI command that writes its first argument to stdout and "bye"
+    /// to stderr, then exits with 3, or traps if it has no argument.
+    const ARGS_THEN_EXIT: &str = r#"(module
+      (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+      (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+      (import "wasi_snapshot_preview1" "args_sizes_get" (func $args_sizes_get (param i32 i32) (result i32)))
+      (import "wasi_snapshot_preview1" "args_get" (func $args_get (param i32 i32) (result i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 1024) "bye")
+      (func $print (param $fd i32) (param $ptr i32) (param $len i32)
+        (i32.store (i32.const 0) (local.get $ptr))
+        (i32.store (i32.const 4) (local.get $len))
+        (drop (call $fd_write (local.get $fd) (i32.const 0) (i32.const 1) (i32.const 8))))
+      (func (export "_start")
+        (drop (call $args_sizes_get (i32.const 16) (i32.const 20)))
+        (if (i32.lt_u (i32.load (i32.const 16)) (i32.const 2)) (then unreachable))
+        (drop (call $args_get (i32.const 4096) (i32.const 8192)))
+        ;; argv[1] runs from its pointer to the end of the buffer, less its NUL.
+        (call $print (i32.const 1) (i32.load (i32.const 4100))
+          (i32.sub (i32.add (i32.const 8191) (i32.load (i32.const 20))) (i32.load (i32.const 4100))))
+        (call $print (i32.const 2) (i32.const 1024) (i32.const 3))
+        (call $proc_exit (i32.const 3))))"#;
+
+    fn run_wasm(path: &Path, args: &[&str], fuel_limit: Option<u64>) -> Request {
+        Request::RunWasm {
+            path: path.display().to_string(),
+            input: String::new(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            env: Vec::new(),
+            fuel_limit,
+            signature: None,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn run_wasm_reports_output_exits_and_traps() {
+        let modules = tempfile::tempdir().unwrap();
+        let path = modules.path().join("args.wat");
+        std::fs::write(&path, ARGS_THEN_EXIT).unwrap();
+        let node = start(&format!("[wasm]\nrun_dirs = [{:?}]", modules.path())).await;
+
+        match node.client().request(run_wasm(&path, &["hello"], None)).await.unwrap() {
+            Response::WasmResult { stdout, stderr, exit_code, fuel_used, trapped, trap_message, trap, .. } => {
+                assert_eq!((stdout.as_str(), stderr.as_str()), ("hello", "bye"));
+                assert_eq!(exit_code, Some(3));
+                assert!(fuel_used.is_some_and(|fuel| fuel > 0));
+                assert!(!trapped && trap_message.is_none() && trap.is_none());
+            }
+            other => panic!("Expected WasmResult, got {:?}", other),
+        }
+
+        match node.client().request(run_wasm(&path, &[], None)).await.unwrap() {
+            Response::WasmResult { exit_code, trapped, trap_message, trap, .. } => {
+                assert_eq!(exit_code, None);
+                assert!(trapped);
+                let trap = trap.unwrap();
+                assert_eq!(trap_message.as_ref(), Some(&trap.message));
+                assert_eq!(trap.code.as_deref(), Some("UnreachableCodeReached"));
+            }
+            other => panic!("Expected WasmResult, got {:?}", other),
+        }
+
+        match node.client().request(run_wasm(&path, &["hello"], Some(10))).await.unwrap() {
+            Response::Error(e) => assert!(e.contains("ran out of fuel"), "{}", e),
+            other => panic!("Expected running out of fuel, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn license_info_answers_from_cache() {
+        // The testkit's finance backend never connects, so a chain check
+        // could not be answered.
+        let node = TestNode::start(Vec::new()).await.unwrap();
+        match node.client().request(Request::GetLicenseInfo).await.unwrap() {
+            Response::LicenseResult { valid, details, report, terms, .. } => {
+                assert!(!valid);
+                assert_eq!(details, "Never verified");
+                assert!(report.is_none());
+                // The terms come from the verifier, which never connected.
+                assert!(terms.is_none());
+            }
+            other => panic!("Expected LicenseResult, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn serves_everything_else_while_electrum_is_unreachable() {
+        let node = TestNode::start_with(TestNodeOptions {
+            config: Some("ipc_idle_timeout_mins = 0\n[finance]\nelectrum_urls = [\"tcp://127.0.0.1:1\"]".into()),
+            connect_finance: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let client = node.client();
+
+        let started = Instant::now();
+        let reason = loop {
+            match client.request(Request::GetStatus).await.unwrap() {
+                Response::Status(NodeStatus { finance: FinanceState::Failed { reason }, .. }) => break reason,
+                Response::Status(status) => assert_eq!(status.finance, FinanceState::Connecting),
+                other => panic!("Expected Status, got {:?}", other),
+            }
+            assert!(started.elapsed() < Duration::from_secs(10), "the connection attempt never failed");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        };
+
+        let query = Request::QueryCore {
+            query: "?[x] := x = 1".into(),
+            params: serde_json::json!({}),
+            timeout_ms: None,
+            readonly: true,
+            limit: None,
+        };
+        assert!(matches!(client.request(query).await.unwrap(), Response::CoreResult(_)));
+        assert!(matches!(client.request(Request::MeshPeers).await.unwrap(), Response::MeshGeneric(_)));
+        let verify = Request::VerifyLicense {
+            tx_id: "00".repeat(32),
+            developer_addr: String::new(),
+            required_sats: 0,
+        };
+        match client.request(verify).await.unwrap() {
+            Response::Unavailable { subsystem, reason: why } => assert_eq!((subsystem.as_str(), why), ("finance", reason)),
+            other => panic!("Expected Unavailable, got {:?}", other),
+        }
+        assert!(matches!(client.request(Request::GetLicenseInfo).await.unwrap(), Response::LicenseResult { valid: false, .. }));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn writes_the_endpoint_it_bound_for_clients() {
+        let node = TestNode::start(Vec::new()).await.unwrap();
+        // Written just after the mesh reports its peer id.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let discovery = loop {
+            match EndpointDiscovery::read(node.data_dir()) {
+                Ok(discovery) => break discovery,
+                Err(e) if Instant::now() >= deadline => panic!("No discovery file: {}", e),
+                Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+            }
+        };
+        assert_eq!(&discovery.endpoint, node.endpoint());
+        assert_eq!(discovery.peer_id, node.peer_id());
+        assert_eq!(discovery.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(&IpcEndpoint::discover(node.data_dir()), node.endpoint());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn clients_notice_the_node_going_away() {
+        let node = start("ipc_idle_timeout_mins = 0\nipc_session_grace_secs = 0").await;
+        let client = node.connect("watcher").await.unwrap();
+        let (tx, rx) = oneshot::channel();
+        client.on_disconnect(move || {
+            let _ = tx.send(());
+        });
+        assert_eq!(client.connection_state(), sovereign_client::ConnectionState::Connected);
+
+        node.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), rx).await.unwrap().unwrap();
+        assert_eq!(client.connection_state(), sovereign_client::ConnectionState::Disconnected);
+        assert!(client.request(Request::Ping).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shutdown_answers_requests_in_flight_before_closing() {
+        let node = start("ipc_idle_timeout_mins = 0\nipc_session_grace_secs = 0").await;
+        let slow = node.connect("slow").await.unwrap();
+        let query = tokio::spawn(async move {
+            slow.request(Request::QueryCore {
+                query: "r[n] := n = 0\nr[m] := r[n], m = n + 1\n?[n] := r[n]".into(),
+                params: serde_json::json!({}),
+                timeout_ms: Some(1500),
+                readonly: true,
+                limit: None,
+            })
+            .await
+        });
+        let started = Instant::now();
+        while !matches!(node.client().request(Request::CoreQueries).await.unwrap(), Response::CoreQueries(queries) if !queries.is_empty()) {
+            assert!(started.elapsed() < Duration::from_secs(10), "the query never showed up");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let IpcEndpoint::UnixSocket(path) = node.endpoint().clone() else { unreachable!() };
+        tokio::time::timeout(Duration::from_secs(8), node.shutdown()).await.unwrap().unwrap();
+        // The query ran out its own timeout rather than being cut off.
+        assert!(started.elapsed() >= Duration::from_millis(1500), "{:?}", started.elapsed());
+        match query.await.unwrap().unwrap() {
+            Response::CoreFailed(failure) => assert_eq!(failure.code, ErrorCode::Timeout, "{:?}", failure),
+            other => panic!("Expected CoreFailed, got {:?}", other),
+        }
+        assert!(UnixStream::connect(&path).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn answers_pipelined_requests_as_they_finish() {
+        let node = start("ipc_idle_timeout_mins = 0").await;
+        let (mut frames, mut writer) = raw_connect(&node).await;
+        raw_hello(&mut frames, &mut writer).await;
+
+        let send = |id: u64, request: Request| framing::encode_frame(&serde_json::to_vec(&RequestEnvelope { id, request }).unwrap()).unwrap();
+        let slow = Request::QueryCore {
+            query: "r[n] := n = 0\nr[m] := r[n], m = n + 1\n?[n] := r[n]".into(),
+            params: serde_json::json!({}),
+            timeout_ms: Some(1000),
+            readonly: true,
+            limit: None,
+        };
+        writer.write_all(&send(1, slow)).await.unwrap();
+        for id in 2..12 {
+            writer.write_all(&send(id, Request::Ping)).await.unwrap();
+        }
+
+        let mut answered = Vec::new();
+        while answered.len() < 11 {
+            let Some(Ok(framing::Frame::Message(body))) = frames.next().await else { panic!("The connection closed") };
+            let envelope: ResponseEnvelope = serde_json::from_slice(&body).unwrap();
+            match envelope.id {
+                1 => assert!(matches!(envelope.response, Response::CoreFailed(ref failure) if failure.code == ErrorCode::Timeout), "{:?}", envelope.response),
+                _ => assert!(matches!(envelope.response, Response::Pong), "{:?}", envelope.response),
+            }
+            answered.push(envelope.id);
+        }
+        // The pings overtook the query, each under its own id.
+        assert_eq!(answered.last(), Some(&1));
+        answered.sort();
+        assert_eq!(answered, (1..12).collect::<Vec<_>>());
+    }
+
+    async fn raw_hello(frames: &mut RawFrames, writer: &mut OwnedWriteHalf) -> u64 {
+        raw_send(writer, &hello("raw")).await;
+        match raw_next(frames).await {
+            Some(Response::HelloAck { max_frame_size, .. }) => max_frame_size,
+            other => panic!("Expected HelloAck, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn answers_an_oversized_frame_and_reads_on() {
+        let node = start("ipc_idle_timeout_mins = 0\nipc_max_frame_bytes = 4096").await;
+        let (mut frames, mut writer) = raw_connect(&node).await;
+        assert_eq!(raw_hello(&mut frames, &mut writer).await, 4096);
+
+        writer.write_all(&8000u32.to_le_bytes()).await.unwrap();
+        writer.write_all(&[b' '; 8000]).await.unwrap();
+        match raw_next(&mut frames).await {
+            Some(Response::FrameTooLarge { declared, max }) => assert_eq!((declared, max), (8000, 4096)),
+            other => panic!("Expected FrameTooLarge, got {:?}", other),
+        }
+        raw_send(&mut writer, &Request::Ping).await;
+        assert!(matches!(raw_next(&mut frames).await, Some(Response::Pong)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn refuses_a_compression_bomb_without_inflating_it() {
+        let node = start("ipc_idle_timeout_mins = 0\nipc_max_frame_bytes = 65536").await;
+        let (mut frames, mut writer) = raw_connect(&node).await;
+        let hello = Request::Hello {
+            client_name: "bomber".into(),
+            protocol_version: PROTOCOL_VERSION,
+            token: None,
+            resume: None,
+            compressions_supported: vec!["gzip".into()],
+        };
+        raw_send(&mut writer, &hello).await;
+        let compression = match raw_next(&mut frames).await {
+            Some(Response::HelloAck { compression: Some(compression), .. }) => compression,
+            other => panic!("Expected HelloAck with compression, got {:?}", other),
+        };
+        assert_eq!(compression.algorithm, Compression::Gzip);
+
+        // 8 MiB of spaces, in a few KiB, honestly declared: refused for
+        // its decompressed size, and the session goes on.
+        let bomb = FrameCompression { min_size: 0, ..compression }.encode_frame(&[b' '; 8 * 1024 * 1024]).unwrap();
+        assert!(bomb.len() < 65536);
+        writer.write_all(&bomb).await.unwrap();
+        match raw_next(&mut frames).await {
+            Some(Response::FrameTooLarge { declared, max }) => assert_eq!((declared, max), (8 * 1024 * 1024, 65536)),
+            other => panic!("Expected FrameTooLarge, got {:?}", other),
+        }
+        raw_send(&mut writer, &Request::Ping).await;
+        assert!(matches!(raw_next(&mut frames).await, Some(Response::Pong)));
+
+        // The same body declaring 1000 bytes is found out, and the
+        // connection dropped, since nothing after it can be trusted.
+        let mut lying = bomb.to_vec();
+        lying[6..10].copy_from_slice(&1000u32.to_le_bytes());
+        writer.write_all(&lying).await.unwrap();
+        match raw_next(&mut frames).await {
+            Some(Response::Error(msg)) => assert_eq!(msg, "Corrupt compressed frame: gzip declared 1000 bytes but holds more"),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        assert!(raw_next(&mut frames).await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn answers_malformed_requests_and_reads_on() {
+        let node = start("ipc_idle_timeout_mins = 0").await;
+        let (mut frames, mut writer) = raw_connect(&node).await;
+        raw_hello(&mut frames, &mut writer).await;
+
+        for (body, at) in [(&b"{not json"[..], 2), (br#"{"NoSuchRequest":{}}"#, 15), (br#"{"Ping":1}"#, 8), (br#"{"GetStatus""#, 11)] {
+            writer.write_all(&framing::encode_frame(body).unwrap()).await.unwrap();
+            match raw_next(&mut frames).await {
+                Some(Response::Error(msg)) => assert!(msg.starts_with(&format!("Invalid request at byte {}:", at)), "{}: {}", String::from_utf8_lossy(body), msg),
+                other => panic!("Expected an error for {}, got {:?}", String::from_utf8_lossy(body), other),
+            }
+            raw_send(&mut writer, &Request::Ping).await;
+            assert!(matches!(raw_next(&mut frames).await, Some(Response::Pong)));
+        }
+
+        // A bad request in an envelope is answered in one, under its id.
+        let body = br#"{"id":7,"request":{"NoSuchRequest":{}}}"#;
+        writer.write_all(&framing::encode_frame(body).unwrap()).await.unwrap();
+        match frames.next().await {
+            Some(Ok(framing::Frame::Message(body))) => {
+                let envelope: ResponseEnvelope = serde_json::from_slice(&body).unwrap();
+                assert_eq!(envelope.id, 7);
+                assert!(matches!(envelope.response, Response::Error(ref msg) if msg.starts_with("Invalid request")), "{:?}", envelope.response);
+            }
+            other => panic!("Expected an envelope, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn answers_a_truncated_frame_before_closing() {
+        let node = start("ipc_idle_timeout_mins = 0").await;
+        let (mut frames, mut writer) = raw_connect(&node).await;
+        raw_hello(&mut frames, &mut writer).await;
+
+        writer.write_all(&100u32.to_le_bytes()).await.unwrap();
+        writer.write_all(br#"{"Ping""#).await.unwrap();
+        writer.shutdown().await.unwrap();
+        assert!(matches!(raw_next(&mut frames).await, Some(Response::Error(_))));
+        assert!(raw_next(&mut frames).await.is_none());
+
+        // The node still takes new connections.
+        let client = node.connect("after").await.unwrap();
+        assert!(matches!(client.request(Request::Ping).await.unwrap(), Response::Pong));
+    }
+
+    /// Fails accepts with the scripted OS errors, in order, and connects
+    /// an in-memory client wherever the script says `None`.
+    struct ScriptedListener(std::collections::VecDeque<Option<i32>>);
+
+    impl IpcListener for ScriptedListener {
+        fn accept(&mut self) -> ipc_transport::AcceptFuture<'_> {
+            let next = self.0.pop_front().expect("the script ran out");
+            Box::pin(async move {
+                match next {
+                    Some(code) => Err(std::io::Error::from_raw_os_error(code)),
+                    None => Ok(Accepted {
+                        stream: Box::new(tokio::io::duplex(64).0),
+                        peer: None,
+                        remote: None,
+                    }),
+                }
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_accept_loop_rides_through_failed_accepts() {
+        let failures = AtomicU64::new(0);
+        let mut listener = ScriptedListener(
+            [Some(libc::ECONNABORTED), Some(libc::EMFILE), Some(libc::EMFILE), Some(libc::ENFILE), None, Some(libc::EMFILE), None, Some(libc::EBADF)].into(),
+        );
+
+        // Three pauses of 10, 20 and 40ms before the first client. The
+        // clock is paused, so only the pauses move it.
+        let started = tokio::time::Instant::now();
+        assert!(accept_next(&mut listener, None, &failures).await.is_ok());
+        assert_eq!(started.elapsed(), Duration::from_millis(70));
+        assert_eq!(failures.load(Ordering::Relaxed), 4);
+
+        // A client resets the backoff.
+        let started = tokio::time::Instant::now();
+        assert!(accept_next(&mut listener, None, &failures).await.is_ok());
+        assert_eq!(started.elapsed(), ACCEPT_BACKOFF_MIN);
+        assert_eq!(failures.load(Ordering::Relaxed), 5);
+
+        // A broken listener is handed back to shut the node down.
+        let Err(e) = accept_next(&mut listener, None, &failures).await else { panic!("Expected the listener to fail") };
+        assert_eq!(e.raw_os_error(), Some(libc::EBADF));
+        assert_eq!(failures.load(Ordering::Relaxed), 6);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn clients_that_hang_up_at_once_do_not_stop_the_node() {
+        let node = start("ipc_idle_timeout_mins = 0").await;
+        let IpcEndpoint::UnixSocket(path) = node.endpoint() else { unreachable!() };
+        for _ in 0..50 {
+            drop(UnixStream::connect(&path).await.unwrap());
+        }
+        let client = node.connect("after").await.unwrap();
+        assert!(matches!(client.request(Request::Ping).await.unwrap(), Response::Pong));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn refuses_requests_over_budget_and_closes_abusive_connections() {
+        let node = start("ipc_idle_timeout_mins = 0\n[rate_limits]\nrequests_per_second = 1\nburst = 5\nclose_after_rejections = 3").await;
+        let (mut frames, mut writer) = raw_connect(&node).await;
+        raw_hello(&mut frames, &mut writer).await;
+        for _ in 0..4 {
+            raw_send(&mut writer, &Request::Ping).await;
+            assert!(matches!(raw_next(&mut frames).await, Some(Response::Pong)));
+        }
+        for _ in 0..3 {
+            raw_send(&mut writer, &Request::Ping).await;
+            match raw_next(&mut frames).await {
+                Some(Response::RateLimited { kind, retry_after_ms }) => {
+                    assert_eq!(kind, "Ping");
+                    assert!((1..=1000).contains(&retry_after_ms), "{}", retry_after_ms);
+                }
+                other => panic!("Expected RateLimited, got {:?}", other),
+            }
+        }
+        // The third refusal within a minute closes the connection.
+        assert!(raw_next(&mut frames).await.is_none());
+
+        // Other connections keep their own budgets.
+        let other = node.connect("other").await.unwrap();
+        assert!(matches!(other.request(Request::Ping).await.unwrap(), Response::Pong));
+        match other.request(Request::GetMetrics).await.unwrap() {
+            Response::Metrics(metrics) => assert_eq!(metrics.ipc.rate_limited.get("Ping"), Some(&3)),
+            other => panic!("Expected Metrics, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn answers_a_frame_too_large_to_skip_before_closing() {
+        let node = start("ipc_idle_timeout_mins = 0\nipc_max_frame_bytes = 4096").await;
+        let (mut frames, mut writer) = raw_connect(&node).await;
+        raw_hello(&mut frames, &mut writer).await;
+
+        let declared = sovereign_protocol::MAX_FRAME_DISCARD as u32 + 1;
+        writer.write_all(&declared.to_le_bytes()).await.unwrap();
+        match raw_next(&mut frames).await {
+            Some(Response::FrameTooLarge { declared: got, max }) => assert_eq!((got, max), (declared as u64, 4096)),
+            other => panic!("Expected FrameTooLarge, got {:?}", other),
+        }
+        assert!(raw_next(&mut frames).await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn clients_refuse_requests_over_the_limit() {
+        let node = start("ipc_idle_timeout_mins = 0\nipc_max_frame_bytes = 4096").await;
+        let client = node.connect("big").await.unwrap();
+        assert_eq!(client.max_frame_size(), 4096);
+        let err = client.request(Request::QueryCore {
+            query: "x".repeat(8000),
+            params: serde_json::json!({}),
+            timeout_ms: None,
+            readonly: true,
+            limit: None,
+        }).await.unwrap_err();
+        assert!(err.to_string().contains("frame limit of 4096"), "{}", err);
+        assert!(matches!(client.request(Request::Ping).await.unwrap(), Response::Pong));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn answers_status_while_modules_spin() {
+        let modules = tempfile::tempdir().unwrap();
+        let path = modules.path().join("spin.wat");
+        std::fs::write(&path, r#"(module (func (export "_start") (loop $forever (br $forever))))"#).unwrap();
+        let node = start(&format!("ipc_idle_timeout_mins = 0\n[wasm]\nrun_dirs = [{:?}]", modules.path())).await;
+
+        let mut runs = Vec::new();
+        for i in 0..4 {
+            let client = node.connect(&format!("spinner-{}", i)).await.unwrap();
+            let request = run_wasm(&path, &[], Some(500_000_000));
+            runs.push(tokio::spawn(async move { client.request(request).await }));
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        for _ in 0..5 {
+            let asked = Instant::now();
+            node.status().await.unwrap();
+            assert!(asked.elapsed() < Duration::from_millis(500), "GetStatus took {:?}", asked.elapsed());
+        }
+        assert!(runs.iter().all(|run| !run.is_finished()), "The modules finished before the status checks");
+
+        for run in runs {
+            match run.await.unwrap().unwrap() {
+                Response::Error(e) => assert!(e.contains("ran out of fuel"), "{}", e),
+                other => panic!("Expected running out of fuel, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn wasm_runs_show_up_in_metrics() {
+        let modules = tempfile::tempdir().unwrap();
+        let path = modules.path().join("args.wat");
+        std::fs::write(&path, ARGS_THEN_EXIT).unwrap();
+        let node = start(&format!("[wasm]\nrun_dirs = [{:?}]", modules.path())).await;
+        for _ in 0..2 {
+            node.client().request(run_wasm(&path, &["hello"], None)).await.unwrap();
+        }
+        match node.client().request(Request::GetMetrics).await.unwrap() {
+            Response::Metrics(metrics) => {
+                assert_eq!((metrics.wasm.admitted, metrics.wasm.running, metrics.wasm.queued), (2, 0, 0));
+                assert_eq!(metrics.wasm.rejected_busy, 0);
+            }
+            other => panic!("Expected Metrics, got {:?}", other),
+        }
+    }
+
+    /// Copies the input stream to the output stream, exiting with the errno
+    /// if either fails.
+    const STREAM_ECHO: &str = r#"(module
+      (import "sovereign" "input_read" (func $input_read (param i32 i32) (result i32)))
+      (import "sovereign" "output_write" (func $output_write (param i32 i32) (result i32)))
+      (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+      (memory (export "memory") 1)
+      (func $check (param $n i32) (result i32)
+        (if (i32.lt_s (local.get $n) (i32.const 0)) (then (call $proc_exit (i32.sub (i32.const 0) (local.get $n)))))
+        (local.get $n))
+      (func (export "_start")
+        (local $n i32) (local $done i32)
+        (block $end (loop $more
+          (local.set $n (call $check (call $input_read (i32.const 0) (i32.const 65536))))
+          (br_if $end (i32.eqz (local.get $n)))
+          (local.set $done (i32.const 0))
+          (loop $write
+            (local.set $done (i32.add (local.get $done)
+              (call $check (call $output_write (local.get $done) (i32.sub (local.get $n) (local.get $done))))))
+            (br_if $write (i32.lt_u (local.get $done) (local.get $n))))
+          (br $more)))))"#;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streams_a_module_input_and_output_over_ipc() {
+        let modules = tempfile::tempdir().unwrap();
+        let path = modules.path().join("echo.wat");
+        std::fs::write(&path, STREAM_ECHO).unwrap();
+        let node = start(&format!("[wasm]\nrun_dirs = [{:?}]", modules.path())).await;
+
+        // Far over the frame limit, so it can only arrive streamed.
+        let input: Vec<u8> = (0..4 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let mut output = Vec::new();
+        let request = Request::RunWasmStreamed {
+            path: path.display().to_string(),
+            args: Vec::new(),
+            env: Vec::new(),
+            fuel_limit: None,
+            signature: None,
+        };
+        match node.client().run_wasm_streamed(request, &input[..], &mut output).await.unwrap() {
+            Response::WasmResult { exit_code, trapped, peak_memory_bytes, .. } => {
+                assert_eq!((exit_code, trapped), (None, false));
+                assert_eq!(peak_memory_bytes, 64 * 1024);
+            }
+            other => panic!("Expected WasmResult, got {:?}", other),
+        }
+        assert!(output == input, "Output of {} bytes differs from the input", output.len());
+        assert!(matches!(node.client().request(Request::Ping).await.unwrap(), Response::Pong));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn module_usage_reaches_info_metrics_and_status() {
+        let modules = tempfile::tempdir().unwrap();
+        let path = modules.path().join("args.wat");
+        std::fs::write(&path, ARGS_THEN_EXIT).unwrap();
+        let node = start(&format!("[wasm]\nrun_dirs = [{:?}]", modules.path())).await;
+        let client = node.client();
+        let upload = || Request::WasmUpload {
+            name: "args".into(),
+            path: path.display().to_string(),
+            manifest: WasmManifest::default(),
+            signature: None,
+            watch: false,
+        };
+        let run = |args: &[&str]| Request::RunWasmModule {
+            name: "args".into(),
+            input: String::new(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            env: Vec::new(),
+            fuel_limit: None,
+        };
+        let stats = |response| match response {
+            Response::WasmModule(info) => info.stats,
+            other => panic!("Expected WasmModule, got {:?}", other),
+        };
+        assert!(stats(client.request(upload()).await.unwrap()).is_none());
+
+        let mut fuel = 0;
+        for args in [&["one"][..], &["two"], &[]] {
+            match client.request(run(args)).await.unwrap() {
+                Response::WasmResult { fuel_used, .. } => fuel += fuel_used.unwrap(),
+                other => panic!("Expected WasmResult, got {:?}", other),
+            }
+        }
+        let info = stats(client.request(Request::WasmInfo { name: "args".into() }).await.unwrap()).unwrap();
+        assert_eq!((info.runs, info.failures, info.traps, info.fuel_used), (3, 0, 1, fuel));
+        assert_eq!(info.peak_memory_bytes, 64 * 1024);
+        assert!(info.last_run_ms.is_some());
+
+        match client.request(Request::GetMetrics).await.unwrap() {
+            Response::Metrics(metrics) => {
+                assert_eq!(metrics.wasm.module_runs, 3);
+                assert_eq!((metrics.wasm.top_module_by_fuel.as_deref(), metrics.wasm.top_module_fuel), (Some("args"), fuel));
+            }
+            other => panic!("Expected Metrics, got {:?}", other),
+        }
+        let status = node.status().await.unwrap();
+        assert_eq!(status.health_details[0], format!("wasm: 3 runs, 0 executing, top fuel args ({})", fuel));
+
+        // Re-uploading keeps the counts, noting the revision that ran last.
+        stats(client.request(upload()).await.unwrap());
+        client.request(run(&["three"])).await.unwrap();
+        let info = stats(client.request(Request::WasmInfo { name: "args".into() }).await.unwrap()).unwrap();
+        assert_eq!((info.runs, info.last_revision), (4, 1));
+        match client.request(Request::WasmList).await.unwrap() {
+            Response::WasmModules(list) => assert_eq!(list[0].stats.as_ref().map(|s| s.runs), Some(4)),
+            other => panic!("Expected WasmModules, got {:?}", other),
+        }
+
+        // Removing the module forgets them.
+        client.request(Request::WasmRemove { name: "args".into() }).await.unwrap();
+        assert!(stats(client.request(upload()).await.unwrap()).is_none());
+    }
+
+    /// Writes its environment to stdout, each `NAME=value` followed by a NUL.
+    const ECHO_ENV: &str = r#"(module
+      (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+      (import "wasi_snapshot_preview1" "environ_sizes_get" (func $environ_sizes_get (param i32 i32) (result i32)))
+      (import "wasi_snapshot_preview1" "environ_get" (func $environ_get (param i32 i32) (result i32)))
+      (memory (export "memory") 1)
+      (func (export "_start")
+        (drop (call $environ_sizes_get (i32.const 16) (i32.const 20)))
+        (drop (call $environ_get (i32.const 4096) (i32.const 8192)))
+        (i32.store (i32.const 0) (i32.const 8192))
+        (i32.store (i32.const 4) (i32.load (i32.const 20)))
+        (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))"#;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn modules_get_only_the_environment_their_manifest_allows() {
+        let modules = tempfile::tempdir().unwrap();
+        let path = modules.path().join("env.wat");
+        std::fs::write(&path, ECHO_ENV).unwrap();
+        let node = start(&format!("[wasm]\nrun_dirs = [{:?}]", modules.path())).await;
+        let client = node.client();
+        let upload = Request::WasmUpload {
+            name: "env".into(),
+            path: path.display().to_string(),
+            manifest: WasmManifest {
+                allowed_env: vec!["GREETING".into(), "SOVEREIGN_PEER_ID".into()],
+                ..Default::default()
+            },
+            signature: None,
+            watch: false,
+        };
+        assert!(matches!(client.request(upload).await.unwrap(), Response::WasmModule(_)));
+        let env = vec![("GREETING".to_string(), "hi".to_string()), ("SECRET".to_string(), "hunter2".to_string())];
+
+        let run = Request::RunWasmModule {
+            name: "env".into(),
+            input: String::new(),
+            args: Vec::new(),
+            env: env.clone(),
+            fuel_limit: None,
+        };
+        match client.request(run).await.unwrap() {
+            Response::WasmResult { stdout, .. } => {
+                assert_eq!(stdout, format!("SOVEREIGN_PEER_ID={}\0GREETING=hi\0", node.peer_id()))
+            }
+            other => panic!("Expected WasmResult, got {:?}", other),
+        }
+        // A path has no manifest, so nothing gets through.
+        let mut run = run_wasm(&path, &[], None);
+        if let Request::RunWasm { env: requested, .. } = &mut run {
+            *requested = env;
+        }
+        match client.request(run).await.unwrap() {
+            Response::WasmResult { stdout, .. } => assert_eq!(stdout, ""),
+            other => panic!("Expected WasmResult, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn runs_pipelines_of_registered_modules_over_ipc() {
+        let modules = tempfile::tempdir().unwrap();
+        let abi = |run: &str| {
+            format!(
+                r#"(module (memory (export "memory") 1) (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+                  (func (export "run") (param $ptr i32) (param $len i32) (result i32 i32) {}))"#,
+                run
+            )
+        };
+        let node = start(&format!("[wasm]\nrun_dirs = [{:?}]", modules.path())).await;
+        let client = node.client();
+        // `first` returns its input without the first byte.
+        for (name, run) in [("first", "(i32.add (local.get $ptr) (i32.const 1)) (i32.sub (local.get $len) (i32.const 1))"), ("trap", "unreachable")] {
+            let path = modules.path().join(format!("{}.wat", name));
+            std::fs::write(&path, abi(run)).unwrap();
+            let upload = Request::WasmUpload {
+                name: name.into(),
+                path: path.display().to_string(),
+                manifest: WasmManifest::default(),
+                signature: None,
+                watch: false,
+            };
+            assert!(matches!(client.request(upload).await.unwrap(), Response::WasmModule(_)));
+        }
+        let pipeline = |names: &[&str]| Request::RunWasmPipeline {
+            stages: names
+                .iter()
+                .map(|name| WasmPipelineStage {
+                    module: name.to_string(),
+                    fuel_limit: None,
+                    max_memory_bytes: None,
+                })
+                .collect(),
+            input: "abcd".into(),
+            timeout_ms: None,
+            total_fuel: None,
+        };
+
+        match client.request(pipeline(&["first", "first", "first"])).await.unwrap() {
+            Response::WasmPipelineResult { stages, output, failure } => {
+                assert_eq!((stages.len(), output.as_str()), (3, "d"));
+                assert!(failure.is_none());
+            }
+            other => panic!("Expected WasmPipelineResult, got {:?}", other),
+        }
+        match client.request(pipeline(&["first", "trap", "first"])).await.unwrap() {
+            Response::WasmPipelineResult { stages, output, failure } => {
+                assert_eq!((stages.len(), output.as_str()), (2, ""));
+                assert!(matches!(&stages[1], Response::WasmResult { trapped: true, .. }));
+                let failure = failure.unwrap();
+                assert_eq!((failure.stage, failure.module.as_str()), (1, "trap"));
+            }
+            other => panic!("Expected WasmPipelineResult, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lists_and_describes_core_relations() {
+        let node = TestNode::start(Vec::new()).await.unwrap();
+        let client = node.client();
+        for query in [":create people {id: Int => name: String?}", "?[id, name] <- [[1, 'ada']] :put people {id => name}"] {
+            let request = Request::QueryCore {
+                query: query.into(),
+                params: serde_json::json!({}),
+                timeout_ms: None,
+                readonly: false,
+                limit: None,
+            };
+            assert!(matches!(client.request(request).await.unwrap(), Response::CoreResult(_)));
+        }
+
+        match client.request(Request::CoreListRelations).await.unwrap() {
+            Response::CoreRelations(relations) => {
+                let people = relations.iter().find(|r| r.name == "people").unwrap();
+                assert_eq!((people.arity, people.keys, people.rows), (2, 1, 1));
+            }
+            other => panic!("Expected CoreRelations, got {:?}", other),
+        }
+        match client.request(Request::CoreDescribe { name: "people".into() }).await.unwrap() {
+            Response::CoreSchema(schema) => {
+                let columns: Vec<(&str, &str, bool)> =
+                    schema.columns.iter().map(|c| (c.name.as_str(), c.column_type.as_str(), c.key)).collect();
+                assert_eq!(columns, [("id", "Int", true), ("name", "String?", false)]);
+                assert_eq!(schema.rows, 1);
+            }
+            other => panic!("Expected CoreSchema, got {:?}", other),
+        }
+        let hostile = client.request(Request::CoreDescribe { name: "people} ::remove people {".into() }).await.unwrap();
+        assert!(matches!(hostile, Response::CoreFailed(CoreFailure { code: ErrorCode::InvalidName, .. })), "{:?}", hostile);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cancels_a_running_core_query() {
+        let node = TestNode::start(Vec::new()).await.unwrap();
+        let runaway = node.connect("runaway").await.unwrap();
+        let query = tokio::spawn(async move {
+            runaway
+                .request(Request::QueryCore {
+                    query: "r[n] := n = 0\nr[m] := r[n], m = n + 1\n?[n] := r[n]".into(),
+                    params: serde_json::json!({}),
+                    timeout_ms: Some(60_000),
+                    readonly: true,
+                    limit: None,
+                })
+                .await
+        });
+
+        let client = node.client();
+        let started = Instant::now();
+        let id = loop {
+            match client.request(Request::CoreQueries).await.unwrap() {
+                Response::CoreQueries(queries) if !queries.is_empty() => break queries[0].query_id,
+                Response::CoreQueries(_) => {}
+                other => panic!("Expected CoreQueries, got {:?}", other),
+            }
+            assert!(started.elapsed() < Duration::from_secs(10), "the query never showed up");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        // Other queries are still answered meanwhile.
+        assert!(matches!(client.request(Request::GetStatus).await.unwrap(), Response::Status(_)));
+        assert!(matches!(client.request(Request::Cancel { execution_id: id }).await.unwrap(), Response::Cancelled { found: true, .. }));
+        match tokio::time::timeout(Duration::from_secs(10), query).await.unwrap().unwrap().unwrap() {
+            Response::CoreFailed(failure) => assert_eq!(failure.code, ErrorCode::Cancelled, "{:?}", failure),
+            other => panic!("Expected CoreFailed, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn readonly_queries_cannot_write() {
+        let node = TestNode::start(Vec::new()).await.unwrap();
+        let query = |query: &str, readonly| Request::QueryCore {
+            query: query.into(),
+            params: serde_json::json!({}),
+            timeout_ms: None,
+            readonly,
+            limit: None,
+        };
+        let client = node.client();
+        assert!(matches!(client.request(query(":create notes {k: Int}", false)).await.unwrap(), Response::CoreResult(_)));
+        match client.request(query("?[k] <- [[1]] :put notes {k}", true)).await.unwrap() {
+            Response::CoreFailed(failure) => assert_eq!(failure.code, ErrorCode::ReadOnlyViolation),
+            other => panic!("Expected CoreFailed, got {:?}", other),
+        }
+        match client.request(query("?[k] := *notes{k}", true)).await.unwrap() {
+            Response::CoreResult(result) => assert_eq!(result["rows"], serde_json::json!([])),
+            other => panic!("Expected CoreResult, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_second_connection_reads_committed_state_while_a_session_is_open() {
+        let node = TestNode::start(Vec::new()).await.unwrap();
+        let query = |query: &str| Request::QueryCore {
+            query: query.into(),
+            params: serde_json::json!({}),
+            timeout_ms: Some(10_000),
+            readonly: false,
+            limit: None,
+        };
+        let rows = |resp: Response| match resp {
+            Response::CoreResult(result) => result["rows"].clone(),
+            other => panic!("Expected CoreResult, got {:?}", other),
+        };
+        let client = node.client();
+        assert!(matches!(client.request(query(":create notes {k: Int}")).await.unwrap(), Response::CoreResult(_)));
+        assert!(matches!(client.request(query("?[k] <- [[1]] :put notes {k}")).await.unwrap(), Response::CoreResult(_)));
+        let Response::CoreSession { session_id } = client.request(Request::CoreBegin).await.unwrap() else { panic!() };
+        let exec = Request::CoreExec {
+            session_id,
+            query: "?[k] <- [[2]] :put notes {k}".into(),
+            params: serde_json::json!({}),
+        };
+        assert!(matches!(client.request(exec).await.unwrap(), Response::CoreResult(_)));
+
+        let other = node.connect("other").await.unwrap();
+        match other.request(Request::CoreBegin).await.unwrap() {
+            Response::CoreFailed(failure) => assert_eq!(failure.code, ErrorCode::TransactionOpen),
+            other => panic!("Expected CoreFailed, got {:?}", other),
+        }
+        // Reads see what is committed, not the open session's write.
+        assert_eq!(rows(other.request(query("?[k] := *notes{k}")).await.unwrap()), serde_json::json!([[1]]));
+        // A write waits for the session to end.
+        let write = tokio::spawn(async move { other.request(query("?[k] <- [[3]] :put notes {k}")).await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!write.is_finished());
+        let committed = client.request(Request::CoreCommit { session_id }).await.unwrap();
+        assert!(matches!(committed, Response::CoreSessionClosed { committed: true, .. }), "{:?}", committed);
+        let written = tokio::time::timeout(Duration::from_secs(10), write).await.unwrap().unwrap().unwrap();
+        assert!(matches!(written, Response::CoreResult(_)), "{:?}", written);
+        assert_eq!(rows(client.request(query("?[k] := *notes{k}")).await.unwrap()), serde_json::json!([[1], [2], [3]]));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn imports_and_exports_relations_over_ipc() {
+        let node = TestNode::start(Vec::new()).await.unwrap();
+        let client = node.client();
+        let create = Request::QueryCore {
+            query: ":create people {id: Int => name: String}".into(),
+            params: serde_json::json!({}),
+            timeout_ms: None,
+            readonly: false,
+            limit: None,
+        };
+        assert!(matches!(client.request(create).await.unwrap(), Response::CoreResult(_)));
+
+        // Several data frames' worth, with one bad row.
+        let mut csv = String::from("id,name\n");
+        for id in 0..20_000 {
+            csv.push_str(&format!("{},person {}\n", id, id));
+        }
+        csv.push_str("oops,nobody\n");
+        match client.import_core("people", CoreDataFormat::Csv, CoreImportMode::Append, csv.as_bytes()).await.unwrap() {
+            Response::CoreImported(summary) => {
+                assert_eq!((summary.rows_read, summary.inserted, summary.rejected), (20_001, 20_000, 1));
+                assert_eq!(summary.rejects[0].line, 20_002);
+            }
+            other => panic!("Expected CoreImported, got {:?}", other),
+        }
+
+        let mut exported = Vec::new();
+        match client.export_core("people", CoreDataFormat::Csv, &mut exported).await.unwrap() {
+            Response::CoreExported { rows } => assert_eq!(rows, 20_000),
+            other => panic!("Expected CoreExported, got {:?}", other),
+        }
+        assert_eq!(exported, csv.trim_end_matches("oops,nobody\n").as_bytes());
+        // The connection is usable again once the stream has ended.
+        assert!(matches!(client.request(Request::Ping).await.unwrap(), Response::Pong));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn asserts_and_retracts_facts_over_ipc() {
+        let node = TestNode::start(Vec::new()).await.unwrap();
+        let client = node.client();
+        let create = Request::QueryCore {
+            query: ":create people {id: Int => name: String}".into(),
+            params: serde_json::json!({}),
+            timeout_ms: None,
+            readonly: false,
+            limit: None,
+        };
+        assert!(matches!(client.request(create).await.unwrap(), Response::CoreResult(_)));
+
+        let rows = (0..2500).map(|i| serde_json::json!({"id": i, "name": format!("p{}", i)})).collect();
+        let asserted = client.request(Request::CoreAssert { name: "people".into(), rows }).await.unwrap();
+        assert!(matches!(asserted, Response::CoreAsserted { rows: 2500 }), "{:?}", asserted);
+        let keys = vec![serde_json::json!(1), serde_json::json!([2]), serde_json::json!({"id": 9999})];
+        let retracted = client.request(Request::CoreRetract { name: "people".into(), keys }).await.unwrap();
+        assert!(matches!(retracted, Response::CoreRetracted { rows: 2 }), "{:?}", retracted);
+
+        let mismatch = vec![serde_json::json!({"id": 1, "name": 7})];
+        let refused = client.request(Request::CoreAssert { name: "people".into(), rows: mismatch }).await.unwrap();
+        assert!(matches!(refused, Response::CoreFailed(CoreFailure { code: ErrorCode::InvalidRow, .. })), "{:?}", refused);
+        let count = Request::QueryCore {
+            query: "?[count(id)] := *people{id}".into(),
+            params: serde_json::json!({}),
+            timeout_ms: None,
+            readonly: true,
+            limit: None,
+        };
+        match client.request(count).await.unwrap() {
+            Response::CoreResult(result) => assert_eq!(result["rows"], serde_json::json!([[2498]])),
+            other => panic!("Expected CoreResult, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn finds_nearest_neighbours_over_ipc() {
+        let node = TestNode::start(Vec::new()).await.unwrap();
+        let client = node.client();
+        for query in [
+            ":create points {id: Int => side: String, at: <F32; 2>}",
+            "::hnsw create points:at_hnsw {fields: [at], dim: 2, dtype: F32, distance: L2, m: 8, ef_construction: 32}",
+        ] {
+            let request = Request::QueryCore {
+                query: query.into(),
+                params: serde_json::json!({}),
+                timeout_ms: None,
+                readonly: false,
+                limit: None,
+            };
+            let response = client.request(request).await.unwrap();
+            assert!(matches!(response, Response::CoreResult(_)), "{:?}", response);
+        }
+        // A ten by ten grid, its left half "west".
+        let rows = (0..100)
+            .map(|i| {
+                let side = if i % 10 < 5 { "west" } else { "east" };
+                serde_json::json!([i, side, [(i % 10) as f64, (i / 10) as f64]])
+            })
+            .collect();
+        assert!(matches!(
+            client.request(Request::CoreAssert { name: "points".into(), rows }).await.unwrap(),
+            Response::CoreAsserted { rows: 100 }
+        ));
+
+        let knn = |vector: Vec<f64>, k, filters| Request::CoreKnn {
+            name: "points".into(),
+            column: "at".into(),
+            vector,
+            k,
+            filters,
+        };
+        match client.request(knn(vec![7.1, 3.0], 3, serde_json::Value::Null)).await.unwrap() {
+            Response::CoreResult(result) => {
+                assert_eq!(result["headers"][0], "distance");
+                let ids: Vec<i64> = result["rows"].as_array().unwrap().iter().map(|row| row[1].as_i64().unwrap()).collect();
+                assert_eq!(ids[0], 37);
+                assert_eq!(ids.len(), 3);
+            }
+            other => panic!("Expected CoreResult, got {:?}", other),
+        }
+        match client.request(knn(vec![7.1, 3.0], 1, serde_json::json!({"side": "west"}))).await.unwrap() {
+            Response::CoreResult(result) => assert_eq!(result["rows"][0][1], 34),
+            other => panic!("Expected CoreResult, got {:?}", other),
+        }
+        let mismatch = client.request(knn(vec![1.0, 2.0, 3.0], 1, serde_json::Value::Null)).await.unwrap();
+        assert!(matches!(mismatch, Response::CoreFailed(CoreFailure { code: ErrorCode::InvalidVector, .. })), "{:?}", mismatch);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streams_query_rows_and_stops_when_cancelled() {
+        let node = TestNode::start(Vec::new()).await.unwrap();
+        let client = node.client();
+        let streamed = |query: &str| Request::QueryCoreStreamed {
+            query: query.into(),
+            params: serde_json::json!({}),
+            timeout_ms: None,
+            readonly: true,
+            limit: None,
+        };
+
+        let mut output = Vec::new();
+        match client.query_core_streamed(streamed("?[n] := n in int_range(1000000)"), &mut output).await.unwrap() {
+            Response::CoreStreamed { headers, rows, .. } => assert_eq!((headers, rows), (vec!["n".to_string()], 1_000_000)),
+            other => panic!("Expected CoreStreamed, got {:?}", other),
+        }
+        let lines: Vec<&[u8]> = output.split(|&b| b == b'\n').filter(|line| !line.is_empty()).collect();
+        assert_eq!(lines.len(), 1_000_000);
+        assert_eq!((lines[0], lines[999_999]), (&b"[0]"[..], &b"[999999]"[..]));
+
+        // A client that stops reading holds the rows back at the node rather
+        // than letting them pile up, and Cancel then ends the stream.
+        let (sink, mut source) = tokio::io::duplex(64 * 1024);
+        let slow = node.connect("slow").await.unwrap();
+        let query = tokio::spawn(async move { slow.query_core_streamed(streamed("?[n] := n in int_range(1000000)"), sink).await });
+        let mut received = vec![0u8; 64 * 1024];
+        source.read_exact(&mut received).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let id = match client.request(Request::CoreQueries).await.unwrap() {
+            Response::CoreQueries(queries) => {
+                assert_eq!(queries.len(), 1, "the stream ran ahead of its reader");
+                queries[0].query_id
+            }
+            other => panic!("Expected CoreQueries, got {:?}", other),
+        };
+        assert!(matches!(client.request(Request::Cancel { execution_id: id }).await.unwrap(), Response::Cancelled { found: true, .. }));
+        source.read_to_end(&mut received).await.unwrap();
+        match tokio::time::timeout(Duration::from_secs(10), query).await.unwrap().unwrap().unwrap() {
+            Response::CoreFailed(failure) => assert_eq!(failure.code, ErrorCode::Cancelled, "{:?}", failure),
+            other => panic!("Expected CoreFailed, got {:?}", other),
+        }
+        // What arrived is what the queues and socket buffers held, a fixed
+        // amount well short of the result.
+        let rows = received.iter().filter(|&&b| b == b'\n').count();
+        assert!(rows > 0 && rows < 500_000, "{} rows arrived", rows);
+        assert!(matches!(client.request(Request::CoreQueries).await.unwrap(), Response::CoreQueries(queries) if queries.is_empty()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn readonly_clients_may_run_named_queries_readonly() {
+        let node = start(
+            r#"ipc_idle_timeout_mins = 0
+[[access.tokens]]
+token = "reader-token-0123456789"
+name = "reader"
+permissions = ["read_status", "query_core_readonly"]"#,
+        )
+        .await;
+        let admin = node.client();
+        let create = Request::QueryCore {
+            query: ":create people {id: Int => name: String}".into(),
+            params: serde_json::json!({}),
+            timeout_ms: None,
+            readonly: false,
+            limit: None,
+        };
+        assert!(matches!(admin.request(create).await.unwrap(), Response::CoreResult(_)));
+        for (name, query) in [
+            ("lookup", "?[name] := *people{id: $id, name}"),
+            ("add", "?[id, name] <- [[$id, 'someone']] :put people {id => name}"),
+        ] {
+            let register = Request::CoreRegisterQuery {
+                name: name.into(),
+                query: query.into(),
+                params: vec![CoreQueryParam {
+                    name: "id".into(),
+                    param_type: CoreParamType::Int,
+                    required: true,
+                }],
+                readonly: name == "lookup",
+            };
+            let registered = admin.request(register).await.unwrap();
+            assert!(matches!(registered, Response::CoreNamedQuery(_)), "{:?}", registered);
+        }
+        let run = |name: &str, id: serde_json::Value, readonly| Request::CoreRunNamed {
+            name: name.into(),
+            params: serde_json::json!({ "id": id }),
+            timeout_ms: None,
+            readonly,
+        };
+        assert!(matches!(admin.request(run("add", 1.into(), false)).await.unwrap(), Response::CoreResult(_)));
+
+        let reader = node.connect_with_token("reader", "reader-token-0123456789").await.unwrap();
+        match reader.request(run("lookup", 1.into(), true)).await.unwrap() {
+            Response::CoreResult(result) => assert_eq!(result["rows"], serde_json::json!([["someone"]])),
+            other => panic!("Expected CoreResult, got {:?}", other),
+        }
+        let denied = reader.request(run("lookup", 1.into(), false)).await.unwrap();
+        assert!(matches!(denied, Response::PermissionDenied { permission: Permission::QueryCoreWrite, .. }), "{:?}", denied);
+        // A writing query asked to run read-only fails rather than writes.
+        let refused = reader.request(run("add", 2.into(), true)).await.unwrap();
+        assert!(matches!(refused, Response::CoreFailed(CoreFailure { code: ErrorCode::ReadOnlyViolation, .. })), "{:?}", refused);
+        let mistyped = reader.request(run("lookup", "one".into(), true)).await.unwrap();
+        assert!(matches!(mistyped, Response::CoreFailed(CoreFailure { code: ErrorCode::InvalidParams, .. })), "{:?}", mistyped);
+        let removed = reader.request(Request::CoreRemoveNamed { name: "lookup".into() }).await.unwrap();
+        assert!(matches!(removed, Response::PermissionDenied { .. }), "{:?}", removed);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pushes_watched_changes_until_unwatched() {
+        let node = TestNode::start(Vec::new()).await.unwrap();
+        let writer = node.client();
+        let create = Request::QueryCore {
+            query: ":create people {id: Int => name: String, age: Int}".into(),
+            params: serde_json::json!({}),
+            timeout_ms: None,
+            readonly: false,
+            limit: None,
+        };
+        assert!(matches!(writer.request(create).await.unwrap(), Response::CoreResult(_)));
+
+        let watcher = node.connect("watcher").await.unwrap();
+        let mut pushes = watcher.pushes();
+        let watch = Request::CoreWatch {
+            relation: "people".into(),
+            filter: Some("age > 40".into()),
+        };
+        let Response::CoreWatching { watch_id } = watcher.request(watch).await.unwrap() else { panic!("not watching") };
+
+        let assert_rows = |rows: serde_json::Value| Request::CoreAssert {
+            name: "people".into(),
+            rows: rows.as_array().unwrap().clone(),
+        };
+        writer.request(assert_rows(serde_json::json!([[1, "ada", 36], [2, "alan", 41]]))).await.unwrap();
+        match tokio::time::timeout(Duration::from_secs(5), pushes.recv()).await.unwrap().unwrap() {
+            Response::CoreChanged(change) => {
+                assert_eq!((change.watch_id, change.relation.as_str(), change.op), (watch_id, "people", CoreChangeOp::Put));
+                assert_eq!(change.rows, [[serde_json::json!(2), serde_json::json!("alan"), serde_json::json!(41)]]);
+            }
+            other => panic!("Expected CoreChanged, got {:?}", other),
+        }
+
+        let unwatched = watcher.request(Request::CoreUnwatch { watch_id }).await.unwrap();
+        assert!(matches!(unwatched, Response::CoreUnwatched { found: true, .. }), "{:?}", unwatched);
+        writer.request(assert_rows(serde_json::json!([[3, "grace", 85]]))).await.unwrap();
+        let late = tokio::time::timeout(Duration::from_millis(750), pushes.recv()).await;
+        assert!(late.is_err(), "{:?}", late);
+        let again = watcher.request(Request::CoreUnwatch { watch_id }).await.unwrap();
+        assert!(matches!(again, Response::CoreUnwatched { found: false, .. }), "{:?}", again);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn confined_clients_cannot_watch_or_export_other_namespaces() {
+        let node = TestNode::start_with(TestNodeOptions {
+            tokens: vec![("tenant".into(), TENANT_TOKEN.into())],
+            config: Some("[core.namespaces]\n\"token:tenant\" = \"tenant\"\n".into()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let create = |query: &str| Request::QueryCore {
+            query: query.into(),
+            params: serde_json::json!({}),
+            timeout_ms: None,
+            readonly: false,
+            limit: None,
+        };
+        let created = node.client().request(create(":create other.secret {k: String => v: Int}")).await.unwrap();
+        assert!(matches!(created, Response::CoreResult(_)), "{:?}", created);
+
+        let tenant = node.connect_with_token("confined-tenant", TENANT_TOKEN).await.unwrap();
+        let denied = |resp: Response| matches!(resp, Response::CoreFailed(CoreFailure { code: ErrorCode::NamespaceDenied, .. }));
+        let watch = Request::CoreWatch {
+            relation: "other.secret".into(),
+            filter: None,
+        };
+        let resp = tenant.request(watch).await.unwrap();
+        assert!(denied(resp.clone()), "{:?}", resp);
+        let export = Request::CoreExport {
+            name: "other.secret".into(),
+            format: CoreDataFormat::JsonLines,
+        };
+        let resp = tenant.request(export).await.unwrap();
+        assert!(denied(resp.clone()), "{:?}", resp);
+
+        // Its own relations it may watch, by the names its queries use.
+        assert!(matches!(tenant.request(create(":create notes {k: String}")).await.unwrap(), Response::CoreResult(_)));
+        let own = Request::CoreWatch {
+            relation: "notes".into(),
+            filter: None,
+        };
+        let resp = tenant.request(own).await.unwrap();
+        assert!(matches!(resp, Response::CoreWatching { .. }), "{:?}", resp);
+    }
+
+    const TENANT_TOKEN: &str = "tenant-access-token-0123";
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_confined_user_stays_confined_under_any_name_or_without_hello() {
+        let node = TestNode::start_with(TestNodeOptions {
+            tokens: vec![("admin".into(), TENANT_TOKEN.into())],
+            config: Some(format!("[core.namespaces]\n\"uid:{}\" = \"tenant\"\n", ipc_transport::own_uid())),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let create = |name: &str| Request::QueryCore {
+            query: format!(":create {} {{k: String}}", name),
+            params: serde_json::json!({}),
+            timeout_ms: None,
+            readonly: false,
+            limit: None,
+        };
+
+        // The name a client says Hello with has no say in its namespace.
+        let renamed = node.connect("admin").await.unwrap();
+        assert!(matches!(renamed.request(create("renamed")).await.unwrap(), Response::CoreResult(_)));
+        // Nor does skipping Hello take it out of the namespace.
+        let (mut frames, mut writer) = raw_connect(&node).await;
+        raw_send(&mut writer, &create("unnamed")).await;
+        let resp = raw_next(&mut frames).await.unwrap();
+        assert!(matches!(resp, Response::CoreResult(_)), "{:?}", resp);
+        let resp = renamed.request(create("other.escaped")).await.unwrap();
+        assert!(matches!(resp, Response::CoreFailed(CoreFailure { code: ErrorCode::NamespaceDenied, .. })), "{:?}", resp);
+
+        // A token is a principal of its own, and this one is not confined.
+        let admin = node.connect_with_token("admin", TENANT_TOKEN).await.unwrap();
+        let Response::CoreRelations(relations) = admin.request(Request::CoreListRelations).await.unwrap() else { panic!("no relations") };
+        let mut names: Vec<_> = relations.iter().map(|r| r.name.as_str()).filter(|name| !name.starts_with("sovereign_")).collect();
+        names.sort();
+        assert_eq!(names, ["tenant.renamed", "tenant.unnamed"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_subscriber_gets_mesh_and_license_pushes_in_order() {
+        // The mesh actor, as far as `track_mesh` sees it.
+        let (mesh, mut commands) = mpsc::channel(8);
+        let (mesh_events_tx, mesh_events) = oneshot::channel();
+        tokio::spawn(async move {
+            let mut mesh_events = Some(mesh_events_tx);
+            while let Some(command) = commands.recv().await {
+                match command {
+                    MeshCommand::GetPeerId(tx) => drop(tx.send("local".into())),
+                    MeshCommand::SubscribeEvents(events) => drop(mesh_events.take().map(|tx| tx.send(events))),
+                    _ => {}
+                }
+            }
+        });
+        let state = Arc::new(watch::Sender::new(SharedState {
+            peer_id: "Initializing...".into(),
+            connections: 0,
+            listen_addrs: Vec::new(),
+            mesh_phase: MeshPhase::Ready,
+            license_active: false,
+            license_report: None,
+            presence: None,
+        }));
+        let bus = Arc::new(EventBus::new());
+        let mut subscription = Subscription::default();
+        subscription.subscribe(&bus, vec![EventTopic::Mesh, EventTopic::License]);
+        tokio::spawn(track_mesh(mesh, state.clone(), bus.clone(), |_| {}));
+        tokio::spawn(event_bus::publish_license(state.subscribe(), bus.clone()));
+
+        let mesh_events = tokio::time::timeout(Duration::from_secs(5), mesh_events).await.unwrap().unwrap();
+        mesh_events.send(MeshEvent::PeerConnected("remote".into())).unwrap();
+        let next = async { tokio::time::timeout(Duration::from_secs(5), subscription.next()).await.unwrap() };
+        match next.await {
+            Response::Event { seq: 1, event: NodeEvent::PeerConnected { peer } } => assert_eq!(peer, "remote"),
+            other => panic!("Expected the peer connecting, got {:?}", other),
+        }
+
+        // As the license monitor flips it.
+        state.send_modify(|s| s.license_active = true);
+        let next = async { tokio::time::timeout(Duration::from_secs(5), subscription.next()).await.unwrap() };
+        assert!(matches!(next.await, Response::Event { seq: 2, event: NodeEvent::LicenseChanged { active: true, .. } }));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn status_reports_health_against_the_configured_thresholds() {
+        // Whatever the disk the tests run on has free.
+        let node = start("ipc_idle_timeout_mins = 0\n[health]\ndisk_free_degraded_mb = 0\ndisk_free_critical_mb = 0").await;
+        let status = node.status().await.unwrap();
+        assert_eq!((status.system_health.as_str(), &status.health), ("OK", &HealthLevel::Ok));
+        assert!(status.resources.rss_bytes.is_some_and(|rss| rss > 0));
+        assert!(status.resources.data_dir_free_bytes.is_some());
+
+        // Any node uses more than a megabyte.
+        let node = start("ipc_idle_timeout_mins = 0\n[health]\nrss_degraded_mb = 1").await;
+        let status = node.status().await.unwrap();
+        assert_eq!(status.system_health, "DEGRADED");
+        assert!(matches!(&status.health, HealthLevel::Degraded { reasons } if reasons.len() == 1 && reasons[0].starts_with("memory: ")), "{:?}", status.health);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn says_how_it_was_built_in_hello_status_and_get_version() {
+        let node = start("ipc_idle_timeout_mins = 0").await;
+        let build = crate::build_info::build_info();
+        assert_eq!(node.client().node_build(), Some(&build));
+        assert_eq!(node.status().await.unwrap().version, build);
+        match node.client().request(Request::GetVersion).await.unwrap() {
+            Response::Version(version) => assert_eq!(version, build),
+            other => panic!("Expected Version, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_read_only_token_may_read_status_but_not_run_modules() {
+        let node = start(
+            r#"ipc_idle_timeout_mins = 0
+[[access.tokens]]
+token = "dashboard-token-0123456789"
+name = "dashboard"
+permissions = ["read_status"]"#,
+        )
+        .await;
+        let dashboard = node.connect_with_token("dashboard", "dashboard-token-0123456789").await.unwrap();
+        assert!(matches!(dashboard.request(Request::GetStatus).await.unwrap(), Response::Status(_)));
+        let denied = dashboard.request(run_wasm(Path::new("/nowhere.wasm"), &[], None)).await.unwrap();
+        match denied {
+            Response::PermissionDenied { kind, permission } => assert_eq!((kind.as_str(), permission), ("RunWasm", Permission::RunWasm)),
+            other => panic!("Expected PermissionDenied, got {:?}", other),
+        }
+        // Denied, not dropped: the connection carries on.
+        assert!(matches!(dashboard.request(Request::Ping).await.unwrap(), Response::Pong));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn audits_who_asked_what_without_what_they_sent() {
+        let node = start(
+            r#"ipc_idle_timeout_mins = 0
+[ipc_audit]
+enabled = true
+[[access.tokens]]
+token = "auditor-token-0123456789"
+name = "auditor"
+permissions = ["read_status", "query_core_readonly"]"#,
+        )
+        .await;
+        let auditor = node.connect_with_token("auditor", "auditor-token-0123456789").await.unwrap();
+        let query = Request::QueryCore {
+            query: "?[secret] <- [['hunter2-planted']]".into(),
+            params: serde_json::json!({}),
+            timeout_ms: None,
+            readonly: true,
+            limit: None,
+        };
+        assert!(matches!(auditor.request(query).await.unwrap(), Response::CoreResult(_)));
+        assert!(matches!(auditor.request(Request::AuditTail { limit: 10 }).await.unwrap(), Response::PermissionDenied { .. }));
+
+        let Response::IpcAudit { entries, dropped } = node.client().request(Request::AuditTail { limit: 100 }).await.unwrap() else {
+            panic!("Expected IpcAudit");
+        };
+        assert_eq!(dropped, 0);
+        let asked: Vec<_> = entries.iter().filter(|e| e.client == "auditor").map(|e| (e.request.as_str(), e.outcome.as_str())).collect();
+        assert_eq!(asked, [("QueryCore", "ok"), ("AuditTail", "permission_denied")]);
+        let by_token = entries.iter().find(|e| e.request == "QueryCore").unwrap();
+        assert_eq!(by_token.token_id.as_deref(), Some(access::token_id("auditor-token-0123456789").as_str()));
+        let log = std::fs::read_to_string(node.data_dir().join("logs/ipc-audit.jsonl")).unwrap();
+        assert!(!log.contains("hunter2-planted") && !log.contains("auditor-token-0123456789"), "{}", log);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_resumed_session_keeps_its_watches_and_numbers_on() {
+        let node = start("ipc_idle_timeout_mins = 0\nipc_session_grace_secs = 30").await;
+        let writer = node.client();
+        let create = Request::QueryCore {
+            query: ":create people {id: Int => name: String}".into(),
+            params: serde_json::json!({}),
+            timeout_ms: None,
+            readonly: false,
+            limit: None,
+        };
+        assert!(matches!(writer.request(create).await.unwrap(), Response::CoreResult(_)));
+        let put = |id: i64| Request::CoreAssert {
+            name: "people".into(),
+            rows: vec![serde_json::json!([id, format!("person-{}", id)])],
+        };
+        let resume_as = |session_id: u64, token: &str, last_seq: u64| Request::Hello {
+            client_name: "raw".into(),
+            protocol_version: PROTOCOL_VERSION,
+            token: None,
+            resume: Some(SessionResume {
+                session_id,
+                token: token.into(),
+                last_seq,
+            }),
+            compressions_supported: Vec::new(),
+        };
+        // The id of the row an event carries, by its seq.
+        let pushed = |resp: Option<Response>| match resp {
+            Some(Response::Event { seq, event: NodeEvent::CoreChanged(change) }) => (seq, change.rows[0][0].as_i64().unwrap()),
+            other => panic!("Expected a core event, got {:?}", other),
+        };
+
+        let (mut frames, mut raw) = raw_connect(&node).await;
+        raw_send(&mut raw, &hello("raw")).await;
+        let Some(Response::HelloAck { session: Some(grant), .. }) = raw_next(&mut frames).await else { panic!("no session") };
+        assert!(!grant.resumed);
+        raw_send(&mut raw, &Request::Subscribe { topics: vec![EventTopic::Core] }).await;
+        assert!(matches!(raw_next(&mut frames).await, Some(Response::Subscribed { .. })));
+        raw_send(&mut raw, &Request::CoreWatch { relation: "people".into(), filter: None }).await;
+        assert!(matches!(raw_next(&mut frames).await, Some(Response::CoreWatching { .. })));
+        writer.request(put(1)).await.unwrap();
+        assert_eq!(pushed(raw_next(&mut frames).await), (1, 1));
+        writer.request(put(2)).await.unwrap();
+        assert_eq!(pushed(raw_next(&mut frames).await), (2, 2));
+
+        // The socket dies, and a change lands while the session is parked.
+        drop((frames, raw));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        writer.request(put(3)).await.unwrap();
+
+        // Someone without the token gets a session of their own.
+        let (mut frames, mut raw) = raw_connect(&node).await;
+        raw_send(&mut raw, &resume_as(grant.session_id, "not-the-token", 0)).await;
+        let Some(Response::HelloAck { session: Some(other), .. }) = raw_next(&mut frames).await else { panic!("no session") };
+        assert!(!other.resumed);
+        assert_ne!(other.session_id, grant.session_id);
+        drop((frames, raw));
+
+        // As if event 2 was lost with the socket: it comes again, then the
+        // parked change, each once and in order.
+        let (mut frames, mut raw) = raw_connect(&node).await;
+        raw_send(&mut raw, &resume_as(grant.session_id, &grant.token, 1)).await;
+        match raw_next(&mut frames).await {
+            Some(Response::HelloAck { session: Some(resumed), .. }) => {
+                assert!(resumed.resumed);
+                assert_eq!((resumed.session_id, resumed.last_seq), (grant.session_id, 2));
+            }
+            other => panic!("Expected the session resumed, got {:?}", other),
+        }
+        assert_eq!(pushed(raw_next(&mut frames).await), (2, 2));
+        assert_eq!(pushed(raw_next(&mut frames).await), (3, 3));
+        writer.request(put(4)).await.unwrap();
+        assert_eq!(pushed(raw_next(&mut frames).await), (4, 4));
+        let extra = tokio::time::timeout(Duration::from_millis(500), raw_next(&mut frames)).await;
+        assert!(extra.is_err(), "{:?}", extra);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn checks_itself_for_an_admin_and_names_a_tampered_module() {
+        let node = start(
+            r#"ipc_idle_timeout_mins = 0
+[finance]
+electrum_urls = ["tcp://127.0.0.1:1"]
+[[access.tokens]]
+token = "dashboard-token-0123456789"
+name = "dashboard"
+permissions = ["read_status"]"#,
+        )
+        .await;
+        let store = NodeConfig::default().module_store(&DataDir::at(node.data_dir()));
+        std::fs::create_dir_all(store.join("tampered")).unwrap();
+        std::fs::write(store.join("tampered/module.wasm"), b"changed").unwrap();
+        std::fs::write(store.join("tampered/manifest.json"), format!(r#"{{"sha256": "{}"}}"#, "0".repeat(64))).unwrap();
+
+        let Response::SelfCheck(report) = node.client().request(Request::SelfCheck).await.unwrap() else { panic!("no report") };
+        let status = |name: &str| report.checks.iter().find(|c| c.name == name).map(|c| c.status);
+        assert_eq!(status("module_store"), Some(CheckStatus::Fail));
+        assert_eq!(status("electrum"), Some(CheckStatus::Warn));
+        for name in ["config", "data_dir", "ipc_endpoint", "core", "wasm_engine"] {
+            assert_eq!(status(name), Some(CheckStatus::Pass), "{}: {:?}", name, report);
+        }
+
+        let dashboard = node.connect_with_token("dashboard", "dashboard-token-0123456789").await.unwrap();
+        let denied = dashboard.request(Request::SelfCheck).await.unwrap();
+        assert!(matches!(denied, Response::PermissionDenied { permission: Permission::NodeAdmin, .. }), "{:?}", denied);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn speaks_json_lines_to_a_scripted_shell_session() {
+        use tokio::io::{AsyncBufReadExt, BufReader};
+
+        let node = start("ipc_idle_timeout_mins = 0\nipc_max_frame_bytes = 4096").await;
+        let create = Request::QueryCore {
+            query: ":create notes {k: String}".into(),
+            params: serde_json::json!({}),
+            timeout_ms: None,
+            readonly: false,
+            limit: None,
+        };
+        assert!(matches!(node.client().request(create).await.unwrap(), Response::CoreResult(_)));
+        let IpcEndpoint::UnixSocket(path) = node.endpoint() else { unreachable!() };
+        let (reader, mut shell) = UnixStream::connect(path).await.unwrap().into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let mut next = async || -> serde_json::Value {
+            let line = tokio::time::timeout(Duration::from_secs(5), lines.next_line()).await.unwrap().unwrap().expect("a line");
+            serde_json::from_str(&line).unwrap_or_else(|e| panic!("{}: {}", e, line))
+        };
+
+        // What `printf '...' | socat - UNIX-CONNECT:...` sends: every line
+        // at once, each request on one line, newlines in strings escaped.
+        let script = format!(
+            concat!(
+                r#"{{"Hello":{{"client_name":"shell","protocol_version":{}}}}}"#,
+                "\n\"GetStatus\"\n",
+                r#"{{"NoSuchRequest":{{}}}}"#,
+                "\n",
+                r#"{{"QueryCore":{{"query":"?[x] :=\n    x = 'a\nb'","params":{{}},"readonly":true}}}}"#,
+                "\n",
+                r#"{{"Subscribe":{{"topics":["core"]}}}}"#,
+                "\n",
+                r#"{{"CoreWatch":{{"relation":"notes"}}}}"#,
+                "\n",
+            ),
+            PROTOCOL_VERSION
+        );
+        shell.write_all(script.as_bytes()).await.unwrap();
+
+        let ack = next().await;
+        assert_eq!(ack["HelloAck"]["protocol_version"], PROTOCOL_VERSION);
+        // Lines carry text, so nothing is compressed.
+        assert!(ack["HelloAck"]["compression"].is_null(), "{}", ack);
+        assert_eq!(next().await["Status"]["system_health"], "OK");
+        let error = next().await;
+        assert!(error["Error"].as_str().is_some_and(|e| e.contains("NoSuchRequest")), "{}", error);
+        let rows = next().await;
+        assert_eq!(rows["CoreResult"]["rows"], serde_json::json!([["a\nb"]]), "{}", rows);
+        assert_eq!(next().await, serde_json::json!({"Subscribed": {"topics": ["core"]}}));
+        assert!(next().await["CoreWatching"]["watch_id"].is_u64());
+
+        // A push is a line of its own too.
+        let assert = Request::CoreAssert {
+            name: "notes".into(),
+            rows: vec![serde_json::json!(["pushed"])],
+        };
+        node.client().request(assert).await.unwrap();
+        let push = next().await;
+        assert_eq!(push["Event"]["seq"], 1, "{}", push);
+        assert_eq!(push["Event"]["event"]["event"], "core_changed", "{}", push);
+        assert_eq!(push["Event"]["event"]["rows"], serde_json::json!([["pushed"]]), "{}", push);
+
+        // A line past the frame limit is refused, and the session goes on.
+        shell.write_all(format!("{{\"Ping\":\"{}\"}}\n\"Ping\"\n", "x".repeat(8192)).as_bytes()).await.unwrap();
+        let refused = next().await;
+        assert!(refused.to_string().contains("4096"), "{}", refused);
+        assert_eq!(next().await, serde_json::json!("Pong"));
+        // Enveloped, the answer carries the request's id.
+        shell.write_all(b"{\"id\":7,\"request\":\"Ping\"}\n").await.unwrap();
+        assert_eq!(next().await, serde_json::json!({"id": 7, "response": "Pong"}));
+    }
+
+    #[tokio::test]
+    async fn detects_the_framing_across_split_reads() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let detect = tokio::spawn(async move { detect_framing(&mut server).await.unwrap() });
+        client.write_all(b"{").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        client.write_all(b"\"Pi").await.unwrap();
+        assert_eq!(detect.await.unwrap(), (Framing::JsonLines, b"{\"Pi".to_vec()));
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(b"{").await.unwrap();
+        drop(client);
+        assert_eq!(detect_framing(&mut server).await.unwrap(), (Framing::JsonLines, b"{".to_vec()));
+        let (client, mut server) = tokio::io::duplex(64);
+        drop(client);
+        assert_eq!(detect_framing(&mut server).await.unwrap(), (Framing::Binary, Vec::new()));
+        let mut frame: &[u8] = &framing::encode_frame(b"{}").unwrap();
+        assert_eq!(detect_framing(&mut frame).await.unwrap().0, Framing::Binary);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lists_a_slowed_request_with_who_sent_it_and_what_it_asked() {
+        let modules = tempfile::tempdir().unwrap();
+        let path = modules.path().join("spin.wat");
+        std::fs::write(&path, r#"(module (func (export "_start") (loop $forever (br $forever))))"#).unwrap();
+        let node = start(&format!("ipc_idle_timeout_mins = 0\nipc_slow_request_ms = 100\n[wasm]\nrun_dirs = [{:?}]", modules.path())).await;
+
+        let slowpoke = node.connect("slowpoke").await.unwrap();
+        assert!(matches!(slowpoke.request(Request::Ping).await.unwrap(), Response::Pong));
+        let started = Instant::now();
+        let spun = slowpoke.request(run_wasm(&path, &[], Some(200_000_000))).await.unwrap();
+        assert!(matches!(&spun, Response::Error(e) if e.contains("ran out of fuel")), "{:?}", spun);
+        assert!(started.elapsed() >= Duration::from_millis(100), "the module ran too briefly: {:?}", started.elapsed());
+
+        let admin = node.client();
+        let Response::Connections(open) = admin.request(Request::ConnectionStats).await.unwrap() else { panic!("no connections") };
+        let connection = open.iter().find(|c| c.client == "slowpoke").expect("slowpoke's connection");
+        assert_eq!(connection.requests["RunWasm"].count, 1);
+        assert_eq!(connection.requests["RunWasm"].errors, 1);
+
+        match admin.request(Request::SlowRequests { limit: 10 }).await.unwrap() {
+            Response::SlowRequests { requests, threshold_ms } => {
+                assert_eq!(threshold_ms, Some(100));
+                assert_eq!(requests.len(), 1, "{:?}", requests);
+                let slow = &requests[0];
+                assert_eq!((slow.request.as_str(), slow.client.as_str(), slow.connection_id), ("RunWasm", "slowpoke", connection.connection_id));
+                assert!(slow.duration_ms >= 100.0, "{}", slow.duration_ms);
+                assert_ne!(slow.outcome, "ok");
+                assert_eq!(slow.params["path"], path.display().to_string());
+                assert_eq!(slow.params["fuel_limit"], "200000000");
+            }
+            other => panic!("Expected SlowRequests, got {:?}", other),
+        }
+
+        let Response::Metrics(metrics) = admin.request(Request::GetMetrics).await.unwrap() else { panic!("no metrics") };
+        assert_eq!(metrics.ipc.requests["RunWasm"].count, 1);
+        assert!(metrics.ipc.bytes_in > 0 && metrics.ipc.bytes_out > 0);
+    }
+
+    /// A node serving TCP on a free local port, accepting `TCP_TOKEN`.
+    async fn start_tcp(config: &str) -> (TestNode, String) {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let addr = format!("127.0.0.1:{}", port);
+        let node = TestNode::start_with(TestNodeOptions {
+            config: Some(format!("ipc_idle_timeout_mins = 0\n[tcp]\nlisten = \"{}\"\n{}", addr, config)),
+            tokens: vec![("remote".into(), TCP_TOKEN.into())],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        (node, addr)
+    }
+
+    const TCP_TOKEN: &str = "remote-dashboard-token";
+
+    /// The next response over TCP, or `None` once the node closes.
+    async fn tcp_next(frames: &mut FramedRead<tokio::net::tcp::OwnedReadHalf, FrameCodec>) -> Option<Response> {
+        match tokio::time::timeout(Duration::from_secs(5), frames.next()).await.unwrap()? {
+            Ok(framing::Frame::Message(body)) => Some(serde_json::from_slice(&body).unwrap()),
+            Ok(other) => panic!("Unexpected frame {:?}", other),
+            Err(_) => None,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn serves_tls_only_to_clients_pinning_its_certificate() {
+        let (node, addr) = start_tcp("").await;
+        let data_dir = crate::data_dir::DataDir::at(node.data_dir());
+        assert!(data_dir.tls_cert().is_file() && data_dir.tls_key().is_file());
+        let pin = std::fs::read_to_string(data_dir.tls_fingerprint()).unwrap();
+
+        let client = sovereign_client::NodeClient::connect_tcp(&addr, "dashboard", &pin, TCP_TOKEN).await.unwrap();
+        assert!(matches!(client.request(Request::GetStatus).await.unwrap(), Response::Status(_)));
+
+        // Any other certificate is refused during the handshake, before a
+        // token is sent.
+        let other = sovereign_protocol::CertPin::of(b"some other certificate").to_string();
+        let e = sovereign_client::NodeClient::connect_tcp(&addr, "dashboard", &other, TCP_TOKEN).await.err().unwrap();
+        assert!(e.to_string().contains(&format!("does not match the pinned {}", other)), "{:#}", e);
+        let e = sovereign_client::NodeClient::connect_tcp(&addr, "dashboard", "not a pin", TCP_TOKEN).await.err().unwrap();
+        assert_eq!(e.to_string(), "'not a pin' is not a SHA-256 certificate fingerprint");
+        // The right certificate with the wrong token gets no further.
+        let e = sovereign_client::NodeClient::connect_tcp(&addr, "dashboard", &pin, "not-the-token-at-all").await.err().unwrap();
+        assert!(e.to_string().contains("Unknown access token"), "{:#}", e);
+        // Nor does a client that does not speak TLS.
+        let mut plain = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        plain.write_all(&framing::encode_frame(&serde_json::to_vec(&hello("plain")).unwrap()).unwrap()).await.unwrap();
+        let mut reply = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(5), plain.read_to_end(&mut reply)).await.unwrap();
+        assert!(!reply.windows(8).any(|w| w == b"HelloAck"));
+
+        // The local socket is served as before.
+        assert!(matches!(node.client().request(Request::GetStatus).await.unwrap(), Response::Status(_)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_tcp_client_must_say_hello_with_a_token_first() {
+        let (_node, addr) = start_tcp("tls = false\ni_know_this_is_insecure = true").await;
+        let connect = || async {
+            let (reader, writer) = tokio::net::TcpStream::connect(&addr).await.unwrap().into_split();
+            (FramedRead::new(reader, FrameCodec::new(16 * 1024 * 1024)), writer)
+        };
+        let send = |req: Request| framing::encode_frame(&serde_json::to_vec(&req).unwrap()).unwrap();
+        let with_token = |token: Option<&str>| Request::Hello {
+            client_name: "remote".into(),
+            protocol_version: PROTOCOL_VERSION,
+            token: token.map(str::to_string),
+            resume: None,
+            compressions_supported: Vec::new(),
+        };
+
+        // Anything before Hello, even what any local client may send.
+        let (mut frames, mut writer) = connect().await;
+        writer.write_all(&send(Request::Ping)).await.unwrap();
+        match tcp_next(&mut frames).await {
+            Some(Response::Error(msg)) => assert_eq!(msg, "A TCP connection must say Hello with an access token first"),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        assert!(tcp_next(&mut frames).await.is_none());
+
+        for (token, expected) in [(None, "A TCP connection must present an access token"), (Some("not-the-token-at-all"), "Unknown access token")] {
+            let (mut frames, mut writer) = connect().await;
+            writer.write_all(&send(with_token(token))).await.unwrap();
+            match tcp_next(&mut frames).await {
+                Some(Response::Error(msg)) => assert_eq!(msg, expected),
+                other => panic!("Expected an error, got {:?}", other),
+            }
+            assert!(tcp_next(&mut frames).await.is_none(), "{:?}", token);
+        }
+
+        let (mut frames, mut writer) = connect().await;
+        writer.write_all(&send(with_token(Some(TCP_TOKEN)))).await.unwrap();
+        assert!(matches!(tcp_next(&mut frames).await, Some(Response::HelloAck { .. })));
+        writer.write_all(&send(Request::GetStatus)).await.unwrap();
+        assert!(matches!(tcp_next(&mut frames).await, Some(Response::Status(_))));
     }
 }
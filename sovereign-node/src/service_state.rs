@@ -0,0 +1,276 @@
+//! What a service manager is told about the node as it starts and stops:
+//! the Windows service control manager's state machine. The transitions are
+//! kept apart from the SCM calls, behind `StatusSink`, so they hold
+//! whatever receives them.
+#![cfg_attr(not(windows), allow(dead_code))]
+
+use std::io;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// One state, as reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ServicePhase {
+    /// Starting; `checkpoint` rises while it makes progress, and the
+    /// manager gives up if it does not within `wait_hint`.
+    StartPending { checkpoint: u32, wait_hint: Duration },
+    Running,
+    /// Draining; as with `StartPending`.
+    StopPending { checkpoint: u32, wait_hint: Duration },
+    Stopped { exit_code: u32 },
+}
+
+/// Where states are reported: the SCM when running as a service.
+pub(crate) trait StatusSink: Send {
+    fn set_status(&mut self, phase: ServicePhase) -> io::Result<()>;
+}
+
+impl<S: StatusSink + ?Sized> StatusSink for Box<S> {
+    fn set_status(&mut self, phase: ServicePhase) -> io::Result<()> {
+        (**self).set_status(phase)
+    }
+}
+
+/// The state machine: start pending, running, stop pending, stopped, each
+/// only from the states before it. A transition out of order is ignored.
+pub(crate) struct ServiceLifecycle<S> {
+    sink: S,
+    phase: Option<ServicePhase>,
+}
+
+impl<S: StatusSink> ServiceLifecycle<S> {
+    pub fn new(sink: S) -> Self {
+        Self { sink, phase: None }
+    }
+
+    /// The first state: starting, expected to progress within `wait_hint`.
+    pub fn starting(&mut self, wait_hint: Duration) -> bool {
+        match self.phase {
+            None => self.enter(ServicePhase::StartPending { checkpoint: 1, wait_hint }),
+            _ => self.refuse("start pending"),
+        }
+    }
+
+    /// Bumps a pending state's checkpoint; nothing otherwise.
+    pub fn progress(&mut self) -> bool {
+        match self.phase {
+            Some(ServicePhase::StartPending { checkpoint, wait_hint }) => self.enter(ServicePhase::StartPending {
+                checkpoint: checkpoint + 1,
+                wait_hint,
+            }),
+            Some(ServicePhase::StopPending { checkpoint, wait_hint }) => self.enter(ServicePhase::StopPending {
+                checkpoint: checkpoint + 1,
+                wait_hint,
+            }),
+            _ => false,
+        }
+    }
+
+    pub fn running(&mut self) -> bool {
+        match self.phase {
+            Some(ServicePhase::StartPending { .. }) => self.enter(ServicePhase::Running),
+            _ => self.refuse("running"),
+        }
+    }
+
+    /// Asked to stop: draining, expected to progress within `wait_hint`.
+    pub fn stopping(&mut self, wait_hint: Duration) -> bool {
+        match self.phase {
+            Some(ServicePhase::StartPending { .. } | ServicePhase::Running) => {
+                self.enter(ServicePhase::StopPending { checkpoint: 1, wait_hint })
+            }
+            _ => self.refuse("stop pending"),
+        }
+    }
+
+    /// The last state, from any other.
+    pub fn stopped(&mut self, exit_code: u32) -> bool {
+        match self.phase {
+            Some(ServicePhase::Stopped { .. }) => self.refuse("stopped"),
+            _ => self.enter(ServicePhase::Stopped { exit_code }),
+        }
+    }
+
+    fn enter(&mut self, phase: ServicePhase) -> bool {
+        if let Err(e) = self.sink.set_status(phase) {
+            warn!("Failed to report service state {:?}: {}", phase, e);
+        }
+        self.phase = Some(phase);
+        true
+    }
+
+    fn refuse(&self, to: &str) -> bool {
+        debug!("Not reporting {} after {:?}", to, self.phase);
+        false
+    }
+}
+
+type Active = Mutex<ServiceLifecycle<Box<dyn StatusSink>>>;
+
+static ACTIVE: OnceLock<Active> = OnceLock::new();
+
+/// Makes `lifecycle` the one `ready` reports to. Once per process.
+pub(crate) fn activate(lifecycle: ServiceLifecycle<Box<dyn StatusSink>>) -> &'static Active {
+    let mut lifecycle = Some(lifecycle);
+    let active = ACTIVE.get_or_init(|| Mutex::new(lifecycle.take().expect("taken once")));
+    if lifecycle.is_some() {
+        warn!("A service lifecycle is already active; keeping it");
+    }
+    active
+}
+
+/// Reports the node running, if a service manager is waiting to hear it.
+/// Outside a service this does nothing.
+pub(crate) fn ready() {
+    if let Some(active) = ACTIVE.get() {
+        active.lock().unwrap_or_else(|e| e.into_inner()).running();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Stands in for the SCM: records every state reported, and fails to
+    /// take them while `failing` is set.
+    #[derive(Clone, Default)]
+    struct MockScm {
+        reported: Arc<Mutex<Vec<ServicePhase>>>,
+        failing: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl MockScm {
+        fn reported(&self) -> Vec<ServicePhase> {
+            self.reported.lock().unwrap().clone()
+        }
+    }
+
+    impl StatusSink for MockScm {
+        fn set_status(&mut self, phase: ServicePhase) -> io::Result<()> {
+            self.reported.lock().unwrap().push(phase);
+            match self.failing.load(std::sync::atomic::Ordering::Relaxed) {
+                true => Err(io::Error::other("the SCM went away")),
+                false => Ok(()),
+            }
+        }
+    }
+
+    const WAIT: Duration = Duration::from_secs(30);
+
+    fn start_pending(checkpoint: u32) -> ServicePhase {
+        ServicePhase::StartPending { checkpoint, wait_hint: WAIT }
+    }
+
+    fn stop_pending(checkpoint: u32) -> ServicePhase {
+        ServicePhase::StopPending { checkpoint, wait_hint: WAIT }
+    }
+
+    #[test]
+    fn starts_runs_drains_and_stops_with_rising_checkpoints() {
+        let scm = MockScm::default();
+        let mut lifecycle = ServiceLifecycle::new(scm.clone());
+        assert!(lifecycle.starting(WAIT));
+        assert!(lifecycle.progress());
+        assert!(lifecycle.progress());
+        assert!(lifecycle.running());
+        // Running has no checkpoint to bump.
+        assert!(!lifecycle.progress());
+        assert!(lifecycle.stopping(WAIT));
+        assert!(lifecycle.progress());
+        assert!(lifecycle.stopped(0));
+        assert_eq!(
+            scm.reported(),
+            [
+                start_pending(1),
+                start_pending(2),
+                start_pending(3),
+                ServicePhase::Running,
+                stop_pending(1),
+                stop_pending(2),
+                ServicePhase::Stopped { exit_code: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_transitions_out_of_order() {
+        let scm = MockScm::default();
+        let mut lifecycle = ServiceLifecycle::new(scm.clone());
+        // Nothing before starting but stopping outright.
+        assert!(!lifecycle.running());
+        assert!(!lifecycle.stopping(WAIT));
+        assert!(!lifecycle.progress());
+        assert!(lifecycle.starting(WAIT));
+        assert!(!lifecycle.starting(WAIT));
+        assert!(lifecycle.running());
+        assert!(!lifecycle.running());
+        assert!(lifecycle.stopping(WAIT));
+        assert!(!lifecycle.stopping(WAIT));
+        assert!(!lifecycle.running());
+        assert!(lifecycle.stopped(0));
+        assert!(!lifecycle.stopped(1));
+        assert!(!lifecycle.progress());
+        assert_eq!(scm.reported(), [start_pending(1), ServicePhase::Running, stop_pending(1), ServicePhase::Stopped { exit_code: 0 }]);
+    }
+
+    #[test]
+    fn a_stop_while_starting_drains_and_a_failure_stops_from_anywhere() {
+        // Stop or Shutdown before the node said it was ready.
+        let scm = MockScm::default();
+        let mut lifecycle = ServiceLifecycle::new(scm.clone());
+        lifecycle.starting(WAIT);
+        assert!(lifecycle.stopping(WAIT));
+        assert!(lifecycle.stopped(0));
+        assert_eq!(scm.reported(), [start_pending(1), stop_pending(1), ServicePhase::Stopped { exit_code: 0 }]);
+
+        // A node that fails to start exits nonzero for the restart policy.
+        let scm = MockScm::default();
+        let mut lifecycle = ServiceLifecycle::new(scm.clone());
+        lifecycle.starting(WAIT);
+        assert!(lifecycle.stopped(1));
+        assert_eq!(scm.reported(), [start_pending(1), ServicePhase::Stopped { exit_code: 1 }]);
+
+        let scm = MockScm::default();
+        let mut lifecycle = ServiceLifecycle::new(scm.clone());
+        lifecycle.starting(WAIT);
+        lifecycle.running();
+        assert!(lifecycle.stopped(1));
+        assert_eq!(scm.reported().last(), Some(&ServicePhase::Stopped { exit_code: 1 }));
+    }
+
+    #[test]
+    fn carries_on_when_the_scm_refuses_a_state() {
+        let scm = MockScm::default();
+        let mut lifecycle = ServiceLifecycle::new(Box::new(scm.clone()) as Box<dyn StatusSink>);
+        lifecycle.starting(WAIT);
+        scm.failing.store(true, std::sync::atomic::Ordering::Relaxed);
+        assert!(lifecycle.running());
+        scm.failing.store(false, std::sync::atomic::Ordering::Relaxed);
+        // It counts as running all the same.
+        assert!(!lifecycle.running());
+        assert!(lifecycle.stopping(WAIT));
+        assert_eq!(scm.reported(), [start_pending(1), ServicePhase::Running, stop_pending(1)]);
+    }
+
+    #[test]
+    fn ready_reports_running_to_the_active_lifecycle_only() {
+        // Outside a service there is nothing to report to.
+        ready();
+        let scm = MockScm::default();
+        let mut lifecycle = ServiceLifecycle::new(Box::new(scm.clone()) as Box<dyn StatusSink>);
+        lifecycle.starting(WAIT);
+        let active = activate(lifecycle);
+        ready();
+        assert_eq!(scm.reported(), [start_pending(1), ServicePhase::Running]);
+
+        // A second one is not taken.
+        let other = MockScm::default();
+        let again = activate(ServiceLifecycle::new(Box::new(other.clone()) as Box<dyn StatusSink>));
+        assert!(std::ptr::eq(active, again));
+        active.lock().unwrap().stopping(WAIT);
+        assert_eq!(scm.reported().last(), Some(&stop_pending(1)));
+        assert!(other.reported().is_empty());
+    }
+}
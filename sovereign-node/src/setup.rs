@@ -0,0 +1,654 @@
+//! First-run setup. A node started with an empty data directory serves only
+//! `Ping`, `GetStatus`, `GetVersion`, `SetupState` and `SetupApply` on its
+//! local endpoint until a client sets it up, then starts in full on the same
+//! listener: clients reconnect and find it running, with no restart.
+
+use crate::build_info::build_info;
+use crate::config::NodeConfig;
+use crate::data_dir::DataDir;
+use crate::ipc_transport::{self, AcceptFailure, BoxedStream, IpcListener, PeerCred};
+use crate::sd_notify;
+use crate::service_loop::{self, InboundFrame, Wire};
+use crate::service_state;
+use anyhow::{bail, Context};
+use rand_core::{OsRng, RngCore};
+use sovereign_mesh::Multiaddr;
+use sovereign_protocol::{
+    envelope_id, Framing, LicenseTerms, LicenseTierInfo, NodeMode, NodeStatus, Request, RequestEnvelope, Response, SetupSettings, SetupState, SwarmKeyChoice,
+    DEFAULT_MAX_FRAME_SIZE, PROTOCOL_VERSION,
+};
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+/// How often a client that said Hello is sent a heartbeat while it sets
+/// the node up.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long connections get to close once the node is set up.
+const HANDOVER_DRAIN: Duration = Duration::from_secs(1);
+
+/// The swarm key file's first two lines.
+const SWARM_KEY_HEADER: &str = "/key/swarm/psk/1.0.0/\n/base16/\n";
+
+/// What setup reads and writes: the config file the node started from and
+/// its data directory.
+#[derive(Debug, Clone)]
+pub(crate) struct SetupTarget {
+    pub config_path: PathBuf,
+    /// As the node loaded it.
+    pub config: NodeConfig,
+    pub data_dir: PathBuf,
+}
+
+impl SetupTarget {
+    /// The choices setup offers, with what the config holds now.
+    pub fn state(&self, pending: bool) -> SetupState {
+        let config = &self.config;
+        let policy = config.license_policy();
+        SetupState {
+            pending,
+            config_path: self.config_path.display().to_string(),
+            data_dir: self.data_dir.display().to_string(),
+            network: config.finance.network.clone(),
+            networks: NodeConfig::NETWORKS.iter().map(|n| n.to_string()).collect(),
+            bootstrap_peers: config.mesh.bootstrap_peers.clone(),
+            swarm_key_present: config.psk_path(&DataDir::at(&self.data_dir)).exists(),
+            license_terms: LicenseTerms {
+                developer_addr: config.finance.developer_address.clone(),
+                required_sats: config.finance.required_sats,
+                min_confirmations: policy.min_confirmations,
+                validity_blocks: policy.validity_blocks,
+                tiers: policy
+                    .tiers
+                    .iter()
+                    .map(|t| LicenseTierInfo { name: t.name.clone(), min_sats: t.min_sats })
+                    .collect(),
+            },
+        }
+    }
+
+    /// Checks `settings` and the config they make, then writes the swarm
+    /// key and the config file, each through a temporary file moved into
+    /// place. Returns the config as the node loads it from now on.
+    pub fn apply(&self, settings: SetupSettings) -> anyhow::Result<NodeConfig> {
+        if !settings.accept_license_policy {
+            bail!("Setup needs the license policy accepted");
+        }
+        if !same_dir(Path::new(&settings.data_dir), &self.data_dir) {
+            bail!(
+                "The node keeps its data in {}, not {}. To use another directory, set data_dir or SOVEREIGN_DATA_DIR and restart the node.",
+                self.data_dir.display(),
+                settings.data_dir
+            );
+        }
+        if !NodeConfig::NETWORKS.contains(&settings.network.as_str()) {
+            bail!("network must be bitcoin, testnet, signet or regtest, not '{}'", settings.network);
+        }
+        for peer in &settings.bootstrap_peers {
+            Multiaddr::from_str(peer).with_context(|| format!("Bootstrap peer '{}' is not a multiaddr", peer))?;
+        }
+        let key = match &settings.swarm_key {
+            SwarmKeyChoice::Generate => {
+                let mut key = [0u8; 32];
+                OsRng.try_fill_bytes(&mut key).map_err(|e| anyhow::anyhow!("Failed to generate a swarm key: {}", e))?;
+                key
+            }
+            SwarmKeyChoice::Import { key } => parse_swarm_key(key)?,
+        };
+
+        let current = std::fs::read_to_string(&self.config_path).with_context(|| format!("Failed to read config file {}", self.config_path.display()))?;
+        let peers = settings.bootstrap_peers.iter().cloned().map(toml::Value::String).collect();
+        let text = set(&current, "finance", "network", &toml::Value::String(settings.network));
+        let text = set(&text, "mesh", "bootstrap_peers", &toml::Value::Array(peers));
+        // Loaded as the node will load it, before anything is written.
+        let mut config = NodeConfig::parse(&text).context("Setup made an invalid config")?;
+        config.apply_env()?;
+        config.validate()?;
+
+        let swarm_key = config.psk_path(&DataDir::at(&self.data_dir));
+        let key_file = format!("{}{}\n", SWARM_KEY_HEADER, hex::encode(key));
+        write_replacing(&swarm_key, key_file.as_bytes(), true).with_context(|| format!("Failed to write the swarm key to {}", swarm_key.display()))?;
+        write_replacing(&self.config_path, text.as_bytes(), false).with_context(|| format!("Failed to write config file {}", self.config_path.display()))?;
+        info!("Setup wrote {} and the swarm key {}", self.config_path.display(), swarm_key.display());
+        Ok(config)
+    }
+}
+
+/// Serves setup on the node's local endpoint until a client sets the node
+/// up, or `stop` resolves first. Returns the config to start with and the
+/// listener to go on serving on; `None` if stopped.
+pub(crate) async fn wait<F>(target: SetupTarget, mut stop: Pin<&mut F>) -> anyhow::Result<Option<(NodeConfig, Box<dyn IpcListener>)>>
+where
+    F: Future<Output = io::Result<()>>,
+{
+    let endpoint = target.config.endpoint();
+    let peers = target.config.peer_policy();
+    let mut listener = match ipc_transport::activated()? {
+        Some(listener) => listener,
+        None => ipc_transport::bind(&endpoint)?,
+    };
+    info!("The data directory {} is empty. Waiting on {} to be set up: run `sovereignctl setup`.", target.data_dir.display(), endpoint);
+    // Up and answering, if only to setup.
+    sd_notify::notify(&[("READY", "1"), ("STATUS", "Waiting for setup")]);
+    service_state::ready();
+
+    let setup = Arc::new(Setup {
+        target,
+        start_time: SystemTime::now(),
+        applied: Mutex::new(None),
+        done: watch::Sender::new(false),
+    });
+    let mut done = setup.done.subscribe();
+    let own_uid = ipc_transport::own_uid();
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let accepted = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => match AcceptFailure::classify(&e) {
+                        AcceptFailure::Fatal => return Err(anyhow::Error::new(e).context("IPC listener failed")),
+                        _ => {
+                            debug!("Failed to accept an IPC client: {}", e);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    },
+                };
+                if !peers.admits(own_uid, accepted.peer.as_ref()) {
+                    if let Some(peer) = &accepted.peer {
+                        warn!("Refused IPC connection from {}: not the node's user or an allowed uid or gid", peer);
+                    }
+                    continue;
+                }
+                connections.spawn(connection(accepted.stream, accepted.peer, setup.clone()));
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = done.changed() => break,
+            received = stop.as_mut() => {
+                received?;
+                return Ok(None);
+            }
+        }
+    }
+
+    // Each connection closes as `done` turns; the one that applied setup
+    // has already been answered.
+    if tokio::time::timeout(HANDOVER_DRAIN, async { while connections.join_next().await.is_some() {} }).await.is_err() {
+        connections.abort_all();
+    }
+    let config = setup.applied.lock().unwrap_or_else(|e| e.into_inner()).take().context("Setup finished without a config")?;
+    info!("Set up. Starting the node.");
+    Ok(Some((config, listener)))
+}
+
+/// Shared by the connections served while waiting.
+struct Setup {
+    target: SetupTarget,
+    start_time: SystemTime,
+    /// The config setup wrote; set once.
+    applied: Mutex<Option<NodeConfig>>,
+    /// Turns true once setup is applied.
+    done: watch::Sender<bool>,
+}
+
+impl Setup {
+    fn handle(&self, req: Request) -> Response {
+        match req {
+            Request::Ping => Response::Pong,
+            Request::GetStatus => Response::Status(self.status()),
+            Request::GetVersion => Response::Version(build_info()),
+            Request::SetupState => Response::SetupState(self.target.state(true)),
+            Request::SetupApply { settings, .. } => self.apply(settings),
+            other => Response::SetupRequired { kind: other.kind().into() },
+        }
+    }
+
+    /// Applies `settings` unless another connection got there first.
+    fn apply(&self, settings: SetupSettings) -> Response {
+        let mut applied = self.applied.lock().unwrap_or_else(|e| e.into_inner());
+        if applied.is_some() {
+            return Response::Error("The node is already set up".into());
+        }
+        match self.target.apply(settings) {
+            Ok(config) => {
+                *applied = Some(config);
+                Response::SetupApplied { restart_required: false }
+            }
+            Err(e) => {
+                warn!("Setup failed: {:#}", e);
+                Response::Error(format!("{:#}", e))
+            }
+        }
+    }
+
+    fn status(&self) -> NodeStatus {
+        NodeStatus {
+            uptime_ms: SystemTime::now().duration_since(self.start_time).unwrap_or_default().as_millis() as u64,
+            mesh_peer_id: String::new(),
+            mesh_connections: 0,
+            mesh_listen_addrs: Vec::new(),
+            license_active: false,
+            system_health: "SETUP".into(),
+            health_details: vec!["setup: waiting for `sovereignctl setup`".into()],
+            finance: Default::default(),
+            mesh_phase: Default::default(),
+            machine_binding: Default::default(),
+            ipc_connections: Default::default(),
+            health: Default::default(),
+            resources: Default::default(),
+            version: build_info(),
+            mode: NodeMode::Setup,
+        }
+    }
+}
+
+/// One client of a node waiting for setup: the requests `Setup` answers,
+/// heartbeats once it says Hello, and nothing more. Closes once setup is
+/// applied, by this client or another.
+async fn connection(stream: BoxedStream, peer: Option<PeerCred>, setup: Arc<Setup>) {
+    let (reader, writer) = tokio::io::split(stream);
+    let mut writer = Wire::new(writer, Framing::Binary);
+    let (reader_task, mut frames, mut framing) = service_loop::read_frames(reader, DEFAULT_MAX_FRAME_SIZE);
+    let mut done = setup.done.subscribe();
+    let mut ticker = tokio::time::interval(HEARTBEAT_INTERVAL);
+    ticker.tick().await; // The first tick completes immediately.
+    let mut handshaken = false;
+    let mut seq = 0u64;
+    if let Some(peer) = &peer {
+        debug!("IPC connection for setup opened by {}", peer);
+    }
+
+    loop {
+        tokio::select! {
+            frame = frames.recv() => {
+                let Some(frame) = frame else { break };
+                if let Ok(framing) = framing.try_recv() {
+                    writer.framing = framing;
+                }
+                let buf = match frame {
+                    InboundFrame::Request(buf) => buf,
+                    InboundFrame::Data(_) => continue,
+                    InboundFrame::TooLarge { declared, fatal } => {
+                        let resp = Response::FrameTooLarge {
+                            declared: declared as u64,
+                            max: DEFAULT_MAX_FRAME_SIZE as u64,
+                        };
+                        if service_loop::write_frame(&mut writer, &resp).await.is_err() || fatal {
+                            break;
+                        }
+                        continue;
+                    }
+                    InboundFrame::Broken(reason) => {
+                        let _ = service_loop::write_frame(&mut writer, &Response::Error(reason)).await;
+                        break;
+                    }
+                };
+                let id = envelope_id(&buf);
+                let parsed = match id {
+                    Some(_) => serde_json::from_slice::<RequestEnvelope>(&buf).map(|envelope| envelope.request),
+                    None => serde_json::from_slice::<Request>(&buf),
+                };
+                let resp = match parsed {
+                    Ok(Request::HeartbeatAck { .. }) => continue,
+                    Ok(Request::Hello { client_name, protocol_version, .. }) => {
+                        info!("IPC client '{}' connected to set the node up (protocol v{})", client_name, protocol_version);
+                        handshaken = true;
+                        Response::HelloAck {
+                            protocol_version: PROTOCOL_VERSION,
+                            heartbeat_interval_ms: HEARTBEAT_INTERVAL.as_millis() as u64,
+                            idle_timeout_ms: (HEARTBEAT_INTERVAL * 4).as_millis() as u64,
+                            max_frame_size: DEFAULT_MAX_FRAME_SIZE as u64,
+                            build: Some(build_info()),
+                            session: None,
+                            compression: None,
+                        }
+                    }
+                    Ok(req) => setup.handle(req),
+                    Err(e) => {
+                        let offset = service_loop::byte_offset(&buf, e.line(), e.column());
+                        Response::Error(format!("Invalid request at byte {}: {}", offset, e))
+                    }
+                };
+                let applied = matches!(resp, Response::SetupApplied { .. });
+                if service_loop::write_reply(&mut writer, id, resp).await.is_err() {
+                    break;
+                }
+                if applied {
+                    setup.done.send_replace(true);
+                    break;
+                }
+            }
+            _ = done.changed() => break,
+            _ = ticker.tick(), if handshaken => {
+                seq += 1;
+                if service_loop::write_frame(&mut writer, &Response::Heartbeat { seq }).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    reader_task.abort();
+    let _ = writer.inner.shutdown().await;
+}
+
+/// A swarm key file's text, or its 64 hex digits alone. The all-zero
+/// development key is refused: setup is for a mesh's own key.
+fn parse_swarm_key(text: &str) -> anyhow::Result<[u8; 32]> {
+    let text = text.trim();
+    let digits = match text.strip_prefix("/key/swarm/psk/1.0.0/") {
+        Some(rest) => match rest.trim_start().strip_prefix("/base16/") {
+            Some(digits) => digits.trim(),
+            None => bail!("The swarm key must be base16"),
+        },
+        None => text,
+    };
+    let key: [u8; 32] = hex::decode(digits)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .context("A swarm key is three lines: /key/swarm/psk/1.0.0/, /base16/ and 64 hex digits; or the 64 digits alone")?;
+    if key.iter().all(|b| *b == 0) {
+        bail!("That is the shared development key. Generate a key, or import your mesh's own.");
+    }
+    Ok(key)
+}
+
+/// `text` with `key` in `[section]` set to `value`: on the line that sets
+/// it, or else sets it commented out, or else just below the section's
+/// header, or else in a new section at the end. Comments and every other
+/// line are kept as they are.
+fn set(text: &str, section: &str, key: &str, value: &toml::Value) -> String {
+    let setting = format!("{} = {}", key, value);
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+    let header = format!("[{}]", section);
+    match lines.iter().position(|line| line.trim() == header) {
+        Some(start) => {
+            let end = lines[start + 1..].iter().position(|line| line.trim_start().starts_with('[')).map_or(lines.len(), |i| start + 1 + i);
+            let sets = |line: &str| line.strip_prefix(key).is_some_and(|rest| rest.trim_start().starts_with('='));
+            let set_at = (start + 1..end).find(|&i| sets(lines[i].trim_start()));
+            let commented_at = || (start + 1..end).find(|&i| lines[i].trim_start().strip_prefix('#').is_some_and(|line| sets(line.trim_start())));
+            match set_at {
+                Some(i) => {
+                    // An array spread over several lines goes with it.
+                    let opened = lines[i].split_once('=').is_some_and(|(_, value)| value.contains('[') && !value.contains(']'));
+                    let close = (i + 1..end).find(|&j| lines[j].contains(']')).filter(|_| opened);
+                    lines.splice(i..=close.unwrap_or(i), [setting]);
+                }
+                None => match commented_at() {
+                    Some(i) => lines[i] = setting,
+                    None => lines.insert(start + 1, setting),
+                },
+            }
+        }
+        None => {
+            lines.push(String::new());
+            lines.push(header);
+            lines.push(setting);
+        }
+    }
+    let mut text = lines.join("\n");
+    text.push('\n');
+    text
+}
+
+/// Writes `contents` to a temporary file beside `path`, then moves it over
+/// `path`, so a reader sees the old file or the new one and never part of
+/// either. A `private` file is readable by the node's user alone.
+fn write_replacing(path: &Path, contents: &[u8], private: bool) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("setup-tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    let mut file = options.open(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp, path)
+}
+
+/// Whether `a` and `b` name the same directory, links and `..` resolved
+/// where they exist.
+fn same_dir(a: &Path, b: &Path) -> bool {
+    let resolve = |path: &Path| std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    resolve(a) == resolve(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::finance_backend::FinanceBackend;
+    use sovereign_client::NodeClient;
+    use sovereign_protocol::IpcEndpoint;
+
+    const KEY: &str = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+
+    /// A config file in `dir` for a node that keeps its data in `dir/data`,
+    /// with a token that may only read status.
+    fn config_file(dir: &Path) -> PathBuf {
+        let path = dir.join("node.toml");
+        let text = format!(
+            concat!(
+                "data_dir = \"{0}/data\"\n",
+                "ipc_endpoint = \"{0}/node.sock\"\n",
+                "ipc_idle_timeout_mins = 0\n\n",
+                "[mesh]\n",
+                "listen_addrs = [\"/ip4/127.0.0.1/tcp/0\"]\n",
+                "# bootstrap_peers = []\n\n",
+                "[core]\n",
+                "backend = \"mem\"\n\n",
+                "[[access.tokens]]\n",
+                "name = \"viewer\"\n",
+                "token = \"viewer-token-0123456789\"\n",
+                "permissions = [\"read_status\"]\n",
+            ),
+            dir.display()
+        );
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn target(config_path: &Path) -> SetupTarget {
+        let config = NodeConfig::load(config_path).unwrap();
+        SetupTarget {
+            config_path: config_path.to_path_buf(),
+            data_dir: config.data_dir(),
+            config,
+        }
+    }
+
+    fn settings(target: &SetupTarget) -> SetupSettings {
+        SetupSettings {
+            network: "bitcoin".into(),
+            swarm_key: SwarmKeyChoice::Import { key: KEY.into() },
+            bootstrap_peers: vec!["/ip4/10.0.0.1/tcp/4001".into()],
+            accept_license_policy: true,
+            data_dir: target.data_dir.display().to_string(),
+        }
+    }
+
+    #[test]
+    fn reads_swarm_keys_as_files_or_digits() {
+        let expected: [u8; 32] = hex::decode(KEY).unwrap().try_into().unwrap();
+        assert_eq!(parse_swarm_key(KEY).unwrap(), expected);
+        assert_eq!(parse_swarm_key(&format!("{}{}\n", SWARM_KEY_HEADER, KEY.to_uppercase())).unwrap(), expected);
+        for (text, error) in [
+            ("/key/swarm/psk/1.0.0/\n/base64/\nAAAA", "The swarm key must be base16"),
+            (&KEY[2..], "A swarm key is three lines"),
+            (&format!("{}zz", &KEY[2..]), "A swarm key is three lines"),
+            (&"0".repeat(64), "That is the shared development key"),
+        ] {
+            let e = parse_swarm_key(text).unwrap_err().to_string();
+            assert!(e.starts_with(error), "{:?}: {}", text, e);
+        }
+    }
+
+    #[test]
+    fn sets_keys_keeping_every_other_line() {
+        let text = "# top\n[mesh]\n# bootstrap_peers = []\nlisten_addrs = [\n  \"a\",\n  \"b\",\n]\n\n[finance]\nnetwork = \"bitcoin\" # why\n";
+        let peers = toml::Value::Array(vec![toml::Value::String("p".into())]);
+        // Set, uncommented, and an array spread over lines replaced whole.
+        assert_eq!(
+            set(&set(text, "mesh", "bootstrap_peers", &peers), "mesh", "listen_addrs", &peers),
+            "# top\n[mesh]\nbootstrap_peers = [\"p\"]\nlisten_addrs = [\"p\"]\n\n[finance]\nnetwork = \"bitcoin\" # why\n"
+        );
+        let signet = toml::Value::String("signet".into());
+        assert_eq!(set(text, "finance", "network", &signet), text.replace("\"bitcoin\" # why", "\"signet\""));
+        // A key not in its section goes under the header, and a section not
+        // in the file at the end; a key of the same name elsewhere is left.
+        assert_eq!(set("[finance]\nx = 1\n[mesh]\nnetwork = 2\n", "finance", "network", &signet), "[finance]\nnetwork = \"signet\"\nx = 1\n[mesh]\nnetwork = 2\n");
+        assert_eq!(set("x = 1", "finance", "network", &signet), "x = 1\n\n[finance]\nnetwork = \"signet\"\n");
+    }
+
+    /// Spoils one setting.
+    type Change = fn(&mut SetupSettings);
+
+    #[test]
+    fn refuses_settings_before_writing_anything() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_file(dir.path());
+        let before = std::fs::read_to_string(&config_path).unwrap();
+        let target = target(&config_path);
+        let elsewhere = dir.path().join("elsewhere").display().to_string();
+        let cases: [(Change, &str); 6] = [
+            (|s| s.accept_license_policy = false, "Setup needs the license policy accepted"),
+            (|s| s.network = "mainnet".into(), "network must be bitcoin, testnet, signet or regtest, not 'mainnet'"),
+            // The config's developer address is a mainnet one.
+            (|s| s.network = "signet".into(), "finance.developer_address: Developer Address is not a signet address"),
+            (|s| s.bootstrap_peers.push("10.0.0.2:4001".into()), "Bootstrap peer '10.0.0.2:4001' is not a multiaddr"),
+            (|s| s.swarm_key = SwarmKeyChoice::Import { key: "0".repeat(64) }, "That is the shared development key"),
+            (|s| s.data_dir = "/".into(), "The node keeps its data in"),
+        ];
+        for (change, error) in cases {
+            let mut settings = settings(&target);
+            change(&mut settings);
+            let e = format!("{:#}", target.apply(settings).unwrap_err());
+            assert!(e.starts_with(error), "{}", e);
+        }
+        let mut moved = settings(&target);
+        moved.data_dir = elsewhere;
+        assert!(target.apply(moved).is_err());
+
+        assert_eq!(std::fs::read_to_string(&config_path).unwrap(), before);
+        assert!(!target.config.psk_path(&DataDir::at(&target.data_dir)).exists());
+        assert!(!target.state(true).swarm_key_present);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_fresh_node_serves_only_setup_until_set_up_then_starts() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_file(dir.path());
+        let target = target(&config_path);
+        let data_dir = DataDir::open(&target.data_dir).unwrap();
+        assert!(data_dir.is_fresh());
+        let endpoint = IpcEndpoint::UnixSocket(dir.path().join("node.sock"));
+        let waiting = tokio::spawn(async move {
+            let stop = std::pin::pin!(std::future::pending::<io::Result<()>>());
+            wait(target, stop).await
+        });
+        let client = loop {
+            match NodeClient::connect(&endpoint, "setup").await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        };
+
+        let Response::Status(status) = client.request(Request::GetStatus).await.unwrap() else { panic!() };
+        assert_eq!((status.mode, status.system_health.as_str()), (NodeMode::Setup, "SETUP"));
+        assert!(matches!(client.request(Request::Ping).await.unwrap(), Response::Pong));
+        for req in [Request::MeshPeers, Request::WasmList, Request::CoreListRelations, Request::ExportSnapshot { path: None }] {
+            let kind = req.kind();
+            match client.request(req).await.unwrap() {
+                Response::SetupRequired { kind: refused } => assert_eq!(refused, kind),
+                other => panic!("Expected SetupRequired for {}, got {:?}", kind, other),
+            }
+        }
+        let Response::SetupState(state) = client.request(Request::SetupState).await.unwrap() else { panic!() };
+        assert!(state.pending && !state.swarm_key_present);
+        assert_eq!((state.network.as_str(), state.config_path.as_str()), ("bitcoin", config_path.to_str().unwrap()));
+
+        // A refused attempt leaves setup waiting.
+        let target = self::target(&config_path);
+        let mut refused = settings(&target);
+        refused.accept_license_policy = false;
+        let req = Request::SetupApply { settings: refused, force: false };
+        assert!(matches!(client.request(req).await.unwrap(), Response::Error(_)));
+        assert!(!waiting.is_finished());
+
+        let req = Request::SetupApply { settings: settings(&target), force: false };
+        match client.request(req).await.unwrap() {
+            Response::SetupApplied { restart_required } => assert!(!restart_required),
+            other => panic!("Expected SetupApplied, got {:?}", other),
+        }
+        let (config, listener) = tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap().unwrap().unwrap();
+        assert_eq!(config.finance.network, "bitcoin");
+        assert_eq!(config.mesh.bootstrap_peers, ["/ip4/10.0.0.1/tcp/4001"]);
+        let swarm_key = config.psk_path(&data_dir);
+        assert_eq!(std::fs::read_to_string(&swarm_key).unwrap(), format!("{}{}\n", SWARM_KEY_HEADER, KEY));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&swarm_key).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        // The file the node loads from now on says the same, and still
+        // holds what it held before.
+        let reloaded = NodeConfig::load(&config_path).unwrap();
+        assert_eq!((reloaded.mesh.bootstrap_peers.len(), reloaded.access.tokens.len()), (1, 1));
+        assert!(std::fs::read_to_string(&config_path).unwrap().contains("[finance]\nnetwork = \"bitcoin\"\n"));
+        assert!(!config_path.with_extension("setup-tmp").exists());
+
+        // It starts on the same listener, without a restart.
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let target = SetupTarget { config: config.clone(), ..target };
+        let node = tokio::spawn(crate::serve(config, data_dir, FinanceBackend::offline().unwrap(), Some(target.clone()), Some(listener), None, async move {
+            let _ = stopped.await;
+            Ok(())
+        }));
+        let client = loop {
+            match NodeClient::connect(&endpoint, "after-setup").await {
+                Ok(client) => break client,
+                Err(e) => {
+                    assert!(!node.is_finished(), "{}", e);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            }
+        };
+        let Response::Status(status) = client.request(Request::GetStatus).await.unwrap() else { panic!() };
+        assert_eq!(status.mode, NodeMode::Running);
+        let Response::SetupState(state) = client.request(Request::SetupState).await.unwrap() else { panic!() };
+        assert!(!state.pending && state.swarm_key_present);
+
+        // Setting it up again takes force, and node_admin.
+        let again = || Request::SetupApply { settings: settings(&target), force: true };
+        match client.request(Request::SetupApply { settings: settings(&target), force: false }).await.unwrap() {
+            Response::Error(msg) => assert_eq!(msg, "The node is already set up; send force to set it up again"),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        let viewer = NodeClient::connect_with_token(&endpoint, "viewer", Some("viewer-token-0123456789")).await.unwrap();
+        assert!(matches!(viewer.request(again()).await.unwrap(), Response::PermissionDenied { .. }));
+        assert!(matches!(client.request(again()).await.unwrap(), Response::SetupApplied { restart_required: true }));
+
+        drop((client, viewer));
+        let _ = stop.send(());
+        tokio::time::timeout(Duration::from_secs(10), node).await.unwrap().unwrap().unwrap();
+    }
+}
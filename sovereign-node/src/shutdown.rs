@@ -0,0 +1,37 @@
+use std::io;
+
+/// Exit code when a second signal cuts the shutdown short.
+pub(crate) const FORCED_EXIT_CODE: i32 = 2;
+
+/// Resolves on the next SIGTERM or SIGINT.
+#[cfg(unix)]
+pub(crate) async fn signalled() -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+    Ok(())
+}
+
+/// Resolves on the next Ctrl-C or Ctrl-Break, or when the console closes or
+/// the system shuts down.
+#[cfg(windows)]
+pub(crate) async fn signalled() -> io::Result<()> {
+    use tokio::signal::windows::{ctrl_break, ctrl_c, ctrl_close, ctrl_shutdown};
+
+    let mut c = ctrl_c()?;
+    let mut brk = ctrl_break()?;
+    let mut close = ctrl_close()?;
+    let mut shutdown = ctrl_shutdown()?;
+    tokio::select! {
+        _ = c.recv() => {}
+        _ = brk.recv() => {}
+        _ = close.recv() => {}
+        _ = shutdown.recv() => {}
+    }
+    Ok(())
+}
@@ -0,0 +1,1007 @@
+//! Node snapshots, for moving a node to another machine: one tar archive,
+//! compressed with zstd, of what the node holds that is not bound to the
+//! machine it runs on.
+//!
+//! ```text
+//! manifest.json                 versions, counts, and every other file's size and SHA-256
+//! core/relations.json           each stored relation's columns and row count
+//! core/relations/<name>.jsonl   its rows, as `CoreExport` writes JSON Lines
+//! core/named_queries.json
+//! modules/<name>/module.wasm
+//! modules/<name>/manifest.json  its grant, without the source path
+//! state/jobs.json               scheduled jobs, by name
+//! state/peers.json              peers dialed with persist
+//! config.toml                   the node's config file, if it was started from one
+//! ```
+//!
+//! The swarm key, install salt, TLS key and license record are never
+//! written: a license is bound to the machine it was paid from, and the new
+//! machine makes its own keys when it is set up. Event subscriptions and
+//! core watches belong to connections, which make them again.
+
+use crate::blocking_pool::BlockingPool;
+use crate::node_state::StateStore;
+use crate::service_loop::{core_named_query, named_query, unix_millis, write_data_frame, InboundFrame, Wire};
+use crate::wasm_stream::is_heartbeat_ack;
+use anyhow::{bail, Context};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sovereign_core::{CognitiveCore, ColumnInfo, DataFormat, NamedQuery, RelationRestore};
+use sovereign_protocol::{CoreNamedQuery, Response, SnapshotSummary, PROTOCOL_VERSION};
+use sovereign_runtime_wasm::{JobSpec, ModuleManifest, ModuleRegistry, Scheduler};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, RwLock};
+use tracing::{info, warn};
+
+/// The archive layout written here. Older layouts are read; newer ones are
+/// refused.
+const FORMAT: u32 = 1;
+
+const MANIFEST: &str = "manifest.json";
+const RELATIONS: &str = "core/relations.json";
+const NAMED_QUERIES: &str = "core/named_queries.json";
+const JOBS: &str = "state/jobs.json";
+const PEERS: &str = "state/peers.json";
+const CONFIG: &str = "config.toml";
+
+/// zstd's default level: quick, and most of the size win.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Largest data frame a streamed archive is sent in.
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// How long an import waits for requests already running to finish before
+/// it gives up.
+const MAINTENANCE_WAIT: Duration = Duration::from_secs(30);
+
+/// What snapshots are taken from and restored to.
+#[derive(Clone)]
+pub(crate) struct SnapshotTarget {
+    pub core: Arc<CognitiveCore>,
+    pub modules: Arc<ModuleRegistry>,
+    pub scheduler: Arc<Scheduler>,
+    pub store: Arc<StateStore>,
+    /// The node's config file, if it was started from one.
+    pub config_path: Option<PathBuf>,
+    /// Where archives are staged, under the data directory's `cache/`.
+    pub spool: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    format: u32,
+    node_version: String,
+    protocol_version: u32,
+    core_schema_version: u32,
+    created_at_ms: u64,
+    relations: u64,
+    rows: u64,
+    named_queries: u64,
+    modules: u64,
+    jobs: u64,
+    peers: u64,
+    /// Every file in the archive but this one, by path.
+    files: BTreeMap<String, FileEntry>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq)]
+struct FileEntry {
+    size: u64,
+    sha256: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RelationEntry {
+    name: String,
+    columns: Vec<ColumnEntry>,
+    rows: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ColumnEntry {
+    name: String,
+    /// The engine's type text, e.g. `Int?`.
+    #[serde(rename = "type")]
+    column_type: String,
+    key: bool,
+}
+
+/// Writes a snapshot to `path` on the node or, without one, streams it to
+/// the client as data frames, then answers with what it holds. An `Err`
+/// means the connection is broken.
+pub(crate) async fn export<W: AsyncWrite + Unpin>(
+    pool: &BlockingPool,
+    target: SnapshotTarget,
+    path: Option<String>,
+    writer: &mut Wire<W>,
+) -> io::Result<Response> {
+    if let Some(path) = path {
+        return Ok(match pool.spawn(move || target.export_to(Path::new(&path))).await {
+            Ok(Ok(summary)) => Response::SnapshotExported(summary),
+            Ok(Err(e)) => Response::Error(format!("{:#}", e)),
+            Err(e) => e.into_response("Snapshot export failed"),
+        });
+    }
+
+    let spooled = target.spool.join(format!("export-{}.tar.zst", unix_millis()));
+    let packed = {
+        let spooled = spooled.clone();
+        pool.spawn(move || target.export_to(&spooled)).await
+    };
+    let summary = match packed {
+        Ok(Ok(summary)) => summary,
+        Ok(Err(e)) => return Ok(Response::Error(format!("{:#}", e))),
+        Err(e) => return Ok(e.into_response("Snapshot export failed")),
+    };
+    let sent = send_file(&spooled, writer).await;
+    let _ = tokio::fs::remove_file(&spooled).await;
+    Ok(match sent? {
+        Ok(()) => Response::SnapshotExported(summary),
+        Err(e) => Response::Error(format!("Failed to read the snapshot back from {}: {}", spooled.display(), e)),
+    })
+}
+
+/// Restores a snapshot read from `path` on the node or, without one, from
+/// the data frames that follow the request, which are spooled to a file
+/// first. Other requests are turned away from when `maintenance` is taken
+/// until the snapshot is applied or undone. An `Err` means the connection
+/// is broken or the client sent a request before ending the archive.
+pub(crate) async fn import(
+    pool: &BlockingPool,
+    target: SnapshotTarget,
+    path: Option<String>,
+    maintenance: Arc<RwLock<()>>,
+    frames: &mut mpsc::Receiver<InboundFrame>,
+) -> io::Result<Response> {
+    let (archive, spooled) = match path {
+        Some(path) => (PathBuf::from(path), false),
+        None => {
+            let spooled = target.spool.join(format!("import-{}.tar.zst", unix_millis()));
+            if let Err(e) = receive_file(&spooled, frames).await? {
+                let _ = tokio::fs::remove_file(&spooled).await;
+                return Ok(Response::Error(format!("Failed to spool the snapshot to {}: {}", spooled.display(), e)));
+            }
+            (spooled, true)
+        }
+    };
+
+    let resp = match tokio::time::timeout(MAINTENANCE_WAIT, maintenance.write_owned()).await {
+        Ok(paused) => {
+            info!("Importing the snapshot {}; other requests are turned away until it is done", archive.display());
+            let imported = {
+                let archive = archive.clone();
+                // Scheduling a job starts its timer on the runtime, which the
+                // pool's threads are not part of.
+                let runtime = tokio::runtime::Handle::current();
+                pool.spawn(move || {
+                    let _runtime = runtime.enter();
+                    target.import_from(&archive)
+                })
+                .await
+            };
+            drop(paused);
+            match imported {
+                Ok(Ok((summary, config_written))) => Response::SnapshotImported {
+                    summary,
+                    config_written: config_written.map(|path| path.display().to_string()),
+                },
+                Ok(Err(e)) => Response::Error(format!("{:#}", e)),
+                Err(e) => e.into_response("Snapshot import failed"),
+            }
+        }
+        Err(_) => Response::Unavailable {
+            subsystem: "node".into(),
+            reason: format!("Requests still running after {:?} kept the snapshot from being imported", MAINTENANCE_WAIT),
+        },
+    };
+    if spooled {
+        let _ = tokio::fs::remove_file(&archive).await;
+    }
+    Ok(resp)
+}
+
+/// Sends the file at `path` as data frames. The outer `Err` means the
+/// connection is broken, the inner one that the file could not be read.
+async fn send_file<W: AsyncWrite + Unpin>(path: &Path, writer: &mut Wire<W>) -> io::Result<io::Result<()>> {
+    let mut file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(e) => return Ok(Err(e)),
+    };
+    let mut buf = vec![0u8; CHUNK_BYTES];
+    loop {
+        let n = match file.read(&mut buf).await {
+            Ok(0) => return Ok(Ok(())),
+            Ok(n) => n,
+            Err(e) => return Ok(Err(e)),
+        };
+        write_data_frame(writer, &buf[..n]).await?;
+    }
+}
+
+/// Writes the data frames that follow the request to `path`, up to the
+/// empty one that ends them. Frames are read to the end even once the file
+/// fails, so the connection stays in step. The outer `Err` means the
+/// connection is broken, the inner one that the file could not be written.
+async fn receive_file(path: &Path, frames: &mut mpsc::Receiver<InboundFrame>) -> io::Result<io::Result<()>> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut file = match tokio::fs::create_dir_all(dir).await {
+        Ok(()) => tokio::fs::File::create(path).await,
+        Err(e) => Err(e),
+    };
+    loop {
+        match frames.recv().await {
+            Some(InboundFrame::Data(data)) if data.is_empty() => break,
+            Some(InboundFrame::Data(data)) => {
+                if let Ok(out) = &mut file {
+                    if let Err(e) = out.write_all(&data).await {
+                        file = Err(e);
+                    }
+                }
+            }
+            Some(InboundFrame::Request(body)) if is_heartbeat_ack(&body) => {}
+            Some(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "request sent before the end of the snapshot"));
+            }
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+    Ok(match file {
+        Ok(file) => file.sync_all().await,
+        Err(e) => Err(e),
+    })
+}
+
+impl SnapshotTarget {
+    /// Stages every part of a snapshot, then packs it into `out` through a
+    /// temporary file beside it.
+    fn export_to(&self, out: &Path) -> anyhow::Result<SnapshotSummary> {
+        let staging = Staging::new(&self.spool, "export")?;
+        let mut staged = Staged {
+            dir: &staging.0,
+            files: BTreeMap::new(),
+        };
+
+        let mut relations = Vec::new();
+        for relation in self.core.list_relations()? {
+            let schema = self.core.describe(&relation.name)?;
+            let mut rows = 0;
+            staged.add(&relation_file(&relation.name), |out| {
+                rows = self.core.export_relation(&relation.name, DataFormat::JsonLines, out)?;
+                Ok(())
+            })?;
+            let columns = schema
+                .columns
+                .into_iter()
+                .map(|c| ColumnEntry {
+                    name: c.name,
+                    column_type: c.column_type,
+                    key: c.key,
+                })
+                .collect();
+            relations.push(RelationEntry { name: relation.name, columns, rows });
+        }
+        staged.add(RELATIONS, |out| json(out, &relations))?;
+
+        let named: Vec<CoreNamedQuery> = self.core.list_named()?.into_iter().map(core_named_query).collect();
+        staged.add(NAMED_QUERIES, |out| json(out, &named))?;
+
+        let mut modules = 0;
+        for info in self.modules.list() {
+            // Removed since it was listed.
+            let Some(bytes) = self.modules.bytes(&info.name) else { continue };
+            // The source file and compiled artifact stay on this machine.
+            let manifest = ModuleManifest {
+                source_path: None,
+                artifact: None,
+                ..info.manifest
+            };
+            staged.add(&module_file(&info.name), |out| Ok(out.write_all(&bytes)?))?;
+            staged.add(&module_manifest(&info.name), |out| json(out, &manifest))?;
+            modules += 1;
+        }
+
+        let jobs: BTreeMap<String, JobSpec> = self.scheduler.list().into_iter().map(|job| (job.name, job.spec)).collect();
+        staged.add(JOBS, |out| json(out, &jobs))?;
+        let peers = self.store.get().peers;
+        staged.add(PEERS, |out| json(out, &peers))?;
+        if let Some(config_path) = &self.config_path {
+            let config = std::fs::read(config_path).with_context(|| format!("Failed to read the config file {}", config_path.display()))?;
+            staged.add(CONFIG, |out| Ok(out.write_all(&config)?))?;
+        }
+
+        let manifest = Manifest {
+            format: FORMAT,
+            node_version: env!("CARGO_PKG_VERSION").into(),
+            protocol_version: PROTOCOL_VERSION,
+            core_schema_version: self.core.schema_version()?,
+            created_at_ms: unix_millis(),
+            relations: relations.len() as u64,
+            rows: relations.iter().map(|r| r.rows).sum(),
+            named_queries: named.len() as u64,
+            modules,
+            jobs: jobs.len() as u64,
+            peers: peers.len() as u64,
+            files: staged.files,
+        };
+        std::fs::write(staging.0.join(MANIFEST), serde_json::to_vec_pretty(&manifest)?)?;
+
+        let partial = out.with_extension("partial");
+        let packed = pack(&staging.0, &manifest, &partial).and_then(|()| Ok(std::fs::rename(&partial, out)?));
+        if let Err(e) = packed {
+            let _ = std::fs::remove_file(&partial);
+            return Err(e).with_context(|| format!("Failed to write the snapshot to {}", out.display()));
+        }
+        let bytes = std::fs::metadata(out)?.len();
+        info!("Exported a snapshot of {} relations and {} modules to {} ({} bytes)", manifest.relations, modules, out.display(), bytes);
+        Ok(summary(&manifest, bytes))
+    }
+
+    /// Unpacks and checks the archive at `archive`, then applies it part by
+    /// part, undoing what was applied if a later part fails. The config
+    /// file is written beside the node's own, not over it.
+    fn import_from(&self, archive: &Path) -> anyhow::Result<(SnapshotSummary, Option<PathBuf>)> {
+        let bytes = std::fs::metadata(archive).with_context(|| format!("Failed to read the snapshot {}", archive.display()))?.len();
+        let staging = Staging::new(&self.spool, "import")?;
+        let unpacked = unpack(archive, &staging.0).with_context(|| format!("Failed to unpack the snapshot {}", archive.display()))?;
+        let manifest: Manifest = read_json(&staging.0, MANIFEST)?;
+        self.check(&manifest, &unpacked)?;
+        let snapshot = Snapshot::read(&staging.0, &manifest)?;
+
+        let mut undo = Vec::new();
+        let applied = self.apply(&snapshot, &staging.0, &mut undo);
+        if let Err(e) = applied {
+            warn!("Snapshot import failed, undoing it: {:#}", e);
+            for step in undo.into_iter().rev() {
+                if let Err(e) = self.undo(step) {
+                    warn!("Failed to undo part of the snapshot import: {:#}", e);
+                }
+            }
+            return Err(e);
+        }
+
+        let config_written = match (&snapshot.config, &self.config_path) {
+            (Some(config), Some(config_path)) => {
+                let written = PathBuf::from(format!("{}.from-snapshot", config_path.display()));
+                match std::fs::write(&written, config) {
+                    Ok(()) => Some(written),
+                    Err(e) => {
+                        warn!("Failed to write the snapshot's config file to {}: {}", written.display(), e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        info!(
+            "Imported a snapshot of {} relations and {} modules from {}, exported by node {}",
+            manifest.relations,
+            manifest.modules,
+            archive.display(),
+            manifest.node_version
+        );
+        Ok((summary(&manifest, bytes), config_written))
+    }
+
+    /// Refuses archives from newer releases, and any whose files are not
+    /// exactly those the manifest lists, with the sizes and digests it gives.
+    fn check(&self, manifest: &Manifest, unpacked: &BTreeMap<String, FileEntry>) -> anyhow::Result<()> {
+        if manifest.format > FORMAT {
+            bail!("The snapshot is in format {}, newer than this node's {}", manifest.format, FORMAT);
+        }
+        let own = env!("CARGO_PKG_VERSION");
+        if release(&manifest.node_version) > release(own) {
+            bail!("The snapshot was exported by node {}, newer than this node's {}; upgrade this node first", manifest.node_version, own);
+        }
+        let schema = self.core.schema_version()?;
+        if manifest.core_schema_version > schema {
+            bail!("The snapshot's core schema is at version {}, newer than this node's {}", manifest.core_schema_version, schema);
+        }
+        for (name, entry) in unpacked {
+            match manifest.files.get(name) {
+                Some(listed) if listed == entry => {}
+                Some(_) => bail!("{} in the snapshot does not match its checksum", name),
+                None => bail!("The snapshot holds {}, which its manifest does not list", name),
+            }
+        }
+        if let Some(name) = manifest.files.keys().find(|name| !unpacked.contains_key(*name)) {
+            bail!("The snapshot is missing {}", name);
+        }
+        Ok(())
+    }
+
+    fn apply(&self, snapshot: &Snapshot, staging: &Path, undo: &mut Vec<Undo>) -> anyhow::Result<()> {
+        // The relations the snapshot replaces are kept, to put back if a
+        // later part fails.
+        let existing: HashSet<String> = self.core.list_relations()?.into_iter().map(|r| r.name).collect();
+        let backup_dir = staging.join("backup");
+        std::fs::create_dir_all(&backup_dir)?;
+        let mut backup = Vec::new();
+        for relation in snapshot.relations.iter().filter(|r| existing.contains(&r.name)) {
+            let path = backup_dir.join(format!("{}.jsonl", relation.name));
+            self.core.export_relation(&relation.name, DataFormat::JsonLines, io::BufWriter::new(File::create(&path)?))?;
+            backup.push((relation.name.clone(), self.core.describe(&relation.name)?.columns, path));
+        }
+        let restores = snapshot
+            .relations
+            .iter()
+            .map(|relation| {
+                Ok(RelationRestore {
+                    name: relation.name.clone(),
+                    columns: relation.columns.iter().map(column_info).collect(),
+                    rows: BufReader::new(File::open(staging.join(relation_file(&relation.name)))?),
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        self.core.restore_relations(restores).context("Failed to restore the core's relations")?;
+        let created = snapshot.relations.iter().filter(|r| !existing.contains(&r.name)).map(|r| r.name.clone()).collect();
+        undo.push(Undo::Relations { created, backup });
+
+        // Named queries are checked against the relations, so they follow them.
+        let named: HashMap<String, NamedQuery> = self.core.list_named()?.into_iter().map(|q| (q.name.clone(), q)).collect();
+        for query in &snapshot.named_queries {
+            self.core
+                .register_query(&named_query(query.clone()))
+                .with_context(|| format!("Failed to register the named query '{}'", query.name))?;
+            undo.push(Undo::NamedQuery {
+                name: query.name.clone(),
+                previous: named.get(&query.name).cloned(),
+            });
+        }
+
+        for (name, bytes, manifest) in &snapshot.modules {
+            let previous = self.modules.get(name).zip(self.modules.bytes(name)).map(|(info, bytes)| Box::new((bytes.to_vec(), info.manifest)));
+            self.modules
+                .register(name, bytes.clone(), manifest.clone())
+                .with_context(|| format!("Failed to register the module '{}'", name))?;
+            undo.push(Undo::Module { name: name.clone(), previous });
+        }
+
+        // Jobs run modules, so they follow them.
+        for (name, spec) in &snapshot.jobs {
+            let previous = self.scheduler.get(name).map(|job| job.spec);
+            self.scheduler.schedule(name, spec.clone()).with_context(|| format!("Failed to schedule the job '{}'", name))?;
+            undo.push(Undo::Job { name: name.clone(), previous });
+        }
+
+        // Peers are added to those already kept, and dialed from the next start.
+        let previous = self.store.get().peers;
+        self.store.update(|state| {
+            for peer in &snapshot.peers {
+                if !state.peers.contains(peer) {
+                    state.peers.push(peer.clone());
+                }
+            }
+        });
+        undo.push(Undo::Peers(previous));
+        Ok(())
+    }
+
+    fn undo(&self, step: Undo) -> anyhow::Result<()> {
+        match step {
+            Undo::Relations { created, backup } => {
+                for name in created {
+                    self.core.drop_relation(&name, true)?;
+                }
+                let restores = backup
+                    .into_iter()
+                    .map(|(name, columns, path)| Ok(RelationRestore { name, columns, rows: BufReader::new(File::open(path)?) }))
+                    .collect::<io::Result<Vec<_>>>()?;
+                self.core.restore_relations(restores)?;
+            }
+            Undo::NamedQuery { name, previous: Some(query) } => {
+                self.core.register_query(&query).with_context(|| format!("Failed to restore the named query '{}'", name))?;
+            }
+            Undo::NamedQuery { name, previous: None } => {
+                self.core.remove_named(&name)?;
+            }
+            Undo::Module { name, previous: Some(previous) } => {
+                let (bytes, manifest) = *previous;
+                self.modules.register(&name, bytes, manifest).with_context(|| format!("Failed to restore the module '{}'", name))?;
+            }
+            Undo::Module { name, previous: None } => {
+                self.modules.remove(&name)?;
+            }
+            Undo::Job { name, previous: Some(spec) } => {
+                self.scheduler.schedule(&name, spec).with_context(|| format!("Failed to restore the job '{}'", name))?;
+            }
+            Undo::Job { name, previous: None } => {
+                self.scheduler.remove(&name)?;
+            }
+            Undo::Peers(peers) => self.store.update(|state| state.peers = peers),
+        }
+        Ok(())
+    }
+}
+
+/// A checked archive's parts, read before anything is applied.
+struct Snapshot {
+    relations: Vec<RelationEntry>,
+    named_queries: Vec<CoreNamedQuery>,
+    /// Name, bytes and manifest.
+    modules: Vec<(String, Vec<u8>, ModuleManifest)>,
+    jobs: BTreeMap<String, JobSpec>,
+    peers: Vec<String>,
+    config: Option<Vec<u8>>,
+}
+
+impl Snapshot {
+    fn read(staging: &Path, manifest: &Manifest) -> anyhow::Result<Self> {
+        let relations: Vec<RelationEntry> = read_json(staging, RELATIONS)?;
+        let mut known: HashSet<String> = [RELATIONS, NAMED_QUERIES, JOBS, PEERS, CONFIG].iter().map(|name| name.to_string()).collect();
+        for relation in &relations {
+            known.insert(relation_file(&relation.name));
+        }
+        let mut modules = Vec::new();
+        for name in manifest.files.keys() {
+            let Some(module) = name.strip_prefix("modules/").and_then(|rest| rest.strip_suffix("/manifest.json")) else { continue };
+            let bytes = std::fs::read(staging.join(module_file(module))).with_context(|| format!("The snapshot is missing module '{}'", module))?;
+            modules.push((module.to_string(), bytes, read_json(staging, &module_manifest(module))?));
+            known.insert(module_file(module));
+            known.insert(module_manifest(module));
+        }
+        if let Some(name) = manifest.files.keys().find(|name| !known.contains(*name)) {
+            bail!("The snapshot holds {}, which this node does not know what to do with", name);
+        }
+        let config = match manifest.files.contains_key(CONFIG) {
+            true => Some(std::fs::read(staging.join(CONFIG))?),
+            false => None,
+        };
+        Ok(Self {
+            relations,
+            named_queries: read_json(staging, NAMED_QUERIES)?,
+            modules,
+            jobs: read_json(staging, JOBS)?,
+            peers: read_json(staging, PEERS)?,
+            config,
+        })
+    }
+}
+
+/// What puts an applied part of a snapshot back as it was.
+enum Undo {
+    /// Relations the snapshot created, and those it replaced with where
+    /// their rows were kept.
+    Relations {
+        created: Vec<String>,
+        backup: Vec<(String, Vec<ColumnInfo>, PathBuf)>,
+    },
+    NamedQuery { name: String, previous: Option<NamedQuery> },
+    Module {
+        name: String,
+        previous: Option<Box<(Vec<u8>, ModuleManifest)>>,
+    },
+    Job { name: String, previous: Option<JobSpec> },
+    Peers(Vec<String>),
+}
+
+/// Files written for an archive, with their sizes and digests.
+struct Staged<'a> {
+    dir: &'a Path,
+    files: BTreeMap<String, FileEntry>,
+}
+
+impl Staged<'_> {
+    /// Writes the file `name` with `write`.
+    fn add(&mut self, name: &str, write: impl FnOnce(&mut dyn Write) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        let path = self.dir.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = Hashed::new(io::BufWriter::new(File::create(&path)?));
+        write(&mut file)?;
+        self.files.insert(name.to_string(), file.finish()?);
+        Ok(())
+    }
+}
+
+/// A directory under the spool, removed with everything in it when dropped.
+struct Staging(PathBuf);
+
+impl Staging {
+    fn new(spool: &Path, purpose: &str) -> io::Result<Self> {
+        let dir = spool.join(format!("{}-{}", purpose, unix_millis()));
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self(dir))
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Counts and hashes what is written through it.
+struct Hashed<W> {
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> Hashed<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, hasher: Sha256::new(), size: 0 }
+    }
+
+    fn finish(mut self) -> io::Result<FileEntry> {
+        self.inner.flush()?;
+        Ok(FileEntry {
+            size: self.size,
+            sha256: hex::encode(self.hasher.finalize()),
+        })
+    }
+}
+
+impl<W: Write> Write for Hashed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Packs the staged files into `out`, the manifest first.
+fn pack(staging: &Path, manifest: &Manifest, out: &Path) -> anyhow::Result<()> {
+    let file = File::create(out)?;
+    let mut archive = tar::Builder::new(zstd::Encoder::new(file, ZSTD_LEVEL)?);
+    for name in std::iter::once(MANIFEST).chain(manifest.files.keys().map(String::as_str)) {
+        archive.append_path_with_name(staging.join(name), name)?;
+    }
+    let file = archive.into_inner()?.finish()?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Unpacks `archive` into `staging`, and returns the size and digest of
+/// every file but the manifest. Only plain files at plain relative paths
+/// are accepted.
+fn unpack(archive: &Path, staging: &Path) -> anyhow::Result<BTreeMap<String, FileEntry>> {
+    let mut archive = tar::Archive::new(zstd::Decoder::new(File::open(archive)?)?);
+    let mut unpacked = BTreeMap::new();
+    let mut seen_manifest = false;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let mut parts = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(part) => parts.push(part.to_str().context("a path in the snapshot is not UTF-8")?.to_string()),
+                _ => bail!("The snapshot holds the unsafe path {}", path.display()),
+            }
+        }
+        if !entry.header().entry_type().is_file() {
+            bail!("The snapshot holds {}, which is not a plain file", path.display());
+        }
+        let name = parts.join("/");
+        if name == MANIFEST {
+            if seen_manifest {
+                bail!("The snapshot holds {} twice", name);
+            }
+            seen_manifest = true;
+        } else if unpacked.contains_key(&name) {
+            bail!("The snapshot holds {} twice", name);
+        }
+        let dest = staging.join(&name);
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = Hashed::new(io::BufWriter::new(File::create(&dest)?));
+        io::copy(&mut entry, &mut file)?;
+        let written = file.finish()?;
+        if name != MANIFEST {
+            unpacked.insert(name, written);
+        }
+    }
+    if !seen_manifest {
+        bail!("The snapshot has no {}", MANIFEST);
+    }
+    Ok(unpacked)
+}
+
+fn summary(manifest: &Manifest, bytes: u64) -> SnapshotSummary {
+    SnapshotSummary {
+        node_version: manifest.node_version.clone(),
+        created_at_ms: manifest.created_at_ms,
+        relations: manifest.relations,
+        rows: manifest.rows,
+        named_queries: manifest.named_queries,
+        modules: manifest.modules,
+        jobs: manifest.jobs,
+        peers: manifest.peers,
+        bytes,
+    }
+}
+
+fn column_info(column: &ColumnEntry) -> ColumnInfo {
+    ColumnInfo {
+        name: column.name.clone(),
+        column_type: column.column_type.clone(),
+        key: column.key,
+        has_default: false,
+    }
+}
+
+/// A version's release numbers, `1.2.3-rc.1` as `[1, 2, 3]`, to compare.
+fn release(version: &str) -> Vec<u64> {
+    let release = version.split(['-', '+']).next().unwrap_or_default();
+    release.split('.').map(|part| part.parse().unwrap_or(0)).collect()
+}
+
+fn relation_file(name: &str) -> String {
+    format!("core/relations/{}.jsonl", name)
+}
+
+fn module_file(name: &str) -> String {
+    format!("modules/{}/module.wasm", name)
+}
+
+fn module_manifest(name: &str) -> String {
+    format!("modules/{}/manifest.json", name)
+}
+
+fn json(out: &mut dyn Write, value: &impl Serialize) -> anyhow::Result<()> {
+    serde_json::to_writer_pretty(out, value)?;
+    Ok(())
+}
+
+fn read_json<T: DeserializeOwned>(staging: &Path, name: &str) -> anyhow::Result<T> {
+    let bytes = std::fs::read(staging.join(name)).with_context(|| format!("The snapshot is missing {}", name))?;
+    serde_json::from_slice(&bytes).with_context(|| format!("The snapshot's {} is not valid", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_dir::DataDir;
+    use crate::license_monitor::LicenseRecord;
+    use crate::testkit::{TestNode, TestNodeOptions};
+    use sovereign_protocol::{LicenseReport, Request, WasmJobSchedule, WasmJobSpec, WasmManifest};
+
+    /// In every secret planted in the exporting node, to look for in its archive.
+    const SECRET: &str = "5ec7e75ec7e75ec7e75ec7e75ec7e75ec7e75ec7e75ec7e75ec7e75ec7e75ec7";
+
+    fn query(query: &str) -> Request {
+        Request::QueryCore {
+            query: query.into(),
+            params: serde_json::json!({}),
+            timeout_ms: None,
+            readonly: false,
+            limit: None,
+        }
+    }
+
+    /// `req`'s answer from `node`, as JSON, failing on an error.
+    async fn ask(node: &TestNode, req: Request) -> serde_json::Value {
+        let resp = node.client().request(req).await.unwrap();
+        assert!(!matches!(resp, Response::Error(_) | Response::PermissionDenied { .. }), "{:?}", resp);
+        serde_json::to_value(resp).unwrap()
+    }
+
+    /// A node holding a relation, a named query, a module, a job and a
+    /// pinned peer, and a swarm key, TLS key and license record that must
+    /// not leave it.
+    async fn populated(modules: &Path) -> TestNode {
+        std::fs::write(modules.join("noop.wat"), "(module (func (export \"_start\")))").unwrap();
+        let node = TestNode::start_with(TestNodeOptions {
+            persistent_core: true,
+            config: Some(format!("ipc_idle_timeout_mins = 0\n[wasm]\nrun_dirs = [{:?}]", modules)),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        ask(&node, query(":create notes {k: String => v: Int}")).await;
+        ask(&node, query("?[k, v] <- [['a', 1], ['b', 2]] :put notes {k => v}")).await;
+        let register = Request::CoreRegisterQuery {
+            name: "keys".into(),
+            query: "?[k] := *notes{k}".into(),
+            params: Vec::new(),
+            readonly: true,
+        };
+        ask(&node, register).await;
+        let upload = Request::WasmUpload {
+            name: "noop".into(),
+            path: modules.join("noop.wat").display().to_string(),
+            manifest: WasmManifest::default(),
+            signature: None,
+            watch: false,
+        };
+        ask(&node, upload).await;
+        let job = WasmJobSpec {
+            module: "noop".into(),
+            input: String::new(),
+            schedule: WasmJobSchedule::Interval { every_ms: 3_600_000 },
+            fuel_limit: None,
+            max_memory_bytes: None,
+            overlap: Default::default(),
+            enabled: true,
+        };
+        ask(&node, Request::WasmScheduleJob { name: "hourly".into(), job }).await;
+        ask(&node, Request::MeshDial { addr: "/ip4/10.9.9.9/tcp/4001".into(), persist: true }).await;
+
+        // A running node keeps its state in memory and rewrites state.json
+        // from it, so the record planted here is only on disk: enough to show
+        // that the archive takes nothing from the file.
+        let data_dir = DataDir::at(node.data_dir());
+        let store = StateStore::open(&data_dir.state());
+        store.update(|state| {
+            state.license = Some(LicenseRecord {
+                valid: true,
+                report: LicenseReport {
+                    txid: SECRET.into(),
+                    found: true,
+                    paid_sats: 50_000,
+                    payment_ok: true,
+                    binding_ok: true,
+                    confirmations: 6,
+                    confirmed_height: Some(800_000),
+                    tier: None,
+                    valid_until_height: None,
+                    checked_at_ms: unix_millis(),
+                },
+            })
+        });
+        std::fs::write(data_dir.swarm_key(), format!("/key/swarm/psk/1.0.0/\n/base16/\n{}\n", SECRET)).unwrap();
+        std::fs::write(data_dir.tls_key(), SECRET).unwrap();
+        node
+    }
+
+    /// Every file in the archive at `path`, by name.
+    fn entries(path: &Path) -> BTreeMap<String, Vec<u8>> {
+        let mut archive = tar::Archive::new(zstd::Decoder::new(File::open(path).unwrap()).unwrap());
+        let mut files = BTreeMap::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().display().to_string();
+            let mut bytes = Vec::new();
+            io::Read::read_to_end(&mut entry, &mut bytes).unwrap();
+            files.insert(name, bytes);
+        }
+        files
+    }
+
+    /// Packs `files` into `path`, with each listed in the manifest as it is
+    /// now unless `reseal` is false. Names are written as they are, `..`
+    /// included.
+    fn repack(path: &Path, mut files: BTreeMap<String, Vec<u8>>, reseal: bool) {
+        if reseal {
+            let mut manifest: serde_json::Value = serde_json::from_slice(&files[MANIFEST]).unwrap();
+            manifest["files"] = files
+                .iter()
+                .filter(|(name, _)| *name != MANIFEST)
+                .map(|(name, bytes)| (name.clone(), serde_json::json!({"size": bytes.len(), "sha256": hex::encode(Sha256::digest(bytes))})))
+                .collect::<serde_json::Map<_, _>>()
+                .into();
+            files.insert(MANIFEST.into(), serde_json::to_vec(&manifest).unwrap());
+        }
+        let mut archive = tar::Builder::new(zstd::Encoder::new(File::create(path).unwrap(), ZSTD_LEVEL).unwrap());
+        for (name, bytes) in &files {
+            let mut header = tar::Header::new_gnu();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(bytes.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive.append(&header, bytes.as_slice()).unwrap();
+        }
+        archive.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn carries_the_data_to_a_fresh_node_and_leaves_keys_and_license_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let node = populated(dir.path()).await;
+        let archive = dir.path().join("node.tar.zst");
+        let exported = ask(&node, Request::ExportSnapshot { path: Some(archive.display().to_string()) }).await;
+        assert_eq!(exported["SnapshotExported"]["rows"], 2, "{}", exported);
+
+        let files = entries(&archive);
+        let names: Vec<&str> = files.keys().map(String::as_str).collect();
+        assert_eq!(
+            names,
+            [
+                "core/named_queries.json",
+                "core/relations.json",
+                "core/relations/notes.jsonl",
+                "manifest.json",
+                "modules/noop/manifest.json",
+                "modules/noop/module.wasm",
+                "state/jobs.json",
+                "state/peers.json",
+            ]
+        );
+        // What stays on the machine is really there, and in no file.
+        let data_dir = DataDir::at(node.data_dir());
+        let salt = std::fs::read(data_dir.install_salt()).unwrap();
+        assert!(std::fs::read_to_string(data_dir.state().join("state.json")).unwrap().contains(SECRET));
+        for (name, bytes) in &files {
+            let text = String::from_utf8_lossy(bytes);
+            assert!(!text.contains(SECRET) && !text.contains("license") && !text.contains("source_path"), "{}: {}", name, text);
+            assert!(!bytes.windows(salt.len()).any(|w| w == salt.as_slice()), "{} holds the install salt", name);
+        }
+        assert_eq!(files["state/peers.json"], serde_json::to_vec_pretty(&["/ip4/10.9.9.9/tcp/4001"]).unwrap());
+
+        let fresh = TestNode::start_with(TestNodeOptions::default()).await.unwrap();
+        let imported = ask(&fresh, Request::ImportSnapshot { path: Some(archive.display().to_string()) }).await;
+        let summary = &imported["SnapshotImported"]["summary"];
+        assert_eq!((&summary["relations"], &summary["modules"], &summary["jobs"], &summary["peers"]), (&1.into(), &1.into(), &1.into(), &1.into()), "{}", imported);
+        let rows = ask(&fresh, query("?[k, v] := *notes{k, v}")).await;
+        assert_eq!(rows["CoreResult"]["rows"], serde_json::json!([["a", 1], ["b", 2]]));
+        assert!(ask(&fresh, Request::CoreListNamed).await.to_string().contains("\"keys\""));
+        assert!(ask(&fresh, Request::WasmList).await.to_string().contains("\"noop\""));
+        assert!(ask(&fresh, Request::WasmListJobs).await.to_string().contains("\"hourly\""));
+        let fresh_dir = DataDir::at(fresh.data_dir());
+        assert!(!std::fs::read_to_string(fresh_dir.state().join("state.json")).unwrap_or_default().contains(SECRET));
+        assert!(!std::fs::read_to_string(fresh_dir.swarm_key()).unwrap_or_default().contains(SECRET));
+        assert!(!fresh_dir.tls_key().exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn refuses_tampered_archives_and_undoes_a_partial_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let node = populated(dir.path()).await;
+        let archive = dir.path().join("node.tar.zst");
+        ask(&node, Request::ExportSnapshot { path: Some(archive.display().to_string()) }).await;
+        let files = entries(&archive);
+
+        let target = TestNode::start_with(TestNodeOptions::default()).await.unwrap();
+        ask(&target, query(":create notes {k: String => v: Int}")).await;
+        ask(&target, query("?[k, v] <- [['kept', 7]] :put notes {k => v}")).await;
+        let import = |files: BTreeMap<String, Vec<u8>>, reseal: bool| {
+            let path = dir.path().join("tampered.tar.zst");
+            repack(&path, files, reseal);
+            let target = &target;
+            async move {
+                match target.client().request(Request::ImportSnapshot { path: Some(path.display().to_string()) }).await.unwrap() {
+                    Response::Error(e) => e,
+                    other => panic!("Expected an error, got {:?}", other),
+                }
+            }
+        };
+
+        let mut rows = files.clone();
+        rows.insert("core/relations/notes.jsonl".into(), b"[\"a\",1000000]\n[\"b\",2]\n".to_vec());
+        assert_eq!(import(rows, false).await, "core/relations/notes.jsonl in the snapshot does not match its checksum");
+        let mut key = files.clone();
+        key.insert("keys/swarm.key".into(), SECRET.into());
+        assert_eq!(import(key.clone(), false).await, "The snapshot holds keys/swarm.key, which its manifest does not list");
+        assert_eq!(import(key, true).await, "The snapshot holds keys/swarm.key, which this node does not know what to do with");
+        let mut escape = files.clone();
+        escape.insert("../escape".into(), Vec::new());
+        assert!(import(escape, true).await.ends_with("The snapshot holds the unsafe path ../escape"));
+        assert!(!dir.path().join("escape").exists());
+        let mut missing = files.clone();
+        missing.remove("state/jobs.json");
+        assert_eq!(import(missing, false).await, "The snapshot is missing state/jobs.json");
+        let mut newer = files.clone();
+        let mut manifest: serde_json::Value = serde_json::from_slice(&newer[MANIFEST]).unwrap();
+        manifest["node_version"] = "999.0.0".into();
+        newer.insert(MANIFEST.into(), serde_json::to_vec(&manifest).unwrap());
+        assert!(import(newer, false).await.starts_with("The snapshot was exported by node 999.0.0, newer than this node's"));
+
+        // Jobs are applied last; one that cannot be scheduled undoes the
+        // relations, named queries and modules applied before it.
+        let mut jobs: serde_json::Value = serde_json::from_slice(&files["state/jobs.json"]).unwrap();
+        jobs["not a name!"] = jobs["hourly"].clone();
+        let mut partial = files.clone();
+        partial.insert("state/jobs.json".into(), serde_json::to_vec(&jobs).unwrap());
+        assert!(import(partial, true).await.starts_with("Failed to schedule the job 'not a name!'"));
+
+        let rows = ask(&target, query("?[k, v] := *notes{k, v}")).await;
+        assert_eq!(rows["CoreResult"]["rows"], serde_json::json!([["kept", 7]]));
+        assert!(!ask(&target, Request::CoreListNamed).await.to_string().contains("\"keys\""));
+        assert!(!ask(&target, Request::WasmList).await.to_string().contains("\"noop\""));
+        assert!(!ask(&target, Request::WasmListJobs).await.to_string().contains("\"hourly\""));
+        assert!(std::fs::read_dir(DataDir::at(target.data_dir()).snapshot_spool()).map_or(true, |mut dir| dir.next().is_none()));
+    }
+}
@@ -0,0 +1,756 @@
+//! Whole nodes, in-process, for integration tests.
+//!
+//! Each node gets its own temporary data directory and IPC socket, a mesh
+//! listening on an ephemeral loopback port, an in-memory core and a finance
+//! backend that never connects. Nodes are bootstrapped to the ones started
+//! before them, so a `TestNet` forms one mesh. They send presence beacons
+//! every `PRESENCE_INTERVAL`, and count a node stale after `PRESENCE_STALE`.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use sovereign_node::testkit::TestNet;
+//! use std::time::Duration;
+//!
+//! let net = TestNet::start(3).await?;
+//! net.wait_for_peers(2, Duration::from_secs(10)).await?;
+//! let status = net.node(0).status().await?;
+//! assert_eq!(status.mesh_connections, 2);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! With the `fault-injection` feature, `TestNode::inject_fault` breaks one
+//! of a node's subsystems for a while, for tests of how the rest of it
+//! copes: a crashed mesh actor restarting, license checks failing, core
+//! requests timing out, module runs trapping, clients that stop being
+//! written to.
+//!
+//! `TestNode::outbox` and `wait_for_outbox_drained` look into the
+//! replication outbox of a node that replicates, as
+//! `TestNodeOptions::replicate` or its config's `[replication]` says,
+//! e.g. to check that writes made while the mesh was down by
+//! `inject_fault` all go out once it is back, each once and in order.
+//!
+//! `TestNode::start_with` takes `TestNodeOptions`: access tokens, whose
+//! connections are core grant principals of their own, a core kept on
+//! disk, which `TestNode::restart` carries over, a script setting it up,
+//! relations to replicate and a config for the rest. `TestNode::grant`
+//! and `revoke` manage core grants.
+//!
+//! Dropping a node stops it and removes its directory, also when a test
+//! panics. A node's settings come from `TestNodeOptions::config` alone:
+//! `SOVEREIGN_*` variables, which would reach every test at once, are not
+//! applied to it.
+
+use crate::blocking_pool::BlockingPool;
+use crate::config::{NodeConfig, TokenRule};
+use crate::data_dir::DataDir;
+use crate::finance_backend::FinanceBackend;
+use anyhow::{anyhow, bail, Context};
+use sovereign_client::NodeClient;
+use sovereign_core::CognitiveCore;
+#[cfg(feature = "fault-injection")]
+use sovereign_protocol::{FaultKind, FaultTarget};
+use sovereign_protocol::{
+    CoreGrantInfo, CoreGrantRights, EventTopic, IpcEndpoint, NodeEvent, NodeStatus, OutboxEntryInfo, PeerPresence, ReplicationOutboxReport, Request, Response,
+};
+use std::path::Path;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+pub use sovereign_replication::ReplicationConfig;
+
+/// How long a node gets to start listening, on IPC and on the mesh.
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the wait helpers ask for the node's status.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Outbox entries asked for at a time; the most a node lists at once.
+const OUTBOX_PAGE: u32 = 1000;
+
+/// How often test nodes send presence beacons.
+pub const PRESENCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a test node goes unheard before the others count it stale.
+pub const PRESENCE_STALE: Duration = Duration::from_secs(3);
+
+/// Several nodes meshed together.
+pub struct TestNet {
+    nodes: Vec<TestNode>,
+}
+
+impl TestNet {
+    /// Starts `n` nodes one after another, each bootstrapped to every node
+    /// before it.
+    pub async fn start(n: usize) -> anyhow::Result<Self> {
+        let mut nodes: Vec<TestNode> = Vec::with_capacity(n);
+        for index in 0..n {
+            let bootstrap = nodes.iter().flat_map(TestNode::dial_addrs).collect();
+            let node = TestNode::start(bootstrap).await.with_context(|| format!("Failed to start test node {}", index))?;
+            nodes.push(node);
+        }
+        Ok(Self { nodes })
+    }
+
+    pub fn nodes(&self) -> &[TestNode] {
+        &self.nodes
+    }
+
+    /// Panics if there is no node `index`.
+    pub fn node(&self, index: usize) -> &TestNode {
+        &self.nodes[index]
+    }
+
+    /// Waits until every node has at least `n` mesh peers.
+    pub async fn wait_for_peers(&self, n: u32, timeout: Duration) -> anyhow::Result<()> {
+        let deadline = Instant::now() + timeout;
+        for (index, node) in self.nodes.iter().enumerate() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            node.wait_for_peers(n, left).await.with_context(|| format!("Test node {}", index))?;
+        }
+        Ok(())
+    }
+
+    /// Waits until every node has a fresh presence beacon from every other.
+    pub async fn wait_for_presence(&self, timeout: Duration) -> anyhow::Result<()> {
+        let deadline = Instant::now() + timeout;
+        for (index, node) in self.nodes.iter().enumerate() {
+            let others: Vec<&str> = self.nodes.iter().map(TestNode::peer_id).filter(|peer| *peer != node.peer_id()).collect();
+            let left = deadline.saturating_duration_since(Instant::now());
+            node.wait_for_table(left, "every other node fresh", |nodes| {
+                others.iter().all(|peer| nodes.iter().any(|n| n.peer_id == *peer && !n.stale))
+            })
+            .await
+            .with_context(|| format!("Test node {}", index))?;
+        }
+        Ok(())
+    }
+
+    /// Stops every node, waiting for each to shut down cleanly.
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        for node in self.nodes {
+            node.shutdown().await?;
+        }
+        Ok(())
+    }
+}
+
+/// How `TestNode::start_with` sets a node up beyond what `start` does.
+#[derive(Debug, Clone, Default)]
+pub struct TestNodeOptions {
+    /// Peers the node dials at startup.
+    pub bootstrap: Vec<String>,
+    /// `(name, token)` pairs the node accepts in Hello, each with every
+    /// permission. A connection from `TestNode::connect_with_token` is the
+    /// core grant principal `token:<name>`. Tokens are at least 16
+    /// characters.
+    pub tokens: Vec<(String, String)>,
+    /// Keeps the core in SQLite under the data directory instead of in
+    /// memory, so that it survives `TestNode::restart`.
+    pub persistent_core: bool,
+    /// A core script run once before the node first starts, e.g. creating
+    /// the relations `replicate` names, which must exist by then. Needs
+    /// `persistent_core`.
+    pub core_script: Option<String>,
+    /// Core relations to sync with the other nodes replicating them in the
+    /// same namespace. Unset, the config's `[replication]` decides.
+    pub replicate: Option<ReplicationConfig>,
+    /// Config file text to start from, for settings the options above do
+    /// not cover. Its data directory, endpoint, mesh addresses, presence and
+    /// core backend are the testkit's, and `tokens` are added to its own.
+    /// Unset, the defaults, except that idle connections are never closed.
+    pub config: Option<String>,
+    /// Connects the finance backend to the config's Electrum servers in the
+    /// background, retrying as the binary does. Unset, it never connects
+    /// and license checks answer `Unavailable`.
+    pub connect_finance: bool,
+}
+
+/// One node, and a client connected to it.
+pub struct TestNode {
+    client: NodeClient,
+    endpoint: IpcEndpoint,
+    peer_id: String,
+    listen_addrs: Vec<String>,
+    /// Kept for `restart`.
+    config: NodeConfig,
+    replication: Option<ReplicationConfig>,
+    connect_finance: bool,
+    stop: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<anyhow::Result<()>>>,
+    // Dropped last, once the node has been told to stop.
+    dir: TempDir,
+}
+
+impl TestNode {
+    /// Starts a node that dials `bootstrap` at startup.
+    pub async fn start(bootstrap: Vec<String>) -> anyhow::Result<Self> {
+        Self::start_with(TestNodeOptions { bootstrap, ..Default::default() }).await
+    }
+
+    /// Starts a node set up as `options` says.
+    pub async fn start_with(options: TestNodeOptions) -> anyhow::Result<Self> {
+        let dir = tempfile::Builder::new().prefix("sovereign-testkit-").tempdir()?;
+        let socket = dir.path().join("node.sock");
+        let mut config = match &options.config {
+            Some(text) => NodeConfig::parse(text)?,
+            // Tests may sit idle for long between requests.
+            None => NodeConfig {
+                ipc_idle_timeout_mins: 0,
+                ..Default::default()
+            },
+        };
+        config.data_dir = Some(dir.path().to_path_buf());
+        config.ipc_endpoint = Some(socket.display().to_string());
+        config.mesh.listen_addrs = vec!["/ip4/127.0.0.1/tcp/0".into()];
+        config.mesh.bootstrap_peers = options.bootstrap;
+        config.mesh.warmup_timeout_secs = 10;
+        config.presence.enabled = true;
+        config.presence.interval_secs = PRESENCE_INTERVAL.as_secs();
+        config.presence.stale_after_secs = PRESENCE_STALE.as_secs();
+        config.core.backend = if options.persistent_core { "sqlite" } else { "mem" }.into();
+        config.access.tokens.extend(options.tokens.into_iter().map(|(name, token)| TokenRule {
+            token,
+            name: Some(name),
+            permissions: vec!["all".into()],
+        }));
+        config.validate()?;
+        if let Some(script) = &options.core_script {
+            if !options.persistent_core {
+                bail!("TestNodeOptions::core_script needs persistent_core");
+            }
+            let data_dir = DataDir::open(dir.path())?;
+            let core = CognitiveCore::new(crate::core_config(&config, &data_dir)?)?;
+            core.migrate()?;
+            core.run(script, serde_json::Value::Null).context("The test node's core script failed")?;
+        }
+        let replication = options.replicate.or_else(|| config.replication());
+
+        let endpoint = IpcEndpoint::UnixSocket(socket);
+        let (client, stop, task) = launch(&config, &replication, options.connect_finance, &dir, &endpoint).await?;
+        let mut node = Self {
+            client,
+            endpoint,
+            peer_id: String::new(),
+            listen_addrs: Vec::new(),
+            config,
+            replication,
+            connect_finance: options.connect_finance,
+            stop: Some(stop),
+            task: Some(task),
+            dir,
+        };
+        node.wait_for_listen_addrs().await?;
+        Ok(node)
+    }
+
+    /// Stops the node, waits for it to shut down and starts it again on the
+    /// same data directory, reconnecting `client`. Only what the node keeps
+    /// on disk carries over, and the core only with
+    /// `TestNodeOptions::persistent_core`. The mesh listens on a new port.
+    pub async fn restart(&mut self) -> anyhow::Result<()> {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(task) = self.task.take() {
+            task.await.context("The test node panicked")??;
+        }
+        let (client, stop, task) = launch(&self.config, &self.replication, self.connect_finance, &self.dir, &self.endpoint).await?;
+        self.client = client;
+        self.stop = Some(stop);
+        self.task = Some(task);
+        self.wait_for_listen_addrs().await
+    }
+
+    async fn wait_for_listen_addrs(&mut self) -> anyhow::Result<()> {
+        let status = self.wait_for(START_TIMEOUT, "a mesh listen address", |s| !s.mesh_listen_addrs.is_empty()).await?;
+        self.peer_id = status.mesh_peer_id;
+        self.listen_addrs = status.mesh_listen_addrs;
+        Ok(())
+    }
+
+    /// The client connected when the node started. Its pushes go to
+    /// `wait_for_event` while that runs.
+    pub fn client(&self) -> &NodeClient {
+        &self.client
+    }
+
+    /// Another client, for tests that need a connection of their own.
+    pub async fn connect(&self, client_name: &str) -> anyhow::Result<NodeClient> {
+        NodeClient::connect(&self.endpoint, client_name).await
+    }
+
+    /// Another client, saying Hello with `token`, one of
+    /// `TestNodeOptions::tokens`.
+    pub async fn connect_with_token(&self, client_name: &str, token: &str) -> anyhow::Result<NodeClient> {
+        NodeClient::connect_with_token(&self.endpoint, client_name, Some(token)).await
+    }
+
+    pub fn endpoint(&self) -> &IpcEndpoint {
+        &self.endpoint
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    pub fn listen_addrs(&self) -> &[String] {
+        &self.listen_addrs
+    }
+
+    /// Where other nodes can dial this one, peer id included.
+    pub fn dial_addrs(&self) -> Vec<String> {
+        self.listen_addrs.iter().map(|addr| format!("{}/p2p/{}", addr, self.peer_id)).collect()
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub async fn status(&self) -> anyhow::Result<NodeStatus> {
+        match self.client.request(Request::GetStatus).await? {
+            Response::Status(status) => Ok(status),
+            other => bail!("Unexpected answer to GetStatus: {:?}", other),
+        }
+    }
+
+    /// Lets `principal`, e.g. `token:<name>`, touch the relations
+    /// `pattern` names. Once it has a grant, its queries may touch nothing
+    /// else.
+    pub async fn grant(&self, principal: &str, pattern: &str, rights: CoreGrantRights) -> anyhow::Result<CoreGrantInfo> {
+        let req = Request::CoreGrant {
+            principal: principal.into(),
+            relation_pattern: pattern.into(),
+            rights,
+        };
+        match self.client.request(req).await? {
+            Response::CoreGranted(grant) => Ok(grant),
+            other => bail!("Unexpected answer to CoreGrant: {:?}", other),
+        }
+    }
+
+    /// Whether `principal` had a grant on exactly `pattern` to revoke.
+    pub async fn revoke(&self, principal: &str, pattern: &str) -> anyhow::Result<bool> {
+        let req = Request::CoreRevoke {
+            principal: principal.into(),
+            relation_pattern: pattern.into(),
+        };
+        match self.client.request(req).await? {
+            Response::CoreRevoked { revoked, .. } => Ok(revoked),
+            other => bail!("Unexpected answer to CoreRevoke: {:?}", other),
+        }
+    }
+
+    pub async fn grants(&self, principal: Option<&str>) -> anyhow::Result<Vec<CoreGrantInfo>> {
+        match self.client.request(Request::CoreListGrants { principal: principal.map(str::to_string) }).await? {
+            Response::CoreGrants(grants) => Ok(grants),
+            other => bail!("Unexpected answer to CoreListGrants: {:?}", other),
+        }
+    }
+
+    /// Makes `target` fail as `kind` says for `duration`, or ends its fault
+    /// for a zero `duration`. Whether a fault is left armed.
+    #[cfg(feature = "fault-injection")]
+    pub async fn inject_fault(&self, target: FaultTarget, kind: FaultKind, duration: Duration) -> anyhow::Result<bool> {
+        let duration_ms = duration.as_millis() as u64;
+        match self.client.request(Request::InjectFault { target, kind, duration_ms }).await? {
+            Response::FaultInjected { active, .. } => Ok(active),
+            other => bail!("Unexpected answer to InjectFault: {:?}", other),
+        }
+    }
+
+    /// Waits until the node has at least `n` mesh peers.
+    pub async fn wait_for_peers(&self, n: u32, timeout: Duration) -> anyhow::Result<()> {
+        self.wait_for(timeout, &format!("{} mesh peer(s)", n), |s| s.mesh_connections >= n).await.map(drop)
+    }
+
+    /// The other nodes this one has presence beacons from.
+    pub async fn presence(&self) -> anyhow::Result<Vec<PeerPresence>> {
+        match self.client.request(Request::MeshPresence).await? {
+            Response::MeshPresence { nodes, .. } => Ok(nodes),
+            other => bail!("Unexpected answer to MeshPresence: {:?}", other),
+        }
+    }
+
+    /// Waits until this node counts `peer` stale, as it should within
+    /// `PRESENCE_STALE` of `peer` stopping.
+    pub async fn wait_for_stale(&self, peer: &str, timeout: Duration) -> anyhow::Result<()> {
+        self.wait_for_table(timeout, &format!("{} stale", peer), |nodes| nodes.iter().any(|n| n.peer_id == peer && n.stale)).await
+    }
+
+    /// The replication outbox: the pending entries, and with
+    /// `include_published` the ones gossiped lately too, in `seq` order.
+    /// Fails unless the node replicates.
+    pub async fn outbox(&self, include_published: bool) -> anyhow::Result<ReplicationOutboxReport> {
+        let mut report = self.outbox_page(None, include_published).await?;
+        let mut page = report.entries.len();
+        while page == OUTBOX_PAGE as usize {
+            let after = report.entries.last().map(|e| e.seq);
+            let more = self.outbox_page(after, include_published).await?.entries;
+            page = more.len();
+            report.entries.extend(more);
+        }
+        Ok(report)
+    }
+
+    async fn outbox_page(&self, after_seq: Option<u64>, include_published: bool) -> anyhow::Result<ReplicationOutboxReport> {
+        let req = Request::ReplicationOutbox {
+            after_seq,
+            include_published,
+            limit: Some(OUTBOX_PAGE),
+        };
+        match self.client.request(req).await? {
+            Response::ReplicationOutbox(report) => Ok(report),
+            other => bail!("Unexpected answer to ReplicationOutbox: {:?}", other),
+        }
+    }
+
+    /// Makes the entries `seqs` pending again; how many there were.
+    pub async fn requeue(&self, seqs: Vec<u64>) -> anyhow::Result<u64> {
+        match self.client.request(Request::ReplicationRequeue { seqs }).await? {
+            Response::ReplicationRequeued { requeued } => Ok(requeued),
+            other => bail!("Unexpected answer to ReplicationRequeue: {:?}", other),
+        }
+    }
+
+    /// Waits until nothing is left in the replication outbox, and returns
+    /// every entry it still holds, published ones included.
+    pub async fn wait_for_outbox_drained(&self, timeout: Duration) -> anyhow::Result<Vec<OutboxEntryInfo>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let report = self.outbox(true).await?;
+            if report.depth == 0 && report.entries.iter().all(|e| e.published_ms.is_some()) {
+                return Ok(report.entries);
+            }
+            if Instant::now() >= deadline {
+                bail!("The replication outbox still held {} entries after {:?}", report.depth, timeout);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Polls the node's presence table until `done` accepts it.
+    async fn wait_for_table(&self, timeout: Duration, what: &str, done: impl Fn(&[PeerPresence]) -> bool) -> anyhow::Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if done(&self.presence().await?) {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                bail!("The node's presence table did not reach {} within {:?}", what, timeout);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Subscribes to `topic` and waits for an event on it that `predicate`
+    /// accepts, returning it. Only events after the call count.
+    pub async fn wait_for_event(
+        &self,
+        topic: EventTopic,
+        predicate: impl Fn(&NodeEvent) -> bool,
+        timeout: Duration,
+    ) -> anyhow::Result<NodeEvent> {
+        let mut pushes = self.client.pushes();
+        match self.client.request(Request::Subscribe { topics: vec![topic] }).await? {
+            Response::Subscribed { .. } => {}
+            other => bail!("Unexpected answer to Subscribe: {:?}", other),
+        }
+        let found = tokio::time::timeout(timeout, async {
+            while let Some(push) = pushes.recv().await {
+                if let Response::Event { event, .. } = push {
+                    if predicate(&event) {
+                        return Ok(event);
+                    }
+                }
+            }
+            Err(anyhow!("The connection to the node closed"))
+        })
+        .await;
+        let _ = self.client.request(Request::Unsubscribe { topics: vec![topic] }).await;
+        found.map_err(|_| anyhow!("No matching {:?} event within {:?}", topic, timeout))?
+    }
+
+    /// Stops the node and waits for it to shut down, then removes its
+    /// directory.
+    pub async fn shutdown(mut self) -> anyhow::Result<()> {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        match self.task.take() {
+            Some(task) => task.await.context("The test node panicked")?,
+            None => Ok(()),
+        }
+    }
+
+    /// Polls the node's status until `done` accepts it.
+    async fn wait_for(&self, timeout: Duration, what: &str, done: impl Fn(&NodeStatus) -> bool) -> anyhow::Result<NodeStatus> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let status = self.status().await?;
+            if done(&status) {
+                return Ok(status);
+            }
+            if Instant::now() >= deadline {
+                bail!("The node did not reach {} within {:?}", what, timeout);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+impl Drop for TestNode {
+    /// Tells the node to stop; it shuts down in the background while the
+    /// runtime lasts.
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+/// Serves `config` from `dir` until told to stop, and connects to it.
+async fn launch(
+    config: &NodeConfig,
+    replication: &Option<ReplicationConfig>,
+    connect_finance: bool,
+    dir: &TempDir,
+    endpoint: &IpcEndpoint,
+) -> anyhow::Result<(NodeClient, oneshot::Sender<()>, JoinHandle<anyhow::Result<()>>)> {
+    let (stop, stopped) = oneshot::channel::<()>();
+    let data_dir = DataDir::open(dir.path())?;
+    let finance = if connect_finance {
+        let pool = BlockingPool::new("finance", config.finance_pool())?;
+        let config = config.clone();
+        FinanceBackend::start(pool, move || config.license_verifier())
+    } else {
+        FinanceBackend::offline()?
+    };
+    let mut task = tokio::spawn(crate::serve(config.clone(), data_dir, finance, None, None, replication.clone(), async move {
+        let _ = stopped.await;
+        Ok(())
+    }));
+    match connect(endpoint, &mut task).await {
+        Ok(client) => Ok((client, stop, task)),
+        Err(e) => {
+            let _ = stop.send(());
+            Err(e)
+        }
+    }
+}
+
+/// Connects once the node's socket is up, or fails with why the node
+/// stopped.
+async fn connect(endpoint: &IpcEndpoint, task: &mut JoinHandle<anyhow::Result<()>>) -> anyhow::Result<NodeClient> {
+    let deadline = Instant::now() + START_TIMEOUT;
+    loop {
+        if task.is_finished() {
+            return match task.await {
+                Ok(Ok(())) => Err(anyhow!("The node stopped as it started")),
+                Ok(Err(e)) => Err(e.context("The node failed to start")),
+                Err(e) => Err(anyhow!("The node panicked as it started: {}", e)),
+            };
+        }
+        match NodeClient::connect(endpoint, "testkit").await {
+            Ok(client) => return Ok(client),
+            Err(e) if Instant::now() >= deadline => return Err(e.context("The node's IPC socket never came up")),
+            Err(_) => tokio::time::sleep(POLL_INTERVAL).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn nodes_mesh_and_tear_down() {
+        let net = TestNet::start(2).await.unwrap();
+        net.wait_for_peers(1, Duration::from_secs(20)).await.unwrap();
+        let status = net.node(1).status().await.unwrap();
+        assert_eq!(status.mesh_peer_id, net.node(1).peer_id());
+        assert!(status.mesh_connections >= 1);
+        let dirs: Vec<_> = net.nodes().iter().map(|node| node.data_dir().to_path_buf()).collect();
+        net.shutdown().await.unwrap();
+        assert!(dirs.iter().all(|dir| !dir.exists()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn nodes_learn_of_each_other_and_of_one_that_stops() {
+        let mut net = TestNet::start(3).await.unwrap();
+        net.wait_for_presence(Duration::from_secs(30)).await.unwrap();
+        for node in net.nodes() {
+            let nodes = node.presence().await.unwrap();
+            assert_eq!(nodes.len(), 2);
+            assert!(nodes.iter().all(|n| n.connected && n.node_version == env!("CARGO_PKG_VERSION")), "{:?}", nodes);
+        }
+
+        // The two left count the third stale within the window, and say so.
+        let gone = net.nodes.pop().unwrap();
+        let peer = gone.peer_id().to_string();
+        let window = PRESENCE_STALE + PRESENCE_INTERVAL * 2;
+        let stopped = Instant::now();
+        let (event, stop) = tokio::join!(
+            net.node(0).wait_for_event(EventTopic::Mesh, |event| matches!(event, NodeEvent::PresenceStale { peer: p } if *p == peer), window * 2),
+            gone.shutdown(),
+        );
+        stop.unwrap();
+        event.unwrap();
+        net.node(1).wait_for_stale(&peer, window.saturating_sub(stopped.elapsed())).await.unwrap();
+        assert!(stopped.elapsed() <= window, "took {:?}", stopped.elapsed());
+        for node in net.nodes() {
+            let nodes = node.presence().await.unwrap();
+            assert!(nodes.iter().all(|n| n.stale == (n.peer_id == peer)), "{:?}", nodes);
+            let status = node.status().await.unwrap();
+            assert!(status.health_details.iter().any(|d| d == "presence: 1 of 2 known nodes heard from, 1 connected"), "{:?}", status.health_details);
+        }
+        net.shutdown().await.unwrap();
+    }
+
+    async fn query(node: &TestNode, query: &str) -> serde_json::Value {
+        let request = Request::QueryCore {
+            query: query.into(),
+            params: serde_json::json!({}),
+            timeout_ms: None,
+            readonly: false,
+            limit: None,
+        };
+        match node.client().request(request).await.unwrap() {
+            Response::CoreResult(result) => result["rows"].clone(),
+            other => panic!("{} got {:?}", query, other),
+        }
+    }
+
+    /// Writes ids `ids` on `node`, five to a script, as `by` wrote them.
+    async fn write(node: &TestNode, by: &str, ids: std::ops::Range<i64>) {
+        for batch in ids.collect::<Vec<_>>().chunks(5) {
+            let rows: Vec<String> = batch.iter().map(|id| format!("[{}, '{} {}']", id, by, id)).collect();
+            query(node, &format!("?[id, body] <- [{}] :put notes {{id => body}}", rows.join(", "))).await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replicating_nodes_converge_after_concurrent_writes() {
+        let mut replicate = ReplicationConfig::new(vec!["notes".into()]);
+        replicate.namespace = format!("converge-{}", std::process::id());
+        replicate.digest_interval = Duration::from_secs(1);
+        let options = TestNodeOptions {
+            persistent_core: true,
+            core_script: Some(":create notes {id: Int => body: String}".into()),
+            replicate: Some(replicate),
+            ..Default::default()
+        };
+        let a = TestNode::start_with(options.clone()).await.unwrap();
+        let b = TestNode::start_with(TestNodeOptions { bootstrap: a.dial_addrs(), ..options }).await.unwrap();
+        b.wait_for_peers(1, Duration::from_secs(20)).await.unwrap();
+
+        // Both write ids 20 to 39 at the same time, and `a` then deletes
+        // rows only it wrote and one both did.
+        tokio::join!(write(&a, "a", 0..40), write(&b, "b", 20..60));
+        query(&a, "?[id] <- [[0], [1], [2], [25]] :rm notes {id}").await;
+
+        let read = "?[id, body] := *notes{id, body}";
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let (ours, theirs) = loop {
+            let (ours, theirs) = (query(&a, read).await, query(&b, read).await);
+            if ours == theirs && ours.as_array().unwrap().len() == 56 || Instant::now() >= deadline {
+                break (ours, theirs);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        };
+        assert_eq!(ours, theirs);
+        let rows = ours.as_array().unwrap();
+        let ids: Vec<i64> = rows.iter().map(|row| row[0].as_i64().unwrap()).collect();
+        assert_eq!(ids, (3..60).filter(|id| *id != 25).collect::<Vec<_>>());
+        for row in rows {
+            let (id, body) = (row[0].as_i64().unwrap(), row[1].as_str().unwrap());
+            let writers: &[&str] = match id {
+                ..=19 => &["a"],
+                20..=39 => &["a", "b"],
+                _ => &["b"],
+            };
+            assert!(writers.iter().any(|by| body == format!("{} {}", by, id)), "{}", row);
+        }
+
+        // Both outboxes went out, and a later write still reaches the other.
+        a.wait_for_outbox_drained(Duration::from_secs(20)).await.unwrap();
+        b.wait_for_outbox_drained(Duration::from_secs(20)).await.unwrap();
+        query(&b, "?[id, body] <- [[25, 'b again']] :put notes {id => body}").await;
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while query(&a, "?[body] := *notes{id: 25, body}").await != serde_json::json!([["b again"]]) {
+            assert!(Instant::now() < deadline, "the write never reached a");
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn outbox_publishes_each_op_once_and_in_order_through_mesh_faults() {
+        let mut replicate = ReplicationConfig::new(vec!["notes".into()]);
+        replicate.namespace = format!("outbox-{}", std::process::id());
+        // Only gossip carries the ops; digests would sync them in any order.
+        replicate.digest_interval = Duration::from_secs(3600);
+        let options = TestNodeOptions {
+            persistent_core: true,
+            core_script: Some(":create notes {id: Int => body: String}".into()),
+            replicate: Some(replicate),
+            ..Default::default()
+        };
+        let a = TestNode::start_with(options.clone()).await.unwrap();
+        let b = TestNode::start_with(TestNodeOptions { bootstrap: a.dial_addrs(), ..options }).await.unwrap();
+        b.wait_for_peers(1, Duration::from_secs(20)).await.unwrap();
+        let watcher = b.connect("watcher").await.unwrap();
+        let mut pushes = watcher.pushes();
+        let watch = Request::CoreWatch {
+            relation: "notes".into(),
+            filter: None,
+        };
+        assert!(matches!(watcher.request(watch).await.unwrap(), Response::CoreWatching { .. }));
+
+        // Everything `a` sends the mesh is dropped while it writes.
+        assert!(a.inject_fault(FaultTarget::Mesh, FaultKind::Drop, Duration::from_secs(60)).await.unwrap());
+        write(&a, "a", 0..5).await;
+        for id in 5..10 {
+            query(&a, &format!("?[id, body] <- [[{}, 'a {}']] :put notes {{id => body}}", id, id)).await;
+        }
+        // Replication queues each op once it hears of the write.
+        let deadline = Instant::now() + Duration::from_secs(20);
+        let held = loop {
+            let held = a.outbox(true).await.unwrap();
+            if held.entries.len() >= 10 || Instant::now() >= deadline {
+                break held;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        };
+        assert_eq!((held.depth, held.entries.len()), (10, 10));
+        assert!(held.entries.iter().all(|e| e.published_ms.is_none()));
+
+        // Then it reaches the mesh late, and then normally.
+        assert!(a.inject_fault(FaultTarget::Mesh, FaultKind::Delay { delay_ms: 300 }, Duration::from_secs(3)).await.unwrap());
+        let entries = a.wait_for_outbox_drained(Duration::from_secs(60)).await.unwrap();
+        assert_eq!(entries.len(), 10);
+        assert!(entries.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+        assert!(entries.windows(2).all(|pair| pair[0].published_ms <= pair[1].published_ms));
+        assert!(entries[0].attempts > 1, "{:?}", entries[0]);
+        let Response::Metrics(metrics) = a.client().request(Request::GetMetrics).await.unwrap() else { panic!("no metrics") };
+        let replication = metrics.replication.unwrap();
+        assert_eq!((replication.outbox_depth, replication.outbox_published), (0, 10));
+        assert!(replication.outbox_failures >= 1);
+
+        // `b` applied every op once, in the order `a` made them.
+        let mut applied = Vec::new();
+        while applied.len() < 10 {
+            match tokio::time::timeout(Duration::from_secs(20), pushes.recv()).await.unwrap().unwrap() {
+                Response::CoreChanged(change) => applied.extend(change.rows.iter().map(|row| row[0].as_i64().unwrap())),
+                other => panic!("Expected CoreChanged, got {:?}", other),
+            }
+        }
+        assert_eq!(applied, (0..10).collect::<Vec<_>>());
+        let late = tokio::time::timeout(Duration::from_secs(2), pushes.recv()).await;
+        assert!(late.is_err(), "{:?}", late);
+    }
+}
@@ -0,0 +1,159 @@
+use crate::presence;
+use sovereign_core::{AuditSource, CognitiveCore, QueryOptions};
+use sovereign_mesh::MeshCommand;
+use sovereign_runtime_wasm::{HostContext, HostFuture, PublishRejected};
+use std::sync::Arc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc;
+
+/// Node services exposed to WASM modules through the runtime's host imports.
+pub struct NodeHost {
+    core: Arc<CognitiveCore>,
+    mesh: mpsc::Sender<MeshCommand>,
+}
+
+impl NodeHost {
+    pub fn new(core: Arc<CognitiveCore>, mesh: mpsc::Sender<MeshCommand>) -> Self {
+        Self { core, mesh }
+    }
+}
+
+impl HostContext for NodeHost {
+    fn core_query(&self, request: String, namespace: Option<String>, principal: Option<String>) -> HostFuture<'_, anyhow::Result<String>> {
+        Box::pin(async move {
+            let request: serde_json::Value = serde_json::from_str(&request)?;
+            let query = request
+                .get("query")
+                .and_then(|q| q.as_str())
+                .ok_or_else(|| anyhow::anyhow!("missing 'query' string"))?;
+            let params = request.get("params").cloned().unwrap_or_else(|| serde_json::json!({}));
+
+            let options = QueryOptions {
+                source: AuditSource::Wasm,
+                namespace,
+                principal,
+                ..Default::default()
+            };
+            Ok(self.core.run_with(query, params, &options)?.to_string())
+        })
+    }
+
+    fn mesh_publish(&self, topic: &str, data: &[u8]) -> Result<(), PublishRejected> {
+        if topic.starts_with(presence::RESERVED_PREFIX) {
+            return Err(PublishRejected::Reserved);
+        }
+        let command = MeshCommand::Publish {
+            topic: topic.to_string(),
+            data: data.to_vec(),
+        };
+        match self.mesh.try_send(command) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(PublishRejected::QueueFull),
+            Err(TrySendError::Closed(_)) => Err(PublishRejected::Unavailable),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sovereign_core::CoreConfig;
+    use sovereign_runtime_wasm::{errno, Capability, ExecutionLimits, HostBudget, RunOptions, WasmRuntime};
+
+    /// A WASI command that sends `query` through `core_query`, prints the
+    /// result to stdout and exits with the errno.
+    fn querying(query: &str) -> String {
+        let request = serde_json::json!({ "query": query }).to_string();
+        format!(
+            r#"(module
+      (import "sovereign" "core_query" (func $core_query (param i32 i32 i32) (result i32)))
+      (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+      (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+      (memory (export "memory") 1)
+      (global $next (mut i32) (i32.const 4096))
+      (data (i32.const 1024) "{}")
+      (func (export "alloc") (param $len i32) (result i32)
+        (global.get $next)
+        (global.set $next (i32.add (global.get $next) (local.get $len))))
+      (func (export "_start")
+        (local $errno i32)
+        (local.set $errno (call $core_query (i32.const 1024) (i32.const {}) (i32.const 16)))
+        (i32.store (i32.const 0) (i32.load (i32.const 16)))
+        (i32.store (i32.const 4) (i32.load (i32.const 20)))
+        (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+        (call $proc_exit (local.get $errno))))"#,
+            request.replace('\\', "\\\\").replace('"', "\\\""),
+            request.len()
+        )
+    }
+
+    fn seeded_runtime() -> WasmRuntime {
+        let core = Arc::new(CognitiveCore::new(CoreConfig::default()).unwrap());
+        core.run(":create seeded {x: Int}", serde_json::json!({})).unwrap();
+        core.run("?[x] <- [[1], [2], [3]] :put seeded {x}", serde_json::json!({})).unwrap();
+        let (mesh, _) = mpsc::channel(1);
+        WasmRuntime::new().unwrap().with_host_context(Arc::new(NodeHost::new(core, mesh)))
+    }
+
+    fn core_read() -> RunOptions {
+        RunOptions {
+            capabilities: vec![Capability::CoreRead],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn modules_count_a_seeded_relation() {
+        let runtime = seeded_runtime();
+        let wat = querying("?[count(x)] := *seeded{x}");
+        let result = runtime.run_module(wat.as_bytes(), "", &core_read()).await.unwrap();
+        assert_eq!(result.exit_code, Some(errno::OK));
+        let rows: serde_json::Value = serde_json::from_str(&result.stdout).unwrap();
+        assert_eq!(rows["rows"], serde_json::json!([[3]]));
+    }
+
+    #[tokio::test]
+    async fn core_query_needs_core_read() {
+        let runtime = seeded_runtime();
+        let wat = querying("?[count(x)] := *seeded{x}");
+        let result = runtime.run_module(wat.as_bytes(), "", &RunOptions::default()).await.unwrap();
+        assert_eq!(result.exit_code, Some(errno::DENIED));
+        assert_eq!(result.stdout, "");
+    }
+
+    #[tokio::test]
+    async fn failures_reach_the_guest_as_error_codes() {
+        let runtime = seeded_runtime();
+        let broken = runtime.run_module(querying("?[x] := *missing{x}").as_bytes(), "", &core_read()).await.unwrap();
+        assert!(!broken.trapped);
+        assert_eq!(broken.exit_code, Some(errno::FAILED));
+        assert!(broken.stdout.contains("missing"), "{}", broken.stdout);
+
+        let no_queries = RunOptions {
+            limits: ExecutionLimits {
+                host_budget: Some(HostBudget {
+                    max_core_queries: 0,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..core_read()
+        };
+        let wat = querying("?[count(x)] := *seeded{x}");
+        let spent = runtime.run_module(wat.as_bytes(), "", &no_queries).await.unwrap();
+        assert_eq!(spent.exit_code, Some(errno::BUDGET_EXCEEDED));
+
+        let tiny_results = RunOptions {
+            limits: ExecutionLimits {
+                host_budget: Some(HostBudget {
+                    max_core_result_bytes: 4,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..core_read()
+        };
+        let too_big = runtime.run_module(wat.as_bytes(), "", &tiny_results).await.unwrap();
+        assert_eq!(too_big.exit_code, Some(errno::BUDGET_EXCEEDED));
+    }
+}
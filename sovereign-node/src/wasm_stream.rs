@@ -0,0 +1,120 @@
+use crate::service_loop::{wasm_result, write_data_frame, InboundFrame, Wire};
+use sovereign_protocol::{Request, Response};
+use sovereign_runtime_wasm::{RunOptions, StreamIo, WasmRuntime};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Input chunks queued between the connection and the module. Together with
+/// the output pipe this bounds what a streamed run buffers on the node.
+const INPUT_QUEUE_DEPTH: usize = 4;
+const OUTPUT_PIPE_BYTES: usize = 64 * 1024;
+
+/// Runs the module at `path` with its input taken from the data frames that
+/// follow the request, forwarding its output as data frames as it is written.
+///
+/// Input is consumed up to the empty end-of-input frame even if the module
+/// stops reading early, so the connection is back in step when the response
+/// goes out. An `Err` means the connection is broken or the client sent a
+/// request before finishing its input.
+pub(crate) async fn run_streamed<W: AsyncWrite + Unpin>(
+    wasm: &WasmRuntime,
+    path: &str,
+    options: RunOptions,
+    frames: &mut mpsc::Receiver<InboundFrame>,
+    writer: &mut Wire<W>,
+) -> io::Result<Response> {
+    let (input_tx, input_rx) = mpsc::channel::<Vec<u8>>(INPUT_QUEUE_DEPTH);
+    let (output_tx, mut output_rx) = tokio::io::duplex(OUTPUT_PIPE_BYTES);
+    let stream = StreamIo::new(ChannelReader::new(input_rx), output_tx);
+
+    let run = async move {
+        let bytes = match tokio::fs::read(path).await {
+            Ok(bytes) => bytes,
+            Err(e) => return Response::Error(format!("Cannot read WASM module {}: {}", path, e)),
+        };
+        wasm_result(wasm.run_streaming(&bytes, stream, &options).await)
+    };
+    tokio::pin!(run);
+
+    // Dropping the sender is what the module sees as end of input.
+    let mut input_tx = Some(input_tx);
+    let mut end_of_input = false;
+    let mut pending: Option<Vec<u8>> = None;
+    let mut response = None;
+    let mut output_open = true;
+    let mut buf = vec![0u8; OUTPUT_PIPE_BYTES];
+
+    while response.is_none() || input_tx.is_some() || output_open {
+        tokio::select! {
+            resp = &mut run, if response.is_none() => response = Some(resp),
+            // The pipe closes when the run finishes, so this also drains it.
+            n = output_rx.read(&mut buf), if output_open => match n {
+                Ok(0) | Err(_) => output_open = false,
+                Ok(n) => write_data_frame(writer, &buf[..n]).await?,
+            },
+            frame = frames.recv(), if input_tx.is_some() && pending.is_none() => match frame {
+                Some(InboundFrame::Data(data)) if data.is_empty() => end_of_input = true,
+                Some(InboundFrame::Data(data)) => pending = Some(data),
+                Some(InboundFrame::Request(body)) if is_heartbeat_ack(&body) => {}
+                Some(_) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "request sent before the end of streamed input"));
+                }
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+            },
+            permit = async { input_tx.as_ref()?.reserve().await.ok() }, if pending.is_some() => {
+                let chunk = pending.take().expect("branch only runs with a pending chunk");
+                match permit {
+                    Some(permit) => permit.send(chunk),
+                    None => debug!("Discarding streamed input the module did not read"),
+                }
+            }
+        }
+        if end_of_input {
+            input_tx = None;
+        }
+    }
+
+    Ok(response.expect("loop runs until the module has finished"))
+}
+
+pub(crate) fn is_heartbeat_ack(body: &[u8]) -> bool {
+    matches!(serde_json::from_slice(body), Ok(Request::HeartbeatAck { .. }))
+}
+
+/// Presents queued chunks as one continuous byte stream.
+struct ChannelReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelReader {
+    fn new(rx: mpsc::Receiver<Vec<u8>>) -> Self {
+        Self { rx, chunk: Vec::new(), pos: 0 }
+    }
+}
+
+impl AsyncRead for ChannelReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.pos == self.chunk.len() {
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                // End of input.
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = buf.remaining().min(self.chunk.len() - self.pos);
+        let start = self.pos;
+        buf.put_slice(&self.chunk[start..start + n]);
+        self.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
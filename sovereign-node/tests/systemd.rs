@@ -0,0 +1,85 @@
+//! Runs the `sovereign-node` binary as a `Type=notify` unit under the
+//! user's systemd instance. Opt in with `SOVEREIGN_SYSTEMD_TESTS=1`; the
+//! tests pass without running anything otherwise, or where there is no
+//! user instance to run under.
+
+use std::process::{Command, Output};
+use std::time::{Duration, Instant};
+
+fn opted_in() -> bool {
+    if std::env::var_os("SOVEREIGN_SYSTEMD_TESTS").is_none_or(|v| v != "1") {
+        eprintln!("Skipped: set SOVEREIGN_SYSTEMD_TESTS=1 to run under a user systemd instance");
+        return false;
+    }
+    let state = Command::new("systemctl").args(["--user", "is-system-running"]).output();
+    let state = state.map(|o| String::from_utf8_lossy(&o.stdout).trim().to_owned()).unwrap_or_default();
+    if !matches!(state.as_str(), "running" | "degraded") {
+        eprintln!("Skipped: no user systemd instance is running ({:?})", state);
+        return false;
+    }
+    true
+}
+
+fn run(program: &str, args: &[&str]) -> Output {
+    let output = Command::new(program).args(args).output().unwrap_or_else(|e| panic!("Failed to run {}: {}", program, e));
+    assert!(output.status.success(), "{} {:?}: {}", program, args, String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+fn property(unit: &str, name: &str) -> String {
+    let output = run("systemctl", &["--user", "show", "--value", "-p", name, unit]);
+    String::from_utf8_lossy(&output.stdout).trim().to_owned()
+}
+
+#[test]
+fn reports_ready_and_stopping_to_systemd() {
+    if !opted_in() {
+        return;
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("node.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "data_dir = {:?}\nipc_endpoint = {:?}\n[mesh]\nlisten_addrs = [\"/ip4/127.0.0.1/tcp/0\"]\n[finance]\nelectrum_urls = [\"tcp://127.0.0.1:1\"]\n",
+            dir.path().join("data"),
+            dir.path().join("node.sock"),
+        ),
+    )
+    .unwrap();
+    let unit = format!("sovereign-node-test-{}.service", std::process::id());
+
+    // With Type=notify, systemd-run returns once the node sent READY=1.
+    let started = Instant::now();
+    run(
+        "systemd-run",
+        &[
+            "--user",
+            "--unit",
+            &unit,
+            "-p",
+            "Type=notify",
+            "-p",
+            "NotifyAccess=main",
+            "-p",
+            "WatchdogSec=4",
+            "-p",
+            "TimeoutStartSec=60",
+            env!("CARGO_BIN_EXE_sovereign-node"),
+            "--config",
+            &config.to_string_lossy(),
+        ],
+    );
+    assert_eq!(property(&unit, "ActiveState"), "active");
+    assert!(dir.path().join("node.sock").exists(), "READY=1 came before the IPC socket was bound");
+
+    // Outlives a few watchdog deadlines, so it is sending WATCHDOG=1.
+    std::thread::sleep(Duration::from_secs(10).saturating_sub(started.elapsed()));
+    assert_eq!(property(&unit, "ActiveState"), "active");
+    let status = property(&unit, "StatusText");
+    assert!(status.starts_with("Serving IPC"), "{}", status);
+
+    run("systemctl", &["--user", "stop", &unit]);
+    let result = property(&unit, "Result");
+    assert!(result == "success" || result.is_empty(), "{}", result);
+}
@@ -0,0 +1,254 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::PROTOCOL_VERSION;
+#[cfg(windows)]
+use crate::PIPE_NAME;
+
+/// Environment variable overriding where the node listens and clients connect.
+pub const IPC_ENV_VAR: &str = "SOVEREIGN_IPC";
+
+/// Name of the discovery file the node writes into its data directory.
+pub const DISCOVERY_FILE: &str = "endpoint.json";
+
+/// Where the node's IPC server can be reached.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", content = "path")]
+pub enum IpcEndpoint {
+    /// A Windows named pipe, e.g. `\\.\pipe\SovereignNode-alice`.
+    NamedPipe(String),
+    /// A Unix domain socket path.
+    UnixSocket(PathBuf),
+}
+
+impl IpcEndpoint {
+    /// The per-user default for this platform.
+    ///
+    /// Windows: `\\.\pipe\SovereignNode-<user>`.
+    /// Unix: `$XDG_RUNTIME_DIR/sovereign/node.sock`, else `~/.sovereign/run/node.sock`.
+    pub fn default_for_platform() -> Self {
+        #[cfg(windows)]
+        {
+            IpcEndpoint::NamedPipe(format!("{}-{}", PIPE_NAME, current_user()))
+        }
+        #[cfg(not(windows))]
+        {
+            // Per-user directories, so no other user can swap the socket out.
+            match std::env::var_os("XDG_RUNTIME_DIR") {
+                Some(dir) if !dir.is_empty() => {
+                    IpcEndpoint::UnixSocket(PathBuf::from(dir).join("sovereign").join("node.sock"))
+                }
+                _ => IpcEndpoint::UnixSocket(home_dir().join(".sovereign").join("run").join("node.sock")),
+            }
+        }
+    }
+
+    /// `SOVEREIGN_IPC` when set, otherwise the platform default.
+    pub fn from_env() -> Self {
+        match std::env::var(IPC_ENV_VAR) {
+            Ok(value) if !value.trim().is_empty() => Self::parse(value.trim()),
+            _ => Self::default_for_platform(),
+        }
+    }
+
+    /// Interprets a user-supplied address. Pipe paths (`\\.\pipe\...`) are named
+    /// pipes; anything else is taken as a socket path.
+    pub fn parse(value: &str) -> Self {
+        if value.starts_with(r"\\.\pipe\") {
+            IpcEndpoint::NamedPipe(value.to_string())
+        } else {
+            IpcEndpoint::UnixSocket(PathBuf::from(value))
+        }
+    }
+
+    /// Resolution order used by clients: `SOVEREIGN_IPC`, then the discovery file
+    /// in `data_dir`, then the platform default.
+    pub fn discover(data_dir: &Path) -> Self {
+        if let Ok(value) = std::env::var(IPC_ENV_VAR) {
+            if !value.trim().is_empty() {
+                return Self::parse(value.trim());
+            }
+        }
+        match EndpointDiscovery::read(data_dir) {
+            Ok(discovery) => discovery.endpoint,
+            Err(_) => Self::default_for_platform(),
+        }
+    }
+}
+
+impl Default for IpcEndpoint {
+    fn default() -> Self {
+        Self::default_for_platform()
+    }
+}
+
+impl fmt::Display for IpcEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpcEndpoint::NamedPipe(name) => write!(f, "{}", name),
+            IpcEndpoint::UnixSocket(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Contents of `endpoint.json`: what a running node actually bound.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EndpointDiscovery {
+    pub endpoint: IpcEndpoint,
+    pub peer_id: String,
+    pub protocol_version: u32,
+    pub pid: u32,
+}
+
+impl EndpointDiscovery {
+    pub fn new(endpoint: IpcEndpoint, peer_id: String) -> Self {
+        Self {
+            endpoint,
+            peer_id,
+            protocol_version: PROTOCOL_VERSION,
+            pid: std::process::id(),
+        }
+    }
+
+    pub fn write(&self, data_dir: &Path) -> std::io::Result<()> {
+        std::fs::create_dir_all(data_dir)?;
+        let bytes = serde_json::to_vec_pretty(self).map_err(std::io::Error::other)?;
+        let tmp = data_dir.join(format!("{}.tmp", DISCOVERY_FILE));
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(tmp, data_dir.join(DISCOVERY_FILE))
+    }
+
+    pub fn read(data_dir: &Path) -> std::io::Result<Self> {
+        let bytes = std::fs::read(data_dir.join(DISCOVERY_FILE))?;
+        serde_json::from_slice(&bytes).map_err(std::io::Error::other)
+    }
+}
+
+/// The node's default data directory.
+///
+/// Linux: `$XDG_DATA_HOME/sovereign` or `~/.local/share/sovereign`.
+/// macOS: `~/Library/Application Support/Sovereign`.
+/// Windows: `%LOCALAPPDATA%\Sovereign`.
+pub fn default_data_dir() -> PathBuf {
+    #[cfg(windows)]
+    {
+        let base = std::env::var_os("LOCALAPPDATA").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("."));
+        base.join("Sovereign")
+    }
+    #[cfg(target_os = "macos")]
+    {
+        home_dir().join("Library").join("Application Support").join("Sovereign")
+    }
+    #[cfg(all(not(windows), not(target_os = "macos")))]
+    {
+        match std::env::var_os("XDG_DATA_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("sovereign"),
+            _ => home_dir().join(".local").join("share").join("sovereign"),
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn home_dir() -> PathBuf {
+    std::env::var_os("HOME").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("."))
+}
+
+#[cfg(windows)]
+fn current_user() -> String {
+    ["USER", "USERNAME", "LOGNAME"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|v| !v.is_empty()))
+        .map(|user| user.chars().filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_').collect())
+        .unwrap_or_else(|| "default".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Held by tests that set environment variables, which the process
+    /// shares.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Runs `f` with each of `vars` set, or removed for `None`, then puts
+    /// them back.
+    fn with_env<T>(vars: &[(&str, Option<&str>)], f: impl FnOnce() -> T) -> T {
+        let _held = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let saved: Vec<_> = vars.iter().map(|(name, _)| (*name, std::env::var_os(name))).collect();
+        for (name, value) in vars {
+            match value {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+        }
+        let out = f();
+        for (name, value) in saved {
+            match value {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn env_variable_overrides_the_default() {
+        with_env(&[(IPC_ENV_VAR, Some(" /srv/node.sock "))], || {
+            assert_eq!(IpcEndpoint::from_env(), IpcEndpoint::UnixSocket("/srv/node.sock".into()));
+        });
+        with_env(&[(IPC_ENV_VAR, Some(r"\\.\pipe\Custom"))], || {
+            assert_eq!(IpcEndpoint::from_env(), IpcEndpoint::NamedPipe(r"\\.\pipe\Custom".into()));
+        });
+        with_env(&[(IPC_ENV_VAR, Some("  "))], || {
+            assert_eq!(IpcEndpoint::from_env(), IpcEndpoint::default_for_platform());
+        });
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn unix_default_is_per_user() {
+        let runtime = with_env(&[("XDG_RUNTIME_DIR", Some("/run/user/1000"))], IpcEndpoint::default_for_platform);
+        assert_eq!(runtime, IpcEndpoint::UnixSocket("/run/user/1000/sovereign/node.sock".into()));
+        let home = with_env(&[("XDG_RUNTIME_DIR", None), ("HOME", Some("/home/alice"))], IpcEndpoint::default_for_platform);
+        assert_eq!(home, IpcEndpoint::UnixSocket("/home/alice/.sovereign/run/node.sock".into()));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_default_is_per_user() {
+        let endpoint = with_env(&[("USER", None), ("USERNAME", Some("al ice"))], IpcEndpoint::default_for_platform);
+        assert_eq!(endpoint, IpcEndpoint::NamedPipe(r"\\.\pipe\SovereignNode-alice".into()));
+    }
+
+    #[test]
+    fn discovery_file_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = IpcEndpoint::UnixSocket(dir.path().join("node.sock"));
+        EndpointDiscovery::new(endpoint.clone(), "12D3KooWpeer".into()).write(dir.path()).unwrap();
+
+        let read = EndpointDiscovery::read(dir.path()).unwrap();
+        assert_eq!(read.endpoint, endpoint);
+        assert_eq!(read.peer_id, "12D3KooWpeer");
+        assert_eq!(read.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(read.pid, std::process::id());
+        assert!(!dir.path().join(format!("{}.tmp", DISCOVERY_FILE)).exists());
+
+        // Clients take the variable first, then the file, then the default.
+        let other = with_env(&[(IPC_ENV_VAR, Some("/elsewhere.sock"))], || IpcEndpoint::discover(dir.path()));
+        assert_eq!(other, IpcEndpoint::UnixSocket("/elsewhere.sock".into()));
+        assert_eq!(with_env(&[(IPC_ENV_VAR, None)], || IpcEndpoint::discover(dir.path())), endpoint);
+        let empty = tempfile::tempdir().unwrap();
+        let default = with_env(&[(IPC_ENV_VAR, None)], || (IpcEndpoint::discover(empty.path()), IpcEndpoint::default_for_platform()));
+        assert_eq!(default.0, default.1);
+    }
+
+    #[test]
+    fn endpoints_serialize_for_config_files() {
+        let endpoint = IpcEndpoint::UnixSocket("/run/node.sock".into());
+        let json = serde_json::to_value(&endpoint).unwrap();
+        assert_eq!(json, serde_json::json!({"kind": "UnixSocket", "path": "/run/node.sock"}));
+        assert_eq!(serde_json::from_value::<IpcEndpoint>(json).unwrap(), endpoint);
+    }
+}
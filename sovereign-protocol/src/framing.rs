@@ -0,0 +1,855 @@
+//! The IPC wire framing: each frame is a 4-byte little-endian body length
+//! followed by the body, or, for shell tooling, one JSON message per line.
+//! Shared by the node and its clients so both ends read and write frames
+//! the same way.
+//!
+//! A binary frame's body may be compressed, once Hello has settled how: it
+//! is then `COMPRESSED_FRAME_TAG`, a `Compression` tag byte, the body's
+//! decompressed length as 4 little-endian bytes, and the compressed body.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Read, Write};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{COMPRESSED_FRAME_TAG, DATA_FRAME_TAG, MAX_FRAME_DISCARD};
+
+/// Size of the length prefix.
+const HEADER_LEN: usize = 4;
+
+/// What comes before a compressed body: the frame tag, the compression's
+/// tag and the decompressed length.
+const COMPRESSED_HEADER_LEN: usize = 6;
+
+/// The zstd level frames are compressed at: fast, for a local socket.
+const ZSTD_LEVEL: i32 = 3;
+
+/// One decoded frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// A JSON message body. May be empty.
+    Message(Bytes),
+    /// A data frame's payload, without the tag byte.
+    Data(Bytes),
+    /// A body over the size limit, announced as `declared` bytes. It is
+    /// skipped as it arrives, and the next frame is read as usual. For a
+    /// compressed body, `declared` is its decompressed length, and it is
+    /// never decompressed.
+    Oversized { declared: usize },
+}
+
+/// Why no further frame can be read from a stream.
+#[derive(Debug)]
+pub enum FrameError {
+    /// A body too large even to skip.
+    TooLarge { declared: usize, max: usize },
+    /// The stream ended partway through a frame. Says where.
+    Truncated(String),
+    /// A compressed body that does not decompress to what it declared.
+    Corrupt(String),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { declared, max } => write!(f, "Frame of {} bytes exceeds the limit of {} bytes", declared, max),
+            FrameError::Truncated(what) => write!(f, "{}", what),
+            FrameError::Corrupt(what) => write!(f, "Corrupt compressed frame: {}", what),
+            FrameError::Io(e) => write!(f, "Failed to read a frame: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<std::io::Error> for FrameError {
+    fn from(e: std::io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+/// Length-prefixed frames, for `tokio_util::codec::FramedRead` and
+/// `FramedWrite`, or to call directly. Decoding never buffers more than one
+/// frame's body, and an oversized body is skipped without being buffered.
+/// Compressed bodies are decompressed as they are decoded, up to
+/// `max_frame_size`; encoding compresses only `with_compression`.
+#[derive(Debug, Clone)]
+pub struct FrameCodec {
+    max_frame_size: usize,
+    discard_limit: usize,
+    compression: Option<FrameCompression>,
+    /// Bytes of an oversized body still to skip.
+    skipping: usize,
+    /// The body length of the frame being read, once its prefix is.
+    body_len: Option<usize>,
+}
+
+impl FrameCodec {
+    /// Decodes bodies up to `max_frame_size` bytes, skipping larger ones up
+    /// to `MAX_FRAME_DISCARD`.
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            max_frame_size,
+            discard_limit: MAX_FRAME_DISCARD,
+            compression: None,
+            skipping: 0,
+            body_len: None,
+        }
+    }
+
+    /// How large an oversized body may be and still be skipped. Past it the
+    /// stream fails with `FrameError::TooLarge`; below `max_frame_size`,
+    /// every oversized body does.
+    pub fn with_discard_limit(mut self, discard_limit: usize) -> Self {
+        self.discard_limit = discard_limit;
+        self
+    }
+
+    /// Compresses the bodies it encodes as Hello settled, if it did.
+    pub fn with_compression(mut self, compression: Option<FrameCompression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// Frames `data` as a data frame.
+    pub fn encode_data(&mut self, data: &[u8], dst: &mut BytesMut) -> std::io::Result<()> {
+        let mut body = Vec::with_capacity(1 + data.len());
+        body.push(DATA_FRAME_TAG);
+        body.extend_from_slice(data);
+        self.encode(body.as_slice(), dst)
+    }
+
+    /// What a compressed body holds. One that would decompress past
+    /// `max_frame_size` is not decompressed at all.
+    fn decompress(&self, body: &[u8]) -> Result<Frame, FrameError> {
+        if body.len() < COMPRESSED_HEADER_LEN {
+            return Err(FrameError::Corrupt(format!("Header cut short after {} bytes", body.len())));
+        }
+        let compression = Compression::from_tag(body[1]).ok_or_else(|| FrameError::Corrupt(format!("Unknown compression tag {}", body[1])))?;
+        let declared = u32::from_le_bytes([body[2], body[3], body[4], body[5]]) as usize;
+        if declared > self.max_frame_size {
+            return Ok(Frame::Oversized { declared });
+        }
+        let inner = compression.decompress(&body[COMPRESSED_HEADER_LEN..], declared).map_err(|e| FrameError::Corrupt(e.to_string()))?;
+        // A compressed body is a message or a data frame, never compressed again.
+        Ok(plain_frame(inner.into()))
+    }
+}
+
+/// A message, or a data frame's payload if `body` starts with the tag.
+fn plain_frame(mut body: Bytes) -> Frame {
+    if body.first() == Some(&DATA_FRAME_TAG) {
+        body.advance(1);
+        return Frame::Data(body);
+    }
+    Frame::Message(body)
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = FrameError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, FrameError> {
+        if self.skipping > 0 {
+            let skipped = self.skipping.min(src.len());
+            src.advance(skipped);
+            self.skipping -= skipped;
+            if self.skipping > 0 {
+                return Ok(None);
+            }
+        }
+
+        let len = match self.body_len {
+            Some(len) => len,
+            None => {
+                if src.len() < HEADER_LEN {
+                    return Ok(None);
+                }
+                let len = src.get_u32_le() as usize;
+                if len > self.max_frame_size {
+                    if len > self.discard_limit {
+                        return Err(FrameError::TooLarge {
+                            declared: len,
+                            max: self.max_frame_size,
+                        });
+                    }
+                    let skipped = len.min(src.len());
+                    src.advance(skipped);
+                    self.skipping = len - skipped;
+                    return Ok(Some(Frame::Oversized { declared: len }));
+                }
+                self.body_len = Some(len);
+                len
+            }
+        };
+
+        if src.len() < len {
+            src.reserve(len - src.len());
+            return Ok(None);
+        }
+        self.body_len = None;
+        let body = src.split_to(len).freeze();
+        if body.first() == Some(&COMPRESSED_FRAME_TAG) {
+            return self.decompress(&body).map(Some);
+        }
+        Ok(Some(plain_frame(body)))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, FrameError> {
+        if let Some(frame) = self.decode(src)? {
+            return Ok(Some(frame));
+        }
+        if self.skipping > 0 {
+            return Err(FrameError::Truncated(format!("Stream ended {} bytes short of the end of an oversized frame", self.skipping)));
+        }
+        match self.body_len {
+            Some(len) => Err(FrameError::Truncated(format!("Frame of {} bytes cut short after {} bytes", len, src.len()))),
+            None if !src.is_empty() => Err(FrameError::Truncated(format!("Frame length prefix cut short after {} bytes", src.len()))),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<&[u8]> for FrameCodec {
+    type Error = std::io::Error;
+
+    /// Frames a message body, compressed if it is large enough. The
+    /// receiver's limit is not checked here.
+    fn encode(&mut self, body: &[u8], dst: &mut BytesMut) -> std::io::Result<()> {
+        let compressed = match self.compression {
+            Some(compression) => compression.compress(body)?,
+            None => None,
+        };
+        let body = compressed.as_deref().unwrap_or(body);
+        let len = u32::try_from(body.len()).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Frame body of {} bytes is too large to send", body.len()))
+        })?;
+        dst.reserve(HEADER_LEN + body.len());
+        dst.put_u32_le(len);
+        dst.put_slice(body);
+        Ok(())
+    }
+}
+
+/// A complete frame carrying `body`, ready to write.
+pub fn encode_frame(body: &[u8]) -> std::io::Result<BytesMut> {
+    let mut dst = BytesMut::new();
+    FrameCodec::new(usize::MAX).encode(body, &mut dst)?;
+    Ok(dst)
+}
+
+/// A complete data frame carrying `data`, ready to write.
+pub fn encode_data_frame(data: &[u8]) -> std::io::Result<BytesMut> {
+    let len = u32::try_from(data.len() + 1).map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Data frame of {} bytes is too large to send", data.len()))
+    })?;
+    let mut dst = BytesMut::with_capacity(HEADER_LEN + 1 + data.len());
+    dst.put_u32_le(len);
+    dst.put_u8(DATA_FRAME_TAG);
+    dst.put_slice(data);
+    Ok(dst)
+}
+
+/// A way to compress frame bodies, offered by name in `Request::Hello`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    /// Every compression, in the order a node prefers them by default.
+    pub const ALL: [Compression; 2] = [Compression::Zstd, Compression::Gzip];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+            Compression::Gzip => "gzip",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.name() == name)
+    }
+
+    /// The first of `preferred` that `offered` names, if any.
+    pub fn pick(preferred: &[Compression], offered: &[String]) -> Option<Self> {
+        preferred.iter().copied().find(|c| offered.iter().any(|name| name == c.name()))
+    }
+
+    /// The byte that names it in a compressed frame's header.
+    fn tag(self) -> u8 {
+        match self {
+            Compression::Zstd => 1,
+            Compression::Gzip => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.tag() == tag)
+    }
+
+    fn compress(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::Zstd => zstd::bulk::compress(body, ZSTD_LEVEL),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Decompresses `body`, reading no more than one byte past `declared`,
+    /// so a body that inflates further is caught without being held.
+    fn decompress(self, body: &[u8], declared: usize) -> std::io::Result<Vec<u8>> {
+        let mut inner = Vec::with_capacity(declared);
+        let limit = declared as u64 + 1;
+        match self {
+            Compression::Zstd => zstd::stream::read::Decoder::new(body)?.take(limit).read_to_end(&mut inner)?,
+            Compression::Gzip => GzDecoder::new(body).take(limit).read_to_end(&mut inner)?,
+        };
+        if inner.len() != declared {
+            let held = if inner.len() > declared { "more" } else { "fewer" };
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} declared {} bytes but holds {}", self.name(), declared, held),
+            ));
+        }
+        Ok(inner)
+    }
+}
+
+/// How a connection compresses its binary frames, as `Response::HelloAck`
+/// settled it: bodies of `min_size` bytes or more, with `algorithm`. Both
+/// ends compress with it; either decompresses whatever it is sent, up to its
+/// own frame limit.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameCompression {
+    pub algorithm: Compression,
+    pub min_size: u64,
+}
+
+impl FrameCompression {
+    /// `body` as a compressed body, with its header; `None` if it is too
+    /// small to bother, or would not shrink.
+    fn compress(self, body: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
+        if (body.len() as u64) < self.min_size {
+            return Ok(None);
+        }
+        let Ok(declared) = u32::try_from(body.len()) else {
+            return Ok(None);
+        };
+        let compressed = self.algorithm.compress(body)?;
+        if COMPRESSED_HEADER_LEN + compressed.len() >= body.len() {
+            return Ok(None);
+        }
+        let mut framed = Vec::with_capacity(COMPRESSED_HEADER_LEN + compressed.len());
+        framed.push(COMPRESSED_FRAME_TAG);
+        framed.push(self.algorithm.tag());
+        framed.extend_from_slice(&declared.to_le_bytes());
+        framed.extend_from_slice(&compressed);
+        Ok(Some(framed))
+    }
+
+    /// A complete frame carrying `body`, compressed if large enough.
+    pub fn encode_frame(self, body: &[u8]) -> std::io::Result<BytesMut> {
+        let mut dst = BytesMut::new();
+        FrameCodec::new(usize::MAX).with_compression(Some(self)).encode(body, &mut dst)?;
+        Ok(dst)
+    }
+
+    /// A complete data frame carrying `data`, compressed if large enough.
+    /// Each chunk of a stream is compressed on its own.
+    pub fn encode_data_frame(self, data: &[u8]) -> std::io::Result<BytesMut> {
+        let mut dst = BytesMut::new();
+        FrameCodec::new(usize::MAX).with_compression(Some(self)).encode_data(data, &mut dst)?;
+        Ok(dst)
+    }
+}
+
+/// How a connection's frames are laid out. The client picks it with its
+/// first bytes; the node answers in kind.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Length-prefixed frames: `FrameCodec`.
+    #[default]
+    Binary,
+    /// One JSON message per line: `LineCodec`.
+    JsonLines,
+}
+
+impl Framing {
+    /// The bytes `detect` wants to see before it decides.
+    pub const DETECT_LEN: usize = HEADER_LEN;
+
+    /// The framing a connection that opened with `prefix` uses, or `None`
+    /// until `DETECT_LEN` bytes have arrived. A line starts with `{` or `"`,
+    /// but so can a length prefix; a prefix under 16 MiB ends in a zero
+    /// byte, though, which JSON text never holds.
+    pub fn detect(prefix: &[u8]) -> Option<Framing> {
+        if !matches!(prefix.first()?, b'{' | b'"') {
+            return Some(Framing::Binary);
+        }
+        if prefix.len() < Self::DETECT_LEN {
+            return None;
+        }
+        match prefix[..Self::DETECT_LEN].contains(&0) {
+            true => Some(Framing::Binary),
+            false => Some(Framing::JsonLines),
+        }
+    }
+
+    /// A complete message carrying `body`, ready to write. Binary frames
+    /// are compressed as `compression` says; lines never are.
+    pub fn encode(self, body: &[u8], compression: Option<FrameCompression>) -> std::io::Result<BytesMut> {
+        match (self, compression) {
+            (Framing::Binary, Some(compression)) => compression.encode_frame(body),
+            (Framing::Binary, None) => encode_frame(body),
+            (Framing::JsonLines, _) => encode_line(body),
+        }
+    }
+
+    /// A complete data frame carrying `data`, ready to write, compressed
+    /// as `encode` is.
+    pub fn encode_data(self, data: &[u8], compression: Option<FrameCompression>) -> std::io::Result<BytesMut> {
+        match (self, compression) {
+            (Framing::Binary, Some(compression)) => compression.encode_data_frame(data),
+            (Framing::Binary, None) => encode_data_frame(data),
+            (Framing::JsonLines, _) => encode_data_line(data),
+        }
+    }
+}
+
+/// A data frame as a line: `{"data":"<hex>"}`.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DataLine {
+    data: String,
+}
+
+/// Newline-delimited JSON, for `Framing::JsonLines`. JSON escapes the
+/// newlines inside strings, so a newline always ends a message. Blank lines
+/// are skipped and a trailing `\r` dropped. A line past the limit is
+/// reported as `Frame::Oversized` and skipped to its end without being
+/// buffered; `declared` is what had arrived of it.
+#[derive(Debug, Clone)]
+pub struct LineCodec {
+    max_line_len: usize,
+    /// Bytes at the front of the buffer already searched for a newline.
+    scanned: usize,
+    /// Set while the rest of an oversized line is skipped.
+    skipping: bool,
+}
+
+impl LineCodec {
+    /// Decodes lines up to `max_line_len` bytes, not counting the newline.
+    pub fn new(max_line_len: usize) -> Self {
+        Self {
+            max_line_len,
+            scanned: 0,
+            skipping: false,
+        }
+    }
+
+    pub fn max_line_len(&self) -> usize {
+        self.max_line_len
+    }
+}
+
+impl Decoder for LineCodec {
+    type Item = Frame;
+    type Error = FrameError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, FrameError> {
+        loop {
+            let newline = src[self.scanned..].iter().position(|b| *b == b'\n').map(|at| self.scanned + at);
+            let Some(end) = newline else {
+                if self.skipping {
+                    src.clear();
+                    self.scanned = 0;
+                    return Ok(None);
+                }
+                if src.len() > self.max_line_len {
+                    let declared = src.len();
+                    src.clear();
+                    self.scanned = 0;
+                    self.skipping = true;
+                    return Ok(Some(Frame::Oversized { declared }));
+                }
+                self.scanned = src.len();
+                return Ok(None);
+            };
+            let line = src.split_to(end + 1);
+            self.scanned = 0;
+            if std::mem::take(&mut self.skipping) {
+                continue;
+            }
+            if end > self.max_line_len {
+                return Ok(Some(Frame::Oversized { declared: end }));
+            }
+            if let Some(frame) = line_frame(line.freeze()) {
+                return Ok(Some(frame));
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, FrameError> {
+        if let Some(frame) = self.decode(src)? {
+            return Ok(Some(frame));
+        }
+        // The last line may go without its newline.
+        self.scanned = 0;
+        if std::mem::take(&mut self.skipping) {
+            src.clear();
+            return Ok(None);
+        }
+        Ok(line_frame(src.split().freeze()))
+    }
+}
+
+/// What one line carries, without its line ending; `None` for a blank line.
+fn line_frame(mut line: Bytes) -> Option<Frame> {
+    while line.last().is_some_and(|b| b.is_ascii_whitespace()) {
+        line.truncate(line.len() - 1);
+    }
+    let start = line.iter().position(|b| !b.is_ascii_whitespace())?;
+    line.advance(start);
+    let is_data = line.strip_prefix(b"{").is_some_and(|rest| rest.trim_ascii_start().starts_with(b"\"data\""));
+    // A malformed data line goes through as a message, to be answered with
+    // a parse error.
+    if let Some(data) = is_data.then(|| serde_json::from_slice::<DataLine>(&line).ok().and_then(|l| from_hex(&l.data))).flatten() {
+        return Some(Frame::Data(data.into()));
+    }
+    Some(Frame::Message(line))
+}
+
+impl Encoder<&[u8]> for LineCodec {
+    type Error = std::io::Error;
+
+    /// Writes a compact JSON body as a line. The receiver's limit is not
+    /// checked here.
+    fn encode(&mut self, body: &[u8], dst: &mut BytesMut) -> std::io::Result<()> {
+        if body.contains(&b'\n') {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "A line's body cannot hold a newline"));
+        }
+        dst.reserve(body.len() + 1);
+        dst.put_slice(body);
+        dst.put_u8(b'\n');
+        Ok(())
+    }
+}
+
+/// A complete line carrying `body`, ready to write.
+pub fn encode_line(body: &[u8]) -> std::io::Result<BytesMut> {
+    let mut dst = BytesMut::new();
+    LineCodec::new(usize::MAX).encode(body, &mut dst)?;
+    Ok(dst)
+}
+
+/// A complete data line carrying `data`, ready to write.
+pub fn encode_data_line(data: &[u8]) -> std::io::Result<BytesMut> {
+    let line = DataLine { data: to_hex(data) };
+    encode_line(&serde_json::to_vec(&line)?)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|at| u8::from_str_radix(text.get(at..at + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What a decoder made of a stream: its frames, then the error that
+    /// stopped it, if any.
+    type Decoded = (Vec<Frame>, Option<String>);
+
+    /// Feeds `stream` to `codec` in the chunks `splits` cuts it into, as
+    /// reads would deliver it, then ends the stream.
+    fn decode<D: Decoder<Item = Frame, Error = FrameError>>(mut codec: D, stream: &[u8], splits: &[usize]) -> Decoded {
+        let mut frames = Vec::new();
+        let mut src = BytesMut::new();
+        let mut from = 0;
+        for to in splits.iter().copied().chain(Some(stream.len())) {
+            src.extend_from_slice(&stream[from..to]);
+            from = to;
+            loop {
+                match codec.decode(&mut src) {
+                    Ok(Some(frame)) => frames.push(frame),
+                    Ok(None) => break,
+                    Err(e) => return (frames, Some(e.to_string())),
+                }
+            }
+        }
+        loop {
+            match codec.decode_eof(&mut src) {
+                Ok(Some(frame)) => frames.push(frame),
+                Ok(None) => return (frames, None),
+                Err(e) => return (frames, Some(e.to_string())),
+            }
+        }
+    }
+
+    /// `decoded` with each oversized line's length, which is however much
+    /// of it had arrived, checked against `limit` and cleared.
+    fn settled_lines((frames, error): Decoded, limit: usize) -> Decoded {
+        let frames = frames
+            .into_iter()
+            .map(|frame| match frame {
+                Frame::Oversized { declared } => {
+                    assert!(declared > limit, "{} is not oversized", declared);
+                    Frame::Oversized { declared: 0 }
+                }
+                frame => frame,
+            })
+            .collect();
+        (frames, error)
+    }
+
+    /// A small deterministic generator, so failures can be replayed.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    fn message(body: &str) -> Frame {
+        Frame::Message(Bytes::copy_from_slice(body.as_bytes()))
+    }
+
+    fn zstd() -> FrameCompression {
+        FrameCompression {
+            algorithm: Compression::Zstd,
+            min_size: 64,
+        }
+    }
+
+    /// Frames of every sort: a message, an empty one, data, an oversized
+    /// body to skip, and compressed ones, under a 1 KiB limit.
+    fn mixed_stream() -> (Vec<u8>, Vec<Frame>) {
+        let large = format!("\"{}\"", "a".repeat(900));
+        let mut stream = Vec::new();
+        stream.extend_from_slice(&encode_frame(b"{\"Ping\":null}").unwrap());
+        stream.extend_from_slice(&encode_frame(b"").unwrap());
+        stream.extend_from_slice(&encode_data_frame(&[0, 1, 2, 255]).unwrap());
+        stream.extend_from_slice(&encode_frame(&[b'x'; 2000]).unwrap());
+        stream.extend_from_slice(&zstd().encode_frame(large.as_bytes()).unwrap());
+        stream.extend_from_slice(&zstd().encode_data_frame(&[7; 500]).unwrap());
+        stream.extend_from_slice(&encode_frame(b"\"last\"").unwrap());
+        let frames = vec![
+            message("{\"Ping\":null}"),
+            message(""),
+            Frame::Data(Bytes::from_static(&[0, 1, 2, 255])),
+            Frame::Oversized { declared: 2000 },
+            message(&large),
+            Frame::Data(Bytes::from(vec![7; 500])),
+            message("\"last\""),
+        ];
+        (stream, frames)
+    }
+
+    #[test]
+    fn decodes_the_same_however_reads_split_the_stream() {
+        let (stream, frames) = mixed_stream();
+        // The compressed frames really are.
+        assert!(stream.len() < 4 * 7 + 13 + 5 + 2000 + 902 + 501 + 6);
+        let whole = decode(FrameCodec::new(1024), &stream, &[]);
+        assert_eq!(whole, (frames, None));
+        for at in 0..=stream.len() {
+            assert_eq!(decode(FrameCodec::new(1024), &stream, &[at]), whole, "split at {}", at);
+        }
+        let every_byte: Vec<usize> = (1..stream.len()).collect();
+        assert_eq!(decode(FrameCodec::new(1024), &stream, &every_byte), whole);
+    }
+
+    #[test]
+    fn a_stream_cut_short_says_where() {
+        let (stream, frames) = mixed_stream();
+        let (decoded, error) = decode(FrameCodec::new(1024), &stream[..2], &[]);
+        assert_eq!((decoded, error.as_deref()), (Vec::new(), Some("Frame length prefix cut short after 2 bytes")));
+        let (decoded, error) = decode(FrameCodec::new(1024), &stream[..10], &[]);
+        assert_eq!((decoded, error.as_deref()), (Vec::new(), Some("Frame of 13 bytes cut short after 6 bytes")));
+        // Partway through the body being skipped.
+        let oversized_at = 4 + 13 + 4 + 4 + 5;
+        let (decoded, error) = decode(FrameCodec::new(1024), &stream[..oversized_at + 1000], &[oversized_at + 10]);
+        assert_eq!(decoded, frames[..4]);
+        assert_eq!(error.as_deref(), Some("Stream ended 1004 bytes short of the end of an oversized frame"));
+    }
+
+    #[test]
+    fn refuses_what_is_too_large_to_skip() {
+        let mut stream = encode_frame(&[b'x'; 100]).unwrap().to_vec();
+        stream.extend_from_slice(&encode_frame(b"\"next\"").unwrap());
+        assert_eq!(decode(FrameCodec::new(64).with_discard_limit(100), &stream, &[]).0, [Frame::Oversized { declared: 100 }, message("\"next\"")]);
+        let (decoded, error) = decode(FrameCodec::new(64).with_discard_limit(99), &stream, &[]);
+        assert!(decoded.is_empty());
+        assert_eq!(error.as_deref(), Some("Frame of 100 bytes exceeds the limit of 64 bytes"));
+        // Whatever a length prefix claims, nothing is reserved for it.
+        let mut codec = FrameCodec::new(64);
+        let mut src = BytesMut::from(&u32::MAX.to_le_bytes()[..]);
+        assert!(matches!(codec.decode(&mut src), Err(FrameError::TooLarge { declared, .. }) if declared == u32::MAX as usize));
+        assert!(src.capacity() < 1024);
+    }
+
+    #[test]
+    fn rejects_malformed_compressed_bodies() {
+        let corrupt = |body: &[u8]| decode(FrameCodec::new(1024), &encode_frame(body).unwrap(), &[]).1.unwrap();
+        assert_eq!(corrupt(&[COMPRESSED_FRAME_TAG, 1, 0]), "Corrupt compressed frame: Header cut short after 3 bytes");
+        assert_eq!(corrupt(&[COMPRESSED_FRAME_TAG, 9, 1, 0, 0, 0, 0]), "Corrupt compressed frame: Unknown compression tag 9");
+        assert!(corrupt(&[COMPRESSED_FRAME_TAG, 1, 4, 0, 0, 0, 1, 2, 3, 4]).starts_with("Corrupt compressed frame: "));
+        // A body declared past the limit is skipped, not decompressed.
+        let mut header = vec![COMPRESSED_FRAME_TAG, 2];
+        header.extend_from_slice(&5000u32.to_le_bytes());
+        header.extend_from_slice(b"not gzip at all");
+        assert_eq!(decode(FrameCodec::new(1024), &encode_frame(&header).unwrap(), &[]), (vec![Frame::Oversized { declared: 5000 }], None));
+    }
+
+    /// A frame whose body is `inflated` spaces compressed with
+    /// `algorithm`, but whose header declares `declared`.
+    fn bomb(algorithm: Compression, inflated: usize, declared: u32) -> Vec<u8> {
+        let mut body = vec![COMPRESSED_FRAME_TAG, algorithm.tag()];
+        body.extend_from_slice(&declared.to_le_bytes());
+        body.extend_from_slice(&algorithm.compress(&vec![b' '; inflated]).unwrap());
+        encode_frame(&body).unwrap().to_vec()
+    }
+
+    #[test]
+    fn never_inflates_a_bomb_past_the_limit() {
+        const INFLATED: usize = 8 * 1024 * 1024;
+        for algorithm in Compression::ALL {
+            // Honest about its size: skipped whole, and the stream reads on.
+            let mut stream = bomb(algorithm, INFLATED, INFLATED as u32);
+            assert!(stream.len() < 64 * 1024, "{:?} made {} bytes", algorithm, stream.len());
+            stream.extend_from_slice(&encode_frame(b"\"next\"").unwrap());
+            assert_eq!(decode(FrameCodec::new(64 * 1024), &stream, &[]), (vec![Frame::Oversized { declared: INFLATED }, message("\"next\"")], None), "{:?}", algorithm);
+
+            // Lying about it: decompression stops one byte past what was
+            // declared, and the stream fails rather than hold the rest.
+            let (decoded, error) = decode(FrameCodec::new(64 * 1024), &bomb(algorithm, INFLATED, 1000), &[]);
+            assert!(decoded.is_empty());
+            assert_eq!(error.unwrap(), format!("Corrupt compressed frame: {} declared 1000 bytes but holds more", algorithm.name()));
+            let (_, error) = decode(FrameCodec::new(64 * 1024), &bomb(algorithm, 10, 1000), &[]);
+            assert_eq!(error.unwrap(), format!("Corrupt compressed frame: {} declared 1000 bytes but holds fewer", algorithm.name()));
+            // Exactly at the limit is fine.
+            assert_eq!(decode(FrameCodec::new(1000), &bomb(algorithm, 1000, 1000), &[]), (vec![message(&" ".repeat(1000))], None));
+        }
+    }
+
+    #[test]
+    fn compresses_large_compressible_bodies_only() {
+        let mut rng = XorShift(0xc0ff_ee00_1234_5678);
+        let noise: Vec<u8> = (0..64 * 1024).map(|_| rng.next() as u8).collect();
+        let rows: Vec<String> = (0..20_000).map(|i| format!("[{},\"row\",true]", i)).collect();
+        let json = format!("{{\"CoreResult\":{{\"rows\":[{}]}}}}", rows.join(","));
+        for algorithm in Compression::ALL {
+            let compression = FrameCompression { algorithm, min_size: 1024 };
+            let framed = compression.encode_frame(json.as_bytes()).unwrap();
+            assert!(framed.len() * 4 < json.len(), "{:?} made {} of {} bytes", algorithm, framed.len(), json.len());
+            assert_eq!(framed[HEADER_LEN], COMPRESSED_FRAME_TAG);
+            assert_eq!(decode(FrameCodec::new(json.len()), &framed, &[]), (vec![message(&json)], None));
+            let data = compression.encode_data_frame(json.as_bytes()).unwrap();
+            assert_eq!(decode(FrameCodec::new(json.len() + 1), &data, &[]), (vec![Frame::Data(Bytes::from(json.clone()))], None));
+
+            // Noise would not shrink, so it goes as it is.
+            assert_eq!(compression.encode_frame(&noise).unwrap(), encode_frame(&noise).unwrap());
+            // Nor does anything below the threshold, however compressible.
+            let below = vec![b' '; 1023];
+            assert_eq!(compression.encode_frame(&below).unwrap(), encode_frame(&below).unwrap());
+            assert_eq!(compression.encode_frame(&[b' '; 1024]).unwrap()[HEADER_LEN], COMPRESSED_FRAME_TAG);
+        }
+    }
+
+    #[test]
+    fn random_streams_never_panic_and_fail_the_same_way_however_split() {
+        let mut rng = XorShift(0x5eed_f00d_dead_beef);
+        for _ in 0..500 {
+            let mut stream: Vec<u8> = (0..rng.below(200)).map(|_| rng.next() as u8).collect();
+            // Mostly small length prefixes, which random bytes rarely make.
+            if rng.below(2) == 0 && stream.len() >= 4 {
+                let len = rng.below(stream.len()) as u32;
+                stream[..4].copy_from_slice(&len.to_le_bytes());
+            }
+            let limit = 16 + rng.below(64);
+            let whole = decode(FrameCodec::new(limit).with_discard_limit(limit * 2), &stream, &[]);
+            let mut splits: Vec<usize> = (0..rng.below(6)).map(|_| rng.below(stream.len() + 1)).collect();
+            splits.sort_unstable();
+            assert_eq!(decode(FrameCodec::new(limit).with_discard_limit(limit * 2), &stream, &splits), whole, "{:?} split at {:?}", stream, splits);
+            let lines = settled_lines(decode(LineCodec::new(limit), &stream, &splits), limit);
+            assert_eq!(lines, settled_lines(decode(LineCodec::new(limit), &stream, &[]), limit), "{:?} split at {:?}", stream, splits);
+        }
+    }
+
+    #[test]
+    fn lines_decode_the_same_however_reads_split_them() {
+        let data = encode_data_line(&[0, 10, 255]).unwrap();
+        let mut stream = b"{\"Ping\":null}\r\n\n   \n\"quoted\"\n".to_vec();
+        stream.extend_from_slice(&data);
+        stream.extend_from_slice(&[b'x'; 100]);
+        stream.extend_from_slice(b"\n{\"data\":\"zz\"}\n\"no newline\"");
+        let expected = vec![
+            message("{\"Ping\":null}"),
+            message("\"quoted\""),
+            Frame::Data(Bytes::from_static(&[0, 10, 255])),
+            Frame::Oversized { declared: 0 },
+            // Not hex, so left for the node to refuse.
+            message("{\"data\":\"zz\"}"),
+            message("\"no newline\""),
+        ];
+        for at in 0..=stream.len() {
+            assert_eq!(settled_lines(decode(LineCodec::new(64), &stream, &[at]), 64), (expected.clone(), None), "split at {}", at);
+        }
+    }
+
+    #[test]
+    fn encodes_what_decodes() {
+        for framing in [Framing::Binary, Framing::JsonLines] {
+            let mut stream = framing.encode(b"{\"Ping\":null}", None).unwrap().to_vec();
+            stream.extend_from_slice(&framing.encode_data(b"chunk\n", Some(zstd())).unwrap());
+            let decoded = match framing {
+                Framing::Binary => decode(FrameCodec::new(1024), &stream, &[]),
+                Framing::JsonLines => decode(LineCodec::new(1024), &stream, &[]),
+            };
+            assert_eq!(decoded, (vec![message("{\"Ping\":null}"), Frame::Data(Bytes::from_static(b"chunk\n"))], None), "{:?}", framing);
+        }
+        assert!(encode_line(b"two\nlines").is_err());
+        // Small or incompressible bodies go as they are.
+        assert_eq!(zstd().encode_frame(b"short").unwrap(), encode_frame(b"short").unwrap());
+    }
+
+    #[test]
+    fn tells_lines_from_length_prefixes() {
+        assert_eq!(Framing::detect(b""), None);
+        assert_eq!(Framing::detect(b"{\"Pi"), Some(Framing::JsonLines));
+        assert_eq!(Framing::detect(b"\"Pin"), Some(Framing::JsonLines));
+        assert_eq!(Framing::detect(b"{\"P"), None);
+        assert_eq!(Framing::detect(&encode_frame(b"{}").unwrap()), Some(Framing::Binary));
+        // A 123-byte frame's prefix starts with `{`.
+        assert_eq!(Framing::detect(&encode_frame(&[b' '; 123]).unwrap()), Some(Framing::Binary));
+    }
+}
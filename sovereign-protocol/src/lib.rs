@@ -1,52 +1,2235 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
 
-/// The Windows Named Pipe address for IPC.
+pub mod endpoint;
+pub mod framing;
+pub mod pin;
+
+pub use endpoint::{default_data_dir, EndpointDiscovery, IpcEndpoint};
+pub use framing::{Compression, Frame, FrameCodec, FrameCompression, FrameError, Framing, LineCodec};
+pub use pin::CertPin;
+
+/// Base name of the Windows Named Pipe for IPC. The per-user pipe is
+/// derived from it by [`IpcEndpoint::default_for_platform`].
 pub const PIPE_NAME: &str = r"\\.\pipe\SovereignNode";
 
+/// Wire protocol revision. Exchanged in the Hello handshake.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The first revision that accepts `RequestEnvelope`s.
+pub const ENVELOPE_PROTOCOL_VERSION: u32 = 2;
+
+/// Default cap on a single request frame body. The effective limit for a
+/// connection is advertised in `HelloAck`.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// First byte of a raw data frame, which carries streamed bytes instead of a
+/// JSON message. JSON bodies never start with it. An empty data frame (the tag
+/// alone) ends a stream.
+pub const DATA_FRAME_TAG: u8 = 0;
+
+/// First byte of a compressed frame, whose body decompresses to a message
+/// or a data frame. Only sent to a peer that agreed to it in the Hello
+/// handshake; see `FrameCompression`.
+pub const COMPRESSED_FRAME_TAG: u8 = 1;
+
+/// Oversized bodies up to this size are skipped so the connection survives;
+/// anything larger closes the connection after the error frame.
+pub const MAX_FRAME_DISCARD: usize = 1024 * 1024;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
     Ping,
     GetStatus,
+    /// Counters from the node's subsystems; answered with `Response::Metrics`.
+    GetMetrics,
+    /// How the node was built; answered with `Response::Version`.
+    GetVersion,
+    /// Handshake: opts the connection into heartbeats and returns the server's timing parameters.
+    Hello {
+        client_name: String,
+        protocol_version: u32,
+        /// An access token from the node's config, granting its
+        /// permissions. Without one the connection gets those configured
+        /// for its user, or the node's default.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+        /// Picks up the session of an earlier connection, if the node
+        /// still holds it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resume: Option<SessionResume>,
+        /// Compressions the client can read and write, by `Compression`
+        /// name, most preferred first. Names the node does not know are
+        /// ignored.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        compressions_supported: Vec<String>,
+    },
+    /// Answer to a server `Response::Heartbeat`. The server does not reply to it.
+    HeartbeatAck {
+        seq: u64,
+    },
     /// Execute a Datalog query (Cognitive Layer)
     QueryCore {
         query: String,
         params: serde_json::Value,
+        /// Overrides the node's query timeout.
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+        /// Run read-only; a query that would write fails instead.
+        #[serde(default)]
+        readonly: bool,
+        /// Lowers the node's cap on result rows; it cannot raise it.
+        #[serde(default)]
+        limit: Option<u64>,
+    },
+    /// `QueryCore`, with the rows sent back as data frames of JSON Lines,
+    /// one array per row, followed by `Response::CoreStreamed`. Listed by
+    /// `CoreQueries` until the last row is sent, so `Cancel` from another
+    /// connection also stops the rows.
+    QueryCoreStreamed {
+        query: String,
+        params: serde_json::Value,
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+        #[serde(default)]
+        readonly: bool,
+        /// Lowers the node's ceiling on streamed rows; it cannot raise it.
+        /// Streamed rows are not held to the cap on result size.
+        #[serde(default)]
+        limit: Option<u64>,
+    },
+    /// Parse and plan a query without running it; answered with
+    /// `Response::CoreExplained`. A syntax error is answered with a
+    /// `Response::CoreFailed` giving its line and column.
+    CoreExplain {
+        query: String,
+        #[serde(default)]
+        params: serde_json::Value,
+    },
+    /// Store a query under a name, replacing any of that name; answered with
+    /// `Response::CoreNamedQuery`. The query is compiled first, and every
+    /// `$name` it uses must be declared in `params`. A `readonly` query
+    /// always runs read-only.
+    CoreRegisterQuery {
+        name: String,
+        query: String,
+        #[serde(default)]
+        params: Vec<CoreQueryParam>,
+        #[serde(default)]
+        readonly: bool,
+    },
+    /// Run a registered query with `params` checked against its declared
+    /// parameters; answered with `Response::CoreResult`. Cancellable like
+    /// `QueryCore`.
+    CoreRunNamed {
+        name: String,
+        #[serde(default)]
+        params: serde_json::Value,
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+        /// Run read-only even if the query was not registered so, which is
+        /// all a connection without `query_core_write` may ask for.
+        #[serde(default)]
+        readonly: bool,
+    },
+    CoreListNamed,
+    CoreRemoveNamed {
+        name: String,
+    },
+    /// Privileged: lets `principal` read, or read and write, the stored
+    /// relations `relation_pattern` names, replacing what it had on that
+    /// pattern; answered with `Response::CoreGranted`. A trailing `*`
+    /// matches any rest of a name. Once a principal has a grant, its queries
+    /// may only touch relations its grants cover.
+    CoreGrant {
+        principal: String,
+        relation_pattern: String,
+        rights: CoreGrantRights,
+    },
+    /// Privileged: removes the grant on exactly `relation_pattern`; answered
+    /// with `Response::CoreRevoked`.
+    CoreRevoke {
+        principal: String,
+        relation_pattern: String,
+    },
+    /// Privileged: grants, only `principal`'s if given; answered with
+    /// `Response::CoreGrants`.
+    CoreListGrants {
+        #[serde(default)]
+        principal: Option<String>,
+    },
+    /// In-flight `QueryCore` and `QueryCoreStreamed` requests, with the ids
+    /// `Cancel` takes.
+    CoreQueries,
+    /// Stored relations in the core; answered with `Response::CoreRelations`.
+    CoreListRelations,
+    /// Columns of one stored relation; answered with `Response::CoreSchema`.
+    CoreDescribe {
+        name: String,
+    },
+    /// Stream every row of a stored relation back as data frames, followed
+    /// by `Response::CoreExported`.
+    CoreExport {
+        name: String,
+        format: CoreDataFormat,
+    },
+    /// Load rows into a stored relation. The data follows as data frames,
+    /// ended by an empty one, and is answered with `Response::CoreImported`.
+    /// No other request may be sent until the data has been ended.
+    CoreImport {
+        name: String,
+        format: CoreDataFormat,
+        mode: CoreImportMode,
+    },
+    /// Write rows to a stored relation, each an object keyed by column name
+    /// or an array of every column's value, replacing rows with the same
+    /// key; answered with `Response::CoreAsserted`.
+    CoreAssert {
+        name: String,
+        rows: Vec<serde_json::Value>,
+    },
+    /// Delete rows of a stored relation by key, each an array or object of
+    /// the key columns, or the bare value of a single key column; answered
+    /// with `Response::CoreRetracted`.
+    CoreRetract {
+        name: String,
+        keys: Vec<serde_json::Value>,
+    },
+    /// Find the `k` rows of a stored relation whose vector-indexed `column`
+    /// is nearest to `vector`, answered with `Response::CoreResult` with a
+    /// leading `distance` column. `filters` is an object of column values
+    /// the rows must equal. The vector must fit in one frame.
+    CoreKnn {
+        name: String,
+        column: String,
+        vector: Vec<f64>,
+        k: u32,
+        #[serde(default)]
+        filters: serde_json::Value,
+    },
+    /// Push committed changes to a stored relation to this connection as
+    /// `Response::CoreChanged`, until `CoreUnwatch` or the connection closes.
+    /// `filter` is an expression over the relation's columns, e.g.
+    /// `age > 30`; only matching rows are pushed. Answered with
+    /// `Response::CoreWatching`.
+    CoreWatch {
+        relation: String,
+        #[serde(default)]
+        filter: Option<String>,
+    },
+    CoreUnwatch {
+        watch_id: u64,
+    },
+    /// Privileged: the newest `limit` audited core queries, oldest first;
+    /// answered with `Response::CoreAudit`. Fails unless the node audits
+    /// queries.
+    CoreAuditTail {
+        limit: u32,
+    },
+    /// Privileged: the newest `limit` audited IPC requests, oldest first;
+    /// answered with `Response::IpcAudit`. Fails unless the node audits
+    /// IPC requests.
+    AuditTail {
+        limit: u32,
+    },
+    /// Privileged: the newest `limit` requests that took longer than the
+    /// node's slow-request threshold, oldest first; answered with
+    /// `Response::SlowRequests`.
+    SlowRequests {
+        limit: u32,
+    },
+    /// Privileged: each open connection's traffic and requests; answered
+    /// with `Response::Connections`.
+    ConnectionStats,
+    /// Privileged: checks the node's config, data directory, keys, core,
+    /// WASM engine, module store and Electrum servers; answered with
+    /// `Response::SelfCheck`.
+    SelfCheck,
+    /// What first-run setup asks for and what the node would use now;
+    /// answered with `Response::SetupState`.
+    SetupState,
+    /// Sets the node up: writes its config file and swarm key from
+    /// `settings`. A node waiting to be set up then starts in full. One
+    /// already set up takes it only with `force` and `node_admin`, and uses
+    /// it from its next start. Answered with `Response::SetupApplied`.
+    SetupApply {
+        settings: SetupSettings,
+        #[serde(default)]
+        force: bool,
+    },
+    /// Privileged: packs the core's relations and named queries, the
+    /// registered modules, scheduled jobs, persisted peers and config file
+    /// into one archive, for moving the node to another machine. Keys, the
+    /// install salt and license records are left out. With `path` the node
+    /// writes the archive there; without, it is streamed back as data
+    /// frames. Answered with `Response::SnapshotExported`.
+    ExportSnapshot {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+    },
+    /// Privileged: restores an `ExportSnapshot` archive, read from `path` on
+    /// the node or, without one, sent as data frames ended by an empty one.
+    /// Other requests are turned away while it runs, and a failure leaves
+    /// the node as it was. Answered with `Response::SnapshotImported`.
+    ImportSnapshot {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+    },
+    /// Privileged, for resilience tests: makes `target` fail as `kind` says
+    /// for `duration_ms`, replacing the fault it had; 0 ends it. Only nodes
+    /// built with `fault-injection` know it. Answered with
+    /// `Response::FaultInjected`.
+    #[cfg(feature = "fault-injection")]
+    InjectFault {
+        target: FaultTarget,
+        kind: FaultKind,
+        duration_ms: u64,
+    },
+    /// Open a core transaction bound to this connection.
+    CoreBegin,
+    /// Execute a Datalog query inside an open transaction.
+    CoreExec {
+        session_id: u64,
+        query: String,
+        params: serde_json::Value,
+    },
+    CoreCommit {
+        session_id: u64,
+    },
+    CoreRollback {
+        session_id: u64,
     },
     /// Execute a WASM module (Compute Layer)
     RunWasm {
+        /// A file in the node's module store or `wasm.run_dirs`, or the bare
+        /// name of a registered module.
         path: String,
         input: String,
+        /// WASI argv passed to the module.
+        #[serde(default)]
+        args: Vec<String>,
+        /// WASI environment. Path-based runs have no manifest allowing any
+        /// names, so it is dropped; use `RunWasmModule` to pass variables.
+        #[serde(default)]
+        env: Vec<(String, String)>,
+        /// Overrides the runtime's default fuel budget.
+        #[serde(default)]
+        fuel_limit: Option<u64>,
+        /// Publisher signature over the module bytes; required when the node enforces signatures.
+        #[serde(default)]
+        signature: Option<WasmSignature>,
     },
+    /// Like `RunWasm`, but the input follows as data frames, ended by an empty
+    /// one, and is read by the module through `sovereign.input_read`. What the
+    /// module writes with `sovereign.output_write` comes back as data frames
+    /// before the `WasmResult`. No other request may be sent until the input
+    /// has been ended.
+    RunWasmStreamed {
+        path: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        env: Vec<(String, String)>,
+        #[serde(default)]
+        fuel_limit: Option<u64>,
+        #[serde(default)]
+        signature: Option<WasmSignature>,
+    },
+    /// Register the module at `path` (on the node's filesystem) under `name`,
+    /// replacing any module already registered under it.
+    WasmUpload {
+        name: String,
+        path: String,
+        #[serde(default)]
+        manifest: WasmManifest,
+        /// Publisher signature over the module bytes and manifest.
+        #[serde(default)]
+        signature: Option<WasmSignature>,
+        /// Reload the module whenever the file at `path` changes.
+        #[serde(default)]
+        watch: bool,
+    },
+    WasmList,
+    WasmInfo {
+        name: String,
+    },
+    WasmRemove {
+        name: String,
+    },
+    /// Running WASM executions, with the ids `Cancel` takes.
+    WasmExecutions,
+    /// Stop a running WASM execution or core query; it finishes with an error.
+    Cancel {
+        execution_id: u64,
+    },
+    /// Execute a registered module with the capabilities granted at upload.
+    RunWasmModule {
+        name: String,
+        input: String,
+        #[serde(default)]
+        args: Vec<String>,
+        /// Only names in the module's `allowed_env` are passed on.
+        #[serde(default)]
+        env: Vec<(String, String)>,
+        #[serde(default)]
+        fuel_limit: Option<u64>,
+    },
+    /// Run registered modules in sequence, each stage's output (its `run`
+    /// result, or stdout) being the next stage's input.
+    RunWasmPipeline {
+        stages: Vec<WasmPipelineStage>,
+        input: String,
+        /// Wall-clock limit for the whole pipeline.
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+        /// Fuel shared by all stages.
+        #[serde(default)]
+        total_fuel: Option<u64>,
+    },
+    /// Run a registered module on a schedule, replacing any job of the same name.
+    WasmScheduleJob {
+        name: String,
+        job: WasmJobSpec,
+    },
+    WasmListJobs,
+    WasmDeleteJob {
+        name: String,
+    },
+    /// Recent runs of a job, oldest first.
+    WasmJobHistory {
+        name: String,
+    },
+    /// Privileged: allow modules with this SHA-256 to run.
+    WasmAllowlistAdd {
+        sha256: String,
+    },
+    /// Privileged: stop allowing modules with this SHA-256.
+    WasmAllowlistRemove {
+        sha256: String,
+    },
+    WasmAllowlistList,
     /// Mesh: Connect to a specific peer
     MeshDial {
         addr: String,
+        /// Remember the peer and dial it again whenever the node starts.
+        #[serde(default)]
+        persist: bool,
+    },
+    /// Forget a peer dialed with `persist`. Its connection stays open.
+    MeshUnpin {
+        addr: String,
     },
     /// Mesh: List active connections
     MeshPeers,
+    /// The other nodes heard from over presence beacons, answered with
+    /// `Response::MeshPresence`. Fails while presence is off.
+    MeshPresence,
+    /// Privileged: the replication outbox, answered with
+    /// `Response::ReplicationOutbox`. Lists up to `limit` entries after
+    /// `after_seq`, only pending ones unless `include_published`. Fails
+    /// while replication is off.
+    ReplicationOutbox {
+        #[serde(default)]
+        after_seq: Option<u64>,
+        #[serde(default)]
+        include_published: bool,
+        #[serde(default)]
+        limit: Option<u32>,
+    },
+    /// Privileged: makes outbox entries pending again with no tries, so
+    /// published ones are gossiped once more and failing ones are retried
+    /// at once.
+    ReplicationRequeue {
+        seqs: Vec<u64>,
+    },
     /// Finance: Check for a valid license on-chain
     VerifyLicense {
         tx_id: String,
         developer_addr: String,
         required_sats: u64,
     },
+    /// Finance: Return the last known license state without an on-chain check
+    GetLicenseInfo,
+    /// Answered like `GetLicenseInfo`, then pushes
+    /// `Response::LicenseStatusChanged` to this connection whenever the
+    /// license turns active or inactive, until the connection closes.
+    WatchLicense,
+    /// Adds `topics` to what this connection is pushed as `Response::Event`,
+    /// until it unsubscribes or closes. Answered with `Response::Subscribed`.
+    Subscribe {
+        topics: Vec<EventTopic>,
+    },
+    /// Answered with `Response::Subscribed`, naming the topics left.
+    Unsubscribe {
+        topics: Vec<EventTopic>,
+    },
+}
+impl Request {
+    /// The variant's name, e.g. `"QueryCore"`, for logs and per-kind
+    /// counters.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::Ping => "Ping",
+            Request::GetStatus => "GetStatus",
+            Request::GetMetrics => "GetMetrics",
+            Request::GetVersion => "GetVersion",
+            Request::Hello { .. } => "Hello",
+            Request::HeartbeatAck { .. } => "HeartbeatAck",
+            Request::QueryCore { .. } => "QueryCore",
+            Request::QueryCoreStreamed { .. } => "QueryCoreStreamed",
+            Request::CoreExplain { .. } => "CoreExplain",
+            Request::CoreRegisterQuery { .. } => "CoreRegisterQuery",
+            Request::CoreRunNamed { .. } => "CoreRunNamed",
+            Request::CoreListNamed => "CoreListNamed",
+            Request::CoreRemoveNamed { .. } => "CoreRemoveNamed",
+            Request::CoreGrant { .. } => "CoreGrant",
+            Request::CoreRevoke { .. } => "CoreRevoke",
+            Request::CoreListGrants { .. } => "CoreListGrants",
+            Request::CoreQueries => "CoreQueries",
+            Request::CoreListRelations => "CoreListRelations",
+            Request::CoreDescribe { .. } => "CoreDescribe",
+            Request::CoreExport { .. } => "CoreExport",
+            Request::CoreImport { .. } => "CoreImport",
+            Request::CoreAssert { .. } => "CoreAssert",
+            Request::CoreRetract { .. } => "CoreRetract",
+            Request::CoreKnn { .. } => "CoreKnn",
+            Request::CoreWatch { .. } => "CoreWatch",
+            Request::CoreUnwatch { .. } => "CoreUnwatch",
+            Request::CoreAuditTail { .. } => "CoreAuditTail",
+            Request::AuditTail { .. } => "AuditTail",
+            Request::SelfCheck => "SelfCheck",
+            Request::SetupState => "SetupState",
+            Request::SetupApply { .. } => "SetupApply",
+            Request::ExportSnapshot { .. } => "ExportSnapshot",
+            Request::ImportSnapshot { .. } => "ImportSnapshot",
+            #[cfg(feature = "fault-injection")]
+            Request::InjectFault { .. } => "InjectFault",
+            Request::CoreBegin => "CoreBegin",
+            Request::CoreExec { .. } => "CoreExec",
+            Request::CoreCommit { .. } => "CoreCommit",
+            Request::CoreRollback { .. } => "CoreRollback",
+            Request::RunWasm { .. } => "RunWasm",
+            Request::RunWasmStreamed { .. } => "RunWasmStreamed",
+            Request::WasmUpload { .. } => "WasmUpload",
+            Request::WasmList => "WasmList",
+            Request::WasmInfo { .. } => "WasmInfo",
+            Request::WasmRemove { .. } => "WasmRemove",
+            Request::WasmExecutions => "WasmExecutions",
+            Request::Cancel { .. } => "Cancel",
+            Request::RunWasmModule { .. } => "RunWasmModule",
+            Request::RunWasmPipeline { .. } => "RunWasmPipeline",
+            Request::WasmScheduleJob { .. } => "WasmScheduleJob",
+            Request::WasmListJobs => "WasmListJobs",
+            Request::WasmDeleteJob { .. } => "WasmDeleteJob",
+            Request::WasmJobHistory { .. } => "WasmJobHistory",
+            Request::WasmAllowlistAdd { .. } => "WasmAllowlistAdd",
+            Request::WasmAllowlistRemove { .. } => "WasmAllowlistRemove",
+            Request::WasmAllowlistList => "WasmAllowlistList",
+            Request::MeshDial { .. } => "MeshDial",
+            Request::MeshUnpin { .. } => "MeshUnpin",
+            Request::MeshPeers => "MeshPeers",
+            Request::MeshPresence => "MeshPresence",
+            Request::ReplicationOutbox { .. } => "ReplicationOutbox",
+            Request::ReplicationRequeue { .. } => "ReplicationRequeue",
+            Request::VerifyLicense { .. } => "VerifyLicense",
+            Request::GetLicenseInfo => "GetLicenseInfo",
+            Request::WatchLicense => "WatchLicense",
+            Request::Subscribe { .. } => "Subscribe",
+            Request::Unsubscribe { .. } => "Unsubscribe",
+            Request::SlowRequests { .. } => "SlowRequests",
+            Request::ConnectionStats => "ConnectionStats",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Response {
     Pong,
     Status(NodeStatus),
+    Metrics(MetricsSnapshot),
+    Version(BuildInfo),
+    HelloAck {
+        protocol_version: u32,
+        /// How often the server probes an idle connection.
+        heartbeat_interval_ms: u64,
+        /// Silence after which either side may consider the connection dead.
+        idle_timeout_ms: u64,
+        /// Largest request body this connection accepts.
+        #[serde(default = "default_max_frame_size")]
+        max_frame_size: u64,
+        /// How the node was built; absent from older nodes.
+        #[serde(default)]
+        build: Option<BuildInfo>,
+        /// The connection's session, for resuming it after a reconnect;
+        /// absent from older nodes and those that keep no sessions.
+        #[serde(default)]
+        session: Option<SessionGrant>,
+        /// How both ends compress large frames from now on; absent when the
+        /// client offered nothing the node allows.
+        #[serde(default)]
+        compression: Option<FrameCompression>,
+    },
+    /// Unsolicited liveness probe, only sent after a successful Hello.
+    Heartbeat {
+        seq: u64,
+    },
+    /// Unsolicited, after a Hello: the client has sent nothing for a while
+    /// and the node closes the connection in `closes_in_ms` unless it sends
+    /// a frame.
+    IdleWarning {
+        closes_in_ms: u64,
+    },
+    /// Sent, unasked, to a client that connects while the node already
+    /// has `max_connections` open. The node closes the connection after it.
+    Busy {
+        max_connections: u64,
+    },
+    /// The connection lacks `permission`, which a `kind` request needs.
+    PermissionDenied {
+        kind: String,
+        permission: Permission,
+    },
+    /// The node is waiting to be set up, and serves no `kind` requests
+    /// until `SetupApply` succeeds.
+    SetupRequired {
+        kind: String,
+    },
     CoreResult(serde_json::Value),
-    WasmOutput(String),
+    CoreQueries(Vec<CoreQueryInfo>),
+    CoreRelations(Vec<CoreRelation>),
+    CoreSchema(CoreRelationSchema),
+    CoreExported {
+        rows: u64,
+    },
+    /// The end of a `QueryCoreStreamed` result.
+    CoreStreamed {
+        headers: Vec<String>,
+        rows: u64,
+        took_ms: f64,
+    },
+    CoreExplained(CoreExplainReport),
+    CoreNamedQuery(CoreNamedQuery),
+    CoreNamedQueries(Vec<CoreNamedQuery>),
+    CoreNamedRemoved {
+        name: String,
+        removed: bool,
+    },
+    CoreGranted(CoreGrantInfo),
+    /// `revoked` is false if the principal had no grant on that pattern.
+    CoreRevoked {
+        principal: String,
+        relation_pattern: String,
+        revoked: bool,
+    },
+    CoreGrants(Vec<CoreGrantInfo>),
+    CoreImported(CoreImportSummary),
+    CoreAsserted {
+        rows: u64,
+    },
+    /// Rows that existed and were deleted.
+    CoreRetracted {
+        rows: u64,
+    },
+    CoreWatching {
+        watch_id: u64,
+    },
+    /// `found` is false if no such watch was open on this connection.
+    CoreUnwatched {
+        watch_id: u64,
+        found: bool,
+    },
+    /// The topics this connection is now subscribed to.
+    Subscribed {
+        topics: Vec<EventTopic>,
+    },
+    /// Unsolicited: an event on a subscribed topic. `seq` counts the
+    /// connection's events from 1, so a gap never appears without a
+    /// `PushDropped` before it.
+    Event {
+        seq: u64,
+        event: NodeEvent,
+    },
+    /// Unsolicited: the connection fell behind and `count` events were
+    /// dropped, oldest first, before the next `Event`.
+    PushDropped {
+        count: u64,
+    },
+    /// Unsolicited: a watched relation changed.
+    CoreChanged(CoreChange),
+    CoreAudit(Vec<CoreAuditEntry>),
+    /// Audited IPC requests, and how many entries were dropped because
+    /// the writer fell behind.
+    IpcAudit {
+        entries: Vec<IpcAuditEntry>,
+        dropped: u64,
+    },
+    SlowRequests {
+        requests: Vec<SlowRequest>,
+        /// The node's threshold; `None` if it records no slow requests.
+        threshold_ms: Option<u64>,
+    },
+    Connections(Vec<IpcConnectionInfo>),
+    SelfCheck(SelfCheckReport),
+    SetupState(SetupState),
+    SetupApplied {
+        /// The node goes on with its old settings until it restarts; false
+        /// when setup started it.
+        restart_required: bool,
+    },
+    SnapshotExported(SnapshotSummary),
+    #[cfg(feature = "fault-injection")]
+    FaultInjected {
+        target: FaultTarget,
+        /// False once the fault has ended or, for one-off kinds, struck.
+        active: bool,
+    },
+    SnapshotImported {
+        summary: SnapshotSummary,
+        /// Where the snapshot's config file was written for review; the
+        /// node's own config is left alone.
+        config_written: Option<String>,
+    },
+    CoreSession {
+        session_id: u64,
+    },
+    CoreSessionClosed {
+        session_id: u64,
+        committed: bool,
+    },
+    /// Outcome of a `RunWasm`. A module that printed an error, exited nonzero
+    /// and trapped are all distinguishable here.
+    WasmResult {
+        stdout: String,
+        stderr: String,
+        exit_code: Option<i32>,
+        fuel_used: Option<u64>,
+        duration_ms: u64,
+        trapped: bool,
+        /// Kept alongside `trap` for clients that predate it.
+        trap_message: Option<String>,
+        #[serde(default)]
+        peak_memory_bytes: u64,
+        /// Memory-ABI output, lossily decoded as UTF-8. Older nodes appended it to `stdout`.
+        #[serde(default)]
+        output: Option<String>,
+        #[serde(default)]
+        trap: Option<WasmTrap>,
+        /// The node ran the module in deterministic mode.
+        #[serde(default)]
+        deterministic: bool,
+        /// Messages the module wrote through the `sovereign.log` import.
+        #[serde(default)]
+        logs: Vec<WasmLogLine>,
+        /// Log calls refused once the per-execution limit was reached.
+        #[serde(default)]
+        logs_dropped: u32,
+    },
+    WasmModule(WasmModuleInfo),
+    WasmModules(Vec<WasmModuleInfo>),
+    WasmRemoved {
+        name: String,
+        removed: bool,
+    },
+    WasmExecutions(Vec<WasmExecutionInfo>),
+    /// `found` is false if the execution had already finished.
+    Cancelled {
+        execution_id: u64,
+        found: bool,
+    },
+    WasmPipelineResult {
+        /// A `WasmResult` for each stage that ran.
+        stages: Vec<Response>,
+        /// The last stage's output, when every stage succeeded.
+        output: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        failure: Option<WasmPipelineFailure>,
+    },
+    WasmJob(WasmJobInfo),
+    WasmJobs(Vec<WasmJobInfo>),
+    WasmJobDeleted {
+        name: String,
+        deleted: bool,
+    },
+    WasmJobHistory {
+        name: String,
+        runs: Vec<WasmJobRun>,
+    },
+    /// The allow-list after a `WasmAllowlist*` request.
+    WasmAllowlist {
+        /// False in allow-all development mode, where unlisted modules still run.
+        enforced: bool,
+        hashes: Vec<String>,
+        /// Whether an add or remove changed the list.
+        #[serde(default)]
+        changed: bool,
+    },
     MeshGeneric(String),
-    LicenseResult { valid: bool, details: String },
+    MeshPresence {
+        nodes: Vec<PeerPresence>,
+        /// How long a node goes unheard before it is stale.
+        stale_after_ms: u64,
+    },
+    ReplicationOutbox(ReplicationOutboxReport),
+    ReplicationRequeued {
+        /// How many of the asked-for entries were in the outbox.
+        requeued: u64,
+    },
+    /// The extra fields are optional so clients built against the old
+    /// `{ valid, details }` shape keep deserializing this variant.
+    LicenseResult {
+        valid: bool,
+        details: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        report: Option<LicenseReport>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        terms: Option<LicenseTerms>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        binding: Option<LicenseBinding>,
+    },
+    /// The client announced a frame longer than `max`. The body was skipped,
+    /// or the connection is closed right after this if it was too large to skip.
+    FrameTooLarge {
+        declared: u64,
+        max: u64,
+    },
+    /// Pushed after `Request::WatchLicense` when the license turns active
+    /// or inactive, by a client's check or the node's periodic one.
+    LicenseStatusChanged {
+        active: bool,
+        report: Option<LicenseReport>,
+    },
+    /// A core request failed; `code` says how.
+    CoreFailed(CoreFailure),
+    /// The request was abandoned after `timeout_ms`, still waiting on
+    /// `subsystem` (`node`, `core`, `wasm`, `mesh` or `finance`). The
+    /// connection stays usable.
+    TimedOut {
+        subsystem: String,
+        timeout_ms: u64,
+    },
+    /// `subsystem` is not up yet or has failed, so the request was not
+    /// tried. Other subsystems are unaffected; retrying later may succeed.
+    Unavailable {
+        subsystem: String,
+        reason: String,
+    },
+    /// The client has sent too many requests, or too many of `kind`; the
+    /// request was not tried. Retrying after `retry_after_ms` may succeed.
+    RateLimited {
+        kind: String,
+        retry_after_ms: u64,
+    },
+    /// The node's `subsystem` workers (`finance` or `compute`) were all busy
+    /// with a full queue behind them, so the request was not tried.
+    /// Retrying after `retry_after_ms` may succeed.
+    Overloaded {
+        subsystem: String,
+        retry_after_ms: u64,
+    },
+    /// `RunWasm` would not load the module at `path`.
+    WasmPathRejected {
+        path: String,
+        reason: WasmPathRejection,
+    },
     Error(String),
 }
 
+/// A request tagged with an id the client picks. The node may handle
+/// enveloped requests concurrently and answer them out of order, each in a
+/// `ResponseEnvelope` with the same id. Bare requests are still answered in
+/// order, bare.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RequestEnvelope {
+    pub id: u64,
+    pub request: Request,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResponseEnvelope {
+    pub id: u64,
+    pub response: Response,
+}
+
+/// The id of the envelope `frame` holds, or `None` for a bare request or
+/// response.
+pub fn envelope_id(frame: &[u8]) -> Option<u64> {
+    #[derive(Deserialize)]
+    struct Probe {
+        id: u64,
+    }
+    serde_json::from_slice::<Probe>(frame).ok().map(|probe| probe.id)
+}
+
+impl Response {
+    /// Sent unasked, after a watch request, rather than as the answer to
+    /// the request before it.
+    pub fn is_push(&self) -> bool {
+        matches!(
+            self,
+            Response::CoreChanged(_)
+                | Response::LicenseStatusChanged { .. }
+                | Response::IdleWarning { .. }
+                | Response::Event { .. }
+                | Response::PushDropped { .. }
+        )
+    }
+}
+
+fn default_max_frame_size() -> u64 {
+    DEFAULT_MAX_FRAME_SIZE as u64
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct NodeStatus {
     pub uptime_ms: u64,
     pub mesh_peer_id: String,
+    /// Peers with at least one open connection.
     pub mesh_connections: u32,
+    /// Where the mesh accepts peer connections.
+    #[serde(default)]
+    pub mesh_listen_addrs: Vec<String>,
     pub license_active: bool,
     pub system_health: String,
+    /// Short per-subsystem summaries behind `system_health`.
+    #[serde(default)]
+    pub health_details: Vec<String>,
+    /// Whether license checks can reach the blockchain.
+    #[serde(default)]
+    pub finance: FinanceState,
+    /// How far the mesh is through its startup dials.
+    #[serde(default)]
+    pub mesh_phase: MeshPhase,
+    /// Whether licenses can be bound to this install at all.
+    #[serde(default)]
+    pub machine_binding: MachineBinding,
+    #[serde(default)]
+    pub ipc_connections: IpcConnectionStats,
+    /// What `system_health` summarises, with the reasons behind it.
+    #[serde(default)]
+    pub health: HealthLevel,
+    /// The figures `health` was judged on.
+    #[serde(default)]
+    pub resources: NodeResources,
+    /// How the node was built.
+    #[serde(default)]
+    pub version: BuildInfo,
+    #[serde(default)]
+    pub mode: NodeMode,
+}
+
+/// Whether the node is serving, or waiting for first-run setup.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeMode {
+    #[default]
+    Running,
+    /// Started with an empty data directory. Only `Ping`, `GetStatus`,
+    /// `GetVersion`, `SetupState` and `SetupApply` are served until it is
+    /// set up.
+    Setup,
+}
+
+/// Sent in `Hello` to resume a session after reconnecting.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SessionResume {
+    pub session_id: u64,
+    pub token: String,
+    /// The `seq` of the last `Response::Event` the client received. Events
+    /// after it that the node still holds are sent again.
+    pub last_seq: u64,
+}
+
+/// A connection's session, from `HelloAck`. Its subscriptions, core
+/// watches and license watch outlive a disconnect by `grace_ms`; its core
+/// transactions do not.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SessionGrant {
+    pub session_id: u64,
+    /// Presented in `SessionResume`; a secret, like an access token.
+    pub token: String,
+    /// Whether `Hello` resumed an earlier session. If it asked to and this
+    /// is false, the earlier session is gone with its subscriptions.
+    pub resumed: bool,
+    /// The `seq` of the last event the node sent in this session.
+    pub last_seq: u64,
+    pub grace_ms: u64,
+}
+
+/// What a node binary was built from, fixed at build time.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// The node crate's version, such as `0.3.0`.
+    pub version: String,
+    /// The git commit built, or `unknown` outside a git checkout.
+    pub git_commit: String,
+    /// Whether the checkout had uncommitted changes.
+    #[serde(default)]
+    pub git_dirty: bool,
+    /// Seconds since the Unix epoch; `SOURCE_DATE_EPOCH` when set.
+    #[serde(default)]
+    pub built_at_unix: u64,
+    /// Cargo features the node was built with.
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub wasmtime_version: String,
+    #[serde(default)]
+    pub libp2p_version: String,
+}
+
+impl BuildInfo {
+    /// `0.3.0 (1a2b3c4d5e6f, dirty)`, for logs and version banners.
+    pub fn describe(&self) -> String {
+        let commit: String = self.git_commit.chars().take(12).collect();
+        match self.git_dirty {
+            true => format!("{} ({}, dirty)", self.version, commit),
+            false => format!("{} ({})", self.version, commit),
+        }
+    }
+}
+
+/// The node's overall health. `NodeStatus::system_health` carries the same
+/// judgement as `OK`, `DEGRADED` or `CRITICAL` for older clients.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "level", rename_all = "snake_case")]
+pub enum HealthLevel {
+    Ok,
+    /// Working, with the listed problems.
+    Degraded { reasons: Vec<String> },
+    /// Close to failing: out of disk or memory.
+    Critical { reasons: Vec<String> },
+    /// A level this client does not know, or a node too old to report one.
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+impl HealthLevel {
+    /// The `system_health` string for this level.
+    pub fn summary(&self) -> &'static str {
+        match self {
+            HealthLevel::Ok => "OK",
+            HealthLevel::Degraded { .. } => "DEGRADED",
+            HealthLevel::Critical { .. } => "CRITICAL",
+            HealthLevel::Unknown => "UNKNOWN",
+        }
+    }
+
+    pub fn reasons(&self) -> &[String] {
+        match self {
+            HealthLevel::Degraded { reasons } | HealthLevel::Critical { reasons } => reasons,
+            _ => &[],
+        }
+    }
+}
+
+/// Resource use behind `NodeStatus::health`. Host figures are `None` where
+/// the platform does not tell, and may be a couple of seconds old.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NodeResources {
+    /// The node process's resident memory.
+    pub rss_bytes: Option<u64>,
+    /// The node process's CPU use since the last sample, as a share of the
+    /// whole machine.
+    pub cpu_percent: Option<f64>,
+    /// Space left on the volume holding the data directory.
+    pub data_dir_free_bytes: Option<u64>,
+    pub core_size_bytes: u64,
+    /// WASM executions waiting for a slot.
+    pub wasm_queued: u64,
+}
+
+/// IPC client connections, open now and since the node started.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct IpcConnectionStats {
+    pub open: u64,
+    pub accepted: u64,
+    /// Turned away with `Response::Busy` at the connection limit.
+    pub rejected: u64,
+    /// Closed for sending nothing within the idle timeout.
+    #[serde(default)]
+    pub idled_out: u64,
+}
+
+/// Whether the node has an identity to bind licenses to. It derives one
+/// from the machine uid and a per-install salt, and has none only when
+/// both are missing.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum MachineBinding {
+    Bound,
+    /// License checks are refused until the cause is fixed and the node
+    /// restarted.
+    Unbindable { reason: String },
+    /// A state this client does not know, or a node too old to report one.
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+/// The license verifier's connection to its Electrum server. The node
+/// starts without it and keeps retrying in the background.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum FinanceState {
+    /// The first attempt is still under way.
+    #[default]
+    Connecting,
+    Ready,
+    /// The last attempt failed; another follows after a pause.
+    Failed { reason: String },
+    /// A state this client does not know, from a newer node.
+    #[serde(other)]
+    Unknown,
+}
+
+/// The mesh's progress after startup. Peers may connect and leave in any
+/// phase; this only says whether the node has found the mesh yet, or lost
+/// its mesh actor.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "phase", rename_all = "snake_case")]
+pub enum MeshPhase {
+    /// Dialing the bootstrap and pinned peers; none has connected yet.
+    WarmingUp,
+    /// At least one peer connected.
+    Ready,
+    /// Warm-up ran out of time without a peer. The node keeps listening
+    /// and accepts peers that dial it.
+    Isolated,
+    /// The mesh actor died and is being started again. Mesh requests fail
+    /// until it is back.
+    Restarting { attempt: u32, reason: String },
+    /// The mesh actor kept dying and the node stopped restarting it. Mesh
+    /// requests fail until the node restarts.
+    Failed { reason: String },
+    /// A phase this client does not know, or a node too old to report one.
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+/// Why `RunWasm` refused a module path.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum WasmPathRejection {
+    NotFound,
+    /// Larger than the node's `wasm.max_module_bytes`.
+    TooLarge { size: u64, max: u64 },
+    /// Resolves, links followed, outside the module store and the node's
+    /// `wasm.run_dirs`.
+    OutsideAllowedDirs,
+    Unreadable { message: String },
+}
+
+/// Point-in-time counters from the node's subsystems.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MetricsSnapshot {
+    pub wasm: WasmMetrics,
+    #[serde(default)]
+    pub core: CoreMetrics,
+    /// Requests answered with `Response::TimedOut`, by `Request::kind`.
+    #[serde(default)]
+    pub timeouts: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub ipc: IpcMetrics,
+    /// The node's blocking worker pools, by name.
+    #[serde(default)]
+    pub pools: BTreeMap<String, PoolMetrics>,
+    /// Unset while replication is off.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replication: Option<ReplicationMetrics>,
+}
+
+/// One blocking worker pool: its size and what it has done since the node
+/// started.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PoolMetrics {
+    pub threads: u64,
+    /// Work that may wait for a thread before more is refused.
+    pub queue_capacity: u64,
+    pub queued: u64,
+    pub running: u64,
+    pub completed: u64,
+    /// Work refused with `Response::Overloaded`.
+    pub rejected: u64,
+}
+
+/// What a connection may do. The node grants each connection a set of
+/// these, by access token or by user.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// Status, metrics, listings and event subscriptions.
+    ReadStatus,
+    /// Read-only core queries, exports and watches.
+    QueryCoreReadonly,
+    /// Core queries that write, transactions, imports and named queries.
+    QueryCoreWrite,
+    RunWasm,
+    /// Uploading and removing modules, scheduled jobs and the allow-list.
+    ManageWasm,
+    /// Dialing and unpinning mesh peers.
+    MeshControl,
+    /// Verifying licenses on-chain.
+    LicenseAdmin,
+    /// Cancelling any connection's work.
+    NodeAdmin,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 8] = [
+        Permission::ReadStatus,
+        Permission::QueryCoreReadonly,
+        Permission::QueryCoreWrite,
+        Permission::RunWasm,
+        Permission::ManageWasm,
+        Permission::MeshControl,
+        Permission::LicenseAdmin,
+        Permission::NodeAdmin,
+    ];
+
+    /// The name used on the wire and in the node's config.
+    pub fn name(self) -> &'static str {
+        match self {
+            Permission::ReadStatus => "read_status",
+            Permission::QueryCoreReadonly => "query_core_readonly",
+            Permission::QueryCoreWrite => "query_core_write",
+            Permission::RunWasm => "run_wasm",
+            Permission::ManageWasm => "manage_wasm",
+            Permission::MeshControl => "mesh_control",
+            Permission::LicenseAdmin => "license_admin",
+            Permission::NodeAdmin => "node_admin",
+        }
+    }
+}
+
+/// What `Request::Subscribe` can ask for.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum EventTopic {
+    /// Peers connecting and leaving, listen address changes, and nodes'
+    /// presence beacons going stale or returning.
+    Mesh,
+    /// The license turning active or inactive.
+    License,
+    /// Changes to relations this connection watches with `CoreWatch`,
+    /// which then come as events rather than `CoreChanged`.
+    Core,
+    /// Scheduled WASM jobs finishing a run.
+    WasmJobs,
+}
+
+impl EventTopic {
+    /// The name used on the wire.
+    pub fn name(self) -> &'static str {
+        match self {
+            EventTopic::Mesh => "mesh",
+            EventTopic::License => "license",
+            EventTopic::Core => "core",
+            EventTopic::WasmJobs => "wasm_jobs",
+        }
+    }
+}
+
+/// Something that happened on the node, pushed to connections subscribed
+/// to its topic.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum NodeEvent {
+    PeerConnected { peer: String },
+    PeerDisconnected { peer: String },
+    ListenersChanged { addrs: Vec<String> },
+    /// A node has sent no presence beacon for the staleness window.
+    PresenceStale { peer: String },
+    /// A stale node was heard from again.
+    PresenceReturned { peer: String },
+    LicenseChanged { active: bool, report: Option<LicenseReport> },
+    CoreChanged(CoreChange),
+    WasmJobCompleted { job: String, run: WasmJobRun },
+}
+
+impl NodeEvent {
+    pub fn topic(&self) -> EventTopic {
+        match self {
+            NodeEvent::PeerConnected { .. }
+            | NodeEvent::PeerDisconnected { .. }
+            | NodeEvent::ListenersChanged { .. }
+            | NodeEvent::PresenceStale { .. }
+            | NodeEvent::PresenceReturned { .. } => EventTopic::Mesh,
+            NodeEvent::LicenseChanged { .. } => EventTopic::License,
+            NodeEvent::CoreChanged(_) => EventTopic::Core,
+            NodeEvent::WasmJobCompleted { .. } => EventTopic::WasmJobs,
+        }
+    }
+}
+
+/// A node as its last presence beacon describes it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerPresence {
+    pub peer_id: String,
+    pub node_version: String,
+    pub uptime_ms: u64,
+    /// Its `system_health` when it sent the beacon.
+    pub health: String,
+    pub capabilities: Vec<String>,
+    /// When its last beacon arrived, in Unix milliseconds.
+    pub last_seen_ms: u64,
+    /// Not heard from for longer than `stale_after_ms`.
+    pub stale: bool,
+    /// Connected to this node directly, not only heard from through others.
+    pub connected: bool,
+}
+
+/// The replication outbox: local ops waiting to be gossiped, and the ones
+/// gossiped lately.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplicationOutboxReport {
+    /// Entries not yet published.
+    pub depth: u64,
+    /// Past this depth the node reports itself degraded.
+    pub high_water: u64,
+    pub entries: Vec<OutboxEntryInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutboxEntryInfo {
+    /// Rises with every op queued; each relation's ops go out in this order.
+    pub seq: u64,
+    pub relation: String,
+    pub topic: String,
+    pub queued_ms: u64,
+    /// Unset until gossipsub takes the op.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_ms: Option<u64>,
+    /// Tries at gossiping it, the one that worked included.
+    pub attempts: u32,
+    /// Why the last try failed, until one works.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// What `Request::InjectFault` breaks.
+#[cfg(feature = "fault-injection")]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultTarget {
+    /// The channel that carries commands to the mesh actor.
+    Mesh,
+    /// License checks against the Electrum server.
+    Finance,
+    Core,
+    Wasm,
+    /// Writes to IPC clients.
+    IpcWriter,
+}
+
+/// How `Request::InjectFault` breaks its target. Each kind fits the
+/// targets named on it.
+#[cfg(feature = "fault-injection")]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FaultKind {
+    /// Mesh: commands are dropped, failing their callers at once.
+    Drop,
+    /// Mesh: each command reaches the actor `delay_ms` late.
+    Delay { delay_ms: u64 },
+    /// Mesh: the actor is killed once, as if it had panicked, and the
+    /// supervisor restarts it.
+    Crash,
+    /// Finance: checks fail as if the Electrum server were unreachable.
+    NetworkError,
+    /// Finance and core: calls hang until the fault ends or their request
+    /// times out.
+    Hang,
+    /// Core: the lock running queries are registered under is poisoned
+    /// once, as by a thread panicking while it held it.
+    PoisonLock,
+    /// Wasm: runs trap out of fuel at their first instruction.
+    Trap,
+    /// IPC writer: writes wait until the fault ends.
+    Stall,
+}
+
+/// IPC listener counters, cumulative since the node started.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct IpcMetrics {
+    /// Failed attempts to accept a client, fatal or not.
+    pub accept_failures: u64,
+    /// Requests handled, by `Request::kind`.
+    #[serde(default)]
+    pub requests: BTreeMap<String, IpcRequestStats>,
+    /// Bytes read from and written to clients, on every connection.
+    #[serde(default)]
+    pub bytes_in: u64,
+    #[serde(default)]
+    pub bytes_out: u64,
+    /// Requests answered with `Response::RateLimited`, by `Request::kind`.
+    #[serde(default)]
+    pub rate_limited: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub connections: IpcConnectionStats,
+    /// Sessions whose connection dropped, kept for their client to resume.
+    #[serde(default)]
+    pub parked_sessions: u64,
+}
+
+/// Upper bounds, in milliseconds, of the latency buckets in
+/// `IpcRequestStats::latency`.
+pub const LATENCY_BUCKETS_MS: [u64; 12] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 10000];
+
+/// How many requests of one kind were handled, and how long they took.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct IpcRequestStats {
+    pub count: u64,
+    pub total_micros: u64,
+    /// Answered with something other than what was asked for, such as
+    /// `Response::Error` or `Response::TimedOut`.
+    #[serde(default)]
+    pub errors: u64,
+    /// Requests that took at most each of `LATENCY_BUCKETS_MS`, cumulative;
+    /// `count` is the rest of the histogram.
+    #[serde(default)]
+    pub latency: Vec<u64>,
+}
+
+/// A request that took longer than the node's slow-request threshold.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SlowRequest {
+    /// When the request arrived, in milliseconds since the Unix epoch.
+    pub at_ms: u64,
+    pub connection_id: u64,
+    /// The name the client said Hello with, or `connection-<id>`.
+    pub client: String,
+    /// The request's `Request::kind`.
+    pub request: String,
+    pub duration_ms: f64,
+    /// `ok`, or what came back instead, as in `IpcAuditEntry::outcome`.
+    pub outcome: String,
+    /// What the request asked for, redacted as in `IpcAuditEntry::params`.
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+}
+
+/// One open IPC connection, as `Request::ConnectionStats` reports it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IpcConnectionInfo {
+    pub connection_id: u64,
+    /// The name the client said Hello with, or `connection-<id>`.
+    pub client: String,
+    /// The connecting user, where the transport tells.
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// When the connection was accepted, in milliseconds since the Unix epoch.
+    pub opened_at_ms: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Requests handled on this connection, by `Request::kind`.
+    #[serde(default)]
+    pub requests: BTreeMap<String, IpcRequestStats>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoreQueryInfo {
+    pub query_id: u64,
+    /// The query text, shortened if long.
+    pub query: String,
+    pub running_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoreRelation {
+    pub name: String,
+    pub arity: u32,
+    /// Number of key columns.
+    pub keys: u32,
+    pub rows: u64,
+    #[serde(default)]
+    pub indices: Vec<CoreIndex>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoreRelationSchema {
+    pub name: String,
+    /// Key columns first, in key order.
+    pub columns: Vec<CoreColumn>,
+    pub rows: u64,
+    #[serde(default)]
+    pub indices: Vec<CoreIndex>,
+    /// Rows kept in the relation's history, if it keeps one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_rows: Option<u64>,
+}
+
+/// An index attached to a stored relation.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoreIndex {
+    pub name: String,
+    /// `normal`, `fts`, `hnsw` or `lsh`.
+    pub kind: String,
+    /// The engine's settings for the index.
+    pub config: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoreColumn {
+    pub name: String,
+    /// Engine type text, e.g. `Int?`.
+    pub column_type: String,
+    pub key: bool,
+    pub has_default: bool,
+}
+
+/// Committed changes to a relation watched with `Request::CoreWatch`. The
+/// changes of one transaction normally arrive as one event per kind.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoreChange {
+    pub watch_id: u64,
+    pub relation: String,
+    pub op: CoreChangeOp,
+    /// The relation's columns, keys first.
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<serde_json::Value>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CoreChangeOp {
+    /// Rows written, as they now are.
+    Put,
+    /// Rows deleted, as they were.
+    Remove,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoreFailure {
+    pub code: ErrorCode,
+    pub message: String,
+    /// 1-based position in the query of a syntax error, when the engine
+    /// gives one.
+    #[serde(default)]
+    pub line: Option<u32>,
+    #[serde(default)]
+    pub column: Option<u32>,
+}
+
+/// Why a core request failed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The query is not valid syntax.
+    Parse,
+    UnknownRelation,
+    /// A value does not fit its column's type.
+    TypeMismatch,
+    /// A query run read-only tried to write.
+    ReadOnlyViolation,
+    /// The store failed to read or write.
+    Storage,
+    /// Another writer holds the store; retrying may succeed.
+    TransactionConflict,
+    InvalidParams,
+    MissingParam,
+    Timeout,
+    Cancelled,
+    Interrupted,
+    /// Another core session is open; the core runs one at a time.
+    TransactionOpen,
+    TransactionAborted,
+    InvalidName,
+    InvalidSchema,
+    RelationNotEmpty,
+    Io,
+    InvalidImport,
+    InvalidRow,
+    UnknownNamedQuery,
+    Migration,
+    InvalidVector,
+    AuditDisabled,
+    /// The client is confined to a core namespace, and the request reaches
+    /// outside it.
+    NamespaceDenied,
+    /// The client's principal has grants, and none covers a relation the
+    /// request reads or writes.
+    GrantDenied,
+    /// The result passed the node's cap on rows or bytes.
+    ResultTooLarge,
+    /// Any other failure of a query.
+    Query,
+    /// A code this client does not know, from a newer node.
+    #[serde(other)]
+    Unknown,
+}
+
+/// What a query would do, from `Request::CoreExplain`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoreExplainReport {
+    /// Stored relations the query reads or writes; empty for system ops.
+    pub relations: Vec<CoreExplainRelation>,
+    /// The columns the query returns; empty for system ops.
+    pub headers: Vec<String>,
+    /// How the query writes, e.g. `:put` or `::remove`; absent for a read.
+    #[serde(default)]
+    pub mutation: Option<String>,
+    /// The engine's plan, one object per step.
+    pub plan: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoreExplainRelation {
+    pub name: String,
+    pub exists: bool,
+    pub written: bool,
+}
+
+/// What first-run setup asks for, with what the node would use now.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetupState {
+    /// The node is waiting to be set up.
+    pub pending: bool,
+    /// Where setup writes the config.
+    pub config_path: String,
+    /// Where the node keeps its data; `SetupSettings::data_dir` must name it.
+    pub data_dir: String,
+    /// The Bitcoin network licenses are checked on.
+    pub network: String,
+    /// The networks `SetupSettings::network` may name.
+    pub networks: Vec<String>,
+    pub bootstrap_peers: Vec<String>,
+    /// A swarm key is in place already.
+    pub swarm_key_present: bool,
+    /// What a license costs and where it is paid, which setup must accept.
+    pub license_terms: LicenseTerms,
+}
+
+/// The choices `Request::SetupApply` makes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetupSettings {
+    /// bitcoin, testnet, signet or regtest.
+    pub network: String,
+    pub swarm_key: SwarmKeyChoice,
+    /// Multiaddrs dialed at startup.
+    #[serde(default)]
+    pub bootstrap_peers: Vec<String>,
+    /// Accepts `SetupState::license_terms`; setup fails without it.
+    pub accept_license_policy: bool,
+    /// Confirms `SetupState::data_dir`; setup fails if it names another.
+    pub data_dir: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SwarmKeyChoice {
+    /// A new random key, for the first node of a mesh.
+    Generate,
+    /// The mesh's key, as a swarm key file's text or its 64 hex digits.
+    Import { key: String },
+}
+
+/// What a node snapshot holds, from its manifest.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SnapshotSummary {
+    /// The release of the node that exported it.
+    pub node_version: String,
+    /// Unix milliseconds.
+    pub created_at_ms: u64,
+    pub relations: u64,
+    pub rows: u64,
+    pub named_queries: u64,
+    pub modules: u64,
+    pub jobs: u64,
+    pub peers: u64,
+    /// The archive's size.
+    pub bytes: u64,
+}
+
+/// What `sovereign-node --check` and `Request::SelfCheck` found.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SelfCheckReport {
+    pub checks: Vec<SelfCheck>,
+}
+
+impl SelfCheckReport {
+    /// Whether any check failed. Warnings do not count.
+    pub fn failed(&self) -> bool {
+        self.checks.iter().any(|check| check.status == CheckStatus::Fail)
+    }
+
+    /// The check named `name`, if it ran.
+    pub fn get(&self, name: &str) -> Option<&SelfCheck> {
+        self.checks.iter().find(|check| check.name == name)
+    }
+}
+
+impl fmt::Display for SelfCheckReport {
+    /// One line per check, with its hint indented below.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            let status = match check.status {
+                CheckStatus::Pass => "PASS",
+                CheckStatus::Warn => "WARN",
+                CheckStatus::Fail => "FAIL",
+            };
+            writeln!(f, "{}  {:<17} {}", status, check.name, check.detail)?;
+            if let Some(hint) = &check.hint {
+                writeln!(f, "      {:<17} {}", "", hint)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One self-check, such as `data_dir` or `module_store`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SelfCheck {
+    pub name: String,
+    pub status: CheckStatus,
+    /// What was found.
+    pub detail: String,
+    /// What to do about a warning or failure.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Pass,
+    /// Works, but worth a look; a warning alone does not fail the check.
+    Warn,
+    /// The node will not start, or will misbehave, until it is fixed.
+    Fail,
+}
+
+/// An IPC request as the node's IPC audit log recorded it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IpcAuditEntry {
+    /// When the request arrived, in milliseconds since the Unix epoch.
+    pub at_ms: u64,
+    pub connection_id: u64,
+    /// The name the client said Hello with, or `connection-<id>`.
+    pub client: String,
+    /// The connecting user, where the transport tells.
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// The first 12 hex digits of the SHA-256 of the access token the
+    /// connection presented, if any.
+    #[serde(default)]
+    pub token_id: Option<String>,
+    /// The request's `Request::kind`.
+    pub request: String,
+    /// What the request asked for, with query text, transaction ids and
+    /// other sensitive values hashed or reduced to sizes.
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+    /// `ok`, or what came back instead, such as `permission_denied` or
+    /// `core_failed:parse`; `aborted` if the connection ended first.
+    pub outcome: String,
+    pub duration_ms: f64,
+}
+
+/// A core query as the node's audit log recorded it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoreAuditEntry {
+    /// When the query started, in milliseconds since the Unix epoch.
+    pub at_ms: u64,
+    /// `internal`, `ipc:<client>` or `wasm`.
+    pub source: String,
+    /// Hex SHA-256 of the query text.
+    pub query_hash: String,
+    /// Absent unless the node records query text.
+    #[serde(default)]
+    pub query: Option<String>,
+    pub params: Vec<String>,
+    /// Absent unless the node records parameter values.
+    #[serde(default)]
+    pub param_values: Option<serde_json::Value>,
+    pub duration_ms: f64,
+    /// Rows returned; absent if the query failed.
+    #[serde(default)]
+    pub rows: Option<u64>,
+    /// Why the query failed; absent if it succeeded.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoreNamedQuery {
+    pub name: String,
+    pub query: String,
+    pub params: Vec<CoreQueryParam>,
+    pub readonly: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoreGrantInfo {
+    pub principal: String,
+    pub relation_pattern: String,
+    pub rights: CoreGrantRights,
+    pub granted_ms: u64,
+}
+
+/// What a grant lets its principal do; `write` includes reading.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CoreGrantRights {
+    Read,
+    Write,
+}
+
+/// A parameter a named query declares.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoreQueryParam {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub param_type: CoreParamType,
+    /// An optional parameter may be left out and is then null.
+    pub required: bool,
+}
+
+/// The JSON types named query parameters are checked against.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CoreParamType {
+    Any,
+    Bool,
+    /// An integer in the i64 range.
+    Int,
+    /// Any number.
+    Float,
+    String,
+    List,
+    Object,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CoreDataFormat {
+    /// One JSON object per line, keyed by column name.
+    JsonLines,
+    /// A header row of column names, then one record per row.
+    Csv,
+}
+
+/// How imported rows combine with the relation's existing rows.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CoreImportMode {
+    /// Delete every existing row first.
+    Replace,
+    /// Reject rows whose key is already present.
+    Append,
+    /// Overwrite rows whose key is already present.
+    Upsert,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoreImportSummary {
+    pub rows_read: u64,
+    pub inserted: u64,
+    pub rejected: u64,
+    /// Reasons for the first rejected rows; the rest are only counted.
+    pub rejects: Vec<CoreImportReject>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoreImportReject {
+    /// 1-based line of the row in the imported data.
+    pub line: u64,
+    pub reason: String,
+}
+
+/// The cognitive core's storage.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CoreMetrics {
+    /// `mem`, `sqlite` or `rocksdb`.
+    pub backend: String,
+    /// Size of the store on disk; 0 for `mem`.
+    pub size_bytes: u64,
+    /// Queries running now.
+    #[serde(default)]
+    pub running_queries: u64,
+}
+
+/// The replication outbox's depth, and what the dispatcher has done since
+/// the node started.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ReplicationMetrics {
+    pub outbox_depth: u64,
+    pub outbox_high_water: u64,
+    pub outbox_published: u64,
+    /// Failed tries at gossiping an entry.
+    pub outbox_failures: u64,
+}
+
+/// WASM execution admission counters, cumulative since the node started.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WasmMetrics {
+    /// Executions holding a slot.
+    pub running: u64,
+    /// Executions waiting for a slot.
+    pub queued: u64,
+    pub admitted: u64,
+    /// Executions refused as busy: the queue was full or their wait ran out.
+    pub rejected_busy: u64,
+    /// Time admitted executions spent queued.
+    pub avg_queue_wait_ms: u64,
+    pub max_queue_wait_ms: u64,
+    /// Runs of registered modules, summed over modules.
+    #[serde(default)]
+    pub module_runs: u64,
+    /// The registered module that has consumed the most fuel, and how much.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_module_by_fuel: Option<String>,
+    #[serde(default)]
+    pub top_module_fuel: u64,
+}
+
+/// Capabilities and limits requested when uploading a module.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WasmManifest {
+    /// Any of `wasi.fs`, `core.read`, `core.write`, `mesh.publish`.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    #[serde(default)]
+    pub fuel_limit: Option<u64>,
+    #[serde(default)]
+    pub max_memory_bytes: Option<u64>,
+    /// Hex SHA-256 the module must match.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// Environment variable names the module may receive. Caller-supplied
+    /// variables not listed are dropped; the node injects `SOVEREIGN_PEER_ID`
+    /// and `SOVEREIGN_MACHINE_HASH` when listed.
+    #[serde(default)]
+    pub allowed_env: Vec<String>,
+    /// Core namespace the module's queries are confined to. A client that
+    /// is itself confined may only give its own.
+    #[serde(default)]
+    pub namespace: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WasmTrap {
+    /// e.g. `UnreachableCodeReached`; absent for traps raised by the host.
+    pub code: Option<String>,
+    pub message: String,
+    pub backtrace: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WasmLogLine {
+    /// `TRACE`, `DEBUG`, `INFO`, `WARN` or `ERROR`.
+    pub level: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WasmExecutionInfo {
+    pub execution_id: u64,
+    /// Module name, or path for unregistered modules.
+    pub label: String,
+    pub running_ms: u64,
+}
+
+/// Detached ed25519 module signature.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WasmSignature {
+    pub key_id: String,
+    /// Hex-encoded 64-byte signature.
+    pub signature: String,
+}
+
+/// A recurring run of a registered module.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WasmJobSpec {
+    pub module: String,
+    /// `{{job}}`, `{{run}}` and `{{scheduled_ms}}` are substituted on each run.
+    #[serde(default)]
+    pub input: String,
+    pub schedule: WasmJobSchedule,
+    #[serde(default)]
+    pub fuel_limit: Option<u64>,
+    #[serde(default)]
+    pub max_memory_bytes: Option<u64>,
+    #[serde(default)]
+    pub overlap: WasmJobOverlap,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum WasmJobSchedule {
+    Interval { every_ms: u64 },
+    /// Five-field cron expression, evaluated in UTC.
+    Cron { spec: String },
+}
+
+/// What to do when a job comes due while its previous run is still going.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WasmJobOverlap {
+    #[default]
+    Skip,
+    /// Run again once the current run finishes; at most one run waits.
+    Queue,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WasmJobInfo {
+    pub name: String,
+    pub job: WasmJobSpec,
+    pub runs: u64,
+    /// Runs dropped because the previous one was still going.
+    pub skipped: u64,
+    pub running: bool,
+    /// Unix milliseconds.
+    pub next_run_ms: Option<u64>,
+}
+
+/// One module in a `RunWasmPipeline`. Unset limits fall back to its manifest.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WasmPipelineStage {
+    pub module: String,
+    #[serde(default)]
+    pub fuel_limit: Option<u64>,
+    #[serde(default)]
+    pub max_memory_bytes: Option<u64>,
+}
+
+/// The stage that stopped a pipeline.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WasmPipelineFailure {
+    /// Zero-based stage index.
+    pub stage: u32,
+    pub module: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WasmJobRun {
+    pub run: u64,
+    /// Unix milliseconds.
+    pub started_ms: u64,
+    /// A `WasmResult`, or `Error` if the run could not start.
+    pub result: Box<Response>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WasmModuleInfo {
+    pub name: String,
+    pub sha256: String,
+    pub size_bytes: u64,
+    pub capabilities: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_env: Vec<String>,
+    /// Core namespace the module's queries are confined to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// Bumped each time the module is re-uploaded or reloaded.
+    #[serde(default)]
+    pub revision: u64,
+    /// Watched source file, for modules uploaded with `watch`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_path: Option<String>,
+    /// Why the last reload failed; the previous revision is still the one that runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reload_error: Option<String>,
+    /// A precompiled artifact valid for this node's engine is stored with the
+    /// module, so it loads without recompiling.
+    #[serde(default)]
+    pub precompiled: bool,
+    /// Only filled in by `WasmInfo`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<WasmModuleDetails>,
+    /// Usage since the node started; absent until the module first runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<WasmModuleStats>,
+}
+
+/// Cumulative resource usage of a registered module. Kept across reloads,
+/// cleared when the module is removed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WasmModuleStats {
+    pub runs: u64,
+    /// Runs that failed without producing a result.
+    pub failures: u64,
+    pub traps: u64,
+    pub fuel_used: u64,
+    pub total_duration_ms: u64,
+    pub peak_memory_bytes: u64,
+    /// Unix time in milliseconds when the last run finished.
+    pub last_run_ms: Option<u64>,
+    /// Revision the last run executed.
+    pub last_revision: u64,
+}
+
+/// What a module imports, exports and declares.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WasmModuleDetails {
+    pub imports: Vec<WasmImport>,
+    pub exports: Vec<WasmExport>,
+    pub memories: Vec<WasmLimits>,
+    pub tables: Vec<WasmLimits>,
+    pub has_start: bool,
+    /// The module was uploaded in the text format.
+    pub text_format: bool,
+    /// A preview-2 component; `imports` and `exports` then name its interfaces.
+    #[serde(default)]
+    pub component: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WasmImport {
+    pub module: String,
+    pub name: String,
+    /// `func`, `table`, `memory`, `global` or `tag`.
+    pub kind: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WasmExport {
+    pub name: String,
+    pub kind: String,
+}
+
+/// Memory limits are in 64 KiB pages, table limits in elements.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WasmLimits {
+    pub initial: u64,
+    pub maximum: Option<u64>,
+    pub imported: bool,
+    pub shared: bool,
+}
+
+/// Outcome of the most recent on-chain license check.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LicenseReport {
+    pub txid: String,
+    pub found: bool,
+    pub paid_sats: u64,
+    pub payment_ok: bool,
+    pub binding_ok: bool,
+    pub confirmations: u32,
+    pub confirmed_height: Option<u32>,
+    pub tier: Option<String>,
+    pub valid_until_height: Option<u32>,
+    pub checked_at_ms: u64,
+}
+
+/// The canonical terms a license is bought and verified under.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LicenseTerms {
+    pub developer_addr: String,
+    pub required_sats: u64,
+    pub min_confirmations: u32,
+    pub validity_blocks: Option<u32>,
+    pub tiers: Vec<LicenseTierInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LicenseTierInfo {
+    pub name: String,
+    pub min_sats: u64,
+}
+
+/// What a purchase transaction must carry to bind a license to this machine.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LicenseBinding {
+    /// Hex OP_RETURN payload.
+    pub op_return_hex: String,
+    /// BIP21 payment URI for the required amount.
+    pub payment_uri: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn full_license_result() -> Response {
+        Response::LicenseResult {
+            valid: true,
+            details: "Active".into(),
+            report: Some(LicenseReport {
+                txid: "ab".repeat(32),
+                found: true,
+                paid_sats: 50_000,
+                payment_ok: true,
+                binding_ok: true,
+                confirmations: 6,
+                confirmed_height: Some(800_000),
+                tier: Some("pro".into()),
+                valid_until_height: Some(852_560),
+                checked_at_ms: 1_700_000_000_000,
+            }),
+            terms: Some(LicenseTerms {
+                developer_addr: "bc1qexample".into(),
+                required_sats: 50_000,
+                min_confirmations: 3,
+                validity_blocks: Some(52_560),
+                tiers: vec![LicenseTierInfo {
+                    name: "pro".into(),
+                    min_sats: 50_000,
+                }],
+            }),
+            binding: Some(LicenseBinding {
+                op_return_hex: "00".repeat(32),
+                payment_uri: "bitcoin:bc1qexample?amount=0.0005".into(),
+            }),
+        }
+    }
+
+    #[test]
+    fn old_clients_read_the_rich_license_result() {
+        /// `Response::LicenseResult` as clients before the report had it.
+        #[derive(Deserialize)]
+        enum OldResponse {
+            LicenseResult { valid: bool, details: String },
+        }
+
+        let wire = serde_json::to_string(&full_license_result()).unwrap();
+        let OldResponse::LicenseResult { valid, details } = serde_json::from_str(&wire).unwrap();
+        assert!(valid);
+        assert_eq!(details, "Active");
+    }
+
+    #[test]
+    fn new_clients_read_old_license_results() {
+        let old = json!({"LicenseResult": {"valid": false, "details": "Payment not found"}});
+        match serde_json::from_value(old).unwrap() {
+            Response::LicenseResult { valid, details, report, terms, binding } => {
+                assert!(!valid);
+                assert_eq!(details, "Payment not found");
+                assert!(report.is_none() && terms.is_none() && binding.is_none());
+            }
+            other => panic!("Expected LicenseResult, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn license_result_round_trips() {
+        let wire = serde_json::to_value(full_license_result()).unwrap();
+        assert_eq!(wire["LicenseResult"]["report"]["tier"], "pro");
+        assert_eq!(wire["LicenseResult"]["terms"]["tiers"][0]["min_sats"], 50_000);
+        let back: Response = serde_json::from_value(wire.clone()).unwrap();
+        assert_eq!(serde_json::to_value(back).unwrap(), wire);
+
+        // Unset parts are left out rather than sent as null.
+        let bare = Response::LicenseResult {
+            valid: false,
+            details: "Never verified".into(),
+            report: None,
+            terms: None,
+            binding: None,
+        };
+        assert_eq!(serde_json::to_value(bare).unwrap(), json!({"LicenseResult": {"valid": false, "details": "Never verified"}}));
+    }
 }
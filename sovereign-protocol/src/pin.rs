@@ -0,0 +1,81 @@
+//! Certificate pins for TCP connections: the SHA-256 of the certificate the
+//! node serves, which a client checks instead of a chain of trust. Written
+//! as 32 colon-separated hex pairs, as `openssl x509 -fingerprint -sha256`
+//! prints them.
+
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// The SHA-256 fingerprint of a DER certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CertPin([u8; 32]);
+
+impl CertPin {
+    /// The fingerprint of `der`.
+    pub fn of(der: &[u8]) -> Self {
+        Self(Sha256::digest(der).into())
+    }
+
+    /// Reads a fingerprint as `Display` writes it, or as 64 hex digits,
+    /// in either case, with an optional `sha256:` prefix.
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        let text = text.strip_prefix("sha256:").unwrap_or(text);
+        let digits: Vec<u8> = text.bytes().filter(|b| *b != b':').collect();
+        if digits.len() != 64 {
+            return None;
+        }
+        let mut pin = [0u8; 32];
+        for (byte, pair) in pin.iter_mut().zip(digits.chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+        }
+        Some(Self(pin))
+    }
+
+    /// Whether `der` is the pinned certificate.
+    pub fn matches(&self, der: &[u8]) -> bool {
+        let presented = Self::of(der);
+        self.0.iter().zip(presented.0.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
+impl fmt::Display for CertPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(":")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_what_it_writes_and_nothing_else() {
+        let pin = CertPin::of(b"certificate");
+        let written = pin.to_string();
+        assert_eq!(written.len(), 32 * 3 - 1);
+        assert_eq!(CertPin::parse(&written), Some(pin));
+        let bare = written.replace(':', "").to_lowercase();
+        assert_eq!(CertPin::parse(&format!(" sha256:{}\n", bare)), Some(pin));
+
+        for text in ["", "sha256:", &written[3..], &format!("{}:00", written), &format!("g{}", &bare[1..]), &format!("{}é", &bare[..62])] {
+            assert_eq!(CertPin::parse(text), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn matches_only_the_pinned_certificate() {
+        let pin = CertPin::of(b"certificate");
+        assert!(pin.matches(b"certificate"));
+        assert!(!pin.matches(b"certificatf"));
+        assert!(!pin.matches(b""));
+        // A pin is not a certificate of its own.
+        assert!(!pin.matches(&pin.0));
+    }
+}
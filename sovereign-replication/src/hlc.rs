@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A hybrid logical clock reading: wall-clock milliseconds, and a counter
+/// that orders readings within a millisecond, or past a clock seen running
+/// ahead of this one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Hlc {
+    pub ms: u64,
+    pub counter: u32,
+}
+
+/// Each reading is later than every earlier one and every remote one
+/// observed.
+pub(crate) struct Clock {
+    last: Hlc,
+}
+
+impl Clock {
+    /// `last` is the latest reading already handed out, e.g. before a restart.
+    pub(crate) fn new(last: Hlc) -> Self {
+        Self { last }
+    }
+
+    /// A reading for a change made at wall-clock `ms`, later than every
+    /// earlier one even if `ms` is not.
+    pub(crate) fn at(&mut self, ms: u64) -> Hlc {
+        self.last = if ms > self.last.ms {
+            Hlc { ms, counter: 0 }
+        } else {
+            Hlc {
+                ms: self.last.ms,
+                counter: self.last.counter + 1,
+            }
+        };
+        self.last
+    }
+
+    pub(crate) fn observe(&mut self, remote: Hlc) {
+        self.last = self.last.max(remote);
+    }
+}
+
+pub(crate) fn wall_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readings_rise_past_remote_clocks_running_ahead() {
+        let mut clock = Clock::new(Hlc::default());
+        let first = clock.at(wall_ms());
+        let second = clock.at(wall_ms());
+        assert!(second > first);
+
+        let ahead = Hlc { ms: wall_ms() + 60_000, counter: 7 };
+        clock.observe(ahead);
+        assert_eq!(clock.at(wall_ms()), Hlc { ms: ahead.ms, counter: 8 });
+        // An older remote reading does not set the clock back.
+        clock.observe(first);
+        assert_eq!(clock.at(wall_ms()), Hlc { ms: ahead.ms, counter: 9 });
+    }
+
+    #[test]
+    fn readings_for_earlier_changes_still_rise() {
+        let mut clock = Clock::new(Hlc::default());
+        assert_eq!(clock.at(1_000), Hlc { ms: 1_000, counter: 0 });
+        assert_eq!(clock.at(900), Hlc { ms: 1_000, counter: 1 });
+        assert_eq!(clock.at(1_001), Hlc { ms: 1_001, counter: 0 });
+    }
+}
@@ -0,0 +1,605 @@
+use hlc::{wall_ms, Clock};
+use log::{debug, info, warn};
+use oplog::{Batch, Cursor, Digest, Table};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sovereign_core::{CognitiveCore, CoreError, WatchHandle};
+use sovereign_mesh::identity::Keypair;
+use sovereign_mesh::{MeshCommand, MeshMessage, MeshRequest, MAX_MESSAGE_SIZE};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::mpsc::{self as std_mpsc, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot};
+
+mod hlc;
+mod op;
+mod oplog;
+mod outbox;
+
+pub use hlc::Hlc;
+pub use op::{Op, SignedOp};
+pub use outbox::{Outbox, OutboxEntry};
+
+/// How long the worker waits for mesh input before looking for captured
+/// changes again.
+const POLL: Duration = Duration::from_millis(50);
+
+/// Remote ops stamped further ahead of the local clock are refused, so a
+/// peer with a wrong clock cannot win every conflict for that long.
+const MAX_CLOCK_AHEAD_MS: u64 = 5 * 60 * 1000;
+
+/// Bytes of ops sent per sync response; the protocol caps a response at
+/// 10 MiB, and a page always holds at least one op.
+const SYNC_PAGE_BYTES: usize = 2 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct ReplicationConfig {
+    /// Stored relations to keep in sync. Every node replicating one must
+    /// have it, with the same columns.
+    pub relations: Vec<String>,
+    /// Prefixes the gossip topics; nodes only replicate with nodes in the
+    /// same namespace.
+    pub namespace: String,
+    /// How often each relation's digest is gossiped for comparison.
+    pub digest_interval: Duration,
+    /// Ops waiting in the outbox past which the node reports itself
+    /// degraded. None are dropped.
+    pub outbox_high_water: u64,
+}
+
+impl ReplicationConfig {
+    pub fn new(relations: Vec<String>) -> Self {
+        Self {
+            relations,
+            namespace: "sovereign".into(),
+            digest_interval: Duration::from_secs(30),
+            outbox_high_water: 10_000,
+        }
+    }
+}
+
+/// Asks a peer for its logged ops in some of a relation's digest buckets.
+#[derive(Debug, Serialize, Deserialize)]
+struct SyncRequest {
+    namespace: String,
+    relation: String,
+    buckets: Vec<u8>,
+    after: Option<Cursor>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SyncResponse {
+    ops: Vec<SignedOp>,
+    /// Set if there are more; asked for with `after`.
+    next: Option<Cursor>,
+}
+
+enum Input {
+    Message(MeshMessage),
+    Request(MeshRequest),
+    /// Time to gossip the digests.
+    Digest,
+    /// A peer's answer to a `SyncRequest`.
+    Synced { peer: String, request: SyncRequest, answer: anyhow::Result<Vec<u8>> },
+}
+
+/// Starts replicating `config.relations` over the mesh, as the mesh node
+/// whose identity is `keys`, and returns once their changes are captured, with
+/// the outbox their local ops wait in to be gossiped.
+///
+/// Committed writes to those relations, through any API, are captured in
+/// the outbox by triggers, in the transaction that commits them. Each then
+/// becomes an op signed with `keys` and stamped with a hybrid logical clock
+/// at the time it was committed, logged and filled into its outbox entry by
+/// one script; the caller gossips the outbox, so a mesh that is down delays
+/// ops rather than losing them. Writes made by other relations' triggers are
+/// not captured, as the engine runs no triggers for them, and are found at
+/// the next start. Remote
+/// ops are checked and applied last-writer-wins per key: the greater clock
+/// reading wins, then the greater origin peer id. Every node logs the
+/// winning op on each key, deletions included, in the `sovereign_replica_log`
+/// relation; digests of that log are gossiped regularly, and a node whose
+/// digest differs from a peer's fetches the differing part of the peer's
+/// log, which is also how a node that joins late catches up.
+///
+/// Any peer on the mesh that can sign may write the replicated relations.
+pub async fn start(
+    core: Arc<CognitiveCore>,
+    mesh: mpsc::Sender<MeshCommand>,
+    keys: Keypair,
+    config: ReplicationConfig,
+) -> anyhow::Result<Arc<Outbox>> {
+    let (messages_tx, mut messages) = mpsc::channel(1024);
+    for relation in &config.relations {
+        let topic = op_topic(&config.namespace, relation);
+        mesh.send(MeshCommand::Subscribe { topic, messages: messages_tx.clone() }).await?;
+    }
+    let topic = digest_topic(&config.namespace);
+    mesh.send(MeshCommand::Subscribe { topic, messages: messages_tx }).await?;
+    let (requests_tx, mut requests) = mpsc::channel(16);
+    mesh.send(MeshCommand::ServeRequests(requests_tx)).await?;
+
+    let (inbox_tx, inbox) = std_mpsc::channel();
+    let (ready_tx, ready) = oneshot::channel();
+    let (runtime, worker_config, worker_inbox) = (Handle::current(), config.clone(), inbox_tx.clone());
+    std::thread::Builder::new().name("replication".into()).spawn(move || {
+        let mut worker = match Worker::new(core, mesh, keys, &worker_config, worker_inbox, runtime) {
+            Ok(worker) => worker,
+            Err(e) => {
+                let _ = ready_tx.send(Err(e));
+                return;
+            }
+        };
+        let _ = ready_tx.send(Ok(worker.outbox.clone()));
+        worker.run(inbox);
+    })?;
+    let outbox = ready.await??;
+
+    let interval = config.digest_interval;
+    tokio::spawn(async move {
+        let mut digests = tokio::time::interval(interval);
+        loop {
+            let input = tokio::select! {
+                Some(message) = messages.recv() => Input::Message(message),
+                Some(request) = requests.recv() => Input::Request(request),
+                _ = digests.tick() => Input::Digest,
+            };
+            if inbox_tx.send(input).is_err() {
+                break;
+            }
+        }
+    });
+    info!("Replicating {} in namespace {}", config.relations.join(", "), config.namespace);
+    Ok(outbox)
+}
+
+/// Removes the capture triggers of relations no longer replicated.
+fn release(core: &CognitiveCore, replicated: &BTreeMap<String, Replicated>) -> anyhow::Result<()> {
+    let listed = core.run("::relations", Value::Null)?;
+    let names: Vec<Vec<Value>> = serde_json::from_value(listed["rows"].clone()).unwrap_or_default();
+    for name in names.iter().filter_map(|row| row.first()?.as_str()) {
+        if !name.starts_with("sovereign_") && !replicated.contains_key(name) {
+            outbox::release_capture(core, name)?;
+        }
+    }
+    Ok(())
+}
+
+fn op_topic(namespace: &str, relation: &str) -> String {
+    format!("{}/replica/{}", namespace, relation)
+}
+
+fn digest_topic(namespace: &str) -> String {
+    format!("{}/replica-digest", namespace)
+}
+
+struct Replicated {
+    table: Table,
+    /// The log's digest, until the log changes.
+    digest: Option<Digest>,
+}
+
+/// Owns the replicated relations' side of the core, on its own thread.
+struct Worker {
+    core: Arc<CognitiveCore>,
+    mesh: mpsc::Sender<MeshCommand>,
+    runtime: Handle,
+    inbox: std_mpsc::Sender<Input>,
+    keys: Keypair,
+    origin: String,
+    namespace: String,
+    relations: BTreeMap<String, Replicated>,
+    clock: Clock,
+    outbox: Arc<Outbox>,
+    /// Wakes the worker as changes are captured.
+    captures: WatchHandle,
+    /// Set while captured changes are left unstamped by a failure.
+    unstamped: bool,
+    /// Peers and relations with a sync under way.
+    syncing: HashSet<(String, String)>,
+}
+
+impl Worker {
+    fn new(
+        core: Arc<CognitiveCore>,
+        mesh: mpsc::Sender<MeshCommand>,
+        keys: Keypair,
+        config: &ReplicationConfig,
+        inbox: std_mpsc::Sender<Input>,
+        runtime: Handle,
+    ) -> anyhow::Result<Self> {
+        oplog::create(&core)?;
+        let outbox = Arc::new(Outbox::open(core.clone(), config.outbox_high_water)?);
+        let mut relations = BTreeMap::new();
+        for name in &config.relations {
+            let schema = core.describe(name)?;
+            if name.starts_with("sovereign_") {
+                anyhow::bail!("{} is one of the core's own relations and cannot be replicated", name);
+            }
+            let table = Table {
+                name: name.clone(),
+                keys: schema.columns.iter().filter(|c| c.key).count(),
+                columns: schema.columns.into_iter().map(|c| c.name).collect(),
+            };
+            outbox::set_capture(&core, &table, &op_topic(&config.namespace, name))?;
+            relations.insert(name.clone(), Replicated { table, digest: None });
+        }
+        release(&core, &relations)?;
+        let clock = Clock::new(oplog::latest(&core)?);
+        let captures = core.watch(outbox::OUTBOX, Some("is_null(op)"))?;
+        Ok(Self {
+            origin: keys.public().to_peer_id().to_string(),
+            runtime,
+            namespace: config.namespace.clone(),
+            syncing: HashSet::new(),
+            core,
+            mesh,
+            inbox,
+            keys,
+            relations,
+            clock,
+            outbox,
+            captures,
+            unstamped: true,
+        })
+    }
+
+    fn run(&mut self, inbox: std_mpsc::Receiver<Input>) {
+        // Changes captured before a restart go first, at the time they were
+        // made, then the ones made while nothing captured them.
+        self.local_changes();
+        let names: Vec<String> = self.relations.keys().cloned().collect();
+        for name in names {
+            if let Err(e) = self.reconcile(&name) {
+                warn!("Could not check {} for unreplicated changes: {}", name, e);
+            }
+        }
+        loop {
+            let mut captured = false;
+            while self.captures.try_recv().is_some() {
+                captured = true;
+            }
+            if captured || self.unstamped {
+                self.local_changes();
+            }
+            match inbox.recv_timeout(POLL) {
+                Ok(Input::Message(message)) => self.message(message),
+                Ok(Input::Request(request)) => self.serve(request),
+                Ok(Input::Digest) => self.publish_digests(),
+                Ok(Input::Synced { peer, request, answer }) => self.synced(peer, request, answer),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+
+    /// Compares every row of `name` with the log, capturing the ones that
+    /// differ as local changes made now.
+    fn reconcile(&mut self, name: &str) -> anyhow::Result<()> {
+        let mut keys = oplog::keys(&self.core, name)?;
+        let table = &self.relations[name].table;
+        let script = format!("?[{keys}] := *{}{{{keys}}}", name, keys = table.columns[..table.keys].join(", "));
+        let stored = self.core.run(&script, Value::Null)?;
+        keys.extend(serde_json::from_value::<Vec<Vec<Value>>>(stored["rows"].clone()).unwrap_or_default());
+        let keys: BTreeMap<String, Vec<Value>> = keys.into_iter().map(|key| (oplog::key_text(&key), key)).collect();
+        let texts: Vec<String> = keys.keys().cloned().collect();
+        let logged = oplog::entries(&self.core, name, &texts)?;
+        let stored = table.rows(&self.core, &keys.values().cloned().collect::<Vec<_>>())?;
+        let changes = keys
+            .into_iter()
+            .filter(|(text, _)| stored.get(text) != logged.get(text).and_then(|(op, _)| op.row.as_ref()))
+            .map(|(text, key)| json!([key, stored.get(&text).map(|row| table.values(row))]))
+            .collect();
+        self.outbox.capture(table, &op_topic(&self.namespace, name), changes)?;
+        Ok(())
+    }
+
+    fn local_changes(&mut self) {
+        self.unstamped = false;
+        if let Err(e) = self.stamp_captured() {
+            warn!("Could not replicate local changes: {}", e);
+            self.unstamped = true;
+        }
+    }
+
+    /// Stamps the captured changes in the order they were committed, at the
+    /// time they were, logging each op and filling it into its outbox
+    /// entry. A change that matches its key's logged op was made by a
+    /// remote op or was already sent, and one whose row has changed again
+    /// since is superseded by a later capture; both are forgotten.
+    fn stamp_captured(&mut self) -> anyhow::Result<()> {
+        loop {
+            let captured = self.outbox.captured()?;
+            if captured.is_empty() {
+                return Ok(());
+            }
+            let mut keys: BTreeMap<&str, BTreeMap<String, Vec<Value>>> = BTreeMap::new();
+            for change in captured.iter().filter(|change| self.relations.contains_key(&change.relation)) {
+                keys.entry(&change.relation).or_default().insert(oplog::key_text(&change.key), change.key.clone());
+            }
+            let mut logged = HashMap::new();
+            let mut stored = HashMap::new();
+            for (relation, keys) in keys {
+                let texts: Vec<String> = keys.keys().cloned().collect();
+                logged.insert(relation.to_string(), oplog::entries(&self.core, relation, &texts)?);
+                let rows = self.relations[relation].table.rows(&self.core, &keys.into_values().collect::<Vec<_>>())?;
+                stored.insert(relation.to_string(), rows);
+            }
+
+            let mut stamped = BTreeMap::new();
+            let mut filled = Vec::new();
+            let mut forgotten = Vec::new();
+            for change in captured {
+                let (Some(replicated), Some(logged)) = (self.relations.get(&change.relation), logged.get_mut(&change.relation)) else {
+                    forgotten.push(json!([change.seq]));
+                    continue;
+                };
+                let text = oplog::key_text(&change.key);
+                let row = change.row.map(|values| replicated.table.row_of(values));
+                if row.as_ref() == logged.get(&text).and_then(|(op, _)| op.row.as_ref()) || row.as_ref() != stored[&change.relation].get(&text) {
+                    forgotten.push(json!([change.seq]));
+                    continue;
+                }
+                let op = Op {
+                    relation: change.relation.clone(),
+                    key: change.key,
+                    row,
+                    origin: self.origin.clone(),
+                    hlc: self.clock.at(change.queued_ms),
+                };
+                let signed = SignedOp::sign(&op, &self.keys)?;
+                match serde_json::to_string(&signed) {
+                    Ok(body) if body.len() <= MAX_MESSAGE_SIZE => filled.push(json!([change.seq, body])),
+                    _ => {
+                        debug!("An op on {} is too large to gossip; peers get it when they sync", change.relation);
+                        forgotten.push(json!([change.seq]));
+                    }
+                }
+                logged.insert(text.clone(), (op.clone(), signed.clone()));
+                stamped.insert((change.relation, text), (op, signed));
+            }
+            let ops: Vec<&(Op, SignedOp)> = stamped.values().collect();
+            let queued = filled.len();
+            let mut batch = Batch::default();
+            oplog::record(&mut batch, &ops);
+            outbox::stamp(&mut batch, filled);
+            outbox::forget(&mut batch, forgotten);
+            batch.run(&self.core)?;
+            self.outbox.queued(queued);
+            for (relation, _) in stamped.into_keys() {
+                self.changed(&relation);
+            }
+        }
+    }
+
+    fn changed(&mut self, relation: &str) {
+        if let Some(replicated) = self.relations.get_mut(relation) {
+            replicated.digest = None;
+        }
+    }
+
+    fn message(&mut self, message: MeshMessage) {
+        if message.topic == digest_topic(&self.namespace) {
+            match serde_json::from_slice(&message.data) {
+                Ok(digest) => self.compare(message.source, digest),
+                Err(e) => debug!("Ignored a malformed digest from {}: {}", message.source, e),
+            }
+            return;
+        }
+        let prefix = op_topic(&self.namespace, "");
+        let Some(relation) = message.topic.strip_prefix(&prefix).map(str::to_string) else { return };
+        match serde_json::from_slice::<SignedOp>(&message.data) {
+            Ok(signed) => self.apply(&relation, vec![signed]),
+            Err(e) => debug!("Ignored a malformed op from {}: {}", message.source, e),
+        }
+    }
+
+    /// Checks remote ops on `relation` and applies the ones newer than what
+    /// the log holds for their keys.
+    fn apply(&mut self, relation: &str, ops: Vec<SignedOp>) {
+        let Some(replicated) = self.relations.get(relation) else { return };
+        let newest = wall_ms() + MAX_CLOCK_AHEAD_MS;
+        let mut checked = Vec::new();
+        for signed in ops {
+            let op = match signed.verify() {
+                Ok(op) => op,
+                Err(e) => {
+                    warn!("Rejected an op on {}: {}", relation, e);
+                    continue;
+                }
+            };
+            let fits = if op.relation == relation {
+                replicated.table.check(&op)
+            } else {
+                Err(format!("it is for {}", op.relation))
+            };
+            if let Err(e) = fits {
+                warn!("Rejected an op on {} from {}: {}", relation, op.origin, e);
+            } else if op.hlc.ms > newest {
+                warn!("Rejected an op on {} from {}: its clock is too far ahead", relation, op.origin);
+            } else {
+                checked.push((op, signed));
+            }
+        }
+        if checked.is_empty() {
+            return;
+        }
+        // Local changes compete with them as of when they were committed.
+        self.local_changes();
+        if self.unstamped {
+            warn!("Left {} op(s) on {} for a later sync, as local changes are not stamped yet", checked.len(), relation);
+            return;
+        }
+        let hlcs: Vec<Hlc> = checked.iter().map(|(op, _)| op.hlc).collect();
+        if let Err(e) = self.write_remote(relation, &checked) {
+            warn!("Could not apply {} op(s) on {}: {}", checked.len(), relation, e);
+        }
+        for hlc in hlcs {
+            self.clock.observe(hlc);
+        }
+        self.changed(relation);
+    }
+
+    /// Applies the `ops` newer than what is logged for their keys,
+    /// atomically. Their writes are captured too, and forgotten once seen to
+    /// match the log.
+    fn write_remote(&mut self, relation: &str, ops: &[(Op, SignedOp)]) -> anyhow::Result<()> {
+        let table = &self.relations[relation].table;
+        let texts: Vec<String> = ops.iter().map(|(op, _)| oplog::key_text(&op.key)).collect();
+        let mut logged = oplog::entries(&self.core, relation, &texts)?;
+        let mut remote = HashSet::new();
+        for (op, signed) in ops {
+            let text = oplog::key_text(&op.key);
+            if logged.get(&text).is_none_or(|(current, _)| op.version() > current.version()) {
+                logged.insert(text.clone(), (op.clone(), signed.clone()));
+                remote.insert(text);
+            }
+        }
+        if remote.is_empty() {
+            return Ok(());
+        }
+
+        let mut puts = Vec::new();
+        let mut removes = Vec::new();
+        for text in &remote {
+            match &logged[text].0.row {
+                Some(row) => puts.push(row),
+                None => removes.push(logged[text].0.key.as_slice()),
+            }
+        }
+        // One script rather than a `CoreTransaction`, which would fail
+        // clients' `begin` while open. A local write committed since the
+        // changes were stamped is overwritten without being logged, so it
+        // loses to the remote op on every node.
+        let mut batch = Batch::default();
+        table.write(&mut batch, &puts, &removes);
+        let changed: Vec<&(Op, SignedOp)> = remote.iter().map(|text| &logged[text]).collect();
+        oplog::record(&mut batch, &changed);
+        batch.run(&self.core)?;
+        debug!("Applied {} remote op(s) on {}", remote.len(), relation);
+        Ok(())
+    }
+
+    fn publish_digests(&mut self) {
+        let topic = digest_topic(&self.namespace);
+        let names: Vec<String> = self.relations.keys().cloned().collect();
+        for name in names {
+            let digest = match self.digest(&name) {
+                Ok(digest) => digest,
+                Err(e) => {
+                    debug!("Could not digest {}: {}", name, e);
+                    continue;
+                }
+            };
+            if let Ok(data) = serde_json::to_vec(&digest) {
+                let _ = self.mesh.blocking_send(MeshCommand::Publish { topic: topic.clone(), data });
+            }
+        }
+    }
+
+    fn digest(&mut self, relation: &str) -> Result<Digest, CoreError> {
+        if let Some(digest) = &self.relations[relation].digest {
+            return Ok(digest.clone());
+        }
+        let digest = oplog::digest(&self.core, relation)?;
+        if let Some(replicated) = self.relations.get_mut(relation) {
+            replicated.digest = Some(digest.clone());
+        }
+        Ok(digest)
+    }
+
+    /// Fetches the parts of the relation in which `peer`'s digest differs.
+    fn compare(&mut self, peer: String, theirs: Digest) {
+        if !self.relations.contains_key(&theirs.relation) || self.syncing.contains(&(peer.clone(), theirs.relation.clone())) {
+            return;
+        }
+        let ours = match self.digest(&theirs.relation) {
+            Ok(digest) => digest,
+            Err(e) => {
+                debug!("Could not digest {}: {}", theirs.relation, e);
+                return;
+            }
+        };
+        let buckets = ours.differences(&theirs);
+        if buckets.is_empty() {
+            return;
+        }
+        info!(
+            "{} differs from {} in {} of {} digest buckets; syncing",
+            theirs.relation,
+            peer,
+            buckets.len(),
+            oplog::BUCKETS
+        );
+        let request = SyncRequest {
+            namespace: self.namespace.clone(),
+            relation: theirs.relation,
+            buckets,
+            after: None,
+        };
+        self.request(peer, request);
+    }
+
+    /// Sends a page of a sync; the sync lasts until its last page is in.
+    fn request(&mut self, peer: String, request: SyncRequest) {
+        let sync = (peer.clone(), request.relation.clone());
+        let Ok(data) = serde_json::to_vec(&request) else { return };
+        let (reply, answer) = oneshot::channel();
+        if self.mesh.blocking_send(MeshCommand::Request { peer: peer.clone(), data, reply }).is_err() {
+            self.syncing.remove(&sync);
+            return;
+        }
+        self.syncing.insert(sync);
+        let inbox = self.inbox.clone();
+        self.runtime.spawn(async move {
+            let answer = answer.await.unwrap_or_else(|_| Err(anyhow::anyhow!("the mesh stopped")));
+            let _ = inbox.send(Input::Synced { peer, request, answer });
+        });
+    }
+
+    fn synced(&mut self, peer: String, request: SyncRequest, answer: anyhow::Result<Vec<u8>>) {
+        let response = answer.and_then(|data| Ok(serde_json::from_slice::<SyncResponse>(&data)?));
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Syncing {} from {} failed: {}", request.relation, peer, e);
+                self.syncing.remove(&(peer, request.relation));
+                return;
+            }
+        };
+        self.apply(&request.relation, response.ops);
+        match response.next {
+            Some(next) => self.request(peer, SyncRequest { after: Some(next), ..request }),
+            None => {
+                self.syncing.remove(&(peer, request.relation));
+            }
+        }
+    }
+
+    fn serve(&mut self, request: MeshRequest) {
+        let response = match serde_json::from_slice::<SyncRequest>(&request.data) {
+            Ok(sync) if sync.namespace == self.namespace && self.relations.contains_key(&sync.relation) => {
+                match oplog::page(&self.core, &sync.relation, &sync.buckets, sync.after.as_ref(), SYNC_PAGE_BYTES) {
+                    Ok((ops, next)) => SyncResponse { ops, next },
+                    Err(e) => {
+                        warn!("Could not serve {} to {}: {}", sync.relation, request.peer, e);
+                        SyncResponse::default()
+                    }
+                }
+            }
+            Ok(sync) => {
+                debug!("{} asked for {}, which is not replicated here", request.peer, sync.relation);
+                SyncResponse::default()
+            }
+            Err(e) => {
+                debug!("Ignored a malformed request from {}: {}", request.peer, e);
+                return;
+            }
+        };
+        if let Ok(data) = serde_json::to_vec(&response) {
+            let _ = request.reply.send(data);
+        }
+    }
+}
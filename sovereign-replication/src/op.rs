@@ -0,0 +1,109 @@
+use crate::hlc::Hlc;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sovereign_mesh::identity::{Keypair, PublicKey};
+
+/// A write or deletion of one row of a replicated relation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Op {
+    pub relation: String,
+    /// The key columns' values, in key order.
+    pub key: Vec<Value>,
+    /// Every column by name; `None` deletes the row.
+    pub row: Option<Map<String, Value>>,
+    /// Peer id of the node that made the change.
+    pub origin: String,
+    pub hlc: Hlc,
+}
+
+impl Op {
+    /// Of two ops on a row, the one with the greater version wins.
+    pub fn version(&self) -> (Hlc, &str) {
+        (self.hlc, &self.origin)
+    }
+}
+
+/// An `Op` as it travels, signed with its origin's mesh identity so it can
+/// be relayed by any node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedOp {
+    /// The `Op` as JSON; the signature covers these bytes.
+    pub body: String,
+    /// The origin's public key, protobuf-encoded, in base64.
+    pub public_key: String,
+    pub signature: String,
+}
+
+impl SignedOp {
+    pub(crate) fn sign(op: &Op, keys: &Keypair) -> anyhow::Result<Self> {
+        let body = serde_json::to_string(op)?;
+        let signature = keys.sign(body.as_bytes())?;
+        Ok(Self {
+            public_key: STANDARD.encode(keys.public().encode_protobuf()),
+            signature: STANDARD.encode(signature),
+            body,
+        })
+    }
+
+    /// The op, if it was signed by the origin it names.
+    pub(crate) fn verify(&self) -> anyhow::Result<Op> {
+        let op: Op = serde_json::from_str(&self.body)?;
+        let public_key = PublicKey::try_decode_protobuf(&STANDARD.decode(&self.public_key)?)?;
+        if public_key.to_peer_id().to_string() != op.origin {
+            anyhow::bail!("signed by {}, not its origin {}", public_key.to_peer_id(), op.origin);
+        }
+        if !public_key.verify(self.body.as_bytes(), &STANDARD.decode(&self.signature)?) {
+            anyhow::bail!("bad signature from {}", op.origin);
+        }
+        Ok(op)
+    }
+
+    /// The op of a signed op this node already checked.
+    pub(crate) fn op(&self) -> anyhow::Result<Op> {
+        Ok(serde_json::from_str(&self.body)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn op(keys: &Keypair, ms: u64) -> Op {
+        Op {
+            relation: "notes".into(),
+            key: vec![json!(1)],
+            row: Some(json!({ "id": 1, "body": "hello" }).as_object().unwrap().clone()),
+            origin: keys.public().to_peer_id().to_string(),
+            hlc: Hlc { ms, counter: 0 },
+        }
+    }
+
+    #[test]
+    fn verifies_only_ops_signed_by_their_origin() {
+        let keys = Keypair::generate_ed25519();
+        let signed = SignedOp::sign(&op(&keys, 1), &keys).unwrap();
+        assert_eq!(signed.verify().unwrap().row, op(&keys, 1).row);
+
+        let mut tampered = signed.clone();
+        tampered.body = tampered.body.replace("hello", "goodbye");
+        assert!(tampered.verify().unwrap_err().to_string().contains("bad signature"));
+
+        // Signed by one node in another's name.
+        let other = Keypair::generate_ed25519();
+        let forged = SignedOp::sign(&op(&keys, 1), &other).unwrap();
+        assert!(forged.verify().unwrap_err().to_string().contains("not its origin"));
+    }
+
+    #[test]
+    fn the_later_clock_then_the_greater_origin_wins() {
+        let keys = Keypair::generate_ed25519();
+        let (early, late) = (op(&keys, 1), op(&keys, 2));
+        assert!(late.version() > early.version());
+        let mut tied = early.clone();
+        tied.origin.push('~');
+        assert!(tied.version() > early.version());
+    }
+}
@@ -0,0 +1,290 @@
+use crate::hlc::Hlc;
+use crate::op::{Op, SignedOp};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sha2::{Digest as _, Sha256};
+use sovereign_core::{CognitiveCore, CoreError};
+use std::collections::HashMap;
+
+/// The compacted op log: the latest op on each key of each replicated
+/// relation, deletions included, which is what peers are synced from.
+pub(crate) const LOG: &str = "sovereign_replica_log";
+
+/// Digests split each relation's keys this many ways, so a sync fetches
+/// only the parts that differ.
+pub(crate) const BUCKETS: u8 = 16;
+
+/// Statements run as one script, so they commit together or not at all.
+#[derive(Default)]
+pub(crate) struct Batch {
+    statements: Vec<String>,
+    params: Map<String, Value>,
+}
+
+impl Batch {
+    /// Adds `statement`, which takes its rows from `$rows`; skipped if
+    /// there are none.
+    pub(crate) fn push(&mut self, statement: &str, rows: Vec<Value>) {
+        if rows.is_empty() {
+            return;
+        }
+        let name = format!("rows{}", self.statements.len());
+        self.statements.push(format!("{{ {} }}", statement.replace("$rows", &format!("${}", name))));
+        self.params.insert(name, Value::Array(rows));
+    }
+
+    pub(crate) fn run(self, core: &CognitiveCore) -> Result<(), CoreError> {
+        if !self.statements.is_empty() {
+            core.run(&self.statements.join("\n"), Value::Object(self.params))?;
+        }
+        Ok(())
+    }
+}
+
+/// A replicated relation's columns.
+#[derive(Clone)]
+pub(crate) struct Table {
+    pub name: String,
+    /// Every column, keys first.
+    pub columns: Vec<String>,
+    pub keys: usize,
+}
+
+impl Table {
+    /// The stored rows with the given keys, by `key_text`.
+    pub(crate) fn rows(&self, core: &CognitiveCore, keys: &[Vec<Value>]) -> Result<HashMap<String, Map<String, Value>>, CoreError> {
+        if keys.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let script = format!(
+            "keys[{keys}] <- $keys\n?[{columns}] := keys[{keys}], *{}{{{columns}}}",
+            self.name,
+            keys = self.columns[..self.keys].join(", "),
+            columns = self.columns.join(", ")
+        );
+        let result = core.run(&script, json!({ "keys": keys }))?;
+        Ok(rows(&result)
+            .into_iter()
+            .map(|row| (key_text(&row[..self.keys]), self.row_of(row)))
+            .collect())
+    }
+
+    /// Adds writing `puts` and deleting the rows with keys `removes`.
+    pub(crate) fn write(&self, batch: &mut Batch, puts: &[&Map<String, Value>], removes: &[&[Value]]) {
+        let keys = self.columns[..self.keys].join(", ");
+        let spec = if self.columns.len() > self.keys {
+            format!("{} => {}", keys, self.columns[self.keys..].join(", "))
+        } else {
+            keys.clone()
+        };
+        let rows = puts.iter().map(|row| Value::from(self.values(row))).collect();
+        batch.push(&format!("?[{}] <- $rows :put {} {{{}}}", self.columns.join(", "), self.name, spec), rows);
+        let keys_removed = removes.iter().map(|key| Value::from(key.to_vec())).collect();
+        batch.push(&format!("?[{keys}] <- $rows :rm {} {{{keys}}}", self.name, keys = keys), keys_removed);
+    }
+
+    /// A full row's values, in column order.
+    pub(crate) fn values(&self, row: &Map<String, Value>) -> Vec<Value> {
+        self.columns.iter().map(|c| row.get(c).cloned().unwrap_or(Value::Null)).collect()
+    }
+
+    /// The full row with these values, in column order.
+    pub(crate) fn row_of(&self, values: Vec<Value>) -> Map<String, Value> {
+        self.columns.iter().cloned().zip(values).collect()
+    }
+
+    /// The key columns' values of a full row.
+    pub(crate) fn key_of(&self, row: &Map<String, Value>) -> Vec<Value> {
+        self.columns[..self.keys].iter().map(|c| row.get(c).cloned().unwrap_or(Value::Null)).collect()
+    }
+
+    /// Why `op` cannot be applied to this relation, if it cannot.
+    pub(crate) fn check(&self, op: &Op) -> Result<(), String> {
+        if op.key.len() != self.keys {
+            return Err(format!("expected {} key values, got {}", self.keys, op.key.len()));
+        }
+        if let Some(row) = &op.row {
+            if row.len() != self.columns.len() || self.columns.iter().any(|c| !row.contains_key(c)) {
+                return Err(format!("expected the columns {}", self.columns.join(", ")));
+            }
+            if self.key_of(row) != op.key {
+                return Err("the row does not have the op's key".into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The text a key is logged under.
+pub(crate) fn key_text(key: &[Value]) -> String {
+    Value::from(key.to_vec()).to_string()
+}
+
+fn bucket(key: &str) -> u8 {
+    Sha256::digest(key.as_bytes())[0] % BUCKETS
+}
+
+pub(crate) fn create(core: &CognitiveCore) -> Result<(), CoreError> {
+    let listed = core.run("::relations", Value::Null)?;
+    if rows(&listed).iter().any(|row| row[0] == LOG) {
+        return Ok(());
+    }
+    let script = format!(
+        ":create {} {{relation: String, key: String => bucket: Int, hlc_ms: Int, hlc_counter: Int, origin: String, op: String}}",
+        LOG
+    );
+    core.run(&script, Value::Null)?;
+    Ok(())
+}
+
+/// The logged ops on the given keys of `relation`, by key text.
+pub(crate) fn entries(core: &CognitiveCore, relation: &str, keys: &[String]) -> Result<HashMap<String, (Op, SignedOp)>, CoreError> {
+    if keys.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let script = format!("keys[relation, key] <- $keys\n?[key, op] := keys[relation, key], *{}{{relation, key, op}}", LOG);
+    let keys: Vec<[&str; 2]> = keys.iter().map(|key| [relation, key.as_str()]).collect();
+    let result = core.run(&script, json!({ "keys": keys }))?;
+    Ok(rows(&result)
+        .into_iter()
+        .filter_map(|row| {
+            let signed: SignedOp = serde_json::from_str(row[1].as_str()?).ok()?;
+            let op = signed.op().ok()?;
+            Some((row[0].as_str()?.to_string(), (op, signed)))
+        })
+        .collect())
+}
+
+/// Adds logging `ops`, each replacing what was logged for its key.
+pub(crate) fn record(batch: &mut Batch, ops: &[&(Op, SignedOp)]) {
+    let rows = ops
+        .iter()
+        .map(|(op, signed)| {
+            let key = key_text(&op.key);
+            json!([op.relation, key, bucket(&key), op.hlc.ms, op.hlc.counter, op.origin, serde_json::to_string(signed).unwrap_or_default()])
+        })
+        .collect();
+    let statement = format!(
+        "?[relation, key, bucket, hlc_ms, hlc_counter, origin, op] <- $rows :put {} {{relation, key => bucket, hlc_ms, hlc_counter, origin, op}}",
+        LOG
+    );
+    batch.push(&statement, rows);
+}
+
+/// The latest clock reading logged, to carry the clock over a restart.
+pub(crate) fn latest(core: &CognitiveCore) -> Result<Hlc, CoreError> {
+    let script = format!("?[hlc_ms, hlc_counter] := *{}{{hlc_ms, hlc_counter}} :order -hlc_ms, -hlc_counter :limit 1", LOG);
+    let result = core.run(&script, Value::Null)?;
+    Ok(rows(&result)
+        .first()
+        .map(|row| Hlc {
+            ms: row[0].as_u64().unwrap_or_default(),
+            counter: row[1].as_u64().unwrap_or_default() as u32,
+        })
+        .unwrap_or_default())
+}
+
+/// The keys of `relation` that have a logged op.
+pub(crate) fn keys(core: &CognitiveCore, relation: &str) -> Result<Vec<Vec<Value>>, CoreError> {
+    let script = format!("?[key] := *{}{{relation, key}}, relation = $relation", LOG);
+    let result = core.run(&script, json!({ "relation": relation }))?;
+    Ok(rows(&result)
+        .iter()
+        .filter_map(|row| serde_json::from_str(row[0].as_str()?).ok())
+        .collect())
+}
+
+/// Where a page of logged ops ends: the bucket and key of its last op.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Cursor {
+    pub bucket: u8,
+    pub key: String,
+}
+
+/// Logged ops of `relation` in `buckets`, in bucket and key order from
+/// just after `after`, until about `budget` bytes. The cursor is set if
+/// there are more.
+pub(crate) fn page(
+    core: &CognitiveCore,
+    relation: &str,
+    buckets: &[u8],
+    after: Option<&Cursor>,
+    budget: usize,
+) -> Result<(Vec<SignedOp>, Option<Cursor>), CoreError> {
+    const PAGE_ROWS: usize = 500;
+    let script = format!(
+        "?[bucket, key, op] := *{}{{relation, key, bucket, op}}, relation = $relation, is_in(bucket, $buckets), \
+         bucket > $bucket || (bucket == $bucket && key > $key)\n:order bucket, key\n:limit {}",
+        LOG, PAGE_ROWS
+    );
+    let params = json!({
+        "relation": relation,
+        "buckets": buckets,
+        "bucket": after.map_or(-1, |c| c.bucket as i64),
+        "key": after.map_or("", |c| c.key.as_str()),
+    });
+    let rows = rows(&core.run(&script, params)?);
+    let full = rows.len() == PAGE_ROWS;
+    let mut ops = Vec::new();
+    let mut size = 0;
+    let mut last = None;
+    for row in &rows {
+        let Some(text) = row[2].as_str() else { continue };
+        if size > 0 && size + text.len() > budget {
+            break;
+        }
+        if let Ok(op) = serde_json::from_str(text) {
+            size += text.len();
+            ops.push(op);
+        }
+        last = Some(Cursor {
+            bucket: row[0].as_u64().unwrap_or_default() as u8,
+            key: row[1].as_str().unwrap_or_default().to_string(),
+        });
+    }
+    let more = full || ops.len() < rows.len();
+    Ok((ops, last.filter(|_| more)))
+}
+
+/// A relation's log, hashed per bucket. Nodes whose digests match hold
+/// the same latest op on every key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Digest {
+    pub relation: String,
+    /// Hex SHA-256 per bucket, over its keys' versions in key order.
+    pub buckets: Vec<String>,
+}
+
+impl Digest {
+    /// The buckets in which the two digests differ.
+    pub(crate) fn differences(&self, other: &Digest) -> Vec<u8> {
+        (0..BUCKETS)
+            .filter(|&b| self.buckets.get(b as usize) != other.buckets.get(b as usize))
+            .collect()
+    }
+}
+
+pub(crate) fn digest(core: &CognitiveCore, relation: &str) -> Result<Digest, CoreError> {
+    let script = format!(
+        "?[bucket, key, hlc_ms, hlc_counter, origin] := *{}{{relation, key, bucket, hlc_ms, hlc_counter, origin}}, \
+         relation = $relation\n:order bucket, key",
+        LOG
+    );
+    let result = core.run(&script, json!({ "relation": relation }))?;
+    let mut hashers: Vec<Sha256> = (0..BUCKETS).map(|_| Sha256::new()).collect();
+    for row in rows(&result) {
+        let Some(hasher) = row[0].as_u64().and_then(|b| hashers.get_mut(b as usize)) else { continue };
+        for value in &row[1..] {
+            hasher.update(value.to_string().as_bytes());
+            hasher.update(b"\n");
+        }
+    }
+    Ok(Digest {
+        relation: relation.to_string(),
+        buckets: hashers.into_iter().map(|h| hex::encode(h.finalize())).collect(),
+    })
+}
+
+fn rows(result: &Value) -> Vec<Vec<Value>> {
+    serde_json::from_value(result["rows"].clone()).unwrap_or_default()
+}
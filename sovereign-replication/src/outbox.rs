@@ -0,0 +1,382 @@
+use crate::hlc::wall_ms;
+use crate::oplog::{Batch, Table};
+use serde_json::{json, Value};
+use sovereign_core::{CognitiveCore, CoreError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Local changes waiting to be gossiped, and the ones gossiped lately.
+/// Triggers on the replicated relations queue each change in the
+/// transaction that commits it, with no op until replication stamps it.
+pub(crate) const OUTBOX: &str = "sovereign_replica_outbox";
+
+const COLUMNS: &str = "seq, relation, topic, op, queued_ms, published_ms, attempts, error";
+
+/// Captured changes read at a time.
+const CAPTURED_BATCH: usize = 256;
+
+/// A local op queued for gossip.
+#[derive(Debug, Clone)]
+pub struct OutboxEntry {
+    /// Rises with every op queued, so entries go out in the order their
+    /// ops were made.
+    pub seq: u64,
+    pub relation: String,
+    pub topic: String,
+    /// The `SignedOp` as JSON, as gossiped.
+    pub op: String,
+    /// When the change was committed.
+    pub queued_ms: u64,
+    /// Unset until gossipsub takes the op.
+    pub published_ms: Option<u64>,
+    /// Tries at gossiping it, the one that worked included.
+    pub attempts: u32,
+    /// Why the last try failed, until one works.
+    pub error: Option<String>,
+}
+
+/// The outbox of a replicating node. Replication only queues ops here;
+/// gossiping them is up to whoever holds this, in `seq` order per
+/// relation, marking each `published` once gossipsub takes it. Entries
+/// are listed once replication has stamped them.
+///
+/// Nothing queued is dropped: an outbox at `high_water` entries or more is
+/// for the node to report, not to trim. Methods block on the core.
+pub struct Outbox {
+    core: Arc<CognitiveCore>,
+    high_water: u64,
+    depth: AtomicU64,
+    published: AtomicU64,
+    failures: AtomicU64,
+    /// Woken as entries are queued or requeued.
+    queued: Notify,
+}
+
+impl Outbox {
+    pub(crate) fn open(core: Arc<CognitiveCore>, high_water: u64) -> anyhow::Result<Self> {
+        let listed = core.run("::relations", Value::Null)?;
+        if !rows(&listed).iter().any(|row| row[0] == OUTBOX) {
+            let script = format!(
+                ":create {} {{seq: Int => relation: String, topic: String, key: Any, row: Any?, op: String?, queued_ms: Int, published_ms: Int?, attempts: Int, error: String?}}",
+                OUTBOX
+            );
+            core.run(&script, Value::Null)?;
+        }
+        let script = format!("?[count(seq)] := *{}{{seq, op, published_ms}}, !is_null(op), is_null(published_ms)", OUTBOX);
+        let depth = first_u64(&core.run(&script, Value::Null)?);
+        Ok(Self {
+            core,
+            high_water,
+            depth: AtomicU64::new(depth),
+            published: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            queued: Notify::new(),
+        })
+    }
+
+    /// Stamped entries not yet published.
+    pub fn depth(&self) -> u64 {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    pub fn high_water(&self) -> u64 {
+        self.high_water
+    }
+
+    /// Entries marked published since the node started.
+    pub fn published_total(&self) -> u64 {
+        self.published.load(Ordering::Relaxed)
+    }
+
+    /// Failed tries at gossiping since the node started.
+    pub fn failures_total(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Waits until entries are queued or requeued, or returns at once if
+    /// they were since the last wait.
+    pub async fn changed(&self) {
+        self.queued.notified().await
+    }
+
+    /// The oldest `limit` entries not yet published, in `seq` order.
+    pub fn pending(&self, limit: usize) -> anyhow::Result<Vec<OutboxEntry>> {
+        let script = format!(
+            "?[{columns}] := *{}{{{columns}}}, !is_null(op), is_null(published_ms)\n:order seq\n:limit {}",
+            OUTBOX,
+            limit,
+            columns = COLUMNS
+        );
+        Ok(entries(&self.core.run(&script, Value::Null)?))
+    }
+
+    /// Up to `limit` entries after `after`, in `seq` order; with
+    /// `published`, the ones gossiped lately too.
+    pub fn list(&self, after: Option<u64>, published: bool, limit: usize) -> anyhow::Result<Vec<OutboxEntry>> {
+        let script = format!(
+            "?[{columns}] := *{}{{{columns}}}, seq > $after, !is_null(op), $published || is_null(published_ms)\n:order seq\n:limit {}",
+            OUTBOX,
+            limit,
+            columns = COLUMNS
+        );
+        let params = json!({ "after": after.map_or(-1, |seq| seq as i64), "published": published });
+        Ok(entries(&self.core.run(&script, params)?))
+    }
+
+    /// Marks `seq` gossiped, on its `attempts`-th try.
+    pub fn published(&self, seq: u64, attempts: u32) -> anyhow::Result<()> {
+        let script = format!("?[seq, published_ms, attempts, error] <- [[$seq, $now, $attempts, null]] :update {} {{seq => published_ms, attempts, error}}", OUTBOX);
+        let params = json!({ "seq": seq, "now": wall_ms(), "attempts": attempts });
+        self.core.run(&script, params)?;
+        let _ = self.depth.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| Some(depth.saturating_sub(1)));
+        self.published.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Notes a failed try at gossiping `seq`; it stays pending.
+    pub fn failed(&self, seq: u64, attempts: u32, error: &str) -> anyhow::Result<()> {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        let script = format!("?[seq, attempts, error] <- [[$seq, $attempts, $error]] :update {} {{seq => attempts, error}}", OUTBOX);
+        let params = json!({ "seq": seq, "attempts": attempts, "error": error });
+        self.core.run(&script, params)?;
+        Ok(())
+    }
+
+    /// Makes the entries `seqs` pending again with no tries, so published
+    /// ones are gossiped once more and failing ones are retried at once.
+    /// How many of them there were.
+    pub fn requeue(&self, seqs: &[u64]) -> anyhow::Result<u64> {
+        let script = format!("seqs[seq] <- $seqs\n?[seq, published_ms] := seqs[seq], *{}{{seq, op, published_ms}}, !is_null(op)", OUTBOX);
+        let seqs: Vec<[u64; 1]> = seqs.iter().map(|&seq| [seq]).collect();
+        let found = rows(&self.core.run(&script, json!({ "seqs": seqs }))?);
+        if found.is_empty() {
+            return Ok(0);
+        }
+        let republished = found.iter().filter(|row| !row[1].is_null()).count() as u64;
+        let rows: Vec<Value> = found.iter().map(|row| json!([row[0], null, 0, null])).collect();
+        let script = format!("?[seq, published_ms, attempts, error] <- $rows :update {} {{seq => published_ms, attempts, error}}", OUTBOX);
+        self.core.run(&script, json!({ "rows": rows }))?;
+        self.depth.fetch_add(republished, Ordering::Relaxed);
+        self.queued.notify_one();
+        Ok(found.len() as u64)
+    }
+
+    /// Forgets entries published more than `retain` ago, but for the
+    /// latest one, which keeps `seq` rising over a restart.
+    pub fn prune(&self, retain: Duration) -> anyhow::Result<()> {
+        let script = format!(
+            "newest[max(seq)] := *{outbox}{{seq, published_ms}}, !is_null(published_ms)\n\
+             ?[seq] := *{outbox}{{seq, published_ms}}, !is_null(published_ms), published_ms < $before, newest[top], seq < top\n\
+             :rm {outbox} {{seq}}",
+            outbox = OUTBOX
+        );
+        let before = wall_ms().saturating_sub(retain.as_millis() as u64);
+        self.core.run(&script, json!({ "before": before }))?;
+        Ok(())
+    }
+
+    /// The oldest changes captured but not yet stamped, in `seq` order.
+    pub(crate) fn captured(&self) -> Result<Vec<Captured>, CoreError> {
+        let script = format!(
+            "?[seq, relation, key, row, queued_ms] := *{}{{seq, relation, key, row, op, queued_ms}}, is_null(op)\n:order seq\n:limit {}",
+            OUTBOX, CAPTURED_BATCH
+        );
+        Ok(rows(&self.core.run(&script, Value::Null)?)
+            .into_iter()
+            .map(|row| Captured {
+                seq: row[0].as_u64().unwrap_or_default(),
+                relation: row[1].as_str().unwrap_or_default().to_string(),
+                key: serde_json::from_value(row[2].clone()).unwrap_or_default(),
+                row: serde_json::from_value(row[3].clone()).unwrap_or_default(),
+                queued_ms: row[4].as_u64().unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    /// Captures `changes` to `table` as if committed now, each
+    /// `[key, row]` with a null row for a deletion.
+    pub(crate) fn capture(&self, table: &Table, topic: &str, changes: Vec<Value>) -> Result<(), CoreError> {
+        if !changes.is_empty() {
+            let script = capture_script(&table.name, topic, "changes[key, row] <- $changes");
+            self.core.run(&script, json!({ "changes": changes }))?;
+        }
+        Ok(())
+    }
+
+    /// Notes `count` entries just stamped, waking whoever gossips them.
+    pub(crate) fn queued(&self, count: usize) {
+        if count > 0 {
+            self.depth.fetch_add(count as u64, Ordering::Relaxed);
+            self.queued.notify_one();
+        }
+    }
+}
+
+/// A committed change to a replicated relation, not yet stamped.
+pub(crate) struct Captured {
+    pub seq: u64,
+    pub relation: String,
+    pub key: Vec<Value>,
+    /// Every column's value, in column order; `None` for a deletion.
+    pub row: Option<Vec<Value>>,
+    pub queued_ms: u64,
+}
+
+/// Adds setting the ops of captured entries, from `rows` of `[seq, op]`.
+pub(crate) fn stamp(batch: &mut Batch, rows: Vec<Value>) {
+    batch.push(&format!("?[seq, op] <- $rows :update {} {{seq => op}}", OUTBOX), rows);
+}
+
+/// Adds forgetting the captured entries `seqs`, which need no op.
+pub(crate) fn forget(batch: &mut Batch, seqs: Vec<Value>) {
+    batch.push(&format!("?[seq] <- $rows :rm {} {{seq}}", OUTBOX), seqs);
+}
+
+/// Sets the triggers that capture the changes to `table` into the outbox,
+/// keeping the relation's other triggers.
+pub(crate) fn set_capture(core: &CognitiveCore, table: &Table, topic: &str) -> Result<(), CoreError> {
+    let (mut clauses, _) = other_triggers(core, &table.name)?;
+    // Rules apply by position, so the columns get names no column can clash with.
+    let vars: Vec<String> = (0..table.columns.len()).map(|i| format!("c{}", i)).collect();
+    let (all, keys) = (vars.join(", "), vars[..table.keys].join(", "));
+    let put = format!("changes[key, row] := _new[{all}], not _old[{all}], key = [{keys}], row = [{all}]");
+    let rm = format!("changes[key, row] := _old[{all}], key = [{keys}], row = null");
+    clauses.push(format!("on put {{ {} }}", capture_script(&table.name, topic, &put)));
+    clauses.push(format!("on rm {{ {} }}", capture_script(&table.name, topic, &rm)));
+    core.run(&format!("::set_triggers {} {}", table.name, clauses.join(" ")), Value::Null)?;
+    Ok(())
+}
+
+/// Removes the capture triggers of `relation`, if it has them.
+pub(crate) fn release_capture(core: &CognitiveCore, relation: &str) -> Result<(), CoreError> {
+    let (clauses, captured) = other_triggers(core, relation)?;
+    if captured {
+        core.run(&format!("::set_triggers {} {}", relation, clauses.join(" ")), Value::Null)?;
+    }
+    Ok(())
+}
+
+/// The clauses setting `relation`'s triggers but the ones that write the
+/// outbox, and whether it has those.
+fn other_triggers(core: &CognitiveCore, relation: &str) -> Result<(Vec<String>, bool), CoreError> {
+    let shown = core.run(&format!("::show_triggers {}", relation), Value::Null)?;
+    let mut clauses = Vec::new();
+    let mut captured = false;
+    for row in rows(&shown) {
+        let (Some(kind), Some(trigger)) = (row[0].as_str(), row[2].as_str()) else { continue };
+        if trigger.contains(OUTBOX) {
+            captured = true;
+        } else {
+            clauses.push(format!("on {} {{ {} }}", kind, trigger));
+        }
+    }
+    Ok((clauses, captured))
+}
+
+/// A script queueing the rows of `changes[key, row]`, a rule it defines,
+/// after the outbox's last entry, in key order.
+fn capture_script(relation: &str, topic: &str, changes: &str) -> String {
+    format!(
+        "{changes}\n\
+         ranked[rank, key, row] <~ ReorderSort(changes[key, row], out: [key, row], sort_by: key, break_ties: true, take: {take})\n\
+         seqs[seq] := *{outbox}{{seq}}\n\
+         seqs[seq] := seq = 0\n\
+         last[max(seq)] := seqs[seq]\n\
+         ?[seq, relation, topic, key, row, op, queued_ms, published_ms, attempts, error] := ranked[rank, key, row], last[top], \
+         seq = top + rank, relation = {relation}, topic = {topic}, op = null, queued_ms = to_int(now() * 1000), \
+         published_ms = null, attempts = 0, error = null\n\
+         :put {outbox} {{seq => relation, topic, key, row, op, queued_ms, published_ms, attempts, error}}",
+        take = i64::MAX,
+        outbox = OUTBOX,
+        relation = Value::from(relation),
+        topic = Value::from(topic),
+    )
+}
+
+fn entries(result: &Value) -> Vec<OutboxEntry> {
+    rows(result)
+        .into_iter()
+        .map(|row| {
+            let text = |i: usize| row[i].as_str().unwrap_or_default().to_string();
+            OutboxEntry {
+                seq: row[0].as_u64().unwrap_or_default(),
+                relation: text(1),
+                topic: text(2),
+                op: text(3),
+                queued_ms: row[4].as_u64().unwrap_or_default(),
+                published_ms: row[5].as_u64(),
+                attempts: row[6].as_u64().unwrap_or_default() as u32,
+                error: row[7].as_str().map(str::to_string),
+            }
+        })
+        .collect()
+}
+
+fn first_u64(result: &Value) -> u64 {
+    rows(result).first().and_then(|row| row.first()).and_then(Value::as_u64).unwrap_or_default()
+}
+
+fn rows(result: &Value) -> Vec<Vec<Value>> {
+    serde_json::from_value(result["rows"].clone()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sovereign_core::CoreConfig;
+
+    fn outbox() -> (Arc<CognitiveCore>, Outbox, Table) {
+        let core = Arc::new(CognitiveCore::new(CoreConfig::default()).unwrap());
+        core.run(":create notes {id: Int => body: String}", Value::Null).unwrap();
+        core.run(":create seen {id: Int}", Value::Null).unwrap();
+        core.run("::set_triggers notes on put { ?[id] := _new[id, body] :put seen {id} }", Value::Null).unwrap();
+        let outbox = Outbox::open(core.clone(), 10).unwrap();
+        let table = Table {
+            name: "notes".into(),
+            columns: vec!["id".into(), "body".into()],
+            keys: 1,
+        };
+        set_capture(&core, &table, "test/replica/notes").unwrap();
+        (core, outbox, table)
+    }
+
+    fn changes(outbox: &Outbox) -> Vec<(u64, Vec<Value>, Option<Vec<Value>>)> {
+        outbox.captured().unwrap().into_iter().map(|change| (change.seq, change.key, change.row)).collect()
+    }
+
+    #[test]
+    fn changes_are_captured_as_their_transaction_commits() {
+        let (core, outbox, _) = outbox();
+        let began = wall_ms();
+        let mut tx = core.begin().unwrap();
+        tx.exec("?[id, body] <- [[2, 'b'], [1, 'a']] :put notes {id => body}", Value::Null).unwrap();
+        tx.exec("?[id] <- [[1], [9]] :rm notes {id}", Value::Null).unwrap();
+        assert!(changes(&outbox).is_empty());
+        tx.commit().unwrap();
+
+        let captured = outbox.captured().unwrap();
+        assert!(captured.iter().all(|change| change.relation == "notes" && change.queued_ms >= began));
+        assert_eq!(
+            changes(&outbox),
+            [(1, vec![json!(1)], Some(vec![json!(1), json!("a")])), (2, vec![json!(2)], Some(vec![json!(2), json!("b")])), (3, vec![json!(1)], None)]
+        );
+        // Rewriting a row unchanged captures nothing; the relation's own
+        // trigger still runs.
+        core.run("?[id, body] <- [[2, 'b']] :put notes {id => body}", Value::Null).unwrap();
+        assert_eq!(changes(&outbox).len(), 3);
+        assert_eq!(core.run("?[id] := *seen{id}", Value::Null).unwrap()["rows"], json!([[1], [2]]));
+        // Pending entries are not listed until stamped.
+        assert!(outbox.pending(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn releasing_keeps_the_relations_other_triggers() {
+        let (core, outbox, table) = outbox();
+        release_capture(&core, &table.name).unwrap();
+        core.run("?[id, body] <- [[3, 'c']] :put notes {id => body}", Value::Null).unwrap();
+        assert!(changes(&outbox).is_empty());
+        assert_eq!(core.run("?[id] := *seen{id}", Value::Null).unwrap()["rows"], json!([[3]]));
+    }
+}
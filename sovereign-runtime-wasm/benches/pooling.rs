@@ -0,0 +1,64 @@
+//! Throughput of a small registered module run back to back, with the
+//! pooling allocator and with the on-demand one.
+//!
+//! `cargo bench -p sovereign-runtime-wasm --features bench`
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use sovereign_runtime_wasm::{
+    AllocationStrategy, ModuleManifest, ModuleRegistry, PoolingConfig, RunOptions, RuntimeConfig, WasmRuntime,
+};
+use std::sync::Arc;
+
+/// Writes "hello\n" to stdout.
+const HELLO: &str = r#"(module
+  (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 1024) "hello\n")
+  (func (export "_start")
+    (i32.store (i32.const 0) (i32.const 1024))
+    (i32.store (i32.const 4) (i32.const 6))
+    (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))"#;
+
+fn registry(config: RuntimeConfig, dir: &tempfile::TempDir) -> (ModuleRegistry, AllocationStrategy) {
+    let runtime = Arc::new(WasmRuntime::with_config(config).unwrap());
+    let strategy = runtime.allocation_strategy();
+    let registry = ModuleRegistry::open(runtime, dir.path().to_path_buf()).unwrap();
+    registry.register("hello", HELLO.as_bytes().to_vec(), ModuleManifest::default()).unwrap();
+    (registry, strategy)
+}
+
+fn pooling(c: &mut Criterion) {
+    let tokio = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+    let pooled_dir = tempfile::tempdir().unwrap();
+    let on_demand_dir = tempfile::tempdir().unwrap();
+    let (pooled, strategy) = registry(
+        RuntimeConfig {
+            pooling: Some(PoolingConfig {
+                max_memory_bytes: 16 * 1024 * 1024,
+                ..Default::default()
+            }),
+            max_memory_bytes: 16 * 1024 * 1024,
+            ..Default::default()
+        },
+        &pooled_dir,
+    );
+    let (on_demand, _) = registry(RuntimeConfig::default(), &on_demand_dir);
+    if strategy != AllocationStrategy::Pooling {
+        eprintln!("the pool could not be reserved on this host; both runs are on-demand");
+    }
+
+    let mut group = c.benchmark_group("run_registered");
+    group.throughput(Throughput::Elements(1));
+    for (name, registry) in [("pooled", &pooled), ("on_demand", &on_demand)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let result = tokio.block_on(registry.run("hello", "", &RunOptions::default())).unwrap();
+                assert_eq!(result.stdout, "hello\n");
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pooling);
+criterion_main!(benches);
@@ -0,0 +1,19 @@
+//! A WASI preview-2 command for the runtime's tests. It writes its
+//! arguments, its stdin and the `GREETING` variable to stdout, a line on
+//! stderr, and exits with the status an `exit=<code>` argument names.
+
+use std::io::Read;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input).expect("reading stdin");
+    println!("args: {}", args.join(" "));
+    println!("stdin: {}", input);
+    println!("greeting: {}", std::env::var("GREETING").unwrap_or_default());
+    eprintln!("done");
+    let status = args.iter().find_map(|arg| arg.strip_prefix("exit=")?.parse().ok());
+    if let Some(status) = status {
+        std::process::exit(status);
+    }
+}
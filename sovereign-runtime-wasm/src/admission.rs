@@ -0,0 +1,215 @@
+use crate::WasmError;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Admission counters at one instant.
+#[derive(Debug, Clone, Default)]
+pub struct AdmissionStats {
+    pub running: usize,
+    /// Executions waiting for a slot.
+    pub queued: usize,
+    pub admitted: u64,
+    /// Refused with `Busy`, because the queue was full or the wait ran out.
+    pub rejected: u64,
+    /// Time admitted executions spent queued, in total and at most.
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+}
+
+/// Bounds how many executions run at once. Excess executions wait in FIFO
+/// order, up to a queue depth and a wait limit. A source (e.g. one IPC
+/// client) may be capped below the total so others still get slots.
+pub(crate) struct Admission {
+    slots: Arc<Semaphore>,
+    max_running: usize,
+    max_queued: usize,
+    max_per_source: usize,
+    sources: Mutex<HashMap<String, Arc<Semaphore>>>,
+    queued: AtomicUsize,
+    admitted: AtomicU64,
+    rejected: AtomicU64,
+    total_wait_us: AtomicU64,
+    max_wait_us: AtomicU64,
+}
+
+/// Holds an execution slot, and its source's slot, until dropped.
+pub(crate) struct Admitted {
+    _slot: OwnedSemaphorePermit,
+    _source: Option<OwnedSemaphorePermit>,
+}
+
+impl Admission {
+    /// `max_per_source` of 0 leaves sources uncapped.
+    pub fn new(max_running: usize, max_queued: usize, max_per_source: usize) -> Self {
+        let max_running = max_running.max(1);
+        Self {
+            slots: Arc::new(Semaphore::new(max_running)),
+            max_running,
+            max_queued,
+            max_per_source,
+            sources: Mutex::new(HashMap::new()),
+            queued: AtomicUsize::new(0),
+            admitted: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            total_wait_us: AtomicU64::new(0),
+            max_wait_us: AtomicU64::new(0),
+        }
+    }
+
+    /// Waits up to `max_wait` for a slot. An empty `source` is never capped.
+    pub async fn admit(&self, source: &str, max_wait: Duration) -> Result<Admitted, WasmError> {
+        let source_slots = self.source_slots(source);
+
+        // Free permits only exist while nobody is waiting, so this keeps FIFO order.
+        let source_permit = match &source_slots {
+            Some(slots) => slots.clone().try_acquire_owned().ok(),
+            None => None,
+        };
+        if source_slots.is_none() || source_permit.is_some() {
+            if let Ok(slot) = self.slots.clone().try_acquire_owned() {
+                self.admitted.fetch_add(1, Ordering::Relaxed);
+                return Ok(Admitted {
+                    _slot: slot,
+                    _source: source_permit,
+                });
+            }
+        }
+
+        let depth = self.queued.fetch_add(1, Ordering::SeqCst);
+        let _queued = Queued(&self.queued);
+        if depth >= self.max_queued {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(WasmError::Busy { queue_depth: depth });
+        }
+
+        let started = Instant::now();
+        let acquire = async {
+            let source = match (source_permit, &source_slots) {
+                (Some(permit), _) => Some(permit),
+                (None, Some(slots)) => {
+                    Some(slots.clone().acquire_owned().await.expect("source semaphore is never closed"))
+                }
+                (None, None) => None,
+            };
+            let slot = self.slots.clone().acquire_owned().await.expect("execution semaphore is never closed");
+            Admitted {
+                _slot: slot,
+                _source: source,
+            }
+        };
+        match tokio::time::timeout(max_wait, acquire).await {
+            Ok(admitted) => {
+                let waited = started.elapsed().as_micros() as u64;
+                self.admitted.fetch_add(1, Ordering::Relaxed);
+                self.total_wait_us.fetch_add(waited, Ordering::Relaxed);
+                self.max_wait_us.fetch_max(waited, Ordering::Relaxed);
+                Ok(admitted)
+            }
+            Err(_) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(WasmError::Busy {
+                    queue_depth: self.queued.load(Ordering::SeqCst) - 1,
+                })
+            }
+        }
+    }
+
+    pub fn stats(&self) -> AdmissionStats {
+        AdmissionStats {
+            running: self.max_running - self.slots.available_permits(),
+            queued: self.queued.load(Ordering::SeqCst),
+            admitted: self.admitted.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            total_wait: Duration::from_micros(self.total_wait_us.load(Ordering::Relaxed)),
+            max_wait: Duration::from_micros(self.max_wait_us.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn source_slots(&self, source: &str) -> Option<Arc<Semaphore>> {
+        if source.is_empty() || self.max_per_source == 0 {
+            return None;
+        }
+        let mut sources = self.sources.lock().unwrap_or_else(|e| e.into_inner());
+        // Sources nobody is running or waiting under only hold the map's reference.
+        sources.retain(|_, slots| Arc::strong_count(slots) > 1);
+        let slots = sources
+            .entry(source.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_per_source)));
+        Some(slots.clone())
+    }
+}
+
+/// Counts an execution as queued until dropped.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG: Duration = Duration::from_secs(10);
+
+    #[tokio::test]
+    async fn admits_waiters_in_arrival_order() {
+        let admission = Admission::new(1, 10, 0);
+        let held = admission.admit("", LONG).await.unwrap();
+        let order = Mutex::new(Vec::new());
+        let waiter = |id| {
+            let (admission, order) = (&admission, &order);
+            async move {
+                let _slot = admission.admit("", LONG).await.unwrap();
+                order.lock().unwrap().push(id);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        let release = async {
+            while admission.stats().queued < 3 {
+                tokio::task::yield_now().await;
+            }
+            drop(held);
+        };
+        tokio::join!(waiter(1), waiter(2), waiter(3), release);
+        assert_eq!(*order.lock().unwrap(), [1, 2, 3]);
+        let stats = admission.stats();
+        assert_eq!((stats.running, stats.queued, stats.admitted, stats.rejected), (0, 0, 4, 0));
+        assert!(stats.max_wait >= Duration::from_millis(5));
+    }
+
+    #[tokio::test]
+    async fn refuses_when_the_wait_runs_out_or_the_queue_is_full() {
+        let admission = Admission::new(1, 1, 0);
+        let _held = admission.admit("", LONG).await.unwrap();
+        assert!(matches!(admission.admit("", Duration::from_millis(20)).await, Err(WasmError::Busy { queue_depth: 0 })));
+
+        let queued = admission.admit("", LONG);
+        let refused = async {
+            tokio::task::yield_now().await;
+            admission.admit("", LONG).await
+        };
+        tokio::select! {
+            result = refused => assert!(matches!(result, Err(WasmError::Busy { queue_depth: 1 }))),
+            _ = queued => panic!("the queued execution was admitted while the slot was held"),
+        }
+        let stats = admission.stats();
+        assert_eq!((stats.running, stats.queued, stats.admitted, stats.rejected), (1, 0, 1, 2));
+    }
+
+    #[tokio::test]
+    async fn one_source_cannot_take_every_slot() {
+        let admission = Admission::new(2, 10, 1);
+        let _greedy = admission.admit("greedy", LONG).await.unwrap();
+        assert!(matches!(admission.admit("greedy", Duration::from_millis(20)).await, Err(WasmError::Busy { .. })));
+        let _other = admission.admit("other", Duration::ZERO).await.unwrap();
+        assert_eq!(admission.stats().running, 2);
+        // Unnamed callers are only held to the total.
+        assert!(matches!(admission.admit("", Duration::from_millis(20)).await, Err(WasmError::Busy { .. })));
+    }
+}
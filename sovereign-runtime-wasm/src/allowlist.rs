@@ -0,0 +1,258 @@
+use crate::registry::write_atomic;
+use crate::WasmError;
+use anyhow::Context;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+/// Where the allow-list lives and whether it is enforced.
+#[derive(Debug, Clone)]
+pub struct AllowlistConfig {
+    /// One hex SHA-256 per line; blank lines and `#` comments are ignored.
+    pub path: PathBuf,
+    pub mode: AllowlistMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllowlistMode {
+    /// Refuse modules that are not listed.
+    Enforce,
+    /// Development: run everything, warning about each unlisted module.
+    AllowAll,
+}
+
+/// SHA-256 hashes of the modules the runtime may execute.
+///
+/// The file is re-read whenever it changes on disk, so edits take effect on
+/// the next execution without a restart.
+pub struct Allowlist {
+    path: PathBuf,
+    mode: AllowlistMode,
+    state: RwLock<State>,
+}
+
+#[derive(Default)]
+struct State {
+    hashes: BTreeSet<String>,
+    /// Modification time and length of the file when it was last read.
+    stamp: Option<(SystemTime, u64)>,
+}
+
+impl Allowlist {
+    pub fn open(config: &AllowlistConfig) -> anyhow::Result<Self> {
+        let allowlist = Self {
+            path: config.path.clone(),
+            mode: config.mode,
+            state: RwLock::new(State::default()),
+        };
+        allowlist.reload()?;
+        let count = allowlist.list().len();
+        match allowlist.mode {
+            AllowlistMode::Enforce => {
+                log::info!("WASM allow-list enforced: {} module(s) listed in {}", count, allowlist.path.display())
+            }
+            AllowlistMode::AllowAll => log::warn!(
+                "WASM allow-list is in ALLOW-ALL mode: any module will run. Do not use this outside development ({} listed in {})",
+                count,
+                allowlist.path.display()
+            ),
+        }
+        Ok(allowlist)
+    }
+
+    pub fn mode(&self) -> AllowlistMode {
+        self.mode
+    }
+
+    /// Fails with `NotAllowed` if `sha256` is not listed and the list is enforced.
+    pub fn check(&self, sha256: &str) -> Result<(), WasmError> {
+        if let Err(e) = self.refresh() {
+            // Keep the last good list rather than opening up or locking out.
+            log::warn!("Failed to reload WASM allow-list {}: {:#}", self.path.display(), e);
+        }
+        if self.read().hashes.contains(&sha256.to_ascii_lowercase()) {
+            return Ok(());
+        }
+        match self.mode {
+            AllowlistMode::Enforce => Err(WasmError::NotAllowed { hash: sha256.to_string() }),
+            AllowlistMode::AllowAll => {
+                log::warn!("ALLOW-ALL: running WASM module {} which is not on the allow-list", sha256);
+                Ok(())
+            }
+        }
+    }
+
+    /// Listed hashes, sorted.
+    pub fn list(&self) -> Vec<String> {
+        self.read().hashes.iter().cloned().collect()
+    }
+
+    /// Returns whether the hash was newly added.
+    pub fn add(&self, sha256: &str) -> anyhow::Result<bool> {
+        let hash = parse_hash(sha256)?;
+        self.update(|hashes| hashes.insert(hash))
+    }
+
+    /// Returns whether the hash was listed.
+    pub fn remove(&self, sha256: &str) -> anyhow::Result<bool> {
+        let hash = parse_hash(sha256)?;
+        self.update(|hashes| hashes.remove(&hash))
+    }
+
+    /// Re-reads the file. A missing file is an empty list.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let state = self.read_file()?;
+        *self.write() = state;
+        Ok(())
+    }
+
+    fn refresh(&self) -> anyhow::Result<()> {
+        if self.stamp()? != self.read().stamp {
+            self.reload()?;
+        }
+        Ok(())
+    }
+
+    fn update(&self, change: impl FnOnce(&mut BTreeSet<String>) -> bool) -> anyhow::Result<bool> {
+        // Pick up edits made to the file since it was last read.
+        self.refresh()?;
+        let mut state = self.write();
+        if !change(&mut state.hashes) {
+            return Ok(false);
+        }
+        let mut contents = String::from("# SHA-256 hashes of WASM modules allowed to run\n");
+        for hash in &state.hashes {
+            contents.push_str(hash);
+            contents.push('\n');
+        }
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_atomic(&self.path, contents.as_bytes()).with_context(|| format!("writing {}", self.path.display()))?;
+        state.stamp = self.stamp()?;
+        Ok(true)
+    }
+
+    fn read_file(&self) -> anyhow::Result<State> {
+        let stamp = self.stamp()?;
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(State::default()),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", self.path.display())),
+        };
+        let mut hashes = BTreeSet::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            match parse_hash(line) {
+                Ok(hash) => {
+                    hashes.insert(hash);
+                }
+                Err(e) => log::warn!("{}:{}: {}", self.path.display(), number + 1, e),
+            }
+        }
+        Ok(State { hashes, stamp })
+    }
+
+    fn stamp(&self) -> anyhow::Result<Option<(SystemTime, u64)>> {
+        match fs::metadata(&self.path) {
+            Ok(meta) => Ok(Some((meta.modified()?, meta.len()))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("reading {}", self.path.display())),
+        }
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, State> {
+        self.state.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, State> {
+        self.state.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn parse_hash(hash: &str) -> anyhow::Result<String> {
+    anyhow::ensure!(
+        hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()),
+        "'{}' is not a hex SHA-256",
+        hash
+    );
+    Ok(hash.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures, ModuleManifest, ModuleRegistry, RunOptions, RuntimeConfig, WasmRuntime};
+    use sha2::{Digest, Sha256};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    fn runtime(path: &Path, mode: AllowlistMode) -> WasmRuntime {
+        WasmRuntime::with_config(RuntimeConfig {
+            allowlist: Some(AllowlistConfig {
+                path: path.to_path_buf(),
+                mode,
+            }),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn refuses_unlisted_modules_on_every_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let runtime = Arc::new(runtime(&dir.path().join("allowlist"), AllowlistMode::Enforce));
+        let bytes = fixtures::prints().into_bytes();
+        let hash = hex::encode(Sha256::digest(&bytes));
+
+        match runtime.run_module(&bytes, "", &RunOptions::default()).await {
+            Err(WasmError::NotAllowed { hash: refused }) => assert_eq!(refused, hash),
+            other => panic!("Expected NotAllowed, got {:?}", other.map(|r| r.stdout)),
+        }
+        let registry = ModuleRegistry::open(runtime.clone(), dir.path().join("modules")).unwrap();
+        registry.register("printer", bytes.clone(), ModuleManifest::default()).unwrap();
+        assert!(matches!(registry.run("printer", "", &RunOptions::default()).await, Err(WasmError::NotAllowed { .. })));
+
+        let allowlist = runtime.allowlist().unwrap();
+        assert!(allowlist.add(&hash.to_uppercase()).unwrap());
+        assert!(!allowlist.add(&hash).unwrap());
+        assert_eq!(allowlist.list(), vec![hash.clone()]);
+        assert_eq!(runtime.run_module(&bytes, "", &RunOptions::default()).await.unwrap().stdout, "to stdout\n");
+        assert!(registry.run("printer", "", &RunOptions::default()).await.is_ok());
+
+        assert!(allowlist.remove(&hash).unwrap());
+        assert!(matches!(runtime.run_module(&bytes, "", &RunOptions::default()).await, Err(WasmError::NotAllowed { .. })));
+        assert!(allowlist.add("not a hash").is_err());
+    }
+
+    #[tokio::test]
+    async fn allow_all_runs_unlisted_modules() {
+        let dir = tempfile::tempdir().unwrap();
+        let runtime = runtime(&dir.path().join("allowlist"), AllowlistMode::AllowAll);
+        assert_eq!(runtime.allowlist().unwrap().mode(), AllowlistMode::AllowAll);
+        let result = runtime.run_module(fixtures::prints().as_bytes(), "", &RunOptions::default()).await.unwrap();
+        assert_eq!(result.stdout, "to stdout\n");
+    }
+
+    #[tokio::test]
+    async fn picks_up_edits_to_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("allowlist");
+        let runtime = runtime(&path, AllowlistMode::Enforce);
+        let bytes = fixtures::prints().into_bytes();
+        let hash = hex::encode(Sha256::digest(&bytes));
+        assert!(runtime.run_module(&bytes, "", &RunOptions::default()).await.is_err());
+
+        fs::write(&path, format!("# reviewed\n{}  # printer\nnonsense\n\n", hash.to_uppercase())).unwrap();
+        assert!(runtime.run_module(&bytes, "", &RunOptions::default()).await.is_ok());
+        assert_eq!(runtime.allowlist().unwrap().list(), [hash]);
+
+        fs::write(&path, "").unwrap();
+        assert!(runtime.run_module(&bytes, "", &RunOptions::default()).await.is_err());
+    }
+}
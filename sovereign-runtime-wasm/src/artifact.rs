@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::hash::{Hash, Hasher};
+use wasmtime::Engine;
+
+/// The wasmtime release artifacts are compiled with. Informational: the
+/// `engine` tag is what actually decides compatibility.
+const WASMTIME_VERSION: &str = "39";
+
+/// What a precompiled artifact was built by and for. An artifact is only
+/// loaded when every tag matches the running engine, because one compiled on
+/// another machine or wasmtime release is not valid here.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArtifactTags {
+    pub wasmtime: String,
+    /// `<arch>-<os>`, e.g. `aarch64-linux`.
+    pub target: String,
+    /// Hash of the engine's target, compiler flags and enabled features.
+    pub engine: String,
+    /// SHA-256 of the module the artifact was compiled from.
+    pub sha256: String,
+}
+
+impl ArtifactTags {
+    /// Tags for `sha256` compiled by `engine`.
+    pub fn current(engine: &Engine, sha256: &str) -> Self {
+        let mut hasher = Sha256Hasher(Sha256::new());
+        engine.precompile_compatibility_hash().hash(&mut hasher);
+        Self {
+            wasmtime: WASMTIME_VERSION.to_string(),
+            target: format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+            engine: hex::encode(hasher.0.finalize()),
+            sha256: sha256.to_string(),
+        }
+    }
+}
+
+/// Feeds `Hash` output into SHA-256, which unlike `DefaultHasher` is stable
+/// across processes.
+struct Sha256Hasher(Sha256);
+
+impl Hasher for Sha256Hasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    /// Unused: the digest is read from the inner hasher instead.
+    fn finish(&self) -> u64 {
+        0
+    }
+}
@@ -0,0 +1,164 @@
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use crate::component::Compiled;
+use wasmtime::Engine;
+
+const CACHE_EXTENSION: &str = "cwasm";
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Compiled modules and components on disk, keyed by the SHA-256 of the module bytes.
+///
+/// Entries are written to a temp file and renamed into place, so concurrent
+/// runs of the same module either see a complete artifact or none at all.
+pub(crate) struct ModuleCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// Serialises eviction so two writers don't both delete the same entries.
+    evict_lock: Mutex<()>,
+}
+
+impl ModuleCache {
+    pub fn open(dir: PathBuf, max_bytes: u64) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir).with_context(|| format!("creating module cache {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            max_bytes,
+            evict_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn path_for(&self, bytes: &[u8]) -> PathBuf {
+        let digest = Sha256::digest(bytes);
+        self.dir.join(format!("{}.{}", hex::encode(digest), CACHE_EXTENSION))
+    }
+
+    /// Returns the module and whether it came from the cache.
+    pub fn load_or_compile(&self, engine: &Engine, bytes: &[u8]) -> anyhow::Result<(Compiled, bool)> {
+        let path = self.path_for(bytes);
+        if path.exists() {
+            // SAFETY: only this cache writes into `dir`, and only output of
+            // `Compiled::serialize` for these bytes. wasmtime still rejects
+            // artifacts from a different version or configuration, which we
+            // treat as a miss.
+            match unsafe { Compiled::deserialize_file(engine, bytes, &path) } {
+                Ok(module) => {
+                    touch(&path);
+                    return Ok((module, true));
+                }
+                Err(e) => {
+                    log::debug!("Discarding stale cache entry {}: {:#}", path.display(), e);
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+
+        let module = Compiled::new(engine, bytes)?;
+        if let Err(e) = self.store(&path, &module) {
+            log::warn!("Failed to cache compiled module {}: {:#}", path.display(), e);
+        }
+        Ok((module, false))
+    }
+
+    /// Compiles `bytes` into the cache if it is not already there.
+    pub fn precompile(&self, engine: &Engine, bytes: &[u8]) -> anyhow::Result<PathBuf> {
+        let path = self.path_for(bytes);
+        if path.exists() {
+            touch(&path);
+            return Ok(path);
+        }
+        let module = Compiled::new(engine, bytes)?;
+        self.store(&path, &module)?;
+        Ok(path)
+    }
+
+    fn store(&self, path: &Path, module: &Compiled) -> anyhow::Result<()> {
+        let serialized = module.serialize()?;
+        let tmp = path.with_extension(format!(
+            "{}.{}.tmp",
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp, &serialized)?;
+        if let Err(e) = fs::rename(&tmp, path) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+        self.evict(path);
+        Ok(())
+    }
+
+    /// Drops least recently used entries until the cache fits in `max_bytes`.
+    /// `keep` is the entry just written, which is never evicted.
+    fn evict(&self, keep: &Path) {
+        let _guard = self.evict_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let Ok(dir) = fs::read_dir(&self.dir) else { return };
+
+        let mut entries: Vec<(SystemTime, u64, PathBuf)> = dir
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == CACHE_EXTENSION))
+            .filter(|entry| entry.path() != keep)
+            .filter_map(|entry| {
+                let meta = entry.metadata().ok()?;
+                Some((meta.modified().ok()?, meta.len(), entry.path()))
+            })
+            .collect();
+        let kept = fs::metadata(keep).map(|meta| meta.len()).unwrap_or(0);
+        let mut total: u64 = kept + entries.iter().map(|(_, len, _)| len).sum::<u64>();
+        if total <= self.max_bytes {
+            return;
+        }
+
+        entries.sort_by_key(|(modified, _, _)| *modified);
+        for (_, len, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                total -= len;
+            }
+        }
+    }
+}
+
+/// Bumps the mtime so eviction sees the entry as recently used.
+fn touch(path: &Path) {
+    if let Ok(file) = fs::File::options().append(true).open(path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn evicts_the_least_recently_used_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = Engine::default();
+        let (a, b, c) = (fixtures::prints(), fixtures::spins(), fixtures::echo());
+        let unbounded = ModuleCache::open(dir.path().join("sizing"), u64::MAX).unwrap();
+        let entry_len = |wat: &str| fs::metadata(unbounded.precompile(&engine, wat.as_bytes()).unwrap()).unwrap().len();
+        let room = entry_len(&a) + entry_len(&b) + entry_len(&c) - 1;
+
+        let cache = ModuleCache::open(dir.path().join("cache"), room).unwrap();
+        let a_path = cache.precompile(&engine, a.as_bytes()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let b_path = cache.precompile(&engine, b.as_bytes()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        // Using `a` makes `b` the oldest.
+        assert!(cache.load_or_compile(&engine, a.as_bytes()).unwrap().1);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let c_path = cache.precompile(&engine, c.as_bytes()).unwrap();
+
+        assert!(a_path.exists());
+        assert!(!b_path.exists());
+        assert!(c_path.exists());
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{CallError, StoreState, WasmError};
+use std::path::Path;
+use wasmtime::component::{Component, Linker};
+use wasmtime::{Engine, Module, Store, Trap};
+
+/// Export name prefix of the `wasi:cli/run` interface that command components implement.
+const RUN_INTERFACE: &str = "wasi:cli/run@0.2";
+/// Interfaces whose results differ between hosts or runs.
+const NONDETERMINISTIC_INTERFACES: &[&str] = &["wasi:clocks/", "wasi:random/", "wasi:sockets/"];
+
+/// A compiled core module or preview-2 component.
+#[derive(Clone)]
+pub(crate) enum Compiled {
+    Module(Module),
+    Component(Component),
+}
+
+impl Compiled {
+    /// Compiles binary `bytes`, which may be either kind.
+    pub fn new(engine: &Engine, bytes: &[u8]) -> anyhow::Result<Self> {
+        if wasmparser::Parser::is_component(bytes) {
+            Component::new(engine, bytes).map(Compiled::Component)
+        } else {
+            Module::new(engine, bytes).map(Compiled::Module)
+        }
+    }
+
+    /// Loads an artifact written by `serialize` for the same kind of binary as `bytes`.
+    ///
+    /// # Safety
+    /// `path` must hold the output of `serialize`; see `Module::deserialize_file`.
+    pub unsafe fn deserialize_file(engine: &Engine, bytes: &[u8], path: &Path) -> anyhow::Result<Self> {
+        if wasmparser::Parser::is_component(bytes) {
+            unsafe { Component::deserialize_file(engine, path) }.map(Compiled::Component)
+        } else {
+            unsafe { Module::deserialize_file(engine, path) }.map(Compiled::Module)
+        }
+    }
+
+    pub fn serialize(&self) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Compiled::Module(module) => module.serialize(),
+            Compiled::Component(component) => component.serialize(),
+        }
+    }
+}
+
+/// Instantiates a command component and calls its `wasi:cli/run` export.
+/// Returns the exit code: `None` for success, 1 if `run` returned an error.
+pub(crate) async fn call_run(
+    store: &mut Store<StoreState>,
+    linker: &Linker<StoreState>,
+    component: &Component,
+) -> Result<Option<i32>, CallError> {
+    let engine = store.engine().clone();
+    let interface = component
+        .component_type()
+        .exports(&engine)
+        .map(|(name, _)| name)
+        .find(|name| name.starts_with(RUN_INTERFACE))
+        .map(str::to_string)
+        .ok_or_else(|| WasmError::MissingExport(format!("{}.x run", RUN_INTERFACE)))?;
+
+    let instance = match linker.instantiate_async(&mut *store, component).await {
+        Ok(instance) => instance,
+        Err(e) if e.downcast_ref::<Trap>().is_some() => return Err(CallError::Trap(e)),
+        Err(e) => return Err(WasmError::Instantiate(format!("{:#}", e)).into()),
+    };
+    let run = instance
+        .get_export_index(&mut *store, None, &interface)
+        .and_then(|index| instance.get_export_index(&mut *store, Some(&index), "run"))
+        .ok_or_else(|| WasmError::MissingExport(format!("{}#run", interface)))?;
+    let run = instance
+        .get_typed_func::<(), (Result<(), ()>,)>(&mut *store, &run)
+        .map_err(|e| WasmError::MissingExport(format!("{}#run: {:#}", interface, e)))?;
+
+    let (status,) = run.call_async(&mut *store, ()).await.map_err(CallError::Trap)?;
+    run.post_return_async(&mut *store).await.map_err(CallError::Trap)?;
+    Ok(status.err().map(|()| 1))
+}
+
+/// Rejects components importing clocks, randomness or sockets.
+pub(crate) fn check_deterministic_imports(engine: &Engine, component: &Component) -> Result<(), WasmError> {
+    for (name, _) in component.component_type().imports(engine) {
+        if NONDETERMINISTIC_INTERFACES.iter().any(|prefix| name.starts_with(prefix)) {
+            return Err(WasmError::ForbiddenImport {
+                module: name.to_string(),
+                name: String::new(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{fixtures, ExecutionLimits, RunOptions, WasmError, WasmRuntime};
+
+    async fn run(wat: &str, options: &RunOptions) -> Result<crate::ExecutionResult, WasmError> {
+        WasmRuntime::new().unwrap().run_module(wat.as_bytes(), "", options).await
+    }
+
+    #[tokio::test]
+    async fn runs_a_command_component() {
+        let ok = run(&fixtures::component("", "(i32.const 0)"), &RunOptions::default()).await.unwrap();
+        assert_eq!((ok.exit_code, ok.trapped), (None, false));
+        assert!(ok.fuel_used.is_some_and(|fuel| fuel > 0));
+
+        let failed = run(&fixtures::component("", "(i32.const 1)"), &RunOptions::default()).await.unwrap();
+        assert_eq!((failed.exit_code, failed.trapped), (Some(1), false));
+
+        let exited = run(&fixtures::component("", "(call $exit (i32.const 1)) (i32.const 0)"), &RunOptions::default()).await.unwrap();
+        assert_eq!((exited.exit_code, exited.trapped), (Some(1), false));
+
+        let trapped = run(&fixtures::component("", "unreachable"), &RunOptions::default()).await.unwrap();
+        assert!(trapped.trapped);
+        assert_eq!(trapped.trap.unwrap().code.as_deref(), Some("UnreachableCodeReached"));
+    }
+
+    #[tokio::test]
+    async fn components_are_held_to_the_same_limits() {
+        let options = RunOptions {
+            limits: ExecutionLimits {
+                fuel_limit: Some(50_000),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let spins = fixtures::component("", "(loop $forever (br $forever)) (i32.const 0)");
+        assert!(matches!(run(&spins, &options).await, Err(WasmError::OutOfFuel { consumed: 50_000 })));
+    }
+
+    #[tokio::test]
+    async fn interfaces_the_host_does_not_provide_are_refused() {
+        let secret = r#"(import "sovereign:host/secrets@0.1.0" (instance (export "read" (func (result u64)))))"#;
+        let result = run(&fixtures::component(secret, "(i32.const 0)"), &RunOptions::default()).await;
+        assert!(matches!(&result, Err(WasmError::Instantiate(e)) if e.contains("sovereign:host/secrets")), "{:?}", result.map(|r| r.exit_code));
+
+        let random = r#"(import "wasi:random/random@0.2.0" (instance (export "get-random-u64" (func (result u64)))))"#;
+        let wat = fixtures::component(random, "(i32.const 0)");
+        assert!(run(&wat, &RunOptions::default()).await.is_ok());
+        let deterministic = WasmRuntime::new_deterministic().unwrap().run_module(wat.as_bytes(), "", &RunOptions::default()).await;
+        assert!(matches!(deterministic, Err(WasmError::ForbiddenImport { module, .. }) if module == "wasi:random/random@0.2.0"));
+    }
+
+    /// Built from `fixtures/echo-component` by rustc for wasm32-wasip2.
+    const ECHO: &[u8] = include_bytes!("../fixtures/echo-component.wasm");
+
+    #[tokio::test]
+    async fn runs_a_component_built_from_rust() {
+        let options = RunOptions {
+            args: vec!["hello".into(), "world".into()],
+            env: vec![("GREETING".into(), "hi".into())],
+            allowed_env: vec!["GREETING".into()],
+            ..Default::default()
+        };
+        let runtime = WasmRuntime::new().unwrap();
+        let ok = runtime.run_module(ECHO, "from stdin", &options).await.unwrap();
+        assert_eq!((ok.exit_code, ok.trapped), (None, false));
+        assert_eq!(ok.stdout, "args: hello world\nstdin: from stdin\ngreeting: hi\n");
+        assert_eq!(ok.stderr, "done\n");
+
+        let options = RunOptions {
+            args: vec!["exit=3".into()],
+            ..Default::default()
+        };
+        let exited = runtime.run_module(ECHO, "", &options).await.unwrap();
+        // `wasi:cli/exit` only tells failure from success.
+        assert_eq!((exited.exit_code, exited.trapped), (Some(1), false));
+        assert!(WasmRuntime::new().unwrap().validate_only(ECHO).component);
+    }
+
+    #[test]
+    fn reports_a_components_interfaces() {
+        let report = WasmRuntime::new().unwrap().validate_only(fixtures::component("", "(i32.const 0)").as_bytes());
+        assert!(report.valid && report.component);
+        let imports: Vec<(&str, &str)> = report.imports.iter().map(|i| (i.name.as_str(), i.kind.as_str())).collect();
+        assert_eq!(imports, [("wasi:cli/exit@0.2.0", "instance")]);
+        let exports: Vec<(&str, &str)> = report.exports.iter().map(|e| (e.name.as_str(), e.kind.as_str())).collect();
+        assert_eq!(exports, [("wasi:cli/run@0.2.0", "instance")]);
+    }
+}
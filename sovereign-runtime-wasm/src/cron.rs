@@ -0,0 +1,170 @@
+use std::str::FromStr;
+
+/// A five-field cron expression (`minute hour day-of-month month day-of-week`),
+/// evaluated in UTC. Fields take `*`, numbers, ranges `a-b`, lists `a,b` and
+/// steps `*/n` or `a-b/n`. Day of week runs 0-6 from Sunday; 7 is also Sunday.
+///
+/// As in classic cron, when both day fields are restricted a day matching
+/// either one fires.
+#[derive(Debug, Clone)]
+pub struct CronSpec {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl FromStr for CronSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        anyhow::ensure!(fields.len() == 5, "cron spec '{}' must have 5 fields", s);
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays & !(1 << 7)) | 1;
+        }
+        Ok(Self {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            days_restricted: fields[2] != "*",
+            weekdays_restricted: fields[4] != "*",
+        })
+    }
+}
+
+impl CronSpec {
+    /// The first matching minute strictly after `unix_secs`, in Unix seconds.
+    /// `None` if nothing matches within four years, e.g. `0 0 31 2 *`.
+    pub fn next_after(&self, unix_secs: u64) -> Option<u64> {
+        let mut t = (unix_secs / 60 + 1) * 60;
+        let end = unix_secs + 4 * 366 * 86_400;
+        while t <= end {
+            let days = t / 86_400;
+            let (_, month, day) = civil_from_days(days);
+            let weekday = (days + 4) % 7; // 1970-01-01 was a Thursday.
+            if !self.day_matches(month, day, weekday) {
+                t = (days + 1) * 86_400;
+                continue;
+            }
+            let hour = (t % 86_400) / 3600;
+            if self.hours & (1 << hour) == 0 {
+                t = (t / 3600 + 1) * 3600;
+                continue;
+            }
+            if self.minutes & (1 << ((t % 3600) / 60)) != 0 {
+                return Some(t);
+            }
+            t += 60;
+        }
+        None
+    }
+
+    fn day_matches(&self, month: u64, day: u64, weekday: u64) -> bool {
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+        let by_day = self.days & (1 << day) != 0;
+        let by_weekday = self.weekdays & (1 << weekday) != 0;
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => by_day || by_weekday,
+            _ => by_day && by_weekday,
+        }
+    }
+}
+
+/// Parses one field into a bitmask over `min..=max`.
+fn parse_field(field: &str, min: u64, max: u64) -> anyhow::Result<u64> {
+    let mut mask = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u64>()?),
+            None => (part, 1),
+        };
+        anyhow::ensure!(step > 0, "cron step in '{}' must be positive", field);
+        let (lo, hi) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((lo, hi)) => (lo.parse()?, hi.parse()?),
+                None => {
+                    let value = range.parse()?;
+                    (value, if part.contains('/') { max } else { value })
+                }
+            },
+        };
+        anyhow::ensure!(
+            min <= lo && lo <= hi && hi <= max,
+            "cron field '{}' is outside {}-{}",
+            field,
+            min,
+            max
+        );
+        for value in (lo..=hi).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+/// Days since the Unix epoch to a proleptic Gregorian `(year, month, day)`.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01T00:00:00Z, a Monday.
+    const NEW_YEAR_2024: u64 = 1_704_067_200;
+    const DAY: u64 = 86_400;
+
+    fn next(spec: &str, after: u64) -> Option<u64> {
+        spec.parse::<CronSpec>().unwrap().next_after(after)
+    }
+
+    #[test]
+    fn finds_the_next_matching_minute() {
+        assert_eq!(next("* * * * *", NEW_YEAR_2024), Some(NEW_YEAR_2024 + 60));
+        assert_eq!(next("*/15 * * * *", NEW_YEAR_2024), Some(NEW_YEAR_2024 + 900));
+        assert_eq!(next("30 9 * * 1-5", NEW_YEAR_2024), Some(NEW_YEAR_2024 + 9 * 3600 + 1800));
+        assert_eq!(next("0 0 * * 0", NEW_YEAR_2024), Some(NEW_YEAR_2024 + 6 * DAY));
+        assert_eq!(next("0 0 * * 7", NEW_YEAR_2024), Some(NEW_YEAR_2024 + 6 * DAY));
+        assert_eq!(next("0 12 1,15 * *", NEW_YEAR_2024 + DAY), Some(NEW_YEAR_2024 + 14 * DAY + 12 * 3600));
+    }
+
+    #[test]
+    fn either_restricted_day_field_fires() {
+        // The 13th, or any Friday; Friday the 5th comes first.
+        assert_eq!(next("0 0 13 * 5", NEW_YEAR_2024), Some(NEW_YEAR_2024 + 4 * DAY));
+    }
+
+    #[test]
+    fn handles_leap_days_and_impossible_dates() {
+        // From 2024-03-01, the next 29 February is in 2028.
+        assert_eq!(next("0 0 29 2 *", 1_709_251_200), Some(1_835_395_200));
+        assert_eq!(next("0 0 31 2 *", NEW_YEAR_2024), None);
+    }
+
+    #[test]
+    fn rejects_malformed_specs() {
+        for spec in ["* * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "* * * 13 *", "*/0 * * * *", "5-1 * * * *", "x * * * *"] {
+            assert!(spec.parse::<CronSpec>().is_err(), "{} parsed", spec);
+        }
+    }
+}
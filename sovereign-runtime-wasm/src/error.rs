@@ -0,0 +1,78 @@
+use crate::{Capability, ValidationError};
+use std::fmt;
+
+/// Why a module could not be run to completion.
+///
+/// A trap is not an error: it is reported through `ExecutionResult::trapped`
+/// so callers still receive whatever the module produced.
+#[derive(Debug)]
+pub enum WasmError {
+    /// The bytes are not a valid module.
+    Compile(String),
+    /// The module failed validation; the error says where.
+    Validation(ValidationError),
+    /// Imports could not be satisfied or the start function failed.
+    Instantiate(String),
+    /// The module exports neither entry point, or lacks what the ABI needs.
+    MissingExport(String),
+    /// The module returned an output region that is out of bounds.
+    InvalidOutput(String),
+    /// The fuel budget ran out before the module finished.
+    OutOfFuel { consumed: u64 },
+    /// No module is registered under this name.
+    UnknownModule(String),
+    /// The run asked for a capability the module was not granted.
+    CapabilityDenied(Capability),
+    /// The run was cancelled through its `ExecutionHandle`.
+    Cancelled,
+    /// The module imports something the runtime's mode does not allow.
+    ForbiddenImport { module: String, name: String },
+    /// Signatures are required and this one is missing, untrusted or invalid.
+    SignatureRejected { key_id: Option<String>, reason: String },
+    /// An allow-list is enforced and this module's SHA-256 is not on it.
+    NotAllowed { hash: String },
+    /// No execution slot came free: the queue was full or the wait ran out.
+    Busy { queue_depth: usize },
+    /// The pipeline is empty or too long.
+    InvalidPipeline(String),
+    /// The module is confined to core namespace `module`, and the caller
+    /// to `caller`.
+    NamespaceMismatch { module: String, caller: String },
+}
+
+impl fmt::Display for WasmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WasmError::Compile(msg) => write!(f, "WASM compilation failed: {}", msg),
+            WasmError::Validation(error) => write!(f, "WASM validation failed: {}", error),
+            WasmError::Instantiate(msg) => write!(f, "WASM instantiation failed: {}", msg),
+            WasmError::MissingExport(name) => write!(f, "WASM module is missing required export: {}", name),
+            WasmError::InvalidOutput(msg) => write!(f, "WASM module produced invalid output: {}", msg),
+            WasmError::OutOfFuel { consumed } => write!(f, "WASM module ran out of fuel after consuming {}", consumed),
+            WasmError::UnknownModule(name) => write!(f, "No WASM module registered as '{}'", name),
+            WasmError::CapabilityDenied(cap) => write!(f, "WASM module was not granted capability {}", cap),
+            WasmError::Cancelled => write!(f, "WASM execution was cancelled"),
+            // Components import whole interfaces, so `name` is empty for them.
+            WasmError::ForbiddenImport { module, name } if name.is_empty() => {
+                write!(f, "WASM component imports {}, which deterministic mode forbids", module)
+            }
+            WasmError::ForbiddenImport { module, name } => {
+                write!(f, "WASM module imports {}::{}, which deterministic mode forbids", module, name)
+            }
+            WasmError::SignatureRejected { key_id: Some(key_id), reason } => {
+                write!(f, "WASM module signature rejected (key {}): {}", key_id, reason)
+            }
+            WasmError::SignatureRejected { key_id: None, reason } => write!(f, "WASM module signature rejected: {}", reason),
+            WasmError::NotAllowed { hash } => write!(f, "WASM module {} is not on the allow-list", hash),
+            WasmError::InvalidPipeline(msg) => write!(f, "Invalid WASM pipeline: {}", msg),
+            WasmError::NamespaceMismatch { module, caller } => {
+                write!(f, "WASM module is confined to core namespace '{}', not the caller's '{}'", module, caller)
+            }
+            WasmError::Busy { queue_depth } => {
+                write!(f, "WASM runtime is busy ({} executions queued); try again later", queue_depth)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WasmError {}
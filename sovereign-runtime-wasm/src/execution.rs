@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use wasmtime::Engine;
+
+/// Cancels one running execution.
+#[derive(Clone)]
+pub struct ExecutionHandle {
+    id: u64,
+    cancelled: Arc<AtomicBool>,
+    engine: Engine,
+}
+
+impl ExecutionHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Stops the execution at its next epoch check, which this triggers
+    /// immediately. The run then fails with `WasmError::Cancelled`.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.engine.increment_epoch();
+    }
+}
+
+/// A currently running execution, as listed by `WasmRuntime::executions`.
+#[derive(Debug, Clone)]
+pub struct ExecutionSummary {
+    pub id: u64,
+    pub label: String,
+    pub running_for: Duration,
+}
+
+struct Entry {
+    handle: ExecutionHandle,
+    label: String,
+    started: Instant,
+}
+
+/// Running executions by id, so they can be cancelled from elsewhere.
+#[derive(Default)]
+pub(crate) struct ExecutionTable {
+    next_id: AtomicU64,
+    entries: Mutex<HashMap<u64, Entry>>,
+}
+
+impl ExecutionTable {
+    pub fn register(&self, engine: &Engine, label: String) -> (ExecutionHandle, ExecutionGuard<'_>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let handle = ExecutionHandle {
+            id,
+            cancelled: cancelled.clone(),
+            engine: engine.clone(),
+        };
+        self.lock().insert(
+            id,
+            Entry {
+                handle: handle.clone(),
+                label,
+                started: Instant::now(),
+            },
+        );
+        (handle, ExecutionGuard { id, cancelled, table: self })
+    }
+
+    pub fn get(&self, id: u64) -> Option<ExecutionHandle> {
+        self.lock().get(&id).map(|entry| entry.handle.clone())
+    }
+
+    pub fn list(&self) -> Vec<ExecutionSummary> {
+        let mut list: Vec<ExecutionSummary> = self
+            .lock()
+            .iter()
+            .map(|(id, entry)| ExecutionSummary {
+                id: *id,
+                label: entry.label.clone(),
+                running_for: entry.started.elapsed(),
+            })
+            .collect();
+        list.sort_by_key(|summary| summary.id);
+        list
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Entry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Removes the execution from the table when it finishes or its future is dropped.
+pub(crate) struct ExecutionGuard<'a> {
+    pub id: u64,
+    pub cancelled: Arc<AtomicBool>,
+    table: &'a ExecutionTable,
+}
+
+impl Drop for ExecutionGuard<'_> {
+    fn drop(&mut self) {
+        self.table.lock().remove(&self.id);
+    }
+}
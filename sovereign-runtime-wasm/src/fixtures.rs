@@ -0,0 +1,293 @@
+//! Modules for the crate's tests, in the text format.
+//!
+//! `command` wraps a `_start` body in a module with the WASI and host
+//! imports, a page of memory and `$print (fd, ptr, len)`. Offsets 0..64 are
+//! scratch for the imports' out-parameters; data goes at 1024 and up, and
+//! 8192 on is a free buffer. `memory_abi` modules export `alloc` and `run`
+//! instead, and `component` builds a preview-2 command component.
+
+/// A WASI command running `body`, with `extra` (data segments or
+/// functions) at module level.
+pub fn command(extra: &str, body: &str) -> String {
+    format!(
+        r#"(module
+  (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+  (import "wasi_snapshot_preview1" "args_sizes_get" (func $args_sizes_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "args_get" (func $args_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "environ_sizes_get" (func $environ_sizes_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "environ_get" (func $environ_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "path_open" (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+  (import "sovereign" "mesh_publish" (func $mesh_publish (param i32 i32 i32 i32) (result i32)))
+  (import "sovereign" "log" (func $log (param i32 i32 i32) (result i32)))
+  (import "sovereign" "input_read" (func $input_read (param i32 i32) (result i32)))
+  (import "sovereign" "output_write" (func $output_write (param i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (func $print (param $fd i32) (param $ptr i32) (param $len i32)
+    (i32.store (i32.const 0) (local.get $ptr))
+    (i32.store (i32.const 4) (local.get $len))
+    (drop (call $fd_write (local.get $fd) (i32.const 0) (i32.const 1) (i32.const 8))))
+  {extra}
+  (func (export "_start")
+    {body}))"#
+    )
+}
+
+/// Writes "to stdout\n" to stdout and "to stderr\n" to stderr.
+pub fn prints() -> String {
+    command(
+        r#"(data (i32.const 1024) "to stdout\n") (data (i32.const 1040) "to stderr\n")"#,
+        "(call $print (i32.const 1) (i32.const 1024) (i32.const 10)) (call $print (i32.const 2) (i32.const 1040) (i32.const 10))",
+    )
+}
+
+/// Writes "bye\n" to stderr and exits with `code`.
+pub fn exits(code: i32) -> String {
+    command(
+        r#"(data (i32.const 1024) "bye\n")"#,
+        &format!("(call $print (i32.const 2) (i32.const 1024) (i32.const 4)) (call $proc_exit (i32.const {}))", code),
+    )
+}
+
+/// Writes "before\n" to stdout and traps.
+pub fn traps() -> String {
+    command(r#"(data (i32.const 1024) "before\n")"#, "(call $print (i32.const 1) (i32.const 1024) (i32.const 7)) unreachable")
+}
+
+/// Counts down from `loops`, then writes `text` to stdout.
+pub fn prints_after(loops: u32, text: &str) -> String {
+    command(
+        &format!(r#"(data (i32.const 1024) "{text}")"#),
+        &format!(
+            "(local $left i32)
+             (local.set $left (i32.const {loops}))
+             (block $done (loop $count
+               (br_if $done (i32.eqz (local.get $left)))
+               (local.set $left (i32.sub (local.get $left) (i32.const 1)))
+               (br $count)))
+             (call $print (i32.const 1) (i32.const 1024) (i32.const {}))",
+            text.len()
+        ),
+    )
+}
+
+/// Loops until it runs out of fuel or is cancelled.
+pub fn spins() -> String {
+    command("", "(loop $forever (br $forever))")
+}
+
+/// Writes its argv to stdout, each argument followed by a NUL.
+pub fn echo_args() -> String {
+    command(
+        "",
+        "(drop (call $args_sizes_get (i32.const 16) (i32.const 20)))
+         (drop (call $args_get (i32.const 4096) (i32.const 8192)))
+         (call $print (i32.const 1) (i32.const 8192) (i32.load (i32.const 20)))",
+    )
+}
+
+/// Writes its environment to stdout, each `NAME=value` followed by a NUL.
+pub fn echo_env() -> String {
+    command(
+        "",
+        "(drop (call $environ_sizes_get (i32.const 16) (i32.const 20)))
+         (drop (call $environ_get (i32.const 4096) (i32.const 8192)))
+         (call $print (i32.const 1) (i32.const 8192) (i32.load (i32.const 20)))",
+    )
+}
+
+/// Copies everything readable from `fd` to stdout.
+fn copy_to_stdout(fd: &str) -> String {
+    format!(
+        "(block $done (loop $more
+           (i32.store (i32.const 0) (i32.const 8192))
+           (i32.store (i32.const 4) (i32.const 4096))
+           (drop (call $fd_read {fd} (i32.const 0) (i32.const 1) (i32.const 8)))
+           (br_if $done (i32.eqz (i32.load (i32.const 8))))
+           (call $print (i32.const 1) (i32.const 8192) (i32.load (i32.const 8)))
+           (br $more)))"
+    )
+}
+
+/// Copies stdin to stdout.
+pub fn cat() -> String {
+    command("", &copy_to_stdout("(i32.const 0)"))
+}
+
+/// Opens `path` under the first preopen (fd 3) and copies it to stdout, or
+/// exits with the WASI errno if it can't.
+pub fn cat_file(path: &str) -> String {
+    command(
+        &format!(r#"(data (i32.const 1024) "{path}")"#),
+        &format!(
+            "(local $errno i32)
+             ;; Read rights only, the opened fd lands at 32.
+             (local.set $errno (call $path_open (i32.const 3) (i32.const 0) (i32.const 1024) (i32.const {len})
+               (i32.const 0) (i64.const 2) (i64.const 0) (i32.const 0) (i32.const 32)))
+             (if (local.get $errno) (then (call $proc_exit (local.get $errno))))
+             {copy}",
+            len = path.len(),
+            copy = copy_to_stdout("(i32.load (i32.const 32))"),
+        ),
+    )
+}
+
+/// Grows its memory by `pages` 64 KiB pages, exiting with 1 if refused.
+pub fn grows(pages: u32) -> String {
+    command(
+        "",
+        &format!("(if (i32.eq (memory.grow (i32.const {})) (i32.const -1)) (then (call $proc_exit (i32.const 1))))", pages),
+    )
+}
+
+/// Publishes `data` to `topic` `times` times, exiting with the first errno
+/// that isn't `OK`.
+pub fn publishes(topic: &str, data: &str, times: u32) -> String {
+    command(
+        &format!(r#"(data (i32.const 1024) "{topic}") (data (i32.const 2048) "{data}")"#),
+        &format!(
+            "(local $sent i32) (local $errno i32)
+             (loop $again
+               (local.set $errno (call $mesh_publish (i32.const 1024) (i32.const {}) (i32.const 2048) (i32.const {})))
+               (if (local.get $errno) (then (call $proc_exit (local.get $errno))))
+               (local.set $sent (i32.add (local.get $sent) (i32.const 1)))
+               (br_if $again (i32.lt_u (local.get $sent) (i32.const {times}))))",
+            topic.len(),
+            data.len(),
+        ),
+    )
+}
+
+/// A memory-ABI module whose `alloc` hands out 1024 and whose `run` is `body`.
+pub fn memory_abi(body: &str) -> String {
+    format!(
+        r#"(module
+  (memory (export "memory") 1)
+  (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+  (func (export "run") (param $ptr i32) (param $len i32) (result i32 i32)
+    {body}))"#
+    )
+}
+
+/// Returns its input unchanged.
+pub fn echo() -> String {
+    memory_abi("(local.get $ptr) (local.get $len)")
+}
+
+/// Returns its input with ASCII letters uppercased.
+pub fn uppercase() -> String {
+    memory_abi(
+        "(local $i i32) (local $byte i32)
+         (block $done (loop $next
+           (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+           (local.set $byte (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+           (if (i32.lt_u (i32.sub (local.get $byte) (i32.const 97)) (i32.const 26))
+             (then (i32.store8 (i32.add (local.get $ptr) (local.get $i)) (i32.sub (local.get $byte) (i32.const 32)))))
+           (local.set $i (i32.add (local.get $i) (i32.const 1)))
+           (br $next)))
+         (local.get $ptr) (local.get $len)",
+    )
+}
+
+/// Returns its input reversed byte by byte.
+pub fn reverse() -> String {
+    memory_abi(
+        "(local $i i32)
+         (block $done (loop $next
+           (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+           (i32.store8 (i32.sub (i32.const 8191) (local.get $i)) (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+           (local.set $i (i32.add (local.get $i) (i32.const 1)))
+           (br $next)))
+         (i32.sub (i32.const 8192) (local.get $len)) (local.get $len)",
+    )
+}
+
+/// Returns the length of its input in decimal.
+pub fn length() -> String {
+    memory_abi(
+        "(local $at i32)
+         (local.set $at (i32.const 8192))
+         (loop $digit
+           (local.set $at (i32.sub (local.get $at) (i32.const 1)))
+           (i32.store8 (local.get $at) (i32.add (i32.const 48) (i32.rem_u (local.get $len) (i32.const 10))))
+           (local.set $len (i32.div_u (local.get $len) (i32.const 10)))
+           (br_if $digit (local.get $len)))
+         (local.get $at) (i32.sub (i32.const 8192) (local.get $at))",
+    )
+}
+
+/// Traps in `run`.
+pub fn run_traps() -> String {
+    memory_abi("unreachable")
+}
+
+/// Returns 64 steps of a floating-point recurrence and a NaN from `inf - inf`.
+pub fn floats() -> String {
+    memory_abi(
+        "(local $i i32) (local $x f64)
+         (local.set $x (f64.const 1.5))
+         (loop $step
+           (local.set $x (f64.add (f64.sqrt (local.get $x)) (f64.div (f64.const 1) (f64.add (local.get $x) (f64.const 3)))))
+           (f64.store (i32.add (i32.const 2048) (i32.mul (local.get $i) (i32.const 8))) (local.get $x))
+           (local.set $i (i32.add (local.get $i) (i32.const 1)))
+           (br_if $step (i32.lt_u (local.get $i) (i32.const 64))))
+         (f64.store (i32.const 2560) (f64.sub (f64.const inf) (f64.const inf)))
+         (i32.const 2048) (i32.const 520)",
+    )
+}
+
+/// Calls `log` with each `(level, message)` in turn.
+pub fn logs(messages: &[(i32, &[u8])]) -> String {
+    let mut data = String::new();
+    let mut body = String::new();
+    let mut at = 1024;
+    for (level, message) in messages {
+        let escaped: String = message.iter().map(|byte| format!("\\{:02x}", byte)).collect();
+        data.push_str(&format!(r#"(data (i32.const {}) "{}")"#, at, escaped));
+        body.push_str(&format!("(drop (call $log (i32.const {}) (i32.const {}) (i32.const {})))\n", level, at, message.len()));
+        at += message.len();
+    }
+    command(&data, &body)
+}
+
+/// Copies the input stream to the output stream 32 KiB at a time, exiting
+/// with the errno if either fails.
+pub fn stream_echo() -> String {
+    command(
+        "",
+        "(local $n i32) (local $done i32) (local $wrote i32)
+         (block $end (loop $more
+           (local.set $n (call $input_read (i32.const 8192) (i32.const 32768)))
+           (if (i32.lt_s (local.get $n) (i32.const 0)) (then (call $proc_exit (i32.sub (i32.const 0) (local.get $n)))))
+           (br_if $end (i32.eqz (local.get $n)))
+           (local.set $done (i32.const 0))
+           (loop $write
+             (local.set $wrote (call $output_write (i32.add (i32.const 8192) (local.get $done)) (i32.sub (local.get $n) (local.get $done))))
+             (if (i32.lt_s (local.get $wrote) (i32.const 0)) (then (call $proc_exit (i32.sub (i32.const 0) (local.get $wrote)))))
+             (local.set $done (i32.add (local.get $done) (local.get $wrote)))
+             (br_if $write (i32.lt_u (local.get $done) (local.get $n))))
+           (br $more)))",
+    )
+}
+
+/// A component exporting `wasi:cli/run` whose `run` is `body`, a core
+/// function returning 0 for `ok` and 1 for `err`, with `$exit (i32)`
+/// lowered from `wasi:cli/exit`. `extra_import` adds one more instance import.
+pub fn component(extra_import: &str, body: &str) -> String {
+    format!(
+        r#"(component
+  (import "wasi:cli/exit@0.2.0" (instance $exit (export "exit" (func (param "status" (result))))))
+  {extra_import}
+  (core func $exit (canon lower (func $exit "exit")))
+  (core module $m
+    (import "host" "exit" (func $exit (param i32)))
+    (func (export "run") (result i32)
+      {body}))
+  (core instance $host (export "exit" (func $exit)))
+  (core instance $i (instantiate $m (with "host" (instance $host))))
+  (func $run (result (result)) (canon lift (core func $i "run")))
+  (instance $cli (export "run" (func $run)))
+  (export "wasi:cli/run@0.2.0" (instance $cli)))"#
+    )
+}
@@ -0,0 +1,694 @@
+use crate::{RunOptions, StoreState};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use wasmtime::{Caller, Linker};
+
+/// Boxed future returned by async `HostContext` methods.
+pub type HostFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Import module name for host functions.
+pub const HOST_MODULE: &str = "sovereign";
+
+/// Most bytes one `input_read` or `output_write` call moves, so a single call
+/// never makes the host buffer more than this.
+const STREAM_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Status codes returned to the guest by host imports. Failures never trap.
+pub mod errno {
+    pub const OK: i32 = 0;
+    /// The module was not granted the capability the call needs.
+    pub const DENIED: i32 = 1;
+    /// The per-execution budget for this call is spent.
+    pub const BUDGET_EXCEEDED: i32 = 2;
+    /// A pointer, length or encoding in the arguments is invalid.
+    pub const INVALID_ARGUMENT: i32 = 3;
+    /// The call reached the node and failed there.
+    pub const FAILED: i32 = 4;
+    /// The node does not provide this service, or it is down.
+    pub const UNAVAILABLE: i32 = 5;
+    /// The host queue is full; the call may succeed later.
+    pub const BUSY: i32 = 6;
+}
+
+/// Permissions a module can be granted at registration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Capability {
+    WasiFs,
+    CoreRead,
+    CoreWrite,
+    MeshPublish,
+}
+
+impl Capability {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::WasiFs => "wasi.fs",
+            Capability::CoreRead => "core.read",
+            Capability::CoreWrite => "core.write",
+            Capability::MeshPublish => "mesh.publish",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Capability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "wasi.fs" => Ok(Capability::WasiFs),
+            "core.read" => Ok(Capability::CoreRead),
+            "core.write" => Ok(Capability::CoreWrite),
+            "mesh.publish" => Ok(Capability::MeshPublish),
+            other => anyhow::bail!("unknown capability '{}'", other),
+        }
+    }
+}
+
+impl Serialize for Capability {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Capability {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Node services reachable from guests. The node implements this so the
+/// runtime does not depend on the services themselves.
+///
+/// Modules run on the async executor, so nothing here may block.
+pub trait HostContext: Send + Sync {
+    /// Runs a query against the cognitive core, confined to `namespace` and
+    /// held to `principal`'s grants if the run is. `request` is
+    /// `{"query": "...", "params": {...}}` and the result is JSON.
+    fn core_query(&self, request: String, namespace: Option<String>, principal: Option<String>) -> HostFuture<'_, anyhow::Result<String>>;
+
+    /// Queues a gossip message for the mesh.
+    fn mesh_publish(&self, _topic: &str, _data: &[u8]) -> Result<(), PublishRejected> {
+        Err(PublishRejected::Unavailable)
+    }
+}
+
+/// Why `HostContext::mesh_publish` did not queue a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublishRejected {
+    QueueFull,
+    Unavailable,
+    /// The topic is kept for the node's own messages.
+    Reserved,
+}
+
+/// Per-execution allowance for host calls.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HostBudget {
+    pub max_core_queries: u32,
+    pub max_core_result_bytes: usize,
+    pub max_publishes: u32,
+    /// Total payload bytes across all publishes.
+    pub max_publish_bytes: usize,
+    /// `log` calls beyond this are dropped and counted.
+    pub max_log_messages: u32,
+    /// Longer messages are truncated.
+    pub max_log_message_bytes: usize,
+    /// Total bytes `input_read` may consume from the input stream.
+    pub max_input_bytes: u64,
+    /// Total bytes `output_write` may produce.
+    pub max_output_bytes: u64,
+}
+
+impl Default for HostBudget {
+    fn default() -> Self {
+        Self {
+            max_core_queries: 100,
+            max_core_result_bytes: 1024 * 1024,
+            max_publishes: 100,
+            max_publish_bytes: 1024 * 1024,
+            max_log_messages: 1000,
+            max_log_message_bytes: 4096,
+            max_input_bytes: 256 * 1024 * 1024,
+            max_output_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Host ends of the streaming ABI: the module pulls from `input` with
+/// `input_read` and pushes to `output` with `output_write`.
+pub struct StreamIo {
+    pub input: Box<dyn AsyncRead + Send + Unpin>,
+    pub output: Box<dyn AsyncWrite + Send + Unpin>,
+}
+
+impl StreamIo {
+    pub fn new(input: impl AsyncRead + Send + Unpin + 'static, output: impl AsyncWrite + Send + Unpin + 'static) -> Self {
+        Self {
+            input: Box::new(input),
+            output: Box::new(output),
+        }
+    }
+}
+
+pub(crate) struct StreamState {
+    pub io: StreamIo,
+    read: u64,
+    written: u64,
+}
+
+/// A message a module wrote through the `log` import.
+#[derive(Debug, Clone)]
+pub struct GuestLog {
+    pub level: log::Level,
+    pub message: String,
+}
+
+pub(crate) struct HostState {
+    context: Option<Arc<dyn HostContext>>,
+    capabilities: Vec<Capability>,
+    namespace: Option<String>,
+    principal: Option<String>,
+    budget: HostBudget,
+    core_queries: u32,
+    publishes: u32,
+    publish_bytes: usize,
+    /// `wasm::<label>`, the target guest messages are logged under.
+    log_target: String,
+    execution_id: u64,
+    pub logs: Vec<GuestLog>,
+    pub logs_dropped: u32,
+    /// Only set for streaming runs.
+    pub stream: Option<StreamState>,
+}
+
+impl HostState {
+    /// Takes the run's capabilities, namespace, principal and label from
+    /// `options`.
+    pub fn new(
+        context: Option<Arc<dyn HostContext>>,
+        options: &RunOptions,
+        budget: HostBudget,
+        execution_id: u64,
+        stream: Option<StreamIo>,
+    ) -> Self {
+        Self {
+            context,
+            capabilities: options.capabilities.clone(),
+            namespace: options.namespace.clone(),
+            principal: options.principal.clone(),
+            budget,
+            core_queries: 0,
+            publishes: 0,
+            publish_bytes: 0,
+            log_target: format!("wasm::{}", options.label),
+            execution_id,
+            logs: Vec::new(),
+            logs_dropped: 0,
+            stream: stream.map(|io| StreamState { io, read: 0, written: 0 }),
+        }
+    }
+}
+
+pub(crate) fn add_to_linker(linker: &mut Linker<StoreState>) -> anyhow::Result<()> {
+    linker.func_wrap_async(HOST_MODULE, "core_query", |caller, (ptr, len, ret_ptr): (i32, i32, i32)| {
+        Box::new(core_query(caller, ptr, len, ret_ptr))
+    })?;
+    linker.func_wrap(HOST_MODULE, "mesh_publish", mesh_publish)?;
+    linker.func_wrap(HOST_MODULE, "log", guest_log)?;
+    linker.func_wrap_async(HOST_MODULE, "input_read", |caller, (ptr, len): (i32, i32)| {
+        Box::new(input_read(caller, ptr, len))
+    })?;
+    linker.func_wrap_async(HOST_MODULE, "output_write", |caller, (ptr, len): (i32, i32)| {
+        Box::new(output_write(caller, ptr, len))
+    })?;
+    Ok(())
+}
+
+/// `core_query(query_ptr, query_len, ret_ptr) -> errno`
+///
+/// The result JSON (or, with `FAILED`, the error message) is copied into a
+/// buffer from the guest's `alloc` export and `(ptr: u32, len: u32)` is
+/// written little-endian at `ret_ptr`.
+async fn core_query(mut caller: Caller<'_, StoreState>, ptr: i32, len: i32, ret_ptr: i32) -> anyhow::Result<i32> {
+    let host = &mut caller.data_mut().host;
+    if !host.capabilities.contains(&Capability::CoreRead) {
+        return Ok(errno::DENIED);
+    }
+    let Some(context) = host.context.clone() else {
+        return Ok(errno::UNAVAILABLE);
+    };
+    if host.core_queries >= host.budget.max_core_queries {
+        return Ok(errno::BUDGET_EXCEEDED);
+    }
+    host.core_queries += 1;
+    let max_result_bytes = host.budget.max_core_result_bytes;
+    let namespace = host.namespace.clone();
+    let principal = host.principal.clone();
+
+    let Some(request) = read_guest_str(&mut caller, ptr, len) else {
+        return Ok(errno::INVALID_ARGUMENT);
+    };
+    let (status, result) = match context.core_query(request, namespace, principal).await {
+        Ok(result) if result.len() > max_result_bytes => return Ok(errno::BUDGET_EXCEEDED),
+        Ok(result) => (errno::OK, result),
+        Err(e) => (errno::FAILED, format!("{:#}", e)),
+    };
+    match write_guest_buffer(&mut caller, result.as_bytes(), ret_ptr).await? {
+        errno::OK => Ok(status),
+        err => Ok(err),
+    }
+}
+
+/// `mesh_publish(topic_ptr, topic_len, data_ptr, data_len) -> errno`
+///
+/// Fire-and-forget: `OK` means the message was queued, not delivered.
+fn mesh_publish(
+    mut caller: Caller<'_, StoreState>,
+    topic_ptr: i32,
+    topic_len: i32,
+    data_ptr: i32,
+    data_len: i32,
+) -> anyhow::Result<i32> {
+    let host = &caller.data().host;
+    if !host.capabilities.contains(&Capability::MeshPublish) {
+        return Ok(errno::DENIED);
+    }
+    let Some(context) = host.context.clone() else {
+        return Ok(errno::UNAVAILABLE);
+    };
+    let budget = host.budget;
+    if host.publishes >= budget.max_publishes
+        || host.publish_bytes.saturating_add(data_len as u32 as usize) > budget.max_publish_bytes
+    {
+        return Ok(errno::BUDGET_EXCEEDED);
+    }
+
+    let (Some(topic), Some(data)) = (
+        read_guest_str(&mut caller, topic_ptr, topic_len),
+        read_guest_bytes(&mut caller, data_ptr, data_len),
+    ) else {
+        return Ok(errno::INVALID_ARGUMENT);
+    };
+    if topic.is_empty() {
+        return Ok(errno::INVALID_ARGUMENT);
+    }
+
+    match context.mesh_publish(&topic, &data) {
+        Ok(()) => {
+            let host = &mut caller.data_mut().host;
+            host.publishes += 1;
+            host.publish_bytes += data.len();
+            Ok(errno::OK)
+        }
+        Err(PublishRejected::QueueFull) => Ok(errno::BUSY),
+        Err(PublishRejected::Unavailable) => Ok(errno::UNAVAILABLE),
+        Err(PublishRejected::Reserved) => Ok(errno::DENIED),
+    }
+}
+
+/// `log(level, ptr, len) -> errno`
+///
+/// Levels 0-4 are trace, debug, info, warn and error. Needs no capability;
+/// past `max_log_messages` calls return `BUDGET_EXCEEDED`.
+fn guest_log(mut caller: Caller<'_, StoreState>, level: i32, ptr: i32, len: i32) -> anyhow::Result<i32> {
+    let level = match level {
+        0 => log::Level::Trace,
+        1 => log::Level::Debug,
+        2 => log::Level::Info,
+        3 => log::Level::Warn,
+        4 => log::Level::Error,
+        _ => return Ok(errno::INVALID_ARGUMENT),
+    };
+    let host = &mut caller.data_mut().host;
+    if host.logs.len() as u32 >= host.budget.max_log_messages {
+        host.logs_dropped = host.logs_dropped.saturating_add(1);
+        return Ok(errno::BUDGET_EXCEEDED);
+    }
+    let max_bytes = host.budget.max_log_message_bytes;
+
+    // Only the first `max_bytes` are read from the guest.
+    let truncated = len as u32 as usize > max_bytes;
+    let read_len = if truncated { max_bytes as i32 } else { len };
+    let Some(bytes) = read_guest_bytes(&mut caller, ptr, read_len) else {
+        return Ok(errno::INVALID_ARGUMENT);
+    };
+    let mut message = String::from_utf8_lossy(&bytes).into_owned();
+    if truncated {
+        message.push_str("... [truncated]");
+    }
+
+    let host = &mut caller.data_mut().host;
+    log::log!(target: &host.log_target, level, "[execution {}] {}", host.execution_id, message);
+    host.logs.push(GuestLog { level, message });
+    Ok(errno::OK)
+}
+
+/// `input_read(ptr, len) -> n`
+///
+/// Reads up to `len` bytes of the input stream into `ptr`. Returns the count,
+/// `0` at end of input, or a negated errno: `UNAVAILABLE` outside streaming
+/// runs, `BUDGET_EXCEEDED` once `max_input_bytes` have been read.
+async fn input_read(mut caller: Caller<'_, StoreState>, ptr: i32, len: i32) -> anyhow::Result<i32> {
+    let Some(memory) = caller.get_export("memory").and_then(|e| e.into_memory()) else {
+        return Ok(-errno::INVALID_ARGUMENT);
+    };
+    let start = ptr as u32 as usize;
+    let len = (len as u32 as usize).min(STREAM_CHUNK_BYTES);
+    if start.checked_add(len).is_none_or(|end| end > memory.data_size(&caller)) {
+        return Ok(-errno::INVALID_ARGUMENT);
+    }
+
+    let host = &mut caller.data_mut().host;
+    let max_input_bytes = host.budget.max_input_bytes;
+    let Some(stream) = host.stream.as_mut() else {
+        return Ok(-errno::UNAVAILABLE);
+    };
+    let remaining = max_input_bytes.saturating_sub(stream.read);
+    // At the budget, probe a byte to tell end of input from more input.
+    let mut buf = vec![0u8; len.min(remaining as usize).max(1)];
+    let n = match stream.io.input.read(&mut buf).await {
+        Ok(n) => n,
+        Err(e) => {
+            log::debug!("WASM input stream failed: {}", e);
+            return Ok(-errno::FAILED);
+        }
+    };
+    if n > 0 && remaining == 0 {
+        return Ok(-errno::BUDGET_EXCEEDED);
+    }
+    stream.read += n as u64;
+    if memory.write(&mut caller, start, &buf[..n]).is_err() {
+        return Ok(-errno::INVALID_ARGUMENT);
+    }
+    Ok(n as i32)
+}
+
+/// `output_write(ptr, len) -> n`
+///
+/// Writes up to `len` bytes from `ptr` to the output stream and returns how
+/// many were taken, which may be fewer; errors are negated errnos as for
+/// `input_read`.
+async fn output_write(mut caller: Caller<'_, StoreState>, ptr: i32, len: i32) -> anyhow::Result<i32> {
+    let len = (len as u32 as usize).min(STREAM_CHUNK_BYTES);
+    let Some(data) = read_guest_bytes(&mut caller, ptr, len as i32) else {
+        return Ok(-errno::INVALID_ARGUMENT);
+    };
+
+    let host = &mut caller.data_mut().host;
+    let max_output_bytes = host.budget.max_output_bytes;
+    let Some(stream) = host.stream.as_mut() else {
+        return Ok(-errno::UNAVAILABLE);
+    };
+    if stream.written.saturating_add(data.len() as u64) > max_output_bytes {
+        return Ok(-errno::BUDGET_EXCEEDED);
+    }
+    match stream.io.output.write(&data).await {
+        Ok(n) => {
+            stream.written += n as u64;
+            Ok(n as i32)
+        }
+        Err(e) => {
+            log::debug!("WASM output stream failed: {}", e);
+            Ok(-errno::FAILED)
+        }
+    }
+}
+
+/// Flushes the output stream of a streaming run once the module is done.
+pub(crate) async fn flush_stream(host: &mut HostState) {
+    if let Some(stream) = host.stream.as_mut() {
+        if let Err(e) = stream.io.output.flush().await {
+            log::warn!("Failed to flush WASM output stream: {}", e);
+        }
+    }
+}
+
+fn read_guest_str(caller: &mut Caller<'_, StoreState>, ptr: i32, len: i32) -> Option<String> {
+    String::from_utf8(read_guest_bytes(caller, ptr, len)?).ok()
+}
+
+fn read_guest_bytes(caller: &mut Caller<'_, StoreState>, ptr: i32, len: i32) -> Option<Vec<u8>> {
+    let memory = caller.get_export("memory")?.into_memory()?;
+    let start = ptr as u32 as usize;
+    let bytes = memory.data(&*caller).get(start..start.checked_add(len as u32 as usize)?)?;
+    Some(bytes.to_vec())
+}
+
+/// Copies `data` into guest memory obtained from `alloc` and records its
+/// location at `ret_ptr`. A trap inside `alloc` propagates.
+async fn write_guest_buffer(caller: &mut Caller<'_, StoreState>, data: &[u8], ret_ptr: i32) -> anyhow::Result<i32> {
+    let (Some(memory), Some(alloc)) = (
+        caller.get_export("memory").and_then(|e| e.into_memory()),
+        caller.get_export("alloc").and_then(|e| e.into_func()),
+    ) else {
+        return Ok(errno::INVALID_ARGUMENT);
+    };
+    let Ok(alloc) = alloc.typed::<i32, i32>(&*caller) else {
+        return Ok(errno::INVALID_ARGUMENT);
+    };
+    let Ok(len) = i32::try_from(data.len()) else {
+        return Ok(errno::INVALID_ARGUMENT);
+    };
+
+    let ptr = alloc.call_async(&mut *caller, len).await?;
+    let mut ret = [0u8; 8];
+    ret[..4].copy_from_slice(&(ptr as u32).to_le_bytes());
+    ret[4..].copy_from_slice(&(len as u32).to_le_bytes());
+    if memory.write(&mut *caller, ptr as u32 as usize, data).is_err()
+        || memory.write(&mut *caller, ret_ptr as u32 as usize, &ret).is_err()
+    {
+        return Ok(errno::INVALID_ARGUMENT);
+    }
+    Ok(errno::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures, ExecutionLimits, ExecutionResult, WasmRuntime};
+    use sha2::{Digest, Sha256};
+    use std::sync::Mutex;
+
+    /// Records publishes, or turns them all down with `reject`.
+    #[derive(Default)]
+    struct StubHost {
+        reject: Option<PublishRejected>,
+        published: Mutex<Vec<(String, Vec<u8>)>>,
+    }
+
+    impl HostContext for StubHost {
+        fn core_query(&self, _request: String, _namespace: Option<String>, _principal: Option<String>) -> HostFuture<'_, anyhow::Result<String>> {
+            Box::pin(async { anyhow::bail!("no core here") })
+        }
+
+        fn mesh_publish(&self, topic: &str, data: &[u8]) -> Result<(), PublishRejected> {
+            if let Some(rejected) = self.reject {
+                return Err(rejected);
+            }
+            self.published.lock().unwrap().push((topic.to_string(), data.to_vec()));
+            Ok(())
+        }
+    }
+
+    fn publisher(budget: HostBudget) -> RunOptions {
+        RunOptions {
+            capabilities: vec![Capability::MeshPublish],
+            limits: ExecutionLimits {
+                host_budget: Some(budget),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Runs `wat` against `host` and returns the exit code.
+    async fn run(host: &Arc<StubHost>, wat: &str, options: &RunOptions) -> Option<i32> {
+        let runtime = WasmRuntime::new().unwrap().with_host_context(host.clone());
+        runtime.run_module(wat.as_bytes(), "", options).await.unwrap().exit_code
+    }
+
+    #[tokio::test]
+    async fn queues_publishes_with_the_capability() {
+        let host = Arc::new(StubHost::default());
+        assert_eq!(run(&host, &fixtures::publishes("news", "hello", 3), &publisher(HostBudget::default())).await, None);
+        let published = host.published.lock().unwrap();
+        assert_eq!(published.len(), 3);
+        assert!(published.iter().all(|(topic, data)| topic == "news" && data == b"hello"));
+    }
+
+    #[tokio::test]
+    async fn refuses_publishes_without_the_capability() {
+        let host = Arc::new(StubHost::default());
+        assert_eq!(run(&host, &fixtures::publishes("news", "hello", 1), &RunOptions::default()).await, Some(errno::DENIED));
+        assert!(host.published.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stops_publishes_at_the_budget() {
+        let host = Arc::new(StubHost::default());
+        let two_messages = HostBudget {
+            max_publishes: 2,
+            ..Default::default()
+        };
+        let wat = fixtures::publishes("news", "hello", 3);
+        assert_eq!(run(&host, &wat, &publisher(two_messages)).await, Some(errno::BUDGET_EXCEEDED));
+        assert_eq!(host.published.lock().unwrap().len(), 2);
+
+        let host = Arc::new(StubHost::default());
+        let twelve_bytes = HostBudget {
+            max_publish_bytes: 12,
+            ..Default::default()
+        };
+        assert_eq!(run(&host, &wat, &publisher(twelve_bytes)).await, Some(errno::BUDGET_EXCEEDED));
+        assert_eq!(host.published.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn tells_a_rejected_publish_from_a_queued_one() {
+        let wat = fixtures::publishes("news", "hello", 1);
+        let options = publisher(HostBudget::default());
+        for (reject, code) in [
+            (PublishRejected::QueueFull, errno::BUSY),
+            (PublishRejected::Unavailable, errno::UNAVAILABLE),
+            (PublishRejected::Reserved, errno::DENIED),
+        ] {
+            let host = Arc::new(StubHost {
+                reject: Some(reject),
+                ..Default::default()
+            });
+            assert_eq!(run(&host, &wat, &options).await, Some(code), "{:?}", reject);
+        }
+
+        let host = Arc::new(StubHost::default());
+        assert_eq!(run(&host, &fixtures::publishes("", "hello", 1), &options).await, Some(errno::INVALID_ARGUMENT));
+
+        let no_host = WasmRuntime::new().unwrap();
+        let result = no_host.run_module(wat.as_bytes(), "", &options).await.unwrap();
+        assert_eq!(result.exit_code, Some(errno::UNAVAILABLE));
+    }
+
+    #[tokio::test]
+    async fn keeps_guest_logs_with_their_levels() {
+        let wat = fixtures::logs(&[
+            (0, b"trace"),
+            (1, b"debug"),
+            (2, b"info"),
+            (3, b"warn"),
+            (4, b"error"),
+            (5, b"no such level"),
+            (2, b"bad \xff byte"),
+        ]);
+        let result = WasmRuntime::new().unwrap().run_module(wat.as_bytes(), "", &RunOptions::default()).await.unwrap();
+        let logs: Vec<(log::Level, &str)> = result.logs.iter().map(|entry| (entry.level, entry.message.as_str())).collect();
+        assert_eq!(
+            logs,
+            [
+                (log::Level::Trace, "trace"),
+                (log::Level::Debug, "debug"),
+                (log::Level::Info, "info"),
+                (log::Level::Warn, "warn"),
+                (log::Level::Error, "error"),
+                (log::Level::Info, "bad \u{fffd} byte"),
+            ]
+        );
+        assert_eq!(result.logs_dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn caps_guest_logs_per_execution() {
+        let long = [b'x'; 100];
+        let wat = fixtures::logs(&[(2, b"one"), (2, &long), (2, b"three"), (2, b"four")]);
+        let options = RunOptions {
+            limits: ExecutionLimits {
+                host_budget: Some(HostBudget {
+                    max_log_messages: 2,
+                    max_log_message_bytes: 10,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let result = WasmRuntime::new().unwrap().run_module(wat.as_bytes(), "", &options).await.unwrap();
+        let messages: Vec<&str> = result.logs.iter().map(|entry| entry.message.as_str()).collect();
+        assert_eq!(messages, ["one", "xxxxxxxxxx... [truncated]"]);
+        assert_eq!(result.logs_dropped, 2);
+    }
+
+    /// Streams `input` through `stream_echo` and returns the result with
+    /// the SHA-256 and length of what came out.
+    async fn stream_through(input: Vec<u8>, options: &RunOptions) -> (ExecutionResult, [u8; 32], usize) {
+        let (output, mut sink) = tokio::io::duplex(64 * 1024);
+        let drain = tokio::spawn(async move {
+            let (mut hasher, mut len, mut buf) = (Sha256::new(), 0, vec![0u8; 64 * 1024]);
+            loop {
+                let n = sink.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    return (hasher.finalize().into(), len);
+                }
+                hasher.update(&buf[..n]);
+                len += n;
+            }
+        });
+        let stream = StreamIo::new(std::io::Cursor::new(input), output);
+        let result = WasmRuntime::new().unwrap().run_streaming(fixtures::stream_echo().as_bytes(), stream, options).await.unwrap();
+        let (hash, len) = drain.await.unwrap();
+        (result, hash, len)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streams_a_large_input_through_a_module() {
+        let input: Vec<u8> = (0..20 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let expected: [u8; 32] = Sha256::digest(&input).into();
+        let (result, hash, len) = stream_through(input, &RunOptions::default()).await;
+        assert_eq!(result.exit_code, None);
+        assert_eq!(len, 20 * 1024 * 1024);
+        assert_eq!(hash, expected);
+        // The payload never sat in guest memory all at once.
+        assert_eq!(result.peak_memory_bytes, 64 * 1024);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stops_streams_at_the_budget() {
+        let options = RunOptions {
+            limits: ExecutionLimits {
+                host_budget: Some(HostBudget {
+                    max_input_bytes: 100_000,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (result, _, len) = stream_through(vec![7; 200_000], &options).await;
+        assert_eq!(result.exit_code, Some(errno::BUDGET_EXCEEDED));
+        assert_eq!(len, 100_000);
+
+        let options = RunOptions {
+            limits: ExecutionLimits {
+                host_budget: Some(HostBudget {
+                    max_output_bytes: 50_000,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (result, _, len) = stream_through(vec![7; 200_000], &options).await;
+        assert_eq!(result.exit_code, Some(errno::BUDGET_EXCEEDED));
+        assert!(len <= 50_000);
+    }
+}
@@ -1,24 +1,1345 @@
-use anyhow::Result;
-use wasmtime::{Config, Engine};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use wasmtime::{
+    Config, Engine, Instance, InstanceAllocationStrategy, Linker, Module, PoolingAllocationConfig, Store, Trap,
+    UpdateDeadline, WasmBacktrace,
+};
+use wasmtime::component::Linker as ComponentLinker;
+use wasmtime_wasi::p1::WasiP1Ctx;
+use wasmtime_wasi::{WasiCtxView, WasiView};
+use wasmtime_wasi::p2::pipe::MemoryOutputPipe;
+use wasmtime_wasi::I32Exit;
+
+mod admission;
+mod allowlist;
+mod artifact;
+mod cache;
+mod component;
+mod cron;
+mod error;
+mod execution;
+#[cfg(test)]
+mod fixtures;
+mod host;
+mod limits;
+mod pipeline;
+mod registry;
+mod scheduler;
+mod signing;
+mod stats;
+mod validate;
+mod wasi;
+
+pub use admission::AdmissionStats;
+pub use allowlist::{Allowlist, AllowlistConfig, AllowlistMode};
+pub use artifact::ArtifactTags;
+pub use error::WasmError;
+pub use execution::{ExecutionHandle, ExecutionSummary};
+pub use host::{errno, Capability, GuestLog, HostBudget, HostContext, HostFuture, PublishRejected, StreamIo, HOST_MODULE};
+pub use limits::ExecutionLimits;
+pub use pipeline::{PipelineFailure, PipelineLimits, PipelineResult, PipelineStage, MAX_PIPELINE_STAGES};
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+pub use cron::CronSpec;
+pub use registry::{ModuleInfo, ModuleManifest, ModuleRegistry, ModuleReloaded};
+pub use scheduler::{JobCompleted, JobInfo, JobRun, JobSchedule, JobSpec, OverlapPolicy, Scheduler};
+pub use stats::ModuleStats;
+pub use signing::{generate_signing_key, key_id, sign_module, ModuleSignature};
+pub use validate::{ExportInfo, ImportInfo, LimitsInfo, ValidationError, ValidationReport};
+pub use wasi::Preopen;
+
+use admission::Admission;
+use cache::ModuleCache;
+use component::Compiled;
+use execution::{ExecutionGuard, ExecutionTable};
+use host::HostState;
+use limits::StoreLimiter;
+use stats::StatsTable;
+
+/// Runtime-wide settings.
+#[derive(Debug, Clone)]
+pub struct RuntimeConfig {
+    /// Host directories visible to modules granted `wasi.fs`. Other modules
+    /// never see a filesystem.
+    pub preopens: Vec<Preopen>,
+    /// Give modules the real wall and monotonic clocks instead of a frozen one.
+    pub allow_clocks: bool,
+    /// Fuel granted when a call does not ask for a specific amount.
+    pub default_fuel_limit: u64,
+    /// Ceiling for per-call fuel requests.
+    pub max_fuel_limit: u64,
+    /// Linear memory granted when a call does not ask for a specific amount.
+    pub default_memory_bytes: usize,
+    /// Ceiling for per-call memory requests.
+    pub max_memory_bytes: usize,
+    pub max_table_elements: usize,
+    pub max_instances: usize,
+    /// Trap instead of failing `memory.grow`/`table.grow` when a cap is hit.
+    pub trap_on_grow_failure: bool,
+    /// Where compiled modules are kept between runs. `None` disables the cache.
+    pub cache_dir: Option<PathBuf>,
+    /// Total size of the compilation cache before least recently used entries are evicted.
+    pub cache_max_bytes: u64,
+    /// Host call allowance when a call does not override it.
+    pub host_budget: HostBudget,
+    /// Executions allowed to run at once; further calls queue for a slot.
+    pub max_concurrent_executions: usize,
+    /// Executions allowed to wait for a slot before new ones fail with `Busy`.
+    pub max_queued_executions: usize,
+    /// How long an execution may wait for a slot when the call doesn't say.
+    pub max_queue_wait: Duration,
+    /// Slots one source (`RunOptions::source`) may hold at once, so a single
+    /// client can't starve the others. 0 leaves sources uncapped.
+    pub max_executions_per_source: usize,
+    /// Fuel consumed between yields back to the async executor.
+    pub fuel_yield_interval: u64,
+    /// Preallocate instance slots instead of mapping memory per call. Falls
+    /// back to on-demand allocation if the host can't reserve the pool.
+    pub pooling: Option<PoolingConfig>,
+    /// Make results reproducible across hosts; see `WasmRuntime::new_deterministic`.
+    pub deterministic: bool,
+    /// Refuse modules without a valid signature from `trusted_publishers`.
+    pub require_signatures: bool,
+    pub trusted_publishers: Vec<VerifyingKey>,
+    /// Only run modules whose SHA-256 is on this list. `None` runs anything.
+    pub allowlist: Option<AllowlistConfig>,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            preopens: Vec::new(),
+            allow_clocks: false,
+            default_fuel_limit: 1_000_000_000,
+            max_fuel_limit: 10_000_000_000,
+            default_memory_bytes: 64 * 1024 * 1024,
+            max_memory_bytes: 512 * 1024 * 1024,
+            max_table_elements: 100_000,
+            max_instances: 10,
+            trap_on_grow_failure: false,
+            cache_dir: None,
+            cache_max_bytes: 256 * 1024 * 1024,
+            host_budget: HostBudget::default(),
+            max_concurrent_executions: 8,
+            max_queued_executions: 64,
+            max_queue_wait: Duration::from_secs(30),
+            max_executions_per_source: 0,
+            fuel_yield_interval: 10_000,
+            pooling: None,
+            deterministic: false,
+            require_signatures: false,
+            trusted_publishers: Vec::new(),
+            allowlist: None,
+        }
+    }
+}
+
+/// Sizing of the pooling instance allocator.
+#[derive(Debug, Clone)]
+pub struct PoolingConfig {
+    /// Instances (and linear memories) that can be live at once.
+    pub total_instances: u32,
+    /// Size of each memory slot. Growth past it fails even if the execution
+    /// limit is higher, so keep it at or above `max_memory_bytes`.
+    pub max_memory_bytes: usize,
+    pub table_elements: usize,
+}
+
+impl Default for PoolingConfig {
+    fn default() -> Self {
+        Self {
+            total_instances: 64,
+            max_memory_bytes: 512 * 1024 * 1024,
+            table_elements: 100_000,
+        }
+    }
+}
+
+/// How instance memory is allocated, as actually configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocationStrategy {
+    OnDemand,
+    Pooling,
+}
+
+/// Per-call parameters forwarded from the `RunWasm` request.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Passed after the program name, which is `label`.
+    pub args: Vec<String>,
+    /// Requested environment; only names in `allowed_env` reach the module.
+    pub env: Vec<(String, String)>,
+    /// Environment variable names the module may receive, from `env` or the
+    /// node (`WasmRuntime::set_node_env`). Registered modules use their
+    /// manifest's list instead.
+    pub allowed_env: Vec<String>,
+    pub limits: ExecutionLimits,
+    /// Capabilities granted to the module; host imports check these.
+    pub capabilities: Vec<Capability>,
+    /// Signature over the module bytes, checked when signatures are required.
+    pub signature: Option<ModuleSignature>,
+    /// Core namespace the module's `core_query` calls are confined to.
+    /// Registered modules confined by their manifest only run for callers
+    /// in that namespace, or none.
+    pub namespace: Option<String>,
+    /// Whose core grants the module's `core_query` calls are held to, if
+    /// any. Registered modules always run as `module:<name>`.
+    pub principal: Option<String>,
+    /// Shown in `WasmRuntime::executions`, e.g. the module name or path.
+    pub label: String,
+    /// Who asked for the run, e.g. an IPC client; capped by
+    /// `max_executions_per_source`. Empty is never capped.
+    pub source: String,
+    /// Overrides `RuntimeConfig::max_queue_wait`.
+    pub max_queue_wait: Option<Duration>,
+}
+
+/// What happened when a module ran.
+#[derive(Debug, Clone, Default)]
+pub struct ExecutionResult {
+    pub stdout: String,
+    pub stderr: String,
+    /// The region returned by a memory-ABI `run`; `None` for `_start` modules.
+    pub output: Option<Vec<u8>>,
+    /// Set when the module exited through WASI `proc_exit`.
+    pub exit_code: Option<i32>,
+    /// Only known when fuel metering is enabled.
+    pub fuel_used: Option<u64>,
+    /// High-water mark of the instance's linear memory.
+    pub peak_memory_bytes: u64,
+    pub duration: Duration,
+    pub trapped: bool,
+    pub trap: Option<TrapInfo>,
+    /// Messages from the `log` import, up to `HostBudget::max_log_messages`.
+    pub logs: Vec<GuestLog>,
+    /// `log` calls refused after the budget ran out.
+    pub logs_dropped: u32,
+    /// The compiled module was loaded from the cache instead of compiled.
+    pub cache_hit: bool,
+    /// Ran under deterministic mode, so the same module and input give the same result anywhere.
+    pub deterministic: bool,
+}
+
+/// Why a module trapped.
+#[derive(Debug, Clone)]
+pub struct TrapInfo {
+    /// wasmtime's trap code, e.g. `UnreachableCodeReached`. `None` for traps
+    /// raised by host functions.
+    pub code: Option<String>,
+    pub message: String,
+    /// Wasm frames at the trap, when wasmtime captured them.
+    pub backtrace: Option<String>,
+}
+
+/// Per-execution store contents.
+pub(crate) struct StoreState {
+    wasi: WasiP1Ctx,
+    limiter: StoreLimiter,
+    host: HostState,
+}
+
+/// Gives components the same WASI context core modules see through preview 1.
+impl WasiView for StoreState {
+    fn ctx(&mut self) -> WasiCtxView<'_> {
+        self.wasi.ctx()
+    }
+}
 
-#[allow(dead_code)]
 pub struct WasmRuntime {
     engine: Engine,
+    linker: Linker<StoreState>,
+    component_linker: ComponentLinker<StoreState>,
+    cache: Option<ModuleCache>,
+    host: Option<Arc<dyn HostContext>>,
+    admission: Admission,
+    executions: ExecutionTable,
+    module_stats: StatsTable,
+    /// Node-provided variables, injected into modules allowed to see them.
+    node_env: RwLock<Vec<(String, String)>>,
+    allocation: AllocationStrategy,
+    allowlist: Option<Allowlist>,
+    config: RuntimeConfig,
 }
 
 impl WasmRuntime {
-    pub fn new() -> Result<Self> {
+    pub fn new() -> anyhow::Result<Self> {
+        Self::with_config(RuntimeConfig::default())
+    }
+
+    /// A runtime for modules whose results are compared across mesh nodes.
+    ///
+    /// NaNs are canonicalised, SIMD and threads are disabled, and modules
+    /// importing clocks, randomness or timers are rejected before they run.
+    pub fn new_deterministic() -> anyhow::Result<Self> {
+        Self::with_config(RuntimeConfig {
+            deterministic: true,
+            ..Default::default()
+        })
+    }
+
+    pub fn with_config(runtime_config: RuntimeConfig) -> anyhow::Result<Self> {
         let mut config = Config::default();
         // Configure for security: limit memory, CPU, etc.
         config.max_wasm_stack(1024 * 1024); // 1MB stack limit
-        let engine = Engine::new(&config)?;
-        Ok(Self { engine })
+        config.consume_fuel(true); // Bound CPU: every instruction costs fuel
+        config.async_support(true); // Run on the executor, yielding as fuel is consumed
+        config.epoch_interruption(true); // Lets ExecutionHandle::cancel interrupt a running store
+        config.wasm_component_model(true); // Preview-2 components run alongside core modules
+        if runtime_config.deterministic {
+            config
+                .cranelift_nan_canonicalization(true)
+                .wasm_simd(false)
+                .wasm_relaxed_simd(false)
+                .wasm_threads(false);
+        }
+        let (engine, allocation) = build_engine(&mut config, runtime_config.pooling.as_ref())?;
+
+        // WASI preview1 imports are linked for every module; modules that
+        // don't import them are unaffected.
+        let mut linker = Linker::new(&engine);
+        wasmtime_wasi::p1::add_to_linker_async(&mut linker, |state: &mut StoreState| &mut state.wasi)?;
+        host::add_to_linker(&mut linker)?;
+        // Components get WASI preview 2 only; the `sovereign` host imports are core-module ABI.
+        let mut component_linker = ComponentLinker::new(&engine);
+        wasmtime_wasi::p2::add_to_linker_async(&mut component_linker)?;
+
+        let cache = match &runtime_config.cache_dir {
+            Some(dir) => Some(ModuleCache::open(dir.clone(), runtime_config.cache_max_bytes)?),
+            None => None,
+        };
+        let allowlist = runtime_config.allowlist.as_ref().map(Allowlist::open).transpose()?;
+
+        Ok(Self {
+            engine,
+            linker,
+            component_linker,
+            cache,
+            host: None,
+            admission: Admission::new(
+                runtime_config.max_concurrent_executions,
+                runtime_config.max_queued_executions,
+                runtime_config.max_executions_per_source,
+            ),
+            executions: ExecutionTable::default(),
+            module_stats: StatsTable::default(),
+            node_env: RwLock::new(Vec::new()),
+            allocation,
+            allowlist,
+            config: runtime_config,
+        })
+    }
+
+    pub fn allocation_strategy(&self) -> AllocationStrategy {
+        self.allocation
+    }
+
+    /// The module allow-list, if one is configured.
+    pub fn allowlist(&self) -> Option<&Allowlist> {
+        self.allowlist.as_ref()
+    }
+
+    /// Refuses `sha256` if an enforced allow-list does not contain it.
+    pub(crate) fn check_allowed(&self, sha256: &str) -> Result<(), WasmError> {
+        match &self.allowlist {
+            Some(allowlist) => allowlist.check(sha256),
+            None => Ok(()),
+        }
+    }
+
+    /// Cancels a running execution. Returns whether one with this id was running.
+    pub fn cancel(&self, execution_id: u64) -> bool {
+        match self.executions.get(execution_id) {
+            Some(handle) => {
+                handle.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn executions(&self) -> Vec<ExecutionSummary> {
+        self.executions.list()
+    }
+
+    /// Sets the node-provided environment variables. A module only receives
+    /// those its `allowed_env` names; the values are never logged.
+    pub fn set_node_env(&self, vars: Vec<(String, String)>) {
+        *self.node_env.write().unwrap_or_else(|e| e.into_inner()) = vars;
+    }
+
+    /// Cumulative usage of each registered module that has run, by name.
+    pub fn module_stats(&self) -> HashMap<String, ModuleStats> {
+        self.module_stats.snapshot()
+    }
+
+    /// Running and queued executions, and how long the queue has made them wait.
+    pub fn admission_stats(&self) -> AdmissionStats {
+        self.admission.stats()
+    }
+
+    /// Connects the `sovereign.*` host imports to node services.
+    pub fn with_host_context(mut self, host: Arc<dyn HostContext>) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    /// Compiles a module into the on-disk cache ahead of its first run and
+    /// returns the path of the compiled artifact.
+    pub fn precompile(&self, bytes: &[u8]) -> anyhow::Result<PathBuf> {
+        match &self.cache {
+            Some(cache) => cache.precompile(&self.engine, &validate::to_binary(bytes)?),
+            None => anyhow::bail!("module cache is disabled"),
+        }
+    }
+
+    /// Checks that `bytes` is a module this runtime can compile, without running it.
+    /// Compiles fully, so with the cache enabled the first run is warm.
+    pub fn validate(&self, bytes: &[u8]) -> Result<(), WasmError> {
+        self.compile(bytes).map(|_| ())
+    }
+
+    /// Lists what a module imports, exports and declares, and whether it is valid,
+    /// without compiling it to native code. Accepts the text format too.
+    pub fn validate_only(&self, bytes: &[u8]) -> ValidationReport {
+        let binary = match validate::to_binary(bytes) {
+            Ok(binary) => binary,
+            Err(e) => {
+                return ValidationReport {
+                    error: Some(ValidationError {
+                        offset: 0,
+                        section: "text".to_string(),
+                        instruction: None,
+                        message: e.to_string(),
+                    }),
+                    text_format: true,
+                    ..Default::default()
+                }
+            }
+        };
+        let mut report = validate::inspect(&binary);
+        report.text_format = matches!(binary, Cow::Owned(_));
+        // wasmparser accepts proposals this engine may have disabled.
+        // Components are only checked by wasmparser here; compiling one is the full check.
+        if report.valid && !report.component {
+            if let Err(e) = Module::validate(&self.engine, &binary) {
+                report.valid = false;
+                report.error = Some(ValidationError {
+                    offset: 0,
+                    section: "module".to_string(),
+                    instruction: None,
+                    message: format!("{:#}", e),
+                });
+            }
+        }
+        report
+    }
+
+    /// Compiles `bytes`, converting the text format first. Invalid modules are
+    /// reported with the offset and section that failed.
+    pub(crate) fn compile(&self, bytes: &[u8]) -> Result<(Compiled, bool), WasmError> {
+        let binary = validate::to_binary(bytes)?;
+        let compiled = match &self.cache {
+            Some(cache) => cache.load_or_compile(&self.engine, &binary),
+            None => Compiled::new(&self.engine, &binary).map(|module| (module, false)),
+        };
+        compiled.map_err(|e| match validate::inspect(&binary).error {
+            Some(error) => WasmError::Validation(error),
+            None => WasmError::Compile(format!("{:#}", e)),
+        })
+    }
+
+    /// Compiles, instantiates and runs a module. `bytes` may be a binary module
+    /// or the text format.
+    ///
+    /// Two entry points are recognised, in this order:
+    /// - `run(ptr: i32, len: i32) -> (i32, i32)`: the memory ABI. The module must also
+    ///   export `memory` and `alloc(len: i32) -> i32`; the host copies `input` into a
+    ///   buffer from `alloc`, and the returned `(ptr, len)` region is the output.
+    /// - `_start`: a command-style module, called with no arguments.
+    ///
+    /// Or `bytes` may be a preview-2 component exporting `wasi:cli/run`.
+    /// Components see WASI preview 2 only; a failing `run` is reported as exit code 1.
+    ///
+    /// Core modules may import WASI preview1 and the `sovereign` host functions,
+    /// which check `options.capabilities`. `input` is also readable on stdin,
+    /// stdout/stderr are captured, and the configured preopens are visible only
+    /// with the `wasi.fs` capability.
+    ///
+    /// Execution yields to the executor every `fuel_yield_interval` units of
+    /// fuel, so a slow module does not hold a worker thread. Compilation is
+    /// still synchronous; the compilation cache keeps repeat runs cheap.
+    ///
+    /// With `require_signatures`, `options.signature` must be a valid
+    /// signature over `bytes` from a trusted publisher. With an allow-list,
+    /// the SHA-256 of `bytes` must be on it.
+    pub async fn run_module(&self, bytes: &[u8], input: &str, options: &RunOptions) -> Result<ExecutionResult, WasmError> {
+        self.start(bytes, input, options).1.await
+    }
+
+    /// Like `run_module`, but also returns a handle that can cancel the run.
+    /// The execution is listed in `executions` until the future completes or is dropped.
+    pub fn start<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        input: &'a str,
+        options: &'a RunOptions,
+    ) -> (ExecutionHandle, impl Future<Output = Result<ExecutionResult, WasmError>> + Send + 'a) {
+        self.start_with(bytes, input, None, options)
+    }
+
+    /// Runs a module that moves its data through the streaming imports
+    /// `sovereign.input_read(ptr, len) -> n` and `sovereign.output_write(ptr, len) -> n`
+    /// instead of holding it all in memory. Stdin is empty, and output written
+    /// by the module goes to `stream.output` as it is produced; it is flushed
+    /// before this returns. `HostBudget::max_input_bytes` and `max_output_bytes`
+    /// cap both directions.
+    pub async fn run_streaming(
+        &self,
+        bytes: &[u8],
+        stream: StreamIo,
+        options: &RunOptions,
+    ) -> Result<ExecutionResult, WasmError> {
+        self.start_streaming(bytes, stream, options).1.await
+    }
+
+    /// Like `run_streaming`, but also returns a handle that can cancel the run.
+    pub fn start_streaming<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        stream: StreamIo,
+        options: &'a RunOptions,
+    ) -> (ExecutionHandle, impl Future<Output = Result<ExecutionResult, WasmError>> + Send + 'a) {
+        self.start_with(bytes, "", Some(stream), options)
+    }
+
+    fn start_with<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        input: &'a str,
+        stream: Option<StreamIo>,
+        options: &'a RunOptions,
+    ) -> (ExecutionHandle, impl Future<Output = Result<ExecutionResult, WasmError>> + Send + 'a) {
+        let (handle, guard) = self.executions.register(&self.engine, options.label.clone());
+        let run = async move {
+            if self.allowlist.is_some() {
+                self.check_allowed(&hex::encode(Sha256::digest(bytes)))?;
+            }
+            if self.config.require_signatures {
+                signing::verify_module(bytes, None, options.signature.as_ref(), &self.config.trusted_publishers)?;
+            }
+            let (module, cache_hit) = self.compile(bytes)?;
+            self.execute_module(&module, cache_hit, input, stream, options, &guard).await
+        };
+        (handle, run)
+    }
+
+    /// Runs a compiled module in a fresh store. This is the hot path for
+    /// registered modules, which are compiled once at registration.
+    pub(crate) async fn execute_module(
+        &self,
+        compiled: &Compiled,
+        cache_hit: bool,
+        input: &str,
+        stream: Option<StreamIo>,
+        options: &RunOptions,
+        execution: &ExecutionGuard<'_>,
+    ) -> Result<ExecutionResult, WasmError> {
+        let cancelled = &execution.cancelled;
+        let max_wait = options.max_queue_wait.unwrap_or(self.config.max_queue_wait);
+        let _slot = self.admission.admit(&options.source, max_wait).await?;
+        let started = Instant::now();
+        if self.config.deterministic {
+            match compiled {
+                Compiled::Module(module) => check_deterministic_imports(module)?,
+                Compiled::Component(component) => component::check_deterministic_imports(&self.engine, component)?,
+            }
+        }
+
+        let preopens: &[Preopen] = if options.capabilities.contains(&Capability::WasiFs) {
+            &self.config.preopens
+        } else {
+            &[]
+        };
+        let allow_clocks = self.config.allow_clocks && !self.config.deterministic;
+        let argv: Vec<String> = std::iter::once(options.label.clone()).chain(options.args.iter().cloned()).collect();
+        let env = wasi::policy_env(
+            &options.env,
+            &options.allowed_env,
+            &self.node_env.read().unwrap_or_else(|e| e.into_inner()),
+        );
+        let session = wasi::build_session(input, &argv, &env, preopens, allow_clocks)
+            .map_err(|e| WasmError::Instantiate(format!("WASI setup failed: {:#}", e)))?;
+        let limits = options.limits.resolve(&self.config);
+        let fuel_limit = limits.fuel_limit;
+        let mut store = Store::new(
+            &self.engine,
+            StoreState {
+                wasi: session.ctx,
+                limiter: StoreLimiter::new(limits, self.config.trap_on_grow_failure),
+                host: HostState::new(self.host.clone(), options, limits.host_budget, execution.id, stream),
+            },
+        );
+        store.limiter(|state| &mut state.limiter);
+        // Epochs only advance on cancel, so the callback runs once per cancel anywhere.
+        store.set_epoch_deadline(1);
+        let flag = cancelled.clone();
+        store.epoch_deadline_callback(move |_| {
+            if flag.load(Ordering::SeqCst) {
+                anyhow::bail!("execution cancelled");
+            }
+            Ok(UpdateDeadline::Continue(1))
+        });
+        // Cancelled while queued for a slot or before the deadline was armed.
+        if cancelled.load(Ordering::SeqCst) {
+            return Err(WasmError::Cancelled);
+        }
+        let mut result = ExecutionResult {
+            cache_hit,
+            deterministic: self.config.deterministic,
+            ..Default::default()
+        };
+
+        store
+            .set_fuel(fuel_limit)
+            .and_then(|()| store.fuel_async_yield_interval(Some(self.config.fuel_yield_interval.max(1))))
+            .map_err(|e| WasmError::Instantiate(format!("{:#}", e)))?;
+
+        let outcome = match compiled {
+            Compiled::Module(module) => self
+                .call_module(&mut store, module, input)
+                .await
+                .map(|output| result.output = output),
+            Compiled::Component(component) => component::call_run(&mut store, &self.component_linker, component)
+                .await
+                .map(|exit_code| result.exit_code = exit_code),
+        };
+
+        match outcome {
+            Ok(()) => {}
+            // proc_exit unwinds like a trap but is a normal way to finish.
+            Err(CallError::Trap(e)) => match e.downcast_ref::<I32Exit>() {
+                Some(exit) => result.exit_code = Some(exit.0),
+                None => {
+                    check_aborted(&store, fuel_limit, cancelled, &e)?;
+                    record_trap(&mut result, &e);
+                }
+            },
+            Err(CallError::Wasm(e)) => return Err(e),
+        }
+
+        host::flush_stream(&mut store.data_mut().host).await;
+        finish(&mut result, &mut store, (&session.stdout, &session.stderr), fuel_limit, started);
+        Ok(result)
+    }
+
+    /// Instantiates a core module and calls its entry point. Returns the
+    /// memory-ABI output, or `None` for `_start` modules.
+    async fn call_module(
+        &self,
+        store: &mut Store<StoreState>,
+        module: &Module,
+        input: &str,
+    ) -> Result<Option<Vec<u8>>, CallError> {
+        let instance = match self.linker.instantiate_async(&mut *store, module).await {
+            Ok(instance) => instance,
+            // A trapping start function is still a trap, not a broken module.
+            Err(e) if e.downcast_ref::<Trap>().is_some() => return Err(CallError::Trap(e)),
+            Err(e) => return Err(WasmError::Instantiate(format!("{:#}", e)).into()),
+        };
+
+        if instance.get_export(&mut *store, "run").is_some() {
+            run_memory_abi(store, &instance, input).await.map(Some)
+        } else if instance.get_export(&mut *store, "_start").is_some() {
+            let start = instance
+                .get_typed_func::<(), ()>(&mut *store, "_start")
+                .map_err(|e| WasmError::MissingExport(format!("_start: {:#}", e)))?;
+            start.call_async(&mut *store, ()).await.map_err(CallError::Trap)?;
+            Ok(None)
+        } else {
+            Err(WasmError::MissingExport("run or _start".into()).into())
+        }
+    }
+}
+
+/// Fills in the fields every outcome reports.
+fn finish(
+    result: &mut ExecutionResult,
+    store: &mut Store<StoreState>,
+    (stdout, stderr): (&MemoryOutputPipe, &MemoryOutputPipe),
+    fuel_limit: u64,
+    started: Instant,
+) {
+    result.stdout = wasi::captured(stdout);
+    result.stderr = wasi::captured(stderr);
+    result.fuel_used = Some(fuel_limit - store.get_fuel().unwrap_or(0));
+    result.peak_memory_bytes = store.data().limiter.peak_memory_bytes as u64;
+    let host = &mut store.data_mut().host;
+    result.logs = std::mem::take(&mut host.logs);
+    result.logs_dropped = host.logs_dropped;
+    result.duration = started.elapsed();
+}
+
+pub(crate) enum CallError {
+    Trap(anyhow::Error),
+    Wasm(WasmError),
+}
+
+impl From<WasmError> for CallError {
+    fn from(e: WasmError) -> Self {
+        CallError::Wasm(e)
+    }
+}
+
+/// Creates the engine, preferring the pooling allocator when configured.
+fn build_engine(config: &mut Config, pooling: Option<&PoolingConfig>) -> anyhow::Result<(Engine, AllocationStrategy)> {
+    if let Some(pooling) = pooling {
+        let mut pool = PoolingAllocationConfig::default();
+        pool.total_core_instances(pooling.total_instances)
+            .total_memories(pooling.total_instances)
+            .total_tables(pooling.total_instances)
+            .total_stacks(pooling.total_instances)
+            .max_memory_size(pooling.max_memory_bytes)
+            .table_elements(pooling.table_elements);
+        config.allocation_strategy(InstanceAllocationStrategy::Pooling(pool));
+        match Engine::new(config) {
+            Ok(engine) => {
+                log::info!("WASM runtime using the pooling allocator ({} slots)", pooling.total_instances);
+                return Ok((engine, AllocationStrategy::Pooling));
+            }
+            Err(e) => log::warn!("Pooling allocator unavailable, falling back to on-demand: {:#}", e),
+        }
+        config.allocation_strategy(InstanceAllocationStrategy::OnDemand);
+    }
+    log::info!("WASM runtime using the on-demand allocator");
+    Ok((Engine::new(config)?, AllocationStrategy::OnDemand))
+}
+
+/// WASI imports whose results differ between hosts or runs.
+const NONDETERMINISTIC_IMPORTS: &[&str] = &["clock_res_get", "clock_time_get", "random_get", "poll_oneoff"];
+
+fn check_deterministic_imports(module: &Module) -> Result<(), WasmError> {
+    for import in module.imports() {
+        let allowed = match import.module() {
+            HOST_MODULE => true,
+            "wasi_snapshot_preview1" | "wasi_unstable" => !NONDETERMINISTIC_IMPORTS.contains(&import.name()),
+            _ => false,
+        };
+        if !allowed {
+            return Err(WasmError::ForbiddenImport {
+                module: import.module().to_string(),
+                name: import.name().to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Fuel exhaustion and cancellation are reported as errors rather than ordinary traps.
+fn check_aborted(
+    store: &Store<StoreState>,
+    fuel_limit: u64,
+    cancelled: &AtomicBool,
+    e: &anyhow::Error,
+) -> Result<(), WasmError> {
+    if cancelled.load(Ordering::SeqCst) {
+        return Err(WasmError::Cancelled);
+    }
+    match e.downcast_ref::<Trap>() {
+        Some(Trap::OutOfFuel) => Err(WasmError::OutOfFuel {
+            consumed: fuel_limit - store.get_fuel().unwrap_or(0),
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn record_trap(result: &mut ExecutionResult, e: &anyhow::Error) {
+    let trap = e.downcast_ref::<Trap>();
+    result.trapped = true;
+    result.trap = Some(TrapInfo {
+        code: trap.map(|trap| format!("{:?}", trap)),
+        // For host-raised traps the root cause is the useful part.
+        message: match trap {
+            Some(trap) => trap.to_string(),
+            None => e.root_cause().to_string(),
+        },
+        backtrace: e.downcast_ref::<WasmBacktrace>().map(|bt| bt.to_string()),
+    });
+}
+
+/// Passes `input` through guest memory and reads back the output region.
+async fn run_memory_abi(store: &mut Store<StoreState>, instance: &Instance, input: &str) -> Result<Vec<u8>, CallError> {
+    let memory = instance
+        .get_memory(&mut *store, "memory")
+        .ok_or_else(|| WasmError::MissingExport("memory".into()))?;
+    let alloc = instance
+        .get_typed_func::<i32, i32>(&mut *store, "alloc")
+        .map_err(|e| WasmError::MissingExport(format!("alloc(i32) -> i32: {:#}", e)))?;
+    let run = instance
+        .get_typed_func::<(i32, i32), (i32, i32)>(&mut *store, "run")
+        .map_err(|e| WasmError::MissingExport(format!("run(i32, i32) -> (i32, i32): {:#}", e)))?;
+
+    let input_len = i32::try_from(input.len()).map_err(|_| WasmError::InvalidOutput("input too large".into()))?;
+    let in_ptr = alloc.call_async(&mut *store, input_len).await.map_err(CallError::Trap)?;
+    memory
+        .write(&mut *store, in_ptr as u32 as usize, input.as_bytes())
+        .map_err(|_| WasmError::InvalidOutput(format!("alloc returned out-of-bounds pointer {}", in_ptr)))?;
+
+    let (out_ptr, out_len) = run
+        .call_async(&mut *store, (in_ptr, input_len))
+        .await
+        .map_err(CallError::Trap)?;
+
+    let mut output = vec![0u8; out_len as u32 as usize];
+    memory
+        .read(&*store, out_ptr as u32 as usize, &mut output)
+        .map_err(|_| WasmError::InvalidOutput(format!("output region {}+{} is out of bounds", out_ptr, out_len)))?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use std::path::Path;
+
+    async fn run(wat: &str, options: &RunOptions) -> Result<ExecutionResult, WasmError> {
+        WasmRuntime::new().unwrap().run_module(wat.as_bytes(), "", options).await
+    }
+
+    #[tokio::test]
+    async fn captures_stdout_and_stderr() {
+        let result = run(&fixtures::prints(), &RunOptions::default()).await.unwrap();
+        assert_eq!(result.stdout, "to stdout\n");
+        assert_eq!(result.stderr, "to stderr\n");
+        assert_eq!(result.exit_code, None);
+        assert!(!result.trapped);
+        assert!(result.trap.is_none());
+        assert!(result.fuel_used.is_some_and(|fuel| fuel > 0));
+    }
+
+    #[tokio::test]
+    async fn reports_a_nonzero_exit_apart_from_a_trap() {
+        let result = run(&fixtures::exits(3), &RunOptions::default()).await.unwrap();
+        assert_eq!(result.exit_code, Some(3));
+        assert_eq!(result.stderr, "bye\n");
+        assert!(!result.trapped);
+    }
+
+    #[tokio::test]
+    async fn reports_a_trap_and_keeps_what_was_printed() {
+        let result = run(&fixtures::traps(), &RunOptions::default()).await.unwrap();
+        assert!(result.trapped);
+        assert_eq!(result.exit_code, None);
+        assert_eq!(result.stdout, "before\n");
+        let trap = result.trap.unwrap();
+        assert_eq!(trap.code.as_deref(), Some("UnreachableCodeReached"));
+        assert!(!trap.message.is_empty());
+    }
+
+    #[tokio::test]
+    async fn passes_args_after_the_label() {
+        let options = RunOptions {
+            label: "prog".into(),
+            args: vec!["a".into(), "b c".into()],
+            ..Default::default()
+        };
+        let result = run(&fixtures::echo_args(), &options).await.unwrap();
+        assert_eq!(result.stdout, "prog\0a\0b c\0");
+    }
+
+    #[tokio::test]
+    async fn passes_only_allowed_env() {
+        let options = RunOptions {
+            env: vec![("KEEP".into(), "1".into()), ("DROP".into(), "2".into())],
+            allowed_env: vec!["KEEP".into()],
+            ..Default::default()
+        };
+        let result = run(&fixtures::echo_env(), &options).await.unwrap();
+        assert_eq!(result.stdout, "KEEP=1\0");
+    }
+
+    #[tokio::test]
+    async fn injects_node_variables_only_where_allowed() {
+        let runtime = WasmRuntime::new().unwrap();
+        runtime.set_node_env(vec![("SOVEREIGN_PEER_ID".into(), "12D3Koo".into()), ("SOVEREIGN_MACHINE_HASH".into(), "ab12".into())]);
+        let env = |allowed: &[&str]| RunOptions {
+            env: vec![("SOVEREIGN_PEER_ID".into(), "spoofed".into()), ("GREETING".into(), "hi".into())],
+            allowed_env: allowed.iter().map(|name| name.to_string()).collect(),
+            ..Default::default()
+        };
+        let (runtime, wat) = (&runtime, fixtures::echo_env());
+        let wat = &wat;
+        let stdout = |options| async move { runtime.run_module(wat.as_bytes(), "", &options).await.unwrap().stdout };
+        // The node's value wins over the caller's.
+        assert_eq!(stdout(env(&["SOVEREIGN_PEER_ID", "GREETING"])).await, "SOVEREIGN_PEER_ID=12D3Koo\0GREETING=hi\0");
+        assert_eq!(stdout(env(&["SOVEREIGN_MACHINE_HASH"])).await, "SOVEREIGN_MACHINE_HASH=ab12\0");
+        assert_eq!(stdout(env(&[])).await, "");
+    }
+
+    #[tokio::test]
+    async fn fuel_limit_bounds_the_run() {
+        let options = RunOptions {
+            limits: ExecutionLimits {
+                fuel_limit: Some(50_000),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        match run(&fixtures::spins(), &options).await {
+            Err(WasmError::OutOfFuel { consumed }) => assert_eq!(consumed, 50_000),
+            other => panic!("Expected OutOfFuel, got {:?}", other.map(|r| r.stdout)),
+        }
+
+        let small = run(&fixtures::prints(), &options).await.unwrap();
+        assert!(small.fuel_used.unwrap() < 50_000);
+    }
+
+    #[tokio::test]
+    async fn echoes_input_through_the_memory_abi() {
+        let runtime = WasmRuntime::new().unwrap();
+        let result = runtime.run_module(fixtures::echo().as_bytes(), "hello", &RunOptions::default()).await.unwrap();
+        assert_eq!(result.output.as_deref(), Some(&b"hello"[..]));
+        assert!(!result.trapped);
+    }
+
+    #[tokio::test]
+    async fn a_trap_in_run_is_reported_as_a_trap() {
+        let result = run(&fixtures::run_traps(), &RunOptions::default()).await.unwrap();
+        assert!(result.trapped);
+        assert_eq!(result.output, None);
+        assert_eq!(result.trap.unwrap().code.as_deref(), Some("UnreachableCodeReached"));
+    }
+
+    #[tokio::test]
+    async fn broken_modules_fail_with_distinct_errors() {
+        let no_entry = r#"(module (memory (export "memory") 1))"#;
+        assert!(matches!(run(no_entry, &RunOptions::default()).await, Err(WasmError::MissingExport(name)) if name == "run or _start"));
+
+        let no_alloc = r#"(module (memory (export "memory") 1) (func (export "run") (param i32 i32) (result i32 i32) (i32.const 0) (i32.const 0)))"#;
+        assert!(matches!(run(no_alloc, &RunOptions::default()).await, Err(WasmError::MissingExport(name)) if name.starts_with("alloc")));
+
+        let unknown_import = r#"(module (import "elsewhere" "f" (func)) (func (export "_start")))"#;
+        assert!(matches!(run(unknown_import, &RunOptions::default()).await, Err(WasmError::Instantiate(_))));
+
+        assert!(matches!(run("(module (func", &RunOptions::default()).await, Err(WasmError::Compile(_))));
+    }
+
+    #[tokio::test]
+    async fn reads_stdin_and_writes_stdout() {
+        let runtime = WasmRuntime::new().unwrap();
+        let result = runtime.run_module(fixtures::cat().as_bytes(), "piped in", &RunOptions::default()).await.unwrap();
+        assert_eq!(result.stdout, "piped in");
+        assert_eq!(result.exit_code, None);
+    }
+
+    #[tokio::test]
+    async fn opens_files_only_under_its_preopens() {
+        let root = tempfile::tempdir().unwrap();
+        let data = root.path().join("data");
+        std::fs::create_dir(&data).unwrap();
+        std::fs::write(data.join("inside.txt"), "inside").unwrap();
+        std::fs::write(root.path().join("outside.txt"), "outside").unwrap();
+        let runtime = WasmRuntime::with_config(RuntimeConfig {
+            preopens: vec![Preopen {
+                host_path: data,
+                guest_path: "/data".into(),
+                read_only: true,
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+        let granted = RunOptions {
+            capabilities: vec![Capability::WasiFs],
+            ..Default::default()
+        };
+        let cat_file = |path: &str, options: &RunOptions| {
+            let wat = fixtures::cat_file(path);
+            let options = options.clone();
+            let runtime = &runtime;
+            async move { runtime.run_module(wat.as_bytes(), "", &options).await.unwrap() }
+        };
+
+        let inside = cat_file("inside.txt", &granted).await;
+        assert_eq!((inside.stdout.as_str(), inside.exit_code), ("inside", None));
+
+        for escape in ["../outside.txt", "/etc/passwd"] {
+            let result = cat_file(escape, &granted).await;
+            assert!(result.exit_code.is_some_and(|errno| errno != 0), "{} opened", escape);
+            assert_eq!(result.stdout, "");
+        }
+
+        // Without `wasi.fs` there is no preopen at all.
+        let denied = cat_file("inside.txt", &RunOptions::default()).await;
+        assert!(denied.exit_code.is_some_and(|errno| errno != 0));
+        assert_eq!(denied.stdout, "");
+    }
+
+    #[tokio::test]
+    async fn fuel_requests_are_held_to_the_runtime_ceiling() {
+        let runtime = WasmRuntime::with_config(RuntimeConfig {
+            default_fuel_limit: 20_000,
+            max_fuel_limit: 30_000,
+            ..Default::default()
+        })
+        .unwrap();
+        let spins = fixtures::spins();
+        match runtime.run_module(spins.as_bytes(), "", &RunOptions::default()).await {
+            Err(WasmError::OutOfFuel { consumed }) => assert_eq!(consumed, 20_000),
+            other => panic!("Expected OutOfFuel, got {:?}", other.map(|r| r.stdout)),
+        }
+        let greedy = RunOptions {
+            limits: ExecutionLimits {
+                fuel_limit: Some(u64::MAX),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        match runtime.run_module(spins.as_bytes(), "", &greedy).await {
+            Err(WasmError::OutOfFuel { consumed }) => assert_eq!(consumed, 30_000),
+            other => panic!("Expected OutOfFuel, got {:?}", other.map(|r| r.stdout)),
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_growth_stops_at_the_cap() {
+        const PAGE: u64 = 64 * 1024;
+        let capped = RunOptions {
+            limits: ExecutionLimits {
+                max_memory_bytes: Some(16 * 1024 * 1024),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        // One page to start with, plus a gigabyte.
+        let greedy = run(&fixtures::grows(16 * 1024), &capped).await.unwrap();
+        assert_eq!(greedy.exit_code, Some(1));
+        assert_eq!(greedy.peak_memory_bytes, PAGE);
+
+        let modest = run(&fixtures::grows(10), &capped).await.unwrap();
+        assert_eq!(modest.exit_code, None);
+        assert_eq!(modest.peak_memory_bytes, 11 * PAGE);
+    }
+
+    #[tokio::test]
+    async fn memory_growth_can_trap_instead() {
+        let runtime = WasmRuntime::with_config(RuntimeConfig {
+            max_memory_bytes: 16 * 1024 * 1024,
+            trap_on_grow_failure: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let result = runtime.run_module(fixtures::grows(16 * 1024).as_bytes(), "", &RunOptions::default()).await.unwrap();
+        assert!(result.trapped);
+        assert_eq!(result.exit_code, None);
+        assert!(result.trap.unwrap().message.contains("exceeds the limit"));
+    }
+
+    fn cached_runtime(dir: &Path) -> WasmRuntime {
+        WasmRuntime::with_config(RuntimeConfig {
+            cache_dir: Some(dir.to_path_buf()),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_second_run_loads_the_compiled_module_from_the_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let runtime = cached_runtime(dir.path());
+        let wat = fixtures::prints();
+        let first = runtime.run_module(wat.as_bytes(), "", &RunOptions::default()).await.unwrap();
+        let second = runtime.run_module(wat.as_bytes(), "", &RunOptions::default()).await.unwrap();
+        assert!(!first.cache_hit);
+        assert!(second.cache_hit);
+        assert_eq!((&first.stdout, &first.stderr, first.fuel_used), (&second.stdout, &second.stderr, second.fuel_used));
+
+        // A fresh runtime over the same directory, as after a restart.
+        let restarted = cached_runtime(dir.path());
+        assert!(restarted.run_module(wat.as_bytes(), "", &RunOptions::default()).await.unwrap().cache_hit);
+    }
+
+    #[tokio::test]
+    async fn precompiled_modules_run_from_the_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let runtime = cached_runtime(dir.path());
+        let wat = fixtures::echo();
+        let path = runtime.precompile(wat.as_bytes()).unwrap();
+        assert!(path.starts_with(dir.path()));
+        let result = runtime.run_module(wat.as_bytes(), "hi", &RunOptions::default()).await.unwrap();
+        assert!(result.cache_hit);
+        assert_eq!(result.output.as_deref(), Some(&b"hi"[..]));
+    }
+
+    #[tokio::test]
+    async fn a_corrupt_cache_entry_is_recompiled() {
+        let dir = tempfile::tempdir().unwrap();
+        let runtime = cached_runtime(dir.path());
+        let wat = fixtures::prints();
+        let path = runtime.precompile(wat.as_bytes()).unwrap();
+        std::fs::write(&path, b"not a compiled module").unwrap();
+        let result = runtime.run_module(wat.as_bytes(), "", &RunOptions::default()).await.unwrap();
+        assert!(!result.cache_hit);
+        assert_eq!(result.stdout, "to stdout\n");
+        assert!(runtime.run_module(wat.as_bytes(), "", &RunOptions::default()).await.unwrap().cache_hit);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_runs_of_an_uncached_module_all_succeed() {
+        let dir = tempfile::tempdir().unwrap();
+        let runtime = Arc::new(cached_runtime(dir.path()));
+        let runs: Vec<_> = (0..8)
+            .map(|_| {
+                let runtime = runtime.clone();
+                tokio::spawn(async move { runtime.run_module(fixtures::prints().as_bytes(), "", &RunOptions::default()).await })
+            })
+            .collect();
+        for run in runs {
+            assert_eq!(run.await.unwrap().unwrap().stdout, "to stdout\n");
+        }
+        let entries: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(entries.len(), 1, "{:?}", entries);
+    }
+
+    /// With one executor thread, a ticker only keeps ticking if the modules
+    /// yield to it instead of holding the thread.
+    #[tokio::test(flavor = "current_thread")]
+    async fn spinning_modules_yield_to_other_tasks() {
+        let runtime = WasmRuntime::with_config(RuntimeConfig {
+            max_concurrent_executions: 2,
+            ..Default::default()
+        })
+        .unwrap();
+        let options = RunOptions {
+            limits: ExecutionLimits {
+                fuel_limit: Some(200_000_000),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let spins = fixtures::spins();
+        let ticks = std::sync::atomic::AtomicU32::new(0);
+        let ticker = async {
+            loop {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                ticks.fetch_add(1, Ordering::Relaxed);
+            }
+        };
+        let run = || runtime.run_module(spins.as_bytes(), "", &options);
+        let results = tokio::select! {
+            (a, b, c) = async { tokio::join!(run(), run(), run()) } => [a, b, c],
+            _ = ticker => unreachable!(),
+        };
+        assert!(results.iter().all(|result| matches!(result, Err(WasmError::OutOfFuel { .. }))));
+        assert!(ticks.load(Ordering::Relaxed) > 10, "ticked {} times", ticks.load(Ordering::Relaxed));
+        // Two ran at once and the third waited for a slot.
+        let stats = runtime.admission_stats();
+        assert_eq!((stats.running, stats.admitted), (0, 3));
+        assert!(stats.max_wait > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn every_outcome_fills_in_the_whole_result() {
+        const PAGE: u64 = 64 * 1024;
+        let clean = run(&fixtures::prints(), &RunOptions::default()).await.unwrap();
+        let exited = run(&fixtures::exits(7), &RunOptions::default()).await.unwrap();
+        let trapped = run(&fixtures::traps(), &RunOptions::default()).await.unwrap();
+        for result in [&clean, &exited, &trapped] {
+            assert_eq!(result.output, None);
+            assert!(result.fuel_used.is_some_and(|fuel| fuel > 0));
+            assert_eq!(result.peak_memory_bytes, PAGE);
+            assert!(result.duration > Duration::ZERO);
+            assert!(result.logs.is_empty());
+            assert_eq!(result.logs_dropped, 0);
+            assert!(!result.cache_hit);
+            assert!(!result.deterministic);
+        }
+
+        assert_eq!((clean.stdout.as_str(), clean.stderr.as_str(), clean.exit_code), ("to stdout\n", "to stderr\n", None));
+        assert!(!clean.trapped && clean.trap.is_none());
+
+        assert_eq!((exited.stdout.as_str(), exited.stderr.as_str(), exited.exit_code), ("", "bye\n", Some(7)));
+        assert!(!exited.trapped && exited.trap.is_none());
+
+        assert_eq!((trapped.stdout.as_str(), trapped.stderr.as_str(), trapped.exit_code), ("before\n", "", None));
+        assert!(trapped.trapped);
+        let trap = trapped.trap.unwrap();
+        assert_eq!(trap.code.as_deref(), Some("UnreachableCodeReached"));
+        assert!(trap.backtrace.is_some(), "{:?}", trap);
+    }
+
+    #[tokio::test]
+    async fn registered_runs_add_up_in_module_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = ModuleRegistry::open(Arc::new(WasmRuntime::new().unwrap()), dir.path().to_path_buf()).unwrap();
+        registry.register("printer", fixtures::prints().into_bytes(), ModuleManifest::default()).unwrap();
+        registry.register("trapper", fixtures::traps().into_bytes(), ModuleManifest::default()).unwrap();
+        let first = registry.run("printer", "", &RunOptions::default()).await.unwrap();
+        let second = registry.run("printer", "", &RunOptions::default()).await.unwrap();
+        registry.run("trapper", "", &RunOptions::default()).await.unwrap();
+
+        let stats = registry.runtime().module_stats();
+        let printer = &stats["printer"];
+        assert_eq!((printer.runs, printer.failures, printer.traps), (2, 0, 0));
+        assert_eq!(printer.fuel_used, first.fuel_used.unwrap() + second.fuel_used.unwrap());
+        assert_eq!(printer.total_duration, first.duration + second.duration);
+        assert_eq!(printer.peak_memory_bytes, 64 * 1024);
+        assert!(printer.last_run_ms.is_some());
+        assert_eq!((stats["trapper"].runs, stats["trapper"].traps), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn deterministic_runs_repeat_byte_for_byte() {
+        let runtime = WasmRuntime::new_deterministic().unwrap();
+        let wat = fixtures::floats();
+        let first = runtime.run_module(wat.as_bytes(), "", &RunOptions::default()).await.unwrap();
+        let second = runtime.run_module(wat.as_bytes(), "", &RunOptions::default()).await.unwrap();
+        assert!(first.deterministic && second.deterministic);
+        let output = first.output.unwrap();
+        assert_eq!(output.len(), 520);
+        assert_eq!(Some(output.clone()), second.output);
+        // The NaN comes out canonical.
+        assert_eq!(&output[512..], &f64::NAN.to_bits().to_le_bytes());
+    }
+
+    #[tokio::test]
+    async fn deterministic_mode_refuses_randomness_and_clocks() {
+        let runtime = WasmRuntime::new_deterministic().unwrap();
+        for (name, params) in [("random_get", "i32 i32"), ("clock_time_get", "i32 i64 i32")] {
+            let wat = format!(
+                r#"(module (import "wasi_snapshot_preview1" "{}" (func (param {}) (result i32))) (memory (export "memory") 1) (func (export "_start")))"#,
+                name, params
+            );
+            match runtime.run_module(wat.as_bytes(), "", &RunOptions::default()).await {
+                Err(WasmError::ForbiddenImport { module, name: import }) => assert_eq!((module.as_str(), import.as_str()), ("wasi_snapshot_preview1", name)),
+                other => panic!("Expected {} to be refused, got {:?}", name, other.map(|r| r.stdout)),
+            }
+            assert!(!WasmRuntime::new().unwrap().run_module(wat.as_bytes(), "", &RunOptions::default()).await.unwrap().deterministic);
+        }
+    }
+
+    #[tokio::test]
+    async fn pooled_and_on_demand_runs_agree() {
+        let pooled = WasmRuntime::with_config(RuntimeConfig {
+            pooling: Some(PoolingConfig {
+                total_instances: 4,
+                max_memory_bytes: 16 * 1024 * 1024,
+                table_elements: 1000,
+            }),
+            max_memory_bytes: 16 * 1024 * 1024,
+            ..Default::default()
+        })
+        .unwrap();
+        let on_demand = WasmRuntime::new().unwrap();
+        assert_eq!(pooled.allocation_strategy(), AllocationStrategy::Pooling);
+        assert_eq!(on_demand.allocation_strategy(), AllocationStrategy::OnDemand);
+
+        for (wat, input) in [(fixtures::prints(), ""), (fixtures::exits(2), ""), (fixtures::traps(), ""), (fixtures::echo(), "pooled")] {
+            // More runs than slots, so slots are reused.
+            for _ in 0..6 {
+                let a = pooled.run_module(wat.as_bytes(), input, &RunOptions::default()).await.unwrap();
+                let b = on_demand.run_module(wat.as_bytes(), input, &RunOptions::default()).await.unwrap();
+                assert_eq!((&a.stdout, &a.stderr, &a.output), (&b.stdout, &b.stderr, &b.output));
+                assert_eq!((a.exit_code, a.trapped, a.fuel_used), (b.exit_code, b.trapped, b.fuel_used));
+            }
+        }
+    }
+
+    #[test]
+    fn falls_back_to_on_demand_when_the_pool_cannot_be_reserved() {
+        let runtime = WasmRuntime::with_config(RuntimeConfig {
+            pooling: Some(PoolingConfig {
+                total_instances: 1_000_000,
+                max_memory_bytes: 1 << 40,
+                table_elements: 1000,
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(runtime.allocation_strategy(), AllocationStrategy::OnDemand);
+    }
+
+    #[tokio::test]
+    async fn cancelling_stops_a_spinning_module() {
+        let runtime = WasmRuntime::new().unwrap();
+        let spins = fixtures::spins();
+        let options = RunOptions {
+            label: "spinner".into(),
+            limits: ExecutionLimits {
+                fuel_limit: Some(u64::MAX),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (handle, run) = runtime.start(spins.as_bytes(), "", &options);
+        let cancel = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let running = runtime.executions();
+            assert_eq!(running.len(), 1);
+            assert_eq!((running[0].id, running[0].label.as_str()), (handle.id(), "spinner"));
+            assert!(runtime.cancel(handle.id()));
+            Instant::now()
+        };
+        let (result, cancelled_at) = tokio::join!(run, cancel);
+        assert!(matches!(result, Err(WasmError::Cancelled)), "{:?}", result.map(|r| r.stdout));
+        assert!(cancelled_at.elapsed() < Duration::from_millis(100), "Stopped {:?} after cancelling", cancelled_at.elapsed());
+        assert!(runtime.executions().is_empty());
+        assert!(!runtime.cancel(handle.id()));
+    }
+
+    #[tokio::test]
+    async fn a_run_cancelled_before_it_starts_never_runs() {
+        let runtime = WasmRuntime::new().unwrap();
+        let (prints, options) = (fixtures::prints(), RunOptions::default());
+        let (handle, run) = runtime.start(prints.as_bytes(), "", &options);
+        handle.cancel();
+        assert!(matches!(run.await, Err(WasmError::Cancelled)));
+
+        // Dropping the future unregisters it as well.
+        let (handle, run) = runtime.start(prints.as_bytes(), "", &options);
+        assert_eq!(runtime.executions().len(), 1);
+        drop(run);
+        assert!(runtime.executions().is_empty());
+        assert!(!runtime.cancel(handle.id()));
     }
 
-    pub fn run_module(&self, _bytes: &[u8], input: &str) -> Result<String> {
-        // Stub: For now, just simulate running WASM
-        // In full implementation, instantiate and run the module with input
-        let output = format!("Stub WASM execution: input={}", input);
-        Ok(output)
+    #[tokio::test]
+    async fn a_busy_client_leaves_slots_for_others() {
+        let runtime = WasmRuntime::with_config(RuntimeConfig {
+            max_concurrent_executions: 2,
+            max_executions_per_source: 1,
+            ..Default::default()
+        })
+        .unwrap();
+        let slow = fixtures::prints_after(30_000_000, "slow");
+        let greedy = RunOptions {
+            source: "greedy".into(),
+            max_queue_wait: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let other = RunOptions {
+            source: "other".into(),
+            ..Default::default()
+        };
+        let (first, second, third) = tokio::join!(
+            runtime.run_module(slow.as_bytes(), "", &greedy),
+            async {
+                tokio::task::yield_now().await;
+                runtime.run_module(slow.as_bytes(), "", &greedy).await
+            },
+            async {
+                tokio::task::yield_now().await;
+                runtime.run_module(fixtures::prints().as_bytes(), "", &other).await
+            },
+        );
+        assert_eq!(first.unwrap().stdout, "slow");
+        assert!(matches!(second, Err(WasmError::Busy { .. })));
+        assert_eq!(third.unwrap().stdout, "to stdout\n");
+        let stats = runtime.admission_stats();
+        assert_eq!((stats.admitted, stats.rejected), (2, 1));
     }
 }
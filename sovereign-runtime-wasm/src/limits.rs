@@ -0,0 +1,104 @@
+use crate::{HostBudget, RuntimeConfig};
+use serde::{Deserialize, Serialize};
+use wasmtime::ResourceLimiter;
+
+/// Resource bounds for a single execution. Unset fields fall back to the
+/// runtime defaults, and every field is clamped to the runtime's ceiling.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExecutionLimits {
+    pub fuel_limit: Option<u64>,
+    /// Total linear memory across all of the instance's memories.
+    pub max_memory_bytes: Option<usize>,
+    pub max_table_elements: Option<usize>,
+    pub max_instances: Option<usize>,
+    pub host_budget: Option<HostBudget>,
+}
+
+/// `ExecutionLimits` after defaults and ceilings have been applied.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ResolvedLimits {
+    pub fuel_limit: u64,
+    pub max_memory_bytes: usize,
+    pub max_table_elements: usize,
+    pub max_instances: usize,
+    pub host_budget: HostBudget,
+}
+
+impl ExecutionLimits {
+    /// Fills unset fields from `fallback`.
+    pub fn or(&self, fallback: &ExecutionLimits) -> ExecutionLimits {
+        ExecutionLimits {
+            fuel_limit: self.fuel_limit.or(fallback.fuel_limit),
+            max_memory_bytes: self.max_memory_bytes.or(fallback.max_memory_bytes),
+            max_table_elements: self.max_table_elements.or(fallback.max_table_elements),
+            max_instances: self.max_instances.or(fallback.max_instances),
+            host_budget: self.host_budget.or(fallback.host_budget),
+        }
+    }
+
+    pub(crate) fn resolve(&self, config: &RuntimeConfig) -> ResolvedLimits {
+        ResolvedLimits {
+            fuel_limit: self.fuel_limit.unwrap_or(config.default_fuel_limit).min(config.max_fuel_limit),
+            max_memory_bytes: self
+                .max_memory_bytes
+                .unwrap_or(config.default_memory_bytes)
+                .min(config.max_memory_bytes),
+            max_table_elements: self
+                .max_table_elements
+                .unwrap_or(config.max_table_elements)
+                .min(config.max_table_elements),
+            max_instances: self.max_instances.unwrap_or(config.max_instances).min(config.max_instances),
+            host_budget: self.host_budget.unwrap_or(config.host_budget),
+        }
+    }
+}
+
+/// Enforces memory and table caps on one store and records the memory high-water mark.
+pub(crate) struct StoreLimiter {
+    limits: ResolvedLimits,
+    trap_on_grow_failure: bool,
+    memory_bytes: usize,
+    pub peak_memory_bytes: usize,
+}
+
+impl StoreLimiter {
+    pub fn new(limits: ResolvedLimits, trap_on_grow_failure: bool) -> Self {
+        Self {
+            limits,
+            trap_on_grow_failure,
+            memory_bytes: 0,
+            peak_memory_bytes: 0,
+        }
+    }
+
+    fn deny(&self, what: &str, desired: usize, cap: usize) -> anyhow::Result<bool> {
+        if self.trap_on_grow_failure {
+            anyhow::bail!("{} growth to {} exceeds the limit of {}", what, desired, cap);
+        }
+        Ok(false)
+    }
+}
+
+impl ResourceLimiter for StoreLimiter {
+    fn memory_growing(&mut self, current: usize, desired: usize, _maximum: Option<usize>) -> anyhow::Result<bool> {
+        let total = self.memory_bytes + desired.saturating_sub(current);
+        if total > self.limits.max_memory_bytes {
+            return self.deny("Memory", total, self.limits.max_memory_bytes);
+        }
+        self.memory_bytes = total;
+        self.peak_memory_bytes = self.peak_memory_bytes.max(total);
+        Ok(true)
+    }
+
+    fn table_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> anyhow::Result<bool> {
+        if desired > self.limits.max_table_elements {
+            return self.deny("Table", desired, self.limits.max_table_elements);
+        }
+        Ok(true)
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.max_instances
+    }
+}
@@ -0,0 +1,241 @@
+use crate::{ExecutionLimits, ExecutionResult, ModuleRegistry, RunOptions, WasmError};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Longest pipeline accepted, to bound the work one request can start.
+pub const MAX_PIPELINE_STAGES: usize = 16;
+
+/// One step of a pipeline: a registered module and limits for its run.
+#[derive(Debug, Clone, Default)]
+pub struct PipelineStage {
+    pub module: String,
+    /// Unset fields fall back to the module's manifest, as for a single run.
+    pub limits: ExecutionLimits,
+}
+
+/// Bounds on the pipeline as a whole, applied on top of each stage's limits.
+#[derive(Debug, Clone, Default)]
+pub struct PipelineLimits {
+    /// Wall-clock time for all stages together.
+    pub timeout: Option<Duration>,
+    /// Fuel for all stages together.
+    pub total_fuel: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PipelineResult {
+    /// Results of the stages that ran, in order. A stage that failed to run
+    /// at all has no entry.
+    pub stages: Vec<ExecutionResult>,
+    /// What the last stage produced, when every stage succeeded.
+    pub output: Vec<u8>,
+    pub failure: Option<PipelineFailure>,
+}
+
+/// The stage that stopped the pipeline.
+#[derive(Debug, Clone)]
+pub struct PipelineFailure {
+    /// Zero-based index into the pipeline.
+    pub stage: usize,
+    pub module: String,
+    pub reason: String,
+}
+
+impl ModuleRegistry {
+    /// Runs registered modules in sequence, feeding each stage's output to the
+    /// next as its input. A stage's output is the region returned by a
+    /// memory-ABI `run`, or its stdout otherwise.
+    ///
+    /// Stops at the first stage that fails, traps, exits nonzero or runs past
+    /// the pipeline limits, and reports it in `PipelineResult::failure`.
+    /// Unknown modules are rejected before any stage runs. `options` applies
+    /// to every stage, e.g. for `source`.
+    pub async fn run_pipeline(
+        &self,
+        stages: &[PipelineStage],
+        input: &str,
+        limits: &PipelineLimits,
+        options: &RunOptions,
+    ) -> Result<PipelineResult, WasmError> {
+        if stages.is_empty() || stages.len() > MAX_PIPELINE_STAGES {
+            return Err(WasmError::InvalidPipeline(format!(
+                "a pipeline needs 1 to {} stages, not {}",
+                MAX_PIPELINE_STAGES,
+                stages.len()
+            )));
+        }
+        let mut defaults = Vec::with_capacity(stages.len());
+        for stage in stages {
+            let info = self.get(&stage.module).ok_or_else(|| WasmError::UnknownModule(stage.module.clone()))?;
+            defaults.push(info.manifest.limits);
+        }
+
+        let deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
+        let mut remaining_fuel = limits.total_fuel;
+        let mut result = PipelineResult::default();
+        let mut data = input.as_bytes().to_vec();
+
+        for (index, (stage, defaults)) in stages.iter().zip(&defaults).enumerate() {
+            let fail = |reason: String| PipelineFailure {
+                stage: index,
+                module: stage.module.clone(),
+                reason,
+            };
+            let mut stage_options = options.clone();
+            stage_options.limits = stage.limits.or(defaults);
+            if let Some(remaining) = remaining_fuel {
+                if remaining == 0 {
+                    result.failure = Some(fail("the pipeline's fuel budget is used up".into()));
+                    return Ok(result);
+                }
+                let stage_fuel = stage_options.limits.fuel_limit.unwrap_or(self.runtime().config.default_fuel_limit);
+                stage_options.limits.fuel_limit = Some(stage_fuel.min(remaining));
+            }
+
+            let stage_input = String::from_utf8_lossy(&data).into_owned();
+            let outcome = match self.start(&stage.module, &stage_input, &stage_options) {
+                // Dropping a run at the deadline stops the guest where it last yielded.
+                Ok((_, run)) => match deadline {
+                    Some(deadline) => match tokio::time::timeout_at(deadline, run).await {
+                        Ok(outcome) => outcome.map_err(|e| e.to_string()),
+                        Err(_) => Err(format!(
+                            "the pipeline timed out after {} ms",
+                            limits.timeout.unwrap_or_default().as_millis()
+                        )),
+                    },
+                    None => run.await.map_err(|e| e.to_string()),
+                },
+                Err(e) => Err(e.to_string()),
+            };
+            let out = match outcome {
+                Ok(out) => out,
+                Err(reason) => {
+                    result.failure = Some(fail(reason));
+                    return Ok(result);
+                }
+            };
+
+            if let (Some(remaining), Some(used)) = (remaining_fuel.as_mut(), out.fuel_used) {
+                *remaining = remaining.saturating_sub(used);
+            }
+            let reason = if out.trapped {
+                Some(format!(
+                    "trapped: {}",
+                    out.trap.as_ref().map_or("unknown trap", |trap| trap.message.as_str())
+                ))
+            } else {
+                out.exit_code.filter(|code| *code != 0).map(|code| format!("exited with code {}", code))
+            };
+            data = match &out.output {
+                Some(output) => output.clone(),
+                None => out.stdout.clone().into_bytes(),
+            };
+            result.stages.push(out);
+            if let Some(reason) = reason {
+                result.failure = Some(fail(reason));
+                return Ok(result);
+            }
+        }
+        result.output = data;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures, ModuleManifest, WasmRuntime};
+    use std::sync::Arc;
+
+    fn registry(dir: &std::path::Path, modules: &[(&str, String)]) -> ModuleRegistry {
+        let registry = ModuleRegistry::open(Arc::new(WasmRuntime::new().unwrap()), dir.to_path_buf()).unwrap();
+        for (name, wat) in modules {
+            registry.register(name, wat.clone().into_bytes(), ModuleManifest::default()).unwrap();
+        }
+        registry
+    }
+
+    fn stages(modules: &[&str]) -> Vec<PipelineStage> {
+        modules
+            .iter()
+            .map(|module| PipelineStage {
+                module: module.to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    async fn run(registry: &ModuleRegistry, modules: &[&str], limits: &PipelineLimits) -> Result<PipelineResult, WasmError> {
+        registry.run_pipeline(&stages(modules), "hello, world", limits, &RunOptions::default()).await
+    }
+
+    #[tokio::test]
+    async fn feeds_each_stage_the_previous_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = registry(
+            dir.path(),
+            &[("uppercase", fixtures::uppercase()), ("reverse", fixtures::reverse()), ("length", fixtures::length())],
+        );
+        let result = run(&registry, &["uppercase", "reverse", "length"], &PipelineLimits::default()).await.unwrap();
+        assert!(result.failure.is_none());
+        let outputs: Vec<&[u8]> = result.stages.iter().map(|stage| stage.output.as_deref().unwrap()).collect();
+        assert_eq!(outputs, [&b"HELLO, WORLD"[..], b"DLROW ,OLLEH", b"12"]);
+        assert_eq!(result.output, b"12");
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_stage_that_traps() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = registry(
+            dir.path(),
+            &[("uppercase", fixtures::uppercase()), ("trap", fixtures::run_traps()), ("length", fixtures::length())],
+        );
+        let result = run(&registry, &["uppercase", "trap", "length"], &PipelineLimits::default()).await.unwrap();
+        let failure = result.failure.unwrap();
+        assert_eq!((failure.stage, failure.module.as_str()), (1, "trap"));
+        assert!(failure.reason.starts_with("trapped: "), "{}", failure.reason);
+        assert_eq!(result.stages.len(), 2);
+        assert!(result.stages[1].trapped);
+        assert!(result.output.is_empty());
+    }
+
+    #[tokio::test]
+    async fn holds_every_stage_to_the_pipeline_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = registry(dir.path(), &[("uppercase", fixtures::uppercase()), ("spin", fixtures::spins())]);
+
+        let fuel = PipelineLimits {
+            total_fuel: Some(100_000),
+            ..Default::default()
+        };
+        let result = run(&registry, &["uppercase", "spin"], &fuel).await.unwrap();
+        let failure = result.failure.unwrap();
+        assert_eq!(failure.stage, 1);
+        assert!(failure.reason.contains("fuel"), "{}", failure.reason);
+        assert_eq!(result.stages.len(), 1);
+
+        let timeout = PipelineLimits {
+            timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let result = run(&registry, &["uppercase", "spin", "uppercase"], &timeout).await.unwrap();
+        let failure = result.failure.unwrap();
+        assert_eq!(failure.stage, 1);
+        assert_eq!(failure.reason, "the pipeline timed out after 50 ms");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn refuses_pipelines_that_cannot_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = registry(dir.path(), &[("uppercase", fixtures::uppercase())]);
+        assert!(matches!(run(&registry, &[], &PipelineLimits::default()).await, Err(WasmError::InvalidPipeline(_))));
+        let too_long = vec!["uppercase"; MAX_PIPELINE_STAGES + 1];
+        assert!(matches!(run(&registry, &too_long, &PipelineLimits::default()).await, Err(WasmError::InvalidPipeline(_))));
+        // Nothing runs when a later stage is missing.
+        let result = run(&registry, &["uppercase", "missing"], &PipelineLimits::default()).await;
+        assert!(matches!(result, Err(WasmError::UnknownModule(name)) if name == "missing"));
+        assert!(registry.runtime().module_stats().is_empty());
+    }
+}
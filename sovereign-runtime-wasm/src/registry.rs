@@ -0,0 +1,718 @@
+use crate::{Capability, ExecutionHandle, ExecutionLimits, ExecutionResult, ModuleSignature, RunOptions, ValidationReport, WasmError, WasmRuntime};
+use crate::artifact::ArtifactTags;
+use crate::component::Compiled;
+use crate::validate;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use crate::signing::verify_module;
+use sha2::{Digest, Sha256};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+
+const MODULE_FILE: &str = "module.wasm";
+const MANIFEST_FILE: &str = "manifest.json";
+/// The compiled module, valid for the engine described by `ModuleManifest::artifact`.
+const ARTIFACT_FILE: &str = "module.cwasm";
+/// Quiet period after a source file changes before it is reloaded, so a
+/// build that writes the file in several steps triggers one reload.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(250);
+/// Reload events buffered for slow subscribers before they lag.
+const EVENT_CAPACITY: usize = 64;
+
+/// What a module asks for when it is registered.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModuleManifest {
+    /// Capabilities granted to every run of the module.
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    /// Defaults for this module; per-run limits still take precedence.
+    #[serde(default)]
+    pub limits: ExecutionLimits,
+    /// Hex SHA-256 the bytes must match, if the publisher pinned one.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// Publisher signature over the module and the rest of this manifest.
+    #[serde(default)]
+    pub signature: Option<ModuleSignature>,
+    /// File the module was registered from. While the registry is watching,
+    /// changes to it are reloaded automatically.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_path: Option<PathBuf>,
+    /// Environment variable names the module may receive. Anything else the
+    /// caller passes is dropped; node-provided variables are injected if listed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_env: Vec<String>,
+    /// Core namespace the module's queries are confined to; see
+    /// `RunOptions::namespace`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// What the stored precompiled artifact was built for. Local to the node
+    /// and managed by the registry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact: Option<ArtifactTags>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModuleInfo {
+    pub name: String,
+    pub sha256: String,
+    pub size_bytes: u64,
+    pub manifest: ModuleManifest,
+    /// Imports, exports and declarations, recorded at registration.
+    pub report: ValidationReport,
+    /// Bumped each time the module is replaced, by re-registration or reload.
+    pub revision: u64,
+    /// Why the last reload from `source_path` failed; the previous revision
+    /// stays active until one succeeds.
+    pub reload_error: Option<String>,
+    /// A precompiled artifact matching this engine is stored with the module.
+    pub precompiled: bool,
+}
+
+/// Published to `ModuleRegistry::subscribe` receivers after each reload attempt.
+#[derive(Debug, Clone)]
+pub struct ModuleReloaded {
+    pub name: String,
+    /// The active revision: the new one, or the old one if `error` is set.
+    pub revision: u64,
+    pub sha256: String,
+    pub error: Option<String>,
+}
+
+#[derive(Clone)]
+struct RegisteredModule {
+    info: ModuleInfo,
+    bytes: Arc<Vec<u8>>,
+    /// Compiled once at registration and reused by every run.
+    compiled: Compiled,
+}
+
+/// Named modules with their capability grants, persisted under a store directory
+/// as `<name>/module.wasm` and `<name>/manifest.json`.
+pub struct ModuleRegistry {
+    runtime: Arc<WasmRuntime>,
+    dir: PathBuf,
+    modules: RwLock<HashMap<String, Arc<RegisteredModule>>>,
+    watcher: Mutex<Option<SourceWatcher>>,
+    events: broadcast::Sender<ModuleReloaded>,
+}
+
+struct SourceWatcher {
+    watcher: RecommendedWatcher,
+    /// Directories being watched. Source files are watched through their
+    /// directory so that editors replacing the file are noticed.
+    dirs: HashSet<PathBuf>,
+}
+
+impl ModuleRegistry {
+    /// Opens the store, loading every module that still validates.
+    pub fn open(runtime: Arc<WasmRuntime>, dir: PathBuf) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir).with_context(|| format!("creating module store {}", dir.display()))?;
+        let registry = Self {
+            runtime,
+            dir,
+            modules: RwLock::new(HashMap::new()),
+            watcher: Mutex::new(None),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        };
+
+        let mut modules = HashMap::new();
+        for entry in fs::read_dir(&registry.dir)?.flatten() {
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else { continue };
+            if validate_name("module", &name).is_err() || !entry.path().is_dir() {
+                continue;
+            }
+            match registry.load(&name, &entry.path()) {
+                Ok(module) => {
+                    modules.insert(name, Arc::new(module));
+                }
+                Err(e) => log::warn!("Skipping stored module {}: {:#}", name, e),
+            }
+        }
+        log::info!("Module registry loaded {} module(s) from {}", modules.len(), registry.dir.display());
+        *registry.modules.write().unwrap_or_else(|e| e.into_inner()) = modules;
+        Ok(registry)
+    }
+
+    /// Validates and stores a module, replacing any module of the same name.
+    pub fn register(&self, name: &str, bytes: Vec<u8>, mut manifest: ModuleManifest) -> anyhow::Result<ModuleInfo> {
+        validate_name("module", name)?;
+        if let Some(source) = &manifest.source_path {
+            manifest.source_path = Some(fs::canonicalize(source).with_context(|| format!("resolving {}", source.display()))?);
+        }
+        manifest.artifact = None;
+        let mut module = self.check(name, bytes, manifest, None)?;
+        if let Some(previous) = self.read_modules().get(name) {
+            module.info.revision = previous.info.revision + 1;
+        }
+
+        let module_dir = self.dir.join(name);
+        fs::create_dir_all(&module_dir)?;
+        write_atomic(&module_dir.join(MODULE_FILE), &module.bytes)?;
+        self.store_artifact(&module_dir, &mut module);
+        // The manifest goes last: a directory without one is ignored on load.
+        write_atomic(&module_dir.join(MANIFEST_FILE), &serde_json::to_vec_pretty(&module.info.manifest)?)?;
+
+        let info = module.info.clone();
+        self.write_modules().insert(name.to_string(), Arc::new(module));
+        log::info!("Registered module {} ({}, {} bytes)", name, info.sha256, info.size_bytes);
+        if let Some(source) = &info.manifest.source_path {
+            self.watch_source(source);
+        }
+        Ok(info)
+    }
+
+    /// Starts reloading modules from their `source_path` when it changes,
+    /// including modules registered later. New bytes are validated and compiled
+    /// before they replace the old module; runs already in progress finish on
+    /// the old one. Must be called inside a Tokio runtime.
+    pub fn watch(self: &Arc<Self>) -> anyhow::Result<()> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                for path in event.paths {
+                    let _ = tx.send(path);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Module source watcher error: {}", e),
+        })?;
+        *self.lock_watcher() = Some(SourceWatcher {
+            watcher,
+            dirs: HashSet::new(),
+        });
+
+        let sources: Vec<PathBuf> = self
+            .read_modules()
+            .values()
+            .filter_map(|m| m.info.manifest.source_path.clone())
+            .collect();
+        for source in &sources {
+            self.watch_source(source);
+        }
+        tokio::spawn(reload_changes(Arc::downgrade(self), rx));
+        Ok(())
+    }
+
+    pub(crate) fn runtime(&self) -> &WasmRuntime {
+        &self.runtime
+    }
+
+    /// Receives an event for every reload attempt from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ModuleReloaded> {
+        self.events.subscribe()
+    }
+
+    pub fn list(&self) -> Vec<ModuleInfo> {
+        let mut list: Vec<ModuleInfo> = self.read_modules().values().map(|m| m.info.clone()).collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    pub fn get(&self, name: &str) -> Option<ModuleInfo> {
+        self.read_modules().get(name).map(|m| m.info.clone())
+    }
+
+    /// The bytes of the module's active revision.
+    pub fn bytes(&self, name: &str) -> Option<Arc<Vec<u8>>> {
+        self.read_modules().get(name).map(|m| m.bytes.clone())
+    }
+
+    /// Returns whether a module was removed.
+    pub fn remove(&self, name: &str) -> anyhow::Result<bool> {
+        validate_name("module", name)?;
+        let removed = self.write_modules().remove(name).is_some();
+        if removed {
+            self.runtime.module_stats.remove(name);
+            fs::remove_dir_all(self.dir.join(name))?;
+        }
+        Ok(removed)
+    }
+
+    /// Runs a registered module with the capabilities granted at registration.
+    ///
+    /// `options.capabilities` may narrow the grant; asking for anything beyond
+    /// it fails with `CapabilityDenied`. Empty means the full grant.
+    /// Unset limits fall back to the manifest's.
+    pub async fn run(&self, name: &str, input: &str, options: &RunOptions) -> Result<ExecutionResult, WasmError> {
+        let (_, run) = self.start(name, input, options)?;
+        run.await
+    }
+
+    /// Like `run`, but also returns a handle that can cancel the run.
+    pub fn start<'a>(
+        &'a self,
+        name: &str,
+        input: &'a str,
+        options: &RunOptions,
+    ) -> Result<(ExecutionHandle, impl Future<Output = Result<ExecutionResult, WasmError>> + Send + 'a), WasmError> {
+        let module = self
+            .read_modules()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| WasmError::UnknownModule(name.to_string()))?;
+        let manifest = &module.info.manifest;
+        self.runtime.check_allowed(&module.info.sha256)?;
+
+        if let Some(cap) = options.capabilities.iter().find(|c| !manifest.capabilities.contains(c)) {
+            return Err(WasmError::CapabilityDenied(*cap));
+        }
+        let mut options = options.clone();
+        if options.capabilities.is_empty() {
+            options.capabilities = manifest.capabilities.clone();
+        }
+        options.limits = options.limits.or(&manifest.limits);
+        options.allowed_env = manifest.allowed_env.clone();
+        options.namespace = match (options.namespace, &manifest.namespace) {
+            (Some(caller), Some(module)) if caller != *module => {
+                return Err(WasmError::NamespaceMismatch { module: module.clone(), caller })
+            }
+            (caller, module) => caller.or_else(|| module.clone()),
+        };
+        if options.label.is_empty() {
+            options.label = name.to_string();
+        }
+        options.principal = Some(format!("module:{}", name));
+
+        // The signature was checked against the manifest at registration.
+        let (handle, guard) = self.runtime.executions.register(&self.runtime.engine, options.label.clone());
+        let name = name.to_string();
+        let run = async move {
+            let guard = guard;
+            let result = self
+                .runtime
+                .execute_module(&module.compiled, true, input, None, &options, &guard)
+                .await;
+            self.runtime.module_stats.record(&name, module.info.revision, &result);
+            result
+        };
+        Ok((handle, run))
+    }
+
+    fn load(&self, name: &str, dir: &Path) -> anyhow::Result<RegisteredModule> {
+        let manifest: ModuleManifest = serde_json::from_slice(&fs::read(dir.join(MANIFEST_FILE))?)?;
+        let bytes = fs::read(dir.join(MODULE_FILE))?;
+        let mut module = self.check(name, bytes, manifest, Some(&dir.join(ARTIFACT_FILE)))?;
+        if !module.info.precompiled {
+            self.store_artifact(dir, &mut module);
+            if let Err(e) = write_atomic(&dir.join(MANIFEST_FILE), &serde_json::to_vec_pretty(&module.info.manifest)?) {
+                log::warn!("Failed to record the precompiled artifact of module {}: {:#}", name, e);
+            }
+        }
+        Ok(module)
+    }
+
+    /// Verifies and compiles a module. With `artifact`, a stored precompiled
+    /// module is used instead when its tags match this engine.
+    fn check(
+        &self,
+        name: &str,
+        bytes: Vec<u8>,
+        manifest: ModuleManifest,
+        artifact: Option<&Path>,
+    ) -> anyhow::Result<RegisteredModule> {
+        let sha256 = hex::encode(Sha256::digest(&bytes));
+        if let Some(expected) = &manifest.sha256 {
+            anyhow::ensure!(
+                expected.eq_ignore_ascii_case(&sha256),
+                "SHA-256 mismatch: manifest expects {}, module is {}",
+                expected,
+                sha256
+            );
+        }
+        if self.runtime.config.require_signatures {
+            verify_module(&bytes, Some(&manifest), manifest.signature.as_ref(), &self.runtime.config.trusted_publishers)?;
+        }
+        let binary = validate::to_binary(&bytes)?;
+        let precompiled = artifact.and_then(|path| self.load_artifact(name, &sha256, &binary, &manifest, path));
+        let precompiled_ok = precompiled.is_some();
+        let compiled = match precompiled {
+            Some(compiled) => compiled,
+            None => self.runtime.compile(&bytes)?.0,
+        };
+        let mut report = validate::inspect(&binary);
+        report.text_format = !bytes.starts_with(b"\0asm");
+
+        Ok(RegisteredModule {
+            info: ModuleInfo {
+                name: name.to_string(),
+                sha256,
+                size_bytes: bytes.len() as u64,
+                manifest,
+                report,
+                revision: 0,
+                reload_error: None,
+                precompiled: precompiled_ok,
+            },
+            bytes: Arc::new(bytes),
+            compiled,
+        })
+    }
+
+    /// Loads the precompiled artifact at `path` if its tags match this engine.
+    /// A mismatched or unreadable artifact is reported and ignored.
+    fn load_artifact(
+        &self,
+        name: &str,
+        sha256: &str,
+        binary: &[u8],
+        manifest: &ModuleManifest,
+        path: &Path,
+    ) -> Option<Compiled> {
+        let tags = manifest.artifact.as_ref()?;
+        if *tags != ArtifactTags::current(&self.runtime.engine, sha256) {
+            log::info!(
+                "Precompiled artifact of module {} does not match this engine (built for {}, wasmtime {}); recompiling",
+                name,
+                tags.target,
+                tags.wasmtime
+            );
+            return None;
+        }
+        // SAFETY: `store_artifact` wrote this file with `Compiled::serialize`
+        // for these bytes under this engine configuration, as its tags record.
+        // wasmtime still checks the header and rejects incompatible artifacts.
+        match unsafe { Compiled::deserialize_file(&self.runtime.engine, binary, path) } {
+            Ok(compiled) => Some(compiled),
+            Err(e) => {
+                log::warn!("Discarding precompiled artifact of module {}: {:#}", name, e);
+                None
+            }
+        }
+    }
+
+    /// Writes the compiled module next to its bytes and records its tags in
+    /// the manifest, which the caller persists. A failure only costs a
+    /// recompile on the next load.
+    fn store_artifact(&self, module_dir: &Path, module: &mut RegisteredModule) {
+        let stored = module
+            .compiled
+            .serialize()
+            .and_then(|serialized| write_atomic(&module_dir.join(ARTIFACT_FILE), &serialized));
+        match stored {
+            Ok(()) => {
+                module.info.manifest.artifact = Some(ArtifactTags::current(&self.runtime.engine, &module.info.sha256));
+                module.info.precompiled = true;
+            }
+            Err(e) => {
+                log::warn!("Failed to store precompiled artifact of module {}: {:#}", module.info.name, e);
+                module.info.manifest.artifact = None;
+                module.info.precompiled = false;
+            }
+        }
+    }
+
+    fn watch_source(&self, source: &Path) {
+        let mut watcher = self.lock_watcher();
+        let Some(watcher) = watcher.as_mut() else { return };
+        let Some(dir) = source.parent() else { return };
+        if watcher.dirs.contains(dir) {
+            return;
+        }
+        match watcher.watcher.watch(dir, RecursiveMode::NonRecursive) {
+            Ok(()) => {
+                watcher.dirs.insert(dir.to_path_buf());
+            }
+            Err(e) => log::warn!("Cannot watch {} for module changes: {}", dir.display(), e),
+        }
+    }
+
+    /// Reloads every module whose source is one of `paths`.
+    fn reload_sources(&self, paths: &HashSet<PathBuf>) {
+        let names: Vec<String> = self
+            .read_modules()
+            .iter()
+            .filter(|(_, m)| m.info.manifest.source_path.as_ref().is_some_and(|p| paths.contains(p)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in names {
+            self.reload(&name);
+        }
+    }
+
+    fn reload(&self, name: &str) {
+        let Some(current) = self.read_modules().get(name).cloned() else { return };
+        let Some(source) = current.info.manifest.source_path.clone() else { return };
+
+        let replacement = match fs::read(&source) {
+            // Touched but unchanged.
+            Ok(bytes) if *bytes == **current.bytes => return,
+            Ok(bytes) => self.check(name, bytes, current.info.manifest.clone(), None).and_then(|mut module| {
+                module.info.revision = current.info.revision + 1;
+                let module_dir = self.dir.join(name);
+                write_atomic(&module_dir.join(MODULE_FILE), &module.bytes)?;
+                self.store_artifact(&module_dir, &mut module);
+                write_atomic(&module_dir.join(MANIFEST_FILE), &serde_json::to_vec_pretty(&module.info.manifest)?)?;
+                Ok(module)
+            }),
+            Err(e) => Err(anyhow::Error::new(e).context(format!("reading {}", source.display()))),
+        };
+        let (module, error) = match replacement {
+            Ok(module) => {
+                log::info!("Reloaded module {} from {} (revision {})", name, source.display(), module.info.revision);
+                (module, None)
+            }
+            Err(e) => {
+                let error = format!("{:#}", e);
+                log::warn!("Keeping revision {} of module {}: reload failed: {}", current.info.revision, name, error);
+                let mut module = (*current).clone();
+                module.info.reload_error = Some(error.clone());
+                (module, Some(error))
+            }
+        };
+
+        let event = ModuleReloaded {
+            name: name.to_string(),
+            revision: module.info.revision,
+            sha256: module.info.sha256.clone(),
+            error,
+        };
+        {
+            let mut modules = self.write_modules();
+            // Re-registered or removed while reloading: that takes precedence.
+            if !modules.get(name).is_some_and(|m| Arc::ptr_eq(m, &current)) {
+                return;
+            }
+            modules.insert(name.to_string(), Arc::new(module));
+        }
+        // No receivers is fine.
+        let _ = self.events.send(event);
+    }
+
+    fn lock_watcher(&self) -> std::sync::MutexGuard<'_, Option<SourceWatcher>> {
+        self.watcher.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn read_modules(&self) -> std::sync::RwLockReadGuard<'_, HashMap<String, Arc<RegisteredModule>>> {
+        self.modules.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write_modules(&self) -> std::sync::RwLockWriteGuard<'_, HashMap<String, Arc<RegisteredModule>>> {
+        self.modules.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Collects changed paths until they settle, then reloads the affected modules.
+async fn reload_changes(registry: Weak<ModuleRegistry>, mut changes: mpsc::UnboundedReceiver<PathBuf>) {
+    while let Some(path) = changes.recv().await {
+        let mut paths = HashSet::from([path]);
+        while let Ok(Some(path)) = tokio::time::timeout(RELOAD_DEBOUNCE, changes.recv()).await {
+            paths.insert(path);
+        }
+        let Some(registry) = registry.upgrade() else { return };
+        // Reading and compiling the new module is blocking work.
+        let reloaded = tokio::task::spawn_blocking(move || registry.reload_sources(&paths)).await;
+        if let Err(e) = reloaded {
+            log::warn!("Module reload task failed: {}", e);
+        }
+    }
+}
+
+/// Names double as file and directory names, so keep them to a safe alphabet.
+pub(crate) fn validate_name(what: &str, name: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !name.is_empty()
+            && name.len() <= 64
+            && !name.starts_with('.')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')),
+        "invalid {} name '{}': use 1-64 of [A-Za-z0-9._-], not starting with '.'",
+        what,
+        name
+    );
+    Ok(())
+}
+
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{errno, fixtures};
+
+    fn open(dir: &Path) -> ModuleRegistry {
+        ModuleRegistry::open(Arc::new(WasmRuntime::new().unwrap()), dir.to_path_buf()).unwrap()
+    }
+
+    fn granting(capabilities: &[Capability]) -> ModuleManifest {
+        ModuleManifest {
+            capabilities: capabilities.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn refuses_modules_that_do_not_validate() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = open(dir.path());
+        assert!(registry.register("broken", b"(module (func".to_vec(), ModuleManifest::default()).is_err());
+        let pinned = ModuleManifest {
+            sha256: Some("00".repeat(32)),
+            ..Default::default()
+        };
+        let err = registry.register("pinned", fixtures::prints().into_bytes(), pinned).unwrap_err();
+        assert!(err.to_string().contains("SHA-256 mismatch"), "{}", err);
+        assert!(registry.register("../escape", fixtures::prints().into_bytes(), ModuleManifest::default()).is_err());
+
+        assert!(registry.list().is_empty());
+        assert!(open(dir.path()).list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn runs_only_with_the_capabilities_granted_at_registration() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = open(dir.path());
+        let wat = fixtures::publishes("news", "hello", 1).into_bytes();
+        registry.register("granted", wat.clone(), granting(&[Capability::MeshPublish])).unwrap();
+        registry.register("ungranted", wat, granting(&[Capability::CoreRead])).unwrap();
+
+        // Granted, so the call gets as far as the missing mesh.
+        let granted = registry.run("granted", "", &RunOptions::default()).await.unwrap();
+        assert_eq!(granted.exit_code, Some(errno::UNAVAILABLE));
+        let ungranted = registry.run("ungranted", "", &RunOptions::default()).await.unwrap();
+        assert_eq!(ungranted.exit_code, Some(errno::DENIED));
+
+        let asking = RunOptions {
+            capabilities: vec![Capability::MeshPublish],
+            ..Default::default()
+        };
+        assert!(matches!(
+            registry.run("ungranted", "", &asking).await,
+            Err(WasmError::CapabilityDenied(Capability::MeshPublish))
+        ));
+        assert!(matches!(registry.run("missing", "", &asking).await, Err(WasmError::UnknownModule(_))));
+    }
+
+    #[tokio::test]
+    async fn modules_survive_reopening_the_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let registered = open(dir.path()).register("printer", fixtures::prints().into_bytes(), granting(&[Capability::CoreRead])).unwrap();
+
+        let registry = open(dir.path());
+        let info = registry.get("printer").unwrap();
+        assert_eq!(info.sha256, registered.sha256);
+        assert_eq!(info.manifest.capabilities, vec![Capability::CoreRead]);
+        assert!(info.precompiled);
+        assert_eq!(registry.run("printer", "", &RunOptions::default()).await.unwrap().stdout, "to stdout\n");
+
+        assert!(registry.remove("printer").unwrap());
+        assert!(!registry.remove("printer").unwrap());
+        assert!(open(dir.path()).list().is_empty());
+    }
+
+    /// Rewrites the stored manifest of `name` through `edit`.
+    fn edit_manifest(dir: &Path, name: &str, edit: impl FnOnce(&mut ModuleManifest)) {
+        let path = dir.join(name).join(MANIFEST_FILE);
+        let mut manifest: ModuleManifest = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        edit(&mut manifest);
+        fs::write(&path, serde_json::to_vec(&manifest).unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recompiles_and_replaces_artifacts_built_for_another_engine() {
+        let dir = tempfile::tempdir().unwrap();
+        open(dir.path()).register("printer", fixtures::prints().into_bytes(), ModuleManifest::default()).unwrap();
+        let artifact = dir.path().join("printer").join(ARTIFACT_FILE);
+        let current = |dir: &Path| open(dir).get("printer").unwrap().manifest.artifact.unwrap();
+        let tags = current(dir.path());
+        let mismatches: [fn(&mut ArtifactTags); 4] = [
+            |tags| tags.wasmtime = "1".into(),
+            |tags| tags.target = "riscv64-plan9".into(),
+            |tags| tags.engine = "00".repeat(32),
+            |tags| tags.sha256 = "00".repeat(32),
+        ];
+        for mismatch in mismatches {
+            edit_manifest(dir.path(), "printer", |manifest| mismatch(manifest.artifact.as_mut().unwrap()));
+            // The artifact itself still loads; only the tags say it must not be used.
+            fs::File::options().write(true).open(&artifact).unwrap().set_modified(std::time::UNIX_EPOCH).unwrap();
+
+            let registry = open(dir.path());
+            let info = registry.get("printer").unwrap();
+            assert!(info.precompiled);
+            assert_eq!(info.manifest.artifact.as_ref(), Some(&tags));
+            assert!(fs::metadata(&artifact).unwrap().modified().unwrap() > std::time::UNIX_EPOCH, "the artifact was not replaced");
+            assert_eq!(registry.run("printer", "", &RunOptions::default()).await.unwrap().stdout, "to stdout\n");
+        }
+        assert_eq!(current(dir.path()), tags);
+    }
+
+    #[tokio::test]
+    async fn falls_back_from_corrupt_or_missing_artifacts() {
+        let dir = tempfile::tempdir().unwrap();
+        open(dir.path()).register("printer", fixtures::prints().into_bytes(), ModuleManifest::default()).unwrap();
+        let artifact = dir.path().join("printer").join(ARTIFACT_FILE);
+
+        // Tags that match, over bytes that don't deserialize.
+        let mut corrupt = fs::read(&artifact).unwrap();
+        corrupt.truncate(corrupt.len() / 2);
+        fs::write(&artifact, &corrupt).unwrap();
+        let registry = open(dir.path());
+        assert!(registry.get("printer").unwrap().precompiled);
+        assert_eq!(registry.run("printer", "", &RunOptions::default()).await.unwrap().stdout, "to stdout\n");
+        assert!(fs::read(&artifact).unwrap().len() > corrupt.len());
+
+        fs::remove_file(&artifact).unwrap();
+        edit_manifest(dir.path(), "printer", |manifest| manifest.artifact = None);
+        let registry = open(dir.path());
+        assert!(registry.get("printer").unwrap().precompiled);
+        assert!(artifact.exists());
+        assert_eq!(registry.run("printer", "", &RunOptions::default()).await.unwrap().stdout, "to stdout\n");
+    }
+
+    async fn next_reload(events: &mut broadcast::Receiver<ModuleReloaded>) -> ModuleReloaded {
+        tokio::time::timeout(Duration::from_secs(10), events.recv()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reloads_a_changed_source_without_disturbing_runs_in_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("greeter.wat");
+        fs::write(&source, fixtures::prints_after(30_000_000, "old")).unwrap();
+        let registry = Arc::new(open(&dir.path().join("store")));
+        registry.watch().unwrap();
+        let mut events = registry.subscribe();
+        let manifest = ModuleManifest {
+            source_path: Some(source.clone()),
+            ..Default::default()
+        };
+        assert_eq!(registry.register("greeter", fs::read(&source).unwrap(), manifest).unwrap().revision, 0);
+
+        let old_run = tokio::spawn({
+            let registry = registry.clone();
+            async move { registry.run("greeter", "", &RunOptions::default()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        fs::write(&source, fixtures::prints_after(0, "new")).unwrap();
+
+        let reloaded = next_reload(&mut events).await;
+        assert_eq!((reloaded.name.as_str(), reloaded.revision, reloaded.error), ("greeter", 1, None));
+        assert_eq!(registry.run("greeter", "", &RunOptions::default()).await.unwrap().stdout, "new");
+        assert_eq!(old_run.await.unwrap().unwrap().stdout, "old");
+        assert!(open(&dir.path().join("store")).get("greeter").unwrap().sha256 == reloaded.sha256);
+
+        // A broken rewrite leaves the last good revision running.
+        fs::write(&source, "(module (func").unwrap();
+        let failed = next_reload(&mut events).await;
+        assert_eq!(failed.revision, 1);
+        assert!(failed.error.is_some());
+        assert!(registry.get("greeter").unwrap().reload_error.is_some());
+        assert_eq!(registry.run("greeter", "", &RunOptions::default()).await.unwrap().stdout, "new");
+
+        // Writes in quick succession are one reload.
+        fs::write(&source, fixtures::prints_after(0, "first")).unwrap();
+        fs::write(&source, fixtures::prints_after(0, "second")).unwrap();
+        assert_eq!(next_reload(&mut events).await.revision, 2);
+        assert_eq!(registry.run("greeter", "", &RunOptions::default()).await.unwrap().stdout, "second");
+        assert!(tokio::time::timeout(RELOAD_DEBOUNCE * 2, events.recv()).await.is_err());
+    }
+}
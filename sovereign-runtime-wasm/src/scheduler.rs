@@ -0,0 +1,471 @@
+use crate::cron::CronSpec;
+use crate::registry::{validate_name, write_atomic};
+use crate::{ExecutionLimits, ExecutionResult, ModuleRegistry, RunOptions};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+/// Completion events buffered for slow subscribers before they lag.
+const EVENT_CAPACITY: usize = 64;
+
+/// When a job runs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobSchedule {
+    /// Every `every_ms`, starting one interval after the job is scheduled.
+    Interval { every_ms: u64 },
+    /// A five-field cron expression in UTC; see `CronSpec`.
+    Cron { spec: String },
+}
+
+/// What happens when a job comes due while its previous run is still going.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlapPolicy {
+    /// Drop the run and count it as skipped.
+    #[default]
+    Skip,
+    /// Run once more as soon as the current run finishes. At most one run waits.
+    Queue,
+}
+
+/// A recurring run of a registered module.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobSpec {
+    pub module: String,
+    /// Input for each run. `{{job}}`, `{{run}}` and `{{scheduled_ms}}` (Unix
+    /// milliseconds) are substituted.
+    #[serde(default)]
+    pub input: String,
+    pub schedule: JobSchedule,
+    /// Merged over the module's manifest limits.
+    #[serde(default)]
+    pub limits: ExecutionLimits,
+    #[serde(default)]
+    pub overlap: OverlapPolicy,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Clone)]
+pub struct JobInfo {
+    pub name: String,
+    pub spec: JobSpec,
+    /// Runs started since the scheduler opened.
+    pub runs: u64,
+    /// Runs dropped under `OverlapPolicy::Skip`.
+    pub skipped: u64,
+    pub running: bool,
+    /// Unix milliseconds; `None` when disabled or nothing is due.
+    pub next_run_ms: Option<u64>,
+}
+
+/// One finished run of a job.
+#[derive(Debug, Clone)]
+pub struct JobRun {
+    /// 1-based, counting from when the scheduler opened.
+    pub run: u64,
+    pub started_ms: u64,
+    /// The error is rendered, since `WasmError` is not `Clone`.
+    pub result: Result<ExecutionResult, String>,
+}
+
+/// Published to `Scheduler::subscribe` receivers after every run.
+#[derive(Debug, Clone)]
+pub struct JobCompleted {
+    pub job: String,
+    pub run: JobRun,
+}
+
+#[derive(Default)]
+struct JobState {
+    running: AtomicBool,
+    runs: AtomicU64,
+    skipped: AtomicU64,
+    next_run_ms: AtomicU64,
+    history: Mutex<VecDeque<JobRun>>,
+}
+
+struct Job {
+    spec: JobSpec,
+    state: Arc<JobState>,
+    /// The timer and runner tasks; aborted when the job is replaced or removed.
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Runs registered modules on a schedule. Jobs are persisted to a JSON file
+/// and picked up again when the scheduler is reopened; run history is kept
+/// in memory only.
+///
+/// Must be opened inside a Tokio runtime, which runs the jobs. Dropping the
+/// scheduler stops them.
+pub struct Scheduler {
+    registry: Arc<ModuleRegistry>,
+    path: PathBuf,
+    history_len: usize,
+    jobs: Mutex<HashMap<String, Job>>,
+    events: broadcast::Sender<JobCompleted>,
+}
+
+impl Scheduler {
+    /// Opens the job file at `path`, starting every enabled job in it. Each job
+    /// keeps its last `history_len` runs.
+    pub fn open(registry: Arc<ModuleRegistry>, path: PathBuf, history_len: usize) -> anyhow::Result<Self> {
+        let specs: HashMap<String, JobSpec> = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).with_context(|| format!("reading jobs from {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("reading jobs from {}", path.display())),
+        };
+        let scheduler = Self {
+            registry,
+            path,
+            history_len: history_len.max(1),
+            jobs: Mutex::new(HashMap::new()),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        };
+
+        let mut jobs = scheduler.lock();
+        for (name, spec) in specs {
+            match scheduler.start(&name, spec) {
+                Ok(job) => {
+                    jobs.insert(name, job);
+                }
+                Err(e) => log::warn!("Skipping stored job {}: {:#}", name, e),
+            }
+        }
+        log::info!("Scheduler loaded {} job(s) from {}", jobs.len(), scheduler.path.display());
+        drop(jobs);
+        Ok(scheduler)
+    }
+
+    /// Adds a job, or replaces the job of the same name and its history.
+    pub fn schedule(&self, name: &str, spec: JobSpec) -> anyhow::Result<JobInfo> {
+        validate_name("job", name)?;
+        let job = self.start(name, spec)?;
+        let mut jobs = self.lock();
+        jobs.insert(name.to_string(), job);
+        self.persist(&jobs)?;
+        Ok(info(name, &jobs[name]))
+    }
+
+    pub fn list(&self) -> Vec<JobInfo> {
+        let jobs = self.lock();
+        let mut list: Vec<JobInfo> = jobs.iter().map(|(name, job)| info(name, job)).collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    pub fn get(&self, name: &str) -> Option<JobInfo> {
+        self.lock().get(name).map(|job| info(name, job))
+    }
+
+    /// Returns whether a job was removed. A run in progress is abandoned.
+    pub fn remove(&self, name: &str) -> anyhow::Result<bool> {
+        let mut jobs = self.lock();
+        let removed = jobs.remove(name).is_some();
+        if removed {
+            self.persist(&jobs)?;
+        }
+        Ok(removed)
+    }
+
+    /// The job's most recent runs, oldest first.
+    pub fn history(&self, name: &str) -> Option<Vec<JobRun>> {
+        let jobs = self.lock();
+        let history = jobs.get(name)?.state.history.lock().unwrap_or_else(|e| e.into_inner());
+        Some(history.iter().cloned().collect())
+    }
+
+    /// Receives an event for every run that finishes from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<JobCompleted> {
+        self.events.subscribe()
+    }
+
+    /// Validates `spec` and spawns its tasks.
+    fn start(&self, name: &str, spec: JobSpec) -> anyhow::Result<Job> {
+        let cron = match &spec.schedule {
+            JobSchedule::Interval { every_ms } => {
+                anyhow::ensure!(*every_ms > 0, "job interval must be positive");
+                None
+            }
+            JobSchedule::Cron { spec } => Some(spec.parse::<CronSpec>()?),
+        };
+        let state = Arc::new(JobState::default());
+        if !spec.enabled {
+            return Ok(Job { spec, state, tasks: Vec::new() });
+        }
+
+        // Capacity 1: a queued run is one that is already waiting here.
+        let (due_tx, due_rx) = mpsc::channel(1);
+        let timer = tokio::spawn(timer(spec.schedule.clone(), cron, spec.overlap, state.clone(), due_tx));
+        let runner = tokio::spawn(runner(
+            name.to_string(),
+            spec.clone(),
+            self.registry.clone(),
+            state.clone(),
+            self.history_len,
+            self.events.clone(),
+            due_rx,
+        ));
+        Ok(Job {
+            spec,
+            state,
+            tasks: vec![timer, runner],
+        })
+    }
+
+    fn persist(&self, jobs: &HashMap<String, Job>) -> anyhow::Result<()> {
+        let specs: HashMap<&String, &JobSpec> = jobs.iter().map(|(name, job)| (name, &job.spec)).collect();
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_atomic(&self.path, &serde_json::to_vec_pretty(&specs)?)
+            .with_context(|| format!("writing jobs to {}", self.path.display()))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Job>> {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn info(name: &str, job: &Job) -> JobInfo {
+    let next_run_ms = job.state.next_run_ms.load(Ordering::Relaxed);
+    JobInfo {
+        name: name.to_string(),
+        spec: job.spec.clone(),
+        runs: job.state.runs.load(Ordering::Relaxed),
+        skipped: job.state.skipped.load(Ordering::Relaxed),
+        running: job.state.running.load(Ordering::Relaxed),
+        next_run_ms: (job.spec.enabled && next_run_ms > 0).then_some(next_run_ms),
+    }
+}
+
+/// Signals the runner each time the job comes due, applying the overlap policy.
+async fn timer(
+    schedule: JobSchedule,
+    cron: Option<CronSpec>,
+    overlap: OverlapPolicy,
+    state: Arc<JobState>,
+    due: mpsc::Sender<u64>,
+) {
+    let mut interval = match schedule {
+        JobSchedule::Interval { every_ms } => {
+            let period = Duration::from_millis(every_ms);
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            Some((interval, every_ms))
+        }
+        JobSchedule::Cron { .. } => None,
+    };
+
+    loop {
+        let scheduled_ms = match (&mut interval, &cron) {
+            (Some((interval, every_ms)), _) => {
+                state.next_run_ms.store(unix_millis() + *every_ms, Ordering::Relaxed);
+                interval.tick().await;
+                unix_millis()
+            }
+            (None, Some(cron)) => {
+                let Some(next) = cron.next_after(unix_millis() / 1000) else {
+                    log::warn!("Cron job will never run again; stopping its timer");
+                    state.next_run_ms.store(0, Ordering::Relaxed);
+                    return;
+                };
+                let next_ms = next * 1000;
+                state.next_run_ms.store(next_ms, Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(next_ms.saturating_sub(unix_millis()))).await;
+                next_ms
+            }
+            (None, None) => return,
+        };
+
+        if state.running.load(Ordering::SeqCst) && overlap == OverlapPolicy::Skip {
+            state.skipped.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        match due.try_send(scheduled_ms) {
+            Ok(()) => {}
+            // A run is already queued behind the current one.
+            Err(mpsc::error::TrySendError::Full(_)) => {}
+            Err(mpsc::error::TrySendError::Closed(_)) => return,
+        }
+    }
+}
+
+/// Executes due runs one at a time and records their results.
+async fn runner(
+    name: String,
+    spec: JobSpec,
+    registry: Arc<ModuleRegistry>,
+    state: Arc<JobState>,
+    history_len: usize,
+    events: broadcast::Sender<JobCompleted>,
+    mut due: mpsc::Receiver<u64>,
+) {
+    let options = RunOptions {
+        limits: spec.limits.clone(),
+        source: format!("job:{}", name),
+        ..Default::default()
+    };
+    while let Some(scheduled_ms) = due.recv().await {
+        state.running.store(true, Ordering::SeqCst);
+        let run = state.runs.fetch_add(1, Ordering::Relaxed) + 1;
+        let input = spec
+            .input
+            .replace("{{job}}", &name)
+            .replace("{{run}}", &run.to_string())
+            .replace("{{scheduled_ms}}", &scheduled_ms.to_string());
+
+        let started_ms = unix_millis();
+        let started = Instant::now();
+        let result = registry.run(&spec.module, &input, &options).await.map_err(|e| e.to_string());
+        match &result {
+            Ok(out) if out.trapped => log::warn!("Job {} run {} trapped after {:?}", name, run, started.elapsed()),
+            Ok(_) => log::debug!("Job {} run {} finished in {:?}", name, run, started.elapsed()),
+            Err(e) => log::warn!("Job {} run {} failed: {}", name, run, e),
+        }
+
+        let record = JobRun { run, started_ms, result };
+        {
+            let mut history = state.history.lock().unwrap_or_else(|e| e.into_inner());
+            if history.len() >= history_len {
+                history.pop_front();
+            }
+            history.push_back(record.clone());
+        }
+        state.running.store(false, Ordering::SeqCst);
+        // No receivers is fine.
+        let _ = events.send(JobCompleted { job: name.clone(), run: record });
+    }
+}
+
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures, ModuleManifest, WasmRuntime};
+    use std::path::Path;
+
+    fn registry(dir: &Path) -> Arc<ModuleRegistry> {
+        let registry = ModuleRegistry::open(Arc::new(WasmRuntime::new().unwrap()), dir.join("modules")).unwrap();
+        registry.register("cat", fixtures::cat().into_bytes(), ModuleManifest::default()).unwrap();
+        registry.register("spin", fixtures::spins().into_bytes(), ModuleManifest::default()).unwrap();
+        Arc::new(registry)
+    }
+
+    fn every(every_ms: u64, module: &str) -> JobSpec {
+        JobSpec {
+            module: module.into(),
+            input: "{{job}} run {{run}}".into(),
+            schedule: JobSchedule::Interval { every_ms },
+            limits: ExecutionLimits::default(),
+            overlap: OverlapPolicy::Skip,
+            enabled: true,
+        }
+    }
+
+    async fn wait_for_runs(events: &mut broadcast::Receiver<JobCompleted>, n: usize) -> Vec<JobCompleted> {
+        let mut completed = Vec::new();
+        while completed.len() < n {
+            completed.push(tokio::time::timeout(Duration::from_secs(10), events.recv()).await.unwrap().unwrap());
+        }
+        completed
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn runs_jobs_on_their_interval_and_keeps_recent_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let scheduler = Scheduler::open(registry(dir.path()), dir.path().join("jobs.json"), 3).unwrap();
+        let mut events = scheduler.subscribe();
+        scheduler.schedule("count", every(50, "cat")).unwrap();
+
+        let completed = wait_for_runs(&mut events, 5).await;
+        for (i, event) in completed.iter().enumerate() {
+            assert_eq!((event.job.as_str(), event.run.run), ("count", i as u64 + 1));
+            assert_eq!(event.run.result.as_ref().unwrap().stdout, format!("count run {}", i + 1));
+        }
+        assert!(scheduler.get("count").unwrap().runs >= 5);
+        let history = scheduler.history("count").unwrap();
+        assert_eq!(history.len(), 3);
+        assert!(history.windows(2).all(|pair| pair[0].run + 1 == pair[1].run));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn skips_or_queues_runs_that_come_due_during_a_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let scheduler = Scheduler::open(registry(dir.path()), dir.path().join("jobs.json"), 10).unwrap();
+        let mut events = scheduler.subscribe();
+        // Each run spins through its fuel for far longer than the interval.
+        let slow = |overlap| JobSpec {
+            limits: ExecutionLimits {
+                fuel_limit: Some(100_000_000),
+                ..Default::default()
+            },
+            overlap,
+            ..every(10, "spin")
+        };
+
+        scheduler.schedule("skip", slow(OverlapPolicy::Skip)).unwrap();
+        let run = &wait_for_runs(&mut events, 1).await[0];
+        assert!(run.run.result.as_ref().unwrap_err().contains("ran out of fuel"));
+        let skip = scheduler.get("skip").unwrap();
+        assert!(skip.skipped > 0, "{:?}", skip);
+        assert!(scheduler.remove("skip").unwrap());
+
+        scheduler.schedule("queue", slow(OverlapPolicy::Queue)).unwrap();
+        wait_for_runs(&mut events, 2).await;
+        assert_eq!(scheduler.get("queue").unwrap().skipped, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn jobs_survive_reopening_the_scheduler() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = registry(dir.path());
+        let path = dir.path().join("jobs.json");
+        {
+            let scheduler = Scheduler::open(registry.clone(), path.clone(), 3).unwrap();
+            scheduler.schedule("count", every(50, "cat")).unwrap();
+            let paused = JobSpec {
+                enabled: false,
+                ..every(50, "cat")
+            };
+            scheduler.schedule("paused", paused).unwrap();
+            let err = scheduler.schedule("bad", every(0, "cat")).unwrap_err();
+            assert!(err.to_string().contains("positive"), "{}", err);
+        }
+
+        let scheduler = Scheduler::open(registry, path, 3).unwrap();
+        let names: Vec<String> = scheduler.list().into_iter().map(|job| job.name).collect();
+        assert_eq!(names, ["count", "paused"]);
+        assert!(scheduler.get("paused").unwrap().next_run_ms.is_none());
+        let mut events = scheduler.subscribe();
+        let run = &wait_for_runs(&mut events, 1).await[0];
+        assert_eq!((run.job.as_str(), run.run.run), ("count", 1));
+    }
+}
@@ -0,0 +1,179 @@
+use crate::{ModuleManifest, WasmError};
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Domain separator so module signatures can't be replayed as anything else.
+const SIGNATURE_CONTEXT: &[u8] = b"sovereign-module-signature-v1\0";
+
+/// Detached ed25519 signature over a module and, for registered modules, its manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModuleSignature {
+    /// `key_id` of the publisher key that produced the signature.
+    pub key_id: String,
+    /// Hex-encoded 64-byte signature.
+    pub signature: String,
+}
+
+/// Short stable identifier for a publisher key: the first 8 bytes of its SHA-256, in hex.
+pub fn key_id(key: &VerifyingKey) -> String {
+    hex::encode(&Sha256::digest(key.as_bytes())[..8])
+}
+
+/// Creates a new publisher key from the OS random source.
+pub fn generate_signing_key() -> SigningKey {
+    SigningKey::generate(&mut rand_core::OsRng)
+}
+
+/// Signs `bytes` and, if given, `manifest` (its own `signature` field is ignored).
+pub fn sign_module(bytes: &[u8], manifest: Option<&ModuleManifest>, signing_key: &SigningKey) -> ModuleSignature {
+    let signature = signing_key.sign(&signed_message(bytes, manifest));
+    ModuleSignature {
+        key_id: key_id(&signing_key.verifying_key()),
+        signature: hex::encode(signature.to_bytes()),
+    }
+}
+
+/// Checks `signature` against the trusted publisher keys.
+pub(crate) fn verify_module(
+    bytes: &[u8],
+    manifest: Option<&ModuleManifest>,
+    signature: Option<&ModuleSignature>,
+    trusted: &[VerifyingKey],
+) -> Result<(), WasmError> {
+    let Some(signature) = signature else {
+        return Err(WasmError::SignatureRejected {
+            key_id: None,
+            reason: "module is not signed".into(),
+        });
+    };
+    let rejected = |reason: &str| WasmError::SignatureRejected {
+        key_id: Some(signature.key_id.clone()),
+        reason: reason.to_string(),
+    };
+
+    let key = trusted
+        .iter()
+        .find(|key| key_id(key) == signature.key_id)
+        .ok_or_else(|| rejected("key is not a trusted publisher"))?;
+    let raw: [u8; 64] = hex::decode(&signature.signature)
+        .ok()
+        .and_then(|raw| raw.try_into().ok())
+        .ok_or_else(|| rejected("signature is not 64 hex-encoded bytes"))?;
+    key.verify(&signed_message(bytes, manifest), &ed25519_dalek::Signature::from_bytes(&raw))
+        .map_err(|_| rejected("signature does not match the module"))
+}
+
+fn signed_message(bytes: &[u8], manifest: Option<&ModuleManifest>) -> Vec<u8> {
+    let mut message = SIGNATURE_CONTEXT.to_vec();
+    message.extend_from_slice(&Sha256::digest(bytes));
+    if let Some(manifest) = manifest {
+        // The source path and artifact are local to the node, not something the publisher vouches for.
+        let unsigned = ModuleManifest {
+            signature: None,
+            source_path: None,
+            artifact: None,
+            ..manifest.clone()
+        };
+        let encoded = serde_json::to_vec(&unsigned).expect("manifest serialization cannot fail");
+        message.extend_from_slice(&Sha256::digest(&encoded));
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures, Capability, ModuleRegistry, RunOptions, RuntimeConfig, WasmRuntime};
+    use std::sync::Arc;
+
+    fn manifest() -> ModuleManifest {
+        ModuleManifest {
+            capabilities: vec![Capability::CoreRead],
+            ..Default::default()
+        }
+    }
+
+    fn rejected_key(result: Result<(), WasmError>) -> Option<String> {
+        match result {
+            Err(WasmError::SignatureRejected { key_id, .. }) => key_id,
+            other => panic!("Expected a rejected signature, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn accepts_a_valid_signature() {
+        let key = generate_signing_key();
+        let bytes = fixtures::prints().into_bytes();
+        let signature = sign_module(&bytes, Some(&manifest()), &key);
+        assert_eq!(signature.key_id, key_id(&key.verifying_key()));
+        verify_module(&bytes, Some(&manifest()), Some(&signature), &[key.verifying_key()]).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_tampered_module_or_manifest() {
+        let key = generate_signing_key();
+        let trusted = [key.verifying_key()];
+        let bytes = fixtures::prints().into_bytes();
+        let signature = sign_module(&bytes, Some(&manifest()), &key);
+
+        let tampered = fixtures::spins().into_bytes();
+        assert_eq!(rejected_key(verify_module(&tampered, Some(&manifest()), Some(&signature), &trusted)), Some(signature.key_id.clone()));
+
+        let widened = ModuleManifest {
+            capabilities: vec![Capability::CoreRead, Capability::CoreWrite],
+            ..manifest()
+        };
+        assert_eq!(rejected_key(verify_module(&bytes, Some(&widened), Some(&signature), &trusted)), Some(signature.key_id.clone()));
+
+        // Node-local fields aren't covered.
+        let moved = ModuleManifest {
+            source_path: Some("/elsewhere/module.wat".into()),
+            ..manifest()
+        };
+        verify_module(&bytes, Some(&moved), Some(&signature), &trusted).unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_keys_by_id_and_unsigned_modules() {
+        let (trusted, stranger) = (generate_signing_key(), generate_signing_key());
+        let bytes = fixtures::prints().into_bytes();
+        let signature = sign_module(&bytes, None, &stranger);
+        let err = verify_module(&bytes, None, Some(&signature), &[trusted.verifying_key()]).unwrap_err();
+        assert!(err.to_string().contains(&key_id(&stranger.verifying_key())), "{}", err);
+        assert!(err.to_string().contains("not a trusted publisher"), "{}", err);
+
+        assert_eq!(rejected_key(verify_module(&bytes, None, None, &[trusted.verifying_key()])), None);
+    }
+
+    #[tokio::test]
+    async fn required_signatures_guard_runs_and_registration() {
+        let key = generate_signing_key();
+        let runtime = Arc::new(
+            WasmRuntime::with_config(RuntimeConfig {
+                require_signatures: true,
+                trusted_publishers: vec![key.verifying_key()],
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let bytes = fixtures::prints().into_bytes();
+
+        let unsigned = runtime.run_module(&bytes, "", &RunOptions::default()).await;
+        assert!(matches!(unsigned, Err(WasmError::SignatureRejected { .. })));
+        let signed = RunOptions {
+            signature: Some(sign_module(&bytes, None, &key)),
+            ..Default::default()
+        };
+        assert_eq!(runtime.run_module(&bytes, "", &signed).await.unwrap().stdout, "to stdout\n");
+
+        let dir = tempfile::tempdir().unwrap();
+        let registry = ModuleRegistry::open(runtime, dir.path().to_path_buf()).unwrap();
+        assert!(registry.register("unsigned", bytes.clone(), manifest()).is_err());
+        let signed = ModuleManifest {
+            signature: Some(sign_module(&bytes, Some(&manifest()), &key)),
+            ..manifest()
+        };
+        registry.register("signed", bytes, signed).unwrap();
+    }
+}
@@ -0,0 +1,60 @@
+use crate::scheduler::unix_millis;
+use crate::{ExecutionResult, WasmError};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Cumulative resource usage of one registered module, across reloads.
+#[derive(Debug, Clone, Default)]
+pub struct ModuleStats {
+    pub runs: u64,
+    /// Runs that returned an error instead of a result.
+    pub failures: u64,
+    pub traps: u64,
+    /// Fuel consumed by runs that reported it.
+    pub fuel_used: u64,
+    pub total_duration: Duration,
+    /// Largest linear memory any run reached.
+    pub peak_memory_bytes: u64,
+    /// Unix time in milliseconds when the last run finished.
+    pub last_run_ms: Option<u64>,
+    /// Module revision the last run executed.
+    pub last_revision: u64,
+}
+
+/// Stats by module name. Entries outlive reloads and are dropped on removal.
+#[derive(Default)]
+pub(crate) struct StatsTable {
+    modules: Mutex<HashMap<String, ModuleStats>>,
+}
+
+impl StatsTable {
+    pub fn record(&self, name: &str, revision: u64, result: &Result<ExecutionResult, WasmError>) {
+        let mut modules = self.lock();
+        let stats = modules.entry(name.to_string()).or_default();
+        stats.runs += 1;
+        stats.last_run_ms = Some(unix_millis());
+        stats.last_revision = revision;
+        match result {
+            Ok(result) => {
+                stats.traps += result.trapped as u64;
+                stats.fuel_used += result.fuel_used.unwrap_or(0);
+                stats.total_duration += result.duration;
+                stats.peak_memory_bytes = stats.peak_memory_bytes.max(result.peak_memory_bytes);
+            }
+            Err(_) => stats.failures += 1,
+        }
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.lock().remove(name);
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, ModuleStats> {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, ModuleStats>> {
+        self.modules.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,357 @@
+use crate::WasmError;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::fmt;
+use wasmparser::{
+    ComponentExternalKind, ComponentTypeRef, ExternalKind, Operator, Parser, Payload, TypeRef, ValidPayload, Validator,
+};
+
+const WASM_MAGIC: &[u8] = b"\0asm";
+
+/// Where a module failed validation.
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidationError {
+    /// Byte offset into the binary module.
+    pub offset: usize,
+    /// Section being read, e.g. `code` or `import`.
+    pub section: String,
+    /// The instruction at `offset`, when the failure is inside a function body.
+    pub instruction: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at offset {:#x} in {} section", self.message, self.offset, self.section)?;
+        if let Some(instruction) = &self.instruction {
+            write!(f, ", instruction {}", instruction)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// A core import, or for components an imported interface or item, whose
+/// name is in `name` with `module` left empty.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportInfo {
+    pub module: String,
+    pub name: String,
+    pub kind: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportInfo {
+    pub name: String,
+    pub kind: String,
+}
+
+/// A memory or table declaration, imported or defined.
+#[derive(Debug, Clone, Serialize)]
+pub struct LimitsInfo {
+    /// Pages for memories, elements for tables.
+    pub initial: u64,
+    pub maximum: Option<u64>,
+    pub imported: bool,
+    pub shared: bool,
+}
+
+/// What a module declares, and whether it is valid.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ValidationReport {
+    pub valid: bool,
+    pub error: Option<ValidationError>,
+    /// The input was the text format and was converted before validation.
+    pub text_format: bool,
+    /// A preview-2 component rather than a core module. Imports and exports
+    /// are then the component's interfaces, e.g. `wasi:cli/run@0.2.0`.
+    pub component: bool,
+    pub imports: Vec<ImportInfo>,
+    pub exports: Vec<ExportInfo>,
+    pub memories: Vec<LimitsInfo>,
+    pub tables: Vec<LimitsInfo>,
+    pub has_start: bool,
+}
+
+/// Returns binary module bytes, converting the text format if `bytes` is not
+/// already a binary module.
+pub(crate) fn to_binary(bytes: &[u8]) -> Result<Cow<'_, [u8]>, WasmError> {
+    if bytes.starts_with(WASM_MAGIC) {
+        return Ok(Cow::Borrowed(bytes));
+    }
+    wat::parse_bytes(bytes).map_err(|e| WasmError::Compile(format!("invalid WAT: {}", e)))
+}
+
+/// Walks the module with wasmparser, recording declarations until the first
+/// validation error.
+pub(crate) fn inspect(bytes: &[u8]) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    match walk(bytes, &mut report) {
+        Ok(()) => report.valid = true,
+        Err(error) => report.error = Some(error),
+    }
+    report
+}
+
+fn walk(bytes: &[u8], report: &mut ValidationReport) -> Result<(), ValidationError> {
+    let mut validator = Validator::new();
+    let mut section = "header";
+    report.component = Parser::is_component(bytes);
+    // Core modules nested in a component are validated but not reported.
+    let mut depth = 0usize;
+
+    for payload in Parser::new(0).parse_all(bytes) {
+        let payload = payload.map_err(|e| binary_error(e, section, None))?;
+        section = section_name(&payload).unwrap_or(section);
+        let nested = depth > 0;
+        match payload {
+            Payload::ModuleSection { .. } | Payload::ComponentSection { .. } => depth += 1,
+            Payload::End(_) => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+
+        let valid = validator.payload(&payload).map_err(|e| binary_error(e, section, None))?;
+        if let ValidPayload::Func(func, body) = valid {
+            let mut func = func.into_validator(Default::default());
+            if let Err(e) = func.validate(&body) {
+                let instruction = instruction_at(&body, e.offset());
+                return Err(binary_error(e, section, instruction));
+            }
+        }
+        if nested {
+            continue;
+        }
+
+        match payload {
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import.map_err(|e| binary_error(e, section, None))?;
+                    let kind = match import.ty {
+                        TypeRef::Func(_) => "func",
+                        TypeRef::Table(table) => {
+                            report.tables.push(LimitsInfo {
+                                initial: table.initial,
+                                maximum: table.maximum,
+                                imported: true,
+                                shared: table.shared,
+                            });
+                            "table"
+                        }
+                        TypeRef::Memory(memory) => {
+                            report.memories.push(LimitsInfo {
+                                initial: memory.initial,
+                                maximum: memory.maximum,
+                                imported: true,
+                                shared: memory.shared,
+                            });
+                            "memory"
+                        }
+                        TypeRef::Global(_) => "global",
+                        TypeRef::Tag(_) => "tag",
+                    };
+                    report.imports.push(ImportInfo {
+                        module: import.module.to_string(),
+                        name: import.name.to_string(),
+                        kind: kind.to_string(),
+                    });
+                }
+            }
+            Payload::TableSection(reader) => {
+                for table in reader {
+                    let table = table.map_err(|e| binary_error(e, section, None))?;
+                    report.tables.push(LimitsInfo {
+                        initial: table.ty.initial,
+                        maximum: table.ty.maximum,
+                        imported: false,
+                        shared: table.ty.shared,
+                    });
+                }
+            }
+            Payload::MemorySection(reader) => {
+                for memory in reader {
+                    let memory = memory.map_err(|e| binary_error(e, section, None))?;
+                    report.memories.push(LimitsInfo {
+                        initial: memory.initial,
+                        maximum: memory.maximum,
+                        imported: false,
+                        shared: memory.shared,
+                    });
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export.map_err(|e| binary_error(e, section, None))?;
+                    let kind = match export.kind {
+                        ExternalKind::Func => "func",
+                        ExternalKind::Table => "table",
+                        ExternalKind::Memory => "memory",
+                        ExternalKind::Global => "global",
+                        ExternalKind::Tag => "tag",
+                    };
+                    report.exports.push(ExportInfo {
+                        name: export.name.to_string(),
+                        kind: kind.to_string(),
+                    });
+                }
+            }
+            Payload::StartSection { .. } => report.has_start = true,
+            Payload::ComponentImportSection(reader) => {
+                for import in reader {
+                    let import = import.map_err(|e| binary_error(e, section, None))?;
+                    let kind = match import.ty {
+                        ComponentTypeRef::Module(_) => "module",
+                        ComponentTypeRef::Func(_) => "func",
+                        ComponentTypeRef::Value(_) => "value",
+                        ComponentTypeRef::Type(_) => "type",
+                        ComponentTypeRef::Instance(_) => "instance",
+                        ComponentTypeRef::Component(_) => "component",
+                    };
+                    report.imports.push(ImportInfo {
+                        module: String::new(),
+                        name: import.name.0.to_string(),
+                        kind: kind.to_string(),
+                    });
+                }
+            }
+            Payload::ComponentExportSection(reader) => {
+                for export in reader {
+                    let export = export.map_err(|e| binary_error(e, section, None))?;
+                    let kind = match export.kind {
+                        ComponentExternalKind::Module => "module",
+                        ComponentExternalKind::Func => "func",
+                        ComponentExternalKind::Value => "value",
+                        ComponentExternalKind::Type => "type",
+                        ComponentExternalKind::Instance => "instance",
+                        ComponentExternalKind::Component => "component",
+                    };
+                    report.exports.push(ExportInfo {
+                        name: export.name.0.to_string(),
+                        kind: kind.to_string(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn section_name(payload: &Payload<'_>) -> Option<&'static str> {
+    Some(match payload {
+        Payload::Version { .. } => "header",
+        Payload::TypeSection(_) => "type",
+        Payload::ImportSection(_) => "import",
+        Payload::FunctionSection(_) => "function",
+        Payload::TableSection(_) => "table",
+        Payload::MemorySection(_) => "memory",
+        Payload::TagSection(_) => "tag",
+        Payload::GlobalSection(_) => "global",
+        Payload::ExportSection(_) => "export",
+        Payload::StartSection { .. } => "start",
+        Payload::ElementSection(_) => "element",
+        Payload::DataCountSection { .. } => "datacount",
+        Payload::DataSection(_) => "data",
+        Payload::CodeSectionStart { .. } | Payload::CodeSectionEntry(_) => "code",
+        Payload::CustomSection(_) => "custom",
+        Payload::ModuleSection { .. } => "module",
+        Payload::ComponentSection { .. } => "component",
+        Payload::ComponentImportSection(_) => "component import",
+        Payload::ComponentExportSection(_) => "component export",
+        Payload::ComponentTypeSection(_) => "component type",
+        Payload::ComponentCanonicalSection(_) => "canonical function",
+        Payload::ComponentInstanceSection(_) | Payload::InstanceSection(_) => "instance",
+        Payload::ComponentAliasSection(_) => "alias",
+        _ => return None,
+    })
+}
+
+fn binary_error(e: wasmparser::BinaryReaderError, section: &str, instruction: Option<String>) -> ValidationError {
+    ValidationError {
+        offset: e.offset(),
+        section: section.to_string(),
+        instruction,
+        message: e.message().to_string(),
+    }
+}
+
+/// Names the operator starting at `offset`, e.g. `I32Add`.
+fn instruction_at(body: &wasmparser::FunctionBody<'_>, offset: usize) -> Option<String> {
+    let mut reader = body.get_operators_reader().ok()?;
+    while !reader.eof() {
+        let (op, at) = reader.read_with_offset().ok()?;
+        if at == offset {
+            return Some(operator_name(&op));
+        }
+        if at > offset {
+            break;
+        }
+    }
+    None
+}
+
+fn operator_name(op: &Operator<'_>) -> String {
+    let debug = format!("{:?}", op);
+    let end = debug.find([' ', '{', '(']).unwrap_or(debug.len());
+    debug[..end].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures, RunOptions, WasmRuntime};
+
+    #[test]
+    fn reports_what_a_module_declares() {
+        let wat = r#"(module
+          (import "env" "tick" (func $tick))
+          (memory (export "memory") 1 2)
+          (table 3 funcref)
+          (func $main (export "main") (call $tick))
+          (start $main))"#;
+        let report = WasmRuntime::new().unwrap().validate_only(wat.as_bytes());
+        assert!(report.valid && report.text_format && !report.component);
+        assert!(report.error.is_none());
+        assert_eq!(report.imports.len(), 1);
+        assert_eq!((report.imports[0].module.as_str(), report.imports[0].name.as_str(), report.imports[0].kind.as_str()), ("env", "tick", "func"));
+        let exports: Vec<(&str, &str)> = report.exports.iter().map(|e| (e.name.as_str(), e.kind.as_str())).collect();
+        assert_eq!(exports, [("memory", "memory"), ("main", "func")]);
+        assert_eq!((report.memories[0].initial, report.memories[0].maximum, report.memories[0].imported), (1, Some(2), false));
+        assert_eq!((report.tables[0].initial, report.tables[0].maximum), (3, None));
+        assert!(report.has_start);
+    }
+
+    #[test]
+    fn pinpoints_the_failing_instruction() {
+        let binary = wat::parse_str("(module (func (drop (i32.add (i32.const 1) (i64.const 2)))))").unwrap();
+        let report = inspect(&binary);
+        assert!(!report.valid);
+        let error = report.error.unwrap();
+        assert_eq!(error.section, "code");
+        assert_eq!(error.instruction.as_deref(), Some("I32Add"));
+        // i32.add is the byte before the final drop and end.
+        assert_eq!(error.offset, binary.len() - 3);
+        assert!(error.message.contains("type mismatch"), "{}", error.message);
+        assert!(error.to_string().contains(&format!("at offset {:#x} in code section, instruction I32Add", error.offset)), "{}", error);
+    }
+
+    #[test]
+    fn reports_where_a_truncated_binary_ends() {
+        let binary = wat::parse_str(fixtures::prints()).unwrap();
+        let truncated = &binary[..binary.len() / 2];
+        let error = inspect(truncated).error.unwrap();
+        assert!(error.offset <= truncated.len());
+        assert_ne!(error.section, "header");
+    }
+
+    #[tokio::test]
+    async fn runs_the_text_format_and_refuses_invalid_binaries_with_the_location() {
+        let runtime = WasmRuntime::new().unwrap();
+        assert_eq!(runtime.run_module(fixtures::prints().as_bytes(), "", &RunOptions::default()).await.unwrap().stdout, "to stdout\n");
+
+        let binary = wat::parse_str("(module (func (drop (i32.add (i32.const 1) (i64.const 2)))))").unwrap();
+        match runtime.run_module(&binary, "", &RunOptions::default()).await {
+            Err(WasmError::Validation(error)) => assert_eq!(error.instruction.as_deref(), Some("I32Add")),
+            other => panic!("Expected a validation error, got {:?}", other.map(|r| r.stdout)),
+        }
+        assert!(matches!(runtime.validate(&binary), Err(WasmError::Validation(_))));
+    }
+}
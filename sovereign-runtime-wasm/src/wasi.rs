@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use wasmtime_wasi::p1::WasiP1Ctx;
+use wasmtime_wasi::p2::pipe::{MemoryInputPipe, MemoryOutputPipe};
+use wasmtime_wasi::{DirPerms, FilePerms, HostMonotonicClock, HostWallClock, WasiCtxBuilder};
+
+/// Cap on captured stdout/stderr per stream. Writes beyond it fail in the guest.
+const MAX_CAPTURED_OUTPUT: usize = 1024 * 1024;
+
+/// A host directory exposed to modules at `guest_path`.
+#[derive(Debug, Clone)]
+pub struct Preopen {
+    pub host_path: PathBuf,
+    pub guest_path: String,
+    pub read_only: bool,
+}
+
+/// A clock stuck at zero, used unless clocks are explicitly granted.
+struct FrozenClock;
+
+impl HostWallClock for FrozenClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn now(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+impl HostMonotonicClock for FrozenClock {
+    fn resolution(&self) -> u64 {
+        1_000_000_000
+    }
+
+    fn now(&self) -> u64 {
+        0
+    }
+}
+
+/// Per-execution WASI state plus handles to read back what the guest printed.
+pub(crate) struct WasiSession {
+    pub ctx: WasiP1Ctx,
+    pub stdout: MemoryOutputPipe,
+    pub stderr: MemoryOutputPipe,
+}
+
+/// Builds a WASI context that can see only `input` on stdin, `argv`, `env` and the given preopens.
+pub(crate) fn build_session(
+    input: &str,
+    argv: &[String],
+    env: &[(String, String)],
+    preopens: &[Preopen],
+    allow_clocks: bool,
+) -> anyhow::Result<WasiSession> {
+    let stdout = MemoryOutputPipe::new(MAX_CAPTURED_OUTPUT);
+    let stderr = MemoryOutputPipe::new(MAX_CAPTURED_OUTPUT);
+
+    let mut builder = WasiCtxBuilder::new();
+    builder
+        .stdin(MemoryInputPipe::new(input.as_bytes().to_vec()))
+        .stdout(stdout.clone())
+        .stderr(stderr.clone())
+        .args(argv)
+        .envs(env)
+        .allow_tcp(false)
+        .allow_udp(false)
+        .allow_ip_name_lookup(false);
+
+    if !allow_clocks {
+        builder.wall_clock(FrozenClock).monotonic_clock(FrozenClock);
+    }
+
+    for preopen in preopens {
+        let (dir_perms, file_perms) = if preopen.read_only {
+            (DirPerms::READ, FilePerms::READ)
+        } else {
+            (DirPerms::all(), FilePerms::all())
+        };
+        builder.preopened_dir(&preopen.host_path, &preopen.guest_path, dir_perms, file_perms)?;
+    }
+
+    Ok(WasiSession {
+        ctx: builder.build_p1(),
+        stdout,
+        stderr,
+    })
+}
+
+/// The environment a module receives: caller-supplied and node-provided
+/// variables whose names are in `allowed`, node values taking precedence.
+/// Everything else is dropped. Only names are ever logged, never values.
+pub(crate) fn policy_env(
+    requested: &[(String, String)],
+    allowed: &[String],
+    node: &[(String, String)],
+) -> Vec<(String, String)> {
+    let mut env: Vec<(String, String)> = node.iter().filter(|(name, _)| allowed.contains(name)).cloned().collect();
+    for (name, value) in requested {
+        if !allowed.contains(name) {
+            log::debug!("Dropping environment variable {}: not allowed for this module", name);
+        } else if !env.iter().any(|(n, _)| n == name) {
+            env.push((name.clone(), value.clone()));
+        }
+    }
+    env
+}
+
+pub(crate) fn captured(pipe: &MemoryOutputPipe) -> String {
+    String::from_utf8_lossy(&pipe.contents()).into_owned()
+}